@@ -0,0 +1,41 @@
+//! Build metadata for `darknode_backend::build_info`
+//!
+//! Builds from outside a git checkout, such as container builds of a source tarball, can
+//! set `DARKNODE_GIT_HASH` themselves, and reproducible builds pin the build time with
+//! `SOURCE_DATE_EPOCH`.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let git_hash = std::env::var("DARKNODE_GIT_HASH")
+        .ok()
+        .or_else(|| {
+            let output = Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output().ok()?;
+            output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let built_at_millis = match std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|secs| secs.parse::<u64>().ok()) {
+        Some(secs) => secs * 1000,
+        None => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64),
+    };
+
+    println!("cargo:rustc-env=DARKNODE_GIT_HASH={}", git_hash);
+    println!("cargo:rustc-env=DARKNODE_BUILT_AT_MILLIS={}", built_at_millis);
+    println!("cargo:rerun-if-env-changed=DARKNODE_GIT_HASH");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    // A commit moves the branch HEAD names, not HEAD itself, and the branch may only be
+    // kept in packed-refs; paths that don't exist would rerun this on every build
+    let branch = std::fs::read_to_string("../.git/HEAD")
+        .ok()
+        .and_then(|head| head.strip_prefix("ref: ").map(|branch| format!("../.git/{}", branch.trim())));
+    for path in branch.iter().map(String::as_str).chain(["../.git/packed-refs"]) {
+        if std::path::Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
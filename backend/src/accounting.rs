@@ -0,0 +1,507 @@
+//! Accounting of the work routing and exit nodes do for the network
+//!
+//! Node operators get credit for the traffic they carry, so the network can eventually
+//! compensate them. Two sides count the same traffic independently: each entry node
+//! tallies the requests and bytes it sent through every downstream node of its circuits
+//! and delivers the tally as a signed [`WorkReceipt`], and each downstream node reports its
+//! own counts in its heartbeats. The coordinator reconciles the two per node and epoch, and
+//! flags nodes reporting noticeably more work than entry nodes credited them with as
+//! suspected of inflating their counts. Only per-node, per-epoch totals exist anywhere,
+//! never anything about individual requests or users.
+//!
+//! Each receipt carries a nonce under its signature, and the coordinator takes a receipt
+//! once per nonce, so a receipt delivered twice, or posted again by whoever saw it, isn't
+//! credited twice.
+
+use super::*;
+use super::canonical::{self, CanonicalError, Signable};
+use super::epochs::Epoch;
+use super::identity::{self, NodeIdentity};
+use super::outbox::{Outbox, Report, ReportKind};
+use super::traits::{Crypto, NodeManager};
+use super::types::{NodeId, NodeRole};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// How work is counted and reconciled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountingConfig {
+    /// Whether nodes count work at all
+    pub enabled: bool,
+    /// Length of the epochs work is counted in; must match the coordinator's epoch length
+    pub epoch_length: Duration,
+    /// How often entry nodes deliver their receipts to the coordinator
+    pub receipt_interval: Duration,
+    /// Share by which a node's own count may exceed its credited work before it is flagged
+    pub max_discrepancy: f64,
+    /// Bytes per request a node may count on top of its credited bytes for encryption layers
+    pub envelope_overhead: u64,
+    /// Epochs the coordinator keeps totals for
+    pub retained_epochs: usize,
+}
+
+impl Default for AccountingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            epoch_length: Duration::from_secs(6 * 3600),
+            receipt_interval: Duration::from_secs(60),
+            max_discrepancy: 0.05,
+            envelope_overhead: 256,
+            retained_epochs: 28,
+        }
+    }
+}
+
+impl AccountingConfig {
+    /// The epoch work done at `now` counts towards
+    pub fn epoch_at(&self, now: Timestamp) -> u64 {
+        Epoch::at(self.epoch_length, now).number
+    }
+}
+
+/// Requests and bytes carried
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Work {
+    /// Requests carried
+    pub requests: u64,
+    /// Bytes of requests and responses carried
+    pub bytes: u64,
+}
+
+impl Work {
+    fn add(&mut self, other: Work) {
+        self.requests += other.requests;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Work a node reports having done itself during an epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EpochWork {
+    /// The epoch the work was done in
+    pub epoch: u64,
+    /// The work done
+    pub work: Work,
+}
+
+/// Work an entry node credits to one downstream node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeTally {
+    /// The node credited
+    pub node_id: NodeId,
+    /// The work it was sent
+    pub work: Work,
+}
+
+/// An entry node's signed statement of the work it sent through downstream nodes
+///
+/// Receipts are incremental: each covers the work since the issuer's previous receipt for
+/// the same epoch, and the coordinator adds them up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkReceipt {
+    /// The entry node issuing the receipt
+    pub issuer: NodeId,
+    /// The epoch the work was done in
+    pub epoch: u64,
+    /// Work per downstream node
+    pub tallies: Vec<NodeTally>,
+    /// Tells the receipt from any other of the issuer's, so it is credited once
+    pub nonce: Uuid,
+    /// The issuer's signature over the other fields
+    pub signature: Vec<u8>,
+}
+
+/// Domain line of signed work receipts, see [`crate::canonical`]
+const RECEIPT_DOMAIN: &str = "darknode-work-receipt:v1";
+
+/// The fields of a [`WorkReceipt`] its issuer signs
+#[derive(Serialize)]
+struct SignedWork<'a> {
+    issuer: &'a NodeId,
+    epoch: u64,
+    tallies: &'a [NodeTally],
+    nonce: Uuid,
+}
+
+impl Signable for WorkReceipt {
+    fn canonical_bytes(&self) -> Result<Vec<u8>, CanonicalError> {
+        canonical::encode(
+            RECEIPT_DOMAIN,
+            &SignedWork {
+                issuer: &self.issuer,
+                epoch: self.epoch,
+                tallies: &self.tallies,
+                nonce: self.nonce,
+            },
+        )
+    }
+}
+
+/// A receipt was refused by the coordinator
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ReceiptRejected {
+    /// The issuer isn't a registered entry node
+    #[error("receipt issuer {0} is not a registered entry node")]
+    UnknownIssuer(Uuid),
+    /// The signature doesn't verify under any of the issuer's keys
+    #[error("receipt signature from {0} does not verify")]
+    BadSignature(Uuid),
+    /// A receipt with the same nonce was credited already
+    #[error("receipt {1} from {0} was already credited")]
+    Replayed(Uuid, Uuid),
+}
+
+/// Work an entry node credits downstream nodes with, per epoch, until delivered as receipts
+pub struct WorkTally {
+    config: AccountingConfig,
+    epochs: parking_lot::Mutex<BTreeMap<u64, BTreeMap<Uuid, Work>>>,
+}
+
+impl WorkTally {
+    /// Create an empty tally
+    pub fn new(config: AccountingConfig) -> Self {
+        Self {
+            config,
+            epochs: parking_lot::Mutex::new(BTreeMap::new()),
+        }
+    }
+    
+    /// Credit each of `nodes` with `work` done at `now`
+    pub fn credit(&self, nodes: &[NodeId], work: Work, now: Timestamp) {
+        if !self.config.enabled {
+            return;
+        }
+        let epoch = self.config.epoch_at(now);
+        let mut epochs = self.epochs.lock();
+        let tallies = epochs.entry(epoch).or_default();
+        for node in nodes {
+            tallies.entry(node.0).or_default().add(work);
+        }
+    }
+    
+    /// Take the work credited since the last call, per epoch
+    pub fn take(&self) -> BTreeMap<u64, Vec<NodeTally>> {
+        std::mem::take(&mut *self.epochs.lock())
+            .into_iter()
+            .map(|(epoch, tallies)| {
+                let tallies = tallies
+                    .into_iter()
+                    .map(|(node_id, work)| NodeTally {
+                        node_id: NodeId(node_id),
+                        work,
+                    })
+                    .collect();
+                (epoch, tallies)
+            })
+            .collect()
+    }
+    
+    /// Add work back after the receipts carrying it could not be signed
+    pub fn restore(&self, epoch: u64, tallies: Vec<NodeTally>) {
+        let mut epochs = self.epochs.lock();
+        let restored = epochs.entry(epoch).or_default();
+        for tally in tallies {
+            restored.entry(tally.node_id.0).or_default().add(tally.work);
+        }
+    }
+}
+
+/// Coordinator path work receipts are posted to
+const RECEIPTS_PATH: &str = "/accounting/receipts";
+
+/// Sign the tally as receipts and queue them for the coordinator every receipt interval
+pub async fn deliver_receipts(
+    issuer: NodeId,
+    identity: Arc<NodeIdentity>,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    tally: Arc<WorkTally>,
+    outbox: Arc<Outbox>,
+) {
+    let mut ticker = tokio::time::interval(tally.config.receipt_interval);
+    
+    loop {
+        ticker.tick().await;
+        
+        for (epoch, tallies) in tally.take() {
+            let signed = async {
+                let mut receipt = WorkReceipt {
+                    issuer: issuer.clone(),
+                    epoch,
+                    tallies: tallies.clone(),
+                    nonce: Uuid::new_v4(),
+                    signature: Vec::new(),
+                };
+                receipt.signature = identity.sign(&*crypto, &receipt.canonical_bytes()?, Timestamp::now()).await?;
+                Report::new(ReportKind::Accounting, RECEIPTS_PATH, &receipt)
+            };
+            match signed.await {
+                Ok(report) => outbox.push(report),
+                Err(e) => {
+                    tracing::warn!("Failed to sign work receipt for epoch {}: {}", epoch, e);
+                    tally.restore(epoch, tallies);
+                }
+            }
+        }
+    }
+}
+
+/// One node's work during an epoch, as credited by entry nodes and as reported by itself
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeAccount {
+    /// The node
+    pub node_id: NodeId,
+    /// Work entry nodes' receipts credit the node with
+    pub credited: Work,
+    /// Work the node reported in its heartbeats
+    pub reported: Work,
+    /// Whether the node reported more work than it was credited beyond the tolerance
+    pub suspected_inflation: bool,
+}
+
+/// Per-node totals for one epoch
+#[derive(Debug, Clone, Serialize)]
+pub struct EpochAccounts {
+    /// The epoch
+    pub epoch: u64,
+    /// Totals of every node that was credited or reported work, by node ID
+    pub nodes: Vec<NodeAccount>,
+}
+
+/// Credited and reported totals of one node in one epoch
+#[derive(Debug, Clone, Copy, Default)]
+struct Totals {
+    credited: Work,
+    reported: Work,
+}
+
+/// Totals of every node in one epoch, and the receipts credited to them
+#[derive(Debug, Default)]
+struct EpochLedger {
+    nodes: HashMap<Uuid, Totals>,
+    receipts: HashSet<(Uuid, Uuid)>,
+}
+
+/// The coordinator's per-epoch totals of credited and reported work
+pub struct AccountingLedger {
+    config: AccountingConfig,
+    epochs: parking_lot::RwLock<BTreeMap<u64, EpochLedger>>,
+}
+
+impl AccountingLedger {
+    /// Create an empty ledger
+    pub fn new(config: AccountingConfig) -> Self {
+        Self {
+            config,
+            epochs: parking_lot::RwLock::new(BTreeMap::new()),
+        }
+    }
+    
+    /// Verify a receipt against its issuer's keys and add its tallies to the credited totals
+    pub async fn record_receipt(
+        &self,
+        receipt: &WorkReceipt,
+        node_manager: &(dyn NodeManager + Send + Sync),
+        crypto: &(dyn Crypto + Send + Sync),
+    ) -> Result<()> {
+        let issuer = node_manager
+            .get_node(&receipt.issuer)
+            .await?
+            .filter(|node| node.has_role(NodeRole::Entry))
+            .ok_or(ReceiptRejected::UnknownIssuer(receipt.issuer.0))?;
+        let data = receipt.canonical_bytes()?;
+        if !identity::verify_node_signature(crypto, &issuer, &data, &receipt.signature, Timestamp::now()).await? {
+            return Err(ReceiptRejected::BadSignature(receipt.issuer.0).into());
+        }
+        
+        let mut epochs = self.epochs.write();
+        let ledger = epochs.entry(receipt.epoch).or_default();
+        if !ledger.receipts.insert((receipt.issuer.0, receipt.nonce)) {
+            return Err(ReceiptRejected::Replayed(receipt.issuer.0, receipt.nonce).into());
+        }
+        for tally in &receipt.tallies {
+            ledger.nodes.entry(tally.node_id.0).or_default().credited.add(tally.work);
+        }
+        self.prune(&mut epochs);
+        Ok(())
+    }
+    
+    /// Add a node's own counts from a heartbeat to its reported totals
+    pub fn record_report(&self, node_id: &NodeId, work: &[EpochWork]) {
+        if work.is_empty() {
+            return;
+        }
+        let mut epochs = self.epochs.write();
+        for report in work {
+            epochs
+                .entry(report.epoch)
+                .or_default()
+                .nodes
+                .entry(node_id.0)
+                .or_default()
+                .reported
+                .add(report.work);
+        }
+        self.prune(&mut epochs);
+    }
+    
+    /// Reconciled totals for `epoch`
+    pub fn epoch(&self, epoch: u64) -> EpochAccounts {
+        let epochs = self.epochs.read();
+        let mut nodes: Vec<NodeAccount> = epochs
+            .get(&epoch)
+            .into_iter()
+            .flat_map(|ledger| &ledger.nodes)
+            .map(|(node_id, totals)| NodeAccount {
+                node_id: NodeId(*node_id),
+                credited: totals.credited,
+                reported: totals.reported,
+                suspected_inflation: self.inflated(totals),
+            })
+            .collect();
+        nodes.sort_by_key(|account| account.node_id.0);
+        EpochAccounts { epoch, nodes }
+    }
+    
+    /// Whether a node's reported work exceeds its credited work beyond the tolerance
+    fn inflated(&self, totals: &Totals) -> bool {
+        let tolerance = 1.0 + self.config.max_discrepancy;
+        let requests = totals.reported.requests as f64 > totals.credited.requests as f64 * tolerance;
+        let allowed_bytes = totals.credited.bytes + totals.credited.requests * self.config.envelope_overhead;
+        let bytes = totals.reported.bytes as f64 > allowed_bytes as f64 * tolerance;
+        requests || bytes
+    }
+    
+    /// Forget the oldest epochs beyond the retention
+    fn prune(&self, epochs: &mut BTreeMap<u64, EpochLedger>) {
+        while epochs.len() > self.config.retained_epochs {
+            epochs.pop_first();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::{CryptoImpl, StoredNodeManager};
+    use crate::storage::memory::MemoryStorage;
+    use crate::types::Node;
+    
+    /// A registered entry node and the coordinator's ledger
+    struct Fixture {
+        crypto: Arc<dyn Crypto + Send + Sync>,
+        node_manager: StoredNodeManager,
+        identity: NodeIdentity,
+        issuer: NodeId,
+        ledger: AccountingLedger,
+    }
+    
+    async fn fixture() -> Fixture {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let node_manager = StoredNodeManager::new(Arc::new(MemoryStorage::new()));
+        let identity = NodeIdentity::generate(&*crypto, Duration::from_secs(3600)).await.unwrap();
+        let issuer = NodeId(Uuid::new_v4());
+        node_manager
+            .register_node(Node {
+                id: issuer.clone(),
+                public_key: identity.public_key(Timestamp::now()),
+                port: 3001,
+                ..crate::fixtures::node(&[NodeRole::Entry])
+            })
+            .await
+            .unwrap();
+        Fixture {
+            crypto,
+            node_manager,
+            identity,
+            issuer,
+            ledger: AccountingLedger::new(AccountingConfig::default()),
+        }
+    }
+    
+    impl Fixture {
+        async fn receipt(&self, epoch: u64, tallies: Vec<NodeTally>) -> WorkReceipt {
+            let mut receipt = WorkReceipt {
+                issuer: self.issuer.clone(),
+                epoch,
+                tallies,
+                nonce: Uuid::new_v4(),
+                signature: Vec::new(),
+            };
+            let data = receipt.canonical_bytes().unwrap();
+            receipt.signature = self.identity.sign(&*self.crypto, &data, Timestamp::now()).await.unwrap();
+            receipt
+        }
+        
+        async fn record(&self, receipt: &WorkReceipt) -> Result<()> {
+            self.ledger.record_receipt(receipt, &self.node_manager, &*self.crypto).await
+        }
+    }
+    
+    #[tokio::test]
+    async fn simulated_traffic_tallies_alike_on_both_sides() {
+        let fixture = fixture().await;
+        let config = AccountingConfig::default();
+        let tally = WorkTally::new(config.clone());
+        let routing = NodeId(Uuid::new_v4());
+        let exit = NodeId(Uuid::new_v4());
+        let now = Timestamp::now();
+        let epoch = config.epoch_at(now);
+        
+        // The entry node credits both hops of every request; each hop counts what it carried
+        let mut carried = Work::default();
+        for bytes in [120, 480, 64] {
+            let work = Work { requests: 1, bytes };
+            tally.credit(&[routing.clone(), exit.clone()], work, now);
+            carried.add(work);
+        }
+        for (epoch, tallies) in tally.take() {
+            let receipt = fixture.receipt(epoch, tallies).await;
+            fixture.record(&receipt).await.unwrap();
+        }
+        for node in [&routing, &exit] {
+            fixture.ledger.record_report(node, &[EpochWork { epoch, work: carried }]);
+        }
+        
+        let accounts = fixture.ledger.epoch(epoch);
+        assert_eq!(accounts.nodes.len(), 2);
+        for account in &accounts.nodes {
+            assert_eq!(account.credited, carried);
+            assert_eq!(account.reported, carried);
+            assert!(!account.suspected_inflation);
+        }
+    }
+    
+    #[tokio::test]
+    async fn inflated_reports_are_flagged() {
+        let fixture = fixture().await;
+        let node = NodeId(Uuid::new_v4());
+        let credited = Work { requests: 100, bytes: 10_000 };
+        let receipt = fixture.receipt(7, vec![NodeTally { node_id: node.clone(), work: credited }]).await;
+        fixture.record(&receipt).await.unwrap();
+        fixture.ledger.record_report(&node, &[EpochWork { epoch: 7, work: Work { requests: 150, bytes: 10_000 } }]);
+        
+        assert!(fixture.ledger.epoch(7).nodes[0].suspected_inflation);
+    }
+    
+    #[tokio::test]
+    async fn receipts_are_credited_once() {
+        let fixture = fixture().await;
+        let node = NodeId(Uuid::new_v4());
+        let work = Work { requests: 10, bytes: 1_000 };
+        let receipt = fixture.receipt(7, vec![NodeTally { node_id: node.clone(), work }]).await;
+        fixture.record(&receipt).await.unwrap();
+        let replayed = fixture.record(&receipt).await.unwrap_err();
+        assert!(matches!(replayed.downcast_ref::<ReceiptRejected>(), Some(ReceiptRejected::Replayed(..))));
+        
+        // A fresh receipt for the same work is another receipt, and a changed nonce breaks the signature
+        fixture.record(&fixture.receipt(7, vec![NodeTally { node_id: node.clone(), work }]).await).await.unwrap();
+        let forged = WorkReceipt {
+            nonce: Uuid::new_v4(),
+            ..receipt
+        };
+        let refused = fixture.record(&forged).await.unwrap_err();
+        assert!(matches!(refused.downcast_ref::<ReceiptRejected>(), Some(ReceiptRejected::BadSignature(_))));
+        assert_eq!(fixture.ledger.epoch(7).nodes[0].credited.requests, 20);
+    }
+}
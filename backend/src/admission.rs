@@ -0,0 +1,317 @@
+//! Load shedding at the entry node when the network behind it is struggling
+//!
+//! When exit nodes are overloaded, every request the entry node accepts just waits for a
+//! timeout and adds to the pile. The [`AdmissionController`] watches a rolling window of
+//! request outcomes, and while the error rate or p95 latency is over its thresholds it
+//! sheds a growing share of new requests, lowest priority first. The admitted share backs
+//! off multiplicatively each time the network is found overloaded and recovers additively
+//! while it stays healthy, so shedding ramps up quickly and eases off gradually.
+
+use super::*;
+use super::events::{Event, EventSubscriber, RequestOutcome};
+use super::types::PriorityClass;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// Thresholds and pace of load shedding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdmissionConfig {
+    /// Whether requests are ever shed
+    pub enabled: bool,
+    /// How far back request outcomes are considered
+    pub window: Duration,
+    /// Outcomes needed in the window before the network can be judged overloaded
+    pub min_samples: usize,
+    /// Share of failed requests above which the network counts as overloaded
+    pub max_error_rate: f64,
+    /// p95 latency above which the network counts as overloaded
+    pub max_p95_latency: Duration,
+    /// How often the shed level is reconsidered
+    pub adjust_interval: Duration,
+    /// Factor the admitted share is multiplied by when the network is overloaded
+    pub decrease_factor: f64,
+    /// Share of requests re-admitted per interval while the network is healthy
+    pub increase_step: f64,
+    /// Highest shed level, so some high priority traffic always gets through
+    pub max_shed_level: f64,
+    /// How long shed clients are told to wait before retrying
+    pub retry_after: Duration,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: Duration::from_secs(10),
+            min_samples: 20,
+            max_error_rate: 0.25,
+            max_p95_latency: Duration::from_secs(5),
+            adjust_interval: Duration::from_secs(1),
+            decrease_factor: 0.75,
+            increase_step: 0.05,
+            max_shed_level: 0.9,
+            retry_after: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The entry node is shedding load and refused the request
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("entry node is overloaded, retry in {}s", retry_after.as_secs())]
+pub struct Overloaded {
+    /// How long the client should wait before retrying
+    pub retry_after: Duration,
+}
+
+/// The controller's view of the network, as reported on the readiness endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct AdmissionState {
+    /// Share of requests being shed, from 0 (none) to the configured maximum
+    pub shed_level: f64,
+    /// Whether the last assessment found the network overloaded
+    pub overloaded: bool,
+    /// Outcomes in the current window
+    pub samples: usize,
+    /// Share of failed requests in the current window
+    pub error_rate: f64,
+    /// p95 latency of the requests in the current window
+    pub p95_latency: Duration,
+}
+
+/// The outcome of one request that reached the network
+struct Sample {
+    at: Instant,
+    success: bool,
+    latency: Duration,
+}
+
+/// Recent outcomes and the shed level they led to
+struct Window {
+    samples: VecDeque<Sample>,
+    shed_level: f64,
+    overloaded: bool,
+    last_adjusted: Option<Instant>,
+    last_decreased: Option<Instant>,
+}
+
+/// Share of traffic in `priority` shed at `level`
+///
+/// Each class takes a third of the range: low priority traffic is shed first and is
+/// entirely shed by the time standard traffic starts, and high priority traffic is only
+/// shed once standard traffic is.
+pub fn shed_probability(level: f64, priority: PriorityClass) -> f64 {
+    let tier = match priority {
+        PriorityClass::Low => 0.0,
+        PriorityClass::Standard => 1.0,
+        PriorityClass::High => 2.0,
+    };
+    (level * 3.0 - tier).clamp(0.0, 1.0)
+}
+
+/// Label of a priority class in metrics
+pub fn priority_label(priority: PriorityClass) -> &'static str {
+    match priority {
+        PriorityClass::Low => "low",
+        PriorityClass::Standard => "standard",
+        PriorityClass::High => "high",
+    }
+}
+
+/// Decides which new requests the entry node admits, from the outcomes of earlier ones
+pub struct AdmissionController {
+    config: AdmissionConfig,
+    window: parking_lot::Mutex<Window>,
+}
+
+impl AdmissionController {
+    /// Create a controller that admits everything until it sees the network struggle
+    pub fn new(config: AdmissionConfig) -> Self {
+        Self {
+            config,
+            window: parking_lot::Mutex::new(Window {
+                samples: VecDeque::new(),
+                shed_level: 0.0,
+                overloaded: false,
+                last_adjusted: None,
+                last_decreased: None,
+            }),
+        }
+    }
+    
+    /// Admit or shed a new request of the given priority
+    pub fn admit(&self, priority: PriorityClass, now: Instant) -> Result<(), Overloaded> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let level = {
+            let mut window = self.window.lock();
+            self.adjust(&mut window, now);
+            window.shed_level
+        };
+        if rand::random::<f64>() < shed_probability(level, priority) {
+            metrics::increment_counter!("darknode_requests_shed_total", "priority" => priority_label(priority));
+            return Err(Overloaded {
+                retry_after: self.config.retry_after,
+            });
+        }
+        Ok(())
+    }
+    
+    /// Record the outcome of a request that went out into the network
+    pub fn record(&self, success: bool, latency: Duration, now: Instant) {
+        let mut window = self.window.lock();
+        window.samples.push_back(Sample {
+            at: now,
+            success,
+            latency,
+        });
+        self.adjust(&mut window, now);
+    }
+    
+    /// The controller's current view of the network
+    pub fn state(&self, now: Instant) -> AdmissionState {
+        let mut window = self.window.lock();
+        self.adjust(&mut window, now);
+        let (error_rate, p95_latency) = assess(&window.samples);
+        AdmissionState {
+            shed_level: window.shed_level,
+            overloaded: window.overloaded,
+            samples: window.samples.len(),
+            error_rate,
+            p95_latency,
+        }
+    }
+    
+    /// Whether the node should keep receiving traffic, false only while shedding at the maximum
+    pub fn ready(&self, now: Instant) -> bool {
+        self.state(now).shed_level < self.config.max_shed_level
+    }
+    
+    /// Drop outcomes that left the window and, once per interval, move the shed level
+    ///
+    /// After a decrease the admitted share is held for a full window before it can be cut
+    /// again, so the outcomes that caused one cut can't cause the next.
+    fn adjust(&self, window: &mut Window, now: Instant) {
+        while let Some(sample) = window.samples.front() {
+            if now.saturating_duration_since(sample.at) <= self.config.window {
+                break;
+            }
+            window.samples.pop_front();
+        }
+        if let Some(last) = window.last_adjusted {
+            if now.saturating_duration_since(last) < self.config.adjust_interval {
+                return;
+            }
+        }
+        window.last_adjusted = Some(now);
+        
+        let (error_rate, p95_latency) = assess(&window.samples);
+        window.overloaded = window.samples.len() >= self.config.min_samples
+            && (error_rate > self.config.max_error_rate || p95_latency > self.config.max_p95_latency);
+        
+        let admitted = 1.0 - window.shed_level;
+        let admitted = if window.overloaded {
+            let holding = window
+                .last_decreased
+                .map_or(false, |last| now.saturating_duration_since(last) < self.config.window);
+            if holding {
+                admitted
+            } else {
+                window.last_decreased = Some(now);
+                admitted * self.config.decrease_factor
+            }
+        } else {
+            admitted + self.config.increase_step
+        };
+        window.shed_level = (1.0 - admitted).clamp(0.0, self.config.max_shed_level);
+        metrics::gauge!("darknode_admission_shed_level", window.shed_level);
+    }
+}
+
+/// Error rate and p95 latency of a window of outcomes
+fn assess(samples: &VecDeque<Sample>) -> (f64, Duration) {
+    if samples.is_empty() {
+        return (0.0, Duration::ZERO);
+    }
+    let failures = samples.iter().filter(|sample| !sample.success).count();
+    let mut latencies: Vec<Duration> = samples.iter().map(|sample| sample.latency).collect();
+    latencies.sort_unstable();
+    let p95 = latencies[(latencies.len() * 95 / 100).min(latencies.len() - 1)];
+    (failures as f64 / samples.len() as f64, p95)
+}
+
+impl EventSubscriber for AdmissionController {
+    fn on_event(&self, event: &Event) {
+        match event {
+            Event::RequestCompleted {
+                outcome: outcome @ (RequestOutcome::Success | RequestOutcome::Failure),
+                latency,
+                ..
+            } => self.record(*outcome == RequestOutcome::Success, *latency, Instant::now()),
+            Event::CircuitBuildFailed => self.record(false, Duration::ZERO, Instant::now()),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Record one outcome every 100ms for `seconds`, returning the shed level at the end of each second
+    fn feed(controller: &AdmissionController, start: Instant, from: u64, seconds: u64, success: bool) -> Vec<f64> {
+        (from * 10..(from + seconds) * 10)
+            .filter_map(|tick| {
+                let now = start + Duration::from_millis(tick * 100);
+                controller.record(success, Duration::from_millis(50), now);
+                (tick % 10 == 9).then(|| controller.state(now).shed_level)
+            })
+            .collect()
+    }
+    
+    #[test]
+    fn shedding_ramps_up_while_requests_fail_and_eases_off_steadily_once_they_succeed() {
+        let config = AdmissionConfig::default();
+        let controller = AdmissionController::new(config.clone());
+        let start = Instant::now();
+        
+        let failing = feed(&controller, start, 0, 30, false);
+        assert!(failing.windows(2).all(|pair| pair[1] >= pair[0]), "{:?}", failing);
+        let peak = *failing.last().unwrap();
+        assert!(peak > 0.5, "{:?}", failing);
+        
+        // Low priority traffic is shed first, and high priority traffic still gets through
+        let now = start + Duration::from_secs(30);
+        assert!((0..100).all(|_| controller.admit(PriorityClass::Low, now).is_err()));
+        assert!((0..100).all(|_| controller.admit(PriorityClass::High, now).is_ok()));
+        
+        // Failures still in the window may cut once more, but once it eases off it never
+        // climbs back, and re-admits a step at a time
+        let recovering = feed(&controller, start, 30, 40, true);
+        let turned = recovering.windows(2).position(|pair| pair[1] < pair[0]).unwrap();
+        assert!(recovering[..=turned].iter().all(|level| *level >= peak), "{:?}", recovering);
+        for pair in recovering[turned..].windows(2) {
+            assert!(pair[1] <= pair[0], "{:?}", recovering);
+            assert!(pair[0] - pair[1] <= config.increase_step + 1e-9, "{:?}", recovering);
+        }
+        assert_eq!(*recovering.last().unwrap(), 0.0);
+        let now = start + Duration::from_secs(70);
+        assert!((0..100).all(|_| controller.admit(PriorityClass::Low, now).is_ok()));
+    }
+    
+    #[test]
+    fn each_class_takes_a_third_of_the_shed_level() {
+        let cases = [
+            (0.2, PriorityClass::Low, 0.6),
+            (0.2, PriorityClass::Standard, 0.0),
+            (0.5, PriorityClass::Low, 1.0),
+            (0.5, PriorityClass::Standard, 0.5),
+            (0.5, PriorityClass::High, 0.0),
+            (0.9, PriorityClass::High, 0.7),
+        ];
+        for (level, priority, shed) in cases {
+            assert!((shed_probability(level, priority) - shed).abs() < 1e-9, "{} {:?}", level, priority);
+        }
+    }
+}
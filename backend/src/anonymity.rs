@@ -0,0 +1,158 @@
+//! The boundary keeping clients' addressing out of circuits
+//!
+//! Providers must never learn who sent a request, so nothing the client's connection
+//! reveals about them may reach a circuit: not their socket address, and not the headers
+//! that name them, `X-Forwarded-For`, `Forwarded`, `X-Real-IP`, `User-Agent`, and cookies.
+//! The entry node enforces this in three places:
+//!
+//! - [`strip_client_headers`], the outermost middleware, removes those headers and the
+//!   socket address before any handler or log sees the request, keeping their values only
+//!   in the request's [`ClientTraces`], which never gives them back
+//! - the RPC extractor builds requests from body bytes and the client's own `X-Darknode-*`
+//!   headers alone, so everything a payload carries was either written by the client or set
+//!   by the node
+//! - as a request is sealed for the circuit, what the node added to it is looked over for
+//!   any trace, which is counted and logged for an operator to look into
+//!
+//! It is where bytes came from that keeps clients anonymous, not what they say: once the
+//! middleware has run nothing downstream can read the connection's values, and a request
+//! echoing its own `User-Agent` is the client's to send. The look-over is a tripwire for
+//! bugs, not a filter, and never fails a request.
+
+use crate::types::ExitPayload;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::net::SocketAddr;
+
+/// Headers naming the client, removed before the request is handled
+pub const STRIPPED_HEADERS: [&str; 5] = ["x-forwarded-for", "forwarded", "x-real-ip", "user-agent", "cookie"];
+
+/// Traces shorter than this are too common to identify anyone, and are left unchecked
+const MIN_TRACE_LEN: usize = 6;
+
+/// What the client's connection revealed about them, which must not reach a circuit
+#[derive(Debug, Clone, Default)]
+pub struct ClientTraces {
+    /// Each trace and where it came from
+    traces: Vec<(&'static str, String)>,
+}
+
+impl ClientTraces {
+    /// Note `value` from `source`, and each of the addresses or tokens it lists
+    fn note(&mut self, source: &'static str, value: &str) {
+        let parts = value.split(|c| c == ',' || c == ';' || c == '=');
+        for trace in std::iter::once(value).chain(parts) {
+            let trace = trace.trim_matches(|c: char| c.is_whitespace() || c == '"' || c == '[' || c == ']');
+            if trace.len() >= MIN_TRACE_LEN && !self.traces.iter().any(|(_, known)| known == trace) {
+                self.traces.push((source, trace.to_string()));
+            }
+        }
+    }
+    
+    /// Look over what the node added to `payload` for a trace, counting and logging one found
+    ///
+    /// The client's request is left out, as it came from their body and is theirs to send
+    /// whatever it says. A trace elsewhere points at a bug letting the connection's values
+    /// through, but may also be a client's own `X-Darknode-*` header echoing them, so it
+    /// is reported without failing the request.
+    pub fn check(&self, payload: &ExitPayload) -> Option<ClientTraceLeaked> {
+        let mut added = serde_json::to_value(payload).ok()?;
+        added.as_object_mut()?.remove("request");
+        let added = serde_json::to_vec(&added).ok()?;
+        let (origin, _) = self.traces.iter().find(|(_, trace)| contains(&added, trace.as_bytes()))?;
+        metrics::increment_counter!("darknode_client_trace_leaks_total", "origin" => *origin);
+        let leaked = ClientTraceLeaked { origin };
+        tracing::error!("{}", leaked);
+        Some(leaked)
+    }
+    
+    /// Whether nothing was noted
+    pub fn is_empty(&self) -> bool {
+        self.traces.is_empty()
+    }
+}
+
+/// Whether `needle` occurs in `haystack`
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+/// A request carrying what its client's connection revealed into a circuit
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("request carried the client's {origin} into the circuit")]
+pub struct ClientTraceLeaked {
+    /// Where the trace came from, such as `user-agent` or `peer address`
+    pub origin: &'static str,
+}
+
+/// Middleware removing the headers and address that name the client, noting them as [`ClientTraces`]
+///
+/// Install with `axum::middleware::from_fn(strip_client_headers)` as the outermost layer,
+/// on a server started with connect info so the socket address is noted too.
+pub async fn strip_client_headers<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let mut traces = ClientTraces::default();
+    if let Some(ConnectInfo(peer)) = request.extensions_mut().remove::<ConnectInfo<SocketAddr>>() {
+        traces.note("peer address", &peer.to_string());
+        traces.note("peer address", &peer.ip().to_string());
+    }
+    for name in STRIPPED_HEADERS {
+        for value in request.headers().get_all(name) {
+            traces.note(name, &String::from_utf8_lossy(value.as_bytes()));
+        }
+        request.headers_mut().remove(name);
+    }
+    request.extensions_mut().insert(traces);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+    
+    fn traces() -> ClientTraces {
+        let mut traces = ClientTraces::default();
+        traces.note("user-agent", "curl/8.4.0");
+        traces.note("peer address", "203.0.113.7");
+        traces
+    }
+    
+    #[test]
+    fn a_request_echoing_its_own_traces_is_the_clients_to_send() {
+        let mut payload = crate::emulation::version_request(crate::chains::Network::Mainnet);
+        payload.request["params"] = serde_json::json!(["curl/8.4.0", "203.0.113.7"]);
+        assert!(traces().check(&payload).is_none());
+    }
+    
+    #[test]
+    fn a_trace_in_what_the_node_added_is_reported() {
+        let mut payload = crate::emulation::version_request(crate::chains::Network::Mainnet);
+        payload.trace_token = Some("from-203.0.113.7".to_string());
+        assert_eq!(traces().check(&payload).map(|leaked| leaked.origin), Some("peer address"));
+    }
+    
+    #[tokio::test]
+    async fn nothing_past_the_middleware_can_read_the_connection() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|request: Request<Body>| async move {
+                    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().is_some();
+                    let agent = request.headers().contains_key("user-agent");
+                    let noted = request.extensions().get::<ClientTraces>().map_or(0, |traces| traces.traces.len());
+                    format!("{} {} {}", peer, agent, noted)
+                }),
+            )
+            .layer(axum::middleware::from_fn(strip_client_headers));
+        let mut request = Request::builder().uri("/").header("user-agent", "curl/8.4.0").body(Body::empty()).unwrap();
+        request.extensions_mut().insert(ConnectInfo("203.0.113.7:4100".parse::<SocketAddr>().unwrap()));
+        let response = app.oneshot(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"false false 3");
+    }
+}
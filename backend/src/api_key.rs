@@ -0,0 +1,35 @@
+//! Taking users' API keys on routes outside JSON-RPC
+//!
+//! Routes that act for a user, such as circuit rotation on entry nodes or mapping imports
+//! on the coordinator, take the user's API key in the `X-DarkNode-API-Key` header, read by
+//! the [`UserApiKey`] extractor. Keys are never taken from the URL, which ends up in access
+//! logs, proxies and browser history.
+
+use super::*;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+
+/// Header carrying the API key on routes that don't take it in a JSON-RPC body
+pub const API_KEY_HEADER: &str = "x-darknode-api-key";
+
+/// The API key a request carries in the `X-DarkNode-API-Key` header
+///
+/// Requests without one are refused with `401 Unauthorized`.
+#[derive(Debug, Clone)]
+pub struct UserApiKey(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for UserApiKey {
+    type Rejection = (StatusCode, String);
+    
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .headers
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|key| !key.is_empty())
+            .map(|key| UserApiKey(key.to_string()))
+            .ok_or_else(|| (StatusCode::UNAUTHORIZED, format!("Missing {} header", API_KEY_HEADER)))
+    }
+}
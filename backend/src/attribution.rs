@@ -0,0 +1,327 @@
+//! Attesting the class of provider that answered a request, without naming the provider
+//!
+//! A user may need to show which kind of provider served them, say that a historical
+//! balance came from an archive node, without DarkNode telling them, or anyone they show
+//! it to, which provider it was. Mappings with `provider_attribution` set get a
+//! [`ProviderAttestation`] in the response's `darknode` extension: the exit node's
+//! signature over the answering provider's class and a salted hash of the response.
+//!
+//! The class is the provider's capability set, hashed under a salt derived from the
+//! network epoch, whether it is an archive node, and the region of the exit node that
+//! called it. Providers with the same capabilities share a class hash within an epoch, and
+//! nothing about a provider other than its capabilities goes into it, so its ID and URL
+//! can't be recovered; the hash changes with every epoch, so classes can't be followed
+//! across epochs either. Anyone can rebuild the hash of a capability set with
+//! [`class_hash`] and compare.
+//!
+//! Attestations don't name the exit node, which the user's circuit keeps from them. Exits
+//! sign them with an attestation key of their own rather than their identity, which is
+//! never rotated so attestations keep verifying, and report it to the coordinator in their
+//! signed heartbeats. The coordinator publishes the keys of all exits at [`KEYS_PATH`] as
+//! one set, never beside the node each belongs to, so an attestation verifying under one
+//! of them shows an exit of the network signed it and not which. Clients fetch the set
+//! with [`fetch_keys`] and check a response they were given with [`verify_response`].
+//! Attested responses are always fetched from a provider, never answered from the exit
+//! node's cache.
+
+use super::*;
+use super::canonical::{self, CanonicalError, Signable};
+use super::epochs::Epoch;
+use super::identity::NodeIdentity;
+use super::methods;
+use super::traits::Crypto;
+use super::types::{CryptoKey, RpcProvider};
+use ed25519_dalek::Verifier;
+use sha2::{Digest, Sha256};
+
+/// Field of the `darknode` extension attestations are attached under
+pub const EXTENSION_FIELD: &str = "attribution";
+
+/// Coordinator path the set of exit nodes' attestation keys is published at
+pub const KEYS_PATH: &str = "/attestation-keys";
+
+/// Domain line of signed attestations, see [`crate::canonical`]
+const ATTESTATION_DOMAIN: &str = "darknode-attribution:v1";
+
+/// The capability marking archive providers
+const ARCHIVE_CAPABILITY: &str = "archive";
+
+/// Response members that differ between what the exit node attested and the client received
+const RESPONSE_IGNORED: &[&str] = &["id", "jsonrpc", methods::EXTENSION_KEY];
+
+/// An exit node's signed statement of the class of provider that answered a request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProviderAttestation {
+    /// The network epoch the class hash is salted for
+    pub epoch: u64,
+    /// Hex hash of the provider's capability set under the epoch's salt
+    pub class_hash: String,
+    /// Whether the provider is an archive node
+    pub archive: bool,
+    /// The region of the exit node that called the provider
+    pub region: String,
+    /// When the response was attested, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// Hex random salt of the response hash
+    pub salt: String,
+    /// Hex salted hash of the response
+    pub response_hash: String,
+    /// Hex signature by the exit node over the other fields
+    pub signature: String,
+}
+
+/// The fields of a [`ProviderAttestation`] the exit node signs
+#[derive(Serialize)]
+struct SignedAttestation<'a> {
+    epoch: u64,
+    class_hash: &'a str,
+    archive: bool,
+    region: &'a str,
+    timestamp: u64,
+    salt: &'a str,
+    response_hash: &'a str,
+}
+
+impl Signable for ProviderAttestation {
+    fn canonical_bytes(&self) -> Result<Vec<u8>, CanonicalError> {
+        canonical::encode(
+            ATTESTATION_DOMAIN,
+            &SignedAttestation {
+                epoch: self.epoch,
+                class_hash: &self.class_hash,
+                archive: self.archive,
+                region: &self.region,
+                timestamp: self.timestamp,
+                salt: &self.salt,
+                response_hash: &self.response_hash,
+            },
+        )
+    }
+}
+
+/// An attestation doesn't prove what it was presented as proving
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AttestationInvalid {
+    /// The response carries no attestation
+    #[error("response is not attested")]
+    Missing,
+    /// A field of the attestation can't be decoded
+    #[error("attestation is malformed")]
+    Malformed,
+    /// The response presented isn't the one the attestation covers
+    #[error("response does not match the attestation")]
+    ResponseMismatch,
+    /// The signature doesn't verify under any of the keys given
+    #[error("attestation signature does not verify")]
+    BadSignature,
+}
+
+/// Signs attestations for the responses an exit node's providers give
+pub struct Attestor {
+    key: Arc<NodeIdentity>,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    region: String,
+    epoch_length: Duration,
+}
+
+impl Attestor {
+    /// Create an attestor signing with the attestation `key` for an exit node in `region`,
+    /// salting under epochs of `epoch_length`
+    ///
+    /// The key should never be rotated, or attestations stop verifying once it is.
+    pub fn new(
+        key: Arc<NodeIdentity>,
+        crypto: Arc<dyn Crypto + Send + Sync>,
+        region: String,
+        epoch_length: Duration,
+    ) -> Self {
+        Self {
+            key,
+            crypto,
+            region,
+            epoch_length,
+        }
+    }
+    
+    /// Attest that `provider` gave `response` at `now`
+    pub async fn attest(
+        &self,
+        provider: &RpcProvider,
+        response: &serde_json::Value,
+        now: Timestamp,
+    ) -> Result<ProviderAttestation> {
+        let epoch = Epoch::at(self.epoch_length, now).number;
+        let salt: [u8; 16] = rand::random();
+        let mut attestation = ProviderAttestation {
+            epoch,
+            class_hash: class_hash(epoch, &provider.capabilities),
+            archive: provider.capabilities.iter().any(|capability| capability == ARCHIVE_CAPABILITY),
+            region: self.region.clone(),
+            timestamp: now.as_secs(),
+            salt: hex::encode(&salt),
+            response_hash: hex::encode(&response_digest(&salt, response)?),
+            signature: String::new(),
+        };
+        attestation.signature = hex::encode(&self.key.sign(&*self.crypto, &attestation.canonical_bytes()?, now).await?);
+        Ok(attestation)
+    }
+}
+
+/// Hex hash of a capability set under the salt of `epoch`, whatever order the capabilities are in
+pub fn class_hash(epoch: u64, capabilities: &[String]) -> String {
+    let mut capabilities: Vec<&str> = capabilities.iter().map(String::as_str).collect();
+    capabilities.sort_unstable();
+    capabilities.dedup();
+    let salt = Sha256::digest(format!("{}\nepoch {}", ATTESTATION_DOMAIN, epoch));
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    for capability in capabilities {
+        hasher.update(capability.as_bytes());
+        hasher.update(b"\n");
+    }
+    hex::encode(&hasher.finalize())
+}
+
+/// Check that `attestation` was signed with one of `keys` for `response`
+pub fn verify(
+    attestation: &ProviderAttestation,
+    response: &serde_json::Value,
+    keys: &[CryptoKey],
+) -> Result<(), AttestationInvalid> {
+    let salt = hex::decode(&attestation.salt).ok_or(AttestationInvalid::Malformed)?;
+    let response_hash = response_digest(&salt, response).map_err(|_| AttestationInvalid::Malformed)?;
+    if hex::encode(&response_hash) != attestation.response_hash {
+        return Err(AttestationInvalid::ResponseMismatch);
+    }
+    
+    let signature = hex::decode(&attestation.signature)
+        .and_then(|bytes| ed25519_dalek::Signature::from_bytes(&bytes).ok())
+        .ok_or(AttestationInvalid::Malformed)?;
+    let data = attestation.canonical_bytes().map_err(|_| AttestationInvalid::Malformed)?;
+    let signed = keys.iter().any(|key| {
+        ed25519_dalek::PublicKey::from_bytes(&key.0).map_or(false, |key| key.verify(&data, &signature).is_ok())
+    });
+    match signed {
+        true => Ok(()),
+        false => Err(AttestationInvalid::BadSignature),
+    }
+}
+
+/// Check the attestation `response` carries in its `darknode` extension against the
+/// exit nodes' attestation `keys`, returning it to read the class from
+///
+/// `response` is the JSON-RPC response as the client received it.
+pub fn verify_response(response: &serde_json::Value, keys: &[CryptoKey]) -> Result<ProviderAttestation, AttestationInvalid> {
+    let attestation = response
+        .get(methods::EXTENSION_KEY)
+        .and_then(|extension| extension.get(EXTENSION_FIELD))
+        .ok_or(AttestationInvalid::Missing)?;
+    let attestation: ProviderAttestation =
+        serde_json::from_value(attestation.clone()).map_err(|_| AttestationInvalid::Malformed)?;
+    verify(&attestation, response, keys)?;
+    Ok(attestation)
+}
+
+/// Fetch the set of exit nodes' attestation keys the coordinator at `coordinator_url` publishes
+pub async fn fetch_keys(coordinator_url: &str) -> Result<Vec<CryptoKey>> {
+    let url = format!("{}{}", coordinator_url.trim_end_matches('/'), KEYS_PATH);
+    let keys = reqwest::get(url).await?.error_for_status()?.json().await?;
+    Ok(keys)
+}
+
+/// Salted hash of `response` in canonical form, without the members the entry node may change
+fn response_digest(salt: &[u8], response: &serde_json::Value) -> Result<[u8; 32]> {
+    let mut response = canonical::sorted(response.clone());
+    if let Some(object) = response.as_object_mut() {
+        object.retain(|key, member| !RESPONSE_IGNORED.contains(&key.as_str()) && !member.is_null());
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(serde_json::to_vec(&response)?);
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::CryptoImpl;
+    
+    const EPOCH_LENGTH: Duration = Duration::from_secs(3600);
+    
+    fn provider(url: &str, capabilities: &[&str]) -> RpcProvider {
+        RpcProvider {
+            url: url.to_string(),
+            success_rate: 1.0,
+            avg_latency: Duration::from_millis(10),
+            capabilities: capabilities.iter().map(|capability| capability.to_string()).collect(),
+            ..crate::fixtures::provider()
+        }
+    }
+    
+    async fn attestor() -> (Attestor, CryptoKey) {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let key = Arc::new(NodeIdentity::generate(&*crypto, Duration::ZERO).await.unwrap());
+        let public = key.public_key(Timestamp::now());
+        (Attestor::new(key, crypto, "eu-west".to_string(), EPOCH_LENGTH), public)
+    }
+    
+    /// `response` as the client gets it: attested, under its own request ID
+    fn delivered(response: &serde_json::Value, attestation: &ProviderAttestation) -> serde_json::Value {
+        let mut delivered = response.clone();
+        delivered["id"] = serde_json::json!(7);
+        methods::set_extension(&mut delivered, EXTENSION_FIELD, serde_json::json!(attestation));
+        delivered
+    }
+    
+    #[tokio::test]
+    async fn attestations_verify_and_report_the_providers_class() {
+        let (attestor, key) = attestor().await;
+        let (_, other_key) = self::attestor().await;
+        let response = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "result": { "value": 42 } });
+        let now = Timestamp::from_secs(1_700_000_000);
+        
+        let archive = attestor.attest(&provider("https://a.example", &["archive", "trace"]), &response, now).await.unwrap();
+        let verified = verify_response(&delivered(&response, &archive), &[other_key.clone(), key.clone()]).unwrap();
+        assert!(verified.archive);
+        assert_eq!(verified.region, "eu-west");
+        assert_eq!(verified.class_hash, class_hash(verified.epoch, &["trace".to_string(), "archive".to_string()]));
+        let full = attestor.attest(&provider("https://b.example", &["trace"]), &response, now).await.unwrap();
+        assert!(!verify_response(&delivered(&response, &full), std::slice::from_ref(&key)).unwrap().archive);
+        
+        // Another result, another signer, or no attestation at all don't verify
+        let mut tampered = delivered(&response, &archive);
+        tampered["result"]["value"] = serde_json::json!(43);
+        assert_eq!(verify_response(&tampered, std::slice::from_ref(&key)), Err(AttestationInvalid::ResponseMismatch));
+        assert_eq!(verify_response(&delivered(&response, &archive), &[other_key]), Err(AttestationInvalid::BadSignature));
+        assert_eq!(verify_response(&response, &[key]), Err(AttestationInvalid::Missing));
+    }
+    
+    #[tokio::test]
+    async fn providers_with_the_same_capabilities_share_a_class_hash() {
+        let (attestor, _) = attestor().await;
+        let response = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "result": 250_000_000 });
+        let now = Timestamp::from_secs(1_700_000_000);
+        let first = attestor.attest(&provider("https://a.example", &["archive", "trace"]), &response, now).await.unwrap();
+        let second = attestor.attest(&provider("https://b.example", &["trace", "archive", "trace"]), &response, now).await.unwrap();
+        let third = attestor.attest(&provider("https://a.example", &["trace"]), &response, now).await.unwrap();
+        assert_eq!(first.class_hash, second.class_hash);
+        assert_ne!(first.class_hash, third.class_hash);
+        
+        // Nothing but the class hash and the archive flag come from the provider
+        let attested = serde_json::to_string(&first).unwrap();
+        assert!(!attested.contains("a.example"));
+    }
+    
+    #[tokio::test]
+    async fn class_hashes_change_across_epochs() {
+        let (attestor, _) = attestor().await;
+        let response = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "result": 250_000_000 });
+        let archive = provider("https://a.example", &["archive"]);
+        let now = Timestamp::from_secs(1_700_000_000);
+        let this_epoch = attestor.attest(&archive, &response, now).await.unwrap();
+        let next_epoch = attestor.attest(&archive, &response, Timestamp::from_secs(1_700_000_000 + 3600)).await.unwrap();
+        assert_eq!(next_epoch.epoch, this_epoch.epoch + 1);
+        assert_ne!(this_epoch.class_hash, next_epoch.class_hash);
+        assert_eq!(class_hash(this_epoch.epoch, &archive.capabilities), this_epoch.class_hash);
+    }
+}
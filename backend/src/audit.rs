@@ -0,0 +1,369 @@
+//! Privacy-preserving audit trail of provider responses
+//!
+//! Exit nodes keep salted hashes of what providers returned, never the responses
+//! themselves, so an operator can settle "DarkNode returned wrong data" disputes by
+//! comparing what the user received against the trail.
+//!
+//! The trail covers a response as the user sees it, less what nodes change on the way
+//! back: the JSON-RPC `id`, which the entry node restores to the client's, and the
+//! DarkNode extension, which every node may add to, see [`audited_body`]. The trail and
+//! its salt are kept in a file, so a dispute can be settled after the node restarts.
+
+use super::*;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+/// How long audit records are kept, and where
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Records older than this are dropped
+    pub retention: Duration,
+    /// The file the trail and its salt are kept in; the trail lasts only as long as the
+    /// process if unset
+    pub path: Option<PathBuf>,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            retention: Duration::from_secs(7 * 24 * 60 * 60),
+            path: None,
+        }
+    }
+}
+
+/// The part of a response body the trail covers
+///
+/// A JSON-RPC response, or each in a batch, is taken without its `id` and DarkNode
+/// extension, with the members of every object sorted. Anything else is covered as it is.
+pub fn audited_body(response: &[u8]) -> Vec<u8> {
+    fn strip(response: &mut serde_json::Value) {
+        match response {
+            serde_json::Value::Array(responses) => responses.iter_mut().for_each(strip),
+            serde_json::Value::Object(members) => {
+                members.remove("id");
+                members.remove(methods::EXTENSION_KEY);
+            }
+            _ => {}
+        }
+    }
+    
+    match serde_json::from_slice::<serde_json::Value>(response) {
+        Ok(mut value) => {
+            strip(&mut value);
+            serde_json::to_vec(&canonical::sorted(value)).unwrap_or_else(|_| response.to_vec())
+        }
+        Err(_) => response.to_vec(),
+    }
+}
+
+/// A salted SHA-256 digest
+///
+/// Only [`AuditLog`] can produce one, by hashing or reading back its trail, so records
+/// built from digests can never carry payload bytes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct AuditDigest([u8; 32]);
+
+impl AuditDigest {
+    /// The digest before the first record in a trail
+    const GENESIS: Self = Self([0; 32]);
+}
+
+impl fmt::Display for AuditDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(&self.0))
+    }
+}
+
+impl fmt::Debug for AuditDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AuditDigest({})", self)
+    }
+}
+
+impl Serialize for AuditDigest {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for AuditDigest {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        hex::decode(&encoded)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(AuditDigest)
+            .ok_or_else(|| serde::de::Error::custom("expected a 32 byte hex digest"))
+    }
+}
+
+/// One provider response in the audit trail
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Digest of the trace token the request was served under
+    pub trace: AuditDigest,
+    /// The provider that produced the response
+    pub provider_id: Uuid,
+    /// Digest of the response body
+    pub response: AuditDigest,
+    /// When the response was recorded
+    pub recorded_at: Timestamp,
+    /// Digest of the previous record in the trail
+    pub previous: AuditDigest,
+    /// Digest of this record, which the next record chains from
+    pub digest: AuditDigest,
+}
+
+/// The first line of a trail file
+#[derive(Serialize, Deserialize)]
+struct TrailHeader {
+    salt: AuditDigest,
+}
+
+/// A change to the trail file, made by the log's writer task
+enum TrailWrite {
+    /// Append a record
+    Append(AuditRecord),
+    /// Rewrite the file with only the records still kept
+    Compact(Vec<AuditRecord>),
+}
+
+/// An append-only, hash-chained log of provider response digests
+pub struct AuditLog {
+    salt: [u8; 32],
+    retention: Duration,
+    records: parking_lot::Mutex<VecDeque<AuditRecord>>,
+    /// Records dropped from memory since the file was last compacted
+    pruned: parking_lot::Mutex<usize>,
+    writer: Option<mpsc::UnboundedSender<TrailWrite>>,
+}
+
+impl AuditLog {
+    /// Create an empty log with a fresh random salt, kept in memory only
+    pub fn new(config: AuditConfig) -> Self {
+        Self {
+            salt: rand::random(),
+            retention: config.retention,
+            records: parking_lot::Mutex::new(VecDeque::new()),
+            pruned: parking_lot::Mutex::new(0),
+            writer: None,
+        }
+    }
+    
+    /// Open the log kept in the configured file, starting one with a fresh salt if there is
+    /// none, or a log in memory if no file is configured
+    ///
+    /// Records are written by a task of their own, so must be opened on a Tokio runtime.
+    pub fn open(config: AuditConfig, now: Timestamp) -> Result<Self> {
+        let Some(path) = config.path.clone() else {
+            return Ok(Self::new(config));
+        };
+        let mut log = Self::new(config);
+        match std::fs::File::open(&path) {
+            Ok(file) => {
+                let mut lines = std::io::BufReader::new(file).lines();
+                let header: TrailHeader = match lines.next() {
+                    Some(line) => serde_json::from_str(&line?)?,
+                    None => anyhow::bail!("Audit trail {} has no header", path.display()),
+                };
+                log.salt = header.salt.0;
+                let mut records = log.records.lock();
+                for line in lines {
+                    records.push_back(serde_json::from_str(&line?)?);
+                }
+                log.prune(&mut records, now);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => anyhow::bail!("Failed to read audit trail {}: {}", path.display(), e),
+        }
+        
+        // Start from a compacted file, which also writes the header of a new one
+        let records: Vec<AuditRecord> = log.records.lock().iter().cloned().collect();
+        rewrite(&path, log.salt, &records)?;
+        let (writer, writes) = mpsc::unbounded_channel();
+        tokio::spawn(write_trail(path, log.salt, writes));
+        log.writer = Some(writer);
+        Ok(log)
+    }
+    
+    /// Salted digest of the concatenated parts
+    fn digest(&self, parts: &[&[u8]]) -> AuditDigest {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt);
+        for part in parts {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part);
+        }
+        AuditDigest(hasher.finalize().into())
+    }
+    
+    /// Append the digest of a provider response served under `trace_token`
+    pub fn record(&self, trace_token: &str, provider_id: Uuid, response: &[u8], now: Timestamp) -> AuditRecord {
+        let trace = self.digest(&[trace_token.as_bytes()]);
+        let response = self.digest(&[&audited_body(response)]);
+        let recorded_secs = now.as_secs();
+        
+        let mut records = self.records.lock();
+        self.prune(&mut records, now);
+        let previous = records.back().map_or(AuditDigest::GENESIS, |record| record.digest);
+        let digest = self.digest(&[
+            &previous.0,
+            &trace.0,
+            provider_id.as_bytes(),
+            &response.0,
+            &recorded_secs.to_be_bytes(),
+        ]);
+        let record = AuditRecord {
+            trace,
+            provider_id,
+            response,
+            recorded_at: now,
+            previous,
+            digest,
+        };
+        records.push_back(record.clone());
+        if let Some(writer) = &self.writer {
+            let _ = writer.send(TrailWrite::Append(record.clone()));
+        }
+        record
+    }
+    
+    /// Records for a trace token, oldest first
+    pub fn lookup(&self, trace_token: &str, now: Timestamp) -> Vec<AuditRecord> {
+        let trace = self.digest(&[trace_token.as_bytes()]);
+        let mut records = self.records.lock();
+        self.prune(&mut records, now);
+        records.iter().filter(|record| record.trace == trace).cloned().collect()
+    }
+    
+    /// Whether `response`, as the user received it, is the body a record was made from
+    pub fn matches(&self, record: &AuditRecord, response: &[u8]) -> bool {
+        self.digest(&[&audited_body(response)]) == record.response
+    }
+    
+    /// Drop records older than the retention period, compacting the file once it holds more
+    /// dropped records than kept ones
+    fn prune(&self, records: &mut VecDeque<AuditRecord>, now: Timestamp) {
+        let mut dropped = 0;
+        while let Some(oldest) = records.front() {
+            let expired = now.saturating_duration_since(oldest.recorded_at) > self.retention;
+            if !expired {
+                break;
+            }
+            records.pop_front();
+            dropped += 1;
+        }
+        
+        let mut pruned = self.pruned.lock();
+        *pruned += dropped;
+        if let Some(writer) = &self.writer {
+            if *pruned > 0 && *pruned >= records.len() {
+                let _ = writer.send(TrailWrite::Compact(records.iter().cloned().collect()));
+                *pruned = 0;
+            }
+        }
+    }
+}
+
+/// Write `records` to the trail file at `path`, replacing it whole
+fn rewrite(path: &Path, salt: [u8; 32], records: &[AuditRecord]) -> Result<()> {
+    let partial = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = std::io::BufWriter::new(options.open(&partial)?);
+    serde_json::to_writer(&mut file, &TrailHeader { salt: AuditDigest(salt) })?;
+    writeln!(file)?;
+    for record in records {
+        serde_json::to_writer(&mut file, record)?;
+        writeln!(file)?;
+    }
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// Apply the log's writes to the trail file, in order, until the log is dropped
+///
+/// File access is blocking, so each write runs on the blocking pool rather than in the
+/// callers recording responses.
+async fn write_trail(path: PathBuf, salt: [u8; 32], mut writes: mpsc::UnboundedReceiver<TrailWrite>) {
+    while let Some(write) = writes.recv().await {
+        let file = path.clone();
+        let written = tokio::task::spawn_blocking(move || match write {
+            TrailWrite::Append(record) => {
+                let mut line = serde_json::to_vec(&record)?;
+                line.push(b'\n');
+                let mut file = std::fs::OpenOptions::new().append(true).open(&file)?;
+                file.write_all(&line)?;
+                Ok(())
+            }
+            TrailWrite::Compact(records) => rewrite(&file, salt, &records),
+        })
+        .await;
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!("Failed to write audit trail {}: {}", path.display(), e),
+            Err(e) => tracing::error!("Audit trail writer failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+    
+    fn config(path: Option<PathBuf>) -> AuditConfig {
+        AuditConfig { retention: DAY, path }
+    }
+    
+    #[tokio::test]
+    async fn matches_the_response_the_user_received() {
+        let log = AuditLog::new(config(None));
+        let now = Timestamp::now();
+        let provider = br#"{"jsonrpc":"2.0","id":41,"result":{"value":1,"context":{"slot":9}}}"#;
+        log.record("trace", Uuid::new_v4(), provider, now);
+        
+        // The entry node restored the client's id, and nodes added their extension
+        let received = br#"{"id":"client-7","jsonrpc":"2.0","result":{"context":{"slot":9},"value":1},"darknode":{"trace_token":"trace"}}"#;
+        let trail = log.lookup("trace", now);
+        assert_eq!(trail.len(), 1);
+        assert!(log.matches(&trail[0], received));
+        let altered = br#"{"id":"client-7","jsonrpc":"2.0","result":{"context":{"slot":9},"value":2}}"#;
+        assert!(!log.matches(&trail[0], altered));
+        assert!(log.lookup("other", now).is_empty());
+    }
+    
+    #[tokio::test]
+    async fn keeps_the_trail_and_salt_across_restarts_until_retention() {
+        let path = std::env::temp_dir().join(format!("darknode-audit-{}.jsonl", Uuid::new_v4()));
+        let now = Timestamp::now();
+        let response = br#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#;
+        {
+            let log = AuditLog::open(config(Some(path.clone())), now).unwrap();
+            log.record("trace", Uuid::new_v4(), response, now);
+            // Let the writer task catch up
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        
+        let reopened = AuditLog::open(config(Some(path.clone())), now).unwrap();
+        let trail = reopened.lookup("trace", now);
+        assert_eq!(trail.len(), 1);
+        assert!(reopened.matches(&trail[0], response));
+        
+        // Past retention the record is gone, in memory and in the file
+        assert!(reopened.lookup("trace", now + DAY * 2).is_empty());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let expired = AuditLog::open(config(Some(path.clone())), now).unwrap();
+        assert!(expired.lookup("trace", now).is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
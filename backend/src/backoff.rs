@@ -0,0 +1,38 @@
+//! Exponential backoff with jitter, for retrying calls to a peer that is down
+
+use super::*;
+
+/// Delays between attempts, doubling from `initial` up to `max`
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    /// Fraction of each delay randomly added or removed so retrying nodes don't fire in lockstep
+    const JITTER: f64 = 0.2;
+    
+    /// A backoff starting at `initial`
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+    
+    /// The delay before the next attempt, doubling the one after it
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        let factor = 1.0 + Self::JITTER * (rand::random::<f64>() * 2.0 - 1.0);
+        delay.mul_f64(factor)
+    }
+    
+    /// Start again from the initial delay, after an attempt succeeded
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
@@ -0,0 +1,299 @@
+//! Egress bandwidth caps and fair scheduling between circuits on routing nodes
+//!
+//! Operators on metered hosts need a hard ceiling on what their node sends, and one busy
+//! circuit must not starve the others sharing the node. Every message a routing node
+//! forwards first waits its turn in a per-circuit queue. Queues are served by deficit round
+//! robin, so each circuit with traffic waiting gets an equal share of bytes, and releases
+//! are paced by a token bucket refilled at the configured rate. Utilization of the cap is
+//! published as the node's load, so the coordinator steers new circuits elsewhere.
+//!
+//! Without a cap messages are released at once; there is no contention to arbitrate.
+
+use super::*;
+use super::heartbeat::ActivityCounters;
+use super::types::CircuitId;
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+/// How much a node sends and how it is shared between circuits
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BandwidthConfig {
+    /// Most bytes per second the node forwards, if capped
+    #[serde(default)]
+    pub egress_limit: Option<u64>,
+    /// How long the node may send at full rate after being idle; keep it short so the cap holds
+    pub burst: Duration,
+    /// Bytes a circuit may send per round before the next circuit's turn
+    pub quantum: usize,
+    /// How often throughput and utilization are published
+    pub sample_interval: Duration,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        Self {
+            egress_limit: None,
+            burst: Duration::from_millis(20),
+            quantum: 16 * 1024,
+            sample_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Token bucket pacing releases at the egress limit
+///
+/// The balance may go negative when a message is larger than what is available, so the
+/// debt delays whatever follows and the average rate still holds.
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: Duration, now: Instant) -> Self {
+        let capacity = rate as f64 * burst.as_secs_f64();
+        Self {
+            rate: rate as f64,
+            capacity,
+            tokens: capacity,
+            refilled_at: now,
+        }
+    }
+    
+    /// How long until the balance is no longer in debt
+    fn delay(&mut self, now: Instant) -> Duration {
+        self.tokens = (self.tokens + self.rate * (now - self.refilled_at).as_secs_f64()).min(self.capacity);
+        self.refilled_at = now;
+        match self.tokens >= 0.0 {
+            true => Duration::ZERO,
+            false => Duration::from_secs_f64(-self.tokens / self.rate),
+        }
+    }
+    
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// A message waiting to be released
+struct Pending {
+    bytes: usize,
+    release: oneshot::Sender<()>,
+}
+
+/// A circuit's waiting messages and the bytes it may still send this round
+#[derive(Default)]
+struct CircuitQueue {
+    pending: VecDeque<Pending>,
+    deficit: usize,
+}
+
+#[derive(Default)]
+struct Queues {
+    circuits: HashMap<CircuitId, CircuitQueue>,
+    /// Circuits with messages waiting, in round robin order
+    active: VecDeque<CircuitId>,
+    /// Whether the circuit at the front of `active` has already been given its quantum this round
+    turn_started: bool,
+    /// Bytes released per circuit since the last sample
+    sent: HashMap<CircuitId, u64>,
+}
+
+impl Queues {
+    /// The next message to release, by deficit round robin
+    fn next(&mut self, quantum: usize) -> Option<(CircuitId, Pending)> {
+        loop {
+            let circuit = self.active.front()?.clone();
+            let queue = self.circuits.get_mut(&circuit).expect("active circuits have queues");
+            let Some(head) = queue.pending.front() else {
+                self.circuits.remove(&circuit);
+                self.active.pop_front();
+                self.turn_started = false;
+                continue;
+            };
+            if !self.turn_started {
+                queue.deficit += quantum;
+                self.turn_started = true;
+            }
+            if queue.deficit < head.bytes {
+                self.active.rotate_left(1);
+                self.turn_started = false;
+                continue;
+            }
+            let pending = queue.pending.pop_front().expect("head is present");
+            queue.deficit -= pending.bytes;
+            return Some((circuit, pending));
+        }
+    }
+}
+
+/// Paces and shares a node's egress between its circuits
+pub struct EgressScheduler {
+    config: BandwidthConfig,
+    queues: parking_lot::Mutex<Queues>,
+    wake: tokio::sync::Notify,
+}
+
+impl EgressScheduler {
+    /// Create a scheduler with nothing queued
+    pub fn new(config: BandwidthConfig) -> Self {
+        Self {
+            config,
+            queues: parking_lot::Mutex::new(Queues::default()),
+            wake: tokio::sync::Notify::new(),
+        }
+    }
+    
+    /// Wait until `bytes` may be sent for `circuit`
+    ///
+    /// Returns at once when egress isn't capped; otherwise [`Self::run`] must be running.
+    pub async fn admit(&self, circuit: &CircuitId, bytes: usize) {
+        if self.config.egress_limit.is_none() {
+            *self.queues.lock().sent.entry(circuit.clone()).or_insert(0) += bytes as u64;
+            return;
+        }
+        let (release, released) = oneshot::channel();
+        {
+            let mut queues = self.queues.lock();
+            let pending = Pending { bytes, release };
+            match queues.circuits.entry(circuit.clone()) {
+                std::collections::hash_map::Entry::Occupied(mut queue) => queue.get_mut().pending.push_back(pending),
+                std::collections::hash_map::Entry::Vacant(queue) => {
+                    queue.insert(CircuitQueue::default()).pending.push_back(pending);
+                    queues.active.push_back(circuit.clone());
+                }
+            }
+        }
+        self.wake.notify_one();
+        let _ = released.await;
+    }
+    
+    /// Release queued messages at the egress limit and publish throughput, until the task is dropped
+    ///
+    /// Utilization of the limit is reported to `counters` as the node's load.
+    pub async fn run(self: Arc<Self>, counters: Arc<ActivityCounters>) {
+        let mut next_sample = Instant::now() + self.config.sample_interval;
+        let mut bucket = self
+            .config
+            .egress_limit
+            .map(|rate| TokenBucket::new(rate, self.config.burst, Instant::now()));
+        
+        loop {
+            if Instant::now() >= next_sample {
+                self.sample(&counters);
+                next_sample += self.config.sample_interval;
+            }
+            
+            let next = match &bucket {
+                Some(_) => self.queues.lock().next(self.config.quantum),
+                None => None,
+            };
+            let Some((circuit, pending)) = next else {
+                tokio::select! {
+                    _ = self.wake.notified() => {}
+                    _ = tokio::time::sleep_until(next_sample) => {}
+                }
+                continue;
+            };
+            
+            // A sender that gave up waiting costs nothing
+            if pending.release.is_closed() {
+                continue;
+            }
+            if let Some(bucket) = bucket.as_mut() {
+                let delay = bucket.delay(Instant::now());
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                    bucket.delay(Instant::now());
+                }
+                bucket.consume(pending.bytes);
+            }
+            *self.queues.lock().sent.entry(circuit).or_insert(0) += pending.bytes as u64;
+            let _ = pending.release.send(());
+        }
+    }
+    
+    /// Publish per-circuit throughput and utilization since the last sample
+    fn sample(&self, counters: &ActivityCounters) {
+        let sent = std::mem::take(&mut self.queues.lock().sent);
+        let window = self.config.sample_interval.as_secs_f64();
+        let total: u64 = sent.values().sum();
+        for bytes in sent.values() {
+            metrics::histogram!("darknode_circuit_throughput_bytes_per_second", *bytes as f64 / window);
+        }
+        metrics::counter!("darknode_egress_bytes_total", total);
+        
+        let utilization = match self.config.egress_limit {
+            Some(limit) => (total as f64 / window / limit as f64).min(1.0),
+            None => 0.0,
+        };
+        metrics::gauge!("darknode_egress_utilization", utilization);
+        counters.set_load(utilization);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    
+    const LIMIT: u64 = 100_000;
+    
+    /// Keep `senders` messages of `bytes` waiting for `circuit` at all times, counting those released
+    fn flood(scheduler: &Arc<EgressScheduler>, circuit: &CircuitId, senders: usize, bytes: usize) -> Arc<AtomicU64> {
+        let released = Arc::new(AtomicU64::new(0));
+        for _ in 0..senders {
+            let (scheduler, circuit, released) = (scheduler.clone(), circuit.clone(), released.clone());
+            tokio::spawn(async move {
+                loop {
+                    scheduler.admit(&circuit, bytes).await;
+                    released.fetch_add(bytes as u64, Ordering::Relaxed);
+                }
+            });
+        }
+        released
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn circuits_share_the_cap_equally_however_hard_they_push() {
+        let scheduler = Arc::new(EgressScheduler::new(BandwidthConfig {
+            egress_limit: Some(LIMIT),
+            quantum: 1024,
+            ..Default::default()
+        }));
+        tokio::spawn(scheduler.clone().run(Arc::new(ActivityCounters::new())));
+        let heavy = flood(&scheduler, &CircuitId(Uuid::new_v4()), 8, 1000);
+        let light = flood(&scheduler, &CircuitId(Uuid::new_v4()), 1, 1000);
+        
+        let seconds = 10;
+        tokio::time::sleep(Duration::from_secs(seconds)).await;
+        let (heavy, light) = (heavy.load(Ordering::Relaxed) as f64, light.load(Ordering::Relaxed) as f64);
+        
+        // Eight senders on one circuit get no more through than one on the other
+        assert!((heavy - light).abs() / light < 0.05, "heavy {} light {}", heavy, light);
+        
+        // And the two together stay within the cap
+        let cap = (LIMIT * seconds) as f64;
+        assert!(((heavy + light) - cap).abs() / cap < 0.05, "sent {} of {}", heavy + light, cap);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_lone_circuit_is_held_to_the_cap_in_large_messages_too() {
+        let scheduler = Arc::new(EgressScheduler::new(BandwidthConfig {
+            egress_limit: Some(LIMIT),
+            ..Default::default()
+        }));
+        tokio::spawn(scheduler.clone().run(Arc::new(ActivityCounters::new())));
+        
+        // Messages larger than the bucket holds go into debt, which the next ones wait out
+        let released = flood(&scheduler, &CircuitId(Uuid::new_v4()), 2, 40_000);
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        let sent = released.load(Ordering::Relaxed) as f64;
+        let cap = (LIMIT * 20) as f64;
+        assert!((sent - cap).abs() / cap < 0.05, "sent {} of {}", sent, cap);
+    }
+}
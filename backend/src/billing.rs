@@ -0,0 +1,805 @@
+//! Usage-based invoicing, settled with Solana transfers
+//!
+//! Operators billing subscribers need invoices they can process mechanically. With billing
+//! enabled, entry nodes meter each user's requests, by [`MethodClass`], and bytes into a
+//! [`DailyUsage`] per UTC day, flushed to the shared storage backend every
+//! [`BillingConfig::flush_interval`]. Usage metered since the last flush is lost if the
+//! node stops abruptly.
+//!
+//! The coordinator closes billing periods of whole UTC days: each user on a plan with
+//! [`PlanPricing`] who owes something for the period gets an [`Invoice`] recording their
+//! usage, the requests their plan includes, and the overage beyond them. Invoices are never
+//! changed afterwards except to mark them paid. Periods can't overlap, and closing the last
+//! period again only issues the invoices missing from it, so a close that failed part way
+//! can be retried.
+//!
+//! An invoice is paid with a Solana transfer of exactly its amount, in lamports, from the
+//! invoiced user's wallet to [`BillingConfig::treasury`]. The payer hands in the transaction's signature, and the
+//! coordinator looks the transaction up at [`BillingConfig::rpc_url`], reading the answer
+//! under the same [`UpstreamLimits`] exit nodes hold providers to, before marking the
+//! invoice paid. A transaction pays one invoice only. Metered usage lives in the storage
+//! backend, so billing is refused on memory storage, where the coordinator would find none.
+//!
+//! Closing periods, listing invoices, and recording payments are for operators only.
+
+use super::*;
+use super::schema::base58_decode;
+use super::storage::{Collection, Precondition, Storage, VersionConflict};
+use super::timeouts::MethodClass;
+use super::traits::UserManager;
+use super::types::{Plan, User};
+use super::wallets::WalletChain;
+use super::upstream::{self, UpstreamLimits};
+use std::collections::{BTreeMap, HashMap};
+
+/// Collection of metered usage, keyed by user and UTC day
+pub(crate) const DAILY_USAGE: &str = "daily_usage";
+
+/// Collection of invoices, keyed by user and the start of their period
+const INVOICES: &str = "invoices";
+
+/// Collection of the keys of invoices, keyed by invoice ID
+const INVOICES_BY_ID: &str = "invoices_by_id";
+
+/// Collection of the invoice each transaction paid, keyed by transaction signature
+const INVOICE_PAYMENTS: &str = "invoice_payments";
+
+/// Collection holding the last billing period closed
+const BILLING_PERIODS: &str = "billing_periods";
+
+/// Key of the last billing period closed
+const LAST_PERIOD: &str = "last";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Whether usage is metered and invoiced, and how invoices are paid
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BillingConfig {
+    /// Whether entry nodes meter usage and the coordinator invoices it
+    pub enabled: bool,
+    /// How often entry nodes write metered usage to storage
+    pub flush_interval: Duration,
+    /// Solana RPC the coordinator looks payments up at
+    pub rpc_url: Option<String>,
+    /// Solana account invoices are paid to; payments are refused without one
+    pub treasury: Option<String>,
+    /// Longest to wait for the RPC to answer a lookup
+    pub payment_timeout: Duration,
+}
+
+impl Default for BillingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            flush_interval: Duration::from_secs(60),
+            rpc_url: None,
+            treasury: None,
+            payment_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What a plan costs per billing period, in lamports
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlanPricing {
+    /// Charged every period
+    pub base_price: u64,
+    /// Requests each period includes
+    pub included_requests: u64,
+    /// Charged for every thousand requests beyond those included, rounded up
+    pub overage_price_per_thousand: u64,
+}
+
+/// A user's usage on one UTC day, as metered by entry nodes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DailyUsage {
+    /// The user
+    pub user_id: Uuid,
+    /// Days since the Unix epoch
+    pub day: u64,
+    /// The user's plan when they last made a request that day
+    #[serde(default)]
+    pub plan_id: Option<Uuid>,
+    /// Requests made, by method class
+    pub requests: BTreeMap<MethodClass, u64>,
+    /// Bytes of the requests as sent to entry nodes
+    pub request_bytes: u64,
+    /// Bytes of the responses as returned to the user
+    pub response_bytes: u64,
+    /// Requests served straight from a fallback provider, without a circuit, see
+    /// [`crate::fallback`]
+    #[serde(default)]
+    pub degraded_requests: u64,
+}
+
+impl DailyUsage {
+    /// Add `other`, usage of the same user on the same day, to this
+    fn merge(&mut self, other: &DailyUsage) {
+        if other.plan_id.is_some() {
+            self.plan_id = other.plan_id;
+        }
+        for (class, requests) in &other.requests {
+            *self.requests.entry(*class).or_default() += requests;
+        }
+        self.request_bytes += other.request_bytes;
+        self.response_bytes += other.response_bytes;
+        self.degraded_requests += other.degraded_requests;
+    }
+}
+
+/// Meters users' usage on an entry node, writing it to storage from time to time
+pub struct UsageMeter {
+    usage: Collection<DailyUsage>,
+    pending: parking_lot::Mutex<HashMap<(Uuid, u64), DailyUsage>>,
+}
+
+impl UsageMeter {
+    /// Meter into `storage`
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self {
+            usage: Collection::new(storage, DAILY_USAGE),
+            pending: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+    
+    /// Apply `record` to the usage `user_id` has pending for `day`
+    fn with_pending(&self, user_id: Uuid, day: u64, record: impl FnOnce(&mut DailyUsage)) {
+        let mut pending = self.pending.lock();
+        let usage = pending.entry((user_id, day)).or_insert_with(|| DailyUsage {
+            user_id,
+            day,
+            ..Default::default()
+        });
+        record(usage);
+    }
+    
+    /// Count a request of `class`, `bytes` long, that `user` made at `now`
+    pub fn record_request(&self, user: &User, class: MethodClass, bytes: usize, now: Timestamp) {
+        self.with_pending(user.id, now.as_secs() / SECONDS_PER_DAY, |usage| {
+            usage.plan_id = user.plan_id;
+            *usage.requests.entry(class).or_default() += 1;
+            usage.request_bytes += bytes as u64;
+        });
+    }
+    
+    /// Count `bytes` of response returned to `user_id` at `now`
+    pub fn record_response(&self, user_id: Uuid, bytes: usize, now: Timestamp) {
+        self.with_pending(user_id, now.as_secs() / SECONDS_PER_DAY, |usage| {
+            usage.response_bytes += bytes as u64
+        });
+    }
+    
+    /// Count a request of `user_id`'s served at `now` without a circuit
+    pub fn record_degraded(&self, user_id: Uuid, now: Timestamp) {
+        self.with_pending(user_id, now.as_secs() / SECONDS_PER_DAY, |usage| usage.degraded_requests += 1);
+    }
+    
+    /// Write the usage metered since the last flush to storage
+    ///
+    /// Usage that fails to be written is kept for the next flush.
+    pub async fn flush(&self) -> Result<()> {
+        let pending = std::mem::take(&mut *self.pending.lock());
+        let mut failure = None;
+        for ((user_id, day), usage) in pending {
+            let key = format!("{}/{:010}", user_id, day);
+            let stored = self
+                .usage
+                .update(&key, |stored| {
+                    let mut merged = stored.unwrap_or_else(|| DailyUsage {
+                        user_id,
+                        day,
+                        ..Default::default()
+                    });
+                    merged.merge(&usage);
+                    Ok(Some(merged))
+                })
+                .await;
+            if let Err(e) = stored {
+                self.with_pending(user_id, day, |pending| pending.merge(&usage));
+                failure = Some(e);
+            }
+        }
+        failure.map_or(Ok(()), Err)
+    }
+    
+    /// Flush every `interval` until the task is dropped
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.flush().await {
+                tracing::warn!("Failed to write metered usage: {}", e);
+            }
+        }
+    }
+}
+
+/// Whether an invoice has been paid
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvoiceStatus {
+    /// Awaiting payment
+    Open,
+    /// Paid with a verified transaction
+    Paid,
+}
+
+/// The transaction that paid an invoice
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Payment {
+    /// Signature of the Solana transaction
+    pub signature: String,
+    /// When the transaction was verified
+    pub verified_at: Timestamp,
+}
+
+/// What a user owes for one billing period
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invoice {
+    /// Unique identifier of the invoice
+    pub id: Uuid,
+    /// The user billed
+    pub user_id: Uuid,
+    /// Name of the plan billed for
+    pub plan: String,
+    /// Start of the period, a UTC midnight
+    pub period_start: Timestamp,
+    /// End of the period, the UTC midnight after its last day
+    pub period_end: Timestamp,
+    /// Requests made in the period, by method class
+    pub requests: BTreeMap<MethodClass, u64>,
+    /// Requests made in the period
+    pub total_requests: u64,
+    /// Bytes of the requests as sent to entry nodes
+    pub request_bytes: u64,
+    /// Bytes of the responses as returned to the user
+    pub response_bytes: u64,
+    /// Requests the plan includes
+    pub included_requests: u64,
+    /// Requests beyond those included
+    pub overage_requests: u64,
+    /// The plan's base price, in lamports
+    pub base_amount: u64,
+    /// Charged for the overage, in lamports
+    pub overage_amount: u64,
+    /// Owed in total, in lamports
+    pub amount: u64,
+    /// When the invoice was issued
+    pub issued_at: Timestamp,
+    /// Whether the invoice has been paid
+    pub status: InvoiceStatus,
+    /// The transaction that paid the invoice, once one has
+    pub payment: Option<Payment>,
+}
+
+impl Invoice {
+    /// The invoice for `usage`, one user's usage over the period, on `plan` priced at `pricing`
+    pub fn issue(
+        user_id: Uuid,
+        plan: &Plan,
+        pricing: &PlanPricing,
+        period: (Timestamp, Timestamp),
+        usage: &[DailyUsage],
+        now: Timestamp,
+    ) -> Self {
+        let mut total = DailyUsage::default();
+        for day in usage {
+            total.merge(day);
+        }
+        let total_requests: u64 = total.requests.values().sum();
+        let overage_requests = total_requests.saturating_sub(pricing.included_requests);
+        let overage_amount = (overage_requests as u128 * pricing.overage_price_per_thousand as u128).div_ceil(1000);
+        let overage_amount = u64::try_from(overage_amount).unwrap_or(u64::MAX);
+        Self {
+            id: Uuid::new_v4(),
+            user_id,
+            plan: plan.name.clone(),
+            period_start: period.0,
+            period_end: period.1,
+            requests: total.requests,
+            total_requests,
+            request_bytes: total.request_bytes,
+            response_bytes: total.response_bytes,
+            included_requests: pricing.included_requests,
+            overage_requests,
+            base_amount: pricing.base_price,
+            overage_amount,
+            amount: pricing.base_price.saturating_add(overage_amount),
+            issued_at: now,
+            status: InvoiceStatus::Open,
+            payment: None,
+        }
+    }
+}
+
+/// The last billing period closed, in days since the Unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct ClosedPeriod {
+    start_day: u64,
+    end_day: u64,
+}
+
+/// A billing period that can't be closed
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PeriodRefused {
+    /// Billing isn't enabled
+    #[error("billing is not enabled on this network")]
+    Disabled,
+    /// The period doesn't start and end on UTC midnights, or ends before it starts
+    #[error("a billing period must span whole UTC days")]
+    NotWholeDays,
+    /// The period isn't over yet
+    #[error("the billing period has not ended yet")]
+    NotOver,
+    /// The period overlaps one already closed
+    #[error("the billing period overlaps one already closed, which ended at {closed_until}")]
+    Overlaps {
+        /// End of the last period closed
+        closed_until: Timestamp,
+    },
+}
+
+/// A payment that doesn't settle its invoice
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PaymentRejected {
+    /// Payments aren't accepted, for want of a treasury or RPC
+    #[error("payments are not accepted on this network")]
+    Disabled,
+    /// The invoice is already paid
+    #[error("the invoice is already paid")]
+    AlreadyPaid,
+    /// The signature isn't a transaction signature
+    #[error("payment must be a base58 Solana transaction signature")]
+    Malformed,
+    /// The transaction isn't on chain, or not finalized yet
+    #[error("transaction not found; it may not be finalized yet")]
+    NotFound,
+    /// The transaction failed on chain
+    #[error("transaction failed on chain")]
+    Failed,
+    /// The transaction transferred nothing to the treasury
+    #[error("transaction does not transfer to the treasury")]
+    WrongDestination,
+    /// The transaction transferred to the treasury, but not from the invoiced user's wallet
+    #[error("transaction is not paid from the invoiced user's wallet")]
+    WrongSource,
+    /// The transaction transferred a different amount to the treasury
+    #[error("transaction transfers {paid} lamports to the treasury, but {expected} are owed")]
+    WrongAmount {
+        /// Owed, in lamports
+        expected: u64,
+        /// Transferred, in lamports
+        paid: u64,
+    },
+    /// The transaction already paid another invoice
+    #[error("transaction already paid another invoice")]
+    AlreadyUsed,
+    /// The RPC couldn't be asked about the transaction
+    #[error("could not look the transaction up: {0}")]
+    Unavailable(String),
+}
+
+/// Checks payments on chain
+#[async_trait]
+pub trait PaymentVerifier {
+    /// Check that the transaction `signature` transferred exactly `lamports` from `source` to `destination`
+    async fn verify(&self, signature: &str, source: &str, destination: &str, lamports: u64) -> Result<(), PaymentRejected>;
+}
+
+/// Looks payments up with `getTransaction` at a Solana RPC
+pub struct SolanaPaymentVerifier {
+    url: String,
+    client: reqwest::Client,
+    limits: UpstreamLimits,
+    timeout: Duration,
+}
+
+impl SolanaPaymentVerifier {
+    /// Look payments up at `url`, waiting up to `timeout` for each
+    pub fn new(url: String, timeout: Duration) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+            limits: UpstreamLimits::default(),
+            timeout,
+        }
+    }
+}
+
+/// Lamports the system-program transfers among `instructions` send to `destination`, by source account
+fn transferred_to<'a>(instructions: &[&'a serde_json::Value], destination: &str) -> HashMap<&'a str, u64> {
+    let mut totals: HashMap<&str, u64> = HashMap::new();
+    for instruction in instructions {
+        if instruction.get("program").and_then(|program| program.as_str()) != Some("system") {
+            continue;
+        }
+        let parsed = &instruction["parsed"];
+        let transfer = matches!(parsed["type"].as_str(), Some("transfer" | "transferWithSeed"));
+        if transfer && parsed["info"]["destination"].as_str() == Some(destination) {
+            let source = parsed["info"]["source"].as_str().unwrap_or_default();
+            let lamports = parsed["info"]["lamports"].as_u64().unwrap_or(0);
+            let total = totals.entry(source).or_insert(0);
+            *total = total.saturating_add(lamports);
+        }
+    }
+    totals
+}
+
+#[async_trait]
+impl PaymentVerifier for SolanaPaymentVerifier {
+    async fn verify(&self, signature: &str, source: &str, destination: &str, lamports: u64) -> Result<(), PaymentRejected> {
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "getTransaction",
+            "params": [
+                signature,
+                { "encoding": "jsonParsed", "commitment": "finalized", "maxSupportedTransactionVersion": 0 },
+            ],
+        });
+        let unavailable = |e: anyhow::Error| PaymentRejected::Unavailable(e.to_string());
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&request)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| unavailable(e.without_url().into()))?;
+        let body = upstream::read_body(response, &self.limits).await.map_err(unavailable)?;
+        let body: serde_json::Value = serde_json::from_slice(&body).map_err(|e| unavailable(e.into()))?;
+        if let Some(error) = body.get("error") {
+            return Err(PaymentRejected::Unavailable(error.to_string()));
+        }
+        
+        let transaction = &body["result"];
+        if transaction.is_null() {
+            return Err(PaymentRejected::NotFound);
+        }
+        if !transaction["meta"]["err"].is_null() {
+            return Err(PaymentRejected::Failed);
+        }
+        let mut instructions: Vec<&serde_json::Value> = transaction["transaction"]["message"]["instructions"]
+            .as_array()
+            .map(|instructions| instructions.iter().collect())
+            .unwrap_or_default();
+        for inner in transaction["meta"]["innerInstructions"].as_array().into_iter().flatten() {
+            instructions.extend(inner["instructions"].as_array().into_iter().flatten());
+        }
+        let transferred = transferred_to(&instructions, destination);
+        if transferred.is_empty() {
+            return Err(PaymentRejected::WrongDestination);
+        }
+        match transferred.get(source) {
+            None => Err(PaymentRejected::WrongSource),
+            Some(&paid) if paid != lamports => Err(PaymentRejected::WrongAmount { expected: lamports, paid }),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/// Closes billing periods into invoices and settles them, on the coordinator
+pub struct Billing {
+    config: BillingConfig,
+    user_manager: Arc<dyn UserManager + Send + Sync>,
+    verifier: Option<Arc<dyn PaymentVerifier + Send + Sync>>,
+    usage: Collection<DailyUsage>,
+    invoices: Collection<Invoice>,
+    invoices_by_id: Collection<String>,
+    payments: Collection<Uuid>,
+    periods: Collection<ClosedPeriod>,
+}
+
+impl Billing {
+    /// Invoice the usage metered into `storage` for the plans `user_manager` knows
+    ///
+    /// Payments are looked up at the configured RPC, if there is one.
+    pub fn new(
+        config: BillingConfig,
+        storage: Arc<dyn Storage + Send + Sync>,
+        user_manager: Arc<dyn UserManager + Send + Sync>,
+    ) -> Self {
+        let verifier = config.rpc_url.clone().map(|url| {
+            Arc::new(SolanaPaymentVerifier::new(url, config.payment_timeout)) as Arc<dyn PaymentVerifier + Send + Sync>
+        });
+        Self {
+            config,
+            user_manager,
+            verifier,
+            usage: Collection::new(storage.clone(), DAILY_USAGE),
+            invoices: Collection::new(storage.clone(), INVOICES),
+            invoices_by_id: Collection::new(storage.clone(), INVOICES_BY_ID),
+            payments: Collection::new(storage.clone(), INVOICE_PAYMENTS),
+            periods: Collection::new(storage, BILLING_PERIODS),
+        }
+    }
+    
+    /// Look payments up with `verifier` rather than at the configured RPC
+    pub fn with_verifier(mut self, verifier: Arc<dyn PaymentVerifier + Send + Sync>) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+    
+    /// Close the period from `start` to `end`, issuing the invoices of everyone who owes something for it
+    ///
+    /// Returns the invoices issued, which leaves out those issued by an earlier close of
+    /// the same period.
+    pub async fn close_period(&self, start: Timestamp, end: Timestamp, now: Timestamp) -> Result<Vec<Invoice>> {
+        if !self.config.enabled {
+            return Err(PeriodRefused::Disabled.into());
+        }
+        let whole_days = start.as_millis() % (SECONDS_PER_DAY * 1000) == 0 && end.as_millis() % (SECONDS_PER_DAY * 1000) == 0;
+        if !whole_days || end <= start {
+            return Err(PeriodRefused::NotWholeDays.into());
+        }
+        if end > now {
+            return Err(PeriodRefused::NotOver.into());
+        }
+        let period = ClosedPeriod {
+            start_day: start.as_secs() / SECONDS_PER_DAY,
+            end_day: end.as_secs() / SECONDS_PER_DAY,
+        };
+        
+        // Claim the period, unless it overlaps the last one; closing the last one again is a retry
+        self.periods
+            .update(LAST_PERIOD, |last| match last {
+                Some(last) if last == period => Ok(None),
+                Some(last) if period.start_day < last.end_day => Err(PeriodRefused::Overlaps {
+                    closed_until: Timestamp::from_secs(last.end_day * SECONDS_PER_DAY),
+                }
+                .into()),
+                _ => Ok(Some(period)),
+            })
+            .await?;
+        
+        // Gather each user's usage over the period
+        let mut by_user: BTreeMap<Uuid, Vec<DailyUsage>> = BTreeMap::new();
+        for usage in self.usage.all().await? {
+            if (period.start_day..period.end_day).contains(&usage.day) {
+                by_user.entry(usage.user_id).or_default().push(usage);
+            }
+        }
+        
+        let mut issued = Vec::new();
+        for (user_id, usage) in by_user {
+            // Bill the plan the user was on last in the period
+            let plan_id = usage.iter().rev().find_map(|day| day.plan_id);
+            let plan = match plan_id {
+                Some(plan_id) => self.user_manager.get_plan(plan_id).await?.unwrap_or_default(),
+                None => Plan::default(),
+            };
+            let Some(pricing) = &plan.pricing else { continue };
+            let invoice = Invoice::issue(user_id, &plan, pricing, (start, end), &usage, now);
+            if invoice.amount == 0 {
+                continue;
+            }
+            
+            let key = format!("{}/{:020}", user_id, start.as_millis());
+            match self.invoices.put(&key, &invoice, Precondition::Absent).await {
+                Ok(_) => {}
+                Err(e) if e.is::<VersionConflict>() => continue,
+                Err(e) => return Err(e),
+            }
+            self.invoices_by_id.put(&invoice.id.to_string(), &key, Precondition::Absent).await?;
+            metrics::increment_counter!("darknode_invoices_issued_total");
+            issued.push(invoice);
+        }
+        Ok(issued)
+    }
+    
+    /// The invoices issued to `user_id`, oldest period first
+    pub async fn invoices_for(&self, user_id: Uuid) -> Result<Vec<Invoice>> {
+        let invoices = self.invoices.scan(&format!("{}/", user_id)).await?;
+        Ok(invoices.into_iter().map(|(_, invoice)| invoice).collect())
+    }
+    
+    /// Mark the invoice `invoice_id` paid by the transaction `signature`, once it is verified on chain
+    ///
+    /// Returns `None` if there is no such invoice.
+    pub async fn pay(&self, invoice_id: Uuid, signature: &str, now: Timestamp) -> Result<Option<Invoice>> {
+        let (Some(treasury), Some(verifier)) = (&self.config.treasury, &self.verifier) else {
+            return Err(PaymentRejected::Disabled.into());
+        };
+        let Some((key, _)) = self.invoices_by_id.get(&invoice_id.to_string()).await? else {
+            return Ok(None);
+        };
+        let Some((invoice, _)) = self.invoices.get(&key).await? else {
+            return Ok(None);
+        };
+        if invoice.status == InvoiceStatus::Paid {
+            return Err(PaymentRejected::AlreadyPaid.into());
+        }
+        if base58_decode(signature).map_or(true, |bytes| bytes.len() != 64) {
+            return Err(PaymentRejected::Malformed.into());
+        }
+        
+        // Only a transfer from the invoiced user's own wallet pays their invoice, so no one
+        // can claim another's payment
+        let payer = self.user_manager.get_user(invoice.user_id).await?;
+        let Some(payer) = payer.filter(|user| user.wallet_chain == WalletChain::Solana) else {
+            return Err(PaymentRejected::WrongSource.into());
+        };
+        verifier.verify(signature, &payer.wallet_address, treasury, invoice.amount).await?;
+        
+        // A transaction pays one invoice, however often it is handed in
+        match self.payments.put(signature, &invoice_id, Precondition::Absent).await {
+            Ok(_) => {}
+            Err(e) if e.is::<VersionConflict>() => {
+                let paid = self.payments.get(signature).await?.map(|(paid, _)| paid);
+                if paid != Some(invoice_id) {
+                    return Err(PaymentRejected::AlreadyUsed.into());
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        let paid = self
+            .invoices
+            .update(&key, |invoice| {
+                let Some(mut invoice) = invoice else { return Ok(None) };
+                invoice.status = InvoiceStatus::Paid;
+                invoice.payment = Some(Payment {
+                    signature: signature.to_string(),
+                    verified_at: now,
+                });
+                Ok(Some(invoice))
+            })
+            .await?;
+        metrics::increment_counter!("darknode_invoices_paid_total");
+        Ok(paid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::StoredUserManager;
+    use crate::storage::MemoryStorage;
+    
+    /// Finds every transaction a transfer of what is owed, from `0` alone
+    struct PaidFrom(String);
+    
+    #[async_trait]
+    impl PaymentVerifier for PaidFrom {
+        async fn verify(&self, _signature: &str, source: &str, _destination: &str, _lamports: u64) -> Result<(), PaymentRejected> {
+            match source == self.0 {
+                true => Ok(()),
+                false => Err(PaymentRejected::WrongSource),
+            }
+        }
+    }
+    
+    #[test]
+    fn transfers_to_the_treasury_are_totalled_by_source() {
+        let transfer = |source: &str, destination: &str, lamports: u64| {
+            serde_json::json!({
+                "program": "system",
+                "parsed": { "type": "transfer", "info": { "source": source, "destination": destination, "lamports": lamports } },
+            })
+        };
+        let instructions = [
+            transfer("payer", "treasury", 5),
+            transfer("other", "treasury", 7),
+            transfer("payer", "treasury", 1),
+            transfer("payer", "elsewhere", 100),
+        ];
+        let instructions: Vec<&serde_json::Value> = instructions.iter().collect();
+        let transferred = transferred_to(&instructions, "treasury");
+        assert_eq!(transferred.len(), 2);
+        assert_eq!(transferred.get("payer"), Some(&6));
+        assert_eq!(transferred.get("other"), Some(&7));
+    }
+    
+    #[tokio::test]
+    async fn only_a_transfer_from_the_invoiced_wallet_pays() {
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(MemoryStorage::new());
+        let users = Arc::new(StoredUserManager::new(storage.clone()));
+        let plan = Plan {
+            pricing: Some(PlanPricing {
+                base_price: 1_000,
+                included_requests: 0,
+                overage_price_per_thousand: 0,
+            }),
+            ..Default::default()
+        };
+        users.create_plan(plan.clone()).await.unwrap();
+        let invoiced = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let other = users.create_user("11111111111111111111111111111111").await.unwrap();
+        let usage = DailyUsage {
+            user_id: invoiced.id,
+            day: 1,
+            plan_id: Some(plan.id),
+            ..Default::default()
+        };
+        Collection::new(storage.clone(), DAILY_USAGE)
+            .put(&format!("{}/{:010}", invoiced.id, 1), &usage, Precondition::Absent)
+            .await
+            .unwrap();
+        
+        let config = BillingConfig {
+            enabled: true,
+            treasury: Some("treasury".to_string()),
+            ..Default::default()
+        };
+        let billing = |payer: &User| {
+            Billing::new(config.clone(), storage.clone(), users.clone()).with_verifier(Arc::new(PaidFrom(payer.wallet_address.clone())))
+        };
+        let day = Duration::from_secs(SECONDS_PER_DAY);
+        let (start, end) = (Timestamp::from_secs(0) + day, Timestamp::from_secs(0) + day * 2);
+        let invoices = billing(&invoiced).close_period(start, end, Timestamp::now()).await.unwrap();
+        assert_eq!(invoices.len(), 1);
+        
+        // The other user's transfer to the treasury doesn't pay the invoice
+        let signature = "1".repeat(64);
+        let refused = billing(&other).pay(invoices[0].id, &signature, Timestamp::now()).await.unwrap_err();
+        assert_eq!(refused.downcast_ref::<PaymentRejected>(), Some(&PaymentRejected::WrongSource));
+        
+        let paid = billing(&invoiced).pay(invoices[0].id, &signature, Timestamp::now()).await.unwrap().unwrap();
+        assert_eq!(paid.status, InvoiceStatus::Paid);
+    }
+    
+    #[tokio::test]
+    async fn closing_a_period_invoices_its_metered_usage_by_class_with_the_overage() {
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(MemoryStorage::new());
+        let users = Arc::new(StoredUserManager::new(storage.clone()));
+        let plan = Plan {
+            name: "metered".to_string(),
+            pricing: Some(PlanPricing {
+                base_price: 1_000,
+                included_requests: 3,
+                overage_price_per_thousand: 2_500,
+            }),
+            ..Default::default()
+        };
+        users.create_plan(plan.clone()).await.unwrap();
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        users.set_user_plan(user.id, plan.id).await.unwrap();
+        let user = users.get_user(user.id).await.unwrap().unwrap();
+        
+        // Two days of usage in the period, and a day after it
+        let day = Duration::from_secs(SECONDS_PER_DAY);
+        let (start, end) = (Timestamp::from_secs(0) + day * 10, Timestamp::from_secs(0) + day * 12);
+        let meter = UsageMeter::new(storage.clone());
+        let usage = [
+            (start, MethodClass::Read, 100),
+            (start, MethodClass::Read, 100),
+            (start + Duration::from_secs(60), MethodClass::Write, 300),
+            (start + day, MethodClass::Read, 100),
+            (start + day, MethodClass::HeavyRead, 200),
+            (start + day * 2 - Duration::from_secs(1), MethodClass::Read, 100),
+            (end, MethodClass::Read, 100),
+            (end, MethodClass::Write, 300),
+        ];
+        for (at, class, bytes) in usage {
+            meter.record_request(&user, class, bytes, at);
+            meter.record_response(user.id, 1_000, at);
+        }
+        meter.flush().await.unwrap();
+        
+        let billing = Billing::new(
+            BillingConfig {
+                enabled: true,
+                ..Default::default()
+            },
+            storage,
+            users.clone(),
+        );
+        let invoices = billing.close_period(start, end, end + day).await.unwrap();
+        assert_eq!(invoices.len(), 1);
+        let invoice = &invoices[0];
+        assert_eq!((invoice.user_id, invoice.plan.as_str()), (user.id, "metered"));
+        assert_eq!((invoice.period_start, invoice.period_end), (start, end));
+        let by_class = BTreeMap::from([(MethodClass::Read, 4), (MethodClass::HeavyRead, 1), (MethodClass::Write, 1)]);
+        assert_eq!(invoice.requests, by_class);
+        assert_eq!(invoice.total_requests, 6);
+        assert_eq!((invoice.request_bytes, invoice.response_bytes), (900, 6_000));
+        assert_eq!((invoice.included_requests, invoice.overage_requests), (3, 3));
+        
+        // Three requests over at 2,500 lamports a thousand come to 7.5, rounded up
+        assert_eq!((invoice.base_amount, invoice.overage_amount, invoice.amount), (1_000, 8, 1_008));
+        assert_eq!(billing.invoices_for(user.id).await.unwrap(), invoices);
+    }
+}
@@ -0,0 +1,265 @@
+//! DarkNode Admin CLI
+//!
+//! This binary provides operator commands for managing DarkNode nodes and the coordinator.
+//!
+//! Usage:
+//!   darknode-admin node rotate-key --node <url> [--activate-in <secs>]
+//!   darknode-admin nodes available --coordinator <url> --role <role>
+//!   darknode-admin nodes versions --coordinator <url>
+//!   darknode-admin flags list --coordinator <url>
+//!   darknode-admin flags set --coordinator <url> --flag <name> --value <value> --expires-in <secs>
+//!   darknode-admin flags clear --coordinator <url> --flag <name>
+//!
+//! Roles are matched in any case, as by the coordinator and in config files. Flag values
+//! are `true`, `false` or a number, see `darknode_backend::flags`.
+//!
+//! Administrative routes take the operator token, read from `DARKNODE_OPERATOR_TOKEN`,
+//! see `darknode_backend::operator`.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use darknode_backend::flags::{Flag, FlagValue};
+use darknode_backend::identity::RotationOutcome;
+use darknode_backend::protocol::VersionReport;
+use darknode_backend::types::NodeRole;
+use serde_json::json;
+
+/// Default overlap between publishing a new key and switching to it
+const DEFAULT_ACTIVATE_IN: Duration = Duration::from_secs(3600);
+
+/// The environment variable holding the operator token
+const OPERATOR_TOKEN_VAR: &str = "DARKNODE_OPERATOR_TOKEN";
+
+/// Print usage information
+fn usage() -> ! {
+    eprintln!("Usage:");
+    eprintln!("  darknode-admin node rotate-key --node <url> [--activate-in <secs>]");
+    eprintln!("  darknode-admin nodes available --coordinator <url> --role <role>");
+    eprintln!("  darknode-admin nodes versions --coordinator <url>");
+    eprintln!("  darknode-admin flags list --coordinator <url>");
+    eprintln!("  darknode-admin flags set --coordinator <url> --flag <name> --value <value> --expires-in <secs>");
+    eprintln!("  darknode-admin flags clear --coordinator <url> --flag <name>");
+    std::process::exit(2);
+}
+
+/// Get the value following a `--flag` argument
+fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
+/// The operator token to present to administrative routes
+fn operator_token() -> Result<String> {
+    std::env::var(OPERATOR_TOKEN_VAR).with_context(|| format!("{} must be set", OPERATOR_TOKEN_VAR))
+}
+
+/// Rotate a node's long-term key through its admin endpoint
+async fn rotate_key(args: &[String]) -> Result<()> {
+    let node_url = flag_value(args, "--node").unwrap_or_else(|| usage());
+    let activate_in = match flag_value(args, "--activate-in") {
+        Some(secs) => Duration::from_secs(secs.parse().context("--activate-in must be a number of seconds")?),
+        None => DEFAULT_ACTIVATE_IN,
+    };
+
+    let outcome: RotationOutcome = reqwest::Client::new()
+        .post(format!("{}/admin/rotate-key", node_url.trim_end_matches('/')))
+        .bearer_auth(operator_token()?)
+        .json(&json!({ "activate_in_secs": activate_in.as_secs() }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    println!("Published next key, activating in {}s", activate_in.as_secs());
+    println!("{}", serde_json::to_string_pretty(&outcome)?);
+    Ok(())
+}
+
+/// List the nodes of a role the coordinator considers available
+async fn available_nodes(args: &[String]) -> Result<()> {
+    let coordinator_url = flag_value(args, "--coordinator").unwrap_or_else(|| usage());
+    let role: NodeRole = flag_value(args, "--role").unwrap_or_else(|| usage()).parse()?;
+
+    let response: serde_json::Value = reqwest::Client::new()
+        .get(format!("{}/nodes/available/{}", coordinator_url.trim_end_matches('/'), role))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    println!("{}", serde_json::to_string_pretty(&response["nodes"])?);
+    Ok(())
+}
+
+/// Summarize the releases and protocol versions the available nodes run
+async fn node_versions(args: &[String]) -> Result<()> {
+    let coordinator_url = flag_value(args, "--coordinator").unwrap_or_else(|| usage());
+
+    let report: VersionReport = reqwest::Client::new()
+        .get(format!("{}/nodes/versions", coordinator_url.trim_end_matches('/')))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    print!("{}", version_summary(&report));
+    Ok(())
+}
+
+/// The fleet summary printed by `nodes versions`
+fn version_summary(report: &VersionReport) -> String {
+    let mut summary = String::from("Releases:\n");
+    for (release, nodes) in &report.by_release {
+        summary.push_str(&format!("  {:<32} {:>5} nodes\n", release, nodes));
+    }
+    summary.push_str("Protocol versions:\n");
+    for (range, nodes) in &report.by_range {
+        summary.push_str(&format!("  {:<32} {:>5} nodes\n", range, nodes));
+    }
+    summary.push_str(&format!(
+        "{} nodes share no protocol version with the coordinator ({})\n",
+        report.incompatible.len(),
+        report.supported
+    ));
+    for node_id in &report.incompatible {
+        summary.push_str(&format!("  {}\n", node_id.0));
+    }
+    summary
+}
+
+/// List the feature flags in force
+async fn list_flags(args: &[String]) -> Result<()> {
+    let coordinator_url = flag_value(args, "--coordinator").unwrap_or_else(|| usage());
+
+    let flags: BTreeMap<String, Flag> = reqwest::Client::new()
+        .get(format!("{}/flags", coordinator_url.trim_end_matches('/')))
+        .bearer_auth(operator_token()?)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    for (name, flag) in &flags {
+        let value = serde_json::to_string(&flag.value)?;
+        println!("{:<24} {:<8} until {}", name, value, flag.expires_at);
+    }
+    Ok(())
+}
+
+/// Set a feature flag across the network until it expires
+async fn set_flag(args: &[String]) -> Result<()> {
+    let coordinator_url = flag_value(args, "--coordinator").unwrap_or_else(|| usage());
+    let name = flag_value(args, "--flag").unwrap_or_else(|| usage());
+    let value: FlagValue = serde_json::from_str(&flag_value(args, "--value").unwrap_or_else(|| usage()))
+        .context("--value must be true, false or a number")?;
+    let expires_in: u64 = flag_value(args, "--expires-in")
+        .context("flags must be set with --expires-in")?
+        .parse()
+        .context("--expires-in must be a number of seconds")?;
+
+    let response = reqwest::Client::new()
+        .put(format!("{}/flags/{}", coordinator_url.trim_end_matches('/'), name))
+        .bearer_auth(operator_token()?)
+        .json(&json!({ "value": value, "expires_in_secs": expires_in }))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        anyhow::bail!("{}: {}", response.status(), response.text().await?);
+    }
+    let flag: Flag = response.json().await?;
+
+    println!("Set {} to {} until {}", name, serde_json::to_string(&flag.value)?, flag.expires_at);
+    Ok(())
+}
+
+/// Clear a feature flag, the feature going back to each node's config
+async fn clear_flag(args: &[String]) -> Result<()> {
+    let coordinator_url = flag_value(args, "--coordinator").unwrap_or_else(|| usage());
+    let name = flag_value(args, "--flag").unwrap_or_else(|| usage());
+
+    reqwest::Client::new()
+        .delete(format!("{}/flags/{}", coordinator_url.trim_end_matches('/'), name))
+        .bearer_auth(operator_token()?)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    println!("Cleared {}", name);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command: Vec<&str> = args.iter().take(2).map(String::as_str).collect();
+
+    match command.as_slice() {
+        ["node", "rotate-key"] => rotate_key(&args[2..]).await,
+        ["nodes", "available"] => available_nodes(&args[2..]).await,
+        ["nodes", "versions"] => node_versions(&args[2..]).await,
+        ["flags", "list"] => list_flags(&args[2..]).await,
+        ["flags", "set"] => set_flag(&args[2..]).await,
+        ["flags", "clear"] => clear_flag(&args[2..]).await,
+        _ => usage(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use darknode_backend::protocol::ProtocolRange;
+    use darknode_backend::types::NodeId;
+    use uuid::Uuid;
+
+    #[test]
+    fn version_summary_lists_releases_ranges_and_incompatible_nodes() {
+        let stranded = NodeId(Uuid::nil());
+        let report = VersionReport {
+            supported: ProtocolRange::legacy(),
+            by_range: BTreeMap::from([("v1".to_string(), 3), ("v2".to_string(), 1)]),
+            by_max_version: BTreeMap::from([(1, 3), (2, 1)]),
+            by_release: BTreeMap::from([("0.1.0+1a2b3c4d5e6f".to_string(), 4)]),
+            incompatible: vec![stranded],
+        };
+        let expected = format!(
+            "Releases:\n  {:<32} {:>5} nodes\nProtocol versions:\n  {:<32} {:>5} nodes\n  {:<32} {:>5} nodes\n\
+             1 nodes share no protocol version with the coordinator ({})\n  {}\n",
+            "0.1.0+1a2b3c4d5e6f",
+            4,
+            "v1",
+            3,
+            "v2",
+            1,
+            ProtocolRange::legacy(),
+            Uuid::nil(),
+        );
+        assert_eq!(version_summary(&report), expected);
+    }
+
+    #[test]
+    fn version_summary_of_an_empty_fleet_names_no_nodes() {
+        let report = VersionReport {
+            supported: ProtocolRange::legacy(),
+            by_range: BTreeMap::new(),
+            by_max_version: BTreeMap::new(),
+            by_release: BTreeMap::new(),
+            incompatible: Vec::new(),
+        };
+        assert_eq!(
+            version_summary(&report),
+            format!(
+                "Releases:\nProtocol versions:\n\
+                 0 nodes share no protocol version with the coordinator ({})\n",
+                ProtocolRange::legacy()
+            )
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! DarkNode Canary
+//!
+//! This binary sends canary requests through a fixed set of entry nodes, for operators who
+//! want end-to-end validation from outside the coordinator's network.
+//!
+//! Usage:
+//!   darknode-canary --api-key <key> --entry <url> [--entry <url>...] [--webhook <url>] [--listen <addr>] [--check-config]
+//!
+//! `--check-config` only prints the settings the canary would run with, its API key redacted.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::{extract::Extension, routing::get, Json, Router};
+use darknode_backend::{
+    build_info::BuildInfo,
+    canary::{CanaryConfig, CanaryRunner, CanaryStatus, EntrySource},
+    config,
+    traffic,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
+use tracing::{info, Level};
+use tracing_subscriber::{filter, prelude::*};
+
+/// Print usage information
+fn usage() -> ! {
+    eprintln!("Usage:");
+    eprintln!("  darknode-canary --api-key <key> --entry <url> [--entry <url>...] [--webhook <url>] [--listen <addr>] [--check-config]");
+    std::process::exit(2);
+}
+
+/// Get the value following a `--flag` argument
+fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
+/// Get the values following every occurrence of a `--flag` argument
+fn flag_values(args: &[String], flag: &str) -> Vec<String> {
+    args.windows(2)
+        .filter(|pair| pair[0] == flag)
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+/// Handler for the results of recent canary requests
+async fn canary_status(Extension(runner): Extension<Arc<CanaryRunner>>) -> Json<CanaryStatus> {
+    Json(runner.status())
+}
+
+/// Handler for Prometheus scrapes
+async fn prometheus_metrics(Extension(handle): Extension<PrometheusHandle>) -> String {
+    handle.render()
+}
+
+/// Handler for health checks
+async fn health_check() -> &'static str {
+    "OK"
+}
+
+/// Handler for the build of this binary
+async fn version() -> Json<BuildInfo> {
+    Json(BuildInfo::current())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Initialize tracing
+    tracing_subscriber::registry()
+        .with(filter::LevelFilter::from_level(Level::INFO))
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    // Load configuration
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = CanaryConfig {
+        api_key: flag_value(&args, "--api-key").unwrap_or_else(|| usage()),
+        entry_urls: flag_values(&args, "--entry"),
+        webhook_url: flag_value(&args, "--webhook"),
+        ..CanaryConfig::default()
+    };
+    if config.entry_urls.is_empty() {
+        usage();
+    }
+    let listen_addr: SocketAddr = flag_value(&args, "--listen")
+        .unwrap_or_else(|| "127.0.0.1:3100".to_string())
+        .parse()?;
+    if config::check_requested(&args) {
+        print!("{}", config::render(&config)?);
+        return Ok(());
+    }
+
+    BuildInfo::current().log_banner("darknode-canary");
+    info!("Sending canary requests through {} entry nodes", config.entry_urls.len());
+
+    // Export the canary's metrics for Prometheus
+    let prometheus = traffic::install_prometheus()?;
+
+    let entries = EntrySource::Static(config.entry_urls.clone());
+    let runner = Arc::new(CanaryRunner::new(config, entries));
+    tokio::spawn(runner.clone().run());
+
+    let app = Router::new()
+        .route("/canary/status", get(canary_status))
+        .route("/metrics", get(prometheus_metrics))
+        .route("/health", get(health_check))
+        .route("/version", get(version))
+        .layer(Extension(runner))
+        .layer(Extension(prometheus));
+
+    // Start the server
+    info!("Listening on {}", listen_addr);
+    axum::Server::bind(&listen_addr)
+        .serve(app.into_make_service())
+        .await?;
+
+    Ok(())
+}
@@ -7,39 +7,85 @@
 //! 3. Distributing routing information
 //! 4. Monitoring RPC provider health
 //! 5. Providing a dashboard for network administrators
+//!
+//! Usage:
+//!   coordinator [--config <file>] [--check-config] [--seed <file>] [--reseed]
+//!
+//! `--config` reads the `[common]` and `[coordinator]` sections of a config file, and
+//! `--check-config` only validates and prints them (see `darknode_backend::config`).
+//!
+//! Providers and authorized node keys are seeded from `[coordinator.bootstrap]`, or from
+//! the seed file given with `--seed` instead, on first start; `--reseed` applies them again
+//! to a coordinator that already has providers.
 
-use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use axum::{
-    extract::{Extension, Path},
-    http::StatusCode,
-    routing::{get, post},
+    body::Bytes,
+    extract::{Extension, Path, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{delete, get, patch, post, put},
     Json, Router,
 };
 use darknode_backend::{
+    accounting::{EpochAccounts, ReceiptRejected, WorkReceipt},
+    api_key::UserApiKey,
+    attribution,
+    billing::{Billing, Invoice, PaymentRejected, PeriodRefused, PlanPricing},
+    bootstrap::{self, BootstrapConfig},
+    build_info::BuildInfo,
+    chains::Network,
+    clock::{self, Timestamp},
+    config::{self, DarknodeConfig},
     coordinator::CoordinatorService,
-    impls::CryptoImpl,
-    traits::{Crypto, NodeManager, RpcManager},
-    types::{Node, NodeId, NodeRole, NodeStatus, RpcProvider},
+    dashboard::{Bucket, DashboardMetric, Overview},
+    directory::{DirectoryPublisher, SignedDirectory, Which},
+    directory_watch::{SignedDiff, VersionNotice},
+    epochs::Epoch,
+    flags::{Flag, FlagBoard, FlagRefused, FlagValue},
+    identity::{NodeIdentity, PublishNextKeyRequest, PublishNextKeyResponse},
+    impls::{CryptoImpl, StoredNodeManager, StoredRpcManager, StoredUserManager},
+    maintenance::{InvalidWindow, MaintenanceWindow},
+    managers::quota::QuotaExceeded,
+    method_routing::{InvalidRoutes, MethodRoutes},
+    operator,
+    privacy::NoiseLayer,
+    probe::ProbeSummary,
+    protocol::VersionReport,
+    provisioning::{self, ImportError, MappingFormat, ProvisioningConfig, RowError},
+    reachability::PartitionWarning,
+    report_auth::{self, ReportSender, ReportVerifier},
+    recommend::{PathConstraints, Recommendation},
+    regions::MeasuredLatency,
+    scopes::{Scope, ScopeError},
+    signing::{SIGNATURE_HEADER, TIMESTAMP_HEADER},
+    storage,
+    submissions::{ProviderProposal, ReviewDecision, ReviewRefused, SubmissionRejected},
+    telemetry::{self, HttpSpans},
+    traffic,
+    traits::{Crypto, NodeManager, RpcManager, UserManager},
+    types::{ApiKey, CryptoKey, Heartbeat, Node, NodeId, NodeRole, NodeStatus, Plan, PriorityClass, ProviderState, RpcProvider, UnknownVariant, User},
+    webhooks::{CreatedWebhook, DeadLetter, DeliveryReport, Webhook, WebhookSpec, Webhooks},
+    whatif::{Projection, Scenario},
 };
+#[cfg(feature = "canary")]
+use darknode_backend::canary::{CanaryRunner, CanaryStatus, EntrySource};
+use metrics_exporter_prometheus::PrometheusHandle;
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
 use tower_http::trace::TraceLayer;
 use tracing::{info, Level};
-use tracing_subscriber::{filter, prelude::*};
 use uuid::Uuid;
 
-/// Configuration for the coordinator node
-#[derive(Debug, Clone, Deserialize)]
-struct Config {
-    /// The address to listen on
-    listen_addr: SocketAddr,
-    /// The region this node is in
-    region: String,
-}
+/// Path nodes register on, part of the message they sign to register
+const REGISTER_NODE_PATH: &str = "/nodes";
 
 /// Request body for registering a node
 #[derive(Debug, Clone, Deserialize)]
@@ -114,6 +160,8 @@ struct UpdateProviderStatusResponse {
 struct GetAvailableNodesResponse {
     /// The available nodes
     nodes: Vec<Node>,
+    /// The epoch the network is in
+    epoch: Epoch,
 }
 
 /// Response body for getting active providers
@@ -146,134 +194,229 @@ struct CheckRpcHealthResponse {
     success: bool,
     /// Error message, if any
     error: Option<String>,
+    /// How the providers fared, if they were probed
+    summary: Option<ProbeSummary>,
 }
 
-/// Mock implementation of the NodeManager trait
-struct MockNodeManager {
-    nodes: Arc<RwLock<Vec<Node>>>,
+/// Response body for removing an RPC provider
+#[derive(Debug, Clone, Serialize)]
+struct RemoveProviderResponse {
+    /// Whether the removal was successful
+    success: bool,
+    /// Error message, if any
+    error: Option<String>,
 }
 
-impl MockNodeManager {
-    fn new() -> Self {
-        Self {
-            nodes: Arc::new(RwLock::new(Vec::new())),
-        }
-    }
+/// Response body for recording a heartbeat
+#[derive(Debug, Clone, Serialize)]
+struct HeartbeatResponse {
+    /// Whether the heartbeat was recorded
+    success: bool,
+    /// Error message, if any
+    error: Option<String>,
 }
 
-#[async_trait::async_trait]
-impl NodeManager for MockNodeManager {
-    async fn register_node(&self, node: Node) -> Result<()> {
-        let mut nodes = self.nodes.write().await;
-        nodes.push(node);
-        Ok(())
-    }
+/// Query parameters for fetching a directory
+#[derive(Debug, Clone, Deserialize)]
+struct DirectoryQuery {
+    /// Which directory, the current one if not given
+    #[serde(default)]
+    which: Which,
+}
 
-    async fn update_node_status(&self, node_id: &NodeId, status: NodeStatus) -> Result<()> {
-        let mut nodes = self.nodes.write().await;
-        if let Some(node) = nodes.iter_mut().find(|n| n.id == *node_id) {
-            node.status = status;
-        }
-        Ok(())
-    }
-
-    async fn get_available_nodes(&self, role: NodeRole) -> Result<Vec<Node>> {
-        let nodes = self.nodes.read().await;
-        Ok(nodes
-            .iter()
-            .filter(|n| n.role == role && n.status == NodeStatus::Online)
-            .cloned()
-            .collect())
-    }
-
-    async fn get_node(&self, node_id: &NodeId) -> Result<Option<Node>> {
-        let nodes = self.nodes.read().await;
-        Ok(nodes.iter().find(|n| n.id == *node_id).cloned())
-    }
-}
-
-/// Mock implementation of the RpcManager trait
-struct MockRpcManager {
-    providers: Arc<RwLock<Vec<RpcProvider>>>,
-}
-
-impl MockRpcManager {
-    fn new() -> Self {
-        let mut providers = Vec::new();
-        
-        // Add some mock RPC providers
-        providers.push(RpcProvider {
-            id: Uuid::new_v4(),
-            url: "https://api.mainnet-beta.solana.com".to_string(),
-            provider_type: "solana".to_string(),
-            active: true,
-            success_rate: 0.99,
-            avg_latency: Duration::from_millis(100),
-            last_checked: SystemTime::now(),
-        });
-        
-        providers.push(RpcProvider {
-            id: Uuid::new_v4(),
-            url: "https://solana-api.projectserum.com".to_string(),
-            provider_type: "solana".to_string(),
-            active: true,
-            success_rate: 0.98,
-            avg_latency: Duration::from_millis(120),
-            last_checked: SystemTime::now(),
-        });
-        
-        Self {
-            providers: Arc::new(RwLock::new(providers)),
-        }
-    }
+/// Query parameters for watching or catching up on the directory
+#[derive(Debug, Clone, Deserialize)]
+struct WatchQuery {
+    /// The directory version the node holds, none if not given
+    #[serde(default)]
+    since: u64,
 }
 
-#[async_trait::async_trait]
-impl RpcManager for MockRpcManager {
-    async fn register_provider(&self, provider: RpcProvider) -> Result<()> {
-        let mut providers = self.providers.write().await;
-        providers.push(provider);
-        Ok(())
-    }
-    
-    async fn update_provider_status(&self, provider_id: Uuid, active: bool) -> Result<()> {
-        let mut providers = self.providers.write().await;
-        if let Some(provider) = providers.iter_mut().find(|p| p.id == provider_id) {
-            provider.active = active;
-        }
-        Ok(())
-    }
-    
-    async fn get_active_providers(&self) -> Result<Vec<RpcProvider>> {
-        let providers = self.providers.read().await;
-        Ok(providers.iter().filter(|p| p.active).cloned().collect())
-    }
-    
-    async fn get_best_provider(&self) -> Result<Option<RpcProvider>> {
-        let providers = self.providers.read().await;
-        let active_providers: Vec<_> = providers.iter().filter(|p| p.active).collect();
-        
-        if active_providers.is_empty() {
-            return Ok(None);
-        }
-        
-        // Find the provider with the highest success rate
-        let best_provider = active_providers
-            .iter()
-            .max_by(|a, b| a.success_rate.partial_cmp(&b.success_rate).unwrap())
-            .unwrap();
-        
-        Ok(Some((*best_provider).clone()))
+/// Request body for setting a feature flag
+#[derive(Debug, Clone, Deserialize)]
+struct SetFlagRequest {
+    /// What the flag is set to
+    value: FlagValue,
+    /// How long from now the flag applies; flags without are refused
+    #[serde(default)]
+    expires_in_secs: u64,
+}
+
+/// Query parameters for the dashboard time series
+#[derive(Debug, Clone, Deserialize)]
+struct TimeseriesQuery {
+    /// The metric to chart
+    metric: DashboardMetric,
+    /// The trailing window to chart, in seconds
+    window: u64,
+}
+
+/// Query parameters for the published statistics
+#[derive(Debug, Clone, Deserialize)]
+struct StatsQuery {
+    /// The trailing window to publish, in seconds
+    window: u64,
+}
+
+/// Response body for the dashboard time series
+#[derive(Debug, Clone, Serialize)]
+struct TimeseriesResponse {
+    /// The charted metric
+    metric: DashboardMetric,
+    /// The buckets, oldest first
+    buckets: Vec<Bucket>,
+}
+
+/// Request body for creating a plan
+#[derive(Debug, Clone, Deserialize)]
+struct CreatePlanRequest {
+    /// Human-readable name of the plan
+    name: String,
+    /// Maximum number of requests per UTC day
+    daily_request_cap: u64,
+    /// Maximum number of concurrently active circuits
+    max_circuits: u32,
+    /// Maximum number of concurrent WebSocket subscriptions
+    max_subscriptions: u32,
+    /// Scheduling priority of the plan's traffic
+    priority_class: PriorityClass,
+    /// Maximum number of RPC mappings a user holds
+    #[serde(default = "Plan::default_max_mappings")]
+    max_mappings: u32,
+    /// Largest quorum a request may ask providers to agree in
+    #[serde(default = "Plan::default_max_quorum")]
+    max_quorum: u8,
+    /// What the plan costs per billing period, if it is billed
+    #[serde(default)]
+    pricing: Option<PlanPricing>,
+}
+
+/// Response body for creating a plan
+#[derive(Debug, Clone, Serialize)]
+struct CreatePlanResponse {
+    /// The created plan, if successful
+    plan: Option<Plan>,
+    /// Error message, if any
+    error: Option<String>,
+}
+
+/// Request body for assigning a plan to a user
+#[derive(Debug, Clone, Deserialize)]
+struct SetUserPlanRequest {
+    /// The ID of the plan to assign
+    plan_id: Uuid,
+}
+
+/// Response body for assigning a plan to a user
+#[derive(Debug, Clone, Serialize)]
+struct SetUserPlanResponse {
+    /// Whether the assignment was successful
+    success: bool,
+    /// Error message, if any
+    error: Option<String>,
+}
+
+/// Request body for closing a billing period
+#[derive(Debug, Clone, Deserialize)]
+struct ClosePeriodRequest {
+    /// Start of the period, a UTC midnight
+    start: Timestamp,
+    /// End of the period, the UTC midnight after its last day
+    end: Timestamp,
+}
+
+/// Query parameters for listing a user's invoices
+#[derive(Debug, Clone, Deserialize)]
+struct InvoicesQuery {
+    /// The user
+    user: Uuid,
+}
+
+/// Request body for paying an invoice
+#[derive(Debug, Clone, Deserialize)]
+struct PayInvoiceRequest {
+    /// Signature of the Solana transaction paying the invoice
+    signature: String,
+}
+
+/// Query parameters for importing or exporting a user's mappings
+#[derive(Debug, Clone, Deserialize)]
+struct MappingsQuery {
+    /// The file format, taken from the content type for imports if not given
+    format: Option<MappingFormat>,
+}
+
+/// Request body for creating a further API key
+#[derive(Debug, Clone, Deserialize)]
+struct CreateKeyRequest {
+    /// What the key is for
+    name: String,
+    /// What the key is limited to, nothing but the plan if empty
+    #[serde(default)]
+    scopes: Vec<Scope>,
+}
+
+/// Request body for replacing a key's scopes
+#[derive(Debug, Clone, Deserialize)]
+struct SetKeyScopesRequest {
+    /// What the key is limited to from now on
+    scopes: Vec<Scope>,
+}
+
+/// A mapping created by an import
+#[derive(Debug, Clone, Serialize)]
+struct ImportedMapping {
+    /// The row it was created from, from 1
+    row: usize,
+    /// The ID of the mapping
+    id: Uuid,
+    /// The original RPC URL
+    original_rpc: String,
+    /// The DarkNode HTTPS RPC URL
+    darknode_https_rpc: String,
+    /// The DarkNode WSS RPC URL
+    darknode_wss_rpc: String,
+}
+
+/// Response body for importing mappings
+#[derive(Debug, Clone, Serialize)]
+struct ImportMappingsResponse {
+    /// The mappings created, in row order
+    mappings: Vec<ImportedMapping>,
+    /// Why nothing was imported, if so
+    error: Option<String>,
+    /// The rows that failed their checks, if that is why
+    invalid_rows: Vec<RowError>,
+}
+
+impl ImportMappingsResponse {
+    /// A response for an import that created nothing
+    fn failed(error: impl ToString, invalid_rows: Vec<RowError>) -> Json<Self> {
+        Json(Self {
+            mappings: Vec::new(),
+            error: Some(error.to_string()),
+            invalid_rows,
+        })
     }
 }
 
-/// Handler for registering a node
+/// Handler for registering a node, signed by the node with the key it registers
 async fn register_node(
-    Json(request): Json<RegisterNodeRequest>,
-    Extension(node_manager): Extension<Arc<dyn NodeManager + Send + Sync>>,
-) -> Result<Json<RegisterNodeResponse>, StatusCode> {
-    match node_manager.register_node(request.node).await {
-        Ok(_) => Ok(Json(RegisterNodeResponse {
+    Extension(service): Extension<Arc<CoordinatorService>>,
+    Extension(verifier): Extension<Arc<ReportVerifier>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<RegisterNodeResponse>, Response> {
+    let request: RegisterNodeRequest = serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+    if verifier.enabled() {
+        verifier
+            .verify_registration(&headers, REGISTER_NODE_PATH, &body, &request.node, Timestamp::now())
+            .await
+            .map_err(report_auth::reject)?;
+    }
+    match service.register_node(request.node).await {
+        Ok(false) => Err(StatusCode::FORBIDDEN.into_response()),
+        Ok(true) => Ok(Json(RegisterNodeResponse {
             success: true,
             error: None,
         })),
@@ -287,9 +430,9 @@ async fn register_node(
 /// Handler for updating a node's status
 async fn update_node_status(
     Json(request): Json<UpdateNodeStatusRequest>,
-    Extension(node_manager): Extension<Arc<dyn NodeManager + Send + Sync>>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
 ) -> Result<Json<UpdateNodeStatusResponse>, StatusCode> {
-    match node_manager.update_node_status(&request.node_id, request.status).await {
+    match service.update_node_status(&request.node_id, request.status).await {
         Ok(_) => Ok(Json(UpdateNodeStatusResponse {
             success: true,
             error: None,
@@ -301,17 +444,44 @@ async fn update_node_status(
     }
 }
 
+/// Handler for listing the nodes in maintenance, for entry nodes to drain
+async fn draining_nodes(Extension(service): Extension<Arc<CoordinatorService>>) -> Json<Vec<NodeId>> {
+    Json(service.draining_nodes())
+}
+
+/// Handler for listing exit nodes' attestation keys, never naming the node each belongs to
+async fn attestation_keys(Extension(service): Extension<Arc<CoordinatorService>>) -> Json<Vec<CryptoKey>> {
+    Json(service.attestation_keys())
+}
+
 /// Handler for getting available nodes
+///
+/// The role is matched in any case, and an unknown one is answered with the valid roles.
 async fn get_available_nodes(
-    Path(role): Path<NodeRole>,
-    Extension(node_manager): Extension<Arc<dyn NodeManager + Send + Sync>>,
-) -> Result<Json<GetAvailableNodesResponse>, StatusCode> {
-    match node_manager.get_available_nodes(role).await {
-        Ok(nodes) => Ok(Json(GetAvailableNodesResponse { nodes })),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    Path(role): Path<String>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Result<Json<GetAvailableNodesResponse>, (StatusCode, String)> {
+    let role: NodeRole = role.parse().map_err(|e: UnknownVariant| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    match service.available_nodes(role).await {
+        Ok(nodes) => Ok(Json(GetAvailableNodesResponse {
+            nodes,
+            epoch: service.current_epoch(),
+        })),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
     }
 }
 
+/// Handler for the spread of available nodes across protocol versions
+async fn version_report(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Result<Json<VersionReport>, (StatusCode, String)> {
+    service
+        .version_report()
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
 /// Handler for registering an RPC provider
 async fn register_provider(
     Json(request): Json<RegisterProviderRequest>,
@@ -332,9 +502,9 @@ async fn register_provider(
 /// Handler for updating an RPC provider's status
 async fn update_provider_status(
     Json(request): Json<UpdateProviderStatusRequest>,
-    Extension(rpc_manager): Extension<Arc<dyn RpcManager + Send + Sync>>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
 ) -> Result<Json<UpdateProviderStatusResponse>, StatusCode> {
-    match rpc_manager
+    match service
         .update_provider_status(request.provider_id, request.active)
         .await
     {
@@ -351,10 +521,12 @@ async fn update_provider_status(
 
 /// Handler for getting active providers
 async fn get_active_providers(
-    Extension(rpc_manager): Extension<Arc<dyn RpcManager + Send + Sync>>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
 ) -> Result<Json<GetActiveProvidersResponse>, StatusCode> {
-    match rpc_manager.get_active_providers().await {
-        Ok(providers) => Ok(Json(GetActiveProvidersResponse { providers })),
+    match service.active_providers().await {
+        Ok(providers) => Ok(Json(GetActiveProvidersResponse {
+            providers: providers.into_iter().map(RpcProvider::redacted).collect(),
+        })),
         Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
@@ -364,7 +536,9 @@ async fn get_best_provider(
     Extension(rpc_manager): Extension<Arc<dyn RpcManager + Send + Sync>>,
 ) -> Result<Json<GetBestProviderResponse>, StatusCode> {
     match rpc_manager.get_best_provider().await {
-        Ok(provider) => Ok(Json(GetBestProviderResponse { provider })),
+        Ok(provider) => Ok(Json(GetBestProviderResponse {
+            provider: provider.map(RpcProvider::redacted),
+        })),
         Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
@@ -385,73 +559,995 @@ async fn update_topology(
     }
 }
 
+/// Handler for recommending circuit paths to an entry node
+async fn recommend_paths(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+    Json(constraints): Json<PathConstraints>,
+) -> Result<Json<Recommendation>, (StatusCode, String)> {
+    service
+        .recommend_paths(&constraints)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Handler for projecting how the network would cope with a scenario, changing nothing
+async fn what_if(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+    Json(scenario): Json<Scenario>,
+) -> Result<Json<Projection>, (StatusCode, String)> {
+    service
+        .what_if(&scenario)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Handler for the round trips between regions the nodes have measured
+async fn latency_matrix(Extension(service): Extension<Arc<CoordinatorService>>) -> Json<Vec<MeasuredLatency>> {
+    Json(service.latency_matrix())
+}
+
 /// Handler for checking RPC health
 async fn check_rpc_health(
     Extension(service): Extension<Arc<CoordinatorService>>,
 ) -> Result<Json<CheckRpcHealthResponse>, StatusCode> {
     match service.check_rpc_health().await {
-        Ok(_) => Ok(Json(CheckRpcHealthResponse {
+        Ok(summary) => Ok(Json(CheckRpcHealthResponse {
             success: true,
             error: None,
+            summary: Some(summary),
         })),
         Err(e) => Ok(Json(CheckRpcHealthResponse {
             success: false,
             error: Some(e.to_string()),
+            summary: None,
+        })),
+    }
+}
+
+/// Handler for removing an RPC provider
+async fn remove_provider(
+    Path(provider_id): Path<Uuid>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Result<Json<RemoveProviderResponse>, StatusCode> {
+    match service.remove_provider(provider_id).await {
+        Ok(_) => Ok(Json(RemoveProviderResponse {
+            success: true,
+            error: None,
+        })),
+        Err(e) => Ok(Json(RemoveProviderResponse {
+            success: false,
+            error: Some(e.to_string()),
+        })),
+    }
+}
+
+/// Handler for listing a provider's maintenance windows
+async fn maintenance_windows(
+    Path(provider_id): Path<Uuid>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Result<Json<Vec<MaintenanceWindow>>, StatusCode> {
+    match service.maintenance_windows(provider_id).await {
+        Ok(Some(windows)) => Ok(Json(windows)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Handler for scheduling a maintenance window for a provider
+async fn add_maintenance_window(
+    Path(provider_id): Path<Uuid>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
+    Json(window): Json<MaintenanceWindow>,
+) -> Result<(StatusCode, Json<Vec<MaintenanceWindow>>), (StatusCode, String)> {
+    match service.add_maintenance_window(provider_id, window).await {
+        Ok(Some(windows)) => Ok((StatusCode::CREATED, Json(windows))),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("Unknown provider {}", provider_id))),
+        Err(e) if e.is::<InvalidWindow>() => Err((StatusCode::BAD_REQUEST, e.to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// Query parameters naming a maintenance window
+#[derive(Debug, Clone, Deserialize)]
+struct WindowQuery {
+    /// When the window starts
+    start: Timestamp,
+}
+
+/// Handler for cancelling one of a provider's maintenance windows, named by when it starts
+async fn remove_maintenance_window(
+    Path(provider_id): Path<Uuid>,
+    Query(query): Query<WindowQuery>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Result<Json<Vec<MaintenanceWindow>>, StatusCode> {
+    match service.remove_maintenance_window(provider_id, query.start).await {
+        Ok(Some(windows)) => Ok(Json(windows)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Handler for cancelling all of a provider's maintenance windows
+async fn clear_maintenance_windows(
+    Path(provider_id): Path<Uuid>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> StatusCode {
+    match service.clear_maintenance_windows(provider_id).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Handler for proposing a provider, signed with the proposer's wallet
+async fn submit_provider(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+    headers: HeaderMap,
+    Json(proposal): Json<ProviderProposal>,
+) -> Result<(StatusCode, Json<RpcProvider>), (StatusCode, String)> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let timestamp = header(TIMESTAMP_HEADER).and_then(|value| value.parse().ok());
+    let signature = header(SIGNATURE_HEADER).zip(timestamp);
+    match service.submit_provider(proposal, signature).await {
+        Ok(provider) => Ok((StatusCode::CREATED, Json(provider.redacted()))),
+        Err(e) => {
+            let status = match e.downcast_ref::<SubmissionRejected>() {
+                Some(SubmissionRejected::Disabled) => StatusCode::NOT_FOUND,
+                Some(SubmissionRejected::InvalidWallet(_)) => StatusCode::BAD_REQUEST,
+                Some(SubmissionRejected::Signature(_)) => StatusCode::UNAUTHORIZED,
+                Some(SubmissionRejected::InvalidUrl) => StatusCode::BAD_REQUEST,
+                Some(SubmissionRejected::Egress(_)) => StatusCode::BAD_REQUEST,
+                Some(SubmissionRejected::AlreadyRegistered) => StatusCode::CONFLICT,
+                Some(SubmissionRejected::TooManyPending(_)) => StatusCode::TOO_MANY_REQUESTS,
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Err((status, e.to_string()))
+        }
+    }
+}
+
+/// Handler for listing the providers under review
+async fn provider_submissions(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Result<Json<Vec<RpcProvider>>, (StatusCode, String)> {
+    service
+        .provider_submissions()
+        .await
+        .map(|providers| Json(providers.into_iter().map(RpcProvider::redacted).collect()))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Handler for approving or rejecting a provider under review
+async fn review_provider(
+    Path(provider_id): Path<Uuid>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
+    Json(decision): Json<ReviewDecision>,
+) -> Result<Json<RpcProvider>, (StatusCode, String)> {
+    match service.review_provider(provider_id, decision).await {
+        Ok(Some(provider)) => Ok(Json(provider.redacted())),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("Unknown provider {}", provider_id))),
+        Err(e) if e.is::<ReviewRefused>() => Err((StatusCode::CONFLICT, e.to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// Handler for publishing a node's next key, which only the node itself may send
+async fn publish_next_key(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+    Extension(sender): Extension<ReportSender>,
+    Json(request): Json<PublishNextKeyRequest>,
+) -> Result<Json<PublishNextKeyResponse>, StatusCode> {
+    if !sender.may_report_for(&request.node_id) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    match service
+        .publish_next_key(&request.node_id, request.next_public_key, request.activates_at)
+        .await
+    {
+        Ok(_) => Ok(Json(PublishNextKeyResponse {
+            success: true,
+            error: None,
+        })),
+        Err(e) => Ok(Json(PublishNextKeyResponse {
+            success: false,
+            error: Some(e.to_string()),
         })),
     }
 }
 
+/// Handler for recording a node heartbeat, which only the node itself may send
+async fn record_heartbeat(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+    Extension(sender): Extension<ReportSender>,
+    Json(heartbeat): Json<Heartbeat>,
+) -> Result<Json<HeartbeatResponse>, StatusCode> {
+    if !sender.may_report_for(&heartbeat.node_id) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    match service.record_heartbeat(&heartbeat, sender.0.is_some()).await {
+        Ok(_) => Ok(Json(HeartbeatResponse {
+            success: true,
+            error: None,
+        })),
+        Err(e) => Ok(Json(HeartbeatResponse {
+            success: false,
+            error: Some(e.to_string()),
+        })),
+    }
+}
+
+/// Handler for recording an entry node's work receipt
+async fn record_receipt(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+    Json(receipt): Json<WorkReceipt>,
+) -> StatusCode {
+    match service.record_receipt(&receipt).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(e) if e.downcast_ref::<ReceiptRejected>().is_some() => {
+            tracing::warn!("Rejected work receipt: {}", e);
+            StatusCode::FORBIDDEN
+        }
+        Err(e) => {
+            tracing::error!("Failed to record work receipt: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Handler for the work of every node during an epoch
+async fn epoch_accounts(
+    Path(epoch): Path<u64>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Json<EpochAccounts> {
+    Json(service.epoch_accounts(epoch))
+}
+
+/// Handler for the current or next signed directory
+async fn get_directory(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+    Query(query): Query<DirectoryQuery>,
+) -> Result<Json<SignedDirectory>, (StatusCode, String)> {
+    match service.directory(query.which, Timestamp::now()).await {
+        Ok(Some(signed)) => Ok(Json(signed)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "directory is not published yet".to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// Handler for watching the directory's version, as server-sent events or a long poll
+///
+/// The version is pushed whenever it differs from the one the node holds; a long poll is
+/// answered once it does, or unchanged when it times out. Watchers past the cap are told
+/// to poll instead, see `darknode_backend::directory_watch`.
+async fn watch_directory(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+    Query(query): Query<WatchQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let changes = service.directory_changes();
+    let config = changes.config().clone();
+    let mut watch = match changes.watch() {
+        Ok(watch) => watch,
+        Err(e) => {
+            let retry_after = config.poll_interval.as_secs().to_string();
+            return (StatusCode::SERVICE_UNAVAILABLE, [(header::RETRY_AFTER, retry_after)], e.to_string()).into_response();
+        }
+    };
+    
+    let wants_events = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map_or(false, |accept| accept.contains("text/event-stream"));
+    if !wants_events {
+        let changed = tokio::time::timeout(config.long_poll_timeout, watch.changed_from(query.since)).await;
+        let version = changed.ok().flatten().unwrap_or_else(|| changes.version());
+        return Json(VersionNotice { version }).into_response();
+    }
+    
+    let versions = futures::stream::unfold((watch, query.since), |(mut watch, since)| async move {
+        let version = watch.changed_from(since).await?;
+        let event = Event::default().event("version").data(version.to_string());
+        Some((Ok::<_, std::convert::Infallible>(event), (watch, version)))
+    });
+    Sse::new(versions)
+        .keep_alive(KeepAlive::new().interval(config.heartbeat_interval))
+        .into_response()
+}
+
+/// Handler for what changed in the directory since a version
+async fn directory_changes(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+    Query(query): Query<WatchQuery>,
+) -> Result<Json<SignedDiff>, (StatusCode, String)> {
+    service
+        .directory_diff(query.since, Timestamp::now())
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Handler for the feature flags in force, see `darknode_backend::flags`
+async fn list_flags(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Result<Json<BTreeMap<String, Flag>>, (StatusCode, String)> {
+    service
+        .flags_in_force(Timestamp::now())
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Handler for setting a feature flag, published in the directory at once
+///
+/// Flags switch features off network-wide, so only operators may set them.
+async fn set_flag(
+    Path(name): Path<String>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
+    Json(request): Json<SetFlagRequest>,
+) -> Result<Json<Flag>, (StatusCode, String)> {
+    match service
+        .set_flag(&name, request.value, Duration::from_secs(request.expires_in_secs))
+        .await
+    {
+        Ok(flag) => Ok(Json(flag)),
+        Err(e) if e.is::<FlagRefused>() => Err((StatusCode::UNPROCESSABLE_ENTITY, e.to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// Handler for clearing a feature flag, the feature going back to each node's config
+async fn clear_flag(
+    Path(name): Path<String>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match service.clear_flag(&name).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((StatusCode::NOT_FOUND, format!("Flag {} is not set", name))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// Handler for the current epoch
+async fn current_epoch(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Json<Epoch> {
+    Json(service.current_epoch())
+}
+
+/// Handler for the dashboard overview
+async fn dashboard_overview(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Json<Overview> {
+    Json(service.dashboard_overview())
+}
+
+/// Handler for the dashboard's partition warnings
+async fn dashboard_partitions(
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Json<Vec<PartitionWarning>> {
+    Json(service.dashboard_partitions())
+}
+
+/// Handler for the dashboard time series
+async fn dashboard_timeseries(
+    Query(query): Query<TimeseriesQuery>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Json<TimeseriesResponse> {
+    let buckets = service.dashboard_timeseries(query.metric, Duration::from_secs(query.window));
+    Json(TimeseriesResponse {
+        metric: query.metric,
+        buckets,
+    })
+}
+
+/// Handler for the requests per region published to the public, noised
+async fn region_stats(
+    Query(query): Query<StatsQuery>,
+    Extension(service): Extension<Arc<CoordinatorService>>,
+) -> Json<BTreeMap<String, Vec<Bucket>>> {
+    Json(service.public_region_stats(Duration::from_secs(query.window)))
+}
+
+/// Handler for creating a plan
+async fn create_plan(
+    Extension(user_manager): Extension<Arc<dyn UserManager + Send + Sync>>,
+    Json(request): Json<CreatePlanRequest>,
+) -> Result<Json<CreatePlanResponse>, StatusCode> {
+    let plan = Plan {
+        id: Uuid::new_v4(),
+        name: request.name,
+        daily_request_cap: request.daily_request_cap,
+        max_circuits: request.max_circuits,
+        max_subscriptions: request.max_subscriptions,
+        priority_class: request.priority_class,
+        max_mappings: request.max_mappings,
+        max_quorum: request.max_quorum,
+        pricing: request.pricing,
+    };
+
+    match user_manager.create_plan(plan.clone()).await {
+        Ok(_) => Ok(Json(CreatePlanResponse {
+            plan: Some(plan),
+            error: None,
+        })),
+        Err(e) => Ok(Json(CreatePlanResponse {
+            plan: None,
+            error: Some(e.to_string()),
+        })),
+    }
+}
+
+/// Handler for assigning a plan to a user
+async fn set_user_plan(
+    Path(user_id): Path<Uuid>,
+    Extension(user_manager): Extension<Arc<dyn UserManager + Send + Sync>>,
+    Json(request): Json<SetUserPlanRequest>,
+) -> Result<Json<SetUserPlanResponse>, StatusCode> {
+    match user_manager.set_user_plan(user_id, request.plan_id).await {
+        Ok(_) => Ok(Json(SetUserPlanResponse {
+            success: true,
+            error: None,
+        })),
+        Err(e) => Ok(Json(SetUserPlanResponse {
+            success: false,
+            error: Some(e.to_string()),
+        })),
+    }
+}
+
+/// The user with `api_key` and their plan
+async fn user_and_plan(user_manager: &(dyn UserManager + Send + Sync), api_key: &str) -> Result<(User, Plan), StatusCode> {
+    let user = match user_manager.get_user_by_api_key(api_key).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err(StatusCode::UNAUTHORIZED),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let plan = match user.plan_id {
+        Some(plan_id) => match user_manager.get_plan(plan_id).await {
+            Ok(Some(plan)) => plan,
+            _ => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        },
+        None => Plan::default(),
+    };
+    Ok((user, plan))
+}
+
+/// Handler for provisioning a user's mappings in bulk, all or none, with the account's own API key
+async fn import_mappings(
+    UserApiKey(api_key): UserApiKey,
+    Query(query): Query<MappingsQuery>,
+    Extension(user_manager): Extension<Arc<dyn UserManager + Send + Sync>>,
+    Extension(config): Extension<Arc<ProvisioningConfig>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, Json<ImportMappingsResponse>) {
+    let (user, plan) = match key_owner(&*user_manager, &api_key).await {
+        Ok(found) => found,
+        Err((status, e)) => return (status, ImportMappingsResponse::failed(e, Vec::new())),
+    };
+    let format = query.format.unwrap_or_else(|| {
+        let content_type = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+        MappingFormat::of_content_type(content_type.unwrap_or_default())
+    });
+    
+    let existing = match user_manager.get_rpc_mappings(user.id).await {
+        Ok(existing) => existing,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, ImportMappingsResponse::failed(e, Vec::new())),
+    };
+    let provisioned = provisioning::parse(format, &body).and_then(|rows| {
+        provisioning::provision(&rows, &existing, &plan, &config, Timestamp::now())
+    });
+    let mappings = match provisioned {
+        Ok(mappings) => mappings,
+        Err(e) => {
+            let status = match &e {
+                ImportError::Malformed(_) | ImportError::TooManyRows { .. } => StatusCode::BAD_REQUEST,
+                ImportError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                ImportError::Quota(_) => StatusCode::TOO_MANY_REQUESTS,
+            };
+            let rows = e.rows().to_vec();
+            return (status, ImportMappingsResponse::failed(e, rows));
+        }
+    };
+    
+    let imported = mappings
+        .iter()
+        .enumerate()
+        .map(|(i, mapping)| ImportedMapping {
+            row: i + 1,
+            id: mapping.id,
+            original_rpc: mapping.original_rpc.clone(),
+            darknode_https_rpc: mapping.darknode_https_rpc.clone(),
+            darknode_wss_rpc: mapping.darknode_wss_rpc.clone(),
+        })
+        .collect();
+    match user_manager.add_rpc_mappings(user.id, mappings, &plan).await {
+        Ok(()) => (
+            StatusCode::CREATED,
+            Json(ImportMappingsResponse {
+                mappings: imported,
+                error: None,
+                invalid_rows: Vec::new(),
+            }),
+        ),
+        Err(e) => {
+            let status = if e.downcast_ref::<QuotaExceeded>().is_some() {
+                StatusCode::TOO_MANY_REQUESTS
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            (status, ImportMappingsResponse::failed(e, Vec::new()))
+        }
+    }
+}
+
+/// Handler for exporting a user's mappings in the format they are imported in, with the
+/// account's own API key
+async fn export_mappings(
+    UserApiKey(api_key): UserApiKey,
+    Query(query): Query<MappingsQuery>,
+    Extension(user_manager): Extension<Arc<dyn UserManager + Send + Sync>>,
+) -> Result<([(header::HeaderName, &'static str); 1], Vec<u8>), (StatusCode, String)> {
+    let (user, _) = key_owner(&*user_manager, &api_key).await?;
+    let mappings = user_manager
+        .get_rpc_mappings(user.id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let format = query.format.unwrap_or_default();
+    Ok((
+        [(header::CONTENT_TYPE, format.content_type())],
+        provisioning::export(&mappings, format),
+    ))
+}
+
+/// Handler for setting which of a user's mappings a mapping's requests go to by method
+///
+/// Rules move the user's traffic between their mappings, so they are set with the
+/// account's own API key only.
+async fn set_method_routes(
+    Path(mapping_id): Path<Uuid>,
+    UserApiKey(api_key): UserApiKey,
+    Extension(user_manager): Extension<Arc<dyn UserManager + Send + Sync>>,
+    Json(routes): Json<MethodRoutes>,
+) -> Result<Json<MethodRoutes>, (StatusCode, String)> {
+    let (user, _) = key_owner(&*user_manager, &api_key).await?;
+    if !user.rpc_mappings.iter().any(|mapping| mapping.id == mapping_id) {
+        return Err((StatusCode::NOT_FOUND, format!("Unknown mapping {}", mapping_id)));
+    }
+    match user_manager.set_method_routes(user.id, mapping_id, routes.clone()).await {
+        Ok(()) => Ok(Json(routes)),
+        Err(e) if e.is::<InvalidRoutes>() => Err((StatusCode::UNPROCESSABLE_ENTITY, e.to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// Handler for removing one of a user's mappings, and the routing rules sending requests to it
+async fn remove_mapping(
+    Path(mapping_id): Path<Uuid>,
+    UserApiKey(api_key): UserApiKey,
+    Extension(user_manager): Extension<Arc<dyn UserManager + Send + Sync>>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let (user, _) = key_owner(&*user_manager, &api_key).await?;
+    if !user.rpc_mappings.iter().any(|mapping| mapping.id == mapping_id) {
+        return Err((StatusCode::NOT_FOUND, format!("Unknown mapping {}", mapping_id)));
+    }
+    user_manager
+        .remove_rpc_mapping(user.id, mapping_id)
+        .await
+        .map(|()| StatusCode::NO_CONTENT)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// The user whose own API key `api_key` is, and their plan, their further keys being refused
+///
+/// Further keys are scoped for requests only, so every route managing the account takes
+/// the account's own key.
+async fn key_owner(user_manager: &(dyn UserManager + Send + Sync), api_key: &str) -> Result<(User, Plan), (StatusCode, String)> {
+    let (user, plan) = user_and_plan(user_manager, api_key)
+        .await
+        .map_err(|status| (status, status.to_string()))?;
+    if user.api_key != api_key {
+        return Err((StatusCode::FORBIDDEN, "The account is managed with its own API key".to_string()));
+    }
+    Ok((user, plan))
+}
+
+/// Handler for creating a further API key for a user
+async fn create_key(
+    UserApiKey(api_key): UserApiKey,
+    Extension(user_manager): Extension<Arc<dyn UserManager + Send + Sync>>,
+    Json(request): Json<CreateKeyRequest>,
+) -> Result<(StatusCode, Json<ApiKey>), (StatusCode, String)> {
+    let (user, _) = key_owner(&*user_manager, &api_key).await?;
+    match user_manager.create_key(user.id, &request.name, request.scopes).await {
+        Ok(key) => Ok((StatusCode::CREATED, Json(key))),
+        Err(e) if e.is::<ScopeError>() => Err((StatusCode::UNPROCESSABLE_ENTITY, e.to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// Handler for replacing the scopes of one of a user's further API keys
+async fn set_key_scopes(
+    Path(key_id): Path<Uuid>,
+    UserApiKey(api_key): UserApiKey,
+    Extension(user_manager): Extension<Arc<dyn UserManager + Send + Sync>>,
+    Json(request): Json<SetKeyScopesRequest>,
+) -> Result<Json<ApiKey>, (StatusCode, String)> {
+    let (user, _) = key_owner(&*user_manager, &api_key).await?;
+    if !user.keys.iter().any(|key| key.id == key_id) {
+        return Err((StatusCode::NOT_FOUND, format!("Unknown key {}", key_id)));
+    }
+    match user_manager.set_key_scopes(user.id, key_id, request.scopes).await {
+        Ok(key) => Ok(Json(key)),
+        Err(e) if e.is::<ScopeError>() => Err((StatusCode::UNPROCESSABLE_ENTITY, e.to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// Handler for registering a webhook
+async fn create_webhook(
+    Extension(webhooks): Extension<Arc<Webhooks>>,
+    Json(spec): Json<WebhookSpec>,
+) -> Result<(StatusCode, Json<CreatedWebhook>), (StatusCode, String)> {
+    match webhooks.create(spec) {
+        Ok(created) => Ok((StatusCode::CREATED, Json(created))),
+        Err(e) => Err((StatusCode::BAD_REQUEST, e.to_string())),
+    }
+}
+
+/// Handler for listing webhooks, without their secrets
+async fn list_webhooks(Extension(webhooks): Extension<Arc<Webhooks>>) -> Json<Vec<Webhook>> {
+    Json(webhooks.list())
+}
+
+/// Handler for sending a test delivery to a webhook
+async fn test_webhook(
+    Path(id): Path<Uuid>,
+    Extension(webhooks): Extension<Arc<Webhooks>>,
+) -> Result<Json<DeliveryReport>, StatusCode> {
+    webhooks.test(id).await.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Handler for webhook deliveries given up on
+async fn webhook_dead_letters(Extension(webhooks): Extension<Arc<Webhooks>>) -> Json<Vec<DeadLetter>> {
+    Json(webhooks.dead_letters())
+}
+
+/// Handler for closing a billing period into invoices
+async fn close_billing_period(
+    Extension(billing): Extension<Arc<Billing>>,
+    Json(request): Json<ClosePeriodRequest>,
+) -> Result<Json<Vec<Invoice>>, (StatusCode, String)> {
+    match billing.close_period(request.start, request.end, Timestamp::now()).await {
+        Ok(invoices) => Ok(Json(invoices)),
+        Err(e) => {
+            let status = match e.downcast_ref::<PeriodRefused>() {
+                Some(PeriodRefused::Disabled) => StatusCode::NOT_FOUND,
+                Some(PeriodRefused::NotWholeDays | PeriodRefused::NotOver) => StatusCode::BAD_REQUEST,
+                Some(PeriodRefused::Overlaps { .. }) => StatusCode::CONFLICT,
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Err((status, e.to_string()))
+        }
+    }
+}
+
+/// Handler for listing a user's invoices
+async fn list_invoices(
+    Query(query): Query<InvoicesQuery>,
+    Extension(billing): Extension<Arc<Billing>>,
+) -> Result<Json<Vec<Invoice>>, (StatusCode, String)> {
+    billing
+        .invoices_for(query.user)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Handler for paying an invoice with a Solana transaction, verified on chain
+async fn pay_invoice(
+    Path(invoice_id): Path<Uuid>,
+    Extension(billing): Extension<Arc<Billing>>,
+    Json(request): Json<PayInvoiceRequest>,
+) -> Result<Json<Invoice>, (StatusCode, String)> {
+    match billing.pay(invoice_id, &request.signature, Timestamp::now()).await {
+        Ok(Some(invoice)) => Ok(Json(invoice)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("Unknown invoice {}", invoice_id))),
+        Err(e) => {
+            let status = match e.downcast_ref::<PaymentRejected>() {
+                Some(PaymentRejected::Disabled) => StatusCode::NOT_FOUND,
+                Some(PaymentRejected::AlreadyPaid | PaymentRejected::AlreadyUsed) => StatusCode::CONFLICT,
+                Some(PaymentRejected::Unavailable(_)) => StatusCode::BAD_GATEWAY,
+                Some(_) => StatusCode::PAYMENT_REQUIRED,
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Err((status, e.to_string()))
+        }
+    }
+}
+
+/// Handler for the results of recent canary requests
+#[cfg(feature = "canary")]
+async fn canary_status(Extension(runner): Extension<Arc<CanaryRunner>>) -> Json<CanaryStatus> {
+    Json(runner.status())
+}
+
+/// Handler for Prometheus scrapes
+async fn prometheus_metrics(Extension(handle): Extension<PrometheusHandle>) -> String {
+    handle.render()
+}
+
 /// Handler for health checks
 async fn health_check() -> &'static str {
     "OK"
 }
 
+/// Handler for the build of this binary
+async fn version() -> Json<BuildInfo> {
+    Json(BuildInfo::current())
+}
+
+/// Get the value following a `--flag` argument
+fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
+/// Register the demo providers if there are none yet, as on a first start
+async fn register_demo_providers(rpc_manager: &(dyn RpcManager + Send + Sync)) -> Result<()> {
+    if !rpc_manager.get_providers().await?.is_empty() {
+        return Ok(());
+    }
+    
+    // Add some mock RPC providers
+    rpc_manager.register_provider(RpcProvider {
+        id: Uuid::new_v4(),
+        url: "https://api.mainnet-beta.solana.com".to_string(),
+        provider_type: "solana".to_string(),
+        state: ProviderState::Active,
+        success_rate: 0.99,
+        avg_latency: Duration::from_millis(100),
+        last_checked: Timestamp::now(),
+        capabilities: vec!["archive".to_string(), "transaction_history".to_string()],
+        pool: None,
+        network: Network::Mainnet,
+        auth: None,
+        weight: 1,
+        maintenance_windows: Vec::new(),
+        tripped_breakers: 0,
+        failed_probes: 0,
+        quota: None,
+        submission: None,
+    }).await?;
+    
+    rpc_manager.register_provider(RpcProvider {
+        id: Uuid::new_v4(),
+        url: "https://solana-api.projectserum.com".to_string(),
+        provider_type: "solana".to_string(),
+        state: ProviderState::Active,
+        success_rate: 0.98,
+        avg_latency: Duration::from_millis(120),
+        last_checked: Timestamp::now(),
+        capabilities: vec!["program_accounts_filters".to_string()],
+        pool: None,
+        network: Network::Mainnet,
+        auth: None,
+        weight: 1,
+        maintenance_windows: Vec::new(),
+        tripped_breakers: 0,
+        failed_probes: 0,
+        quota: None,
+        submission: None,
+    }).await?;
+    
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Load configuration, refusing to start on an invalid seed file, and only printing it
+    // when asked to check it
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let seed_file = flag_value(&args, "--seed").map(PathBuf::from);
+    let reseed = args.iter().any(|arg| arg == "--reseed");
+    let mut config = DarknodeConfig::from_args(&args)?;
+    if let Some(path) = &seed_file {
+        config.coordinator.bootstrap = BootstrapConfig::load(path)?;
+    }
+    if config::check_requested(&args) {
+        print!("{}", config.render(&["common", "coordinator"])?);
+        return Ok(());
+    }
+    
     // Initialize tracing
-    tracing_subscriber::registry()
-        .with(filter::LevelFilter::from_level(Level::INFO))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    telemetry::init(&config.common.telemetry, "darknode-coordinator", Level::INFO)?;
     
-    // Load configuration
-    let config = Config {
-        listen_addr: "127.0.0.1:3001".parse()?,
-        region: "us-east".to_string(),
-    };
+    BuildInfo::current().log_banner("coordinator");
+    info!("Starting coordinator node in region {}", config.common.region);
     
-    info!("Starting coordinator node in region {}", config.region);
+    // Export metrics, including the traffic totals pushed in heartbeats, for Prometheus
+    let prometheus = traffic::install_prometheus()?;
     
     // Create dependencies
-    let node_manager: Arc<dyn NodeManager + Send + Sync> = Arc::new(MockNodeManager::new());
-    let rpc_manager: Arc<dyn RpcManager + Send + Sync> = Arc::new(MockRpcManager::new());
+    let storage = storage::open(&config.common.storage).await?;
+    let node_manager: Arc<dyn NodeManager + Send + Sync> = Arc::new(StoredNodeManager::new(storage.clone()));
+    let rpc_manager: Arc<dyn RpcManager + Send + Sync> = Arc::new(StoredRpcManager::new(storage.clone()));
+    let user_manager = StoredUserManager::new(storage.clone());
+    let migrated = user_manager.migrate_wallets().await?;
+    if migrated > 0 {
+        tracing::info!("Normalized the wallets of {} users created before they were validated", migrated);
+    }
+    let user_manager: Arc<dyn UserManager + Send + Sync> = Arc::new(user_manager);
+    let seeds_providers = seed_file.is_some() || !config.coordinator.bootstrap.providers.is_empty();
+    if !seeds_providers {
+        register_demo_providers(rpc_manager.as_ref()).await?;
+    }
+    
+    // Sign directories with a key of the coordinator's own, which nodes are configured with,
+    // so it is kept across restarts; it is never rotated, so there's no previous key to keep
+    let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+    let Some(identity_file) = config.common.identity_file.as_deref() else {
+        bail!("common.identity_file must be set, or the directory key nodes trust changes on every restart");
+    };
+    let identity = Arc::new(NodeIdentity::load_or_generate(&*crypto, Some(identity_file), Duration::ZERO).await?);
+    let directory = DirectoryPublisher::new(
+        config.common.directory.clone(),
+        config.common.epochs.length,
+        crypto.clone(),
+        identity,
+    );
+    info!("Signing directories with key {}", directory.signer(Timestamp::now()));
     
     // Create the coordinator service
     let service = Arc::new(CoordinatorService::new(
         node_manager.clone(),
         rpc_manager.clone(),
+        crypto.clone(),
+        config.coordinator.dashboard.clone(),
+        config.coordinator.probe.clone(),
+        config.common.epochs.clone(),
+        config.common.accounting.clone(),
+    )
+    .with_recommend(config.coordinator.recommend.clone())
+    .with_latency(config.common.latency.clone())
+    .with_submissions(config.coordinator.submissions.clone())
+    .with_reachability(config.common.reachability.clone())
+    .with_directory(directory)
+    .with_directory_watch(config.common.directory_watch.clone())
+    .with_flags(FlagBoard::new(config.coordinator.flags.clone(), storage.clone()))
+    .with_noise(NoiseLayer::open(config.coordinator.privacy.clone(), storage.clone()).await?));
+    
+    // Seed providers and the node allowlist before anything reads them
+    let seeded = bootstrap::seed(&config.coordinator.bootstrap, &*rpc_manager, &service.allowlist(), reseed).await?;
+    info!(
+        "Seeded {} new providers, updated {}, authorized {} node keys",
+        seeded.providers_added, seeded.providers_updated, seeded.nodes_authorized
+    );
+    
+    // Probe each RPC provider on its own schedule
+    tokio::spawn(service.probes().run());
+    
+    // Invoice the usage entry nodes meter, if billing is enabled
+    let billing = Arc::new(Billing::new(config.common.billing.clone(), storage, user_manager.clone()));
+    
+    // Notify operators' webhooks of critical events
+    let webhooks = Arc::new(Webhooks::new(config.coordinator.webhooks.clone()));
+    tokio::spawn(webhooks.clone().run(service.events()));
+    
+    // Send canary requests through the registered entry nodes
+    #[cfg(feature = "canary")]
+    let canary = config.coordinator.canary.clone().map(|canary| {
+        let entries = match canary.entry_urls.is_empty() {
+            true => EntrySource::Directory(node_manager.clone()),
+            false => EntrySource::Static(canary.entry_urls.clone()),
+        };
+        let runner = Arc::new(CanaryRunner::new(canary, entries).with_events(service.events()));
+        tokio::spawn(runner.clone().run());
+        runner
+    });
+    
+    // Reports nodes post about themselves must be signed by them
+    let report_verifier = Arc::new(ReportVerifier::new(
+        config.coordinator.report_auth.clone(),
+        node_manager.clone(),
+        crypto.clone(),
     ));
     
+    // Routes for operators only. The dashboard's raw counts are among them; the public gets
+    // them noised on /stats
+    let admin = Router::new()
+        .route("/dashboard/overview", get(dashboard_overview))
+        .route("/dashboard/timeseries", get(dashboard_timeseries))
+        .route("/dashboard/partitions", get(dashboard_partitions))
+        .route("/webhooks", post(create_webhook).get(list_webhooks))
+        .route("/webhooks/dead-letters", get(webhook_dead_letters))
+        .route("/webhooks/:id/test", post(test_webhook))
+        .route(
+            "/providers/:id/maintenance",
+            get(maintenance_windows)
+                .post(add_maintenance_window)
+                .delete(clear_maintenance_windows),
+        )
+        .route("/providers/:id/maintenance/window", delete(remove_maintenance_window))
+        .route("/providers/submissions", get(provider_submissions))
+        .route("/providers/:id/review", post(review_provider))
+        .route("/billing/close-period", post(close_billing_period))
+        .route("/billing/invoices", get(list_invoices))
+        .route("/billing/invoices/:id/pay", post(pay_invoice))
+        .route("/flags", get(list_flags))
+        .route("/flags/:name", put(set_flag).delete(clear_flag))
+        .route_layer(axum::middleware::from_fn(operator::require_operator));
+    
     // Create the router
     let app = Router::new()
-        .route("/nodes", post(register_node))
+        .route("/nodes/heartbeat", post(record_heartbeat))
+        .route("/nodes/next-key", post(publish_next_key))
+        .route_layer(axum::middleware::from_fn(report_auth::require_signed_report))
+        .route(REGISTER_NODE_PATH, post(register_node))
         .route("/nodes/status", post(update_node_status))
         .route("/nodes/available/:role", get(get_available_nodes))
+        .route("/nodes/versions", get(version_report))
+        .route("/nodes/draining", get(draining_nodes))
+        .route("/epoch", get(current_epoch))
+        .route("/directory", get(get_directory))
+        .route("/directory/watch", get(watch_directory))
+        .route("/directory/changes", get(directory_changes))
+        .route(attribution::KEYS_PATH, get(attestation_keys))
+        .route("/accounting/receipts", post(record_receipt))
+        .route("/accounting/epochs/:epoch", get(epoch_accounts))
         .route("/providers", post(register_provider))
+        .route("/providers/:id", delete(remove_provider))
         .route("/providers/status", post(update_provider_status))
+        .route("/providers/submit", post(submit_provider))
         .route("/providers/active", get(get_active_providers))
         .route("/providers/best", get(get_best_provider))
         .route("/topology/update", post(update_topology))
+        .route("/topology/recommend", post(recommend_paths))
+        .route("/topology/latency", get(latency_matrix))
+        .route("/topology/whatif", post(what_if))
         .route("/rpc/health", post(check_rpc_health))
+        .route("/plans", post(create_plan))
+        .route("/users/:id/plan", patch(set_user_plan))
+        .route("/mappings/import", post(import_mappings))
+        .route("/mappings/export", get(export_mappings))
+        .route("/mappings/:id", delete(remove_mapping))
+        .route("/mappings/:id/routes", put(set_method_routes))
+        .route("/keys", post(create_key))
+        .route("/keys/:id", patch(set_key_scopes))
+        .route("/stats/regions", get(region_stats))
+        .route("/metrics", get(prometheus_metrics))
         .route("/health", get(health_check))
-        .layer(TraceLayer::new_for_http())
+        .route("/version", get(version))
+        .merge(admin)
+        .layer(axum::middleware::from_fn(clock::write_timestamps))
+        .layer(TraceLayer::new_for_http().make_span_with(HttpSpans::client_facing()))
+        .layer(Extension(prometheus))
+        .layer(Extension(report_verifier))
         .layer(Extension(node_manager))
         .layer(Extension(rpc_manager))
+        .layer(Extension(user_manager))
+        .layer(Extension(webhooks))
+        .layer(Extension(billing))
+        .layer(Extension(Arc::new(config.coordinator.provisioning.clone())))
+        .layer(Extension(Arc::new(config.common.operator.clone())))
+        .layer(Extension(config.common.timestamps))
         .layer(Extension(service));
     
+    #[cfg(feature = "canary")]
+    let app = match canary {
+        Some(runner) => app
+            .route("/canary/status", get(canary_status))
+            .layer(Extension(runner)),
+        None => app,
+    };
+    
     // Start the server
-    info!("Listening on {}", config.listen_addr);
-    axum::Server::bind(&config.listen_addr)
+    info!("Listening on {}", config.coordinator.listen_addr);
+    axum::Server::bind(&config.coordinator.listen_addr)
         .serve(app.into_make_service())
         .await?;
     
@@ -7,6 +7,14 @@
 //! 3. Creating and managing circuits through the network
 //! 4. Encrypting requests for the circuit
 //! 5. Decrypting responses from the circuit
+//!
+//! Usage:
+//!   entry-node [--config <file>] [--check-config] [--dev-verbose-logging]
+//!
+//! `--config` reads the `[common]` and `[entry]` sections of a config file, and
+//! `--check-config` only validates and prints them (see `darknode_backend::config`).
+//! `--dev-verbose-logging` logs full request and response bodies at debug level, for local
+//! development. It is refused unless the binary was built with the `dev-logging` feature.
 
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -14,34 +22,100 @@ use std::time::Duration;
 
 use anyhow::Result;
 use axum::{
-    extract::{Extension, Path},
-    http::StatusCode,
+    body::StreamBody,
+    error_handling::HandleErrorLayer,
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Extension, FromRequest, Path, Query,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Json, Router,
 };
+use base64::Engine;
 use darknode_backend::{
-    entry_node::EntryNodeService,
-    impls::CryptoImpl,
-    traits::{Crypto, NodeManager, RequestSanitizer, Router as RouterTrait, UserManager},
-    types::{NodeId, NodeRole, NodeStatus},
+    accounting,
+    api_key::UserApiKey,
+    admission::{AdmissionState, Overloaded},
+    anonymity::{self, ClientTraces},
+    billing::UsageMeter,
+    build_info::BuildInfo,
+    cache_hints::{self, CacheHintConfig},
+    capabilities::CapabilityError,
+    chains::ChainError,
+    circuit_class::CircuitClass,
+    circuit_info::{CircuitInfo, RotationThrottled},
+    clock::{self, Timestamp},
+    compliance::{AuditingDisabled, UsageAudit, UsageRecord},
+    compression,
+    config::{self, DarknodeConfig},
+    context::{InvalidContextHeader, RequestContext},
+    diagnostics::{CircuitBuildReport, CircuitUnavailable},
+    directory::{self, DirectoryFollower},
+    flags::FeatureFlags,
+    directory_watch,
+    drain,
+    fallback::{self, DirectProxy},
+    heartbeat::{self, HeartbeatSource},
+    idempotency::{self, IdempotencyError, IdempotencyStore, StoredResponse},
+    identity::{KeyRotator, NodeIdentity, RotationOutcome},
+    journal::{RequestJournal, RequestStatus},
+    entry_node::{is_streamable, EntryNodeConfig, EntryNodeService},
+    hop_auth::HopSigner,
+    impls::{CryptoImpl, RouterImpl, StoredNodeManager, StoredUserManager},
+    methods::{self, EXTENSION_KEY},
+    operator,
+    outbox::Outbox,
+    pipelining::{self, PipelineConfig, ResponseOrder, ORDERED_HEADER},
+    reachability::{self, ReachabilityMatrix},
+    quota::{CircuitCapacityExhausted, QuotaExceeded},
+    receipts::ServiceReceipt,
+    recommend::PathAdvisor,
+    regions::{self, LatencyMatrix},
+    relay,
+    replay::{self, HopFailureKind},
+    report_auth::ReportSigner,
+    resources::{ProcSampler, ResourceGuard},
+    sanitizer::Sanitizer,
+    schema::InvalidParams,
+    scopes::ScopeError,
+    sessions::SessionExpired,
+    shadow::ShadowReport,
+    signing::SignatureRejected,
+    storage,
+    telemetry::{self, HttpSpans},
+    timeouts::TimedOut,
+    timing::{ServerTiming, SERVER_TIMING_HEADER},
+    traffic,
+    traits::{Crypto, NodeManager, RequestSanitizer, ResponseStream, Router as RouterTrait, UserManager},
+    transport::HopClient,
+    types::{NodeId, NodeRole},
 };
+#[cfg(feature = "dev-logging")]
+use darknode_backend::dev_logging;
+use futures::StreamExt;
+use metrics_exporter_prometheus::PrometheusHandle;
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
-use tower_http::trace::TraceLayer;
+use tower::ServiceBuilder;
+use tower_http::{decompression::RequestDecompressionLayer, trace::TraceLayer};
 use tracing::{info, Level};
-use tracing_subscriber::{filter, prelude::*};
 use uuid::Uuid;
 
-/// Configuration for the entry node
-#[derive(Debug, Clone, Deserialize)]
-struct Config {
-    /// The address to listen on
-    listen_addr: SocketAddr,
-    /// The region this node is in
-    region: String,
-    /// The coordinator node to register with
-    coordinator_url: String,
-}
+/// How long a replaced key keeps decrypting traffic for circuits built before rotation
+const KEY_RETENTION: Duration = Duration::from_secs(3600);
+
+/// How often disconnected WebSocket sessions are checked for expiry
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// WebSocket close code sent when a session can't be resumed
+const SESSION_EXPIRED_CLOSE_CODE: u16 = 4001;
+
+/// How often the coordinator is asked for the current directory
+const DIRECTORY_POLL_INTERVAL: Duration = Duration::from_secs(60);
 
 /// Request body for RPC requests
 #[derive(Debug, Clone, Deserialize)]
@@ -52,8 +126,192 @@ struct RpcRequest {
     method: String,
     /// The parameters for the RPC method
     params: Vec<serde_json::Value>,
-    /// The JSON-RPC ID
-    id: serde_json::Value,
+    /// The JSON-RPC ID; absent for notifications, which get no response
+    #[serde(default, deserialize_with = "present")]
+    id: Option<serde_json::Value>,
+    /// The user's RPC mapping the request was sent to, if known
+    #[serde(default)]
+    mapping_id: Option<Uuid>,
+    /// DarkNode extension fields, such as provider hints or the relay flag
+    #[serde(default)]
+    darknode: Option<serde_json::Value>,
+}
+
+impl RpcRequest {
+    /// The ID the response carries, `null` for notifications
+    fn response_id(&self) -> serde_json::Value {
+        self.id.clone().unwrap_or_default()
+    }
+
+    /// The request as forwarded to the entry node service
+    fn to_value(&self) -> serde_json::Value {
+        let mut request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": self.method,
+            "params": self.params,
+        });
+        if let Some(id) = &self.id {
+            request["id"] = id.clone();
+        }
+        if let Some(extension) = &self.darknode {
+            request[EXTENSION_KEY] = extension.clone();
+        }
+        request
+    }
+}
+
+/// Deserialize a field that is present, so that an explicit `null` isn't taken for absence
+fn present<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<serde_json::Value>, D::Error> {
+    serde_json::Value::deserialize(deserializer).map(Some)
+}
+
+/// Request body of the RPC endpoint, a single request or a batch
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum RpcBody {
+    /// A single request
+    Single(RpcRequest),
+    /// A batch of requests, answered together, each parsed on its own
+    Batch(Vec<serde_json::Value>),
+}
+
+/// An element of a batch
+enum Batched {
+    /// A request and the context it is served with
+    Call(RpcRequest, RequestContext),
+    /// An element that isn't a valid request, and the error it is answered with
+    Invalid(RpcResponse),
+}
+
+/// RPC requests and the contexts they are served with, built from their headers and bodies
+enum RpcCall {
+    /// A single request
+    Single(RpcRequest, RequestContext),
+    /// A batch of requests; an invalid element is answered with an error of its own
+    Batch(Vec<Batched>),
+}
+
+impl RpcCall {
+    /// The ID an error about the whole call is answered with
+    fn response_id(&self) -> serde_json::Value {
+        match self {
+            RpcCall::Single(request, _) => request.response_id(),
+            RpcCall::Batch(_) => serde_json::Value::Null,
+        }
+    }
+
+    /// Whether the call is answered with a stream rather than a complete response
+    fn streamed(&self) -> bool {
+        match self {
+            RpcCall::Single(request, _) if request.id.is_some() => {
+                is_streamable(&request.method, &request.params) || relay::requested(&request.to_value())
+            }
+            _ => false,
+        }
+    }
+
+    /// The requests of the call
+    fn requests(&self) -> Vec<&RpcRequest> {
+        match self {
+            RpcCall::Single(request, _) => vec![request],
+            RpcCall::Batch(calls) => calls
+                .iter()
+                .filter_map(|call| match call {
+                    Batched::Call(request, _) => Some(request),
+                    Batched::Invalid(_) => None,
+                })
+                .collect(),
+        }
+    }
+
+    /// The users' API keys an idempotency key of the call is scoped to
+    fn idempotency_scope(&self) -> String {
+        let mut api_keys: Vec<&str> = self.requests().iter().map(|request| request.api_key.as_str()).collect();
+        api_keys.sort_unstable();
+        api_keys.dedup();
+        api_keys.join("\n")
+    }
+
+    /// Hash of everything the call asks for, telling a duplicate from a different call
+    fn fingerprint(&self) -> [u8; 32] {
+        let requests: Vec<serde_json::Value> = self
+            .requests()
+            .iter()
+            .map(|request| serde_json::json!([request.api_key, request.mapping_id, request.to_value()]))
+            .collect();
+        idempotency::fingerprint(&serde_json::Value::Array(requests))
+    }
+}
+
+#[async_trait::async_trait]
+impl<S, B> FromRequest<S, B> for RpcCall
+where
+    Json<RpcBody>: FromRequest<S, B>,
+    S: Send + Sync,
+    B: Send + 'static,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: axum::http::Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let headers = req.headers().clone();
+        let traces = req.extensions().get::<ClientTraces>().cloned().unwrap_or_default();
+        let Json(body) = Json::<RpcBody>::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        // The request is taken from the body alone; the connection's traces go with it only
+        // to look over what the node adds
+        let with_context = |request: RpcRequest| {
+            RequestContext::new(request.api_key.clone())
+                .with_client_traces(traces.clone())
+                .with_mapping(request.mapping_id)
+                .with_headers(&headers)
+                .map(|ctx| (request, ctx))
+        };
+        match body {
+            RpcBody::Single(request) => {
+                let id = request.response_id();
+                let (request, ctx) = with_context(request).map_err(|e| rpc_failure(id, e.into()))?;
+                Ok(RpcCall::Single(request, ctx))
+            }
+            // An empty batch is an invalid request, answered with a single error
+            RpcBody::Batch(requests) if requests.is_empty() => Err((
+                StatusCode::BAD_REQUEST,
+                Json(RpcResponse {
+                    id: serde_json::Value::Null,
+                    result: None,
+                    error: Some(serde_json::json!({
+                        "code": -32600,
+                        "message": "Empty batch",
+                    })),
+                    darknode: None,
+                }),
+            )
+                .into_response()),
+            RpcBody::Batch(requests) => Ok(RpcCall::Batch(
+                requests
+                    .into_iter()
+                    .map(|request| {
+                        let id = request.get("id").cloned().unwrap_or_default();
+                        let Ok(request) = serde_json::from_value::<RpcRequest>(request) else {
+                            return Batched::Invalid(RpcResponse {
+                                id,
+                                result: None,
+                                error: Some(serde_json::json!({
+                                    "code": -32600,
+                                    "message": "Invalid request",
+                                })),
+                                darknode: None,
+                            });
+                        };
+                        match with_context(request) {
+                            Ok((request, ctx)) => Batched::Call(request, ctx),
+                            Err(e) => Batched::Invalid(rpc_error(id, e.into()).1 .0),
+                        }
+                    })
+                    .collect(),
+            )),
+        }
+    }
 }
 
 /// Response body for RPC requests
@@ -65,215 +323,520 @@ struct RpcResponse {
     result: Option<serde_json::Value>,
     /// The error, if any
     error: Option<serde_json::Value>,
+    /// DarkNode metadata about how the request was served, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    darknode: Option<serde_json::Value>,
 }
 
-/// Mock implementation of the NodeManager trait
-struct MockNodeManager {
-    nodes: Arc<RwLock<Vec<darknode_backend::types::Node>>>,
-}
+/// Error returned from the RPC handler
+type RpcError = (StatusCode, Json<RpcResponse>);
 
-impl MockNodeManager {
-    fn new() -> Self {
-        Self {
-            nodes: Arc::new(RwLock::new(Vec::new())),
-        }
+/// Map a service error to an HTTP status and JSON-RPC error body
+fn rpc_error(id: serde_json::Value, err: anyhow::Error) -> RpcError {
+    if let Some(quota) = err.downcast_ref::<QuotaExceeded>() {
+        let resets_at = quota
+            .resets_at
+            .map(|t| t.as_secs());
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(RpcResponse {
+                id,
+                result: None,
+                error: Some(serde_json::json!({
+                    "code": -32005,
+                    "message": quota.to_string(),
+                    "data": {
+                        "cap": quota.cap,
+                        "limit": quota.limit,
+                        "plan": quota.plan,
+                        "resets_at": resets_at,
+                    }
+                })),
+                darknode: None,
+            }),
+        );
     }
-}
 
-#[async_trait::async_trait]
-impl NodeManager for MockNodeManager {
-    async fn register_node(&self, node: darknode_backend::types::Node) -> Result<()> {
-        let mut nodes = self.nodes.write().await;
-        nodes.push(node);
-        Ok(())
+    if let Some(capability) = err.downcast_ref::<CapabilityError>() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(RpcResponse {
+                id,
+                result: None,
+                error: Some(serde_json::json!({
+                    "code": -32602,
+                    "message": capability.to_string(),
+                })),
+                darknode: None,
+            }),
+        );
     }
 
-    async fn update_node_status(&self, node_id: &NodeId, status: NodeStatus) -> Result<()> {
-        let mut nodes = self.nodes.write().await;
-        if let Some(node) = nodes.iter_mut().find(|n| n.id == *node_id) {
-            node.status = status;
-        }
-        Ok(())
+    if let Some(chain) = err.downcast_ref::<ChainError>() {
+        let data = match chain {
+            ChainError::Mismatch { pinned, detected } => serde_json::json!({
+                "pinned": pinned,
+                "detected": detected,
+            }),
+            ChainError::NoProvider(chain) => serde_json::json!({
+                "chain": chain,
+            }),
+            ChainError::NoNetworkProvider(network) => serde_json::json!({
+                "network": network,
+            }),
+        };
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(RpcResponse {
+                id,
+                result: None,
+                error: Some(serde_json::json!({
+                    "code": -32600,
+                    "message": chain.to_string(),
+                    "data": data,
+                })),
+                darknode: None,
+            }),
+        );
     }
 
-    async fn get_available_nodes(&self, role: NodeRole) -> Result<Vec<darknode_backend::types::Node>> {
-        let nodes = self.nodes.read().await;
-        Ok(nodes
-            .iter()
-            .filter(|n| n.role == role && n.status == NodeStatus::Online)
-            .cloned()
-            .collect())
+    if let Some(invalid) = err.downcast_ref::<InvalidContextHeader>() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(RpcResponse {
+                id,
+                result: None,
+                error: Some(serde_json::json!({
+                    "code": -32600,
+                    "message": invalid.to_string(),
+                    "data": {
+                        "header": invalid.header,
+                    }
+                })),
+                darknode: None,
+            }),
+        );
     }
 
-    async fn get_node(&self, node_id: &NodeId) -> Result<Option<darknode_backend::types::Node>> {
-        let nodes = self.nodes.read().await;
-        Ok(nodes.iter().find(|n| n.id == *node_id).cloned())
+    if let Some(idempotency) = err.downcast_ref::<IdempotencyError>() {
+        let status = match idempotency {
+            IdempotencyError::InvalidKey => StatusCode::BAD_REQUEST,
+            IdempotencyError::KeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+        };
+        return (
+            status,
+            Json(RpcResponse {
+                id,
+                result: None,
+                error: Some(serde_json::json!({
+                    "code": -32600,
+                    "message": idempotency.to_string(),
+                })),
+                darknode: None,
+            }),
+        );
     }
-}
 
-/// Mock implementation of the Router trait
-struct MockRouter {
-    crypto: Arc<dyn Crypto + Send + Sync>,
-}
-
-impl MockRouter {
-    fn new(crypto: Arc<dyn Crypto + Send + Sync>) -> Self {
-        Self { crypto }
+    if let Some(rejected) = err.downcast_ref::<SignatureRejected>() {
+        let status = match rejected {
+            SignatureRejected::Unrecorded => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::UNAUTHORIZED,
+        };
+        return (
+            status,
+            Json(RpcResponse {
+                id,
+                result: None,
+                error: Some(serde_json::json!({
+                    "code": -32001,
+                    "message": rejected.to_string(),
+                    "data": {
+                        "reason": rejected.label(),
+                    }
+                })),
+                darknode: None,
+            }),
+        );
     }
-}
 
-#[async_trait::async_trait]
-impl RouterTrait for MockRouter {
-    async fn create_circuit(&self) -> Result<darknode_backend::types::Circuit> {
-        // Create a mock circuit
-        let entry_node = NodeId(Uuid::new_v4());
-        let routing_nodes = vec![NodeId(Uuid::new_v4()), NodeId(Uuid::new_v4())];
-        let exit_node = NodeId(Uuid::new_v4());
-
-        // Generate mock symmetric keys
-        let mut symmetric_keys = Vec::new();
-        for _ in 0..routing_nodes.len() + 2 {
-            let (public_key, _) = self.crypto.generate_keypair().await?;
-            symmetric_keys.push(public_key);
-        }
+    if let Some(scope) = err.downcast_ref::<ScopeError>() {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(RpcResponse {
+                id,
+                result: None,
+                error: Some(serde_json::json!({
+                    "code": -32001,
+                    "message": scope.to_string(),
+                })),
+                darknode: None,
+            }),
+        );
+    }
 
-        Ok(darknode_backend::types::Circuit {
-            id: darknode_backend::types::CircuitId(Uuid::new_v4()),
-            entry_node,
-            routing_nodes,
-            exit_node,
-            symmetric_keys,
-            created_at: std::time::SystemTime::now(),
-            expires_at: std::time::SystemTime::now() + Duration::from_secs(3600),
-        })
+    if let Some(invalid) = err.downcast_ref::<InvalidParams>() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(RpcResponse {
+                id,
+                result: None,
+                error: Some(serde_json::json!({
+                    "code": -32602,
+                    "message": invalid.to_string(),
+                    "data": {
+                        "method": invalid.method,
+                        "pointer": invalid.pointer,
+                    }
+                })),
+                darknode: None,
+            }),
+        );
     }
 
-    async fn send_request(
-        &self,
-        _circuit: &darknode_backend::types::Circuit,
-        _request: &[u8],
-    ) -> Result<Uuid> {
-        // Generate a mock request ID
-        Ok(Uuid::new_v4())
+    if let Some(overloaded) = err.downcast_ref::<Overloaded>() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(RpcResponse {
+                id,
+                result: None,
+                error: Some(serde_json::json!({
+                    "code": -32000,
+                    "message": overloaded.to_string(),
+                    "data": {
+                        "retry_after": overloaded.retry_after.as_secs(),
+                    }
+                })),
+                darknode: None,
+            }),
+        );
     }
 
-    async fn receive_response(&self, _request_id: Uuid) -> Result<Vec<u8>> {
-        // Generate a mock response
-        Ok(serde_json::to_vec(&serde_json::json!({
-            "jsonrpc": "2.0",
-            "id": 1,
-            "result": "0x123456"
-        }))?)
+    if let Some(exhausted) = err.downcast_ref::<CircuitCapacityExhausted>() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(RpcResponse {
+                id,
+                result: None,
+                error: Some(serde_json::json!({
+                    "code": -32000,
+                    "message": exhausted.to_string(),
+                    "data": {
+                        "limit": exhausted.limit,
+                    }
+                })),
+                darknode: None,
+            }),
+        );
     }
-}
 
-/// Mock implementation of the RequestSanitizer trait
-struct MockRequestSanitizer;
+    if let Some(timed_out) = err.downcast_ref::<TimedOut>() {
+        return (
+            StatusCode::GATEWAY_TIMEOUT,
+            Json(RpcResponse {
+                id,
+                result: None,
+                error: Some(timed_out.error()),
+                darknode: None,
+            }),
+        );
+    }
 
-#[async_trait::async_trait]
-impl RequestSanitizer for MockRequestSanitizer {
-    async fn sanitize_request(&self, request: &[u8]) -> Result<Vec<u8>> {
-        // In a real implementation, this would remove identifying information
-        // For simplicity, we'll just return the request as-is
-        Ok(request.to_vec())
+    // An exit out of budget refused the request unsent, and it couldn't be sent elsewhere in time
+    if let Some(failure) = replay::hop_failure(&err).filter(|failure| failure.kind == HopFailureKind::AtCapacity) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(RpcResponse {
+                id,
+                result: None,
+                error: Some(serde_json::json!({
+                    "code": -32000,
+                    "message": failure.to_string(),
+                })),
+                darknode: None,
+            }),
+        );
     }
 
-    async fn prepare_response(&self, response: &[u8]) -> Result<Vec<u8>> {
-        // In a real implementation, this would prepare the response for delivery
-        // For simplicity, we'll just return the response as-is
-        Ok(response.to_vec())
+    if let Some(unavailable) = err.downcast_ref::<CircuitUnavailable>() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(RpcResponse {
+                id,
+                result: None,
+                error: Some(serde_json::json!({
+                    "code": -32000,
+                    "message": unavailable.to_string(),
+                })),
+                darknode: None,
+            }),
+        );
     }
-}
 
-/// Mock implementation of the UserManager trait
-struct MockUserManager {
-    users: Arc<RwLock<Vec<darknode_backend::types::User>>>,
+    internal_error(id)
 }
 
-impl MockUserManager {
-    fn new() -> Self {
-        Self {
-            users: Arc::new(RwLock::new(Vec::new())),
-        }
+/// Map a service error to a response, telling clients that were shed when to retry
+fn rpc_failure(id: serde_json::Value, err: anyhow::Error) -> Response {
+    let retry_after = err
+        .downcast_ref::<Overloaded>()
+        .map(|overloaded| overloaded.retry_after.as_secs().max(1));
+    let (status, body) = rpc_error(id, err);
+    match retry_after {
+        Some(secs) => (status, [(header::RETRY_AFTER, secs.to_string())], body).into_response(),
+        None => (status, body).into_response(),
     }
 }
 
-#[async_trait::async_trait]
-impl UserManager for MockUserManager {
-    async fn create_user(&self, wallet_address: &str) -> Result<darknode_backend::types::User> {
-        let user = darknode_backend::types::User {
-            id: Uuid::new_v4(),
-            wallet_address: wallet_address.to_string(),
-            api_key: format!("api-{}", Uuid::new_v4()),
-            active: true,
-            expires_at: None,
-            rpc_mappings: Vec::new(),
-        };
+/// Build a generic JSON-RPC internal error
+fn internal_error(id: serde_json::Value) -> RpcError {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(RpcResponse {
+            id,
+            result: None,
+            error: Some(serde_json::json!({
+                "code": -32603,
+                "message": "Internal error"
+            })),
+            darknode: None,
+        }),
+    )
+}
 
-        let mut users = self.users.write().await;
-        users.push(user.clone());
+/// Whether the client asked for the response as server-sent events
+fn wants_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map_or(false, |accept| accept.contains("text/event-stream"))
+}
 
-        Ok(user)
-    }
+/// Stream response chunks to the client as a chunked HTTP body
+fn chunked_response(chunks: ResponseStream) -> Response {
+    let body = StreamBody::new(chunks.map(|chunk| chunk.map(|chunk| chunk.data)));
+    ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+}
 
-    async fn get_user_by_api_key(&self, api_key: &str) -> Result<Option<darknode_backend::types::User>> {
-        let users = self.users.read().await;
-        Ok(users.iter().find(|u| u.api_key == api_key).cloned())
-    }
+/// Stream response chunks to the client as server-sent events, one event per chunk
+fn event_stream_response(chunks: ResponseStream) -> Response {
+    let mut pending = Vec::new();
+    let events = chunks.map(move |chunk| {
+        let chunk = chunk?;
+        pending.extend_from_slice(&chunk.data);
 
-    async fn get_user_by_wallet(&self, wallet_address: &str) -> Result<Option<darknode_backend::types::User>> {
-        let users = self.users.read().await;
-        Ok(users
-            .iter()
-            .find(|u| u.wallet_address == wallet_address)
-            .cloned())
-    }
-
-    async fn add_rpc_mapping(
-        &self,
-        user_id: Uuid,
-        mapping: darknode_backend::types::RpcMapping,
-    ) -> Result<()> {
-        let mut users = self.users.write().await;
-        if let Some(user) = users.iter_mut().find(|u| u.id == user_id) {
-            user.rpc_mappings.push(mapping);
-        }
-        Ok(())
-    }
+        // Hold back a trailing partial UTF-8 sequence until the next chunk completes it
+        let complete = match std::str::from_utf8(&pending) {
+            Ok(_) => pending.len(),
+            Err(e) if e.error_len().is_none() && !chunk.last => e.valid_up_to(),
+            Err(_) => anyhow::bail!("Response is not valid UTF-8"),
+        };
+        let text = String::from_utf8(pending.drain(..complete).collect())?;
 
-    async fn get_rpc_mappings(&self, user_id: Uuid) -> Result<Vec<darknode_backend::types::RpcMapping>> {
-        let users = self.users.read().await;
-        if let Some(user) = users.iter().find(|u| u.id == user_id) {
-            Ok(user.rpc_mappings.clone())
-        } else {
-            Ok(Vec::new())
-        }
-    }
+        let event = Event::default().event(if chunk.last { "end" } else { "chunk" });
+        Ok::<_, anyhow::Error>(event.data(text))
+    });
+    Sse::new(events).into_response()
 }
 
 /// Handler for RPC requests
+///
+/// A call sent with an idempotency key is served once per key; duplicate deliveries get
+/// the first delivery's response replayed instead of being sent through a circuit again.
+/// Such calls holding a mutating method are journaled, see `darknode_backend::journal`.
 async fn handle_rpc(
-    Json(request): Json<RpcRequest>,
     Extension(service): Extension<Arc<EntryNodeService>>,
-) -> Result<Json<RpcResponse>, StatusCode> {
+    Extension(idempotency): Extension<Arc<IdempotencyStore>>,
+    Extension(journal): Extension<Arc<RequestJournal>>,
+    Extension(cache_hints): Extension<Arc<CacheHintConfig>>,
+    headers: HeaderMap,
+    call: RpcCall,
+) -> Result<Response, Response> {
+    let key = idempotency::key(&headers).map_err(|e| rpc_failure(call.response_id(), e.into()))?;
+    let Some(key) = key.filter(|_| !call.streamed()) else {
+        return serve_rpc(&service, &cache_hints, &headers, call).await;
+    };
+    
+    // Calls that fail to authenticate are refused as usual, without claiming the key
+    let checks = call.requests().into_iter().map(|request| service.check_api_key(&request.api_key));
+    if futures::future::join_all(checks).await.iter().any(Result::is_err) {
+        return serve_rpc(&service, &cache_hints, &headers, call).await;
+    }
+    let scope = call.idempotency_scope();
+    let fingerprint = call.fingerprint();
+    let slot = idempotency
+        .claim(&scope, &key, fingerprint, tokio::time::Instant::now())
+        .map_err(|e| rpc_failure(call.response_id(), e.into()))?;
+    let journaled = call.requests().iter().any(|request| methods::is_mutating(&request.method));
+    let response_id = call.response_id();
+    
+    // Only the delivery that fills the slot is served; the others wait for and replay its response
+    let mut served_here = false;
+    let served = &mut served_here;
+    let (scope, key) = (&scope, &key);
+    let stored = slot
+        .get_or_try_init(|| async move {
+            *served = true;
+            
+            // Journal a mutating call before it goes out and once it is answered, so its
+            // client can learn what became of it should this node crash in between; one that
+            // can't be journaled isn't sent, and the key stays free to send it again
+            if journaled {
+                if let Err(e) = journal.sent(scope, key, fingerprint).await {
+                    tracing::warn!("Refusing a mutating call that couldn't be journaled: {}", e);
+                    return Err(rpc_failure(response_id, e.context("the call could not be journaled and was not sent")));
+                }
+            }
+            let response = match serve_rpc(&service, &cache_hints, &headers, call).await {
+                Ok(response) | Err(response) => response,
+            };
+            let stored = StoredResponse::read(response)
+                .await
+                .map_err(|_| internal_error(serde_json::Value::Null).into_response());
+            if journaled {
+                let completed = match &stored {
+                    Ok(stored) if stored.status.is_success() => {
+                        journal.completed(scope, key, stored.status.as_u16(), Some(&stored.body)).await
+                    }
+                    Ok(stored) => journal.completed(scope, key, stored.status.as_u16(), None).await,
+                    Err(response) => journal.completed(scope, key, response.status().as_u16(), None).await,
+                };
+                if let Err(e) = completed {
+                    tracing::warn!("Failed to journal the answer to a mutating call: {}", e);
+                }
+            }
+            stored
+        })
+        .await?;
+    Ok(stored.replay(!served_here))
+}
+
+/// Serve an RPC call
+///
+/// Notifications are answered with an empty 204 at once and sent on in the background, and
+/// a batch of nothing but notifications is answered the same way; other batches are answered
+/// with the responses to their calls and invalid elements, in order. Only single calls'
+/// responses may be cached by the client, see `darknode_backend::cache_hints`.
+async fn serve_rpc(
+    service: &Arc<EntryNodeService>,
+    cache_hints: &CacheHintConfig,
+    headers: &HeaderMap,
+    call: RpcCall,
+) -> Result<Response, Response> {
+    let (request, ctx) = match call {
+        RpcCall::Single(request, ctx) => (request, ctx),
+        RpcCall::Batch(calls) => {
+            let responses: Vec<RpcResponse> = futures::future::join_all(calls.into_iter().map(|call| async move {
+                match call {
+                    Batched::Call(request, ctx) => serve_batched(service, request, ctx).await,
+                    Batched::Invalid(response) => Some(response),
+                }
+            }))
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+            if responses.is_empty() {
+                return Ok(StatusCode::NO_CONTENT.into_response());
+            }
+            return Ok(Json(responses).into_response());
+        }
+    };
+
     // Convert the request to JSON
-    let request_json = serde_json::to_vec(&serde_json::json!({
-        "jsonrpc": "2.0",
-        "method": request.method,
-        "params": request.params,
-        "id": request.id
-    }))
-    .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let request_value = request.to_value();
+    let request_json =
+        serde_json::to_vec(&request_value).map_err(|_| internal_error(request.response_id()).into_response())?;
+
+    // Notifications go out through the circuit and nothing waits for them
+    if request.id.is_none() {
+        send_notification(service, ctx, request_json);
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+
+    // Raw account data is passed through as it arrives instead of being buffered, and
+    // relayed transactions report their progress as it happens
+    if is_streamable(&request.method, &request.params) || relay::requested(&request_value) {
+        let mut chunks = service
+            .handle_request_stream(ctx, &request_json)
+            .await
+            .map_err(|e| rpc_failure(request.response_id(), e))?;
+
+        // A failure before anything was sent is answered like any other, later ones close
+        // the response with an error, see `darknode_backend::streamed`
+        let first = match chunks.next().await {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(e)) => return Err(rpc_failure(request.response_id(), e)),
+            None => return Err(internal_error(request.response_id()).into_response()),
+        };
+        let chunks: ResponseStream = Box::pin(futures::stream::once(async move { Ok(first) }).chain(chunks));
+
+        if wants_event_stream(headers) {
+            return Ok(event_stream_response(chunks));
+        }
+        return Ok(chunked_response(chunks));
+    }
 
     // Process the request
     let response_bytes = service
-        .handle_request(&request.api_key, &request_json)
+        .handle_request(ctx, &request_json)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|e| rpc_failure(request.response_id(), e))?;
+    let response = rpc_response(&response_bytes).ok_or_else(|| internal_error(request.response_id()).into_response())?;
+    
+    // Report where the request's time went in a header too, if the client asked, flag a
+    // response served without a circuit where no client can miss it, and tell the client
+    // how long it may keep the answer
+    let timing = response.darknode.as_ref().and_then(ServerTiming::in_extension);
+    let degraded = response.darknode.as_ref().map_or(false, fallback::is_direct);
+    let cache_control = cache_hints.cache_control(&request.method, &request.params, response.result.as_ref());
+    let mut response = Json(response).into_response();
+    if let Ok(value) = header::HeaderValue::from_str(&cache_control) {
+        response.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+    if let Some(value) = timing.and_then(|timing| header::HeaderValue::from_str(&timing.header_value()).ok()) {
+        response.headers_mut().insert(SERVER_TIMING_HEADER, value);
+    }
+    if degraded {
+        response
+            .headers_mut()
+            .insert(fallback::DEGRADED_HEADER, header::HeaderValue::from_static(fallback::DIRECT));
+    }
+    Ok(response)
+}
+
+/// Send a notification on in the background, dropping a failure as the spec requires
+fn send_notification(service: &Arc<EntryNodeService>, ctx: RequestContext, request_json: Vec<u8>) {
+    let service = service.clone();
+    tokio::spawn(async move {
+        if let Err(e) = service.handle_notification(ctx, &request_json).await {
+            tracing::debug!("Dropped a notification: {}", e);
+        }
+    });
+}
+
+/// Serve one request of a batch, returning its response unless it is a notification
+///
+/// Batched requests are always answered whole, never streamed, and notifications are sent
+/// on in the background.
+async fn serve_batched(service: &Arc<EntryNodeService>, request: RpcRequest, ctx: RequestContext) -> Option<RpcResponse> {
+    let request_json = serde_json::to_vec(&request.to_value());
+    let Some(id) = request.id.clone() else {
+        if let Ok(request_json) = request_json {
+            send_notification(service, ctx, request_json);
+        }
+        return None;
+    };
+    let Ok(request_json) = request_json else { return Some(internal_error(id).1 .0) };
+    let response = match service.handle_request(ctx, &request_json).await {
+        Ok(response) => rpc_response(&response).unwrap_or_else(|| internal_error(id).1 .0),
+        Err(e) => rpc_error(id, e).1 .0,
+    };
+    Some(response)
+}
 
-    // Parse the response
-    let response: serde_json::Value =
-        serde_json::from_slice(&response_bytes).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+/// Parse the entry node service's answer into the response sent to the client
+fn rpc_response(response: &[u8]) -> Option<RpcResponse> {
+    let response: serde_json::Value = serde_json::from_slice(response).ok()?;
 
     // Extract the result and error
     let id = response["id"].clone();
@@ -287,8 +850,377 @@ async fn handle_rpc(
     } else {
         Some(response["error"].clone())
     };
+    let darknode = response.get(EXTENSION_KEY).cloned();
+
+    Some(RpcResponse {
+        id,
+        result,
+        error,
+        darknode,
+    })
+}
+
+/// Request body for rotating the node's long-term key
+#[derive(Debug, Clone, Deserialize)]
+struct RotateKeyRequest {
+    /// Seconds until the new key replaces the current one
+    activate_in_secs: u64,
+}
+
+/// Handler for rotating the node's long-term key
+async fn rotate_key(
+    Extension(rotator): Extension<Arc<KeyRotator>>,
+    Json(request): Json<RotateKeyRequest>,
+) -> Result<Json<RotationOutcome>, (StatusCode, String)> {
+    rotator
+        .rotate(Duration::from_secs(request.activate_in_secs))
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::CONFLICT, e.to_string()))
+}
+
+/// Query parameters for opening a WebSocket, which takes the API key in its header
+#[derive(Debug, Clone, Deserialize)]
+struct WsParams {
+    /// A session token from an earlier connection, to resume that session
+    session: Option<String>,
+    /// The user's RPC mapping the connection was opened for, if known
+    mapping_id: Option<Uuid>,
+}
+
+/// Handler for WebSocket connections, answering requests in order if asked to by
+/// `X-DarkNode-Ordered: 1`, see `darknode_backend::pipelining`
+async fn handle_ws(
+    Extension(service): Extension<Arc<EntryNodeService>>,
+    Extension(pipelining): Extension<Arc<PipelineConfig>>,
+    UserApiKey(api_key): UserApiKey,
+    Query(params): Query<WsParams>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let ordered = match headers.get(ORDERED_HEADER).map(|value| value.to_str().ok().and_then(pipelining::parse_header)) {
+        None => false,
+        Some(Some(ordered)) => ordered,
+        Some(None) => return (StatusCode::BAD_REQUEST, format!("Invalid {} header", ORDERED_HEADER)).into_response(),
+    };
+    let order = ResponseOrder::new(&pipelining, ordered);
+    ws.on_upgrade(move |socket| serve_ws(socket, service, api_key, params, order))
+}
+
+/// Serve a WebSocket connection, keeping its session alive for resumption after it drops
+///
+/// Requests are answered concurrently, their responses sent as `order` releases them,
+/// except that subscription calls wait for those before them.
+async fn serve_ws(
+    mut socket: WebSocket,
+    service: Arc<EntryNodeService>,
+    api_key: String,
+    params: WsParams,
+    mut order: ResponseOrder,
+) {
+    let attachment = match &params.session {
+        Some(token) => service.resume_session(&api_key, token).await,
+        None => service.open_session(&api_key).await,
+    };
+    let mut attachment = match attachment {
+        Ok(attachment) => attachment,
+        Err(e) => {
+            let code = if e.is::<SessionExpired>() {
+                SESSION_EXPIRED_CLOSE_CODE
+            } else {
+                axum::extract::ws::close_code::POLICY
+            };
+            let frame = CloseFrame {
+                code,
+                reason: e.to_string().into(),
+            };
+            let _ = socket.send(Message::Close(Some(frame))).await;
+            return;
+        }
+    };
+
+    // Tell the client how to resume, then replay what it missed in order
+    let session = serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "darknode_session",
+        "params": {
+            "token": attachment.token,
+            "subscriptions": attachment.subscriptions.iter().map(|s| s.id).collect::<Vec<_>>(),
+        }
+    });
+    let mut backlog = vec![session];
+    backlog.append(&mut attachment.missed);
+    for message in backlog {
+        if socket.send(Message::Text(message.to_string())).await.is_err() {
+            service.sessions().detach(&attachment);
+            return;
+        }
+    }
+
+    let (token, user_id) = (attachment.token.clone(), attachment.user_id);
+    let (serving, api_key, connection, token) = (&service, api_key.as_str(), &params, token.as_str());
+    let mut in_flight = futures::stream::FuturesUnordered::new();
+    let mut subscription_calls = pipelining::SubscriptionCalls::default();
+    'connection: loop {
+        let head_timeout = order.head_deadline().map(|deadline| deadline.remaining());
+        let outgoing = tokio::select! {
+            incoming = socket.recv(), if order.has_room() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    let seq = order.admit(pipelining::request_id(&text));
+                    let mut turn = pipelining::is_subscription_call(&text).then(|| subscription_calls.queue());
+                    in_flight.push(async move {
+                        if let Some(turn) = &mut turn {
+                            turn.wait().await;
+                        }
+                        let response = ws_request(serving, api_key, connection, token, user_id, &text).await;
+                        drop(turn);
+                        (seq, response)
+                    });
+                    continue;
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            Some((seq, response)) = in_flight.next() => order.complete(seq, response),
+            _ = tokio::time::sleep(head_timeout.unwrap_or_default()), if head_timeout.is_some() => order.expire_head(),
+            Some(notification) = attachment.notifications.recv() => vec![notification],
+        };
+        for message in outgoing {
+            if socket.send(Message::Text(message.to_string())).await.is_err() {
+                break 'connection;
+            }
+        }
+    }
+
+    // Requests already on their way are seen through, as a write can't be called back
+    while in_flight.next().await.is_some() {}
+    service.sessions().detach(&attachment);
+}
+
+/// Answer a JSON-RPC request received over a WebSocket, or forward it if it is a notification
+async fn ws_request(
+    service: &Arc<EntryNodeService>,
+    api_key: &str,
+    connection: &WsParams,
+    token: &str,
+    user_id: Uuid,
+    text: &str,
+) -> Option<serde_json::Value> {
+    let request: serde_json::Value = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(_) => {
+            return Some(serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": { "code": -32700, "message": "Parse error" }
+            }))
+        }
+    };
+    if methods::is_notification(&request) {
+        let ctx = RequestContext::new(api_key).with_mapping(connection.mapping_id);
+        let _ = service.handle_notification(ctx, text.as_bytes()).await;
+        return None;
+    }
+    let id = request["id"].clone();
+    let method = request["method"].as_str().unwrap_or_default();
+    let params = request["params"].clone();
+
+    let result = if method.ends_with("Unsubscribe") || method.ends_with("_unsubscribe") {
+        match params[0].as_u64() {
+            Some(subscription) => Ok(serde_json::json!(service.unsubscribe(user_id, token, subscription))),
+            None => Ok(serde_json::json!(false)),
+        }
+    } else if methods::is_subscription(method) {
+        service
+            .subscribe(api_key, connection.mapping_id, token, method, params)
+            .await
+            .map(|subscription| serde_json::json!(subscription))
+    } else {
+        // Everything else is answered exactly as it would be over HTTP, on the user's
+        // subscription circuit once the session holds subscriptions
+        let class = if service.sessions().has_subscriptions(token) {
+            CircuitClass::Subscription
+        } else {
+            CircuitClass::Interactive
+        };
+        let ctx = RequestContext::new(api_key)
+            .with_mapping(connection.mapping_id)
+            .with_circuit_class(class);
+        match service.handle_request(ctx, text.as_bytes()).await {
+            Ok(response) => {
+                return Some(
+                    serde_json::from_slice(&response)
+                        .unwrap_or_else(|_| serde_json::json!(internal_error(id).1 .0)),
+                )
+            }
+            Err(e) => Err(e),
+        }
+    };
+
+    Some(match result {
+        Ok(result) => serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => serde_json::json!(rpc_error(id, e).1 .0),
+    })
+}
+
+/// Request body for verifying a receipt
+#[derive(Debug, Clone, Deserialize)]
+struct VerifyReceiptRequest {
+    /// The receipt returned with the response
+    receipt: ServiceReceipt,
+    /// The client's copy of the request
+    request: serde_json::Value,
+    /// The client's copy of the response
+    response: serde_json::Value,
+}
+
+/// The outcome of verifying a receipt
+#[derive(Debug, Clone, Serialize)]
+struct ReceiptVerification {
+    /// Whether the receipt proves the request was served with the response
+    valid: bool,
+    /// Why it doesn't, if it doesn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+}
+
+/// Handler for checking a receipt this node issued; nothing about the check is kept
+///
+/// Served on `GET`, taking the receipt, request and response in the body, never in the
+/// URL, where proxies and access logs would keep them; clients whose HTTP library can't
+/// send a body with `GET` may `POST` the same body.
+async fn verify_receipt(
+    Extension(service): Extension<Arc<EntryNodeService>>,
+    Json(verify): Json<VerifyReceiptRequest>,
+) -> Json<ReceiptVerification> {
+    let outcome = service.verify_receipt(&verify.receipt, &verify.request, &verify.response);
+    Json(ReceiptVerification {
+        valid: outcome.is_ok(),
+        reason: outcome.err().map(|e| e.label()),
+    })
+}
+
+/// Query parameters picking which of a user's circuits is meant
+#[derive(Debug, Clone, Deserialize)]
+struct CircuitParams {
+    /// The exit pool the circuit is pinned to, if any
+    exit_pool: Option<String>,
+}
+
+/// Status and message for a failed circuit lookup or rotation
+fn circuit_error(err: anyhow::Error) -> (StatusCode, String) {
+    let status = if err.is::<QuotaExceeded>() || err.is::<RotationThrottled>() {
+        StatusCode::TOO_MANY_REQUESTS
+    } else if err.is::<CircuitCapacityExhausted>() || err.is::<CircuitUnavailable>() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::UNAUTHORIZED
+    };
+    (status, err.to_string())
+}
+
+/// Handler for describing the circuit carrying a user's requests, without naming its nodes
+async fn circuit_info(
+    Extension(service): Extension<Arc<EntryNodeService>>,
+    UserApiKey(api_key): UserApiKey,
+    Query(params): Query<CircuitParams>,
+) -> Result<Json<CircuitInfo>, (StatusCode, String)> {
+    match service.circuit_info(&api_key, params.exit_pool).await {
+        Ok(Some(info)) => Ok(Json(info)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "No circuit is up yet".to_string())),
+        Err(e) => Err(circuit_error(e)),
+    }
+}
+
+/// Handler for replacing a user's circuit with a new one at once
+async fn rotate_circuit(
+    Extension(service): Extension<Arc<EntryNodeService>>,
+    UserApiKey(api_key): UserApiKey,
+    Query(params): Query<CircuitParams>,
+) -> Result<Json<CircuitInfo>, (StatusCode, String)> {
+    service
+        .rotate_circuit(&api_key, params.exit_pool)
+        .await
+        .map(Json)
+        .map_err(circuit_error)
+}
+
+/// Query parameters of a user's audit consent
+#[derive(Debug, Clone, Deserialize)]
+struct AuditConsentParams {
+    /// Whether they agree to have their usage recorded
+    consent: bool,
+}
+
+/// Status and message for a failed audit consent change or export
+fn audit_error(err: anyhow::Error) -> (StatusCode, String) {
+    let status = if err.is::<AuditingDisabled>() {
+        StatusCode::NOT_FOUND
+    } else {
+        StatusCode::UNAUTHORIZED
+    };
+    (status, err.to_string())
+}
+
+/// Handler for a user giving or withdrawing consent to have their usage audited
+async fn set_audit_consent(
+    Extension(service): Extension<Arc<EntryNodeService>>,
+    UserApiKey(api_key): UserApiKey,
+    Query(params): Query<AuditConsentParams>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    service
+        .set_audit_consent(&api_key, params.consent)
+        .await
+        .map(|()| StatusCode::NO_CONTENT)
+        .map_err(audit_error)
+}
+
+/// Handler for exporting the usage records kept of the requesting user
+async fn audit_records(
+    Extension(service): Extension<Arc<EntryNodeService>>,
+    UserApiKey(api_key): UserApiKey,
+) -> Result<Json<Vec<UsageRecord>>, (StatusCode, String)> {
+    service
+        .audit_records(&api_key)
+        .await
+        .map(Json)
+        .map_err(audit_error)
+}
+
+/// Handler for what became of a mutating call sent with an idempotency key
+async fn request_status(
+    Path(idempotency_key): Path<String>,
+    UserApiKey(api_key): UserApiKey,
+    Extension(journal): Extension<Arc<RequestJournal>>,
+) -> Result<Json<RequestStatus>, (StatusCode, String)> {
+    journal
+        .status(&api_key, &idempotency_key)
+        .map(Json)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "No mutating call was journaled under this key".to_string()))
+}
+
+/// Handler for listing recent circuit build failures, for operators only
+async fn circuit_failures(
+    Extension(service): Extension<Arc<EntryNodeService>>,
+) -> Json<Vec<CircuitBuildReport>> {
+    Json(service.circuit_failures())
+}
+
+/// Handler for how requests mirrored onto shadow circuits compared
+async fn shadow_report(
+    Extension(service): Extension<Arc<EntryNodeService>>,
+) -> Json<ShadowReport> {
+    Json(service.shadow_report())
+}
+
+/// Handler for Prometheus scrapes
+async fn prometheus_metrics(Extension(handle): Extension<PrometheusHandle>) -> String {
+    handle.render()
+}
 
-    Ok(Json(RpcResponse { id, result, error }))
+/// Reject request bodies that can't be decompressed
+async fn decompression_error(err: axum::BoxError) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, format!("Invalid request body encoding: {}", err))
 }
 
 /// Handler for health checks
@@ -296,51 +1228,553 @@ async fn health_check() -> &'static str {
     "OK"
 }
 
+/// Handler for the build of this binary
+async fn version() -> Json<BuildInfo> {
+    Json(BuildInfo::current())
+}
+
+/// Handler for readiness checks, reporting load shedding and failing while it is at its maximum
+async fn readiness(Extension(service): Extension<Arc<EntryNodeService>>) -> (StatusCode, Json<AdmissionState>) {
+    let admission = service.admission();
+    let now = std::time::Instant::now();
+    let status = if admission.ready(now) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(admission.state(now)))
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Load configuration, only printing it when asked to check it
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = DarknodeConfig::from_args(&args)?;
+    if config::check_requested(&args) {
+        print!("{}", config.render(&["common", "entry"])?);
+        return Ok(());
+    }
+
+    // Verbose body logging doesn't exist in builds without the `dev-logging` feature
+    let dev_verbose_logging = config::dev_verbose_logging_requested(&args)?;
+
+    // Every entry node must derive a user's exit subsets alike, or a user reaches the union
+    // of their subsets by spreading requests over entry nodes
+    if config.common.epochs.enabled && config.common.epochs.secret.is_none() {
+        anyhow::bail!("common.epochs.secret must be set, the same on every entry node, while epochs are enabled");
+    }
+
     // Initialize tracing
-    tracing_subscriber::registry()
-        .with(filter::LevelFilter::from_level(Level::INFO))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    // Load configuration
-    let config = Config {
-        listen_addr: "127.0.0.1:3000".parse()?,
-        region: "us-east".to_string(),
-        coordinator_url: "http://localhost:3001".to_string(),
+    let level = match dev_verbose_logging {
+        true => Level::DEBUG,
+        false => Level::INFO,
     };
+    telemetry::init(&config.common.telemetry, "darknode-entry", level)?;
+
+    #[cfg(feature = "dev-logging")]
+    if dev_verbose_logging {
+        dev_logging::warn_enabled();
+    }
 
-    info!("Starting entry node in region {}", config.region);
+    BuildInfo::current().log_banner("entry-node");
+    info!("Starting entry node in region {}", config.common.region);
+
+    // Export metrics for Prometheus to scrape
+    let prometheus = traffic::install_prometheus()?;
 
     // Create dependencies
-    let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl);
-    let node_manager: Arc<dyn NodeManager + Send + Sync> = Arc::new(MockNodeManager::new());
-    let router: Arc<dyn RouterTrait + Send + Sync> = Arc::new(MockRouter::new(crypto.clone()));
-    let sanitizer: Arc<dyn RequestSanitizer + Send + Sync> = Arc::new(MockRequestSanitizer);
-    let user_manager: Arc<dyn UserManager + Send + Sync> = Arc::new(MockUserManager::new());
-
-    // Create the entry node service
-    let service = Arc::new(EntryNodeService::new(
-        NodeId(Uuid::new_v4()),
-        crypto,
-        router,
-        sanitizer,
-        user_manager,
+    let node_id = NodeId(config.common.node_id.unwrap_or_else(Uuid::new_v4));
+    let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+    let storage = storage::open(&config.common.storage).await?;
+    let node_manager: Arc<dyn NodeManager + Send + Sync> = Arc::new(StoredNodeManager::new(storage.clone()));
+    let sanitizer: Arc<dyn RequestSanitizer + Send + Sync> = Arc::new(Sanitizer::new(&config.entry.sanitizer));
+    let user_manager: Arc<dyn UserManager + Send + Sync> = Arc::new(StoredUserManager::new(storage.clone()));
+
+    // Set up the node's long-term identity
+    let identity = Arc::new(
+        NodeIdentity::load_or_generate(&*crypto, config.common.identity_file.as_deref(), KEY_RETENTION).await?,
+    );
+    info!(
+        "Node {} has identity key {}",
+        node_id.0,
+        base64::engine::general_purpose::STANDARD.encode(&identity.public_key(Timestamp::now()).0)
+    );
+
+    // Client receipts are signed with a key of their own, which is never rotated so receipts
+    // keep verifying; it is published as the node's receipt key
+    let receipt_key = Arc::new(
+        NodeIdentity::load_or_generate(&*crypto, config.entry.receipt_key_file.as_deref(), Duration::ZERO).await?,
+    );
+    info!(
+        "Node {} signs receipts with key {}",
+        node_id.0,
+        base64::engine::general_purpose::STANDARD.encode(&receipt_key.public_key(Timestamp::now()).0)
+    );
+    if config.entry.receipt_key_file.is_none() {
+        tracing::warn!("No receipt key file is configured, so receipts issued now stop verifying once the node restarts");
+    }
+
+    // Build circuits through the nodes in the directory, sending handshakes and requests along
+    // them signed with the node's identity; circuit keys ratchet as the exit nodes' do. Paths
+    // are drawn from the coordinator's recommendations where they fit, and hops ordered by
+    // the round trips it sends along with them
+    let hops = Arc::new(HopClient::new(
+        HopSigner::new(node_id.clone(), identity.clone(), crypto.clone()).with_telemetry(config.common.telemetry.clone()),
+    ));
+    let latency = Arc::new(LatencyMatrix::new(config.common.latency.clone()));
+    let reachable = Arc::new(ReachabilityMatrix::new(config.common.reachability.clone()));
+    let advisor = Arc::new(
+        PathAdvisor::new(&config.common.coordinator_url, config.entry.advice.clone()).with_latency(latency.clone()),
+    );
+    let router: Arc<dyn RouterTrait + Send + Sync> = Arc::new(
+        RouterImpl::new(node_manager.clone(), crypto.clone())
+            .with_hops(hops, config.exit.membership.ratchet.clone())
+            .with_advisor(advisor)
+            .with_latency(latency)
+            .with_reachability(reachable.clone()),
+    );
+
+    // The network's feature flags, as the directory followed below carries them
+    let feature_flags = FeatureFlags::new();
+
+    // Create the entry node service, serving opted-in users from the fallback providers while
+    // no circuit can be built, metering usage if the deployment bills it, and recording
+    // consenting users' usage if it audits it
+    let features = EntryNodeConfig {
+        sessions: config.entry.sessions.clone(),
+        validation: config.entry.validation.clone(),
+        keepalive: config.entry.keepalive.clone(),
+        epochs: config.common.epochs.clone(),
+        admission: config.entry.admission.clone(),
+        capacity: config.entry.circuits.clone(),
+        emulation: config.entry.emulation.clone(),
+        timeouts: config.entry.timeouts.clone(),
+        accounting: config.common.accounting.clone(),
+        signing: config.entry.signing.clone(),
+        shaping: config.common.shaping.clone(),
+        fairness: config.entry.fairness.clone(),
+        replay: config.entry.replay.clone(),
+        shadow: config.entry.shadow.clone(),
+        drain: config.entry.drain.clone(),
+        relaxation: config.entry.relaxation.clone(),
+    };
+    let mut service = EntryNodeService::new(node_id.clone(), crypto.clone(), router, sanitizer, user_manager, receipt_key, features)
+    .with_circuit_classes(config.entry.circuit_classes.clone())
+    .with_scatter(config.entry.scatter.clone())
+    .with_flags(feature_flags.clone())
+    .with_shared_nonces(storage.clone());
+    if config.entry.shadow.enabled {
+        service = service.with_shadow_sanitizer(Arc::new(Sanitizer::new(&config.entry.shadow.sanitizer)));
+    }
+    if let Some(proxy) = DirectProxy::new(config.entry.fallback.clone()) {
+        service = service.with_fallback(proxy);
+    }
+    if config.common.billing.enabled {
+        let meter = Arc::new(UsageMeter::new(storage.clone()));
+        tokio::spawn(meter.clone().run(config.common.billing.flush_interval));
+        service = service.with_meter(meter);
+    }
+    let service = match UsageAudit::open(&config.entry.compliance, storage)? {
+        Some(audit) => {
+            let audit = Arc::new(audit);
+            tokio::spawn(audit.clone().run_pruning());
+            Arc::new(service.with_usage_audit(audit))
+        }
+        None => Arc::new(service),
+    };
+
+    // Release messages into circuits on the traffic shaping ticks
+    tokio::spawn(service.clone().run_shaping());
+
+    // Expire WebSocket sessions that weren't resumed in time
+    let sweeper = service.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(SESSION_SWEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            sweeper.sweep_sessions();
+        }
+    });
+
+    // Forget the nonces of signed requests once they are no longer fresh
+    let pruner = service.clone();
+    let interval = config.entry.signing.max_age.max(Duration::from_secs(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            pruner.prune_nonces().await;
+        }
+    });
+
+    // Ping idle circuits so dead hops are found before a user request is, as often as the
+    // circuit class pinged most often needs
+    if config.entry.keepalive.enabled {
+        let pinger = service.clone();
+        let interval = config.entry.circuit_classes.keepalive_tick(config.entry.keepalive.interval);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                pinger.keepalive().await;
+            }
+        });
+    }
+
+    // Move circuits off nodes the coordinator is draining for maintenance
+    if config.entry.drain.enabled {
+        let drainer = service.clone();
+        let coordinator_url = config.common.coordinator_url.clone();
+        let interval = config.entry.drain.poll_interval;
+        tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match drain::draining(&client, &coordinator_url).await {
+                    Ok(draining) => drainer.drain(draining).await,
+                    Err(e) => tracing::warn!("Failed to fetch draining nodes from the coordinator: {}", e),
+                }
+            }
+        });
+    }
+
+    // Keep a copy of the exit side's version to answer `getVersion` with
+    if config.entry.emulation.enabled {
+        let refresher = service.clone();
+        let interval = config.entry.emulation.version_refresh;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                refresher.refresh_version().await;
+            }
+        });
+    }
+
+    // Follow the coordinator's directories so users' exit subsets rotate network-wide, taking
+    // each epoch's ahead of time and switching to it on this node's clock, and the network's
+    // feature flags with them
+    let follower = Arc::new(
+        DirectoryFollower::new(config.common.directory.clone(), crypto.clone())?
+            .with_flags(feature_flags.clone())
+            .with_nodes(node_manager.clone()),
+    );
+    tokio::spawn(directory::follow(
+        config.common.coordinator_url.clone(),
+        DIRECTORY_POLL_INTERVAL,
+        follower.clone(),
+        service.epochs(),
+    ));
+
+    // Rotate the node's long-term identity
+    let rotator = Arc::new(KeyRotator::new(
+        node_id.clone(),
+        identity.clone(),
+        crypto.clone(),
+        config.common.coordinator_url.clone(),
+    ));
+
+    // Keep the local view of the network in step with the coordinator's as it changes
+    tokio::spawn(directory_watch::follow(
+        config.common.coordinator_url.clone(),
+        config.common.directory_watch.clone(),
+        follower,
+        node_manager.clone(),
     ));
 
+    // Journal mutating calls, so clients can ask what became of them after a crash
+    let journal = Arc::new(RequestJournal::open(config.entry.journal.clone())?);
+    tokio::spawn(journal.clone().run());
+
+    // Queue reports for the coordinator and deliver them whenever it is reachable
+    let outbox = Arc::new(
+        Outbox::open(config.common.outbox.clone())?
+            .with_signer(ReportSigner::new(node_id.clone(), identity.clone(), crypto.clone())),
+    );
+    tokio::spawn(outbox.clone().run(config.common.coordinator_url.clone()));
+
+    // Deliver signed receipts for the work sent through downstream nodes
+    if config.common.accounting.enabled {
+        tokio::spawn(accounting::deliver_receipts(
+            node_id.clone(),
+            identity,
+            crypto.clone(),
+            service.work_tally(),
+            outbox.clone(),
+        ));
+    }
+
+    // Time round trips to the other regions for the coordinator's latency matrix
+    if config.common.latency.enabled {
+        tokio::spawn(regions::ping_peers(
+            config.common.latency.clone(),
+            node_id.clone(),
+            node_manager.clone(),
+            service.counters(),
+        ));
+    }
+
+    // Probe a few peers at random so the coordinator, and this node's router, can route
+    // around broken edges
+    if config.common.reachability.enabled {
+        tokio::spawn(reachability::probe_peers(
+            config.common.reachability.clone(),
+            node_id.clone(),
+            node_manager.clone(),
+            service.counters(),
+            Some(reachable),
+        ));
+    }
+
+    // Sample the node's resources for the load it reports; entry nodes shed nothing
+    let resources = Arc::new(ResourceGuard::new(config.common.resources.clone()));
+    tokio::spawn(resources.clone().run(Arc::new(ProcSampler), Vec::new()));
+
+    // Report activity to the coordinator
+    tokio::spawn(heartbeat::run(
+        config.common.heartbeat_interval,
+        HeartbeatSource {
+            node_id,
+            roles: vec![NodeRole::Entry],
+            region: config.common.region.clone(),
+            method_classes: None,
+            attestation_key: None,
+            resources: Some(resources.clone()),
+        },
+        service.counters(),
+        outbox,
+    ));
+
+    // Administrative routes take the operator token
+    let admin = Router::new()
+        .route("/admin/rotate-key", post(rotate_key))
+        .route("/debug/shadow", get(shadow_report))
+        .route("/debug/circuit-failures", get(circuit_failures))
+        .route_layer(axum::middleware::from_fn(operator::require_operator));
+
     // Create the router
     let app = Router::new()
         .route("/", post(handle_rpc))
+        .route("/ws", get(handle_ws))
+        .merge(admin)
+        .route("/receipts/verify", get(verify_receipt).post(verify_receipt))
+        .route("/circuit/info", get(circuit_info))
+        .route("/circuit/rotate", post(rotate_circuit))
+        .route("/account/audit", get(audit_records))
+        .route("/account/audit/consent", post(set_audit_consent))
+        .route("/requests/:idempotency_key/status", get(request_status))
+        .route("/metrics", get(prometheus_metrics))
         .route("/health", get(health_check))
-        .layer(TraceLayer::new_for_http())
-        .layer(Extension(service));
+        .route("/version", get(version))
+        .route("/health/ready", get(readiness))
+        // Compression only happens here, on the client-facing edge, see `darknode_backend::compression`
+        .layer(compression::client_compression(config.entry.compression_min_size, feature_flags))
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(decompression_error))
+                .layer(RequestDecompressionLayer::new()),
+        )
+        .layer(axum::middleware::from_fn(clock::write_timestamps))
+        .layer(TraceLayer::new_for_http().make_span_with(HttpSpans::client_facing()))
+        .layer(Extension(service))
+        .layer(Extension(Arc::new(IdempotencyStore::new(config.entry.idempotency.clone()))))
+        .layer(Extension(journal))
+        .layer(Extension(Arc::new(config.entry.cache_hints.clone())))
+        .layer(Extension(Arc::new(config.entry.pipelining.clone())))
+        .layer(Extension(rotator))
+        .layer(Extension(Arc::new(config.common.operator.clone())))
+        .layer(Extension(config.common.timestamps))
+        .layer(Extension(prometheus));
+
+    // Log full bodies as they cross the edge, outside compression, when developing locally
+    #[cfg(feature = "dev-logging")]
+    let app = match dev_verbose_logging {
+        true => app.layer(axum::middleware::from_fn(dev_logging::log_bodies)),
+        false => app,
+    };
+
+    // Responses that don't say otherwise must not be cached, see `darknode_backend::cache_hints`
+    let app = app.layer(axum::middleware::from_fn(cache_hints::default_to_no_store));
+
+    // Strip the headers naming clients before anything else sees them, see `darknode_backend::anonymity`
+    let app = app.layer(axum::middleware::from_fn(anonymity::strip_client_headers));
 
     // Start the server
-    info!("Listening on {}", config.listen_addr);
-    axum::Server::bind(&config.listen_addr)
-        .serve(app.into_make_service())
+    info!("Listening on {}", config.entry.listen_addr);
+    axum::Server::bind(&config.entry.listen_addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use darknode_backend::config::EntryConfig;
+    use darknode_backend::fixtures::{self, StubRouter};
+    use darknode_backend::types::{Circuit, CircuitPreferences, ResponseChunk};
+    use futures::channel::oneshot;
+    use serde_json::json;
+
+    /// Pieces of account data the streamed response carries, and their size
+    const PIECES: usize = 10;
+    const PIECE: usize = 1024 * 1024;
+
+    /// Serve RPC calls to `service` on loopback, returning the URL to send them to
+    fn serve(service: EntryNodeService) -> String {
+        let app = Router::new()
+            .route("/", post(handle_rpc))
+            .layer(Extension(Arc::new(service)))
+            .layer(Extension(Arc::new(IdempotencyStore::new(Default::default()))))
+            .layer(Extension(Arc::new(RequestJournal::open(Default::default()).unwrap())))
+            .layer(Extension(Arc::new(CacheHintConfig::default())));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        url
+    }
+
+    /// A router streaming raw account data in pieces, producing the last only once released
+    struct Streaming {
+        stub: StubRouter,
+        release: std::sync::Mutex<Option<oneshot::Receiver<()>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl RouterTrait for Streaming {
+        async fn create_circuit(&self) -> Result<Circuit> {
+            self.stub.create_circuit().await
+        }
+
+        async fn create_circuit_with(&self, preferences: &CircuitPreferences) -> Result<Circuit> {
+            self.stub.create_circuit_with(preferences).await
+        }
+
+        async fn send_request(&self, ctx: &RequestContext, circuit: &Circuit, request: &[u8]) -> Result<Uuid> {
+            self.stub.send_request(ctx, circuit, request).await
+        }
+
+        async fn receive_response(&self, request_id: Uuid) -> Result<Vec<u8>> {
+            self.stub.receive_response(request_id).await
+        }
+
+        async fn receive_response_stream(&self, request_id: Uuid) -> Result<ResponseStream> {
+            let id = self.stub.sent().last().map(|(_, sent)| sent.request["id"].clone()).unwrap_or_default();
+            let head = format!(r#"{{"jsonrpc":"2.0","id":{},"result":{{"context":{{"slot":1}},"value":{{"data":[""#, id);
+            let release = self.release.lock().unwrap().take().expect("a single streamed response");
+            let data = std::iter::once(head.into_bytes()).chain(std::iter::repeat(vec![b'A'; PIECE]).take(PIECES));
+            let tail = async move {
+                let _ = release.await;
+                br#"","base64"]}}}"#.to_vec()
+            };
+            let chunks = futures::stream::iter(data)
+                .chain(futures::stream::once(tail))
+                .enumerate()
+                .map(move |(sequence, data)| {
+                    Ok(ResponseChunk {
+                        request_id,
+                        sequence: sequence as u32,
+                        data,
+                        last: sequence == PIECES + 1,
+                    })
+                });
+            Ok(Box::pin(chunks))
+        }
+    }
+
+    #[tokio::test]
+    async fn raw_account_data_reaches_the_client_before_its_last_piece_is_produced() {
+        let (release, released) = oneshot::channel();
+        let router = Arc::new(Streaming {
+            stub: StubRouter::new(|_| json!(null)),
+            release: std::sync::Mutex::new(Some(released)),
+        });
+        let (service, users) = fixtures::entry(router, &EntryConfig::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let url = serve(service);
+        let request = json!({
+            "api_key": user.api_key,
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "getAccountInfo",
+            "params": ["Vote111111111111111111111111111111111111111", { "encoding": "base64" }],
+        });
+
+        let mut response = reqwest::Client::new().post(&url).json(&request).send().await.unwrap();
+        assert!(response.content_length().is_none());
+        let first = tokio::time::timeout(Duration::from_secs(30), response.chunk())
+            .await
+            .expect("the response started before its last piece was produced")
+            .unwrap()
+            .unwrap();
+
+        release.send(()).unwrap();
+        let mut body = first.to_vec();
+        while let Some(chunk) = response.chunk().await.unwrap() {
+            body.extend(chunk);
+        }
+        let answered: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(answered["id"], 1);
+        assert_eq!(answered["result"]["value"]["data"][0].as_str().unwrap().len(), PIECES * PIECE);
+    }
+
+    /// An entry node answering every call with its method, served on loopback, and an API key for it
+    async fn answering_methods() -> (String, String, Arc<StubRouter>) {
+        let router = Arc::new(StubRouter::new(|sent| json!({ "jsonrpc": "2.0", "result": sent.request["method"] })));
+        let (service, users) = fixtures::entry(router.clone(), &EntryConfig::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        (serve(service), user.api_key, router)
+    }
+
+    #[tokio::test]
+    async fn a_batch_of_only_notifications_is_answered_with_an_empty_204() {
+        let (url, api_key, router) = answering_methods().await;
+        let batch = json!([
+            { "api_key": api_key, "jsonrpc": "2.0", "method": "getSlot", "params": [] },
+            { "api_key": api_key, "jsonrpc": "2.0", "method": "getBlockHeight", "params": [] },
+        ]);
+
+        let response = reqwest::Client::new().post(&url).json(&batch).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.bytes().await.unwrap().is_empty());
+
+        // Both are still sent on, in the background
+        for _ in 0..100 {
+            if router.sent().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(router.sent().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn a_mixed_batch_is_answered_with_the_responses_to_its_calls_in_order() {
+        let (url, api_key, _router) = answering_methods().await;
+        let batch = json!([
+            { "api_key": api_key, "jsonrpc": "2.0", "id": 1, "method": "getSlot", "params": [] },
+            { "api_key": api_key, "jsonrpc": "2.0", "method": "getHealth", "params": [] },
+            { "jsonrpc": "2.0", "id": 2, "method": "getBalance" },
+            { "api_key": api_key, "jsonrpc": "2.0", "id": "three", "method": "getBlockHeight", "params": [] },
+            { "api_key": api_key, "jsonrpc": "2.0", "method": "getVersion", "params": [] },
+        ]);
+
+        let response = reqwest::Client::new().post(&url).json(&batch).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let answered: Vec<serde_json::Value> = response.json().await.unwrap();
+        let summary: Vec<_> = answered
+            .iter()
+            .map(|response| (response["id"].clone(), response["result"].clone(), response["error"]["code"].clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (json!(1), json!("getSlot"), json!(null)),
+                (json!(2), json!(null), json!(-32600)),
+                (json!("three"), json!("getBlockHeight"), json!(null)),
+            ]
+        );
+    }
+}
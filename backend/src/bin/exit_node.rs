@@ -7,234 +7,307 @@
 //! 3. Receiving responses from RPC providers
 //! 4. Encrypting responses for the return journey
 //! 5. Sending responses back through the circuit
+//!
+//! Usage:
+//!   exit-node [--config <file>] [--check-config]
+//!
+//! `--config` reads the `[common]` and `[exit]` sections of a config file, and
+//! `--check-config` only validates and prints them (see `darknode_backend::config`).
 
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 
 use anyhow::Result;
-use axum::{
-    extract::{Extension, Path},
-    http::StatusCode,
-    routing::{get, post},
-    Json, Router,
-};
+use axum::extract::Extension;
+use base64::Engine;
 use darknode_backend::{
-    exit_node::ExitNodeService,
-    impls::CryptoImpl,
+    attribution::Attestor,
+    audit::AuditLog,
+    build_info::BuildInfo,
+    chains::Network,
+    clock::{self, Timestamp},
+    config::{self, DarknodeConfig},
+    directory::{self, DirectoryFollower},
+    directory_watch,
+    epochs::EpochTracker,
+    flags::FeatureFlags,
+    heartbeat::{self, HeartbeatSource},
+    hop_auth::HopVerifier,
+    identity::{KeyRotator, NodeIdentity},
+    dns::ProviderResolver,
+    nodes::http,
+    outbox::Outbox,
+    reachability,
+    regions,
+    report_auth::ReportSigner,
+    resources::{ProcSampler, ResourceGuard},
+    exit_node::{ExitNodeConfig, ExitNodeService},
+    impls::{CryptoImpl, StoredNodeManager, StoredRpcManager},
+    storage,
+    telemetry::{self, HttpSpans},
     traits::{Crypto, NodeManager, RpcManager},
-    types::{NodeId, NodeRole, NodeStatus, Request, Response, RpcProvider},
+    types::{NodeId, NodeRole, ProviderState, RpcProvider},
 };
-use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
 use tower_http::trace::TraceLayer;
 use tracing::{info, Level};
-use tracing_subscriber::{filter, prelude::*};
 use uuid::Uuid;
 
-/// Configuration for the exit node
-#[derive(Debug, Clone, Deserialize)]
-struct Config {
-    /// The address to listen on
-    listen_addr: SocketAddr,
-    /// The region this node is in
-    region: String,
-    /// The coordinator node to register with
-    coordinator_url: String,
-}
-
-/// Request body for circuit requests
-#[derive(Debug, Clone, Deserialize)]
-struct CircuitRequest {
-    /// The encrypted request
-    request: Request,
-}
-
-/// Response body for circuit responses
-#[derive(Debug, Clone, Serialize)]
-struct CircuitResponse {
-    /// The encrypted response
-    response: Response,
-}
-
-/// Mock implementation of the NodeManager trait
-struct MockNodeManager {
-    nodes: Arc<RwLock<Vec<darknode_backend::types::Node>>>,
-}
-
-impl MockNodeManager {
-    fn new() -> Self {
-        Self {
-            nodes: Arc::new(RwLock::new(Vec::new())),
-        }
-    }
-}
-
-#[async_trait::async_trait]
-impl NodeManager for MockNodeManager {
-    async fn register_node(&self, node: darknode_backend::types::Node) -> Result<()> {
-        let mut nodes = self.nodes.write().await;
-        nodes.push(node);
-        Ok(())
-    }
-
-    async fn update_node_status(&self, node_id: &NodeId, status: NodeStatus) -> Result<()> {
-        let mut nodes = self.nodes.write().await;
-        if let Some(node) = nodes.iter_mut().find(|n| n.id == *node_id) {
-            node.status = status;
-        }
-        Ok(())
-    }
-
-    async fn get_available_nodes(&self, role: NodeRole) -> Result<Vec<darknode_backend::types::Node>> {
-        let nodes = self.nodes.read().await;
-        Ok(nodes
-            .iter()
-            .filter(|n| n.role == role && n.status == NodeStatus::Online)
-            .cloned()
-            .collect())
-    }
-
-    async fn get_node(&self, node_id: &NodeId) -> Result<Option<darknode_backend::types::Node>> {
-        let nodes = self.nodes.read().await;
-        Ok(nodes.iter().find(|n| n.id == *node_id).cloned())
-    }
-}
-
-/// Mock implementation of the RpcManager trait
-struct MockRpcManager {
-    providers: Arc<RwLock<Vec<RpcProvider>>>,
-}
+/// How long a replaced key keeps decrypting traffic for circuits built before rotation
+const KEY_RETENTION: Duration = Duration::from_secs(3600);
 
-impl MockRpcManager {
-    fn new() -> Self {
-        let mut providers = Vec::new();
-        
-        // Add some mock RPC providers
-        providers.push(RpcProvider {
-            id: Uuid::new_v4(),
-            url: "https://api.mainnet-beta.solana.com".to_string(),
-            provider_type: "solana".to_string(),
-            active: true,
-            success_rate: 0.99,
-            avg_latency: Duration::from_millis(100),
-            last_checked: SystemTime::now(),
-        });
-        
-        providers.push(RpcProvider {
-            id: Uuid::new_v4(),
-            url: "https://solana-api.projectserum.com".to_string(),
-            provider_type: "solana".to_string(),
-            active: true,
-            success_rate: 0.98,
-            avg_latency: Duration::from_millis(120),
-            last_checked: SystemTime::now(),
-        });
-        
-        Self {
-            providers: Arc::new(RwLock::new(providers)),
-        }
-    }
-}
+/// How often the coordinator is asked for the current directory
+const DIRECTORY_POLL_INTERVAL: Duration = Duration::from_secs(60);
 
-#[async_trait::async_trait]
-impl RpcManager for MockRpcManager {
-    async fn register_provider(&self, provider: RpcProvider) -> Result<()> {
-        let mut providers = self.providers.write().await;
-        providers.push(provider);
-        Ok(())
+/// Register the demo providers if there are none yet, as on a first start
+async fn register_demo_providers(rpc_manager: &(dyn RpcManager + Send + Sync)) -> Result<()> {
+    if !rpc_manager.get_providers().await?.is_empty() {
+        return Ok(());
     }
     
-    async fn update_provider_status(&self, provider_id: Uuid, active: bool) -> Result<()> {
-        let mut providers = self.providers.write().await;
-        if let Some(provider) = providers.iter_mut().find(|p| p.id == provider_id) {
-            provider.active = active;
-        }
-        Ok(())
-    }
+    // Add some mock RPC providers
+    rpc_manager.register_provider(RpcProvider {
+        id: Uuid::new_v4(),
+        url: "https://api.mainnet-beta.solana.com".to_string(),
+        provider_type: "solana".to_string(),
+        state: ProviderState::Active,
+        success_rate: 0.99,
+        avg_latency: Duration::from_millis(100),
+        last_checked: Timestamp::now(),
+        capabilities: vec!["archive".to_string(), "transaction_history".to_string()],
+        pool: None,
+        network: Network::Mainnet,
+        auth: None,
+        weight: 1,
+        maintenance_windows: Vec::new(),
+        tripped_breakers: 0,
+        failed_probes: 0,
+        quota: None,
+        submission: None,
+    }).await?;
     
-    async fn get_active_providers(&self) -> Result<Vec<RpcProvider>> {
-        let providers = self.providers.read().await;
-        Ok(providers.iter().filter(|p| p.active).cloned().collect())
-    }
+    rpc_manager.register_provider(RpcProvider {
+        id: Uuid::new_v4(),
+        url: "https://solana-api.projectserum.com".to_string(),
+        provider_type: "solana".to_string(),
+        state: ProviderState::Active,
+        success_rate: 0.98,
+        avg_latency: Duration::from_millis(120),
+        last_checked: Timestamp::now(),
+        capabilities: vec!["program_accounts_filters".to_string()],
+        pool: None,
+        network: Network::Mainnet,
+        auth: None,
+        weight: 1,
+        maintenance_windows: Vec::new(),
+        tripped_breakers: 0,
+        failed_probes: 0,
+        quota: None,
+        submission: None,
+    }).await?;
     
-    async fn get_best_provider(&self) -> Result<Option<RpcProvider>> {
-        let providers = self.providers.read().await;
-        let active_providers: Vec<_> = providers.iter().filter(|p| p.active).collect();
-        
-        if active_providers.is_empty() {
-            return Ok(None);
-        }
-        
-        // Find the provider with the highest success rate
-        let best_provider = active_providers
-            .iter()
-            .max_by(|a, b| a.success_rate.partial_cmp(&b.success_rate).unwrap())
-            .unwrap();
-        
-        Ok(Some((*best_provider).clone()))
-    }
-}
-
-/// Handler for circuit requests
-async fn handle_circuit_request(
-    Json(request): Json<CircuitRequest>,
-    Extension(service): Extension<Arc<ExitNodeService>>,
-) -> Result<Json<CircuitResponse>, StatusCode> {
-    // Process the request
-    let response = service
-        .handle_request(&request.request)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    Ok(Json(CircuitResponse { response }))
-}
-
-/// Handler for health checks
-async fn health_check() -> &'static str {
-    "OK"
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::registry()
-        .with(filter::LevelFilter::from_level(Level::INFO))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    // Load configuration, only printing it when asked to check it
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = DarknodeConfig::from_args(&args)?;
+    if config::check_requested(&args) {
+        print!("{}", config.render(&["common", "exit"])?);
+        return Ok(());
+    }
     
-    // Load configuration
-    let config = Config {
-        listen_addr: "127.0.0.1:3002".parse()?,
-        region: "us-east".to_string(),
-        coordinator_url: "http://localhost:3001".to_string(),
-    };
+    // Initialize tracing
+    telemetry::init(&config.common.telemetry, "darknode-exit", Level::INFO)?;
     
-    info!("Starting exit node in region {}", config.region);
+    BuildInfo::current().log_banner("exit-node");
+    info!("Starting exit node in region {}", config.common.region);
     
     // Create dependencies
-    let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl);
-    let node_manager: Arc<dyn NodeManager + Send + Sync> = Arc::new(MockNodeManager::new());
-    let rpc_manager: Arc<dyn RpcManager + Send + Sync> = Arc::new(MockRpcManager::new());
+    let node_id = NodeId(config.common.node_id.unwrap_or_else(Uuid::new_v4));
+    let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+    let storage = storage::open(&config.common.storage).await?;
+    let node_manager: Arc<dyn NodeManager + Send + Sync> = Arc::new(StoredNodeManager::new(storage.clone()));
+    let rpc_manager: Arc<dyn RpcManager + Send + Sync> = Arc::new(StoredRpcManager::new(storage));
+    register_demo_providers(rpc_manager.as_ref()).await?;
+    
+    // Set up the node's long-term identity, which also opens circuit handshakes
+    let identity = Arc::new(
+        NodeIdentity::load_or_generate(&*crypto, config.common.identity_file.as_deref(), KEY_RETENTION).await?,
+    );
+    info!(
+        "Node {} has identity key {}",
+        node_id.0,
+        base64::engine::general_purpose::STANDARD.encode(&identity.public_key(Timestamp::now()).0)
+    );
     
-    // Create the exit node service
+    // Provider attestations are signed with a key of their own, which is never rotated so
+    // attestations keep verifying; the coordinator publishes it apart from the node's record
+    let attestation_key = Arc::new(
+        NodeIdentity::load_or_generate(&*crypto, config.exit.attestation_key_file.as_deref(), Duration::ZERO).await?,
+    );
+    if config.exit.attestation_key_file.is_none() {
+        tracing::warn!("No attestation key file is configured, so attestations issued now stop verifying once the node restarts");
+    }
+    
+    // The network's feature flags, as the directory followed below carries them
+    let feature_flags = FeatureFlags::new();
+    
+    // Create the exit node service, shedding load before it runs out of resources
+    let resources = Arc::new(ResourceGuard::new(config.common.resources.clone()));
     let service = Arc::new(ExitNodeService::new(
-        NodeId(Uuid::new_v4()),
-        crypto,
+        node_id.clone(),
+        crypto.clone(),
         rpc_manager,
+        ProviderResolver::new(config.exit.resolver.clone()),
+        AuditLog::open(config.exit.audit.clone(), Timestamp::now())?,
+        ExitNodeConfig {
+            hedge: config.exit.hedge.clone(),
+            pool: config.exit.pool.clone(),
+            relay: config.exit.relay.clone(),
+            membership: config.exit.membership.clone(),
+            accounting: config.common.accounting.clone(),
+            warmup: config.exit.warmup.clone(),
+            limits: config.exit.upstream.clone(),
+            cache: config.exit.cache.clone(),
+            shaping: config.common.shaping.clone(),
+            multiplex: config.exit.multiplex.clone(),
+            breaker: config.exit.breaker.clone(),
+            budget: config.exit.budget.clone(),
+        },
+    )
+    .with_resource_guard(resources.clone())
+    .with_egress(config.exit.egress.clone())
+    .with_reclaim(config.common.reclaim.clone())
+    .with_identity(identity.clone())
+    .with_attestor(Attestor::new(
+        attestation_key.clone(),
+        crypto.clone(),
+        config.common.region.clone(),
+        config.common.epochs.length,
+    ))
+    .with_flags(feature_flags.clone()));
+    tokio::spawn(resources.clone().run(Arc::new(ProcSampler), vec![service.clone()]));
+    
+    // Reclaim abandoned circuits and forget peers whose strikes have lapsed
+    tokio::spawn(service.clone().run_reclaim());
+    
+    // Keep the budget left across providers current for heartbeats
+    if config.exit.budget.enabled {
+        let budgeter = service.clone();
+        let interval = config.common.heartbeat_interval;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = budgeter.report_budget().await {
+                    tracing::warn!("Failed to work out the request budget left: {}", e);
+                }
+            }
+        });
+    }
+    
+    // Open provider connections now rather than on the first user request
+    tokio::spawn(service.clone().run_warmup());
+    
+    // Release responses on the traffic shaping ticks
+    tokio::spawn(service.clone().run_shaping());
+    
+    // Drop forwarded messages not signed by a node in the directory
+    let hop_verifier = Arc::new(HopVerifier::new(
+        config.common.hop_auth.clone(),
+        node_manager.clone(),
+        crypto.clone(),
+    ));
+    
+    // Rotate the node's identity
+    let rotator = Arc::new(KeyRotator::new(
+        node_id.clone(),
+        identity.clone(),
+        crypto.clone(),
+        config.common.coordinator_url.clone(),
+    ));
+    
+    // Follow the coordinator's signed directories for the nodes and feature flags they carry
+    let follower = Arc::new(
+        DirectoryFollower::new(config.common.directory.clone(), crypto.clone())?
+            .with_flags(feature_flags)
+            .with_nodes(node_manager.clone()),
+    );
+    tokio::spawn(directory::follow(
+        config.common.coordinator_url.clone(),
+        DIRECTORY_POLL_INTERVAL,
+        follower.clone(),
+        Arc::new(EpochTracker::new(config.common.epochs.clone())),
+    ));
+    
+    // Keep the local view of the network in step with the coordinator's as it changes
+    tokio::spawn(directory_watch::follow(
+        config.common.coordinator_url.clone(),
+        config.common.directory_watch.clone(),
+        follower,
+        node_manager.clone(),
+    ));
+    
+    // Queue reports for the coordinator and deliver them whenever it is reachable
+    let outbox = Arc::new(
+        Outbox::open(config.common.outbox.clone())?
+            .with_signer(ReportSigner::new(node_id.clone(), identity.clone(), crypto.clone())),
+    );
+    tokio::spawn(outbox.clone().run(config.common.coordinator_url.clone()));
+    
+    // Time round trips to the other regions for the coordinator's latency matrix
+    if config.common.latency.enabled {
+        tokio::spawn(regions::ping_peers(
+            config.common.latency.clone(),
+            node_id.clone(),
+            node_manager.clone(),
+            service.counters(),
+        ));
+    }
+    
+    // Probe a few peers at random so the coordinator can route around broken edges
+    if config.common.reachability.enabled {
+        tokio::spawn(reachability::probe_peers(
+            config.common.reachability.clone(),
+            node_id.clone(),
+            node_manager.clone(),
+            service.counters(),
+            None,
+        ));
+    }
+    
+    // Report activity to the coordinator
+    tokio::spawn(heartbeat::run(
+        config.common.heartbeat_interval,
+        HeartbeatSource {
+            node_id,
+            roles: vec![NodeRole::Exit],
+            region: config.common.region.clone(),
+            method_classes: Some(config.exit.egress.advertised()),
+            attestation_key: Some(attestation_key.public_key(Timestamp::now())),
+            resources: Some(resources.clone()),
+        },
+        service.counters(),
+        outbox,
     ));
     
-    // Create the router
-    let app = Router::new()
-        .route("/", post(handle_circuit_request))
-        .route("/health", get(health_check))
-        .layer(TraceLayer::new_for_http())
-        .layer(Extension(service));
+    // Create the router; administrative routes take the operator token
+    let app = http::exit_routes(service)
+        .merge(http::node_routes())
+        .layer(axum::middleware::from_fn(clock::write_timestamps))
+        .layer(TraceLayer::new_for_http().make_span_with(HttpSpans::between_hops(&config.common.telemetry)))
+        .layer(Extension(rotator))
+        .layer(Extension(Arc::new(config.common.operator.clone())))
+        .layer(Extension(config.common.timestamps))
+        .layer(Extension(hop_verifier));
     
     // Start the server
-    info!("Listening on {}", config.listen_addr);
-    axum::Server::bind(&config.listen_addr)
-        .serve(app.into_make_service())
+    info!("Listening on {}", config.exit.listen_addr);
+    axum::Server::bind(&config.exit.listen_addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await?;
     
     Ok(())
@@ -0,0 +1,331 @@
+//! DarkNode Node
+//!
+//! This binary runs several node roles in one process, on one listener.
+//! Roles are enabled in the `[node]` section of the config file:
+//! - Routing: forwards layered requests and responses between hops (`/forward`, `/receive`)
+//! - Exit: serves requests against RPC providers (`/`, `/admin/audit/...`)
+//!
+//! All roles share the node's identity, key rotation, and heartbeat, so the coordinator
+//! sees a single node advertising every enabled role. Entry and coordinator nodes keep
+//! their dedicated binaries.
+//!
+//! Usage:
+//!   darknode-node [--config <file>] [--check-config]
+//!
+//! `--config` reads the `[common]` and `[node]` sections of a config file, and the
+//! `[routing]` and `[exit]` sections for the roles, whose listen addresses are ignored.
+//! `--check-config` only validates and prints them (see `darknode_backend::config`).
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use axum::extract::Extension;
+use base64::Engine;
+use darknode_backend::{
+    attribution::Attestor,
+    audit::AuditLog,
+    build_info::BuildInfo,
+    chains::Network,
+    clock::{self, Timestamp},
+    config::{self, DarknodeConfig},
+    directory::{self, DirectoryFollower},
+    directory_watch,
+    dns::ProviderResolver,
+    epochs::EpochTracker,
+    exit_node::{ExitNodeConfig, ExitNodeService},
+    flags::FeatureFlags,
+    nodes::http,
+    outbox::Outbox,
+    heartbeat::{self, ActivityCounters, HeartbeatSource},
+    hop_auth::{HopSigner, HopVerifier},
+    identity::{KeyRotator, NodeIdentity},
+    impls::{CryptoImpl, StoredNodeManager, StoredRpcManager},
+    report_auth::ReportSigner,
+    resources::{LoadShedding, ProcSampler, ResourceGuard},
+    routing_node::RoutingNodeService,
+    storage,
+    telemetry::{self, HttpSpans},
+    traits::{Crypto, NodeManager, RpcManager},
+    transport::HopClient,
+    types::{NodeId, NodeRole, ProviderState, RpcProvider},
+};
+use tower_http::trace::TraceLayer;
+use tracing::{info, Level};
+use uuid::Uuid;
+
+/// How long a replaced key keeps decrypting traffic for circuits built before rotation
+const KEY_RETENTION: Duration = Duration::from_secs(3600);
+
+/// How often the coordinator is asked for the current directory
+const DIRECTORY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Register the demo providers if there are none yet, as on a first start
+async fn register_demo_providers(rpc_manager: &(dyn RpcManager + Send + Sync)) -> Result<()> {
+    if !rpc_manager.get_providers().await?.is_empty() {
+        return Ok(());
+    }
+    
+    // Add some mock RPC providers
+    rpc_manager.register_provider(RpcProvider {
+        id: Uuid::new_v4(),
+        url: "https://api.mainnet-beta.solana.com".to_string(),
+        provider_type: "solana".to_string(),
+        state: ProviderState::Active,
+        success_rate: 0.99,
+        avg_latency: Duration::from_millis(100),
+        last_checked: Timestamp::now(),
+        capabilities: vec!["archive".to_string(), "transaction_history".to_string()],
+        pool: None,
+        network: Network::Mainnet,
+        auth: None,
+        weight: 1,
+        maintenance_windows: Vec::new(),
+        tripped_breakers: 0,
+        failed_probes: 0,
+        quota: None,
+        submission: None,
+    }).await?;
+    
+    rpc_manager.register_provider(RpcProvider {
+        id: Uuid::new_v4(),
+        url: "https://solana-api.projectserum.com".to_string(),
+        provider_type: "solana".to_string(),
+        state: ProviderState::Active,
+        success_rate: 0.98,
+        avg_latency: Duration::from_millis(120),
+        last_checked: Timestamp::now(),
+        capabilities: vec!["program_accounts_filters".to_string()],
+        pool: None,
+        network: Network::Mainnet,
+        auth: None,
+        weight: 1,
+        maintenance_windows: Vec::new(),
+        tripped_breakers: 0,
+        failed_probes: 0,
+        quota: None,
+        submission: None,
+    }).await?;
+    
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Load configuration, only printing it when asked to check it
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = DarknodeConfig::from_args(&args)?;
+    if config::check_requested(&args) {
+        print!("{}", config.render(&["common", "node", "routing", "exit"])?);
+        return Ok(());
+    }
+    
+    // Initialize tracing
+    telemetry::init(&config.common.telemetry, "darknode-node", Level::INFO)?;
+    
+    if config.node.roles.is_empty() {
+        bail!("No roles enabled");
+    }
+    for role in &config.node.roles {
+        match role {
+            NodeRole::Routing | NodeRole::Exit => {}
+            NodeRole::Entry => bail!("The entry role runs in the entry-node binary"),
+            NodeRole::Coordinator => bail!("The coordinator role runs in the coordinator binary"),
+        }
+    }
+    
+    BuildInfo::current().log_banner("darknode-node");
+    info!("Starting node with roles {:?} in region {}", config.node.roles, config.common.region);
+    
+    // Create dependencies shared by every role
+    let node_id = NodeId(config.common.node_id.unwrap_or_else(Uuid::new_v4));
+    let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+    let counters = Arc::new(ActivityCounters::new());
+    let resources = Arc::new(ResourceGuard::new(config.common.resources.clone()));
+    let mut shedding: Vec<Arc<dyn LoadShedding + Send + Sync>> = Vec::new();
+    let storage = storage::open(&config.common.storage).await?;
+    let node_manager: Arc<dyn NodeManager + Send + Sync> = Arc::new(StoredNodeManager::new(storage.clone()));
+    let feature_flags = FeatureFlags::new();
+    
+    // Drop forwarded messages not signed by a node in the directory
+    let hop_verifier = Arc::new(HopVerifier::new(config.common.hop_auth.clone(), node_manager.clone(), crypto.clone()));
+    
+    // Set up the node's long-term identity and its rotation
+    let identity = Arc::new(
+        NodeIdentity::load_or_generate(&*crypto, config.common.identity_file.as_deref(), KEY_RETENTION).await?,
+    );
+    info!(
+        "Node {} has identity key {}",
+        node_id.0,
+        base64::engine::general_purpose::STANDARD.encode(&identity.public_key(Timestamp::now()).0)
+    );
+    let rotator = Arc::new(KeyRotator::new(
+        node_id.clone(),
+        identity.clone(),
+        crypto.clone(),
+        config.common.coordinator_url.clone(),
+    ));
+    
+    // Create the router, mounting the routes of each enabled role; administrative routes
+    // take the operator token
+    let mut app = http::node_routes();
+    
+    if config.node.roles.contains(&NodeRole::Routing) {
+        let hops = Arc::new(HopClient::new(
+            HopSigner::new(node_id.clone(), identity.clone(), crypto.clone()).with_telemetry(config.common.telemetry.clone()),
+        ));
+        let service = Arc::new(
+            RoutingNodeService::new(
+                node_id.clone(),
+                crypto.clone(),
+                identity.clone(),
+                hops,
+                config.common.accounting.clone(),
+                config.routing.bandwidth.clone(),
+            )
+            .with_counters(counters.clone())
+            .with_resource_guard(resources.clone())
+            .with_reclaim(config.common.reclaim.clone()),
+        );
+        tokio::spawn(service.clone().run_egress());
+        tokio::spawn(service.clone().run_reclaim());
+        shedding.push(service.clone());
+        app = app.merge(http::routing_routes(service));
+    }
+    
+    let mut attestation_key = None;
+    if config.node.roles.contains(&NodeRole::Exit) {
+        // Provider attestations are signed with a key of their own, never rotated, see the exit node
+        let attestor_key = Arc::new(
+            NodeIdentity::load_or_generate(&*crypto, config.exit.attestation_key_file.as_deref(), Duration::ZERO).await?,
+        );
+        if config.exit.attestation_key_file.is_none() {
+            tracing::warn!("No attestation key file is configured, so attestations issued now stop verifying once the node restarts");
+        }
+        attestation_key = Some(attestor_key.public_key(Timestamp::now()));
+        let rpc_manager: Arc<dyn RpcManager + Send + Sync> = Arc::new(StoredRpcManager::new(storage));
+        register_demo_providers(rpc_manager.as_ref()).await?;
+        let service = Arc::new(
+            ExitNodeService::new(
+                node_id.clone(),
+                crypto.clone(),
+                rpc_manager,
+                ProviderResolver::new(config.exit.resolver.clone()),
+                AuditLog::open(config.exit.audit.clone(), Timestamp::now())?,
+                ExitNodeConfig {
+                    hedge: config.exit.hedge.clone(),
+                    pool: config.exit.pool.clone(),
+                    relay: config.exit.relay.clone(),
+                    membership: config.exit.membership.clone(),
+                    accounting: config.common.accounting.clone(),
+                    warmup: config.exit.warmup.clone(),
+                    limits: config.exit.upstream.clone(),
+                    cache: config.exit.cache.clone(),
+                    shaping: config.common.shaping.clone(),
+                    multiplex: config.exit.multiplex.clone(),
+                    breaker: config.exit.breaker.clone(),
+                    budget: config.exit.budget.clone(),
+                },
+            )
+            .with_counters(counters.clone())
+            .with_resource_guard(resources.clone())
+            .with_egress(config.exit.egress.clone())
+            .with_reclaim(config.common.reclaim.clone())
+            .with_identity(identity.clone())
+            .with_attestor(Attestor::new(
+                attestor_key,
+                crypto.clone(),
+                config.common.region.clone(),
+                config.common.epochs.length,
+            ))
+            .with_flags(feature_flags.clone()),
+        );
+        shedding.push(service.clone());
+        tokio::spawn(service.clone().run_reclaim());
+        
+        // Keep the budget left across providers current for heartbeats
+        if config.exit.budget.enabled {
+            let budgeter = service.clone();
+            let interval = config.common.heartbeat_interval;
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    if let Err(e) = budgeter.report_budget().await {
+                        tracing::warn!("Failed to work out the request budget left: {}", e);
+                    }
+                }
+            });
+        }
+        
+        // Open provider connections now rather than on the first user request
+        tokio::spawn(service.clone().run_warmup());
+        
+        // Release responses on the traffic shaping ticks
+        tokio::spawn(service.clone().run_shaping());
+        app = app.merge(http::exit_routes(service));
+    }
+    
+    // Shed the load of every role before the node runs out of resources
+    tokio::spawn(resources.clone().run(Arc::new(ProcSampler), shedding));
+    
+    // Follow the coordinator's signed directories for the nodes and feature flags they carry
+    let follower = Arc::new(
+        DirectoryFollower::new(config.common.directory.clone(), crypto.clone())?
+            .with_flags(feature_flags)
+            .with_nodes(node_manager.clone()),
+    );
+    tokio::spawn(directory::follow(
+        config.common.coordinator_url.clone(),
+        DIRECTORY_POLL_INTERVAL,
+        follower.clone(),
+        Arc::new(EpochTracker::new(config.common.epochs.clone())),
+    ));
+    
+    // Keep the local view of the network in step with the coordinator's as it changes
+    tokio::spawn(directory_watch::follow(
+        config.common.coordinator_url.clone(),
+        config.common.directory_watch.clone(),
+        follower,
+        node_manager.clone(),
+    ));
+    
+    // Queue reports for the coordinator and deliver them whenever it is reachable
+    let outbox = Arc::new(
+        Outbox::open(config.common.outbox.clone())?
+            .with_signer(ReportSigner::new(node_id.clone(), identity.clone(), crypto.clone())),
+    );
+    tokio::spawn(outbox.clone().run(config.common.coordinator_url.clone()));
+    
+    // Report the activity of all roles to the coordinator as one node
+    tokio::spawn(heartbeat::run(
+        config.common.heartbeat_interval,
+        HeartbeatSource {
+            node_id,
+            roles: config.node.roles.clone(),
+            region: config.common.region.clone(),
+            method_classes: config.node.roles.contains(&NodeRole::Exit).then(|| config.exit.egress.advertised()),
+            attestation_key,
+            resources: Some(resources.clone()),
+        },
+        counters,
+        outbox,
+    ));
+    
+    let app = app
+        .layer(axum::middleware::from_fn(clock::write_timestamps))
+        .layer(TraceLayer::new_for_http().make_span_with(HttpSpans::between_hops(&config.common.telemetry)))
+        .layer(Extension(rotator))
+        .layer(Extension(Arc::new(config.common.operator.clone())))
+        .layer(Extension(config.common.timestamps))
+        .layer(Extension(hop_verifier));
+    
+    // Start the server
+    info!("Listening on {}", config.node.listen_addr);
+    axum::Server::bind(&config.node.listen_addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+    
+    Ok(())
+}
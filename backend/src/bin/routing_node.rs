@@ -7,196 +7,183 @@
 //! 3. Re-encrypting for the next hop
 //! 4. Forwarding to the next hop
 //! 5. Handling responses in the reverse direction
+//!
+//! Usage:
+//!   routing-node [--config <file>] [--check-config]
+//!
+//! `--config` reads the `[common]` and `[routing]` sections of a config file, and
+//! `--check-config` only validates and prints them (see `darknode_backend::config`).
 
-use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::Duration;
 
 use anyhow::Result;
-use axum::{
-    extract::{Extension, Path},
-    http::StatusCode,
-    routing::{get, post},
-    Json, Router,
-};
+use axum::extract::Extension;
+use base64::Engine;
 use darknode_backend::{
-    impls::CryptoImpl,
+    build_info::BuildInfo,
+    clock,
+    config::{self, DarknodeConfig},
+    directory::DirectoryFollower,
+    directory_watch,
+    heartbeat::{self, HeartbeatSource},
+    hop_auth::{HopSigner, HopVerifier},
+    identity::{KeyRotator, NodeIdentity},
+    impls::{CryptoImpl, StoredNodeManager},
+    nodes::http,
+    outbox::Outbox,
+    reachability,
+    regions,
+    report_auth::ReportSigner,
+    resources::{ProcSampler, ResourceGuard},
     routing_node::RoutingNodeService,
+    storage,
+    telemetry::{self, HttpSpans},
     traits::{Crypto, NodeManager},
-    types::{NodeId, NodeRole, NodeStatus, Request, Response},
+    transport::HopClient,
+    types::{NodeId, NodeRole},
 };
-use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
 use tower_http::trace::TraceLayer;
 use tracing::{info, Level};
-use tracing_subscriber::{filter, prelude::*};
 use uuid::Uuid;
 
-/// Configuration for the routing node
-#[derive(Debug, Clone, Deserialize)]
-struct Config {
-    /// The address to listen on
-    listen_addr: SocketAddr,
-    /// The region this node is in
-    region: String,
-    /// The coordinator node to register with
-    coordinator_url: String,
-}
-
-/// Request body for forwarding requests
-#[derive(Debug, Clone, Deserialize)]
-struct ForwardRequest {
-    /// The encrypted request
-    request: Request,
-}
-
-/// Response body for forwarding responses
-#[derive(Debug, Clone, Serialize)]
-struct ForwardResponse {
-    /// Whether the forwarding was successful
-    success: bool,
-    /// Error message, if any
-    error: Option<String>,
-}
-
-/// Request body for receiving responses
-#[derive(Debug, Clone, Deserialize)]
-struct ReceiveResponse {
-    /// The encrypted response
-    response: Response,
-}
-
-/// Response body for receiving responses
-#[derive(Debug, Clone, Serialize)]
-struct ReceiveResponseResult {
-    /// Whether the receiving was successful
-    success: bool,
-    /// Error message, if any
-    error: Option<String>,
-}
-
-/// Mock implementation of the NodeManager trait
-struct MockNodeManager {
-    nodes: Arc<RwLock<Vec<darknode_backend::types::Node>>>,
-}
-
-impl MockNodeManager {
-    fn new() -> Self {
-        Self {
-            nodes: Arc::new(RwLock::new(Vec::new())),
-        }
-    }
-}
-
-#[async_trait::async_trait]
-impl NodeManager for MockNodeManager {
-    async fn register_node(&self, node: darknode_backend::types::Node) -> Result<()> {
-        let mut nodes = self.nodes.write().await;
-        nodes.push(node);
-        Ok(())
-    }
-
-    async fn update_node_status(&self, node_id: &NodeId, status: NodeStatus) -> Result<()> {
-        let mut nodes = self.nodes.write().await;
-        if let Some(node) = nodes.iter_mut().find(|n| n.id == *node_id) {
-            node.status = status;
-        }
-        Ok(())
-    }
-
-    async fn get_available_nodes(&self, role: NodeRole) -> Result<Vec<darknode_backend::types::Node>> {
-        let nodes = self.nodes.read().await;
-        Ok(nodes
-            .iter()
-            .filter(|n| n.role == role && n.status == NodeStatus::Online)
-            .cloned()
-            .collect())
-    }
-
-    async fn get_node(&self, node_id: &NodeId) -> Result<Option<darknode_backend::types::Node>> {
-        let nodes = self.nodes.read().await;
-        Ok(nodes.iter().find(|n| n.id == *node_id).cloned())
-    }
-}
-
-/// Handler for forwarding requests
-async fn handle_forward_request(
-    Json(request): Json<ForwardRequest>,
-    Extension(service): Extension<Arc<RoutingNodeService>>,
-) -> Result<Json<ForwardResponse>, StatusCode> {
-    // Process the request
-    match service.handle_request(&request.request).await {
-        Ok(_) => Ok(Json(ForwardResponse {
-            success: true,
-            error: None,
-        })),
-        Err(e) => Ok(Json(ForwardResponse {
-            success: false,
-            error: Some(e.to_string()),
-        })),
-    }
-}
-
-/// Handler for receiving responses
-async fn handle_receive_response(
-    Json(response): Json<ReceiveResponse>,
-    Extension(service): Extension<Arc<RoutingNodeService>>,
-) -> Result<Json<ReceiveResponseResult>, StatusCode> {
-    // Process the response
-    match service.handle_response(&response.response).await {
-        Ok(_) => Ok(Json(ReceiveResponseResult {
-            success: true,
-            error: None,
-        })),
-        Err(e) => Ok(Json(ReceiveResponseResult {
-            success: false,
-            error: Some(e.to_string()),
-        })),
-    }
-}
-
-/// Handler for health checks
-async fn health_check() -> &'static str {
-    "OK"
-}
+/// How long a replaced key keeps decrypting traffic for circuits built before rotation
+const KEY_RETENTION: Duration = Duration::from_secs(3600);
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::registry()
-        .with(filter::LevelFilter::from_level(Level::INFO))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    // Load configuration, only printing it when asked to check it
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = DarknodeConfig::from_args(&args)?;
+    if config::check_requested(&args) {
+        print!("{}", config.render(&["common", "routing"])?);
+        return Ok(());
+    }
     
-    // Load configuration
-    let config = Config {
-        listen_addr: "127.0.0.1:3003".parse()?,
-        region: "us-east".to_string(),
-        coordinator_url: "http://localhost:3001".to_string(),
-    };
+    // Initialize tracing
+    telemetry::init(&config.common.telemetry, "darknode-routing", Level::INFO)?;
     
-    info!("Starting routing node in region {}", config.region);
+    BuildInfo::current().log_banner("routing-node");
+    info!("Starting routing node in region {}", config.common.region);
     
     // Create dependencies
-    let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl);
+    let node_id = NodeId(config.common.node_id.unwrap_or_else(Uuid::new_v4));
+    let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+    let node_manager: Arc<dyn NodeManager + Send + Sync> = Arc::new(StoredNodeManager::new(storage::open(&config.common.storage).await?));
+    
+    // Set up the node's long-term identity and its rotation
+    let identity = Arc::new(
+        NodeIdentity::load_or_generate(&*crypto, config.common.identity_file.as_deref(), KEY_RETENTION).await?,
+    );
+    info!(
+        "Node {} has identity key {}",
+        node_id.0,
+        base64::engine::general_purpose::STANDARD.encode(&identity.public_key(clock::Timestamp::now()).0)
+    );
+    
+    // Create the routing node service, passing circuits on signed with the node's identity and
+    // shedding load before it runs out of resources
+    let hops = Arc::new(HopClient::new(
+        HopSigner::new(node_id.clone(), identity.clone(), crypto.clone()).with_telemetry(config.common.telemetry.clone()),
+    ));
+    let resources = Arc::new(ResourceGuard::new(config.common.resources.clone()));
+    let service = Arc::new(
+        RoutingNodeService::new(
+            node_id.clone(),
+            crypto.clone(),
+            identity.clone(),
+            hops,
+            config.common.accounting.clone(),
+            config.routing.bandwidth.clone(),
+        )
+        .with_resource_guard(resources.clone())
+        .with_reclaim(config.common.reclaim.clone()),
+    );
+    tokio::spawn(service.clone().run_egress());
+    tokio::spawn(service.clone().run_reclaim());
+    tokio::spawn(resources.clone().run(Arc::new(ProcSampler), vec![service.clone()]));
+    
+    // Drop forwarded messages not signed by a node in the directory
+    let hop_verifier = Arc::new(HopVerifier::new(
+        config.common.hop_auth.clone(),
+        node_manager.clone(),
+        crypto.clone(),
+    ));
+    
+    // Set up the node's key rotation
+    let rotator = Arc::new(KeyRotator::new(
+        node_id.clone(),
+        identity.clone(),
+        crypto.clone(),
+        config.common.coordinator_url.clone(),
+    ));
+    
+    // Keep the local view of the network in step with the coordinator's as it changes,
+    // taking only changes signed with the coordinator's key
+    let follower = Arc::new(DirectoryFollower::new(config.common.directory.clone(), crypto.clone())?);
+    tokio::spawn(directory_watch::follow(
+        config.common.coordinator_url.clone(),
+        config.common.directory_watch.clone(),
+        follower,
+        node_manager.clone(),
+    ));
+    
+    // Queue reports for the coordinator and deliver them whenever it is reachable
+    let outbox = Arc::new(
+        Outbox::open(config.common.outbox.clone())?
+            .with_signer(ReportSigner::new(node_id.clone(), identity.clone(), crypto.clone())),
+    );
+    tokio::spawn(outbox.clone().run(config.common.coordinator_url.clone()));
+    
+    // Time round trips to the other regions for the coordinator's latency matrix
+    if config.common.latency.enabled {
+        tokio::spawn(regions::ping_peers(
+            config.common.latency.clone(),
+            node_id.clone(),
+            node_manager.clone(),
+            service.counters(),
+        ));
+    }
+    
+    // Probe a few peers at random so the coordinator can route around broken edges
+    if config.common.reachability.enabled {
+        tokio::spawn(reachability::probe_peers(
+            config.common.reachability.clone(),
+            node_id.clone(),
+            node_manager.clone(),
+            service.counters(),
+            None,
+        ));
+    }
     
-    // Create the routing node service
-    let service = Arc::new(RoutingNodeService::new(
-        NodeId(Uuid::new_v4()),
-        crypto,
+    // Report activity to the coordinator
+    tokio::spawn(heartbeat::run(
+        config.common.heartbeat_interval,
+        HeartbeatSource {
+            node_id,
+            roles: vec![NodeRole::Routing],
+            region: config.common.region.clone(),
+            method_classes: None,
+            attestation_key: None,
+            resources: Some(resources.clone()),
+        },
+        service.counters(),
+        outbox,
     ));
     
-    // Create the router
-    let app = Router::new()
-        .route("/forward", post(handle_forward_request))
-        .route("/receive", post(handle_receive_response))
-        .route("/health", get(health_check))
-        .layer(TraceLayer::new_for_http())
-        .layer(Extension(service));
+    // Create the router; administrative routes take the operator token
+    let app = http::routing_routes(service)
+        .merge(http::node_routes())
+        .layer(axum::middleware::from_fn(clock::write_timestamps))
+        .layer(TraceLayer::new_for_http().make_span_with(HttpSpans::between_hops(&config.common.telemetry)))
+        .layer(Extension(rotator))
+        .layer(Extension(Arc::new(config.common.operator.clone())))
+        .layer(Extension(config.common.timestamps))
+        .layer(Extension(hop_verifier));
     
     // Start the server
-    info!("Listening on {}", config.listen_addr);
-    axum::Server::bind(&config.listen_addr)
+    info!("Listening on {}", config.routing.listen_addr);
+    axum::Server::bind(&config.routing.listen_addr)
         .serve(app.into_make_service())
         .await?;
     
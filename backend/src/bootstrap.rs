@@ -0,0 +1,351 @@
+//! Seeding a fresh coordinator from a static list of providers and nodes
+//!
+//! A new deployment starts with an empty coordinator. Instead of scripting registrations
+//! by hand, operators list the initial RPC providers and the public keys of nodes allowed
+//! to register in a seed file. The coordinator seeds its providers from it on first start,
+//! when it has none, or whenever it is started with `--reseed`. Seeding only ever adds or
+//! updates entries, matching providers by URL, so applying the same file twice changes
+//! nothing and nothing registered since is removed.
+
+use super::*;
+use super::chains::Network;
+use super::traits::RpcManager;
+use super::types::{CryptoKey, ProviderState, RpcProvider};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Length of a node's public key in bytes
+const PUBLIC_KEY_LEN: usize = 32;
+
+/// An RPC provider listed in the seed file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SeedProvider {
+    /// The URL of the provider
+    pub url: String,
+    /// The type of provider (e.g., solana, ethereum)
+    pub provider_type: String,
+    /// Value of the `Authorization` header the provider requires, if any
+    #[serde(default)]
+    pub auth: Option<String>,
+    /// Relative weight in provider selection
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Capabilities the provider supports
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// The operator pool the provider belongs to, or `None` for the shared pool
+    #[serde(default)]
+    pub pool: Option<String>,
+    /// The network of its chain the provider is on
+    #[serde(default)]
+    pub network: Network,
+}
+
+/// Weight of seeded providers that don't set one
+fn default_weight() -> u32 {
+    1
+}
+
+/// The providers and nodes a coordinator starts out with
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootstrapConfig {
+    /// RPC providers to register
+    #[serde(default)]
+    pub providers: Vec<SeedProvider>,
+    /// Base64 public keys of the nodes allowed to register; any node may if empty
+    #[serde(default)]
+    pub authorized_nodes: Vec<String>,
+}
+
+/// An entry in the seed file is invalid
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("invalid seed entry {entry}: {reason}")]
+pub struct InvalidSeed {
+    /// The offending entry, such as `providers[1] (htp://bad)`
+    pub entry: String,
+    /// What is wrong with it
+    pub reason: String,
+}
+
+impl BootstrapConfig {
+    /// Read and validate a seed file
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read seed file {}: {}", path.display(), e))?;
+        let config: Self = serde_json::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Failed to parse seed file {}: {}", path.display(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+    
+    /// Check every entry, failing on the first invalid one
+    pub fn validate(&self) -> Result<(), InvalidSeed> {
+        let mut urls = HashSet::new();
+        for (i, provider) in self.providers.iter().enumerate() {
+            let invalid = |reason: &str| InvalidSeed {
+                entry: format!("providers[{}] ({})", i, provider.url),
+                reason: reason.to_string(),
+            };
+            match reqwest::Url::parse(&provider.url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") && url.host().is_some() => {}
+                Ok(_) => return Err(invalid("url must be an http or https URL with a host")),
+                Err(e) => return Err(invalid(&format!("url is not valid: {}", e))),
+            }
+            if provider.provider_type.trim().is_empty() {
+                return Err(invalid("provider_type must not be empty"));
+            }
+            if provider.weight == 0 {
+                return Err(invalid("weight must be at least 1"));
+            }
+            if !urls.insert(provider.url.as_str()) {
+                return Err(invalid("url is listed more than once"));
+            }
+        }
+        for (i, key) in self.authorized_nodes.iter().enumerate() {
+            decode_key(key).map_err(|reason| InvalidSeed {
+                entry: format!("authorized_nodes[{}] ({})", i, key),
+                reason,
+            })?;
+        }
+        Ok(())
+    }
+    
+    /// The public keys of the nodes allowed to register
+    pub fn node_keys(&self) -> Result<Vec<CryptoKey>, InvalidSeed> {
+        self.validate()?;
+        Ok(self
+            .authorized_nodes
+            .iter()
+            .filter_map(|key| decode_key(key).ok())
+            .collect())
+    }
+}
+
+/// Decode a base64 public key, checking its length
+fn decode_key(key: &str) -> Result<CryptoKey, String> {
+    let bytes = STANDARD
+        .decode(key)
+        .map_err(|e| format!("public key is not valid base64: {}", e))?;
+    if bytes.len() != PUBLIC_KEY_LEN {
+        return Err(format!(
+            "public key must be {} bytes, got {}",
+            PUBLIC_KEY_LEN,
+            bytes.len()
+        ));
+    }
+    Ok(CryptoKey(bytes))
+}
+
+/// Public keys of the nodes allowed to register with the coordinator
+///
+/// An empty allowlist lets any node register, as before seeding existed.
+#[derive(Default)]
+pub struct NodeAllowlist {
+    keys: parking_lot::RwLock<HashSet<Vec<u8>>>,
+}
+
+impl NodeAllowlist {
+    /// Create an empty allowlist
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Allow the node holding `key` to register, returning whether it was newly added
+    pub fn authorize(&self, key: &CryptoKey) -> bool {
+        self.keys.write().insert(key.0.clone())
+    }
+    
+    /// Whether the node holding `key` may register
+    pub fn allows(&self, key: &CryptoKey) -> bool {
+        let keys = self.keys.read();
+        keys.is_empty() || keys.contains(&key.0)
+    }
+    
+    /// Number of authorized keys
+    pub fn len(&self) -> usize {
+        self.keys.read().len()
+    }
+    
+    /// Whether no keys are authorized, leaving registration open
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// What seeding changed
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SeedReport {
+    /// Providers registered for the first time
+    pub providers_added: usize,
+    /// Existing providers whose settings were updated
+    pub providers_updated: usize,
+    /// Node keys newly allowed to register
+    pub nodes_authorized: usize,
+}
+
+/// Apply a seed file to a coordinator's providers and node allowlist
+///
+/// Providers are only seeded when none are registered yet, unless `reseed` is set. The
+/// allowlist isn't persisted, so node keys are always applied.
+pub async fn seed(
+    config: &BootstrapConfig,
+    rpc_manager: &(dyn RpcManager + Send + Sync),
+    allowlist: &NodeAllowlist,
+    reseed: bool,
+) -> Result<SeedReport> {
+    let mut report = SeedReport::default();
+    for key in config.node_keys()? {
+        if allowlist.authorize(&key) {
+            report.nodes_authorized += 1;
+        }
+    }
+    
+    let existing = rpc_manager.get_providers().await?;
+    if !existing.is_empty() && !reseed {
+        return Ok(report);
+    }
+    for seed in &config.providers {
+        match existing.iter().find(|provider| provider.url == seed.url) {
+            Some(provider) => {
+                let updated = RpcProvider {
+                    provider_type: seed.provider_type.clone(),
+                    auth: seed.auth.clone(),
+                    weight: seed.weight,
+                    capabilities: seed.capabilities.clone(),
+                    pool: seed.pool.clone(),
+                    network: seed.network,
+                    ..provider.clone()
+                };
+                if !same_settings(provider, &updated) {
+                    rpc_manager.update_provider(updated).await?;
+                    report.providers_updated += 1;
+                }
+            }
+            None => {
+                rpc_manager
+                    .register_provider(RpcProvider {
+                        id: Uuid::new_v4(),
+                        url: seed.url.clone(),
+                        provider_type: seed.provider_type.clone(),
+                        state: ProviderState::Active,
+                        success_rate: 1.0,
+                        avg_latency: Duration::ZERO,
+                        last_checked: Timestamp::now(),
+                        capabilities: seed.capabilities.clone(),
+                        pool: seed.pool.clone(),
+                        network: seed.network,
+                        auth: seed.auth.clone(),
+                        weight: seed.weight,
+                        maintenance_windows: Vec::new(),
+                        tripped_breakers: 0,
+                        failed_probes: 0,
+                        quota: None,
+                        submission: None,
+                    })
+                    .await?;
+                report.providers_added += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Whether two providers have the same seeded settings
+fn same_settings(a: &RpcProvider, b: &RpcProvider) -> bool {
+    a.provider_type == b.provider_type
+        && a.auth == b.auth
+        && a.weight == b.weight
+        && a.capabilities == b.capabilities
+        && a.pool == b.pool
+        && a.network == b.network
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::StoredRpcManager;
+    use crate::storage::MemoryStorage;
+    use serde_json::json;
+    
+    fn write(path: &Path, providers: serde_json::Value) {
+        let seed = json!({ "providers": providers, "authorized_nodes": [STANDARD.encode([7; PUBLIC_KEY_LEN])] });
+        std::fs::write(path, serde_json::to_vec(&seed).unwrap()).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn a_bad_entry_aborts_and_once_fixed_reseeding_changes_nothing() {
+        let dir = std::env::temp_dir().join(format!("darknode-seed-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("seed.json");
+        let good = [
+            json!({ "url": "https://rpc-a.example.com/", "provider_type": "solana" }),
+            json!({ "url": "https://rpc-b.example.com/", "provider_type": "solana", "weight": 3 }),
+        ];
+        
+        write(&path, json!([good[0], good[1], { "url": "htp//rpc-c.example.com", "provider_type": "solana" }]));
+        let refused = BootstrapConfig::load(&path).unwrap_err();
+        let invalid = refused.downcast_ref::<InvalidSeed>().unwrap();
+        assert_eq!(invalid.entry, "providers[2] (htp//rpc-c.example.com)");
+        
+        write(&path, json!(good));
+        let config = BootstrapConfig::load(&path).unwrap();
+        let rpc_manager = StoredRpcManager::new(Arc::new(MemoryStorage::new()));
+        let allowlist = NodeAllowlist::new();
+        let report = seed(&config, &rpc_manager, &allowlist, false).await.unwrap();
+        assert_eq!((report.providers_added, report.providers_updated, report.nodes_authorized), (2, 0, 1));
+        let mut urls: Vec<String> = rpc_manager
+            .get_active_providers()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|provider| provider.url)
+            .collect();
+        urls.sort();
+        assert_eq!(urls, ["https://rpc-a.example.com/", "https://rpc-b.example.com/"]);
+        
+        // Reseeding matches providers by URL, and leaves those registered since alone
+        let registered = crate::fixtures::provider();
+        rpc_manager.register_provider(registered.clone()).await.unwrap();
+        let report = seed(&config, &rpc_manager, &allowlist, true).await.unwrap();
+        assert_eq!((report.providers_added, report.providers_updated, report.nodes_authorized), (0, 0, 0));
+        let providers = rpc_manager.get_providers().await.unwrap();
+        assert_eq!(providers.len(), 3);
+        assert!(providers.iter().any(|provider| provider.id == registered.id));
+        assert_eq!(allowlist.len(), 1);
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn reseeding_updates_changed_settings_and_first_starts_only_seed_an_empty_coordinator() {
+        let mut config = BootstrapConfig {
+            providers: vec![SeedProvider {
+                url: "https://rpc-a.example.com/".to_string(),
+                provider_type: "solana".to_string(),
+                auth: None,
+                weight: 1,
+                capabilities: Vec::new(),
+                pool: None,
+                network: Network::Mainnet,
+            }],
+            authorized_nodes: Vec::new(),
+        };
+        let rpc_manager = StoredRpcManager::new(Arc::new(MemoryStorage::new()));
+        let allowlist = NodeAllowlist::new();
+        seed(&config, &rpc_manager, &allowlist, false).await.unwrap();
+        
+        config.providers[0].weight = 5;
+        let report = seed(&config, &rpc_manager, &allowlist, false).await.unwrap();
+        assert_eq!(report.providers_updated, 0);
+        let report = seed(&config, &rpc_manager, &allowlist, true).await.unwrap();
+        assert_eq!((report.providers_added, report.providers_updated), (0, 1));
+        let providers = rpc_manager.get_providers().await.unwrap();
+        assert_eq!(providers.len(), 1);
+        assert_eq!(providers[0].weight, 5);
+        assert!(allowlist.is_empty());
+    }
+}
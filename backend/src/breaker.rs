@@ -0,0 +1,585 @@
+//! Per-provider circuit breakers on exit nodes
+//!
+//! Health scores are averages, so a provider that suddenly fails every request keeps
+//! getting traffic for as long as its score takes to decay. A breaker reacts within a few
+//! requests instead. It starts closed, letting every request through, and opens after
+//! `consecutive_failures` failures in a row, or once at least `min_requests` requests
+//! have finished in the last `window` and `error_rate` of them failed. An open breaker
+//! refuses requests at once, so they fail over to the next provider without waiting on
+//! the broken one. After `cooldown` it half-opens and lets `half_open_probes` requests
+//! through: if they all succeed it closes again, and if any fails it opens for another
+//! cooldown.
+//!
+//! A request fails when the provider can't be reached, answers with an HTTP error, or
+//! sends a body that can't be read. JSON-RPC errors are answers, and requests abandoned
+//! by the caller, such as a hedge that lost the race, count as neither success nor failure.
+//!
+//! Transitions are emitted as [`Event::BreakerTransition`] and counted in
+//! `darknode_provider_breaker_transitions_total`. Exit nodes report their breakers that
+//! aren't closed in heartbeats, and the coordinator marks providers that exits report as
+//! tripped, so other exits try them last before their own breakers have had to open. Only
+//! signed heartbeats of registered exit nodes count, each names at most
+//! [`MAX_REPORTED_BREAKERS`] providers, and the coordinator keeps the reports of at most
+//! [`MAX_REPORTING_NODES`] exits, dropping the oldest.
+
+use super::*;
+use super::events::{Event, EventBus};
+use super::types::{NodeId, RpcProvider};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::Instant;
+
+/// How long a heartbeat's breaker report counts towards a provider being tripped
+pub const REPORT_LIFETIME: Duration = Duration::from_secs(120);
+
+/// Most breakers one heartbeat's report is kept with
+pub const MAX_REPORTED_BREAKERS: usize = 1024;
+
+/// Most exit nodes whose reports the coordinator keeps
+pub const MAX_REPORTING_NODES: usize = 10_000;
+
+/// When breakers open and close again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BreakerConfig {
+    /// Whether breakers open at all
+    pub enabled: bool,
+    /// Failures in a row that open a breaker
+    pub consecutive_failures: u32,
+    /// Share of requests in the window that, failing, opens a breaker (0.5 = half)
+    pub error_rate: f64,
+    /// How far back requests count towards the error rate
+    pub window: Duration,
+    /// Fewest requests in the window for the error rate to count
+    pub min_requests: u32,
+    /// How long a breaker stays open before letting probes through
+    pub cooldown: Duration,
+    /// Requests let through while half-open, all of which must succeed to close it
+    pub half_open_probes: u32,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            consecutive_failures: 5,
+            error_rate: 0.5,
+            window: Duration::from_secs(10),
+            min_requests: 20,
+            cooldown: Duration::from_secs(30),
+            half_open_probes: 3,
+        }
+    }
+}
+
+/// The state of a provider's breaker
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Requests go through
+    #[default]
+    Closed,
+    /// Requests are refused until the cooldown ends
+    Open,
+    /// A few probe requests go through to see if the provider recovered
+    HalfOpen,
+}
+
+impl BreakerState {
+    /// Label for metrics
+    pub fn label(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// A request refused by breakers
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BreakerRejected {
+    /// The provider's breaker is open, or half-open with its probes taken
+    #[error("provider {0} is failing and its circuit breaker is open")]
+    Open(Uuid),
+    /// Every provider that could serve the request has its breaker open
+    #[error("every provider able to serve the request is failing and has its circuit breaker open")]
+    AllOpen,
+}
+
+/// One provider's breaker
+#[derive(Debug)]
+struct Breaker {
+    state: BreakerState,
+    /// Failures in a row while closed
+    consecutive_failures: u32,
+    /// When requests finished while closed, and whether they failed, oldest first
+    outcomes: VecDeque<(Instant, bool)>,
+    /// When the breaker last opened
+    opened_at: Instant,
+    /// Probes in flight while half-open
+    probes: u32,
+    /// Probes that succeeded while half-open
+    successes: u32,
+}
+
+impl Breaker {
+    fn new(now: Instant) -> Self {
+        Self {
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            outcomes: VecDeque::new(),
+            opened_at: now,
+            probes: 0,
+            successes: 0,
+        }
+    }
+    
+    /// Move to `state`, starting it afresh
+    fn enter(&mut self, state: BreakerState, now: Instant) {
+        self.state = state;
+        self.consecutive_failures = 0;
+        self.outcomes.clear();
+        self.probes = 0;
+        self.successes = 0;
+        if state == BreakerState::Open {
+            self.opened_at = now;
+        }
+    }
+}
+
+/// A provider's breaker changed state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Transition {
+    provider: Uuid,
+    from: BreakerState,
+    to: BreakerState,
+}
+
+/// The breakers of every provider an exit node sends requests to
+pub struct ProviderBreakers {
+    config: BreakerConfig,
+    breakers: dashmap::DashMap<Uuid, Breaker>,
+    events: Arc<EventBus>,
+}
+
+impl ProviderBreakers {
+    /// Create breakers, all closed, announcing their transitions on `events`
+    pub fn new(config: BreakerConfig, events: Arc<EventBus>) -> Self {
+        Self {
+            config,
+            breakers: dashmap::DashMap::new(),
+            events,
+        }
+    }
+    
+    /// Announce transitions on `events` instead
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = events;
+        self
+    }
+    
+    /// The state of a provider's breaker
+    pub fn state(&self, provider: Uuid) -> BreakerState {
+        self.breakers.get(&provider).map_or(BreakerState::Closed, |breaker| breaker.state)
+    }
+    
+    /// Whether a request to `provider` would be let through at `now`
+    ///
+    /// Unlike [`ProviderBreakers::admit`] this changes nothing, for choosing providers.
+    pub fn allows(&self, provider: Uuid, now: Instant) -> bool {
+        let Some(breaker) = self.breakers.get(&provider).filter(|_| self.config.enabled) else {
+            return true;
+        };
+        match breaker.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => now.saturating_duration_since(breaker.opened_at) >= self.config.cooldown,
+            BreakerState::HalfOpen => breaker.probes < self.config.half_open_probes,
+        }
+    }
+    
+    /// Let a request to `provider` through, or refuse it if the breaker is open
+    ///
+    /// The request's outcome is recorded through the permit; a permit dropped without one
+    /// counts as neither a success nor a failure.
+    pub fn admit(&self, provider: Uuid, now: Instant) -> Result<Permit<'_>, BreakerRejected> {
+        let mut permit = Permit {
+            breakers: self,
+            provider,
+            probe: false,
+            settled: false,
+        };
+        if !self.config.enabled {
+            return Ok(permit);
+        }
+        
+        let mut breaker = self.breakers.entry(provider).or_insert_with(|| Breaker::new(now));
+        let mut transition = None;
+        if breaker.state == BreakerState::Open && now.saturating_duration_since(breaker.opened_at) >= self.config.cooldown {
+            breaker.enter(BreakerState::HalfOpen, now);
+            transition = Some(Transition {
+                provider,
+                from: BreakerState::Open,
+                to: BreakerState::HalfOpen,
+            });
+        }
+        let admitted = match breaker.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen if breaker.probes < self.config.half_open_probes => {
+                breaker.probes += 1;
+                permit.probe = true;
+                true
+            }
+            BreakerState::HalfOpen => false,
+        };
+        drop(breaker);
+        
+        if let Some(transition) = transition {
+            self.announce(transition);
+        }
+        match admitted {
+            true => Ok(permit),
+            false => Err(BreakerRejected::Open(provider)),
+        }
+    }
+    
+    /// Record how a request let through by a permit ended
+    fn record(&self, provider: Uuid, probe: bool, failed: bool, now: Instant) {
+        let Some(mut breaker) = self.breakers.get_mut(&provider) else {
+            return;
+        };
+        let from = breaker.state;
+        match breaker.state {
+            BreakerState::Closed => {
+                breaker.outcomes.push_back((now, failed));
+                while breaker
+                    .outcomes
+                    .front()
+                    .map_or(false, |(at, _)| now.saturating_duration_since(*at) > self.config.window)
+                {
+                    breaker.outcomes.pop_front();
+                }
+                breaker.consecutive_failures = if failed { breaker.consecutive_failures + 1 } else { 0 };
+                
+                let requests = breaker.outcomes.len();
+                let failures = breaker.outcomes.iter().filter(|(_, failed)| *failed).count();
+                let tripped = breaker.consecutive_failures >= self.config.consecutive_failures
+                    || (requests >= self.config.min_requests as usize
+                        && failures as f64 >= self.config.error_rate * requests as f64);
+                if tripped {
+                    breaker.enter(BreakerState::Open, now);
+                }
+            }
+            // Requests let through before the breaker opened end while it is open or probing
+            BreakerState::Open => {}
+            BreakerState::HalfOpen if !probe => {}
+            BreakerState::HalfOpen => {
+                breaker.probes = breaker.probes.saturating_sub(1);
+                if failed {
+                    breaker.enter(BreakerState::Open, now);
+                } else {
+                    breaker.successes += 1;
+                    if breaker.successes >= self.config.half_open_probes {
+                        breaker.enter(BreakerState::Closed, now);
+                    }
+                }
+            }
+        }
+        let to = breaker.state;
+        drop(breaker);
+        
+        if from != to {
+            self.announce(Transition { provider, from, to });
+        }
+    }
+    
+    /// Give back the slot of a probe that ended without an outcome
+    fn release(&self, provider: Uuid) {
+        if let Some(mut breaker) = self.breakers.get_mut(&provider) {
+            if breaker.state == BreakerState::HalfOpen {
+                breaker.probes = breaker.probes.saturating_sub(1);
+            }
+        }
+    }
+    
+    fn announce(&self, transition: Transition) {
+        match transition.to {
+            BreakerState::Open => tracing::warn!("Circuit breaker of provider {} opened", transition.provider),
+            _ => tracing::info!("Circuit breaker of provider {} is {}", transition.provider, transition.to.label()),
+        }
+        metrics::increment_counter!(
+            "darknode_provider_breaker_transitions_total",
+            "from" => transition.from.label(),
+            "to" => transition.to.label()
+        );
+        self.events.emit(Event::BreakerTransition {
+            provider_id: transition.provider,
+            from: transition.from,
+            to: transition.to,
+        });
+    }
+}
+
+/// A request let through a provider's breaker, whose outcome the breaker waits on
+pub struct Permit<'a> {
+    breakers: &'a ProviderBreakers,
+    provider: Uuid,
+    probe: bool,
+    settled: bool,
+}
+
+impl Permit<'_> {
+    /// Record that the request succeeded
+    pub fn succeeded(self, now: Instant) {
+        self.settle(false, now);
+    }
+    
+    /// Record that the request failed
+    pub fn failed(self, now: Instant) {
+        self.settle(true, now);
+    }
+    
+    fn settle(mut self, failed: bool, now: Instant) {
+        self.settled = true;
+        self.breakers.record(self.provider, self.probe, failed, now);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.probe && !self.settled {
+            self.breakers.release(self.provider);
+        }
+    }
+}
+
+/// Breakers exit nodes report in their heartbeats, kept by the coordinator
+#[derive(Default)]
+pub struct BreakerBoard {
+    reports: parking_lot::Mutex<HashMap<NodeId, (Instant, BTreeMap<Uuid, BreakerState>)>>,
+}
+
+impl BreakerBoard {
+    /// Create a board with no reports
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Replace an exit node's report with the breakers it reported at `now` that aren't closed
+    ///
+    /// The caller checks that the report comes from a registered exit node.
+    pub fn record(&self, node: &NodeId, breakers: &BTreeMap<Uuid, BreakerState>, now: Instant) {
+        let tripped: BTreeMap<Uuid, BreakerState> = breakers
+            .iter()
+            .filter(|(_, state)| **state != BreakerState::Closed)
+            .take(MAX_REPORTED_BREAKERS)
+            .map(|(provider, state)| (*provider, *state))
+            .collect();
+        let mut reports = self.reports.lock();
+        reports.retain(|_, (at, _)| now.saturating_duration_since(*at) <= REPORT_LIFETIME);
+        if tripped.is_empty() {
+            reports.remove(node);
+            return;
+        }
+        if !reports.contains_key(node) && reports.len() >= MAX_REPORTING_NODES {
+            let oldest = reports.iter().min_by_key(|(_, (at, _))| *at).map(|(node, _)| node.clone());
+            if let Some(oldest) = oldest {
+                reports.remove(&oldest);
+            }
+        }
+        reports.insert(node.clone(), (now, tripped));
+    }
+    
+    /// Forget a node's report, as when it isn't an exit node
+    pub fn forget(&self, node: &NodeId) {
+        self.reports.lock().remove(node);
+    }
+    
+    /// Set each provider's `tripped_breakers` to the exits currently reporting it not closed
+    pub fn annotate(&self, providers: &mut [RpcProvider], now: Instant) {
+        let reports = self.reports.lock();
+        for provider in providers {
+            provider.tripped_breakers = reports
+                .values()
+                .filter(|(at, _)| now.saturating_duration_since(*at) <= REPORT_LIFETIME)
+                .filter(|(_, breakers)| {
+                    breakers
+                        .get(&provider.id)
+                        .map_or(false, |state| *state != BreakerState::Closed)
+                })
+                .count() as u32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn the_board_keeps_a_bounded_report_of_tripped_breakers() {
+        let board = BreakerBoard::new();
+        let node = NodeId(Uuid::new_v4());
+        let now = Instant::now();
+        let mut breakers: BTreeMap<Uuid, BreakerState> = (0..MAX_REPORTED_BREAKERS as u128 + 10)
+            .map(|i| (Uuid::from_u128(i), BreakerState::Open))
+            .collect();
+        breakers.insert(Uuid::nil(), BreakerState::Closed);
+        
+        board.record(&node, &breakers, now);
+        {
+            let reports = board.reports.lock();
+            let (_, kept) = &reports[&node];
+            assert_eq!(kept.len(), MAX_REPORTED_BREAKERS);
+            assert!(kept.values().all(|state| *state != BreakerState::Closed));
+        }
+        
+        // A node whose breakers have all closed again has nothing left to report
+        board.record(&node, &BTreeMap::from([(Uuid::nil(), BreakerState::Closed)]), now);
+        assert!(board.reports.lock().is_empty());
+    }
+    
+    fn breakers(config: BreakerConfig) -> ProviderBreakers {
+        ProviderBreakers::new(config, Arc::new(EventBus::new()))
+    }
+    
+    #[test]
+    fn opens_after_failures_in_a_row_and_closes_once_its_probes_succeed() {
+        let config = BreakerConfig::default();
+        let breakers = breakers(config.clone());
+        let provider = Uuid::new_v4();
+        let start = Instant::now();
+        
+        for _ in 0..config.consecutive_failures {
+            breakers.admit(provider, start).unwrap().failed(start);
+        }
+        assert_eq!(breakers.state(provider), BreakerState::Open);
+        assert_eq!(breakers.admit(provider, start).err(), Some(BreakerRejected::Open(provider)));
+        assert!(!breakers.allows(provider, start + config.cooldown / 2));
+        
+        // After the cooldown a few probes go through, and a failing one opens it again
+        let cooled = start + config.cooldown;
+        breakers.admit(provider, cooled).unwrap().failed(cooled);
+        assert_eq!(breakers.state(provider), BreakerState::Open);
+        
+        let cooled = cooled + config.cooldown;
+        let probes: Vec<_> = (0..config.half_open_probes)
+            .map(|_| breakers.admit(provider, cooled).unwrap())
+            .collect();
+        assert_eq!(breakers.state(provider), BreakerState::HalfOpen);
+        assert!(breakers.admit(provider, cooled).is_err());
+        for probe in probes {
+            probe.succeeded(cooled);
+        }
+        assert_eq!(breakers.state(provider), BreakerState::Closed);
+    }
+    
+    #[test]
+    fn a_probe_given_up_on_frees_its_slot() {
+        let config = BreakerConfig {
+            half_open_probes: 1,
+            ..Default::default()
+        };
+        let breakers = breakers(config.clone());
+        let provider = Uuid::new_v4();
+        let start = Instant::now();
+        for _ in 0..config.consecutive_failures {
+            breakers.admit(provider, start).unwrap().failed(start);
+        }
+        
+        let cooled = start + config.cooldown;
+        drop(breakers.admit(provider, cooled).unwrap());
+        breakers.admit(provider, cooled).unwrap().succeeded(cooled);
+        assert_eq!(breakers.state(provider), BreakerState::Closed);
+    }
+    
+    #[tokio::test]
+    async fn requests_skip_a_failing_provider_at_once_until_it_recovers() {
+        use crate::exit_node::ExitNodeConfig;
+        use crate::fixtures;
+        use crate::hedge::HedgeConfig;
+        use crate::impls::StoredRpcManager;
+        use crate::storage::MemoryStorage;
+        use crate::traits::RpcManager;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        
+        // The preferred provider answers slowly with an HTTP error until it recovers
+        const SLOW: Duration = Duration::from_millis(200);
+        let (recovered, hits) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicUsize::new(0)));
+        let (up, counted) = (recovered.clone(), hits.clone());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let flaky = RpcProvider {
+            url: format!("http://{}/", listener.local_addr().unwrap()),
+            success_rate: 1.0,
+            ..fixtures::provider()
+        };
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::post(move |axum::Json(request): axum::Json<serde_json::Value>| {
+                let (up, counted) = (up.clone(), counted.clone());
+                async move {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    if !up.load(Ordering::SeqCst) {
+                        tokio::time::sleep(SLOW).await;
+                        return Err(axum::http::StatusCode::BAD_GATEWAY);
+                    }
+                    Ok(axum::Json(serde_json::json!({ "jsonrpc": "2.0", "id": request["id"], "result": "flaky" })))
+                }
+            }),
+        );
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        let steady = RpcProvider {
+            success_rate: 0.9,
+            ..fixtures::serving(|request: serde_json::Value| async move {
+                serde_json::json!({ "jsonrpc": "2.0", "id": request["id"], "result": "steady" })
+            })
+        };
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        rpc_manager.register_provider(flaky).await.unwrap();
+        rpc_manager.register_provider(steady).await.unwrap();
+        
+        let breaker = BreakerConfig {
+            cooldown: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let config = ExitNodeConfig {
+            hedge: HedgeConfig {
+                enabled: false,
+                ..Default::default()
+            },
+            breaker: breaker.clone(),
+            ..Default::default()
+        };
+        let exit = Arc::new(fixtures::exit_with(rpc_manager, config));
+        let payload = fixtures::payload("getSlot", serde_json::json!([]));
+        let (exit, payload) = (&exit, &payload);
+        let served_by = move || async move {
+            let started = Instant::now();
+            let response = exit.serve(payload).await?;
+            let answer: serde_json::Value = serde_json::from_slice(&response)?;
+            Ok::<_, anyhow::Error>((answer["result"].as_str().unwrap_or_default().to_string(), started.elapsed()))
+        };
+        
+        // Requests wait on the failing provider until its breaker opens
+        for _ in 0..breaker.consecutive_failures {
+            assert!(served_by().await.is_err());
+        }
+        let failing = hits.load(Ordering::SeqCst);
+        assert_eq!(failing, breaker.consecutive_failures as usize);
+        
+        // From then on they go straight to the other one, without waiting on it first
+        for _ in 0..3 {
+            let (served, took) = served_by().await.unwrap();
+            assert_eq!(served, "steady");
+            assert!(took < SLOW, "took {:?}", took);
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), failing);
+        
+        // Once it recovers, its probes after the cooldown succeed and it is preferred again
+        recovered.store(true, Ordering::SeqCst);
+        tokio::time::sleep(breaker.cooldown).await;
+        for _ in 0..breaker.half_open_probes + 2 {
+            assert_eq!(served_by().await.unwrap().0, "flaky");
+        }
+    }
+}
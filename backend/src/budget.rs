@@ -0,0 +1,503 @@
+//! Request budgets of exit nodes, and steering circuits away from exits running out
+//!
+//! Providers sell a number of requests a day or a month, and an exit node that has spent
+//! them fails every request at the last hop. Providers registered with a
+//! [`ProviderQuota`] get a [`TokenBucket`] on each exit node sending them requests, which
+//! every request sent takes a token from and which fills up again at the start of each
+//! UTC day or month. A provider's quota is shared by every exit node using it, so each
+//! exit's bucket holds only its `quota_share` of it. An exit node may also be given a
+//! `ceiling` of its own, a bucket every request to any provider takes from too. With a
+//! `state_file` set, what the buckets have spent this period is written there every
+//! heartbeat and taken up again when the node restarts; otherwise buckets start full.
+//!
+//! Providers whose bucket is empty are left out of selection. Once none can serve a
+//! request, or the ceiling is spent, the exit node refuses it with [`ExitAtCapacity`]
+//! without sending it anywhere, and entry nodes rebuild the circuit through another exit,
+//! see [`crate::replay`].
+//!
+//! Exit nodes report the budget they have left in heartbeats, and the coordinator marks
+//! exits in the directory with the share left. Exits below [`LOW_BUDGET`] are left out
+//! of recommended paths and circuits built from the directory while others have budget
+//! to spare, so they finish the circuits they have rather than taking on new ones.
+
+use super::*;
+use super::clock;
+use super::types::{Node, RpcProvider};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Share of its budget left below which an exit node stops being given new circuits
+pub const LOW_BUDGET: f64 = 0.1;
+
+/// Milliseconds in a day
+const MILLIS_PER_DAY: u64 = 86_400_000;
+
+/// When a bucket fills up again
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Replenish {
+    /// At the start of every UTC day
+    #[default]
+    Daily,
+    /// At the start of the first day of every month, UTC
+    Monthly,
+}
+
+impl Replenish {
+    /// The first time after `now` the bucket fills up
+    pub fn next_after(self, now: Timestamp) -> Timestamp {
+        let days = now.as_millis() / MILLIS_PER_DAY;
+        let days = match self {
+            Replenish::Daily => days + 1,
+            Replenish::Monthly => {
+                let (year, month, _) = clock::civil_from_days(days as i64);
+                let (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                clock::days_from_civil(year, month, 1) as u64
+            }
+        };
+        Timestamp::from_millis(days * MILLIS_PER_DAY)
+    }
+}
+
+/// The requests a provider takes in each period
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProviderQuota {
+    /// Requests per period
+    pub requests: u64,
+    /// When the quota is renewed
+    #[serde(default)]
+    pub replenish: Replenish,
+}
+
+/// A budget of requests, filled up to `capacity` on a schedule
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: u64,
+    tokens: u64,
+    replenish: Replenish,
+    resets_at: Timestamp,
+}
+
+impl TokenBucket {
+    /// Create a full bucket
+    pub fn new(capacity: u64, replenish: Replenish, now: Timestamp) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            replenish,
+            resets_at: replenish.next_after(now),
+        }
+    }
+    
+    /// Create a bucket that has already spent what `spent` records, if its period is still running
+    fn resume(capacity: u64, replenish: Replenish, spent: Option<Spent>, now: Timestamp) -> Self {
+        let mut bucket = Self::new(capacity, replenish, now);
+        if let Some(spent) = spent.filter(|spent| now < spent.resets_at && spent.resets_at <= bucket.resets_at) {
+            bucket.tokens = capacity.saturating_sub(spent.spent);
+            bucket.resets_at = spent.resets_at;
+        }
+        bucket
+    }
+    
+    /// What the bucket has spent this period
+    fn spent(&self) -> Spent {
+        Spent {
+            spent: self.capacity - self.tokens,
+            resets_at: self.resets_at,
+        }
+    }
+    
+    /// Fill the bucket up if its period ended
+    fn refill(&mut self, now: Timestamp) {
+        if now >= self.resets_at {
+            self.tokens = self.capacity;
+            self.resets_at = self.replenish.next_after(now);
+        }
+    }
+    
+    /// Take a token, returning whether there was one
+    pub fn take(&mut self, now: Timestamp) -> bool {
+        self.refill(now);
+        let taken = self.tokens > 0;
+        self.tokens = self.tokens.saturating_sub(1);
+        taken
+    }
+    
+    /// Tokens left
+    pub fn remaining(&mut self, now: Timestamp) -> u64 {
+        self.refill(now);
+        self.tokens
+    }
+    
+    /// Tokens the bucket holds when full
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+    
+    /// When the bucket next fills up
+    pub fn resets_at(&self) -> Timestamp {
+        self.resets_at
+    }
+    
+    /// Follow a change of quota, keeping the tokens already spent this period
+    fn resize(&mut self, quota: ProviderQuota, now: Timestamp) {
+        if quota.replenish != self.replenish {
+            *self = Self::new(quota.requests, quota.replenish, now);
+            return;
+        }
+        self.refill(now);
+        let spent = self.capacity - self.tokens;
+        self.capacity = quota.requests;
+        self.tokens = quota.requests.saturating_sub(spent);
+    }
+}
+
+/// The request budget of an exit node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BudgetConfig {
+    /// Whether provider quotas and the ceiling are enforced and reported at all
+    pub enabled: bool,
+    /// Requests the node sends to all its providers together each period, if limited
+    pub ceiling: Option<u64>,
+    /// When the ceiling is renewed
+    pub replenish: Replenish,
+    /// Share of each provider's quota this node may spend, the rest being left to the
+    /// other exit nodes using the provider
+    pub quota_share: f64,
+    /// File the requests spent this period are kept in, if they should survive restarts
+    pub state_file: Option<PathBuf>,
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ceiling: None,
+            replenish: Replenish::Daily,
+            quota_share: 1.0,
+            state_file: None,
+        }
+    }
+}
+
+impl BudgetConfig {
+    /// The requests this node may send a provider with `quota` each period
+    fn capacity(&self, quota: ProviderQuota) -> u64 {
+        (quota.requests as f64 * self.quota_share.clamp(0.0, 1.0)).floor() as u64
+    }
+}
+
+/// What a bucket spent in the period ending at `resets_at`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Spent {
+    spent: u64,
+    resets_at: Timestamp,
+}
+
+/// What an exit node's buckets have spent, as kept in its state file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BudgetState {
+    ceiling: Option<Spent>,
+    providers: HashMap<Uuid, Spent>,
+}
+
+/// A request refused because the exit node has spent its budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("exit node is at capacity until {resets_at}")]
+pub struct ExitAtCapacity {
+    /// When budget is next renewed
+    pub resets_at: Timestamp,
+}
+
+/// The budget an exit node has left, as reported in heartbeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BudgetReport {
+    /// Requests left this period
+    pub remaining: u64,
+    /// Requests in a full period
+    pub capacity: u64,
+    /// When budget is next renewed
+    pub resets_at: Timestamp,
+}
+
+impl BudgetReport {
+    /// Share of the budget left at `now`, which is all of it once the period ended
+    pub fn share(&self, now: Timestamp) -> f64 {
+        if now >= self.resets_at {
+            return 1.0;
+        }
+        if self.capacity == 0 {
+            return 0.0;
+        }
+        (self.remaining as f64 / self.capacity as f64).clamp(0.0, 1.0)
+    }
+}
+
+/// The buckets of an exit node's providers and its ceiling
+pub struct ExitBudget {
+    config: BudgetConfig,
+    ceiling: Option<parking_lot::Mutex<TokenBucket>>,
+    providers: dashmap::DashMap<Uuid, TokenBucket>,
+    restored: dashmap::DashMap<Uuid, Spent>,
+}
+
+impl ExitBudget {
+    /// Create a budget, taking up what the state file says was spent this period
+    ///
+    /// Buckets the file doesn't cover, or all of them if it can't be read, start full.
+    pub fn new(config: BudgetConfig, now: Timestamp) -> Self {
+        let state = config
+            .state_file
+            .as_ref()
+            .filter(|_| config.enabled)
+            .and_then(|path| match std::fs::read(path) {
+                Ok(bytes) => serde_json::from_slice::<BudgetState>(&bytes)
+                    .map_err(|e| tracing::warn!("Ignoring budget state {}: {}", path.display(), e))
+                    .ok(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    tracing::warn!("Failed to read budget state {}: {}", path.display(), e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        let ceiling = config
+            .ceiling
+            .filter(|_| config.enabled)
+            .map(|ceiling| parking_lot::Mutex::new(TokenBucket::resume(ceiling, config.replenish, state.ceiling, now)));
+        Self {
+            config,
+            ceiling,
+            providers: dashmap::DashMap::new(),
+            restored: state.providers.into_iter().collect(),
+        }
+    }
+    
+    /// How the budget is set
+    pub fn config(&self) -> &BudgetConfig {
+        &self.config
+    }
+    
+    /// The bucket of `provider`, following its current quota; `None` if it has none
+    fn bucket(&self, provider: &RpcProvider, now: Timestamp) -> Option<dashmap::mapref::one::RefMut<'_, Uuid, TokenBucket>> {
+        let quota = provider.quota?;
+        let quota = ProviderQuota {
+            requests: self.config.capacity(quota),
+            replenish: quota.replenish,
+        };
+        let mut bucket = self.providers.entry(provider.id).or_insert_with(|| {
+            let spent = self.restored.remove(&provider.id).map(|(_, spent)| spent);
+            TokenBucket::resume(quota.requests, quota.replenish, spent, now)
+        });
+        if bucket.capacity != quota.requests || bucket.replenish != quota.replenish {
+            bucket.resize(quota, now);
+        }
+        Some(bucket)
+    }
+    
+    /// Tokens left in `provider`'s bucket, with its capacity and when it fills up; `None` if it has none
+    fn provider_remaining(&self, provider: &RpcProvider, now: Timestamp) -> Option<(u64, u64, Timestamp)> {
+        let mut bucket = self.bucket(provider, now)?;
+        Some((bucket.remaining(now), bucket.capacity, bucket.resets_at))
+    }
+    
+    /// The `providers` with budget left, or why there are none
+    ///
+    /// Nothing is spent; that is left to [`Self::spend`] once a request is actually sent.
+    pub fn fund(&self, providers: Vec<RpcProvider>, now: Timestamp) -> Result<Vec<RpcProvider>, ExitAtCapacity> {
+        if !self.config.enabled {
+            return Ok(providers);
+        }
+        if let Some(ceiling) = &self.ceiling {
+            let mut ceiling = ceiling.lock();
+            if ceiling.remaining(now) == 0 {
+                metrics::increment_counter!("darknode_exit_at_capacity_total", "budget" => "ceiling");
+                return Err(ExitAtCapacity {
+                    resets_at: ceiling.resets_at(),
+                });
+            }
+        }
+        let mut resets_at: Option<Timestamp> = None;
+        let funded: Vec<RpcProvider> = providers
+            .into_iter()
+            .filter(|provider| match self.provider_remaining(provider, now) {
+                Some((0, _, resets)) => {
+                    resets_at = Some(resets_at.map_or(resets, |earliest| earliest.min(resets)));
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+        match resets_at {
+            Some(resets_at) if funded.is_empty() => {
+                metrics::increment_counter!("darknode_exit_at_capacity_total", "budget" => "providers");
+                Err(ExitAtCapacity { resets_at })
+            }
+            _ => Ok(funded),
+        }
+    }
+    
+    /// Take a request sent to `provider` out of its bucket and the ceiling
+    ///
+    /// Requests checked by [`Self::fund`] at the same time may take the last few tokens
+    /// together, overshooting a quota by the requests in flight.
+    pub fn spend(&self, provider: &RpcProvider, now: Timestamp) {
+        if !self.config.enabled {
+            return;
+        }
+        if let Some(ceiling) = &self.ceiling {
+            ceiling.lock().take(now);
+        }
+        if let Some(mut bucket) = self.bucket(provider, now) {
+            bucket.take(now);
+        }
+    }
+    
+    /// Write what the buckets have spent this period to the state file, if there is one
+    ///
+    /// Whatever is spent after the last save is lost should the node crash.
+    pub async fn save(&self) -> Result<()> {
+        let Some(path) = self.config.state_file.clone().filter(|_| self.config.enabled) else {
+            return Ok(());
+        };
+        let mut providers: HashMap<Uuid, Spent> = self.restored.iter().map(|entry| (*entry.key(), *entry.value())).collect();
+        providers.extend(self.providers.iter().map(|entry| (*entry.key(), entry.value().spent())));
+        let state = BudgetState {
+            ceiling: self.ceiling.as_ref().map(|ceiling| ceiling.lock().spent()),
+            providers,
+        };
+        let bytes = serde_json::to_vec(&state)?;
+        
+        // Write beside the state file and rename, so a crash never leaves it half written
+        tokio::task::spawn_blocking(move || {
+            let partial = path.with_extension("tmp");
+            std::fs::write(&partial, bytes).and_then(|()| std::fs::rename(&partial, &path))
+        })
+        .await??;
+        Ok(())
+    }
+    
+    /// The budget left across `providers`, the node's active ones, capped by the ceiling
+    ///
+    /// Whichever runs out first is reported: the ceiling, or the quotas of the providers
+    /// together. `None` if neither limits the node, as when a provider has no quota.
+    pub fn report(&self, providers: &[RpcProvider], now: Timestamp) -> Option<BudgetReport> {
+        if !self.config.enabled {
+            return None;
+        }
+        let mut quotas = Some(BudgetReport {
+            remaining: 0,
+            capacity: 0,
+            resets_at: Timestamp::from_millis(u64::MAX),
+        });
+        for provider in providers {
+            quotas = match (quotas, self.provider_remaining(provider, now)) {
+                (Some(total), Some((remaining, capacity, resets_at))) => Some(BudgetReport {
+                    remaining: total.remaining + remaining,
+                    capacity: total.capacity + capacity,
+                    resets_at: total.resets_at.min(resets_at),
+                }),
+                _ => None,
+            };
+        }
+        let quotas = quotas.filter(|_| !providers.is_empty());
+        
+        // The providers' buckets are dropped once they leave the active set
+        self.providers
+            .retain(|id, _| providers.iter().any(|provider| provider.id == *id));
+        
+        let ceiling = self.ceiling.as_ref().map(|ceiling| {
+            let mut ceiling = ceiling.lock();
+            BudgetReport {
+                remaining: ceiling.remaining(now),
+                capacity: ceiling.capacity(),
+                resets_at: ceiling.resets_at(),
+            }
+        });
+        match (quotas, ceiling) {
+            (Some(quotas), Some(ceiling)) if ceiling.remaining < quotas.remaining => Some(ceiling),
+            (Some(quotas), _) => Some(quotas),
+            (None, ceiling) => ceiling,
+        }
+    }
+}
+
+/// Leave exits with little budget left out of `exits`, unless none has more
+pub fn prefer_funded<'a>(exits: Vec<&'a Node>) -> Vec<&'a Node> {
+    let funded = |node: &&Node| node.budget.map_or(true, |share| share >= LOW_BUDGET);
+    if exits.iter().any(funded) {
+        exits.into_iter().filter(funded).collect()
+    } else {
+        exits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn provider(requests: u64) -> RpcProvider {
+        RpcProvider {
+            last_checked: Timestamp::UNIX_EPOCH,
+            quota: Some(ProviderQuota {
+                requests,
+                replenish: Replenish::Daily,
+            }),
+            ..crate::fixtures::provider()
+        }
+    }
+    
+    #[test]
+    fn an_exit_spends_only_its_share_of_a_provider_quota() {
+        let now = Timestamp::from_secs(3600);
+        let config = BudgetConfig {
+            quota_share: 0.25,
+            ..Default::default()
+        };
+        let budget = ExitBudget::new(config, now);
+        let provider = provider(10);
+        
+        let report = budget.report(std::slice::from_ref(&provider), now).unwrap();
+        assert_eq!((report.remaining, report.capacity), (2, 2));
+        budget.spend(&provider, now);
+        budget.spend(&provider, now);
+        assert_eq!(
+            budget.fund(vec![provider.clone()], now).unwrap_err(),
+            ExitAtCapacity { resets_at: Replenish::Daily.next_after(now) }
+        );
+        assert_eq!(budget.report(&[provider], now).unwrap().share(now), 0.0);
+    }
+    
+    #[tokio::test]
+    async fn what_was_spent_survives_a_restart_until_the_period_ends() {
+        let path = std::env::temp_dir().join(format!("darknode-budget-{}.json", Uuid::new_v4()));
+        let now = Timestamp::from_secs(3600);
+        let config = BudgetConfig {
+            ceiling: Some(100),
+            state_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let provider = provider(10);
+        
+        let budget = ExitBudget::new(config.clone(), now);
+        for _ in 0..3 {
+            budget.spend(&provider, now);
+        }
+        budget.save().await.unwrap();
+        
+        // A restart in the same day picks up where the node left off
+        let later = now + Duration::from_secs(60);
+        let restarted = ExitBudget::new(config.clone(), later);
+        let report = restarted.report(std::slice::from_ref(&provider), later).unwrap();
+        assert_eq!((report.remaining, report.capacity), (7, 10));
+        assert_eq!(restarted.ceiling.as_ref().unwrap().lock().remaining(later), 97);
+        
+        // One the next day starts full
+        let tomorrow = Replenish::Daily.next_after(now);
+        let restarted = ExitBudget::new(config, tomorrow);
+        let report = restarted.report(std::slice::from_ref(&provider), tomorrow).unwrap();
+        assert_eq!(report.remaining, 10);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,77 @@
+//! What a binary was built from, so operators can tell what is deployed where
+//!
+//! Every binary logs a [`BuildInfo`] when it starts and serves it on `GET /version`. Nodes
+//! send theirs with their registration and every heartbeat, since an upgraded node restarts
+//! without registering again, and the coordinator shows each node's build in its node
+//! listing, counts nodes by release on `GET /nodes/versions` and on the dashboard. The
+//! git hash and build time come from `build.rs`.
+
+use super::*;
+use super::protocol::ProtocolRange;
+
+/// The label of nodes that don't report their build, such as nodes of older releases
+pub const UNKNOWN_RELEASE: &str = "unknown";
+
+/// What a binary was built from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    /// The crate version
+    pub version: String,
+    /// The commit built, `unknown` outside a git checkout
+    pub git_hash: String,
+    /// When the binary was built
+    pub built_at: Timestamp,
+    /// The cargo features enabled
+    pub features: Vec<String>,
+    /// The protocol versions the binary speaks, see [`crate::protocol`]
+    pub protocol: ProtocolRange,
+}
+
+impl BuildInfo {
+    /// The build of the running binary
+    pub fn current() -> Self {
+        let features = [
+            ("canary", cfg!(feature = "canary")),
+            ("dev-logging", cfg!(feature = "dev-logging")),
+            ("sled", cfg!(feature = "sled")),
+            ("postgres", cfg!(feature = "postgres")),
+            ("otel", cfg!(feature = "otel")),
+        ];
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: env!("DARKNODE_GIT_HASH").to_string(),
+            built_at: Timestamp::from_millis(env!("DARKNODE_BUILT_AT_MILLIS").parse().unwrap_or(0)),
+            features: features
+                .into_iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(feature, _)| feature.to_string())
+                .collect(),
+            protocol: ProtocolRange::SUPPORTED,
+        }
+    }
+
+    /// The release, such as `0.1.0+1a2b3c4d5e6f`, that nodes are grouped by
+    pub fn release(&self) -> String {
+        format!("{}+{}", self.version, self.git_hash)
+    }
+
+    /// Log the build as the binary `name` starts
+    pub fn log_banner(&self, name: &str) {
+        tracing::info!(
+            binary = name,
+            version = %self.version,
+            git_hash = %self.git_hash,
+            built_at = %self.built_at,
+            features = ?self.features,
+            protocol = %self.protocol,
+            "{} {}",
+            name,
+            self.release()
+        );
+    }
+}
+
+/// The release of a node's build, [`UNKNOWN_RELEASE`] if it doesn't report one
+pub fn release_of(build: Option<&BuildInfo>) -> String {
+    build.map_or_else(|| UNKNOWN_RELEASE.to_string(), BuildInfo::release)
+}
@@ -0,0 +1,351 @@
+//! Response cache for the reads exit nodes serve most
+//!
+//! Some reads are asked constantly and change rarely or predictably, so exit nodes answer
+//! them from a cache with a TTL per method. When popular entries expire together, every
+//! request would go to the provider at once, so an entry past its TTL but within its
+//! method's grace period is still served as is while a single background refresh per key
+//! replaces it. Deterministic errors, such as an unknown method or invalid params, are cached
+//! too, for a short TTL without grace, so repeating a bad request doesn't reach the provider
+//! every time. Requests finding no entry at all are deduplicated the same way: the first
+//! fetches the response while the others wait for it to be stored.
+//!
+//! Entries are keyed by chain, network, method, params, required capabilities, and error
+//! verbosity, never by request id; the id is put back into each response served from the
+//! cache.
+
+use super::*;
+use super::methods;
+use super::types::ExitPayload;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Errors a provider returns for a request regardless of when it is sent
+const DETERMINISTIC_ERROR_CODES: &[i64] = &[-32601, -32602];
+
+/// How long one method's responses are cached
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CachePolicy {
+    /// How long a response is served as fresh
+    pub ttl: Duration,
+    /// How long after its TTL a response is still served while it is refreshed
+    #[serde(default)]
+    pub grace: Duration,
+}
+
+impl CachePolicy {
+    fn new(ttl: Duration, grace: Duration) -> Self {
+        Self { ttl, grace }
+    }
+}
+
+/// Which responses are cached and for how long
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Whether responses are cached at all
+    pub enabled: bool,
+    /// Methods whose responses are cached, and their policies
+    pub methods: BTreeMap<String, CachePolicy>,
+    /// How long deterministic errors are cached
+    pub negative_ttl: Duration,
+    /// Most entries kept
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        let hour = Duration::from_secs(3600);
+        let methods = [
+            // Solana
+            ("getGenesisHash", CachePolicy::new(hour, hour)),
+            ("getEpochSchedule", CachePolicy::new(hour, hour)),
+            ("getMinimumBalanceForRentExemption", CachePolicy::new(hour, hour)),
+            ("getSlot", CachePolicy::new(Duration::from_millis(400), Duration::from_secs(1))),
+            ("getLatestBlockhash", CachePolicy::new(Duration::from_secs(1), Duration::from_secs(2))),
+            // Ethereum
+            ("eth_chainId", CachePolicy::new(hour, hour)),
+            ("net_version", CachePolicy::new(hour, hour)),
+            ("eth_blockNumber", CachePolicy::new(Duration::from_secs(1), Duration::from_secs(2))),
+        ];
+        Self {
+            enabled: false,
+            methods: methods
+                .into_iter()
+                .map(|(method, policy)| (method.to_string(), policy))
+                .collect(),
+            negative_ttl: Duration::from_secs(5),
+            max_entries: 10_000,
+        }
+    }
+}
+
+/// What the cache holds for a request
+#[derive(Debug)]
+pub enum Lookup {
+    /// A response within its TTL
+    Fresh(serde_json::Value),
+    /// A response past its TTL but within its grace period
+    Stale {
+        /// The response to serve meanwhile
+        response: serde_json::Value,
+        /// Whether this caller should refresh the entry; only one caller per key is told to
+        refresh: bool,
+    },
+    /// Nothing usable, and this caller is to fetch and store it; others wait until the
+    /// [`Fill`] is dropped
+    Miss(Fill),
+    /// Nothing usable, and another request is fetching it
+    Pending(Pending),
+}
+
+/// Keys being fetched after a miss, each with the channel its waiters are told on
+type Fetching = Arc<parking_lot::Mutex<HashMap<String, watch::Sender<()>>>>;
+
+/// Held by the one request fetching a missing entry, releasing its waiters when dropped
+#[derive(Debug)]
+pub struct Fill {
+    key: String,
+    fetching: Fetching,
+}
+
+impl Drop for Fill {
+    fn drop(&mut self) {
+        self.fetching.lock().remove(&self.key);
+    }
+}
+
+/// A wait for another request to fetch a missing entry
+#[derive(Debug)]
+pub struct Pending(watch::Receiver<()>);
+
+impl Pending {
+    /// Wait until the request fetching the entry has stored it or given up
+    pub async fn wait(mut self) {
+        while self.0.changed().await.is_ok() {}
+    }
+}
+
+struct Entry {
+    response: serde_json::Value,
+    fresh_until: Instant,
+    stale_until: Instant,
+}
+
+/// Cached provider responses, shared by every request the exit node serves
+pub struct ResponseCache {
+    config: CacheConfig,
+    entries: parking_lot::Mutex<HashMap<String, Entry>>,
+    refreshing: parking_lot::Mutex<HashSet<String>>,
+    fetching: Fetching,
+}
+
+impl ResponseCache {
+    /// Create an empty cache
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            entries: parking_lot::Mutex::new(HashMap::new()),
+            refreshing: parking_lot::Mutex::new(HashSet::new()),
+            fetching: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+        }
+    }
+    
+    /// The key `payload`'s request is cached under, if its method is cached at all
+    pub fn key(&self, payload: &ExitPayload) -> Option<String> {
+        if !self.config.enabled {
+            return None;
+        }
+        let method = methods::method_name(&payload.request)?;
+        self.config.methods.get(method)?;
+        serde_json::to_string(&serde_json::json!([
+            payload.chain,
+            payload.network,
+            method,
+            payload.request["params"],
+            payload.capabilities,
+            payload.debug_errors,
+            payload.normalize,
+        ]))
+        .ok()
+    }
+    
+    /// Look up the response cached under `key`
+    pub fn lookup(&self, key: &str, now: Instant) -> Lookup {
+        let lookup = match self.entries.lock().get(key) {
+            Some(entry) if now < entry.fresh_until => Lookup::Fresh(entry.response.clone()),
+            Some(entry) if now < entry.stale_until => Lookup::Stale {
+                response: entry.response.clone(),
+                refresh: self.refreshing.lock().insert(key.to_string()),
+            },
+            _ => {
+                let mut fetching = self.fetching.lock();
+                match fetching.get(key) {
+                    Some(fetched) => Lookup::Pending(Pending(fetched.subscribe())),
+                    None => {
+                        fetching.insert(key.to_string(), watch::channel(()).0);
+                        Lookup::Miss(Fill {
+                            key: key.to_string(),
+                            fetching: self.fetching.clone(),
+                        })
+                    }
+                }
+            }
+        };
+        let result = match &lookup {
+            Lookup::Fresh(_) => "fresh",
+            Lookup::Stale { .. } => "stale",
+            Lookup::Miss(_) => "miss",
+            Lookup::Pending(_) => "pending",
+        };
+        metrics::increment_counter!("darknode_cache_lookups_total", "result" => result);
+        lookup
+    }
+    
+    /// Cache `response` to a request for `method` under `key`, if it is cacheable
+    ///
+    /// Results are cached under the method's policy, deterministic errors under the
+    /// negative TTL, and other errors not at all. A refresh of the key is over either way.
+    pub fn store(&self, key: &str, method: &str, response: &serde_json::Value, now: Instant) {
+        self.refreshing.lock().remove(key);
+        let Some(policy) = self.config.methods.get(method) else { return };
+        let (ttl, grace) = match response.get("error").filter(|error| !error.is_null()) {
+            None => (policy.ttl, policy.grace),
+            Some(error) if error["code"].as_i64().map_or(false, |code| DETERMINISTIC_ERROR_CODES.contains(&code)) => {
+                (self.config.negative_ttl, Duration::ZERO)
+            }
+            Some(_) => return,
+        };
+        
+        let mut entries = self.entries.lock();
+        if entries.len() >= self.config.max_entries && !entries.contains_key(key) {
+            entries.retain(|_, entry| now < entry.stale_until);
+            if entries.len() >= self.config.max_entries {
+                return;
+            }
+        }
+        let mut response = response.clone();
+        if let Some(object) = response.as_object_mut() {
+            object.remove("id");
+        }
+        entries.insert(
+            key.to_string(),
+            Entry {
+                response,
+                fresh_until: now + ttl,
+                stale_until: now + ttl + grace,
+            },
+        );
+    }
+    
+    /// Give up refreshing `key`, so the next stale lookup tries again
+    pub fn abandon(&self, key: &str) {
+        self.refreshing.lock().remove(key);
+    }
+}
+
+/// A cached response as the answer to request `id`
+pub fn respond(mut response: serde_json::Value, id: &serde_json::Value) -> serde_json::Value {
+    if let Some(object) = response.as_object_mut() {
+        object.insert("id".to_string(), id.clone());
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chains::{Chain, Network};
+    
+    fn cache() -> ResponseCache {
+        ResponseCache::new(CacheConfig {
+            enabled: true,
+            ..CacheConfig::default()
+        })
+    }
+    
+    fn payload(chain: Chain, network: Network) -> ExitPayload {
+        let mut payload = crate::emulation::version_request(Network::Mainnet);
+        payload.request = serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "getGenesisHash"});
+        payload.chain = Some(chain);
+        payload.network = network;
+        payload
+    }
+    
+    #[test]
+    fn networks_are_cached_apart() {
+        let cache = cache();
+        let mainnet = cache.key(&payload(Chain::Solana, Network::Mainnet)).unwrap();
+        let devnet = cache.key(&payload(Chain::Solana, Network::Devnet)).unwrap();
+        assert_ne!(mainnet, devnet);
+        
+        let now = Instant::now();
+        cache.store(&mainnet, "getGenesisHash", &serde_json::json!({"result": "mainnet"}), now);
+        assert!(matches!(cache.lookup(&mainnet, now), Lookup::Fresh(_)));
+        assert!(matches!(cache.lookup(&devnet, now), Lookup::Miss(_)));
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn requests_missing_the_same_entry_wait_for_the_first_to_fetch_it() {
+        let cache = Arc::new(cache());
+        let key = cache.key(&payload(Chain::Solana, Network::Mainnet)).unwrap();
+        let Lookup::Miss(fill) = cache.lookup(&key, Instant::now()) else { panic!("expected a miss") };
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let Lookup::Pending(pending) = cache.lookup(&key, Instant::now()) else { panic!("expected to wait") };
+                let (cache, key) = (cache.clone(), key.clone());
+                tokio::spawn(async move {
+                    pending.wait().await;
+                    cache.lookup(&key, Instant::now())
+                })
+            })
+            .collect();
+        
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        cache.store(&key, "getGenesisHash", &serde_json::json!({"result": "hash"}), Instant::now());
+        drop(fill);
+        for waiter in waiters {
+            match waiter.await.unwrap() {
+                Lookup::Fresh(response) => assert_eq!(response["result"], "hash"),
+                other => panic!("expected the fetched entry, got {:?}", other),
+            }
+        }
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_fetch_given_up_lets_the_next_request_fetch_instead() {
+        let cache = cache();
+        let key = cache.key(&payload(Chain::Solana, Network::Mainnet)).unwrap();
+        let Lookup::Miss(fill) = cache.lookup(&key, Instant::now()) else { panic!("expected a miss") };
+        let Lookup::Pending(pending) = cache.lookup(&key, Instant::now()) else { panic!("expected to wait") };
+        drop(fill);
+        pending.wait().await;
+        assert!(matches!(cache.lookup(&key, Instant::now()), Lookup::Miss(_)));
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn stale_entries_are_served_while_one_request_refreshes_them() {
+        let cache = cache();
+        let key = cache.key(&payload(Chain::Solana, Network::Mainnet)).unwrap();
+        cache.store(&key, "getGenesisHash", &serde_json::json!({"result": "old"}), Instant::now());
+        tokio::time::advance(Duration::from_secs(3601)).await;
+        
+        let refreshes: Vec<bool> = (0..5)
+            .map(|_| match cache.lookup(&key, Instant::now()) {
+                Lookup::Stale { response, refresh } => {
+                    assert_eq!(response["result"], "old");
+                    refresh
+                }
+                other => panic!("expected a stale entry, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(refreshes.iter().filter(|refresh| **refresh).count(), 1);
+        
+        cache.store(&key, "getGenesisHash", &serde_json::json!({"result": "new"}), Instant::now());
+        match cache.lookup(&key, Instant::now()) {
+            Lookup::Fresh(response) => assert_eq!(response["result"], "new"),
+            other => panic!("expected the refreshed entry, got {:?}", other),
+        }
+    }
+}
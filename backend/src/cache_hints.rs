@@ -0,0 +1,211 @@
+//! Telling HTTP clients which entry node responses they may cache
+//!
+//! Dapp frontends fetch the same confirmed transactions and finalized blocks over and over,
+//! and every fetch costs a trip through a circuit. Much of that data can't change once the
+//! cluster has finalized it, so the entry node tells HTTP-aware clients to keep it, with a
+//! `Cache-Control` header on the response.
+//!
+//! Each method falls in a [`Cacheability`] class. A method that is immutable once finalized
+//! is only cached when the request's commitment level is `finalized`, which Solana assumes
+//! when none is given: `getTransaction` at `finalized` is cached for `immutable_max_age`,
+//! while at `confirmed` or `processed` it is not. Short-lived answers, such as the current
+//! slot, are cached for `short_lived_max_age`. Everything else, errors, empty results and
+//! every batch response are sent with `no-store`, see [`default_to_no_store`]. Responses
+//! are marked `private`, since a shared cache in front of the entry node would see the API
+//! key in the URL.
+
+use super::*;
+use axum::body::Body;
+use axum::http::{header, HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::BTreeMap;
+
+/// Directive for responses clients must not keep
+pub const NO_STORE: &str = "no-store";
+
+/// Commitment level under which a finalized answer can't change
+const FINALIZED: &str = "finalized";
+
+/// Methods whose answers can't change once the data they are about is finalized
+const IMMUTABLE_ONCE_FINALIZED: &[&str] = &[
+    // Solana
+    "getBlock",
+    "getBlockTime",
+    "getGenesisHash",
+    "getTransaction",
+    // Ethereum
+    "eth_chainId",
+    "net_version",
+];
+
+/// Methods whose answers change every few hundred milliseconds
+const SHORT_LIVED: &[&str] = &[
+    // Solana
+    "getBlockHeight",
+    "getEpochInfo",
+    "getLatestBlockhash",
+    "getSlot",
+    // Ethereum
+    "eth_blockNumber",
+    "eth_gasPrice",
+];
+
+/// How long a method's answers may be kept by the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cacheability {
+    /// Kept for long once the request's commitment level is `finalized`
+    ImmutableOnceFinalized,
+    /// Kept for a moment
+    ShortLived,
+    /// Never kept
+    Never,
+}
+
+/// How long clients are told to cache responses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheHintConfig {
+    /// Whether cacheable responses are marked as such; if not, every response is `no-store`
+    pub enabled: bool,
+    /// How long finalized, immutable answers are cached
+    pub immutable_max_age: Duration,
+    /// How long short-lived answers are cached
+    pub short_lived_max_age: Duration,
+    /// Classes replacing the built-in one of these methods
+    pub methods: BTreeMap<String, Cacheability>,
+}
+
+impl Default for CacheHintConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            immutable_max_age: Duration::from_secs(24 * 3600),
+            short_lived_max_age: Duration::from_secs(1),
+            methods: BTreeMap::new(),
+        }
+    }
+}
+
+impl CacheHintConfig {
+    /// The class of `method`'s answers
+    pub fn cacheability(&self, method: &str) -> Cacheability {
+        if let Some(class) = self.methods.get(method) {
+            *class
+        } else if IMMUTABLE_ONCE_FINALIZED.contains(&method) {
+            Cacheability::ImmutableOnceFinalized
+        } else if SHORT_LIVED.contains(&method) {
+            Cacheability::ShortLived
+        } else {
+            Cacheability::Never
+        }
+    }
+    
+    /// How long the answer to `method` called with `params` may be cached, if at all
+    pub fn max_age(&self, method: &str, params: &[serde_json::Value]) -> Option<Duration> {
+        if !self.enabled {
+            return None;
+        }
+        match self.cacheability(method) {
+            Cacheability::ImmutableOnceFinalized if commitment(params) == FINALIZED => Some(self.immutable_max_age),
+            Cacheability::ShortLived => Some(self.short_lived_max_age),
+            _ => None,
+        }
+    }
+    
+    /// The `Cache-Control` value for a single request's response
+    ///
+    /// Only a successful, non-empty `result` is ever cached.
+    pub fn cache_control(
+        &self,
+        method: &str,
+        params: &[serde_json::Value],
+        result: Option<&serde_json::Value>,
+    ) -> String {
+        let max_age = match result {
+            Some(result) if !result.is_null() => self.max_age(method, params),
+            _ => None,
+        };
+        match max_age {
+            Some(max_age) if max_age.as_secs() > 0 => format!("private, max-age={}", max_age.as_secs()),
+            _ => NO_STORE.to_string(),
+        }
+    }
+}
+
+/// The commitment level a request asks for, `finalized` unless its config param says otherwise
+fn commitment(params: &[serde_json::Value]) -> &str {
+    params
+        .iter()
+        .find_map(|param| param.get("commitment"))
+        .and_then(|commitment| commitment.as_str())
+        .unwrap_or(FINALIZED)
+}
+
+/// Middleware marking every response that doesn't say how long it may be cached as `no-store`
+pub async fn default_to_no_store(request: Request<Body>, next: Next<Body>) -> Response {
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .entry(header::CACHE_CONTROL)
+        .or_insert(HeaderValue::from_static(NO_STORE));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::post;
+    use axum::Router;
+    use serde_json::json;
+    use tower::ServiceExt;
+    
+    fn transaction(commitment: &str) -> Vec<serde_json::Value> {
+        vec![
+            json!("5VERv8NMvzbJMEkV8xnrLkEaWRtSz9CosKDYjCJjBRnbJLgp8uirBgmQpjKhoR4tjF3ZpRzrFmBV6UjKdiSZkQUW"),
+            json!({ "commitment": commitment }),
+        ]
+    }
+    
+    #[test]
+    fn a_finalized_transaction_is_kept_and_a_processed_one_is_not() {
+        let config = CacheHintConfig::default();
+        let result = json!({ "slot": 250_000_000 });
+        
+        let finalized = config.cache_control("getTransaction", &transaction("finalized"), Some(&result));
+        assert_eq!(finalized, format!("private, max-age={}", config.immutable_max_age.as_secs()));
+        assert_eq!(config.cache_control("getTransaction", &transaction("processed"), Some(&result)), NO_STORE);
+        assert_eq!(config.cache_control("getTransaction", &transaction("finalized"), Some(&json!(null))), NO_STORE);
+        
+        let disabled = CacheHintConfig {
+            enabled: false,
+            ..CacheHintConfig::default()
+        };
+        assert_eq!(disabled.cache_control("getTransaction", &transaction("finalized"), Some(&result)), NO_STORE);
+    }
+    
+    #[tokio::test]
+    async fn responses_that_say_nothing_such_as_batches_are_not_kept() {
+        let config = CacheHintConfig::default();
+        let single = config.cache_control("getTransaction", &transaction("finalized"), Some(&json!({ "slot": 1 })));
+        let app = Router::new()
+            .route("/single", post(move || async move { ([(header::CACHE_CONTROL, single)], "{}") }))
+            .route(
+                "/batch",
+                post(|| async { axum::Json(json!([{ "jsonrpc": "2.0", "id": 1, "result": { "slot": 1 } }])) }),
+            )
+            .layer(axum::middleware::from_fn(default_to_no_store));
+        let cache_control = |path: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = Request::post(path).body(Body::empty()).unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                response.headers()[header::CACHE_CONTROL].to_str().unwrap().to_string()
+            }
+        };
+        
+        assert_eq!(cache_control("/single").await, format!("private, max-age={}", config.immutable_max_age.as_secs()));
+        assert_eq!(cache_control("/batch").await, NO_STORE);
+    }
+}
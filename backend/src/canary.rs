@@ -0,0 +1,269 @@
+//! Canary requests validating the path users actually take, end to end
+//!
+//! Health checks only show that each component is up. The canary sends a known read
+//! through a randomly chosen entry node every interval, using an API key of its own, and
+//! checks that a well-formed answer comes back in time. Results are published as metrics
+//! and through [`CanaryRunner::status`], and a webhook fires when the canary starts
+//! failing, as does a [`Event::CanaryFailed`] on the coordinator's bus if one is attached. Entry nodes recognize the canary's user and keep its traffic out of user-facing
+//! stats, counting it under its own metric instead.
+
+use super::*;
+use super::events::{Event, EventBus};
+use super::traits::NodeManager;
+use super::types::NodeRole;
+use rand::seq::SliceRandom;
+use std::collections::VecDeque;
+
+/// What the canary sends, where, and what it accepts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CanaryConfig {
+    /// API key of the canary's user, which must be marked as the canary
+    pub api_key: String,
+    /// URLs of the entry nodes to pick from; when empty, entry nodes come from the directory
+    #[serde(default)]
+    pub entry_urls: Vec<String>,
+    /// How often a canary request is sent
+    pub interval: Duration,
+    /// The read sent through the network
+    pub method: String,
+    /// Parameters of the read
+    #[serde(default)]
+    pub params: Vec<serde_json::Value>,
+    /// Latency above which a response counts as a failure
+    pub max_latency: Duration,
+    /// URL notified when the canary starts failing
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    /// Results kept for the status endpoint
+    pub history: usize,
+}
+
+impl Default for CanaryConfig {
+    fn default() -> Self {
+        Self {
+            api_key: String::new(),
+            entry_urls: Vec::new(),
+            interval: Duration::from_secs(30),
+            method: "getSlot".to_string(),
+            params: Vec::new(),
+            max_latency: Duration::from_secs(5),
+            webhook_url: None,
+            history: 50,
+        }
+    }
+}
+
+/// Where the canary finds entry nodes to send through
+pub enum EntrySource {
+    /// A fixed list of entry node URLs
+    Static(Vec<String>),
+    /// The entry nodes currently registered in the directory
+    Directory(Arc<dyn NodeManager + Send + Sync>),
+}
+
+/// The outcome of one canary request
+#[derive(Debug, Clone, Serialize)]
+pub struct CanaryResult {
+    /// When the request was sent
+    pub at: Timestamp,
+    /// The entry node it was sent through, if one was available
+    pub entry: Option<String>,
+    /// Whether a valid response came back in time
+    pub success: bool,
+    /// Time until the response, or until the request failed
+    pub latency: Duration,
+    /// Why the request failed
+    pub failure: Option<String>,
+}
+
+/// Recent canary results, as reported on the status endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct CanaryStatus {
+    /// Whether the latest request succeeded; false before the first one
+    pub healthy: bool,
+    /// Failed requests since the last success
+    pub consecutive_failures: u32,
+    /// Recent results, newest first
+    pub recent: Vec<CanaryResult>,
+}
+
+/// Sends canary requests and keeps their results
+pub struct CanaryRunner {
+    config: CanaryConfig,
+    entries: EntrySource,
+    client: reqwest::Client,
+    results: parking_lot::Mutex<VecDeque<CanaryResult>>,
+    events: Option<Arc<EventBus>>,
+}
+
+impl CanaryRunner {
+    /// Create a runner sending through entry nodes from `entries`
+    pub fn new(config: CanaryConfig, entries: EntrySource) -> Self {
+        Self {
+            config,
+            entries,
+            client: reqwest::Client::new(),
+            results: parking_lot::Mutex::new(VecDeque::new()),
+            events: None,
+        }
+    }
+    
+    /// Also announce the canary starting to fail on `events`
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+    
+    /// Send a canary request every interval until the task is dropped
+    pub async fn run(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(self.config.interval);
+        loop {
+            ticker.tick().await;
+            self.check().await;
+        }
+    }
+    
+    /// Send one canary request through a random entry node and record the result
+    pub async fn check(&self) -> CanaryResult {
+        let at = Timestamp::now();
+        let started = std::time::Instant::now();
+        let (entry, outcome) = match self.pick_entry().await {
+            Ok(entry) => {
+                let outcome = self.send(&entry).await;
+                (Some(entry), outcome)
+            }
+            Err(e) => (None, Err(e.to_string())),
+        };
+        let latency = started.elapsed();
+        let outcome = outcome.and_then(|()| match latency > self.config.max_latency {
+            true => Err(format!(
+                "took {}ms, over the {}ms limit",
+                latency.as_millis(),
+                self.config.max_latency.as_millis()
+            )),
+            false => Ok(()),
+        });
+        let result = CanaryResult {
+            at,
+            entry,
+            success: outcome.is_ok(),
+            latency,
+            failure: outcome.err(),
+        };
+        
+        metrics::increment_counter!(
+            "darknode_canary_checks_total",
+            "outcome" => if result.success { "success" } else { "failure" }
+        );
+        metrics::histogram!("darknode_canary_latency_seconds", latency.as_secs_f64());
+        metrics::gauge!("darknode_canary_healthy", if result.success { 1.0 } else { 0.0 });
+        
+        // Notify on the first failure after a success, not on every failure in a row
+        let started_failing = !result.success && self.status().consecutive_failures == 0;
+        self.record(result.clone());
+        if started_failing {
+            tracing::warn!("Canary request failed: {}", result.failure.as_deref().unwrap_or_default());
+            if let Some(events) = &self.events {
+                events.emit(Event::CanaryFailed {
+                    entry: result.entry.clone(),
+                    failure: result.failure.clone().unwrap_or_default(),
+                });
+            }
+            self.notify(&result).await;
+        }
+        result
+    }
+    
+    /// Recent results and whether the canary is currently passing
+    pub fn status(&self) -> CanaryStatus {
+        let results = self.results.lock();
+        CanaryStatus {
+            healthy: results.front().map_or(false, |result| result.success),
+            consecutive_failures: results.iter().take_while(|result| !result.success).count() as u32,
+            recent: results.iter().cloned().collect(),
+        }
+    }
+    
+    /// Keep a result, dropping the oldest beyond the history
+    fn record(&self, result: CanaryResult) {
+        let mut results = self.results.lock();
+        results.push_front(result);
+        results.truncate(self.config.history);
+    }
+    
+    /// A random entry node URL
+    async fn pick_entry(&self) -> Result<String> {
+        let urls = match &self.entries {
+            EntrySource::Static(urls) => urls.clone(),
+            EntrySource::Directory(node_manager) => node_manager
+                .get_available_nodes(NodeRole::Entry)
+                .await?
+                .into_iter()
+                .map(|node| format!("http://{}:{}/", node.ip_address, node.port))
+                .collect(),
+        };
+        urls.choose(&mut rand::thread_rng())
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No entry nodes to send the canary through"))
+    }
+    
+    /// Send the canary read to `entry` and validate the response
+    async fn send(&self, entry: &str) -> Result<(), String> {
+        let id = Uuid::new_v4().to_string();
+        let response = self
+            .client
+            .post(entry)
+            .timeout(self.config.max_latency)
+            .json(&serde_json::json!({
+                "api_key": self.config.api_key,
+                "method": self.config.method,
+                "params": self.config.params,
+                "id": id,
+            }))
+            .send()
+            .await
+            .map_err(|e| format!("request failed: {}", e))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("entry node answered with status {}", status));
+        }
+        let response: serde_json::Value = response
+            .json()
+            .await
+            .map_err(|e| format!("response is not JSON: {}", e))?;
+        validate(&response, &id)
+    }
+    
+    /// Post a failed result to the webhook, if one is configured
+    async fn notify(&self, result: &CanaryResult) {
+        let Some(url) = &self.config.webhook_url else { return };
+        let delivered = self
+            .client
+            .post(url)
+            .json(&serde_json::json!({
+                "event": "canary_failed",
+                "result": result,
+            }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = delivered {
+            tracing::warn!("Failed to deliver canary webhook: {}", e);
+        }
+    }
+}
+
+/// Check that a response answers request `id` with a result rather than an error
+pub fn validate(response: &serde_json::Value, id: &str) -> Result<(), String> {
+    if response["id"] != serde_json::json!(id) {
+        return Err(format!("response id {} does not match request id {}", response["id"], id));
+    }
+    if !response["error"].is_null() {
+        return Err(format!("response is an error: {}", response["error"]));
+    }
+    if response["result"].is_null() {
+        return Err("response has no result".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,245 @@
+//! Deterministic encoding of the structures nodes sign
+//!
+//! A signature only verifies if the verifier rebuilds the exact bytes the signer signed.
+//! Serializing a struct with `serde_json` follows the order its fields are declared in,
+//! which a refactor may change, and prints floats however the formatter of the day rounds
+//! them, so signatures made by one release can fail to verify on the next.
+//!
+//! Every signed structure implements [`Signable`] and is signed over its
+//! [`Signable::canonical_bytes`]: a domain line naming the structure and its encoding
+//! version, then the signed fields as JSON with the members of every object sorted by key
+//! and no whitespace, as [`encode`] writes them. Floats are refused; a signed structure
+//! carries them as integers scaled with [`scaled`] instead. Service receipts and hop
+//! messages, signed over a few plain strings and integers since before this module, keep
+//! their line formats, and directories are signed over their JSON exactly as carried.
+//!
+//! The encoding of a structure never changes within a version, so changing what is signed
+//! means a new domain line. The tests hold each encoding to a fixture under
+//! `tests/fixtures/canonical`, made by the release that introduced it.
+
+use super::*;
+
+/// A structure nodes sign, encoded the same way by every release
+pub trait Signable {
+    /// The bytes signed, and rebuilt to verify the signature
+    fn canonical_bytes(&self) -> Result<Vec<u8>, CanonicalError>;
+}
+
+/// A structure couldn't be encoded canonically
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CanonicalError {
+    /// The structure holds a float, which has no canonical form
+    #[error("float at `{0}` has no canonical encoding, scale it to an integer")]
+    Float(String),
+    /// The structure can't be serialized at all
+    #[error("failed to serialize for signing: {0}")]
+    Serialize(String),
+}
+
+/// Encode `value` under `domain`: the domain line, then `value` as sorted, compact JSON
+pub fn encode<T: Serialize>(domain: &str, value: &T) -> Result<Vec<u8>, CanonicalError> {
+    let value = serde_json::to_value(value).map_err(|e| CanonicalError::Serialize(e.to_string()))?;
+    check_integers(&value, "")?;
+    let mut bytes = format!("{}\n", domain).into_bytes();
+    serde_json::to_writer(&mut bytes, &sorted(value)).map_err(|e| CanonicalError::Serialize(e.to_string()))?;
+    Ok(bytes)
+}
+
+/// `value` with the members of every object in key order, whatever order they arrived in
+pub fn sorted(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(object) => {
+            let mut members: Vec<_> = object.into_iter().collect();
+            members.sort_by(|a, b| a.0.cmp(&b.0));
+            serde_json::Value::Object(members.into_iter().map(|(key, value)| (key, sorted(value))).collect())
+        }
+        serde_json::Value::Array(items) => serde_json::Value::Array(items.into_iter().map(sorted).collect()),
+        value => value,
+    }
+}
+
+/// `value` as an integer count of `10^-decimals`, rounded to the nearest
+pub fn scaled(value: f64, decimals: u32) -> i64 {
+    (value * 10f64.powi(decimals as i32)).round() as i64
+}
+
+/// Fail on the first number in `value` that isn't an integer, naming where it is
+fn check_integers(value: &serde_json::Value, path: &str) -> Result<(), CanonicalError> {
+    match value {
+        serde_json::Value::Number(number) if number.is_f64() => Err(CanonicalError::Float(path.to_string())),
+        serde_json::Value::Object(object) => object
+            .iter()
+            .try_for_each(|(key, member)| match path {
+                "" => check_integers(member, key),
+                path => check_integers(member, &format!("{}.{}", path, key)),
+            }),
+        serde_json::Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, item)| check_integers(item, &format!("{}[{}]", path, i))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounting::{NodeTally, Work, WorkReceipt};
+    use crate::attribution::ProviderAttestation;
+    use crate::directory::SignedDirectory;
+    use crate::directory_watch::SignedDiff;
+    use crate::hop_auth::HopMessage;
+    use crate::receipts::ServiceReceipt;
+    use crate::types::NodeId;
+    use serde::de::DeserializeOwned;
+    
+    fn node(n: u128) -> NodeId {
+        NodeId(Uuid::from_u128(n))
+    }
+    
+    fn work_receipt() -> WorkReceipt {
+        WorkReceipt {
+            issuer: node(1),
+            epoch: 42,
+            tallies: vec![
+                NodeTally {
+                    node_id: node(2),
+                    work: Work { requests: 3, bytes: 4096 },
+                },
+                NodeTally {
+                    node_id: node(3),
+                    work: Work { requests: 1, bytes: 512 },
+                },
+            ],
+            nonce: Uuid::from_u128(7),
+            signature: vec![1, 2, 3],
+        }
+    }
+    
+    fn service_receipt() -> ServiceReceipt {
+        ServiceReceipt {
+            node_id: node(1),
+            timestamp: 1_700_000_000,
+            circuit: "0011223344556677".to_string(),
+            salt: "aa".repeat(16),
+            request_hash: "bb".repeat(32),
+            response_hash: "cc".repeat(32),
+            signature: "dd".repeat(64),
+        }
+    }
+    
+    fn attestation() -> ProviderAttestation {
+        ProviderAttestation {
+            epoch: 42,
+            class_hash: "ee".repeat(32),
+            archive: true,
+            region: "eu-west".to_string(),
+            timestamp: 1_700_000_000,
+            salt: "aa".repeat(16),
+            response_hash: "cc".repeat(32),
+            signature: "dd".repeat(64),
+        }
+    }
+    
+    fn directory() -> SignedDirectory {
+        SignedDirectory {
+            directory: r#"{"epoch":{"number":42},"nodes":[]}"#.to_string(),
+            signer: "ff".repeat(32),
+            signature: "dd".repeat(64),
+        }
+    }
+    
+    fn directory_diff() -> SignedDiff {
+        SignedDiff {
+            diff: r#"{"version":7,"full":false,"nodes":[],"removed":[]}"#.to_string(),
+            signer: "ff".repeat(32),
+            signature: "dd".repeat(64),
+        }
+    }
+    
+    /// `value` as JSON text with the members of every object in reverse key order
+    fn reversed(value: &serde_json::Value) -> String {
+        match value {
+            serde_json::Value::Object(object) => {
+                let members: Vec<_> = object
+                    .iter()
+                    .rev()
+                    .map(|(key, member)| format!("{}:{}", serde_json::Value::from(key.as_str()), reversed(member)))
+                    .collect();
+                format!("{{{}}}", members.join(","))
+            }
+            serde_json::Value::Array(items) => format!("[{}]", items.iter().map(reversed).collect::<Vec<_>>().join(",")),
+            value => value.to_string(),
+        }
+    }
+    
+    /// `value` read back from its JSON with every object's members in reverse order
+    fn reordered<T: Serialize + DeserializeOwned>(value: &T) -> T {
+        serde_json::from_str(&reversed(&serde_json::to_value(value).unwrap())).unwrap()
+    }
+    
+    #[test]
+    fn encode_sorts_members_and_refuses_floats() {
+        let bytes = encode("test:v1", &serde_json::json!({"b": 1, "a": {"d": [2, 3], "c": true}})).unwrap();
+        assert_eq!(String::from_utf8(bytes).unwrap(), "test:v1\n{\"a\":{\"c\":true,\"d\":[2,3]},\"b\":1}");
+        
+        let refused = encode("test:v1", &serde_json::json!({"a": [{"load": 0.5}]})).unwrap_err();
+        assert_eq!(refused, CanonicalError::Float("a[0].load".to_string()));
+        assert_eq!(scaled(0.123456, 4), 1235);
+    }
+    
+    #[test]
+    fn reordered_fields_encode_to_the_same_bytes() {
+        let receipt = work_receipt();
+        assert_eq!(reordered(&receipt).canonical_bytes(), receipt.canonical_bytes());
+        let receipt = service_receipt();
+        assert_eq!(reordered(&receipt).canonical_bytes(), receipt.canonical_bytes());
+        let attestation = attestation();
+        assert_eq!(reordered(&attestation).canonical_bytes(), attestation.canonical_bytes());
+        let directory = directory();
+        assert_eq!(reordered(&directory).canonical_bytes(), directory.canonical_bytes());
+    }
+    
+    #[test]
+    fn encodings_match_the_stored_fixtures() {
+        // Each fixture holds the bytes signed by the release that introduced the encoding; a
+        // change here breaks every signature already made, so it needs a new domain line
+        let hop = HopMessage {
+            node_id: &node(1),
+            timestamp: 1_700_000_000,
+            nonce: "000102030405060708090a0b0c0d0e0f",
+            body: b"{\"request\":{}}",
+        };
+        let cases: [(&str, Vec<u8>, &str); 6] = [
+            (
+                "work receipt",
+                work_receipt().canonical_bytes().unwrap(),
+                include_str!("../tests/fixtures/canonical/work_receipt_v1.txt"),
+            ),
+            (
+                "service receipt",
+                service_receipt().canonical_bytes().unwrap(),
+                include_str!("../tests/fixtures/canonical/service_receipt_v1.txt"),
+            ),
+            (
+                "attestation",
+                attestation().canonical_bytes().unwrap(),
+                include_str!("../tests/fixtures/canonical/attestation_v1.txt"),
+            ),
+            (
+                "directory",
+                directory().canonical_bytes().unwrap(),
+                include_str!("../tests/fixtures/canonical/directory_v1.txt"),
+            ),
+            (
+                "directory diff",
+                directory_diff().canonical_bytes().unwrap(),
+                include_str!("../tests/fixtures/canonical/directory_diff_v1.txt"),
+            ),
+            ("hop message", hop.canonical_bytes().unwrap(), include_str!("../tests/fixtures/canonical/hop_v2.txt")),
+        ];
+        for (name, bytes, fixture) in cases {
+            assert_eq!(String::from_utf8(bytes).unwrap(), fixture, "{}", name);
+        }
+    }
+}
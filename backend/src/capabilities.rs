@@ -0,0 +1,142 @@
+//! Provider capabilities and client selection hints
+
+use super::methods::EXTENSION_KEY;
+use super::timeouts::MethodClass;
+use super::types::RpcProvider;
+
+/// Capabilities clients may ask for
+pub const KNOWN_CAPABILITIES: &[&str] = &[
+    "archive",
+    "program_accounts_filters",
+    "transaction_history",
+];
+
+/// Errors from capability-based provider selection
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum CapabilityError {
+    /// The client hinted a capability DarkNode doesn't know about
+    #[error("unknown provider capability {0:?}")]
+    Unknown(String),
+    /// The hints field was not a list of strings
+    #[error("provider capabilities must be a list of strings")]
+    Malformed,
+    /// No active provider supports every required capability
+    #[error("no provider supports all of {required:?}")]
+    NoCapableProvider {
+        /// The capabilities that were required
+        required: Vec<String>,
+    },
+    /// This exit node doesn't serve methods of the class, see [`crate::egress`]
+    #[error("this exit node does not serve {} methods", .0.label())]
+    MethodClassRefused(MethodClass),
+}
+
+/// Remove the client's provider hints from a request and validate them
+pub fn take_hints(request: &mut serde_json::Value) -> Result<Vec<String>, CapabilityError> {
+    let Some(extension) = request
+        .as_object_mut()
+        .and_then(|object| object.remove(EXTENSION_KEY))
+    else {
+        return Ok(Vec::new());
+    };
+    let hints = match extension.get("capabilities") {
+        Some(hints) => hints.as_array().ok_or(CapabilityError::Malformed)?,
+        None => return Ok(Vec::new()),
+    };
+    
+    let mut capabilities = Vec::with_capacity(hints.len());
+    for hint in hints {
+        let hint = hint.as_str().ok_or(CapabilityError::Malformed)?;
+        if !KNOWN_CAPABILITIES.contains(&hint) {
+            return Err(CapabilityError::Unknown(hint.to_string()));
+        }
+        if !capabilities.iter().any(|c| c == hint) {
+            capabilities.push(hint.to_string());
+        }
+    }
+    Ok(capabilities)
+}
+
+/// Whether a provider supports every required capability
+pub fn supports(provider: &RpcProvider, required: &[String]) -> bool {
+    required.iter().all(|c| provider.capabilities.contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::impls::StoredRpcManager;
+    use crate::sanitizer::{Sanitizer, SanitizerConfig};
+    use crate::storage::MemoryStorage;
+    use crate::traits::{RequestSanitizer, RpcManager};
+    use crate::types::ExitPayload;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    
+    /// A provider answering every request with `name`
+    fn named(name: &'static str) -> RpcProvider {
+        fixtures::serving(move |request: Value| async move { json!({ "jsonrpc": "2.0", "id": request["id"], "result": name }) })
+    }
+    
+    fn hinted(capabilities: &[&str]) -> ExitPayload {
+        ExitPayload {
+            capabilities: capabilities.iter().map(|capability| capability.to_string()).collect(),
+            ..fixtures::payload("getTransaction", json!(["5VERv8NMvzbJMEkV8xnrLkEaWRtSz9CosKDYjCJjBRnb"]))
+        }
+    }
+    
+    #[tokio::test]
+    async fn hints_send_requests_to_the_providers_able_to_serve_them() {
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        rpc_manager.register_provider(named("recent")).await.unwrap();
+        let archive = RpcProvider {
+            capabilities: vec!["archive".to_string()],
+            success_rate: 0.9,
+            ..named("archive")
+        };
+        rpc_manager.register_provider(archive).await.unwrap();
+        let exit = Arc::new(fixtures::exit(rpc_manager));
+        let served_by = |payload: ExitPayload| {
+            let exit = exit.clone();
+            async move {
+                let answer: Value = serde_json::from_slice(&exit.serve(&payload).await.unwrap()).unwrap();
+                answer["result"].as_str().unwrap().to_string()
+            }
+        };
+        
+        // Unhinted requests go to the better scored provider, hinted ones only to the archive
+        assert_eq!(served_by(hinted(&[])).await, "recent");
+        for _ in 0..4 {
+            assert_eq!(served_by(hinted(&["archive"])).await, "archive");
+        }
+        
+        let refused = exit.serve(&hinted(&["archive", "transaction_history"])).await.unwrap_err();
+        match refused.downcast_ref::<CapabilityError>() {
+            Some(CapabilityError::NoCapableProvider { required }) => assert_eq!(required, &["archive", "transaction_history"]),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn the_extension_never_leaves_the_entry_node_once_read() {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "sendTransaction",
+            "params": ["AQABAg=="],
+            EXTENSION_KEY: { "refresh_blockhash": true, "capabilities": ["archive", "archive"] },
+        });
+        let sanitizer = Sanitizer::new(&SanitizerConfig::default());
+        let payload = sanitizer
+            .sanitize_payload(uuid::Uuid::new_v4(), &serde_json::to_vec(&request).unwrap())
+            .await
+            .unwrap();
+        assert!(payload.relay);
+        assert_eq!(payload.capabilities, vec!["archive"]);
+        assert!(payload.request.get(EXTENSION_KEY).is_none());
+        
+        let mut unknown = json!({ "method": "getSlot", EXTENSION_KEY: { "capabilities": ["teleport"] } });
+        assert!(matches!(take_hints(&mut unknown), Err(CapabilityError::Unknown(hint)) if hint == "teleport"));
+    }
+}
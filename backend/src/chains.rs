@@ -0,0 +1,317 @@
+//! Which chain a request is for, told from its method
+//!
+//! A mapping can carry traffic for more than one chain by mistake, and a Solana method sent
+//! to an Ethereum provider only comes back as a confusing upstream error. The sanitizer
+//! detects the chain from the method, by the Ethereum namespaces or the known Solana
+//! methods, and the exit node only picks providers on that chain. Methods that could belong
+//! to either, or to neither, are served on the chain the mapping is pinned to, if any. A
+//! request detected as one chain sent to a mapping pinned to another is refused outright.
+//!
+//! Within a chain, providers and mappings are on a [`Network`], mainnet unless tagged
+//! otherwise, and a mapping's requests are only served by providers on its network.
+
+use super::*;
+use super::types::{RpcProvider, UnknownVariant};
+
+/// Method namespaces only Ethereum uses
+const ETHEREUM_PREFIXES: &[&str] = &["eth_", "net_", "web3_"];
+
+/// Methods of the Solana JSON-RPC API
+const SOLANA_METHODS: &[&str] = &[
+    "getAccountInfo",
+    "getBalance",
+    "getBlock",
+    "getBlockCommitment",
+    "getBlockHeight",
+    "getBlockProduction",
+    "getBlockTime",
+    "getBlocks",
+    "getBlocksWithLimit",
+    "getClusterNodes",
+    "getEpochInfo",
+    "getEpochSchedule",
+    "getFeeForMessage",
+    "getFirstAvailableBlock",
+    "getGenesisHash",
+    "getHealth",
+    "getHighestSnapshotSlot",
+    "getIdentity",
+    "getInflationGovernor",
+    "getInflationRate",
+    "getInflationReward",
+    "getLargestAccounts",
+    "getLatestBlockhash",
+    "getLeaderSchedule",
+    "getMaxRetransmitSlot",
+    "getMaxShredInsertSlot",
+    "getMinimumBalanceForRentExemption",
+    "getMultipleAccounts",
+    "getProgramAccounts",
+    "getRecentPerformanceSamples",
+    "getRecentPrioritizationFees",
+    "getSignatureStatuses",
+    "getSignaturesForAddress",
+    "getSlot",
+    "getSlotLeader",
+    "getSlotLeaders",
+    "getStakeMinimumDelegation",
+    "getSupply",
+    "getTokenAccountBalance",
+    "getTokenAccountsByDelegate",
+    "getTokenAccountsByOwner",
+    "getTokenLargestAccounts",
+    "getTokenSupply",
+    "getTransaction",
+    "getTransactionCount",
+    "getVersion",
+    "getVoteAccounts",
+    "isBlockhashValid",
+    "minimumLedgerSlot",
+    "requestAirdrop",
+    "sendTransaction",
+    "simulateTransaction",
+];
+
+/// A chain DarkNode serves
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Chain {
+    /// Solana
+    Solana,
+    /// Ethereum and EVM chains speaking its JSON-RPC API
+    Ethereum,
+}
+
+impl Chain {
+    /// Every chain
+    pub const ALL: [Chain; 2] = [Chain::Solana, Chain::Ethereum];
+    
+    /// The chain's name, as providers' types spell it
+    pub fn name(self) -> &'static str {
+        match self {
+            Chain::Solana => "solana",
+            Chain::Ethereum => "ethereum",
+        }
+    }
+    
+    /// Whether `provider` serves this chain
+    pub fn served_by(self, provider: &RpcProvider) -> bool {
+        provider.provider_type.eq_ignore_ascii_case(self.name())
+    }
+}
+
+impl std::fmt::Display for Chain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Chain {
+    type Err = UnknownVariant;
+    
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|chain| chain.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| UnknownVariant {
+                kind: "chain",
+                value: s.to_string(),
+                expected: Self::ALL.iter().map(|chain| chain.name()).collect(),
+            })
+    }
+}
+
+/// A network of a chain, such as Solana's devnet or an Ethereum testnet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Network {
+    /// The chain's main network
+    #[default]
+    Mainnet,
+    /// A public test network
+    Testnet,
+    /// A development network
+    Devnet,
+}
+
+impl Network {
+    /// Every network
+    pub const ALL: [Network; 3] = [Network::Mainnet, Network::Testnet, Network::Devnet];
+    
+    /// The network's name
+    pub fn name(self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Devnet => "devnet",
+        }
+    }
+    
+    /// Whether `provider` serves this network
+    pub fn served_by(self, provider: &RpcProvider) -> bool {
+        provider.network == self
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Network {
+    type Err = UnknownVariant;
+    
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|network| network.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| UnknownVariant {
+                kind: "network",
+                value: s.to_string(),
+                expected: Self::ALL.iter().map(|network| network.name()).collect(),
+            })
+    }
+}
+
+/// Errors from chain detection and chain-based provider selection
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ChainError {
+    /// The request's method belongs to another chain than the mapping is pinned to
+    #[error("this mapping serves {pinned}, but the request's method is for {detected}")]
+    Mismatch {
+        /// The chain the mapping is pinned to
+        pinned: Chain,
+        /// The chain detected from the method
+        detected: Chain,
+    },
+    /// No active provider serves the request's chain
+    #[error("no provider serves {0}")]
+    NoProvider(Chain),
+    /// No active provider of the request's chain serves its network
+    #[error("no provider serves {0}")]
+    NoNetworkProvider(Network),
+}
+
+/// The chain `method` belongs to, if it can be told
+pub fn detect(method: &str) -> Option<Chain> {
+    if ETHEREUM_PREFIXES.iter().any(|prefix| method.starts_with(prefix)) {
+        return Some(Chain::Ethereum);
+    }
+    if SOLANA_METHODS.contains(&method) {
+        return Some(Chain::Solana);
+    }
+    None
+}
+
+/// The chain a request is served on, given the chain detected from it and the mapping's
+pub fn resolve(detected: Option<Chain>, pinned: Option<Chain>) -> Result<Option<Chain>, ChainError> {
+    match (detected, pinned) {
+        (Some(detected), Some(pinned)) if detected != pinned => Err(ChainError::Mismatch { pinned, detected }),
+        (detected, pinned) => Ok(detected.or(pinned)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EntryConfig;
+    use crate::context::RequestContext;
+    use crate::fixtures::{self, StubRouter};
+    use crate::impls::StoredRpcManager;
+    use crate::storage::MemoryStorage;
+    use crate::traits::{RpcManager, UserManager};
+    use crate::types::{ExitPayload, RpcMapping};
+    use serde_json::json;
+    
+    #[test]
+    fn methods_are_told_apart_by_namespace_and_the_solana_api() {
+        for method in ["eth_call", "eth_getLogs", "net_version", "web3_clientVersion"] {
+            assert_eq!(detect(method), Some(Chain::Ethereum), "{}", method);
+        }
+        for method in ["getAccountInfo", "getProgramAccounts", "sendTransaction", "isBlockhashValid"] {
+            assert_eq!(detect(method), Some(Chain::Solana), "{}", method);
+        }
+        for method in ["getBlockNumber", "debug_traceTransaction", "health", ""] {
+            assert_eq!(detect(method), None, "{}", method);
+        }
+    }
+    
+    #[test]
+    fn an_unknown_method_falls_back_to_the_mapping_and_a_known_one_must_agree_with_it() {
+        assert_eq!(resolve(None, Some(Chain::Ethereum)), Ok(Some(Chain::Ethereum)));
+        assert_eq!(resolve(None, None), Ok(None));
+        assert_eq!(resolve(Some(Chain::Solana), None), Ok(Some(Chain::Solana)));
+        assert_eq!(resolve(Some(Chain::Solana), Some(Chain::Solana)), Ok(Some(Chain::Solana)));
+        assert_eq!(
+            resolve(Some(Chain::Solana), Some(Chain::Ethereum)),
+            Err(ChainError::Mismatch {
+                pinned: Chain::Ethereum,
+                detected: Chain::Solana,
+            })
+        );
+    }
+    
+    #[tokio::test]
+    async fn the_entry_node_refuses_another_chain_than_the_mapping_is_pinned_to() {
+        let router = Arc::new(StubRouter::new(|_| json!({ "jsonrpc": "2.0", "result": "0x1" })));
+        let (entry, users) = fixtures::entry(router.clone(), &EntryConfig::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let ethereum = RpcMapping {
+            original_rpc: "https://eth.example.com".to_string(),
+            chain: Some(Chain::Ethereum),
+            ..fixtures::mapping()
+        };
+        users.add_rpc_mapping(user.id, ethereum.clone()).await.unwrap();
+        let entry = &entry;
+        let send = |method: &str| {
+            let request = serde_json::to_vec(&json!({ "jsonrpc": "2.0", "id": 1, "method": method })).unwrap();
+            let ctx = RequestContext::new(&user.api_key).with_mapping(Some(ethereum.id));
+            async move { entry.handle_request(ctx, &request).await }
+        };
+        
+        send("eth_blockNumber").await.unwrap();
+        assert_eq!(router.sent()[0].1.chain, Some(Chain::Ethereum));
+        
+        let refused = send("getSlot").await.unwrap_err();
+        assert_eq!(
+            refused.downcast_ref::<ChainError>(),
+            Some(&ChainError::Mismatch {
+                pinned: Chain::Ethereum,
+                detected: Chain::Solana,
+            })
+        );
+        assert_eq!(router.sent().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn the_exit_node_serves_a_request_from_providers_of_its_chain() {
+        let answering = |chain: Chain| {
+            let provider = fixtures::serving(move |request: serde_json::Value| async move {
+                json!({ "jsonrpc": "2.0", "id": request["id"], "result": chain.name() })
+            });
+            RpcProvider {
+                provider_type: chain.name().to_string(),
+                ..provider
+            }
+        };
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        rpc_manager.register_provider(answering(Chain::Ethereum)).await.unwrap();
+        let exit = Arc::new(fixtures::exit(rpc_manager.clone()));
+        let on = |chain: Chain| ExitPayload {
+            chain: Some(chain),
+            ..fixtures::payload("eth_blockNumber", json!([]))
+        };
+        
+        let served: serde_json::Value = serde_json::from_slice(&exit.serve(&on(Chain::Ethereum)).await.unwrap()).unwrap();
+        assert_eq!(served["result"], "ethereum");
+        let refused = exit.serve(&on(Chain::Solana)).await.unwrap_err();
+        assert_eq!(refused.downcast_ref::<ChainError>(), Some(&ChainError::NoProvider(Chain::Solana)));
+        
+        rpc_manager.register_provider(answering(Chain::Solana)).await.unwrap();
+        for chain in Chain::ALL {
+            let served: serde_json::Value = serde_json::from_slice(&exit.serve(&on(chain)).await.unwrap()).unwrap();
+            assert_eq!(served["result"], chain.name());
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Classes of circuits, rotated and kept alive by different policies
+//!
+//! Most circuits carry short request/response traffic, and rotating them often is cheap:
+//! the next request simply goes out on a new circuit. A WebSocket subscription is different.
+//! Its upstream subscription lives on one provider behind one exit node, and rotating the
+//! circuit under it forces the client to resubscribe and miss what happened in between.
+//!
+//! The entry node therefore builds circuits in one of two classes. [`CircuitClass::Interactive`]
+//! circuits, for everything else, keep today's behaviour: they live an hour and are replaced
+//! when an epoch ends. [`CircuitClass::Subscription`] circuits, built for sessions holding
+//! subscriptions, live longer, ride out epochs and request counts, are pinged more often
+//! since a dead hop would otherwise go unnoticed until the next notification is missed, and
+//! carry fewer requests at once. Each class's [`ClassPolicy`] is configured separately, and
+//! circuit metrics are labelled with the class.
+//!
+//! Exit nodes keep every request of a subscription circuit on the provider that served its
+//! first, as long as that provider can still serve it.
+
+use super::*;
+
+/// How long circuits live when their class doesn't say otherwise
+pub const DEFAULT_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// What a circuit is built to carry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitClass {
+    /// Request/response traffic
+    #[default]
+    Interactive,
+    /// The traffic of WebSocket sessions holding subscriptions
+    Subscription,
+}
+
+impl CircuitClass {
+    /// Label used in logs and metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            CircuitClass::Interactive => "interactive",
+            CircuitClass::Subscription => "subscription",
+        }
+    }
+}
+
+/// How the circuits of one class are rotated and kept alive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClassPolicy {
+    /// How long a circuit lives before it is replaced
+    pub lifetime: Duration,
+    /// Requests a circuit is handed out for before it is replaced, unlimited if unset
+    pub max_requests: Option<u64>,
+    /// Whether a circuit is replaced when the epoch it was built in ends
+    pub rotate_at_epoch: bool,
+    /// How long a circuit may sit idle before it is pinged, the keepalive interval if unset
+    pub keepalive_interval: Option<Duration>,
+    /// Requests a circuit carries at once, the others waiting their turn, unlimited if unset
+    pub max_in_flight: Option<usize>,
+}
+
+impl Default for ClassPolicy {
+    fn default() -> Self {
+        Self {
+            lifetime: DEFAULT_LIFETIME,
+            max_requests: None,
+            rotate_at_epoch: true,
+            keepalive_interval: None,
+            max_in_flight: None,
+        }
+    }
+}
+
+/// The policy of every circuit class
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitClassConfig {
+    /// The policy of interactive circuits
+    pub interactive: ClassPolicy,
+    /// The policy of subscription circuits
+    pub subscription: ClassPolicy,
+}
+
+impl Default for CircuitClassConfig {
+    fn default() -> Self {
+        Self {
+            interactive: ClassPolicy::default(),
+            subscription: ClassPolicy {
+                lifetime: Duration::from_secs(6 * 3600),
+                max_requests: None,
+                rotate_at_epoch: false,
+                keepalive_interval: Some(Duration::from_secs(5)),
+                max_in_flight: Some(4),
+            },
+        }
+    }
+}
+
+impl CircuitClassConfig {
+    /// The policy of `class`
+    pub fn policy(&self, class: CircuitClass) -> &ClassPolicy {
+        match class {
+            CircuitClass::Interactive => &self.interactive,
+            CircuitClass::Subscription => &self.subscription,
+        }
+    }
+    
+    /// How long a circuit of `class` may sit idle before it is pinged, given the keepalive `interval`
+    pub fn keepalive_interval(&self, class: CircuitClass, interval: Duration) -> Duration {
+        self.policy(class).keepalive_interval.unwrap_or(interval)
+    }
+    
+    /// How often idle circuits must be looked at for every class to be pinged in time
+    pub fn keepalive_tick(&self, interval: Duration) -> Duration {
+        [CircuitClass::Interactive, CircuitClass::Subscription]
+            .into_iter()
+            .map(|class| self.keepalive_interval(class, interval))
+            .min()
+            .unwrap_or(interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EntryConfig;
+    use crate::context::RequestContext;
+    use crate::fixtures::{self, StubRouter};
+    use crate::traits::UserManager;
+    use crate::types::CircuitId;
+    
+    /// The circuits a request of each class went out on, interactive then subscription,
+    /// for `requests` of each sent `gap` apart
+    async fn carried(classes: CircuitClassConfig, requests: usize, gap: Duration) -> (Vec<CircuitId>, Vec<CircuitId>) {
+        let router = Arc::new(StubRouter::new(|_| serde_json::json!({ "jsonrpc": "2.0", "result": 311_029_712 })));
+        let config = EntryConfig {
+            circuit_classes: classes,
+            ..Default::default()
+        };
+        let (entry, users) = fixtures::entry(router.clone(), &config).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let request = serde_json::to_vec(&serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" })).unwrap();
+        
+        let (mut interactive, mut subscription) = (Vec::new(), Vec::new());
+        for _ in 0..requests {
+            for (class, used) in [
+                (CircuitClass::Interactive, &mut interactive),
+                (CircuitClass::Subscription, &mut subscription),
+            ] {
+                let ctx = RequestContext::new(&user.api_key).with_circuit_class(class);
+                entry.handle_request(ctx, &request).await.unwrap();
+                used.push(router.sent().last().unwrap().0.clone());
+            }
+            tokio::time::sleep(gap).await;
+        }
+        (interactive, subscription)
+    }
+    
+    /// How many circuits `carried` went through, in turn
+    fn circuits(mut carried: Vec<CircuitId>) -> usize {
+        carried.dedup();
+        carried.len()
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_subscription_circuit_outlives_the_interactive_lifetime() {
+        let classes = CircuitClassConfig {
+            interactive: ClassPolicy {
+                lifetime: Duration::from_secs(5 * 60),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        
+        // A request a minute for half an hour: interactive circuits are replaced every five
+        let (interactive, subscription) = carried(classes, 30, Duration::from_secs(60)).await;
+        assert_eq!(circuits(interactive.clone()), 6);
+        assert!(interactive.chunks(5).all(|chunk| chunk.iter().all(|id| *id == chunk[0])));
+        assert_eq!(circuits(subscription.clone()), 1);
+        assert!(interactive.iter().all(|id| *id != subscription[0]));
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_subscription_circuit_is_not_rotated_by_request_count() {
+        let classes = CircuitClassConfig {
+            interactive: ClassPolicy {
+                max_requests: Some(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        
+        // Forty requests in four seconds: interactive circuits are replaced every ten or so
+        let (interactive, subscription) = carried(classes, 40, Duration::from_millis(100)).await;
+        assert!(circuits(interactive) >= 3);
+        assert_eq!(circuits(subscription), 1);
+    }
+    
+    #[test]
+    fn idle_circuits_are_looked_at_often_enough_for_every_class() {
+        let classes = CircuitClassConfig::default();
+        let interval = Duration::from_secs(30);
+        assert_eq!(classes.keepalive_interval(CircuitClass::Interactive, interval), interval);
+        assert_eq!(classes.keepalive_interval(CircuitClass::Subscription, interval), Duration::from_secs(5));
+        assert_eq!(classes.keepalive_tick(interval), Duration::from_secs(5));
+        assert_eq!(classes.keepalive_tick(Duration::from_secs(2)), Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,210 @@
+//! What users may learn about the circuit carrying their requests
+//!
+//! Users who rely on the network for privacy want to check that their traffic really
+//! crosses several hops, and through which regions. [`CircuitInfo`] describes a circuit by
+//! what each hop does and where it is, when the circuit was built and will be replaced, and
+//! whether traffic shaping covers it, and never by anything that identifies a node: no node
+//! ids, addresses, or keys. Circuits are told apart by a fingerprint hashed from their id,
+//! which changes whenever the circuit is replaced.
+//!
+//! Users may also have their circuit replaced at once, at most once per
+//! [`MIN_ROTATION_INTERVAL`], as every rotation builds a circuit across several nodes.
+
+use super::*;
+use crate::relaxation::Relaxation;
+use crate::types::Circuit;
+use sha2::{Digest, Sha256};
+
+/// Shortest time between two rotations a user asks for
+pub const MIN_ROTATION_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Error returned when a user asks to rotate their circuit again too soon
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("circuit was rotated less than {} seconds ago; retry in {} seconds", MIN_ROTATION_INTERVAL.as_secs(), .retry_after.as_secs().max(1))]
+pub struct RotationThrottled {
+    /// How long until the user may rotate again
+    pub retry_after: Duration,
+}
+
+/// What a hop does in a circuit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HopRole {
+    /// The node the user connects to
+    Entry,
+    /// A node relaying between the entry and the exit
+    Routing,
+    /// The node sending requests on to providers
+    Exit,
+}
+
+/// One hop of a circuit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hop {
+    /// What the hop does
+    pub role: HopRole,
+    /// The region the hop's node is in, if known
+    pub region: Option<String>,
+}
+
+/// When a circuit gets replaced
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RotationPolicy {
+    /// How long circuits live
+    pub lifetime: Duration,
+    /// When the current epoch ends, if circuits are replaced at epoch boundaries
+    pub epoch_ends_at: Option<Timestamp>,
+    /// When the circuit will be replaced at the latest
+    pub rotates_at: Timestamp,
+    /// Whether a circuit that stops answering keepalive pings is replaced early
+    pub replaced_when_unresponsive: bool,
+}
+
+/// A circuit as its user may see it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitInfo {
+    /// Identifies the circuit without revealing its id
+    pub fingerprint: String,
+    /// Nodes the circuit crosses, the entry node included
+    pub hop_count: usize,
+    /// The circuit's hops, entry first and exit last
+    pub hops: Vec<Hop>,
+    /// When the circuit was built
+    pub created_at: Timestamp,
+    /// When the circuit expires
+    pub expires_at: Timestamp,
+    /// When the circuit gets replaced
+    pub rotation: RotationPolicy,
+    /// Whether traffic through the circuit is shaped, see [`crate::shaping`]
+    pub shaping: bool,
+    /// The round trip along the circuit's hops, as estimated when it was built
+    pub estimated_latency: Option<Duration>,
+    /// Constraints of the circuit policy relaxed to build the circuit, see [`crate::relaxation`]
+    pub relaxed: Vec<Relaxation>,
+}
+
+impl CircuitInfo {
+    /// Describe `circuit`, which is replaced at the end of the epoch ending at `epoch_ends_at`
+    /// if any, and early if `keepalive` is on and it stops answering
+    pub fn of(circuit: &Circuit, epoch_ends_at: Option<Timestamp>, keepalive: bool, shaping: bool) -> Self {
+        let roles = std::iter::once(HopRole::Entry)
+            .chain(circuit.routing_nodes.iter().map(|_| HopRole::Routing))
+            .chain(std::iter::once(HopRole::Exit));
+        let hops: Vec<Hop> = roles
+            .enumerate()
+            .map(|(i, role)| Hop {
+                role,
+                region: circuit.regions.get(i).cloned(),
+            })
+            .collect();
+        let rotates_at = epoch_ends_at.map_or(circuit.expires_at, |ends_at| ends_at.min(circuit.expires_at));
+        Self {
+            fingerprint: fingerprint(circuit),
+            hop_count: hops.len(),
+            hops,
+            created_at: circuit.created_at,
+            expires_at: circuit.expires_at,
+            rotation: RotationPolicy {
+                lifetime: circuit.lifetime(),
+                epoch_ends_at,
+                rotates_at,
+                replaced_when_unresponsive: keepalive,
+            },
+            shaping,
+            estimated_latency: circuit.estimated_latency,
+            relaxed: circuit.relaxed.clone(),
+        }
+    }
+}
+
+/// A short hash of the circuit's id, telling circuits apart without naming them
+pub fn fingerprint(circuit: &Circuit) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"darknode-circuit-fingerprint");
+    hasher.update(circuit.id.0.as_bytes());
+    hex::encode(&hasher.finalize()[..8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EntryConfig;
+    use crate::fixtures::{self, StubRouter};
+    use crate::traits::UserManager;
+    use crate::types::{CircuitId, CryptoKey, NodeId};
+    
+    /// Every field name and every leaf of `value`, as text
+    fn walk(value: &serde_json::Value, names: &mut Vec<String>, leaves: &mut Vec<String>) {
+        match value {
+            serde_json::Value::Object(fields) => {
+                for (name, field) in fields {
+                    names.push(name.clone());
+                    walk(field, names, leaves);
+                }
+            }
+            serde_json::Value::Array(items) => items.iter().for_each(|item| walk(item, names, leaves)),
+            serde_json::Value::String(leaf) => leaves.push(leaf.clone()),
+            leaf => leaves.push(leaf.to_string()),
+        }
+    }
+    
+    #[test]
+    fn nothing_described_names_or_addresses_a_node() {
+        let created_at = Timestamp::now();
+        let circuit = Circuit {
+            id: CircuitId(Uuid::new_v4()),
+            entry_node: NodeId(Uuid::new_v4()),
+            routing_nodes: vec![NodeId(Uuid::new_v4()), NodeId(Uuid::new_v4())],
+            exit_node: NodeId(Uuid::new_v4()),
+            symmetric_keys: vec![CryptoKey(vec![9; 32]); 4],
+            created_at,
+            expires_at: created_at + Duration::from_secs(600),
+            regions: ["us-east", "eu-west", "ap-south", "eu-central"].map(String::from).to_vec(),
+            protocol_version: crate::protocol::legacy_version(),
+            estimated_latency: Some(Duration::from_millis(180)),
+            relaxed: vec![Relaxation::RegionDiversity],
+            exit_classes: None,
+        };
+        let info = CircuitInfo::of(&circuit, Some(created_at + Duration::from_secs(300)), true, true);
+        assert_eq!(info.hop_count, 4);
+        assert_eq!(info.hops[3], Hop { role: HopRole::Exit, region: Some("eu-central".to_string()) });
+        
+        let (mut names, mut leaves) = (Vec::new(), Vec::new());
+        walk(&serde_json::to_value(&info).unwrap(), &mut names, &mut leaves);
+        let identifying = ["id", "ip", "addr", "address", "key", "node", "public"];
+        for name in &names {
+            assert!(!name.split('_').any(|word| identifying.contains(&word)), "field {}", name);
+        }
+        let ids: Vec<String> = std::iter::once(&circuit.entry_node)
+            .chain(&circuit.routing_nodes)
+            .chain(std::iter::once(&circuit.exit_node))
+            .map(|node| node.0.to_string())
+            .chain(std::iter::once(circuit.id.0.to_string()))
+            .collect();
+        for leaf in &leaves {
+            assert!(leaf.parse::<Uuid>().is_err() && leaf.parse::<IpAddr>().is_err(), "value {}", leaf);
+            assert!(!ids.iter().any(|id| leaf.contains(id.as_str())), "value {}", leaf);
+        }
+    }
+    
+    #[tokio::test]
+    async fn rotating_gives_the_next_info_another_fingerprint() {
+        let router = Arc::new(StubRouter::new(|_| serde_json::json!({ "jsonrpc": "2.0", "result": 1 })));
+        let (entry, users) = fixtures::entry(router.clone(), &EntryConfig::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        assert_eq!(entry.circuit_info(&user.api_key, None).await.unwrap(), None);
+        
+        let request = serde_json::to_vec(&serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" })).unwrap();
+        entry
+            .handle_request(crate::context::RequestContext::new(&user.api_key), &request)
+            .await
+            .unwrap();
+        let before = entry.circuit_info(&user.api_key, None).await.unwrap().unwrap();
+        assert_eq!(before.fingerprint, fingerprint(&router.circuits()[0]));
+        
+        let rotated = entry.rotate_circuit(&user.api_key, None).await.unwrap();
+        let after = entry.circuit_info(&user.api_key, None).await.unwrap().unwrap();
+        assert_ne!(after.fingerprint, before.fingerprint);
+        assert_eq!(after, rotated);
+    }
+}
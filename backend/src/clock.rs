@@ -0,0 +1,464 @@
+//! Clock handling that tolerates skew between nodes
+//!
+//! Wall-clock times are only trusted at the API boundary. Internally, expiry is tracked
+//! as monotonic deadlines, and lifetimes travel between nodes as relative durations that
+//! each hop re-anchors on its own clock.
+//!
+//! Wall-clock times are [`Timestamp`]s: whole UTC milliseconds, whose arithmetic
+//! saturates instead of failing when the clock steps back, and which are written as
+//! RFC 3339 strings, or as integer milliseconds in responses [`write_timestamps`] covers,
+//! rather than the platform-dependent structs `SystemTime` serializes to. Either form is
+//! read back, and so are those structs, as written by earlier releases. The current time
+//! comes from a [`Clock`], so code taking one can be driven by a [`ManualClock`].
+
+use super::*;
+use super::types::*;
+use axum::extract::Extension;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+/// Milliseconds in a day
+const MILLIS_PER_DAY: u64 = 86_400_000;
+
+/// The largest clock offset tolerated between two nodes
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// How old a request may be before it is rejected as a replay
+pub const MAX_REQUEST_AGE: Duration = Duration::from_secs(60);
+
+/// A point in time on this node's monotonic clock
+///
+/// Deadlines are kept on tokio's clock, so tests that pause time drive expiry too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    /// A deadline `ttl` from now
+    pub fn after(ttl: Duration) -> Self {
+        Self(Instant::now() + ttl)
+    }
+    
+    /// Time left until the deadline, zero once it has passed
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+    
+    /// Whether the deadline has passed
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_zero()
+    }
+    
+    /// The deadline as a wall-clock time, `now` being the time on the node's clock
+    pub fn to_timestamp(&self, now: Timestamp) -> Timestamp {
+        now + self.remaining()
+    }
+}
+
+/// A wall-clock time, in whole milliseconds since the Unix epoch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    /// The Unix epoch
+    pub const UNIX_EPOCH: Timestamp = Timestamp(0);
+    
+    /// The current time on the system clock
+    pub fn now() -> Self {
+        SystemClock.now()
+    }
+    
+    /// The time `millis` milliseconds after the epoch
+    pub const fn from_millis(millis: u64) -> Self {
+        Self(millis)
+    }
+    
+    /// The time `secs` seconds after the epoch
+    pub const fn from_secs(secs: u64) -> Self {
+        Self(secs.saturating_mul(1000))
+    }
+    
+    /// Milliseconds since the epoch
+    pub const fn as_millis(self) -> u64 {
+        self.0
+    }
+    
+    /// Whole seconds since the epoch
+    pub const fn as_secs(self) -> u64 {
+        self.0 / 1000
+    }
+    
+    /// Time elapsed from `earlier` to `self`, zero if `earlier` is later
+    pub fn saturating_duration_since(self, earlier: Timestamp) -> Duration {
+        Duration::from_millis(self.0.saturating_sub(earlier.0))
+    }
+    
+    /// Time elapsed from `earlier` to `self`, if `earlier` isn't later
+    pub fn checked_duration_since(self, earlier: Timestamp) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_millis)
+    }
+    
+    /// The time `duration` earlier, if that is after the epoch
+    pub fn checked_sub(self, duration: Duration) -> Option<Timestamp> {
+        self.0.checked_sub(millis(duration)).map(Self)
+    }
+}
+
+/// A duration in whole milliseconds, saturating
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+impl std::ops::Add<Duration> for Timestamp {
+    type Output = Timestamp;
+    
+    fn add(self, duration: Duration) -> Timestamp {
+        Timestamp(self.0.saturating_add(millis(duration)))
+    }
+}
+
+impl std::ops::AddAssign<Duration> for Timestamp {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl std::ops::Sub<Duration> for Timestamp {
+    type Output = Timestamp;
+    
+    /// The time `duration` earlier, the epoch at the earliest
+    fn sub(self, duration: Duration) -> Timestamp {
+        Timestamp(self.0.saturating_sub(millis(duration)))
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    /// The time in whole milliseconds, the epoch for times before it
+    fn from(time: SystemTime) -> Self {
+        Self(time.duration_since(UNIX_EPOCH).map_or(0, millis))
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> Self {
+        UNIX_EPOCH + Duration::from_millis(timestamp.0)
+    }
+}
+
+impl std::fmt::Display for Timestamp {
+    /// RFC 3339 in UTC with milliseconds, such as `2024-05-01T12:00:00.000Z`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (year, month, day) = civil_from_days((self.0 / MILLIS_PER_DAY) as i64);
+        let millis = self.0 % MILLIS_PER_DAY;
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            millis / 3_600_000,
+            millis / 60_000 % 60,
+            millis / 1000 % 60,
+            millis % 1000,
+        )
+    }
+}
+
+/// A timestamp that isn't RFC 3339 or is before the epoch
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid timestamp `{0}`, expected RFC 3339 such as 2024-05-01T12:00:00Z")]
+pub struct InvalidTimestamp(pub String);
+
+impl std::str::FromStr for Timestamp {
+    type Err = InvalidTimestamp;
+    
+    /// Parse RFC 3339 with any offset, keeping milliseconds of the fraction
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        parse_rfc3339(s.trim()).ok_or_else(|| InvalidTimestamp(s.to_string()))
+    }
+}
+
+/// The time `s` names in RFC 3339, if it is one after the epoch
+fn parse_rfc3339(s: &str) -> Option<Timestamp> {
+    let bytes = s.as_bytes();
+    let separators = [(4, b'-'), (7, b'-'), (13, b':'), (16, b':')];
+    if bytes.len() < 20 || separators.iter().any(|(i, c)| bytes[*i] != *c) || !matches!(bytes[10], b'T' | b't' | b' ') {
+        return None;
+    }
+    let number = |from: usize, to: usize| -> Option<i64> {
+        let digits = s.get(from..to)?;
+        digits.bytes().all(|c| c.is_ascii_digit()).then(|| digits.parse().ok())?
+    };
+    let (year, month, day) = (number(0, 4)?, number(5, 7)?, number(8, 10)?);
+    let (hour, minute, second) = (number(11, 13)?, number(14, 16)?, number(17, 19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    if civil_from_days(days) != (year, month, day) {
+        return None;
+    }
+    
+    // Keep the first three digits of a fraction, then read the offset from UTC
+    let mut rest = &s[19..];
+    let mut millis = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return None;
+        }
+        millis = format!("{:0<3}", &fraction[..digits.min(3)]).parse().ok()?;
+        rest = &fraction[digits..];
+    }
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let (hours, minutes) = (number(s.len() - 5, s.len() - 3)?, number(s.len() - 2, s.len())?);
+            sign * (hours * 3600 + minutes * 60)
+        }
+        _ => return None,
+    };
+    
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second.min(59) - offset;
+    let secs = u64::try_from(secs).ok()?;
+    Some(Timestamp(secs * 1000 + millis))
+}
+
+/// Days since the epoch of a proleptic Gregorian date
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The proleptic Gregorian date `days` after the epoch
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// How timestamps are written in API responses and messages between nodes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    /// RFC 3339 strings in UTC, such as `2024-05-01T12:00:00.000Z`
+    #[default]
+    Rfc3339,
+    /// Integer milliseconds since the Unix epoch
+    Millis,
+}
+
+tokio::task_local! {
+    /// The format timestamps are written in by the task serializing them
+    static FORMAT: TimestampFormat;
+}
+
+/// Run `future` writing timestamps in `format`, RFC 3339 being written outside of one
+///
+/// Timestamps are read in any format, so nodes and tasks set differently still understand
+/// each other.
+pub async fn with_timestamp_format<F: std::future::Future>(format: TimestampFormat, future: F) -> F::Output {
+    FORMAT.scope(format, future).await
+}
+
+/// The format timestamps are written in by the current task
+pub fn timestamp_format() -> TimestampFormat {
+    FORMAT.try_with(|format| *format).unwrap_or_default()
+}
+
+/// Middleware writing the timestamps of API responses in the node's format
+///
+/// Install with `axum::middleware::from_fn(write_timestamps)`, with the node's
+/// [`TimestampFormat`] as an extension.
+pub async fn write_timestamps<B>(
+    Extension(format): Extension<TimestampFormat>,
+    request: axum::http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    with_timestamp_format(format, next.run(request)).await
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match timestamp_format() {
+            TimestampFormat::Rfc3339 => serializer.collect_str(self),
+            TimestampFormat::Millis => serializer.serialize_u64(self.0),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+/// Reads a timestamp in any format written now or by earlier releases
+struct TimestampVisitor;
+
+impl<'de> serde::de::Visitor<'de> for TimestampVisitor {
+    type Value = Timestamp;
+    
+    fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("an RFC 3339 timestamp, milliseconds since the Unix epoch, or a serialized SystemTime")
+    }
+    
+    fn visit_u64<E: serde::de::Error>(self, millis: u64) -> std::result::Result<Timestamp, E> {
+        Ok(Timestamp(millis))
+    }
+    
+    fn visit_i64<E: serde::de::Error>(self, millis: i64) -> std::result::Result<Timestamp, E> {
+        u64::try_from(millis)
+            .map(Timestamp)
+            .map_err(|_| E::custom(format!("timestamp {} is before the Unix epoch", millis)))
+    }
+    
+    fn visit_str<E: serde::de::Error>(self, s: &str) -> std::result::Result<Timestamp, E> {
+        s.parse().map_err(E::custom)
+    }
+    
+    /// `SystemTime` as serde wrote it: `{"secs_since_epoch": .., "nanos_since_epoch": ..}`
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> std::result::Result<Timestamp, A::Error> {
+        let (mut secs, mut nanos) = (None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "secs_since_epoch" => secs = Some(map.next_value::<u64>()?),
+                "nanos_since_epoch" => nanos = Some(map.next_value::<u32>()?),
+                _ => {
+                    map.next_value::<serde::de::IgnoredAny>()?;
+                }
+            }
+        }
+        let secs = secs.ok_or_else(|| serde::de::Error::missing_field("secs_since_epoch"))?;
+        let nanos = nanos.ok_or_else(|| serde::de::Error::missing_field("nanos_since_epoch"))?;
+        Ok(Timestamp::from_secs(secs) + Duration::from_nanos(nanos as u64))
+    }
+}
+
+/// A source of the current wall-clock time
+///
+/// Code that decides by the time takes a clock, so tests can control what time it is.
+pub trait Clock: Send + Sync {
+    /// The current time
+    fn now(&self) -> Timestamp;
+}
+
+/// The system's clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        SystemTime::now().into()
+    }
+}
+
+/// A clock that only moves when told to
+#[derive(Debug, Default)]
+pub struct ManualClock(AtomicU64);
+
+impl ManualClock {
+    /// A clock stopped at `now`
+    pub fn new(now: Timestamp) -> Self {
+        Self(AtomicU64::new(now.0))
+    }
+    
+    /// Set the clock to `now`, even if that is earlier
+    pub fn set(&self, now: Timestamp) {
+        self.0.store(now.0, Ordering::SeqCst);
+    }
+    
+    /// Move the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        self.0.fetch_add(millis(duration), Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Timestamp {
+        Timestamp(self.0.load(Ordering::SeqCst))
+    }
+}
+
+/// Whether `timestamp` falls within `max_age` of `now`, allowing for clock skew either way
+///
+/// Never fails on clock regression: timestamps from the future are compared the same way.
+pub fn is_fresh(timestamp: Timestamp, max_age: Duration, now: Timestamp) -> bool {
+    match now.checked_duration_since(timestamp) {
+        Some(age) => age <= max_age + MAX_CLOCK_SKEW,
+        None => timestamp.saturating_duration_since(now) <= MAX_CLOCK_SKEW,
+    }
+}
+
+/// Admit a request arriving at this hop, returning its deadline on the local clock
+///
+/// The request's remaining lifetime should be passed on to the next hop as
+/// `deadline.remaining()`.
+pub fn admit(request: &Request, now: Timestamp) -> Result<Deadline> {
+    if !is_fresh(request.created_at, MAX_REQUEST_AGE, now) {
+        anyhow::bail!("Request {} is outside the replay window", request.id);
+    }
+    if request.ttl.is_zero() {
+        anyhow::bail!("Circuit for request {} has expired", request.id);
+    }
+    Ok(Deadline::after(request.ttl))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn admits_requests_from_skewed_and_older_peers() {
+        let now = Timestamp::now();
+        let request = Request {
+            id: Uuid::new_v4(),
+            circuit_id: CircuitId(Uuid::new_v4()),
+            payload: EncryptedData {
+                data: vec![],
+                nonce: vec![],
+                aad: None,
+            },
+            created_at: now + Duration::from_secs(4 * 60),
+            ttl: Duration::from_secs(30),
+            key_step: 0,
+        };
+        assert!(admit(&request, now).is_ok());
+        assert!(admit(&request, now + MAX_REQUEST_AGE + MAX_CLOCK_SKEW + Duration::from_secs(5 * 60)).is_err());
+        
+        // A peer that predates the ttl field gets the replay window
+        let mut legacy = serde_json::to_value(&request).unwrap();
+        legacy.as_object_mut().unwrap().remove("ttl");
+        let legacy: Request = serde_json::from_value(legacy).unwrap();
+        assert!(admit(&legacy, now).unwrap().remaining() > Duration::from_secs(30));
+    }
+    
+    #[tokio::test]
+    async fn timestamps_are_written_in_the_format_of_the_task_writing_them() {
+        let timestamp = Timestamp::from_millis(1_714_564_800_123);
+        let as_millis = with_timestamp_format(TimestampFormat::Millis, async { serde_json::to_string(&timestamp).unwrap() });
+        let (as_millis, as_rfc3339) = tokio::join!(as_millis, async { serde_json::to_string(&timestamp).unwrap() });
+        assert_eq!(as_millis, "1714564800123");
+        assert_eq!(as_rfc3339, "\"2024-05-01T12:00:00.123Z\"");
+        assert_eq!(serde_json::from_str::<Timestamp>(&as_millis).unwrap(), timestamp);
+        assert_eq!(serde_json::from_str::<Timestamp>(&as_rfc3339).unwrap(), timestamp);
+    }
+}
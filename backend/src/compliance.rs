@@ -0,0 +1,476 @@
+//! Usage auditing for deployments that must keep records of their own users' requests
+//!
+//! Some operators, such as an enterprise running DarkNode for its employees, are required
+//! to retain metadata of the RPC usage they serve. With auditing enabled in the entry
+//! configuration, a user who consents, which only they can do with their own API key, has
+//! a [`UsageRecord`] written for each request they send: which method, when, how it was
+//! answered, and how many bytes went each way. Payloads are never recorded, and a user who
+//! hasn't consented has nothing written at all.
+//!
+//! Records go to an [`AuditSink`] of their own rather than the operational logs, so they
+//! can be kept, secured, and deleted on their own terms, and are dropped once older than
+//! the configured retention. Sinks must outlive the node, so the database sink is refused
+//! on storage kept in memory. Users can export their own records and no one else's. Requests turned away before they are sent, such as over a
+//! quota, aren't recorded. Requests served without a circuit, see [`crate::fallback`], are
+//! marked as degraded.
+
+use super::*;
+use super::storage::{Collection, Precondition, Storage};
+use super::types::User;
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+/// How often records past their retention are dropped
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Whether usage is audited, and where records go
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ComplianceConfig {
+    /// Whether users may consent to auditing and have their usage recorded
+    pub enabled: bool,
+    /// Where records are written
+    pub sink: AuditSinkConfig,
+    /// Records older than this are dropped
+    pub retention: Duration,
+}
+
+impl Default for ComplianceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sink: AuditSinkConfig::default(),
+            retention: Duration::from_secs(365 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// Where usage records are written
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AuditSinkConfig {
+    /// Appended to a file as one JSON record per line
+    File {
+        /// The file, created if it doesn't exist
+        path: PathBuf,
+    },
+    /// Kept in the node's storage backend, which must not be `memory`, see [`crate::storage`]
+    Database,
+}
+
+impl Default for AuditSinkConfig {
+    fn default() -> Self {
+        AuditSinkConfig::Database
+    }
+}
+
+/// How an audited request was answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditStatus {
+    /// With a result
+    Ok,
+    /// With a JSON-RPC error from the provider
+    Error,
+    /// Not at all, as the circuit or exit node failed it
+    Failed,
+}
+
+/// Metadata of one request a consenting user sent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageRecord {
+    /// Unique identifier of the record
+    pub id: Uuid,
+    /// The user who sent the request
+    pub user_id: Uuid,
+    /// The method's label, as in traffic metrics
+    pub method: String,
+    /// When the request completed
+    pub recorded_at: Timestamp,
+    /// How the request was answered
+    pub status: AuditStatus,
+    /// Size of the request as sent to the entry node
+    pub request_bytes: u64,
+    /// Size of the response as returned to the user
+    pub response_bytes: u64,
+    /// Whether the request was served straight from a fallback provider, without a circuit
+    #[serde(default)]
+    pub degraded: bool,
+}
+
+/// Where usage records are kept, apart from operational logs
+#[async_trait]
+pub trait AuditSink {
+    /// Keep a record
+    async fn write(&self, record: UsageRecord) -> Result<()>;
+    
+    /// Every record kept of the user `user_id`, oldest first
+    async fn records_for(&self, user_id: Uuid) -> Result<Vec<UsageRecord>>;
+    
+    /// Drop the records made before `before`, returning how many
+    async fn prune(&self, before: Timestamp) -> Result<usize>;
+}
+
+/// A change to the audit file, made by the sink's writer task
+enum FileWrite {
+    /// Append a record
+    Append(UsageRecord),
+    /// Rewrite the file with only the records still kept
+    Compact(Vec<UsageRecord>),
+}
+
+/// Records appended to a newline-delimited JSON file
+///
+/// The records are also held in memory, so exports never read the file. File access is
+/// blocking, so a task of the sink's own makes every write on the blocking pool.
+pub struct FileAuditSink {
+    records: parking_lot::Mutex<VecDeque<UsageRecord>>,
+    writer: mpsc::UnboundedSender<FileWrite>,
+}
+
+impl FileAuditSink {
+    /// Append to the file at `path`, reading the records already in it or creating it if needed
+    ///
+    /// Must be opened on a Tokio runtime.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut records = VecDeque::new();
+        match std::fs::File::open(path) {
+            Ok(file) => {
+                for line in std::io::BufReader::new(file).lines() {
+                    records.push_back(serde_json::from_str(&line?)?);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => anyhow::bail!("Failed to read audit file {}: {}", path.display(), e),
+        }
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open audit file {}: {}", path.display(), e))?;
+        let (writer, writes) = mpsc::unbounded_channel();
+        tokio::spawn(write_file(path.to_path_buf(), writes));
+        Ok(Self {
+            records: parking_lot::Mutex::new(records),
+            writer,
+        })
+    }
+}
+
+#[async_trait]
+impl AuditSink for FileAuditSink {
+    async fn write(&self, record: UsageRecord) -> Result<()> {
+        // Queued under the lock, so a compaction never misses the record or follows its append
+        let mut records = self.records.lock();
+        records.push_back(record.clone());
+        self.writer
+            .send(FileWrite::Append(record))
+            .map_err(|_| anyhow::anyhow!("Audit file writer stopped"))
+    }
+    
+    async fn records_for(&self, user_id: Uuid) -> Result<Vec<UsageRecord>> {
+        Ok(self
+            .records
+            .lock()
+            .iter()
+            .filter(|record| record.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+    
+    async fn prune(&self, before: Timestamp) -> Result<usize> {
+        let mut records = self.records.lock();
+        let kept = records.len();
+        records.retain(|record| record.recorded_at >= before);
+        let pruned = kept - records.len();
+        if pruned > 0 {
+            self.writer
+                .send(FileWrite::Compact(records.iter().cloned().collect()))
+                .map_err(|_| anyhow::anyhow!("Audit file writer stopped"))?;
+        }
+        Ok(pruned)
+    }
+}
+
+/// Apply the sink's writes to the audit file at `path`, in order, until the sink is dropped
+async fn write_file(path: PathBuf, mut writes: mpsc::UnboundedReceiver<FileWrite>) {
+    while let Some(write) = writes.recv().await {
+        let file = path.clone();
+        let written = tokio::task::spawn_blocking(move || -> Result<()> {
+            match write {
+                FileWrite::Append(record) => {
+                    let mut line = serde_json::to_vec(&record)?;
+                    line.push(b'\n');
+                    let mut file = std::fs::OpenOptions::new().append(true).open(&file)?;
+                    file.write_all(&line)?;
+                    file.sync_data()?;
+                }
+                FileWrite::Compact(records) => {
+                    let partial = file.with_extension("tmp");
+                    let mut lines = Vec::new();
+                    for record in &records {
+                        serde_json::to_writer(&mut lines, record)?;
+                        lines.push(b'\n');
+                    }
+                    std::fs::write(&partial, lines)?;
+                    std::fs::rename(&partial, &file)?;
+                }
+            }
+            Ok(())
+        })
+        .await;
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                metrics::increment_counter!("darknode_audit_write_failures_total");
+                tracing::error!("Failed to write audit file {}: {}", path.display(), e);
+            }
+            Err(e) => tracing::error!("Audit file writer failed: {}", e),
+        }
+    }
+}
+
+/// Collection of usage records, keyed by user, completion time, and record ID
+const USAGE_RECORDS: &str = "usage_records";
+
+/// Records kept in the node's storage backend
+///
+/// Keys start with the user's ID, so a user's records are found with one scan and come
+/// back in the order they were written.
+pub struct DatabaseAuditSink {
+    records: Collection<UsageRecord>,
+}
+
+impl DatabaseAuditSink {
+    /// Keep records in `storage`
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self {
+            records: Collection::new(storage, USAGE_RECORDS),
+        }
+    }
+}
+
+#[async_trait]
+impl AuditSink for DatabaseAuditSink {
+    async fn write(&self, record: UsageRecord) -> Result<()> {
+        let key = format!("{}/{:020}/{}", record.user_id, record.recorded_at.as_millis(), record.id);
+        self.records.put(&key, &record, Precondition::Absent).await?;
+        Ok(())
+    }
+    
+    async fn records_for(&self, user_id: Uuid) -> Result<Vec<UsageRecord>> {
+        let records = self.records.scan(&format!("{}/", user_id)).await?;
+        Ok(records.into_iter().map(|(_, record)| record).collect())
+    }
+    
+    async fn prune(&self, before: Timestamp) -> Result<usize> {
+        let mut pruned = 0;
+        for (key, record) in self.records.scan("").await? {
+            if record.recorded_at < before && self.records.delete(&key, Precondition::Any).await? {
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+}
+
+/// Auditing turned off in this deployment
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("usage auditing is not enabled on this network")]
+pub struct AuditingDisabled;
+
+/// Writes usage records for consenting users to a sink
+pub struct UsageAudit {
+    sink: Arc<dyn AuditSink + Send + Sync>,
+    retention: Duration,
+}
+
+impl UsageAudit {
+    /// Audit into `sink`, keeping records for `retention`
+    pub fn new(sink: Arc<dyn AuditSink + Send + Sync>, retention: Duration) -> Self {
+        Self { sink, retention }
+    }
+    
+    /// Open the sink `config` names, or nothing if auditing is disabled
+    pub fn open(config: &ComplianceConfig, storage: Arc<dyn Storage + Send + Sync>) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let sink: Arc<dyn AuditSink + Send + Sync> = match &config.sink {
+            AuditSinkConfig::File { path } => Arc::new(FileAuditSink::open(path)?),
+            AuditSinkConfig::Database => Arc::new(DatabaseAuditSink::new(storage)),
+        };
+        Ok(Some(Self::new(sink, config.retention)))
+    }
+    
+    /// Record a request `user` sent, if they consented to auditing
+    ///
+    /// The record is written in the background so the request isn't held up; a write that
+    /// fails is counted and logged without saying whose it was.
+    pub fn record(
+        &self,
+        user: &User,
+        method: &str,
+        status: AuditStatus,
+        request_bytes: usize,
+        response_bytes: usize,
+        degraded: bool,
+    ) {
+        if !user.audit_consent {
+            return;
+        }
+        let record = UsageRecord {
+            id: Uuid::new_v4(),
+            user_id: user.id,
+            method: method.to_string(),
+            recorded_at: Timestamp::now(),
+            status,
+            request_bytes: request_bytes as u64,
+            response_bytes: response_bytes as u64,
+            degraded,
+        };
+        let sink = self.sink.clone();
+        tokio::spawn(async move {
+            if let Err(e) = sink.write(record).await {
+                metrics::increment_counter!("darknode_audit_write_failures_total");
+                tracing::warn!("Failed to write a usage record: {}", e);
+            }
+        });
+    }
+    
+    /// Every record kept of the user `user_id`, oldest first
+    pub async fn export(&self, user_id: Uuid) -> Result<Vec<UsageRecord>> {
+        self.sink.records_for(user_id).await
+    }
+    
+    /// Drop the records older than the retention period at `now`
+    pub async fn prune(&self, now: Timestamp) -> Result<usize> {
+        let pruned = self.sink.prune(now - self.retention).await?;
+        metrics::counter!("darknode_audit_records_pruned_total", pruned as u64);
+        Ok(pruned)
+    }
+    
+    /// Drop records past their retention every [`PRUNE_INTERVAL`], for as long as the node runs
+    pub async fn run_pruning(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.prune(Timestamp::now()).await {
+                tracing::warn!("Failed to drop expired usage records: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallets::WalletChain;
+    
+    /// A sink remembering every write it was asked for
+    #[derive(Default)]
+    struct RecordingSink {
+        written: parking_lot::Mutex<Vec<UsageRecord>>,
+    }
+    
+    #[async_trait]
+    impl AuditSink for RecordingSink {
+        async fn write(&self, record: UsageRecord) -> Result<()> {
+            self.written.lock().push(record);
+            Ok(())
+        }
+        
+        async fn records_for(&self, user_id: Uuid) -> Result<Vec<UsageRecord>> {
+            Ok(self.written.lock().iter().filter(|record| record.user_id == user_id).cloned().collect())
+        }
+        
+        async fn prune(&self, _before: Timestamp) -> Result<usize> {
+            Ok(0)
+        }
+    }
+    
+    fn user(audit_consent: bool) -> User {
+        User {
+            id: Uuid::new_v4(),
+            wallet_address: "4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T".to_string(),
+            wallet_chain: WalletChain::Solana,
+            api_key: format!("api-{}", Uuid::new_v4()),
+            keys: Vec::new(),
+            active: true,
+            expires_at: None,
+            rpc_mappings: Vec::new(),
+            plan_id: None,
+            canary: false,
+            audit_consent,
+        }
+    }
+    
+    fn record(user_id: Uuid, recorded_at: Timestamp) -> UsageRecord {
+        UsageRecord {
+            id: Uuid::new_v4(),
+            user_id,
+            method: "getBalance".to_string(),
+            recorded_at,
+            status: AuditStatus::Ok,
+            request_bytes: 64,
+            response_bytes: 128,
+            degraded: false,
+        }
+    }
+    
+    /// Wait for `done` to hold, for up to a second
+    async fn eventually(mut done: impl FnMut() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition never held");
+    }
+    
+    #[tokio::test]
+    async fn users_who_did_not_consent_have_nothing_written() {
+        let sink = Arc::new(RecordingSink::default());
+        let audit = UsageAudit::new(sink.clone(), Duration::from_secs(3600));
+        let (consenting, declining) = (user(true), user(false));
+        
+        for round in 0..20 {
+            let user = if round % 2 == 0 { &consenting } else { &declining };
+            audit.record(user, "getBalance", AuditStatus::Ok, 64, 128, false);
+        }
+        eventually(|| sink.written.lock().len() == 10).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        
+        let written = sink.written.lock().clone();
+        assert_eq!(written.len(), 10);
+        assert!(written.iter().all(|record| record.user_id == consenting.id));
+        assert_eq!(audit.export(consenting.id).await.unwrap().len(), 10);
+        assert!(audit.export(declining.id).await.unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn the_file_sink_keeps_records_across_restarts_until_they_expire() {
+        let path = std::env::temp_dir().join(format!("darknode-usage-{}.jsonl", Uuid::new_v4()));
+        let (user_id, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let old = record(user_id, Timestamp::from_secs(1_000));
+        let recent = record(user_id, Timestamp::from_secs(2_000));
+        
+        let sink = FileAuditSink::open(&path).unwrap();
+        for written in [old.clone(), record(other, Timestamp::from_secs(1_500)), recent.clone()] {
+            sink.write(written).await.unwrap();
+        }
+        assert_eq!(sink.records_for(user_id).await.unwrap(), vec![old, recent.clone()]);
+        let lines = || std::fs::read_to_string(&path).map_or(0, |contents| contents.lines().count());
+        eventually(|| lines() == 3).await;
+        
+        let reopened = FileAuditSink::open(&path).unwrap();
+        assert_eq!(reopened.records_for(user_id).await.unwrap().len(), 2);
+        assert_eq!(reopened.prune(Timestamp::from_secs(1_800)).await.unwrap(), 2);
+        assert_eq!(reopened.records_for(user_id).await.unwrap(), vec![recent]);
+        eventually(|| lines() == 1).await;
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,21 @@
+//! Compression of responses on the entry node's client-facing edge
+//!
+//! Only the edge compresses. Circuit messages between hops are never compressed, since
+//! their sizes on the wire would then depend on what they carry. Event streams, responses
+//! below the configured size and responses that already carry a `Content-Encoding` are
+//! sent as they are, and nothing is compressed while [`flags::COMPRESSION`] is off.
+
+use super::flags::{self, FeatureFlags};
+use axum::http::{Extensions, HeaderMap, StatusCode, Version};
+use tower_http::compression::predicate::{DefaultPredicate, NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+
+/// The layer compressing responses of `min_size` bytes or more for clients that accept it
+pub fn client_compression(min_size: u16, flags: FeatureFlags) -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new().compress_when(
+        DefaultPredicate::new()
+            .and(NotForContentType::const_new("text/event-stream"))
+            .and(SizeAbove::new(min_size))
+            .and(move |_: StatusCode, _: Version, _: &HeaderMap, _: &Extensions| flags.enabled(flags::COMPRESSION)),
+    )
+}
@@ -0,0 +1,797 @@
+//! Typed configuration shared by every binary, read from one TOML file
+//!
+//! A deployment describes all its nodes in one file, and each binary reads the sections it
+//! needs: `[common]` for settings every node shares, and `[entry]`, `[routing]`, `[exit]`,
+//! or `[coordinator]` for its role. The combined `darknode-node` binary reads `[node]` for
+//! its roles and listener, and `[routing]` and `[exit]` for the roles themselves.
+//!
+//! Defaulting rules: every section, and every setting within one, may be left out and then
+//! takes the value of its type's `Default`, the same as a binary started without a config
+//! file. Settings within a list or map entry, such as a seeded provider or a cache policy,
+//! are required unless documented otherwise on their type. A `[coordinator.canary]` section
+//! turns the canary on and must set `api_key`. Durations are written as a number and a unit
+//! of `ms`, `s`, `m`, `h` or `d`, such as `tick = "50ms"`, or as tables of whole seconds and
+//! nanoseconds, such as `tick = { secs = 0, nanos = 50000000 }`, and printed the first way.
+//!
+//! Parsing is strict: a key no section knows is refused rather than ignored, so a typo
+//! can't silently leave a setting at its default. Errors name the offending key by its path
+//! in the file, such as `entry.idempotency`.
+//!
+//! Every binary takes `--config <file>` to read a config file and `--check-config` to only
+//! validate it and print the settings it would run with, secrets redacted. Values of keys
+//! naming a secret are replaced, and so are the credentials, paths and queries of URLs,
+//! where providers put API keys.
+
+use super::*;
+use super::accounting::AccountingConfig;
+use super::admission::AdmissionConfig;
+use super::audit::AuditConfig;
+use super::bandwidth::BandwidthConfig;
+use super::billing::BillingConfig;
+use super::bootstrap::BootstrapConfig;
+use super::breaker::BreakerConfig;
+use super::circuit_class::CircuitClassConfig;
+use super::budget::BudgetConfig;
+use super::cache::CacheConfig;
+use super::cache_hints::CacheHintConfig;
+use super::clock::TimestampFormat;
+use super::compliance::{AuditSinkConfig, ComplianceConfig};
+#[cfg(feature = "canary")]
+use super::canary::CanaryConfig;
+use super::directory::DirectoryConfig;
+use super::directory_watch::WatchConfig;
+use super::dns::ResolverConfig;
+use super::drain::DrainConfig;
+use super::egress::EgressConfig;
+use super::emulation::EmulationConfig;
+use super::epochs::EpochConfig;
+use super::fairness::FairnessConfig;
+use super::fallback::FallbackConfig;
+use super::flags::FlagsConfig;
+use super::hedge::HedgeConfig;
+use super::hop_auth::HopAuthConfig;
+use super::idempotency::IdempotencyConfig;
+use super::journal::JournalConfig;
+use super::keepalive::KeepaliveConfig;
+use super::managers::dashboard::DashboardConfig;
+use super::managers::probe::ProbeConfig;
+use super::managers::quota::CircuitCapacityConfig;
+use super::membership::MembershipConfig;
+use super::multiplex::MultiplexConfig;
+use super::operator::OperatorConfig;
+use super::outbox::OutboxConfig;
+use super::pipelining::PipelineConfig;
+use super::pools::PoolConfig;
+use super::privacy::NoiseConfig;
+use super::provisioning::ProvisioningConfig;
+use super::reachability::ReachabilityConfig;
+use super::reclaim::ReclaimConfig;
+use super::recommend::{AdviceConfig, RecommendConfig};
+use super::regions::LatencyConfig;
+use super::relaxation::RelaxationConfig;
+use super::relay::RelayConfig;
+use super::replay::ReplayConfig;
+use super::report_auth::ReportAuthConfig;
+use super::resources::ResourceConfig;
+use super::sanitizer::SanitizerConfig;
+use super::scatter::ScatterConfig;
+use super::schema::ValidationConfig;
+use super::sessions::SessionConfig;
+use super::shadow::ShadowConfig;
+use super::shaping::ShapingConfig;
+use super::signing::SigningConfig;
+use super::storage::StorageConfig;
+use super::submissions::SubmissionConfig;
+use super::telemetry::TelemetryConfig;
+use super::timeouts::TimeoutConfig;
+use super::types::NodeRole;
+use super::upstream::UpstreamLimits;
+use super::warmup::WarmupConfig;
+use super::webhooks::WebhookConfig;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// Flag naming the config file to read
+pub const CONFIG_FLAG: &str = "--config";
+
+/// Flag asking a binary to validate and print its config instead of starting
+pub const CHECK_CONFIG_FLAG: &str = "--check-config";
+
+/// Flag asking the entry node to log full request and response bodies, see `dev_logging`
+pub const DEV_VERBOSE_LOGGING_FLAG: &str = "--dev-verbose-logging";
+
+/// Words naming the keys whose values are replaced when a config is printed
+const SECRET_KEYS: &[&str] = &["api_key", "auth", "password", "secret", "token"];
+
+/// Units durations may be written in, with their length
+const DURATION_UNITS: &[(&str, Duration)] = &[
+    ("d", Duration::from_secs(86_400)),
+    ("h", Duration::from_secs(3_600)),
+    ("m", Duration::from_secs(60)),
+    ("s", Duration::from_secs(1)),
+    ("ms", Duration::from_millis(1)),
+];
+
+/// What secret values are printed or served as
+pub const REDACTED: &str = "<redacted>";
+
+/// Errors from config files
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ConfigError {
+    /// The file isn't TOML or doesn't fit the schema; the message names the key
+    #[error("{0}")]
+    Parse(String),
+    /// A required setting is left out
+    #[error("missing field `{field}` for key `{key}`")]
+    Missing {
+        /// Path of the table the setting belongs in
+        key: String,
+        /// The setting
+        field: String,
+    },
+    /// A setting has a value that can't be used
+    #[error("invalid value for key `{key}`: {reason}")]
+    Invalid {
+        /// Path of the setting
+        key: String,
+        /// What is wrong with it
+        reason: String,
+    },
+}
+
+/// Every binary's settings, one section per role
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DarknodeConfig {
+    /// Settings every node shares
+    pub common: CommonConfig,
+    /// Settings of entry nodes
+    pub entry: EntryConfig,
+    /// Settings of routing nodes, and of the routing role of combined nodes
+    pub routing: RoutingConfig,
+    /// Settings of exit nodes, and of the exit role of combined nodes
+    pub exit: ExitConfig,
+    /// Roles and listener of combined nodes
+    pub node: NodeConfig,
+    /// Settings of the coordinator
+    pub coordinator: CoordinatorConfig,
+}
+
+/// Settings every node shares, which should agree across a deployment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommonConfig {
+    /// The region this node is in
+    pub region: String,
+    /// The node's ID, a fresh one on every start if unset
+    pub node_id: Option<Uuid>,
+    /// Where the node's identity keys are kept, fresh keys on every start if unset, see
+    /// [`crate::identity::NodeIdentity::load_or_generate`]
+    pub identity_file: Option<std::path::PathBuf>,
+    /// Who may call the node's administrative routes, see [`crate::operator`]
+    pub operator: OperatorConfig,
+    /// The coordinator node to register with
+    pub coordinator_url: String,
+    /// How often to send heartbeats to the coordinator
+    pub heartbeat_interval: Duration,
+    /// How many exits each user may reach per epoch, and how long epochs are
+    pub epochs: EpochConfig,
+    /// How the work nodes carry is counted and reconciled
+    pub accounting: AccountingConfig,
+    /// Whether traffic is held back to decorrelate requests from responses
+    pub shaping: ShapingConfig,
+    /// Queueing of reports while the coordinator is unreachable
+    pub outbox: OutboxConfig,
+    /// How timestamps are written in the responses this node serves
+    pub timestamps: TimestampFormat,
+    /// How latency between regions is measured and shapes circuits
+    pub latency: LatencyConfig,
+    /// Where node, user and provider records are kept
+    pub storage: StorageConfig,
+    /// When directories are published ahead of their epoch, and which nodes trust
+    pub directory: DirectoryConfig,
+    /// How changes to the directory are pushed to nodes, see [`crate::directory_watch`]
+    pub directory_watch: WatchConfig,
+    /// Whether usage is metered and invoiced, and how invoices are paid, see [`crate::billing`]
+    pub billing: BillingConfig,
+    /// How nodes probe whether they reach each other, see [`crate::reachability`]
+    pub reachability: ReachabilityConfig,
+    /// How routing and exit nodes authenticate the hop a message comes from, see [`crate::hop_auth`]
+    pub hop_auth: HopAuthConfig,
+    /// When routing and exit nodes shed load to keep memory and file descriptors, see [`crate::resources`]
+    pub resources: ResourceConfig,
+    /// Where tracing spans are exported, and whether traces cross hops, see [`crate::telemetry`]
+    pub telemetry: TelemetryConfig,
+    /// When routing and exit nodes reclaim circuits abandoned by their entry node, see [`crate::reclaim`]
+    pub reclaim: ReclaimConfig,
+}
+
+impl Default for CommonConfig {
+    fn default() -> Self {
+        Self {
+            region: "us-east".to_string(),
+            node_id: None,
+            identity_file: None,
+            operator: OperatorConfig::default(),
+            coordinator_url: "http://localhost:3001".to_string(),
+            heartbeat_interval: Duration::from_secs(30),
+            epochs: EpochConfig::default(),
+            accounting: AccountingConfig::default(),
+            shaping: ShapingConfig::default(),
+            outbox: OutboxConfig::default(),
+            timestamps: TimestampFormat::default(),
+            latency: LatencyConfig::default(),
+            storage: StorageConfig::default(),
+            directory: DirectoryConfig::default(),
+            directory_watch: WatchConfig::default(),
+            billing: BillingConfig::default(),
+            reachability: ReachabilityConfig::default(),
+            hop_auth: HopAuthConfig::default(),
+            resources: ResourceConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            reclaim: ReclaimConfig::default(),
+        }
+    }
+}
+
+/// Settings of entry nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EntryConfig {
+    /// The address to listen on
+    pub listen_addr: SocketAddr,
+    /// How long WebSocket sessions survive a disconnect
+    pub sessions: SessionConfig,
+    /// How many requests WebSocket clients may pipeline, see [`crate::pipelining`]
+    pub pipelining: PipelineConfig,
+    /// Smallest response body, in bytes, worth compressing for clients
+    pub compression_min_size: u16,
+    /// Which chain's param schemas requests are checked against
+    pub validation: ValidationConfig,
+    /// How idle circuits are pinged to detect dead hops
+    pub keepalive: KeepaliveConfig,
+    /// When requests are shed because the network behind the node is overloaded
+    pub admission: AdmissionConfig,
+    /// How many circuits the node holds for all users together
+    pub circuits: CircuitCapacityConfig,
+    /// Whether `getHealth` and `getVersion` are answered without going through a circuit
+    pub emulation: EmulationConfig,
+    /// End-to-end time budgets of requests by method class
+    pub timeouts: TimeoutConfig,
+    /// How requests to mappings requiring wallet signatures are checked
+    pub signing: SigningConfig,
+    /// Where the key client receipts are signed with is kept, see [`crate::receipts`]
+    ///
+    /// The key is never rotated, so receipts verify for as long as the node publishes it.
+    /// Without a file a key is generated that lasts as long as the process.
+    pub receipt_key_file: Option<std::path::PathBuf>,
+    /// How long responses are replayed to duplicate deliveries of requests with idempotency keys
+    pub idempotency: IdempotencyConfig,
+    /// How requests share the network fairly between users once it is busy
+    pub fairness: FairnessConfig,
+    /// When reads are sent again on a rebuilt circuit after a hop failed under them
+    pub replay: ReplayConfig,
+    /// Which reads are mirrored onto shadow circuits to try a new path
+    pub shadow: ShadowConfig,
+    /// How circuits are moved off nodes going into maintenance
+    pub drain: DrainConfig,
+    /// Whether consenting users' usage is recorded, and where
+    pub compliance: ComplianceConfig,
+    /// Providers served from directly while no circuit can be built, see [`crate::fallback`]
+    pub fallback: FallbackConfig,
+    /// The circuit policy, and how far it is relaxed while circuits fail to build, see [`crate::relaxation`]
+    pub relaxation: RelaxationConfig,
+    /// How long request ids replaced on the way out are remembered, see [`crate::sanitizer`]
+    pub sanitizer: SanitizerConfig,
+    /// How circuits of each class are rotated and kept alive, see [`crate::circuit_class`]
+    pub circuit_classes: CircuitClassConfig,
+    /// How far the addresses a user queries are spread over circuits, see [`crate::scatter`]
+    pub scatter: ScatterConfig,
+    /// How long clients are told to cache responses, see [`crate::cache_hints`]
+    pub cache_hints: CacheHintConfig,
+    /// Where mutating calls are journaled so their outcome outlives a crash, see [`crate::journal`]
+    pub journal: JournalConfig,
+    /// Whether circuits are drawn from the coordinator's recommended paths, see [`crate::recommend`]
+    pub advice: AdviceConfig,
+}
+
+impl Default for EntryConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            sessions: SessionConfig::default(),
+            pipelining: PipelineConfig::default(),
+            compression_min_size: 1024,
+            validation: ValidationConfig::default(),
+            keepalive: KeepaliveConfig::default(),
+            admission: AdmissionConfig::default(),
+            circuits: CircuitCapacityConfig::default(),
+            emulation: EmulationConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            signing: SigningConfig::default(),
+            receipt_key_file: None,
+            idempotency: IdempotencyConfig::default(),
+            fairness: FairnessConfig::default(),
+            replay: ReplayConfig::default(),
+            shadow: ShadowConfig::default(),
+            drain: DrainConfig::default(),
+            compliance: ComplianceConfig::default(),
+            fallback: FallbackConfig::default(),
+            relaxation: RelaxationConfig::default(),
+            sanitizer: SanitizerConfig::default(),
+            circuit_classes: CircuitClassConfig::default(),
+            scatter: ScatterConfig::default(),
+            cache_hints: CacheHintConfig::default(),
+            journal: JournalConfig::default(),
+            advice: AdviceConfig::default(),
+        }
+    }
+}
+
+/// Settings of routing nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    /// The address to listen on, unless run as a role of a combined node
+    pub listen_addr: SocketAddr,
+    /// How much the node forwards and how it is shared between circuits
+    pub bandwidth: BandwidthConfig,
+}
+
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 3003)),
+            bandwidth: BandwidthConfig::default(),
+        }
+    }
+}
+
+/// Settings of exit nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExitConfig {
+    /// The address to listen on, unless run as a role of a combined node
+    pub listen_addr: SocketAddr,
+    /// How provider hostnames are resolved
+    pub resolver: ResolverConfig,
+    /// How long provider response digests are kept
+    pub audit: AuditConfig,
+    /// When slow reads are hedged to a second provider
+    pub hedge: HedgeConfig,
+    /// The provider pool the node serves from
+    pub pool: PoolConfig,
+    /// How transactions clients ask to have relayed are rebroadcast
+    pub relay: RelayConfig,
+    /// Limits on circuits served and peers sending forged requests
+    pub membership: MembershipConfig,
+    /// When connections to providers are opened and refreshed
+    pub warmup: WarmupConfig,
+    /// What the node accepts in provider responses
+    pub upstream: UpstreamLimits,
+    /// Which provider responses are cached, and for how long
+    pub cache: CacheConfig,
+    /// How requests to providers share connections
+    pub multiplex: MultiplexConfig,
+    /// When providers that keep failing stop being sent requests
+    pub breaker: BreakerConfig,
+    /// The requests the node may send its providers, see [`crate::budget`]
+    pub budget: BudgetConfig,
+    /// The method classes the node serves, see [`crate::egress`]
+    pub egress: EgressConfig,
+    /// Where the key provider attestations are signed with is kept, see [`crate::attribution`]
+    ///
+    /// The key is never rotated, so attestations verify for as long as the coordinator
+    /// publishes it. Without a file a key is generated that lasts as long as the process.
+    pub attestation_key_file: Option<std::path::PathBuf>,
+}
+
+impl Default for ExitConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 3002)),
+            resolver: ResolverConfig::default(),
+            audit: AuditConfig::default(),
+            hedge: HedgeConfig::default(),
+            pool: PoolConfig::default(),
+            relay: RelayConfig::default(),
+            membership: MembershipConfig::default(),
+            warmup: WarmupConfig::default(),
+            upstream: UpstreamLimits::default(),
+            cache: CacheConfig::default(),
+            multiplex: MultiplexConfig::default(),
+            breaker: BreakerConfig::default(),
+            budget: BudgetConfig::default(),
+            egress: EgressConfig::default(),
+            attestation_key_file: None,
+        }
+    }
+}
+
+/// Roles and listener of combined nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    /// The address every role is served on
+    pub listen_addr: SocketAddr,
+    /// The roles the node serves, such as `["routing", "exit"]`
+    pub roles: Vec<NodeRole>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 3005)),
+            roles: vec![NodeRole::Routing, NodeRole::Exit],
+        }
+    }
+}
+
+/// Settings of the coordinator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoordinatorConfig {
+    /// The address to listen on
+    pub listen_addr: SocketAddr,
+    /// Retention and bucketing of heartbeat samples for the dashboard
+    pub dashboard: DashboardConfig,
+    /// Noise on the statistics published to the public, see [`crate::privacy`]
+    pub privacy: NoiseConfig,
+    /// Scheduling of RPC provider health probes
+    pub probe: ProbeConfig,
+    /// Providers and node keys to seed the coordinator with, unless a seed file is given
+    pub bootstrap: BootstrapConfig,
+    /// Retrying of webhook deliveries to operators
+    pub webhooks: WebhookConfig,
+    /// Paths recommended to entry nodes to spread circuits over the network
+    pub recommend: RecommendConfig,
+    /// Bulk import and export of users' RPC mappings
+    pub provisioning: ProvisioningConfig,
+    /// Providers proposed by the community and their review
+    pub submissions: SubmissionConfig,
+    /// Feature flags published in the directory
+    pub flags: FlagsConfig,
+    /// How the reports nodes post are authenticated, see [`crate::report_auth`]
+    pub report_auth: ReportAuthConfig,
+    /// Canary requests sent through the network's entry nodes, if enabled
+    #[cfg(feature = "canary")]
+    pub canary: Option<CanaryConfig>,
+}
+
+impl Default for CoordinatorConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 3001)),
+            dashboard: DashboardConfig::default(),
+            privacy: NoiseConfig::default(),
+            probe: ProbeConfig::default(),
+            bootstrap: BootstrapConfig::default(),
+            webhooks: WebhookConfig::default(),
+            recommend: RecommendConfig::default(),
+            provisioning: ProvisioningConfig::default(),
+            submissions: SubmissionConfig::default(),
+            flags: FlagsConfig::default(),
+            report_auth: ReportAuthConfig::default(),
+            #[cfg(feature = "canary")]
+            canary: None,
+        }
+    }
+}
+
+impl DarknodeConfig {
+    /// Parse and validate a config
+    pub fn parse(contents: &str) -> Result<Self, ConfigError> {
+        // Durations written with a unit are read as tables, which is how they deserialize;
+        // the file is only rewritten if it has any, so errors keep naming the key either way
+        let mut value: toml::Value = contents.parse().map_err(|e: toml::de::Error| ConfigError::Parse(e.to_string()))?;
+        let rewritten = match read_durations(&mut value) {
+            true => Some(toml::to_string(&value).map_err(|e| ConfigError::Parse(e.to_string()))?),
+            false => None,
+        };
+        let config: Self =
+            toml::from_str(rewritten.as_deref().unwrap_or(contents)).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+    
+    /// Read, parse, and validate a config file
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read config file {}: {}", path.display(), e))?;
+        Self::parse(&contents).map_err(|e| anyhow::anyhow!("Invalid config file {}: {}", path.display(), e))
+    }
+    
+    /// The config a binary started with `args` runs with
+    ///
+    /// That is the file given with `--config`, or the defaults without one.
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let path = args.iter().position(|arg| arg == CONFIG_FLAG).map(|i| args.get(i + 1));
+        match path {
+            Some(Some(path)) => Self::load(Path::new(path)),
+            Some(None) => anyhow::bail!("{} requires a file", CONFIG_FLAG),
+            None => Ok(Self::default()),
+        }
+    }
+    
+    /// Check the settings the schema alone can't
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.coordinator.bootstrap.validate().map_err(|e| ConfigError::Invalid {
+            key: format!("coordinator.bootstrap.{}", e.entry),
+            reason: e.reason,
+        })?;
+        let audited = &self.entry.compliance;
+        if audited.enabled && audited.sink == AuditSinkConfig::Database && self.common.storage == StorageConfig::Memory {
+            return Err(ConfigError::Invalid {
+                key: "entry.compliance.sink".to_string(),
+                reason: "usage records would be lost on restart with common.storage in memory".to_string(),
+            });
+        }
+        if self.common.billing.enabled && self.common.storage == StorageConfig::Memory {
+            return Err(ConfigError::Invalid {
+                key: "common.billing.enabled".to_string(),
+                reason: "metered usage would never reach the coordinator with common.storage in memory".to_string(),
+            });
+        }
+        let ratio = self.common.telemetry.sampling_ratio;
+        if !(0.0..=1.0).contains(&ratio) {
+            return Err(ConfigError::Invalid {
+                key: "common.telemetry.sampling_ratio".to_string(),
+                reason: format!("{} is not a share in [0, 1]", ratio),
+            });
+        }
+        let share = self.exit.budget.quota_share;
+        if !(share > 0.0 && share <= 1.0) {
+            return Err(ConfigError::Invalid {
+                key: "exit.budget.quota_share".to_string(),
+                reason: format!("{} is not a share in (0, 1]", share),
+            });
+        }
+        #[cfg(feature = "canary")]
+        if self.coordinator.canary.as_ref().map_or(false, |canary| canary.api_key.is_empty()) {
+            return Err(ConfigError::Missing {
+                key: "coordinator.canary".to_string(),
+                field: "api_key".to_string(),
+            });
+        }
+        Ok(())
+    }
+    
+    /// The named sections as TOML, secrets redacted
+    pub fn render(&self, sections: &[&str]) -> Result<String> {
+        let value = match toml::Value::try_from(self)? {
+            toml::Value::Table(table) => toml::Value::Table(
+                table
+                    .into_iter()
+                    .filter(|(key, _)| sections.contains(&key.as_str()))
+                    .collect(),
+            ),
+            value => value,
+        };
+        render(&value)
+    }
+}
+
+/// Settings as TOML, secrets redacted and durations written with a unit
+pub fn render<T: Serialize>(settings: &T) -> Result<String> {
+    let mut value = toml::Value::try_from(settings)?;
+    redact(&mut value, false);
+    write_durations(&mut value);
+    Ok(toml::to_string_pretty(&value)?)
+}
+
+/// `url` with its credentials, path and query replaced, as any of them may hold an API key
+///
+/// Strings that aren't URLs, and URLs naming nothing but a host, are returned unchanged.
+pub fn redact_url(url: &str) -> String {
+    let Ok(parsed) = reqwest::Url::parse(url) else { return url.to_string() };
+    let Some(host) = parsed.host_str() else { return url.to_string() };
+    let bare = parsed.username().is_empty()
+        && parsed.password().is_none()
+        && parsed.query().is_none()
+        && matches!(parsed.path(), "" | "/");
+    if bare {
+        return url.to_string();
+    }
+    let port = parsed.port().map(|port| format!(":{}", port)).unwrap_or_default();
+    format!("{}://{}{}/{}", parsed.scheme(), host, port, REDACTED)
+}
+
+/// Whether a binary started with `args` should only check its config
+pub fn check_requested(args: &[String]) -> bool {
+    args.iter().any(|arg| arg == CHECK_CONFIG_FLAG)
+}
+
+/// Whether a binary started with `args` should log full request and response bodies
+///
+/// Refused in builds without the `dev-logging` feature, which don't contain the logging.
+pub fn dev_verbose_logging_requested(args: &[String]) -> Result<bool> {
+    let requested = args.iter().any(|arg| arg == DEV_VERBOSE_LOGGING_FLAG);
+    if requested && !cfg!(feature = "dev-logging") {
+        anyhow::bail!("{} requires a build with the dev-logging feature", DEV_VERBOSE_LOGGING_FLAG);
+    }
+    Ok(requested)
+}
+
+/// Replace the values of secret keys anywhere in `value`, and what URLs may hide keys in
+///
+/// A key is secret if its name contains one of [`SECRET_KEYS`], such as `coordinator_token`;
+/// everything under it is replaced. `secret` says whether `value` is under such a key.
+fn redact(value: &mut toml::Value, secret: bool) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                let key = key.to_ascii_lowercase();
+                redact(value, secret || SECRET_KEYS.iter().any(|word| key.contains(word)));
+            }
+        }
+        toml::Value::Array(values) => values.iter_mut().for_each(|value| redact(value, secret)),
+        toml::Value::String(text) if secret && !text.is_empty() => *text = REDACTED.to_string(),
+        toml::Value::String(text) if text.contains("://") => *text = redact_url(text),
+        _ => {}
+    }
+}
+
+/// The duration `text` writes with a unit, such as `50ms` or `1.5h`
+fn parse_duration(text: &str) -> Option<Duration> {
+    let split = text.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+    let (number, unit) = text.split_at(split);
+    let length = DURATION_UNITS.iter().find(|(name, _)| *name == unit)?.1;
+    if number.is_empty() || number.starts_with('.') || number.ends_with('.') {
+        return None;
+    }
+    let number: f64 = number.parse().ok()?;
+    Duration::try_from_secs_f64(number * length.as_secs_f64()).ok()
+}
+
+/// Replace the durations written with a unit anywhere in `value` with tables, returning
+/// whether there were any
+fn read_durations(value: &mut toml::Value) -> bool {
+    match value {
+        toml::Value::Table(table) => table.iter_mut().map(|(_, value)| read_durations(value)).fold(false, |any, read| any | read),
+        toml::Value::Array(values) => values.iter_mut().map(read_durations).fold(false, |any, read| any | read),
+        toml::Value::String(text) => match parse_duration(text) {
+            Some(duration) => {
+                *value = toml::Value::try_from(duration).expect("durations serialize");
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// Write the durations anywhere in `value` with the largest unit that keeps them exact
+///
+/// Durations finer than a millisecond are left as tables.
+fn write_durations(value: &mut toml::Value) {
+    let duration = match value {
+        toml::Value::Table(table) if table.len() == 2 => match (table.get("secs"), table.get("nanos")) {
+            (Some(toml::Value::Integer(secs)), Some(toml::Value::Integer(nanos))) if *secs >= 0 && *nanos >= 0 => {
+                Some(Duration::new(*secs as u64, *nanos as u32))
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    match (duration, value) {
+        (Some(duration), value) if duration.subsec_nanos() % 1_000_000 == 0 => {
+            let millis = duration.as_millis();
+            let (unit, length) = DURATION_UNITS
+                .iter()
+                .find(|(_, length)| millis % length.as_millis() == 0)
+                .expect("every whole number of milliseconds has a unit");
+            *value = toml::Value::String(format!("{}{}", millis / length.as_millis(), unit));
+        }
+        (_, toml::Value::Table(table)) => table.iter_mut().for_each(|(_, value)| write_durations(value)),
+        (_, toml::Value::Array(values)) => values.iter_mut().for_each(write_durations),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+    
+    #[test]
+    fn secrets_and_the_keys_in_urls_are_redacted_when_printed() {
+        let settings: toml::Value = toml::from_str(
+            r#"
+            coordinator_token = "hunter2"
+            api_keys = ["key-1", "key-2"]
+            database_url = "postgres://darknode:hunter2@db:5432/darknode"
+            providers = ["https://eth.example.com/v2/key-3", "https://rpc.example.com/?api-key=key-4", "https://public.example.com"]
+            region = "eu-west"
+            "#,
+        )
+        .unwrap();
+        let printed = render(&settings).unwrap();
+        for secret in ["hunter2", "key-1", "key-2", "key-3", "key-4"] {
+            assert!(!printed.contains(secret), "{} printed in\n{}", secret, printed);
+        }
+        assert!(printed.contains("https://eth.example.com/<redacted>"), "{}", printed);
+        assert!(printed.contains("https://public.example.com"), "{}", printed);
+        assert!(printed.contains("eu-west"), "{}", printed);
+    }
+    
+    #[test]
+    fn the_storage_url_is_printed_without_its_credentials() {
+        let config = DarknodeConfig::parse(
+            "[common.storage]\nbackend = \"postgres\"\nurl = \"postgres://darknode:hunter2@db:5432/darknode\"\n",
+        )
+        .unwrap();
+        let printed = config.render(&["common"]).unwrap();
+        assert!(!printed.contains("hunter2") && !printed.contains("darknode@"), "{}", printed);
+        assert!(printed.contains("postgres://db:5432/<redacted>"), "{}", printed);
+    }
+    
+    #[test]
+    fn durations_are_read_and_printed_with_units() {
+        let config = DarknodeConfig::parse("[entry.idempotency]\nttl = \"90s\"\ncapacity = 10\n").unwrap();
+        assert_eq!(config.entry.idempotency.ttl, Duration::from_secs(90));
+        let legacy = DarknodeConfig::parse("[entry.idempotency]\nttl = { secs = 0, nanos = 50000000 }\n").unwrap();
+        assert_eq!(legacy.entry.idempotency.ttl, Duration::from_millis(50));
+        
+        let printed = config.render(&["entry"]).unwrap();
+        assert!(printed.contains("ttl = '90s'"), "{}", printed);
+        assert_eq!(DarknodeConfig::parse(&printed).unwrap().entry.idempotency.ttl, Duration::from_secs(90));
+        
+        assert_eq!(parse_duration("1.5h"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        for text in ["90", "s", ".5s", "5 s", "5w", "eu-west"] {
+            assert_eq!(parse_duration(text), None, "{}", text);
+        }
+    }
+    
+    #[test]
+    fn unknown_keys_are_named_by_their_path() {
+        for contents in ["[entry.idempotency]\ntll = 5\n", "[entry.idempotency]\nttl = \"5s\"\ntll = 5\n"] {
+            let refused = DarknodeConfig::parse(contents).unwrap_err().to_string();
+            assert!(refused.contains("tll") && refused.contains("entry.idempotency"), "{}", refused);
+        }
+    }
+    
+    #[test]
+    fn a_missing_required_field_is_named_with_its_table() {
+        let refused = DarknodeConfig::parse("[common.storage]\nbackend = \"postgres\"\n").unwrap_err().to_string();
+        assert!(refused.contains("missing field `url`") && refused.contains("common.storage"), "{}", refused);
+    }
+    
+    #[test]
+    fn billing_is_refused_on_memory_storage() {
+        let refused = DarknodeConfig::parse("[common.billing]\nenabled = true\n").unwrap_err();
+        assert!(matches!(&refused, ConfigError::Invalid { key, .. } if key == "common.billing.enabled"), "{}", refused);
+    }
+    
+    #[test]
+    fn sampling_ratios_outside_zero_to_one_are_refused() {
+        for ratio in ["1.5", "-0.1", "nan"] {
+            let refused = DarknodeConfig::parse(&format!("[common.telemetry]\nsampling_ratio = {}\n", ratio)).unwrap_err();
+            assert!(
+                matches!(&refused, ConfigError::Invalid { key, .. } if key == "common.telemetry.sampling_ratio"),
+                "{}: {}",
+                ratio,
+                refused
+            );
+        }
+        DarknodeConfig::parse("[common.telemetry]\nsampling_ratio = 1.0\n").unwrap();
+    }
+    
+    #[test]
+    #[cfg(not(feature = "dev-logging"))]
+    fn dev_verbose_logging_is_refused_without_the_feature() {
+        let refused = dev_verbose_logging_requested(&args(&["--config", "entry.toml", DEV_VERBOSE_LOGGING_FLAG])).unwrap_err();
+        assert!(refused.to_string().contains("dev-logging feature"), "{}", refused);
+        assert!(!dev_verbose_logging_requested(&args(&["--config", "entry.toml"])).unwrap());
+    }
+    
+    #[test]
+    #[cfg(feature = "dev-logging")]
+    fn dev_verbose_logging_is_taken_with_the_feature() {
+        assert!(dev_verbose_logging_requested(&args(&[DEV_VERBOSE_LOGGING_FLAG])).unwrap());
+        assert!(!dev_verbose_logging_requested(&args(&[])).unwrap());
+    }
+}
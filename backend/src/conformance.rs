@@ -0,0 +1,1076 @@
+//! Conformance checks for implementations of the manager traits
+//!
+//! The nodes run against [`NodeManager`], [`RpcManager`] and [`UserManager`], and rely on
+//! more than their signatures say: a missing user is an error when written to but `None`
+//! when looked up, registering again replaces rather than duplicates, only online nodes and
+//! active providers are handed out, and updates made at once are all kept. Whoever writes
+//! an implementation of their own, over Redis, Consul or anything else, can check it
+//! against these expectations with [`node_manager`], [`rpc_manager`] and [`user_manager`],
+//! each taking a manager with nothing in it yet and returning a [`Report`] of every check,
+//! which [`Report::assert_passed`] turns into a test failure listing the ones that failed.
+//!
+//! The checks only go through the trait, so they hold the stored managers of
+//! [`crate::managers`] to the same expectations on every storage backend, which the crate's
+//! own tests do for each backend it is built with. They are built with the `test-util`
+//! feature.
+
+use super::*;
+use super::fixtures::mapping;
+use super::method_routing::{InvalidRoutes, MethodRoutes, RouteRule};
+use super::scopes::{Scope, ScopeError};
+use super::timeouts::MethodClass;
+use super::traits::{NodeManager, RpcManager, UserManager};
+use super::types::*;
+use super::wallets::InvalidWalletAddress;
+use anyhow::Context;
+use futures::future::join_all;
+use std::future::Future;
+
+/// Longest a single check may take before it is failed, so a deadlocked one can't hang the suite
+const CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many calls the checks of concurrent access make at once
+const CONCURRENCY: usize = 16;
+
+/// How one check went
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CheckResult {
+    /// What the check expects of the implementation
+    pub check: &'static str,
+    /// Why it failed, if it did
+    pub failure: Option<String>,
+}
+
+/// How an implementation fared against every check of a suite
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    /// The trait the implementation was checked against
+    pub suite: &'static str,
+    /// Every check, in the order it ran
+    pub results: Vec<CheckResult>,
+}
+
+impl Report {
+    /// Whether every check passed
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| result.failure.is_none())
+    }
+    
+    /// The checks that failed
+    pub fn failures(&self) -> impl Iterator<Item = &CheckResult> {
+        self.results.iter().filter(|result| result.failure.is_some())
+    }
+    
+    /// Panic, listing the checks that failed, unless every check passed
+    pub fn assert_passed(&self) {
+        if !self.passed() {
+            panic!("{}", self);
+        }
+    }
+    
+    /// Run `check`, recording whether it passed
+    async fn check<F>(&mut self, check: &'static str, run: F)
+    where
+        F: Future<Output = Result<()>>,
+    {
+        let failure = match tokio::time::timeout(CHECK_TIMEOUT, run).await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(format!("{:#}", e)),
+            Err(_) => Some(format!("took longer than {}s", CHECK_TIMEOUT.as_secs())),
+        };
+        self.results.push(CheckResult { check, failure });
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let failed = self.failures().count();
+        writeln!(f, "{}: {} of {} checks passed", self.suite, self.results.len() - failed, self.results.len())?;
+        for result in &self.results {
+            match &result.failure {
+                None => writeln!(f, "  ok    {}", result.check)?,
+                Some(failure) => writeln!(f, "  FAIL  {}: {}", result.check, failure)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A node of `roles` in `status`, of a key of its own, not known to any manager yet
+fn node(roles: &[NodeRole], status: NodeStatus) -> Node {
+    Node {
+        status,
+        public_key: CryptoKey(Uuid::new_v4().as_bytes().to_vec()),
+        ..fixtures::node(roles)
+    }
+}
+
+/// A provider in `state` with `success_rate`, at a URL of its own, not known to any manager yet
+fn provider(state: ProviderState, success_rate: f64) -> RpcProvider {
+    RpcProvider {
+        url: format!("https://{}.conformance.invalid/", Uuid::new_v4()),
+        state,
+        success_rate,
+        ..fixtures::provider()
+    }
+}
+
+/// A valid Ethereum address no user has yet, in lowercase
+fn wallet() -> String {
+    format!("0x{}{}", Uuid::new_v4().simple(), &Uuid::new_v4().simple().to_string()[..8])
+}
+
+/// A plan of a name no plan has yet
+fn plan() -> Plan {
+    Plan {
+        id: Uuid::new_v4(),
+        name: format!("conformance-{}", Uuid::new_v4()),
+        ..Plan::default()
+    }
+}
+
+/// Whether `nodes` holds the node `node_id` exactly once
+fn listed_once(nodes: &[Node], node_id: &NodeId) -> bool {
+    nodes.iter().filter(|node| node.id == *node_id).count() == 1
+}
+
+/// Whether `providers` holds the provider `provider_id` exactly once
+fn provider_listed_once(providers: &[RpcProvider], provider_id: Uuid) -> bool {
+    providers.iter().filter(|provider| provider.id == provider_id).count() == 1
+}
+
+/// Check `manager`, which must know no nodes yet, against what the nodes expect of a [`NodeManager`]
+pub async fn node_manager(manager: Arc<dyn NodeManager + Send + Sync>) -> Report {
+    let mut report = Report {
+        suite: "NodeManager",
+        ..Default::default()
+    };
+    let m = &*manager;
+    
+    report
+        .check("get_node is None for an unknown node", async {
+            anyhow::ensure!(m.get_node(&NodeId(Uuid::new_v4())).await?.is_none(), "found a node never registered");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("register_node makes the node found by get_node", async {
+            let registered = node(&[NodeRole::Exit], NodeStatus::Online);
+            m.register_node(registered.clone()).await?;
+            let found = m.get_node(&registered.id).await?.context("registered node not found")?;
+            anyhow::ensure!(found.roles == registered.roles, "roles {:?}, expected {:?}", found.roles, registered.roles);
+            anyhow::ensure!(found.status == registered.status, "status {:?}, expected {:?}", found.status, registered.status);
+            anyhow::ensure!(found.public_key.0 == registered.public_key.0, "public key changed");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("register_node of a registered node replaces it", async {
+            let mut registered = node(&[NodeRole::Routing], NodeStatus::Online);
+            m.register_node(registered.clone()).await?;
+            registered.port = 4000;
+            m.register_node(registered.clone()).await?;
+            let found = m.get_node(&registered.id).await?.context("registered node not found")?;
+            anyhow::ensure!(found.port == 4000, "port {}, expected the one registered last", found.port);
+            let available = m.get_available_nodes(NodeRole::Routing).await?;
+            anyhow::ensure!(listed_once(&available, &registered.id), "node not listed exactly once as available");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("get_available_nodes lists online nodes of the role only", async {
+            let online = node(&[NodeRole::Exit], NodeStatus::Online);
+            let both = node(&[NodeRole::Routing, NodeRole::Exit], NodeStatus::Online);
+            let routing = node(&[NodeRole::Routing], NodeStatus::Online);
+            let unavailable: Vec<Node> = [NodeStatus::Busy, NodeStatus::Offline, NodeStatus::Maintenance]
+                .into_iter()
+                .map(|status| node(&[NodeRole::Exit], status))
+                .collect();
+            for registered in [&online, &both, &routing].into_iter().chain(&unavailable) {
+                m.register_node(registered.clone()).await?;
+            }
+            let exits = m.get_available_nodes(NodeRole::Exit).await?;
+            anyhow::ensure!(listed_once(&exits, &online.id), "online exit not listed exactly once");
+            anyhow::ensure!(listed_once(&exits, &both.id), "online node of several roles not listed under each");
+            anyhow::ensure!(!listed_once(&exits, &routing.id), "routing node listed as an exit");
+            for node in &unavailable {
+                anyhow::ensure!(!listed_once(&exits, &node.id), "{:?} node listed as available", node.status);
+            }
+            let routing_nodes = m.get_available_nodes(NodeRole::Routing).await?;
+            anyhow::ensure!(listed_once(&routing_nodes, &both.id), "online node of several roles not listed under each");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("update_node_status takes nodes out of and back into the available ones", async {
+            let registered = node(&[NodeRole::Entry], NodeStatus::Online);
+            m.register_node(registered.clone()).await?;
+            m.update_node_status(&registered.id, NodeStatus::Offline).await?;
+            let found = m.get_node(&registered.id).await?.context("registered node not found")?;
+            anyhow::ensure!(found.status == NodeStatus::Offline, "status {:?} after going offline", found.status);
+            anyhow::ensure!(
+                !listed_once(&m.get_available_nodes(NodeRole::Entry).await?, &registered.id),
+                "offline node listed as available"
+            );
+            m.update_node_status(&registered.id, NodeStatus::Online).await?;
+            anyhow::ensure!(
+                listed_once(&m.get_available_nodes(NodeRole::Entry).await?, &registered.id),
+                "node back online not listed as available"
+            );
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("update_node_status doesn't register an unknown node", async {
+            let unknown = NodeId(Uuid::new_v4());
+            let _ = m.update_node_status(&unknown, NodeStatus::Online).await;
+            anyhow::ensure!(m.get_node(&unknown).await?.is_none(), "a status update registered the node");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("publish_next_key fails for an unknown node", async {
+            let published = m
+                .publish_next_key(&NodeId(Uuid::new_v4()), CryptoKey(vec![1; 32]), Timestamp::now())
+                .await;
+            anyhow::ensure!(published.is_err(), "published a key for a node never registered");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("publish_next_key keys are announced, and take over once they activate", async {
+            let registered = node(&[NodeRole::Routing], NodeStatus::Online);
+            m.register_node(registered.clone()).await?;
+            let later = CryptoKey(vec![2; 32]);
+            m.publish_next_key(&registered.id, later.clone(), Timestamp::now() + Duration::from_secs(3600))
+                .await?;
+            let found = m.get_node(&registered.id).await?.context("registered node not found")?;
+            anyhow::ensure!(found.public_key.0 == registered.public_key.0, "key replaced before it activated");
+            anyhow::ensure!(
+                found.next_public_key.as_ref().map(|key| &key.0) == Some(&later.0),
+                "next key not announced"
+            );
+            
+            let now = CryptoKey(vec![3; 32]);
+            m.publish_next_key(&registered.id, now.clone(), Timestamp::now()).await?;
+            let found = m.get_node(&registered.id).await?.context("registered node not found")?;
+            anyhow::ensure!(found.active_public_key(Timestamp::now()).0 == now.0, "activated key not in use");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("concurrent registrations are all kept", async {
+            let nodes: Vec<Node> = (0..CONCURRENCY).map(|_| node(&[NodeRole::Exit], NodeStatus::Online)).collect();
+            for registered in join_all(nodes.iter().map(|node| m.register_node(node.clone()))).await {
+                registered?;
+            }
+            let available = m.get_available_nodes(NodeRole::Exit).await?;
+            let missing = nodes.iter().filter(|node| !listed_once(&available, &node.id)).count();
+            anyhow::ensure!(missing == 0, "{} of {} nodes registered at once not listed exactly once", missing, CONCURRENCY);
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("concurrent status updates are all kept", async {
+            let nodes: Vec<Node> = (0..CONCURRENCY).map(|_| node(&[NodeRole::Entry], NodeStatus::Online)).collect();
+            for registered in &nodes {
+                m.register_node(registered.clone()).await?;
+            }
+            for updated in join_all(nodes.iter().map(|node| m.update_node_status(&node.id, NodeStatus::Offline))).await {
+                updated?;
+            }
+            let available = m.get_available_nodes(NodeRole::Entry).await?;
+            let kept = nodes.iter().filter(|node| listed_once(&available, &node.id)).count();
+            anyhow::ensure!(kept == 0, "{} of {} nodes taken offline at once still available", kept, CONCURRENCY);
+            Ok(())
+        })
+        .await;
+    
+    report
+}
+
+/// Check `manager`, which must know no providers yet, against what the nodes expect of an [`RpcManager`]
+pub async fn rpc_manager(manager: Arc<dyn RpcManager + Send + Sync>) -> Report {
+    let mut report = Report {
+        suite: "RpcManager",
+        ..Default::default()
+    };
+    let m = &*manager;
+    
+    report
+        .check("get_best_provider is None without active providers", async {
+            m.register_provider(provider(ProviderState::Disabled, 1.0)).await?;
+            let best = m.get_best_provider().await?;
+            anyhow::ensure!(best.is_none(), "best provider {:?} while none is active", best.map(|p| p.id));
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("register_provider makes the provider listed", async {
+            let registered = provider(ProviderState::Active, 0.5);
+            m.register_provider(registered.clone()).await?;
+            let providers = m.get_providers().await?;
+            anyhow::ensure!(provider_listed_once(&providers, registered.id), "provider not listed exactly once");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("register_provider of a registered provider replaces it", async {
+            let mut registered = provider(ProviderState::Active, 0.5);
+            m.register_provider(registered.clone()).await?;
+            registered.weight = 7;
+            m.register_provider(registered.clone()).await?;
+            let providers = m.get_providers().await?;
+            anyhow::ensure!(provider_listed_once(&providers, registered.id), "provider not listed exactly once");
+            let weight = providers.iter().find(|p| p.id == registered.id).map(|p| p.weight);
+            anyhow::ensure!(weight == Some(7), "weight {:?}, expected the one registered last", weight);
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("get_active_providers lists active providers only", async {
+            let active = provider(ProviderState::Active, 0.5);
+            let others: Vec<RpcProvider> = [ProviderState::Disabled, ProviderState::Pending, ProviderState::Trial, ProviderState::Rejected]
+                .into_iter()
+                .map(|state| provider(state, 0.5))
+                .collect();
+            for registered in std::iter::once(&active).chain(&others) {
+                m.register_provider(registered.clone()).await?;
+            }
+            let listed = m.get_active_providers().await?;
+            anyhow::ensure!(provider_listed_once(&listed, active.id), "active provider not listed exactly once");
+            for other in &others {
+                anyhow::ensure!(!provider_listed_once(&listed, other.id), "{:?} provider listed as active", other.state);
+            }
+            let all = m.get_providers().await?;
+            anyhow::ensure!(
+                others.iter().all(|other| provider_listed_once(&all, other.id)),
+                "get_providers leaves out providers that aren't active"
+            );
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("update_provider replaces a registered provider", async {
+            let mut registered = provider(ProviderState::Active, 0.5);
+            m.register_provider(registered.clone()).await?;
+            registered.pool = Some("conformance".to_string());
+            m.update_provider(registered.clone()).await?;
+            let pool = m.get_providers().await?.into_iter().find(|p| p.id == registered.id).and_then(|p| p.pool);
+            anyhow::ensure!(pool.as_deref() == Some("conformance"), "pool {:?} after the update", pool);
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("update_provider fails for an unknown provider, registering nothing", async {
+            let unknown = provider(ProviderState::Active, 0.5);
+            anyhow::ensure!(m.update_provider(unknown.clone()).await.is_err(), "updated a provider never registered");
+            anyhow::ensure!(
+                !provider_listed_once(&m.get_providers().await?, unknown.id),
+                "the update registered the provider"
+            );
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("update_provider_status takes approved providers out of and back into service", async {
+            let registered = provider(ProviderState::Active, 0.5);
+            m.register_provider(registered.clone()).await?;
+            m.update_provider_status(registered.id, false).await?;
+            anyhow::ensure!(
+                !provider_listed_once(&m.get_active_providers().await?, registered.id),
+                "deactivated provider still active"
+            );
+            m.update_provider_status(registered.id, true).await?;
+            anyhow::ensure!(
+                provider_listed_once(&m.get_active_providers().await?, registered.id),
+                "reactivated provider not active"
+            );
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("record_probe leaves providers an operator took out of service out", async {
+            let registered = provider(ProviderState::Active, 0.5);
+            m.register_provider(registered.clone()).await?;
+            m.update_provider_status(registered.id, false).await?;
+            m.record_probe(registered.id, true, Duration::from_millis(10)).await?;
+            anyhow::ensure!(
+                !provider_listed_once(&m.get_active_providers().await?, registered.id),
+                "a passing probe reactivated a provider an operator took out of service"
+            );
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("update_provider_status refuses providers under review", async {
+            let pending = provider(ProviderState::Pending, 0.5);
+            m.register_provider(pending.clone()).await?;
+            anyhow::ensure!(
+                m.update_provider_status(pending.id, true).await.is_err(),
+                "activated a provider no one reviewed"
+            );
+            anyhow::ensure!(
+                !provider_listed_once(&m.get_active_providers().await?, pending.id),
+                "provider under review active"
+            );
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("update_provider_status doesn't register an unknown provider", async {
+            let unknown = Uuid::new_v4();
+            let _ = m.update_provider_status(unknown, true).await;
+            anyhow::ensure!(!provider_listed_once(&m.get_providers().await?, unknown), "a status update registered the provider");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("remove_provider removes the provider, and again does nothing", async {
+            let registered = provider(ProviderState::Active, 0.5);
+            m.register_provider(registered.clone()).await?;
+            m.remove_provider(registered.id).await?;
+            anyhow::ensure!(
+                !provider_listed_once(&m.get_providers().await?, registered.id),
+                "removed provider still listed"
+            );
+            m.remove_provider(registered.id).await.context("removing a removed provider")?;
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("record_probe moves the success rate towards the outcome", async {
+            let registered = provider(ProviderState::Active, 0.5);
+            m.register_provider(registered.clone()).await?;
+            let rate = |providers: Vec<RpcProvider>| providers.into_iter().find(|p| p.id == registered.id).map(|p| p.success_rate);
+            m.record_probe(registered.id, false, Duration::from_millis(100)).await?;
+            let failed = rate(m.get_providers().await?).context("probed provider not listed")?;
+            anyhow::ensure!(failed < 0.5, "success rate {} after a failed probe, from 0.5", failed);
+            m.record_probe(registered.id, true, Duration::from_millis(100)).await?;
+            let passed = rate(m.get_providers().await?).context("probed provider not listed")?;
+            anyhow::ensure!(passed > failed, "success rate {} after a passed probe, from {}", passed, failed);
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("record_probe takes an active provider out of service only after failures in a row", async {
+            let registered = provider(ProviderState::Active, 0.5);
+            m.register_provider(registered.clone()).await?;
+            let active = |providers: Vec<RpcProvider>| provider_listed_once(&providers, registered.id);
+            for _ in 1..PROBE_FAILURES_TO_DISABLE {
+                m.record_probe(registered.id, false, Duration::from_millis(100)).await?;
+            }
+            m.record_probe(registered.id, true, Duration::from_millis(100)).await?;
+            for _ in 1..PROBE_FAILURES_TO_DISABLE {
+                m.record_probe(registered.id, false, Duration::from_millis(100)).await?;
+            }
+            anyhow::ensure!(active(m.get_active_providers().await?), "provider taken out of service before failing enough probes in a row");
+            m.record_probe(registered.id, false, Duration::from_millis(100)).await?;
+            anyhow::ensure!(!active(m.get_active_providers().await?), "provider still active after failing {} probes in a row", PROBE_FAILURES_TO_DISABLE);
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("record_probe doesn't register an unknown provider", async {
+            let unknown = Uuid::new_v4();
+            let _ = m.record_probe(unknown, true, Duration::from_millis(100)).await;
+            anyhow::ensure!(!provider_listed_once(&m.get_providers().await?, unknown), "a probe registered the provider");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("get_best_provider picks the active provider with the highest success rate", async {
+            let best = provider(ProviderState::Active, 0.99);
+            m.register_provider(best.clone()).await?;
+            m.register_provider(provider(ProviderState::Disabled, 1.0)).await?;
+            let picked = m.get_best_provider().await?.context("no best provider among active ones")?;
+            anyhow::ensure!(picked.id == best.id, "picked {} at {}, expected {}", picked.id, picked.success_rate, best.id);
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("record_provider_misbehavior lowers the success rate, not below 0", async {
+            let registered = provider(ProviderState::Active, 0.05);
+            m.register_provider(registered.clone()).await?;
+            m.record_provider_misbehavior(registered.id, "conformance").await?;
+            m.record_provider_misbehavior(registered.id, "conformance").await?;
+            let rate = m
+                .get_providers()
+                .await?
+                .into_iter()
+                .find(|p| p.id == registered.id)
+                .map(|p| p.success_rate)
+                .context("provider not listed")?;
+            anyhow::ensure!((0.0..0.05).contains(&rate), "success rate {} after misbehaving, from 0.05", rate);
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("concurrent registrations are all kept", async {
+            let providers: Vec<RpcProvider> = (0..CONCURRENCY).map(|_| provider(ProviderState::Active, 0.5)).collect();
+            for registered in join_all(providers.iter().map(|p| m.register_provider(p.clone()))).await {
+                registered?;
+            }
+            let listed = m.get_providers().await?;
+            let missing = providers.iter().filter(|p| !provider_listed_once(&listed, p.id)).count();
+            anyhow::ensure!(missing == 0, "{} of {} providers registered at once not listed exactly once", missing, CONCURRENCY);
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("concurrent probes of one provider all succeed", async {
+            let registered = provider(ProviderState::Active, 1.0);
+            m.register_provider(registered.clone()).await?;
+            let probes = (0..CONCURRENCY).map(|_| m.record_probe(registered.id, false, Duration::from_millis(100)));
+            for probed in join_all(probes).await {
+                probed?;
+            }
+            let providers = m.get_providers().await?;
+            anyhow::ensure!(provider_listed_once(&providers, registered.id), "probed provider not listed exactly once");
+            Ok(())
+        })
+        .await;
+    
+    report
+}
+
+/// Check `manager`, which must know no users yet, against what the nodes expect of a [`UserManager`]
+pub async fn user_manager(manager: Arc<dyn UserManager + Send + Sync>) -> Report {
+    let mut report = Report {
+        suite: "UserManager",
+        ..Default::default()
+    };
+    let m = &*manager;
+    
+    report
+        .check("create_user refuses invalid wallet addresses", async {
+            for invalid in ["", "not-a-wallet", "0x1234", "0xZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZ"] {
+                match m.create_user(invalid).await {
+                    Err(e) if e.is::<InvalidWalletAddress>() => {}
+                    Err(e) => anyhow::bail!("refused {:?} with {}, not InvalidWalletAddress", invalid, e),
+                    Ok(_) => anyhow::bail!("created a user for {:?}", invalid),
+                }
+            }
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("create_user creates an active user found by API key and wallet", async {
+            let user = m.create_user(&wallet()).await?;
+            anyhow::ensure!(user.active, "new user is not active");
+            let by_key = m.get_user_by_api_key(&user.api_key).await?.context("user not found by API key")?;
+            anyhow::ensure!(by_key.id == user.id, "API key finds another user");
+            let by_wallet = m.get_user_by_wallet(&user.wallet_address).await?.context("user not found by wallet")?;
+            anyhow::ensure!(by_wallet.id == user.id, "wallet finds another user");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("create_user refuses a wallet that already has a user", async {
+            let user = m.create_user(&wallet()).await?;
+            anyhow::ensure!(m.create_user(&user.wallet_address).await.is_err(), "created a second user for a wallet");
+            let found = m.get_user_by_wallet(&user.wallet_address).await?.context("user not found by wallet")?;
+            anyhow::ensure!(found.id == user.id, "wallet finds another user");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("get_user_by_api_key and get_user_by_wallet are None for unknown ones", async {
+            anyhow::ensure!(m.get_user_by_api_key("api-unknown").await?.is_none(), "found a user by an unknown API key");
+            anyhow::ensure!(m.get_user_by_wallet(&wallet()).await?.is_none(), "found a user by an unknown wallet");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("get_user_by_wallet finds users however the address is cased", async {
+            let address = wallet();
+            let user = m.create_user(&address).await?;
+            let upper = format!("0x{}", address[2..].to_ascii_uppercase());
+            for cased in [&address, &upper] {
+                let found = m.get_user_by_wallet(cased).await?.with_context(|| format!("user not found by {}", cased))?;
+                anyhow::ensure!(found.id == user.id, "{} finds another user", cased);
+            }
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("concurrent create_user calls create distinct users", async {
+            let created = join_all((0..CONCURRENCY).map(|_| async { m.create_user(&wallet()).await })).await;
+            let users = created.into_iter().collect::<Result<Vec<User>>>()?;
+            let ids: std::collections::HashSet<Uuid> = users.iter().map(|user| user.id).collect();
+            let keys: std::collections::HashSet<&str> = users.iter().map(|user| user.api_key.as_str()).collect();
+            anyhow::ensure!(ids.len() == CONCURRENCY && keys.len() == CONCURRENCY, "users created at once share IDs or keys");
+            for user in &users {
+                let found = m.get_user_by_api_key(&user.api_key).await?.map(|found| found.id);
+                anyhow::ensure!(found == Some(user.id), "user created at once not found by API key");
+            }
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("add_rpc_mapping fails for an unknown user", async {
+            anyhow::ensure!(
+                m.add_rpc_mapping(Uuid::new_v4(), mapping()).await.is_err(),
+                "added a mapping to a user that doesn't exist"
+            );
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("add_rpc_mappings fails for an unknown user", async {
+            anyhow::ensure!(
+                m.add_rpc_mappings(Uuid::new_v4(), vec![mapping(), mapping()], &Plan::default()).await.is_err(),
+                "added mappings to a user that doesn't exist"
+            );
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("add_rpc_mapping and add_rpc_mappings add to the user's mappings", async {
+            let user = m.create_user(&wallet()).await?;
+            let (first, second, third) = (mapping(), mapping(), mapping());
+            m.add_rpc_mapping(user.id, first.clone()).await?;
+            m.add_rpc_mappings(user.id, vec![second.clone(), third.clone()], &Plan::default()).await?;
+            let mappings = m.get_rpc_mappings(user.id).await?;
+            for added in [&first, &second, &third] {
+                anyhow::ensure!(
+                    mappings.iter().filter(|mapping| mapping.id == added.id).count() == 1,
+                    "mapping {} not held exactly once",
+                    added.id
+                );
+            }
+            let found = m.get_user_by_api_key(&user.api_key).await?.context("user not found by API key")?;
+            anyhow::ensure!(found.rpc_mappings.len() == 3, "user found by API key holds {} mappings, expected 3", found.rpc_mappings.len());
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("concurrent add_rpc_mappings calls stay within the plan's mapping cap", async {
+            let user = m.create_user(&wallet()).await?;
+            let plan = Plan {
+                max_mappings: 3,
+                ..Plan::default()
+            };
+            let batches: Vec<Vec<RpcMapping>> = (0..CONCURRENCY).map(|_| vec![mapping(), mapping()]).collect();
+            let added = join_all(batches.into_iter().map(|batch| m.add_rpc_mappings(user.id, batch, &plan))).await;
+            let refused = added.iter().filter(|added| added.is_err()).count();
+            anyhow::ensure!(refused == CONCURRENCY - 1, "{} of {} imports past the cap refused", refused, CONCURRENCY - 1);
+            let held = m.get_rpc_mappings(user.id).await?.len();
+            anyhow::ensure!(held == 2, "user holds {} mappings under a cap of 3", held);
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("get_rpc_mappings of an unknown user holds nothing", async {
+            if let Ok(mappings) = m.get_rpc_mappings(Uuid::new_v4()).await {
+                anyhow::ensure!(mappings.is_empty(), "an unknown user holds {} mappings", mappings.len());
+            }
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("concurrent add_rpc_mapping calls for one user are all kept", async {
+            let user = m.create_user(&wallet()).await?;
+            let mappings: Vec<RpcMapping> = (0..CONCURRENCY).map(|_| mapping()).collect();
+            for added in join_all(mappings.iter().map(|mapping| m.add_rpc_mapping(user.id, mapping.clone()))).await {
+                added?;
+            }
+            let held = m.get_rpc_mappings(user.id).await?;
+            anyhow::ensure!(held.len() == CONCURRENCY, "{} of {} mappings added at once kept", held.len(), CONCURRENCY);
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("set_method_routes stores valid rules", async {
+            let user = m.create_user(&wallet()).await?;
+            let (source, target) = (mapping(), mapping());
+            m.add_rpc_mappings(user.id, vec![source.clone(), target.clone()], &Plan::default()).await?;
+            let routes = MethodRoutes {
+                rules: vec![RouteRule {
+                    method: "send*".to_string(),
+                    target: target.id,
+                }],
+                default_target: None,
+            };
+            m.set_method_routes(user.id, source.id, routes.clone()).await?;
+            let stored = m.get_rpc_mappings(user.id).await?.into_iter().find(|mapping| mapping.id == source.id);
+            anyhow::ensure!(stored.map(|mapping| mapping.routes) == Some(routes), "rules not stored");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("set_method_routes refuses invalid rules, keeping those held", async {
+            let user = m.create_user(&wallet()).await?;
+            let (first, second) = (mapping(), mapping());
+            m.add_rpc_mappings(user.id, vec![first.clone(), second.clone()], &Plan::default()).await?;
+            let to_second = MethodRoutes {
+                default_target: Some(second.id),
+                ..Default::default()
+            };
+            m.set_method_routes(user.id, first.id, to_second.clone()).await?;
+            let invalid = [
+                MethodRoutes {
+                    default_target: Some(Uuid::new_v4()),
+                    ..Default::default()
+                },
+                MethodRoutes {
+                    rules: vec![RouteRule {
+                        method: "get*Slot".to_string(),
+                        target: second.id,
+                    }],
+                    default_target: None,
+                },
+            ];
+            for routes in invalid {
+                match m.set_method_routes(user.id, first.id, routes.clone()).await {
+                    Err(e) if e.is::<InvalidRoutes>() => {}
+                    Err(e) => anyhow::bail!("refused {:?} with {}, not InvalidRoutes", routes, e),
+                    Ok(()) => anyhow::bail!("stored invalid rules {:?}", routes),
+                }
+            }
+            let looping = MethodRoutes {
+                default_target: Some(first.id),
+                ..Default::default()
+            };
+            match m.set_method_routes(user.id, second.id, looping).await {
+                Err(e) if e.is::<InvalidRoutes>() => {}
+                Err(e) => anyhow::bail!("refused a loop with {}, not InvalidRoutes", e),
+                Ok(()) => anyhow::bail!("stored rules looping between two mappings"),
+            }
+            let held = m.get_rpc_mappings(user.id).await?;
+            let routes_of = |id: Uuid| held.iter().find(|mapping| mapping.id == id).map(|mapping| mapping.routes.clone());
+            anyhow::ensure!(routes_of(first.id) == Some(to_second), "refused rules replaced those held");
+            anyhow::ensure!(routes_of(second.id) == Some(MethodRoutes::default()), "refused rules were stored");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("set_method_routes fails for an unknown user or mapping", async {
+            let user = m.create_user(&wallet()).await?;
+            let held = mapping();
+            m.add_rpc_mapping(user.id, held.clone()).await?;
+            anyhow::ensure!(
+                m.set_method_routes(Uuid::new_v4(), held.id, MethodRoutes::default()).await.is_err(),
+                "set rules for a user that doesn't exist"
+            );
+            anyhow::ensure!(
+                m.set_method_routes(user.id, Uuid::new_v4(), MethodRoutes::default()).await.is_err(),
+                "set rules for a mapping the user doesn't hold"
+            );
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("remove_rpc_mapping removes the mapping and the rules sending requests to it", async {
+            let user = m.create_user(&wallet()).await?;
+            let (source, target) = (mapping(), mapping());
+            m.add_rpc_mappings(user.id, vec![source.clone(), target.clone()], &Plan::default()).await?;
+            let to_target = MethodRoutes {
+                rules: vec![RouteRule {
+                    method: "send*".to_string(),
+                    target: target.id,
+                }],
+                default_target: Some(target.id),
+            };
+            m.set_method_routes(user.id, source.id, to_target).await?;
+            m.remove_rpc_mapping(user.id, target.id).await?;
+            let held = m.get_rpc_mappings(user.id).await?;
+            anyhow::ensure!(
+                held.iter().map(|mapping| mapping.id).collect::<Vec<_>>() == vec![source.id],
+                "user holds {} mappings after removing one of two",
+                held.len()
+            );
+            anyhow::ensure!(held[0].routes == MethodRoutes::default(), "rules still send requests to the removed mapping");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("remove_rpc_mapping fails for an unknown user or mapping", async {
+            let user = m.create_user(&wallet()).await?;
+            let held = mapping();
+            m.add_rpc_mapping(user.id, held.clone()).await?;
+            anyhow::ensure!(
+                m.remove_rpc_mapping(Uuid::new_v4(), held.id).await.is_err(),
+                "removed a mapping of a user that doesn't exist"
+            );
+            anyhow::ensure!(
+                m.remove_rpc_mapping(user.id, Uuid::new_v4()).await.is_err(),
+                "removed a mapping the user doesn't hold"
+            );
+            anyhow::ensure!(m.get_rpc_mappings(user.id).await?.len() == 1, "failed removal changed the mappings held");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("create_plan makes the plan found by get_plan", async {
+            let created = plan();
+            m.create_plan(created.clone()).await?;
+            let found = m.get_plan(created.id).await?.context("created plan not found")?;
+            anyhow::ensure!(found.name == created.name, "plan found as {:?}", found.name);
+            anyhow::ensure!(m.get_plan(Uuid::new_v4()).await?.is_none(), "found a plan never created");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("create_plan refuses a name already taken", async {
+            let created = plan();
+            m.create_plan(created.clone()).await?;
+            let same_name = Plan {
+                id: Uuid::new_v4(),
+                ..created
+            };
+            anyhow::ensure!(m.create_plan(same_name).await.is_err(), "created two plans of one name");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("set_user_plan assigns the plan, and fails for an unknown plan or user", async {
+            let user = m.create_user(&wallet()).await?;
+            let assigned = plan();
+            m.create_plan(assigned.clone()).await?;
+            anyhow::ensure!(m.set_user_plan(user.id, Uuid::new_v4()).await.is_err(), "assigned a plan that doesn't exist");
+            anyhow::ensure!(
+                m.set_user_plan(Uuid::new_v4(), assigned.id).await.is_err(),
+                "assigned a plan to a user that doesn't exist"
+            );
+            m.set_user_plan(user.id, assigned.id).await?;
+            let found = m.get_user_by_api_key(&user.api_key).await?.context("user not found by API key")?;
+            anyhow::ensure!(found.plan_id == Some(assigned.id), "plan {:?}, expected {}", found.plan_id, assigned.id);
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("set_audit_consent records consent, and fails for an unknown user", async {
+            let user = m.create_user(&wallet()).await?;
+            m.set_audit_consent(user.id, true).await?;
+            let found = m.get_user_by_api_key(&user.api_key).await?.context("user not found by API key")?;
+            anyhow::ensure!(found.audit_consent, "consent not recorded");
+            anyhow::ensure!(
+                m.set_audit_consent(Uuid::new_v4(), true).await.is_err(),
+                "recorded consent of a user that doesn't exist"
+            );
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("create_key makes a key finding its user", async {
+            let user = m.create_user(&wallet()).await?;
+            let scopes = vec![Scope::MethodClasses(vec![MethodClass::Read])];
+            let key = m.create_key(user.id, "conformance", scopes.clone()).await?;
+            anyhow::ensure!(key.scopes == scopes, "key created with scopes {:?}", key.scopes);
+            anyhow::ensure!(key.key != user.api_key, "key is the user's own API key");
+            let found = m.get_user_by_api_key(&key.key).await?.context("user not found by the key")?;
+            anyhow::ensure!(found.id == user.id, "key finds another user");
+            anyhow::ensure!(found.keys.iter().any(|held| held.id == key.id), "user doesn't hold the key");
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("create_key refuses invalid scopes, and fails for an unknown user", async {
+            let user = m.create_user(&wallet()).await?;
+            match m.create_key(user.id, "conformance", vec![Scope::Networks(Vec::new())]).await {
+                Err(e) if e.is::<ScopeError>() => {}
+                Err(e) => anyhow::bail!("refused invalid scopes with {}, not ScopeError", e),
+                Ok(_) => anyhow::bail!("created a key allowing no network"),
+            }
+            anyhow::ensure!(
+                m.create_key(Uuid::new_v4(), "conformance", Vec::new()).await.is_err(),
+                "created a key for a user that doesn't exist"
+            );
+            Ok(())
+        })
+        .await;
+    
+    report
+        .check("set_key_scopes replaces valid scopes only, and fails for an unknown key", async {
+            let user = m.create_user(&wallet()).await?;
+            let key = m.create_key(user.id, "conformance", Vec::new()).await?;
+            let scopes = vec![Scope::RateMultiplier(0.5)];
+            let scoped = m.set_key_scopes(user.id, key.id, scopes.clone()).await?;
+            anyhow::ensure!(scoped.scopes == scopes, "key scoped {:?}", scoped.scopes);
+            match m.set_key_scopes(user.id, key.id, vec![Scope::RateMultiplier(0.0)]).await {
+                Err(e) if e.is::<ScopeError>() => {}
+                Err(e) => anyhow::bail!("refused invalid scopes with {}, not ScopeError", e),
+                Ok(_) => anyhow::bail!("scoped a key to no rate"),
+            }
+            let found = m.get_user_by_api_key(&user.api_key).await?.context("user not found by API key")?;
+            let held = found.keys.iter().find(|held| held.id == key.id).map(|held| held.scopes.clone());
+            anyhow::ensure!(held == Some(scopes), "refused scopes replaced those held");
+            anyhow::ensure!(
+                m.set_key_scopes(user.id, Uuid::new_v4(), Vec::new()).await.is_err(),
+                "scoped a key that doesn't exist"
+            );
+            Ok(())
+        })
+        .await;
+    
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managers::nodes::StoredNodeManager;
+    use crate::managers::providers::StoredRpcManager;
+    use crate::managers::users::StoredUserManager;
+    use crate::scopes::Scope;
+    use crate::storage::Storage;
+    use async_trait::async_trait;
+    
+    /// Run every suite against the stored managers over `storage`
+    async fn assert_stored_managers_pass(storage: Arc<dyn Storage + Send + Sync>) {
+        node_manager(Arc::new(StoredNodeManager::new(storage.clone()))).await.assert_passed();
+        rpc_manager(Arc::new(StoredRpcManager::new(storage.clone()))).await.assert_passed();
+        user_manager(Arc::new(StoredUserManager::new(storage))).await.assert_passed();
+    }
+    
+    #[tokio::test]
+    async fn stored_managers_pass_in_memory() {
+        assert_stored_managers_pass(Arc::new(crate::storage::MemoryStorage::new())).await;
+    }
+    
+    #[cfg(feature = "sled")]
+    #[tokio::test]
+    async fn stored_managers_pass_over_sled() {
+        let path = std::env::temp_dir().join(format!("darknode-conformance-{}", Uuid::new_v4()));
+        let storage = crate::storage::sled::SledStorage::open(&path).unwrap();
+        assert_stored_managers_pass(Arc::new(storage)).await;
+        let _ = std::fs::remove_dir_all(&path);
+    }
+    
+    /// Runs against the database at `DARKNODE_TEST_POSTGRES_URL`, if one is given
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    async fn stored_managers_pass_over_postgres() {
+        let Ok(url) = std::env::var("DARKNODE_TEST_POSTGRES_URL") else {
+            return;
+        };
+        let storage = crate::storage::postgres::PostgresStorage::connect(&url, 4).await.unwrap();
+        assert_stored_managers_pass(Arc::new(storage)).await;
+    }
+    
+    /// A user manager adding mappings of unknown users as if they existed, as mocks have
+    struct SilentOnUnknownUser(StoredUserManager);
+    
+    #[async_trait]
+    impl UserManager for SilentOnUnknownUser {
+        async fn create_user(&self, wallet_address: &str) -> Result<User> {
+            self.0.create_user(wallet_address).await
+        }
+        
+        async fn get_user(&self, user_id: Uuid) -> Result<Option<User>> {
+            self.0.get_user(user_id).await
+        }
+        
+        async fn get_user_by_api_key(&self, api_key: &str) -> Result<Option<User>> {
+            self.0.get_user_by_api_key(api_key).await
+        }
+        
+        async fn get_user_by_wallet(&self, wallet_address: &str) -> Result<Option<User>> {
+            self.0.get_user_by_wallet(wallet_address).await
+        }
+        
+        async fn add_rpc_mapping(&self, user_id: Uuid, mapping: RpcMapping) -> Result<()> {
+            if self.0.get_user(user_id).await?.is_none() {
+                return Ok(());
+            }
+            self.0.add_rpc_mapping(user_id, mapping).await
+        }
+        
+        async fn add_rpc_mappings(&self, user_id: Uuid, mappings: Vec<RpcMapping>, plan: &Plan) -> Result<()> {
+            self.0.add_rpc_mappings(user_id, mappings, plan).await
+        }
+        
+        async fn get_rpc_mappings(&self, user_id: Uuid) -> Result<Vec<RpcMapping>> {
+            self.0.get_rpc_mappings(user_id).await
+        }
+        
+        async fn remove_rpc_mapping(&self, user_id: Uuid, mapping_id: Uuid) -> Result<()> {
+            self.0.remove_rpc_mapping(user_id, mapping_id).await
+        }
+        
+        async fn set_method_routes(&self, user_id: Uuid, mapping_id: Uuid, routes: MethodRoutes) -> Result<()> {
+            self.0.set_method_routes(user_id, mapping_id, routes).await
+        }
+        
+        async fn create_plan(&self, plan: Plan) -> Result<()> {
+            self.0.create_plan(plan).await
+        }
+        
+        async fn get_plan(&self, plan_id: Uuid) -> Result<Option<Plan>> {
+            self.0.get_plan(plan_id).await
+        }
+        
+        async fn set_user_plan(&self, user_id: Uuid, plan_id: Uuid) -> Result<()> {
+            self.0.set_user_plan(user_id, plan_id).await
+        }
+        
+        async fn set_audit_consent(&self, user_id: Uuid, consent: bool) -> Result<()> {
+            self.0.set_audit_consent(user_id, consent).await
+        }
+        
+        async fn create_key(&self, user_id: Uuid, name: &str, scopes: Vec<Scope>) -> Result<ApiKey> {
+            self.0.create_key(user_id, name, scopes).await
+        }
+        
+        async fn set_key_scopes(&self, user_id: Uuid, key_id: Uuid, scopes: Vec<Scope>) -> Result<ApiKey> {
+            self.0.set_key_scopes(user_id, key_id, scopes).await
+        }
+    }
+    
+    #[tokio::test]
+    async fn a_manager_silently_adding_mappings_of_unknown_users_fails_only_that_check() {
+        let storage = Arc::new(crate::storage::MemoryStorage::new());
+        let report = user_manager(Arc::new(SilentOnUnknownUser(StoredUserManager::new(storage)))).await;
+        let failed: Vec<&str> = report.failures().map(|result| result.check).collect();
+        assert_eq!(failed, vec!["add_rpc_mapping fails for an unknown user"], "{}", report);
+    }
+}
@@ -0,0 +1,457 @@
+//! Per-request options, carried together from the edge to the exit node
+//!
+//! Everything that shapes how one request is served travels in a [`RequestContext`]: who
+//! sent it and through which mapping, how long it may take, its priority, the constraints
+//! on the providers serving it, its consistency mode, and the token it is audited under.
+//! The HTTP layer builds the context from the API key, headers, and body; the entry node
+//! fills in what the user's plan and mapping default to; and only the options the exit
+//! node acts on are copied into the encrypted payload. The user, API key, and priority
+//! never leave the entry node.
+
+use super::*;
+use super::anonymity::ClientTraces;
+use super::chains::{Chain, Network};
+use super::circuit_class::CircuitClass;
+use super::clock::Deadline;
+use super::receipts::RECEIPT_HEADER;
+use super::shaping::ShapingConfig;
+use super::signing::{RequestSignature, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use super::timeouts::{MethodClass, TimeoutConfig};
+use super::timing::{self, TIMING_HEADER};
+use super::types::{ExitPayload, Plan, PriorityClass, RpcMapping, User};
+use axum::http::HeaderMap;
+
+/// Header carrying the budget the client gives a request, in milliseconds
+pub const TIMEOUT_HEADER: &str = "x-darknode-timeout-ms";
+
+/// Header asking for a lower priority than the user's plan grants
+pub const PRIORITY_HEADER: &str = "x-darknode-priority";
+
+/// Header asking for a consistency mode other than the mapping's, `single` or `quorum:<n>`
+pub const CONSISTENCY_HEADER: &str = "x-darknode-consistency";
+
+/// Header pinning the request's circuit to exit nodes in one region
+pub const REGION_HEADER: &str = "x-darknode-region";
+
+/// Longest region name the region header is taken with
+const MAX_REGION_LEN: usize = 64;
+
+/// How many providers must agree on a read
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Consistency {
+    /// Any one provider's answer is accepted
+    #[default]
+    Single,
+    /// This many providers must agree on read-only responses
+    Quorum(u8),
+}
+
+impl Consistency {
+    /// The quorum the exit node enforces, if any
+    pub fn quorum(self) -> Option<u8> {
+        match self {
+            Consistency::Single => None,
+            Consistency::Quorum(size) => Some(size),
+        }
+    }
+    
+    /// Parse a header value, `single` or `quorum:<n>` with at least two providers
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "single" => Some(Consistency::Single),
+            value => value
+                .strip_prefix("quorum:")
+                .and_then(|size| size.parse().ok())
+                .filter(|size| *size >= 2)
+                .map(Consistency::Quorum),
+        }
+    }
+}
+
+impl std::fmt::Display for Consistency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Consistency::Single => write!(f, "single"),
+            Consistency::Quorum(size) => write!(f, "quorum:{}", size),
+        }
+    }
+}
+
+/// Requirements on the nodes and providers serving a request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Constraints {
+    /// Capabilities the serving provider must support
+    pub capabilities: Vec<String>,
+    /// Provider pool the circuit's exit node should serve from
+    pub exit_pool: Option<String>,
+    /// Region the circuit's exit node must be in
+    pub exit_region: Option<String>,
+    /// The chain the serving provider must be on
+    pub chain: Option<Chain>,
+    /// The network of its chain the serving provider must be on
+    pub network: Network,
+}
+
+/// A per-request header that couldn't be understood
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("invalid {header} header {value:?}")]
+pub struct InvalidContextHeader {
+    /// The header
+    pub header: &'static str,
+    /// Its value
+    pub value: String,
+}
+
+/// Everything that shapes how one request is served
+///
+/// Options left unset are inferred when the entry node resolves the context against the
+/// user's plan and mapping.
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    /// The API key the request was sent with
+    pub api_key: String,
+    /// The user's mapping the request was sent to, if known
+    pub mapping_id: Option<Uuid>,
+    /// The user, once resolved
+    pub user: Option<User>,
+    /// The mapping's settings, once resolved
+    pub mapping: Option<RpcMapping>,
+    /// Budget the client gave the request; the method class budget applies when unset or shorter
+    pub timeout: Option<Duration>,
+    /// When the request must be answered, once its budget is known
+    pub deadline: Option<Deadline>,
+    /// Priority the request is admitted under; never above the plan's once resolved
+    pub priority: Option<PriorityClass>,
+    /// The token the exit node audits the request under
+    pub trace_token: Option<String>,
+    /// Requirements on the nodes and providers serving the request
+    pub constraints: Constraints,
+    /// How many providers must agree; the mapping's mode applies when unset
+    pub consistency: Option<Consistency>,
+    /// Whether transactions are simulated before they are broadcast
+    pub preflight: bool,
+    /// Whether a transaction is relayed until it confirms
+    pub relay: bool,
+    /// Whether normalized provider errors keep the provider's original error
+    pub debug_errors: bool,
+    /// Whether params are passed through without schema validation
+    pub skip_validation: bool,
+    /// Whether results are projected onto one shape whichever provider answered
+    pub normalize: bool,
+    /// The wallet signature the client sent, see [`crate::signing`]
+    pub signature: Option<RequestSignature>,
+    /// Whether the request is a notification; routers keep nothing to correlate an answer with
+    pub notification: bool,
+    /// Whether the client wants a signed receipt with the response, see [`crate::receipts`]
+    pub receipt: bool,
+    /// Whether the client wants a breakdown of where the request's time went, see [`crate::timing`]
+    pub timing: bool,
+    /// Whether the exit node attests the class of provider that answered, see [`crate::attribution`]
+    pub attribution: bool,
+    /// What the client's connection revealed that the request mustn't carry, see [`crate::anonymity`]
+    pub client_traces: ClientTraces,
+    /// The class of circuit the request is sent on, see [`crate::circuit_class`]
+    pub circuit_class: CircuitClass,
+}
+
+impl RequestContext {
+    /// A context for a request sent with `api_key`, with every option left to defaults
+    pub fn new(api_key: impl Into<String>) -> Self {
+        Self {
+            api_key: api_key.into(),
+            ..Self::default()
+        }
+    }
+    
+    /// Check the request against what its client's connection revealed, see [`crate::anonymity`]
+    pub fn with_client_traces(mut self, traces: ClientTraces) -> Self {
+        self.client_traces = traces;
+        self
+    }
+    
+    /// Send the request to one of the user's mappings
+    pub fn with_mapping(mut self, mapping_id: Option<Uuid>) -> Self {
+        self.mapping_id = mapping_id;
+        self
+    }
+    
+    /// Give the request a budget shorter than its method class's
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    
+    /// Ask for a priority, which is capped at the plan's
+    pub fn with_priority(mut self, priority: PriorityClass) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+    
+    /// Audit the request under `trace_token` instead of a fresh one
+    pub fn with_trace_token(mut self, trace_token: impl Into<String>) -> Self {
+        self.trace_token = Some(trace_token.into());
+        self
+    }
+    
+    /// Require capabilities of the serving provider
+    pub fn with_capabilities(mut self, capabilities: Vec<String>) -> Self {
+        self.constraints.capabilities.extend(capabilities);
+        self
+    }
+    
+    /// Prefer exit nodes serving from `pool` over the mapping's pool
+    pub fn with_exit_pool(mut self, pool: impl Into<String>) -> Self {
+        self.constraints.exit_pool = Some(pool.into());
+        self
+    }
+    
+    /// Use `consistency` instead of the mapping's mode
+    pub fn with_consistency(mut self, consistency: Consistency) -> Self {
+        self.consistency = Some(consistency);
+        self
+    }
+    
+    /// Relay a transaction until it confirms
+    pub fn with_relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+    
+    /// Attach the wallet signature the client sent with the request
+    pub fn with_signature(mut self, signature: RequestSignature) -> Self {
+        self.signature = Some(signature);
+        self
+    }
+    
+    /// Send the request on a circuit of `class`
+    pub fn with_circuit_class(mut self, class: CircuitClass) -> Self {
+        self.circuit_class = class;
+        self
+    }
+    
+    /// Apply the per-request headers the client sent
+    pub fn with_headers(mut self, headers: &HeaderMap) -> Result<Self, InvalidContextHeader> {
+        if let Some(value) = header(headers, TIMEOUT_HEADER)? {
+            let millis: u64 = value.parse().map_err(|_| invalid(TIMEOUT_HEADER, &value))?;
+            self = self.with_timeout(Duration::from_millis(millis));
+        }
+        if let Some(value) = header(headers, PRIORITY_HEADER)? {
+            let priority = match value.as_str() {
+                "low" => PriorityClass::Low,
+                "standard" => PriorityClass::Standard,
+                "high" => PriorityClass::High,
+                _ => return Err(invalid(PRIORITY_HEADER, &value)),
+            };
+            self = self.with_priority(priority);
+        }
+        if let Some(value) = header(headers, CONSISTENCY_HEADER)? {
+            let consistency = Consistency::parse(&value).ok_or_else(|| invalid(CONSISTENCY_HEADER, &value))?;
+            self = self.with_consistency(consistency);
+        }
+        if let Some(value) = header(headers, REGION_HEADER)? {
+            let valid = !value.is_empty()
+                && value.len() <= MAX_REGION_LEN
+                && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                return Err(invalid(REGION_HEADER, &value));
+            }
+            self.constraints.exit_region = Some(value);
+        }
+        // Signature headers are taken as they come: only mappings requiring signatures check
+        // them, and the rest must not refuse requests over them
+        if let Some(signature) = lossy_header(headers, SIGNATURE_HEADER) {
+            self = self.with_signature(RequestSignature {
+                signature,
+                timestamp: lossy_header(headers, TIMESTAMP_HEADER).unwrap_or_default(),
+                nonce: lossy_header(headers, NONCE_HEADER).unwrap_or_default(),
+            });
+        }
+        if let Some(value) = header(headers, RECEIPT_HEADER)? {
+            self.receipt = value.parse().map_err(|_| invalid(RECEIPT_HEADER, &value))?;
+        }
+        if let Some(value) = header(headers, TIMING_HEADER)? {
+            self.timing = timing::parse_header(&value).ok_or_else(|| invalid(TIMING_HEADER, &value))?;
+        }
+        Ok(self)
+    }
+    
+    /// Fill in what the user's plan and mapping default to
+    ///
+    /// Options the client set explicitly are kept, except that priority is capped at the
+    /// plan's, and so is the quorum: a plan allowing less than two providers to agree gets
+    /// single-provider answers.
+    pub fn resolve(&mut self, user: User, plan: &Plan) {
+        let mapping = user
+            .rpc_mappings
+            .iter()
+            .find(|mapping| Some(mapping.id) == self.mapping_id)
+            .cloned();
+        if let Some(mapping) = &mapping {
+            if self.consistency.is_none() {
+                self.consistency = mapping.quorum.map(Consistency::Quorum);
+            }
+            if self.constraints.exit_pool.is_none() {
+                self.constraints.exit_pool = mapping.pool.clone();
+            }
+            if self.constraints.exit_region.is_none() {
+                self.constraints.exit_region = mapping.region.clone();
+            }
+            self.constraints.network = mapping.network;
+            self.preflight |= mapping.preflight;
+            self.debug_errors |= mapping.debug_errors;
+            self.skip_validation |= mapping.skip_validation;
+            self.normalize |= mapping.normalize_results;
+            self.attribution |= mapping.provider_attribution;
+        }
+        self.consistency = Some(match self.consistency.unwrap_or_default() {
+            Consistency::Quorum(_) if plan.max_quorum < 2 => Consistency::Single,
+            Consistency::Quorum(size) => Consistency::Quorum(size.min(plan.max_quorum)),
+            Consistency::Single => Consistency::Single,
+        });
+        self.priority = Some(self.priority.map_or(plan.priority_class, |asked| asked.min(plan.priority_class)));
+        self.trace_token
+            .get_or_insert_with(|| Uuid::new_v4().simple().to_string());
+        self.mapping = mapping;
+        self.user = Some(user);
+    }
+    
+    /// Whether the request comes from the network's canary
+    pub fn is_canary(&self) -> bool {
+        self.user.as_ref().map_or(false, |user| user.canary)
+    }
+    
+    /// The request's end-to-end budget for a method of `class`
+    pub fn budget(&self, timeouts: &TimeoutConfig, class: MethodClass) -> Duration {
+        let budget = timeouts.budget(class, self.mapping.as_ref().map(|mapping| &mapping.timeouts));
+        self.timeout.map_or(budget, |timeout| timeout.min(budget))
+    }
+    
+    /// Take the options the client put in the request body into the context
+    pub fn absorb(&mut self, payload: &mut ExitPayload) {
+        self.constraints
+            .capabilities
+            .append(&mut payload.capabilities);
+        self.relay |= payload.relay;
+        self.notification |= payload.notification;
+    }
+    
+    /// Copy the options the exit node acts on into its payload
+    ///
+    /// `hops` is the number of hops between this node and the exit node. The exit node's
+    /// budget also leaves room for the delay `shaping` adds at both ends.
+    pub fn seal(&self, payload: &mut ExitPayload, timeouts: &TimeoutConfig, hops: usize, shaping: &ShapingConfig) {
+        payload.quorum = self.consistency.and_then(Consistency::quorum);
+        payload.capabilities = self.constraints.capabilities.clone();
+        payload.trace_token = self.trace_token.clone();
+        payload.preflight = self.preflight;
+        payload.relay = self.relay;
+        payload.debug_errors = self.debug_errors;
+        payload.notification = self.notification;
+        payload.chain = self.constraints.chain;
+        payload.network = self.constraints.network;
+        payload.normalize = self.normalize;
+        payload.timing = self.timing;
+        payload.attribution = self.attribution;
+        payload.timeout = self
+            .deadline
+            .map(|deadline| {
+                timeouts
+                    .upstream_budget(deadline.remaining(), hops)
+                    .saturating_sub(shaping.round_trip_delay())
+            });
+    }
+}
+
+/// The value of a header, if present
+fn header(headers: &HeaderMap, name: &'static str) -> Result<Option<String>, InvalidContextHeader> {
+    headers
+        .get(name)
+        .map(|value| {
+            value
+                .to_str()
+                .map(str::to_string)
+                .map_err(|_| invalid(name, &String::from_utf8_lossy(value.as_bytes())))
+        })
+        .transpose()
+}
+
+/// The value of a header, if present, whatever bytes it holds
+fn lossy_header(headers: &HeaderMap, name: &'static str) -> Option<String> {
+    headers
+        .get(name)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+}
+
+/// The error for a header value that can't be understood
+fn invalid(header: &'static str, value: &str) -> InvalidContextHeader {
+    InvalidContextHeader {
+        header,
+        value: value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallets::WalletChain;
+    
+    fn user() -> User {
+        User {
+            id: Uuid::new_v4(),
+            wallet_address: "4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T".to_string(),
+            wallet_chain: WalletChain::Solana,
+            api_key: "api-key".to_string(),
+            keys: Vec::new(),
+            active: true,
+            expires_at: None,
+            rpc_mappings: Vec::new(),
+            plan_id: None,
+            canary: false,
+            audit_consent: false,
+        }
+    }
+    
+    fn asking(consistency: &str) -> RequestContext {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONSISTENCY_HEADER, consistency.parse().unwrap());
+        RequestContext::new("api-key").with_headers(&headers).unwrap()
+    }
+    
+    #[test]
+    fn a_quorum_larger_than_the_plan_allows_is_capped_at_its_maximum() {
+        let plan = Plan {
+            max_quorum: 3,
+            ..Plan::default()
+        };
+        let mut ctx = asking("quorum:9");
+        ctx.resolve(user(), &plan);
+        assert_eq!(ctx.consistency, Some(Consistency::Quorum(3)));
+        
+        let mut ctx = asking("quorum:2");
+        ctx.resolve(user(), &plan);
+        assert_eq!(ctx.consistency, Some(Consistency::Quorum(2)));
+    }
+    
+    #[test]
+    fn malformed_signature_headers_are_left_for_mappings_requiring_signatures() {
+        let mut headers = HeaderMap::new();
+        headers.insert(SIGNATURE_HEADER, "not-a-signature".parse().unwrap());
+        headers.insert(TIMESTAMP_HEADER, "yesterday".parse().unwrap());
+        let ctx = RequestContext::new("api-key").with_headers(&headers).unwrap();
+        let signature = ctx.signature.unwrap();
+        assert_eq!(signature.timestamp, "yesterday");
+        assert_eq!(signature.nonce, "");
+    }
+    
+    #[test]
+    fn plans_without_quorums_answer_from_a_single_provider() {
+        let plan = Plan {
+            max_quorum: 1,
+            ..Plan::default()
+        };
+        let mut ctx = asking("quorum:5");
+        ctx.resolve(user(), &plan);
+        assert_eq!(ctx.consistency, Some(Consistency::Single));
+    }
+}
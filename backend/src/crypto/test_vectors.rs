@@ -0,0 +1,244 @@
+//! Pinned inputs and outputs of every crypto operation
+//!
+//! Deployed nodes only interoperate while the wire format stays the same, so each
+//! operation is pinned here with fixed keys, plaintexts, and randomness. [`verify`] fails
+//! naming the vector and field that changed; a deliberate format change must update
+//! these vectors and is a breaking change for the network.
+//!
+//! Byte strings are standard base64.
+
+use super::*;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+/// Deterministic randomness yielding consecutive byte values from a starting byte
+///
+/// Predictable by design, never use it outside of reproducing vectors.
+#[derive(Debug, Clone)]
+pub struct SequenceRng(u8);
+
+impl SequenceRng {
+    /// Randomness whose first byte is `start`
+    pub fn new(start: u8) -> Self {
+        Self(start)
+    }
+}
+
+impl rand::RngCore for SequenceRng {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+    
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+    
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for byte in dest {
+            *byte = self.0;
+            self.0 = self.0.wrapping_add(1);
+        }
+    }
+    
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> std::result::Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl rand::CryptoRng for SequenceRng {}
+
+/// A key pair generated from pinned randomness
+pub struct KeypairVector {
+    /// Name reported on mismatch
+    pub name: &'static str,
+    /// First byte of the [`SequenceRng`] the key pair is generated from
+    pub rng_start: u8,
+    /// Expected public key
+    pub public_key: &'static str,
+    /// Expected private key
+    pub private_key: &'static str,
+}
+
+/// An encryption with pinned randomness
+pub struct EncryptionVector {
+    /// Name reported on mismatch
+    pub name: &'static str,
+    /// Key the plaintext is encrypted under
+    pub key: &'static str,
+    /// The plaintext
+    pub plaintext: &'static [u8],
+    /// First byte of the [`SequenceRng`] the nonce is drawn from
+    pub rng_start: u8,
+    /// Expected nonce, 12 bytes
+    pub nonce: &'static str,
+    /// Expected ciphertext, the plaintext length plus a 16 byte tag
+    pub ciphertext: &'static str,
+}
+
+/// A signature, which Ed25519 makes deterministic without pinned randomness
+pub struct SignatureVector {
+    /// Name reported on mismatch
+    pub name: &'static str,
+    /// Private key the data is signed with
+    pub private_key: &'static str,
+    /// Public key the signature verifies under
+    pub public_key: &'static str,
+    /// The signed data
+    pub data: &'static [u8],
+    /// Expected signature, 64 bytes
+    pub signature: &'static str,
+}
+
+/// Key pairs the current release must generate
+pub const KEYPAIRS: &[KeypairVector] = &[
+    KeypairVector {
+        name: "keypair/sequence-0",
+        rng_start: 0,
+        public_key: "A6EHv/POEL4dcN0Y50vAmWfk1jCbpQ1fHdyGZBJVMbg=",
+        private_key: "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=",
+    },
+    KeypairVector {
+        name: "keypair/sequence-128",
+        rng_start: 128,
+        public_key: "zRSzf5VulTGU/3+3Oz2B3MVh1hp1OAlLfD4aZD7l86o=",
+        private_key: "gIGCg4SFhoeIiYqLjI2Oj5CRkpOUlZaXmJmam5ydnp8=",
+    },
+];
+
+/// Encryptions the current release must produce byte for byte
+pub const ENCRYPTIONS: &[EncryptionVector] = &[
+    EncryptionVector {
+        name: "encrypt/empty",
+        key: "A6EHv/POEL4dcN0Y50vAmWfk1jCbpQ1fHdyGZBJVMbg=",
+        plaintext: b"",
+        rng_start: 0,
+        nonce: "AAECAwQFBgcICQoL",
+        ciphertext: "DHDoo8OltbpC2VFL6u4CZg==",
+    },
+    EncryptionVector {
+        name: "encrypt/json-rpc",
+        key: "A6EHv/POEL4dcN0Y50vAmWfk1jCbpQ1fHdyGZBJVMbg=",
+        plaintext: br#"{"jsonrpc":"2.0","id":1,"method":"getSlot"}"#,
+        rng_start: 12,
+        nonce: "DA0ODxAREhMUFRYX",
+        ciphertext: "Fqb77sQeKsCt0OAdJUky2ZzEze0VcrFGGDQuciZ1Lcp8SgjbCz2+Vtd3hfqwGCT0tAgQtqY8B83pvyQ=",
+    },
+];
+
+/// Ciphertexts produced by the previous release that the current release must still decrypt
+pub const LEGACY_DECRYPTIONS: &[EncryptionVector] = &[
+    EncryptionVector {
+        name: "decrypt/0.1.0-getbalance",
+        key: "gIGCg4SFhoeIiYqLjI2Oj5CRkpOUlZaXmJmam5ydnp8=",
+        plaintext: br#"{"jsonrpc":"2.0","id":7,"method":"getBalance","params":["11111111111111111111111111111111"]}"#,
+        rng_start: 200,
+        nonce: "yMnKy8zNzs/Q0dLT",
+        ciphertext: "tCUQbbLHUJly0HwaNcmwHPZTFi/THufGa25VLAyrGjlbiFUDhh5CRX5090VgB9NenPRRScF9RYPIDdF7zQNy0wxD5TCaKKlGmWlabEYKxJQBOTPbWakTOBUI/GGfH7G3oFsItq4JmFAmeNYG",
+    },
+];
+
+/// Signatures the current release must produce and accept
+pub const SIGNATURES: &[SignatureVector] = &[
+    SignatureVector {
+        name: "sign/heartbeat",
+        private_key: "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=",
+        public_key: "A6EHv/POEL4dcN0Y50vAmWfk1jCbpQ1fHdyGZBJVMbg=",
+        data: b"darknode heartbeat",
+        signature: "YO+ugLkUWm2uMIkx5n3pxPP9azfP9C/9Gew+c3CbCMUoPVkmhO9pZxJ2HiC7NuZurS2iZG3izN+4gmTjNH6dDw==",
+    },
+];
+
+/// Decode a vector field
+fn decode(vector: &str, field: &str, value: &str) -> Result<Vec<u8>> {
+    STANDARD
+        .decode(value)
+        .map_err(|e| anyhow::anyhow!("vector {}: field {} is not base64: {}", vector, field, e))
+}
+
+/// Fail if `actual` differs from the expected field value
+fn expect(vector: &str, field: &str, expected: &str, actual: &[u8]) -> Result<()> {
+    if decode(vector, field, expected)? != actual {
+        anyhow::bail!(
+            "vector {}: {} changed, expected {} but got {}; the crypto wire format is no longer compatible",
+            vector,
+            field,
+            expected,
+            STANDARD.encode(actual)
+        );
+    }
+    Ok(())
+}
+
+/// Check every operation of [`CryptoImpl`] against the pinned vectors
+pub async fn verify() -> Result<()> {
+    for vector in KEYPAIRS {
+        let crypto = CryptoImpl::with_rng(SequenceRng::new(vector.rng_start));
+        let (public_key, private_key) = crypto.generate_keypair().await?;
+        expect(vector.name, "public_key", vector.public_key, &public_key.0)?;
+        expect(vector.name, "private_key", vector.private_key, &private_key.0)?;
+    }
+    
+    for vector in ENCRYPTIONS {
+        let crypto = CryptoImpl::with_rng(SequenceRng::new(vector.rng_start));
+        let key = CryptoKey(decode(vector.name, "key", vector.key)?);
+        let encrypted = crypto.encrypt(vector.plaintext, &key).await?;
+        expect(vector.name, "nonce", vector.nonce, &encrypted.nonce)?;
+        expect(vector.name, "ciphertext", vector.ciphertext, &encrypted.data)?;
+    }
+    
+    let crypto = CryptoImpl::new();
+    for vector in LEGACY_DECRYPTIONS {
+        let key = CryptoKey(decode(vector.name, "key", vector.key)?);
+        let encrypted = EncryptedData {
+            data: decode(vector.name, "ciphertext", vector.ciphertext)?,
+            nonce: decode(vector.name, "nonce", vector.nonce)?,
+            aad: None,
+        };
+        let plaintext = crypto
+            .decrypt(&encrypted, &key)
+            .await
+            .map_err(|e| anyhow::anyhow!("vector {}: no longer decrypts: {}", vector.name, e))?;
+        if plaintext != vector.plaintext {
+            anyhow::bail!("vector {}: decrypts to a different plaintext", vector.name);
+        }
+    }
+    
+    for vector in SIGNATURES {
+        let private_key = CryptoKey(decode(vector.name, "private_key", vector.private_key)?);
+        let public_key = CryptoKey(decode(vector.name, "public_key", vector.public_key)?);
+        let signature = crypto.sign(vector.data, &private_key).await?;
+        expect(vector.name, "signature", vector.signature, &signature)?;
+        if !crypto.verify(vector.data, &signature, &public_key).await? {
+            anyhow::bail!("vector {}: signature no longer verifies", vector.name);
+        }
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn the_wire_format_matches_the_vectors() {
+        verify().await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn a_changed_format_names_the_vector_and_field() {
+        let crypto = CryptoImpl::with_rng(SequenceRng::new(1));
+        let vector = &ENCRYPTIONS[0];
+        let encrypted = crypto
+            .encrypt(vector.plaintext, &CryptoKey(decode(vector.name, "key", vector.key).unwrap()))
+            .await
+            .unwrap();
+        let err = expect(vector.name, "nonce", vector.nonce, &encrypted.nonce).unwrap_err().to_string();
+        assert!(err.contains("encrypt/empty") && err.contains("nonce changed"), "{}", err);
+    }
+}
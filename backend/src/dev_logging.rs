@@ -0,0 +1,66 @@
+//! Verbose request and response logging, for developing against a node locally
+//!
+//! Node logs are redacted so they never reveal what users asked for, which also hides
+//! everything a developer debugging a request needs to see. This module only exists in
+//! builds with the `dev-logging` feature, so release builds can't be switched into logging
+//! bodies by configuration alone. Even then it must be asked for at startup, which logs a
+//! warning that can't be missed.
+//!
+//! [`log_bodies`] logs every request's method, path, and body, and its response's status
+//! and body, pretty-printed when they are JSON, at debug level. Bodies are logged as they
+//! cross the outermost layer, so a compressed body is only logged by its size, and streamed
+//! responses are passed through without their bodies being logged.
+
+use axum::body::{Body, Bytes, Full, HttpBody};
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+/// Target the bodies are logged under
+const TARGET: &str = "darknode::dev";
+
+/// Log that verbose logging is on, loudly enough that it isn't left on by accident
+pub fn warn_enabled() {
+    tracing::warn!(target: TARGET, "************************************************************");
+    tracing::warn!(target: TARGET, "DEV VERBOSE LOGGING IS ON: full request and response bodies,");
+    tracing::warn!(target: TARGET, "including API keys and user data, are written to the logs.");
+    tracing::warn!(target: TARGET, "Never run this build in production.");
+    tracing::warn!(target: TARGET, "************************************************************");
+}
+
+/// Middleware logging the bodies of each request and its response
+///
+/// Install with `axum::middleware::from_fn(log_bodies)` as the outermost layer.
+pub async fn log_bodies(request: Request<Body>, next: Next<Body>) -> Response {
+    let (parts, body) = request.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    let route = format!("{} {}", parts.method, parts.uri.path());
+    tracing::debug!(target: TARGET, "{} request:\n{}", route, pretty(&body));
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    
+    if response.body().size_hint().exact().is_none() {
+        tracing::debug!(target: TARGET, "{} response {}: streamed, body not logged", route, response.status());
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    tracing::debug!(target: TARGET, "{} response {}:\n{}", route, parts.status, pretty(&body));
+    Response::from_parts(parts, axum::body::boxed(Full::new(body)))
+}
+
+/// A body as pretty-printed JSON, or a note of its size if it isn't JSON
+fn pretty(body: &Bytes) -> String {
+    if body.is_empty() {
+        return "<empty>".to_string();
+    }
+    serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|value| serde_json::to_string_pretty(&value).ok())
+        .unwrap_or_else(|| format!("<{} bytes, not JSON>", body.len()))
+}
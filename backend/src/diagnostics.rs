@@ -0,0 +1,137 @@
+//! Classification and history of failed circuit builds
+
+use super::*;
+use super::types::*;
+use std::collections::{BTreeMap, VecDeque};
+
+/// The stage at which a circuit build failed
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum CircuitBuildFailure {
+    /// No nodes of a role were available
+    NoAvailableNodes {
+        /// The role with no available nodes
+        role: NodeRole,
+    },
+    /// Nodes were available but none satisfied a path constraint
+    ConstraintUnsatisfiable {
+        /// The constraint that could not be met (e.g. "region=eu-west")
+        constraint: String,
+        /// Nodes considered before filtering
+        candidates: usize,
+    },
+    /// A hop did not complete its handshake
+    HandshakeFailed {
+        /// Position of the hop in the circuit, entry first
+        hop: usize,
+        /// The node at that hop
+        node_id: NodeId,
+        /// How long the handshake ran before failing
+        latency: Duration,
+        /// Why the handshake failed
+        error: String,
+    },
+    /// Any other failure
+    Other {
+        /// The underlying error
+        error: String,
+    },
+}
+
+impl CircuitBuildFailure {
+    /// Short label used for metrics
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::NoAvailableNodes { .. } => "no_available_nodes",
+            Self::ConstraintUnsatisfiable { .. } => "constraint_unsatisfiable",
+            Self::HandshakeFailed { .. } => "handshake_failed",
+            Self::Other { .. } => "other",
+        }
+    }
+}
+
+/// A circuit build error carrying the detail needed for a failure report
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("circuit build failed: {}", failure.reason())]
+pub struct CircuitBuildError {
+    /// Where the build failed
+    pub failure: CircuitBuildFailure,
+    /// Available nodes per role seen before the failure
+    pub available: BTreeMap<String, usize>,
+    /// Whether the request's own constraints, such as its exit region or subset, left too
+    /// few nodes where the network as a whole had enough
+    pub narrowed: bool,
+}
+
+/// The generic error returned to clients when no circuit could be built
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("no circuit is currently available")]
+pub struct CircuitUnavailable;
+
+/// An operator-facing record of one failed circuit build
+#[derive(Debug, Clone, Serialize)]
+pub struct CircuitBuildReport {
+    /// When the build failed
+    pub failed_at: Timestamp,
+    /// How long the build ran before failing
+    pub elapsed: Duration,
+    /// Where the build failed
+    pub failure: CircuitBuildFailure,
+    /// Available nodes per role seen before the failure
+    pub available: BTreeMap<String, usize>,
+}
+
+impl CircuitBuildReport {
+    /// Build a report from a `create_circuit` error
+    pub fn from_error(err: &anyhow::Error, elapsed: Duration) -> Self {
+        let (failure, available) = match err.downcast_ref::<CircuitBuildError>() {
+            Some(build) => (build.failure.clone(), build.available.clone()),
+            None => (
+                CircuitBuildFailure::Other {
+                    error: err.to_string(),
+                },
+                BTreeMap::new(),
+            ),
+        };
+        Self {
+            failed_at: Timestamp::now(),
+            elapsed,
+            failure,
+            available,
+        }
+    }
+}
+
+/// A bounded history of circuit build failures, oldest dropped first
+pub struct FailureLog {
+    capacity: usize,
+    reports: parking_lot::Mutex<VecDeque<CircuitBuildReport>>,
+}
+
+impl FailureLog {
+    /// Create a log keeping at most `capacity` reports
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            reports: parking_lot::Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+    
+    /// Store a report and count it by failure reason
+    pub fn record(&self, report: CircuitBuildReport) {
+        metrics::increment_counter!(
+            "darknode_circuit_build_failures_total",
+            "reason" => report.failure.reason()
+        );
+        let mut reports = self.reports.lock();
+        if reports.len() == self.capacity {
+            reports.pop_front();
+        }
+        reports.push_back(report);
+    }
+    
+    /// Stored reports, newest first
+    pub fn recent(&self) -> Vec<CircuitBuildReport> {
+        self.reports.lock().iter().rev().cloned().collect()
+    }
+}
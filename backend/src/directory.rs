@@ -0,0 +1,634 @@
+//! Signed directories of the network, published ahead of the epoch they serve
+//!
+//! For each epoch the coordinator publishes a [`Directory`]: the epoch and the nodes
+//! available in it, signed with the coordinator's key. Were nodes to fetch it only once the
+//! epoch had started, every entry node would ask at the same moment, and pick exits from a
+//! stale view until answered. Instead the next epoch's directory is published
+//! [`DirectoryConfig::lead_time`] before the epoch starts, with the epoch's start as its
+//! `valid_from`, and served alongside the current one as `GET /directory?which=next`. Nodes
+//! fetch it whenever suits them during the lead window and switch to it on their own clock
+//! at `valid_from`, fetching nothing at the boundary.
+//!
+//! A published directory is served unchanged until the network changes, so the nodes that
+//! fetched it switch to the same one; the current directory is then published again, the
+//! next one too if it is out already. Nodes reject any directory not signed by the
+//! coordinator key they are configured with, [`DirectoryConfig::coordinator_key`], which the
+//! coordinator keeps across restarts in its identity file, and a next directory whose `valid_from` has passed by more than
+//! [`DirectoryConfig::skew_tolerance`], which could only be a stale copy.
+//!
+//! The directory also carries the network's feature flags, see [`crate::flags`], which a
+//! follower hands to the node's [`FeatureFlags`] whenever the directory in force changes, and
+//! the nodes available, which [`follow`] makes the node's view of the network each time the
+//! directory in force changes, see [`DirectoryFollower::with_nodes`].
+//!
+//! The signature is over the [`SignedDirectory`]'s [`Signable::canonical_bytes`],
+//! `darknode-directory:v1\n` and the directory's JSON, carried as the exact string that was
+//! signed so no re-encoding can change it.
+
+use super::*;
+use super::canonical::{CanonicalError, Signable};
+use super::clock::{Clock, SystemClock};
+use super::config::ConfigError;
+use super::directory_watch::{DirectoryDiff, SignedDiff};
+use super::epochs::{Epoch, EpochTracker};
+use super::flags::{FeatureFlags, Flag};
+use super::identity::NodeIdentity;
+use super::traits::{Crypto, NodeManager};
+use super::types::{CryptoKey, Node};
+use std::collections::BTreeMap;
+
+/// Prefix of every signed directory, so directory signatures can't be replayed elsewhere
+const MESSAGE_PREFIX: &str = "darknode-directory:v1\n";
+
+/// When directories are published ahead, and which ones nodes accept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DirectoryConfig {
+    /// How long before an epoch starts its directory is published
+    pub lead_time: Duration,
+    /// How far a node's clock may be from the coordinator's
+    pub skew_tolerance: Duration,
+    /// The coordinator's public key in hex, the only key directories are trusted from;
+    /// required by every node following directories
+    pub coordinator_key: Option<String>,
+}
+
+impl Default for DirectoryConfig {
+    fn default() -> Self {
+        Self {
+            lead_time: Duration::from_secs(10 * 60),
+            skew_tolerance: Duration::from_secs(30),
+            coordinator_key: None,
+        }
+    }
+}
+
+/// Which of the published directories
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Which {
+    /// The directory of the epoch the network is in
+    Current,
+    /// The directory of the epoch after, once it is published
+    Next,
+}
+
+impl Default for Which {
+    fn default() -> Self {
+        Which::Current
+    }
+}
+
+/// The network as published for one epoch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Directory {
+    /// The epoch the directory serves
+    pub epoch: Epoch,
+    /// When nodes switch to the directory
+    pub valid_from: Timestamp,
+    /// When the directory is replaced by the next
+    pub valid_until: Timestamp,
+    /// When the coordinator published the directory
+    pub published_at: Timestamp,
+    /// The nodes available when it was published
+    pub nodes: Vec<Node>,
+    /// The feature flags in force when it was published
+    #[serde(default)]
+    pub flags: BTreeMap<String, Flag>,
+}
+
+/// A directory as served, with the coordinator's signature
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedDirectory {
+    /// The directory's JSON, exactly as signed
+    pub directory: String,
+    /// The coordinator's public key in hex
+    pub signer: String,
+    /// The coordinator's signature in hex
+    pub signature: String,
+}
+
+impl Signable for SignedDirectory {
+    /// The prefix line, then the directory's JSON exactly as carried
+    fn canonical_bytes(&self) -> Result<Vec<u8>, CanonicalError> {
+        let mut message = MESSAGE_PREFIX.as_bytes().to_vec();
+        message.extend_from_slice(self.directory.as_bytes());
+        Ok(message)
+    }
+}
+
+/// A directory a node won't use
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DirectoryRejected {
+    /// The directory or its signature couldn't be read
+    #[error("directory is malformed")]
+    Malformed,
+    /// The signature doesn't verify under the key it names
+    #[error("directory signature does not verify")]
+    BadSignature,
+    /// The directory is signed by a key other than the coordinator's
+    #[error("directory is not signed by the trusted coordinator key")]
+    UntrustedSigner,
+    /// A next directory whose time has already come and gone
+    #[error("next directory was valid from {valid_from}, too long ago to be current")]
+    Stale {
+        /// When the directory became valid
+        valid_from: Timestamp,
+    },
+}
+
+/// The directories the coordinator has published, served from memory
+pub struct DirectoryPublisher {
+    config: DirectoryConfig,
+    epoch_length: Duration,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    identity: Arc<NodeIdentity>,
+    published: parking_lot::Mutex<Vec<(u64, SignedDirectory)>>,
+}
+
+impl DirectoryPublisher {
+    /// Publish directories of epochs of `epoch_length`, signed with `identity`
+    pub fn new(
+        config: DirectoryConfig,
+        epoch_length: Duration,
+        crypto: Arc<dyn Crypto + Send + Sync>,
+        identity: Arc<NodeIdentity>,
+    ) -> Self {
+        Self {
+            config,
+            epoch_length,
+            crypto,
+            identity,
+            published: parking_lot::Mutex::new(Vec::new()),
+        }
+    }
+    
+    /// The coordinator's public key directories are signed with at `now`, in hex
+    pub fn signer(&self, now: Timestamp) -> String {
+        hex::encode(&self.identity.public_key(now).0)
+    }
+    
+    /// The epoch `which` directory at `now` serves, or `None` for a next one not yet due
+    pub fn epoch(&self, which: Which, now: Timestamp) -> Option<Epoch> {
+        let current = Epoch::at(self.epoch_length, now);
+        match which {
+            Which::Current => Some(current),
+            Which::Next if current.ends_at.saturating_duration_since(now) <= self.config.lead_time => {
+                Some(Epoch::at(self.epoch_length, current.ends_at))
+            }
+            Which::Next => None,
+        }
+    }
+    
+    /// The directory published for `epoch`, if it has been
+    pub fn published(&self, epoch: &Epoch) -> Option<SignedDirectory> {
+        self.published
+            .lock()
+            .iter()
+            .find(|(number, _)| *number == epoch.number)
+            .map(|(_, signed)| signed.clone())
+    }
+    
+    /// Sign and publish the directory of `epoch` listing `nodes` and `flags`, replacing any
+    /// published before
+    ///
+    /// Directories of epochs over by `now` are forgotten.
+    pub async fn publish(
+        &self,
+        epoch: Epoch,
+        nodes: Vec<Node>,
+        flags: BTreeMap<String, Flag>,
+        now: Timestamp,
+    ) -> Result<SignedDirectory> {
+        let directory = serde_json::to_string(&Directory {
+            epoch,
+            valid_from: epoch.started_at,
+            valid_until: epoch.ends_at,
+            published_at: now,
+            nodes,
+            flags,
+        })?;
+        let mut signed = SignedDirectory {
+            directory,
+            signer: self.signer(now),
+            signature: String::new(),
+        };
+        signed.signature = hex::encode(&self.identity.sign(&*self.crypto, &signed.canonical_bytes()?, now).await?);
+        
+        let current = Epoch::at(self.epoch_length, now).number;
+        let mut published = self.published.lock();
+        published.retain(|(number, _)| *number != epoch.number && *number >= current);
+        published.push((epoch.number, signed.clone()));
+        Ok(signed)
+    }
+    
+    /// Sign `diff` for nodes catching up on directory changes, see [`crate::directory_watch`]
+    pub async fn sign_diff(&self, diff: &DirectoryDiff, now: Timestamp) -> Result<SignedDiff> {
+        let mut signed = SignedDiff {
+            diff: serde_json::to_string(diff)?,
+            signer: self.signer(now),
+            signature: String::new(),
+        };
+        signed.signature = hex::encode(&self.identity.sign(&*self.crypto, &signed.canonical_bytes()?, now).await?);
+        Ok(signed)
+    }
+    
+    /// Forget the published directories, to be published again as the network now is
+    pub fn invalidate(&self) {
+        self.published.lock().clear();
+    }
+}
+
+/// What a node has taken from the coordinator's directories
+#[derive(Default)]
+struct FollowedDirectories {
+    current: Option<Directory>,
+    next: Option<Directory>,
+}
+
+/// A node's view of the published directories, switching to the next at its `valid_from`
+pub struct DirectoryFollower {
+    config: DirectoryConfig,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    signer: Vec<u8>,
+    state: parking_lot::RwLock<FollowedDirectories>,
+    flags: Option<FeatureFlags>,
+    nodes: Option<Arc<dyn NodeManager + Send + Sync>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl DirectoryFollower {
+    /// Follow directories signed by the coordinator key `config` names
+    pub fn new(config: DirectoryConfig, crypto: Arc<dyn Crypto + Send + Sync>) -> Result<Self, ConfigError> {
+        let key = config.coordinator_key.as_deref().ok_or_else(|| ConfigError::Missing {
+            key: "common.directory".to_string(),
+            field: "coordinator_key".to_string(),
+        })?;
+        let signer = hex::decode(key).filter(|key| !key.is_empty()).ok_or_else(|| ConfigError::Invalid {
+            key: "common.directory.coordinator_key".to_string(),
+            reason: "not a public key in hex".to_string(),
+        })?;
+        Ok(Self {
+            config,
+            crypto,
+            signer,
+            state: parking_lot::RwLock::new(FollowedDirectories::default()),
+            flags: None,
+            nodes: None,
+            clock: Arc::new(SystemClock),
+        })
+    }
+    
+    /// Hand the flags of the directory in force to `flags`
+    pub fn with_flags(mut self, flags: FeatureFlags) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+    
+    /// Make the nodes of the directory in force the network `node_manager` knows
+    pub fn with_nodes(mut self, node_manager: Arc<dyn NodeManager + Send + Sync>) -> Self {
+        self.nodes = Some(node_manager);
+        self
+    }
+    
+    /// Tell when to fetch and switch directories by `clock` rather than the system's
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    
+    /// Hand the nodes of `directory` to the node manager, if there is one
+    async fn apply_nodes(&self, directory: &Directory) -> Result<()> {
+        match &self.nodes {
+            Some(node_manager) => directory_watch::take_all(&**node_manager, directory.nodes.clone()).await,
+            None => Ok(()),
+        }
+    }
+    
+    /// Check that `message` is signed by the trusted coordinator with `signature`, as
+    /// `signer` claims, both in hex
+    async fn verify(&self, signer: &str, signature: &str, message: &impl Signable) -> Result<(), DirectoryRejected> {
+        let signer = hex::decode(signer).ok_or(DirectoryRejected::Malformed)?;
+        let signature = hex::decode(signature).ok_or(DirectoryRejected::Malformed)?;
+        if signer != self.signer {
+            return Err(DirectoryRejected::UntrustedSigner);
+        }
+        let message = message.canonical_bytes().map_err(|_| DirectoryRejected::Malformed)?;
+        let verified = self.crypto.verify(&message, &signature, &CryptoKey(signer)).await;
+        if !matches!(verified, Ok(true)) {
+            return Err(DirectoryRejected::BadSignature);
+        }
+        Ok(())
+    }
+    
+    /// Check that `signed` is signed by the trusted coordinator, and read it
+    async fn open(&self, signed: &SignedDirectory) -> Result<Directory, DirectoryRejected> {
+        self.verify(&signed.signer, &signed.signature, signed).await?;
+        serde_json::from_str(&signed.directory).map_err(|_| DirectoryRejected::Malformed)
+    }
+    
+    /// Check that the directory changes `signed` carries are signed by the trusted
+    /// coordinator, and read them
+    pub async fn open_diff(&self, signed: &SignedDiff) -> Result<DirectoryDiff, DirectoryRejected> {
+        self.verify(&signed.signer, &signed.signature, signed).await?;
+        serde_json::from_str(&signed.diff).map_err(|_| DirectoryRejected::Malformed)
+    }
+    
+    /// Take the `which` directory as fetched at `now`
+    ///
+    /// A directory older than the one held is ignored, so a stale answer can't take the
+    /// node back.
+    pub async fn accept(&self, which: Which, signed: &SignedDirectory, now: Timestamp) -> Result<(), DirectoryRejected> {
+        let directory = self.open(signed).await?;
+        if which == Which::Next && directory.valid_from + self.config.skew_tolerance < now {
+            return Err(DirectoryRejected::Stale {
+                valid_from: directory.valid_from,
+            });
+        }
+        
+        let mut state = self.state.write();
+        let slot = match which {
+            Which::Current => &mut state.current,
+            Which::Next => &mut state.next,
+        };
+        let newer = slot.as_ref().map_or(true, |held| {
+            (directory.epoch.number, directory.published_at) > (held.epoch.number, held.published_at)
+        });
+        if newer {
+            *slot = Some(directory);
+        }
+        Ok(())
+    }
+    
+    /// The directory in force at `now`, switching to the next one once it is valid
+    pub fn current(&self, now: Timestamp) -> Option<Directory> {
+        let mut state = self.state.write();
+        if state.next.as_ref().map_or(false, |next| next.valid_from <= now) {
+            let next = state.next.take();
+            let newer = match (&next, &state.current) {
+                (Some(next), Some(current)) => next.epoch.number >= current.epoch.number,
+                _ => true,
+            };
+            if newer {
+                state.current = next;
+            }
+        }
+        if let (Some(flags), Some(current)) = (&self.flags, &state.current) {
+            flags.update(current.flags.clone());
+        }
+        state.current.clone()
+    }
+    
+    /// Whether the next directory is due to be published and hasn't been fetched
+    pub fn wants_next(&self, now: Timestamp) -> bool {
+        let state = self.state.read();
+        match (&state.current, &state.next) {
+            (Some(current), None) => current.valid_until.saturating_duration_since(now) <= self.config.lead_time,
+            _ => false,
+        }
+    }
+    
+    /// When the node next switches directory, if it holds the next one
+    pub fn switches_at(&self) -> Option<Timestamp> {
+        self.state.read().next.as_ref().map(|next| next.valid_from)
+    }
+}
+
+/// Fetch the `which` directory from the coordinator, `None` if it isn't published yet
+async fn fetch(client: &reqwest::Client, url: &str, which: Which) -> Result<Option<SignedDirectory>> {
+    let which = match which {
+        Which::Current => "current",
+        Which::Next => "next",
+    };
+    let response = client.get(url).query(&[("which", which)]).send().await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(response.error_for_status()?.json().await?))
+}
+
+/// Follow the coordinator's directories every `interval` until the task is dropped
+///
+/// The current directory is fetched on every round and the next once it is published,
+/// and the node switches to the next at its `valid_from`, waking for it if need be. The
+/// epoch of the directory in force is passed to `epochs`, and its nodes to the follower's
+/// node manager whenever it changes.
+pub async fn follow(coordinator_url: String, interval: Duration, follower: Arc<DirectoryFollower>, epochs: Arc<EpochTracker>) {
+    let client = reqwest::Client::new();
+    let url = format!("{}/directory", coordinator_url.trim_end_matches('/'));
+    let mut applied = None;
+    
+    loop {
+        let now = follower.clock.now();
+        for which in [Which::Current, Which::Next] {
+            if which == Which::Next && !follower.wants_next(now) {
+                continue;
+            }
+            let fetched = match fetch(&client, &url, which).await {
+                Ok(Some(signed)) => follower.accept(which, &signed, now).await.map_err(anyhow::Error::from),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = fetched {
+                tracing::warn!("Failed to take the {:?} directory from the coordinator: {}", which, e);
+            }
+        }
+        if let Some(directory) = follower.current(follower.clock.now()) {
+            epochs.observe(directory.epoch.number);
+            let version = (directory.epoch.number, directory.published_at);
+            if applied != Some(version) {
+                match follower.apply_nodes(&directory).await {
+                    Ok(()) => applied = Some(version),
+                    Err(e) => tracing::warn!("Failed to take the nodes of directory {}: {}", directory.epoch.number, e),
+                }
+            }
+        }
+        
+        let now = follower.clock.now();
+        let wait = follower
+            .switches_at()
+            .map_or(interval, |switch| switch.saturating_duration_since(now).min(interval));
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::impls::CryptoImpl;
+    use tokio::sync::mpsc;
+    
+    const HOUR: Duration = Duration::from_secs(3600);
+    
+    async fn publisher(crypto: &Arc<dyn Crypto + Send + Sync>) -> DirectoryPublisher {
+        let identity = Arc::new(NodeIdentity::generate(&**crypto, Duration::ZERO).await.unwrap());
+        DirectoryPublisher::new(DirectoryConfig::default(), HOUR, crypto.clone(), identity)
+    }
+    
+    fn follower(publisher: &DirectoryPublisher, crypto: &Arc<dyn Crypto + Send + Sync>, now: Timestamp) -> DirectoryFollower {
+        let config = DirectoryConfig {
+            coordinator_key: Some(publisher.signer(now)),
+            ..Default::default()
+        };
+        DirectoryFollower::new(config, crypto.clone()).unwrap()
+    }
+    
+    /// A coordinator serving `current` and `next`, passing on which directory each fetch asked for
+    async fn coordinator(current: SignedDirectory, next: SignedDirectory) -> (String, mpsc::UnboundedReceiver<Which>) {
+        let (fetches, fetched) = mpsc::unbounded_channel();
+        let app = axum::Router::new().route(
+            "/directory",
+            axum::routing::get(move |axum::extract::Query(query): axum::extract::Query<BTreeMap<String, String>>| {
+                let which = match query.get("which").map(String::as_str) {
+                    Some("next") => Which::Next,
+                    _ => Which::Current,
+                };
+                let _ = fetches.send(which);
+                let signed = match which {
+                    Which::Current => current.clone(),
+                    Which::Next => next.clone(),
+                };
+                async move { axum::Json(signed) }
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        (format!("http://{}", addr), fetched)
+    }
+    
+    fn drain(fetched: &mut mpsc::UnboundedReceiver<Which>) -> Vec<Which> {
+        std::iter::from_fn(|| fetched.try_recv().ok()).collect()
+    }
+    
+    #[test]
+    fn needs_the_coordinator_key() {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let missing = DirectoryFollower::new(DirectoryConfig::default(), crypto.clone());
+        assert!(matches!(missing, Err(ConfigError::Missing { .. })));
+        let config = DirectoryConfig {
+            coordinator_key: Some("not hex".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(DirectoryFollower::new(config, crypto), Err(ConfigError::Invalid { .. })));
+    }
+    
+    #[tokio::test]
+    async fn takes_directories_only_from_the_configured_key() {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let now = Timestamp::now();
+        let trusted = publisher(&crypto).await;
+        let other = publisher(&crypto).await;
+        let config = DirectoryConfig {
+            coordinator_key: Some(trusted.signer(now)),
+            ..Default::default()
+        };
+        let follower = DirectoryFollower::new(config, crypto.clone()).unwrap();
+        let epoch = trusted.epoch(Which::Current, now).unwrap();
+        
+        // Not even the first directory fetched is trusted on sight
+        let signed = other.publish(epoch, Vec::new(), BTreeMap::new(), now).await.unwrap();
+        assert_eq!(follower.accept(Which::Current, &signed, now).await, Err(DirectoryRejected::UntrustedSigner));
+        assert!(follower.current(now).is_none());
+        
+        let signed = trusted.publish(epoch, Vec::new(), BTreeMap::new(), now).await.unwrap();
+        follower.accept(Which::Current, &signed, now).await.unwrap();
+        assert_eq!(follower.current(now).map(|directory| directory.epoch.number), Some(epoch.number));
+    }
+    
+    #[tokio::test]
+    async fn opens_only_diffs_the_configured_key_signed_as_served() {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let now = Timestamp::now();
+        let trusted = publisher(&crypto).await;
+        let other = publisher(&crypto).await;
+        let config = DirectoryConfig {
+            coordinator_key: Some(trusted.signer(now)),
+            ..Default::default()
+        };
+        let follower = DirectoryFollower::new(config, crypto.clone()).unwrap();
+        let diff = DirectoryDiff {
+            version: 7,
+            full: false,
+            nodes: Vec::new(),
+            removed: vec![crate::types::NodeId(Uuid::new_v4())],
+        };
+        
+        let signed = trusted.sign_diff(&diff, now).await.unwrap();
+        let opened = follower.open_diff(&signed).await.unwrap();
+        assert_eq!((opened.version, opened.removed), (7, diff.removed.clone()));
+        
+        let tampered = SignedDiff {
+            diff: signed.diff.replace("\"version\":7", "\"version\":8"),
+            ..signed
+        };
+        assert_eq!(follower.open_diff(&tampered).await.unwrap_err(), DirectoryRejected::BadSignature);
+        let signed = other.sign_diff(&diff, now).await.unwrap();
+        assert_eq!(follower.open_diff(&signed).await.unwrap_err(), DirectoryRejected::UntrustedSigner);
+    }
+    
+    #[tokio::test]
+    async fn the_next_directory_is_fetched_during_the_lead_window_and_taken_exactly_at_valid_from() {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let started_at = Timestamp::from_secs(100 * HOUR.as_secs());
+        let clock = Arc::new(ManualClock::new(started_at + Duration::from_secs(60)));
+        let publisher = publisher(&crypto).await;
+        let current = publisher.epoch(Which::Current, clock.now()).unwrap();
+        assert!(publisher.epoch(Which::Next, clock.now()).is_none());
+        let next = publisher.epoch(Which::Next, current.ends_at - Duration::from_secs(300)).unwrap();
+        assert_eq!((current.number, next.number, next.started_at), (100, 101, current.ends_at));
+        
+        let (url, mut fetched) = coordinator(
+            publisher.publish(current, Vec::new(), BTreeMap::new(), clock.now()).await.unwrap(),
+            publisher.publish(next, Vec::new(), BTreeMap::new(), clock.now()).await.unwrap(),
+        )
+        .await;
+        let follower = Arc::new(follower(&publisher, &crypto, clock.now()).with_clock(clock.clone()));
+        let epochs = Arc::new(EpochTracker::new(crate::epochs::EpochConfig::default()));
+        tokio::spawn(follow(url, Duration::from_millis(10), follower.clone(), epochs));
+        
+        // Before the lead window only the current directory is fetched
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let before = drain(&mut fetched);
+        assert!(!before.is_empty() && before.iter().all(|which| *which == Which::Current));
+        assert_eq!(follower.switches_at(), None);
+        
+        // Within it the next one is fetched, and held until its time comes
+        clock.set(current.ends_at - Duration::from_secs(300));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(drain(&mut fetched).contains(&Which::Next));
+        assert_eq!(follower.switches_at(), Some(next.started_at));
+        let held = follower.current(next.started_at - Duration::from_millis(1)).unwrap();
+        assert_eq!(held.epoch.number, 100);
+        
+        // Holding the next directory, the node fetches nothing more of it and switches on its own clock
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(drain(&mut fetched).iter().all(|which| *which == Which::Current));
+        let taken = follower.current(next.started_at).unwrap();
+        assert_eq!((taken.epoch.number, taken.valid_from), (101, next.started_at));
+        assert_eq!(follower.switches_at(), None);
+    }
+    
+    #[tokio::test]
+    async fn a_next_directory_past_its_valid_from_beyond_the_skew_tolerance_is_rejected() {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let now = Timestamp::from_secs(100 * HOUR.as_secs() + 3_000);
+        let publisher = publisher(&crypto).await;
+        let follower = follower(&publisher, &crypto, now);
+        let next = publisher.epoch(Which::Next, now).unwrap();
+        let signed = publisher.publish(next, Vec::new(), BTreeMap::new(), now).await.unwrap();
+        let tolerance = DirectoryConfig::default().skew_tolerance;
+        
+        let late = next.started_at + tolerance + Duration::from_millis(1);
+        assert_eq!(
+            follower.accept(Which::Next, &signed, late).await,
+            Err(DirectoryRejected::Stale { valid_from: next.started_at })
+        );
+        assert_eq!(follower.switches_at(), None);
+        
+        // A clock behind by no more than the tolerance still takes it
+        follower.accept(Which::Next, &signed, next.started_at + tolerance).await.unwrap();
+        assert_eq!(follower.switches_at(), Some(next.started_at));
+    }
+}
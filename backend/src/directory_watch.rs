@@ -0,0 +1,454 @@
+//! Pushing directory changes to nodes as they happen
+//!
+//! Nodes polling the coordinator learn of a node going offline only on their next poll,
+//! and route through it until then. Instead the coordinator numbers every change to the
+//! nodes it knows with a directory version, and nodes watch it with
+//! `GET /directory/watch?since=<version>`: as server-sent events, or as a long poll for
+//! clients that can't read them. The coordinator only pushes the new version, never the
+//! nodes themselves; a node that sees a version newer than its own fetches what changed
+//! with `GET /directory/changes?since=<version>` and applies it to its local
+//! [`NodeManager`]. Changes are signed with the coordinator's key like the directories
+//! themselves, and a node applies none that its [`DirectoryFollower`] doesn't trust, see
+//! [`crate::directory`]. Nodes the coordinator no longer knows are listed as removed, and
+//! marked offline.
+//!
+//! Event streams carry a comment every `heartbeat_interval`, and a node hearing nothing
+//! for three intervals takes the connection for dead. Dropped connections are opened
+//! again with exponential backoff, and since the node asks for changes since the version
+//! it holds, whatever changed meanwhile is caught up at once. The coordinator keeps the
+//! last `max_changes` changes; a node further behind, or one that holds a version from
+//! before the coordinator restarted, is sent every available node instead, and marks
+//! the nodes it holds that aren't among them offline.
+//!
+//! At most `max_watchers` connections are held open. Nodes turned away fall back to
+//! polling for changes every `poll_interval`, and try watching again after each poll.
+
+use super::*;
+use super::backoff::Backoff;
+use super::canonical::{CanonicalError, Signable};
+use super::directory::DirectoryFollower;
+use super::traits::NodeManager;
+use super::types::{Node, NodeId, NodeRole, NodeStatus};
+use std::collections::{HashSet, VecDeque};
+
+/// Prefix of every signed diff, so diff signatures can't be replayed elsewhere
+const MESSAGE_PREFIX: &str = "darknode-directory-diff:v1\n";
+
+/// How directory changes are pushed to nodes and followed by them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchConfig {
+    /// Whether nodes watch for changes, rather than only polling for them
+    pub enabled: bool,
+    /// Most watch connections the coordinator holds open at once
+    pub max_watchers: usize,
+    /// How often an idle event stream carries a heartbeat
+    pub heartbeat_interval: Duration,
+    /// Longest a long poll is held open before it is answered unchanged
+    pub long_poll_timeout: Duration,
+    /// Changes the coordinator remembers for nodes catching up
+    pub max_changes: usize,
+    /// First delay before a dropped watch is opened again
+    pub reconnect_min: Duration,
+    /// Longest delay before a dropped watch is opened again
+    pub reconnect_max: Duration,
+    /// How often a node turned away, or not watching, polls for changes
+    pub poll_interval: Duration,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_watchers: 10_000,
+            heartbeat_interval: Duration::from_secs(15),
+            long_poll_timeout: Duration::from_secs(30),
+            max_changes: 10_000,
+            reconnect_min: Duration::from_secs(1),
+            reconnect_max: Duration::from_secs(60),
+            poll_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A version the directory has reached, as pushed to watchers
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct VersionNotice {
+    /// The directory's version
+    pub version: u64,
+}
+
+/// What changed in the directory since a version
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryDiff {
+    /// The version the diff brings a node to
+    pub version: u64,
+    /// Whether `nodes` is every available node rather than only those that changed
+    pub full: bool,
+    /// The nodes' records as they are now
+    pub nodes: Vec<Node>,
+    /// Nodes that changed and are no longer known, in a diff that isn't `full`
+    #[serde(default)]
+    pub removed: Vec<NodeId>,
+}
+
+/// A diff as served, with the coordinator's signature
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedDiff {
+    /// The diff's JSON, exactly as signed
+    pub diff: String,
+    /// The coordinator's public key in hex
+    pub signer: String,
+    /// The coordinator's signature in hex
+    pub signature: String,
+}
+
+impl Signable for SignedDiff {
+    /// The prefix line, then the diff's JSON exactly as carried
+    fn canonical_bytes(&self) -> Result<Vec<u8>, CanonicalError> {
+        let mut message = MESSAGE_PREFIX.as_bytes().to_vec();
+        message.extend_from_slice(self.diff.as_bytes());
+        Ok(message)
+    }
+}
+
+/// Returned when the coordinator holds as many watch connections as it may
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("too many nodes are watching the directory, poll for changes instead")]
+pub struct TooManyWatchers;
+
+/// The changes the coordinator remembers, under one lock with the version they reached
+struct ChangeLog {
+    version: u64,
+    changes: VecDeque<(u64, NodeId)>,
+}
+
+/// The coordinator's record of directory changes, see the module docs
+pub struct DirectoryChanges {
+    config: WatchConfig,
+    log: parking_lot::Mutex<ChangeLog>,
+    versions: tokio::sync::watch::Sender<u64>,
+    watchers: Arc<tokio::sync::Semaphore>,
+}
+
+impl DirectoryChanges {
+    /// Start at version 1, so nodes that hold nothing yet catch up in full
+    pub fn new(config: WatchConfig) -> Self {
+        Self {
+            log: parking_lot::Mutex::new(ChangeLog {
+                version: 1,
+                changes: VecDeque::new(),
+            }),
+            versions: tokio::sync::watch::channel(1).0,
+            watchers: Arc::new(tokio::sync::Semaphore::new(config.max_watchers)),
+            config,
+        }
+    }
+    
+    /// How changes are pushed
+    pub fn config(&self) -> &WatchConfig {
+        &self.config
+    }
+    
+    /// The version the directory is at
+    pub fn version(&self) -> u64 {
+        self.log.lock().version
+    }
+    
+    /// Record that `node_id` changed, notifying every watcher
+    pub fn record(&self, node_id: NodeId) {
+        let version = {
+            let mut log = self.log.lock();
+            log.version += 1;
+            let version = log.version;
+            log.changes.push_back((version, node_id));
+            while log.changes.len() > self.config.max_changes {
+                log.changes.pop_front();
+            }
+            version
+        };
+        self.versions.send_replace(version);
+    }
+    
+    /// The version now, with the nodes changed since `since` unless they are no longer all known
+    fn changed_since(&self, since: u64) -> (u64, Option<Vec<NodeId>>) {
+        let log = self.log.lock();
+        let oldest = log.changes.front().map_or(log.version, |(version, _)| version - 1);
+        if since == 0 || since > log.version || since < oldest {
+            return (log.version, None);
+        }
+        let mut seen = HashSet::new();
+        let changed = log
+            .changes
+            .iter()
+            .filter(|(version, _)| *version > since)
+            .filter(|(_, node_id)| seen.insert(node_id.clone()))
+            .map(|(_, node_id)| node_id.clone())
+            .collect();
+        (log.version, Some(changed))
+    }
+    
+    /// What changed since `since`, with the records `node_manager` holds now
+    pub async fn diff(&self, since: u64, node_manager: &(dyn NodeManager + Send + Sync)) -> Result<DirectoryDiff> {
+        let (version, changed) = self.changed_since(since);
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut removed = Vec::new();
+        let full = changed.is_none();
+        match changed {
+            Some(changed) => {
+                for node_id in changed {
+                    match node_manager.get_node(&node_id).await? {
+                        Some(node) => nodes.push(node),
+                        None => removed.push(node_id),
+                    }
+                }
+            }
+            None => {
+                for role in [NodeRole::Entry, NodeRole::Routing, NodeRole::Exit] {
+                    for node in node_manager.get_available_nodes(role).await? {
+                        if !nodes.iter().any(|known| known.id == node.id) {
+                            nodes.push(node);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(DirectoryDiff {
+            version,
+            full,
+            nodes,
+            removed,
+        })
+    }
+    
+    /// Open a watch, unless as many are open as may be
+    pub fn watch(&self) -> Result<DirectoryWatch, TooManyWatchers> {
+        let permit = self.watchers.clone().try_acquire_owned().map_err(|_| {
+            metrics::increment_counter!("darknode_directory_watch_refusals_total");
+            TooManyWatchers
+        })?;
+        Ok(DirectoryWatch {
+            versions: self.versions.subscribe(),
+            _permit: permit,
+        })
+    }
+}
+
+/// One open watch on the directory's version, holding its place among the watchers
+pub struct DirectoryWatch {
+    versions: tokio::sync::watch::Receiver<u64>,
+    _permit: tokio::sync::OwnedSemaphorePermit,
+}
+
+impl DirectoryWatch {
+    /// Wait for a version other than `since`, or `None` once the coordinator stops
+    ///
+    /// A version older than `since` can only be held by a node from before the coordinator
+    /// restarted, and is returned at once.
+    pub async fn changed_from(&mut self, since: u64) -> Option<u64> {
+        loop {
+            let version = *self.versions.borrow_and_update();
+            if version != since {
+                return Some(version);
+            }
+            self.versions.changed().await.ok()?;
+        }
+    }
+}
+
+/// How a watch connection ended
+enum WatchEnd {
+    /// The connection dropped or went quiet
+    Dropped,
+    /// The coordinator holds too many watches
+    Refused,
+}
+
+/// Make `nodes` the whole of the network known to `node_manager`
+///
+/// Nodes missing from `nodes` are no longer available, and are marked offline.
+pub(crate) async fn take_all(node_manager: &(dyn NodeManager + Send + Sync), nodes: Vec<Node>) -> Result<()> {
+    let listed: HashSet<NodeId> = nodes.iter().map(|node| node.id.clone()).collect();
+    for role in [NodeRole::Entry, NodeRole::Routing, NodeRole::Exit] {
+        for node in node_manager.get_available_nodes(role).await? {
+            if !listed.contains(&node.id) {
+                node_manager.update_node_status(&node.id, NodeStatus::Offline).await?;
+            }
+        }
+    }
+    for node in nodes {
+        node_manager.register_node(node).await?;
+    }
+    Ok(())
+}
+
+/// Apply `diff` to `node_manager`
+pub(crate) async fn apply(node_manager: &(dyn NodeManager + Send + Sync), diff: DirectoryDiff) -> Result<()> {
+    if diff.full {
+        return take_all(node_manager, diff.nodes).await;
+    }
+    for node in diff.nodes {
+        node_manager.register_node(node).await?;
+    }
+    for node_id in &diff.removed {
+        if node_manager.get_node(node_id).await?.is_some() {
+            node_manager.update_node_status(node_id, NodeStatus::Offline).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Fetch what changed since `applied` and apply it to `node_manager`, once `follower`
+/// trusts its signature, returning the version reached
+async fn catch_up(
+    client: &reqwest::Client,
+    base: &str,
+    applied: u64,
+    follower: &DirectoryFollower,
+    node_manager: &(dyn NodeManager + Send + Sync),
+) -> Result<u64> {
+    let signed: SignedDiff = client
+        .get(format!("{}/directory/changes", base))
+        .query(&[("since", applied)])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let diff = follower.open_diff(&signed).await?;
+    
+    let (version, changed) = (diff.version, diff.nodes.len() + diff.removed.len());
+    apply(node_manager, diff).await?;
+    tracing::debug!("Directory at version {}, {} nodes changed", version, changed);
+    Ok(version)
+}
+
+/// Watch the coordinator's event stream, catching up on every version it pushes, until it ends
+async fn watch_once(
+    client: &reqwest::Client,
+    base: &str,
+    config: &WatchConfig,
+    applied: &mut u64,
+    follower: &DirectoryFollower,
+    node_manager: &(dyn NodeManager + Send + Sync),
+    backoff: &mut Backoff,
+) -> Result<WatchEnd> {
+    let mut response = client
+        .get(format!("{}/directory/watch", base))
+        .query(&[("since", *applied)])
+        .header(reqwest::header::ACCEPT, "text/event-stream")
+        .send()
+        .await?;
+    if response.status() == reqwest::StatusCode::SERVICE_UNAVAILABLE {
+        return Ok(WatchEnd::Refused);
+    }
+    response = response.error_for_status()?;
+    backoff.reset();
+    
+    // Events are separated by a blank line; only `data` lines matter, comments are heartbeats
+    let mut buffer = String::new();
+    loop {
+        let chunk = match tokio::time::timeout(config.heartbeat_interval * 3, response.chunk()).await {
+            Ok(chunk) => chunk?,
+            Err(_) => {
+                tracing::warn!("Directory watch went quiet, opening it again");
+                return Ok(WatchEnd::Dropped);
+            }
+        };
+        let Some(chunk) = chunk else { return Ok(WatchEnd::Dropped) };
+        buffer.push_str(&String::from_utf8_lossy(&chunk));
+        while let Some(end) = buffer.find("\n\n") {
+            let event: String = buffer.drain(..end + 2).collect();
+            let version = event
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .find_map(|data| data.trim().parse::<u64>().ok());
+            if version.map_or(false, |version| version != *applied) {
+                *applied = catch_up(client, base, *applied, follower, node_manager).await?;
+            }
+        }
+    }
+}
+
+/// Keep `node_manager` in step with the coordinator's directory until the task is dropped
+///
+/// Watches for changes as `config` has it, falling back to polling when watching is
+/// disabled or the coordinator turns the watch away. Only changes signed by the
+/// coordinator `follower` trusts are applied.
+pub async fn follow(
+    coordinator_url: String,
+    config: WatchConfig,
+    follower: Arc<DirectoryFollower>,
+    node_manager: Arc<dyn NodeManager + Send + Sync>,
+) {
+    let client = reqwest::Client::new();
+    let base = coordinator_url.trim_end_matches('/').to_string();
+    let mut applied = 0;
+    let mut backoff = Backoff::new(config.reconnect_min, config.reconnect_max);
+    
+    loop {
+        let watched = match config.enabled {
+            true => watch_once(&client, &base, &config, &mut applied, &follower, &*node_manager, &mut backoff).await,
+            false => Ok(WatchEnd::Refused),
+        };
+        match watched {
+            Ok(WatchEnd::Refused) => {
+                match catch_up(&client, &base, applied, &follower, &*node_manager).await {
+                    Ok(version) => applied = version,
+                    Err(e) => tracing::warn!("Failed to poll the coordinator for directory changes: {}", e),
+                }
+                tokio::time::sleep(config.poll_interval).await;
+            }
+            Ok(WatchEnd::Dropped) => tokio::time::sleep(backoff.next_delay()).await,
+            Err(e) => {
+                tracing::warn!("Directory watch failed: {}", e);
+                tokio::time::sleep(backoff.next_delay()).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::StoredNodeManager;
+    use crate::storage::memory::MemoryStorage;
+    use crate::types::CryptoKey;
+    
+    fn node() -> Node {
+        Node {
+            public_key: CryptoKey(vec![1; 32]),
+            port: 3003,
+            ..crate::fixtures::node(&[NodeRole::Exit])
+        }
+    }
+    
+    #[tokio::test]
+    async fn nodes_removed_since_a_version_are_taken_offline() {
+        let coordinator = StoredNodeManager::new(Arc::new(MemoryStorage::new()));
+        let follower = StoredNodeManager::new(Arc::new(MemoryStorage::new()));
+        let (kept, gone) = (node(), node());
+        for node in [&kept, &gone] {
+            follower.register_node(node.clone()).await.unwrap();
+        }
+        coordinator.register_node(kept.clone()).await.unwrap();
+        
+        // The coordinator no longer knows a node that changed, so the diff lists it as removed
+        let changes = DirectoryChanges::new(WatchConfig::default());
+        changes.record(kept.id.clone());
+        changes.record(gone.id.clone());
+        let diff = changes.diff(1, &coordinator).await.unwrap();
+        assert!(!diff.full);
+        assert_eq!(diff.removed, vec![gone.id.clone()]);
+        
+        apply(&follower, diff).await.unwrap();
+        let available: Vec<NodeId> = follower
+            .get_available_nodes(NodeRole::Exit)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|node| node.id)
+            .collect();
+        assert_eq!(available, vec![kept.id]);
+    }
+}
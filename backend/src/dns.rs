@@ -0,0 +1,519 @@
+//! Hostname resolution for RPC provider connections
+
+use super::*;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+use hyper::client::connect::dns::Name;
+
+/// How provider hostnames are resolved
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ResolverMode {
+    /// Use the operating system resolver
+    System,
+    /// Use a DNS-over-HTTPS resolver speaking the JSON API (`application/dns-json`)
+    DnsOverHttps {
+        /// The resolver endpoint, e.g. `https://cloudflare-dns.com/dns-query`
+        url: String,
+    },
+    /// Only resolve hosts listed in the static overrides
+    Static,
+}
+
+/// Configuration for the provider resolver
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolverConfig {
+    /// The resolution strategy
+    pub mode: ResolverMode,
+    /// Fixed addresses for specific hosts, consulted before any lookup in every mode
+    pub overrides: HashMap<String, Vec<IpAddr>>,
+    /// TTL applied to answers that carry none (system lookups)
+    pub default_ttl: Duration,
+    /// Lower bound applied to upstream TTLs
+    pub min_ttl: Duration,
+    /// Upper bound applied to upstream TTLs
+    pub max_ttl: Duration,
+    /// How long failed lookups are remembered
+    pub negative_ttl: Duration,
+    /// Whether resolved addresses may point into private, loopback, or link-local ranges
+    pub allow_private_addresses: bool,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            mode: ResolverMode::System,
+            overrides: HashMap::new(),
+            default_ttl: Duration::from_secs(60),
+            min_ttl: Duration::from_secs(5),
+            max_ttl: Duration::from_secs(3600),
+            negative_ttl: Duration::from_secs(10),
+            allow_private_addresses: false,
+        }
+    }
+}
+
+/// Errors produced while resolving a provider host
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ResolveError {
+    /// The host has no addresses
+    #[error("no addresses found for {0}")]
+    NotFound(String),
+    /// The lookup itself failed
+    #[error("lookup of {host} failed: {reason}")]
+    Lookup {
+        /// The host being resolved
+        host: String,
+        /// Why the lookup failed
+        reason: String,
+    },
+    /// The host resolved to an address the exit node must not connect to
+    #[error("{host} resolved to disallowed address {addr}")]
+    EgressDenied {
+        /// The host being resolved
+        host: String,
+        /// The offending address
+        addr: IpAddr,
+    },
+}
+
+/// Whether an address is safe to connect to from an exit node
+pub fn is_public_address(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => is_public_v4(v4),
+        IpAddr::V6(v6) => {
+            // IPv4-mapped (::ffff:a.b.c.d) and IPv4-compatible (::a.b.c.d) addresses reach
+            // the IPv4 address they embed, so are held to the IPv4 ranges
+            if let Some(v4) = v6.to_ipv4_mapped().or_else(|| v6.to_ipv4()) {
+                return is_public_v4(&v4);
+            }
+            let first = v6.segments()[0];
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || (first & 0xfe00) == 0xfc00  // unique local
+                || (first & 0xffc0) == 0xfe80) // link local
+        }
+    }
+}
+
+fn is_public_v4(v4: &std::net::Ipv4Addr) -> bool {
+    let [first, second, ..] = v4.octets();
+    !(v4.is_private()
+        || v4.is_loopback()
+        || v4.is_link_local()
+        || v4.is_unspecified()
+        || v4.is_broadcast()
+        || v4.is_documentation()
+        || v4.is_multicast()
+        || first == 0  // this network
+        || (first == 100 && (second & 0xc0) == 64)  // shared address space (CGNAT)
+        || first >= 240) // reserved
+}
+
+/// Reject resolution results that point at non-public addresses
+pub fn check_egress(host: &str, addrs: &[IpAddr]) -> std::result::Result<(), ResolveError> {
+    match addrs.iter().find(|addr| !is_public_address(addr)) {
+        Some(addr) => Err(ResolveError::EgressDenied {
+            host: host.to_string(),
+            addr: *addr,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Reject a URL whose host is an address literal pointing at a non-public address
+///
+/// Connections to address literals never reach a resolver, so the egress policy is
+/// applied to them here. URLs naming hosts are left to [`ProviderResolver::lookup`].
+pub fn check_url_egress(url: &str) -> std::result::Result<(), ResolveError> {
+    let url = reqwest::Url::parse(url).map_err(|e| ResolveError::Lookup {
+        host: url.to_string(),
+        reason: e.to_string(),
+    })?;
+    check_literal(&url)
+}
+
+fn check_literal(url: &reqwest::Url) -> std::result::Result<(), ResolveError> {
+    let host = url.host_str().unwrap_or_default();
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(addr) => check_egress(host, &[addr]),
+        Err(_) => Ok(()),
+    }
+}
+
+/// A cached lookup result
+struct CacheEntry {
+    result: std::result::Result<Vec<IpAddr>, ResolveError>,
+    expires_at: Instant,
+}
+
+/// A single answer record from a DNS-over-HTTPS JSON response
+#[derive(Debug, Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    #[serde(rename = "TTL")]
+    ttl: u64,
+    data: String,
+}
+
+/// A DNS-over-HTTPS JSON response
+#[derive(Debug, Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+struct ResolverInner {
+    config: ResolverConfig,
+    cache: dashmap::DashMap<String, CacheEntry>,
+    http: reqwest::Client,
+}
+
+/// Caching resolver for provider hosts
+///
+/// The egress check runs on the same addresses handed to the connector, so a DNS
+/// change between checking and connecting cannot redirect a connection.
+#[derive(Clone)]
+pub struct ProviderResolver {
+    inner: Arc<ResolverInner>,
+}
+
+impl ProviderResolver {
+    /// Create a resolver with an empty cache
+    pub fn new(config: ResolverConfig) -> Self {
+        Self {
+            inner: Arc::new(ResolverInner {
+                config,
+                cache: dashmap::DashMap::new(),
+                http: reqwest::Client::builder()
+                    .timeout(Duration::from_secs(5))
+                    .build()
+                    .expect("DoH client configuration is valid"),
+            }),
+        }
+    }
+    
+    /// Resolve a host to addresses that are safe to connect to
+    pub async fn lookup(&self, host: &str) -> std::result::Result<Vec<IpAddr>, ResolveError> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        
+        if let Some(addrs) = self.inner.config.overrides.get(&host) {
+            metrics::increment_counter!("darknode_dns_lookups_total", "source" => "override");
+            return match self.inner.config.allow_private_addresses {
+                true => Ok(addrs.clone()),
+                false => check_egress(&host, addrs).map(|_| addrs.clone()),
+            };
+        }
+        
+        if let Some(entry) = self.inner.cache.get(&host) {
+            if entry.expires_at > Instant::now() {
+                metrics::increment_counter!("darknode_dns_lookups_total", "source" => "cache");
+                return entry.result.clone();
+            }
+        }
+        
+        metrics::increment_counter!("darknode_dns_lookups_total", "source" => "miss");
+        let started = Instant::now();
+        let outcome = self.lookup_uncached(&host).await;
+        metrics::histogram!("darknode_dns_lookup_seconds", started.elapsed().as_secs_f64());
+        
+        let (result, ttl) = match outcome {
+            Ok((addrs, ttl)) => {
+                let config = &self.inner.config;
+                let result = if config.allow_private_addresses {
+                    Ok(addrs)
+                } else {
+                    check_egress(&host, &addrs).map(|_| addrs)
+                };
+                (result, ttl.clamp(config.min_ttl, config.max_ttl))
+            }
+            Err(e) => (Err(e), self.inner.config.negative_ttl),
+        };
+        
+        self.inner.cache.insert(
+            host,
+            CacheEntry {
+                result: result.clone(),
+                expires_at: Instant::now() + ttl,
+            },
+        );
+        result
+    }
+    
+    /// Reject a URL the egress policy refuses without a lookup, see [`check_url_egress`]
+    pub fn check_url(&self, url: &str) -> std::result::Result<(), ResolveError> {
+        match self.inner.config.allow_private_addresses {
+            true => Ok(()),
+            false => check_url_egress(url),
+        }
+    }
+    
+    /// A redirect policy applying the egress policy to the address literals redirects lead to
+    pub fn redirect_policy(&self) -> reqwest::redirect::Policy {
+        let allow_private = self.inner.config.allow_private_addresses;
+        reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= 10 {
+                return attempt.error("too many redirects");
+            }
+            match allow_private {
+                true => attempt.follow(),
+                false => match check_literal(attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(e),
+                },
+            }
+        })
+    }
+    
+    /// Resolve a host with the configured strategy, returning addresses and their TTL
+    async fn lookup_uncached(&self, host: &str) -> std::result::Result<(Vec<IpAddr>, Duration), ResolveError> {
+        let (addrs, ttl) = match &self.inner.config.mode {
+            ResolverMode::System => self.lookup_system(host).await?,
+            ResolverMode::DnsOverHttps { url } => self.lookup_doh(url, host).await?,
+            ResolverMode::Static => return Err(ResolveError::NotFound(host.to_string())),
+        };
+        if addrs.is_empty() {
+            return Err(ResolveError::NotFound(host.to_string()));
+        }
+        Ok((addrs, ttl))
+    }
+    
+    async fn lookup_system(&self, host: &str) -> std::result::Result<(Vec<IpAddr>, Duration), ResolveError> {
+        let addrs = tokio::net::lookup_host((host, 0))
+            .await
+            .map_err(|e| ResolveError::Lookup {
+                host: host.to_string(),
+                reason: e.to_string(),
+            })?
+            .map(|addr| addr.ip())
+            .collect();
+        Ok((addrs, self.inner.config.default_ttl))
+    }
+    
+    async fn lookup_doh(&self, url: &str, host: &str) -> std::result::Result<(Vec<IpAddr>, Duration), ResolveError> {
+        let lookup_error = |reason: String| ResolveError::Lookup {
+            host: host.to_string(),
+            reason,
+        };
+        
+        let mut addrs = Vec::new();
+        let mut ttl = self.inner.config.max_ttl;
+        for record_type in ["A", "AAAA"] {
+            let response: DohResponse = self
+                .inner
+                .http
+                .get(url)
+                .query(&[("name", host), ("type", record_type)])
+                .header(reqwest::header::ACCEPT, "application/dns-json")
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| lookup_error(e.to_string()))?
+                .json()
+                .await
+                .map_err(|e| lookup_error(e.to_string()))?;
+            
+            // NXDOMAIN and friends are answered authoritatively and cached negatively
+            if response.status != 0 {
+                return Err(ResolveError::NotFound(host.to_string()));
+            }
+            for answer in response.answer.iter().filter(|a| a.record_type == 1 || a.record_type == 28) {
+                if let Ok(addr) = answer.data.parse::<IpAddr>() {
+                    addrs.push(addr);
+                    ttl = ttl.min(Duration::from_secs(answer.ttl));
+                }
+            }
+        }
+        Ok((addrs, ttl))
+    }
+}
+
+impl reqwest::dns::Resolve for ProviderResolver {
+    fn resolve(&self, name: Name) -> reqwest::dns::Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addrs = resolver.lookup(name.as_str()).await?;
+            let addrs: reqwest::dns::Addrs =
+                Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Query, routing::get, Router};
+    
+    /// Serve DNS-over-HTTPS answers from `answers`, keyed by record type
+    async fn stub_doh(answers: Arc<parking_lot::Mutex<HashMap<String, String>>>) -> String {
+        let app = Router::new().route(
+            "/dns-query",
+            get(move |Query(query): Query<HashMap<String, String>>| async move {
+                let data = answers.lock().get(&query["type"]).cloned();
+                let answer: Vec<serde_json::Value> = data
+                    .into_iter()
+                    .map(|data| serde_json::json!({"type": if data.contains(':') { 28 } else { 1 }, "TTL": 300, "data": data}))
+                    .collect();
+                axum::Json(serde_json::json!({"Status": 0, "Answer": answer}))
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        format!("http://{}/dns-query", addr)
+    }
+    
+    #[tokio::test]
+    async fn resolves_over_https_and_holds_the_answer_for_its_ttl() {
+        let answers = Arc::new(parking_lot::Mutex::new(HashMap::from([("A".to_string(), "93.184.216.34".to_string())])));
+        let url = stub_doh(answers.clone()).await;
+        let resolver = ProviderResolver::new(ResolverConfig {
+            mode: ResolverMode::DnsOverHttps { url },
+            ..Default::default()
+        });
+        let first = resolver.lookup("rpc.example.com").await.unwrap();
+        assert_eq!(first, vec!["93.184.216.34".parse::<IpAddr>().unwrap()]);
+        
+        // The record now points inside the network, but the checked answer is what connects
+        answers.lock().insert("A".to_string(), "10.0.0.1".to_string());
+        assert_eq!(resolver.lookup("rpc.example.com").await.unwrap(), first);
+        let fresh = ProviderResolver::new(resolver.inner.config.clone());
+        assert!(matches!(fresh.lookup("rpc.example.com").await, Err(ResolveError::EgressDenied { .. })));
+    }
+    
+    #[tokio::test]
+    async fn static_overrides_are_held_to_the_egress_policy() {
+        let resolver = ProviderResolver::new(ResolverConfig {
+            mode: ResolverMode::Static,
+            overrides: HashMap::from([
+                ("rpc.example.com".to_string(), vec!["93.184.216.34".parse().unwrap()]),
+                ("internal.example.com".to_string(), vec!["127.0.0.1".parse().unwrap()]),
+            ]),
+            ..Default::default()
+        });
+        assert!(resolver.lookup("RPC.example.com.").await.is_ok());
+        assert!(matches!(resolver.lookup("internal.example.com").await, Err(ResolveError::EgressDenied { .. })));
+        assert!(matches!(resolver.lookup("other.example.com").await, Err(ResolveError::NotFound(_))));
+    }
+    
+    #[test]
+    fn address_literals_are_checked_without_a_lookup() {
+        let resolver = ProviderResolver::new(ResolverConfig::default());
+        assert!(resolver.check_url("http://169.254.169.254/latest/meta-data").is_err());
+        assert!(resolver.check_url("http://[::1]:8545").is_err());
+        assert!(resolver.check_url("https://93.184.216.34").is_ok());
+        assert!(resolver.check_url("https://rpc.example.com").is_ok());
+        let permissive = ProviderResolver::new(ResolverConfig {
+            allow_private_addresses: true,
+            ..Default::default()
+        });
+        assert!(permissive.check_url("http://127.0.0.1:8545").is_ok());
+    }
+    
+    #[test]
+    fn only_public_addresses_pass_the_egress_policy() {
+        let cases = [
+            ("93.184.216.34", true),
+            ("1.1.1.1", true),
+            ("100.63.255.255", true),
+            ("100.128.0.1", true),
+            ("223.255.255.255", true),
+            ("0.0.0.0", false),
+            ("0.1.2.3", false),
+            ("10.0.0.1", false),
+            ("100.64.0.1", false),
+            ("100.127.255.255", false),
+            ("127.0.0.1", false),
+            ("169.254.169.254", false),
+            ("172.16.0.1", false),
+            ("192.168.1.1", false),
+            ("192.0.2.1", false),
+            ("224.0.0.1", false),
+            ("239.255.255.250", false),
+            ("240.0.0.1", false),
+            ("255.255.255.255", false),
+            ("2606:4700:4700::1111", true),
+            ("::ffff:93.184.216.34", true),
+            ("::", false),
+            ("::1", false),
+            ("::ffff:127.0.0.1", false),
+            ("::ffff:169.254.169.254", false),
+            ("::ffff:10.0.0.1", false),
+            ("::ffff:100.64.0.1", false),
+            ("::127.0.0.1", false),
+            ("::169.254.169.254", false),
+            ("fc00::1", false),
+            ("fd12:3456::1", false),
+            ("fe80::1", false),
+            ("ff02::1", false),
+            ("ff0e::1", false),
+        ];
+        for (addr, public) in cases {
+            assert_eq!(is_public_address(&addr.parse().unwrap()), public, "{}", addr);
+        }
+        assert!(check_url_egress("http://[::ffff:169.254.169.254]/latest/meta-data").is_err());
+        assert!(check_url_egress("http://[::ffff:7f00:1]:8545").is_err());
+    }
+    
+    /// Serve `name` on `ip`:`port`, counting the connections accepted
+    fn serving_on(ip: &str, port: u16, name: &'static str) -> Arc<std::sync::atomic::AtomicUsize> {
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = accepted.clone();
+        let listener = std::net::TcpListener::bind((ip, port)).unwrap();
+        let service = hyper::service::make_service_fn(move |_| {
+            counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move { Ok::<_, std::convert::Infallible>(Router::new().route("/", get(move || async move { name }))) }
+        });
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(service));
+        accepted
+    }
+    
+    #[tokio::test]
+    async fn connections_go_to_the_checked_address_and_not_a_rebound_one() {
+        // Two servers on the same port, told apart by the loopback address they listen on
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let first = serving_on("127.0.0.1", port, "checked");
+        let second = serving_on("127.0.0.2", port, "rebound");
+        let answers = Arc::new(parking_lot::Mutex::new(HashMap::from([("A".to_string(), "127.0.0.1".to_string())])));
+        let doh = stub_doh(answers.clone()).await;
+        let client = |resolver: ProviderResolver| {
+            reqwest::Client::builder()
+                .dns_resolver(Arc::new(resolver))
+                .pool_max_idle_per_host(0)
+                .build()
+                .unwrap()
+        };
+        let url = format!("http://rpc.example.com:{}/", port);
+        
+        // Once looked up, the address the connector gets is the one that was answered, even
+        // after the record is pointed elsewhere and on a fresh connection
+        let permissive = client(ProviderResolver::new(ResolverConfig {
+            mode: ResolverMode::DnsOverHttps { url: doh.clone() },
+            allow_private_addresses: true,
+            ..Default::default()
+        }));
+        assert_eq!(permissive.get(&url).send().await.unwrap().text().await.unwrap(), "checked");
+        answers.lock().insert("A".to_string(), "127.0.0.2".to_string());
+        assert_eq!(permissive.get(&url).send().await.unwrap().text().await.unwrap(), "checked");
+        assert_eq!(second.load(std::sync::atomic::Ordering::SeqCst), 0);
+        
+        // Under the egress policy an answer inside the network is refused before connecting
+        let accepted = first.load(std::sync::atomic::Ordering::SeqCst);
+        let strict = client(ProviderResolver::new(ResolverConfig {
+            mode: ResolverMode::DnsOverHttps { url: doh },
+            ..Default::default()
+        }));
+        let refused = strict.get(&url).send().await.unwrap_err();
+        let causes = std::iter::successors(Some(&refused as &dyn std::error::Error), |cause| cause.source());
+        assert!(causes.map(|cause| cause.to_string()).any(|cause| cause.contains("disallowed address 127.0.0.1")));
+        assert_eq!(first.load(std::sync::atomic::Ordering::SeqCst), accepted);
+        assert_eq!(second.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,213 @@
+//! Moving circuits off nodes going into maintenance
+//!
+//! An operator setting a node to Maintenance wants it to stop carrying traffic without
+//! failing the requests it carries. The coordinator lists the nodes it is draining on
+//! `GET /nodes/draining`, and entry nodes poll it every `poll_interval`. Circuits are no
+//! longer built through a draining node, and each circuit already crossing one gets a
+//! replacement built around it; new requests switch to the replacement as soon as it is
+//! up, while those in flight finish on the old circuit. The old circuit is torn down once
+//! the last of them completes, or when `deadline` passes.
+//!
+//! WebSocket subscriptions riding the old circuit are polled on the new one at once, and
+//! once they are served there told they were resumed, with a gap report from when the
+//! switch began, so clients can re-fetch whatever changed in between. The old circuit's
+//! teardown waits on its last request completing rather than checking on a timer.
+
+use super::*;
+use super::clock::Deadline;
+use super::types::{Circuit, CircuitId, NodeId};
+use std::collections::HashSet;
+
+/// Method of the notification telling a subscription it was resumed on a new circuit
+pub const RESUMED_METHOD: &str = "darknode_subscriptionResumed";
+
+/// How entry nodes follow and move circuits off draining nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DrainConfig {
+    /// Whether circuits are moved off draining nodes at all
+    pub enabled: bool,
+    /// How often the coordinator is asked which nodes are draining
+    pub poll_interval: Duration,
+    /// How long requests in flight get to finish on a drained circuit before it is torn down
+    pub deadline: Duration,
+}
+
+impl Default for DrainConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            poll_interval: Duration::from_secs(10),
+            deadline: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The draining nodes an entry node knows of, and the requests in flight on each circuit
+pub struct DrainTracker {
+    config: DrainConfig,
+    draining: parking_lot::RwLock<HashSet<NodeId>>,
+    in_flight: Arc<dashmap::DashMap<CircuitId, usize>>,
+    /// Woken whenever the last request in flight on a circuit completes
+    idle: Arc<tokio::sync::Notify>,
+}
+
+impl DrainTracker {
+    /// Create a tracker knowing of no draining nodes
+    pub fn new(config: DrainConfig) -> Self {
+        Self {
+            config,
+            draining: parking_lot::RwLock::new(HashSet::new()),
+            in_flight: Arc::new(dashmap::DashMap::new()),
+            idle: Arc::new(tokio::sync::Notify::new()),
+        }
+    }
+    
+    /// How draining nodes are followed
+    pub fn config(&self) -> &DrainConfig {
+        &self.config
+    }
+    
+    /// Take `nodes` as the draining ones, returning how many weren't before
+    pub fn observe(&self, nodes: Vec<NodeId>) -> usize {
+        let nodes: HashSet<NodeId> = nodes.into_iter().collect();
+        let mut draining = self.draining.write();
+        let added = nodes.difference(&draining).count();
+        *draining = nodes;
+        added
+    }
+    
+    /// The nodes draining, for new circuits to leave out
+    pub fn draining(&self) -> Vec<NodeId> {
+        self.draining.read().iter().cloned().collect()
+    }
+    
+    /// Whether `circuit` crosses a draining node
+    pub fn crosses(&self, circuit: &Circuit) -> bool {
+        let draining = self.draining.read();
+        std::iter::once(&circuit.entry_node)
+            .chain(&circuit.routing_nodes)
+            .chain(std::iter::once(&circuit.exit_node))
+            .any(|node| draining.contains(node))
+    }
+    
+    /// Count a request in flight on `circuit` until the returned guard is dropped
+    pub fn enter(&self, circuit: &CircuitId) -> InFlight {
+        *self.in_flight.entry(circuit.clone()).or_insert(0) += 1;
+        InFlight {
+            in_flight: self.in_flight.clone(),
+            idle: self.idle.clone(),
+            circuit: circuit.clone(),
+        }
+    }
+    
+    /// Requests in flight on `circuit`
+    pub fn in_flight(&self, circuit: &CircuitId) -> usize {
+        self.in_flight.get(circuit).map_or(0, |count| *count)
+    }
+    
+    /// Wait until no request is in flight on `circuit` or the drain deadline passes,
+    /// returning whether they all finished
+    pub async fn settled(&self, circuit: &CircuitId) -> bool {
+        let deadline = Deadline::after(self.config.deadline);
+        loop {
+            // Wait on the next completion before checking, so none is missed in between
+            let completed = self.idle.notified();
+            if self.in_flight(circuit) == 0 {
+                return true;
+            }
+            if tokio::time::timeout(deadline.remaining(), completed).await.is_err() {
+                return self.in_flight(circuit) == 0;
+            }
+        }
+    }
+}
+
+/// A request in flight on a circuit, counted until dropped
+pub struct InFlight {
+    in_flight: Arc<dashmap::DashMap<CircuitId, usize>>,
+    idle: Arc<tokio::sync::Notify>,
+    circuit: CircuitId,
+}
+
+impl InFlight {
+    /// Count the request on `circuit` instead, once it has been sent again there
+    pub fn switch(&mut self, circuit: &CircuitId) {
+        if *circuit != self.circuit {
+            let moved = InFlight {
+                in_flight: self.in_flight.clone(),
+                idle: self.idle.clone(),
+                circuit: circuit.clone(),
+            };
+            *self.in_flight.entry(circuit.clone()).or_insert(0) += 1;
+            drop(std::mem::replace(self, moved));
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let dashmap::mapref::entry::Entry::Occupied(mut count) = self.in_flight.entry(self.circuit.clone()) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+                self.idle.notify_waiters();
+            }
+        }
+    }
+}
+
+/// The notification telling a subscription it was resumed on a new circuit
+///
+/// Notifications between `from` and `to` may have been missed while the circuit was switched.
+pub fn resumed(subscription: u64, from: Timestamp, to: Timestamp) -> serde_json::Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": RESUMED_METHOD,
+        "params": {
+            "subscription": subscription,
+            "reason": "node_maintenance",
+            "gap": { "from": from, "to": to },
+        }
+    })
+}
+
+/// Ask the coordinator at `coordinator_url` which nodes are draining
+pub async fn draining(client: &reqwest::Client, coordinator_url: &str) -> Result<Vec<NodeId>, reqwest::Error> {
+    client
+        .get(format!("{}/nodes/draining", coordinator_url.trim_end_matches('/')))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_circuit_settles_as_its_last_request_completes_or_at_the_deadline() {
+        let tracker = Arc::new(DrainTracker::new(DrainConfig::default()));
+        let circuit = CircuitId(Uuid::new_v4());
+        let first = tracker.enter(&circuit);
+        let second = tracker.enter(&circuit);
+        
+        let settling = tokio::spawn({
+            let (tracker, circuit) = (tracker.clone(), circuit.clone());
+            async move { tracker.settled(&circuit).await }
+        });
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert!(!settling.is_finished());
+        let completed = tokio::time::Instant::now();
+        drop(second);
+        assert!(settling.await.unwrap());
+        assert_eq!(tokio::time::Instant::now(), completed);
+        
+        let stuck = tracker.enter(&circuit);
+        assert!(!tracker.settled(&circuit).await);
+        drop(stuck);
+    }
+}
@@ -0,0 +1,72 @@
+//! The method classes an exit node serves, whatever the network would send it
+//!
+//! An exit operator answers for the traffic leaving their node, and some won't relay
+//! transactions, or heavy scans of chain state. The `[exit.egress]` section lists the
+//! [`MethodClass`]es the node serves. The list is an allowlist: a class left out of it is
+//! refused, and by default every class is in it.
+//!
+//! The classes are advertised in the node's heartbeats, and the coordinator hands those of
+//! signed heartbeats out with the node's record, so entry nodes build a request's circuit
+//! only to an exit serving its class. A user's circuit whose exit refuses a request's class
+//! is replaced by one to an exit serving it, as it would be on rotation. Exits of older
+//! releases, or whose heartbeats aren't signed, advertise nothing and are taken to serve
+//! every class. The exit still checks every request it is sent, and
+//! refuses one of a class it doesn't serve with a [`CapabilityError`], however it got there.
+
+use super::*;
+use super::capabilities::CapabilityError;
+use super::timeouts::MethodClass;
+
+/// Every method class, served by default
+const ALL_CLASSES: [MethodClass; 4] = [
+    MethodClass::LightRead,
+    MethodClass::Read,
+    MethodClass::HeavyRead,
+    MethodClass::Write,
+];
+
+/// The method classes an exit node serves
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EgressConfig {
+    /// Classes served, all others refused
+    pub serve: Vec<MethodClass>,
+}
+
+impl Default for EgressConfig {
+    fn default() -> Self {
+        Self {
+            serve: ALL_CLASSES.to_vec(),
+        }
+    }
+}
+
+impl EgressConfig {
+    /// The classes to advertise in heartbeats
+    pub fn advertised(&self) -> Vec<MethodClass> {
+        let mut classes = self.serve.clone();
+        classes.sort();
+        classes.dedup();
+        classes
+    }
+    
+    /// Refuse a request for `method` unless its class is served
+    pub fn admit(&self, method: &str) -> Result<(), CapabilityError> {
+        let class = MethodClass::of(method);
+        if !self.serve.contains(&class) {
+            metrics::increment_counter!("darknode_egress_refused_total", "class" => class.label());
+            return Err(CapabilityError::MethodClassRefused(class));
+        }
+        Ok(())
+    }
+}
+
+/// Whether an exit advertising `advertised` serves every one of `classes`
+///
+/// An exit advertising nothing serves every class.
+pub fn serves(advertised: Option<&[MethodClass]>, classes: &[MethodClass]) -> bool {
+    match advertised {
+        Some(advertised) => classes.iter().all(|class| advertised.contains(class)),
+        None => true,
+    }
+}
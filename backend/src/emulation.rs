@@ -0,0 +1,244 @@
+//! Answering Solana's health and version probes at the entry node
+//!
+//! Solana tooling calls `getHealth` and `getVersion` before doing real work. Sending these
+//! through a full circuit costs a round trip through every hop for an answer the entry
+//! node already has: `getHealth` is answered from the node's own view of the network
+//! behind it, and `getVersion` from a copy of the exit side's answer refreshed in the
+//! background, kept for each network since devnet and testnet run other releases than
+//! mainnet. Locally served responses carry `darknode.emulated: true`.
+
+use super::*;
+use super::methods;
+use super::chains::Network;
+use super::types::ExitPayload;
+use std::collections::HashMap;
+
+/// Method answered from the node's own health
+pub const HEALTH_METHOD: &str = "getHealth";
+
+/// Method answered from the cached exit-side version
+pub const VERSION_METHOD: &str = "getVersion";
+
+/// Error code Solana nodes return from `getHealth` when unhealthy
+const NODE_UNHEALTHY_CODE: i64 = -32005;
+
+/// Which probes the entry node answers itself
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmulationConfig {
+    /// Whether probes are answered locally; when off they are forwarded like any request
+    pub enabled: bool,
+    /// How often the cached version is refreshed through a circuit
+    pub version_refresh: Duration,
+    /// Age after which the cached version is no longer served and requests are forwarded
+    pub version_max_age: Duration,
+}
+
+impl Default for EmulationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            version_refresh: Duration::from_secs(600),
+            version_max_age: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// A `getHealth` response for the given health, in the shape Solana nodes use
+pub fn health_response(id: &serde_json::Value, healthy: bool) -> serde_json::Value {
+    let mut response = if healthy {
+        serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": "ok" })
+    } else {
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {
+                "code": NODE_UNHEALTHY_CODE,
+                "message": "Node is unhealthy",
+                "data": {},
+            },
+        })
+    };
+    methods::set_extension(&mut response, "emulated", serde_json::json!(true));
+    response
+}
+
+/// The payload sent through a circuit to fetch the version of `network` on the exit side
+pub fn version_request(network: Network) -> ExitPayload {
+    ExitPayload {
+        request: serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": VERSION_METHOD,
+        }),
+        quorum: None,
+        capabilities: Vec::new(),
+        trace_token: None,
+        preflight: false,
+        relay: false,
+        debug_errors: false,
+        timeout: None,
+        notification: false,
+        chain: None,
+        network,
+        normalize: false,
+        timing: false,
+        attribution: false,
+        provider: None,
+    }
+}
+
+/// The last `getVersion` result seen from the exit side, for each network
+pub struct VersionCache {
+    max_age: Duration,
+    versions: parking_lot::RwLock<HashMap<Network, (serde_json::Value, std::time::Instant)>>,
+}
+
+impl VersionCache {
+    /// Create an empty cache whose entries are served for up to `max_age`
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            versions: parking_lot::RwLock::new(HashMap::new()),
+        }
+    }
+    
+    /// Remember the result of a `getVersion` response on `network`, ignoring errors
+    pub fn observe(&self, network: Network, response: &[u8]) {
+        let Ok(response) = serde_json::from_slice::<serde_json::Value>(response) else { return };
+        if response["result"].is_object() {
+            self.versions
+                .write()
+                .insert(network, (response["result"].clone(), std::time::Instant::now()));
+        }
+    }
+    
+    /// The networks whose version is kept, which are refreshed along with mainnet's
+    pub fn networks(&self) -> Vec<Network> {
+        let mut networks: Vec<Network> = self.versions.read().keys().copied().collect();
+        if !networks.contains(&Network::Mainnet) {
+            networks.push(Network::Mainnet);
+        }
+        networks
+    }
+    
+    /// A `getVersion` response for `network` from the cache, if it holds a fresh enough result
+    pub fn response(&self, network: Network, id: &serde_json::Value) -> Option<serde_json::Value> {
+        let versions = self.versions.read();
+        let (result, fetched_at) = versions.get(&network)?;
+        if fetched_at.elapsed() > self.max_age {
+            return None;
+        }
+        let mut response = serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": result });
+        methods::set_extension(&mut response, "emulated", serde_json::json!(true));
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EntryConfig;
+    use crate::context::RequestContext;
+    use crate::fixtures::{self, StubRouter};
+    use crate::traits::UserManager;
+    use crate::types::RpcMapping;
+    use serde_json::json;
+    
+    /// A router whose exit side runs another release on each network
+    fn router() -> Arc<StubRouter> {
+        Arc::new(StubRouter::new(|payload| {
+            let version = match payload.network {
+                Network::Mainnet => "1.18.26",
+                _ => "2.0.3",
+            };
+            json!({ "jsonrpc": "2.0", "result": { "solana-core": version, "feature-set": 1 } })
+        }))
+    }
+    
+    fn request(method: &str) -> Vec<u8> {
+        serde_json::to_vec(&json!({ "jsonrpc": "2.0", "id": 7, "method": method })).unwrap()
+    }
+    
+    fn answer(response: &[u8]) -> serde_json::Value {
+        serde_json::from_slice(response).unwrap()
+    }
+    
+    #[tokio::test]
+    async fn versions_are_answered_locally_for_the_network_of_the_mapping() {
+        let router = router();
+        let (entry, users) = fixtures::entry(router.clone(), &EntryConfig::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let devnet = RpcMapping {
+            network: Network::Devnet,
+            ..fixtures::mapping()
+        };
+        users.add_rpc_mapping(user.id, devnet.clone()).await.unwrap();
+        let on_devnet = || RequestContext::new(&user.api_key).with_mapping(Some(devnet.id));
+        
+        // Nothing is cached yet, so the first is sent through a circuit and its answer kept
+        let forwarded = answer(&entry.handle_request(on_devnet(), &request(VERSION_METHOD)).await.unwrap());
+        assert_eq!(forwarded["result"]["solana-core"], "2.0.3");
+        assert_eq!(router.sent()[0].1.network, Network::Devnet);
+        
+        let emulated = answer(&entry.handle_request(on_devnet(), &request(VERSION_METHOD)).await.unwrap());
+        assert_eq!(emulated["id"], 7);
+        assert_eq!(emulated["result"]["solana-core"], "2.0.3");
+        assert_eq!(emulated[methods::EXTENSION_KEY]["emulated"], true);
+        assert_eq!(router.sent().len(), 1);
+        
+        // Devnet's version is no answer for mainnet, which is fetched for itself
+        let mainnet = answer(
+            &entry
+                .handle_request(RequestContext::new(&user.api_key), &request(VERSION_METHOD))
+                .await
+                .unwrap(),
+        );
+        assert_eq!(mainnet["result"]["solana-core"], "1.18.26");
+        assert_eq!(router.sent().len(), 2);
+        
+        let health = answer(&entry.handle_request(on_devnet(), &request(HEALTH_METHOD)).await.unwrap());
+        assert_eq!(health["result"], "ok");
+        assert_eq!(health[methods::EXTENSION_KEY]["emulated"], true);
+        assert_eq!(router.sent().len(), 2);
+    }
+    
+    #[tokio::test]
+    async fn with_emulation_off_probes_are_forwarded() {
+        let router = router();
+        let config = EntryConfig {
+            emulation: EmulationConfig {
+                enabled: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (entry, users) = fixtures::entry(router.clone(), &config).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        
+        for method in [VERSION_METHOD, VERSION_METHOD, HEALTH_METHOD] {
+            let response = answer(&entry.handle_request(RequestContext::new(&user.api_key), &request(method)).await.unwrap());
+            assert!(response[methods::EXTENSION_KEY]["emulated"].is_null());
+        }
+        let sent: Vec<_> = router
+            .sent()
+            .into_iter()
+            .map(|(_, payload)| payload.request["method"].clone())
+            .collect();
+        assert_eq!(sent, vec![VERSION_METHOD, VERSION_METHOD, HEALTH_METHOD]);
+    }
+    
+    #[test]
+    fn an_unhealthy_node_answers_in_the_shape_solana_nodes_use() {
+        let response = health_response(&json!(7), false);
+        assert_eq!(
+            response,
+            json!({
+                "jsonrpc": "2.0",
+                "id": 7,
+                "error": { "code": -32005, "message": "Node is unhealthy", "data": {} },
+                "darknode": { "emulated": true },
+            })
+        );
+    }
+}
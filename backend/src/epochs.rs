@@ -0,0 +1,258 @@
+//! Network-wide epochs bounding which exit nodes a user's circuits may use
+//!
+//! An exit node that stays in a user's rotation indefinitely can build a long-term profile
+//! of that user. The coordinator publishes an epoch number that advances every
+//! `EpochConfig::length`, and each entry node restricts a user's exits to a small subset
+//! derived from a keyed hash of the user and the epoch. Within an epoch a user keeps
+//! hitting the same exits, so caching and affinity still work; across epochs the subset
+//! changes. The key is a secret every entry node of the network shares, so a user gets the
+//! same subset whichever entry node they connect to, and exits, which don't know it, can't
+//! predict or link subsets.
+
+use super::*;
+use super::types::Node;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How long epochs last and how many exits a user may reach in one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EpochConfig {
+    /// Whether users' exits are restricted per epoch at all
+    pub enabled: bool,
+    /// How long each epoch lasts
+    pub length: Duration,
+    /// Exit nodes a user may reach within one epoch
+    pub exits_per_user: usize,
+    /// The secret subsets are derived with, the same on every entry node; entry nodes
+    /// restricting exits refuse to start without it
+    pub secret: Option<String>,
+}
+
+impl Default for EpochConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            length: Duration::from_secs(6 * 3600),
+            exits_per_user: 3,
+            secret: None,
+        }
+    }
+}
+
+/// An epoch as published by the coordinator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Epoch {
+    /// Epochs elapsed since the Unix epoch
+    pub number: u64,
+    /// When the epoch started
+    pub started_at: Timestamp,
+    /// When the next epoch starts
+    pub ends_at: Timestamp,
+}
+
+impl Epoch {
+    /// The epoch `now` falls in, for epochs of `length`
+    pub fn at(length: Duration, now: Timestamp) -> Self {
+        let length = length.as_secs().max(1);
+        let number = now.as_secs() / length;
+        let started_at = Timestamp::from_secs(number * length);
+        Self {
+            number,
+            started_at,
+            ends_at: started_at + Duration::from_secs(length),
+        }
+    }
+}
+
+/// The exit nodes one user may reach during one epoch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExitSubset {
+    seed: [u8; 32],
+    size: usize,
+}
+
+impl ExitSubset {
+    /// The members of the subset among `nodes`, best ranked first
+    ///
+    /// Nodes are ranked by a hash of the seed and their ID, so the subset only changes
+    /// when the seed does or when one of its members leaves the network.
+    pub fn select<'a>(&self, nodes: &'a [Node]) -> Vec<&'a Node> {
+        let mut ranked: Vec<([u8; 32], &Node)> = nodes
+            .iter()
+            .map(|node| {
+                let mut hasher = Sha256::new();
+                hasher.update(self.seed);
+                hasher.update(node.id.0.as_bytes());
+                (hasher.finalize().into(), node)
+            })
+            .collect();
+        ranked.sort_by(|a, b| a.0.cmp(&b.0));
+        ranked.into_iter().take(self.size).map(|(_, node)| node).collect()
+    }
+}
+
+/// The current epoch as seen by an entry node, and the key its subsets are derived with
+pub struct EpochTracker {
+    config: EpochConfig,
+    key: [u8; 32],
+    current: AtomicU64,
+}
+
+impl EpochTracker {
+    /// Create a tracker starting from the local clock's epoch
+    ///
+    /// Subsets are derived with the configured secret, or without one with a random key,
+    /// which only suits nodes that follow epochs without restricting exits.
+    pub fn new(config: EpochConfig) -> Self {
+        let key = match &config.secret {
+            Some(secret) => Sha256::digest(secret.as_bytes()).into(),
+            None => {
+                let mut key = [0u8; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            }
+        };
+        let current = Epoch::at(config.length, Timestamp::now()).number;
+        Self {
+            config,
+            key,
+            current: AtomicU64::new(current),
+        }
+    }
+    
+    /// The current epoch number, or `None` if exits aren't restricted per epoch
+    pub fn current(&self) -> Option<u64> {
+        self.config
+            .enabled
+            .then(|| self.current.load(Ordering::Relaxed))
+    }
+    
+    /// When the current epoch ends, or `None` if exits aren't restricted per epoch
+    pub fn ends_at(&self) -> Option<Timestamp> {
+        let length = self.config.length.as_secs().max(1);
+        self.current().map(|epoch| Timestamp::from_secs((epoch + 1) * length))
+    }
+    
+    /// Move to an epoch published by the coordinator
+    ///
+    /// Epochs only move forward, so a stale answer can't bring back an old subset.
+    pub fn observe(&self, epoch: u64) {
+        let previous = self.current.fetch_max(epoch, Ordering::Relaxed);
+        if epoch > previous {
+            tracing::info!("Entered epoch {}, new circuits use the new exit subsets", epoch);
+        }
+    }
+    
+    /// The exits `user_id` may reach in the current epoch, if exits are restricted
+    pub fn subset(&self, user_id: Uuid) -> Option<ExitSubset> {
+        self.current().map(|epoch| self.subset_in(user_id, epoch))
+    }
+    
+    /// The exits `user_id` may reach in `epoch`
+    pub fn subset_in(&self, user_id: Uuid, epoch: u64) -> ExitSubset {
+        let mut hasher = Sha256::new();
+        hasher.update(self.key);
+        hasher.update(user_id.as_bytes());
+        hasher.update(epoch.to_be_bytes());
+        ExitSubset {
+            seed: hasher.finalize().into(),
+            size: self.config.exits_per_user.max(1),
+        }
+    }
+}
+
+/// Poll the coordinator's published epoch every `interval` until the task is dropped
+pub async fn follow(coordinator_url: String, interval: Duration, tracker: Arc<EpochTracker>) {
+    let client = reqwest::Client::new();
+    let url = format!("{}/epoch", coordinator_url.trim_end_matches('/'));
+    let mut ticker = tokio::time::interval(interval);
+    
+    loop {
+        ticker.tick().await;
+        
+        let fetched = async {
+            let epoch: Epoch = client
+                .get(&url)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            Ok::<_, reqwest::Error>(epoch)
+        };
+        match fetched.await {
+            Ok(epoch) => tracker.observe(epoch.number),
+            Err(e) => tracing::warn!("Failed to fetch the current epoch from the coordinator: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::types::{NodeId, NodeRole};
+    
+    fn tracker(secret: &str) -> EpochTracker {
+        EpochTracker::new(EpochConfig {
+            secret: Some(secret.to_string()),
+            ..EpochConfig::default()
+        })
+    }
+    
+    #[test]
+    fn entry_nodes_sharing_the_secret_derive_the_same_subsets() {
+        let (user, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let (first, second) = (tracker("shared"), tracker("shared"));
+        assert_eq!(first.subset_in(user, 7).seed, second.subset_in(user, 7).seed);
+        assert_ne!(first.subset_in(user, 7).seed, first.subset_in(user, 8).seed);
+        assert_ne!(first.subset_in(user, 7).seed, first.subset_in(other, 7).seed);
+        assert_ne!(first.subset_in(user, 7).seed, tracker("another").subset_in(user, 7).seed);
+    }
+    
+    fn exits() -> Vec<Node> {
+        (0..64)
+            .map(|i| Node {
+                id: NodeId(Uuid::from_u128(i)),
+                ..fixtures::node(&[NodeRole::Exit])
+            })
+            .collect()
+    }
+    
+    fn ids(selected: Vec<&Node>) -> Vec<NodeId> {
+        selected.into_iter().map(|node| node.id.clone()).collect()
+    }
+    
+    #[test]
+    fn a_users_subset_is_stable_within_an_epoch() {
+        let (tracker, exits, user) = (tracker("shared"), exits(), Uuid::from_u128(1));
+        let first = ids(tracker.subset(user).unwrap().select(&exits));
+        assert_eq!(first.len(), 3);
+        assert_eq!(ids(tracker.subset(user).unwrap().select(&exits)), first);
+        
+        // A stale epoch from the coordinator doesn't move the tracker back
+        tracker.observe(tracker.current().unwrap() - 1);
+        assert_eq!(ids(tracker.subset(user).unwrap().select(&exits)), first);
+    }
+    
+    #[test]
+    fn a_users_subset_changes_in_the_next_epoch() {
+        let (tracker, exits, user) = (tracker("shared"), exits(), Uuid::from_u128(1));
+        let before = ids(tracker.subset(user).unwrap().select(&exits));
+        let epoch = tracker.current().unwrap();
+        tracker.observe(epoch + 1);
+        assert_eq!(tracker.current(), Some(epoch + 1));
+        assert_ne!(ids(tracker.subset(user).unwrap().select(&exits)), before);
+    }
+    
+    #[test]
+    fn two_users_get_different_subsets() {
+        let (tracker, exits) = (tracker("shared"), exits());
+        assert_ne!(
+            ids(tracker.subset(Uuid::from_u128(1)).unwrap().select(&exits)),
+            ids(tracker.subset(Uuid::from_u128(2)).unwrap().select(&exits))
+        );
+    }
+}
@@ -0,0 +1,366 @@
+//! Internal event bus for service lifecycle and operational events
+//!
+//! Services emit what happened (a circuit was built, a request completed, a provider was
+//! probed) and leave recording it to subscribers registered at construction, such as
+//! [`MetricsSubscriber`] and [`ActivitySubscriber`]. Subscribers run inline when an event
+//! is emitted and must not block; consumers that need to do slow work should take a
+//! [`EventBus::subscribe`] receiver and handle events on their own task.
+//!
+//! Events carry sizes, durations, labels, and opaque ids only, never payload bytes or
+//! anything identifying a user.
+
+use super::*;
+use super::breaker::BreakerState;
+use super::heartbeat::ActivityCounters;
+use super::resources::Pressure;
+use super::types::{CircuitId, NodeId};
+use tokio::sync::broadcast;
+
+/// Events buffered for each `subscribe` receiver before the slowest one starts missing them
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// How a request ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RequestOutcome {
+    /// A response was delivered
+    Success,
+    /// The request was accepted but serving it failed
+    Failure,
+    /// The request was refused before it was served
+    Rejected,
+}
+
+/// Why a circuit stopped being used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CircuitEnd {
+    /// The circuit's lifetime ran out
+    Expired,
+    /// The circuit stopped answering keepalive pings
+    Unresponsive,
+    /// The circuit was built in an earlier epoch
+    EpochEnded,
+    /// The circuit was idle when the node needed its slot for another
+    Evicted,
+    /// The circuit's user asked for a new one
+    Rotated,
+    /// A hop failed under a request, see [`crate::replay`]
+    HopFailed,
+    /// A node of the circuit went into maintenance, see [`crate::drain`]
+    Drained,
+    /// The circuit was idle while the node was short of resources, see [`crate::resources`]
+    Shed,
+    /// The circuit carried nothing for so long its entry node is taken to have abandoned it, see [`crate::reclaim`]
+    Abandoned,
+    /// The entry node tore the circuit down, see [`crate::transport::CircuitDestroy`]
+    Closed,
+}
+
+/// Something that happened in a service
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A circuit was built or joined
+    CircuitCreated {
+        /// The circuit
+        circuit_id: CircuitId,
+    },
+    /// Building a circuit failed
+    CircuitBuildFailed,
+    /// Circuits kept failing to build, so a constraint of the circuit policy was relaxed, see
+    /// [`crate::relaxation`]
+    CircuitPolicyRelaxed {
+        /// Label of the constraint relaxed
+        constraint: &'static str,
+    },
+    /// Circuits build under a relaxed constraint again, so it was restored
+    CircuitPolicyRestored {
+        /// Label of the constraint restored
+        constraint: &'static str,
+    },
+    /// A circuit was dropped
+    CircuitDestroyed {
+        /// The circuit
+        circuit_id: CircuitId,
+        /// Why it was dropped
+        reason: CircuitEnd,
+    },
+    /// A request was admitted
+    RequestAccepted {
+        /// Method label from [`crate::traffic::method_label`]
+        method: &'static str,
+        /// Request size in bytes
+        size: usize,
+    },
+    /// A request was answered, failed, or refused
+    RequestCompleted {
+        /// Method label from [`crate::traffic::method_label`]
+        method: &'static str,
+        /// How the request ended
+        outcome: RequestOutcome,
+        /// Time from admission to completion
+        latency: Duration,
+        /// Response size in bytes, zero unless the request succeeded
+        size: usize,
+    },
+    /// A provider served a request
+    ProviderUsed {
+        /// The provider
+        provider_id: Uuid,
+        /// The pool label the provider serves under
+        pool: String,
+    },
+    /// A provider's circuit breaker changed state, see [`crate::breaker`]
+    BreakerTransition {
+        /// The provider
+        provider_id: Uuid,
+        /// The state it left
+        from: BreakerState,
+        /// The state it entered
+        to: BreakerState,
+    },
+    /// A provider health probe finished
+    ProviderProbed {
+        /// The provider
+        provider_id: Uuid,
+        /// Whether the probe passed
+        healthy: bool,
+        /// How long the probe took
+        latency: Duration,
+    },
+    /// A peer node sent traffic it had no business sending
+    NodeMisbehaved {
+        /// Address of the peer
+        peer: IpAddr,
+        /// What the peer did
+        reason: String,
+    },
+    /// A registered node went offline
+    NodeOffline {
+        /// The node
+        node_id: NodeId,
+    },
+    /// The coordinator caught a node doing something it had no business doing
+    NodeFlagged {
+        /// The node
+        node_id: NodeId,
+        /// What the node did
+        reason: &'static str,
+    },
+    /// A provider stopped being used
+    ProviderDeactivated {
+        /// The provider
+        provider_id: Uuid,
+        /// Why it stopped being used
+        reason: &'static str,
+    },
+    /// The node directory the coordinator serves changed
+    DirectoryPublished {
+        /// The epoch the directory is published in
+        epoch: u64,
+        /// What changed
+        reason: &'static str,
+    },
+    /// The canary started failing after succeeding
+    CanaryFailed {
+        /// The entry node the failing request went through, if one was available
+        entry: Option<String>,
+        /// Why the request failed
+        failure: String,
+    },
+    /// The node came under more or less resource pressure, see [`crate::resources`]
+    ResourcePressureChanged {
+        /// The pressure it was under
+        from: Pressure,
+        /// The pressure it is under now
+        to: Pressure,
+    },
+    /// The node dropped load to relieve resource pressure
+    LoadShed {
+        /// What was dropped, such as `drop_idle_circuits`
+        action: &'static str,
+        /// How many were dropped
+        count: usize,
+    },
+}
+
+/// A consumer of events, called inline as they are emitted
+pub trait EventSubscriber {
+    /// Handle one event; must return quickly
+    fn on_event(&self, event: &Event);
+}
+
+/// Fans events out to registered subscribers and any `subscribe` receivers
+pub struct EventBus {
+    subscribers: parking_lot::RwLock<Vec<Arc<dyn EventSubscriber + Send + Sync>>>,
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    /// Create a bus with no subscribers
+    pub fn new() -> Self {
+        Self {
+            subscribers: parking_lot::RwLock::new(Vec::new()),
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+        }
+    }
+    
+    /// Register a subscriber for every event emitted from now on
+    pub fn register(&self, subscriber: Arc<dyn EventSubscriber + Send + Sync>) {
+        self.subscribers.write().push(subscriber);
+    }
+    
+    /// A receiver for events emitted from now on, for consumers running on their own task
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+    
+    /// Emit an event to every subscriber
+    pub fn emit(&self, event: Event) {
+        for subscriber in self.subscribers.read().iter() {
+            subscriber.on_event(&event);
+        }
+        // No receivers is fine, the inline subscribers already have it
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Records events as Prometheus metrics
+#[derive(Debug, Default)]
+pub struct MetricsSubscriber;
+
+impl EventSubscriber for MetricsSubscriber {
+    fn on_event(&self, event: &Event) {
+        match event {
+            Event::RequestAccepted { method, size } => traffic::record_request(method, *size),
+            Event::RequestCompleted {
+                method,
+                outcome: RequestOutcome::Success,
+                latency,
+                size,
+            } => traffic::record_response(method, *size, *latency),
+            Event::CircuitDestroyed {
+                reason: CircuitEnd::Unresponsive,
+                ..
+            } => metrics::increment_counter!("darknode_circuit_keepalive_failures_total"),
+            Event::CircuitDestroyed {
+                reason: CircuitEnd::Evicted,
+                ..
+            } => metrics::increment_counter!("darknode_circuits_evicted_total"),
+            Event::ProviderProbed { healthy, .. } => metrics::increment_counter!(
+                "darknode_provider_probes_total",
+                "healthy" => if *healthy { "true" } else { "false" }
+            ),
+            Event::CircuitPolicyRelaxed { constraint } => metrics::increment_counter!(
+                "darknode_circuit_policy_changes_total",
+                "change" => "relaxed",
+                "constraint" => *constraint
+            ),
+            Event::CircuitPolicyRestored { constraint } => metrics::increment_counter!(
+                "darknode_circuit_policy_changes_total",
+                "change" => "restored",
+                "constraint" => *constraint
+            ),
+            Event::ResourcePressureChanged { to, .. } => {
+                metrics::gauge!("darknode_resource_pressure", *to as u8 as f64);
+                metrics::increment_counter!("darknode_resource_pressure_changes_total", "to" => to.label());
+            }
+            Event::LoadShed { action, count } => {
+                metrics::counter!("darknode_load_shed_total", *count as u64, "action" => *action)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Tallies events into the activity counters reported in heartbeats
+pub struct ActivitySubscriber {
+    counters: Arc<ActivityCounters>,
+}
+
+impl ActivitySubscriber {
+    /// Count into `counters`
+    pub fn new(counters: Arc<ActivityCounters>) -> Self {
+        Self { counters }
+    }
+}
+
+impl EventSubscriber for ActivitySubscriber {
+    fn on_event(&self, event: &Event) {
+        match event {
+            Event::CircuitCreated { .. } => self.counters.record_circuit_built(),
+            Event::CircuitBuildFailed
+            | Event::CircuitDestroyed {
+                reason: CircuitEnd::Unresponsive,
+                ..
+            } => self.counters.record_error(),
+            Event::RequestAccepted { method, .. } => self.counters.record_method(method),
+            Event::RequestCompleted { outcome, .. } => match outcome {
+                RequestOutcome::Success => self.counters.record_forwarded(),
+                RequestOutcome::Failure | RequestOutcome::Rejected => self.counters.record_error(),
+            },
+            Event::ProviderUsed { pool, .. } => self.counters.record_pool_use(pool),
+            Event::BreakerTransition { provider_id, to, .. } => self.counters.set_breaker(*provider_id, *to),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EntryConfig;
+    use crate::context::RequestContext;
+    use crate::fixtures::{self, StubRouter};
+    use crate::traits::UserManager;
+    use serde_json::json;
+    
+    fn drain(receiver: &mut broadcast::Receiver<Event>) -> Vec<Event> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+    
+    #[tokio::test]
+    async fn a_request_and_its_circuit_are_announced_from_start_to_end_without_the_user() {
+        let router = Arc::new(StubRouter::new(|_| json!({ "jsonrpc": "2.0", "result": 311_029_712 })));
+        let (entry, users) = fixtures::entry(router.clone(), &EntryConfig::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let mut events = entry.events().subscribe();
+        let request = serde_json::to_vec(&json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" })).unwrap();
+        
+        entry.handle_request(RequestContext::new(&user.api_key), &request).await.unwrap();
+        let built = router.circuits()[0].id.clone();
+        let emitted = drain(&mut events);
+        assert!(matches!(
+            &emitted[..],
+            [
+                Event::RequestAccepted { method: "getSlot", size },
+                Event::CircuitCreated { circuit_id },
+                Event::RequestCompleted { method: "getSlot", outcome: RequestOutcome::Success, size: answered, .. },
+            ] if *size == request.len() && *circuit_id == built && *answered > 0
+        ), "{:?}", emitted);
+        
+        // Rotating drops the circuit and builds the next
+        entry.rotate_circuit(&user.api_key, None).await.unwrap();
+        let rotated = drain(&mut events);
+        assert!(matches!(
+            &rotated[..],
+            [
+                Event::CircuitDestroyed { circuit_id, reason: CircuitEnd::Rotated },
+                Event::CircuitCreated { .. },
+            ] if *circuit_id == built
+        ), "{:?}", rotated);
+        
+        for event in emitted.iter().chain(&rotated) {
+            let described = format!("{:?}", event);
+            assert!(!described.contains(&user.api_key) && !described.contains(&user.id.to_string()), "{}", described);
+        }
+        
+        // The activity counters reported in heartbeats are kept by a subscriber
+        let counted = entry.counters().take();
+        assert_eq!((counted.circuits_built, counted.requests_forwarded, counted.errors), (2, 1, 0));
+    }
+}
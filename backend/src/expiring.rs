@@ -0,0 +1,89 @@
+//! A bounded map whose entries expire a fixed time after they were inserted
+//!
+//! Several pieces of edge state only need to be remembered briefly and must not grow
+//! without bound under load. An [`ExpiringMap`] forgets each entry once its TTL has passed
+//! and, when full, the oldest entry to make room for a new one, so it never holds more
+//! than its capacity however fast entries arrive.
+
+use super::*;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use tokio::time::Instant;
+
+/// A map of at most `capacity` entries, each kept for `ttl` after it was inserted
+pub struct ExpiringMap<K, V> {
+    ttl: Duration,
+    capacity: usize,
+    entries: HashMap<K, (Instant, V)>,
+    /// Keys in insertion order, with the time each was inserted; superseded ones are skipped
+    order: VecDeque<(Instant, K)>,
+}
+
+impl<K: Hash + Eq + Clone, V> ExpiringMap<K, V> {
+    /// Create an empty map
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+    
+    /// The value under `key`, unless it has expired by `now`
+    pub fn get(&self, key: &K, now: Instant) -> Option<&V> {
+        self.entries
+            .get(key)
+            .filter(|(inserted_at, _)| now < *inserted_at + self.ttl)
+            .map(|(_, value)| value)
+    }
+    
+    /// Insert `value` under `key` at `now`, replacing any value already there
+    ///
+    /// Expired entries are dropped first, then the oldest ones while the map is full.
+    pub fn insert(&mut self, key: K, value: V, now: Instant) {
+        self.prune(now);
+        if !self.entries.contains_key(&key) {
+            while self.entries.len() >= self.capacity.max(1) {
+                let Some((inserted_at, oldest)) = self.order.pop_front() else { break };
+                self.remove_inserted(&oldest, inserted_at);
+            }
+        }
+        self.entries.insert(key.clone(), (now, value));
+        self.order.push_back((now, key));
+    }
+    
+    /// Take the value under `key` out of the map, unless it has expired by `now`
+    pub fn remove(&mut self, key: &K, now: Instant) -> Option<V> {
+        let (inserted_at, value) = self.entries.remove(key)?;
+        (now < inserted_at + self.ttl).then_some(value)
+    }
+    
+    /// Entries held, including expired ones not yet dropped
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    
+    /// Whether the map holds no entries
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    
+    /// Drop the entries expired by `now`
+    fn prune(&mut self, now: Instant) {
+        while let Some((inserted_at, _)) = self.order.front() {
+            if now < *inserted_at + self.ttl {
+                break;
+            }
+            let (inserted_at, key) = self.order.pop_front().expect("front is present");
+            self.remove_inserted(&key, inserted_at);
+        }
+    }
+    
+    /// Remove `key` if it is still the entry inserted at `inserted_at`, not a later one
+    fn remove_inserted(&mut self, key: &K, inserted_at: Instant) {
+        if self.entries.get(key).map_or(false, |(at, _)| *at == inserted_at) {
+            self.entries.remove(key);
+        }
+    }
+}
@@ -0,0 +1,316 @@
+//! Fair scheduling of requests between users sharing an entry node
+//!
+//! Left to the task scheduler, whoever sends the most requests gets the most of the
+//! circuits: a chatty user's requests keep winning over a light user's, whose few requests
+//! wait behind the pile. The entry node instead holds at most `max_in_flight` requests in
+//! the network at once, and once that many are out, new ones queue per user. As slots free
+//! up they go out by weighted fair queuing: every request is tagged with the virtual time its
+//! user would finish at if each user got a share of the slots in proportion to its plan's
+//! priority weight, and the smallest tag goes first. A user sending many requests only
+//! pushes its own tags further out, so a light user's request goes before most of them.
+//!
+//! Tags alone would still let a user's request wait behind every other user's, if enough of
+//! them are active. As a bound, a user's oldest request goes out first once it has waited
+//! `max_rounds` dispatches as the oldest, taking turns with other users' that waited as long.
+
+use super::*;
+use super::admission::priority_label;
+use super::types::PriorityClass;
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+/// How requests queue for the network once it is busy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FairnessConfig {
+    /// Whether requests queue fairly at all, rather than all going straight out
+    pub enabled: bool,
+    /// Most requests in the network at once, beyond which new ones queue
+    pub max_in_flight: usize,
+    /// Share of the slots low priority users are weighted with
+    pub low_weight: u32,
+    /// Share of the slots standard priority users are weighted with
+    pub standard_weight: u32,
+    /// Share of the slots high priority users are weighted with
+    pub high_weight: u32,
+    /// Dispatches a user's oldest request waits at most before it goes out first
+    pub max_rounds: u64,
+}
+
+impl Default for FairnessConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_in_flight: 512,
+            low_weight: 1,
+            standard_weight: 2,
+            high_weight: 4,
+            max_rounds: 64,
+        }
+    }
+}
+
+impl FairnessConfig {
+    /// The weight users of a priority class are scheduled with
+    pub fn weight(&self, priority: PriorityClass) -> u32 {
+        let weight = match priority {
+            PriorityClass::Low => self.low_weight,
+            PriorityClass::Standard => self.standard_weight,
+            PriorityClass::High => self.high_weight,
+        };
+        weight.max(1)
+    }
+}
+
+/// A request waiting for a slot
+struct Waiter {
+    /// Virtual time the request finishes at under its user's share
+    finish: f64,
+    /// Dispatches made before the request became its user's oldest
+    round: u64,
+    /// When the request queued
+    queued_at: Instant,
+    /// The priority class the request is scheduled under
+    priority: PriorityClass,
+    /// Hands the request its slot
+    wake: oneshot::Sender<DispatchSlot>,
+}
+
+/// A user's waiting requests
+#[derive(Default)]
+struct UserQueue {
+    /// The finish tag of the user's last queued request
+    last_finish: f64,
+    /// The user's requests, oldest first
+    waiting: VecDeque<Waiter>,
+}
+
+/// Slots in use and the requests waiting for one
+#[derive(Default)]
+struct Schedule {
+    in_flight: usize,
+    virtual_time: f64,
+    dispatched: u64,
+    users: HashMap<Uuid, UserQueue>,
+}
+
+impl Schedule {
+    /// Take the request that goes out next, oldest past the round limit first
+    fn next(&mut self, max_rounds: u64) -> Option<Waiter> {
+        let heads = self
+            .users
+            .iter()
+            .filter_map(|(user_id, queue)| queue.waiting.front().map(|head| (*user_id, head)));
+        let starved = heads
+            .clone()
+            .filter(|(_, head)| self.dispatched - head.round >= max_rounds)
+            .min_by_key(|(_, head)| head.round);
+        let next = starved.or_else(|| heads.min_by(|(_, a), (_, b)| a.finish.total_cmp(&b.finish)));
+        let user_id = next.map(|(user_id, _)| user_id)?;
+        let queue = self.users.get_mut(&user_id)?;
+        let waiter = queue.waiting.pop_front()?;
+        if let Some(head) = queue.waiting.front_mut() {
+            head.round = head.round.max(self.dispatched);
+        }
+        self.dispatched += 1;
+        self.virtual_time = self.virtual_time.max(waiter.finish);
+        let virtual_time = self.virtual_time;
+        self.users
+            .retain(|_, queue| !queue.waiting.is_empty() || queue.last_finish > virtual_time);
+        Some(waiter)
+    }
+    
+    /// Requests waiting for a slot
+    fn queued(&self) -> usize {
+        self.users.values().map(|queue| queue.waiting.len()).sum()
+    }
+}
+
+/// Shares the entry node's slots in the network fairly between users
+pub struct FairQueue {
+    config: FairnessConfig,
+    schedule: parking_lot::Mutex<Schedule>,
+}
+
+/// A slot in the network, held while a request is out and passed on when dropped
+pub struct DispatchSlot {
+    queue: Option<Arc<FairQueue>>,
+}
+
+impl Drop for DispatchSlot {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            queue.release();
+        }
+    }
+}
+
+impl FairQueue {
+    /// Create a queue with every slot free
+    pub fn new(config: FairnessConfig) -> Self {
+        Self {
+            config,
+            schedule: parking_lot::Mutex::new(Schedule::default()),
+        }
+    }
+    
+    /// Wait for a slot for one of a user's requests, scheduled with its priority's weight
+    pub async fn enter(self: &Arc<Self>, user_id: Uuid, priority: PriorityClass) -> DispatchSlot {
+        if !self.config.enabled {
+            return DispatchSlot { queue: None };
+        }
+        let queued_at = Instant::now();
+        let woken = {
+            let mut schedule = self.schedule.lock();
+            let idle = schedule.users.values().all(|queue| queue.waiting.is_empty());
+            if idle && schedule.in_flight < self.config.max_in_flight.max(1) {
+                schedule.in_flight += 1;
+                None
+            } else {
+                let (wake, woken) = oneshot::channel();
+                let virtual_time = schedule.virtual_time;
+                let round = schedule.dispatched;
+                let queue = schedule.users.entry(user_id).or_default();
+                let finish = queue.last_finish.max(virtual_time) + 1.0 / self.config.weight(priority) as f64;
+                queue.last_finish = finish;
+                queue.waiting.push_back(Waiter {
+                    finish,
+                    round,
+                    queued_at,
+                    priority,
+                    wake,
+                });
+                metrics::gauge!("darknode_fair_queue_depth", schedule.queued() as f64);
+                Some(woken)
+            }
+        };
+        match woken {
+            // A slot handed to a request given up on is dropped with it, passing it on
+            Some(woken) => woken.await.unwrap_or(DispatchSlot { queue: None }),
+            None => {
+                record_wait(priority, Duration::ZERO);
+                DispatchSlot {
+                    queue: Some(self.clone()),
+                }
+            }
+        }
+    }
+    
+    /// Requests waiting for a slot
+    pub fn queued(&self) -> usize {
+        self.schedule.lock().queued()
+    }
+    
+    /// Pass a freed slot to the request that goes out next, or free it if none waits
+    ///
+    /// Requests given up on while waiting are skipped.
+    fn release(self: &Arc<Self>) {
+        let mut schedule = self.schedule.lock();
+        while let Some(waiter) = schedule.next(self.config.max_rounds) {
+            let slot = DispatchSlot {
+                queue: Some(self.clone()),
+            };
+            match waiter.wake.send(slot) {
+                Ok(()) => {
+                    record_wait(waiter.priority, waiter.queued_at.elapsed());
+                    metrics::gauge!("darknode_fair_queue_depth", schedule.queued() as f64);
+                    return;
+                }
+                Err(mut slot) => slot.queue = None,
+            }
+        }
+        schedule.in_flight -= 1;
+        metrics::gauge!("darknode_fair_queue_depth", 0.0);
+    }
+}
+
+/// Record how long a request waited for a slot
+fn record_wait(priority: PriorityClass, wait: Duration) {
+    metrics::histogram!("darknode_fair_queue_wait_seconds", wait.as_secs_f64(), "priority" => priority_label(priority));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::task::JoinHandle;
+    
+    /// How long each request holds its slot
+    const SERVICE: Duration = Duration::from_millis(10);
+    
+    /// Send `requests` of `user_id`'s through `queue` at once, each waiting its turn and then
+    /// holding its slot for [`SERVICE`], giving how long each waited
+    fn send(queue: &Arc<FairQueue>, user_id: Uuid, requests: usize) -> Vec<JoinHandle<Duration>> {
+        (0..requests)
+            .map(|_| {
+                let queue = queue.clone();
+                tokio::spawn(async move {
+                    let queued_at = tokio::time::Instant::now();
+                    let slot = queue.enter(user_id, PriorityClass::Standard).await;
+                    let waited = queued_at.elapsed();
+                    tokio::time::sleep(SERVICE).await;
+                    drop(slot);
+                    waited
+                })
+            })
+            .collect()
+    }
+    
+    async fn waits(requests: Vec<JoinHandle<Duration>>) -> Vec<Duration> {
+        let mut waits: Vec<Duration> = futures::future::try_join_all(requests).await.unwrap();
+        waits.sort();
+        waits
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_light_users_wait_stays_bounded_however_much_a_heavy_user_sends() {
+        for heavy in [100, 1000] {
+            let config = FairnessConfig {
+                max_in_flight: 4,
+                ..Default::default()
+            };
+            let queue = Arc::new(FairQueue::new(config));
+            let flooding = send(&queue, Uuid::new_v4(), heavy);
+            tokio::task::yield_now().await;
+            assert_eq!(queue.queued(), heavy - 4);
+            let light = send(&queue, Uuid::new_v4(), 2);
+            
+            // The light user's requests are tagged as if it had its share all along, so they
+            // go out with the first slots freed rather than after the pile
+            let light = waits(light).await;
+            let flooding = waits(flooding).await;
+            assert!(*light.last().unwrap() <= SERVICE, "{} heavy requests: {:?}", heavy, light);
+            let p99 = flooding[flooding.len() * 99 / 100];
+            assert!(p99 >= SERVICE * (heavy as u32 / 8), "{} heavy requests: {:?}", heavy, p99);
+        }
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn slots_are_shared_in_proportion_to_priority_weight() {
+        let config = FairnessConfig {
+            max_in_flight: 1,
+            ..Default::default()
+        };
+        let queue = Arc::new(FairQueue::new(config));
+        let held = queue.enter(Uuid::new_v4(), PriorityClass::Standard).await;
+        let order = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let mut requests = Vec::new();
+        for (user_id, priority) in [(Uuid::new_v4(), PriorityClass::Low), (Uuid::new_v4(), PriorityClass::High)] {
+            for _ in 0..10 {
+                let (queue, order) = (queue.clone(), order.clone());
+                requests.push(tokio::spawn(async move {
+                    let _slot = queue.enter(user_id, priority).await;
+                    order.lock().push(priority);
+                }));
+            }
+        }
+        tokio::task::yield_now().await;
+        drop(held);
+        futures::future::try_join_all(requests).await.unwrap();
+        
+        // High priority is weighted four times low, so of the first ten out, eight are high
+        let order = order.lock();
+        let high = order[..10].iter().filter(|priority| **priority == PriorityClass::High).count();
+        assert_eq!(high, 8, "{:?}", order);
+    }
+}
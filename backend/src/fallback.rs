@@ -0,0 +1,287 @@
+//! Serving requests straight from a provider while the circuit network is down
+//!
+//! When no circuit can be built, for want of routing or exit nodes, an entry node fails its
+//! requests. A user may rather keep working with less privacy than be down, and opt into
+//! that per mapping with [`FallbackMode::DirectProxy`]. Their requests are then sanitized as
+//! ever and forwarded by the entry node itself to a provider from its [`FallbackConfig`]
+//! serving the request's chain and network, as an exit node would pick one: the provider
+//! still sees the entry node's address rather than theirs, but the entry node sees both the
+//! user and what they asked, which a circuit would have kept apart.
+//!
+//! Providers are tried in order until one answers, except that a mutating request, such as
+//! `sendTransaction`, only moves on from a provider it couldn't be sent to: one that was
+//! sent may have been acted on, whatever came back.
+//!
+//! Responses served this way carry `"degraded": "direct"` in their DarkNode extension and
+//! an `X-DarkNode-Degraded: direct` header, are counted in
+//! `darknode_degraded_requests_total` and in the user's metered usage, and are marked in
+//! consenting users' usage records. Only the request itself goes to the provider: options
+//! the exit node acts on, such as quorum reads and relaying, aren't applied. Users who
+//! haven't opted in keep getting the error, and so does everyone on an entry node with no
+//! fallback provider for their request's chain and network.
+
+use super::*;
+use super::chains::{Chain, Network};
+use super::methods;
+use super::upstream::{self, UpstreamLimits};
+
+/// Name of the extension field marking a response served without a circuit
+pub const EXTENSION_FIELD: &str = "degraded";
+
+/// Header marking a response served without a circuit
+pub const DEGRADED_HEADER: &str = "x-darknode-degraded";
+
+/// What a response served straight from a fallback provider is marked with
+pub const DIRECT: &str = "direct";
+
+/// How a mapping's requests are served while no circuit can be built
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FallbackMode {
+    /// Forward them from the entry node straight to a fallback provider
+    DirectProxy,
+}
+
+/// A provider an entry node falls back to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FallbackProvider {
+    /// The provider's URL
+    pub url: String,
+    /// The chain the provider serves
+    pub chain: Chain,
+    /// The network of the chain the provider serves
+    pub network: Network,
+}
+
+impl FallbackProvider {
+    /// Whether the provider serves requests on `chain`, any if it isn't known, and `network`
+    fn serves(&self, chain: Option<Chain>, network: Network) -> bool {
+        chain.map_or(true, |chain| chain == self.chain) && network == self.network
+    }
+}
+
+/// The providers an entry node falls back to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FallbackConfig {
+    /// Providers, tried in order until one serving the request answers
+    pub providers: Vec<FallbackProvider>,
+    /// Longest to wait for each provider
+    pub timeout: Duration,
+    /// What is accepted of the providers' responses, as on exit nodes
+    pub limits: UpstreamLimits,
+}
+
+impl Default for FallbackConfig {
+    fn default() -> Self {
+        Self {
+            providers: Vec::new(),
+            timeout: Duration::from_secs(10),
+            limits: UpstreamLimits::default(),
+        }
+    }
+}
+
+/// Forwards requests to the fallback providers
+pub struct DirectProxy {
+    config: FallbackConfig,
+    client: reqwest::Client,
+}
+
+impl DirectProxy {
+    /// Forward to the providers in `config`, or nothing if it lists none
+    pub fn new(config: FallbackConfig) -> Option<Self> {
+        if config.providers.is_empty() {
+            return None;
+        }
+        Some(Self {
+            config,
+            client: reqwest::Client::new(),
+        })
+    }
+    
+    /// Whether any of the providers serves requests on `chain` and `network`
+    pub fn serves(&self, chain: Option<Chain>, network: Network) -> bool {
+        self.config.providers.iter().any(|provider| provider.serves(chain, network))
+    }
+    
+    /// Forward `request`, a sanitized JSON-RPC request on `chain` and `network`, returning
+    /// the first answer of a provider serving them
+    ///
+    /// Each provider is given the configured timeout or what's left of `budget`, whichever
+    /// is shorter, and the last failure is returned if none answers. Requests whose method
+    /// can't be told are treated as mutating.
+    pub async fn forward(
+        &self,
+        request: &serde_json::Value,
+        chain: Option<Chain>,
+        network: Network,
+        budget: Duration,
+    ) -> Result<Vec<u8>> {
+        let started = std::time::Instant::now();
+        let mutating = methods::method_name(request).map_or(true, methods::is_mutating);
+        let mut failure = anyhow::anyhow!("No fallback provider serves {} requests", network);
+        let providers = self.config.providers.iter().filter(|provider| provider.serves(chain, network));
+        for provider in providers {
+            let timeout = self.config.timeout.min(budget.saturating_sub(started.elapsed()));
+            if timeout.is_zero() {
+                failure = anyhow::anyhow!("No fallback provider answered in time");
+                break;
+            }
+            let sent = self.client.post(&provider.url).json(request).timeout(timeout).send().await;
+            let response = match sent.and_then(|response| response.error_for_status()) {
+                Ok(response) => response,
+                Err(e) => {
+                    let unsent = e.is_connect();
+                    let e = e.without_url();
+                    tracing::warn!("Fallback provider failed: {}", e);
+                    if mutating && !unsent {
+                        return Err(e.into());
+                    }
+                    failure = e.into();
+                    continue;
+                }
+            };
+            match upstream::read_body(response, &self.config.limits).await {
+                Ok(body) => return Ok(body),
+                Err(e) if mutating => return Err(e),
+                Err(e) => {
+                    tracing::warn!("Fallback provider's response was refused: {}", e);
+                    failure = e;
+                }
+            }
+        }
+        Err(failure)
+    }
+}
+
+/// Mark `response` as served without a circuit
+pub fn mark_direct(response: &mut serde_json::Value) {
+    methods::set_extension(response, EXTENSION_FIELD, serde_json::json!(DIRECT));
+}
+
+/// Whether `extension`, a response's DarkNode extension, marks it as served without a circuit
+pub fn is_direct(extension: &serde_json::Value) -> bool {
+    extension.get(EXTENSION_FIELD).and_then(|value| value.as_str()) == Some(DIRECT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::ProviderAbuse;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::Router;
+    
+    /// A provider answering every request with `status` and `body`
+    async fn stub_provider_with(status: StatusCode, body: &'static str) -> String {
+        let app = Router::new().route("/", post(move || async move { (status, body) }));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        format!("http://{}/", addr)
+    }
+    
+    /// A provider answering every request with `body`
+    async fn stub_provider(body: &'static str) -> String {
+        stub_provider_with(StatusCode::OK, body).await
+    }
+    
+    /// A Solana mainnet provider at `url`
+    fn mainnet(url: &str) -> FallbackProvider {
+        FallbackProvider {
+            url: url.to_string(),
+            chain: Chain::Solana,
+            network: Network::Mainnet,
+        }
+    }
+    
+    fn request(method: &str) -> serde_json::Value {
+        serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": method})
+    }
+    
+    #[tokio::test]
+    async fn oversized_answers_are_refused_and_the_next_provider_tried() {
+        let oversized = stub_provider(r#"{"jsonrpc":"2.0","id":1,"result":"a result far longer than the limit allows"}"#).await;
+        let small = stub_provider(r#"{"jsonrpc":"2.0","id":1,"result":1}"#).await;
+        let limits = UpstreamLimits {
+            max_response_size: 48,
+            ..UpstreamLimits::default()
+        };
+        let request = request("getSlot");
+        
+        let only_oversized = DirectProxy::new(FallbackConfig {
+            providers: vec![mainnet(&oversized)],
+            limits: limits.clone(),
+            ..FallbackConfig::default()
+        })
+        .unwrap();
+        let refused = only_oversized
+            .forward(&request, Some(Chain::Solana), Network::Mainnet, Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            refused.downcast_ref::<ProviderAbuse>(),
+            Some(&ProviderAbuse::ResponseTooLarge { limit: 48 })
+        );
+        
+        let proxy = DirectProxy::new(FallbackConfig {
+            providers: vec![mainnet(&oversized), mainnet(&small)],
+            limits,
+            ..FallbackConfig::default()
+        })
+        .unwrap();
+        let answer = proxy
+            .forward(&request, Some(Chain::Solana), Network::Mainnet, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(answer, br#"{"jsonrpc":"2.0","id":1,"result":1}"#);
+    }
+    
+    #[tokio::test]
+    async fn requests_only_reach_providers_of_their_chain_and_network() {
+        let mainnet_url = stub_provider(r#"{"jsonrpc":"2.0","id":1,"result":"mainnet"}"#).await;
+        let devnet_url = stub_provider(r#"{"jsonrpc":"2.0","id":1,"result":"devnet"}"#).await;
+        let proxy = DirectProxy::new(FallbackConfig {
+            providers: vec![
+                mainnet(&mainnet_url),
+                FallbackProvider {
+                    network: Network::Devnet,
+                    ..mainnet(&devnet_url)
+                },
+            ],
+            ..FallbackConfig::default()
+        })
+        .unwrap();
+        
+        assert!(proxy.serves(None, Network::Devnet));
+        assert!(!proxy.serves(Some(Chain::Ethereum), Network::Mainnet));
+        assert!(!proxy.serves(Some(Chain::Solana), Network::Testnet));
+        let answer = proxy
+            .forward(&request("getSlot"), Some(Chain::Solana), Network::Devnet, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(answer, br#"{"jsonrpc":"2.0","id":1,"result":"devnet"}"#);
+        assert!(proxy
+            .forward(&request("getSlot"), Some(Chain::Solana), Network::Testnet, Duration::from_secs(5))
+            .await
+            .is_err());
+    }
+    
+    #[tokio::test]
+    async fn writes_are_not_sent_again_to_another_provider() {
+        let failing = stub_provider_with(StatusCode::BAD_GATEWAY, "").await;
+        let answering = stub_provider(r#"{"jsonrpc":"2.0","id":1,"result":"sent"}"#).await;
+        let proxy = DirectProxy::new(FallbackConfig {
+            providers: vec![mainnet(&failing), mainnet(&answering)],
+            ..FallbackConfig::default()
+        })
+        .unwrap();
+        
+        let (read, write) = (request("getSlot"), request("sendTransaction"));
+        let forward = |request| proxy.forward(request, Some(Chain::Solana), Network::Mainnet, Duration::from_secs(5));
+        assert!(forward(&read).await.is_ok());
+        assert!(forward(&write).await.is_err());
+    }
+}
@@ -0,0 +1,315 @@
+//! Records and services for tests to build on
+//!
+//! Tests need nodes, providers and mappings, but only care about a field or two of each.
+//! The functions here give a record with every other field at an unremarkable value,
+//! for tests to override with struct update syntax, so a field added to the type is
+//! only added here:
+//!
+//! ```ignore
+//! let exit = Node {
+//!     region: "eu-west".to_string(),
+//!     ..fixtures::node(&[NodeRole::Exit])
+//! };
+//! ```
+//!
+//! Providers answering on loopback, see [`serving`], can be served from by an exit node
+//! with every option at its default, see [`exit`], or as configured, see [`exit_with`].
+//! An entry node built from its config, see [`entry`], can send through a [`StubRouter`]
+//! standing in for the network. What tests record in metrics can be read back from
+//! [`prometheus`].
+//!
+//! Built for the crate's own tests and with the `test-util` feature. Nothing here is part
+//! of the stable API: the records change with the types, in any release.
+
+use super::*;
+use super::types::*;
+
+/// An online node of `roles` in us-east, speaking the legacy protocol, known to no manager
+pub fn node(roles: &[NodeRole]) -> Node {
+    Node {
+        id: NodeId(Uuid::new_v4()),
+        roles: roles.to_vec(),
+        status: NodeStatus::Online,
+        public_key: CryptoKey(vec![7; 32]),
+        ip_address: IpAddr::from([203, 0, 113, 1]),
+        port: 3000,
+        last_seen: Timestamp::now(),
+        region: "us-east".to_string(),
+        load: 0.0,
+        next_public_key: None,
+        next_key_activates_at: None,
+        pool: None,
+        protocol: protocol::ProtocolRange::legacy(),
+        budget: None,
+        build: None,
+        method_classes: None,
+        receipt_key: None,
+    }
+}
+
+/// An active Solana mainnet provider of the shared pool, at an address nothing listens on
+pub fn provider() -> RpcProvider {
+    RpcProvider {
+        id: Uuid::new_v4(),
+        url: "http://127.0.0.1:1/".to_string(),
+        provider_type: "solana".to_string(),
+        state: ProviderState::Active,
+        success_rate: 0.99,
+        avg_latency: Duration::from_millis(100),
+        last_checked: Timestamp::now(),
+        capabilities: Vec::new(),
+        pool: None,
+        network: chains::Network::Mainnet,
+        auth: None,
+        weight: 1,
+        maintenance_windows: Vec::new(),
+        tripped_breakers: 0,
+        failed_probes: 0,
+        quota: None,
+        submission: None,
+    }
+}
+
+/// A mapping of Solana mainnet with nothing turned on, of no user yet
+pub fn mapping() -> RpcMapping {
+    let id = Uuid::new_v4();
+    RpcMapping {
+        id,
+        original_rpc: "https://api.mainnet-beta.solana.com".to_string(),
+        darknode_https_rpc: format!("https://{}.darknode.invalid", id),
+        darknode_wss_rpc: format!("wss://{}.darknode.invalid", id),
+        created_at: Timestamp::now(),
+        quorum: None,
+        skip_validation: false,
+        preflight: false,
+        pool: None,
+        debug_errors: false,
+        timeouts: Default::default(),
+        require_request_signature: false,
+        chain: None,
+        network: chains::Network::Mainnet,
+        normalize_results: false,
+        fallback_mode: None,
+        address_scatter: false,
+        routes: Default::default(),
+        provider_attribution: false,
+        region: None,
+    }
+}
+
+/// A request for `method` with `params` to an exit node, with every option off
+pub fn payload(method: &str, params: serde_json::Value) -> ExitPayload {
+    ExitPayload {
+        request: serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }),
+        ..keepalive::ping()
+    }
+}
+
+/// The Prometheus recorder of the process, installed the first time it is asked for
+///
+/// Metrics are recorded from every test running at the time, so tests look for labels of
+/// their own or for counts having grown.
+pub fn prometheus() -> &'static metrics_exporter_prometheus::PrometheusHandle {
+    static HANDLE: std::sync::OnceLock<metrics_exporter_prometheus::PrometheusHandle> = std::sync::OnceLock::new();
+    HANDLE.get_or_init(|| traffic::install_prometheus().unwrap())
+}
+
+/// A provider on loopback answering each JSON-RPC request it is sent with `answer`
+///
+/// Must be called within a Tokio runtime, which serves it until the test ends.
+pub fn serving<F, A>(answer: F) -> RpcProvider
+where
+    F: Fn(serde_json::Value) -> A + Clone + Send + Sync + 'static,
+    A: std::future::Future<Output = serde_json::Value> + Send + 'static,
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let app = axum::Router::new().route(
+        "/",
+        axum::routing::post(move |axum::Json(request): axum::Json<serde_json::Value>| {
+            let answer = answer.clone();
+            async move { axum::Json(answer(request).await) }
+        }),
+    );
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+    RpcProvider {
+        url,
+        success_rate: 1.0,
+        avg_latency: Duration::from_millis(10),
+        ..provider()
+    }
+}
+
+/// An exit node with every option at its default, serving from the providers of
+/// `rpc_manager`, which may be on loopback
+pub fn exit(rpc_manager: Arc<dyn traits::RpcManager + Send + Sync>) -> exit_node::ExitNodeService {
+    exit_with(rpc_manager, Default::default())
+}
+
+/// An exit node configured by `config`, serving from the providers of `rpc_manager`,
+/// which may be on loopback
+pub fn exit_with(
+    rpc_manager: Arc<dyn traits::RpcManager + Send + Sync>,
+    config: exit_node::ExitNodeConfig,
+) -> exit_node::ExitNodeService {
+    let resolver = dns::ResolverConfig {
+        allow_private_addresses: true,
+        ..Default::default()
+    };
+    exit_node::ExitNodeService::new(
+        NodeId(Uuid::new_v4()),
+        Arc::new(impls::CryptoImpl::new()),
+        rpc_manager,
+        dns::ProviderResolver::new(resolver),
+        audit::AuditLog::new(Default::default()),
+        config,
+    )
+}
+
+/// An entry node configured by `config` that sends requests through `router`, and the
+/// manager of the users it serves
+pub async fn entry(
+    router: Arc<dyn traits::Router + Send + Sync>,
+    config: &config::EntryConfig,
+) -> (entry_node::EntryNodeService, Arc<impls::StoredUserManager>) {
+    let crypto: Arc<dyn traits::Crypto + Send + Sync> = Arc::new(impls::CryptoImpl::new());
+    let users = Arc::new(impls::StoredUserManager::new(Arc::new(storage::MemoryStorage::new())));
+    let receipt_key = Arc::new(identity::NodeIdentity::generate(&*crypto, Duration::from_secs(3600)).await.unwrap());
+    let features = entry_node::EntryNodeConfig {
+        sessions: config.sessions.clone(),
+        validation: config.validation.clone(),
+        keepalive: config.keepalive.clone(),
+        admission: config.admission.clone(),
+        capacity: config.circuits.clone(),
+        emulation: config.emulation.clone(),
+        timeouts: config.timeouts.clone(),
+        signing: config.signing.clone(),
+        fairness: config.fairness.clone(),
+        replay: config.replay.clone(),
+        shadow: config.shadow.clone(),
+        drain: config.drain.clone(),
+        relaxation: config.relaxation.clone(),
+        ..Default::default()
+    };
+    let service = entry_node::EntryNodeService::new(
+        NodeId(Uuid::new_v4()),
+        crypto,
+        router,
+        Arc::new(sanitizer::Sanitizer::new(&config.sanitizer)),
+        users.clone(),
+        receipt_key,
+        features,
+    )
+    .with_circuit_classes(config.circuit_classes.clone())
+    .with_scatter(config.scatter.clone());
+    (service, users)
+}
+
+/// A router whose circuits lead to no network, `answer` standing in for the exit node
+///
+/// Each circuit gets a routing hop of its own, which [`StubRouter::kill`] makes refuse
+/// whatever is sent through it. Pings are answered with a pong, as an exit node would.
+pub struct StubRouter {
+    answer: Box<dyn Fn(&ExitPayload) -> serde_json::Value + Send + Sync>,
+    circuits: parking_lot::Mutex<Vec<Circuit>>,
+    dead: parking_lot::Mutex<Vec<NodeId>>,
+    sent: parking_lot::Mutex<Vec<(CircuitId, ExitPayload)>>,
+    closed: parking_lot::Mutex<Vec<CircuitId>>,
+    answers: dashmap::DashMap<Uuid, serde_json::Value>,
+}
+
+impl StubRouter {
+    /// Create a router answering each request with the response `answer` gives, whose id is set to the request's
+    pub fn new(answer: impl Fn(&ExitPayload) -> serde_json::Value + Send + Sync + 'static) -> Self {
+        Self {
+            answer: Box::new(answer),
+            circuits: Default::default(),
+            dead: Default::default(),
+            sent: Default::default(),
+            closed: Default::default(),
+            answers: Default::default(),
+        }
+    }
+    
+    /// Make the hop `node` refuse everything sent through it from now on
+    pub fn kill(&self, node: &NodeId) {
+        self.dead.lock().push(node.clone());
+    }
+    
+    /// The circuits built, oldest first
+    pub fn circuits(&self) -> Vec<Circuit> {
+        self.circuits.lock().clone()
+    }
+    
+    /// The requests sent other than pings, and the circuits they were sent through, oldest first
+    pub fn sent(&self) -> Vec<(CircuitId, ExitPayload)> {
+        self.sent.lock().clone()
+    }
+    
+    /// The circuits closed, oldest first
+    pub fn closed(&self) -> Vec<CircuitId> {
+        self.closed.lock().clone()
+    }
+}
+
+#[async_trait]
+impl traits::Router for StubRouter {
+    async fn create_circuit(&self) -> Result<Circuit> {
+        self.create_circuit_with(&CircuitPreferences::default()).await
+    }
+    
+    async fn create_circuit_with(&self, preferences: &CircuitPreferences) -> Result<Circuit> {
+        let created_at = Timestamp::now();
+        let circuit = Circuit {
+            id: CircuitId(Uuid::new_v4()),
+            entry_node: NodeId(Uuid::new_v4()),
+            routing_nodes: vec![NodeId(Uuid::new_v4())],
+            exit_node: NodeId(Uuid::new_v4()),
+            symmetric_keys: Vec::new(),
+            created_at,
+            expires_at: created_at + preferences.lifetime.unwrap_or(circuit_class::DEFAULT_LIFETIME),
+            regions: Vec::new(),
+            protocol_version: protocol::legacy_version(),
+            estimated_latency: None,
+            relaxed: Vec::new(),
+            exit_classes: None,
+        };
+        self.circuits.lock().push(circuit.clone());
+        Ok(circuit)
+    }
+    
+    async fn close_circuit(&self, circuit: &Circuit) -> Result<()> {
+        self.closed.lock().push(circuit.id.clone());
+        Ok(())
+    }
+    
+    async fn send_request(&self, _ctx: &context::RequestContext, circuit: &Circuit, request: &[u8]) -> Result<Uuid> {
+        if let Some(node) = circuit.routing_nodes.iter().find(|node| self.dead.lock().contains(node)) {
+            return Err(replay::HopFailure {
+                node: Some(node.clone()),
+                kind: replay::HopFailureKind::Unreachable,
+            }
+            .into());
+        }
+        let payload: ExitPayload = serde_json::from_slice(request)?;
+        let mut answer = match keepalive::is_ping(&payload) {
+            true => serde_json::from_slice(&keepalive::pong(&payload)?)?,
+            false => (self.answer)(&payload),
+        };
+        answer["id"] = payload.request["id"].clone();
+        if !keepalive::is_ping(&payload) {
+            self.sent.lock().push((circuit.id.clone(), payload));
+        }
+        let request_id = Uuid::new_v4();
+        self.answers.insert(request_id, answer);
+        Ok(request_id)
+    }
+    
+    async fn receive_response(&self, request_id: Uuid) -> Result<Vec<u8>> {
+        let (_, answer) = self
+            .answers
+            .remove(&request_id)
+            .ok_or_else(|| anyhow::anyhow!("Request {} was never sent", request_id))?;
+        Ok(serde_json::to_vec(&answer)?)
+    }
+}
@@ -0,0 +1,347 @@
+//! Feature flags set network-wide through the directory
+//!
+//! During an incident, such as a provider poisoning responses or a bug in a crypto path,
+//! operators need a feature off everywhere at once, not after redeploying every node. The
+//! coordinator keeps a set of [`Flag`]s, each a name with a boolean or numeric value, and
+//! publishes them in the signed directory, so they reach nodes with the same authenticity
+//! as the nodes listed there. Nodes following the directory hand them to a shared
+//! [`FeatureFlags`] handle, which components check at their decision points:
+//!
+//! | Flag           | Default | Checked by                                                |
+//! |----------------|---------|-----------------------------------------------------------|
+//! | `cache`        | on      | Exit nodes answering reads from their response cache      |
+//! | `quorum_reads` | on      | Exit nodes fanning reads out to several providers         |
+//! | `hedging`      | on      | Exit nodes sending slow reads to a second provider        |
+//! | `shaping`      | on      | Nodes holding messages back, where shaping is configured  |
+//! | `compression`  | on      | Entry nodes compressing responses to clients              |
+//!
+//! A flag that isn't set, has expired, or has a value of the wrong type leaves the feature
+//! as the node's config has it, so flags can only turn features off that are on, or back
+//! on, never enable what a node isn't configured for. Setting or clearing a flag publishes
+//! the directory again, and nodes act on it from their next directory refresh.
+//!
+//! Every flag is set with an expiry, `PUT /flags/:name` refusing one without, or beyond
+//! [`FlagsConfig::max_lifetime`], so an override made in a hurry can't be forgotten. The
+//! coordinator's flag routes take the operator token, see [`crate::operator`].
+
+use super::*;
+use super::storage::{Collection, Precondition, Storage};
+use std::collections::BTreeMap;
+
+/// Collection of the flags set, keyed by name
+const FLAGS: &str = "feature_flags";
+
+/// A feature that flags can turn off, and whether it is on when no flag says
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Feature {
+    /// The name of the flag for the feature
+    pub name: &'static str,
+    /// Whether the feature is on without a flag
+    pub default: bool,
+}
+
+/// Exit nodes answering reads from their response cache, see [`crate::cache`]
+pub const CACHE: Feature = Feature {
+    name: "cache",
+    default: true,
+};
+
+/// Exit nodes fanning reads out to several providers, see [`crate::quorum`]
+pub const QUORUM_READS: Feature = Feature {
+    name: "quorum_reads",
+    default: true,
+};
+
+/// Exit nodes sending slow reads to a second provider, see [`crate::hedge`]
+pub const HEDGING: Feature = Feature {
+    name: "hedging",
+    default: true,
+};
+
+/// Nodes holding messages back to the next tick, see [`crate::shaping`]
+pub const SHAPING: Feature = Feature {
+    name: "shaping",
+    default: true,
+};
+
+/// Entry nodes compressing responses to clients
+pub const COMPRESSION: Feature = Feature {
+    name: "compression",
+    default: true,
+};
+
+/// Every feature flags can turn off
+pub const FEATURES: [Feature; 5] = [CACHE, QUORUM_READS, HEDGING, SHAPING, COMPRESSION];
+
+/// The value of a flag
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FlagValue {
+    /// Whether a feature is on
+    Bool(bool),
+    /// A number a component reads, such as a rate
+    Number(f64),
+}
+
+/// A flag as set by an operator
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Flag {
+    /// The flag's value
+    pub value: FlagValue,
+    /// When the flag stops applying
+    pub expires_at: Timestamp,
+}
+
+/// How long flags may be set for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlagsConfig {
+    /// Longest a flag may be set for
+    pub max_lifetime: Duration,
+}
+
+impl Default for FlagsConfig {
+    fn default() -> Self {
+        Self {
+            max_lifetime: Duration::from_secs(7 * 24 * 3600),
+        }
+    }
+}
+
+/// A flag the coordinator won't set
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum FlagRefused {
+    /// The flag was set without a lifetime
+    #[error("flags must be set with an expiry")]
+    NoExpiry,
+    /// The flag was set for longer than flags may be
+    #[error("flags may be set for at most {}s", .max.as_secs())]
+    TooLong {
+        /// Longest a flag may be set for
+        max: Duration,
+    },
+    /// The flag of a feature was set to something other than on or off
+    #[error("flag `{0}` must be true or false")]
+    NotBoolean(String),
+    /// The name isn't fit for a flag
+    #[error("invalid flag name {0:?}")]
+    InvalidName(String),
+}
+
+/// A node's view of the flags published in the directory, shared by its components
+///
+/// Cloning the handle shares the flags.
+#[derive(Debug, Clone, Default)]
+pub struct FeatureFlags {
+    flags: Arc<parking_lot::RwLock<BTreeMap<String, Flag>>>,
+}
+
+impl FeatureFlags {
+    /// A handle with no flags set, every feature as configured
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Take the flags of a newly followed directory, replacing those held
+    pub fn update(&self, flags: BTreeMap<String, Flag>) {
+        let mut held = self.flags.write();
+        if *held != flags {
+            tracing::info!("Feature flags changed to {:?}", flags);
+            *held = flags;
+        }
+    }
+    
+    /// Whether `feature` is on now
+    pub fn enabled(&self, feature: Feature) -> bool {
+        self.enabled_at(feature, Timestamp::now())
+    }
+    
+    /// Whether `feature` is on at `now`
+    pub fn enabled_at(&self, feature: Feature, now: Timestamp) -> bool {
+        match self.value_at(feature.name, now) {
+            Some(FlagValue::Bool(on)) => on,
+            _ => feature.default,
+        }
+    }
+    
+    /// The number flag `name` is set to now, or `default`
+    pub fn number(&self, name: &str, default: f64) -> f64 {
+        match self.value_at(name, Timestamp::now()) {
+            Some(FlagValue::Number(number)) => number,
+            _ => default,
+        }
+    }
+    
+    /// The value of flag `name` at `now`, unless unset or expired
+    fn value_at(&self, name: &str, now: Timestamp) -> Option<FlagValue> {
+        self.flags
+            .read()
+            .get(name)
+            .filter(|flag| flag.expires_at > now)
+            .map(|flag| flag.value)
+    }
+}
+
+/// The flags the coordinator publishes, kept in storage
+pub struct FlagBoard {
+    config: FlagsConfig,
+    flags: Collection<Flag>,
+}
+
+impl FlagBoard {
+    /// Create a board over `storage`
+    pub fn new(config: FlagsConfig, storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self {
+            config,
+            flags: Collection::new(storage, FLAGS),
+        }
+    }
+    
+    /// Set flag `name` to `value` for `lifetime` from `now`
+    pub async fn set(&self, name: &str, value: FlagValue, lifetime: Duration, now: Timestamp) -> Result<Flag> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+            return Err(FlagRefused::InvalidName(name.to_string()).into());
+        }
+        if lifetime.is_zero() {
+            return Err(FlagRefused::NoExpiry.into());
+        }
+        if lifetime > self.config.max_lifetime {
+            return Err(FlagRefused::TooLong {
+                max: self.config.max_lifetime,
+            }
+            .into());
+        }
+        if FEATURES.iter().any(|feature| feature.name == name) && !matches!(value, FlagValue::Bool(_)) {
+            return Err(FlagRefused::NotBoolean(name.to_string()).into());
+        }
+        
+        let flag = Flag {
+            value,
+            expires_at: now + lifetime,
+        };
+        self.flags.put(name, &flag, Precondition::Any).await?;
+        tracing::warn!("Feature flag {} set to {:?} until {}", name, value, flag.expires_at);
+        Ok(flag)
+    }
+    
+    /// Clear flag `name`, returning whether it was set
+    pub async fn clear(&self, name: &str) -> Result<bool> {
+        self.flags.delete(name, Precondition::Any).await
+    }
+    
+    /// The flags that haven't expired by `now`
+    pub async fn in_force(&self, now: Timestamp) -> Result<BTreeMap<String, Flag>> {
+        Ok(self
+            .flags
+            .scan("")
+            .await?
+            .into_iter()
+            .filter(|(_, flag)| flag.expires_at > now)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CacheConfig;
+    use crate::directory::{DirectoryConfig, DirectoryFollower, DirectoryPublisher, Which};
+    use crate::exit_node::ExitNodeConfig;
+    use crate::fixtures;
+    use crate::identity::NodeIdentity;
+    use crate::impls::{CryptoImpl, StoredRpcManager};
+    use crate::storage::MemoryStorage;
+    use crate::traits::{Crypto, RpcManager};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    
+    const HOUR: Duration = Duration::from_secs(3600);
+    
+    fn board() -> FlagBoard {
+        FlagBoard::new(FlagsConfig::default(), Arc::new(MemoryStorage::new()))
+    }
+    
+    #[tokio::test]
+    async fn turning_the_cache_off_in_the_directory_sends_the_next_read_to_a_provider() {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let identity = Arc::new(NodeIdentity::generate(&*crypto, Duration::ZERO).await.unwrap());
+        let publisher = DirectoryPublisher::new(DirectoryConfig::default(), HOUR, crypto.clone(), identity);
+        let now = Timestamp::now();
+        let config = DirectoryConfig {
+            coordinator_key: Some(publisher.signer(now)),
+            ..Default::default()
+        };
+        let flags = FeatureFlags::new();
+        let follower = DirectoryFollower::new(config, crypto).unwrap().with_flags(flags.clone());
+        
+        let fetched = Arc::new(AtomicUsize::new(0));
+        let counted = fetched.clone();
+        let provider = fixtures::serving(move |request: serde_json::Value| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            async move { serde_json::json!({ "jsonrpc": "2.0", "id": request["id"], "result": "genesis" }) }
+        });
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        rpc_manager.register_provider(provider).await.unwrap();
+        let cache = CacheConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        let exit = fixtures::exit_with(rpc_manager, ExitNodeConfig { cache, ..Default::default() });
+        let exit = Arc::new(exit.with_flags(flags));
+        let read = fixtures::payload("getGenesisHash", serde_json::json!([]));
+        
+        exit.serve(&read).await.unwrap();
+        exit.serve(&read).await.unwrap();
+        assert_eq!(fetched.load(Ordering::SeqCst), 1);
+        
+        // An operator turns the cache off, and the node follows the directory published next
+        let board = board();
+        board.set(CACHE.name, FlagValue::Bool(false), HOUR, now).await.unwrap();
+        let later = now + Duration::from_secs(1);
+        let epoch = publisher.epoch(Which::Current, later).unwrap();
+        let signed = publisher.publish(epoch, Vec::new(), board.in_force(later).await.unwrap(), later).await.unwrap();
+        follower.accept(Which::Current, &signed, later).await.unwrap();
+        follower.current(later);
+        
+        exit.serve(&read).await.unwrap();
+        assert_eq!(fetched.load(Ordering::SeqCst), 2);
+    }
+    
+    #[test]
+    fn an_expired_flag_leaves_the_feature_as_configured() {
+        let now = Timestamp::now();
+        let flags = FeatureFlags::new();
+        let off_for_a_minute = Flag {
+            value: FlagValue::Bool(false),
+            expires_at: now + Duration::from_secs(60),
+        };
+        flags.update(BTreeMap::from([(HEDGING.name.to_string(), off_for_a_minute)]));
+        
+        assert!(!flags.enabled_at(HEDGING, now));
+        assert!(flags.enabled_at(HEDGING, now + Duration::from_secs(60)));
+        assert!(flags.enabled_at(CACHE, now));
+    }
+    
+    #[tokio::test]
+    async fn flags_are_refused_without_an_expiry_for_too_long_or_of_the_wrong_type() {
+        let board = board();
+        let now = Timestamp::now();
+        let off = FlagValue::Bool(false);
+        
+        let refused = |result: Result<Flag>| result.unwrap_err().downcast::<FlagRefused>().unwrap();
+        assert_eq!(refused(board.set(CACHE.name, off, Duration::ZERO, now).await), FlagRefused::NoExpiry);
+        let max = FlagsConfig::default().max_lifetime;
+        assert_eq!(
+            refused(board.set(CACHE.name, off, max + Duration::from_secs(1), now).await),
+            FlagRefused::TooLong { max }
+        );
+        assert_eq!(
+            refused(board.set(CACHE.name, FlagValue::Number(0.5), HOUR, now).await),
+            FlagRefused::NotBoolean(CACHE.name.to_string())
+        );
+        assert!(board.in_force(now).await.unwrap().is_empty());
+        
+        // Other flags may hold numbers, for as long as flags may be set
+        board.set("mirror_rate", FlagValue::Number(0.5), max, now).await.unwrap();
+        assert_eq!(board.in_force(now).await.unwrap().len(), 1);
+    }
+}
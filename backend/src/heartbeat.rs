@@ -0,0 +1,349 @@
+//! Node-side activity counting and heartbeat delivery
+
+use super::*;
+use super::accounting::{EpochWork, Work};
+use super::breaker::BreakerState;
+use super::budget::BudgetReport;
+use super::build_info::BuildInfo;
+use super::outbox::{Outbox, Report, ReportKind};
+use super::reachability::ProbeResult;
+use super::resources::{Pressure, ResourceGuard};
+use super::timeouts::MethodClass;
+use super::types::*;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Lock-free activity counters shared between a node's service and its heartbeat task
+#[derive(Debug, Default)]
+pub struct ActivityCounters {
+    circuits_built: AtomicU64,
+    requests_forwarded: AtomicU64,
+    errors: AtomicU64,
+    pool_usage: parking_lot::Mutex<BTreeMap<String, u64>>,
+    method_usage: parking_lot::Mutex<BTreeMap<String, u64>>,
+    unique_users: parking_lot::Mutex<Option<u64>>,
+    work: parking_lot::Mutex<BTreeMap<u64, Work>>,
+    load: parking_lot::Mutex<f64>,
+    overloaded: AtomicBool,
+    breakers: parking_lot::Mutex<BTreeMap<Uuid, BreakerState>>,
+    peer_latency: parking_lot::Mutex<BTreeMap<String, Duration>>,
+    budget: parking_lot::Mutex<Option<BudgetReport>>,
+    probes: parking_lot::Mutex<Vec<ProbeResult>>,
+}
+
+impl ActivityCounters {
+    /// Create counters starting at zero
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Record a circuit built or joined by this node
+    pub fn record_circuit_built(&self) {
+        self.circuits_built.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Record a request forwarded to the next hop or provider
+    pub fn record_forwarded(&self) {
+        self.requests_forwarded.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Record a request that failed at this node
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Record a request served from a provider pool
+    pub fn record_pool_use(&self, pool: &str) {
+        *self.pool_usage.lock().entry(pool.to_string()).or_insert(0) += 1;
+    }
+    
+    /// Record a request for a method, by its label from [`crate::traffic::method_label`]
+    pub fn record_method(&self, method: &str) {
+        *self.method_usage.lock().entry(method.to_string()).or_insert(0) += 1;
+    }
+    
+    /// Update the estimate of distinct users seen today
+    pub fn set_unique_users(&self, estimate: u64) {
+        *self.unique_users.lock() = Some(estimate);
+    }
+    
+    /// Update the node's load, from 0 (idle) to 1 (saturated)
+    pub fn set_load(&self, load: f64) {
+        *self.load.lock() = load.clamp(0.0, 1.0);
+    }
+    
+    /// Report the node as saturated, whatever its load, while it is short of resources, see [`crate::resources`]
+    pub fn set_overloaded(&self, overloaded: bool) {
+        self.overloaded.store(overloaded, Ordering::Relaxed);
+    }
+    
+    /// Record work carried for the network during `epoch`, see [`crate::accounting`]
+    pub fn record_work(&self, epoch: u64, requests: u64, bytes: u64) {
+        let mut work = self.work.lock();
+        let work = work.entry(epoch).or_default();
+        work.requests += requests;
+        work.bytes += bytes;
+    }
+    
+    /// Record the state a provider's circuit breaker is in, see [`crate::breaker`]
+    pub fn set_breaker(&self, provider_id: Uuid, state: BreakerState) {
+        let mut breakers = self.breakers.lock();
+        match state {
+            BreakerState::Closed => breakers.remove(&provider_id),
+            state => breakers.insert(provider_id, state),
+        };
+    }
+    
+    /// Providers whose circuit breaker isn't closed
+    pub fn breakers(&self) -> BTreeMap<Uuid, BreakerState> {
+        self.breakers.lock().clone()
+    }
+    
+    /// Record the round trip to a peer in `region`, see [`crate::regions`]
+    pub fn record_peer_latency(&self, region: &str, rtt: Duration) {
+        self.peer_latency.lock().insert(region.to_string(), rtt);
+    }
+    
+    /// Take the round trips to peers measured since the last call
+    pub fn take_peer_latency(&self) -> BTreeMap<String, Duration> {
+        std::mem::take(&mut *self.peer_latency.lock())
+    }
+    
+    /// Record whether a peer answered a reachability probe, see [`crate::reachability`]
+    pub fn record_probe(&self, probe: ProbeResult) {
+        self.probes.lock().push(probe);
+    }
+    
+    /// Take the reachability probes made since the last call, oldest first
+    pub fn take_probes(&self) -> Vec<ProbeResult> {
+        std::mem::take(&mut *self.probes.lock())
+    }
+    
+    /// Set the request budget left, see [`crate::budget`]
+    pub fn set_budget(&self, budget: Option<BudgetReport>) {
+        *self.budget.lock() = budget;
+    }
+    
+    /// The request budget left, as last set
+    pub fn budget(&self) -> Option<BudgetReport> {
+        *self.budget.lock()
+    }
+    
+    /// Take the per-pool request counts accumulated since the last call
+    pub fn take_pool_usage(&self) -> BTreeMap<String, u64> {
+        std::mem::take(&mut *self.pool_usage.lock())
+    }
+    
+    /// Take the per-method request counts accumulated since the last call
+    pub fn take_method_usage(&self) -> BTreeMap<String, u64> {
+        std::mem::take(&mut *self.method_usage.lock())
+    }
+    
+    /// Take the per-epoch work accumulated since the last call
+    pub fn take_work(&self) -> Vec<EpochWork> {
+        std::mem::take(&mut *self.work.lock())
+            .into_iter()
+            .map(|(epoch, work)| EpochWork { epoch, work })
+            .collect()
+    }
+    
+    /// Whether the node is short of resources, as last set
+    pub fn overloaded(&self) -> bool {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+    
+    /// The node's latest load, saturated while it is short of resources
+    pub fn load(&self) -> f64 {
+        if self.overloaded.load(Ordering::Relaxed) {
+            return 1.0;
+        }
+        *self.load.lock()
+    }
+    
+    /// The latest estimate of distinct users seen today, if this node counts them
+    pub fn unique_users(&self) -> Option<u64> {
+        *self.unique_users.lock()
+    }
+    
+    /// Take the counts accumulated since the last call, resetting them to zero
+    pub fn take(&self) -> NodeCounters {
+        NodeCounters {
+            circuits_built: self.circuits_built.swap(0, Ordering::Relaxed),
+            requests_forwarded: self.requests_forwarded.swap(0, Ordering::Relaxed),
+            errors: self.errors.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+fn merge_usage(total: &mut BTreeMap<String, u64>, usage: BTreeMap<String, u64>) {
+    for (label, requests) in usage {
+        *total.entry(label).or_insert(0) += requests;
+    }
+}
+
+/// Static identity of the node sending heartbeats
+#[derive(Clone)]
+pub struct HeartbeatSource {
+    /// The node sending the heartbeat
+    pub node_id: NodeId,
+    /// The roles the node serves
+    pub roles: Vec<NodeRole>,
+    /// The geographic region of the node
+    pub region: String,
+    /// The method classes the node serves, if it is an exit node, see [`crate::egress`]
+    pub method_classes: Option<Vec<MethodClass>>,
+    /// The key the node signs provider attestations with, if it is an exit node, see [`crate::attribution`]
+    pub attestation_key: Option<CryptoKey>,
+    /// The guard sampling the node's resources, whose readings count towards its load
+    pub resources: Option<Arc<ResourceGuard>>,
+}
+
+impl HeartbeatSource {
+    /// The status and load to report: busy while the node refuses new circuits, and loaded
+    /// as much as the busier of its traffic and its resources say
+    fn status(&self, counters: &ActivityCounters) -> (NodeStatus, f64) {
+        let (pressure, utilization) = match &self.resources {
+            Some(resources) => (resources.pressure(), resources.utilization()),
+            None => (Pressure::Normal, 0.0),
+        };
+        let status = match counters.overloaded() || pressure >= Pressure::Soft {
+            true => NodeStatus::Busy,
+            false => NodeStatus::Online,
+        };
+        (status, counters.load().max(utilization))
+    }
+}
+
+/// Coordinator path heartbeats are posted to
+const HEARTBEAT_PATH: &str = "/nodes/heartbeat";
+
+/// Fold a heartbeat that was never delivered into a newer one, keeping its counts
+fn absorb(heartbeat: &mut Heartbeat, older: Heartbeat) {
+    heartbeat.counters.circuits_built += older.counters.circuits_built;
+    heartbeat.counters.requests_forwarded += older.counters.requests_forwarded;
+    heartbeat.counters.errors += older.counters.errors;
+    merge_usage(&mut heartbeat.pool_usage, older.pool_usage);
+    merge_usage(&mut heartbeat.method_usage, older.method_usage);
+    let mut work = older.work;
+    work.append(&mut heartbeat.work);
+    heartbeat.work = work;
+    for (region, rtt) in older.peer_latency {
+        heartbeat.peer_latency.entry(region).or_insert(rtt);
+    }
+    let mut probes = older.reachability;
+    probes.append(&mut heartbeat.reachability);
+    heartbeat.reachability = probes;
+}
+
+/// Queue a heartbeat for the coordinator every `interval` until the task is dropped
+///
+/// Heartbeats still queued from earlier intervals are folded into the new one, so a
+/// coordinator outage leaves a single heartbeat waiting rather than one per interval.
+pub async fn run(
+    interval: Duration,
+    source: HeartbeatSource,
+    counters: Arc<ActivityCounters>,
+    outbox: Arc<Outbox>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    
+    loop {
+        ticker.tick().await;
+        
+        let (status, load) = source.status(&counters);
+        let mut heartbeat = Heartbeat {
+            node_id: source.node_id.clone(),
+            roles: source.roles.clone(),
+            status,
+            region: source.region.clone(),
+            load,
+            counters: counters.take(),
+            pool_usage: counters.take_pool_usage(),
+            method_usage: counters.take_method_usage(),
+            unique_users: counters.unique_users(),
+            work: counters.take_work(),
+            breakers: counters.breakers(),
+            peer_latency: counters.take_peer_latency(),
+            budget: counters.budget(),
+            reachability: counters.take_probes(),
+            build: Some(BuildInfo::current()),
+            method_classes: source.method_classes.clone(),
+            attestation_key: source.attestation_key.clone(),
+            sent_at: Timestamp::now(),
+        };
+        for older in outbox.take(ReportKind::Heartbeat) {
+            match serde_json::from_value::<Heartbeat>(older.body) {
+                Ok(older) => absorb(&mut heartbeat, older),
+                Err(e) => tracing::warn!("Dropping unreadable queued heartbeat: {}", e),
+            }
+        }
+        
+        match Report::new(ReportKind::Heartbeat, HEARTBEAT_PATH, &heartbeat) {
+            Ok(report) => outbox.push(report),
+            Err(e) => tracing::warn!("Failed to queue heartbeat: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::{ResourceConfig, ResourceReading};
+    
+    #[test]
+    fn reports_busy_and_loaded_from_traffic_and_resources() {
+        let resources = Arc::new(ResourceGuard::new(ResourceConfig {
+            soft_memory_bytes: Some(800),
+            hard_memory_bytes: Some(1000),
+            ..Default::default()
+        }));
+        let source = HeartbeatSource {
+            node_id: NodeId(Uuid::new_v4()),
+            roles: vec![NodeRole::Exit],
+            region: "us-east".to_string(),
+            method_classes: None,
+            attestation_key: None,
+            resources: Some(resources.clone()),
+        };
+        let counters = ActivityCounters::new();
+        assert_eq!(source.status(&counters), (NodeStatus::Online, 0.0));
+        
+        counters.set_load(0.2);
+        resources.observe(&ResourceReading {
+            memory_bytes: Some(500),
+            ..Default::default()
+        });
+        assert_eq!(source.status(&counters), (NodeStatus::Online, 0.5));
+        
+        resources.observe(&ResourceReading {
+            memory_bytes: Some(900),
+            ..Default::default()
+        });
+        assert_eq!(source.status(&counters), (NodeStatus::Busy, 0.9));
+        
+        let unguarded = HeartbeatSource { resources: None, ..source };
+        counters.set_overloaded(true);
+        assert_eq!(unguarded.status(&counters), (NodeStatus::Busy, 1.0));
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn heartbeats_carry_the_build_of_the_binary() {
+        let source = HeartbeatSource {
+            node_id: NodeId(Uuid::new_v4()),
+            roles: vec![NodeRole::Routing],
+            region: "us-east".to_string(),
+            method_classes: None,
+            attestation_key: None,
+            resources: None,
+        };
+        let outbox = Arc::new(Outbox::open(Default::default()).unwrap());
+        let beating = tokio::spawn(run(Duration::from_secs(30), source, Arc::new(ActivityCounters::new()), outbox.clone()));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        beating.abort();
+        
+        let queued = outbox.take(ReportKind::Heartbeat);
+        assert_eq!(queued.len(), 1);
+        let heartbeat: Heartbeat = serde_json::from_value(queued[0].body.clone()).unwrap();
+        assert_eq!(heartbeat.build, Some(BuildInfo::current()));
+    }
+}
@@ -0,0 +1,156 @@
+//! Hedging and retry budgets for provider requests
+//!
+//! A read that a provider is slow to answer is re-sent to the next-best provider, and a
+//! read that fails is retried there. Both draw from the budget of the provider they are
+//! sent to, earned by the requests it serves as primary, so they can only add a bounded
+//! fraction to any provider's load.
+//!
+//! A read is slow once it has taken longer than most reads of its method class: the
+//! configured percentile of the latencies seen recently, or the configured delay until
+//! enough have been seen.
+
+use super::*;
+use super::timeouts::MethodClass;
+use std::collections::{HashMap, VecDeque};
+
+/// Latencies kept per method class to take the percentile of
+const LATENCY_WINDOW: usize = 256;
+
+/// Latencies a method class needs before its percentile is trusted over the default delay
+const MIN_LATENCY_SAMPLES: usize = 20;
+
+/// When to hedge and how much extra load hedges and retries may add
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HedgeConfig {
+    /// Whether slow reads are hedged and failed reads retried
+    pub enabled: bool,
+    /// How long to wait for the primary provider before hedging, until enough latencies of
+    /// the method class have been seen
+    pub delay: Duration,
+    /// Percentile of a method class's recent latencies to wait for before hedging
+    pub percentile: f64,
+    /// Per-method delays, fixed in place of the percentile
+    #[serde(default)]
+    pub method_delays: HashMap<String, Duration>,
+    /// Extra requests allowed per request a provider serves as primary (0.1 = 10% extra load)
+    pub budget_ratio: f64,
+    /// Most hedges or retries a provider can bank for a burst
+    pub max_budget: f64,
+}
+
+impl Default for HedgeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            delay: Duration::from_millis(250),
+            percentile: 0.95,
+            method_delays: HashMap::new(),
+            budget_ratio: 0.1,
+            max_budget: 10.0,
+        }
+    }
+}
+
+/// Per-provider budgets for hedged and retried requests, and the latencies hedges wait for
+pub struct HedgeBudget {
+    config: HedgeConfig,
+    tokens: dashmap::DashMap<Uuid, f64>,
+    latencies: parking_lot::Mutex<HashMap<MethodClass, VecDeque<Duration>>>,
+}
+
+impl HedgeBudget {
+    /// Create budgets that start empty
+    pub fn new(config: HedgeConfig) -> Self {
+        Self {
+            config,
+            tokens: dashmap::DashMap::new(),
+            latencies: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+    
+    /// Whether hedging is enabled at all
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+    
+    /// How long to wait for the primary provider before hedging a method
+    pub fn delay_for(&self, method: &str) -> Duration {
+        if let Some(delay) = self.config.method_delays.get(method) {
+            return *delay;
+        }
+        let latencies = self.latencies.lock();
+        match latencies.get(&MethodClass::of(method)) {
+            Some(seen) if seen.len() >= MIN_LATENCY_SAMPLES => {
+                let mut sorted: Vec<Duration> = seen.iter().copied().collect();
+                sorted.sort_unstable();
+                let rank = (sorted.len() as f64 * self.config.percentile.clamp(0.0, 1.0)).ceil() as usize;
+                sorted[rank.clamp(1, sorted.len()) - 1]
+            }
+            _ => self.config.delay,
+        }
+    }
+    
+    /// Count how long a read of `method` took to be answered
+    pub fn observe(&self, method: &str, latency: Duration) {
+        let mut latencies = self.latencies.lock();
+        let seen = latencies.entry(MethodClass::of(method)).or_default();
+        if seen.len() == LATENCY_WINDOW {
+            seen.pop_front();
+        }
+        seen.push_back(latency);
+    }
+    
+    /// Credit a provider for a request it is serving as primary
+    pub fn deposit(&self, provider_id: Uuid) {
+        let mut tokens = self.tokens.entry(provider_id).or_insert(0.0);
+        *tokens = (*tokens + self.config.budget_ratio).min(self.config.max_budget);
+    }
+    
+    /// Spend one hedge or retry sent to a provider, if its budget allows
+    pub fn try_withdraw(&self, provider_id: Uuid) -> bool {
+        match self.tokens.get_mut(&provider_id) {
+            Some(mut tokens) if *tokens >= 1.0 => {
+                *tokens -= 1.0;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn waits_for_the_percentile_of_recent_latencies() {
+        let budget = HedgeBudget::new(HedgeConfig::default());
+        assert_eq!(budget.delay_for("getAccountInfo"), Duration::from_millis(250));
+        
+        for millis in 1..=100 {
+            budget.observe("getAccountInfo", Duration::from_millis(millis));
+        }
+        assert_eq!(budget.delay_for("getAccountInfo"), Duration::from_millis(95));
+        // Methods of another class wait for their own
+        assert_eq!(budget.delay_for("getProgramAccounts"), Duration::from_millis(250));
+    }
+    
+    #[test]
+    fn hedges_to_a_provider_only_as_its_own_traffic_allows() {
+        let budget = HedgeBudget::new(HedgeConfig {
+            budget_ratio: 0.5,
+            ..Default::default()
+        });
+        let (primary, backup) = (Uuid::new_v4(), Uuid::new_v4());
+        for _ in 0..4 {
+            budget.deposit(primary);
+        }
+        assert!(!budget.try_withdraw(backup));
+        
+        budget.deposit(backup);
+        budget.deposit(backup);
+        assert!(budget.try_withdraw(backup));
+        assert!(!budget.try_withdraw(backup));
+    }
+}
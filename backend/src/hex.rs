@@ -0,0 +1,37 @@
+//! Lowercase hex encoding of hashes, salts, keys and signatures
+
+/// `bytes` as lowercase hex
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The bytes `hex` encodes, in either case, or `None` if it isn't hex
+pub fn decode(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn bytes_round_trip() {
+        let bytes = [0x00, 0x0f, 0xa5, 0xff];
+        assert_eq!(encode(&bytes), "000fa5ff");
+        assert_eq!(decode("000fa5ff"), Some(bytes.to_vec()));
+        assert_eq!(decode("000FA5FF"), Some(bytes.to_vec()));
+    }
+    
+    #[test]
+    fn odd_lengths_and_non_hex_are_refused() {
+        assert_eq!(decode("abc"), None);
+        assert_eq!(decode("zz"), None);
+        assert_eq!(decode("+f"), None);
+    }
+}
@@ -0,0 +1,525 @@
+//! Authenticating the previous hop of every forwarded message
+//!
+//! Onion encryption keeps a hop from reading what it forwards, but says nothing about who
+//! sent it: anyone reaching a routing or exit node's port could post garbage at it, and
+//! the node would only find out after the decryption work. So every message forwarded
+//! between hops carries, in headers, the sending node's ID, the time it was sent, a random
+//! nonce, and the sender's signature over all three and the body's SHA-256 hash, made with
+//! its long-term identity key, see [`HopSigner`].
+//!
+//! The receiving node checks the headers before anything else, in [`require_signed_hop`]:
+//! messages without them, sent too far from its own clock, or from a node the directory
+//! doesn't know are dropped before their body is even read, bodies over `max_body_bytes`
+//! are dropped without being read in full, and messages whose signature doesn't verify
+//! under any key the sender may be using are dropped before they reach the circuit key
+//! store. A nonce is taken once per sender: replaying a captured message within the
+//! freshness window is dropped too. Senders' directory records are cached for `key_cache_ttl`; a
+//! signature that fails under a cached record is checked once more against a fresh one,
+//! so key rotations are picked up as soon as they are published.
+//!
+//! The signed message is the [`HopMessage`]'s [`Signable::canonical_bytes`],
+//! `darknode-hop:v2\n<node id>\n<timestamp>\n<nonce>\n<body hash>`, where `<timestamp>` is in
+//! seconds since the Unix epoch, `<nonce>` is 32 hex digits and `<body hash>` is hex.
+//! Signatures are base64-encoded.
+
+use super::*;
+use super::canonical::{CanonicalError, Signable};
+use super::expiring::ExpiringMap;
+use super::identity::{self, NodeIdentity};
+use super::telemetry::{self, TelemetryConfig};
+use super::traits::{Crypto, NodeManager};
+use super::types::{Node, NodeId};
+use super::upstream;
+use axum::body::Body;
+use axum::extract::Extension;
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use tokio::time::Instant;
+
+/// Header carrying the sending node's ID
+pub const NODE_HEADER: &str = "x-darknode-hop-node";
+
+/// Header carrying when the message was signed, in seconds since the Unix epoch
+pub const TIMESTAMP_HEADER: &str = "x-darknode-hop-timestamp";
+
+/// Header carrying the message's nonce, 16 random bytes in hex
+pub const NONCE_HEADER: &str = "x-darknode-hop-nonce";
+
+/// Header carrying the sender's base64 signature
+pub const SIGNATURE_HEADER: &str = "x-darknode-hop-signature";
+
+/// Prefix of every signed message, so hop signatures can't be replayed elsewhere
+const MESSAGE_PREFIX: &str = "darknode-hop:v2";
+
+/// Most nonces remembered to drop replays
+const MAX_REMEMBERED: usize = 100_000;
+
+/// How forwarded messages are authenticated
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HopAuthConfig {
+    /// Whether forwarded messages must be signed by their sender
+    pub enabled: bool,
+    /// How far a message's timestamp may be from this node's clock
+    pub max_skew: Duration,
+    /// How long a sender's directory record is trusted before it is looked up again
+    pub key_cache_ttl: Duration,
+    /// Most senders whose records are cached at once
+    pub max_cached_senders: usize,
+    /// Largest forwarded body taken
+    pub max_body_bytes: usize,
+}
+
+impl Default for HopAuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_skew: Duration::from_secs(30),
+            key_cache_ttl: Duration::from_secs(60),
+            max_cached_senders: 10_000,
+            max_body_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// Why a forwarded message was dropped
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum HopRejected {
+    /// A header is missing
+    #[error("missing {0} header")]
+    Missing(&'static str),
+    /// A header can't be read
+    #[error("malformed {0} header")]
+    Malformed(&'static str),
+    /// The message was signed too far from this node's clock
+    #[error("message signed at {0}s is outside the freshness window")]
+    Stale(u64),
+    /// The message was taken before
+    #[error("message from node {} was already taken", .0 .0)]
+    Replayed(NodeId),
+    /// The body is larger than taken
+    #[error("message body is larger than {0} bytes")]
+    TooLarge(usize),
+    /// The sender isn't in the directory
+    #[error("node {} is not in the directory", .0 .0)]
+    UnknownSender(NodeId),
+    /// The signature doesn't verify under any of the sender's keys
+    #[error("signature does not verify for node {}", .0 .0)]
+    BadSignature(NodeId),
+}
+
+impl HopRejected {
+    /// Label used in metrics
+    pub fn label(&self) -> &'static str {
+        match self {
+            HopRejected::Missing(_) => "missing",
+            HopRejected::Malformed(_) => "malformed",
+            HopRejected::Stale(_) => "stale",
+            HopRejected::Replayed(_) => "replayed",
+            HopRejected::TooLarge(_) => "too_large",
+            HopRejected::UnknownSender(_) => "unknown_sender",
+            HopRejected::BadSignature(_) => "bad_signature",
+        }
+    }
+}
+
+/// What a hop signs for a message it sends
+#[derive(Debug, Clone, Copy)]
+pub struct HopMessage<'a> {
+    /// The sending node
+    pub node_id: &'a NodeId,
+    /// When the message was sent, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// The message's nonce, in hex
+    pub nonce: &'a str,
+    /// The message's body
+    pub body: &'a [u8],
+}
+
+impl Signable for HopMessage<'_> {
+    /// The prefix line, then each signed field on a line of its own
+    ///
+    /// Hop signatures predate [`canonical::encode`](crate::canonical::encode) and sign only
+    /// an ID, an integer and two hex strings, so they keep the line format they were first
+    /// signed in.
+    fn canonical_bytes(&self) -> Result<Vec<u8>, CanonicalError> {
+        let hash = hex::encode(&Sha256::digest(self.body));
+        Ok(format!("{}\n{}\n{}\n{}\n{}", MESSAGE_PREFIX, self.node_id.0, self.timestamp, self.nonce, hash).into_bytes())
+    }
+}
+
+/// Signs the messages this node forwards, see the module docs
+pub struct HopSigner {
+    node_id: NodeId,
+    identity: Arc<NodeIdentity>,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    telemetry: TelemetryConfig,
+}
+
+impl HopSigner {
+    /// Create a signer for messages sent by `node_id`
+    pub fn new(node_id: NodeId, identity: Arc<NodeIdentity>, crypto: Arc<dyn Crypto + Send + Sync>) -> Self {
+        Self {
+            node_id,
+            identity,
+            crypto,
+            telemetry: TelemetryConfig::default(),
+        }
+    }
+    
+    /// Carry the trace context to the next hop if `config` propagates it, see [`crate::telemetry`]
+    pub fn with_telemetry(mut self, config: TelemetryConfig) -> Self {
+        self.telemetry = config;
+        self
+    }
+    
+    /// The headers to send `body` with at `now`
+    pub async fn headers(&self, body: &[u8], now: Timestamp) -> Result<Vec<(&'static str, String)>> {
+        let timestamp = now.as_secs();
+        let nonce = hex::encode(&rand::random::<[u8; 16]>());
+        let message = HopMessage {
+            node_id: &self.node_id,
+            timestamp,
+            nonce: &nonce,
+            body,
+        }
+        .canonical_bytes()?;
+        let signature = self.identity.sign(&*self.crypto, &message, now).await?;
+        let mut headers = vec![
+            (NODE_HEADER, self.node_id.0.to_string()),
+            (TIMESTAMP_HEADER, timestamp.to_string()),
+            (NONCE_HEADER, nonce),
+            (SIGNATURE_HEADER, STANDARD.encode(signature)),
+        ];
+        headers.extend(telemetry::trace_headers(&self.telemetry));
+        Ok(headers)
+    }
+}
+
+/// What a message's headers claim, before its body is looked at
+struct Claim {
+    node_id: NodeId,
+    timestamp: u64,
+    nonce: String,
+    signature: Vec<u8>,
+}
+
+/// Checks that forwarded messages come from nodes in the directory, see the module docs
+pub struct HopVerifier {
+    config: HopAuthConfig,
+    node_manager: Arc<dyn NodeManager + Send + Sync>,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    senders: parking_lot::Mutex<ExpiringMap<NodeId, Node>>,
+    seen: parking_lot::Mutex<ExpiringMap<(NodeId, String), ()>>,
+}
+
+impl HopVerifier {
+    /// Create a verifier looking senders up through `node_manager`
+    pub fn new(
+        config: HopAuthConfig,
+        node_manager: Arc<dyn NodeManager + Send + Sync>,
+        crypto: Arc<dyn Crypto + Send + Sync>,
+    ) -> Self {
+        Self {
+            senders: parking_lot::Mutex::new(ExpiringMap::new(config.key_cache_ttl, config.max_cached_senders)),
+            // A nonce is remembered as long as its message could pass the freshness check
+            seen: parking_lot::Mutex::new(ExpiringMap::new(config.max_skew * 2 + Duration::from_secs(1), MAX_REMEMBERED)),
+            config,
+            node_manager,
+            crypto,
+        }
+    }
+    
+    /// Whether forwarded messages must be signed
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+    
+    /// Check that `body`, sent with `headers`, was signed by a node in the directory,
+    /// returning the node
+    pub async fn verify(&self, headers: &HeaderMap, body: &[u8], now: Timestamp) -> Result<NodeId, HopRejected> {
+        let claim = self.claim(headers, now)?;
+        let (sender, cached) = self.sender(&claim.node_id, false).await?;
+        if body.len() > self.config.max_body_bytes {
+            return Err(HopRejected::TooLarge(self.config.max_body_bytes));
+        }
+        self.check(&claim, sender, cached, body, now).await
+    }
+    
+    /// Read the headers and check the timestamp, touching nothing else
+    fn claim(&self, headers: &HeaderMap, now: Timestamp) -> Result<Claim, HopRejected> {
+        let header = |name: &'static str| {
+            headers
+                .get(name)
+                .ok_or(HopRejected::Missing(name))?
+                .to_str()
+                .map_err(|_| HopRejected::Malformed(name))
+        };
+        let node_id = header(NODE_HEADER)?
+            .parse()
+            .map(NodeId)
+            .map_err(|_| HopRejected::Malformed(NODE_HEADER))?;
+        let timestamp: u64 = header(TIMESTAMP_HEADER)?
+            .parse()
+            .map_err(|_| HopRejected::Malformed(TIMESTAMP_HEADER))?;
+        let nonce = header(NONCE_HEADER)?;
+        if nonce.len() != 32 || !nonce.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(HopRejected::Malformed(NONCE_HEADER));
+        }
+        let nonce = nonce.to_ascii_lowercase();
+        let signature = STANDARD
+            .decode(header(SIGNATURE_HEADER)?)
+            .map_err(|_| HopRejected::Malformed(SIGNATURE_HEADER))?;
+        
+        if now.as_secs().abs_diff(timestamp) > self.config.max_skew.as_secs() {
+            return Err(HopRejected::Stale(timestamp));
+        }
+        Ok(Claim {
+            node_id,
+            timestamp,
+            nonce,
+            signature,
+        })
+    }
+    
+    /// The directory record of `node_id`, from the cache unless `refresh`, and whether it was
+    async fn sender(&self, node_id: &NodeId, refresh: bool) -> Result<(Node, bool), HopRejected> {
+        if !refresh {
+            if let Some(node) = self.senders.lock().get(node_id, Instant::now()) {
+                return Ok((node.clone(), true));
+            }
+        }
+        let node = match self.node_manager.get_node(node_id).await {
+            Ok(Some(node)) => node,
+            Ok(None) => return Err(HopRejected::UnknownSender(node_id.clone())),
+            Err(e) => {
+                tracing::warn!("Failed to look up hop sender {}: {}", node_id.0, e);
+                return Err(HopRejected::UnknownSender(node_id.clone()));
+            }
+        };
+        self.senders.lock().insert(node_id.clone(), node.clone(), Instant::now());
+        Ok((node, false))
+    }
+    
+    /// Check the signature over `body`, once more against a fresh record if a cached one
+    /// fails, then that its nonce wasn't taken before
+    async fn check(&self, claim: &Claim, sender: Node, cached: bool, body: &[u8], now: Timestamp) -> Result<NodeId, HopRejected> {
+        let message = HopMessage {
+            node_id: &claim.node_id,
+            timestamp: claim.timestamp,
+            nonce: &claim.nonce,
+            body,
+        }
+        .canonical_bytes()
+        .map_err(|_| HopRejected::Malformed(SIGNATURE_HEADER))?;
+        let verifies = |node: Node| {
+            let message = &message;
+            async move { identity::verify_node_signature(&*self.crypto, &node, message, &claim.signature, now).await.unwrap_or(false) }
+        };
+        let mut verified = verifies(sender).await;
+        if !verified && cached {
+            let (fresh, _) = self.sender(&claim.node_id, true).await?;
+            verified = verifies(fresh).await;
+        }
+        if !verified {
+            return Err(HopRejected::BadSignature(claim.node_id.clone()));
+        }
+        let key = (claim.node_id.clone(), claim.nonce.clone());
+        let mut seen = self.seen.lock();
+        if seen.get(&key, Instant::now()).is_some() {
+            return Err(HopRejected::Replayed(claim.node_id.clone()));
+        }
+        seen.insert(key, (), Instant::now());
+        Ok(claim.node_id.clone())
+    }
+}
+
+/// Middleware dropping forwarded messages not signed by a node in the directory
+///
+/// Install on the forwarding routes with `axum::middleware::from_fn(require_signed_hop)` as a
+/// route layer, with the [`HopVerifier`] as an extension. Rejected messages are answered
+/// with `401 Unauthorized`, or `413 Payload Too Large`, and counted in
+/// `darknode_hop_rejections_total`.
+pub async fn require_signed_hop(
+    Extension(verifier): Extension<Arc<HopVerifier>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if !verifier.enabled() {
+        return next.run(request).await;
+    }
+    let now = Timestamp::now();
+    
+    // Headers and the directory first, so unauthenticated messages are dropped unread
+    let claimed = match verifier.claim(request.headers(), now) {
+        Ok(claim) => verifier.sender(&claim.node_id, false).await.map(|sender| (claim, sender)),
+        Err(e) => Err(e),
+    };
+    let (claim, (sender, cached)) = match claimed {
+        Ok(claimed) => claimed,
+        Err(e) => return reject(e),
+    };
+    
+    let (parts, body) = request.into_parts();
+    let Some(body) = upstream::read_request_body(body, verifier.config.max_body_bytes).await else {
+        return reject(HopRejected::TooLarge(verifier.config.max_body_bytes));
+    };
+    if let Err(e) = verifier.check(&claim, sender, cached, &body, now).await {
+        return reject(e);
+    }
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+/// Answer a message whose sender couldn't be authenticated
+fn reject(e: HopRejected) -> Response {
+    tracing::debug!("Dropping forwarded message: {}", e);
+    metrics::increment_counter!("darknode_hop_rejections_total", "reason" => e.label());
+    match e {
+        HopRejected::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+        _ => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::{CryptoImpl, StoredNodeManager};
+    use crate::storage::memory::MemoryStorage;
+    use crate::types::{CryptoKey, NodeRole, NodeStatus};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+    
+    /// A directory counting the lookups made in it
+    struct CountingDirectory {
+        nodes: StoredNodeManager,
+        lookups: AtomicUsize,
+    }
+    
+    #[async_trait]
+    impl NodeManager for CountingDirectory {
+        async fn register_node(&self, node: Node) -> Result<()> {
+            self.nodes.register_node(node).await
+        }
+        
+        async fn update_node_status(&self, node_id: &NodeId, status: NodeStatus) -> Result<()> {
+            self.nodes.update_node_status(node_id, status).await
+        }
+        
+        async fn get_available_nodes(&self, role: NodeRole) -> Result<Vec<Node>> {
+            self.nodes.get_available_nodes(role).await
+        }
+        
+        async fn get_node(&self, node_id: &NodeId) -> Result<Option<Node>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            self.nodes.get_node(node_id).await
+        }
+        
+        async fn publish_next_key(&self, node_id: &NodeId, next_public_key: CryptoKey, activates_at: Timestamp) -> Result<()> {
+            self.nodes.publish_next_key(node_id, next_public_key, activates_at).await
+        }
+    }
+    
+    fn record(identity: &NodeIdentity) -> Node {
+        Node {
+            public_key: identity.public_key(Timestamp::now()),
+            port: 3001,
+            ..crate::fixtures::node(&[NodeRole::Entry])
+        }
+    }
+    
+    fn header_map(headers: Vec<(&'static str, String)>) -> HeaderMap {
+        headers
+            .into_iter()
+            .map(|(name, value)| (axum::http::HeaderName::from_static(name), value.parse().unwrap()))
+            .collect()
+    }
+    
+    #[tokio::test]
+    async fn takes_each_signed_message_once_and_refuses_oversized_bodies() {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let node_manager = Arc::new(StoredNodeManager::new(Arc::new(MemoryStorage::new())));
+        let identity = Arc::new(NodeIdentity::generate(&*crypto, Duration::ZERO).await.unwrap());
+        let node = record(&identity);
+        let node_id = node.id.clone();
+        node_manager.register_node(node).await.unwrap();
+        let config = HopAuthConfig {
+            max_body_bytes: 16,
+            ..HopAuthConfig::default()
+        };
+        let verifier = HopVerifier::new(config, node_manager, crypto.clone());
+        let signer = HopSigner::new(node_id.clone(), identity, crypto);
+        
+        // A captured message is dropped when replayed, while the same body sent again in the
+        // same second carries a fresh nonce and is taken
+        let now = Timestamp::now();
+        let headers = header_map(signer.headers(b"{}", now).await.unwrap());
+        assert_eq!(verifier.verify(&headers, b"{}", now).await, Ok(node_id.clone()));
+        assert_eq!(verifier.verify(&headers, b"{}", now).await, Err(HopRejected::Replayed(node_id.clone())));
+        let again = header_map(signer.headers(b"{}", now).await.unwrap());
+        assert_eq!(verifier.verify(&again, b"{}", now).await, Ok(node_id.clone()));
+        
+        // The nonce is signed, so swapping it doesn't get a replay through
+        let mut swapped = headers.clone();
+        swapped.insert(NONCE_HEADER, hex::encode(&[7u8; 16]).parse().unwrap());
+        assert_eq!(verifier.verify(&swapped, b"{}", now).await, Err(HopRejected::BadSignature(node_id.clone())));
+        
+        let body = [b'x'; 17];
+        let headers = header_map(signer.headers(&body, now).await.unwrap());
+        assert_eq!(verifier.verify(&headers, &body, now).await, Err(HopRejected::TooLarge(16)));
+    }
+    
+    #[tokio::test]
+    async fn unsigned_messages_are_dropped_before_the_circuit_key_store_is_read() {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let directory = Arc::new(CountingDirectory {
+            nodes: StoredNodeManager::new(Arc::new(MemoryStorage::new())),
+            lookups: AtomicUsize::new(0),
+        });
+        let identity = Arc::new(NodeIdentity::generate(&*crypto, Duration::ZERO).await.unwrap());
+        let node = record(&identity);
+        let node_id = node.id.clone();
+        directory.register_node(node).await.unwrap();
+        let verifier = Arc::new(HopVerifier::new(HopAuthConfig::default(), directory.clone(), crypto.clone()));
+        
+        // The forwarding handlers start by opening the circuit's keys, which this one counts
+        let reads = Arc::new(AtomicUsize::new(0));
+        let read = reads.clone();
+        let app = axum::Router::new()
+            .route(
+                "/forward",
+                axum::routing::post(move || async move {
+                    read.fetch_add(1, Ordering::SeqCst);
+                    "{}"
+                }),
+            )
+            .route_layer(axum::middleware::from_fn(require_signed_hop))
+            .layer(Extension(verifier));
+        let send = |headers: Vec<(&'static str, String)>| {
+            let app = app.clone();
+            async move {
+                let mut request = Request::post("/forward");
+                for (name, value) in headers {
+                    request = request.header(name, value);
+                }
+                app.oneshot(request.body(Body::from("{}")).unwrap()).await.unwrap().status()
+            }
+        };
+        
+        assert_eq!(send(Vec::new()).await, StatusCode::UNAUTHORIZED);
+        assert_eq!((reads.load(Ordering::SeqCst), directory.lookups.load(Ordering::SeqCst)), (0, 0));
+        
+        // Signed by a node the directory doesn't know
+        let stranger = Arc::new(NodeIdentity::generate(&*crypto, Duration::ZERO).await.unwrap());
+        let forger = HopSigner::new(NodeId(Uuid::new_v4()), stranger, crypto.clone());
+        assert_eq!(send(forger.headers(b"{}", Timestamp::now()).await.unwrap()).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(reads.load(Ordering::SeqCst), 0);
+        
+        let signer = HopSigner::new(node_id, identity, crypto);
+        assert_eq!(send(signer.headers(b"{}", Timestamp::now()).await.unwrap()).await, StatusCode::OK);
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+    }
+}
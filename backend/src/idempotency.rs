@@ -0,0 +1,249 @@
+//! Idempotency keys protecting against the same HTTP request being delivered twice
+//!
+//! Load balancers and buggy clients sometimes deliver one request twice, and a duplicated
+//! `sendTransaction` is broadcast twice before any circuit-level protection applies.
+//! Clients that send an `Idempotency-Key` header have the entry node remember the response
+//! to the first delivery under their API key and that key, for a short TTL, and every
+//! duplicate within it gets that same response without being sent through a circuit again.
+//! A duplicate arriving while the first delivery is still being served waits for it.
+//!
+//! Reusing a key for a different request is refused, told apart by a hash of the request.
+//! Whatever the first delivery was answered with is remembered, failures too: a call that
+//! timed out may still have been broadcast, so a duplicate gets the failure rather than
+//! sending the call again, and a client retrying on purpose uses a new key. Duplicates get
+//! the first delivery's headers too, such as its `Cache-Control` and `Server-Timing`, with
+//! [`REPLAYED_HEADER`] added. Streamed
+//! responses are never remembered. This is independent of the exit node's response cache.
+//!
+//! Keys are only claimed for calls whose API keys all belong to active users, so callers
+//! that can't authenticate take no room in the store.
+
+use super::*;
+use super::expiring::ExpiringMap;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+
+/// Header carrying the client's idempotency key
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Header set on responses replayed for a duplicate delivery
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// Longest idempotency key accepted
+const MAX_KEY_LEN: usize = 255;
+
+/// How long and how many responses are remembered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdempotencyConfig {
+    /// How long after the first delivery duplicates get its response
+    pub ttl: Duration,
+    /// Most keys remembered before the oldest are forgotten
+    pub capacity: usize,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(300),
+            capacity: 10_000,
+        }
+    }
+}
+
+/// Errors from idempotency keys
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum IdempotencyError {
+    /// The key is empty, too long, or not visible ASCII
+    #[error("Idempotency-Key must be 1 to {MAX_KEY_LEN} visible ASCII characters")]
+    InvalidKey,
+    /// The key was already used for a different request
+    #[error("Idempotency-Key was already used for a different request")]
+    KeyReused,
+}
+
+/// A response remembered for replay to duplicates, whether it succeeded or failed
+#[derive(Debug, Clone)]
+pub struct StoredResponse {
+    /// The HTTP status
+    pub status: StatusCode,
+    /// The headers, replayed as they were
+    pub headers: HeaderMap,
+    /// The body
+    pub body: Vec<u8>,
+}
+
+impl StoredResponse {
+    /// Keep all of `response` to replay
+    pub async fn read(response: Response) -> Result<Self, axum::Error> {
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        Ok(Self {
+            status: parts.status,
+            headers: parts.headers,
+            body: body.to_vec(),
+        })
+    }
+    
+    /// The response to answer with again, marked as `replayed` for a duplicate delivery
+    pub fn replay(&self, replayed: bool) -> Response {
+        let mut response = (self.status, self.body.clone()).into_response();
+        *response.headers_mut() = self.headers.clone();
+        if replayed {
+            response
+                .headers_mut()
+                .insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+        }
+        response
+    }
+}
+
+/// The response of a request's first delivery, once it is served
+pub type ResponseSlot = Arc<OnceCell<StoredResponse>>;
+
+struct Entry {
+    fingerprint: [u8; 32],
+    slot: ResponseSlot,
+}
+
+/// Responses remembered under users' idempotency keys
+pub struct IdempotencyStore {
+    entries: parking_lot::Mutex<ExpiringMap<(String, String), Entry>>,
+}
+
+impl IdempotencyStore {
+    /// Create a store remembering nothing yet
+    pub fn new(config: IdempotencyConfig) -> Self {
+        Self {
+            entries: parking_lot::Mutex::new(ExpiringMap::new(config.ttl, config.capacity)),
+        }
+    }
+    
+    /// The slot for the response to the request `fingerprint` under `scope` and `key`
+    ///
+    /// The first delivery gets an empty slot to fill with its response; duplicates get the
+    /// same slot, filled or being filled. A different request under the same key is refused.
+    pub fn claim(&self, scope: &str, key: &str, fingerprint: [u8; 32], now: Instant) -> Result<ResponseSlot, IdempotencyError> {
+        let mut entries = self.entries.lock();
+        let id = (scope.to_string(), key.to_string());
+        let result = match entries.get(&id, now) {
+            Some(entry) if entry.fingerprint != fingerprint => Err(IdempotencyError::KeyReused),
+            Some(entry) => Ok(entry.slot.clone()),
+            None => {
+                let slot = ResponseSlot::default();
+                entries.insert(id, Entry { fingerprint, slot: slot.clone() }, now);
+                metrics::increment_counter!("darknode_idempotency_requests_total", "result" => "first");
+                return Ok(slot);
+            }
+        };
+        let label = match result {
+            Ok(_) => "duplicate",
+            Err(_) => "key_reused",
+        };
+        metrics::increment_counter!("darknode_idempotency_requests_total", "result" => label);
+        result
+    }
+}
+
+/// The idempotency key in `headers`, if the client sent one
+pub fn key(headers: &HeaderMap) -> Result<Option<String>, IdempotencyError> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY_HEADER) else { return Ok(None) };
+    let key = value.to_str().map_err(|_| IdempotencyError::InvalidKey)?;
+    if key.is_empty() || key.len() > MAX_KEY_LEN || !key.bytes().all(|byte| byte.is_ascii_graphic()) {
+        return Err(IdempotencyError::InvalidKey);
+    }
+    Ok(Some(key.to_string()))
+}
+
+/// Hash identifying a request by everything it was sent with
+pub fn fingerprint(request: &serde_json::Value) -> [u8; 32] {
+    Sha256::digest(serde_json::to_vec(request).unwrap_or_default()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn duplicates_get_the_first_answer_even_when_it_failed() {
+        let store = IdempotencyStore::new(IdempotencyConfig::default());
+        let now = Instant::now();
+        let request = fingerprint(&serde_json::json!({"method": "sendTransaction", "params": ["tx"]}));
+        
+        let first = store.claim("key", "once", request, now).unwrap();
+        let mut sends = 0;
+        let failure = StoredResponse {
+            status: StatusCode::GATEWAY_TIMEOUT,
+            headers: HeaderMap::new(),
+            body: b"timed out".to_vec(),
+        };
+        first
+            .get_or_init(|| async {
+                sends += 1;
+                failure
+            })
+            .await;
+        
+        let duplicate = store.claim("key", "once", request, now).unwrap();
+        let replayed = duplicate
+            .get_or_init(|| async {
+                sends += 1;
+                unreachable!("a duplicate is never served again")
+            })
+            .await;
+        assert_eq!(replayed.status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(sends, 1);
+    }
+    
+    #[test]
+    fn a_key_reused_for_a_different_request_is_refused() {
+        let store = IdempotencyStore::new(IdempotencyConfig::default());
+        let now = Instant::now();
+        store.claim("key", "once", fingerprint(&serde_json::json!([1])), now).unwrap();
+        assert_eq!(
+            store.claim("key", "once", fingerprint(&serde_json::json!([2])), now).err(),
+            Some(IdempotencyError::KeyReused)
+        );
+        assert!(store.claim("other key", "once", fingerprint(&serde_json::json!([2])), now).is_ok());
+    }
+    
+    #[tokio::test]
+    async fn duplicates_get_the_first_answer_with_its_headers() {
+        use axum::http::header;
+        
+        let store = IdempotencyStore::new(IdempotencyConfig::default());
+        let now = Instant::now();
+        let request = fingerprint(&serde_json::json!({"method": "getBalance", "params": ["address"]}));
+        let sends = std::sync::atomic::AtomicUsize::new(0);
+        let serve = || async {
+            sends.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let headers = [
+                (header::CONTENT_TYPE.as_str(), "application/json"),
+                (header::CACHE_CONTROL.as_str(), "max-age=2"),
+                (crate::timing::SERVER_TIMING_HEADER, "entry;dur=1.5, exit;dur=12.0"),
+                (crate::fallback::DEGRADED_HEADER, crate::fallback::DIRECT),
+            ];
+            let response = (headers, r#"{"jsonrpc":"2.0","id":1,"result":5}"#).into_response();
+            StoredResponse::read(response).await.unwrap()
+        };
+        
+        let first = store.claim("key", "once", request, now).unwrap().get_or_init(serve).await.replay(false);
+        let duplicate = store.claim("key", "once", request, now).unwrap().get_or_init(serve).await.replay(true);
+        assert_eq!(sends.load(std::sync::atomic::Ordering::SeqCst), 1);
+        
+        let (first, first_body) = first.into_parts();
+        let (mut duplicate, duplicate_body) = duplicate.into_parts();
+        let first_body = hyper::body::to_bytes(first_body).await.unwrap();
+        assert_eq!(first_body, hyper::body::to_bytes(duplicate_body).await.unwrap());
+        assert_eq!(first_body, r#"{"jsonrpc":"2.0","id":1,"result":5}"#);
+        assert_eq!(first.status, duplicate.status);
+        assert!(first.headers.get(REPLAYED_HEADER).is_none());
+        assert_eq!(duplicate.headers.remove(REPLAYED_HEADER).unwrap(), "true");
+        assert_eq!(first.headers, duplicate.headers);
+        assert_eq!(duplicate.headers[header::CACHE_CONTROL], "max-age=2");
+        assert_eq!(duplicate.headers[crate::fallback::DEGRADED_HEADER], crate::fallback::DIRECT);
+    }
+}
@@ -0,0 +1,367 @@
+//! Node long-term identity keys and their rotation
+
+use super::*;
+use super::traits::*;
+use super::report_auth::ReportSigner;
+use super::types::*;
+use std::path::{Path, PathBuf};
+
+/// A public/private key pair
+#[derive(Clone, Serialize, Deserialize)]
+struct KeyPair {
+    public: CryptoKey,
+    private: CryptoKey,
+}
+
+impl KeyPair {
+    async fn generate(crypto: &(dyn Crypto + Send + Sync)) -> Result<Self> {
+        let (public, private) = crypto.generate_keypair().await?;
+        Ok(Self { public, private })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct IdentityState {
+    /// The key pair in use
+    current: KeyPair,
+    /// A published key pair and when it replaces `current`
+    next: Option<(KeyPair, Timestamp)>,
+    /// The replaced key pair, kept until circuits built under it have expired
+    previous: Option<(KeyPair, Timestamp)>,
+}
+
+/// A node's long-term key pairs, rotated with an overlap period
+///
+/// During a rotation the next key is published ahead of its activation time so peers
+/// can accept both. After activation the previous private key is retained for
+/// `retain_previous` so circuits established under it can still complete.
+///
+/// An identity loaded from a file, see [`NodeIdentity::load_or_generate`], writes every
+/// change of its keys back to it, so the node keeps its identity across restarts.
+pub struct NodeIdentity {
+    state: parking_lot::RwLock<IdentityState>,
+    retain_previous: Duration,
+    path: Option<PathBuf>,
+}
+
+impl NodeIdentity {
+    /// Generate a fresh identity
+    pub async fn generate(crypto: &(dyn Crypto + Send + Sync), retain_previous: Duration) -> Result<Self> {
+        Ok(Self {
+            state: parking_lot::RwLock::new(IdentityState {
+                current: KeyPair::generate(crypto).await?,
+                next: None,
+                previous: None,
+            }),
+            retain_previous,
+            path: None,
+        })
+    }
+    
+    /// Load the identity kept at `path`, generating and keeping a fresh one if there is none
+    ///
+    /// Without a path a fresh identity is generated that lasts as long as the process, so
+    /// the node has to be registered again after every restart.
+    pub async fn load_or_generate(crypto: &(dyn Crypto + Send + Sync), path: Option<&Path>, retain_previous: Duration) -> Result<Self> {
+        let Some(path) = path else {
+            return Self::generate(crypto, retain_previous).await;
+        };
+        let identity = match std::fs::read(path) {
+            Ok(contents) => Self {
+                state: parking_lot::RwLock::new(
+                    serde_json::from_slice(&contents)
+                        .map_err(|e| anyhow::anyhow!("Invalid identity file {}: {}", path.display(), e))?,
+                ),
+                retain_previous,
+                path: Some(path.to_path_buf()),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let identity = Self {
+                    path: Some(path.to_path_buf()),
+                    ..Self::generate(crypto, retain_previous).await?
+                };
+                identity.persist(&identity.state.read())?;
+                identity
+            }
+            Err(e) => anyhow::bail!("Failed to read identity file {}: {}", path.display(), e),
+        };
+        Ok(identity)
+    }
+    
+    /// Write `state` to the identity file, if there is one, readable by the owner only
+    fn persist(&self, state: &IdentityState) -> Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let partial = path.with_extension("tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let written = options
+            .open(&partial)
+            .and_then(|mut file| std::io::Write::write_all(&mut file, &serde_json::to_vec(state)?))
+            .and_then(|()| std::fs::rename(&partial, path));
+        written.map_err(|e| anyhow::anyhow!("Failed to write identity file {}: {}", path.display(), e))
+    }
+    
+    /// Swap in the next key if it has activated and forget a retired previous key
+    fn activate_due(&self, now: Timestamp) {
+        let mut state = self.state.write();
+        if let Some((_, activates_at)) = &state.next {
+            if now >= *activates_at {
+                let (next, _) = state.next.take().expect("next key checked above");
+                let current = std::mem::replace(&mut state.current, next);
+                state.previous = Some((current, now + self.retain_previous));
+                // The key is in use whether or not it was written, so a failure is only logged
+                if let Err(e) = self.persist(&state) {
+                    tracing::error!("{}", e);
+                }
+            }
+        }
+        if let Some((_, retire_at)) = &state.previous {
+            if now >= *retire_at {
+                state.previous = None;
+            }
+        }
+    }
+    
+    /// The public key in use at `now`
+    pub fn public_key(&self, now: Timestamp) -> CryptoKey {
+        self.activate_due(now);
+        self.state.read().current.public.clone()
+    }
+    
+    /// Every public key still live at `now`: the current one, the next, and the retained previous
+    pub fn public_keys(&self, now: Timestamp) -> Vec<CryptoKey> {
+        self.activate_due(now);
+        let state = self.state.read();
+        std::iter::once(&state.current)
+            .chain(state.next.as_ref().map(|(next, _)| next))
+            .chain(state.previous.as_ref().map(|(previous, _)| previous))
+            .map(|pair| pair.public.clone())
+            .collect()
+    }
+    
+    /// The pending next public key and its activation time, if a rotation is in progress
+    pub fn pending_rotation(&self, now: Timestamp) -> Option<(CryptoKey, Timestamp)> {
+        self.activate_due(now);
+        self.state
+            .read()
+            .next
+            .as_ref()
+            .map(|(next, activates_at)| (next.public.clone(), *activates_at))
+    }
+    
+    /// Generate the next key pair, activating at `activates_at`, and return its public key
+    pub async fn begin_rotation(&self, crypto: &(dyn Crypto + Send + Sync), activates_at: Timestamp) -> Result<CryptoKey> {
+        if self.pending_rotation(Timestamp::now()).is_some() {
+            anyhow::bail!("A key rotation is already pending");
+        }
+        let next = KeyPair::generate(crypto).await?;
+        let public = next.public.clone();
+        let mut state = self.state.write();
+        let mut pending = state.clone();
+        pending.next = Some((next, activates_at));
+        self.persist(&pending)?;
+        *state = pending;
+        Ok(public)
+    }
+    
+    /// Abandon the pending rotation to `next`, if it hasn't activated yet
+    pub fn cancel_rotation(&self, next: &CryptoKey) -> Result<()> {
+        let mut state = self.state.write();
+        if !matches!(&state.next, Some((pending, _)) if pending.public.0 == next.0) {
+            return Ok(());
+        }
+        let mut cancelled = state.clone();
+        cancelled.next = None;
+        self.persist(&cancelled)?;
+        *state = cancelled;
+        Ok(())
+    }
+    
+    /// Decrypt data sent to this node under any key that is still live at `now`
+    pub async fn decrypt(&self, crypto: &(dyn Crypto + Send + Sync), data: &EncryptedData, now: Timestamp) -> Result<Vec<u8>> {
+        self.activate_due(now);
+        let keys: Vec<CryptoKey> = {
+            let state = self.state.read();
+            std::iter::once(&state.current)
+                .chain(state.next.as_ref().map(|(next, _)| next))
+                .chain(state.previous.as_ref().map(|(previous, _)| previous))
+                .map(|pair| pair.private.clone())
+                .collect()
+        };
+        
+        for key in &keys {
+            if let Ok(plaintext) = crypto.decrypt(data, key).await {
+                return Ok(plaintext);
+            }
+        }
+        anyhow::bail!("Data could not be decrypted under any live node key")
+    }
+    
+    /// Sign data with the key in use at `now`
+    pub async fn sign(&self, crypto: &(dyn Crypto + Send + Sync), data: &[u8], now: Timestamp) -> Result<Vec<u8>> {
+        self.activate_due(now);
+        let private = self.state.read().current.private.clone();
+        crypto.sign(data, &private).await
+    }
+}
+
+/// Verify a signature from `node` under any key the node may legitimately be using at `now`
+pub async fn verify_node_signature(
+    crypto: &(dyn Crypto + Send + Sync),
+    node: &Node,
+    data: &[u8],
+    signature: &[u8],
+    now: Timestamp,
+) -> Result<bool> {
+    for key in node.accepted_public_keys(now) {
+        if crypto.verify(data, signature, key).await.unwrap_or(false) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Request body for publishing a node's next key to the coordinator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublishNextKeyRequest {
+    /// The node rotating its key
+    pub node_id: NodeId,
+    /// The key the node will switch to
+    pub next_public_key: CryptoKey,
+    /// When the node switches to the new key
+    pub activates_at: Timestamp,
+}
+
+/// Response body for publishing a node's next key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublishNextKeyResponse {
+    /// Whether the key was published
+    pub success: bool,
+    /// Error message, if any
+    pub error: Option<String>,
+}
+
+/// The result of a node-local key rotation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotationOutcome {
+    /// The newly generated public key
+    pub next_public_key: CryptoKey,
+    /// When the node switches to the new key
+    pub activates_at: Timestamp,
+}
+
+/// Generates, publishes, and schedules activation of a node's next key
+///
+/// The next key is published in a report signed with the current one, and the rotation is
+/// abandoned if the coordinator doesn't take it, so the node never switches to a key its
+/// peers don't know.
+pub struct KeyRotator {
+    identity: Arc<NodeIdentity>,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    signer: ReportSigner,
+    coordinator_url: String,
+    client: reqwest::Client,
+}
+
+impl KeyRotator {
+    /// Create a rotator that publishes next keys to the coordinator at `coordinator_url`
+    pub fn new(
+        node_id: NodeId,
+        identity: Arc<NodeIdentity>,
+        crypto: Arc<dyn Crypto + Send + Sync>,
+        coordinator_url: String,
+    ) -> Self {
+        Self {
+            signer: ReportSigner::new(node_id, identity.clone(), crypto.clone()),
+            identity,
+            crypto,
+            coordinator_url,
+            client: reqwest::Client::new(),
+        }
+    }
+    
+    /// Generate a new key, publish it to the coordinator, and activate it after `overlap`
+    ///
+    /// The overlap should be at least one directory refresh interval so every peer sees
+    /// the new key before the node starts using it.
+    pub async fn rotate(&self, overlap: Duration) -> Result<RotationOutcome> {
+        let activates_at = Timestamp::now() + overlap;
+        let next_public_key = self.identity.begin_rotation(&*self.crypto, activates_at).await?;
+        
+        let request = PublishNextKeyRequest {
+            node_id: self.signer.node_id().clone(),
+            next_public_key: next_public_key.clone(),
+            activates_at,
+        };
+        if let Err(e) = self.publish(&request).await {
+            self.identity.cancel_rotation(&next_public_key)?;
+            return Err(e);
+        }
+        
+        Ok(RotationOutcome {
+            next_public_key,
+            activates_at,
+        })
+    }
+    
+    /// Publish a next key to the coordinator, failing unless it was taken
+    async fn publish(&self, request: &PublishNextKeyRequest) -> Result<()> {
+        let response: PublishNextKeyResponse = self
+            .signer
+            .post(&self.client, &self.coordinator_url, "/nodes/next-key", request)
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if !response.success {
+            anyhow::bail!(
+                "Coordinator refused the next key: {}",
+                response.error.unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::CryptoImpl;
+    
+    #[tokio::test]
+    async fn keeps_its_keys_across_restarts() {
+        let crypto = CryptoImpl::new();
+        let path = std::env::temp_dir().join(format!("darknode-identity-{}.json", Uuid::new_v4()));
+        let now = Timestamp::now();
+        
+        let identity = NodeIdentity::load_or_generate(&crypto, Some(&path), Duration::ZERO).await.unwrap();
+        let next = identity.begin_rotation(&crypto, now + Duration::from_secs(60)).await.unwrap();
+        let restarted = NodeIdentity::load_or_generate(&crypto, Some(&path), Duration::ZERO).await.unwrap();
+        assert_eq!(restarted.public_key(now).0, identity.public_key(now).0);
+        assert_eq!(restarted.pending_rotation(now).map(|(key, _)| key.0.clone()), Some(next.0.clone()));
+        
+        let signature = restarted.sign(&crypto, b"report", now).await.unwrap();
+        assert!(crypto.verify(b"report", &signature, &identity.public_key(now)).await.unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn abandons_a_rotation_the_coordinator_did_not_take() {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let identity = Arc::new(NodeIdentity::generate(&*crypto, Duration::ZERO).await.unwrap());
+        // Nothing listens on the discard port
+        let rotator = KeyRotator::new(
+            NodeId(Uuid::new_v4()),
+            identity.clone(),
+            crypto.clone(),
+            "http://127.0.0.1:9".to_string(),
+        );
+        
+        assert!(rotator.rotate(Duration::from_secs(60)).await.is_err());
+        assert!(identity.pending_rotation(Timestamp::now()).is_none());
+        // A later rotation isn't blocked by the abandoned one
+        assert!(identity.begin_rotation(&*crypto, Timestamp::now() + Duration::from_secs(60)).await.is_ok());
+    }
+}
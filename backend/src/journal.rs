@@ -0,0 +1,333 @@
+//! Journaling mutating requests, so their outcome can be asked after a crash
+//!
+//! An entry node that crashes after sending a `sendTransaction` into a circuit, but before
+//! answering it, leaves its user not knowing whether the transaction went out. Sending it
+//! again may broadcast it twice, and the idempotency keys of [`crate::idempotency`] are
+//! forgotten with the process. Calls with an idempotency key that hold a mutating method
+//! are therefore written to an append-only journal before they are dispatched, and again
+//! once they are answered. `GET /requests/:idempotency_key/status` tells the client whether
+//! such a call is still in flight, completed, or was sent before the node restarted and
+//! never answered, in which case its outcome is unknown and should be looked up on chain
+//! before sending it again.
+//!
+//! The journal holds hashes only: of the API key and idempotency key together, of the
+//! request, and of the response, never the payloads themselves. Calls are forgotten once
+//! `retention` has passed since they were last journaled, when the file is compacted.
+//! Without a `path` the journal is only kept in memory, and doesn't outlive the process.
+//!
+//! Lines are written and synced to disk on the blocking pool, never on the async workers
+//! serving requests. A call whose write-ahead line can't be written isn't dispatched.
+
+use super::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// How often calls past their retention are forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Where and for how long mutating calls are journaled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JournalConfig {
+    /// File the journal is appended to, if it should survive restarts
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// How long a call is remembered after it was last journaled
+    pub retention: Duration,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            retention: Duration::from_secs(24 * 3600),
+        }
+    }
+}
+
+/// What became of a journaled call, as far as this node knows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestState {
+    /// Sent and not answered yet
+    InFlight,
+    /// Sent before the node restarted, and never answered
+    OutcomeUnknown,
+    /// Answered
+    Completed,
+}
+
+/// A journaled call's state, as told to its client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestStatus {
+    /// What became of the call
+    pub state: RequestState,
+    /// Hash of the call, to tell it from another sent under the same key
+    pub request_hash: String,
+    /// When the call was sent
+    pub sent_at: Timestamp,
+    /// When the call was answered, if it was
+    pub completed_at: Option<Timestamp>,
+    /// The HTTP status the call was answered with, if it was
+    pub status: Option<u16>,
+    /// Hash of the successful answer's body, if there was one
+    pub response_hash: Option<String>,
+}
+
+/// One line of the journal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Line {
+    /// Hash of the call's scope and idempotency key
+    id: String,
+    /// When the line was written
+    at: Timestamp,
+    /// What happened to the call
+    #[serde(flatten)]
+    event: JournalEvent,
+}
+
+/// What a journal line records
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum JournalEvent {
+    /// The call is about to be dispatched
+    Sent {
+        /// Hash of the call
+        request_hash: String,
+    },
+    /// The call was answered
+    Completed {
+        /// The HTTP status it was answered with
+        status: u16,
+        /// Hash of the body of a successful answer
+        response_hash: Option<String>,
+    },
+}
+
+/// A call as the journal has it
+struct Record {
+    status: RequestStatus,
+    /// When the call was last journaled
+    touched: Timestamp,
+}
+
+/// The entry node's journal of mutating calls, see the module docs
+///
+/// The file lock is held while a line is written and synced, and while the file is
+/// compacted; the calls are only locked to change or read them, so asking after a call
+/// never waits on the disk.
+pub struct RequestJournal {
+    config: JournalConfig,
+    file: Arc<parking_lot::Mutex<Option<File>>>,
+    records: Arc<parking_lot::Mutex<HashMap<String, Record>>>,
+}
+
+impl RequestJournal {
+    /// Open the journal, taking calls sent before a restart and never answered as of unknown outcome
+    pub fn open(config: JournalConfig) -> Result<Self> {
+        let mut records = HashMap::new();
+        if let Some(path) = &config.path {
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)?;
+            }
+            if let Ok(journaled) = std::fs::read_to_string(path) {
+                for line in journaled.lines().filter(|line| !line.trim().is_empty()) {
+                    match serde_json::from_str::<Line>(line) {
+                        Ok(line) => apply(&mut records, line, RequestState::OutcomeUnknown),
+                        Err(e) => tracing::warn!("Skipping unreadable journal line in {}: {}", path.display(), e),
+                    }
+                }
+            }
+        }
+        let journal = Self {
+            config,
+            file: Arc::new(parking_lot::Mutex::new(None)),
+            records: Arc::new(parking_lot::Mutex::new(records)),
+        };
+        journal.compact(Timestamp::now())?;
+        Ok(journal)
+    }
+    
+    /// Journal that the call `fingerprint`, under `scope` and `key`, is about to be dispatched
+    ///
+    /// The call must not be dispatched if this fails.
+    pub async fn sent(&self, scope: &str, key: &str, fingerprint: [u8; 32]) -> Result<()> {
+        let event = JournalEvent::Sent {
+            request_hash: hex::encode(&fingerprint),
+        };
+        self.append(scope, key, event).await
+    }
+    
+    /// Journal that the call under `scope` and `key` was answered with `status`, and `body` if it succeeded
+    pub async fn completed(&self, scope: &str, key: &str, status: u16, body: Option<&[u8]>) -> Result<()> {
+        let event = JournalEvent::Completed {
+            status,
+            response_hash: body.map(|body| hex::encode(&Sha256::digest(body))),
+        };
+        self.append(scope, key, event).await
+    }
+    
+    /// What became of the call under `scope` and `key`, if it was journaled and isn't forgotten
+    pub fn status(&self, scope: &str, key: &str) -> Option<RequestStatus> {
+        self.records.lock().get(&id(scope, key)).map(|record| record.status.clone())
+    }
+    
+    /// Write a line to the journal, and to disk before returning
+    async fn append(&self, scope: &str, key: &str, event: JournalEvent) -> Result<()> {
+        let line = Line {
+            id: id(scope, key),
+            at: Timestamp::now(),
+            event,
+        };
+        if self.config.path.is_none() {
+            apply(&mut self.records.lock(), line, RequestState::InFlight);
+            return Ok(());
+        }
+        let mut encoded = serde_json::to_vec(&line)?;
+        encoded.push(b'\n');
+        let (file, records) = (self.file.clone(), self.records.clone());
+        tokio::task::spawn_blocking(move || {
+            // The line is taken in while the file is held, so a compaction can't drop it
+            let mut file = file.lock();
+            if let Some(file) = file.as_mut() {
+                file.write_all(&encoded)?;
+                file.sync_data()?;
+            }
+            apply(&mut records.lock(), line, RequestState::InFlight);
+            Ok(())
+        })
+        .await?
+    }
+    
+    /// Forget calls last journaled more than `retention` before `now`, compacting the file
+    pub async fn prune(self: &Arc<Self>, now: Timestamp) -> Result<()> {
+        let journal = self.clone();
+        tokio::task::spawn_blocking(move || journal.compact(now)).await?
+    }
+    
+    /// Forget calls past their retention and compact the file, blocking on the disk
+    fn compact(&self, now: Timestamp) -> Result<()> {
+        let mut file = self.file.lock();
+        let kept: Vec<Line> = {
+            let mut records = self.records.lock();
+            let retention = self.config.retention;
+            records.retain(|_, record| now.saturating_duration_since(record.touched) <= retention);
+            metrics::gauge!("darknode_journal_requests", records.len() as f64);
+            records.iter().flat_map(|(id, record)| lines(id, &record.status)).collect()
+        };
+        let Some(path) = &self.config.path else { return Ok(()) };
+        
+        // Write the calls kept to a new file and swap it in, so a crash meanwhile loses nothing
+        let compacted = path.with_extension("compacting");
+        let mut written = File::create(&compacted)?;
+        for line in &kept {
+            serde_json::to_writer(&mut written, line)?;
+            written.write_all(b"\n")?;
+        }
+        written.sync_all()?;
+        std::fs::rename(&compacted, path)?;
+        *file = Some(OpenOptions::new().append(true).open(path)?);
+        Ok(())
+    }
+    
+    /// Forget calls past their retention until the task is dropped
+    pub async fn run(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(PRUNE_INTERVAL.min(self.config.retention));
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.prune(Timestamp::now()).await {
+                tracing::warn!("Failed to compact the request journal: {}", e);
+            }
+        }
+    }
+}
+
+/// Apply a journal line to the calls it holds; a call sent gets `sent_state` until answered
+fn apply(records: &mut HashMap<String, Record>, line: Line, sent_state: RequestState) {
+    match line.event {
+        JournalEvent::Sent { request_hash } => {
+            let status = RequestStatus {
+                state: sent_state,
+                request_hash,
+                sent_at: line.at,
+                completed_at: None,
+                status: None,
+                response_hash: None,
+            };
+            records.insert(line.id, Record { status, touched: line.at });
+        }
+        JournalEvent::Completed { status, response_hash } => {
+            let Some(record) = records.get_mut(&line.id) else { return };
+            record.status.state = RequestState::Completed;
+            record.status.completed_at = Some(line.at);
+            record.status.status = Some(status);
+            record.status.response_hash = response_hash;
+            record.touched = line.at;
+        }
+    }
+}
+
+/// The lines that journal a call as it stands
+fn lines(id: &str, status: &RequestStatus) -> Vec<Line> {
+    let mut lines = vec![Line {
+        id: id.to_string(),
+        at: status.sent_at,
+        event: JournalEvent::Sent {
+            request_hash: status.request_hash.clone(),
+        },
+    }];
+    if let (Some(at), Some(code)) = (status.completed_at, status.status) {
+        lines.push(Line {
+            id: id.to_string(),
+            at,
+            event: JournalEvent::Completed {
+                status: code,
+                response_hash: status.response_hash.clone(),
+            },
+        });
+    }
+    lines
+}
+
+/// The journal ID of the call under `scope` and `key`
+fn id(scope: &str, key: &str) -> String {
+    hex::encode(&Sha256::digest(format!("{}\n{}", scope, key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn calls_sent_before_a_crash_and_never_answered_are_of_unknown_outcome() {
+        let path = std::env::temp_dir().join(format!("darknode-journal-{}.jsonl", Uuid::new_v4()));
+        let config = JournalConfig {
+            path: Some(path.clone()),
+            ..JournalConfig::default()
+        };
+        let journal = Arc::new(RequestJournal::open(config.clone()).unwrap());
+        journal.sent("api-key", "answered", [1; 32]).await.unwrap();
+        journal.completed("api-key", "answered", 200, Some(b"{\"result\":\"sig\"}")).await.unwrap();
+        journal.sent("api-key", "lost", [2; 32]).await.unwrap();
+        assert_eq!(journal.status("api-key", "lost").unwrap().state, RequestState::InFlight);
+        assert!(journal.status("other-key", "lost").is_none());
+        
+        // Compacting keeps both calls, and the node crashes before answering the second
+        journal.prune(Timestamp::now()).await.unwrap();
+        drop(journal);
+        
+        let restarted = RequestJournal::open(config).unwrap();
+        let lost = restarted.status("api-key", "lost").unwrap();
+        assert_eq!(lost.state, RequestState::OutcomeUnknown);
+        assert_eq!(lost.request_hash, hex::encode(&[2; 32]));
+        let answered = restarted.status("api-key", "answered").unwrap();
+        assert_eq!(answered.state, RequestState::Completed);
+        assert_eq!(answered.status, Some(200));
+        assert_eq!(answered.response_hash, Some(hex::encode(&Sha256::digest(b"{\"result\":\"sig\"}"))));
+        let _ = std::fs::remove_file(path);
+    }
+}
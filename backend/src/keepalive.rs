@@ -0,0 +1,124 @@
+//! Keepalive pings through idle circuits
+//!
+//! A hop can die between requests, and without pings the entry node would only find out
+//! when the next real request times out. Idle circuits are pinged through every hop to the
+//! exit node, which answers without contacting a provider; a circuit that misses enough
+//! pongs in a row is dropped and rebuilt before a user request lands on it.
+
+use super::*;
+use super::chains::Network;
+use super::types::ExitPayload;
+
+/// Method of the ping request; exit nodes answer it themselves
+pub const PING_METHOD: &str = "darknode_ping";
+
+/// How idle circuits are pinged and when they are given up on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeepaliveConfig {
+    /// Whether idle circuits are pinged at all
+    pub enabled: bool,
+    /// How long a circuit must be idle before it is pinged, and how often it is pinged after
+    pub interval: Duration,
+    /// How long to wait for a pong
+    pub timeout: Duration,
+    /// Consecutive missed pongs after which a circuit is considered dead
+    pub missed_threshold: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(5),
+            missed_threshold: 2,
+        }
+    }
+}
+
+/// The payload sent through a circuit as a ping
+///
+/// It is a regular exit payload, encrypted and layered like any request, so hops can't
+/// tell it apart from user traffic.
+pub fn ping() -> ExitPayload {
+    ExitPayload {
+        request: serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": PING_METHOD,
+        }),
+        quorum: None,
+        capabilities: Vec::new(),
+        trace_token: None,
+        preflight: false,
+        relay: false,
+        debug_errors: false,
+        timeout: None,
+        notification: false,
+        chain: None,
+        network: Network::Mainnet,
+        normalize: false,
+        timing: false,
+        attribution: false,
+        provider: None,
+    }
+}
+
+/// Whether a payload is a ping
+pub fn is_ping(payload: &ExitPayload) -> bool {
+    super::methods::method_name(&payload.request) == Some(PING_METHOD)
+}
+
+/// The exit node's answer to a ping
+pub fn pong(payload: &ExitPayload) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": payload.request["id"].clone(),
+        "result": "pong",
+    }))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EntryConfig;
+    use crate::context::RequestContext;
+    use crate::fixtures::{self, StubRouter};
+    use crate::traits::UserManager;
+    use crate::types::CircuitId;
+    use serde_json::json;
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_circuit_through_a_dead_hop_is_replaced_before_a_request_uses_it() {
+        let router = Arc::new(StubRouter::new(|_| json!({ "jsonrpc": "2.0", "result": 250_000_000 })));
+        let config = KeepaliveConfig::default();
+        let entry = EntryConfig {
+            keepalive: config.clone(),
+            ..Default::default()
+        };
+        let (entry, users) = fixtures::entry(router.clone(), &entry).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let ctx = || RequestContext {
+            api_key: user.api_key.clone(),
+            ..Default::default()
+        };
+        let request = serde_json::to_vec(&json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" })).unwrap();
+        entry.handle_request(ctx(), &request).await.unwrap();
+        let first = router.circuits().remove(0);
+        
+        // The circuit's routing hop dies while it is idle, and misses pong after pong
+        router.kill(&first.routing_nodes[0]);
+        for missed in 1..=config.missed_threshold {
+            tokio::time::advance(config.interval).await;
+            entry.keepalive().await;
+            assert_eq!(router.circuits().len() == 2, missed == config.missed_threshold);
+        }
+        assert_eq!(router.closed(), vec![first.id.clone()]);
+        
+        // The next request goes out on the circuit built in its place
+        entry.handle_request(ctx(), &request).await.unwrap();
+        let sent: Vec<CircuitId> = router.sent().into_iter().map(|(circuit, _)| circuit).collect();
+        assert_eq!(sent, vec![first.id, router.circuits()[1].id.clone()]);
+    }
+}
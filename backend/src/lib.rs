@@ -4,8 +4,10 @@
 //! which routes blockchain RPC requests through a secure, multi-layered network to
 //! prevent tracking and logging of user activity.
 
+#![deny(missing_docs)]
+
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 use std::net::IpAddr;
 
 use anyhow::Result;
@@ -14,711 +16,130 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
-/// Core types used throughout the DarkNode system
-pub mod types {
-    use super::*;
-
-    /// Unique identifier for a node in the DarkNode network
-    #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-    pub struct NodeId(pub Uuid);
-
-    /// Unique identifier for a circuit through the DarkNode network
-    #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-    pub struct CircuitId(pub Uuid);
-
-    /// Represents a cryptographic key used for encryption and authentication
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    pub struct CryptoKey(pub Vec<u8>);
-
-    /// Represents an encrypted payload
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    pub struct EncryptedData {
-        /// The encrypted data
-        pub data: Vec<u8>,
-        /// The nonce used for encryption
-        pub nonce: Vec<u8>,
-        /// Additional authenticated data
-        pub aad: Option<Vec<u8>>,
-    }
-
-    /// Represents a node's role in the DarkNode network
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-    pub enum NodeRole {
-        /// Entry nodes accept connections from users
-        Entry,
-        /// Routing nodes forward traffic through the network
-        Routing,
-        /// Exit nodes connect to RPC providers
-        Exit,
-        /// Coordinator nodes manage the network topology
-        Coordinator,
-    }
-
-    /// Represents the status of a node
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-    pub enum NodeStatus {
-        /// Node is online and ready to accept connections
-        Online,
-        /// Node is online but not accepting new connections
-        Busy,
-        /// Node is offline
-        Offline,
-        /// Node is in maintenance mode
-        Maintenance,
-    }
-
-    /// Represents a node in the DarkNode network
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    pub struct Node {
-        /// Unique identifier for the node
-        pub id: NodeId,
-        /// The role of the node
-        pub role: NodeRole,
-        /// The status of the node
-        pub status: NodeStatus,
-        /// The public key of the node
-        pub public_key: CryptoKey,
-        /// The IP address of the node
-        pub ip_address: IpAddr,
-        /// The port the node is listening on
-        pub port: u16,
-        /// When the node was last seen
-        pub last_seen: SystemTime,
-        /// The geographic region of the node
-        pub region: String,
-        /// The load on the node (0.0 - 1.0)
-        pub load: f32,
-    }
-
-    /// Represents an RPC provider
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    pub struct RpcProvider {
-        /// Unique identifier for the provider
-        pub id: Uuid,
-        /// The URL of the provider
-        pub url: String,
-        /// The type of provider (e.g., Solana, Ethereum)
-        pub provider_type: String,
-        /// Whether the provider is currently active
-        pub active: bool,
-        /// The success rate of requests to this provider (0.0 - 1.0)
-        pub success_rate: f32,
-        /// The average latency of requests to this provider
-        pub avg_latency: Duration,
-        /// The last time the provider was checked
-        pub last_checked: SystemTime,
-    }
-
-    /// Represents a user of the DarkNode service
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    pub struct User {
-        /// Unique identifier for the user
-        pub id: Uuid,
-        /// The Solana wallet address of the user
-        pub wallet_address: String,
-        /// The API key assigned to the user
-        pub api_key: String,
-        /// Whether the user's subscription is active
-        pub active: bool,
-        /// When the user's subscription expires
-        pub expires_at: Option<SystemTime>,
-        /// The user's custom RPC mappings
-        pub rpc_mappings: Vec<RpcMapping>,
-    }
-
-    /// Represents a mapping from an original RPC to a DarkNode RPC
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    pub struct RpcMapping {
-        /// Unique identifier for the mapping
-        pub id: Uuid,
-        /// The original RPC URL
-        pub original_rpc: String,
-        /// The DarkNode HTTPS RPC URL
-        pub darknode_https_rpc: String,
-        /// The DarkNode WSS RPC URL
-        pub darknode_wss_rpc: String,
-        /// When the mapping was created
-        pub created_at: SystemTime,
-    }
-
-    /// Represents a circuit through the DarkNode network
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    pub struct Circuit {
-        /// Unique identifier for the circuit
-        pub id: CircuitId,
-        /// The entry node for the circuit
-        pub entry_node: NodeId,
-        /// The routing nodes for the circuit
-        pub routing_nodes: Vec<NodeId>,
-        /// The exit node for the circuit
-        pub exit_node: NodeId,
-        /// The symmetric keys for each hop
-        pub symmetric_keys: Vec<CryptoKey>,
-        /// When the circuit was created
-        pub created_at: SystemTime,
-        /// When the circuit expires
-        pub expires_at: SystemTime,
-    }
-
-    /// Represents a request through the DarkNode network
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    pub struct Request {
-        /// Unique identifier for the request
-        pub id: Uuid,
-        /// The circuit ID for the request
-        pub circuit_id: CircuitId,
-        /// The encrypted payload
-        pub payload: EncryptedData,
-        /// When the request was created
-        pub created_at: SystemTime,
-    }
-
-    /// Represents a response through the DarkNode network
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    pub struct Response {
-        /// The request ID this response is for
-        pub request_id: Uuid,
-        /// The circuit ID for the response
-        pub circuit_id: CircuitId,
-        /// The encrypted payload
-        pub payload: EncryptedData,
-        /// When the response was created
-        pub created_at: SystemTime,
-    }
-}
-
-/// Traits defining the behavior of different components in the DarkNode system
-pub mod traits {
-    use super::*;
-    use super::types::*;
-
-    /// Trait for components that can encrypt and decrypt data
-    #[async_trait]
-    pub trait Crypto {
-        /// Generate a new key pair
-        async fn generate_keypair(&self) -> Result<(CryptoKey, CryptoKey)>;
-        
-        /// Encrypt data with a public key
-        async fn encrypt(&self, data: &[u8], public_key: &CryptoKey) -> Result<EncryptedData>;
-        
-        /// Decrypt data with a private key
-        async fn decrypt(&self, data: &EncryptedData, private_key: &CryptoKey) -> Result<Vec<u8>>;
-        
-        /// Sign data with a private key
-        async fn sign(&self, data: &[u8], private_key: &CryptoKey) -> Result<Vec<u8>>;
-        
-        /// Verify a signature with a public key
-        async fn verify(&self, data: &[u8], signature: &[u8], public_key: &CryptoKey) -> Result<bool>;
-    }
-
-    /// Trait for components that can route requests through the network
-    #[async_trait]
-    pub trait Router {
-        /// Create a new circuit through the network
-        async fn create_circuit(&self) -> Result<Circuit>;
-        
-        /// Send a request through a circuit
-        async fn send_request(&self, circuit: &Circuit, request: &[u8]) -> Result<Uuid>;
-        
-        /// Receive a response from a circuit
-        async fn receive_response(&self, request_id: Uuid) -> Result<Vec<u8>>;
-    }
-
-    /// Trait for components that can manage nodes in the network
-    #[async_trait]
-    pub trait NodeManager {
-        /// Register a new node in the network
-        async fn register_node(&self, node: Node) -> Result<()>;
-        
-        /// Update a node's status
-        async fn update_node_status(&self, node_id: &NodeId, status: NodeStatus) -> Result<()>;
-        
-        /// Get a list of available nodes of a specific role
-        async fn get_available_nodes(&self, role: NodeRole) -> Result<Vec<Node>>;
-        
-        /// Get a specific node by ID
-        async fn get_node(&self, node_id: &NodeId) -> Result<Option<Node>>;
-    }
-
-    /// Trait for components that can manage RPC providers
-    #[async_trait]
-    pub trait RpcManager {
-        /// Register a new RPC provider
-        async fn register_provider(&self, provider: RpcProvider) -> Result<()>;
-        
-        /// Update an RPC provider's status
-        async fn update_provider_status(&self, provider_id: Uuid, active: bool) -> Result<()>;
-        
-        /// Get a list of active RPC providers
-        async fn get_active_providers(&self) -> Result<Vec<RpcProvider>>;
-        
-        /// Get the best RPC provider based on performance metrics
-        async fn get_best_provider(&self) -> Result<Option<RpcProvider>>;
-    }
-
-    /// Trait for components that can manage user accounts
-    #[async_trait]
-    pub trait UserManager {
-        /// Create a new user
-        async fn create_user(&self, wallet_address: &str) -> Result<User>;
-        
-        /// Get a user by API key
-        async fn get_user_by_api_key(&self, api_key: &str) -> Result<Option<User>>;
-        
-        /// Get a user by wallet address
-        async fn get_user_by_wallet(&self, wallet_address: &str) -> Result<Option<User>>;
-        
-        /// Add an RPC mapping for a user
-        async fn add_rpc_mapping(&self, user_id: Uuid, mapping: RpcMapping) -> Result<()>;
-        
-        /// Get all RPC mappings for a user
-        async fn get_rpc_mappings(&self, user_id: Uuid) -> Result<Vec<RpcMapping>>;
-    }
-
-    /// Trait for components that can sanitize requests to remove identifying information
-    #[async_trait]
-    pub trait RequestSanitizer {
-        /// Sanitize an RPC request to remove identifying information
-        async fn sanitize_request(&self, request: &[u8]) -> Result<Vec<u8>>;
-        
-        /// Prepare a response for delivery back to the client
-        async fn prepare_response(&self, response: &[u8]) -> Result<Vec<u8>>;
-    }
-}
+use clock::Timestamp;
+
+pub mod accounting;
+pub mod admission;
+pub mod api_key;
+pub mod anonymity;
+pub mod attribution;
+pub mod audit;
+pub mod backoff;
+pub mod bandwidth;
+pub mod billing;
+pub mod bootstrap;
+pub mod breaker;
+pub mod budget;
+pub mod build_info;
+pub mod cache;
+pub mod cache_hints;
+pub mod canonical;
+#[cfg(feature = "canary")]
+pub mod canary;
+pub mod capabilities;
+pub mod chains;
+pub mod circuit_class;
+pub mod circuit_info;
+pub mod clock;
+pub mod compliance;
+pub mod compression;
+pub mod config;
+#[cfg(any(test, feature = "test-util"))]
+pub mod conformance;
+pub mod context;
+pub mod crypto;
+#[cfg(feature = "dev-logging")]
+pub mod dev_logging;
+pub mod diagnostics;
+pub mod directory;
+pub mod directory_watch;
+pub mod dns;
+pub mod drain;
+pub mod egress;
+pub mod emulation;
+pub mod epochs;
+pub mod events;
+pub mod expiring;
+pub mod fairness;
+pub mod fallback;
+#[cfg(any(test, feature = "test-util"))]
+pub mod fixtures;
+pub mod flags;
+pub mod hedge;
+pub mod heartbeat;
+pub mod hex;
+pub mod hop_auth;
+pub mod idempotency;
+pub mod identity;
+pub mod journal;
+pub mod keepalive;
+pub mod maintenance;
+pub mod managers;
+pub mod membership;
+pub mod method_routing;
+pub mod methods;
+pub mod multiplex;
+pub mod operator;
+pub mod outbox;
+pub mod nodes;
+pub mod normalize;
+pub mod pipelining;
+pub mod pools;
+pub mod preflight;
+pub mod privacy;
+pub mod protocol;
+pub mod provisioning;
+pub mod provider_errors;
+pub mod quorum;
+pub mod ratchet;
+pub mod reachability;
+pub mod receipts;
+pub mod recommend;
+pub mod reclaim;
+pub mod regions;
+pub mod relaxation;
+pub mod relay;
+pub mod replay;
+pub mod report_auth;
+pub mod resources;
+pub mod sanitizer;
+pub mod scatter;
+pub mod routing;
+pub mod schema;
+pub mod scopes;
+pub mod scoring;
+pub mod sessions;
+pub mod shadow;
+pub mod shaping;
+pub mod signing;
+pub mod storage;
+pub mod streamed;
+pub mod submissions;
+pub mod telemetry;
+pub mod timeouts;
+pub mod timing;
+pub mod traffic;
+pub mod traits;
+pub mod transport;
+pub mod types;
+pub mod upstream;
+pub mod wallets;
+pub mod warmup;
+pub mod webhooks;
+pub mod whatif;
+
+// Paths from before the split into modules, kept so existing users don't break
+pub use managers::{dashboard, probe, quota};
+pub use nodes::coordinator;
+pub use nodes::entry as entry_node;
+pub use nodes::exit as exit_node;
+pub use nodes::routing as routing_node;
 
 /// Implementations of the core traits
 pub mod impls {
-    use super::*;
-    use super::traits::*;
-    use super::types::*;
-    use rand::rngs::OsRng;
-    use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature};
-    use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
-    use chacha20poly1305::aead::{Aead, NewAead};
-    use sha2::{Sha256, Digest};
-    
-    /// Implementation of the Crypto trait using Ed25519 and ChaCha20Poly1305
-    pub struct CryptoImpl;
-    
-    #[async_trait]
-    impl Crypto for CryptoImpl {
-        async fn generate_keypair(&self) -> Result<(CryptoKey, CryptoKey)> {
-            let mut csprng = OsRng;
-            let keypair = Keypair::generate(&mut csprng);
-            let public_key = CryptoKey(keypair.public.to_bytes().to_vec());
-            let private_key = CryptoKey(keypair.secret.to_bytes().to_vec());
-            Ok((public_key, private_key))
-        }
-        
-        async fn encrypt(&self, data: &[u8], public_key: &CryptoKey) -> Result<EncryptedData> {
-            // In a real implementation, this would use proper hybrid encryption
-            // For simplicity, we're using ChaCha20Poly1305 with a derived key
-            
-            // Derive a symmetric key from the public key
-            let mut hasher = Sha256::new();
-            hasher.update(&public_key.0);
-            let key_bytes = hasher.finalize();
-            
-            let key = Key::from_slice(&key_bytes);
-            let cipher = ChaCha20Poly1305::new(key);
-            
-            // Generate a random nonce
-            let mut nonce_bytes = [0u8; 12];
-            OsRng.fill_bytes(&mut nonce_bytes);
-            let nonce = Nonce::from_slice(&nonce_bytes);
-            
-            // Encrypt the data
-            let ciphertext = cipher.encrypt(nonce, data)?;
-            
-            Ok(EncryptedData {
-                data: ciphertext,
-                nonce: nonce_bytes.to_vec(),
-                aad: None,
-            })
-        }
-        
-        async fn decrypt(&self, data: &EncryptedData, private_key: &CryptoKey) -> Result<Vec<u8>> {
-            // In a real implementation, this would use proper hybrid decryption
-            // For simplicity, we're using ChaCha20Poly1305 with a derived key
-            
-            // Derive a symmetric key from the private key
-            let mut hasher = Sha256::new();
-            hasher.update(&private_key.0);
-            let key_bytes = hasher.finalize();
-            
-            let key = Key::from_slice(&key_bytes);
-            let cipher = ChaCha20Poly1305::new(key);
-            
-            // Create a nonce from the provided bytes
-            let nonce = Nonce::from_slice(&data.nonce);
-            
-            // Decrypt the data
-            let plaintext = cipher.decrypt(nonce, data.data.as_ref())?;
-            
-            Ok(plaintext)
-        }
-        
-        async fn sign(&self, data: &[u8], private_key: &CryptoKey) -> Result<Vec<u8>> {
-            let secret = SecretKey::from_bytes(&private_key.0)?;
-            let public = PublicKey::from(&secret);
-            let keypair = Keypair { secret, public };
-            
-            let signature = keypair.sign(data);
-            Ok(signature.to_bytes().to_vec())
-        }
-        
-        async fn verify(&self, data: &[u8], signature: &[u8], public_key: &CryptoKey) -> Result<bool> {
-            let public = PublicKey::from_bytes(&public_key.0)?;
-            let sig = Signature::from_bytes(signature)?;
-            
-            Ok(public.verify(data, &sig).is_ok())
-        }
-    }
-    
-    /// Implementation of the Router trait
-    pub struct RouterImpl {
-        node_manager: Arc<dyn NodeManager + Send + Sync>,
-        crypto: Arc<dyn Crypto + Send + Sync>,
-    }
-    
-    impl RouterImpl {
-        pub fn new(
-            node_manager: Arc<dyn NodeManager + Send + Sync>,
-            crypto: Arc<dyn Crypto + Send + Sync>,
-        ) -> Self {
-            Self {
-                node_manager,
-                crypto,
-            }
-        }
-    }
-    
-    #[async_trait]
-    impl Router for RouterImpl {
-        async fn create_circuit(&self) -> Result<Circuit> {
-            // Get available entry nodes
-            let entry_nodes = self.node_manager.get_available_nodes(NodeRole::Entry).await?;
-            if entry_nodes.is_empty() {
-                anyhow::bail!("No available entry nodes");
-            }
-            
-            // Select an entry node (in a real implementation, this would use more sophisticated selection)
-            let entry_node = &entry_nodes[0];
-            
-            // Get available routing nodes
-            let routing_nodes = self.node_manager.get_available_nodes(NodeRole::Routing).await?;
-            if routing_nodes.is_empty() {
-                anyhow::bail!("No available routing nodes");
-            }
-            
-            // Select routing nodes (in a real implementation, this would use more sophisticated selection)
-            // For this example, we'll use 2 routing nodes
-            let selected_routing_nodes = vec![
-                routing_nodes[0].id.clone(),
-                routing_nodes[1 % routing_nodes.len()].id.clone(),
-            ];
-            
-            // Get available exit nodes
-            let exit_nodes = self.node_manager.get_available_nodes(NodeRole::Exit).await?;
-            if exit_nodes.is_empty() {
-                anyhow::bail!("No available exit nodes");
-            }
-            
-            // Select an exit node (in a real implementation, this would use more sophisticated selection)
-            let exit_node = &exit_nodes[0];
-            
-            // Generate symmetric keys for each hop
-            let mut symmetric_keys = Vec::new();
-            for _ in 0..selected_routing_nodes.len() + 2 {  // +2 for entry and exit nodes
-                let (public_key, _) = self.crypto.generate_keypair().await?;
-                symmetric_keys.push(public_key);
-            }
-            
-            // Create the circuit
-            let circuit = Circuit {
-                id: CircuitId(Uuid::new_v4()),
-                entry_node: entry_node.id.clone(),
-                routing_nodes: selected_routing_nodes,
-                exit_node: exit_node.id.clone(),
-                symmetric_keys,
-                created_at: SystemTime::now(),
-                expires_at: SystemTime::now() + Duration::from_secs(3600),  // 1 hour expiration
-            };
-            
-            Ok(circuit)
-        }
-        
-        async fn send_request(&self, circuit: &Circuit, request: &[u8]) -> Result<Uuid> {
-            // In a real implementation, this would encrypt the request for each hop in the circuit
-            // and send it to the entry node
-            
-            // For simplicity, we'll just generate a request ID
-            let request_id = Uuid::new_v4();
-            
-            // In a real implementation, we would store the request and circuit information
-            // for later correlation with the response
-            
-            Ok(request_id)
-        }
-        
-        async fn receive_response(&self, request_id: Uuid) -> Result<Vec<u8>> {
-            // In a real implementation, this would wait for and decrypt the response
-            // from the circuit
-            
-            // For simplicity, we'll just return a dummy response
-            Ok(b"dummy response".to_vec())
-        }
-    }
-}
-
-/// Entry node implementation
-pub mod entry_node {
-    use super::*;
-    use super::traits::*;
-    use super::types::*;
-    
-    /// The entry node service
-    pub struct EntryNodeService {
-        node_id: NodeId,
-        crypto: Arc<dyn Crypto + Send + Sync>,
-        router: Arc<dyn Router + Send + Sync>,
-        sanitizer: Arc<dyn RequestSanitizer + Send + Sync>,
-        user_manager: Arc<dyn UserManager + Send + Sync>,
-        active_circuits: Arc<RwLock<dashmap::DashMap<String, Circuit>>>,
-    }
-    
-    impl EntryNodeService {
-        pub fn new(
-            node_id: NodeId,
-            crypto: Arc<dyn Crypto + Send + Sync>,
-            router: Arc<dyn Router + Send + Sync>,
-            sanitizer: Arc<dyn RequestSanitizer + Send + Sync>,
-            user_manager: Arc<dyn UserManager + Send + Sync>,
-        ) -> Self {
-            Self {
-                node_id,
-                crypto,
-                router,
-                sanitizer,
-                user_manager,
-                active_circuits: Arc::new(RwLock::new(dashmap::DashMap::new())),
-            }
-        }
-        
-        /// Handle an incoming RPC request
-        pub async fn handle_request(&self, api_key: &str, request: &[u8]) -> Result<Vec<u8>> {
-            // Validate the API key
-            let user = match self.user_manager.get_user_by_api_key(api_key).await? {
-                Some(user) if user.active => user,
-                Some(_) => anyhow::bail!("User subscription is not active"),
-                None => anyhow::bail!("Invalid API key"),
-            };
-            
-            // Sanitize the request to remove identifying information
-            let sanitized_request = self.sanitizer.sanitize_request(request).await?;
-            
-            // Get or create a circuit for this user
-            let circuit = self.get_or_create_circuit(api_key).await?;
-            
-            // Send the request through the circuit
-            let request_id = self.router.send_request(&circuit, &sanitized_request).await?;
-            
-            // Wait for the response
-            let response = self.router.receive_response(request_id).await?;
-            
-            // Prepare the response for delivery back to the client
-            let prepared_response = self.sanitizer.prepare_response(&response).await?;
-            
-            Ok(prepared_response)
-        }
-        
-        /// Get an existing circuit or create a new one for a user
-        async fn get_or_create_circuit(&self, api_key: &str) -> Result<Circuit> {
-            // Check if we already have a circuit for this user
-            let active_circuits = self.active_circuits.read().await;
-            if let Some(circuit) = active_circuits.get(api_key) {
-                // Check if the circuit is still valid
-                if circuit.expires_at > SystemTime::now() {
-                    return Ok(circuit.clone());
-                }
-            }
-            drop(active_circuits);  // Release the read lock
-            
-            // Create a new circuit
-            let circuit = self.router.create_circuit().await?;
-            
-            // Store the circuit
-            let mut active_circuits = self.active_circuits.write().await;
-            active_circuits.insert(api_key.to_string(), circuit.clone());
-            
-            Ok(circuit)
-        }
-    }
-}
-
-/// Routing node implementation
-pub mod routing_node {
-    use super::*;
-    use super::traits::*;
-    use super::types::*;
-    
-    /// The routing node service
-    pub struct RoutingNodeService {
-        node_id: NodeId,
-        crypto: Arc<dyn Crypto + Send + Sync>,
-        next_hop_connections: Arc<RwLock<dashmap::DashMap<NodeId, hyper::Client<hyper::client::HttpConnector>>>>,
-    }
-    
-    impl RoutingNodeService {
-        pub fn new(
-            node_id: NodeId,
-            crypto: Arc<dyn Crypto + Send + Sync>,
-        ) -> Self {
-            Self {
-                node_id,
-                crypto,
-                next_hop_connections: Arc::new(RwLock::new(dashmap::DashMap::new())),
-            }
-        }
-        
-        /// Handle an incoming request from a previous hop
-        pub async fn handle_request(&self, request: &Request) -> Result<()> {
-            // In a real implementation, this would:
-            // 1. Decrypt the layer of encryption for this hop
-            // 2. Determine the next hop
-            // 3. Re-encrypt for the next hop
-            // 4. Forward to the next hop
-            
-            // For simplicity, we'll just log that we received a request
-            tracing::info!("Routing node {} received request {}", self.node_id.0, request.id);
-            
-            Ok(())
-        }
-        
-        /// Handle an incoming response from a next hop
-        pub async fn handle_response(&self, response: &Response) -> Result<()> {
-            // In a real implementation, this would:
-            // 1. Decrypt the layer of encryption for this hop
-            // 2. Determine the previous hop
-            // 3. Re-encrypt for the previous hop
-            // 4. Forward to the previous hop
-            
-            // For simplicity, we'll just log that we received a response
-            tracing::info!("Routing node {} received response for request {}", self.node_id.0, response.request_id);
-            
-            Ok(())
-        }
-    }
-}
-
-/// Exit node implementation
-pub mod exit_node {
-    use super::*;
-    use super::traits::*;
-    use super::types::*;
-    
-    /// The exit node service
-    pub struct ExitNodeService {
-        node_id: NodeId,
-        crypto: Arc<dyn Crypto + Send + Sync>,
-        rpc_manager: Arc<dyn RpcManager + Send + Sync>,
-        rpc_clients: Arc<RwLock<dashmap::DashMap<Uuid, reqwest::Client>>>,
-    }
-    
-    impl ExitNodeService {
-        pub fn new(
-            node_id: NodeId,
-            crypto: Arc<dyn Crypto + Send + Sync>,
-            rpc_manager: Arc<dyn RpcManager + Send + Sync>,
-        ) -> Self {
-            Self {
-                node_id,
-                crypto,
-                rpc_manager,
-                rpc_clients: Arc::new(RwLock::new(dashmap::DashMap::new())),
-            }
-        }
-        
-        /// Handle an incoming request from the routing layer
-        pub async fn handle_request(&self, request: &Request) -> Result<Response> {
-            // In a real implementation, this would:
-            // 1. Decrypt the final layer of encryption
-            // 2. Forward the request to the appropriate RPC provider
-            // 3. Receive the response from the RPC provider
-            // 4. Encrypt the response for the return journey
-            // 5. Send the response back through the circuit
-            
-            // For simplicity, we'll just log that we received a request and generate a dummy response
-            tracing::info!("Exit node {} received request {}", self.node_id.0, request.id);
-            
-            // Get the best RPC provider
-            let provider = match self.rpc_manager.get_best_provider().await? {
-                Some(provider) => provider,
-                None => anyhow::bail!("No available RPC providers"),
-            };
-            
-            // In a real implementation, we would forward the request to the RPC provider
-            // and receive a response
-            
-            // Generate a dummy response
-            let response = Response {
-                request_id: request.id,
-                circuit_id: request.circuit_id.clone(),
-                payload: request.payload.clone(),  // In a real implementation, this would be the encrypted response
-                created_at: SystemTime::now(),
-            };
-            
-            Ok(response)
-        }
-    }
-}
-
-/// Coordinator node implementation
-pub mod coordinator {
-    use super::*;
-    use super::traits::*;
-    use super::types::*;
-    
-    /// The coordinator service
-    pub struct CoordinatorService {
-        node_manager: Arc<dyn NodeManager + Send + Sync>,
-        rpc_manager: Arc<dyn RpcManager + Send + Sync>,
-    }
-    
-    impl CoordinatorService {
-        pub fn new(
-            node_manager: Arc<dyn NodeManager + Send + Sync>,
-            rpc_manager: Arc<dyn RpcManager + Send + Sync>,
-        ) -> Self {
-            Self {
-                node_manager,
-                rpc_manager,
-            }
-        }
-        
-        /// Update the network topology
-        pub async fn update_topology(&self) -> Result<()> {
-            // In a real implementation, this would:
-            // 1. Check the status of all nodes
-            // 2. Update the routing tables
-            // 3. Distribute the updated topology to all nodes
-            
-            // For simplicity, we'll just log that we're updating the topology
-            tracing::info!("Updating network topology");
-            
-            Ok(())
-        }
-        
-        /// Check the health of RPC providers
-        pub async fn check_rpc_health(&self) -> Result<()> {
-            // In a real implementation, this would:
-            // 1. Check the health of all RPC providers
-            // 2. Update their status and performance metrics
-            
-            // For simplicity, we'll just log that we're checking RPC health
-            tracing::info!("Checking RPC provider health");
-            
-            Ok(())
-        }
-    }
+    pub use crate::crypto::CryptoImpl;
+    pub use crate::managers::nodes::StoredNodeManager;
+    pub use crate::managers::providers::StoredRpcManager;
+    pub use crate::managers::users::StoredUserManager;
+    pub use crate::routing::RouterImpl;
 }
@@ -0,0 +1,160 @@
+//! Maintenance windows providers announce, during which they aren't used
+//!
+//! A provider under maintenance fails requests and probes alike, so using or probing it
+//! then only produces errors and drags its health down for long after it is back. Each
+//! provider carries the windows its operator announced, one-off or recurring, and exit
+//! nodes stop picking it [`DRAIN_LEAD`] before a window starts, so no request is still in
+//! flight when it does, and its warm connections are let go. Probes are skipped inside a
+//! window, leaving its health as it was, and it is picked again as soon as the window ends.
+
+use super::*;
+use crate::types::RpcProvider;
+
+/// How long before a window starts a provider stops being picked
+///
+/// The longest an exit node waits on a provider, so requests sent just before the drain
+/// have finished when the window starts.
+pub const DRAIN_LEAD: Duration = Duration::from_secs(120);
+
+/// A period during which a provider is down for maintenance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    /// When the (first) window starts
+    pub start: Timestamp,
+    /// When the (first) window ends
+    pub end: Timestamp,
+    /// Interval the window repeats at, such as a week, if it recurs
+    #[serde(default)]
+    pub recurring: Option<Duration>,
+}
+
+/// Errors from maintenance windows
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidWindow {
+    /// The window ends before it starts
+    #[error("maintenance window must end after it starts")]
+    Empty,
+    /// The window recurs before it has ended
+    #[error("maintenance window must not recur more often than it lasts")]
+    Overlapping,
+    /// The provider already has a window starting at the same time
+    #[error("provider already has a maintenance window starting then")]
+    Duplicate,
+}
+
+impl MaintenanceWindow {
+    /// Check the window can be scheduled
+    pub fn validate(&self) -> Result<(), InvalidWindow> {
+        let length = self.end.checked_duration_since(self.start).ok_or(InvalidWindow::Empty)?;
+        if length.is_zero() {
+            return Err(InvalidWindow::Empty);
+        }
+        if self.recurring.map_or(false, |period| period < length) {
+            return Err(InvalidWindow::Overlapping);
+        }
+        Ok(())
+    }
+    
+    /// Start and end of the occurrence `at` falls in, or else the next one, if any
+    pub fn occurrence(&self, at: Timestamp) -> Option<(Timestamp, Timestamp)> {
+        let length = self.end.checked_duration_since(self.start)?;
+        let Some(period) = self.recurring.filter(|period| !period.is_zero()) else {
+            return (at < self.end).then_some((self.start, self.end));
+        };
+        let Some(since) = at.checked_duration_since(self.start) else {
+            return Some((self.start, self.end));
+        };
+        let elapsed = since.as_nanos() / period.as_nanos() * period.as_nanos();
+        let start = self.start + Duration::from_nanos(elapsed as u64);
+        match at < start + length {
+            true => Some((start, start + length)),
+            false => Some((start + period, start + period + length)),
+        }
+    }
+    
+    /// Whether the window is on at `at`
+    pub fn contains(&self, at: Timestamp) -> bool {
+        self.starts_within(at, Duration::ZERO)
+    }
+    
+    /// Whether the window is on at `at` or starts within `lead` of it
+    pub fn starts_within(&self, at: Timestamp, lead: Duration) -> bool {
+        self.occurrence(at)
+            .map_or(false, |(start, end)| at + lead >= start && at < end)
+    }
+}
+
+/// Whether `provider` is inside one of its maintenance windows at `now`
+pub fn in_window(provider: &RpcProvider, now: Timestamp) -> bool {
+    provider.maintenance_windows.iter().any(|window| window.contains(now))
+}
+
+/// Whether `provider` must not be picked at `now`: inside a window, or draining before one
+pub fn draining(provider: &RpcProvider, now: Timestamp) -> bool {
+    provider
+        .maintenance_windows
+        .iter()
+        .any(|window| window.starts_within(now, DRAIN_LEAD))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use crate::events::EventBus;
+    use crate::managers::probe::{ProbeConfig, ProbeScheduler};
+    use crate::managers::providers::StoredRpcManager;
+    use crate::storage::MemoryStorage;
+    use crate::traits::RpcManager;
+    
+    const HOUR: Duration = Duration::from_secs(3600);
+    
+    fn provider(windows: Vec<MaintenanceWindow>) -> RpcProvider {
+        RpcProvider {
+            last_checked: Timestamp::UNIX_EPOCH,
+            maintenance_windows: windows,
+            ..crate::fixtures::provider()
+        }
+    }
+    
+    #[test]
+    fn providers_drain_before_a_window_and_return_when_it_ends() {
+        let start = Timestamp::from_secs(10 * HOUR.as_secs());
+        let provider = provider(vec![MaintenanceWindow {
+            start,
+            end: start + HOUR,
+            recurring: Some(24 * HOUR),
+        }]);
+        
+        let before_drain = start - DRAIN_LEAD - Duration::from_secs(1);
+        assert!(!draining(&provider, before_drain));
+        assert!(draining(&provider, start - DRAIN_LEAD));
+        assert!(!in_window(&provider, start - DRAIN_LEAD));
+        assert!(in_window(&provider, start));
+        assert!(!draining(&provider, start + HOUR));
+        
+        // The next day's occurrence drains the same way
+        assert!(draining(&provider, start + 24 * HOUR - DRAIN_LEAD));
+        assert!(in_window(&provider, start + 24 * HOUR + HOUR / 2));
+    }
+    
+    #[tokio::test]
+    async fn probes_skip_providers_in_a_window_and_resume_after_it() {
+        let clock = Arc::new(ManualClock::new(Timestamp::from_secs(10 * HOUR.as_secs())));
+        let start = clock.now();
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        rpc_manager
+            .register_provider(provider(vec![MaintenanceWindow {
+                start,
+                end: start + HOUR,
+                recurring: None,
+            }]))
+            .await
+            .unwrap();
+        let probes = ProbeScheduler::new(rpc_manager, ProbeConfig::default(), Arc::new(EventBus::new())).with_clock(clock.clone());
+        
+        assert!(probes.probe_all_now().await.unwrap().results.is_empty());
+        clock.advance(HOUR);
+        assert_eq!(probes.probe_all_now().await.unwrap().results.len(), 1);
+    }
+}
@@ -0,0 +1,417 @@
+//! Coordinator-side aggregation of node heartbeats for the admin dashboard
+
+use crate::*;
+use crate::types::*;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Configuration for heartbeat sample retention
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DashboardConfig {
+    /// How long samples are kept
+    pub retention: Duration,
+    /// Width of each time series bucket
+    pub bucket_size: Duration,
+    /// How long a node may go without a heartbeat before it is shown offline; it is
+    /// forgotten once it has been silent for the retention period
+    pub stale_after: Duration,
+}
+
+impl Default for DashboardConfig {
+    fn default() -> Self {
+        Self {
+            retention: Duration::from_secs(24 * 3600),
+            bucket_size: Duration::from_secs(60),
+            stale_after: Duration::from_secs(300),
+        }
+    }
+}
+
+/// A counter that can be charted over time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DashboardMetric {
+    /// Circuits built or joined
+    CircuitsBuilt,
+    /// Requests forwarded
+    RequestsForwarded,
+    /// Failed requests
+    Errors,
+}
+
+impl DashboardMetric {
+    fn value(&self, counters: &NodeCounters) -> u64 {
+        match self {
+            DashboardMetric::CircuitsBuilt => counters.circuits_built,
+            DashboardMetric::RequestsForwarded => counters.requests_forwarded,
+            DashboardMetric::Errors => counters.errors,
+        }
+    }
+}
+
+/// Summary of a group of nodes sharing a role, region, or status
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GroupSummary {
+    /// Number of nodes in the group
+    pub nodes: usize,
+    /// Mean load across the group's nodes
+    pub avg_load: f64,
+    /// Activity across the group within the retention window
+    pub counters: NodeCounters,
+}
+
+/// Current network totals grouped several ways
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Overview {
+    /// Activity across all nodes within the retention window
+    pub totals: NodeCounters,
+    /// Nodes grouped by role
+    pub by_role: BTreeMap<String, GroupSummary>,
+    /// Nodes grouped by region
+    pub by_region: BTreeMap<String, GroupSummary>,
+    /// Nodes grouped by status
+    pub by_status: BTreeMap<String, GroupSummary>,
+    /// Nodes grouped by the release they run, see [`crate::build_info`]
+    pub by_release: BTreeMap<String, GroupSummary>,
+    /// Requests served per provider pool within the retention window
+    pub by_pool: BTreeMap<String, u64>,
+    /// Requests per method label within the retention window
+    pub by_method: BTreeMap<String, u64>,
+    /// Sum of the entry nodes' estimates of distinct users today
+    ///
+    /// An upper bound, since a user reaching several entry nodes is counted by each.
+    pub unique_users: u64,
+}
+
+/// A single time series bucket
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Bucket {
+    /// Start of the bucket, in seconds since the Unix epoch
+    pub start: u64,
+    /// Sum of the metric over samples received within the bucket
+    pub value: u64,
+}
+
+/// Heartbeat samples for a single node
+struct NodeSeries {
+    roles: Vec<NodeRole>,
+    region: String,
+    status: NodeStatus,
+    load: f64,
+    release: String,
+    samples: VecDeque<(Timestamp, NodeCounters)>,
+    pool_usage: VecDeque<(Timestamp, BTreeMap<String, u64>)>,
+    method_usage: VecDeque<(Timestamp, BTreeMap<String, u64>)>,
+    unique_users: Option<u64>,
+    last_heard: Timestamp,
+}
+
+fn add_counters(total: &mut NodeCounters, counters: &NodeCounters) {
+    total.circuits_built += counters.circuits_built;
+    total.requests_forwarded += counters.requests_forwarded;
+    total.errors += counters.errors;
+}
+
+fn add_to_group(groups: &mut BTreeMap<String, GroupSummary>, key: String, load: f64, counters: &NodeCounters) {
+    let summary = groups.entry(key).or_default();
+    summary.avg_load = scoring::running_mean(summary.avg_load, summary.nodes, load);
+    summary.nodes += 1;
+    add_counters(&mut summary.counters, counters);
+}
+
+fn prune_usage(usage: &mut VecDeque<(Timestamp, BTreeMap<String, u64>)>, cutoff: Timestamp) {
+    while usage.front().map_or(false, |(at, _)| *at < cutoff) {
+        usage.pop_front();
+    }
+}
+
+fn add_usage(total: &mut BTreeMap<String, u64>, usage: &VecDeque<(Timestamp, BTreeMap<String, u64>)>, cutoff: Timestamp) {
+    for (_, sample) in usage.iter().filter(|(at, _)| *at >= cutoff) {
+        for (label, requests) in sample {
+            *total.entry(label.clone()).or_insert(0) += requests;
+        }
+    }
+}
+
+/// Ring buffers of per-node heartbeat samples
+pub struct Dashboard {
+    config: DashboardConfig,
+    nodes: parking_lot::RwLock<HashMap<NodeId, NodeSeries>>,
+}
+
+impl Dashboard {
+    /// Create an empty dashboard
+    pub fn new(config: DashboardConfig) -> Self {
+        Self {
+            config,
+            nodes: parking_lot::RwLock::new(HashMap::new()),
+        }
+    }
+    
+    /// Record a heartbeat received at `received_at`, dropping samples older than the retention
+    pub fn record(&self, heartbeat: &Heartbeat, received_at: Timestamp) {
+        let mut nodes = self.nodes.write();
+        let series = nodes.entry(heartbeat.node_id.clone()).or_insert_with(|| NodeSeries {
+            roles: heartbeat.roles.clone(),
+            region: heartbeat.region.clone(),
+            status: heartbeat.status,
+            load: heartbeat.load,
+            release: build_info::release_of(heartbeat.build.as_ref()),
+            samples: VecDeque::new(),
+            pool_usage: VecDeque::new(),
+            method_usage: VecDeque::new(),
+            unique_users: None,
+            last_heard: received_at,
+        });
+        series.roles = heartbeat.roles.clone();
+        series.region = heartbeat.region.clone();
+        series.status = heartbeat.status;
+        series.load = heartbeat.load;
+        series.release = build_info::release_of(heartbeat.build.as_ref());
+        series.samples.push_back((received_at, heartbeat.counters));
+        series.unique_users = heartbeat.unique_users;
+        series.last_heard = received_at;
+        if !heartbeat.pool_usage.is_empty() {
+            series.pool_usage.push_back((received_at, heartbeat.pool_usage.clone()));
+        }
+        if !heartbeat.method_usage.is_empty() {
+            series.method_usage.push_back((received_at, heartbeat.method_usage.clone()));
+        }
+        
+        let cutoff = received_at - self.config.retention;
+        nodes.retain(|_, series| series.last_heard >= cutoff);
+        for series in nodes.values_mut() {
+            while series.samples.front().map_or(false, |(at, _)| *at < cutoff) {
+                series.samples.pop_front();
+            }
+            prune_usage(&mut series.pool_usage, cutoff);
+            prune_usage(&mut series.method_usage, cutoff);
+        }
+    }
+    
+    /// Whether a node was heard from recently enough at `now` to be taken as it reported
+    fn is_current(&self, series: &NodeSeries, now: Timestamp) -> bool {
+        now.saturating_duration_since(series.last_heard) <= self.config.stale_after
+    }
+    
+    /// The load each node heard from recently reported in its latest heartbeat
+    pub fn loads(&self, now: Timestamp) -> HashMap<NodeId, f64> {
+        self.nodes
+            .read()
+            .iter()
+            .filter(|(_, series)| self.is_current(series, now))
+            .map(|(node_id, series)| (node_id.clone(), series.load))
+            .collect()
+    }
+    
+    /// Requests each node forwarded per second, averaged over the `window` ending at `now`
+    ///
+    /// The window is capped at the retention period.
+    pub fn request_rates(&self, window: Duration, now: Timestamp) -> HashMap<NodeId, f64> {
+        let window = window.min(self.config.retention);
+        let cutoff = now - window;
+        let secs = window.as_secs_f64().max(1.0);
+        self.nodes
+            .read()
+            .iter()
+            .map(|(node_id, series)| {
+                let requests: u64 = series
+                    .samples
+                    .iter()
+                    .filter(|(at, _)| *at >= cutoff)
+                    .map(|(_, counters)| counters.requests_forwarded)
+                    .sum();
+                (node_id.clone(), requests as f64 / secs)
+            })
+            .collect()
+    }
+    
+    /// Requests served from each provider pool per second, averaged over the `window` ending at `now`
+    ///
+    /// The window is capped at the retention period.
+    pub fn pool_rates(&self, window: Duration, now: Timestamp) -> BTreeMap<String, f64> {
+        let window = window.min(self.config.retention);
+        let cutoff = now - window;
+        let mut usage = BTreeMap::new();
+        for series in self.nodes.read().values() {
+            add_usage(&mut usage, &series.pool_usage, cutoff);
+        }
+        let secs = window.as_secs_f64().max(1.0);
+        usage.into_iter().map(|(pool, requests)| (pool, requests as f64 / secs)).collect()
+    }
+    
+    /// Current totals grouped by role, region, and status
+    ///
+    /// Nodes not heard from within `stale_after` are shown offline and idle.
+    pub fn overview(&self, now: Timestamp) -> Overview {
+        let cutoff = now - self.config.retention;
+        let nodes = self.nodes.read();
+        let mut overview = Overview::default();
+        
+        for series in nodes.values() {
+            let mut counters = NodeCounters::default();
+            for (_, sample) in series.samples.iter().filter(|(at, _)| *at >= cutoff) {
+                add_counters(&mut counters, sample);
+            }
+            add_counters(&mut overview.totals, &counters);
+            add_usage(&mut overview.by_pool, &series.pool_usage, cutoff);
+            add_usage(&mut overview.by_method, &series.method_usage, cutoff);
+            let (status, load) = match self.is_current(series, now) {
+                true => (series.status, series.load),
+                false => (NodeStatus::Offline, 0.0),
+            };
+            if status != NodeStatus::Offline {
+                overview.unique_users += series.unique_users.unwrap_or(0);
+            }
+            
+            // A node serving several roles counts towards each of them
+            for role in &series.roles {
+                add_to_group(&mut overview.by_role, format!("{:?}", role), load, &counters);
+            }
+            add_to_group(&mut overview.by_region, series.region.clone(), load, &counters);
+            add_to_group(&mut overview.by_status, format!("{:?}", status), load, &counters);
+            add_to_group(&mut overview.by_release, series.release.clone(), load, &counters);
+        }
+        
+        overview
+    }
+    
+    /// Bucketed series of `metric` over the `window` ending at `now`
+    ///
+    /// Buckets are aligned to multiples of the bucket size since the Unix epoch, and the
+    /// window is capped at the retention period.
+    pub fn timeseries(&self, metric: DashboardMetric, window: Duration, now: Timestamp) -> Vec<Bucket> {
+        let mut buckets = self.empty_buckets(window, now);
+        let nodes = self.nodes.read();
+        for series in nodes.values() {
+            self.add_samples(&mut buckets, series, metric);
+        }
+        buckets
+    }
+    
+    /// Bucketed series of the requests forwarded in each region over the `window` ending at `now`
+    ///
+    /// Buckets are aligned as in [`Dashboard::timeseries`].
+    pub fn region_timeseries(&self, window: Duration, now: Timestamp) -> BTreeMap<String, Vec<Bucket>> {
+        let mut regions = BTreeMap::new();
+        let nodes = self.nodes.read();
+        for series in nodes.values() {
+            let buckets = regions
+                .entry(series.region.clone())
+                .or_insert_with(|| self.empty_buckets(window, now));
+            self.add_samples(buckets, series, DashboardMetric::RequestsForwarded);
+        }
+        regions
+    }
+    
+    /// Zeroed buckets covering the `window` ending at `now`, capped at the retention period
+    fn empty_buckets(&self, window: Duration, now: Timestamp) -> Vec<Bucket> {
+        let bucket_secs = self.config.bucket_size.as_secs().max(1);
+        let window_secs = window.min(self.config.retention).as_secs();
+        let count = ((window_secs + bucket_secs - 1) / bucket_secs).max(1);
+        
+        let last_start = now.as_secs() / bucket_secs * bucket_secs;
+        let first_start = last_start.saturating_sub((count - 1) * bucket_secs);
+        (0..count)
+            .map(|i| Bucket {
+                start: first_start + i * bucket_secs,
+                value: 0,
+            })
+            .collect()
+    }
+    
+    /// Add `metric` of the samples of `series` falling into `buckets` to them
+    fn add_samples(&self, buckets: &mut [Bucket], series: &NodeSeries, metric: DashboardMetric) {
+        let bucket_secs = self.config.bucket_size.as_secs().max(1);
+        let (Some(first), Some(last)) = (buckets.first(), buckets.last()) else {
+            return;
+        };
+        let (first_start, end) = (first.start, last.start + bucket_secs);
+        for (at, counters) in &series.samples {
+            let at = at.as_secs();
+            if at < first_start || at >= end {
+                continue;
+            }
+            let index = ((at - first_start) / bucket_secs) as usize;
+            buckets[index].value += metric.value(counters);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn heartbeat(node: &NodeId, region: &str, requests: u64) -> Heartbeat {
+        Heartbeat {
+            node_id: node.clone(),
+            roles: vec![NodeRole::Routing],
+            status: NodeStatus::Online,
+            region: region.to_string(),
+            load: 0.5,
+            counters: NodeCounters {
+                circuits_built: 0,
+                requests_forwarded: requests,
+                errors: 0,
+            },
+            pool_usage: BTreeMap::new(),
+            method_usage: BTreeMap::new(),
+            unique_users: None,
+            work: Vec::new(),
+            breakers: BTreeMap::new(),
+            peer_latency: BTreeMap::new(),
+            budget: None,
+            reachability: Vec::new(),
+            build: None,
+            method_classes: None,
+            attestation_key: None,
+            sent_at: Timestamp::UNIX_EPOCH,
+        }
+    }
+    
+    #[test]
+    fn buckets_samples_and_groups_them_by_region() {
+        let dashboard = Dashboard::new(DashboardConfig::default());
+        let (a, b) = (NodeId(Uuid::new_v4()), NodeId(Uuid::new_v4()));
+        let start = Timestamp::from_secs(1_000_020);
+        dashboard.record(&heartbeat(&a, "eu-west", 3), start);
+        dashboard.record(&heartbeat(&b, "eu-west", 4), start + Duration::from_secs(30));
+        dashboard.record(&heartbeat(&a, "us-east", 5), start + Duration::from_secs(90));
+        
+        let now = start + Duration::from_secs(100);
+        let buckets = dashboard.timeseries(DashboardMetric::RequestsForwarded, Duration::from_secs(180), now);
+        let values: Vec<(u64, u64)> = buckets.iter().map(|bucket| (bucket.start, bucket.value)).collect();
+        assert_eq!(values, vec![(999_960, 0), (1_000_020, 7), (1_000_080, 5)]);
+        
+        // A node moving region is grouped where it last reported from
+        let overview = dashboard.overview(now);
+        assert_eq!(overview.totals.requests_forwarded, 12);
+        assert_eq!(overview.by_region["eu-west"].nodes, 1);
+        assert_eq!(overview.by_region["eu-west"].counters.requests_forwarded, 4);
+        assert_eq!(overview.by_region["us-east"].counters.requests_forwarded, 8);
+        let regions = dashboard.region_timeseries(Duration::from_secs(180), now);
+        let values = |region: &str| regions[region].iter().map(|bucket| bucket.value).collect::<Vec<_>>();
+        assert_eq!(values("eu-west"), vec![0, 4, 0]);
+        assert_eq!(values("us-east"), vec![0, 3, 5]);
+    }
+    
+    #[test]
+    fn shows_silent_nodes_offline_then_forgets_them() {
+        let config = DashboardConfig::default();
+        let dashboard = Dashboard::new(config.clone());
+        let (a, b) = (NodeId(Uuid::new_v4()), NodeId(Uuid::new_v4()));
+        let start = Timestamp::from_secs(1_000_000);
+        dashboard.record(&heartbeat(&a, "eu-west", 1), start);
+        
+        let later = start + config.stale_after + Duration::from_secs(1);
+        dashboard.record(&heartbeat(&b, "eu-west", 1), later);
+        let overview = dashboard.overview(later);
+        assert_eq!(overview.by_status["Offline"].nodes, 1);
+        assert_eq!(overview.by_status["Online"].nodes, 1);
+        assert_eq!(dashboard.loads(later).keys().collect::<Vec<_>>(), vec![&b]);
+        
+        let much_later = start + config.retention + Duration::from_secs(1);
+        dashboard.record(&heartbeat(&b, "eu-west", 1), much_later);
+        assert_eq!(dashboard.overview(much_later).by_region["eu-west"].nodes, 1);
+    }
+}
@@ -0,0 +1,8 @@
+//! Stateful bookkeeping for nodes, users, providers and the dashboard
+
+pub mod dashboard;
+pub mod nodes;
+pub mod probe;
+pub mod providers;
+pub mod quota;
+pub mod users;
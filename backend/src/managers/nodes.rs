@@ -0,0 +1,143 @@
+//! The node directory, kept in [`crate::storage`]
+
+use crate::*;
+use crate::traits::*;
+use crate::types::*;
+use crate::storage::{Collection, Precondition, Storage};
+
+/// Collection of registered nodes, keyed by node ID
+const NODES: &str = "nodes";
+
+/// A [`NodeManager`] keeping the nodes it knows of in storage
+///
+/// Registering a node again replaces what was known of it. Keys published to replace a
+/// node's current one take over once they activate, on the first read after.
+pub struct StoredNodeManager {
+    nodes: Collection<Node>,
+}
+
+impl StoredNodeManager {
+    /// Create a manager over `storage`
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self {
+            nodes: Collection::new(storage, NODES),
+        }
+    }
+    
+    /// Swap in the keys of `node` that activated by `now`, storing the change
+    async fn promoted(&self, mut node: Node, now: Timestamp) -> Result<Node> {
+        if node.next_key_activates_at.map_or(true, |activates_at| now < activates_at) {
+            return Ok(node);
+        }
+        let key = node.id.0.to_string();
+        let stored = self
+            .nodes
+            .update(&key, |stored| Ok(stored.and_then(|mut stored: Node| stored.promote_next_key(now).then_some(stored))))
+            .await?;
+        node.promote_next_key(now);
+        Ok(stored.unwrap_or(node))
+    }
+}
+
+#[async_trait]
+impl NodeManager for StoredNodeManager {
+    async fn register_node(&self, node: Node) -> Result<()> {
+        self.nodes.put(&node.id.0.to_string(), &node, Precondition::Any).await?;
+        Ok(())
+    }
+    
+    async fn update_node_status(&self, node_id: &NodeId, status: NodeStatus) -> Result<()> {
+        self.nodes
+            .update(&node_id.0.to_string(), |node| {
+                Ok(node.filter(|node| node.status != status).map(|node| Node { status, ..node }))
+            })
+            .await?;
+        Ok(())
+    }
+    
+    async fn get_available_nodes(&self, role: NodeRole) -> Result<Vec<Node>> {
+        let now = Timestamp::now();
+        let mut available = Vec::new();
+        for node in self.nodes.all().await? {
+            if node.has_role(role) && node.status == NodeStatus::Online {
+                available.push(self.promoted(node, now).await?);
+            }
+        }
+        Ok(available)
+    }
+    
+    async fn get_node(&self, node_id: &NodeId) -> Result<Option<Node>> {
+        match self.nodes.get(&node_id.0.to_string()).await? {
+            Some((node, _)) => Ok(Some(self.promoted(node, Timestamp::now()).await?)),
+            None => Ok(None),
+        }
+    }
+    
+    async fn publish_next_key(&self, node_id: &NodeId, next_public_key: CryptoKey, activates_at: Timestamp) -> Result<()> {
+        let updated = self
+            .nodes
+            .update(&node_id.0.to_string(), |node| {
+                Ok(node.map(|node| Node {
+                    next_public_key: Some(next_public_key.clone()),
+                    next_key_activates_at: Some(activates_at),
+                    ..node
+                }))
+            })
+            .await?;
+        match updated {
+            Some(_) => Ok(()),
+            None => anyhow::bail!("Unknown node {}", node_id.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::storage::MemoryStorage;
+    
+    fn manager() -> StoredNodeManager {
+        StoredNodeManager::new(Arc::new(MemoryStorage::new()))
+    }
+    
+    #[tokio::test]
+    async fn only_online_nodes_of_the_role_are_available() {
+        let manager = manager();
+        let exit = fixtures::node(&[NodeRole::Exit]);
+        let routing = fixtures::node(&[NodeRole::Routing]);
+        let offline = fixtures::node(&[NodeRole::Exit]);
+        for node in [&exit, &routing, &offline] {
+            manager.register_node(node.clone()).await.unwrap();
+        }
+        manager.update_node_status(&offline.id, NodeStatus::Offline).await.unwrap();
+        
+        let available = manager.get_available_nodes(NodeRole::Exit).await.unwrap();
+        assert_eq!(available.into_iter().map(|node| node.id).collect::<Vec<_>>(), vec![exit.id]);
+        assert_eq!(manager.get_node(&offline.id).await.unwrap().unwrap().status, NodeStatus::Offline);
+    }
+    
+    #[tokio::test]
+    async fn a_published_key_takes_over_once_it_activates() {
+        let manager = manager();
+        let node = fixtures::node(&[NodeRole::Exit]);
+        manager.register_node(node.clone()).await.unwrap();
+        
+        let later = Timestamp::now() + Duration::from_secs(3600);
+        manager.publish_next_key(&node.id, CryptoKey(vec![8; 32]), later).await.unwrap();
+        let pending = manager.get_node(&node.id).await.unwrap().unwrap();
+        assert_eq!(pending.public_key.0, node.public_key.0);
+        assert_eq!(pending.next_public_key.map(|key| key.0), Some(vec![8; 32]));
+        
+        manager.publish_next_key(&node.id, CryptoKey(vec![9; 32]), Timestamp::UNIX_EPOCH).await.unwrap();
+        let promoted = manager.get_node(&node.id).await.unwrap().unwrap();
+        assert_eq!(promoted.public_key.0, vec![9; 32]);
+        assert!(promoted.next_public_key.is_none());
+    }
+    
+    #[tokio::test]
+    async fn publishing_a_key_for_an_unknown_node_fails() {
+        let node = fixtures::node(&[NodeRole::Exit]);
+        assert!(manager().publish_next_key(&node.id, CryptoKey(vec![8; 32]), Timestamp::now()).await.is_err());
+    }
+}
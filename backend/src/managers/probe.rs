@@ -0,0 +1,521 @@
+//! Scheduled health probing of RPC providers
+
+use crate::*;
+use crate::traits::*;
+use crate::types::*;
+use crate::clock::{Clock, SystemClock};
+use crate::dns::{ProviderResolver, ResolveError, ResolverConfig};
+use crate::events::{Event, EventBus};
+use crate::maintenance::MaintenanceWindow;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::time::Instant;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+/// Timing and concurrency of provider probes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProbeConfig {
+    /// Interval after a failed probe
+    pub unhealthy_interval: Duration,
+    /// Interval a provider backs off to while it keeps passing
+    pub stable_interval: Duration,
+    /// Fraction of the interval randomly added or removed so probes don't fire in lockstep
+    pub jitter: f64,
+    /// Maximum probes in flight across all providers
+    pub max_concurrent: usize,
+    /// Timeout for a single probe
+    pub timeout: Duration,
+    /// Timeout for connecting to a provider, within the probe's
+    pub connect_timeout: Duration,
+    /// Latency above which a passing probe counts the provider as degraded
+    pub degraded_latency: Duration,
+    /// How often the provider list is re-read to pick up registrations and removals
+    pub sync_interval: Duration,
+    /// How the hosts of providers proposed by wallet holders are resolved; unlike those
+    /// operators register, they are held to the egress policy
+    pub submitted_resolver: ResolverConfig,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            unhealthy_interval: Duration::from_secs(5),
+            stable_interval: Duration::from_secs(60),
+            jitter: 0.2,
+            max_concurrent: 8,
+            timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(3),
+            degraded_latency: Duration::from_secs(2),
+            sync_interval: Duration::from_secs(30),
+            submitted_resolver: ResolverConfig::default(),
+        }
+    }
+}
+
+/// The interval before the next probe, given the current interval and the last result
+///
+/// Failures drop straight to the unhealthy interval; each pass doubles the interval
+/// up to the stable interval.
+pub fn next_interval(current: Duration, healthy: bool, config: &ProbeConfig) -> Duration {
+    if healthy {
+        (current * 2).clamp(config.unhealthy_interval, config.stable_interval)
+    } else {
+        config.unhealthy_interval
+    }
+}
+
+/// Spread an interval by up to `jitter` in either direction
+fn jittered(interval: Duration, jitter: f64) -> Duration {
+    let factor = 1.0 + jitter * (rand::random::<f64>() * 2.0 - 1.0);
+    interval.mul_f64(factor.max(0.0))
+}
+
+/// How a provider fared in a probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderHealth {
+    /// Passed in good time
+    Healthy,
+    /// Passed, but slower than the degraded latency
+    Degraded,
+    /// Failed or timed out
+    Down,
+}
+
+impl ProviderHealth {
+    fn of(healthy: bool, latency: Duration, config: &ProbeConfig) -> Self {
+        match healthy {
+            false => ProviderHealth::Down,
+            true if latency > config.degraded_latency => ProviderHealth::Degraded,
+            true => ProviderHealth::Healthy,
+        }
+    }
+}
+
+/// The outcome of probing one provider
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeResult {
+    /// The provider probed
+    pub provider_id: Uuid,
+    /// Where it was probed, less anything in the URL that may hold a key, see [`crate::config::redact_url`]
+    pub url: String,
+    /// How it fared
+    pub health: ProviderHealth,
+    /// How long the probe took
+    pub latency: Duration,
+}
+
+/// How the providers fared in a probe of them all
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProbeSummary {
+    /// Providers that passed in good time
+    pub healthy: usize,
+    /// Providers that passed slowly
+    pub degraded: usize,
+    /// Providers that failed
+    pub down: usize,
+    /// The provider whose probe took longest, if any were probed
+    pub slowest: Option<ProbeResult>,
+    /// Every provider's result
+    pub results: Vec<ProbeResult>,
+}
+
+impl ProbeSummary {
+    fn of(results: Vec<ProbeResult>) -> Self {
+        let count = |health| results.iter().filter(|result| result.health == health).count();
+        Self {
+            healthy: count(ProviderHealth::Healthy),
+            degraded: count(ProviderHealth::Degraded),
+            down: count(ProviderHealth::Down),
+            slowest: results.iter().max_by_key(|result| result.latency).cloned(),
+            results,
+        }
+    }
+}
+
+/// A running probe loop for one provider
+struct ProbeTask {
+    handle: JoinHandle<()>,
+    /// The provider as last read, so probes follow changes to its URL, credentials and
+    /// maintenance windows
+    provider: Arc<parking_lot::RwLock<RpcProvider>>,
+}
+
+/// Probes each provider on its own adaptive schedule
+pub struct ProbeScheduler {
+    rpc_manager: Arc<dyn RpcManager + Send + Sync>,
+    config: ProbeConfig,
+    client: reqwest::Client,
+    resolver: ProviderResolver,
+    submitted_client: reqwest::Client,
+    permits: Arc<Semaphore>,
+    tasks: parking_lot::Mutex<HashMap<Uuid, ProbeTask>>,
+    events: Arc<EventBus>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ProbeScheduler {
+    /// Create a scheduler with no running probes; call `sync` or `run` to start them
+    ///
+    /// Each finished probe is emitted on `events`.
+    pub fn new(rpc_manager: Arc<dyn RpcManager + Send + Sync>, config: ProbeConfig, events: Arc<EventBus>) -> Self {
+        let client = || {
+            reqwest::Client::builder()
+                .connect_timeout(config.connect_timeout.min(config.timeout))
+                .timeout(config.timeout)
+        };
+        let resolver = ProviderResolver::new(config.submitted_resolver.clone());
+        Self {
+            rpc_manager,
+            client: client().build().expect("probe client configuration is valid"),
+            submitted_client: client()
+                .dns_resolver(Arc::new(resolver.clone()))
+                .redirect(resolver.redirect_policy())
+                .build()
+                .expect("probe client configuration is valid"),
+            resolver,
+            permits: Arc::new(Semaphore::new(config.max_concurrent.max(1))),
+            config,
+            tasks: parking_lot::Mutex::new(HashMap::new()),
+            events,
+            clock: Arc::new(SystemClock),
+        }
+    }
+    
+    /// Tell whether a provider is in a maintenance window by `clock`
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    
+    /// How providers are probed
+    pub fn config(&self) -> &ProbeConfig {
+        &self.config
+    }
+    
+    /// Keep probe tasks in step with the registered providers until the task is dropped
+    pub async fn run(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(self.config.sync_interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.sync().await {
+                tracing::warn!("Failed to sync provider probes: {}", e);
+            }
+        }
+    }
+    
+    /// Start tasks for new providers, stop tasks for removed and rejected ones, and pick up
+    /// changes to the others
+    pub async fn sync(&self) -> Result<()> {
+        let mut providers = self.rpc_manager.get_providers().await?;
+        providers.retain(|provider| provider.state != ProviderState::Rejected);
+        let mut tasks = self.tasks.lock();
+        
+        tasks.retain(|id, task| {
+            let keep = providers.iter().any(|p| p.id == *id);
+            if !keep {
+                task.handle.abort();
+            }
+            keep
+        });
+        for provider in providers {
+            match tasks.get(&provider.id) {
+                Some(task) => *task.provider.write() = provider,
+                None => {
+                    tasks.insert(provider.id, self.spawn(provider));
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    /// Replace the maintenance windows a provider's probes are skipped during
+    pub fn set_windows(&self, provider_id: Uuid, windows: Vec<MaintenanceWindow>) {
+        if let Some(task) = self.tasks.lock().get(&provider_id) {
+            task.provider.write().maintenance_windows = windows;
+        }
+    }
+    
+    /// Stop probing a provider
+    pub fn remove(&self, provider_id: Uuid) {
+        if let Some(task) = self.tasks.lock().remove(&provider_id) {
+            task.handle.abort();
+        }
+    }
+    
+    /// Probe every active provider now, at most `max_concurrent` at once, and summarize
+    ///
+    /// Providers in a maintenance window are left out. The results are recorded once every
+    /// probe is in, by a task of its own, so a caller that gives up part way leaves either
+    /// none or all of them recorded.
+    pub async fn probe_all_now(&self) -> Result<ProbeSummary> {
+        self.sync().await?;
+        let now = self.clock.now();
+        let providers: Vec<RpcProvider> = self
+            .rpc_manager
+            .get_active_providers()
+            .await?
+            .into_iter()
+            .filter(|provider| !provider.maintenance_windows.iter().any(|window| window.contains(now)))
+            .collect();
+        
+        let results: Vec<ProbeResult> = futures::stream::iter(providers)
+            .map(|provider| async move {
+                let (healthy, latency) = probe(self.client_for(&provider), &provider, self.config.timeout).await;
+                ProbeResult {
+                    provider_id: provider.id,
+                    url: config::redact_url(&provider.url),
+                    health: ProviderHealth::of(healthy, latency, &self.config),
+                    latency,
+                }
+            })
+            .buffer_unordered(self.config.max_concurrent.max(1))
+            .collect()
+            .await;
+        
+        let rpc_manager = self.rpc_manager.clone();
+        let events = self.events.clone();
+        let recorded = results.clone();
+        tokio::spawn(async move {
+            for result in &recorded {
+                let healthy = result.health != ProviderHealth::Down;
+                if let Err(e) = rpc_manager.record_probe(result.provider_id, healthy, result.latency).await {
+                    tracing::warn!("Failed to record probe for provider {}: {}", result.provider_id, e);
+                }
+                events.emit(Event::ProviderProbed {
+                    provider_id: result.provider_id,
+                    healthy,
+                    latency: result.latency,
+                });
+            }
+            
+            // Every provider probed was active, so those that failed and no longer are were
+            // taken out by this probe
+            let active = match rpc_manager.get_active_providers().await {
+                Ok(active) => active,
+                Err(e) => return tracing::warn!("Failed to list providers after probing them: {}", e),
+            };
+            for result in recorded.iter().filter(|result| result.health == ProviderHealth::Down) {
+                if !active.iter().any(|provider| provider.id == result.provider_id) {
+                    events.emit(Event::ProviderDeactivated {
+                        provider_id: result.provider_id,
+                        reason: "probe_failed",
+                    });
+                }
+            }
+        })
+        .await?;
+        
+        Ok(ProbeSummary::of(results))
+    }
+    
+    /// Check that a proposed provider's URL leads to addresses it may be probed at
+    pub async fn check_submitted(&self, url: &reqwest::Url) -> std::result::Result<(), ResolveError> {
+        self.resolver.check_url(url.as_str())?;
+        let host = url.host_str().unwrap_or_default();
+        match host.trim_start_matches('[').trim_end_matches(']').parse::<std::net::IpAddr>() {
+            Ok(_) => Ok(()),
+            Err(_) => self.resolver.lookup(host).await.map(|_| ()),
+        }
+    }
+    
+    /// The client to probe `provider` with
+    fn client_for(&self, provider: &RpcProvider) -> &reqwest::Client {
+        match provider.submission {
+            Some(_) => &self.submitted_client,
+            None => &self.client,
+        }
+    }
+    
+    /// Number of providers currently being probed
+    pub fn len(&self) -> usize {
+        self.tasks.lock().len()
+    }
+    
+    /// Whether no providers are being probed
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    fn spawn(&self, provider: RpcProvider) -> ProbeTask {
+        let rpc_manager = self.rpc_manager.clone();
+        let client = self.client_for(&provider).clone();
+        let permits = self.permits.clone();
+        let config = self.config.clone();
+        let events = self.events.clone();
+        let clock = self.clock.clone();
+        let provider = Arc::new(parking_lot::RwLock::new(provider));
+        let task_provider = provider.clone();
+        
+        let handle = tokio::spawn(async move {
+            let mut interval = config.unhealthy_interval;
+            let mut failed_probes = 0;
+            loop {
+                tokio::time::sleep(jittered(interval, config.jitter)).await;
+                let provider = task_provider.read().clone();
+                // A provider under maintenance would fail, and keeps the health it went in with
+                let now = clock.now();
+                if provider.maintenance_windows.iter().any(|window| window.contains(now)) {
+                    metrics::increment_counter!("darknode_probes_skipped_total", "reason" => "maintenance");
+                    continue;
+                }
+                
+                let (healthy, latency) = {
+                    let Ok(_permit) = permits.acquire().await else { return };
+                    probe(&client, &provider, config.timeout).await
+                };
+                if let Err(e) = rpc_manager.record_probe(provider.id, healthy, latency).await {
+                    tracing::warn!("Failed to record probe for provider {}: {}", provider.id, e);
+                }
+                events.emit(Event::ProviderProbed {
+                    provider_id: provider.id,
+                    healthy,
+                    latency,
+                });
+                failed_probes = if healthy { 0 } else { failed_probes + 1 };
+                if provider.state == ProviderState::Active && failed_probes == PROBE_FAILURES_TO_DISABLE {
+                    events.emit(Event::ProviderDeactivated {
+                        provider_id: provider.id,
+                        reason: "probe_failed",
+                    });
+                }
+                interval = next_interval(interval, healthy, &config);
+            }
+        });
+        
+        ProbeTask { handle, provider }
+    }
+}
+
+impl Drop for ProbeScheduler {
+    fn drop(&mut self) {
+        for task in self.tasks.get_mut().values() {
+            task.handle.abort();
+        }
+    }
+}
+
+/// Send a cheap health request to a provider, returning whether it passed and how long it took
+async fn probe(client: &reqwest::Client, provider: &RpcProvider, timeout: Duration) -> (bool, Duration) {
+    let method = match provider.provider_type.as_str() {
+        "ethereum" => "eth_blockNumber",
+        _ => "getHealth",
+    };
+    let body = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": method });
+    
+    let started = Instant::now();
+    let mut request = client.post(&provider.url).json(&body).timeout(timeout);
+    if let Some(auth) = &provider.auth {
+        request = request.header(reqwest::header::AUTHORIZATION, auth);
+    }
+    let response = request.send().await;
+    let healthy = match response {
+        Ok(response) if response.status().is_success() => response
+            .json::<serde_json::Value>()
+            .await
+            .map(|body| body.get("error").map_or(true, |e| e.is_null()))
+            .unwrap_or(false),
+        _ => false,
+    };
+    (healthy, started.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managers::providers::StoredRpcManager;
+    use crate::storage::MemoryStorage;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    
+    #[tokio::test]
+    async fn probes_of_all_providers_hide_their_keys_and_report_deactivation_once() {
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        let provider = RpcProvider {
+            url: "http://127.0.0.1:1/v2/secret-key".to_string(),
+            last_checked: Timestamp::UNIX_EPOCH,
+            ..crate::fixtures::provider()
+        };
+        rpc_manager.register_provider(provider).await.unwrap();
+        let events = Arc::new(EventBus::new());
+        let mut emitted = events.subscribe();
+        let probes = ProbeScheduler::new(rpc_manager, ProbeConfig::default(), events);
+        
+        for _ in 0..PROBE_FAILURES_TO_DISABLE {
+            let summary = probes.probe_all_now().await.unwrap();
+            let result = &summary.results[0];
+            assert_eq!(result.health, ProviderHealth::Down);
+            assert!(!result.url.contains("secret-key"), "{}", result.url);
+        }
+        assert!(probes.probe_all_now().await.unwrap().results.is_empty());
+        
+        let mut deactivated = 0;
+        while let Ok(event) = emitted.try_recv() {
+            deactivated += matches!(event, Event::ProviderDeactivated { .. }) as usize;
+        }
+        assert_eq!(deactivated, 1);
+    }
+    
+    /// A provider on loopback that passes probes while `up` is set, and the times it was probed at
+    fn watched(up: Arc<AtomicBool>) -> (RpcProvider, Arc<parking_lot::Mutex<Vec<tokio::time::Instant>>>) {
+        let probed = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let times = probed.clone();
+        let provider = crate::fixtures::serving(move |request: serde_json::Value| {
+            let (up, times) = (up.clone(), times.clone());
+            async move {
+                times.lock().push(tokio::time::Instant::now());
+                match up.load(Ordering::SeqCst) {
+                    true => serde_json::json!({ "jsonrpc": "2.0", "id": request["id"], "result": "ok" }),
+                    false => serde_json::json!({ "jsonrpc": "2.0", "id": request["id"], "error": { "code": -32005, "message": "Node is behind" } }),
+                }
+            }
+        });
+        (provider, probed)
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn probes_back_off_while_a_provider_passes_and_close_in_once_it_fails() {
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        let up = Arc::new(AtomicBool::new(true));
+        let (provider, probed) = watched(up.clone());
+        rpc_manager.register_provider(provider).await.unwrap();
+        let config = ProbeConfig {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let probes = ProbeScheduler::new(rpc_manager, config, Arc::new(EventBus::new()));
+        probes.sync().await.unwrap();
+        
+        // Passing probes at 5s, 15s, 35s, 75s and 135s, then failing ones at 195s, 200s and 205s
+        tokio::time::sleep(Duration::from_secs(140)).await;
+        up.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(67)).await;
+        
+        let probed = probed.lock();
+        let gaps: Vec<u64> = probed.windows(2).map(|pair| (pair[1] - pair[0]).as_secs_f64().round() as u64).collect();
+        assert_eq!(gaps, [10, 20, 40, 60, 60, 5, 5]);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_removed_provider_is_probed_no_more() {
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        let (provider, probed) = watched(Arc::new(AtomicBool::new(true)));
+        let (kept, _) = watched(Arc::new(AtomicBool::new(true)));
+        let removed = provider.id;
+        rpc_manager.register_provider(provider).await.unwrap();
+        rpc_manager.register_provider(kept).await.unwrap();
+        let probes = ProbeScheduler::new(rpc_manager.clone(), ProbeConfig::default(), Arc::new(EventBus::new()));
+        probes.sync().await.unwrap();
+        
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        let before = probed.lock().len();
+        assert!(before > 0);
+        
+        rpc_manager.remove_provider(removed).await.unwrap();
+        probes.sync().await.unwrap();
+        assert_eq!(probes.len(), 1);
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert_eq!(probed.lock().len(), before);
+    }
+}
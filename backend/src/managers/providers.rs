@@ -0,0 +1,210 @@
+//! The RPC providers, kept in [`crate::storage`]
+
+use crate::*;
+use crate::traits::*;
+use crate::types::*;
+use crate::storage::{Collection, Precondition, Storage};
+
+/// Collection of registered providers, keyed by provider ID
+const PROVIDERS: &str = "providers";
+
+/// An [`RpcManager`] keeping the providers it knows of in storage
+///
+/// Probe outcomes move a provider's success rate and latency by a tenth of the way
+/// towards the probe's, failing [`PROBE_FAILURES_TO_DISABLE`] in a row takes an active
+/// provider out of service, and a provider found misbehaving loses a tenth of its rate.
+/// Providers under review are only ever moved along their trial, see [`crate::submissions`].
+pub struct StoredRpcManager {
+    providers: Collection<RpcProvider>,
+}
+
+impl StoredRpcManager {
+    /// Create a manager over `storage`
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self {
+            providers: Collection::new(storage, PROVIDERS),
+        }
+    }
+    
+    /// Apply `change` to the provider `provider_id`, if it is registered
+    async fn change<F>(&self, provider_id: Uuid, mut change: F) -> Result<Option<RpcProvider>>
+    where
+        F: FnMut(&mut RpcProvider) + Send,
+    {
+        self.providers
+            .update(&provider_id.to_string(), |provider| {
+                Ok(provider.map(|mut provider| {
+                    change(&mut provider);
+                    provider
+                }))
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl RpcManager for StoredRpcManager {
+    async fn register_provider(&self, provider: RpcProvider) -> Result<()> {
+        self.providers.put(&provider.id.to_string(), &provider, Precondition::Any).await?;
+        Ok(())
+    }
+    
+    async fn update_provider(&self, provider: RpcProvider) -> Result<()> {
+        let key = provider.id.to_string();
+        match self.providers.get(&key).await? {
+            Some((_, version)) => {
+                self.providers.put(&key, &provider, Precondition::Version(version)).await?;
+                Ok(())
+            }
+            None => anyhow::bail!("Unknown provider {}", provider.id),
+        }
+    }
+    
+    async fn update_provider_status(&self, provider_id: Uuid, active: bool) -> Result<()> {
+        let state = if active { ProviderState::Active } else { ProviderState::Suspended };
+        self.providers
+            .update(&provider_id.to_string(), |provider| match provider {
+                Some(provider) if !provider.state.is_approved() => {
+                    anyhow::bail!("Provider {} hasn't been approved to serve traffic", provider_id)
+                }
+                provider => Ok(provider.map(|provider| RpcProvider { state, ..provider })),
+            })
+            .await?;
+        Ok(())
+    }
+    
+    async fn get_active_providers(&self) -> Result<Vec<RpcProvider>> {
+        Ok(self
+            .providers
+            .all()
+            .await?
+            .into_iter()
+            .filter(|p| p.state == ProviderState::Active)
+            .collect())
+    }
+    
+    async fn get_providers(&self) -> Result<Vec<RpcProvider>> {
+        self.providers.all().await
+    }
+    
+    async fn remove_provider(&self, provider_id: Uuid) -> Result<()> {
+        self.providers.delete(&provider_id.to_string(), Precondition::Any).await?;
+        Ok(())
+    }
+    
+    async fn record_probe(&self, provider_id: Uuid, healthy: bool, latency: Duration) -> Result<()> {
+        let now = Timestamp::now();
+        self.change(provider_id, |provider| {
+            provider.failed_probes = if healthy { 0 } else { provider.failed_probes.saturating_add(1) };
+            provider.state = provider.state.after_probe(healthy, provider.failed_probes);
+            if provider.state == ProviderState::Trial {
+                if let Some(submission) = &mut provider.submission {
+                    submission.record_probe(healthy, now);
+                }
+            }
+            provider.success_rate = scoring::record_outcome(provider.success_rate, healthy);
+            provider.avg_latency = provider.avg_latency.mul_f32(0.9) + latency.mul_f32(0.1);
+            provider.last_checked = now;
+        })
+        .await?;
+        Ok(())
+    }
+    
+    async fn get_best_provider(&self) -> Result<Option<RpcProvider>> {
+        Ok(self
+            .get_active_providers()
+            .await?
+            .into_iter()
+            .max_by(|a, b| a.success_rate.total_cmp(&b.success_rate)))
+    }
+    
+    async fn record_provider_misbehavior(&self, provider_id: Uuid, reason: &str) -> Result<()> {
+        let changed = self
+            .change(provider_id, |provider| {
+                provider.success_rate = scoring::penalize(provider.success_rate);
+            })
+            .await?;
+        if changed.is_some() {
+            tracing::warn!("Provider {} misbehaved: {}", provider_id, reason);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::storage::MemoryStorage;
+    
+    fn manager() -> StoredRpcManager {
+        StoredRpcManager::new(Arc::new(MemoryStorage::new()))
+    }
+    
+    async fn stored(manager: &StoredRpcManager, id: Uuid) -> RpcProvider {
+        manager.get_providers().await.unwrap().into_iter().find(|p| p.id == id).unwrap()
+    }
+    
+    #[tokio::test]
+    async fn an_active_provider_leaves_service_only_after_failing_probes_in_a_row() {
+        let manager = manager();
+        let provider = fixtures::provider();
+        manager.register_provider(provider.clone()).await.unwrap();
+        
+        for _ in 1..PROBE_FAILURES_TO_DISABLE {
+            manager.record_probe(provider.id, false, Duration::from_millis(100)).await.unwrap();
+        }
+        assert_eq!(stored(&manager, provider.id).await.state, ProviderState::Active);
+        manager.record_probe(provider.id, true, Duration::from_millis(100)).await.unwrap();
+        assert_eq!(stored(&manager, provider.id).await.failed_probes, 0);
+        
+        for _ in 0..PROBE_FAILURES_TO_DISABLE {
+            manager.record_probe(provider.id, false, Duration::from_millis(100)).await.unwrap();
+        }
+        assert_eq!(stored(&manager, provider.id).await.state, ProviderState::Disabled);
+        assert!(manager.get_active_providers().await.unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn a_provider_under_review_cant_be_switched_into_service() {
+        let manager = manager();
+        let provider = RpcProvider {
+            state: ProviderState::Pending,
+            ..fixtures::provider()
+        };
+        manager.register_provider(provider.clone()).await.unwrap();
+        
+        assert!(manager.update_provider_status(provider.id, true).await.is_err());
+        assert_eq!(stored(&manager, provider.id).await.state, ProviderState::Pending);
+    }
+    
+    #[tokio::test]
+    async fn the_best_provider_is_the_active_one_with_the_highest_success_rate() {
+        let manager = manager();
+        let reliable = RpcProvider {
+            success_rate: 0.99,
+            ..fixtures::provider()
+        };
+        let flaky = RpcProvider {
+            success_rate: 0.5,
+            ..fixtures::provider()
+        };
+        let suspended = RpcProvider {
+            success_rate: 1.0,
+            state: ProviderState::Suspended,
+            ..fixtures::provider()
+        };
+        for provider in [&reliable, &flaky, &suspended] {
+            manager.register_provider(provider.clone()).await.unwrap();
+        }
+        assert_eq!(manager.get_best_provider().await.unwrap().unwrap().id, reliable.id);
+        
+        manager.record_provider_misbehavior(reliable.id, "forged response").await.unwrap();
+        assert!(stored(&manager, reliable.id).await.success_rate < 0.99);
+    }
+    
+    #[tokio::test]
+    async fn updating_an_unknown_provider_fails() {
+        assert!(manager().update_provider(fixtures::provider()).await.is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Plan quota tracking and enforcement
+
+use crate::*;
+use crate::types::*;
+use std::fmt;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// The plan limit that a request ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaCap {
+    /// The daily request cap
+    DailyRequests,
+    /// The maximum number of concurrent circuits
+    Circuits,
+    /// The maximum number of concurrent subscriptions
+    Subscriptions,
+    /// The maximum number of RPC mappings
+    Mappings,
+}
+
+impl fmt::Display for QuotaCap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaCap::DailyRequests => write!(f, "daily request cap"),
+            QuotaCap::Circuits => write!(f, "concurrent circuit cap"),
+            QuotaCap::Subscriptions => write!(f, "concurrent subscription cap"),
+            QuotaCap::Mappings => write!(f, "mapping cap"),
+        }
+    }
+}
+
+/// Error returned when a request would exceed one of the user's plan limits
+#[derive(Debug, Clone, thiserror::Error, Serialize)]
+#[error("{cap} of {limit} reached for plan '{plan}'")]
+pub struct QuotaExceeded {
+    /// The cap that was hit
+    pub cap: QuotaCap,
+    /// The limit configured on the plan
+    pub limit: u64,
+    /// The name of the plan
+    pub plan: String,
+    /// When the cap resets, if it resets on a schedule rather than when resources are released
+    pub resets_at: Option<Timestamp>,
+}
+
+impl QuotaExceeded {
+    /// The error for a user whose mappings would go past `plan`'s cap
+    pub fn mappings(plan: &Plan) -> Self {
+        Self {
+            cap: QuotaCap::Mappings,
+            limit: plan.max_mappings as u64,
+            plan: plan.name.clone(),
+            resets_at: None,
+        }
+    }
+}
+
+/// Returns the start of the next UTC day after `now`
+pub fn next_utc_midnight(now: Timestamp) -> Timestamp {
+    Timestamp::from_secs((utc_day(now) + 1) * SECONDS_PER_DAY)
+}
+
+fn utc_day(now: Timestamp) -> u64 {
+    now.as_secs() / SECONDS_PER_DAY
+}
+
+/// Node-wide limit on the circuits an entry node holds for all its users together
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitCapacityConfig {
+    /// Most circuits held at once; at the cap the least recently used idle one is evicted
+    pub max_active_circuits: usize,
+    /// How long a circuit must go unused before it counts as idle and may be evicted
+    pub idle_after: Duration,
+}
+
+impl Default for CircuitCapacityConfig {
+    fn default() -> Self {
+        Self {
+            max_active_circuits: 10_000,
+            idle_after: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Error returned when the node holds its maximum of circuits and none is idle enough to evict
+#[derive(Debug, Clone, thiserror::Error, Serialize)]
+#[error("entry node is at its limit of {limit} active circuits")]
+pub struct CircuitCapacityExhausted {
+    /// The node's circuit limit
+    pub limit: usize,
+}
+
+/// Tracks per-user request and subscription usage against plan limits
+#[derive(Default)]
+pub struct UsageTracker {
+    /// Requests made per user on the given UTC day
+    daily_requests: dashmap::DashMap<Uuid, (u64, u64)>,
+    /// Open subscriptions per user
+    subscriptions: dashmap::DashMap<Uuid, u32>,
+}
+
+impl UsageTracker {
+    /// Create a tracker with no recorded usage
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Count a request against the user's daily cap, failing if the cap is already exhausted
+    pub fn record_request(&self, user_id: Uuid, plan: &Plan) -> std::result::Result<(), QuotaExceeded> {
+        let now = Timestamp::now();
+        let today = utc_day(now);
+        let mut entry = self.daily_requests.entry(user_id).or_insert((today, 0));
+        if entry.0 != today {
+            *entry = (today, 0);
+        }
+        if entry.1 >= plan.daily_request_cap {
+            return Err(QuotaExceeded {
+                cap: QuotaCap::DailyRequests,
+                limit: plan.daily_request_cap,
+                plan: plan.name.clone(),
+                resets_at: Some(next_utc_midnight(now)),
+            });
+        }
+        entry.1 += 1;
+        Ok(())
+    }
+    
+    /// Give back a request counted against the user's daily cap today, for a request another cap refused
+    pub fn release_request(&self, user_id: Uuid) {
+        if let Some(mut entry) = self.daily_requests.get_mut(&user_id) {
+            if entry.0 == utc_day(Timestamp::now()) {
+                entry.1 = entry.1.saturating_sub(1);
+            }
+        }
+    }
+    
+    /// Reserve a subscription slot for the user, failing if the plan's cap is reached
+    pub fn acquire_subscription(&self, user_id: Uuid, plan: &Plan) -> std::result::Result<(), QuotaExceeded> {
+        let mut open = self.subscriptions.entry(user_id).or_insert(0);
+        if *open >= plan.max_subscriptions {
+            return Err(QuotaExceeded {
+                cap: QuotaCap::Subscriptions,
+                limit: plan.max_subscriptions as u64,
+                plan: plan.name.clone(),
+                resets_at: None,
+            });
+        }
+        *open += 1;
+        Ok(())
+    }
+    
+    /// Release a subscription slot previously acquired for the user
+    pub fn release_subscription(&self, user_id: Uuid) {
+        if let Some(mut open) = self.subscriptions.get_mut(&user_id) {
+            *open = open.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn plan() -> Plan {
+        Plan {
+            daily_request_cap: 2,
+            max_subscriptions: 1,
+            ..Plan::default()
+        }
+    }
+    
+    #[test]
+    fn the_daily_cap_refuses_requests_past_it_until_one_is_released() {
+        let (tracker, user) = (UsageTracker::new(), Uuid::new_v4());
+        tracker.record_request(user, &plan()).unwrap();
+        tracker.record_request(user, &plan()).unwrap();
+        
+        let exceeded = tracker.record_request(user, &plan()).unwrap_err();
+        assert_eq!(exceeded.cap, QuotaCap::DailyRequests);
+        assert_eq!(exceeded.limit, 2);
+        assert_eq!(exceeded.resets_at, Some(next_utc_midnight(Timestamp::now())));
+        
+        tracker.release_request(user);
+        tracker.record_request(user, &plan()).unwrap();
+        tracker.record_request(Uuid::new_v4(), &plan()).unwrap();
+    }
+    
+    #[test]
+    fn a_released_subscription_slot_can_be_taken_again() {
+        let (tracker, user) = (UsageTracker::new(), Uuid::new_v4());
+        tracker.acquire_subscription(user, &plan()).unwrap();
+        
+        let exceeded = tracker.acquire_subscription(user, &plan()).unwrap_err();
+        assert_eq!(exceeded.cap, QuotaCap::Subscriptions);
+        assert_eq!(exceeded.resets_at, None);
+        
+        tracker.release_subscription(user);
+        tracker.acquire_subscription(user, &plan()).unwrap();
+    }
+    
+    #[test]
+    fn the_next_utc_midnight_is_the_start_of_the_following_day() {
+        assert_eq!(next_utc_midnight(Timestamp::UNIX_EPOCH), Timestamp::from_secs(SECONDS_PER_DAY));
+        assert_eq!(next_utc_midnight(Timestamp::from_secs(SECONDS_PER_DAY - 1)), Timestamp::from_secs(SECONDS_PER_DAY));
+        assert_eq!(next_utc_midnight(Timestamp::from_secs(SECONDS_PER_DAY)), Timestamp::from_secs(2 * SECONDS_PER_DAY));
+    }
+}
@@ -0,0 +1,454 @@
+//! User accounts and plans, kept in [`crate::storage`]
+
+use crate::*;
+use crate::traits::*;
+use crate::types::*;
+use crate::storage::{Collection, Precondition, Storage, VersionConflict, MAX_UPDATE_ATTEMPTS};
+use crate::hex;
+use sha2::{Digest, Sha256};
+use crate::managers::quota::QuotaExceeded;
+use crate::method_routing::{self, MethodRoutes};
+use crate::scopes::{self, Scope};
+use crate::wallets;
+
+/// Collection of users, keyed by user ID
+const USERS: &str = "users";
+/// Index of users by the SHA-256 of each API key, hex-encoded
+const USERS_BY_API_KEY: &str = "users_by_api_key";
+/// Index of users by normalized wallet address, which are unique
+const USERS_BY_WALLET: &str = "users_by_wallet";
+/// Collection of plans, keyed by plan ID
+const PLANS: &str = "plans";
+/// Index of plans by name, which are unique
+const PLANS_BY_NAME: &str = "plans_by_name";
+
+/// A [`UserManager`] keeping users and plans in storage
+///
+/// Users are found by API key and wallet through index collections, written together
+/// with the user so neither is ever there without the other. The API key index holds
+/// hashes of the keys, so a copy of it doesn't give them away; entries written under the
+/// keys themselves by earlier versions are moved to their hashes as they are looked up.
+/// Plan names are claimed in their index first, so two plans created at once under one
+/// name can't both be.
+pub struct StoredUserManager {
+    storage: Arc<dyn Storage + Send + Sync>,
+    users: Collection<User>,
+    by_api_key: Collection<Uuid>,
+    by_wallet: Collection<Uuid>,
+    plans: Collection<Plan>,
+    plans_by_name: Collection<Uuid>,
+}
+
+impl StoredUserManager {
+    /// Create a manager over `storage`
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self {
+            storage: storage.clone(),
+            users: Collection::new(storage.clone(), USERS),
+            by_api_key: Collection::new(storage.clone(), USERS_BY_API_KEY),
+            by_wallet: Collection::new(storage.clone(), USERS_BY_WALLET),
+            plans: Collection::new(storage.clone(), PLANS),
+            plans_by_name: Collection::new(storage, PLANS_BY_NAME),
+        }
+    }
+    
+    /// The user an index entry points to
+    async fn indexed(&self, index: &Collection<Uuid>, key: &str) -> Result<Option<User>> {
+        match index.get(key).await? {
+            Some((user_id, _)) => Ok(self.users.get(&user_id.to_string()).await?.map(|(user, _)| user)),
+            None => Ok(None),
+        }
+    }
+    
+    /// The user holding `api_key`, moving an entry indexed under the key itself to its hash
+    async fn by_api_key(&self, api_key: &str) -> Result<Option<User>> {
+        let hashed = key_hash(api_key);
+        if let Some(user) = self.indexed(&self.by_api_key, &hashed).await? {
+            return Ok(Some(user));
+        }
+        let Some((user_id, version)) = self.by_api_key.get(api_key).await? else {
+            return Ok(None);
+        };
+        self.by_api_key.put(&hashed, &user_id, Precondition::Any).await?;
+        self.by_api_key.delete(api_key, Precondition::Version(version)).await?;
+        Ok(self.users.get(&user_id.to_string()).await?.map(|(user, _)| user))
+    }
+    
+    /// Bring users created before wallet addresses were validated in line with those created
+    /// since, returning how many were changed
+    ///
+    /// Their addresses were stored as given and their chain taken for Solana, so an Ethereum
+    /// user couldn't be found by the checksummed address lookups use, and was counted as a
+    /// Solana one. Each is rewritten with its normalized address and chain and indexed under
+    /// the address; addresses that don't parse are left as they are, and so is a user whose
+    /// address normalizes to another user's. Nodes may run this at once.
+    pub async fn migrate_wallets(&self) -> Result<usize> {
+        let mut migrated = 0;
+        for (key, _) in self.users.scan("").await? {
+            let Some((mut user, version)) = self.users.get(&key).await? else { continue };
+            let Ok(wallet) = wallets::parse(&user.wallet_address) else { continue };
+            if wallet.address == user.wallet_address && wallet.chain == user.wallet_chain {
+                continue;
+            }
+            let legacy = std::mem::replace(&mut user.wallet_address, wallet.address);
+            user.wallet_chain = wallet.chain;
+            let moved = legacy != user.wallet_address;
+            let mut writes = vec![self.users.write(&key, &user, Precondition::Version(version))?];
+            if moved {
+                writes.push(self.by_wallet.write(&user.wallet_address, &user.id, Precondition::Absent)?);
+            }
+            match self.storage.put_all(writes).await {
+                Err(e) if e.is::<VersionConflict>() => {
+                    tracing::warn!("Left the wallet of user {} as it was: {}", user.id, e);
+                    continue;
+                }
+                written => written?,
+            }
+            if moved {
+                self.by_wallet.delete(&legacy, Precondition::Any).await?;
+            }
+            migrated += 1;
+        }
+        Ok(migrated)
+    }
+    
+    /// Apply `change` to the user `user_id`, failing if there is none
+    async fn change<F>(&self, user_id: Uuid, mut change: F) -> Result<()>
+    where
+        F: FnMut(&mut User) + Send,
+    {
+        let changed = self
+            .users
+            .update(&user_id.to_string(), |user| {
+                Ok(user.map(|mut user| {
+                    change(&mut user);
+                    user
+                }))
+            })
+            .await?;
+        match changed {
+            Some(_) => Ok(()),
+            None => anyhow::bail!("Unknown user {}", user_id),
+        }
+    }
+}
+
+#[async_trait]
+impl UserManager for StoredUserManager {
+    async fn create_user(&self, wallet_address: &str) -> Result<User> {
+        let wallet = wallets::parse(wallet_address)?;
+        let user = User {
+            id: Uuid::new_v4(),
+            wallet_address: wallet.address,
+            wallet_chain: wallet.chain,
+            api_key: format!("api-{}", Uuid::new_v4()),
+            keys: Vec::new(),
+            active: true,
+            expires_at: None,
+            rpc_mappings: Vec::new(),
+            plan_id: None,
+            canary: false,
+            audit_consent: false,
+        };
+        let writes = vec![
+            self.by_api_key.write(&key_hash(&user.api_key), &user.id, Precondition::Absent)?,
+            self.by_wallet.write(&user.wallet_address, &user.id, Precondition::Absent)?,
+            self.users.write(&user.id.to_string(), &user, Precondition::Absent)?,
+        ];
+        match self.storage.put_all(writes).await {
+            Err(e) if e.downcast_ref::<VersionConflict>().map_or(false, |conflict| conflict.collection == USERS_BY_WALLET) => {
+                anyhow::bail!("A user with wallet {} already exists", user.wallet_address)
+            }
+            written => written?,
+        }
+        Ok(user)
+    }
+    
+    async fn get_user(&self, user_id: Uuid) -> Result<Option<User>> {
+        Ok(self.users.get(&user_id.to_string()).await?.map(|(user, _)| user))
+    }
+    
+    async fn get_user_by_api_key(&self, api_key: &str) -> Result<Option<User>> {
+        self.by_api_key(api_key).await
+    }
+    
+    async fn get_user_by_wallet(&self, wallet_address: &str) -> Result<Option<User>> {
+        // Users created before addresses were validated are indexed as they were given
+        match wallets::parse(wallet_address) {
+            Ok(wallet) => self.indexed(&self.by_wallet, &wallet.address).await,
+            Err(_) => self.indexed(&self.by_wallet, wallet_address).await,
+        }
+    }
+    
+    async fn add_rpc_mapping(&self, user_id: Uuid, mapping: RpcMapping) -> Result<()> {
+        self.change(user_id, |user| user.rpc_mappings.push(mapping.clone())).await
+    }
+    
+    async fn add_rpc_mappings(&self, user_id: Uuid, mappings: Vec<RpcMapping>, plan: &Plan) -> Result<()> {
+        // Count the mappings held as they are written, should another import land meanwhile
+        let changed = self
+            .users
+            .update(&user_id.to_string(), |user| {
+                let Some(mut user) = user else { return Ok(None) };
+                if user.rpc_mappings.len() + mappings.len() > plan.max_mappings as usize {
+                    return Err(QuotaExceeded::mappings(plan).into());
+                }
+                user.rpc_mappings.extend(mappings.iter().cloned());
+                Ok(Some(user))
+            })
+            .await?;
+        match changed {
+            Some(_) => Ok(()),
+            None => anyhow::bail!("Unknown user {}", user_id),
+        }
+    }
+    
+    async fn get_rpc_mappings(&self, user_id: Uuid) -> Result<Vec<RpcMapping>> {
+        Ok(self
+            .users
+            .get(&user_id.to_string())
+            .await?
+            .map(|(user, _)| user.rpc_mappings)
+            .unwrap_or_default())
+    }
+    
+    async fn remove_rpc_mapping(&self, user_id: Uuid, mapping_id: Uuid) -> Result<()> {
+        // Drop the rules as the mapping is removed, so none is ever left pointing at nothing
+        let changed = self
+            .users
+            .update(&user_id.to_string(), |user| {
+                let Some(mut user) = user else { return Ok(None) };
+                let held = user.rpc_mappings.len();
+                user.rpc_mappings.retain(|mapping| mapping.id != mapping_id);
+                if user.rpc_mappings.len() == held {
+                    anyhow::bail!("Unknown mapping {}", mapping_id);
+                }
+                method_routing::forget(&mut user.rpc_mappings, mapping_id);
+                Ok(Some(user))
+            })
+            .await?;
+        match changed {
+            Some(_) => Ok(()),
+            None => anyhow::bail!("Unknown user {}", user_id),
+        }
+    }
+    
+    async fn set_method_routes(&self, user_id: Uuid, mapping_id: Uuid, routes: MethodRoutes) -> Result<()> {
+        // Check the rules against the user as they are written, should their mappings change meanwhile
+        let changed = self
+            .users
+            .update(&user_id.to_string(), |user| {
+                let Some(mut user) = user else { return Ok(None) };
+                let Some(mapping) = user.rpc_mappings.iter_mut().find(|mapping| mapping.id == mapping_id) else {
+                    anyhow::bail!("Unknown mapping {}", mapping_id);
+                };
+                mapping.routes = routes.clone();
+                method_routing::validate(&user.rpc_mappings)?;
+                Ok(Some(user))
+            })
+            .await?;
+        match changed {
+            Some(_) => Ok(()),
+            None => anyhow::bail!("Unknown user {}", user_id),
+        }
+    }
+    
+    async fn create_plan(&self, plan: Plan) -> Result<()> {
+        match self.plans_by_name.put(&plan.name, &plan.id, Precondition::Absent).await {
+            Err(e) if e.is::<VersionConflict>() => anyhow::bail!("A plan named '{}' already exists", plan.name),
+            claimed => claimed?,
+        };
+        self.plans.put(&plan.id.to_string(), &plan, Precondition::Any).await?;
+        Ok(())
+    }
+    
+    async fn get_plan(&self, plan_id: Uuid) -> Result<Option<Plan>> {
+        Ok(self.plans.get(&plan_id.to_string()).await?.map(|(plan, _)| plan))
+    }
+    
+    async fn set_user_plan(&self, user_id: Uuid, plan_id: Uuid) -> Result<()> {
+        if self.get_plan(plan_id).await?.is_none() {
+            anyhow::bail!("Unknown plan {}", plan_id);
+        }
+        self.change(user_id, |user| user.plan_id = Some(plan_id)).await
+    }
+    
+    async fn set_audit_consent(&self, user_id: Uuid, consent: bool) -> Result<()> {
+        self.change(user_id, |user| user.audit_consent = consent).await
+    }
+    
+    async fn create_key(&self, user_id: Uuid, name: &str, scopes: Vec<Scope>) -> Result<ApiKey> {
+        scopes::validate(&scopes)?;
+        let key = ApiKey {
+            id: Uuid::new_v4(),
+            key: format!("api-{}", Uuid::new_v4()),
+            name: name.to_string(),
+            scopes,
+            created_at: Timestamp::now(),
+        };
+        
+        // The key is indexed together with the user it is added to, reading the user again
+        // should another write get there first
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let (mut user, version) = self
+                .users
+                .get(&user_id.to_string())
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Unknown user {}", user_id))?;
+            user.keys.push(key.clone());
+            let writes = vec![
+                self.by_api_key.write(&key_hash(&key.key), &user_id, Precondition::Absent)?,
+                self.users.write(&user_id.to_string(), &user, Precondition::Version(version))?,
+            ];
+            match self.storage.put_all(writes).await {
+                Err(e) if e.downcast_ref::<VersionConflict>().map_or(false, |conflict| conflict.collection == USERS) => continue,
+                written => written?,
+            }
+            return Ok(key);
+        }
+        anyhow::bail!("Gave up adding a key to user {} after {} conflicting writes", user_id, MAX_UPDATE_ATTEMPTS)
+    }
+    
+    async fn set_key_scopes(&self, user_id: Uuid, key_id: Uuid, scopes: Vec<Scope>) -> Result<ApiKey> {
+        scopes::validate(&scopes)?;
+        let mut scoped = None;
+        self.change(user_id, |user| {
+            if let Some(key) = user.keys.iter_mut().find(|key| key.id == key_id) {
+                key.scopes = scopes.clone();
+                scoped = Some(key.clone());
+            }
+        })
+        .await?;
+        scoped.ok_or_else(|| anyhow::anyhow!("Unknown key {}", key_id))
+    }
+}
+
+/// The API key index entry of `api_key`
+fn key_hash(api_key: &str) -> String {
+    hex::encode(&Sha256::digest(api_key.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    
+    fn manager() -> StoredUserManager {
+        StoredUserManager::new(Arc::new(MemoryStorage::new()))
+    }
+    
+    #[tokio::test]
+    async fn set_user_plan_refuses_unknown_users_and_plans() {
+        let users = manager();
+        let plan = Plan {
+            name: "one circuit".to_string(),
+            max_circuits: 1,
+            ..Plan::default()
+        };
+        users.create_plan(plan.clone()).await.unwrap();
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        
+        assert!(users.set_user_plan(Uuid::new_v4(), plan.id).await.is_err());
+        assert!(users.set_user_plan(user.id, Uuid::new_v4()).await.is_err());
+        users.set_user_plan(user.id, plan.id).await.unwrap();
+        let found = users.get_user_by_api_key(&user.api_key).await.unwrap().unwrap();
+        assert_eq!(found.plan_id, Some(plan.id));
+    }
+    
+    #[tokio::test]
+    async fn a_wallet_gets_one_user_and_a_refused_one_leaves_nothing_behind() {
+        let storage = Arc::new(MemoryStorage::new());
+        let users = StoredUserManager::new(storage.clone());
+        let wallet = "4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T";
+        let first = users.create_user(wallet).await.unwrap();
+        
+        let refused = users.create_user(wallet).await.unwrap_err();
+        assert!(refused.to_string().contains("already exists"), "{}", refused);
+        assert_eq!(storage.scan(USERS, "").await.unwrap().len(), 1);
+        assert_eq!(storage.scan(USERS_BY_API_KEY, "").await.unwrap().len(), 1);
+        assert_eq!(users.get_user_by_wallet(wallet).await.unwrap().unwrap().id, first.id);
+    }
+    
+    #[tokio::test]
+    async fn routes_only_reach_the_users_own_mappings_and_go_with_a_removed_one() {
+        let users = manager();
+        let owner = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let other = users.create_user("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed").await.unwrap();
+        let mapping = |id| RpcMapping {
+            id,
+            ..crate::fixtures::mapping()
+        };
+        let (shared, byo, foreign) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        users.add_rpc_mapping(owner.id, mapping(shared)).await.unwrap();
+        users.add_rpc_mapping(owner.id, mapping(byo)).await.unwrap();
+        users.add_rpc_mapping(other.id, mapping(foreign)).await.unwrap();
+        let routes = |target| MethodRoutes {
+            rules: vec![method_routing::RouteRule {
+                method: "sendTransaction".to_string(),
+                target,
+            }],
+            default_target: None,
+        };
+        
+        // Another user's mapping can't be routed to, and the rules are left as they were
+        let refused = users.set_method_routes(owner.id, shared, routes(foreign)).await.unwrap_err();
+        assert_eq!(refused.downcast_ref(), Some(&method_routing::InvalidRoutes::UnknownTarget(foreign)));
+        assert!(users.get_rpc_mappings(owner.id).await.unwrap().iter().all(|mapping| mapping.routes.is_empty()));
+        
+        users.set_method_routes(owner.id, shared, routes(byo)).await.unwrap();
+        users.remove_rpc_mapping(owner.id, byo).await.unwrap();
+        let held = users.get_rpc_mappings(owner.id).await.unwrap();
+        assert_eq!(held.iter().map(|mapping| mapping.id).collect::<Vec<_>>(), vec![shared]);
+        assert!(held[0].routes.is_empty());
+        assert!(users.remove_rpc_mapping(owner.id, foreign).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn api_keys_are_indexed_by_hash_and_legacy_entries_are_moved() {
+        let storage = Arc::new(MemoryStorage::new());
+        let users = StoredUserManager::new(storage.clone());
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let key = users.create_key(user.id, "ci", Vec::new()).await.unwrap();
+        let indexed: Vec<String> = storage.scan(USERS_BY_API_KEY, "").await.unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(indexed.len(), 2);
+        assert!(!indexed.contains(&user.api_key) && !indexed.contains(&key.key));
+        assert_eq!(users.get_user_by_api_key(&key.key).await.unwrap().unwrap().id, user.id);
+        
+        // An entry an earlier version wrote under the key itself is found, then kept as its hash
+        storage.delete(USERS_BY_API_KEY, &key_hash(&user.api_key), Precondition::Any).await.unwrap();
+        users.by_api_key.put(&user.api_key, &user.id, Precondition::Absent).await.unwrap();
+        assert_eq!(users.get_user_by_api_key(&user.api_key).await.unwrap().unwrap().id, user.id);
+        assert!(storage.get(USERS_BY_API_KEY, &user.api_key).await.unwrap().is_none());
+        assert!(storage.get(USERS_BY_API_KEY, &key_hash(&user.api_key)).await.unwrap().is_some());
+    }
+    
+    #[tokio::test]
+    async fn wallets_stored_before_validation_are_normalized_and_found() {
+        let storage = Arc::new(MemoryStorage::new());
+        let users = StoredUserManager::new(storage.clone());
+        let solana = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        
+        // As an earlier version wrote it: the address as given, and the chain taken for Solana
+        let lowercase = "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed";
+        let legacy: User = serde_json::from_value(serde_json::json!({
+            "id": Uuid::new_v4(),
+            "wallet_address": lowercase,
+            "api_key": "api-legacy",
+            "active": true,
+            "expires_at": null,
+            "rpc_mappings": [],
+        }))
+        .unwrap();
+        users.users.put(&legacy.id.to_string(), &legacy, Precondition::Absent).await.unwrap();
+        users.by_wallet.put(lowercase, &legacy.id, Precondition::Absent).await.unwrap();
+        
+        assert_eq!(users.migrate_wallets().await.unwrap(), 1);
+        assert_eq!(users.migrate_wallets().await.unwrap(), 0);
+        let found = users.get_user_by_wallet("0x5AAEB6053F3E94C9B9A09F33669435E7EF1BEAED").await.unwrap().unwrap();
+        assert_eq!(found.id, legacy.id);
+        assert_eq!(found.wallet_address, "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed");
+        assert_eq!(found.wallet_chain, wallets::WalletChain::Ethereum);
+        assert!(storage.get(USERS_BY_WALLET, lowercase).await.unwrap().is_none());
+        assert_eq!(users.get_user_by_wallet(&solana.wallet_address).await.unwrap().unwrap().id, solana.id);
+    }
+}
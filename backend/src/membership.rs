@@ -0,0 +1,348 @@
+//! Circuit membership checks at the exit node
+//!
+//! An exit node must only serve circuits it was asked to join, otherwise anyone able to
+//! reach it could use it as an open proxy. Each joined circuit's keys are held in a
+//! [`CircuitKeyStore`], ratcheted as the circuit carries traffic, see [`crate::ratchet`];
+//! a request naming an unknown circuit, or one that doesn't decrypt under its circuit's
+//! key, counts as a strike against the previous hop that sent it, and a [`PeerGuard`]
+//! blocks peers that send too many of them. Circuits the node reclaimed, see
+//! [`crate::reclaim`], are remembered for a while so requests arriving late for them don't.
+//!
+//! Subscription circuits, see [`crate::circuit_class`], carry a session's traffic for as
+//! long as its subscriptions last, and aren't held to the per-circuit request limit.
+
+use super::*;
+use super::circuit_class::CircuitClass;
+use super::clock::Deadline;
+use super::events::CircuitEnd;
+use super::ratchet::{CircuitRatchet, RatchetConfig, RatchetDesync, Side};
+use super::reclaim::{ReclaimConfig, Tombstones};
+use super::types::{CircuitId, CryptoKey};
+use tokio::time::Instant;
+
+/// Limits on the traffic an exit node serves per circuit and accepts per peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MembershipConfig {
+    /// Most requests a single interactive circuit may carry over its lifetime
+    pub max_requests_per_circuit: u64,
+    /// Strikes within `strike_window` after which a peer is blocked
+    pub block_threshold: u32,
+    /// Window strikes are counted in
+    pub strike_window: Duration,
+    /// How long a blocked peer stays blocked
+    pub block_duration: Duration,
+    /// When the keys of joined circuits are ratcheted
+    pub ratchet: RatchetConfig,
+}
+
+impl Default for MembershipConfig {
+    fn default() -> Self {
+        Self {
+            max_requests_per_circuit: 100_000,
+            block_threshold: 50,
+            strike_window: Duration::from_secs(60),
+            block_duration: Duration::from_secs(600),
+            ratchet: RatchetConfig::default(),
+        }
+    }
+}
+
+/// A request named a circuit this node is not part of
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("unknown circuit {}", circuit_id.0)]
+pub struct UnknownCircuit {
+    /// The circuit the request named
+    pub circuit_id: CircuitId,
+}
+
+/// A handshake named a circuit this node already holds, or held until it was reclaimed
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("circuit {} is already held", circuit_id.0)]
+pub struct CircuitTaken {
+    /// The circuit the handshake named
+    pub circuit_id: CircuitId,
+}
+
+/// The previous hop is temporarily blocked for sending forged requests
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("peer {peer} is blocked for {}s after repeated forged requests", retry_after.as_secs())]
+pub struct PeerBlocked {
+    /// The blocked peer
+    pub peer: IpAddr,
+    /// How long until the block lifts
+    pub retry_after: Duration,
+}
+
+/// A circuit this node has joined
+struct Membership {
+    keys: CircuitRatchet,
+    version: u16,
+    class: CircuitClass,
+    deadline: Deadline,
+    requests: u64,
+    used_at: Instant,
+}
+
+/// Keys of the circuits this node has joined, forgotten once the circuits expire
+pub struct CircuitKeyStore {
+    circuits: dashmap::DashMap<CircuitId, Membership>,
+    reclaimed: Tombstones,
+    reclaim: ReclaimConfig,
+    max_requests: u64,
+    ratchet: RatchetConfig,
+}
+
+impl CircuitKeyStore {
+    /// Create an empty store
+    pub fn new(config: &MembershipConfig) -> Self {
+        Self {
+            circuits: dashmap::DashMap::new(),
+            reclaimed: Tombstones::new(),
+            reclaim: ReclaimConfig::default(),
+            max_requests: config.max_requests_per_circuit,
+            ratchet: config.ratchet.clone(),
+        }
+    }
+    
+    /// Reclaim circuits as `config` has it, see [`Self::reclaim`]
+    pub fn with_reclaim(mut self, config: ReclaimConfig) -> Self {
+        self.reclaim = config;
+        self
+    }
+    
+    /// Join a circuit of `class` for `ttl`, serving requests encrypted under keys ratcheted from `key` in protocol `version`
+    ///
+    /// A circuit held already, or reclaimed, can't be joined again under another key.
+    pub fn join(&self, circuit_id: CircuitId, key: CryptoKey, version: u16, class: CircuitClass, ttl: Duration) -> Result<(), CircuitTaken> {
+        if self.reclaimed.contains(&circuit_id) {
+            return Err(CircuitTaken { circuit_id });
+        }
+        let dashmap::mapref::entry::Entry::Vacant(vacant) = self.circuits.entry(circuit_id.clone()) else {
+            return Err(CircuitTaken { circuit_id });
+        };
+        vacant.insert(Membership {
+            keys: CircuitRatchet::new(key, Side::Responder, self.ratchet.clone()),
+            version,
+            class,
+            deadline: Deadline::after(ttl),
+            requests: 0,
+            used_at: Instant::now(),
+        });
+        Ok(())
+    }
+    
+    /// Leave a circuit, returning whether this node was part of it
+    pub fn leave(&self, circuit_id: &CircuitId) -> bool {
+        self.circuits.remove(circuit_id).is_some()
+    }
+    
+    /// The key a request at key step `step`, on a circuit this node is part of, decrypts under,
+    /// and the protocol version its plaintext is framed in
+    ///
+    /// Nothing about the circuit changes until the request has decrypted and [`Self::follow`]
+    /// is called, so a request forged at any step neither moves its ratchet nor keeps it from
+    /// being reclaimed. A step outside the ratchet's window means the circuit's ends are out
+    /// of step, or that the request was forged; the circuit is kept either way, for its entry
+    /// node to replace once its requests are refused.
+    pub fn open(&self, circuit_id: &CircuitId, step: u64) -> Result<Option<(CryptoKey, u16)>, RatchetDesync> {
+        if let Some((_, expired)) = self.circuits.remove_if(circuit_id, |_, membership| membership.deadline.is_expired()) {
+            self.reclaimed.bury(circuit_id.clone(), self.reclaim.remember(expired.deadline));
+        }
+        let Some(membership) = self.circuits.get(circuit_id) else {
+            return Ok(None);
+        };
+        let opened = membership.keys.open(step).map(|key| Some((key, membership.version)));
+        if opened.is_err() {
+            metrics::increment_counter!("darknode_ratchet_desyncs_total");
+        }
+        opened
+    }
+    
+    /// Follow a circuit's ratchet to `step`, that of a request that decrypted under the key
+    /// [`Self::open`] gave for it
+    pub fn follow(&self, circuit_id: &CircuitId, step: u64) {
+        if let Some(mut membership) = self.circuits.get_mut(circuit_id) {
+            membership.used_at = Instant::now();
+            membership.keys.follow(step);
+        }
+    }
+    
+    /// The key step to send a response on a circuit this node is part of at, and its key
+    pub fn seal(&self, circuit_id: &CircuitId) -> Option<(u64, CryptoKey)> {
+        self.circuits.get_mut(circuit_id).map(|mut membership| membership.keys.seal())
+    }
+    
+    /// The class of a circuit this node is part of
+    pub fn class(&self, circuit_id: &CircuitId) -> Option<CircuitClass> {
+        self.circuits.get(circuit_id).map(|membership| membership.class)
+    }
+    
+    /// Whether this node is part of a circuit, expired or not
+    pub fn contains(&self, circuit_id: &CircuitId) -> bool {
+        self.circuits.contains_key(circuit_id)
+    }
+    
+    /// Count a request on a circuit, failing once an interactive circuit has carried its limit
+    pub fn count_request(&self, circuit_id: &CircuitId) -> Result<()> {
+        let mut membership = self
+            .circuits
+            .get_mut(circuit_id)
+            .ok_or_else(|| UnknownCircuit { circuit_id: circuit_id.clone() })?;
+        if membership.class == CircuitClass::Interactive && membership.requests >= self.max_requests {
+            anyhow::bail!(
+                "Circuit {} has carried its limit of {} requests",
+                circuit_id.0,
+                self.max_requests
+            );
+        }
+        membership.requests += 1;
+        Ok(())
+    }
+    
+    /// Whether this node reclaimed a circuit recently, so requests for it aren't forged
+    pub fn reclaimed(&self, circuit_id: &CircuitId) -> bool {
+        self.reclaimed.contains(circuit_id)
+    }
+    
+    /// Leave the circuits that expired or were abandoned, returning them and why
+    pub fn reclaim(&self) -> Vec<(CircuitId, CircuitEnd)> {
+        let mut reclaimed = Vec::new();
+        self.circuits.retain(|circuit_id, membership| match self.reclaim.stale(membership.deadline, membership.used_at) {
+            Some(reason) => {
+                self.reclaimed.bury(circuit_id.clone(), self.reclaim.remember(membership.deadline));
+                reclaimed.push((circuit_id.clone(), reason));
+                false
+            }
+            None => true,
+        });
+        self.reclaimed.sweep();
+        reclaimed
+    }
+    
+    /// Leave the circuits that carried no request for `idle_after`, returning them
+    pub fn drop_idle(&self, idle_after: Duration) -> Vec<CircuitId> {
+        let mut dropped = Vec::new();
+        self.circuits.retain(|circuit_id, membership| {
+            let idle = membership.used_at.elapsed() >= idle_after;
+            if idle {
+                dropped.push(circuit_id.clone());
+            }
+            !idle
+        });
+        dropped
+    }
+}
+
+/// Strikes against one peer
+struct Strikes {
+    count: u32,
+    window: Deadline,
+    blocked: Option<Deadline>,
+}
+
+/// Blocks previous hops that keep sending requests for circuits they can't be part of
+pub struct PeerGuard {
+    config: MembershipConfig,
+    peers: dashmap::DashMap<IpAddr, Strikes>,
+}
+
+impl PeerGuard {
+    /// Create a guard with no strikes recorded
+    pub fn new(config: MembershipConfig) -> Self {
+        Self {
+            config,
+            peers: dashmap::DashMap::new(),
+        }
+    }
+    
+    /// Fail if `peer` is currently blocked
+    pub fn check(&self, peer: IpAddr) -> Result<(), PeerBlocked> {
+        match self.peers.get(&peer).and_then(|strikes| strikes.blocked) {
+            Some(until) if !until.is_expired() => Err(PeerBlocked {
+                peer,
+                retry_after: until.remaining(),
+            }),
+            _ => Ok(()),
+        }
+    }
+    
+    /// Record a forged request from `peer`, blocking it once it reaches the threshold
+    ///
+    /// Only the first strike of each window and the block itself are logged, so a flood of
+    /// forged requests can't flood the logs as well.
+    pub fn strike(&self, peer: IpAddr, reason: &dyn std::fmt::Display) {
+        let mut strikes = self.peers.entry(peer).or_insert_with(|| Strikes {
+            count: 0,
+            window: Deadline::after(self.config.strike_window),
+            blocked: None,
+        });
+        if strikes.window.is_expired() {
+            strikes.count = 0;
+            strikes.window = Deadline::after(self.config.strike_window);
+        }
+        strikes.count += 1;
+        metrics::increment_counter!("darknode_forged_requests_total");
+        
+        if strikes.count == 1 {
+            tracing::warn!("Rejected forged request from {}: {}", peer, reason);
+        }
+        if strikes.count == self.config.block_threshold {
+            tracing::warn!(
+                "Blocking {} for {:?} after {} forged requests",
+                peer,
+                self.config.block_duration,
+                strikes.count
+            );
+            metrics::increment_counter!("darknode_peers_blocked_total");
+            strikes.blocked = Some(Deadline::after(self.config.block_duration));
+        }
+    }
+    
+    /// Forget peers whose strikes and blocks have all lapsed
+    pub fn sweep(&self) {
+        self.peers.retain(|_, strikes| {
+            !strikes.window.is_expired() || strikes.blocked.map_or(false, |until| !until.is_expired())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_peer_is_blocked_at_the_threshold_until_the_block_lapses() {
+        let config = MembershipConfig::default();
+        let guard = PeerGuard::new(config.clone());
+        let (forger, other): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        
+        for _ in 1..config.block_threshold {
+            guard.strike(forger, &"unknown circuit");
+        }
+        assert!(guard.check(forger).is_ok());
+        guard.strike(forger, &"unknown circuit");
+        let blocked = guard.check(forger).unwrap_err();
+        assert_eq!((blocked.peer, blocked.retry_after), (forger, config.block_duration));
+        assert!(guard.check(other).is_ok());
+        
+        tokio::time::advance(config.block_duration).await;
+        assert!(guard.check(forger).is_ok());
+        guard.sweep();
+        assert!(guard.peers.is_empty());
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn strikes_in_different_windows_dont_add_up() {
+        let config = MembershipConfig::default();
+        let guard = PeerGuard::new(config.clone());
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        
+        for _ in 1..config.block_threshold {
+            guard.strike(peer, &"unknown circuit");
+        }
+        tokio::time::advance(config.strike_window).await;
+        guard.strike(peer, &"unknown circuit");
+        assert!(guard.check(peer).is_ok());
+    }
+}
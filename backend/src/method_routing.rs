@@ -0,0 +1,252 @@
+//! Splitting one mapping's traffic across a user's mappings by method
+//!
+//! A user may want transactions sent through one of their mappings, say one preferring a
+//! private provider pool, while reads go through another, all behind the one DarkNode URL
+//! their dapp knows. A mapping's [`MethodRoutes`] are an ordered list of rules, each a
+//! method pattern and the mapping its requests go to, and a default target for requests no
+//! rule matches. Patterns are a method name, or a prefix ending in `*`, and `*` matches
+//! every method. With no default target, unmatched requests stay on the mapping itself.
+//!
+//! The entry node applies the rules once a request is sanitized and its method known, and
+//! serves it under the target's settings from there on: its pool, consistency mode,
+//! timeouts and the rest, on circuits built for it. The target's own rules are followed in
+//! turn. Rules are set with `PUT /mappings/:id/routes` and checked against all of the
+//! user's mappings: every target must be one of them, and no chain of rules may lead back
+//! to a mapping it started from, whatever the methods. A rule targeting its own mapping
+//! keeps the methods it matches there. Removing a mapping removes the rules sending
+//! requests to it, see [`forget`].
+
+use super::*;
+use super::types::RpcMapping;
+use std::collections::HashSet;
+
+/// A mapping's rules for sending requests to the user's other mappings
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MethodRoutes {
+    /// Rules in the order they are tried
+    pub rules: Vec<RouteRule>,
+    /// Mapping requests no rule matches go to, if not this one
+    pub default_target: Option<Uuid>,
+}
+
+impl MethodRoutes {
+    /// Whether the rules send every request to the mapping itself
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.default_target.is_none()
+    }
+    
+    /// The mapping a request for `method` goes to, if a rule or the default names one
+    pub fn target(&self, method: &str) -> Option<Uuid> {
+        self.rules
+            .iter()
+            .find(|rule| matches(&rule.method, method))
+            .map(|rule| rule.target)
+            .or(self.default_target)
+    }
+    
+    /// Every mapping the rules may send requests to
+    fn targets(&self) -> impl Iterator<Item = Uuid> + '_ {
+        self.rules.iter().map(|rule| rule.target).chain(self.default_target)
+    }
+}
+
+/// Requests for methods matching a pattern go to another mapping
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRule {
+    /// A method name, or a prefix of names ending in `*`
+    pub method: String,
+    /// The mapping the requests go to
+    pub target: Uuid,
+}
+
+/// Routing rules that can't be set
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidRoutes {
+    /// A pattern is empty, or has a `*` other than at its end
+    #[error("invalid method pattern `{0}`, expected a method name or a prefix ending in `*`")]
+    Pattern(String),
+    /// A rule sends requests to a mapping the user doesn't hold
+    #[error("mapping {0} is not one of the user's mappings")]
+    UnknownTarget(Uuid),
+    /// Rules lead from a mapping back to itself
+    #[error("routing rules loop through mappings {}", path(.0))]
+    Cycle(Vec<Uuid>),
+}
+
+/// Mapping IDs as a path through them
+fn path(mappings: &[Uuid]) -> String {
+    mappings.iter().map(ToString::to_string).collect::<Vec<_>>().join(" -> ")
+}
+
+/// Whether `pattern` matches `method`
+fn matches(pattern: &str, method: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => method.starts_with(prefix),
+        None => pattern == method,
+    }
+}
+
+/// Check the routing rules of all of a user's `mappings`, see the module docs
+pub fn validate(mappings: &[RpcMapping]) -> Result<(), InvalidRoutes> {
+    let held: HashSet<Uuid> = mappings.iter().map(|mapping| mapping.id).collect();
+    for mapping in mappings {
+        for rule in &mapping.routes.rules {
+            let wildcard = rule.method.find('*');
+            if rule.method.is_empty() || wildcard.map_or(false, |at| at + 1 != rule.method.len()) {
+                return Err(InvalidRoutes::Pattern(rule.method.clone()));
+            }
+        }
+        if let Some(unknown) = mapping.routes.targets().find(|target| !held.contains(target)) {
+            return Err(InvalidRoutes::UnknownTarget(unknown));
+        }
+    }
+    
+    // Walk the rules depth first from every mapping, keeping the path walked to report a loop
+    let mut done = HashSet::new();
+    for mapping in mappings {
+        let mut path = Vec::new();
+        visit(mappings, mapping.id, &mut path, &mut done)?;
+    }
+    Ok(())
+}
+
+/// Walk the rules from `mapping_id`, failing if they lead back into `path`
+fn visit(
+    mappings: &[RpcMapping],
+    mapping_id: Uuid,
+    path: &mut Vec<Uuid>,
+    done: &mut HashSet<Uuid>,
+) -> Result<(), InvalidRoutes> {
+    if let Some(start) = path.iter().position(|visited| *visited == mapping_id) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(mapping_id);
+        return Err(InvalidRoutes::Cycle(cycle));
+    }
+    if done.contains(&mapping_id) {
+        return Ok(());
+    }
+    let Some(mapping) = mappings.iter().find(|mapping| mapping.id == mapping_id) else {
+        return Ok(());
+    };
+    path.push(mapping_id);
+    let targets: HashSet<Uuid> = mapping.routes.targets().filter(|target| *target != mapping_id).collect();
+    for target in targets {
+        visit(mappings, target, path, done)?;
+    }
+    path.pop();
+    done.insert(mapping_id);
+    Ok(())
+}
+
+/// Drop the rules of `mappings` sending requests to the mapping `removed`
+///
+/// Requests they matched fall through to the next rule, or the default target; a default
+/// target of `removed` is cleared, so they stay on the mapping itself.
+pub fn forget(mappings: &mut [RpcMapping], removed: Uuid) {
+    for mapping in mappings {
+        mapping.routes.rules.retain(|rule| rule.target != removed);
+        if mapping.routes.default_target == Some(removed) {
+            mapping.routes.default_target = None;
+        }
+    }
+}
+
+/// The mapping a request for `method` sent to `from` is served under, if the rules send it elsewhere
+///
+/// Rules are followed from mapping to mapping; should they loop, as [`validate`] doesn't let
+/// them, the request stays where the loop was found.
+pub fn target<'a>(mappings: &'a [RpcMapping], from: Uuid, method: &str) -> Option<&'a RpcMapping> {
+    let mut current = mappings.iter().find(|mapping| mapping.id == from)?;
+    let mut visited = HashSet::from([from]);
+    while let Some(next) = current.routes.target(method) {
+        if !visited.insert(next) {
+            break;
+        }
+        match mappings.iter().find(|mapping| mapping.id == next) {
+            Some(mapping) => current = mapping,
+            None => break,
+        }
+    }
+    (current.id != from).then_some(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn mapping(pool: Option<&str>, routes: MethodRoutes) -> RpcMapping {
+        RpcMapping {
+            pool: pool.map(str::to_string),
+            routes,
+            ..crate::fixtures::mapping()
+        }
+    }
+    
+    fn rule(method: &str, target: Uuid) -> RouteRule {
+        RouteRule {
+            method: method.to_string(),
+            target,
+        }
+    }
+    
+    #[test]
+    fn sends_writes_to_the_private_mapping_and_keeps_reads_on_the_shared_pool() {
+        let byo = mapping(Some("byo-helius"), MethodRoutes::default());
+        let shared = mapping(
+            None,
+            MethodRoutes {
+                rules: vec![rule("sendTransaction", byo.id), rule("simulate*", byo.id)],
+                default_target: None,
+            },
+        );
+        let mappings = vec![shared.clone(), byo.clone()];
+        validate(&mappings).unwrap();
+        
+        assert_eq!(target(&mappings, shared.id, "sendTransaction").map(|m| m.id), Some(byo.id));
+        assert_eq!(target(&mappings, shared.id, "simulateTransaction").map(|m| m.id), Some(byo.id));
+        assert!(target(&mappings, shared.id, "getBalance").is_none());
+    }
+    
+    #[test]
+    fn refuses_rules_that_loop_or_leave_the_users_mappings() {
+        let mut first = mapping(None, MethodRoutes::default());
+        let mut second = mapping(None, MethodRoutes::default());
+        first.routes.rules.push(rule("send*", second.id));
+        second.routes.default_target = Some(first.id);
+        assert_eq!(
+            validate(&[first.clone(), second.clone()]),
+            Err(InvalidRoutes::Cycle(vec![first.id, second.id, first.id]))
+        );
+        
+        // A rule keeping methods on its own mapping is no loop
+        second.routes.default_target = Some(second.id);
+        validate(&[first.clone(), second.clone()]).unwrap();
+        
+        let elsewhere = Uuid::new_v4();
+        second.routes.rules.push(rule("getBalance", elsewhere));
+        assert_eq!(validate(&[first, second]), Err(InvalidRoutes::UnknownTarget(elsewhere)));
+    }
+    
+    #[test]
+    fn forgetting_a_mapping_drops_the_rules_sending_to_it() {
+        let removed = Uuid::new_v4();
+        let kept = Uuid::new_v4();
+        let mut mappings = vec![mapping(
+            None,
+            MethodRoutes {
+                rules: vec![rule("sendTransaction", removed), rule("get*", kept)],
+                default_target: Some(removed),
+            },
+        )];
+        forget(&mut mappings, removed);
+        assert_eq!(
+            mappings[0].routes,
+            MethodRoutes {
+                rules: vec![rule("get*", kept)],
+                default_target: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,54 @@
+//! Classification of JSON-RPC methods
+
+/// Methods that change chain state and must never be duplicated or retried blindly
+const MUTATING_METHODS: &[&str] = &[
+    // Solana
+    "sendTransaction",
+    "requestAirdrop",
+    // Ethereum
+    "eth_sendRawTransaction",
+    "eth_sendTransaction",
+    "eth_sign",
+    "eth_signTransaction",
+    "personal_sign",
+];
+
+/// Key under which DarkNode metadata travels in JSON-RPC requests and responses
+pub const EXTENSION_KEY: &str = "darknode";
+
+/// Whether a method changes chain state
+pub fn is_mutating(method: &str) -> bool {
+    MUTATING_METHODS.contains(&method)
+}
+
+/// Whether a method opens or closes a subscription, such as Solana's `accountSubscribe`
+/// or Ethereum's `eth_unsubscribe`
+pub fn is_subscription(method: &str) -> bool {
+    ["Subscribe", "Unsubscribe", "_subscribe", "_unsubscribe"]
+        .iter()
+        .any(|suffix| method.ends_with(suffix))
+}
+
+/// Whether a JSON-RPC request is a notification, which has no `id` and gets no response
+///
+/// A request whose `id` is `null` is still a call and is answered.
+pub fn is_notification(request: &serde_json::Value) -> bool {
+    request.is_object() && request.get("id").is_none()
+}
+
+/// The method name of a JSON-RPC request, if it has one
+pub fn method_name(request: &serde_json::Value) -> Option<&str> {
+    request.get("method").and_then(|method| method.as_str())
+}
+
+/// Attach a DarkNode extension field to a JSON-RPC response object
+pub fn set_extension(response: &mut serde_json::Value, name: &str, value: serde_json::Value) {
+    if let Some(object) = response.as_object_mut() {
+        let extension = object
+            .entry(EXTENSION_KEY)
+            .or_insert_with(|| serde_json::json!({}));
+        if let Some(extension) = extension.as_object_mut() {
+            extension.insert(name.to_string(), value);
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! HTTP/2 multiplexing of exit node requests to providers
+//!
+//! Over HTTP/1.1 every request in flight to a provider needs a connection of its own, so
+//! an exit node under load opens many connections to the same provider and runs into its
+//! connection limits. Provider clients instead offer HTTP/2 during the TLS handshake and
+//! multiplex concurrent requests as streams over one connection. Providers that only speak
+//! HTTP/1.1 keep working over it, and the protocol each one settled on is recorded. Plain
+//! `http://` providers can't negotiate, so HTTP/2 is only spoken to them when configured,
+//! and a provider that turns out not to understand it falls back to HTTP/1.1 for good.
+//!
+//! However a provider is spoken to, at most `max_concurrent_streams` requests are in flight
+//! to it at once; more wait for one to finish rather than opening further connections.
+//!
+//! A provider closing an HTTP/2 connection sends GOAWAY, after which requests on it that it
+//! never started are refused. Those are retried on a new connection without the user
+//! noticing. Requests the provider may already have processed are never retried, so a
+//! GOAWAY with an error code fails the requests caught in it as before.
+
+use super::*;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// How requests to providers share connections
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MultiplexConfig {
+    /// Whether providers are offered HTTP/2
+    pub enabled: bool,
+    /// Whether HTTP/2 is spoken to plain `http://` providers without negotiating it
+    pub cleartext_prior_knowledge: bool,
+    /// Most requests in flight to one provider at once
+    pub max_concurrent_streams: usize,
+    /// Interval of the pings keeping idle HTTP/2 connections open
+    pub keep_alive_interval: Duration,
+    /// How many times a request refused by a connection going away is sent again
+    pub goaway_retries: u32,
+}
+
+impl Default for MultiplexConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            cleartext_prior_knowledge: false,
+            max_concurrent_streams: 100,
+            keep_alive_interval: Duration::from_secs(30),
+            goaway_retries: 2,
+        }
+    }
+}
+
+/// The HTTP version a provider is spoken to over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Protocol {
+    /// HTTP/2, multiplexing requests over one connection
+    #[serde(rename = "h2")]
+    Http2,
+    /// HTTP/1.1, one request per connection at a time
+    #[serde(rename = "http/1.1")]
+    Http1,
+}
+
+impl Protocol {
+    /// The protocol a response came over
+    pub fn of(version: reqwest::Version) -> Self {
+        match version {
+            reqwest::Version::HTTP_2 => Protocol::Http2,
+            _ => Protocol::Http1,
+        }
+    }
+    
+    /// Label used in metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            Protocol::Http2 => "h2",
+            Protocol::Http1 => "http/1.1",
+        }
+    }
+}
+
+/// The protocol each provider settled on, and the requests in flight to it
+pub struct ProviderProtocols {
+    config: MultiplexConfig,
+    protocols: dashmap::DashMap<Uuid, Protocol>,
+    fallen_back: dashmap::DashSet<Uuid>,
+    streams: dashmap::DashMap<Uuid, Arc<Semaphore>>,
+}
+
+impl ProviderProtocols {
+    /// Create a tracker that has seen no provider yet
+    pub fn new(config: MultiplexConfig) -> Self {
+        Self {
+            config,
+            protocols: dashmap::DashMap::new(),
+            fallen_back: dashmap::DashSet::new(),
+            streams: dashmap::DashMap::new(),
+        }
+    }
+    
+    /// The configuration requests are multiplexed with
+    pub fn config(&self) -> &MultiplexConfig {
+        &self.config
+    }
+    
+    /// Set up a client for the provider at `url` to speak the protocols it may
+    pub fn configure(&self, builder: reqwest::ClientBuilder, provider_id: Uuid, url: &str) -> reqwest::ClientBuilder {
+        if !self.config.enabled || self.fallen_back.contains(&provider_id) {
+            return builder.http1_only();
+        }
+        let builder = builder
+            .http2_keep_alive_interval(self.config.keep_alive_interval)
+            .http2_keep_alive_while_idle(true)
+            .http2_adaptive_window(true);
+        match self.config.cleartext_prior_knowledge && url.starts_with("http://") {
+            true => builder.http2_prior_knowledge(),
+            false => builder,
+        }
+    }
+    
+    /// Wait until another request may be sent to a provider, holding the permit while it is
+    pub async fn acquire(&self, provider_id: Uuid) -> OwnedSemaphorePermit {
+        let streams = self
+            .streams
+            .entry(provider_id)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.config.max_concurrent_streams.max(1))))
+            .clone();
+        streams.acquire_owned().await.expect("stream semaphore is never closed")
+    }
+    
+    /// Record the HTTP version a provider's response came over
+    pub fn record(&self, provider_id: Uuid, version: reqwest::Version) {
+        let protocol = Protocol::of(version);
+        metrics::increment_counter!("darknode_provider_requests_by_protocol_total", "protocol" => protocol.label());
+        if self.protocols.insert(provider_id, protocol) != Some(protocol) {
+            tracing::debug!("Provider {} speaks {}", provider_id, protocol.label());
+        }
+    }
+    
+    /// Whether a failed request should be sent again over HTTP/1.1
+    ///
+    /// True the first time a request fails to a provider spoken to over HTTP/2 without
+    /// negotiating it, before it answered anything in HTTP/2. The provider's client must
+    /// then be rebuilt, since it is spoken to over HTTP/1.1 from now on.
+    pub fn fall_back(&self, provider_id: Uuid, url: &str, error: &(dyn std::error::Error + 'static)) -> bool {
+        let prior_knowledge = self.config.enabled && self.config.cleartext_prior_knowledge && url.starts_with("http://");
+        if !prior_knowledge || self.protocols.get(&provider_id).map_or(false, |p| *p == Protocol::Http2) {
+            return false;
+        }
+        if h2_error(error).map_or(false, |h2| h2.is_remote()) {
+            return false;
+        }
+        if !self.fallen_back.insert(provider_id) {
+            return false;
+        }
+        tracing::info!("Provider {} doesn't speak HTTP/2, falling back to HTTP/1.1", provider_id);
+        metrics::increment_counter!("darknode_provider_protocol_fallbacks_total");
+        true
+    }
+    
+    /// The protocol each provider reached so far was last spoken to over
+    pub fn snapshot(&self) -> Vec<(Uuid, Protocol)> {
+        self.protocols.iter().map(|entry| (*entry.key(), *entry.value())).collect()
+    }
+    
+    /// Forget providers not in `active`
+    pub fn retain(&self, active: &[Uuid]) {
+        self.protocols.retain(|id, _| active.contains(id));
+        self.fallen_back.retain(|id| active.contains(id));
+        self.streams.retain(|id, _| active.contains(id));
+    }
+}
+
+/// Whether `error` means the provider refused the request unprocessed, so it may be resent
+///
+/// That is the case for a stream reset with `REFUSED_STREAM`, and for requests caught in a
+/// graceful GOAWAY, which only fails the streams the provider never started (RFC 9113
+/// §8.7). A GOAWAY with an error code may also fail streams that were being processed.
+pub fn refused(error: &(dyn std::error::Error + 'static)) -> bool {
+    match h2_error(error) {
+        Some(h2) => match h2.reason() {
+            Some(h2::Reason::REFUSED_STREAM) => true,
+            Some(h2::Reason::NO_ERROR) => h2.is_go_away() && h2.is_remote(),
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+/// The HTTP/2 error behind `error`, if any
+fn h2_error<'a>(error: &'a (dyn std::error::Error + 'static)) -> Option<&'a h2::Error> {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(h2) = error.downcast_ref::<h2::Error>() {
+            return Some(h2);
+        }
+        source = error.source();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exit_node::ExitNodeConfig;
+    use crate::fixtures;
+    use crate::impls::StoredRpcManager;
+    use crate::storage::MemoryStorage;
+    use crate::traits::RpcManager;
+    use crate::types::RpcProvider;
+    use hyper::body::Bytes;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    
+    /// A provider on loopback speaking only HTTP/2, which goes away gracefully after
+    /// answering `per_connection` requests on a connection, and the connections it accepted
+    async fn h2_provider(per_connection: Option<usize>) -> (RpcProvider, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let Ok(mut connection) = h2::server::handshake(socket).await else { return };
+                    let mut served = 0;
+                    while let Some(Ok((request, mut respond))) = connection.accept().await {
+                        tokio::spawn(async move {
+                            let mut body = request.into_body();
+                            while let Some(Ok(chunk)) = body.data().await {
+                                let _ = body.flow_control().release_capacity(chunk.len());
+                            }
+                            let response = hyper::http::Response::builder()
+                                .header("content-type", "application/json")
+                                .body(())
+                                .unwrap();
+                            let Ok(mut stream) = respond.send_response(response, false) else { return };
+                            let answer = Bytes::from_static(br#"{"jsonrpc":"2.0","id":1,"result":42}"#);
+                            let _ = stream.send_data(answer, true);
+                        });
+                        served += 1;
+                        if Some(served) == per_connection {
+                            connection.graceful_shutdown();
+                        }
+                    }
+                });
+            }
+        });
+        let provider = RpcProvider {
+            url,
+            success_rate: 1.0,
+            ..fixtures::provider()
+        };
+        (provider, connections)
+    }
+    
+    async fn exit(provider: RpcProvider) -> Arc<crate::exit_node::ExitNodeService> {
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        rpc_manager.register_provider(provider).await.unwrap();
+        let config = ExitNodeConfig {
+            multiplex: MultiplexConfig {
+                cleartext_prior_knowledge: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        Arc::new(fixtures::exit_with(rpc_manager, config))
+    }
+    
+    async fn concurrently(exit: &Arc<crate::exit_node::ExitNodeService>, requests: usize) -> Vec<serde_json::Value> {
+        let payload = fixtures::payload("getSlot", serde_json::json!([]));
+        futures::future::join_all((0..requests).map(|_| exit.serve(&payload)))
+            .await
+            .into_iter()
+            .map(|response| serde_json::from_slice(&response.unwrap()).unwrap())
+            .collect()
+    }
+    
+    #[tokio::test]
+    async fn concurrent_requests_share_a_connection() {
+        let (provider, connections) = h2_provider(None).await;
+        let exit = exit(provider.clone()).await;
+        
+        let answers = concurrently(&exit, 50).await;
+        assert!(answers.iter().all(|answer| answer["result"] == 42));
+        assert!(connections.load(Ordering::SeqCst) <= 2);
+        assert_eq!(exit.provider_protocols(), vec![(provider.id, Protocol::Http2)]);
+    }
+    
+    #[tokio::test]
+    async fn requests_a_provider_going_away_never_started_are_sent_again() {
+        let (provider, connections) = h2_provider(Some(5)).await;
+        let exit = exit(provider).await;
+        
+        // The provider goes away once the first requests are answered, with those of the
+        // second still arriving on the connection or about to be sent on it
+        let mut answers = concurrently(&exit, 5).await;
+        answers.extend(concurrently(&exit, 15).await);
+        assert!(answers.iter().all(|answer| answer["result"] == 42));
+        assert!(connections.load(Ordering::SeqCst) >= 2);
+    }
+    
+    #[test]
+    fn only_streams_refused_unprocessed_may_be_sent_again() {
+        let refused_stream = h2::Error::from(h2::Reason::REFUSED_STREAM);
+        assert!(refused(&refused_stream));
+        let cancelled = h2::Error::from(h2::Reason::CANCEL);
+        assert!(!refused(&cancelled));
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        assert!(!refused(&io));
+    }
+    
+    #[test]
+    fn a_cleartext_provider_falls_back_to_http1_once_unless_it_spoke_http2() {
+        let protocols = ProviderProtocols::new(MultiplexConfig {
+            cleartext_prior_knowledge: true,
+            ..Default::default()
+        });
+        let failed = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let (provider, speaking) = (Uuid::new_v4(), Uuid::new_v4());
+        
+        assert!(protocols.fall_back(provider, "http://127.0.0.1:8899/", &failed));
+        assert!(!protocols.fall_back(provider, "http://127.0.0.1:8899/", &failed));
+        
+        // TLS providers negotiate, and one that answered over HTTP/2 does speak it
+        assert!(!protocols.fall_back(speaking, "https://rpc.example.com/", &failed));
+        protocols.record(speaking, reqwest::Version::HTTP_2);
+        assert!(!protocols.fall_back(speaking, "http://127.0.0.1:8899/", &failed));
+    }
+}
@@ -0,0 +1,913 @@
+//! Coordinator node implementation
+
+use crate::*;
+use crate::traits::*;
+use crate::types::*;
+
+use crate::accounting::{AccountingConfig, AccountingLedger, EpochAccounts, ReceiptRejected, WorkReceipt};
+use crate::bootstrap::NodeAllowlist;
+use crate::breaker::BreakerBoard;
+use crate::budget::BudgetReport;
+use crate::build_info::BuildInfo;
+use crate::clock::{self, Clock, SystemClock};
+use crate::directory::{DirectoryPublisher, SignedDirectory, Which};
+use crate::directory_watch::{DirectoryChanges, SignedDiff, WatchConfig};
+use crate::epochs::{Epoch, EpochConfig};
+use crate::events::{Event, EventBus, MetricsSubscriber};
+use crate::flags::{Flag, FlagBoard, FlagValue};
+use crate::maintenance::{InvalidWindow, MaintenanceWindow};
+use crate::managers::dashboard::*;
+use crate::managers::probe::{ProbeConfig, ProbeScheduler, ProbeSummary};
+use crate::privacy::{NoiseConfig, NoiseLayer};
+use crate::protocol::VersionReport;
+use crate::reachability::{PartitionWarning, ReachabilityConfig, ReachabilityMatrix};
+use crate::recommend::{self, PathConstraints, Recommendation, RecommendConfig};
+use crate::regions::{LatencyConfig, LatencyMatrix, MeasuredLatency};
+use crate::submissions::{self, ProviderProposal, ReviewDecision, SubmissionConfig, SubmissionRejected};
+use crate::timeouts::MethodClass;
+use crate::wallets;
+use std::collections::BTreeMap;
+use crate::whatif::{self, NetworkSnapshot, Projection, Scenario};
+
+/// The coordinator service
+pub struct CoordinatorService {
+    node_manager: Arc<dyn NodeManager + Send + Sync>,
+    rpc_manager: Arc<dyn RpcManager + Send + Sync>,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    dashboard: Dashboard,
+    probes: Arc<ProbeScheduler>,
+    epochs: EpochConfig,
+    events: Arc<EventBus>,
+    allowlist: Arc<NodeAllowlist>,
+    ledger: AccountingLedger,
+    recommend: RecommendConfig,
+    breakers: BreakerBoard,
+    latency: LatencyMatrix,
+    reachability: ReachabilityMatrix,
+    draining: dashmap::DashSet<NodeId>,
+    budgets: dashmap::DashMap<NodeId, BudgetReport>,
+    builds: dashmap::DashMap<NodeId, BuildInfo>,
+    method_classes: dashmap::DashMap<NodeId, Vec<MethodClass>>,
+    /// Exit nodes' attestation keys, only ever handed out as a set, see [`Self::attestation_keys`]
+    attestation_keys: dashmap::DashMap<NodeId, CryptoKey>,
+    submissions: SubmissionConfig,
+    directory: Option<DirectoryPublisher>,
+    /// What last changed since the directories were published, until they are again
+    unpublished: parking_lot::Mutex<Option<&'static str>>,
+    changes: DirectoryChanges,
+    flags: Option<FlagBoard>,
+    noise: NoiseLayer,
+    clock: Arc<dyn Clock>,
+}
+
+impl CoordinatorService {
+    /// Create a coordinator service
+    pub fn new(
+        node_manager: Arc<dyn NodeManager + Send + Sync>,
+        rpc_manager: Arc<dyn RpcManager + Send + Sync>,
+        crypto: Arc<dyn Crypto + Send + Sync>,
+        dashboard: DashboardConfig,
+        probe: ProbeConfig,
+        epochs: EpochConfig,
+        accounting: AccountingConfig,
+    ) -> Self {
+        let events = Arc::new(EventBus::new());
+        events.register(Arc::new(MetricsSubscriber));
+        Self {
+            node_manager,
+            probes: Arc::new(ProbeScheduler::new(rpc_manager.clone(), probe, events.clone())),
+            rpc_manager,
+            crypto,
+            dashboard: Dashboard::new(dashboard),
+            epochs,
+            events,
+            allowlist: Arc::new(NodeAllowlist::new()),
+            ledger: AccountingLedger::new(accounting),
+            recommend: RecommendConfig::default(),
+            breakers: BreakerBoard::new(),
+            latency: LatencyMatrix::new(LatencyConfig::default()),
+            reachability: ReachabilityMatrix::new(ReachabilityConfig::default()),
+            draining: dashmap::DashSet::new(),
+            budgets: dashmap::DashMap::new(),
+            builds: dashmap::DashMap::new(),
+            method_classes: dashmap::DashMap::new(),
+            attestation_keys: dashmap::DashMap::new(),
+            submissions: SubmissionConfig::default(),
+            directory: None,
+            unpublished: parking_lot::Mutex::new(None),
+            changes: DirectoryChanges::new(WatchConfig::default()),
+            flags: None,
+            noise: NoiseLayer::new(NoiseConfig::default(), rand::random()),
+            clock: Arc::new(SystemClock),
+        }
+    }
+    
+    /// Schedule maintenance and probes by `clock`
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        let probes = ProbeScheduler::new(self.rpc_manager.clone(), self.probes.config().clone(), self.events.clone());
+        self.probes = Arc::new(probes.with_clock(clock.clone()));
+        self.clock = clock;
+        self
+    }
+    
+    /// Noise the statistics published to the public with `noise`, see [`crate::privacy`]
+    pub fn with_noise(mut self, noise: NoiseLayer) -> Self {
+        self.noise = noise;
+        self
+    }
+    
+    /// Recommend paths to entry nodes as `config` has it, see [`crate::recommend`]
+    pub fn with_recommend(mut self, config: RecommendConfig) -> Self {
+        self.recommend = config;
+        self
+    }
+    
+    /// Keep the round trips between regions that nodes report as `config` has it, see [`crate::regions`]
+    pub fn with_latency(mut self, config: LatencyConfig) -> Self {
+        self.latency = LatencyMatrix::new(config);
+        self
+    }
+    
+    /// Read the reachability probes nodes report as `config` has it, see [`crate::reachability`]
+    pub fn with_reachability(mut self, config: ReachabilityConfig) -> Self {
+        self.reachability = ReachabilityMatrix::new(config);
+        self
+    }
+    
+    /// Accept providers proposed by the community as `config` allows, see [`crate::submissions`]
+    pub fn with_submissions(mut self, config: SubmissionConfig) -> Self {
+        self.submissions = config;
+        self
+    }
+    
+    /// Publish signed directories of the network with `publisher`, see [`crate::directory`]
+    pub fn with_directory(mut self, publisher: DirectoryPublisher) -> Self {
+        self.directory = Some(publisher);
+        self
+    }
+    
+    /// Publish the feature flags kept on `board` in directories, see [`crate::flags`]
+    pub fn with_flags(mut self, board: FlagBoard) -> Self {
+        self.flags = Some(board);
+        self
+    }
+    
+    /// Push directory changes to watching nodes as `config` has it, see [`crate::directory_watch`]
+    pub fn with_directory_watch(mut self, config: WatchConfig) -> Self {
+        self.changes = DirectoryChanges::new(config);
+        self
+    }
+    
+    /// The changes to the nodes this coordinator knows, for nodes to watch and catch up on
+    pub fn directory_changes(&self) -> &DirectoryChanges {
+        &self.changes
+    }
+    
+    /// What changed in the directory since version `since`, signed at `now`, see
+    /// [`crate::directory_watch`]
+    ///
+    /// Nodes apply no changes this coordinator doesn't sign, so a coordinator without a
+    /// directory publisher serves none.
+    pub async fn directory_diff(&self, since: u64, now: Timestamp) -> Result<SignedDiff> {
+        let Some(publisher) = &self.directory else {
+            anyhow::bail!("this coordinator does not sign directories");
+        };
+        let diff = self.changes.diff(since, &*self.node_manager).await?;
+        publisher.sign_diff(&diff, now).await
+    }
+    
+    /// The provider probe scheduler, to be driven with `ProbeScheduler::run`
+    pub fn probes(&self) -> Arc<ProbeScheduler> {
+        self.probes.clone()
+    }
+    
+    /// Public keys of the nodes allowed to register, filled by seeding
+    pub fn allowlist(&self) -> Arc<NodeAllowlist> {
+        self.allowlist.clone()
+    }
+    
+    /// The bus this service emits its events on, for registering further subscribers
+    pub fn events(&self) -> Arc<EventBus> {
+        self.events.clone()
+    }
+    
+    /// The epoch the network is in, published to entry nodes in the directory
+    pub fn current_epoch(&self) -> Epoch {
+        Epoch::at(self.epochs.length, self.clock.now())
+    }
+    
+    /// Register a node, if its key is allowed to join
+    ///
+    /// A node presenting a key that isn't on the allowlist is refused and flagged.
+    pub async fn register_node(&self, node: Node) -> Result<bool> {
+        if !self.allowlist.allows(&node.public_key) {
+            self.events.emit(Event::NodeFlagged {
+                node_id: node.id,
+                reason: "unauthorized_key",
+            });
+            return Ok(false);
+        }
+        let node_id = node.id.clone();
+        self.node_manager.register_node(node).await?;
+        self.publish_directory(&node_id, "node_registered");
+        Ok(true)
+    }
+    
+    /// Set a node's status, announcing it going offline or into maintenance
+    ///
+    /// A node set to Maintenance is drained: entry nodes move their circuits off it, see
+    /// [`crate::drain`].
+    pub async fn update_node_status(&self, node_id: &NodeId, status: NodeStatus) -> Result<()> {
+        let previous = self.node_manager.get_node(node_id).await?.map(|node| node.status);
+        self.node_manager.update_node_status(node_id, status).await?;
+        if status == NodeStatus::Offline && previous.map_or(false, |previous| previous != NodeStatus::Offline) {
+            self.events.emit(Event::NodeOffline { node_id: node_id.clone() });
+        }
+        let drained = match status {
+            NodeStatus::Maintenance => self.draining.insert(node_id.clone()),
+            _ => self.draining.remove(node_id).is_some(),
+        };
+        if drained {
+            self.publish_directory(node_id, "node_draining");
+        } else if previous.map_or(false, |previous| previous != status) {
+            self.publish_directory(node_id, "node_status");
+        }
+        Ok(())
+    }
+    
+    /// Nodes in maintenance, for entry nodes to move circuits off
+    pub fn draining_nodes(&self) -> Vec<NodeId> {
+        self.draining.iter().map(|node| node.key().clone()).collect()
+    }
+    
+    /// Activate or deactivate a provider, announcing its deactivation
+    pub async fn update_provider_status(&self, provider_id: Uuid, active: bool) -> Result<()> {
+        self.rpc_manager.update_provider_status(provider_id, active).await?;
+        if !active {
+            self.events.emit(Event::ProviderDeactivated {
+                provider_id,
+                reason: "operator",
+            });
+        }
+        Ok(())
+    }
+    
+    /// Remove a provider and stop probing it
+    pub async fn remove_provider(&self, provider_id: Uuid) -> Result<()> {
+        self.rpc_manager.remove_provider(provider_id).await?;
+        self.probes.remove(provider_id);
+        self.events.emit(Event::ProviderDeactivated {
+            provider_id,
+            reason: "removed",
+        });
+        Ok(())
+    }
+    
+    /// Register a provider proposed by a wallet holder, to be tried before it is reviewed
+    ///
+    /// `signature` is the base58 signature of the proposal by its wallet and when it was
+    /// signed, see [`crate::submissions`]. Fails with [`SubmissionRejected`] if the proposal
+    /// is turned away.
+    pub async fn submit_provider(&self, proposal: ProviderProposal, signature: Option<(&str, u64)>) -> Result<RpcProvider> {
+        if !self.submissions.enabled {
+            return Err(SubmissionRejected::Disabled.into());
+        }
+        wallets::parse(&proposal.wallet_address).map_err(SubmissionRejected::from)?;
+        let now = self.clock.now();
+        submissions::verify(&proposal, signature, &*self.crypto, &self.submissions, now)
+            .await
+            .map_err(SubmissionRejected::from)?;
+        match reqwest::Url::parse(&proposal.url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+                self.probes.check_submitted(&url).await.map_err(SubmissionRejected::from)?;
+            }
+            _ => return Err(SubmissionRejected::InvalidUrl.into()),
+        }
+        
+        // A URL is tried once, and a wallet can only keep so many providers under review
+        let providers = self.rpc_manager.get_providers().await?;
+        if providers.iter().any(|provider| provider.url == proposal.url) {
+            return Err(SubmissionRejected::AlreadyRegistered.into());
+        }
+        let pending = providers
+            .iter()
+            .filter(|provider| provider.state.is_under_review())
+            .filter_map(|provider| provider.submission.as_ref())
+            .filter(|submission| submission.submitted_by == proposal.wallet_address)
+            .count();
+        if pending >= self.submissions.max_pending_per_wallet {
+            return Err(SubmissionRejected::TooManyPending(pending).into());
+        }
+        
+        let provider = submissions::pending_provider(proposal, now);
+        self.rpc_manager.register_provider(provider.clone()).await?;
+        self.probes.sync().await?;
+        metrics::increment_counter!("darknode_provider_submissions_total");
+        Ok(provider)
+    }
+    
+    /// Providers under review, oldest submission first
+    pub async fn provider_submissions(&self) -> Result<Vec<RpcProvider>> {
+        let mut providers: Vec<RpcProvider> = self
+            .rpc_manager
+            .get_providers()
+            .await?
+            .into_iter()
+            .filter(|provider| provider.state.is_under_review())
+            .collect();
+        providers.sort_by_key(|provider| provider.submission.as_ref().map(|submission| submission.submitted_at));
+        Ok(providers)
+    }
+    
+    /// Approve or reject a provider under review, returning it as reviewed, or `None` if
+    /// there is no such provider
+    ///
+    /// Providers can only be approved once their trial is over; rejected ones are no longer
+    /// probed.
+    pub async fn review_provider(&self, provider_id: Uuid, decision: ReviewDecision) -> Result<Option<RpcProvider>> {
+        let providers = self.rpc_manager.get_providers().await?;
+        let Some(mut provider) = providers.into_iter().find(|provider| provider.id == provider_id) else {
+            return Ok(None);
+        };
+        submissions::review(&mut provider, decision, self.submissions.trial_period, self.clock.now())?;
+        self.rpc_manager.update_provider(provider.clone()).await?;
+        if provider.state == ProviderState::Rejected {
+            self.probes.remove(provider_id);
+        }
+        let outcome = if provider.state == ProviderState::Active { "approved" } else { "rejected" };
+        metrics::increment_counter!("darknode_provider_reviews_total", "outcome" => outcome);
+        Ok(Some(provider))
+    }
+    
+    /// A provider's maintenance windows, or `None` if there is no such provider
+    pub async fn maintenance_windows(&self, provider_id: Uuid) -> Result<Option<Vec<MaintenanceWindow>>> {
+        let providers = self.rpc_manager.get_providers().await?;
+        Ok(providers
+            .into_iter()
+            .find(|provider| provider.id == provider_id)
+            .map(|provider| provider.maintenance_windows))
+    }
+    
+    /// Schedule a maintenance window for a provider, returning its windows, or `None` if
+    /// there is no such provider
+    ///
+    /// Windows that are over and don't recur are dropped at the same time. Windows are told
+    /// apart by when they start, so a provider can't have two starting together.
+    pub async fn add_maintenance_window(&self, provider_id: Uuid, window: MaintenanceWindow) -> Result<Option<Vec<MaintenanceWindow>>> {
+        window.validate()?;
+        let providers = self.rpc_manager.get_providers().await?;
+        let Some(mut provider) = providers.into_iter().find(|provider| provider.id == provider_id) else {
+            return Ok(None);
+        };
+        if provider.maintenance_windows.iter().any(|scheduled| scheduled.start == window.start) {
+            return Err(InvalidWindow::Duplicate.into());
+        }
+        let now = self.clock.now();
+        provider.maintenance_windows.retain(|window| window.occurrence(now).is_some());
+        provider.maintenance_windows.push(window);
+        self.set_maintenance_windows(provider).await.map(Some)
+    }
+    
+    /// Cancel the window of a provider starting at `start`, returning its remaining windows,
+    /// or `None` if there is no such provider or window
+    pub async fn remove_maintenance_window(&self, provider_id: Uuid, start: Timestamp) -> Result<Option<Vec<MaintenanceWindow>>> {
+        let providers = self.rpc_manager.get_providers().await?;
+        let Some(mut provider) = providers.into_iter().find(|provider| provider.id == provider_id) else {
+            return Ok(None);
+        };
+        let scheduled = provider.maintenance_windows.len();
+        provider.maintenance_windows.retain(|window| window.start != start);
+        if provider.maintenance_windows.len() == scheduled {
+            return Ok(None);
+        }
+        self.set_maintenance_windows(provider).await.map(Some)
+    }
+    
+    /// Cancel all of a provider's maintenance windows, returning whether there is such a provider
+    pub async fn clear_maintenance_windows(&self, provider_id: Uuid) -> Result<bool> {
+        let providers = self.rpc_manager.get_providers().await?;
+        let Some(mut provider) = providers.into_iter().find(|provider| provider.id == provider_id) else {
+            return Ok(false);
+        };
+        provider.maintenance_windows.clear();
+        self.set_maintenance_windows(provider).await?;
+        Ok(true)
+    }
+    
+    /// Publish a node's next key so peers accept it alongside the current one
+    pub async fn publish_next_key(&self, node_id: &NodeId, next_public_key: CryptoKey, activates_at: Timestamp) -> Result<()> {
+        // Allow for the node's clock running behind ours
+        if activates_at + clock::MAX_CLOCK_SKEW <= self.clock.now() {
+            anyhow::bail!("Next key activation time must be in the future");
+        }
+        self.node_manager
+            .publish_next_key(node_id, next_public_key, activates_at)
+            .await?;
+        self.publish_directory(node_id, "next_key_published");
+        Ok(())
+    }
+    
+    /// Record a heartbeat from a node, `signed` if it was signed by the node it is about
+    ///
+    /// The reachability and method classes a heartbeat reports are only taken from signed
+    /// ones: with report authentication off anyone could claim the edges to a node broken,
+    /// or an exit serving no class at all and so left out of every circuit, see
+    /// [`crate::report_auth`]. Unsigned heartbeats leave the classes last signed for. The
+    /// attestation key an exit reports is likewise only taken from a signed heartbeat, and
+    /// kept once taken so the attestations it signed keep verifying.
+    pub async fn record_heartbeat(&self, heartbeat: &Heartbeat, signed: bool) -> Result<()> {
+        // A node stays in maintenance until it is taken out, whatever it reports
+        if !self.draining.contains(&heartbeat.node_id) {
+            self.update_node_status(&heartbeat.node_id, heartbeat.status).await?;
+        }
+        self.dashboard.record(heartbeat, self.clock.now());
+        for (pool, requests) in &heartbeat.pool_usage {
+            metrics::counter!("darknode_pool_requests_total", *requests, "pool" => pool.clone());
+        }
+        for (method, requests) in &heartbeat.method_usage {
+            metrics::counter!("darknode_method_requests_total", *requests, "method" => method.clone());
+        }
+        self.ledger.record_report(&heartbeat.node_id, &heartbeat.work);
+        self.record_breakers(heartbeat).await?;
+        for (region, rtt) in &heartbeat.peer_latency {
+            self.latency.record(&heartbeat.region, region, *rtt, self.clock.now());
+        }
+        if signed {
+            self.reachability.record(&heartbeat.node_id, &heartbeat.reachability, self.clock.now());
+        }
+        match heartbeat.budget {
+            Some(budget) => {
+                self.budgets.insert(heartbeat.node_id.clone(), budget);
+            }
+            None => {
+                self.budgets.remove(&heartbeat.node_id);
+            }
+        }
+        // Nodes of older releases don't report their build, keep what they registered with
+        if let Some(build) = &heartbeat.build {
+            self.builds.insert(heartbeat.node_id.clone(), build.clone());
+        }
+        if signed {
+            match &heartbeat.method_classes {
+                Some(classes) => {
+                    self.method_classes.insert(heartbeat.node_id.clone(), classes.clone());
+                }
+                None => {
+                    self.method_classes.remove(&heartbeat.node_id);
+                }
+            }
+            if let Some(key) = &heartbeat.attestation_key {
+                self.attestation_keys.insert(heartbeat.node_id.clone(), key.clone());
+            }
+        }
+        Ok(())
+    }
+    
+    /// Keep the breakers a heartbeat reports tripped, if it comes from a registered exit node
+    async fn record_breakers(&self, heartbeat: &Heartbeat) -> Result<()> {
+        if heartbeat.breakers.is_empty() {
+            self.breakers.forget(&heartbeat.node_id);
+            return Ok(());
+        }
+        let node = self.node_manager.get_node(&heartbeat.node_id).await?;
+        match node.filter(|node| node.has_role(NodeRole::Exit)) {
+            Some(_) => self.breakers.record(&heartbeat.node_id, &heartbeat.breakers, std::time::Instant::now()),
+            None => self.breakers.forget(&heartbeat.node_id),
+        }
+        Ok(())
+    }
+    
+    /// Available nodes of `role`, with the build they last reported and exit nodes marked
+    /// with the share of their request budget left and the method classes they serve
+    ///
+    /// A node's reported build also names the protocol versions it speaks, which replace
+    /// the range it registered with, so nodes advertise their versions with every heartbeat.
+    /// See [`crate::build_info`], [`crate::budget`], [`crate::egress`] and [`crate::protocol`].
+    pub async fn available_nodes(&self, role: NodeRole) -> Result<Vec<Node>> {
+        let mut nodes = self.node_manager.get_available_nodes(role).await?;
+        let now = self.clock.now();
+        for node in nodes.iter_mut() {
+            if let Some(build) = self.builds.get(&node.id) {
+                node.protocol = build.protocol;
+                node.build = Some(build.clone());
+            }
+        }
+        for node in nodes.iter_mut().filter(|node| node.has_role(NodeRole::Exit)) {
+            node.budget = self.budgets.get(&node.id).map(|budget| budget.share(now));
+            node.method_classes = self.method_classes.get(&node.id).map(|classes| classes.clone());
+        }
+        Ok(nodes)
+    }
+    
+    /// Every attestation key exit nodes have reported, in key order, see [`crate::attribution`]
+    ///
+    /// The keys are handed out as a set, never with the node each belongs to, so an
+    /// attestation verifying under one of them shows an exit of the network signed it but
+    /// not which exit served the circuit.
+    pub fn attestation_keys(&self) -> Vec<CryptoKey> {
+        let mut keys: Vec<CryptoKey> = self.attestation_keys.iter().map(|entry| entry.value().clone()).collect();
+        keys.sort_by(|a, b| a.0.cmp(&b.0));
+        keys.dedup_by(|a, b| a.0 == b.0);
+        keys
+    }
+    
+    /// Active providers, marked with how many exit nodes report their breaker tripped
+    pub async fn active_providers(&self) -> Result<Vec<RpcProvider>> {
+        let mut providers = self.rpc_manager.get_active_providers().await?;
+        self.breakers.annotate(&mut providers, std::time::Instant::now());
+        Ok(providers)
+    }
+    
+    /// Record an entry node's receipt for the work it sent through downstream nodes
+    pub async fn record_receipt(&self, receipt: &WorkReceipt) -> Result<()> {
+        let recorded = self
+            .ledger
+            .record_receipt(receipt, &*self.node_manager, &*self.crypto)
+            .await;
+        if let Some(ReceiptRejected::BadSignature(issuer)) = recorded.as_ref().err().and_then(|e| e.downcast_ref()) {
+            self.events.emit(Event::NodeFlagged {
+                node_id: NodeId(*issuer),
+                reason: "forged_receipt",
+            });
+        }
+        recorded
+    }
+    
+    /// Credited and reported work of every node during `epoch`
+    pub fn epoch_accounts(&self, epoch: u64) -> EpochAccounts {
+        self.ledger.epoch(epoch)
+    }
+    
+    /// Current network totals for the dashboard
+    pub fn dashboard_overview(&self) -> Overview {
+        self.dashboard.overview(self.clock.now())
+    }
+    
+    /// Nodes most of the nodes probing them can't reach, see [`crate::reachability`]
+    pub fn dashboard_partitions(&self) -> Vec<PartitionWarning> {
+        self.reachability.partitions(self.clock.now())
+    }
+    
+    /// Bucketed series of a dashboard metric over the trailing `window`
+    pub fn dashboard_timeseries(&self, metric: DashboardMetric, window: Duration) -> Vec<Bucket> {
+        self.dashboard.timeseries(metric, window, self.clock.now())
+    }
+    
+    /// Requests forwarded per region over the trailing `window`, as published to the public
+    ///
+    /// Each bucket is noised and suppressed by the coordinator's [`NoiseLayer`]; suppressed
+    /// buckets are left out, and so are regions left without any.
+    pub fn public_region_stats(&self, window: Duration) -> BTreeMap<String, Vec<Bucket>> {
+        self.dashboard
+            .region_timeseries(window, self.clock.now())
+            .into_iter()
+            .map(|(region, buckets)| {
+                let published: Vec<Bucket> = buckets
+                    .into_iter()
+                    .filter_map(|bucket| {
+                        let window = format!("requests/{}/{}", region, bucket.start);
+                        self.noise.publish(bucket.value, &window).map(|value| Bucket { value, ..bucket })
+                    })
+                    .collect();
+                (region, published)
+            })
+            .filter(|(_, published)| !published.is_empty())
+            .collect()
+    }
+    
+    /// Update the network topology
+    pub async fn update_topology(&self) -> Result<()> {
+        // In a real implementation, this would:
+        // 1. Check the status of all nodes
+        // 2. Update the routing tables
+        // 3. Distribute the updated topology to all nodes
+        
+        // For simplicity, we'll just log that we're updating the topology
+        tracing::info!("Updating network topology");
+        
+        Ok(())
+    }
+    
+    /// How the available nodes of every role are spread across protocol versions and releases
+    pub async fn version_report(&self) -> Result<VersionReport> {
+        let mut nodes: Vec<Node> = Vec::new();
+        for role in NodeRole::ALL {
+            for node in self.available_nodes(role).await? {
+                if nodes.iter().all(|known| known.id != node.id) {
+                    nodes.push(node);
+                }
+            }
+        }
+        Ok(VersionReport::of(&nodes))
+    }
+    
+    /// Paths for an entry node's circuits meeting `constraints`, through nodes with load to spare
+    /// and around edges nodes can't cross
+    pub async fn recommend_paths(&self, constraints: &PathConstraints) -> Result<Recommendation> {
+        let routing = self.node_manager.get_available_nodes(NodeRole::Routing).await?;
+        let exits = self.available_nodes(NodeRole::Exit).await?;
+        let mut recommendation = recommend::recommend(
+            &routing,
+            &exits,
+            &self.dashboard.loads(self.clock.now()),
+            &self.reachability.broken(self.clock.now()),
+            constraints,
+            &self.recommend,
+            self.clock.now(),
+        );
+        recommendation.latencies = self.latency.snapshot(self.clock.now());
+        metrics::histogram!("darknode_recommended_paths", recommendation.paths.len() as f64);
+        Ok(recommendation)
+    }
+    
+    /// How the network would carry its traffic under `scenario`, see [`crate::whatif`]
+    ///
+    /// Nodes take traffic up to the load from which they are left out of recommended paths.
+    pub async fn what_if(&self, scenario: &Scenario) -> Result<Projection> {
+        let now = self.clock.now();
+        let loads = self.dashboard.loads(now);
+        let mut routing = self.node_manager.get_available_nodes(NodeRole::Routing).await?;
+        let mut exits = self.available_nodes(NodeRole::Exit).await?;
+        for node in routing.iter_mut().chain(exits.iter_mut()) {
+            if let Some(load) = loads.get(&node.id) {
+                node.load = *load;
+            }
+        }
+        let snapshot = NetworkSnapshot {
+            entries: self.node_manager.get_available_nodes(NodeRole::Entry).await?,
+            routing,
+            exits,
+            rates: self.dashboard.request_rates(whatif::TRAFFIC_WINDOW, now),
+            pool_rates: self.dashboard.pool_rates(whatif::TRAFFIC_WINDOW, now),
+            providers: self.rpc_manager.get_active_providers().await?,
+        };
+        let projection = whatif::project(&snapshot, scenario, self.recommend.max_load);
+        metrics::increment_counter!("darknode_whatif_projections_total");
+        Ok(projection)
+    }
+    
+    /// Round trips between regions measured lately by the nodes
+    pub fn latency_matrix(&self) -> Vec<MeasuredLatency> {
+        self.latency.snapshot(self.clock.now())
+    }
+    
+    /// Store a provider with its changed windows and have its probes follow them at once
+    async fn set_maintenance_windows(&self, provider: RpcProvider) -> Result<Vec<MaintenanceWindow>> {
+        let windows = provider.maintenance_windows.clone();
+        let provider_id = provider.id;
+        self.rpc_manager.update_provider(provider).await?;
+        self.probes.set_windows(provider_id, windows.clone());
+        Ok(windows)
+    }
+    
+    /// The `which` directory at `now`, publishing it if it hasn't been
+    ///
+    /// `None` if this coordinator doesn't publish directories, or for a next directory
+    /// before its lead time.
+    pub async fn directory(&self, which: Which, now: Timestamp) -> Result<Option<SignedDirectory>> {
+        let Some(publisher) = &self.directory else {
+            return Ok(None);
+        };
+        let Some(epoch) = publisher.epoch(which, now) else {
+            return Ok(None);
+        };
+        if let Some(signed) = publisher.published(&epoch) {
+            return Ok(Some(signed));
+        }
+        
+        // Every available node once, whatever roles it serves
+        let mut nodes: Vec<Node> = Vec::new();
+        for role in [NodeRole::Entry, NodeRole::Routing, NodeRole::Exit] {
+            for node in self.node_manager.get_available_nodes(role).await? {
+                if !nodes.iter().any(|known| known.id == node.id) {
+                    nodes.push(node);
+                }
+            }
+        }
+        let flags = self.flags_in_force(now).await?;
+        let signed = publisher.publish(epoch, nodes, flags, now).await?;
+        let reason = self.unpublished.lock().take().unwrap_or("epoch");
+        self.events.emit(Event::DirectoryPublished {
+            epoch: epoch.number,
+            reason,
+        });
+        Ok(Some(signed))
+    }
+    
+    /// The feature flags that haven't expired by `now`, none if this coordinator keeps no flags
+    pub async fn flags_in_force(&self, now: Timestamp) -> Result<BTreeMap<String, Flag>> {
+        match &self.flags {
+            Some(board) => board.in_force(now).await,
+            None => Ok(BTreeMap::new()),
+        }
+    }
+    
+    /// Set feature flag `name` to `value` for `lifetime`, publishing the directory again
+    pub async fn set_flag(&self, name: &str, value: FlagValue, lifetime: Duration) -> Result<Flag> {
+        let Some(board) = &self.flags else {
+            anyhow::bail!("This coordinator keeps no feature flags");
+        };
+        let flag = board.set(name, value, lifetime, self.clock.now()).await?;
+        self.republish("feature_flag");
+        Ok(flag)
+    }
+    
+    /// Clear feature flag `name`, publishing the directory again if it was set
+    pub async fn clear_flag(&self, name: &str) -> Result<bool> {
+        let Some(board) = &self.flags else {
+            return Ok(false);
+        };
+        let cleared = board.clear(name).await?;
+        if cleared {
+            tracing::warn!("Feature flag {} cleared", name);
+            self.republish("feature_flag");
+        }
+        Ok(cleared)
+    }
+    
+    /// Publish the directory again for a change that isn't to any node
+    ///
+    /// The directory is signed again when next asked for, and [`Event::DirectoryPublished`]
+    /// emitted then, with `reason`.
+    fn republish(&self, reason: &'static str) {
+        if let Some(publisher) = &self.directory {
+            publisher.invalidate();
+            *self.unpublished.lock() = Some(reason);
+        }
+    }
+    
+    /// Announce that `node_id` changed in the directory served to nodes, to be published
+    /// again and pushed to the nodes watching it
+    fn publish_directory(&self, node_id: &NodeId, reason: &'static str) {
+        self.republish(reason);
+        self.changes.record(node_id.clone());
+    }
+    
+    /// Probe every active RPC provider now rather than waiting for its next scheduled probe
+    pub async fn check_rpc_health(&self) -> Result<ProbeSummary> {
+        let summary = self.probes.probe_all_now().await?;
+        tracing::info!(
+            "Forced health probe of {} RPC providers: {} healthy, {} degraded, {} down",
+            summary.results.len(),
+            summary.healthy,
+            summary.degraded,
+            summary.down
+        );
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::NodeIdentity;
+    use crate::impls::{CryptoImpl, StoredNodeManager, StoredRpcManager};
+    use crate::storage::memory::MemoryStorage;
+    use std::collections::HashSet;
+    
+    fn service() -> CoordinatorService {
+        let storage = Arc::new(MemoryStorage::new());
+        CoordinatorService::new(
+            Arc::new(StoredNodeManager::new(storage.clone())),
+            Arc::new(StoredRpcManager::new(storage)),
+            Arc::new(CryptoImpl::new()),
+            DashboardConfig::default(),
+            ProbeConfig::default(),
+            EpochConfig::default(),
+            AccountingConfig::default(),
+        )
+    }
+    
+    async fn exit(service: &CoordinatorService) -> NodeId {
+        let identity = NodeIdentity::generate(&CryptoImpl::new(), Duration::from_secs(3600)).await.unwrap();
+        let node = Node {
+            public_key: identity.public_key(Timestamp::now()),
+            port: 3003,
+            ..crate::fixtures::node(&[NodeRole::Exit])
+        };
+        let node_id = node.id.clone();
+        service.node_manager.register_node(node).await.unwrap();
+        node_id
+    }
+    
+    fn heartbeat(node_id: &NodeId, method_classes: Option<Vec<MethodClass>>) -> Heartbeat {
+        Heartbeat {
+            node_id: node_id.clone(),
+            roles: vec![NodeRole::Exit],
+            status: NodeStatus::Online,
+            region: "us-east".to_string(),
+            load: 0.0,
+            counters: NodeCounters::default(),
+            pool_usage: BTreeMap::new(),
+            method_usage: BTreeMap::new(),
+            unique_users: None,
+            work: Vec::new(),
+            breakers: BTreeMap::new(),
+            peer_latency: BTreeMap::new(),
+            budget: None,
+            reachability: Vec::new(),
+            build: None,
+            method_classes,
+            attestation_key: None,
+            sent_at: Timestamp::now(),
+        }
+    }
+    
+    async fn advertised(service: &CoordinatorService, node_id: &NodeId) -> Option<Vec<MethodClass>> {
+        let nodes = service.available_nodes(NodeRole::Exit).await.unwrap();
+        nodes.into_iter().find(|node| &node.id == node_id).unwrap().method_classes
+    }
+    
+    #[tokio::test]
+    async fn method_classes_are_only_taken_from_signed_heartbeats() {
+        let service = service();
+        let node_id = exit(&service).await;
+        let reads = vec![MethodClass::LightRead, MethodClass::Read];
+        
+        // Nobody can claim an exit serves nothing without its key
+        service.record_heartbeat(&heartbeat(&node_id, Some(Vec::new())), false).await.unwrap();
+        assert_eq!(advertised(&service, &node_id).await, None);
+        
+        service.record_heartbeat(&heartbeat(&node_id, Some(reads.clone())), true).await.unwrap();
+        assert_eq!(advertised(&service, &node_id).await, Some(reads.clone()));
+        service.record_heartbeat(&heartbeat(&node_id, Some(Vec::new())), false).await.unwrap();
+        service.record_heartbeat(&heartbeat(&node_id, None), false).await.unwrap();
+        assert_eq!(advertised(&service, &node_id).await, Some(reads));
+    }
+    
+    #[tokio::test]
+    async fn attestation_keys_are_only_taken_from_signed_heartbeats_and_kept() {
+        let service = service();
+        let node_id = exit(&service).await;
+        let attested = |key: u8| Heartbeat {
+            attestation_key: Some(CryptoKey(vec![key; 32])),
+            ..heartbeat(&node_id, None)
+        };
+        
+        service.record_heartbeat(&attested(1), false).await.unwrap();
+        assert!(service.attestation_keys().is_empty());
+        service.record_heartbeat(&attested(2), true).await.unwrap();
+        service.record_heartbeat(&heartbeat(&node_id, None), true).await.unwrap();
+        let keys: Vec<Vec<u8>> = service.attestation_keys().into_iter().map(|key| key.0.clone()).collect();
+        assert_eq!(keys, vec![vec![2; 32]]);
+    }
+    
+    #[tokio::test]
+    async fn a_node_is_listed_with_the_build_it_registered_until_a_heartbeat_reports_another() {
+        let service = service();
+        let registered = BuildInfo {
+            version: "0.0.1".to_string(),
+            git_hash: "0123456789ab".to_string(),
+            ..BuildInfo::current()
+        };
+        let node = Node {
+            build: Some(registered.clone()),
+            ..crate::fixtures::node(&[NodeRole::Exit])
+        };
+        assert!(service.register_node(node.clone()).await.unwrap());
+        let listed = |nodes: Vec<Node>| nodes.into_iter().find(|listed| listed.id == node.id).unwrap().build;
+        assert_eq!(listed(service.available_nodes(NodeRole::Exit).await.unwrap()), Some(registered.clone()));
+        
+        // Heartbeats of older releases carry no build and leave the registered one
+        service.record_heartbeat(&heartbeat(&node.id, None), true).await.unwrap();
+        assert_eq!(listed(service.available_nodes(NodeRole::Exit).await.unwrap()), Some(registered));
+        let upgraded = Heartbeat {
+            build: Some(BuildInfo::current()),
+            ..heartbeat(&node.id, None)
+        };
+        service.record_heartbeat(&upgraded, true).await.unwrap();
+        assert_eq!(listed(service.available_nodes(NodeRole::Exit).await.unwrap()), Some(BuildInfo::current()));
+    }
+    
+    #[tokio::test]
+    async fn recommended_paths_steer_clear_of_an_overloaded_exit() {
+        let service = service();
+        let relay = crate::fixtures::node(&[NodeRole::Routing]);
+        service.node_manager.register_node(relay.clone()).await.unwrap();
+        let (busy, idle) = (exit(&service).await, exit(&service).await);
+        let constraints = PathConstraints {
+            entry_node: None,
+            exit_pool: None,
+            routing_hops: 1,
+        };
+        let exits = |recommendation: Recommendation| -> HashSet<NodeId> {
+            recommendation.paths.into_iter().map(|path| path.exit_node).collect()
+        };
+        
+        // Both exits have headroom until one reports a load past the limit
+        let recommended = exits(service.recommend_paths(&constraints).await.unwrap());
+        assert!(recommended.is_subset(&HashSet::from([busy.clone(), idle.clone()])));
+        let overloaded = Heartbeat {
+            load: 0.95,
+            ..heartbeat(&busy, None)
+        };
+        service.record_heartbeat(&overloaded, true).await.unwrap();
+        service.record_heartbeat(&heartbeat(&idle, None), true).await.unwrap();
+        for _ in 0..8 {
+            let recommendation = service.recommend_paths(&constraints).await.unwrap();
+            assert!(!recommendation.paths.is_empty());
+            assert!(recommendation.paths.iter().all(|path| path.routing_nodes == vec![relay.id.clone()]));
+            assert_eq!(exits(recommendation), HashSet::from([idle.clone()]));
+        }
+    }
+}
@@ -0,0 +1,2684 @@
+//! Entry node implementation
+
+use crate::*;
+use crate::traits::*;
+use crate::types::*;
+use crate::managers::quota::*;
+use crate::accounting::{AccountingConfig, Work, WorkTally};
+use crate::admission::{AdmissionConfig, AdmissionController};
+use crate::billing::UsageMeter;
+use crate::chains::{self, Chain, Network};
+use crate::circuit_class::{CircuitClass, CircuitClassConfig};
+use crate::circuit_info::{CircuitInfo, RotationThrottled, MIN_ROTATION_INTERVAL};
+use crate::expiring::ExpiringMap;
+use crate::clock::{Clock, Deadline, SystemClock};
+use crate::compliance::{AuditStatus, AuditingDisabled, UsageAudit, UsageRecord};
+use crate::context::RequestContext;
+use crate::emulation::{self, EmulationConfig, VersionCache};
+use crate::diagnostics::{CircuitBuildError, CircuitBuildFailure, CircuitBuildReport, CircuitUnavailable, FailureLog};
+use crate::drain::{self, DrainConfig, DrainTracker, InFlight};
+use crate::egress;
+use crate::epochs::{EpochConfig, EpochTracker};
+use crate::fairness::{DispatchSlot, FairQueue, FairnessConfig};
+use crate::events::{ActivitySubscriber, CircuitEnd, Event, EventBus, MetricsSubscriber, RequestOutcome};
+use crate::fallback::{self, DirectProxy, FallbackMode};
+use crate::flags::FeatureFlags;
+use crate::heartbeat::ActivityCounters;
+use crate::identity::NodeIdentity;
+use crate::keepalive::{self, KeepaliveConfig};
+use crate::method_routing;
+use crate::methods;
+use crate::receipts::{self, ReceiptInvalid, ServiceReceipt};
+use crate::relaxation::{ErrorBudget, PolicyChange, RelaxationConfig};
+use crate::replay::{self, HopFailureKind, ReplayConfig};
+use crate::scatter::{AddressScatter, ScatterConfig};
+use crate::schema::{ChainSchema, ValidationConfig};
+use crate::scopes::{self, Grant};
+use crate::shadow::{Shadow, ShadowConfig, ShadowReport};
+use crate::shaping::{ShapingConfig, TrafficShaper};
+use crate::signing::{self, RequestVerifier, SigningConfig};
+use crate::storage::Storage;
+use crate::streamed::StreamedResponse;
+use crate::telemetry;
+use crate::traffic::{self, DailyUniqueUsers};
+use crate::sessions::{Attachment, SessionConfig, SessionExpired, SessionStore, Watch};
+use crate::timeouts::{MethodClass, TimedOut, TimeoutBudget, TimeoutConfig};
+use crate::timing::{self, Phase, Stopwatch};
+use futures::StreamExt;
+use std::collections::HashMap;
+use tracing::Instrument;
+
+/// Number of circuit build failures kept for the debug endpoint
+const CIRCUIT_FAILURE_HISTORY: usize = 100;
+
+/// Number of users whose last rotation is remembered at once
+const MAX_TRACKED_ROTATIONS: usize = 100_000;
+
+/// Methods whose responses are raw account data and can be streamed to the client
+const STREAMABLE_METHODS: &[&str] = &[
+    "getAccountInfo",
+    "getMultipleAccounts",
+    "getProgramAccounts",
+    "getTokenAccountsByOwner",
+];
+
+/// Account data encodings that carry opaque binary data
+const STREAMABLE_ENCODINGS: &[&str] = &["base64", "base64+zstd", "base58"];
+
+/// Whether a response to `method` can be streamed to the client without buffering
+///
+/// Only methods returning raw binary account data qualify; everything else may need
+/// the full response before it can be prepared for the client.
+pub fn is_streamable(method: &str, params: &[serde_json::Value]) -> bool {
+    if !STREAMABLE_METHODS.contains(&method) {
+        return false;
+    }
+    params.iter().any(|param| {
+        param
+            .get("encoding")
+            .and_then(|encoding| encoding.as_str())
+            .map_or(false, |encoding| STREAMABLE_ENCODINGS.contains(&encoding))
+    })
+}
+
+/// Count a canary request for operators, apart from user traffic
+fn record_canary(outcome: RequestOutcome, latency: Duration) {
+    let outcome = match outcome {
+        RequestOutcome::Success => "success",
+        RequestOutcome::Failure => "failure",
+        RequestOutcome::Rejected => "rejected",
+    };
+    metrics::increment_counter!("darknode_canary_requests_total", "outcome" => outcome);
+    metrics::histogram!("darknode_canary_request_duration_seconds", latency.as_secs_f64());
+}
+
+/// How a response served in full answered the request, for the audit trail
+fn answered_status(response: &[u8]) -> AuditStatus {
+    match serde_json::from_slice::<serde_json::Value>(response) {
+        Ok(response) if !response["error"].is_null() => AuditStatus::Error,
+        _ => AuditStatus::Ok,
+    }
+}
+
+/// A circuit held by the entry node on behalf of a user
+#[derive(Debug, Clone)]
+struct ActiveCircuit {
+    /// The user the circuit was built for
+    user_id: Uuid,
+    /// The API key the circuit was built with, which it is rebuilt with
+    api_key: String,
+    /// The circuit itself
+    circuit: Circuit,
+    /// When the circuit expires on this node's clock
+    deadline: Deadline,
+    /// When the circuit last carried a request or answered a ping
+    last_active: tokio::time::Instant,
+    /// Pings in a row the circuit has failed to answer
+    missed_pongs: u32,
+    /// The epoch whose exit subset the circuit was built from, if exits are restricted
+    epoch: Option<u64>,
+    /// Requests the circuit was handed out for
+    requests: u64,
+    /// Limits the requests the circuit carries at once, if its class does
+    concurrency: Option<Arc<tokio::sync::Semaphore>>,
+}
+
+/// A subscription's poller, as the entry node finds it when moving circuits off draining nodes
+struct Poller {
+    /// The circuit the subscription was last served over, if it has been
+    circuit: Option<CircuitId>,
+    /// Wakes the poller to poll on the replacement circuit at once, with when the switch began
+    migrated: tokio::sync::mpsc::UnboundedSender<Timestamp>,
+}
+
+/// What a user's circuits are held under: one per pinned exit pool and circuit class,
+/// whichever of the user's API keys requests come with, and one more for each address
+/// bucket requests are scattered over
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CircuitKey {
+    /// The user the circuit serves
+    user_id: Uuid,
+    /// The exit pool the circuit is pinned to, if any
+    exit_pool: Option<String>,
+    /// The region the circuit's exit node is pinned to, if any
+    exit_region: Option<String>,
+    /// What the circuit carries, see [`crate::circuit_class`]
+    class: CircuitClass,
+    /// The address bucket the circuit serves, if its requests are scattered, see [`crate::scatter`]
+    scatter: Option<u8>,
+}
+
+impl CircuitKey {
+    /// The key of the circuit built for `user_id` to `preferences`
+    fn of(user_id: Uuid, preferences: &CircuitPreferences) -> Self {
+        CircuitKey {
+            user_id,
+            exit_pool: preferences.exit_pool.clone(),
+            exit_region: preferences.exit_region.clone(),
+            class: preferences.class,
+            scatter: preferences.scatter,
+        }
+    }
+    
+    /// The key of the user's usual circuit, which scattered circuits are siblings of
+    fn base(&self) -> CircuitKey {
+        CircuitKey {
+            scatter: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// A circuit being built, counted against the plan's and the node's circuit limits until dropped
+struct Building<'a> {
+    building: &'a dashmap::DashMap<CircuitKey, usize>,
+    key: CircuitKey,
+}
+
+impl<'a> Building<'a> {
+    /// Count a circuit of `key` as being built
+    fn reserve(building: &'a dashmap::DashMap<CircuitKey, usize>, key: CircuitKey) -> Self {
+        *building.entry(key.clone()).or_insert(0) += 1;
+        Self { building, key }
+    }
+}
+
+impl Drop for Building<'_> {
+    fn drop(&mut self) {
+        if let dashmap::mapref::entry::Entry::Occupied(mut building) = self.building.entry(self.key.clone()) {
+            *building.get_mut() -= 1;
+            if *building.get() == 0 {
+                building.remove();
+            }
+        }
+    }
+}
+
+/// A request sent into a circuit, awaiting its response
+struct Dispatched {
+    /// The router's ID for the request
+    request_id: Uuid,
+    /// The request's context, resolved against the user's plan and mapping
+    ctx: RequestContext,
+    /// The method label traffic metrics are recorded under
+    method: &'static str,
+    /// When the request was accepted
+    started: std::time::Instant,
+    /// When each phase of the request ended, for clients asking where its time went
+    stopwatch: Stopwatch,
+    /// The class of the request's method
+    class: MethodClass,
+    /// The request's end-to-end budget
+    limit: Duration,
+    /// When the budget runs out
+    deadline: Deadline,
+    /// The downstream nodes of the circuit carrying the request, credited with its work
+    hops: Vec<NodeId>,
+    /// The circuit carrying the request
+    circuit: CircuitId,
+    /// The request's slot in the network, freed for the next request once it completes
+    slot: DispatchSlot,
+    /// What the request needs to be sent again, until it has been or if it may not be
+    replay: Option<Replay>,
+    /// How the hop failed that the request was sent again for, if it was
+    retried: Option<HopFailureKind>,
+    /// Holds the circuit carrying the request open while it is drained
+    in_flight: InFlight,
+    /// Holds one of the requests the circuit may carry at once, if its class limits them
+    circuit_permit: Option<tokio::sync::OwnedSemaphorePermit>,
+}
+
+/// A request served straight from a fallback provider, no circuit being available, see [`crate::fallback`]
+struct Degraded {
+    /// The request's context, resolved against the user's plan and mapping
+    ctx: RequestContext,
+    /// The method label traffic metrics are recorded under
+    method: &'static str,
+    /// When the request was accepted
+    started: std::time::Instant,
+    /// When the request's budget runs out
+    deadline: Deadline,
+    /// The sanitized request
+    payload: ExitPayload,
+    /// The providers the request is forwarded to
+    proxy: Arc<DirectProxy>,
+}
+
+/// Where a request went once it was accepted
+enum Dispatch {
+    /// Into a circuit
+    Circuit(Dispatched),
+    /// Nowhere yet, to be served straight from a fallback provider
+    Direct(Degraded),
+}
+
+/// What a request needs to be sent again on a rebuilt circuit, see [`crate::replay`]
+struct Replay {
+    /// The user the circuit is rebuilt for
+    user: User,
+    /// The user's plan
+    plan: Plan,
+    /// Preferences the circuit was built with
+    preferences: CircuitPreferences,
+    /// The sanitized request, sealed again with the budget left
+    payload: ExitPayload,
+}
+
+/// A request handed to the router
+struct Sent {
+    /// The router's ID for the request
+    request_id: Uuid,
+    /// The downstream nodes of the circuit carrying the request
+    hops: Vec<NodeId>,
+    /// The circuit carrying the request
+    circuit: CircuitId,
+}
+
+impl Dispatched {
+    /// The error reporting that the request ran out of its budget at this node
+    fn timed_out(&self) -> TimedOut {
+        TimedOut {
+            budget: TimeoutBudget::Edge,
+            class: self.class,
+            limit: self.limit,
+        }
+        .record()
+    }
+    
+    /// Carry on with the request as sent again after a hop failed under it
+    fn resent(&mut self, sent: Sent, reason: HopFailureKind) {
+        self.request_id = sent.request_id;
+        self.hops = sent.hops;
+        self.in_flight.switch(&sent.circuit);
+        self.circuit = sent.circuit;
+        self.retried = Some(reason);
+    }
+}
+
+/// The entry node service
+pub struct EntryNodeService {
+    node_id: NodeId,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    router: Arc<dyn Router + Send + Sync>,
+    sanitizer: Arc<dyn RequestSanitizer + Send + Sync>,
+    user_manager: Arc<dyn UserManager + Send + Sync>,
+    active_circuits: Arc<RwLock<dashmap::DashMap<CircuitKey, ActiveCircuit>>>,
+    capacity: CircuitCapacityConfig,
+    emulation: EmulationConfig,
+    version: VersionCache,
+    timeouts: TimeoutConfig,
+    work: Arc<WorkTally>,
+    usage: Arc<UsageTracker>,
+    counters: Arc<ActivityCounters>,
+    circuit_failures: FailureLog,
+    sessions: Arc<SessionStore>,
+    schemas: HashMap<Chain, ChainSchema>,
+    validation_chain: Chain,
+    keepalive: KeepaliveConfig,
+    unique_users: DailyUniqueUsers,
+    epochs: Arc<EpochTracker>,
+    events: Arc<EventBus>,
+    admission: Arc<AdmissionController>,
+    signatures: RequestVerifier,
+    receipt_key: Arc<NodeIdentity>,
+    shaper: Arc<TrafficShaper>,
+    fair_queue: Arc<FairQueue>,
+    replay: ReplayConfig,
+    shadow: Arc<Shadow>,
+    shadow_sanitizer: Option<Arc<dyn RequestSanitizer + Send + Sync>>,
+    drains: Arc<DrainTracker>,
+    building: dashmap::DashMap<CircuitKey, usize>,
+    usage_audit: Option<Arc<UsageAudit>>,
+    fallback: Option<Arc<DirectProxy>>,
+    error_budget: ErrorBudget,
+    meter: Option<Arc<UsageMeter>>,
+    circuit_classes: CircuitClassConfig,
+    scatter: AddressScatter,
+    /// When each user last had their circuit rotated, to hold them to [`MIN_ROTATION_INTERVAL`]
+    rotations: parking_lot::Mutex<ExpiringMap<Uuid, tokio::time::Instant>>,
+    /// The poller of each session's subscriptions, by session token and subscription ID
+    pollers: dashmap::DashMap<(String, u64), Poller>,
+    clock: Arc<dyn Clock>,
+}
+
+/// How the features of an entry node are configured, each at its default unless set
+#[derive(Debug, Clone, Default)]
+pub struct EntryNodeConfig {
+    /// How long WebSocket sessions survive a disconnect
+    pub sessions: SessionConfig,
+    /// Which chain's param schemas requests are checked against
+    pub validation: ValidationConfig,
+    /// How idle circuits are pinged to detect dead hops
+    pub keepalive: KeepaliveConfig,
+    /// How long epochs last and how many exit nodes a user may reach in one
+    pub epochs: EpochConfig,
+    /// When requests are shed because the network behind the node is overloaded
+    pub admission: AdmissionConfig,
+    /// How many circuits the node holds for all users together
+    pub capacity: CircuitCapacityConfig,
+    /// Whether `getHealth` and `getVersion` are answered without going through a circuit
+    pub emulation: EmulationConfig,
+    /// End-to-end time budgets of requests by method class
+    pub timeouts: TimeoutConfig,
+    /// How work done for the network is counted and reconciled
+    pub accounting: AccountingConfig,
+    /// How requests to mappings requiring wallet signatures are checked
+    pub signing: SigningConfig,
+    /// Whether and how messages sent into circuits are held back
+    pub shaping: ShapingConfig,
+    /// How requests share the network fairly between users once it is busy
+    pub fairness: FairnessConfig,
+    /// When reads are sent again on a rebuilt circuit after a hop failed under them
+    pub replay: ReplayConfig,
+    /// Which reads are mirrored onto shadow circuits to try a new path
+    pub shadow: ShadowConfig,
+    /// How circuits are moved off nodes going into maintenance
+    pub drain: DrainConfig,
+    /// The circuit policy, and how far it is relaxed while circuits fail to build
+    pub relaxation: RelaxationConfig,
+}
+
+impl EntryNodeService {
+    /// Create an entry node service signing client receipts with `receipt_key`
+    pub fn new(
+        node_id: NodeId,
+        crypto: Arc<dyn Crypto + Send + Sync>,
+        router: Arc<dyn Router + Send + Sync>,
+        sanitizer: Arc<dyn RequestSanitizer + Send + Sync>,
+        user_manager: Arc<dyn UserManager + Send + Sync>,
+        receipt_key: Arc<NodeIdentity>,
+        config: EntryNodeConfig,
+    ) -> Self {
+        let EntryNodeConfig {
+            sessions,
+            validation,
+            keepalive,
+            epochs,
+            admission,
+            capacity,
+            emulation,
+            timeouts,
+            accounting,
+            signing,
+            shaping,
+            fairness,
+            replay,
+            shadow,
+            drain,
+            relaxation,
+        } = config;
+        let counters = Arc::new(ActivityCounters::new());
+        let admission = Arc::new(AdmissionController::new(admission));
+        let events = Arc::new(EventBus::new());
+        events.register(Arc::new(MetricsSubscriber));
+        events.register(Arc::new(ActivitySubscriber::new(counters.clone())));
+        events.register(admission.clone());
+        Self {
+            node_id,
+            signatures: RequestVerifier::new(signing, crypto.clone()),
+            crypto,
+            shadow: Arc::new(Shadow::new(shadow, router.clone(), timeouts.clone(), shaping.clone())),
+            shadow_sanitizer: None,
+            router,
+            sanitizer,
+            user_manager,
+            active_circuits: Arc::new(RwLock::new(dashmap::DashMap::new())),
+            capacity,
+            version: VersionCache::new(emulation.version_max_age),
+            emulation,
+            timeouts,
+            work: Arc::new(WorkTally::new(accounting)),
+            usage: Arc::new(UsageTracker::new()),
+            counters,
+            circuit_failures: FailureLog::new(CIRCUIT_FAILURE_HISTORY),
+            sessions: Arc::new(SessionStore::new(sessions)),
+            schemas: match validation.enabled {
+                true => Chain::ALL
+                    .into_iter()
+                    .filter_map(|chain| Some((chain, ChainSchema::for_chain(chain.name())?)))
+                    .collect(),
+                false => HashMap::new(),
+            },
+            validation_chain: validation.chain,
+            keepalive,
+            unique_users: DailyUniqueUsers::new(),
+            epochs: Arc::new(EpochTracker::new(epochs)),
+            events,
+            admission,
+            receipt_key,
+            shaper: Arc::new(TrafficShaper::new(shaping)),
+            fair_queue: Arc::new(FairQueue::new(fairness)),
+            replay,
+            drains: Arc::new(DrainTracker::new(drain)),
+            building: dashmap::DashMap::new(),
+            usage_audit: None,
+            fallback: None,
+            error_budget: ErrorBudget::new(relaxation),
+            meter: None,
+            circuit_classes: CircuitClassConfig::default(),
+            scatter: AddressScatter::new(ScatterConfig::default()),
+            rotations: parking_lot::Mutex::new(ExpiringMap::new(MIN_ROTATION_INTERVAL, MAX_TRACKED_ROTATIONS)),
+            pollers: dashmap::DashMap::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+    
+    /// Stamp circuits, usage and receipts with the time on `clock` rather than the system's
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    
+    /// Release messages into circuits on the shaping ticks, until the task is dropped
+    pub async fn run_shaping(self: Arc<Self>) {
+        self.shaper.clone().run().await;
+    }
+    
+    /// Rotate and keep alive the circuits of each class as `config` has it, see [`crate::circuit_class`]
+    pub fn with_circuit_classes(mut self, config: CircuitClassConfig) -> Self {
+        self.circuit_classes = config;
+        self
+    }
+    
+    /// Send messages without shaping while `flags` turn it off, see [`crate::flags`]
+    ///
+    /// Rebuilds the shaper, so call it before [`Self::run_shaping`].
+    pub fn with_flags(mut self, flags: FeatureFlags) -> Self {
+        self.shaper = Arc::new(TrafficShaper::new(self.shaper.config().clone()).with_flags(flags));
+        self
+    }
+    
+    /// Spread requests about addresses over as many circuits as `config` has it, for mappings
+    /// that ask to, see [`crate::scatter`]
+    pub fn with_scatter(mut self, config: ScatterConfig) -> Self {
+        self.scatter = AddressScatter::new(config);
+        self
+    }
+    
+    /// WebSocket sessions held by this node
+    pub fn sessions(&self) -> Arc<SessionStore> {
+        self.sessions.clone()
+    }
+    
+    /// Recent circuit build failures, newest first
+    pub fn circuit_failures(&self) -> Vec<CircuitBuildReport> {
+        self.circuit_failures.recent()
+    }
+    
+    /// The epoch tracker, to be kept current with `epochs::follow`
+    pub fn epochs(&self) -> Arc<EpochTracker> {
+        self.epochs.clone()
+    }
+    
+    /// The bus this service emits its events on, for registering further subscribers
+    pub fn events(&self) -> Arc<EventBus> {
+        self.events.clone()
+    }
+    
+    /// The controller deciding which requests are shed while the network is overloaded
+    pub fn admission(&self) -> Arc<AdmissionController> {
+        self.admission.clone()
+    }
+    
+    /// Work credited to downstream nodes, to be delivered with `accounting::deliver_receipts`
+    pub fn work_tally(&self) -> Arc<WorkTally> {
+        self.work.clone()
+    }
+    
+    /// Activity counters reported in this node's heartbeats
+    pub fn counters(&self) -> Arc<ActivityCounters> {
+        self.counters.clone()
+    }
+    
+    /// Sanitize mirrored requests with `sanitizer` rather than the primary rule set, see [`crate::shadow`]
+    pub fn with_shadow_sanitizer(mut self, sanitizer: Arc<dyn RequestSanitizer + Send + Sync>) -> Self {
+        self.shadow_sanitizer = Some(sanitizer);
+        self
+    }
+    
+    /// Record the usage of users who consent to it, see [`crate::compliance`]
+    pub fn with_usage_audit(mut self, audit: Arc<UsageAudit>) -> Self {
+        self.usage_audit = Some(audit);
+        self
+    }
+    
+    /// Serve users who opted into it from `proxy` while no circuit can be built, see [`crate::fallback`]
+    pub fn with_fallback(mut self, proxy: DirectProxy) -> Self {
+        self.fallback = Some(Arc::new(proxy));
+        self
+    }
+    
+    /// Share the nonces of signed requests with the entry nodes using `storage`, see [`crate::signing`]
+    pub fn with_shared_nonces(mut self, storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        self.signatures = self.signatures.with_storage(storage);
+        self
+    }
+    
+    /// Meter users' usage into `meter` for invoicing, see [`crate::billing`]
+    pub fn with_meter(mut self, meter: Arc<UsageMeter>) -> Self {
+        self.meter = Some(meter);
+        self
+    }
+    
+    /// How requests mirrored onto shadow circuits have compared, for the operator
+    pub fn shadow_report(&self) -> ShadowReport {
+        self.shadow.report()
+    }
+    
+    /// Handle an incoming RPC request with the options in `ctx`
+    pub async fn handle_request(&self, ctx: RequestContext, request: &[u8]) -> Result<Vec<u8>> {
+        self.serve(ctx, request).await.map(|(response, _)| response)
+    }
+    
+    /// Handle an incoming RPC request, returning the circuit that answered it, if one did
+    async fn serve(&self, ctx: RequestContext, request: &[u8]) -> Result<(Vec<u8>, Option<CircuitId>)> {
+        // Answer health and version probes without a trip through a circuit
+        if let Some(response) = self.emulate(&ctx, request).await? {
+            return Ok((response, None));
+        }
+        
+        let mut dispatched = match self.dispatch(ctx, request).await? {
+            Dispatch::Circuit(dispatched) => dispatched,
+            Dispatch::Direct(degraded) => return Ok((self.serve_direct(&degraded, request).await?, None)),
+        };
+        let canary = dispatched.ctx.is_canary();
+        
+        // Mirror a sample of reads onto a shadow circuit, never holding up this one
+        let mirror = self
+            .shadow
+            .enabled()
+            .then(|| serde_json::from_slice::<serde_json::Value>(request).ok())
+            .flatten()
+            .filter(|parsed| !canary && self.shadow.samples(parsed))
+            .map(|_| {
+                let sanitizer = self.shadow_sanitizer.clone().unwrap_or_else(|| self.sanitizer.clone());
+                self.shadow.mirror(dispatched.ctx.clone(), dispatched.method, request.to_vec(), sanitizer)
+            });
+        
+        // Wait for the response, for as long as the request's budget allows; a read a hop
+        // failed under, or any request an exit at capacity refused, is sent once more on a
+        // rebuilt circuit, see `crate::replay`
+        let response = match self.receive(&dispatched).await {
+            Err(e) => match self
+                .replay(&dispatched.ctx, &mut dispatched.replay, &dispatched.circuit, dispatched.deadline, e)
+                .await
+            {
+                Ok((sent, reason)) => {
+                    dispatched.resent(sent, reason);
+                    self.receive(&dispatched).await
+                }
+                Err(e) => Err(e),
+            },
+            received => received,
+        }
+            .map_err(|e| {
+                self.record_completion(&dispatched.ctx, dispatched.method, AuditStatus::Failed, request.len(), 0, false);
+                self.failed(dispatched.method, dispatched.started, canary, e)
+            })?;
+        dispatched.stopwatch.end(Phase::Circuit);
+        self.work.credit(
+            &dispatched.hops,
+            Work {
+                requests: 0,
+                bytes: response.len() as u64,
+            },
+            self.clock.now(),
+        );
+        
+        // Prepare the response for delivery back to the client
+        let prepared_response = self.sanitizer.prepare_response(&response).await?;
+        if dispatched.method == emulation::VERSION_METHOD {
+            self.version.observe(dispatched.ctx.constraints.network, &prepared_response);
+        }
+        self.complete(
+            dispatched.method,
+            dispatched.started,
+            canary,
+            RequestOutcome::Success,
+            prepared_response.len(),
+        );
+        self.record_completion(
+            &dispatched.ctx,
+            dispatched.method,
+            answered_status(&prepared_response),
+            request.len(),
+            prepared_response.len(),
+            false,
+        );
+        if let Some(mirror) = mirror {
+            let _ = mirror.send(prepared_response.clone());
+        }
+        let wants_receipt = dispatched.ctx.receipt;
+        let trace_token = dispatched.ctx.trace_token.unwrap_or_default();
+        
+        // Give the client the token to quote if it disputes the response, and a receipt and
+        // where the request's time went if asked; the exit node's timing is for this node only
+        let circuit = Some(dispatched.circuit.clone());
+        let response = match serde_json::from_slice::<serde_json::Value>(&prepared_response) {
+            Ok(mut response) if response.is_object() => {
+                let upstream = timing::take_upstream(&mut response);
+                if dispatched.ctx.timing {
+                    let breakdown = dispatched.stopwatch.finish(upstream.unwrap_or_default());
+                    methods::set_extension(&mut response, timing::EXTENSION_FIELD, serde_json::json!(breakdown));
+                }
+                if wants_receipt {
+                    self.attach_receipt(&dispatched.circuit, request, &mut response).await;
+                }
+                methods::set_extension(&mut response, "trace_token", serde_json::json!(trace_token));
+                if let Some(reason) = dispatched.retried {
+                    methods::set_extension(
+                        &mut response,
+                        "retry",
+                        serde_json::json!({ "circuit_rebuilt": true, "reason": reason.label() }),
+                    );
+                }
+                serde_json::to_vec(&response)?
+            }
+            _ => prepared_response,
+        };
+        Ok((response, circuit))
+    }
+    
+    /// Attach a signed receipt for `response` to `request`, see [`crate::receipts`]
+    ///
+    /// A receipt that can't be issued is left out rather than failing a request already served.
+    async fn attach_receipt(&self, circuit: &CircuitId, request: &[u8], response: &mut serde_json::Value) {
+        let receipt = match serde_json::from_slice(request) {
+            Ok(request) => {
+                receipts::issue(
+                    &*self.crypto,
+                    &self.receipt_key,
+                    &self.node_id,
+                    circuit,
+                    &request,
+                    response,
+                    self.clock.now(),
+                )
+                .await
+            }
+            Err(e) => Err(e.into()),
+        };
+        match receipt {
+            Ok(receipt) => methods::set_extension(response, "receipt", serde_json::json!(receipt)),
+            Err(e) => tracing::warn!("Failed to issue a receipt: {}", e),
+        }
+    }
+    
+    /// Check that `api_key` belongs to an active user, before anything is kept for its calls
+    pub async fn check_api_key(&self, api_key: &str) -> Result<()> {
+        self.authenticate(api_key).await.map(drop)
+    }
+    
+    /// Check a receipt against the client's copies of the request and response
+    ///
+    /// Only receipts this node issued verify here; anyone can verify them offline against
+    /// the receipt key the directory publishes for the node.
+    pub fn verify_receipt(
+        &self,
+        receipt: &ServiceReceipt,
+        request: &serde_json::Value,
+        response: &serde_json::Value,
+    ) -> Result<(), ReceiptInvalid> {
+        let keys = match receipt.node_id == self.node_id {
+            true => self.receipt_key.public_keys(self.clock.now()),
+            false => Vec::new(),
+        };
+        receipts::verify(receipt, request, response, &keys)
+    }
+    
+    /// Handle a JSON-RPC notification, which is sent through the circuit without waiting
+    ///
+    /// The request is authenticated, checked, and counted like any other, but nothing waits
+    /// for the exit node, so it completes as soon as the circuit has taken it.
+    pub async fn handle_notification(&self, mut ctx: RequestContext, request: &[u8]) -> Result<()> {
+        ctx.notification = true;
+        let dispatched = match self.dispatch(ctx, request).await? {
+            Dispatch::Circuit(dispatched) => dispatched,
+            Dispatch::Direct(degraded) => return self.serve_direct(&degraded, request).await.map(drop),
+        };
+        self.complete(
+            dispatched.method,
+            dispatched.started,
+            dispatched.ctx.is_canary(),
+            RequestOutcome::Success,
+            0,
+        );
+        self.record_completion(&dispatched.ctx, dispatched.method, AuditStatus::Ok, request.len(), 0, false);
+        Ok(())
+    }
+    
+    /// Handle an incoming RPC request, yielding the response in chunks as they arrive
+    ///
+    /// Callers should only use this for methods accepted by [`is_streamable`].
+    pub async fn handle_request_stream(&self, ctx: RequestContext, request: &[u8]) -> Result<ResponseStream> {
+        let Dispatched { request_id, ctx, method, started, hops, slot, in_flight, circuit_permit, .. } = match self.dispatch(ctx, request).await? {
+            Dispatch::Circuit(dispatched) => dispatched,
+            Dispatch::Direct(degraded) => {
+                // Served whole, as the one and last chunk
+                let data = self.serve_direct(&degraded, request).await?;
+                let chunk = ResponseChunk {
+                    request_id: Uuid::new_v4(),
+                    sequence: 0,
+                    data,
+                    last: true,
+                };
+                return Ok(Box::pin(futures::stream::once(async move { Ok(chunk) })));
+            }
+        };
+        let canary = ctx.is_canary();
+        let trace_token = ctx.trace_token.clone().unwrap_or_default();
+        
+        // Credit the hops for each chunk as it comes off the circuit
+        let work = self.work.clone();
+        let clock = self.clock.clone();
+        let chunks = self
+            .router
+            .receive_response_stream(request_id)
+            .await
+            .map_err(|e| {
+                self.record_completion(&ctx, method, AuditStatus::Failed, request.len(), 0, false);
+                self.failed(method, started, canary, e)
+            })?;
+        let prepared = chunks.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                let bytes = chunk.data.len() as u64;
+                work.credit(&hops, Work { requests: 0, bytes }, clock.now());
+            }
+        });
+        
+        // The request completes with its last chunk, or with the first error, and only then
+        // frees its slot in the network
+        let events = (!canary).then(|| self.events.clone());
+        let metered = self.meter.clone().zip(ctx.user.as_ref().map(|user| user.id));
+        let clock = self.clock.clone();
+        let audited = self.usage_audit.clone().zip(ctx.user);
+        let request_size = request.len();
+        let mut size = 0;
+        let mut slot = Some((slot, in_flight, circuit_permit));
+        let completing = prepared.inspect(move |chunk: &Result<ResponseChunk>| {
+            let outcome = match chunk {
+                Ok(chunk) => {
+                    size += chunk.data.len();
+                    if !chunk.last {
+                        return;
+                    }
+                    RequestOutcome::Success
+                }
+                Err(_) => RequestOutcome::Failure,
+            };
+            slot.take();
+            if let (Some((meter, user_id)), RequestOutcome::Success) = (&metered, outcome) {
+                meter.record_response(*user_id, size, clock.now());
+            }
+            if let Some((audit, user)) = &audited {
+                match outcome {
+                    RequestOutcome::Success => audit.record(user, method, AuditStatus::Ok, request_size, size, false),
+                    _ => audit.record(user, method, AuditStatus::Failed, request_size, 0, false),
+                }
+            }
+            match &events {
+                Some(events) => events.emit(Event::RequestCompleted {
+                    method,
+                    outcome,
+                    latency: started.elapsed(),
+                    size: if outcome == RequestOutcome::Success { size } else { 0 },
+                }),
+                None => record_canary(outcome, started.elapsed()),
+            }
+        });
+        
+        // Prepare each chunk for the client: the client's id and the DarkNode extension go on
+        // the response, and one cut off partway is closed with an error, see `crate::streamed`
+        let mut extension = serde_json::Map::new();
+        extension.insert("trace_token".to_string(), serde_json::json!(trace_token));
+        let id = serde_json::from_slice::<serde_json::Value>(request)
+            .ok()
+            .and_then(|request| request.get("id").cloned())
+            .unwrap_or_default();
+        let state = (Box::pin(completing), StreamedResponse::new(id, extension), self.sanitizer.clone(), 0, false);
+        let finished = futures::stream::unfold(state, move |(mut chunks, mut streamed, sanitizer, sequence, done)| async move {
+            if done {
+                return None;
+            }
+            let chunk = match chunks.next().await? {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let ended = streamed.abort(&e).map(|data| ResponseChunk {
+                        request_id,
+                        sequence,
+                        data,
+                        last: true,
+                    });
+                    return Some((ended.ok_or(e), (chunks, streamed, sanitizer, sequence, true)));
+                }
+            };
+            let (sequence, last) = (chunk.sequence + 1, chunk.last);
+            let prepared = streamed
+                .prepare(&*sanitizer, &chunk.data)
+                .await
+                .map(|data| ResponseChunk { data, ..chunk });
+            Some((prepared, (chunks, streamed, sanitizer, sequence, last)))
+        });
+        Ok(Box::pin(finished))
+    }
+    
+    /// Authenticate, account, sanitize, and send a request through the user's circuit
+    ///
+    /// If no circuit can be built and the user's mapping opted into it, the request is
+    /// handed back to be served without one instead. Events are emitted with the method
+    /// label only, never the user or circuit.
+    async fn dispatch(&self, mut ctx: RequestContext, request: &[u8]) -> Result<Dispatch> {
+        let started = std::time::Instant::now();
+        let mut stopwatch = Stopwatch::start(tokio::time::Instant::now());
+        
+        // Validate the API key and fill in what the user's plan and mapping default to
+        let user = self.authenticate(&ctx.api_key).await?;
+        let grant = Grant::for_key(&user, &ctx.api_key);
+        let plan = self.plan_for(&user).await?;
+        let routed = user
+            .rpc_mappings
+            .iter()
+            .any(|mapping| Some(mapping.id) == ctx.mapping_id && !mapping.routes.is_empty());
+        let unresolved = routed.then(|| ctx.clone());
+        ctx.resolve(user.clone(), &plan);
+        
+        // Check the wallet signature before anything else, for mappings that require one
+        self.verify_signature(&ctx, &user, request).await?;
+        
+        // Turn requests away early while the network behind this node is struggling
+        let priority = ctx.priority.unwrap_or(plan.priority_class);
+        self.admission.admit(priority, std::time::Instant::now())?;
+        stopwatch.end(Phase::Auth);
+        
+        // Sanitize the request, taking the options in its body into the context
+        let mut payload = self
+            .sanitizer
+            .sanitize_payload(user.id, request)
+            .instrument(tracing::info_span!(telemetry::SANITIZE_SPAN))
+            .await?;
+        
+        // Serve the request under the mapping its method is routed to, if another, see `crate::method_routing`
+        if let (Some(unresolved), Some(from)) = (unresolved, ctx.mapping_id) {
+            let name = methods::method_name(&payload.request).unwrap_or_default();
+            if let Some(target) = method_routing::target(&user.rpc_mappings, from, name) {
+                ctx = unresolved.with_mapping(Some(target.id));
+                ctx.resolve(user.clone(), &plan);
+            }
+        }
+        ctx.absorb(&mut payload);
+        let method = traffic::method_label(methods::method_name(&payload.request).unwrap_or_default());
+        let canary = ctx.is_canary();
+        if !canary {
+            self.events.emit(Event::RequestAccepted {
+                method,
+                size: request.len(),
+            });
+            self.counters.set_unique_users(self.unique_users.observe(user.id, self.clock.now()));
+        }
+        
+        // Serve the request on the chain its method belongs to, or the mapping's if that can't be told
+        let pinned = ctx.mapping.as_ref().and_then(|mapping| mapping.chain);
+        ctx.constraints.chain = chains::resolve(payload.chain, pinned)?;
+        
+        // Refuse what the API key isn't scoped for, before spending quota or circuit work on it
+        let class = MethodClass::of(methods::method_name(&payload.request).unwrap_or_default());
+        grant.admit(ctx.constraints.network, class)?;
+        
+        // Reject malformed params before spending quota or circuit work on them, against the
+        // schemas of the chain the request is served on
+        let chain = ctx.constraints.chain.unwrap_or(self.validation_chain);
+        if let Some(schema) = self.schemas.get(&chain).filter(|_| !ctx.skip_validation) {
+            schema.validate(&payload.request)?;
+        }
+        
+        // Count the request against the key's share of the daily cap, if limited, and the user's,
+        // giving the key's back if the user's refuses it
+        let limited_key = scopes::key_of(&user, &ctx.api_key).zip(grant.limit(&plan));
+        if let Some((key, limited)) = &limited_key {
+            self.usage.record_request(key.id, limited)?;
+        }
+        if let Err(exceeded) = self.usage.record_request(user.id, &plan) {
+            if let Some((key, _)) = &limited_key {
+                self.usage.release_request(key.id);
+            }
+            return Err(exceeded.into());
+        }
+        stopwatch.end(Phase::Sanitize);
+        
+        // The request's budget runs from when it was accepted
+        if let Some(meter) = &self.meter {
+            meter.record_request(&user, class, request.len(), self.clock.now());
+        }
+        let limit = ctx.budget(&self.timeouts, class);
+        let deadline = Deadline::after(limit.saturating_sub(started.elapsed()));
+        ctx.deadline = Some(deadline);
+        
+        // Wait for the user's fair share of the network while it is busy, see `crate::fairness`
+        let slot = tokio::time::timeout(deadline.remaining(), self.fair_queue.enter(user.id, priority))
+            .await
+            .map_err(|_| TimedOut { budget: TimeoutBudget::Edge, class, limit }.record())?;
+        
+        // Get or create a circuit of the request's class for this user, and for the address
+        // it is about if the mapping scatters them
+        let scatter = match &ctx.mapping {
+            Some(mapping) if mapping.address_scatter => self.scatter.bucket(user.id, ctx.constraints.chain, &payload.request),
+            _ => None,
+        };
+        let preferences = CircuitPreferences {
+            exit_pool: ctx.constraints.exit_pool.clone(),
+            exit_region: ctx.constraints.exit_region.clone(),
+            class: ctx.circuit_class,
+            scatter,
+            method_classes: vec![class],
+            ..Default::default()
+        };
+        let circuit = match self.get_or_create_circuit(&ctx.api_key, &user, &plan, &preferences).await {
+            Ok(circuit) => circuit,
+            Err(e) => match self.fallback_for(&ctx).filter(|_| e.is::<CircuitUnavailable>()) {
+                Some(proxy) => {
+                    return Ok(Dispatch::Direct(Degraded {
+                        ctx,
+                        method,
+                        started,
+                        deadline,
+                        payload,
+                        proxy,
+                    }))
+                }
+                None => return Err(e),
+            },
+        };
+        
+        // Wait for room on a circuit whose class carries few requests at once
+        let key = CircuitKey::of(user.id, &preferences);
+        let circuit_permit = self
+            .circuit_permit(&key, &circuit.id, deadline)
+            .await
+            .map_err(|_| TimedOut { budget: TimeoutBudget::Edge, class, limit }.record())?;
+        
+        // Keep what the request needs to be sent again should a hop fail under it
+        let mut replay = self.replay.keeps(&payload.request).then(|| Replay {
+            user,
+            plan,
+            preferences,
+            payload: payload.clone(),
+        });
+        
+        // Send the request through the circuit, or a rebuilt one if a hop fails a read
+        let (sent, retried) = match self.transmit(&ctx, &circuit, &mut payload).await {
+            Ok(sent) => (sent, None),
+            Err(e) => {
+                let (sent, reason) = self
+                    .replay(&ctx, &mut replay, &circuit.id, deadline, e)
+                    .await
+                    .map_err(|e| self.failed(method, started, canary, e))?;
+                (sent, Some(reason))
+            }
+        };
+        
+        Ok(Dispatch::Circuit(Dispatched {
+            request_id: sent.request_id,
+            ctx,
+            method,
+            started,
+            stopwatch,
+            class,
+            limit,
+            deadline,
+            hops: sent.hops,
+            in_flight: self.drains.enter(&sent.circuit),
+            circuit: sent.circuit,
+            slot,
+            replay,
+            retried,
+            circuit_permit,
+        }))
+    }
+    
+    /// One of the requests the circuit held under `key` may carry at once, if its class limits them
+    ///
+    /// Waits until a request on the circuit completes, or `deadline` passes.
+    async fn circuit_permit(
+        &self,
+        key: &CircuitKey,
+        circuit: &CircuitId,
+        deadline: Deadline,
+    ) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, tokio::time::error::Elapsed> {
+        let concurrency = self
+            .active_circuits
+            .read()
+            .await
+            .get(key)
+            .filter(|active| active.circuit.id == *circuit)
+            .and_then(|active| active.concurrency.clone());
+        let Some(concurrency) = concurrency else { return Ok(None) };
+        let permit = tokio::time::timeout(deadline.remaining(), concurrency.acquire_owned()).await?;
+        Ok(permit.ok())
+    }
+    
+    /// The fallback providers a request may be served from, if its mapping opted in and there are any
+    fn fallback_for(&self, ctx: &RequestContext) -> Option<Arc<DirectProxy>> {
+        let mode = ctx.mapping.as_ref().and_then(|mapping| mapping.fallback_mode);
+        match mode {
+            Some(FallbackMode::DirectProxy) => self
+                .fallback
+                .clone()
+                .filter(|proxy| proxy.serves(ctx.constraints.chain, ctx.constraints.network)),
+            None => None,
+        }
+    }
+    
+    /// Serve a request straight from a fallback provider, marking the response as degraded
+    async fn serve_direct(&self, degraded: &Degraded, request: &[u8]) -> Result<Vec<u8>> {
+        let Degraded { ctx, method, started, deadline, payload, proxy } = degraded;
+        let canary = ctx.is_canary();
+        metrics::increment_counter!("darknode_degraded_requests_total", "method" => *method);
+        tracing::debug!("No circuit available, serving a {} request from a fallback provider", method);
+        
+        let (chain, network) = (ctx.constraints.chain, ctx.constraints.network);
+        let response = proxy.forward(&payload.request, chain, network, deadline.remaining()).await.map_err(|e| {
+            self.record_completion(ctx, method, AuditStatus::Failed, request.len(), 0, true);
+            self.failed(method, *started, canary, e)
+        })?;
+        let prepared_response = self.sanitizer.prepare_response(&response).await?;
+        let prepared_response = match serde_json::from_slice::<serde_json::Value>(&prepared_response) {
+            Ok(mut response) if response.is_object() => {
+                fallback::mark_direct(&mut response);
+                serde_json::to_vec(&response)?
+            }
+            _ => prepared_response,
+        };
+        self.complete(method, *started, canary, RequestOutcome::Success, prepared_response.len());
+        self.record_completion(
+            ctx,
+            method,
+            answered_status(&prepared_response),
+            request.len(),
+            prepared_response.len(),
+            true,
+        );
+        Ok(prepared_response)
+    }
+    
+    /// Seal a request's payload and send it through a circuit, crediting the nodes carrying it
+    async fn transmit(&self, ctx: &RequestContext, circuit: &Circuit, payload: &mut ExitPayload) -> Result<Sent> {
+        // Seal the options the exit node acts on into its payload; whatever budget is left
+        // once the circuit is up goes with it, less the hops in between
+        ctx.seal(payload, &self.timeouts, circuit.routing_nodes.len() + 1, self.shaper.config());
+        ctx.client_traces.check(payload);
+        let sanitized_request = serde_json::to_vec(&payload)?;
+        
+        // Send the request through the circuit
+        let hop_count = circuit.routing_nodes.len() + 1;
+        let request_id = self
+            .send(ctx, circuit, &sanitized_request)
+            .instrument(tracing::info_span!(telemetry::CIRCUIT_SEND_SPAN, hops = hop_count))
+            .await?;
+        
+        // Credit the nodes carrying the request, see `crate::accounting`
+        let hops: Vec<NodeId> = circuit
+            .routing_nodes
+            .iter()
+            .chain(std::iter::once(&circuit.exit_node))
+            .cloned()
+            .collect();
+        self.work.credit(
+            &hops,
+            Work {
+                requests: 1,
+                bytes: sanitized_request.len() as u64,
+            },
+            self.clock.now(),
+        );
+        
+        Ok(Sent {
+            request_id,
+            hops,
+            circuit: circuit.id.clone(),
+        })
+    }
+    
+    /// Wait for the response to a request, for as long as its budget allows
+    async fn receive(&self, dispatched: &Dispatched) -> Result<Vec<u8>> {
+        tokio::time::timeout(
+            dispatched.deadline.remaining(),
+            self.router
+                .receive_response(dispatched.request_id)
+                .instrument(tracing::info_span!(telemetry::CIRCUIT_WAIT_SPAN)),
+        )
+            .await
+            .unwrap_or_else(|_| Err(dispatched.timed_out().into()))
+    }
+    
+    /// Send a request again on a circuit rebuilt without the hop that failed it, see [`crate::replay`]
+    ///
+    /// `error` is passed through unless it is a hop failure, the request may still be sent
+    /// again, taking `replay`, and the deadline leaves time to. The failed circuit is torn
+    /// down, and the user's new circuit replaces it for later requests too. A circuit a hop
+    /// no longer holds is torn down even if the request isn't sent again.
+    async fn replay(
+        &self,
+        ctx: &RequestContext,
+        replay: &mut Option<Replay>,
+        failed: &CircuitId,
+        deadline: Deadline,
+        error: anyhow::Error,
+    ) -> Result<(Sent, HopFailureKind)> {
+        let failure = match replay::hop_failure(&error) {
+            Some(failure) => failure.clone(),
+            None => return Err(error),
+        };
+        if failure.kind == HopFailureKind::CircuitUnknown {
+            self.drop_failed(failed).await;
+        }
+        if deadline.remaining() < self.replay.min_remaining {
+            return Err(error);
+        }
+        let Some(mut replay) = replay.take().filter(|replay| self.replay.allows(&replay.payload.request, failure.kind)) else {
+            return Err(error);
+        };
+        tracing::debug!("Hop of circuit {:?} {}, sending the request again", failed, failure.kind);
+        
+        self.drop_failed(failed).await;
+        
+        // Build around the suspect node, giving up on the original error if there's no time
+        // to or the circuit the user ends up with still crosses it
+        replay.preferences.exclude.extend(failure.node.clone());
+        let built = tokio::time::timeout(
+            deadline.remaining(),
+            self.get_or_create_circuit(&ctx.api_key, &replay.user, &replay.plan, &replay.preferences),
+        )
+            .await;
+        let circuit = match built {
+            Ok(Ok(circuit)) => circuit,
+            _ => return Err(error),
+        };
+        let crosses = |node: &NodeId| circuit.routing_nodes.contains(node) || circuit.exit_node == *node;
+        if failure.node.as_ref().map_or(false, crosses) {
+            return Err(error);
+        }
+        
+        metrics::increment_counter!("darknode_circuit_retries_total", "reason" => failure.kind.label());
+        let sent = self.transmit(ctx, &circuit, &mut replay.payload).await?;
+        Ok((sent, failure.kind))
+    }
+    
+    /// Tear down the circuit `failed`, unless another request has already replaced it
+    async fn drop_failed(&self, failed: &CircuitId) {
+        let removed = {
+            let active_circuits = self.active_circuits.read().await;
+            let key = active_circuits
+                .iter()
+                .find(|active| active.circuit.id == *failed)
+                .map(|active| active.key().clone());
+            let removed = key.and_then(|key| active_circuits.remove_if(&key, |_, active| active.circuit.id == *failed));
+            metrics::gauge!("darknode_active_circuits", active_circuits.len() as f64);
+            removed
+        };
+        if let Some((_, removed)) = removed {
+            self.teardown(&removed.circuit, CircuitEnd::HopFailed).await;
+        }
+    }
+    
+    /// Answer a `getHealth` or `getVersion` request locally, if emulation is on and can
+    ///
+    /// `getVersion` is answered with the version of the network of the request's mapping.
+    /// Callers still need a valid API key, but emulated requests don't count against
+    /// their quota and aren't recorded as traffic.
+    async fn emulate(&self, ctx: &RequestContext, body: &[u8]) -> Result<Option<Vec<u8>>> {
+        if !self.emulation.enabled {
+            return Ok(None);
+        }
+        let Ok(request) = serde_json::from_slice::<serde_json::Value>(body) else { return Ok(None) };
+        let method = match methods::method_name(&request) {
+            Some(emulation::HEALTH_METHOD) => emulation::HEALTH_METHOD,
+            Some(emulation::VERSION_METHOD) => emulation::VERSION_METHOD,
+            _ => return Ok(None),
+        };
+        let user = self.authenticate(&ctx.api_key).await?;
+        let response = match method {
+            emulation::HEALTH_METHOD => {
+                let healthy = !self.admission.state(std::time::Instant::now()).overloaded;
+                emulation::health_response(&request["id"], healthy)
+            }
+            _ => {
+                let network = user
+                    .rpc_mappings
+                    .iter()
+                    .find(|mapping| Some(mapping.id) == ctx.mapping_id)
+                    .map_or(Network::default(), |mapping| mapping.network);
+                match self.version.response(network, &request["id"]) {
+                    Some(response) => response,
+                    None => return Ok(None),
+                }
+            }
+        };
+        self.verify_signature(ctx, &user, body).await?;
+        metrics::increment_counter!("darknode_emulated_requests_total", "method" => method);
+        Ok(Some(serde_json::to_vec(&response)?))
+    }
+    
+    /// Fetch the exit side's version of each network kept through a live circuit, for
+    /// answering `getVersion` locally
+    ///
+    /// Meant to be called every version refresh interval; does nothing when emulation is off
+    /// or no circuit is up.
+    pub async fn refresh_version(&self) {
+        if !self.emulation.enabled {
+            return;
+        }
+        let circuit = self
+            .active_circuits
+            .read()
+            .await
+            .iter()
+            .find(|entry| !entry.deadline.is_expired())
+            .map(|entry| entry.circuit.clone());
+        let Some(circuit) = circuit else { return };
+        
+        for network in self.version.networks() {
+            let fetched = async {
+                let request = serde_json::to_vec(&emulation::version_request(network))?;
+                let request_id = self.send(&RequestContext::default(), &circuit, &request).await?;
+                let response = self.router.receive_response(request_id).await?;
+                self.sanitizer.prepare_response(&response).await
+            };
+            match fetched.await {
+                Ok(response) => self.version.observe(network, &response),
+                Err(e) => tracing::debug!("Failed to refresh the exit side's {} version: {}", network.name(), e),
+            }
+        }
+    }
+    
+    /// Emit the completion of a request admitted at `started`
+    ///
+    /// Canary requests only count towards their own metric, not the traffic stats.
+    fn complete(&self, method: &'static str, started: std::time::Instant, canary: bool, outcome: RequestOutcome, size: usize) {
+        if canary {
+            record_canary(outcome, started.elapsed());
+            return;
+        }
+        self.events.emit(Event::RequestCompleted {
+            method,
+            outcome,
+            latency: started.elapsed(),
+            size,
+        });
+    }
+    
+    /// Record how the request ended: its response for billing if usage is metered, and the
+    /// request for its user's audit trail if auditing is on and they consented
+    fn record_completion(
+        &self,
+        ctx: &RequestContext,
+        method: &str,
+        status: AuditStatus,
+        request_bytes: usize,
+        response_bytes: usize,
+        degraded: bool,
+    ) {
+        if let (Some(meter), Some(user)) = (&self.meter, &ctx.user) {
+            meter.record_response(user.id, response_bytes, self.clock.now());
+            if degraded {
+                meter.record_degraded(user.id, self.clock.now());
+            }
+        }
+        if let (Some(audit), Some(user)) = (&self.usage_audit, &ctx.user) {
+            audit.record(user, method, status, request_bytes, response_bytes, degraded);
+        }
+    }
+    
+    /// Let the user behind `api_key` decide whether their usage is audited
+    ///
+    /// Fails with [`AuditingDisabled`] unless this deployment audits usage.
+    pub async fn set_audit_consent(&self, api_key: &str, consent: bool) -> Result<()> {
+        if self.usage_audit.is_none() {
+            return Err(AuditingDisabled.into());
+        }
+        let user = self.authenticate(api_key).await?;
+        self.user_manager.set_audit_consent(user.id, consent).await
+    }
+    
+    /// The usage records kept of the user behind `api_key`, oldest first
+    ///
+    /// Fails with [`AuditingDisabled`] unless this deployment audits usage.
+    pub async fn audit_records(&self, api_key: &str) -> Result<Vec<UsageRecord>> {
+        let Some(audit) = &self.usage_audit else {
+            return Err(AuditingDisabled.into());
+        };
+        let user = self.authenticate(api_key).await?;
+        audit.export(user.id).await
+    }
+    
+    /// Emit the failure of a request in the circuit and pass the error through
+    fn failed(&self, method: &'static str, started: std::time::Instant, canary: bool, err: anyhow::Error) -> anyhow::Error {
+        self.complete(method, started, canary, RequestOutcome::Failure, 0);
+        err
+    }
+    
+    /// Reserve a WebSocket subscription slot for the user behind `api_key`
+    pub async fn open_subscription(&self, api_key: &str) -> Result<Uuid> {
+        let user = self.authenticate(api_key).await?;
+        let plan = self.plan_for(&user).await?;
+        self.usage.acquire_subscription(user.id, &plan)?;
+        Ok(user.id)
+    }
+    
+    /// Release a subscription slot reserved with `open_subscription`
+    pub fn close_subscription(&self, user_id: Uuid) {
+        self.usage.release_subscription(user_id);
+    }
+    
+    /// Start a WebSocket session for the user behind `api_key`
+    pub async fn open_session(&self, api_key: &str) -> Result<Attachment> {
+        let user = self.authenticate(api_key).await?;
+        Ok(self.sessions.open(user.id))
+    }
+    
+    /// Resume a WebSocket session, which must belong to the user behind `api_key`
+    pub async fn resume_session(&self, api_key: &str, token: &str) -> Result<Attachment> {
+        let user = self.authenticate(api_key).await?;
+        if self.sessions.owner(token) != Some(user.id) {
+            return Err(SessionExpired.into());
+        }
+        Ok(self.sessions.resume(token)?)
+    }
+    
+    /// Subscribe within a WebSocket session, counting against the user's subscription cap
+    ///
+    /// The subscription is carried on the user's subscription circuit, built first if the
+    /// user has none, see [`crate::circuit_class`], and served by polling it, see
+    /// [`crate::sessions`].
+    pub async fn subscribe(
+        self: &Arc<Self>,
+        api_key: &str,
+        mapping_id: Option<Uuid>,
+        token: &str,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<u64> {
+        let watch = Watch::of(method, &params)
+            .ok_or_else(|| anyhow::anyhow!("Subscriptions with {} aren't supported", method))?;
+        let user = self.authenticate(api_key).await?;
+        // Scoped keys are held to the network of the mapping the session was opened on
+        let network = user
+            .rpc_mappings
+            .iter()
+            .find(|mapping| Some(mapping.id) == mapping_id)
+            .map_or(Network::default(), |mapping| mapping.network);
+        Grant::for_key(&user, api_key).admit(network, MethodClass::of(method))?;
+        let plan = self.plan_for(&user).await?;
+        self.usage.acquire_subscription(user.id, &plan)?;
+        let preferences = CircuitPreferences {
+            class: CircuitClass::Subscription,
+            ..Default::default()
+        };
+        let request = watch.request(&params);
+        let subscribed = match self.get_or_create_circuit(api_key, &user, &plan, &preferences).await {
+            Ok(_) => self.sessions.subscribe(token, method, params).map_err(Into::into),
+            Err(e) => Err(e),
+        };
+        match subscribed {
+            Ok(id) => {
+                let ctx = RequestContext::new(api_key)
+                    .with_mapping(mapping_id)
+                    .with_circuit_class(CircuitClass::Subscription);
+                tokio::spawn(self.clone().poll_subscription(ctx, user.id, token.to_string(), id, watch, request));
+                Ok(id)
+            }
+            Err(e) => {
+                self.close_subscription(user.id);
+                Err(e)
+            }
+        }
+    }
+    
+    /// Serve subscription `id` of session `token` by polling `request` on the session's
+    /// interval, notifying the session when the answer changes, until the subscription is
+    /// dropped or the session expires
+    ///
+    /// A poll follows at once when the circuit the subscription rides is moved off a
+    /// draining node, and once it is answered on the new circuit the session is told the
+    /// subscription was resumed, with the switch as a gap it may have missed changes in.
+    async fn poll_subscription(
+        self: Arc<Self>,
+        ctx: RequestContext,
+        user_id: Uuid,
+        token: String,
+        id: u64,
+        watch: Watch,
+        request: serde_json::Value,
+    ) {
+        let request = request.to_string();
+        let poller = (token.clone(), id);
+        let (migrated, mut migrations) = tokio::sync::mpsc::unbounded_channel();
+        self.pollers.insert(poller.clone(), Poller { circuit: None, migrated });
+        let mut ticker = tokio::time::interval(self.sessions.poll_interval());
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut previous = None;
+        let mut resumed_from = None;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                Some(switching) = migrations.recv() => {
+                    resumed_from = resumed_from.or(Some(switching));
+                }
+            }
+            if !self.sessions.is_subscribed(&token, id) {
+                break;
+            }
+            let (result, circuit) = match self.serve(ctx.clone(), request.as_bytes()).await {
+                Ok((response, circuit)) => (
+                    serde_json::from_slice::<serde_json::Value>(&response)
+                        .ok()
+                        .and_then(|mut response| response.get_mut("result").map(serde_json::Value::take)),
+                    circuit,
+                ),
+                Err(e) => {
+                    tracing::debug!("Failed to poll subscription {}: {}", id, e);
+                    (None, None)
+                }
+            };
+            if let (Some(circuit), Some(mut poller)) = (circuit, self.pollers.get_mut(&poller)) {
+                poller.circuit = Some(circuit);
+            }
+            let Some(result) = result else {
+                metrics::increment_counter!("darknode_subscription_polls_failed_total");
+                continue;
+            };
+            if let Some(switching) = resumed_from.take() {
+                self.sessions.notify(&token, drain::resumed(id, switching, self.clock.now()));
+            }
+            if let Some(notification) = watch.notification(id, previous.as_ref(), &result) {
+                self.sessions.notify(&token, notification);
+                if watch.is_once() {
+                    self.unsubscribe(user_id, &token, id);
+                    break;
+                }
+            }
+            previous = Some(result);
+        }
+        self.pollers.remove(&poller);
+    }
+    
+    /// Unsubscribe within a WebSocket session, returning whether the subscription existed
+    pub fn unsubscribe(&self, user_id: Uuid, token: &str, id: u64) -> bool {
+        let existed = self.sessions.unsubscribe(token, id);
+        if existed {
+            self.close_subscription(user_id);
+        }
+        existed
+    }
+    
+    /// Forget the nonces of signed requests that are no longer fresh
+    pub async fn prune_nonces(&self) {
+        if let Err(e) = self.signatures.prune(self.clock.now()).await {
+            tracing::warn!("Failed to prune request nonces: {}", e);
+        }
+    }
+    
+    /// Drop sessions whose grace period has passed and release their subscription slots
+    pub fn sweep_sessions(&self) {
+        for session in self.sessions.sweep() {
+            for _ in 0..session.subscriptions {
+                self.close_subscription(session.user_id);
+            }
+        }
+    }
+    
+    /// Describe the circuit serving an API key's requests to an exit pool, if one is up
+    ///
+    /// Fails if the API key isn't valid.
+    pub async fn circuit_info(&self, api_key: &str, exit_pool: Option<String>) -> Result<Option<CircuitInfo>> {
+        let user = self.authenticate(api_key).await?;
+        let key = CircuitKey {
+            user_id: user.id,
+            exit_pool,
+            exit_region: None,
+            class: CircuitClass::Interactive,
+            scatter: None,
+        };
+        let active_circuits = self.active_circuits.read().await;
+        let Some(active) = active_circuits.get(&key) else { return Ok(None) };
+        if active.deadline.is_expired() {
+            return Ok(None);
+        }
+        Ok(Some(self.describe(&active.circuit)))
+    }
+    
+    /// Replace the circuit serving an API key's requests to an exit pool with a new one
+    ///
+    /// Requests already on the old circuit finish on it, as when an epoch ends, and it is torn
+    /// down once they have. Circuits the key's requests about addresses are scattered over are
+    /// retired too, and rebuilt when next needed. Fails with [`RotationThrottled`] if the
+    /// user's circuits were rotated less than [`MIN_ROTATION_INTERVAL`] ago.
+    pub async fn rotate_circuit(&self, api_key: &str, exit_pool: Option<String>) -> Result<CircuitInfo> {
+        let user = self.authenticate(api_key).await?;
+        let plan = self.plan_for(&user).await?;
+        {
+            let now = tokio::time::Instant::now();
+            let mut rotations = self.rotations.lock();
+            if let Some(rotated_at) = rotations.get(&user.id, now) {
+                let retry_after = MIN_ROTATION_INTERVAL.saturating_sub(now.duration_since(*rotated_at));
+                return Err(RotationThrottled { retry_after }.into());
+            }
+            rotations.insert(user.id, now, now);
+        }
+        let key = CircuitKey {
+            user_id: user.id,
+            exit_pool: exit_pool.clone(),
+            exit_region: None,
+            class: CircuitClass::Interactive,
+            scatter: None,
+        };
+        let rotated: Vec<ActiveCircuit> = {
+            let active_circuits = self.active_circuits.read().await;
+            let keys: Vec<CircuitKey> = active_circuits
+                .iter()
+                .filter(|entry| entry.key().base() == key)
+                .map(|entry| entry.key().clone())
+                .collect();
+            let rotated = keys.iter().filter_map(|key| active_circuits.remove(key)).map(|(_, active)| active).collect();
+            metrics::gauge!("darknode_active_circuits", active_circuits.len() as f64);
+            rotated
+        };
+        for rotated in rotated {
+            self.retire(rotated.circuit, CircuitEnd::Rotated);
+        }
+        
+        let preferences = CircuitPreferences {
+            exit_pool,
+            ..Default::default()
+        };
+        let circuit = self.get_or_create_circuit(api_key, &user, &plan, &preferences).await?;
+        Ok(self.describe(&circuit))
+    }
+    
+    /// Describe a circuit to its user
+    fn describe(&self, circuit: &Circuit) -> CircuitInfo {
+        CircuitInfo::of(
+            circuit,
+            self.epochs.ends_at(),
+            self.keepalive.enabled,
+            self.shaper.config().enabled,
+        )
+    }
+    
+    /// Ping every idle circuit and replace those that missed too many pongs in a row
+    ///
+    /// Circuits count as idle after their class's keepalive interval, see
+    /// [`CircuitClassConfig::keepalive_tick`] for how often to call this. Does nothing when
+    /// keepalive is disabled.
+    pub async fn keepalive(&self) {
+        if !self.keepalive.enabled {
+            return;
+        }
+        
+        let idle: Vec<(CircuitKey, String, Circuit)> = self
+            .active_circuits
+            .read()
+            .await
+            .iter()
+            .filter(|entry| !entry.deadline.is_expired())
+            .filter(|entry| {
+                let interval = self.circuit_classes.keepalive_interval(entry.key().class, self.keepalive.interval);
+                entry.last_active.elapsed() >= interval
+            })
+            .map(|entry| (entry.key().clone(), entry.api_key.clone(), entry.circuit.clone()))
+            .collect();
+        
+        let pings = idle.into_iter().map(|(key, api_key, circuit)| async move {
+            let answered = self.ping(&circuit).await;
+            (key, api_key, circuit, answered)
+        });
+        for (key, api_key, circuit, answered) in futures::future::join_all(pings).await {
+            let dead = {
+                let active_circuits = self.active_circuits.read().await;
+                let Some(mut active) = active_circuits.get_mut(&key) else { continue };
+                if active.circuit.id != circuit.id {
+                    // Replaced while the ping was in flight
+                    continue;
+                }
+                if answered {
+                    active.last_active = tokio::time::Instant::now();
+                    active.missed_pongs = 0;
+                    false
+                } else {
+                    active.missed_pongs += 1;
+                    active.missed_pongs >= self.keepalive.missed_threshold
+                }
+            };
+            if dead {
+                self.replace_dead_circuit(&key, &api_key, &circuit).await;
+            }
+        }
+    }
+    
+    /// Send a ping through a circuit, returning whether the exit node answered in time
+    async fn ping(&self, circuit: &Circuit) -> bool {
+        let roundtrip = async {
+            let ping = serde_json::to_vec(&keepalive::ping())?;
+            let request_id = self.send(&RequestContext::default(), circuit, &ping).await?;
+            self.router.receive_response(request_id).await
+        };
+        matches!(tokio::time::timeout(self.keepalive.timeout, roundtrip).await, Ok(Ok(_)))
+    }
+    
+    /// Send a message through a circuit once traffic shaping lets it go
+    ///
+    /// Everything this node sends into circuits goes through here, so shaping treats all
+    /// traffic alike.
+    async fn send(&self, ctx: &RequestContext, circuit: &Circuit, message: &[u8]) -> Result<Uuid> {
+        self.shaper.release().await;
+        self.router.send_request(ctx, circuit, message).await
+    }
+    
+    /// Drop a circuit that stopped answering pings and build its user a new one
+    async fn replace_dead_circuit(&self, key: &CircuitKey, api_key: &str, circuit: &Circuit) {
+        tracing::warn!(
+            "Circuit {:?} stopped answering keepalive pings, unresponsive hop among entry {:?}, routing {:?}, exit {:?}",
+            circuit.id,
+            circuit.entry_node,
+            circuit.routing_nodes,
+            circuit.exit_node,
+        );
+        {
+            let active_circuits = self.active_circuits.read().await;
+            active_circuits.remove(key);
+            metrics::gauge!("darknode_active_circuits", active_circuits.len() as f64);
+        }
+        self.teardown(circuit, CircuitEnd::Unresponsive).await;
+        
+        let rebuilt = async {
+            let user = self.authenticate(api_key).await?;
+            let plan = self.plan_for(&user).await?;
+            let preferences = CircuitPreferences {
+                exit_pool: key.exit_pool.clone(),
+                exit_region: key.exit_region.clone(),
+                class: key.class,
+                ..Default::default()
+            };
+            self.get_or_create_circuit(api_key, &user, &plan, &preferences).await
+        };
+        if let Err(e) = rebuilt.await {
+            tracing::warn!("Failed to rebuild circuit {:?}: {}", circuit.id, e);
+        }
+    }
+    
+    /// Move circuits off the nodes the coordinator lists as draining, see [`crate::drain`]
+    ///
+    /// Meant to be called every drain poll. A circuit whose replacement can't be built yet
+    /// stays in use, and is tried again on the next poll.
+    pub async fn drain(self: &Arc<Self>, draining: Vec<NodeId>) {
+        let added = self.drains.observe(draining);
+        if added > 0 {
+            tracing::info!("{} more nodes are draining, moving circuits off them", added);
+        }
+        
+        let crossing: Vec<(CircuitKey, ActiveCircuit)> = self
+            .active_circuits
+            .read()
+            .await
+            .iter()
+            .filter(|entry| !entry.deadline.is_expired() && self.drains.crosses(&entry.circuit))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        let migrations = crossing.into_iter().map(|(key, active)| self.migrate(key, active));
+        futures::future::join_all(migrations).await;
+    }
+    
+    /// Replace a circuit crossing a draining node for new requests, and tear it down once
+    /// those in flight on it are done
+    async fn migrate(self: &Arc<Self>, key: CircuitKey, old: ActiveCircuit) {
+        let switching = self.clock.now();
+        let preferences = CircuitPreferences {
+            exit_pool: key.exit_pool.clone(),
+            exit_region: key.exit_region.clone(),
+            exit_subset: old.epoch.map(|epoch| self.epochs.subset_in(old.user_id, epoch)),
+            exclude: self.drains.draining(),
+            class: key.class,
+            lifetime: Some(self.circuit_classes.policy(key.class).lifetime),
+            ..Default::default()
+        };
+        let started = std::time::Instant::now();
+        let circuit = match self.build_circuit(&preferences).await {
+            Ok(circuit) => circuit,
+            Err(e) => {
+                let report = CircuitBuildReport::from_error(&e, started.elapsed());
+                tracing::warn!("Failed to move circuit {:?} off a draining node: {:?}", old.circuit.id, report.failure);
+                self.circuit_failures.record(report);
+                self.events.emit(Event::CircuitBuildFailed);
+                metrics::increment_counter!("darknode_circuit_drains_total", "outcome" => "failed");
+                return;
+            }
+        };
+        
+        // Switch new requests over, unless the circuit was replaced while this one was built
+        let switched = {
+            let active_circuits = self.active_circuits.read().await;
+            let switched = match active_circuits.get_mut(&key) {
+                Some(mut active) if active.circuit.id == old.circuit.id => {
+                    *active = ActiveCircuit {
+                        deadline: Deadline::after(circuit.lifetime()),
+                        last_active: tokio::time::Instant::now(),
+                        missed_pongs: 0,
+                        requests: 0,
+                        concurrency: self.concurrency(key.class),
+                        circuit: circuit.clone(),
+                        ..old.clone()
+                    };
+                    true
+                }
+                _ => false,
+            };
+            switched
+        };
+        if !switched {
+            if let Err(e) = self.router.close_circuit(&circuit).await {
+                tracing::warn!("Failed to tear down circuit {:?}: {}", circuit.id, e);
+            }
+            return;
+        }
+        self.events.emit(Event::CircuitCreated {
+            circuit_id: circuit.id.clone(),
+        });
+        metrics::increment_counter!("darknode_circuit_drains_total", "outcome" => "migrated");
+        
+        // Resume the subscriptions riding the old circuit on the new one at once
+        for poller in self.pollers.iter().filter(|poller| poller.circuit.as_ref() == Some(&old.circuit.id)) {
+            let _ = poller.migrated.send(switching);
+        }
+        
+        // Let requests in flight finish on the old circuit, up to the drain deadline
+        let service = self.clone();
+        tokio::spawn(async move {
+            if !service.drains.settled(&old.circuit.id).await {
+                tracing::warn!(
+                    "Tearing down drained circuit {:?} with {} requests still in flight",
+                    old.circuit.id,
+                    service.drains.in_flight(&old.circuit.id)
+                );
+            }
+            service.teardown(&old.circuit, CircuitEnd::Drained).await;
+        });
+    }
+    
+    /// Release a circuit this node dropped and report why
+    async fn teardown(&self, circuit: &Circuit, reason: CircuitEnd) {
+        self.events.emit(Event::CircuitDestroyed {
+            circuit_id: circuit.id.clone(),
+            reason,
+        });
+        if let Err(e) = self.router.close_circuit(circuit).await {
+            tracing::warn!("Failed to tear down circuit {:?}: {}", circuit.id, e);
+        }
+    }
+    
+    /// Tear down a circuit replaced for new requests, once the requests already on it are done
+    fn retire(&self, circuit: Circuit, reason: CircuitEnd) {
+        self.events.emit(Event::CircuitDestroyed {
+            circuit_id: circuit.id.clone(),
+            reason,
+        });
+        let (drains, router) = (self.drains.clone(), self.router.clone());
+        tokio::spawn(async move {
+            drains.settled(&circuit.id).await;
+            if let Err(e) = router.close_circuit(&circuit).await {
+                tracing::warn!("Failed to tear down circuit {:?}: {}", circuit.id, e);
+            }
+        });
+    }
+    
+    /// Evict the least recently used idle circuit if the node is at its circuit cap, to
+    /// make room for a circuit of `key`, returning the circuit evicted
+    ///
+    /// Called under the write lock, so circuits still being built count towards the cap.
+    /// Expired circuits go first. Fails if every circuit is still in use.
+    fn make_room(
+        &self,
+        active_circuits: &dashmap::DashMap<CircuitKey, ActiveCircuit>,
+        key: &CircuitKey,
+    ) -> Result<Option<ActiveCircuit>> {
+        // A circuit being replaced, or being built by another request already, frees its own slot
+        if active_circuits.contains_key(key) || self.building.contains_key(key) {
+            return Ok(None);
+        }
+        let building = self
+            .building
+            .iter()
+            .filter(|entry| !active_circuits.contains_key(entry.key()))
+            .count();
+        if active_circuits.len() + building < self.capacity.max_active_circuits {
+            return Ok(None);
+        }
+        let lru = active_circuits
+            .iter()
+            .filter(|entry| entry.deadline.is_expired() || entry.last_active.elapsed() >= self.capacity.idle_after)
+            .min_by_key(|entry| (!entry.deadline.is_expired(), entry.last_active))
+            .map(|entry| entry.key().clone());
+        let Some(lru) = lru else {
+            return Err(CircuitCapacityExhausted {
+                limit: self.capacity.max_active_circuits,
+            }
+            .into());
+        };
+        Ok(active_circuits.remove(&lru).map(|(_, evicted)| evicted))
+    }
+    
+    /// Look up the user for an API key, rejecting inactive subscriptions
+    async fn authenticate(&self, api_key: &str) -> Result<User> {
+        match self.user_manager.get_user_by_api_key(api_key).await? {
+            Some(user) if user.active => Ok(user),
+            Some(_) => anyhow::bail!("User subscription is not active"),
+            None => anyhow::bail!("Invalid API key"),
+        }
+    }
+    
+    /// Check the request's wallet signature, if the mapping it was sent to requires one
+    async fn verify_signature(&self, ctx: &RequestContext, user: &User, request: &[u8]) -> Result<()> {
+        if signing::required(user, ctx.mapping_id) {
+            self.signatures
+                .verify(user, ctx.signature.as_ref(), request, self.clock.now())
+                .await?;
+        }
+        Ok(())
+    }
+    
+    /// Resolve the plan a user is subscribed to
+    async fn plan_for(&self, user: &User) -> Result<Plan> {
+        match user.plan_id {
+            Some(plan_id) => match self.user_manager.get_plan(plan_id).await? {
+                Some(plan) => Ok(plan),
+                None => anyhow::bail!("Unknown plan {}", plan_id),
+            },
+            None => Ok(Plan::default()),
+        }
+    }
+    
+    /// Build a circuit for `preferences` under the circuit policy, as relaxed as the error budget has it
+    ///
+    /// While relaxed, the next stricter policy is tried first, so strictness returns once
+    /// circuits build under it again, and a failure that relaxes the policy is tried again
+    /// under the relaxed one. A failure the request's own constraints are to blame for isn't
+    /// counted, so no user relaxes the policy for everyone. The circuit is tagged with the
+    /// constraints relaxed.
+    async fn build_circuit(&self, preferences: &CircuitPreferences) -> Result<Circuit> {
+        let mut failure = None;
+        let mut attempts = self.error_budget.attempts();
+        while !attempts.is_empty() {
+            let relaxed = attempts.remove(0);
+            let preferences = CircuitPreferences {
+                policy: self.error_budget.policy(&relaxed),
+                ..preferences.clone()
+            };
+            let built = self
+                .router
+                .create_circuit_with(&preferences)
+                .instrument(tracing::info_span!(telemetry::CIRCUIT_BUILD_SPAN, relaxed = relaxed.len()))
+                .await;
+            let narrowed = built
+                .as_ref()
+                .err()
+                .and_then(|e| e.downcast_ref::<CircuitBuildError>())
+                .map_or(false, |e| e.narrowed);
+            let change = match narrowed {
+                true => None,
+                false => self.error_budget.record(&relaxed, built.is_ok(), self.clock.now()),
+            };
+            if let Some(change) = change {
+                let event = match change {
+                    PolicyChange::Relaxed(relaxation) => {
+                        tracing::warn!("Circuits keep failing to build, relaxing the {} constraint", relaxation.label());
+                        attempts.push(self.error_budget.relaxed());
+                        Event::CircuitPolicyRelaxed {
+                            constraint: relaxation.label(),
+                        }
+                    }
+                    PolicyChange::Restored(relaxation) => {
+                        tracing::info!("Circuits build again, restoring the {} constraint", relaxation.label());
+                        Event::CircuitPolicyRestored {
+                            constraint: relaxation.label(),
+                        }
+                    }
+                };
+                self.events.emit(event);
+                metrics::gauge!("darknode_circuit_policy_relaxations", self.error_budget.relaxed().len() as f64);
+            }
+            match built {
+                Ok(mut circuit) => {
+                    if !relaxed.is_empty() {
+                        metrics::increment_counter!("darknode_relaxed_circuits_total");
+                    }
+                    circuit.relaxed = relaxed;
+                    return Ok(circuit);
+                }
+                Err(e) => failure = Some(e),
+            }
+        }
+        Err(failure.unwrap_or_else(|| {
+            CircuitBuildError {
+                failure: CircuitBuildFailure::Other {
+                    error: "no circuit policy was tried".to_string(),
+                },
+                available: Default::default(),
+                narrowed: false,
+            }
+            .into()
+        }))
+    }
+    
+    /// What limits the requests a new circuit of `class` carries at once, if its class does
+    fn concurrency(&self, class: CircuitClass) -> Option<Arc<tokio::sync::Semaphore>> {
+        let max_in_flight = self.circuit_classes.policy(class).max_in_flight?;
+        Some(Arc::new(tokio::sync::Semaphore::new(max_in_flight.max(1))))
+    }
+    
+    /// Get an existing circuit of the class `preferences` name, or create a new one for a user
+    ///
+    /// Circuits are replaced once they expire, and as their class's policy has it once
+    /// they've been handed out for enough requests or their epoch ends.
+    async fn get_or_create_circuit(
+        &self,
+        api_key: &str,
+        user: &User,
+        plan: &Plan,
+        preferences: &CircuitPreferences,
+    ) -> Result<Circuit> {
+        // Check if we already have a circuit of this class for this user and pool
+        let class = preferences.class;
+        let policy = self.circuit_classes.policy(class);
+        let key = CircuitKey::of(user.id, preferences);
+        let epoch = self.epochs.current();
+        let spent = |active: &ActiveCircuit| policy.max_requests.map_or(false, |max| active.requests >= max);
+        let active_circuits = self.active_circuits.read().await;
+        if let Some(mut active) = active_circuits.get_mut(&key) {
+            // Check if the circuit is still valid. One that is due for rotation is replaced for
+            // new requests, while requests already holding it finish on it.
+            let rotated = (policy.rotate_at_epoch && active.epoch != epoch) || spent(&active);
+            
+            // One ending at an exit that refuses the request's method class is replaced too, see `crate::egress`
+            let serves = egress::serves(active.circuit.exit_classes.as_deref(), &preferences.method_classes);
+            if !serves {
+                tracing::debug!("Exit of circuit {:?} refuses the request's method class, replacing it", active.circuit.id);
+            }
+            if !active.deadline.is_expired() && !rotated && serves {
+                active.last_active = tokio::time::Instant::now();
+                active.requests += 1;
+                return Ok(active.circuit.clone());
+            }
+        }
+        
+        drop(active_circuits);  // Release the read lock
+        
+        // Check the limits and hold a slot for the new circuit under the write lock, so
+        // requests building circuits at once can't each find room for theirs
+        let (sibling_exits, evicted, _building) = {
+            let active_circuits = self.active_circuits.write().await;
+            
+            // Enforce the plan's concurrent circuit limit before building another interactive one.
+            // Subscription circuits are bounded by the plan's subscriptions instead, and scattered
+            // circuits count as one with the usual circuit they are siblings of. Circuits still
+            // being built count too.
+            let open_circuits = active_circuits
+                .iter()
+                .filter(|entry| !entry.deadline.is_expired())
+                .map(|entry| entry.key().base())
+                .chain(self.building.iter().map(|entry| entry.key().base()))
+                .filter(|other| *other != key.base() && other.class == CircuitClass::Interactive && other.user_id == user.id)
+                .collect::<std::collections::HashSet<_>>()
+                .len();
+            if class == CircuitClass::Interactive && open_circuits as u64 >= plan.max_circuits as u64 {
+                return Err(QuotaExceeded {
+                    cap: QuotaCap::Circuits,
+                    limit: plan.max_circuits as u64,
+                    plan: plan.name.clone(),
+                    resets_at: None,
+                }
+                .into());
+            }
+            
+            // Keep a scattered circuit off the exits its live siblings go through
+            let sibling_exits: Vec<NodeId> = match key.scatter {
+                Some(_) => active_circuits
+                    .iter()
+                    .filter(|entry| entry.key().base() == key.base() && entry.key().scatter.is_some() && *entry.key() != key)
+                    .filter(|entry| !entry.deadline.is_expired())
+                    .map(|entry| entry.circuit.exit_node.clone())
+                    .collect(),
+                None => Vec::new(),
+            };
+            
+            // Stay within the node-wide circuit cap
+            let evicted = self.make_room(&active_circuits, &key)?;
+            metrics::gauge!("darknode_active_circuits", active_circuits.len() as f64);
+            (sibling_exits, evicted, Building::reserve(&self.building, key.clone()))
+        };
+        if let Some(evicted) = evicted {
+            let reason = if evicted.deadline.is_expired() {
+                CircuitEnd::Expired
+            } else {
+                CircuitEnd::Evicted
+            };
+            self.teardown(&evicted.circuit, reason).await;
+        }
+        
+        // Create a new circuit, keeping the details of any failure for the operator
+        let started = std::time::Instant::now();
+        let mut preferences = CircuitPreferences {
+            exit_subset: epoch.map(|epoch| self.epochs.subset_in(user.id, epoch)),
+            lifetime: Some(policy.lifetime),
+            ..preferences.clone()
+        };
+        preferences.exclude.extend(self.drains.draining());
+        let mut built = None;
+        if !sibling_exits.is_empty() {
+            // Keep off the siblings' exits under the policy in force, but share one with them
+            // rather than fail for want of others
+            let relaxed = self.error_budget.relaxed();
+            let apart = CircuitPreferences {
+                exclude: [preferences.exclude.clone(), sibling_exits].concat(),
+                policy: self.error_budget.policy(&relaxed),
+                ..preferences.clone()
+            };
+            built = self.router.create_circuit_with(&apart).await.ok().map(|mut circuit| {
+                circuit.relaxed = relaxed;
+                circuit
+            });
+        }
+        let built = match built {
+            Some(circuit) => Ok(circuit),
+            None => self.build_circuit(&preferences).await,
+        };
+        let circuit = match built {
+            Ok(circuit) => circuit,
+            Err(e) => {
+                let report = CircuitBuildReport::from_error(&e, started.elapsed());
+                tracing::warn!("Circuit build failed: {:?}", report.failure);
+                self.circuit_failures.record(report);
+                self.events.emit(Event::CircuitBuildFailed);
+                return Err(CircuitUnavailable.into());
+            }
+        };
+        self.events.emit(Event::CircuitCreated {
+            circuit_id: circuit.id.clone(),
+        });
+        metrics::increment_counter!("darknode_circuits_built_total", "class" => class.label());
+        
+        // Store the circuit
+        let active_circuits = self.active_circuits.write().await;
+        let replaced = active_circuits.insert(
+            key,
+            ActiveCircuit {
+                user_id: user.id,
+                api_key: api_key.to_string(),
+                deadline: Deadline::after(circuit.lifetime()),
+                last_active: tokio::time::Instant::now(),
+                missed_pongs: 0,
+                epoch,
+                requests: 1,
+                concurrency: self.concurrency(class),
+                circuit: circuit.clone(),
+            },
+        );
+        let ended = replaced.map(|replaced| {
+            let (reason, label) = if replaced.deadline.is_expired() {
+                (CircuitEnd::Expired, "expired")
+            } else if policy.rotate_at_epoch && replaced.epoch != epoch {
+                (CircuitEnd::EpochEnded, "epoch")
+            } else if spent(&replaced) {
+                (CircuitEnd::Rotated, "requests")
+            } else {
+                // Another request for the same user built a circuit concurrently
+                (CircuitEnd::Rotated, "concurrent")
+            };
+            metrics::increment_counter!("darknode_circuit_rotations_total", "class" => class.label(), "reason" => label);
+            (replaced.circuit, reason)
+        });
+        metrics::gauge!("darknode_active_circuits", active_circuits.len() as f64);
+        drop(active_circuits);
+        if let Some((replaced, reason)) = ended {
+            self.retire(replaced, reason);
+        }
+        
+        Ok(circuit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::StoredUserManager;
+    
+    /// Builds circuits slowly enough for requests to build theirs at once, and notes those closed
+    #[derive(Default)]
+    struct SlowRouter {
+        closed: std::sync::Mutex<Vec<CircuitId>>,
+    }
+    
+    #[async_trait]
+    impl Router for SlowRouter {
+        async fn create_circuit(&self) -> Result<Circuit> {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let created_at = Timestamp::now();
+            Ok(Circuit {
+                id: CircuitId(Uuid::new_v4()),
+                entry_node: NodeId(Uuid::new_v4()),
+                routing_nodes: vec![NodeId(Uuid::new_v4())],
+                exit_node: NodeId(Uuid::new_v4()),
+                symmetric_keys: Vec::new(),
+                created_at,
+                expires_at: created_at + Duration::from_secs(600),
+                regions: Vec::new(),
+                protocol_version: crate::protocol::legacy_version(),
+                estimated_latency: None,
+                relaxed: Vec::new(),
+                exit_classes: None,
+            })
+        }
+        
+        async fn close_circuit(&self, circuit: &Circuit) -> Result<()> {
+            self.closed.lock().unwrap().push(circuit.id.clone());
+            Ok(())
+        }
+        
+        async fn send_request(&self, _ctx: &RequestContext, _circuit: &Circuit, _request: &[u8]) -> Result<Uuid> {
+            Ok(Uuid::new_v4())
+        }
+        
+        async fn receive_response(&self, _request_id: Uuid) -> Result<Vec<u8>> {
+            anyhow::bail!("no responses in this test")
+        }
+    }
+    
+    async fn service(router: Arc<SlowRouter>, capacity: CircuitCapacityConfig) -> (EntryNodeService, Arc<StoredUserManager>) {
+        let config = crate::config::EntryConfig {
+            circuits: capacity,
+            ..Default::default()
+        };
+        crate::fixtures::entry(router, &config).await
+    }
+    
+    fn mapping_on(network: Network) -> RpcMapping {
+        RpcMapping {
+            original_rpc: "https://api.devnet.solana.com".to_string(),
+            network,
+            ..crate::fixtures::mapping()
+        }
+    }
+    
+    fn pinned_to(region: &str) -> CircuitPreferences {
+        CircuitPreferences {
+            exit_region: Some(region.to_string()),
+            ..Default::default()
+        }
+    }
+    
+    #[tokio::test]
+    async fn a_plan_allowing_two_circuits_refuses_a_third_built_at_once() {
+        let (service, users) = service(Arc::new(SlowRouter::default()), CircuitCapacityConfig::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let plan = Plan {
+            max_circuits: 2,
+            ..Plan::default()
+        };
+        
+        // Each region is a circuit of its own, and all three are built at the same time
+        let preferences = [pinned_to("eu-west"), pinned_to("us-east"), pinned_to("ap-south")];
+        let built = futures::future::join_all(
+            preferences
+                .iter()
+                .map(|preferences| service.get_or_create_circuit(&user.api_key, &user, &plan, preferences)),
+        )
+        .await;
+        assert_eq!(built.iter().filter(|built| built.is_ok()).count(), 2);
+        let refused = built.into_iter().find_map(Result::err).unwrap();
+        assert!(matches!(
+            refused.downcast_ref::<QuotaExceeded>(),
+            Some(QuotaExceeded { cap: QuotaCap::Circuits, limit: 2, .. })
+        ));
+    }
+    
+    #[tokio::test]
+    async fn a_plan_allowing_three_requests_a_day_refuses_a_fourth_until_midnight() {
+        let router = Arc::new(crate::fixtures::StubRouter::new(|_| serde_json::json!({ "jsonrpc": "2.0", "result": 311_029_712 })));
+        let (service, users) = crate::fixtures::entry(router.clone(), &Default::default()).await;
+        let plan = Plan {
+            name: "three a day".to_string(),
+            daily_request_cap: 3,
+            ..Plan::default()
+        };
+        users.create_plan(plan.clone()).await.unwrap();
+        let capped = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        users.set_user_plan(capped.id, plan.id).await.unwrap();
+        let other = users.create_user("9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin").await.unwrap();
+        let request = serde_json::to_vec(&serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" })).unwrap();
+        
+        for _ in 0..3 {
+            service.handle_request(RequestContext::new(&capped.api_key), &request).await.unwrap();
+        }
+        let resets_at = next_utc_midnight(Timestamp::now());
+        let refused = service.handle_request(RequestContext::new(&capped.api_key), &request).await.unwrap_err();
+        let refused = refused.downcast_ref::<QuotaExceeded>().unwrap();
+        assert_eq!((refused.cap, refused.limit, refused.resets_at), (QuotaCap::DailyRequests, 3, Some(resets_at)));
+        assert_eq!(refused.to_string(), "daily request cap of 3 reached for plan 'three a day'");
+        assert_eq!(router.sent().len(), 3);
+        
+        // Other users' requests count against their own plan's cap
+        service.handle_request(RequestContext::new(&other.api_key), &request).await.unwrap();
+        assert_eq!(router.sent().len(), 4);
+    }
+    
+    #[tokio::test]
+    async fn a_plan_allowing_two_subscriptions_refuses_a_third_until_one_is_dropped() {
+        let (service, users) = service(Arc::new(SlowRouter::default()), CircuitCapacityConfig::default()).await;
+        let service = Arc::new(service);
+        let plan = Plan {
+            name: "two subscriptions".to_string(),
+            max_subscriptions: 2,
+            ..Plan::default()
+        };
+        users.create_plan(plan.clone()).await.unwrap();
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        users.set_user_plan(user.id, plan.id).await.unwrap();
+        let session = service.open_session(&user.api_key).await.unwrap();
+        let subscribe = |account: &str| {
+            let params = serde_json::json!([account]);
+            service.subscribe(&user.api_key, None, &session.token, "accountSubscribe", params)
+        };
+        
+        let first = subscribe("11111111111111111111111111111111").await.unwrap();
+        subscribe("SysvarC1ock11111111111111111111111111111111").await.unwrap();
+        let refused = subscribe("SysvarRent111111111111111111111111111111111").await.unwrap_err();
+        assert!(matches!(
+            refused.downcast_ref::<QuotaExceeded>(),
+            Some(QuotaExceeded { cap: QuotaCap::Subscriptions, limit: 2, resets_at: None, .. })
+        ));
+        
+        // Dropping a subscription frees its slot, while the refused one never held one
+        assert!(service.unsubscribe(user.id, &session.token, first));
+        subscribe("SysvarRent111111111111111111111111111111111").await.unwrap();
+        assert!(subscribe("SysvarRent111111111111111111111111111111111").await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn scoped_keys_subscribe_only_on_mappings_of_their_networks() {
+        let (service, users) = service(Arc::new(SlowRouter::default()), CircuitCapacityConfig::default()).await;
+        let service = Arc::new(service);
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let devnet = mapping_on(Network::Devnet);
+        let mainnet = mapping_on(Network::Mainnet);
+        users.add_rpc_mapping(user.id, devnet.clone()).await.unwrap();
+        users.add_rpc_mapping(user.id, mainnet.clone()).await.unwrap();
+        let key = users
+            .create_key(user.id, "dev", vec![scopes::Scope::Networks(vec![Network::Devnet])])
+            .await
+            .unwrap();
+        let session = service.open_session(&key.key).await.unwrap();
+        let params = serde_json::json!(["4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T"]);
+        
+        service
+            .subscribe(&key.key, Some(devnet.id), &session.token, "accountSubscribe", params.clone())
+            .await
+            .unwrap();
+        let refused = service
+            .subscribe(&key.key, Some(mainnet.id), &session.token, "accountSubscribe", params)
+            .await
+            .unwrap_err();
+        assert_eq!(refused.downcast_ref::<scopes::ScopeError>(), Some(&scopes::ScopeError::Network(Network::Mainnet)));
+    }
+    
+    #[tokio::test]
+    async fn a_devnet_scoped_key_is_served_on_devnet_mappings_and_refused_on_mainnet() {
+        let router = Arc::new(crate::fixtures::StubRouter::new(|_| serde_json::json!({ "jsonrpc": "2.0", "result": 311_029_712 })));
+        let (service, users) = crate::fixtures::entry(router.clone(), &Default::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let devnet = mapping_on(Network::Devnet);
+        let mainnet = mapping_on(Network::Mainnet);
+        users.add_rpc_mapping(user.id, devnet.clone()).await.unwrap();
+        users.add_rpc_mapping(user.id, mainnet.clone()).await.unwrap();
+        let key = users
+            .create_key(user.id, "dev", vec![scopes::Scope::Networks(vec![Network::Devnet])])
+            .await
+            .unwrap();
+        let request = serde_json::to_vec(&serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" })).unwrap();
+        
+        let ctx = RequestContext::new(&key.key).with_mapping(Some(devnet.id));
+        service.handle_request(ctx, &request).await.unwrap();
+        let sent = router.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1.network, Network::Devnet);
+        
+        let ctx = RequestContext::new(&key.key).with_mapping(Some(mainnet.id));
+        let refused = service.handle_request(ctx, &request).await.unwrap_err();
+        assert_eq!(refused.downcast_ref::<scopes::ScopeError>(), Some(&scopes::ScopeError::Network(Network::Mainnet)));
+        assert_eq!(router.sent().len(), 1);
+        
+        // The account's own key is limited by nothing but the plan
+        let ctx = RequestContext::new(&user.api_key).with_mapping(Some(mainnet.id));
+        service.handle_request(ctx, &request).await.unwrap();
+        assert_eq!(router.sent()[1].1.network, Network::Mainnet);
+    }
+    
+    #[tokio::test]
+    async fn a_read_only_key_is_refused_send_transaction_before_any_circuit_is_built() {
+        let router = Arc::new(crate::fixtures::StubRouter::new(|_| serde_json::json!({ "jsonrpc": "2.0", "result": "sent" })));
+        let (service, users) = crate::fixtures::entry(router.clone(), &Default::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let reads = vec![MethodClass::LightRead, MethodClass::Read, MethodClass::HeavyRead];
+        let key = users
+            .create_key(user.id, "read only", vec![scopes::Scope::MethodClasses(reads)])
+            .await
+            .unwrap();
+        let request = |method: &str| {
+            serde_json::to_vec(&serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": ["AQAB"] })).unwrap()
+        };
+        
+        let refused = service
+            .handle_request(RequestContext::new(&key.key), &request("sendTransaction"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            refused.downcast_ref::<scopes::ScopeError>(),
+            Some(&scopes::ScopeError::MethodClass(MethodClass::Write))
+        );
+        assert!(router.circuits().is_empty());
+        assert!(router.sent().is_empty());
+        
+        service.handle_request(RequestContext::new(&key.key), &request("getBalance")).await.unwrap();
+        assert_eq!(router.circuits().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn a_keys_share_the_account_cap_refuses_is_given_back() {
+        let router = Arc::new(crate::fixtures::StubRouter::new(|_| serde_json::json!({ "jsonrpc": "2.0", "result": 311_029_712 })));
+        let (service, users) = crate::fixtures::entry(router.clone(), &Default::default()).await;
+        let small = Plan {
+            name: "two a day".to_string(),
+            daily_request_cap: 2,
+            ..Plan::default()
+        };
+        let large = Plan {
+            name: "four a day".to_string(),
+            daily_request_cap: 4,
+            ..Plan::default()
+        };
+        users.create_plan(small.clone()).await.unwrap();
+        users.create_plan(large.clone()).await.unwrap();
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        users.set_user_plan(user.id, small.id).await.unwrap();
+        let key = users
+            .create_key(user.id, "half", vec![scopes::Scope::RateMultiplier(0.5)])
+            .await
+            .unwrap();
+        let request = serde_json::to_vec(&serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" })).unwrap();
+        
+        for _ in 0..2 {
+            service.handle_request(RequestContext::new(&user.api_key), &request).await.unwrap();
+        }
+        let refused = service.handle_request(RequestContext::new(&key.key), &request).await.unwrap_err();
+        assert!(matches!(
+            refused.downcast_ref::<QuotaExceeded>(),
+            Some(QuotaExceeded { cap: QuotaCap::DailyRequests, limit: 2, .. })
+        ));
+        
+        // On the larger plan the key's share is two requests, none of them spent by the refusal
+        users.set_user_plan(user.id, large.id).await.unwrap();
+        for _ in 0..2 {
+            service.handle_request(RequestContext::new(&key.key), &request).await.unwrap();
+        }
+        assert_eq!(router.sent().len(), 4);
+    }
+    
+    #[tokio::test]
+    async fn at_the_node_cap_an_idle_circuit_is_evicted_and_torn_down() {
+        let router = Arc::new(SlowRouter::default());
+        let capacity = CircuitCapacityConfig {
+            max_active_circuits: 1,
+            idle_after: Duration::ZERO,
+        };
+        let (service, users) = service(router.clone(), capacity).await;
+        let plan = Plan::default();
+        let first = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let second = users.create_user("9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin").await.unwrap();
+        
+        let evicted = service
+            .get_or_create_circuit(&first.api_key, &first, &plan, &pinned_to("eu-west"))
+            .await
+            .unwrap();
+        service
+            .get_or_create_circuit(&second.api_key, &second, &plan, &pinned_to("eu-west"))
+            .await
+            .unwrap();
+        assert_eq!(*router.closed.lock().unwrap(), vec![evicted.id]);
+    }
+    
+    #[tokio::test]
+    async fn a_rotated_circuit_is_torn_down_and_rotating_again_at_once_is_refused() {
+        let router = Arc::new(SlowRouter::default());
+        let (service, users) = service(router.clone(), CircuitCapacityConfig::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let old = service
+            .get_or_create_circuit(&user.api_key, &user, &Plan::default(), &CircuitPreferences::default())
+            .await
+            .unwrap();
+        
+        service.rotate_circuit(&user.api_key, None).await.unwrap();
+        let refused = service.rotate_circuit(&user.api_key, None).await.unwrap_err();
+        assert!(refused.downcast_ref::<RotationThrottled>().is_some());
+        
+        // Nothing is in flight on the old circuit, so its teardown follows at once
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(*router.closed.lock().unwrap(), vec![old.id]);
+    }
+    
+    #[tokio::test]
+    async fn a_circuit_of_the_previous_epoch_drains_before_it_is_torn_down() {
+        let router = Arc::new(SlowRouter::default());
+        let (service, users) = service(router.clone(), CircuitCapacityConfig::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let preferences = CircuitPreferences::default();
+        let old = service
+            .get_or_create_circuit(&user.api_key, &user, &Plan::default(), &preferences)
+            .await
+            .unwrap();
+        let in_flight = service.drains.enter(&old.id);
+        let mut events = service.events().subscribe();
+        
+        let epochs = service.epochs();
+        epochs.observe(epochs.current().unwrap() + 1);
+        let new = service
+            .get_or_create_circuit(&user.api_key, &user, &Plan::default(), &preferences)
+            .await
+            .unwrap();
+        assert_ne!(new.id, old.id);
+        assert!(std::iter::from_fn(|| events.try_recv().ok()).any(|event| matches!(
+            event,
+            Event::CircuitDestroyed { circuit_id, reason: CircuitEnd::EpochEnded } if circuit_id == old.id
+        )));
+        
+        // The request still on the old circuit keeps it up until it is done
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(router.closed.lock().unwrap().is_empty());
+        drop(in_flight);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(*router.closed.lock().unwrap(), vec![old.id]);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_circuit_is_replaced_once_the_clock_passes_its_lifetime() {
+        let (service, users) = service(Arc::new(SlowRouter::default()), CircuitCapacityConfig::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let preferences = CircuitPreferences::default();
+        let first = service
+            .get_or_create_circuit(&user.api_key, &user, &Plan::default(), &preferences)
+            .await
+            .unwrap();
+        
+        tokio::time::advance(first.lifetime() - Duration::from_secs(1)).await;
+        let kept = service
+            .get_or_create_circuit(&user.api_key, &user, &Plan::default(), &preferences)
+            .await
+            .unwrap();
+        assert_eq!(kept.id, first.id);
+        
+        tokio::time::advance(Duration::from_secs(2)).await;
+        let replaced = service
+            .get_or_create_circuit(&user.api_key, &user, &Plan::default(), &preferences)
+            .await
+            .unwrap();
+        assert_ne!(replaced.id, first.id);
+    }
+    
+    #[tokio::test]
+    async fn a_circuit_a_hop_no_longer_holds_is_torn_down_even_when_the_request_isnt_sent_again() {
+        let router = Arc::new(SlowRouter::default());
+        let (service, users) = service(router.clone(), CircuitCapacityConfig::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let preferences = CircuitPreferences::default();
+        let reclaimed = service
+            .get_or_create_circuit(&user.api_key, &user, &Plan::default(), &preferences)
+            .await
+            .unwrap();
+        
+        // The exit reclaimed the circuit, and there is nothing to send again
+        let unknown = replay::HopFailure {
+            node: Some(reclaimed.exit_node.clone()),
+            kind: HopFailureKind::CircuitUnknown,
+        };
+        let ctx = RequestContext::new(&user.api_key);
+        let deadline = Deadline::after(Duration::from_secs(10));
+        let Err(failed) = service.replay(&ctx, &mut None, &reclaimed.id, deadline, unknown.clone().into()).await else {
+            panic!("sent a request it had nothing of again");
+        };
+        assert_eq!(failed.downcast_ref::<replay::HopFailure>(), Some(&unknown));
+        
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(*router.closed.lock().unwrap(), vec![reclaimed.id.clone()]);
+        let rebuilt = service
+            .get_or_create_circuit(&user.api_key, &user, &Plan::default(), &preferences)
+            .await
+            .unwrap();
+        assert_ne!(rebuilt.id, reclaimed.id);
+    }
+    
+    #[tokio::test]
+    async fn with_no_exit_nodes_only_mappings_opted_into_the_fallback_are_served_degraded() {
+        use crate::billing::{DailyUsage, DAILY_USAGE};
+        use crate::fallback::{FallbackConfig, FallbackProvider};
+        use crate::storage::{Collection, MemoryStorage};
+        
+        let node_manager = Arc::new(crate::impls::StoredNodeManager::new(Arc::new(MemoryStorage::new())));
+        for node in [crate::fixtures::node(&[NodeRole::Entry]), crate::fixtures::node(&[NodeRole::Routing])] {
+            node_manager.register_node(node).await.unwrap();
+        }
+        let router = Arc::new(crate::impls::RouterImpl::new(node_manager, Arc::new(crate::impls::CryptoImpl::new())));
+        let provider = crate::fixtures::serving(|request| async move {
+            serde_json::json!({ "jsonrpc": "2.0", "id": request["id"], "result": 311_029_712 })
+        });
+        let proxy = DirectProxy::new(FallbackConfig {
+            providers: vec![FallbackProvider {
+                url: provider.url,
+                chain: Chain::Solana,
+                network: Network::Mainnet,
+            }],
+            ..FallbackConfig::default()
+        })
+        .unwrap();
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(MemoryStorage::new());
+        let meter = Arc::new(UsageMeter::new(storage.clone()));
+        let (service, users) = crate::fixtures::entry(router, &Default::default()).await;
+        let service = service.with_fallback(proxy).with_meter(meter.clone());
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let opted_in = RpcMapping {
+            fallback_mode: Some(FallbackMode::DirectProxy),
+            ..crate::fixtures::mapping()
+        };
+        let strict = crate::fixtures::mapping();
+        users.add_rpc_mapping(user.id, opted_in.clone()).await.unwrap();
+        users.add_rpc_mapping(user.id, strict.clone()).await.unwrap();
+        let request = serde_json::to_vec(&serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" })).unwrap();
+        
+        let ctx = RequestContext::new(&user.api_key).with_mapping(Some(opted_in.id));
+        let response: serde_json::Value = serde_json::from_slice(&service.handle_request(ctx, &request).await.unwrap()).unwrap();
+        assert_eq!(response["result"], 311_029_712);
+        assert!(fallback::is_direct(&response[crate::methods::EXTENSION_KEY]));
+        meter.flush().await.unwrap();
+        let usage = Collection::<DailyUsage>::new(storage, DAILY_USAGE).scan(&user.id.to_string()).await.unwrap();
+        assert_eq!(usage.len(), 1);
+        assert_eq!((usage[0].1.requests.values().sum::<u64>(), usage[0].1.degraded_requests), (1, 1));
+        
+        // Without the opt-in the request fails as ever, for want of exits
+        let ctx = RequestContext::new(&user.api_key).with_mapping(Some(strict.id));
+        let failed = service.handle_request(ctx, &request).await.unwrap_err();
+        assert!(failed.is::<CircuitUnavailable>(), "{:?}", failed);
+        let report = service.circuit_failures().into_iter().next().unwrap();
+        assert!(
+            matches!(report.failure, CircuitBuildFailure::NoAvailableNodes { role: NodeRole::Exit }),
+            "{:?}",
+            report.failure
+        );
+    }
+    
+    #[tokio::test]
+    async fn the_policy_relaxes_while_the_network_is_too_small_and_tightens_when_it_recovers() {
+        use crate::relaxation::{CircuitPolicy, Relaxation};
+        
+        let node_manager = Arc::new(crate::impls::StoredNodeManager::new(Arc::new(crate::storage::MemoryStorage::new())));
+        let in_region = |roles: &[NodeRole], region: &str| Node {
+            region: region.to_string(),
+            ..crate::fixtures::node(roles)
+        };
+        let relays = [in_region(&[NodeRole::Routing], "eu-west"), in_region(&[NodeRole::Routing], "ap-south")];
+        let ends = [in_region(&[NodeRole::Entry], "us-east"), in_region(&[NodeRole::Exit], "sa-east")];
+        for node in ends.into_iter().chain(relays.clone()) {
+            node_manager.register_node(node).await.unwrap();
+        }
+        let router = Arc::new(crate::impls::RouterImpl::new(node_manager.clone(), Arc::new(crate::impls::CryptoImpl::new())));
+        let relaxation = RelaxationConfig {
+            policy: CircuitPolicy {
+                routing_hops: 2,
+                min_routing_hops: 2,
+                region_diversity: true,
+            },
+            hop_floor: 0,
+            min_builds: 2,
+            ..Default::default()
+        };
+        let config = crate::config::EntryConfig {
+            relaxation: relaxation.clone(),
+            ..Default::default()
+        };
+        let (service, _) = crate::fixtures::entry(router, &config).await;
+        let preferences = CircuitPreferences::default();
+        
+        // The floor of no hops at all still leaves one
+        assert_eq!(relaxation.policy(&[Relaxation::RoutingHops]).min_routing_hops, 1);
+        
+        // A relay leaves, so two routing hops can't be had and the policy gives way step by step
+        node_manager.update_node_status(&relays[1].id, NodeStatus::Offline).await.unwrap();
+        let mut built = None;
+        for _ in 0..20 {
+            if let Ok(circuit) = service.build_circuit(&preferences).await {
+                built = Some(circuit);
+                break;
+            }
+        }
+        let relaxed = built.expect("no circuit built under the relaxed policy");
+        assert_eq!(relaxed.relaxed, vec![Relaxation::RegionDiversity, Relaxation::RoutingHops]);
+        assert_eq!(relaxed.routing_nodes, vec![relays[0].id.clone()]);
+        
+        // Once it is back, circuits are built under the strict policy again
+        node_manager.update_node_status(&relays[1].id, NodeStatus::Online).await.unwrap();
+        let mut strict = false;
+        for _ in 0..20 {
+            let circuit = service.build_circuit(&preferences).await.unwrap();
+            assert!(circuit.relaxed.len() < 2);
+            if circuit.relaxed.is_empty() {
+                assert_eq!(circuit.routing_nodes.len(), 2);
+                strict = true;
+                break;
+            }
+        }
+        assert!(strict, "the strict policy was never restored");
+    }
+}
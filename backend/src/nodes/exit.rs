@@ -0,0 +1,1368 @@
+//! Exit node implementation
+
+use crate::*;
+use crate::traits::*;
+use crate::types::*;
+
+use crate::accounting::AccountingConfig;
+use crate::attribution::{self, Attestor};
+use crate::audit::{AuditLog, AuditRecord};
+use crate::breaker::{BreakerConfig, BreakerRejected, ProviderBreakers};
+use crate::budget::{BudgetConfig, ExitBudget};
+use crate::cache::{self, CacheConfig, Lookup, ResponseCache};
+use crate::capabilities::{self, CapabilityError};
+use crate::chains::ChainError;
+use crate::circuit_class::CircuitClass;
+use crate::clock::{self, Clock, SystemClock};
+use crate::egress::EgressConfig;
+use crate::dns::ProviderResolver;
+use crate::events::{ActivitySubscriber, CircuitEnd, Event, EventBus, RequestOutcome};
+use crate::flags::{self, FeatureFlags};
+use crate::hedge::{HedgeBudget, HedgeConfig};
+use crate::heartbeat::ActivityCounters;
+use crate::identity::NodeIdentity;
+use crate::keepalive;
+use crate::maintenance;
+use crate::membership::{CircuitKeyStore, MembershipConfig, PeerGuard, UnknownCircuit};
+use crate::methods;
+use crate::multiplex::{self, MultiplexConfig, Protocol, ProviderProtocols};
+use crate::normalize::Normalizer;
+use crate::pools::{self, PoolConfig};
+use crate::preflight;
+use crate::protocol;
+use crate::provider_errors;
+use crate::quorum::{self, QuorumError};
+use crate::reclaim::{self, ReclaimConfig, UpstreamSubscription};
+use crate::scoring;
+use crate::relay::{self, RelayConfig, RelayStatus, StatusSink};
+use crate::resources::{LoadShedding, Pressure, ResourceConfig, ResourceGuard, Shed};
+use crate::shaping::{ShapingConfig, TrafficShaper};
+use crate::telemetry;
+use crate::timeouts::{MethodClass, TimedOut, TimeoutBudget};
+use crate::timing;
+use crate::traffic;
+use crate::transport::{self, CircuitDestroy, CircuitExtend, ExtendLayer};
+use crate::upstream::{self, ProviderAbuse, UpstreamLimits};
+use crate::warmup::{self, ConnectionTracker, WarmupConfig};
+use tracing::Instrument;
+
+/// Timeout applied to upstream provider requests when the entry node sent no budget
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest any provider request may take, whatever budget the entry node sent
+const MAX_PROVIDER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How far apart context slots may be for quorum responses to still agree
+const QUORUM_SLOT_TOLERANCE: u64 = 150;
+
+/// The exit node service
+pub struct ExitNodeService {
+    node_id: NodeId,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    rpc_manager: Arc<dyn RpcManager + Send + Sync>,
+    rpc_clients: Arc<RwLock<dashmap::DashMap<Uuid, reqwest::Client>>>,
+    resolver: ProviderResolver,
+    counters: Arc<ActivityCounters>,
+    audit: AuditLog,
+    hedge: HedgeBudget,
+    pool: PoolConfig,
+    relay: RelayConfig,
+    circuits: CircuitKeyStore,
+    peers: PeerGuard,
+    affinity: dashmap::DashMap<CircuitId, Uuid>,
+    subscriptions: dashmap::DashMap<CircuitId, Vec<UpstreamSubscription>>,
+    reclaim: ReclaimConfig,
+    events: Arc<EventBus>,
+    accounting: AccountingConfig,
+    warmup: WarmupConfig,
+    connections: ConnectionTracker,
+    limits: UpstreamLimits,
+    cache: ResponseCache,
+    shaper: Arc<TrafficShaper>,
+    protocols: ProviderProtocols,
+    normalizer: Normalizer,
+    breakers: ProviderBreakers,
+    budget: ExitBudget,
+    resources: Arc<ResourceGuard>,
+    egress: EgressConfig,
+    attestor: Option<Attestor>,
+    flags: FeatureFlags,
+    identity: Option<Arc<NodeIdentity>>,
+    provider_roots: Vec<reqwest::Certificate>,
+    clock: Arc<dyn Clock>,
+}
+
+/// An event bus whose only subscriber counts activity into `counters`
+fn activity_bus(counters: &Arc<ActivityCounters>) -> Arc<EventBus> {
+    let events = Arc::new(EventBus::new());
+    events.register(Arc::new(ActivitySubscriber::new(counters.clone())));
+    events
+}
+
+/// How the features of an exit node are configured, each at its default unless set
+#[derive(Debug, Clone, Default)]
+pub struct ExitNodeConfig {
+    /// When slow reads are hedged to a second provider
+    pub hedge: HedgeConfig,
+    /// The provider pool the node serves from
+    pub pool: PoolConfig,
+    /// How transactions clients ask to have relayed are rebroadcast
+    pub relay: RelayConfig,
+    /// Limits on circuits served and peers sending forged requests
+    pub membership: MembershipConfig,
+    /// How the work the node carries is counted and reconciled
+    pub accounting: AccountingConfig,
+    /// When connections to providers are opened and refreshed
+    pub warmup: WarmupConfig,
+    /// What the node accepts in provider responses
+    pub limits: UpstreamLimits,
+    /// Which provider responses are cached, and for how long
+    pub cache: CacheConfig,
+    /// Whether responses are held back to decorrelate them from requests
+    pub shaping: ShapingConfig,
+    /// How requests to providers share connections
+    pub multiplex: MultiplexConfig,
+    /// When providers that keep failing stop being sent requests
+    pub breaker: BreakerConfig,
+    /// The requests the node may send its providers, see [`crate::budget`]
+    pub budget: BudgetConfig,
+}
+
+impl ExitNodeService {
+    /// Create an exit node service that reaches providers through `resolver`
+    pub fn new(
+        node_id: NodeId,
+        crypto: Arc<dyn Crypto + Send + Sync>,
+        rpc_manager: Arc<dyn RpcManager + Send + Sync>,
+        resolver: ProviderResolver,
+        audit: AuditLog,
+        config: ExitNodeConfig,
+    ) -> Self {
+        let ExitNodeConfig {
+            hedge,
+            pool,
+            relay,
+            membership,
+            accounting,
+            warmup,
+            limits,
+            cache,
+            shaping,
+            multiplex,
+            breaker,
+            budget,
+        } = config;
+        let counters = Arc::new(ActivityCounters::new());
+        let events = activity_bus(&counters);
+        Self {
+            node_id,
+            crypto,
+            rpc_manager,
+            rpc_clients: Arc::new(RwLock::new(dashmap::DashMap::new())),
+            resolver,
+            breakers: ProviderBreakers::new(breaker, events.clone()),
+            events,
+            counters,
+            audit,
+            hedge: HedgeBudget::new(hedge),
+            pool,
+            relay,
+            circuits: CircuitKeyStore::new(&membership),
+            peers: PeerGuard::new(membership),
+            affinity: dashmap::DashMap::new(),
+            subscriptions: dashmap::DashMap::new(),
+            reclaim: ReclaimConfig::default(),
+            accounting,
+            connections: ConnectionTracker::new(warmup.clone()),
+            warmup,
+            limits,
+            cache: ResponseCache::new(cache),
+            shaper: Arc::new(TrafficShaper::new(shaping)),
+            protocols: ProviderProtocols::new(multiplex),
+            normalizer: Normalizer::new(),
+            budget: ExitBudget::new(budget, Timestamp::now()),
+            resources: Arc::new(ResourceGuard::new(ResourceConfig::default())),
+            egress: EgressConfig::default(),
+            attestor: None,
+            flags: FeatureFlags::new(),
+            identity: None,
+            provider_roots: Vec::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+    
+    /// Stamp audit records, attestations and receipts with the time on `clock` rather than the system's
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    
+    /// Release responses back into circuits on the shaping ticks, until the task is dropped
+    pub async fn run_shaping(self: Arc<Self>) {
+        self.shaper.clone().run().await;
+    }
+    
+    /// The HTTP version each provider reached so far is spoken to over
+    pub fn provider_protocols(&self) -> Vec<(Uuid, Protocol)> {
+        self.protocols.snapshot()
+    }
+    
+    /// Audit records of the provider responses served under a trace token
+    pub fn audit_trail(&self, trace_token: &str) -> Vec<AuditRecord> {
+        self.audit.lookup(trace_token, self.clock.now())
+    }
+    
+    /// Whether `response` is the body recorded in an audit record
+    pub fn audit_matches(&self, record: &AuditRecord, response: &[u8]) -> bool {
+        self.audit.matches(record, response)
+    }
+    
+    /// Activity counters reported in this node's heartbeats
+    pub fn counters(&self) -> Arc<ActivityCounters> {
+        self.counters.clone()
+    }
+    
+    /// Count activity into `counters`, shared with the other roles of the same process
+    ///
+    /// This replaces the event bus, so call it before registering subscribers on [`Self::events`].
+    pub fn with_counters(mut self, counters: Arc<ActivityCounters>) -> Self {
+        self.events = activity_bus(&counters);
+        self.breakers = self.breakers.with_events(self.events.clone());
+        self.counters = counters;
+        self
+    }
+    
+    /// The bus this service emits its events on, for registering further subscribers
+    pub fn events(&self) -> Arc<EventBus> {
+        self.events.clone()
+    }
+    
+    /// Refuse new circuits while `guard` finds the node short of resources, see [`crate::resources`]
+    pub fn with_resource_guard(mut self, guard: Arc<ResourceGuard>) -> Self {
+        self.resources = guard;
+        self
+    }
+    
+    /// Serve only the method classes `config` lists, see [`crate::egress`]
+    pub fn with_egress(mut self, config: EgressConfig) -> Self {
+        self.egress = config;
+        self
+    }
+    
+    /// Attest the class of provider answering requests that ask for it, see [`crate::attribution`]
+    pub fn with_attestor(mut self, attestor: Attestor) -> Self {
+        self.attestor = Some(attestor);
+        self
+    }
+    
+    /// Open circuit handshakes with the keys of `identity`, see [`crate::transport`]
+    pub fn with_identity(mut self, identity: Arc<NodeIdentity>) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+    
+    /// Turn features off while `flags` say, see [`crate::flags`]
+    ///
+    /// Rebuilds the shaper, so call it before [`Self::run_shaping`].
+    pub fn with_flags(mut self, flags: FeatureFlags) -> Self {
+        self.shaper = Arc::new(TrafficShaper::new(self.shaper.config().clone()).with_flags(flags.clone()));
+        self.flags = flags;
+        self
+    }
+    
+    /// Trust `roots` for provider TLS as well as the system's roots, for providers behind a private CA
+    pub fn with_provider_roots(mut self, roots: Vec<reqwest::Certificate>) -> Self {
+        self.provider_roots = roots;
+        self
+    }
+    
+    /// Reclaim circuits abandoned by their entry node as `config` has it, see [`crate::reclaim`]
+    pub fn with_reclaim(mut self, config: ReclaimConfig) -> Self {
+        self.circuits = self.circuits.with_reclaim(config.clone());
+        self.reclaim = config;
+        self
+    }
+    
+    /// Get the HTTP client for a provider, creating it on first use
+    ///
+    /// Clients resolve provider hosts through the node's [`ProviderResolver`], which also
+    /// enforces the egress policy on the addresses the connection will use. Provider URLs
+    /// naming an address, which no resolver sees, are checked here.
+    async fn client_for(&self, provider: &RpcProvider) -> Result<reqwest::Client> {
+        self.resolver.check_url(&provider.url)?;
+        let rpc_clients = self.rpc_clients.read().await;
+        if let Some(client) = rpc_clients.get(&provider.id) {
+            return Ok(client.clone());
+        }
+        
+        let builder = reqwest::Client::builder()
+            .dns_resolver(Arc::new(self.resolver.clone()))
+            .redirect(self.resolver.redirect_policy())
+            .timeout(MAX_PROVIDER_TIMEOUT)
+            .pool_idle_timeout(self.warmup.pool_idle_timeout)
+            .tcp_keepalive(self.warmup.keepalive_interval);
+        let builder = self
+            .provider_roots
+            .iter()
+            .fold(builder, |builder, root| builder.add_root_certificate(root.clone()));
+        let client = self.protocols.configure(builder, provider.id, &provider.url).build()?;
+        // Requests racing to build the first client all use the one kept, and its connections
+        Ok(rpc_clients.entry(provider.id).or_insert(client).clone())
+    }
+    
+    /// Open connections to the providers this node may use that have none, or refresh them
+    ///
+    /// Clients of providers no longer active, or draining for maintenance, are dropped.
+    /// Returns how many providers were probed.
+    pub async fn warm_up(&self) -> Result<usize> {
+        let active = self.rpc_manager.get_active_providers().await?;
+        let now = self.clock.now();
+        let providers: Vec<RpcProvider> = pools::usable(active, &self.pool)
+            .into_iter()
+            .filter(|provider| !maintenance::draining(provider, now))
+            .collect();
+        let providers = pools::select(providers, &self.pool).unwrap_or_default();
+        let ids: Vec<Uuid> = providers.iter().map(|provider| provider.id).collect();
+        self.rpc_clients.read().await.retain(|id, _| ids.contains(id));
+        self.connections.retain(&ids);
+        self.protocols.retain(&ids);
+        
+        let now = std::time::Instant::now();
+        let stale: Vec<&RpcProvider> = providers
+            .iter()
+            .filter(|provider| self.connections.needs_warming(provider.id, now))
+            .collect();
+        futures::future::join_all(stale.iter().map(|provider| async move {
+            let client = self.client_for(provider).await?;
+            warmup::probe(&client, provider, self.warmup.probe_timeout).await?;
+            self.connections.touch(provider.id, std::time::Instant::now());
+            Ok::<_, anyhow::Error>(())
+        }))
+        .await
+        .into_iter()
+        .zip(&stale)
+        .filter_map(|(outcome, provider)| outcome.err().map(|e| (provider, e)))
+        .for_each(|(provider, e)| tracing::debug!("Failed to warm provider {}: {}", provider.id, e));
+        
+        metrics::gauge!("darknode_recently_used_providers", self.connections.recent(std::time::Instant::now()) as f64);
+        Ok(stale.len())
+    }
+    
+    /// Keep provider connections warm, starting now
+    ///
+    /// The provider list is re-read every sync interval, and providers new to it are
+    /// warmed at once; the others are refreshed once idle for the keepalive interval.
+    pub async fn run_warmup(self: Arc<Self>) {
+        if !self.warmup.enabled {
+            return;
+        }
+        let mut ticker = tokio::time::interval(self.warmup.sync_interval.min(self.warmup.keepalive_interval));
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.warm_up().await {
+                tracing::warn!("Failed to warm provider connections: {}", e);
+            }
+        }
+    }
+    
+    /// Serve a decrypted request from a provider and return the raw response body
+    pub async fn serve(self: &Arc<Self>, payload: &ExitPayload) -> Result<Vec<u8>> {
+        // Keepalive pings end here, they never reach a provider
+        if keepalive::is_ping(payload) {
+            return keepalive::pong(payload);
+        }
+        
+        let started = std::time::Instant::now();
+        let body = serde_json::to_vec(&payload.request)?;
+        let method = methods::method_name(&payload.request).unwrap_or_default();
+        
+        // Refuse methods this node's operator doesn't serve, whichever entry node sent them
+        self.egress.admit(method)?;
+        
+        let trace = match &payload.trace_token {
+            Some(trace) => trace.clone(),
+            None => Uuid::new_v4().simple().to_string(),
+        };
+        
+        // Nobody waits for the answer to a notification, so the provider's body is never read
+        if payload.notification {
+            let provider = self.pick_provider(payload).await?;
+            let _stream = self.protocols.acquire(provider.id).await;
+            let response = self.send(&provider, &body).await.map(|_| Vec::new());
+            self.complete(method, started, &response);
+            return response;
+        }
+        
+        // The entry node's budget for the request, anchored on this node's clock on arrival
+        let class = MethodClass::of(method);
+        let limit = payload.timeout.unwrap_or(PROVIDER_TIMEOUT).min(MAX_PROVIDER_TIMEOUT);
+        
+        // Cached reads are answered at once; the first request to find its entry stale also
+        // refreshes it in the background, and the first to find none fetches it while the
+        // others wait for it. Attested reads are always fetched from a provider.
+        let mut fill = None;
+        let cache_key = self
+            .cache
+            .key(payload)
+            .filter(|_| payload.quorum.map_or(true, |quorum| quorum <= 1) && !methods::is_mutating(method))
+            .filter(|_| !payload.attribution && self.flags.enabled(flags::CACHE));
+        if let Some(key) = &cache_key {
+            let mut lookup = self.cache.lookup(key, tokio::time::Instant::now());
+            if let Lookup::Pending(pending) = lookup {
+                let _ = tokio::time::timeout(limit, pending.wait()).await;
+                lookup = self.cache.lookup(key, tokio::time::Instant::now());
+            }
+            let cached = match lookup {
+                Lookup::Fresh(response) => Some(response),
+                Lookup::Stale { response, refresh } => {
+                    if refresh {
+                        let (service, key, payload) = (self.clone(), key.clone(), payload.clone());
+                        tokio::spawn(async move { service.refresh(&key, &payload).await });
+                    }
+                    Some(response)
+                }
+                Lookup::Miss(filling) => {
+                    fill = Some(filling);
+                    None
+                }
+                // Whoever fetched it before gave up, and someone else is at it already
+                Lookup::Pending(_) => None,
+            };
+            if let Some(response) = cached {
+                let response = Ok(serde_json::to_vec(&cache::respond(response, &payload.request["id"]))?);
+                self.complete(method, started, &response);
+                return response;
+            }
+        }
+        
+        let forwarded = async {
+            match payload.quorum {
+                // Writes are never fanned out, whatever the mapping asks for
+                Some(quorum) if quorum > 1 && !methods::is_mutating(method) && self.flags.enabled(flags::QUORUM_READS) => {
+                    self.forward_quorum(&body, quorum, payload, &trace).await
+                }
+                _ => match self.pick_provider(payload).await {
+                    Ok(provider) if payload.preflight && methods::is_mutating(method) => {
+                        self.forward_preflighted(&provider, payload, &body, &trace).await
+                    }
+                    Ok(provider) if self.hedge.enabled() && self.flags.enabled(flags::HEDGING) && !methods::is_mutating(method) => {
+                        self.forward_hedged(&provider, &body, method, payload, &trace).await
+                    }
+                    Ok(provider) => self.forward_audited(&provider, &body, &trace, payload).await,
+                    Err(e) => Err(e),
+                },
+            }
+        };
+        let response = tokio::time::timeout(limit, forwarded).await.unwrap_or_else(|_| {
+            Err(TimedOut {
+                budget: TimeoutBudget::Upstream,
+                class,
+                limit,
+            }
+            .record()
+            .into())
+        });
+        
+        self.complete(method, started, &response);
+        if let (Some(key), Ok(response)) = (&cache_key, &response) {
+            if let Ok(response) = serde_json::from_slice(response) {
+                self.cache.store(key, method, &response, tokio::time::Instant::now());
+            }
+        }
+        drop(fill);
+        
+        // A timeout is answered rather than failed, so the client learns which budget ran out,
+        // and so is a provider response refused for breaking the upstream limits
+        match response {
+            Err(e) => match (e.downcast_ref::<TimedOut>(), e.downcast_ref::<ProviderAbuse>()) {
+                (Some(timed_out), _) => Ok(serde_json::to_vec(&timed_out.response(&payload.request["id"]))?),
+                (_, Some(abuse)) => Ok(serde_json::to_vec(&abuse.response(&payload.request["id"]))?),
+                _ => Err(e),
+            },
+            response => response,
+        }
+    }
+    
+    /// Fetch a new response for the stale cache entry `key`, for a request like `payload`
+    async fn refresh(&self, key: &str, payload: &ExitPayload) {
+        let method = methods::method_name(&payload.request).unwrap_or_default();
+        let trace = Uuid::new_v4().simple().to_string();
+        let fetched = async {
+            let body = serde_json::to_vec(&payload.request)?;
+            let provider = self.pick_provider(payload).await?;
+            let response = self.forward_audited(&provider, &body, &trace, payload).await?;
+            Ok::<serde_json::Value, anyhow::Error>(serde_json::from_slice(&response)?)
+        };
+        let outcome = match tokio::time::timeout(PROVIDER_TIMEOUT, fetched).await {
+            Ok(Ok(response)) => {
+                self.cache.store(key, method, &response, tokio::time::Instant::now());
+                "success"
+            }
+            Ok(Err(e)) => {
+                tracing::debug!("Failed to refresh cached {} response: {}", method, e);
+                self.cache.abandon(key);
+                "failure"
+            }
+            Err(_) => {
+                self.cache.abandon(key);
+                "failure"
+            }
+        };
+        metrics::increment_counter!("darknode_cache_refreshes_total", "outcome" => outcome);
+    }
+    
+    /// Emit the completion of a request for `method` that started at `started`
+    fn complete(&self, method: &str, started: std::time::Instant, response: &Result<Vec<u8>>) {
+        let (outcome, size) = match response {
+            Ok(response) => (RequestOutcome::Success, response.len()),
+            Err(_) => (RequestOutcome::Failure, 0),
+        };
+        self.events.emit(Event::RequestCompleted {
+            method: traffic::method_label(method),
+            outcome,
+            latency: started.elapsed(),
+            size,
+        });
+    }
+    
+    /// Emit a request refused before it was served and pass the error through
+    fn reject(&self, err: anyhow::Error) -> anyhow::Error {
+        self.events.emit(Event::RequestCompleted {
+            method: traffic::OTHER_METHOD,
+            outcome: RequestOutcome::Rejected,
+            latency: Duration::ZERO,
+            size: 0,
+        });
+        err
+    }
+    
+    /// Serve a decrypted request as a stream of chunks, reporting progress ahead of the response
+    ///
+    /// Transactions the client asked to have relayed are answered with status notifications
+    /// followed by the final response; everything else is served by [`Self::serve`] as a
+    /// single chunk.
+    pub fn serve_stream(self: &Arc<Self>, payload: ExitPayload, request_id: Uuid) -> ResponseStream {
+        let (mut sink, chunks) = StatusSink::new(request_id);
+        let service = self.clone();
+        tokio::spawn(async move {
+            let relayed = service.relay.enabled
+                && payload.relay
+                && methods::method_name(&payload.request) == Some("sendTransaction");
+            let response = if relayed {
+                let started = std::time::Instant::now();
+                let response = service.relay(&payload, &mut sink).await;
+                service.complete("sendTransaction", started, &response);
+                response
+            } else {
+                service.serve(&payload).await
+            };
+            match response {
+                Ok(response) => sink.finish(response),
+                Err(e) => sink.fail(e),
+            }
+        });
+        Box::pin(chunks)
+    }
+    
+    /// Broadcast a transaction and keep rebroadcasting it until it settles or the relay
+    /// deadline passes, moving on to the next provider whenever one rejects it
+    async fn relay(&self, payload: &ExitPayload, sink: &mut StatusSink) -> Result<Vec<u8>> {
+        let request = &payload.request;
+        let providers = self.candidates(payload).await?;
+        let trace = match &payload.trace_token {
+            Some(trace) => trace.clone(),
+            None => Uuid::new_v4().simple().to_string(),
+        };
+        
+        if payload.preflight {
+            if let Some(rejection) = self.preflight_rejection(&providers[0], request).await? {
+                return Ok(serde_json::to_vec(&rejection)?);
+            }
+        }
+        
+        // Fetched just before the first broadcast, for the wallet to re-sign against on expiry
+        let blockhash = self.latest_blockhash(&providers[0]).await;
+        let body = serde_json::to_vec(request)?;
+        let deadline = tokio::time::Instant::now() + self.relay.deadline;
+        let mut provider = 0;
+        let mut attempt = 0;
+        let mut accepted: Option<(String, Vec<u8>)> = None;
+        let mut rejected: Option<Result<Vec<u8>>> = None;
+        
+        let outcome = loop {
+            // Check whether an earlier broadcast landed before sending it again
+            if let Some((signature, _)) = &accepted {
+                if let Some(status) = self.signature_status(&providers[provider], signature).await {
+                    break status;
+                }
+            }
+            if tokio::time::Instant::now() >= deadline {
+                break RelayStatus::Expired;
+            }
+            
+            attempt += 1;
+            if attempt > 1 {
+                metrics::increment_counter!("darknode_relay_rebroadcasts_total");
+            }
+            let response = self.forward_audited(&providers[provider], &body, &trace, payload).await;
+            match response.as_deref().ok().and_then(relay::signature) {
+                Some(signature) => {
+                    sink.notify(&relay::notification(request, RelayStatus::Broadcast, attempt, Some(&signature), blockhash.as_ref()));
+                    if accepted.is_none() {
+                        accepted = Some((signature, response?));
+                    }
+                }
+                None => {
+                    let signature = accepted.as_ref().map(|(signature, _)| signature.as_str());
+                    sink.notify(&relay::notification(request, RelayStatus::Rejected, attempt, signature, blockhash.as_ref()));
+                    provider = (provider + 1) % providers.len();
+                    rejected = Some(response);
+                }
+            }
+            
+            tokio::time::sleep_until(deadline.min(tokio::time::Instant::now() + self.relay.retry_interval)).await;
+        };
+        
+        metrics::increment_counter!("darknode_relay_outcomes_total", "status" => outcome.label());
+        let signature = accepted.as_ref().map(|(signature, _)| signature.as_str());
+        sink.notify(&relay::notification(request, outcome, attempt, signature, blockhash.as_ref()));
+        
+        let response = match accepted {
+            Some((_, response)) => response,
+            None => return rejected.unwrap_or_else(|| Err(anyhow::anyhow!("Transaction was never broadcast"))),
+        };
+        let mut response: serde_json::Value = serde_json::from_slice(&response)?;
+        methods::set_extension(
+            &mut response,
+            "relay",
+            serde_json::json!({
+                "status": outcome,
+                "attempts": attempt,
+            }),
+        );
+        Ok(serde_json::to_vec(&response)?)
+    }
+    
+    /// The latest blockhash according to `provider`, if it answers
+    async fn latest_blockhash(&self, provider: &RpcProvider) -> Option<serde_json::Value> {
+        let request = serde_json::to_vec(&relay::latest_blockhash_request()).ok()?;
+        let response = self.forward(provider, &request).await.ok()?;
+        relay::blockhash(&serde_json::from_slice(&response).ok()?)
+    }
+    
+    /// Whether a relayed transaction has settled according to `provider`
+    async fn signature_status(&self, provider: &RpcProvider, signature: &str) -> Option<RelayStatus> {
+        let request = serde_json::to_vec(&relay::status_request(signature)).ok()?;
+        let response = self.forward(provider, &request).await.ok()?;
+        relay::settled(&serde_json::from_slice(&response).ok()?, &self.relay.commitment)
+    }
+    
+    /// Send a read to `quorum` providers concurrently and return the majority answer
+    ///
+    /// Providers whose answers disagree with the majority are reported as misbehaving,
+    /// and the divergence is flagged in the response extension.
+    async fn forward_quorum(
+        &self,
+        body: &[u8],
+        quorum: u8,
+        payload: &ExitPayload,
+        trace: &str,
+    ) -> Result<Vec<u8>> {
+        let mut providers = self.candidates(payload).await?;
+        if providers.len() < quorum as usize {
+            return Err(QuorumError::NotEnoughProviders {
+                required: quorum,
+                available: providers.len(),
+            }
+            .into());
+        }
+        providers.truncate(quorum as usize);
+        
+        let responses: Vec<Option<serde_json::Value>> =
+            futures::future::join_all(
+                providers
+                    .iter()
+                    .map(|provider| self.forward_audited(provider, body, trace, payload)),
+            )
+                .await
+                .into_iter()
+                .map(|response| response.ok().and_then(|bytes| serde_json::from_slice(&bytes).ok()))
+                .collect();
+        
+        let groups = quorum::group(&responses, QUORUM_SLOT_TOLERANCE);
+        let required = quorum::majority(providers.len());
+        let winners = match groups.first() {
+            Some(winners) if winners.len() >= required => winners,
+            _ => {
+                return Err(QuorumError::NoMajority {
+                    required,
+                    queried: providers.len(),
+                }
+                .into())
+            }
+        };
+        
+        // Penalize every provider that answered but disagreed with the majority
+        for minority in groups.iter().skip(1).flatten() {
+            let provider = &providers[*minority];
+            tracing::warn!("Provider {} disagreed with the quorum majority", provider.id);
+            // The majority answer stands whether or not the report lands
+            if let Err(report) = self
+                .rpc_manager
+                .record_provider_misbehavior(provider.id, "quorum divergence")
+                .await
+            {
+                tracing::warn!("Failed to report provider {}: {}", provider.id, report);
+            }
+        }
+        
+        let mut response = responses[winners[0]].clone().expect("grouped responses are present");
+        let divergent = groups.len() > 1;
+        if divergent {
+            methods::set_extension(
+                &mut response,
+                "quorum",
+                serde_json::json!({
+                    "queried": providers.len(),
+                    "agreeing": winners.len(),
+                    "divergent": true,
+                }),
+            );
+        }
+        Ok(serde_json::to_vec(&response)?)
+    }
+    
+    /// Send a read to `primary`, hedging to the next-best provider if it is slow and
+    /// retrying there if it fails, within the budget of the provider it goes to
+    async fn forward_hedged(
+        &self,
+        primary: &RpcProvider,
+        body: &[u8],
+        method: &str,
+        payload: &ExitPayload,
+        trace: &str,
+    ) -> Result<Vec<u8>> {
+        self.hedge.deposit(primary.id);
+        let started = std::time::Instant::now();
+        let first = self.forward_audited(primary, body, trace, payload);
+        tokio::pin!(first);
+        
+        let retry = tokio::select! {
+            response = &mut first => match response {
+                Ok(response) => {
+                    self.hedge.observe(method, started.elapsed());
+                    return Ok(response);
+                }
+                Err(e) => Some(e),
+            },
+            _ = tokio::time::sleep(self.hedge.delay_for(method)) => None,
+        };
+        
+        let backup = self
+            .candidates(payload)
+            .await
+            .ok()
+            .and_then(|candidates| {
+                candidates
+                    .into_iter()
+                    .find(|p| p.id != primary.id && self.hedge.try_withdraw(p.id))
+            });
+        let backup = match backup {
+            Some(backup) => backup,
+            None => {
+                return match retry {
+                    Some(e) => Err(e),
+                    None => first.await,
+                }
+            }
+        };
+        
+        if retry.is_some() {
+            metrics::increment_counter!("darknode_provider_retries_total");
+            return self.forward_audited(&backup, body, trace, payload).await;
+        }
+        
+        // Race the hedge against the primary; whichever loses is dropped, cancelling it
+        metrics::increment_counter!("darknode_hedges_fired_total");
+        let second = self.forward_audited(&backup, body, trace, payload);
+        tokio::pin!(second);
+        let answered = tokio::select! {
+            response = &mut first => match response {
+                Ok(response) => Ok(response),
+                Err(_) => second.await.map(|response| {
+                    metrics::increment_counter!("darknode_hedges_won_total");
+                    response
+                }),
+            },
+            response = &mut second => match response {
+                Ok(response) => {
+                    metrics::increment_counter!("darknode_hedges_won_total");
+                    Ok(response)
+                }
+                Err(_) => first.await,
+            },
+        };
+        if answered.is_ok() {
+            self.hedge.observe(method, started.elapsed());
+        }
+        answered
+    }
+    
+    /// Simulate a send on `provider` and only forward it there if the simulation passes
+    ///
+    /// A failed simulation is answered with its error in place of the provider's response.
+    async fn forward_preflighted(
+        &self,
+        provider: &RpcProvider,
+        payload: &ExitPayload,
+        body: &[u8],
+        trace: &str,
+    ) -> Result<Vec<u8>> {
+        if let Some(rejection) = self.preflight_rejection(provider, &payload.request).await? {
+            return Ok(serde_json::to_vec(&rejection)?);
+        }
+        self.forward_audited(provider, body, trace, payload).await
+    }
+    
+    /// Simulate a send on `provider`, returning the response to give instead if it would fail
+    async fn preflight_rejection(
+        &self,
+        provider: &RpcProvider,
+        request: &serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        let Some(simulation) = preflight::simulation(request) else {
+            return Ok(None);
+        };
+        let simulated = self.forward(provider, &serde_json::to_vec(&simulation)?).await?;
+        let simulated: serde_json::Value = serde_json::from_slice(&simulated)?;
+        let rejection = preflight::rejection(request, &simulated);
+        if rejection.is_some() {
+            metrics::increment_counter!("darknode_preflight_rejections_total");
+        }
+        Ok(rejection)
+    }
+    
+    /// Pick the provider to serve a single request
+    async fn pick_provider(&self, payload: &ExitPayload) -> Result<RpcProvider> {
+        let mut candidates = self.candidates(payload).await?;
+        Ok(candidates.remove(0))
+    }
+    
+    /// Active providers this node may use for `payload`, best first
+    ///
+    /// Providers must be on the request's network, and chain if known, support every required
+    /// capability, not be draining for maintenance, have budget left, and not have their
+    /// breaker open. Of those, the node's own pool is used while any qualify, see
+    /// [`crate::pools`]. Never empty; fails instead when no provider qualifies. The provider
+    /// a subscription circuit is kept on comes first while it qualifies.
+    async fn candidates(&self, payload: &ExitPayload) -> Result<Vec<RpcProvider>> {
+        let active = self.rpc_manager.get_active_providers().await?;
+        let now = self.clock.now();
+        let mut providers: Vec<RpcProvider> = pools::usable(active, &self.pool)
+            .into_iter()
+            .filter(|provider| !maintenance::draining(provider, now))
+            .filter(|provider| payload.chain.map_or(true, |chain| chain.served_by(provider)))
+            .collect();
+        if providers.is_empty() {
+            match payload.chain {
+                Some(chain) => return Err(ChainError::NoProvider(chain).into()),
+                None => anyhow::bail!("No available RPC providers"),
+            }
+        }
+        providers.retain(|provider| payload.network.served_by(provider));
+        if providers.is_empty() {
+            return Err(ChainError::NoNetworkProvider(payload.network).into());
+        }
+        providers.retain(|provider| capabilities::supports(provider, &payload.capabilities));
+        if providers.is_empty() {
+            return Err(CapabilityError::NoCapableProvider {
+                required: payload.capabilities.clone(),
+            }
+            .into());
+        }
+        let mut providers = self.budget.fund(providers, now)?;
+        
+        // Providers whose breaker is open here are skipped, and those other exits report
+        // tripped are tried last
+        let started = std::time::Instant::now();
+        providers.retain(|provider| self.breakers.allows(provider.id, started));
+        if providers.is_empty() {
+            return Err(BreakerRejected::AllOpen.into());
+        }
+        let mut providers = pools::select(providers, &self.pool)?;
+        let score = |provider: &RpcProvider| scoring::provider_score(provider.success_rate, provider.weight);
+        providers.sort_by(|a, b| {
+            (a.tripped_breakers > 0)
+                .cmp(&(b.tripped_breakers > 0))
+                .then(score(b).partial_cmp(&score(a)).unwrap_or(std::cmp::Ordering::Equal))
+        });
+        if let Some(position) = providers.iter().position(|provider| Some(provider.id) == payload.provider) {
+            let pinned = providers.remove(position);
+            providers.insert(0, pinned);
+        }
+        Ok(providers)
+    }
+    
+    /// The provider the requests of subscription circuit `circuit_id` are kept on
+    ///
+    /// The provider serving the circuit's first request holds its upstream subscriptions, so
+    /// later requests stay on it. Should it stop qualifying, the best provider left takes
+    /// over, and the client has to resubscribe there.
+    async fn pin(&self, circuit_id: &CircuitId, payload: &ExitPayload) -> Option<Uuid> {
+        let candidates = self.candidates(payload).await.ok()?;
+        let pinned = self.affinity.get(circuit_id).map(|pinned| *pinned);
+        if let Some(pinned) = pinned.filter(|pinned| candidates.iter().any(|provider| provider.id == *pinned)) {
+            return Some(pinned);
+        }
+        let provider = candidates.first()?.id;
+        if let Some(pinned) = pinned {
+            tracing::info!("Moving subscription circuit {} from provider {} to {}", circuit_id.0, pinned, provider);
+            metrics::increment_counter!("darknode_subscription_provider_switches_total");
+        }
+        self.affinity.insert(circuit_id.clone(), provider);
+        Some(provider)
+    }
+    
+    /// Forward a request and record the digest of the provider's response under `trace`
+    ///
+    /// Errors the provider returns are normalized before anything else sees them, and so
+    /// are results if `payload` asks for it, so quorum comparison and clients get the same
+    /// shape whichever provider answered. The digest is of the response as it leaves this
+    /// node, which is what the user receives, see [`crate::audit`].
+    async fn forward_audited(
+        &self,
+        provider: &RpcProvider,
+        body: &[u8],
+        trace: &str,
+        payload: &ExitPayload,
+    ) -> Result<Vec<u8>> {
+        let response = self.forward(provider, body).await?;
+        self.events.emit(Event::ProviderUsed {
+            provider_id: provider.id,
+            pool: pools::label(provider.pool.as_deref()).to_string(),
+        });
+        let response = provider_errors::normalize(&provider.provider_type, response, payload.debug_errors);
+        let response = match methods::method_name(&payload.request) {
+            Some(method) if payload.normalize => self.normalizer.apply(provider, method, response),
+            _ => response,
+        };
+        let response = match &self.attestor {
+            Some(attestor) if payload.attribution => self.attest(attestor, provider, response).await,
+            _ => response,
+        };
+        self.audit.record(trace, provider.id, &response, self.clock.now());
+        Ok(response)
+    }
+    
+    /// Attach an attestation of `provider`'s class to its `response`, see [`crate::attribution`]
+    ///
+    /// A response that can't be attested is passed on without one rather than failed.
+    async fn attest(&self, attestor: &Attestor, provider: &RpcProvider, response: Vec<u8>) -> Vec<u8> {
+        let Ok(mut parsed) = serde_json::from_slice::<serde_json::Value>(&response) else {
+            return response;
+        };
+        match attestor.attest(provider, &parsed, self.clock.now()).await {
+            Ok(attestation) => {
+                methods::set_extension(&mut parsed, attribution::EXTENSION_FIELD, serde_json::json!(attestation));
+                serde_json::to_vec(&parsed).unwrap_or(response)
+            }
+            Err(e) => {
+                tracing::warn!("Failed to attest the class of provider {}: {}", provider.id, e);
+                response
+            }
+        }
+    }
+    
+    /// Forward a plaintext JSON-RPC request to a provider and return the raw response body
+    ///
+    /// A response breaking the upstream limits is reported as misbehavior by the provider.
+    /// Requests to a provider whose breaker is open are refused without being sent, and
+    /// how the others end is recorded by the breaker.
+    pub async fn forward(&self, provider: &RpcProvider, body: &[u8]) -> Result<Vec<u8>> {
+        let permit = self.breakers.admit(provider.id, std::time::Instant::now())?;
+        let _stream = self.protocols.acquire(provider.id).await;
+        let call = async {
+            let response = self.send(provider, body).await?;
+            upstream::read_body(response, &self.limits).await
+        };
+        let response = call
+            .instrument(tracing::info_span!(telemetry::PROVIDER_CALL_SPAN, provider_id = %provider.id))
+            .await;
+        match &response {
+            Ok(_) => permit.succeeded(std::time::Instant::now()),
+            Err(_) => permit.failed(std::time::Instant::now()),
+        }
+        match response {
+            Err(e) => {
+                if let Some(abuse) = e.downcast_ref::<ProviderAbuse>() {
+                    tracing::warn!("Provider {} response refused: {}", provider.id, abuse);
+                    metrics::increment_counter!("darknode_provider_abuse_total", "reason" => abuse.label());
+                    if let Err(report) = self.rpc_manager.record_provider_misbehavior(provider.id, abuse.label()).await {
+                        tracing::warn!("Failed to report provider {}: {}", provider.id, report);
+                    }
+                }
+                Err(e)
+            }
+            body => body,
+        }
+    }
+    
+    /// Send a plaintext JSON-RPC request to a provider, returning once its headers arrive
+    ///
+    /// Callers hold one of the provider's streams while the request is in flight. A request
+    /// the provider refused unprocessed as its connection went away is sent again on a new
+    /// connection, and one it couldn't take over HTTP/2 is sent again over HTTP/1.1. Each
+    /// request is taken once out of the node's budget, however often it is sent.
+    async fn send(&self, provider: &RpcProvider, body: &[u8]) -> Result<reqwest::Response> {
+        self.budget.spend(provider, self.clock.now());
+        let mut retries = 0;
+        loop {
+            let client = self.client_for(provider).await?;
+            warmup::record(self.connections.touch(provider.id, std::time::Instant::now()));
+            let mut request = client
+                .post(&provider.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json");
+            if let Some(auth) = &provider.auth {
+                request = request.header(reqwest::header::AUTHORIZATION, auth);
+            }
+            let error = match request.body(body.to_vec()).send().await {
+                Ok(response) => {
+                    self.protocols.record(provider.id, response.version());
+                    return Ok(response.error_for_status()?);
+                }
+                Err(e) => e,
+            };
+            if retries < self.protocols.config().goaway_retries && multiplex::refused(&error) {
+                retries += 1;
+                metrics::increment_counter!("darknode_provider_goaway_retries_total");
+                continue;
+            }
+            if self.protocols.fall_back(provider.id, &provider.url, &error) {
+                self.rpc_clients.read().await.remove(&provider.id);
+                continue;
+            }
+            return Err(error.into());
+        }
+    }
+    
+    /// Join a circuit of `class`, serving requests for it that are encrypted under keys ratcheted from `key` for `ttl`
+    ///
+    /// The circuit's plaintext is framed in protocol `version`, which is refused if this
+    /// node doesn't speak it. Requests of subscription circuits are kept on one provider.
+    /// No circuit is joined while the node is short of resources, see [`crate::resources`],
+    /// nor one it holds already. Circuits are joined through the handshake the entry node
+    /// sends along them, see [`Self::handle_extend`].
+    pub fn join_circuit(
+        &self,
+        circuit_id: CircuitId,
+        key: CryptoKey,
+        version: u16,
+        class: CircuitClass,
+        ttl: Duration,
+    ) -> Result<()> {
+        protocol::check(version)?;
+        self.resources.admit_new()?;
+        self.circuits.join(circuit_id.clone(), key, version, class, ttl)?;
+        metrics::increment_counter!("darknode_circuits_joined_total", "class" => class.label());
+        self.events.emit(Event::CircuitCreated { circuit_id });
+        Ok(())
+    }
+    
+    /// Join the circuit whose handshake `extend` reached this node, as the layer sealed to it says
+    pub async fn handle_extend(&self, extend: &CircuitExtend) -> Result<()> {
+        let identity = self
+            .identity
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Exit node {} has no identity to open handshakes with", self.node_id.0))?;
+        match transport::open(&*self.crypto, identity, extend, self.clock.now()).await? {
+            ExtendLayer::Exit { key, version, class, ttl } => self.join_circuit(extend.circuit_id.clone(), key, version, class, ttl),
+            ExtendLayer::Relay { .. } => anyhow::bail!("Circuit {} asks an exit node to relay it", extend.circuit_id.0),
+        }
+    }
+    
+    /// Leave the circuit `destroy` tears down
+    ///
+    /// Its upstream subscriptions are closed on the next sweep, see [`Self::sweep_circuits`].
+    pub fn handle_destroy(&self, destroy: &CircuitDestroy) -> Result<()> {
+        if !self.circuits.leave(&destroy.circuit_id) {
+            return Err(UnknownCircuit {
+                circuit_id: destroy.circuit_id.clone(),
+            }
+            .into());
+        }
+        self.events.emit(Event::CircuitDestroyed {
+            circuit_id: destroy.circuit_id.clone(),
+            reason: CircuitEnd::Closed,
+        });
+        Ok(())
+    }
+    
+    /// Set the request budget left across the node's providers for the next heartbeat, and
+    /// keep what was spent for the next start, see [`crate::budget`]
+    pub async fn report_budget(&self) -> Result<()> {
+        let active = self.rpc_manager.get_active_providers().await?;
+        let report = self.budget.report(&pools::usable(active, &self.pool), self.clock.now());
+        if let Some(report) = &report {
+            metrics::gauge!("darknode_exit_budget_remaining", report.remaining as f64);
+        }
+        self.counters.set_budget(report);
+        self.budget.save().await
+    }
+    
+    /// Reclaim expired and abandoned circuits, forget the providers they were kept on and
+    /// lapsed peer strikes, and close the upstream subscriptions of circuits no longer held
+    pub async fn sweep_circuits(&self) {
+        for (circuit_id, reason) in self.circuits.reclaim() {
+            tracing::debug!("Reclaiming circuit {} ({:?})", circuit_id.0, reason);
+            reclaim::record("exit", reason);
+            self.events.emit(Event::CircuitDestroyed { circuit_id, reason });
+        }
+        self.affinity.retain(|circuit_id, _| self.circuits.contains(circuit_id));
+        self.peers.sweep();
+        
+        // Circuits can be left other ways too, such as being shed, so all are looked for here
+        let mut orphaned = Vec::new();
+        self.subscriptions.retain(|circuit_id, subscriptions| {
+            let held = self.circuits.contains(circuit_id);
+            if !held {
+                orphaned.append(subscriptions);
+            }
+            held
+        });
+        self.unsubscribe(orphaned).await;
+    }
+    
+    /// Reclaim abandoned circuits on the configured interval, until the task is dropped
+    pub async fn run_reclaim(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(self.reclaim.sweep_interval);
+        loop {
+            ticker.tick().await;
+            self.sweep_circuits().await;
+        }
+    }
+    
+    /// Keep track of the upstream subscriptions `payload` opened or closed on subscription
+    /// circuit `circuit_id`, `response` being the provider's answer
+    fn track_subscriptions(&self, circuit_id: &CircuitId, payload: &ExitPayload, response: &[u8]) {
+        let Some(provider) = payload.provider else { return };
+        if let Some(opened) = UpstreamSubscription::opened(provider, &payload.request, response) {
+            self.subscriptions.entry(circuit_id.clone()).or_default().push(opened);
+        } else if let Some(id) = reclaim::closed(&payload.request) {
+            if let Some(mut subscriptions) = self.subscriptions.get_mut(circuit_id) {
+                subscriptions.retain(|subscription| subscription.id != *id);
+            }
+        }
+    }
+    
+    /// Close subscriptions providers hold open for circuits this node no longer holds
+    async fn unsubscribe(&self, subscriptions: Vec<UpstreamSubscription>) {
+        if subscriptions.is_empty() {
+            return;
+        }
+        let providers = match self.rpc_manager.get_providers().await {
+            Ok(providers) => providers,
+            Err(e) => {
+                tracing::warn!("Failed to list providers to close {} subscriptions: {}", subscriptions.len(), e);
+                return;
+            }
+        };
+        for subscription in subscriptions {
+            let Some(provider) = providers.iter().find(|provider| provider.id == subscription.provider) else {
+                continue;
+            };
+            let request = subscription.unsubscribe_request().to_string();
+            match self.forward(provider, request.as_bytes()).await {
+                Ok(_) => metrics::increment_counter!("darknode_upstream_unsubscribes_total"),
+                Err(e) => tracing::warn!("Failed to close subscription at provider {}: {}", provider.id, e),
+            }
+        }
+    }
+    
+    /// Handle an incoming request from the routing layer, sent by the previous hop at `peer`
+    ///
+    /// Only requests for circuits this node joined, decrypting under that circuit's key, are
+    /// served. Anything else counts as a strike against `peer`. Whatever the outcome, the
+    /// answer goes back on a traffic shaping tick.
+    pub async fn handle_request(self: &Arc<Self>, peer: IpAddr, request: &Request) -> Result<Response> {
+        let response = self.answer(peer, request).await;
+        self.shaper.release().await;
+        response
+    }
+    
+    /// Serve a request from the routing layer, see [`Self::handle_request`]
+    async fn answer(self: &Arc<Self>, peer: IpAddr, request: &Request) -> Result<Response> {
+        if let Err(e) = self.peers.check(peer) {
+            return Err(self.reject(e.into()));
+        }
+        if let Err(e) = clock::admit(request, self.clock.now()) {
+            return Err(self.reject(e));
+        }
+        
+        // Membership: the circuit must be known and the request must decrypt under its key
+        // at the ratchet step it names
+        let circuit_id = &request.circuit_id;
+        let (key, version) = match self.circuits.open(circuit_id, request.key_step) {
+            Ok(Some(opened)) => opened,
+            // A circuit reclaimed may still have requests on their way from a live entry node
+            Ok(None) if self.circuits.reclaimed(circuit_id) => {
+                return Err(self.reject(UnknownCircuit { circuit_id: circuit_id.clone() }.into()))
+            }
+            Ok(None) => return Err(self.forged(peer, UnknownCircuit { circuit_id: circuit_id.clone() }.into())),
+            Err(e) => {
+                tracing::debug!("Refusing a request on circuit {}: {}", circuit_id.0, e);
+                return Err(self.reject(e.into()));
+            }
+        };
+        let plaintext = match self.crypto.decrypt(&request.payload, &key).await {
+            Ok(plaintext) => plaintext,
+            Err(_) => {
+                let e = anyhow::anyhow!("Request {} does not decrypt under the key of circuit {}", request.id, circuit_id.0);
+                return Err(self.forged(peer, e));
+            }
+        };
+        self.circuits.follow(circuit_id, request.key_step);
+        if let Err(e) = self.circuits.count_request(circuit_id) {
+            return Err(self.reject(e));
+        }
+        
+        // Serve the request and encrypt the response for the return journey, both framed
+        // in the protocol version the circuit was joined at
+        tracing::debug!("Exit node {} serving request {}", self.node_id.0, request.id);
+        let mut payload: ExitPayload = serde_json::from_slice(&protocol::unframe(version, &plaintext)?)?;
+        let subscription = self.circuits.class(circuit_id) == Some(CircuitClass::Subscription);
+        if subscription {
+            payload.provider = self.pin(circuit_id, &payload).await;
+        }
+        let started = std::time::Instant::now();
+        let mut response = self.serve(&payload).await?;
+        if subscription {
+            self.track_subscriptions(circuit_id, &payload, &response);
+        }
+        if payload.timing {
+            response = timing::report_upstream(response, started.elapsed());
+        }
+        let (key_step, key) = self
+            .circuits
+            .seal(circuit_id)
+            .ok_or_else(|| UnknownCircuit { circuit_id: circuit_id.clone() })?;
+        let response = Response {
+            request_id: request.id,
+            circuit_id: circuit_id.clone(),
+            payload: self.crypto.encrypt(&protocol::frame(version, &response), &key).await?,
+            created_at: self.clock.now(),
+            key_step,
+        };
+        if self.accounting.enabled {
+            let epoch = self.accounting.epoch_at(self.clock.now());
+            let bytes = request.payload.data.len() + response.payload.data.len();
+            self.counters.record_work(epoch, 1, bytes as u64);
+        }
+        Ok(response)
+    }
+    
+    /// Count a request that can't belong to any of this node's circuits against its sender
+    fn forged(&self, peer: IpAddr, err: anyhow::Error) -> anyhow::Error {
+        self.peers.strike(peer, &err);
+        self.events.emit(Event::NodeMisbehaved {
+            peer,
+            reason: err.to_string(),
+        });
+        self.reject(err)
+    }
+}
+
+#[async_trait]
+impl LoadShedding for ExitNodeService {
+    async fn shed(&self, pressure: Pressure, idle_after: Duration) -> Shed {
+        self.counters.set_overloaded(pressure >= Pressure::Soft);
+        if pressure < Pressure::Hard {
+            return Shed::default();
+        }
+        
+        let circuits = self.circuits.drop_idle(idle_after);
+        for circuit_id in &circuits {
+            self.affinity.remove(circuit_id);
+            self.events.emit(Event::CircuitDestroyed {
+                circuit_id: circuit_id.clone(),
+                reason: CircuitEnd::Shed,
+            });
+        }
+        // Requests in flight hold their own handle on a client, so only idle connections close
+        let clients = self.rpc_clients.read().await;
+        let connections = clients.len();
+        clients.clear();
+        Shed {
+            circuits: circuits.len(),
+            connections,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::impls::StoredRpcManager;
+    use crate::membership::CircuitTaken;
+    use crate::storage::MemoryStorage;
+    
+    async fn exit(providers: Vec<RpcProvider>) -> Arc<ExitNodeService> {
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        for provider in providers {
+            rpc_manager.register_provider(provider).await.unwrap();
+        }
+        Arc::new(fixtures::exit(rpc_manager))
+    }
+    
+    #[tokio::test]
+    async fn a_read_is_answered_with_the_providers_body() {
+        let provider = fixtures::serving(|request| async move {
+            serde_json::json!({"jsonrpc": "2.0", "id": request["id"], "result": 7})
+        });
+        let exit = exit(vec![provider]).await;
+        
+        let response = exit.serve(&fixtures::payload("getSlot", serde_json::json!([]))).await.unwrap();
+        let response: serde_json::Value = serde_json::from_slice(&response).unwrap();
+        assert_eq!(response["result"], 7);
+    }
+    
+    #[tokio::test]
+    async fn keepalive_pings_are_answered_without_a_provider() {
+        let exit = exit(Vec::new()).await;
+        
+        let response: serde_json::Value = serde_json::from_slice(&exit.serve(&keepalive::ping()).await.unwrap()).unwrap();
+        assert_eq!(response["result"], "pong");
+    }
+    
+    #[tokio::test]
+    async fn a_circuit_is_joined_once_and_left_once() {
+        let exit = exit(Vec::new()).await;
+        let circuit_id = CircuitId(Uuid::new_v4());
+        let join = |version| {
+            exit.join_circuit(circuit_id.clone(), CryptoKey(vec![1; 32]), version, CircuitClass::Interactive, Duration::from_secs(60))
+        };
+        
+        assert!(join(protocol::PROTOCOL_VERSION + 1).is_err());
+        join(protocol::PROTOCOL_VERSION).unwrap();
+        assert!(join(protocol::PROTOCOL_VERSION).unwrap_err().downcast_ref::<CircuitTaken>().is_some());
+        
+        let destroy = CircuitDestroy { circuit_id };
+        exit.handle_destroy(&destroy).unwrap();
+        assert!(exit.handle_destroy(&destroy).unwrap_err().downcast_ref::<UnknownCircuit>().is_some());
+    }
+    
+    #[tokio::test]
+    async fn handshakes_are_refused_by_an_exit_without_an_identity() {
+        let exit = exit(Vec::new()).await;
+        let extend = CircuitExtend {
+            circuit_id: CircuitId(Uuid::new_v4()),
+            layer: EncryptedData {
+                data: Vec::new(),
+                nonce: vec![0; 12],
+                aad: None,
+            },
+        };
+        
+        assert!(exit.handle_extend(&extend).await.is_err());
+    }
+}
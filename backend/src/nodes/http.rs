@@ -0,0 +1,301 @@
+//! HTTP routes of the node roles
+//!
+//! The routing and exit roles run in their own binaries or together in `darknode-node`,
+//! so their routes are built here once and mounted by every binary that runs the role.
+//! Each router carries its service as an extension; the node's [`KeyRotator`],
+//! [`OperatorConfig`](crate::operator::OperatorConfig) and
+//! [`HopVerifier`](crate::hop_auth::HopVerifier) are layered on by the binary, since
+//! every role on a node shares them.
+
+use crate::*;
+
+use crate::audit::AuditRecord;
+use crate::budget::ExitAtCapacity;
+use crate::build_info::BuildInfo;
+use crate::exit_node::ExitNodeService;
+use crate::hop_auth;
+use crate::identity::{KeyRotator, RotationOutcome};
+use crate::membership::{CircuitTaken, PeerBlocked, UnknownCircuit};
+use crate::ratchet::RatchetDesync;
+use crate::multiplex::Protocol;
+use crate::operator;
+use crate::replay::HopFailure;
+use crate::resources::ResourcesExhausted;
+use crate::routing_node::RoutingNodeService;
+use crate::transport::{self, CircuitDestroy, CircuitExtend, RequestMessage, ResponseMessage};
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, Extension, Path};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use std::net::SocketAddr;
+
+/// Request body for rotating the node's long-term key
+#[derive(Debug, Clone, Deserialize)]
+struct RotateKeyRequest {
+    /// Seconds until the new key replaces the current one
+    activate_in_secs: u64,
+}
+
+/// A provider and the HTTP version it is spoken to over
+#[derive(Debug, Clone, Serialize)]
+struct ProviderProtocol {
+    /// The provider
+    provider_id: Uuid,
+    /// The HTTP version
+    protocol: Protocol,
+}
+
+/// An audit record checked against a response the user received
+#[derive(Debug, Clone, Serialize)]
+struct VerifiedRecord {
+    /// The audit record
+    record: AuditRecord,
+    /// Whether the response matches the record's digest
+    matches: bool,
+}
+
+/// Routes every node serves whatever its roles: health, version and key rotation
+///
+/// Rotating the key takes the operator token.
+pub fn node_routes() -> Router {
+    Router::new()
+        .route("/admin/rotate-key", post(rotate_key))
+        .route_layer(axum::middleware::from_fn(operator::require_operator))
+        .route("/health", get(health_check))
+        .route("/version", get(version))
+}
+
+/// Routes of the routing role: `/extend`, `/forward` and `/destroy`, which take signed hops
+pub fn routing_routes(service: Arc<RoutingNodeService>) -> Router {
+    Router::new()
+        .route(transport::EXTEND_PATH, post(handle_routing_extend))
+        .route(transport::FORWARD_PATH, post(handle_forward_request))
+        .route(transport::DESTROY_PATH, post(handle_routing_destroy))
+        .route_layer(axum::middleware::from_fn(hop_auth::require_signed_hop))
+        .layer(Extension(service))
+}
+
+/// Routes of the exit role: `/extend`, `/` and `/destroy`, which take signed hops, and the audit and
+/// provider routes under `/admin`, which take the operator token
+pub fn exit_routes(service: Arc<ExitNodeService>) -> Router {
+    let admin = Router::new()
+        .route("/admin/audit/:trace_token", get(audit_trail))
+        .route("/admin/providers/protocols", get(provider_protocols))
+        .route("/admin/audit/:trace_token/verify", post(verify_audit))
+        .route_layer(axum::middleware::from_fn(operator::require_operator));
+    Router::new()
+        .route(transport::EXTEND_PATH, post(handle_exit_extend))
+        .route(transport::EXIT_PATH, post(handle_circuit_request))
+        .route(transport::DESTROY_PATH, post(handle_exit_destroy))
+        .route_layer(axum::middleware::from_fn(hop_auth::require_signed_hop))
+        .merge(admin)
+        .layer(Extension(service))
+}
+
+/// Handler for circuit handshakes reaching a routing node
+async fn handle_routing_extend(
+    Extension(service): Extension<Arc<RoutingNodeService>>,
+    Json(extend): Json<CircuitExtend>,
+) -> std::result::Result<Json<()>, axum::response::Response> {
+    service.handle_extend(&extend).await.map(Json).map_err(hop_error)
+}
+
+/// Handler for requests forwarded through a routing node
+async fn handle_forward_request(
+    Extension(service): Extension<Arc<RoutingNodeService>>,
+    Json(message): Json<RequestMessage>,
+) -> std::result::Result<Json<ResponseMessage>, axum::response::Response> {
+    let response = service.handle_request(&message.request).await.map_err(hop_error)?;
+    Ok(Json(ResponseMessage { response }))
+}
+
+/// Handler for circuit teardowns reaching a routing node
+async fn handle_routing_destroy(
+    Extension(service): Extension<Arc<RoutingNodeService>>,
+    Json(destroy): Json<CircuitDestroy>,
+) -> std::result::Result<Json<()>, axum::response::Response> {
+    service.handle_destroy(&destroy).await.map(Json).map_err(hop_error)
+}
+
+/// Handler for circuit handshakes reaching an exit node
+async fn handle_exit_extend(
+    Extension(service): Extension<Arc<ExitNodeService>>,
+    Json(extend): Json<CircuitExtend>,
+) -> std::result::Result<Json<()>, StatusCode> {
+    service.handle_extend(&extend).await.map(Json).map_err(|e| {
+        tracing::debug!("Refused handshake of circuit {}: {}", extend.circuit_id.0, e);
+        circuit_error_status(&e)
+    })
+}
+
+/// Handler for circuit teardowns reaching an exit node
+async fn handle_exit_destroy(
+    Extension(service): Extension<Arc<ExitNodeService>>,
+    Json(destroy): Json<CircuitDestroy>,
+) -> std::result::Result<Json<()>, StatusCode> {
+    service.handle_destroy(&destroy).map(Json).map_err(|e| circuit_error_status(&e))
+}
+
+/// Handler for circuit requests
+async fn handle_circuit_request(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Extension(service): Extension<Arc<ExitNodeService>>,
+    Json(message): Json<RequestMessage>,
+) -> std::result::Result<Json<ResponseMessage>, StatusCode> {
+    let response = service
+        .handle_request(peer.ip(), &message.request)
+        .await
+        .map_err(|e| circuit_error_status(&e))?;
+    
+    Ok(Json(ResponseMessage { response }))
+}
+
+/// Answer to a message a routing node failed: the failure of a hop after it as that hop
+/// reported it, or else the status of its own
+fn hop_error(err: anyhow::Error) -> axum::response::Response {
+    match err.downcast_ref::<HopFailure>() {
+        Some(failure) => (transport::RELAYED_FAILURE_STATUS, Json(failure.clone())).into_response(),
+        None => {
+            tracing::debug!("Refused a circuit message: {}", err);
+            circuit_error_status(&err).into_response()
+        }
+    }
+}
+
+/// Status code for a circuit message the node refused or failed to serve
+fn circuit_error_status(err: &anyhow::Error) -> StatusCode {
+    if err.downcast_ref::<UnknownCircuit>().is_some() || err.downcast_ref::<RatchetDesync>().is_some() {
+        StatusCode::NOT_FOUND
+    } else if err.downcast_ref::<CircuitTaken>().is_some() {
+        StatusCode::CONFLICT
+    } else if err.downcast_ref::<PeerBlocked>().is_some() {
+        StatusCode::FORBIDDEN
+    } else if err.downcast_ref::<ExitAtCapacity>().is_some() || err.downcast_ref::<ResourcesExhausted>().is_some() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Handler for rotating the node's long-term key
+async fn rotate_key(
+    Extension(rotator): Extension<Arc<KeyRotator>>,
+    Json(request): Json<RotateKeyRequest>,
+) -> std::result::Result<Json<RotationOutcome>, (StatusCode, String)> {
+    rotator
+        .rotate(Duration::from_secs(request.activate_in_secs))
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::CONFLICT, e.to_string()))
+}
+
+/// Handler for the HTTP version each provider is spoken to over
+async fn provider_protocols(Extension(service): Extension<Arc<ExitNodeService>>) -> Json<Vec<ProviderProtocol>> {
+    Json(
+        service
+            .provider_protocols()
+            .into_iter()
+            .map(|(provider_id, protocol)| ProviderProtocol { provider_id, protocol })
+            .collect(),
+    )
+}
+
+/// Handler for looking up the audit trail of a trace token
+async fn audit_trail(
+    Path(trace_token): Path<String>,
+    Extension(service): Extension<Arc<ExitNodeService>>,
+) -> Json<Vec<AuditRecord>> {
+    Json(service.audit_trail(&trace_token))
+}
+
+/// Handler for checking a response the user received against the audit trail
+async fn verify_audit(
+    Path(trace_token): Path<String>,
+    Extension(service): Extension<Arc<ExitNodeService>>,
+    response: Bytes,
+) -> Json<Vec<VerifiedRecord>> {
+    let records = service
+        .audit_trail(&trace_token)
+        .into_iter()
+        .map(|record| VerifiedRecord {
+            matches: service.audit_matches(&record, &response),
+            record,
+        })
+        .collect();
+    Json(records)
+}
+
+/// Handler for health checks
+async fn health_check() -> &'static str {
+    "OK"
+}
+
+/// Handler for the build of this binary
+async fn version() -> Json<BuildInfo> {
+    Json(BuildInfo::current())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::HopFailureKind;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+    
+    #[test]
+    fn refused_circuit_messages_map_to_their_status() {
+        let circuit_id = CircuitId(Uuid::new_v4());
+        let unknown = anyhow::Error::new(UnknownCircuit { circuit_id: circuit_id.clone() });
+        let taken = anyhow::Error::new(CircuitTaken { circuit_id });
+        let blocked = anyhow::Error::new(PeerBlocked {
+            peer: IpAddr::from([203, 0, 113, 7]),
+            retry_after: Duration::from_secs(60),
+        });
+        let full = anyhow::Error::new(ExitAtCapacity { resets_at: Timestamp::now() });
+        
+        assert_eq!(circuit_error_status(&unknown), StatusCode::NOT_FOUND);
+        assert_eq!(circuit_error_status(&taken), StatusCode::CONFLICT);
+        assert_eq!(circuit_error_status(&blocked), StatusCode::FORBIDDEN);
+        assert_eq!(circuit_error_status(&full), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(circuit_error_status(&anyhow::anyhow!("disk full")), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    
+    #[tokio::test]
+    async fn a_later_hops_failure_is_passed_back_as_it_reported_it() {
+        let failure = HopFailure {
+            node: Some(NodeId(Uuid::new_v4())),
+            kind: HopFailureKind::TimedOut,
+        };
+        let response = hop_error(anyhow::Error::new(failure.clone()));
+        
+        assert_eq!(response.status(), transport::RELAYED_FAILURE_STATUS);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(serde_json::from_slice::<HopFailure>(&body).unwrap(), failure);
+    }
+    
+    #[tokio::test]
+    async fn every_node_answers_health_checks_without_the_operator_token() {
+        let request = Request::builder().uri("/health").body(Body::empty()).unwrap();
+        let response = node_routes().oneshot(request).await.unwrap();
+        
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"OK");
+    }
+    
+    #[tokio::test]
+    async fn the_version_names_the_crate_version_and_the_commit_built() {
+        let request = Request::builder().uri("/version").body(Body::empty()).unwrap();
+        let response = node_routes().oneshot(request).await.unwrap();
+        
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let build: BuildInfo = serde_json::from_slice(&body).unwrap();
+        assert_eq!(build.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(build.git_hash, env!("DARKNODE_GIT_HASH"));
+        assert_eq!(build, BuildInfo::current());
+    }
+}
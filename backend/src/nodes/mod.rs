@@ -0,0 +1,7 @@
+//! Services run by each node role
+
+pub mod coordinator;
+pub mod entry;
+pub mod exit;
+pub mod http;
+pub mod routing;
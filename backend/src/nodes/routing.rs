@@ -0,0 +1,403 @@
+//! Routing node implementation
+//!
+//! A routing node carries a circuit once the circuit's handshake reached it, passing it on
+//! to the hop its layer names, and relays the circuit's requests there, each answered with
+//! the response coming back, see [`crate::transport`].
+
+use crate::*;
+use crate::traits::*;
+use crate::types::*;
+
+use crate::accounting::AccountingConfig;
+use crate::bandwidth::{BandwidthConfig, EgressScheduler};
+use crate::clock::{self, Clock, Deadline, SystemClock};
+use crate::heartbeat::ActivityCounters;
+use crate::identity::NodeIdentity;
+use crate::membership::{CircuitTaken, UnknownCircuit};
+use crate::reclaim::{self, ReclaimConfig, Tombstones};
+use crate::replay::{HopFailure, HopFailureKind};
+use crate::resources::{LoadShedding, Pressure, ResourceConfig, ResourceGuard, Shed};
+use crate::telemetry;
+use crate::transport::{self, CircuitDestroy, CircuitExtend, ExtendLayer, HopClient, RequestMessage, ResponseMessage};
+use std::net::SocketAddr;
+use tokio::time::Instant;
+use tracing::Instrument;
+
+/// A circuit this node carries
+struct Carried {
+    used_at: Instant,
+    expires: Deadline,
+    next: Onward,
+}
+
+/// The hop a carried circuit's requests are passed on to
+#[derive(Clone)]
+struct Onward {
+    node_id: NodeId,
+    address: SocketAddr,
+    role: NodeRole,
+}
+
+/// The routing node service
+pub struct RoutingNodeService {
+    node_id: NodeId,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    identity: Arc<NodeIdentity>,
+    hops: Arc<HopClient>,
+    counters: Arc<ActivityCounters>,
+    accounting: AccountingConfig,
+    egress: Arc<EgressScheduler>,
+    circuits: dashmap::DashMap<CircuitId, Carried>,
+    reclaimed: Tombstones,
+    reclaim: ReclaimConfig,
+    resources: Arc<ResourceGuard>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RoutingNodeService {
+    /// Create a routing node service opening handshakes with `identity` and passing
+    /// messages on through `hops`
+    pub fn new(
+        node_id: NodeId,
+        crypto: Arc<dyn Crypto + Send + Sync>,
+        identity: Arc<NodeIdentity>,
+        hops: Arc<HopClient>,
+        accounting: AccountingConfig,
+        bandwidth: BandwidthConfig,
+    ) -> Self {
+        Self {
+            node_id,
+            crypto,
+            identity,
+            hops,
+            counters: Arc::new(ActivityCounters::new()),
+            accounting,
+            egress: Arc::new(EgressScheduler::new(bandwidth)),
+            circuits: dashmap::DashMap::new(),
+            reclaimed: Tombstones::new(),
+            reclaim: ReclaimConfig::default(),
+            resources: Arc::new(ResourceGuard::new(ResourceConfig::default())),
+            clock: Arc::new(SystemClock),
+        }
+    }
+    
+    /// Admit requests and open handshakes by the time on `clock` rather than the system's
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    
+    /// Activity counters reported in this node's heartbeats
+    pub fn counters(&self) -> Arc<ActivityCounters> {
+        self.counters.clone()
+    }
+    
+    /// Count activity into `counters`, shared with the other roles of the same process
+    pub fn with_counters(mut self, counters: Arc<ActivityCounters>) -> Self {
+        self.counters = counters;
+        self
+    }
+    
+    /// Refuse new circuits while `guard` finds the node short of resources, see [`crate::resources`]
+    pub fn with_resource_guard(mut self, guard: Arc<ResourceGuard>) -> Self {
+        self.resources = guard;
+        self
+    }
+    
+    /// Reclaim circuits abandoned by their entry node as `config` has it, see [`crate::reclaim`]
+    pub fn with_reclaim(mut self, config: ReclaimConfig) -> Self {
+        self.reclaim = config;
+        self
+    }
+    
+    /// Release forwarded messages within the egress cap, reporting utilization as load
+    pub async fn run_egress(self: Arc<Self>) {
+        self.egress.clone().run(self.counters.clone()).await
+    }
+    
+    /// Start carrying the circuit whose handshake `extend` reached this node, passing the
+    /// rest of the handshake on to the hop its layer names
+    ///
+    /// A circuit carried already, or reclaimed, can't be extended again, and none is taken
+    /// on while the node is short of resources, see [`crate::resources`]. The circuit is
+    /// only carried once every hop after this one has taken it.
+    pub async fn handle_extend(&self, extend: &CircuitExtend) -> Result<()> {
+        let circuit_id = &extend.circuit_id;
+        if self.circuits.contains_key(circuit_id) || self.reclaimed.contains(circuit_id) {
+            return Err(CircuitTaken {
+                circuit_id: circuit_id.clone(),
+            }
+            .into());
+        }
+        let (ttl, next) = match transport::open(&*self.crypto, &self.identity, extend, self.clock.now()).await? {
+            ExtendLayer::Relay { ttl, next } => (ttl, next),
+            ExtendLayer::Exit { .. } => anyhow::bail!("Circuit {} asks a routing node to exit it", circuit_id.0),
+        };
+        if next.extend.circuit_id != *circuit_id {
+            anyhow::bail!("Handshake of circuit {} passes on another circuit", circuit_id.0);
+        }
+        self.resources.admit_new()?;
+        
+        self.hops
+            .send::<_, ()>(&next.node_id, next.address, transport::EXTEND_PATH, &next.extend)
+            .await?;
+        let carried = Carried {
+            used_at: Instant::now(),
+            expires: Deadline::after(ttl),
+            next: Onward {
+                node_id: next.node_id.clone(),
+                address: next.address,
+                role: next.role,
+            },
+        };
+        match self.circuits.entry(circuit_id.clone()) {
+            dashmap::mapref::entry::Entry::Vacant(vacant) => {
+                vacant.insert(carried);
+                Ok(())
+            }
+            dashmap::mapref::entry::Entry::Occupied(_) => Err(CircuitTaken {
+                circuit_id: circuit_id.clone(),
+            }
+            .into()),
+        }
+    }
+    
+    /// Pass a request from the previous hop on along its circuit, returning the response
+    /// the next hop answered with
+    ///
+    /// Requests for circuits this node doesn't carry are refused. Failures of the hops
+    /// after this one come back as the [`crate::replay::HopFailure`] they reported.
+    pub async fn handle_request(&self, request: &Request) -> Result<Response> {
+        let deadline = clock::admit(request, self.clock.now())?;
+        let next = match self.circuits.get_mut(&request.circuit_id) {
+            Some(mut carried) => {
+                carried.used_at = Instant::now();
+                carried.expires = deadline;
+                carried.next.clone()
+            }
+            None => {
+                return Err(UnknownCircuit {
+                    circuit_id: request.circuit_id.clone(),
+                }
+                .into());
+            }
+        };
+        
+        tracing::debug!(
+            "Routing node {} forwarding request {} to {} ({:?} left on circuit)",
+            self.node_id.0,
+            request.id,
+            next.node_id.0,
+            deadline.remaining()
+        );
+        
+        // Wait for this circuit's turn within the node's egress cap
+        self.egress
+            .admit(&request.circuit_id, request.payload.data.len())
+            .instrument(tracing::info_span!(telemetry::HOP_FORWARD_SPAN))
+            .await;
+        self.counters.record_forwarded();
+        self.record_work(1, request.payload.data.len());
+        
+        // A next hop that hasn't answered by the deadline is reported, so the entry node can
+        // build around it rather than find out only once the request's own time is up
+        let message = RequestMessage { request: request.clone() };
+        let sent = self
+            .hops
+            .send::<_, ResponseMessage>(&next.node_id, next.address, transport::request_path(next.role), &message);
+        let answer = tokio::time::timeout(deadline.remaining(), sent)
+            .await
+            .map_err(|_| HopFailure {
+                node: Some(next.node_id.clone()),
+                kind: HopFailureKind::TimedOut,
+            })??;
+        self.carry_back(&answer.response).await;
+        Ok(answer.response)
+    }
+    
+    /// Stop carrying the circuit `destroy` tears down, and pass the teardown on to the next hop
+    ///
+    /// The circuit is remembered as reclaimed, so it can't be extended through this node
+    /// again. Circuits this node doesn't carry are refused.
+    pub async fn handle_destroy(&self, destroy: &CircuitDestroy) -> Result<()> {
+        let Some((circuit_id, carried)) = self.circuits.remove(&destroy.circuit_id) else {
+            return Err(UnknownCircuit {
+                circuit_id: destroy.circuit_id.clone(),
+            }
+            .into());
+        };
+        self.reclaimed.bury(circuit_id, self.reclaim.remember(carried.expires));
+        self.hops
+            .send::<_, ()>(&carried.next.node_id, carried.next.address, transport::DESTROY_PATH, destroy)
+            .await
+    }
+    
+    /// Carry a response from the next hop back towards the previous one
+    async fn carry_back(&self, response: &Response) {
+        self.egress
+            .admit(&response.circuit_id, response.payload.data.len())
+            .instrument(tracing::info_span!(telemetry::HOP_RETURN_SPAN))
+            .await;
+        self.record_work(0, response.payload.data.len());
+    }
+    
+    /// Reclaim the circuits that expired or were abandoned, returning how many
+    pub fn sweep_circuits(&self) -> usize {
+        let mut reclaimed = 0;
+        self.circuits.retain(|circuit_id, carried| match self.reclaim.stale(carried.expires, carried.used_at) {
+            Some(reason) => {
+                tracing::debug!("Reclaiming circuit {} ({:?})", circuit_id.0, reason);
+                reclaim::record("routing", reason);
+                self.reclaimed.bury(circuit_id.clone(), self.reclaim.remember(carried.expires));
+                reclaimed += 1;
+                false
+            }
+            None => true,
+        });
+        self.reclaimed.sweep();
+        reclaimed
+    }
+    
+    /// Reclaim abandoned circuits on the configured interval, until the task is dropped
+    pub async fn run_reclaim(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(self.reclaim.sweep_interval);
+        loop {
+            ticker.tick().await;
+            self.sweep_circuits();
+        }
+    }
+    
+    /// Count requests and bytes carried towards this node's own work report
+    fn record_work(&self, requests: u64, bytes: usize) {
+        if self.accounting.enabled {
+            let epoch = self.accounting.epoch_at(self.clock.now());
+            self.counters.record_work(epoch, requests, bytes as u64);
+        }
+    }
+}
+
+#[async_trait]
+impl LoadShedding for RoutingNodeService {
+    async fn shed(&self, pressure: Pressure, idle_after: Duration) -> Shed {
+        self.counters.set_overloaded(pressure >= Pressure::Soft);
+        if pressure < Pressure::Hard {
+            // Circuits are only carried once their handshake opened, and the reclaim sweep
+            // forgets them once abandoned, so there is nothing more to drop
+            return Shed::default();
+        }
+        
+        // Circuits shed are remembered like reclaimed ones, so they can't be extended again
+        let mut shed = 0;
+        self.circuits.retain(|circuit_id, carried| {
+            if carried.used_at.elapsed() < idle_after {
+                return true;
+            }
+            self.reclaimed.bury(circuit_id.clone(), self.reclaim.remember(carried.expires));
+            shed += 1;
+            false
+        });
+        Shed {
+            circuits: shed,
+            connections: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit_class::CircuitClass;
+    use crate::crypto::CryptoImpl;
+    use crate::fixtures;
+    use crate::hop_auth::HopSigner;
+    
+    /// A routing node and the record other nodes seal its handshakes to
+    async fn routing() -> (RoutingNodeService, Node) {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let identity = Arc::new(NodeIdentity::generate(&*crypto, Duration::from_secs(60)).await.unwrap());
+        let record = Node {
+            public_key: identity.public_key(Timestamp::now()),
+            ..fixtures::node(&[NodeRole::Routing])
+        };
+        let hops = Arc::new(HopClient::new(HopSigner::new(record.id.clone(), identity.clone(), crypto.clone())));
+        let service = RoutingNodeService::new(
+            record.id.clone(),
+            crypto,
+            identity,
+            hops,
+            AccountingConfig::default(),
+            BandwidthConfig::default(),
+        );
+        (service, record)
+    }
+    
+    async fn extend(service: &RoutingNodeService, record: &Node, circuit_id: &CircuitId, layer: ExtendLayer) -> Result<()> {
+        let extend = transport::seal(&*service.crypto, circuit_id, &layer, record, Timestamp::now()).await?;
+        service.handle_extend(&extend).await
+    }
+    
+    #[tokio::test]
+    async fn a_handshake_asking_a_routing_node_to_exit_the_circuit_is_refused() {
+        let (service, record) = routing().await;
+        let circuit_id = CircuitId(Uuid::new_v4());
+        let layer = ExtendLayer::Exit {
+            key: CryptoKey(vec![1; 32]),
+            version: 1,
+            class: CircuitClass::Interactive,
+            ttl: Duration::from_secs(60),
+        };
+        
+        assert!(extend(&service, &record, &circuit_id, layer).await.is_err());
+        assert!(!service.circuits.contains_key(&circuit_id));
+    }
+    
+    #[tokio::test]
+    async fn a_circuit_is_only_carried_once_the_next_hop_took_it() {
+        let (service, record) = routing().await;
+        let circuit_id = CircuitId(Uuid::new_v4());
+        let next = fixtures::node(&[NodeRole::Exit]);
+        let layer = ExtendLayer::Relay {
+            ttl: Duration::from_secs(60),
+            next: Box::new(transport::NextHop {
+                node_id: next.id.clone(),
+                address: "127.0.0.1:1".parse().unwrap(),
+                role: NodeRole::Exit,
+                extend: CircuitExtend {
+                    circuit_id: circuit_id.clone(),
+                    layer: EncryptedData {
+                        data: Vec::new(),
+                        nonce: vec![0; 12],
+                        aad: None,
+                    },
+                },
+            }),
+        };
+        
+        let err = extend(&service, &record, &circuit_id, layer).await.unwrap_err();
+        assert_eq!(crate::replay::hop_failure(&err).map(|failure| failure.kind), Some(HopFailureKind::Unreachable));
+        assert!(!service.circuits.contains_key(&circuit_id));
+    }
+    
+    #[tokio::test]
+    async fn messages_for_circuits_not_carried_are_refused_as_unknown() {
+        let (service, _) = routing().await;
+        let circuit_id = CircuitId(Uuid::new_v4());
+        let request = Request {
+            id: Uuid::new_v4(),
+            circuit_id: circuit_id.clone(),
+            payload: EncryptedData {
+                data: vec![1, 2, 3],
+                nonce: vec![0; 12],
+                aad: None,
+            },
+            created_at: Timestamp::now(),
+            ttl: Duration::from_secs(30),
+            key_step: 0,
+        };
+        
+        let err = service.handle_request(&request).await.unwrap_err();
+        assert!(err.downcast_ref::<UnknownCircuit>().is_some());
+        let err = service.handle_destroy(&CircuitDestroy { circuit_id }).await.unwrap_err();
+        assert!(err.downcast_ref::<UnknownCircuit>().is_some());
+    }
+}
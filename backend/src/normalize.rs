@@ -0,0 +1,308 @@
+//! Normalizing provider results to a common schema per method
+//!
+//! Providers for the same chain agree on the fields that matter but not on the optional
+//! ones: one includes `loadedAddresses` for every transaction, another only for versioned
+//! ones, and each reports its own `context.apiVersion`. A client whose circuits move
+//! between exits sees the shape of its results change, which breaks strict parsers.
+//!
+//! Mappings with `normalize_results` set have the results of the methods below projected
+//! onto one documented shape: fields the shape doesn't list are dropped, and optional
+//! fields a provider left out are filled with their default. Methods without a shape, and
+//! error responses, pass through untouched. Normalization is off by default, since it
+//! hides whatever a provider adds beyond the shape.
+//!
+//! Solana shapes, fields filled when missing in brackets:
+//!
+//! - `getBalance`, `getAccountInfo`, `getMultipleAccounts`, `getLatestBlockhash`: `context`
+//!   keeps only `slot`
+//! - `getAccountInfo`, `getMultipleAccounts`: accounts keep `data`, `executable`,
+//!   `lamports`, `owner`, `rentEpoch`, and `space` [`null`]
+//! - `getTransaction`: `slot`, `transaction`, `blockTime` [`null`], `version`, and `meta`
+//!   with `err`, `fee`, `preBalances`, `postBalances`, `status`, `innerInstructions`,
+//!   `logMessages`, `preTokenBalances`, `postTokenBalances`, `rewards` [all `null`],
+//!   `loadedAddresses` [empty `writable` and `readonly`], `returnData`, and
+//!   `computeUnitsConsumed`
+
+use super::types::RpcProvider;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// The shape a value in a result is projected onto
+#[derive(Debug, Clone)]
+pub enum Shape {
+    /// Any value, kept as is
+    Any,
+    /// An object keeping only these fields, in this order
+    Object(Vec<Field>),
+    /// An array whose items all have the same shape
+    Array(Box<Shape>),
+}
+
+/// A field of an object shape
+#[derive(Debug, Clone)]
+pub struct Field {
+    /// The field's name
+    pub name: &'static str,
+    /// The shape of its value
+    pub shape: Shape,
+    /// The value the field is filled with when a provider leaves it out, if any
+    pub default: Option<Value>,
+}
+
+impl Field {
+    /// A field kept when present and left out when not
+    pub fn kept(name: &'static str, shape: Shape) -> Self {
+        Self {
+            name,
+            shape,
+            default: None,
+        }
+    }
+    
+    /// A field filled with `default` when a provider leaves it out
+    pub fn defaulted(name: &'static str, shape: Shape, default: Value) -> Self {
+        Self {
+            name,
+            shape,
+            default: Some(default),
+        }
+    }
+}
+
+impl Shape {
+    /// Project `value` onto the shape, in place
+    ///
+    /// Values of a different kind than the shape expects, such as a `null` account, are
+    /// left as they are.
+    pub fn project(&self, value: &mut Value) {
+        match (self, value) {
+            (Shape::Array(item), Value::Array(items)) => items.iter_mut().for_each(|value| item.project(value)),
+            (Shape::Object(fields), Value::Object(object)) => {
+                *object = fields
+                    .iter()
+                    .filter_map(|field| {
+                        let mut value = object.remove(field.name).or_else(|| field.default.clone())?;
+                        field.shape.project(&mut value);
+                        Some((field.name.to_string(), value))
+                    })
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Result shapes for the methods of one chain
+#[derive(Debug, Clone, Default)]
+pub struct ResultSchema {
+    methods: HashMap<&'static str, Shape>,
+}
+
+impl ResultSchema {
+    /// A chain with no result shapes
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Add or replace a method's result shape
+    pub fn with(mut self, method: &'static str, shape: Shape) -> Self {
+        self.methods.insert(method, shape);
+        self
+    }
+    
+    /// The result shapes for a known chain
+    pub fn for_chain(chain: &str) -> Option<Self> {
+        match chain {
+            "solana" => Some(Self::solana()),
+            _ => None,
+        }
+    }
+    
+    /// Result shapes for the Solana methods whose optional fields differ between providers
+    pub fn solana() -> Self {
+        let context = || Field::kept("context", Shape::Object(vec![Field::kept("slot", Shape::Any)]));
+        let with_context = |value: Shape| Shape::Object(vec![context(), Field::kept("value", value)]);
+        let account = || Shape::Object(vec![
+            Field::kept("data", Shape::Any),
+            Field::kept("executable", Shape::Any),
+            Field::kept("lamports", Shape::Any),
+            Field::kept("owner", Shape::Any),
+            Field::kept("rentEpoch", Shape::Any),
+            Field::defaulted("space", Shape::Any, Value::Null),
+        ]);
+        let meta = Shape::Object(vec![
+            Field::kept("err", Shape::Any),
+            Field::kept("fee", Shape::Any),
+            Field::kept("preBalances", Shape::Any),
+            Field::kept("postBalances", Shape::Any),
+            Field::kept("status", Shape::Any),
+            Field::defaulted("innerInstructions", Shape::Any, Value::Null),
+            Field::defaulted("logMessages", Shape::Any, Value::Null),
+            Field::defaulted("preTokenBalances", Shape::Any, Value::Null),
+            Field::defaulted("postTokenBalances", Shape::Any, Value::Null),
+            Field::defaulted("rewards", Shape::Any, Value::Null),
+            Field::defaulted(
+                "loadedAddresses",
+                Shape::Object(vec![Field::kept("writable", Shape::Any), Field::kept("readonly", Shape::Any)]),
+                serde_json::json!({ "writable": [], "readonly": [] }),
+            ),
+            Field::kept("returnData", Shape::Any),
+            Field::kept("computeUnitsConsumed", Shape::Any),
+        ]);
+        
+        Self::new()
+            .with("getBalance", with_context(Shape::Any))
+            .with("getLatestBlockhash", with_context(Shape::Any))
+            .with("getAccountInfo", with_context(account()))
+            .with("getMultipleAccounts", with_context(Shape::Array(Box::new(account()))))
+            .with(
+                "getTransaction",
+                Shape::Object(vec![
+                    Field::kept("slot", Shape::Any),
+                    Field::kept("transaction", Shape::Any),
+                    Field::defaulted("blockTime", Shape::Any, Value::Null),
+                    Field::kept("version", Shape::Any),
+                    Field::kept("meta", meta),
+                ]),
+            )
+    }
+    
+    /// The response `response` with its result projected onto the method's shape
+    ///
+    /// Returns whether anything changed, or `None` for responses that aren't JSON-RPC
+    /// results or are for a method without a shape, which are left alone.
+    pub fn normalize(&self, method: &str, response: &mut Value) -> Option<bool> {
+        let shape = self.methods.get(method)?;
+        let result = response.get_mut("result")?;
+        let original = result.clone();
+        shape.project(result);
+        Some(*result != original)
+    }
+}
+
+/// Result shapes by chain, for the providers an exit node serves from
+pub struct Normalizer {
+    chains: HashMap<&'static str, ResultSchema>,
+}
+
+impl Default for Normalizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Normalizer {
+    /// A normalizer for every chain with result shapes
+    pub fn new() -> Self {
+        Self {
+            chains: HashMap::from([("solana", ResultSchema::solana())]),
+        }
+    }
+    
+    /// `response` from `provider` with its result normalized for `method`
+    ///
+    /// Responses normalized are counted per provider by whether anything changed, which
+    /// points out providers that stray from the others. Bodies that aren't JSON are passed
+    /// through as they are.
+    pub fn apply(&self, provider: &RpcProvider, method: &str, response: Vec<u8>) -> Vec<u8> {
+        let Some(schema) = self.chains.get(provider.provider_type.as_str()) else {
+            return response;
+        };
+        let Ok(mut value) = serde_json::from_slice::<Value>(&response) else {
+            return response;
+        };
+        let Some(changed) = schema.normalize(method, &mut value) else {
+            return response;
+        };
+        metrics::increment_counter!(
+            "darknode_result_normalizations_total",
+            "provider" => provider.id.to_string(),
+            "changed" => if changed { "true" } else { "false" }
+        );
+        match changed {
+            true => serde_json::to_vec(&value).unwrap_or(response),
+            false => response,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exit_node::ExitNodeConfig;
+    use crate::fixtures;
+    use crate::hedge::HedgeConfig;
+    use crate::impls::StoredRpcManager;
+    use crate::storage::MemoryStorage;
+    use crate::traits::RpcManager;
+    use crate::types::ExitPayload;
+    use std::sync::Arc;
+    use uuid::Uuid;
+    
+    /// The same transaction as two providers return it, each with fields of its own
+    const PROVIDER_A: &str = include_str!("../tests/fixtures/normalize/get_transaction_a.json");
+    const PROVIDER_B: &str = include_str!("../tests/fixtures/normalize/get_transaction_b.json");
+    
+    fn parsed(response: &str) -> Value {
+        serde_json::from_str(response).unwrap()
+    }
+    
+    #[test]
+    fn two_providers_transactions_normalize_to_the_same_result() {
+        let schema = ResultSchema::solana();
+        let (mut a, mut b) = (parsed(PROVIDER_A), parsed(PROVIDER_B));
+        assert_ne!(a["result"], b["result"]);
+        assert_eq!(schema.normalize("getTransaction", &mut a), Some(true));
+        assert_eq!(schema.normalize("getTransaction", &mut b), Some(true));
+        assert_eq!(a["result"], b["result"]);
+        assert_eq!(b["result"]["meta"]["loadedAddresses"], serde_json::json!({ "writable": [], "readonly": [] }));
+        assert!(a["result"]["meta"].get("costUnits").is_none() && b["result"].get("indexedAt").is_none());
+        
+        // A result already in shape is left as it is, and so is an error or an unshaped method
+        assert_eq!(schema.normalize("getTransaction", &mut a), Some(false));
+        let mut error = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "error": { "code": -32009, "message": "slot skipped" } });
+        assert_eq!(schema.normalize("getTransaction", &mut error), None);
+        assert_eq!(schema.normalize("getSlot", &mut parsed(PROVIDER_A)), None);
+    }
+    
+    #[tokio::test]
+    async fn the_exit_node_normalizes_only_for_mappings_that_ask() {
+        let serving = |fixture: &'static str| {
+            fixtures::serving(move |request: Value| async move {
+                let mut response = parsed(fixture);
+                response["id"] = request["id"].clone();
+                response
+            })
+        };
+        let (a, b) = (serving(PROVIDER_A), serving(PROVIDER_B));
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        rpc_manager.register_provider(a.clone()).await.unwrap();
+        rpc_manager.register_provider(b.clone()).await.unwrap();
+        // Both providers answer at once, so hedging could let either answer a request
+        let config = ExitNodeConfig {
+            hedge: HedgeConfig {
+                enabled: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let exit = Arc::new(fixtures::exit_with(rpc_manager, config));
+        let signature = parsed(PROVIDER_A)["result"]["transaction"]["signatures"][0].clone();
+        let result = |provider: Uuid, normalize: bool| {
+            let payload = ExitPayload {
+                provider: Some(provider),
+                normalize,
+                ..fixtures::payload("getTransaction", serde_json::json!([signature, { "encoding": "json" }]))
+            };
+            let exit = exit.clone();
+            async move {
+                let response: Value = serde_json::from_slice(&exit.serve(&payload).await.unwrap()).unwrap();
+                response["result"].clone()
+            }
+        };
+        
+        assert_eq!(result(a.id, true).await, result(b.id, true).await);
+        assert_eq!(result(a.id, false).await, parsed(PROVIDER_A)["result"]);
+        assert_eq!(result(b.id, false).await, parsed(PROVIDER_B)["result"]);
+    }
+}
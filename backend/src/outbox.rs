@@ -0,0 +1,453 @@
+//! Outbox for the reports nodes send to the coordinator
+//!
+//! Heartbeats and accounting receipts must survive a coordinator outage without being
+//! lost or retried without bound. Reports are queued here and a sender task delivers them
+//! in priority order, heartbeats first, backing off while the coordinator is unreachable.
+//! The queue is bounded: when it is full, the oldest report of the lowest priority queued
+//! is dropped. A node only needs its latest heartbeat delivered, so queued heartbeats are
+//! folded into the next one instead of piling up. With a spool directory configured, the
+//! queue is also kept on disk as one newline-delimited JSON file per kind, rewritten by the
+//! sender task so queueing never waits on the disk, and reloaded when the node restarts.
+//! Reports are signed by the node as they are delivered, see
+//! [`crate::report_auth`].
+
+use super::*;
+use super::backoff::Backoff;
+use super::report_auth::ReportSigner;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::PathBuf;
+
+/// Bounds, spooling, and retry timing of the outbox
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboxConfig {
+    /// Reports kept before the lowest-priority ones are dropped
+    pub capacity: usize,
+    /// Directory the queue is spooled to, if it should survive restarts
+    #[serde(default)]
+    pub spool_dir: Option<PathBuf>,
+    /// Delay before retrying after the first failed delivery
+    pub initial_backoff: Duration,
+    /// Longest delay between retries
+    pub max_backoff: Duration,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1000,
+            spool_dir: None,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The kind of a report, in delivery priority order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportKind {
+    /// Liveness and activity, see [`crate::heartbeat`]
+    Heartbeat,
+    /// Signed work receipts, see [`crate::accounting`]
+    Accounting,
+    /// Usage statistics
+    Stats,
+}
+
+impl ReportKind {
+    /// Every kind, highest priority first
+    const ALL: [ReportKind; 3] = [ReportKind::Heartbeat, ReportKind::Accounting, ReportKind::Stats];
+    
+    /// Label used in metrics and spool file names
+    pub fn label(self) -> &'static str {
+        match self {
+            ReportKind::Heartbeat => "heartbeat",
+            ReportKind::Accounting => "accounting",
+            ReportKind::Stats => "stats",
+        }
+    }
+}
+
+/// A report waiting to be delivered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    /// The kind of report
+    pub kind: ReportKind,
+    /// Coordinator path the report is posted to
+    pub path: String,
+    /// The report itself
+    pub body: serde_json::Value,
+    /// When the report was queued
+    pub queued_at: Timestamp,
+}
+
+impl Report {
+    /// A report of `kind` posting `body` to `path`
+    pub fn new(kind: ReportKind, path: &str, body: &impl Serialize) -> Result<Self> {
+        Ok(Self {
+            kind,
+            path: path.to_string(),
+            body: serde_json::to_value(body)?,
+            queued_at: Timestamp::now(),
+        })
+    }
+}
+
+/// A queued report and the sequence number it was queued under
+struct Queued {
+    seq: u64,
+    report: Report,
+}
+
+/// Queued reports per kind, the one being delivered, and the kinds changed since spooled
+#[derive(Default)]
+struct Queue {
+    kinds: BTreeMap<ReportKind, VecDeque<Queued>>,
+    next_seq: u64,
+    in_flight: Option<u64>,
+    unspooled: BTreeSet<ReportKind>,
+}
+
+impl Queue {
+    fn len(&self) -> usize {
+        self.kinds.values().map(VecDeque::len).sum()
+    }
+}
+
+/// Bounded, prioritized queue of reports for the coordinator
+pub struct Outbox {
+    config: OutboxConfig,
+    queue: parking_lot::Mutex<Queue>,
+    wake: tokio::sync::Notify,
+    changed: tokio::sync::Notify,
+    spooling: Arc<tokio::sync::Mutex<()>>,
+    signer: Option<ReportSigner>,
+}
+
+impl Outbox {
+    /// Create an outbox, reloading the reports spooled before the node last stopped
+    pub fn open(config: OutboxConfig) -> Result<Self> {
+        let mut queue = Queue::default();
+        if let Some(dir) = &config.spool_dir {
+            std::fs::create_dir_all(dir)?;
+            for kind in ReportKind::ALL {
+                let path = spool_path(dir, kind);
+                let Ok(spooled) = std::fs::read_to_string(&path) else { continue };
+                let reports = queue.kinds.entry(kind).or_default();
+                for line in spooled.lines().filter(|line| !line.trim().is_empty()) {
+                    match serde_json::from_str::<Report>(line) {
+                        Ok(report) => {
+                            reports.push_back(Queued {
+                                seq: queue.next_seq,
+                                report,
+                            });
+                            queue.next_seq += 1;
+                        }
+                        Err(e) => tracing::warn!("Skipping unreadable report in {}: {}", path.display(), e),
+                    }
+                }
+            }
+        }
+        
+        let outbox = Self {
+            config,
+            queue: parking_lot::Mutex::new(queue),
+            wake: tokio::sync::Notify::new(),
+            changed: tokio::sync::Notify::new(),
+            spooling: Arc::new(tokio::sync::Mutex::new(())),
+            signer: None,
+        };
+        outbox.record_depth(&outbox.queue.lock());
+        Ok(outbox)
+    }
+    
+    /// Sign reports with `signer` as they are delivered, so the coordinator takes them
+    pub fn with_signer(mut self, signer: ReportSigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+    
+    /// Queue a report, dropping the oldest lowest-priority report if the outbox is full
+    pub fn push(&self, report: Report) {
+        let mut queue = self.queue.lock();
+        let kind = report.kind;
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.kinds.entry(kind).or_default().push_back(Queued { seq, report });
+        
+        let mut changed = vec![kind];
+        while queue.len() > self.config.capacity {
+            let in_flight = queue.in_flight;
+            let evicted = queue.kinds.iter_mut().rev().find_map(|(kind, reports)| {
+                let position = reports.iter().position(|queued| Some(queued.seq) != in_flight)?;
+                reports.remove(position).map(|_| *kind)
+            });
+            let Some(evicted) = evicted else { break };
+            metrics::increment_counter!("darknode_outbox_dropped_total", "kind" => evicted.label());
+            changed.push(evicted);
+        }
+        
+        queue.unspooled.extend(changed);
+        self.record_depth(&queue);
+        drop(queue);
+        self.changed.notify_one();
+        self.wake.notify_one();
+    }
+    
+    /// Remove the queued reports of `kind` that aren't being delivered, oldest first
+    ///
+    /// Used to fold queued heartbeats into the next one instead of sending each.
+    pub fn take(&self, kind: ReportKind) -> Vec<Report> {
+        let mut queue = self.queue.lock();
+        let in_flight = queue.in_flight;
+        let Some(reports) = queue.kinds.get_mut(&kind) else { return Vec::new() };
+        let (kept, taken): (VecDeque<Queued>, VecDeque<Queued>) =
+            reports.drain(..).partition(|queued| Some(queued.seq) == in_flight);
+        *reports = kept;
+        if !taken.is_empty() {
+            queue.unspooled.insert(kind);
+            self.record_depth(&queue);
+            self.changed.notify_one();
+        }
+        taken.into_iter().map(|queued| queued.report).collect()
+    }
+    
+    /// Reports currently queued
+    pub fn len(&self) -> usize {
+        self.queue.lock().len()
+    }
+    
+    /// Whether no reports are queued
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    /// Deliver queued reports to the coordinator, and keep the spool up to date, until
+    /// the task is dropped
+    pub async fn run(self: Arc<Self>, coordinator_url: String) {
+        tokio::join!(self.deliver(coordinator_url), self.keep_spooled());
+    }
+    
+    /// Write the queue to the spool directory as it stands, if spooling is on
+    ///
+    /// The sender task does this whenever the queue changes; calling it once more before
+    /// the node stops keeps what was queued since.
+    pub async fn flush(&self) {
+        let Some(dir) = &self.config.spool_dir else { return };
+        // One write at a time, so an older copy of the queue never replaces a newer one
+        let writing = self.spooling.clone().lock_owned().await;
+        let files: Vec<(ReportKind, PathBuf, String)> = {
+            let mut queue = self.queue.lock();
+            std::mem::take(&mut queue.unspooled)
+                .into_iter()
+                .map(|kind| (kind, spool_path(dir, kind), spooled(&queue, kind)))
+                .collect()
+        };
+        if files.is_empty() {
+            return;
+        }
+        let written = tokio::task::spawn_blocking(move || {
+            let _writing = writing;
+            for (kind, path, lines) in files {
+                spool(kind, &path, lines);
+            }
+        });
+        if let Err(e) = written.await {
+            tracing::warn!("Failed to spool reports: {}", e);
+        }
+    }
+    
+    /// Spool the queue each time it changes, until the task is dropped
+    async fn keep_spooled(&self) {
+        if self.config.spool_dir.is_none() {
+            return;
+        }
+        loop {
+            self.changed.notified().await;
+            self.flush().await;
+        }
+    }
+    
+    /// Deliver queued reports to the coordinator until the task is dropped
+    async fn deliver(&self, coordinator_url: String) {
+        let client = reqwest::Client::new();
+        let base = coordinator_url.trim_end_matches('/').to_string();
+        let mut backoff = Backoff::new(self.config.initial_backoff, self.config.max_backoff);
+        
+        loop {
+            let Some((seq, report)) = self.next() else {
+                self.wake.notified().await;
+                continue;
+            };
+            
+            let delivered = match &self.signer {
+                Some(signer) => signer.post(&client, &base, &report.path, &report.body).await,
+                None => client
+                    .post(format!("{}{}", base, report.path))
+                    .json(&report.body)
+                    .send()
+                    .await
+                    .map_err(Into::into),
+            };
+            let outcome = match delivered {
+                Ok(response) if response.status().is_success() => "delivered",
+                // The coordinator refused the report itself, so sending it again won't help
+                Ok(response) if response.status().is_client_error() => {
+                    tracing::warn!("Coordinator rejected {} report: {}", report.kind.label(), response.status());
+                    "rejected"
+                }
+                Ok(response) => {
+                    tracing::warn!("Failed to deliver {} report: {}", report.kind.label(), response.status());
+                    "failed"
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to deliver {} report: {}", report.kind.label(), e);
+                    "failed"
+                }
+            };
+            metrics::increment_counter!(
+                "darknode_outbox_deliveries_total",
+                "kind" => report.kind.label(),
+                "outcome" => outcome
+            );
+            
+            if outcome == "failed" {
+                self.queue.lock().in_flight = None;
+                tokio::time::sleep(backoff.next_delay()).await;
+            } else {
+                self.complete(report.kind, seq);
+                backoff.reset();
+            }
+        }
+    }
+    
+    /// Mark the highest-priority, oldest report as being delivered and return it
+    fn next(&self) -> Option<(u64, Report)> {
+        let mut queue = self.queue.lock();
+        let queued = queue.kinds.values().find_map(VecDeque::front)?;
+        let next = (queued.seq, queued.report.clone());
+        queue.in_flight = Some(next.0);
+        Some(next)
+    }
+    
+    /// Remove a report once it was delivered or rejected
+    fn complete(&self, kind: ReportKind, seq: u64) {
+        let mut queue = self.queue.lock();
+        queue.in_flight = None;
+        if let Some(reports) = queue.kinds.get_mut(&kind) {
+            reports.retain(|queued| queued.seq != seq);
+        }
+        queue.unspooled.insert(kind);
+        self.record_depth(&queue);
+        drop(queue);
+        self.changed.notify_one();
+    }
+    
+    /// Publish the queue depth per kind
+    fn record_depth(&self, queue: &Queue) {
+        for kind in ReportKind::ALL {
+            let depth = queue.kinds.get(&kind).map_or(0, VecDeque::len);
+            metrics::gauge!("darknode_outbox_depth", depth as f64, "kind" => kind.label());
+        }
+    }
+}
+
+/// The spool file of `kind` in `dir`
+fn spool_path(dir: &std::path::Path, kind: ReportKind) -> PathBuf {
+    dir.join(format!("{}.ndjson", kind.label()))
+}
+
+/// The queued reports of `kind` as spooled, one JSON line each
+fn spooled(queue: &Queue, kind: ReportKind) -> String {
+    queue
+        .kinds
+        .get(&kind)
+        .into_iter()
+        .flatten()
+        .filter_map(|queued| serde_json::to_string(&queued.report).ok())
+        .map(|line| line + "\n")
+        .collect()
+}
+
+/// Rewrite the spool file of `kind` at `path` with `lines`
+fn spool(kind: ReportKind, path: &std::path::Path, lines: String) {
+    // Write beside the spool file and rename, so a crash never leaves it half written
+    let partial = path.with_extension("ndjson.tmp");
+    let written = std::fs::write(&partial, lines).and_then(|()| std::fs::rename(&partial, path));
+    if let Err(e) = written {
+        tracing::warn!("Failed to spool {} reports to {}: {}", kind.label(), path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heartbeat::{self, ActivityCounters, HeartbeatSource};
+    use crate::types::{Heartbeat, NodeId, NodeRole};
+    
+    /// Nothing listens here, so every delivery fails as in a coordinator outage
+    const UNREACHABLE: &str = "http://127.0.0.1:1";
+    
+    const INTERVAL: Duration = Duration::from_secs(10);
+    
+    fn source() -> HeartbeatSource {
+        HeartbeatSource {
+            node_id: NodeId(Uuid::new_v4()),
+            roles: vec![NodeRole::Exit],
+            region: "us-east".to_string(),
+            method_classes: None,
+            attestation_key: None,
+            resources: None,
+        }
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn reports_queued_in_an_outage_outlive_a_restart_and_heartbeats_fold_into_one() {
+        let dir = std::env::temp_dir().join(format!("darknode-outbox-{}", Uuid::new_v4()));
+        let config = OutboxConfig {
+            spool_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        
+        // The coordinator is down while heartbeats and receipts pile up
+        let outbox = Arc::new(Outbox::open(config.clone()).unwrap());
+        let counters = Arc::new(ActivityCounters::new());
+        let sender = tokio::spawn(outbox.clone().run(UNREACHABLE.to_string()));
+        let beating = tokio::spawn(heartbeat::run(INTERVAL, source(), counters.clone(), outbox.clone()));
+        for epoch in 0..3 {
+            let receipt = serde_json::json!({ "epoch": epoch });
+            outbox.push(Report::new(ReportKind::Accounting, "/accounting/receipts", &receipt).unwrap());
+            counters.record_forwarded();
+            counters.record_forwarded();
+            tokio::time::advance(INTERVAL).await;
+            tokio::task::yield_now().await;
+        }
+        beating.abort();
+        sender.abort();
+        outbox.flush().await;
+        drop(outbox);
+        
+        // The node restarts, and the receipts are all still there, oldest first
+        let outbox = Arc::new(Outbox::open(config).unwrap());
+        let epochs: Vec<_> = outbox
+            .take(ReportKind::Accounting)
+            .into_iter()
+            .map(|report| report.body["epoch"].clone())
+            .collect();
+        assert_eq!(epochs, vec![0, 1, 2]);
+        
+        // Whatever heartbeats were spooled are folded into the first one sent after
+        let counters = Arc::new(ActivityCounters::new());
+        counters.record_forwarded();
+        let beating = tokio::spawn(heartbeat::run(INTERVAL, source(), counters, outbox.clone()));
+        tokio::task::yield_now().await;
+        beating.abort();
+        let heartbeats = outbox.take(ReportKind::Heartbeat);
+        assert_eq!(heartbeats.len(), 1);
+        let heartbeat: Heartbeat = serde_json::from_value(heartbeats[0].body.clone()).unwrap();
+        assert_eq!(heartbeat.counters.requests_forwarded, 7);
+        
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
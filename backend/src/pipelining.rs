@@ -0,0 +1,320 @@
+//! Pipelined requests on WebSocket connections, answered in order when asked
+//!
+//! Clients can send requests on a WebSocket without waiting for the answers to those
+//! before, up to [`PipelineConfig::max_in_flight`] at once. Each response goes out as soon
+//! as it is ready, so a slow call doesn't hold back the quick ones sent after it.
+//!
+//! Some client libraries assume responses arrive in the order the requests were sent on
+//! a connection, and break when they don't. Clients opening the connection with
+//! `X-DarkNode-Ordered: 1` get it that way: requests are numbered as they arrive, and a
+//! response completed early is held until those before it have been sent. At most
+//! [`PipelineConfig::reorder_buffer`] requests are outstanding on an ordered connection,
+//! so further requests aren't read until the oldest is answered. A request still
+//! unanswered [`PipelineConfig::head_of_line_timeout`] after it arrived is answered with a
+//! timeout error once it is the oldest, so one stuck request can't wedge the connection.
+//! The responses held behind it then go out, and its own, should it come later, is dropped.
+//!
+//! Whatever the ordering, subscription calls of one connection run one at a time in the
+//! order they arrived, see [`SubscriptionCalls`], so an `eth_unsubscribe` pipelined right
+//! behind its `eth_subscribe` can't run before there is a subscription to close.
+
+use super::*;
+use super::clock::Deadline;
+use super::timeouts::TIMEOUT_CODE;
+use std::collections::BTreeMap;
+use tokio::sync::oneshot;
+
+/// Header asking for responses in request order, `1` to opt in
+pub const ORDERED_HEADER: &str = "x-darknode-ordered";
+
+/// Whether an ordering header value opts in, `1` or `0`
+pub fn parse_header(value: &str) -> Option<bool> {
+    match value.trim() {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// How many requests a WebSocket connection may have outstanding, and for how long
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    /// Most requests of one connection being answered at once, 1 answering them one by one
+    pub max_in_flight: usize,
+    /// Most requests outstanding on an ordered connection, answered or not
+    pub reorder_buffer: usize,
+    /// How long the oldest request of an ordered connection may hold back those after it
+    pub head_of_line_timeout: Duration,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 16,
+            reorder_buffer: 64,
+            head_of_line_timeout: Duration::from_secs(75),
+        }
+    }
+}
+
+/// The oldest request of an ordered connection went unanswered for too long
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("request held back later responses for more than {}ms", limit.as_millis())]
+pub struct HeadOfLineTimeout {
+    /// How long it was allowed to
+    pub limit: Duration,
+}
+
+impl HeadOfLineTimeout {
+    /// A JSON-RPC response to request `id` reporting the timeout
+    pub fn response(&self, id: &serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {
+                "code": TIMEOUT_CODE,
+                "message": self.to_string(),
+                "data": { "timeout_ms": self.limit.as_millis() as u64 },
+            },
+        })
+    }
+}
+
+/// The id of a request as sent, null for batches and anything unparsable
+pub fn request_id(text: &str) -> serde_json::Value {
+    serde_json::from_str::<serde_json::Value>(text)
+        .ok()
+        .and_then(|request| request.get("id").cloned())
+        .unwrap_or_default()
+}
+
+/// Whether a request opens or closes a subscription, and so must wait for those before it
+pub fn is_subscription_call(text: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(text)
+        .ok()
+        .as_ref()
+        .and_then(methods::method_name)
+        .is_some_and(methods::is_subscription)
+}
+
+/// The subscription calls of one connection, run one at a time in the order they arrived
+#[derive(Default)]
+pub struct SubscriptionCalls {
+    last: Option<oneshot::Receiver<()>>,
+}
+
+impl SubscriptionCalls {
+    /// Queue a subscription call that just arrived behind those before it
+    pub fn queue(&mut self) -> Turn {
+        let (done, next) = oneshot::channel();
+        Turn {
+            before: self.last.replace(next),
+            _done: done,
+        }
+    }
+}
+
+/// The place of a subscription call in its connection's queue, given up when dropped
+pub struct Turn {
+    before: Option<oneshot::Receiver<()>>,
+    _done: oneshot::Sender<()>,
+}
+
+impl Turn {
+    /// Wait until the call before has finished, or was dropped unfinished
+    pub async fn wait(&mut self) {
+        if let Some(before) = self.before.take() {
+            let _ = before.await;
+        }
+    }
+}
+
+/// A request of an ordered connection not yet sent its response
+struct Slot {
+    id: serde_json::Value,
+    expires: Deadline,
+    /// The response once completed, `None` within for a notification that has none
+    response: Option<Option<serde_json::Value>>,
+}
+
+/// The requests outstanding on one connection, and the order their responses go out in
+pub struct ResponseOrder {
+    config: PipelineConfig,
+    ordered: bool,
+    next: u64,
+    in_flight: usize,
+    slots: BTreeMap<u64, Slot>,
+}
+
+impl ResponseOrder {
+    /// Track the requests of a connection, releasing responses in request order if `ordered`
+    pub fn new(config: &PipelineConfig, ordered: bool) -> Self {
+        Self {
+            config: PipelineConfig {
+                max_in_flight: config.max_in_flight.max(1),
+                reorder_buffer: config.reorder_buffer.max(1),
+                head_of_line_timeout: config.head_of_line_timeout,
+            },
+            ordered,
+            next: 0,
+            in_flight: 0,
+            slots: BTreeMap::new(),
+        }
+    }
+    
+    /// Whether responses are released in request order
+    pub fn is_ordered(&self) -> bool {
+        self.ordered
+    }
+    
+    /// Whether another request may be read from the connection
+    pub fn has_room(&self) -> bool {
+        self.in_flight < self.config.max_in_flight && (!self.ordered || self.slots.len() < self.config.reorder_buffer)
+    }
+    
+    /// Number a request with id `id` that is about to be answered
+    pub fn admit(&mut self, id: serde_json::Value) -> u64 {
+        let seq = self.next;
+        self.next += 1;
+        self.in_flight += 1;
+        if self.ordered {
+            let slot = Slot {
+                id,
+                expires: Deadline::after(self.config.head_of_line_timeout),
+                response: None,
+            };
+            self.slots.insert(seq, slot);
+        }
+        seq
+    }
+    
+    /// Take the response to request `seq`, returning those now due to be sent, in order
+    pub fn complete(&mut self, seq: u64, response: Option<serde_json::Value>) -> Vec<serde_json::Value> {
+        self.in_flight -= 1;
+        if !self.ordered {
+            return response.into_iter().collect();
+        }
+        // A request answered with a timeout already has no slot, and its response is dropped
+        if let Some(slot) = self.slots.get_mut(&seq) {
+            slot.response = Some(response);
+        }
+        self.release()
+    }
+    
+    /// When the oldest outstanding request of an ordered connection times out
+    pub fn head_deadline(&self) -> Option<Deadline> {
+        self.slots.values().next().map(|slot| slot.expires)
+    }
+    
+    /// Answer the oldest requests with a timeout while theirs have passed, returning the
+    /// responses now due to be sent, in order
+    pub fn expire_head(&mut self) -> Vec<serde_json::Value> {
+        let mut due = Vec::new();
+        while let Some(mut head) = self.slots.first_entry() {
+            if !head.get().expires.is_expired() {
+                break;
+            }
+            let slot = head.get_mut();
+            let timeout = HeadOfLineTimeout {
+                limit: self.config.head_of_line_timeout,
+            };
+            tracing::debug!("Request {} timed out at the head of an ordered connection", slot.id);
+            metrics::increment_counter!("darknode_pipeline_head_timeouts_total");
+            slot.response = Some(Some(timeout.response(&slot.id)));
+            due.extend(self.release());
+        }
+        due
+    }
+    
+    /// Pop the completed responses at the front of the line
+    fn release(&mut self) -> Vec<serde_json::Value> {
+        let mut due = Vec::new();
+        while let Some(head) = self.slots.first_entry() {
+            if head.get().response.is_none() {
+                break;
+            }
+            if let Some(Some(response)) = head.remove().response {
+                due.push(response);
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    
+    fn response(id: u64) -> serde_json::Value {
+        serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": id })
+    }
+    
+    fn ordered(head_of_line_timeout: Duration) -> ResponseOrder {
+        let config = PipelineConfig {
+            head_of_line_timeout,
+            ..Default::default()
+        };
+        ResponseOrder::new(&config, true)
+    }
+    
+    #[test]
+    fn responses_completed_in_reverse_are_sent_in_request_order_when_ordered() {
+        let mut order = ordered(Duration::from_secs(75));
+        let seqs: Vec<u64> = (0..3).map(|id| order.admit(serde_json::json!(id))).collect();
+        assert!(order.complete(seqs[2], Some(response(2))).is_empty());
+        assert!(order.complete(seqs[1], Some(response(1))).is_empty());
+        assert_eq!(order.complete(seqs[0], Some(response(0))), vec![response(0), response(1), response(2)]);
+        
+        let mut unordered = ResponseOrder::new(&PipelineConfig::default(), false);
+        let seqs: Vec<u64> = (0..3).map(|id| unordered.admit(serde_json::json!(id))).collect();
+        assert_eq!(unordered.complete(seqs[2], Some(response(2))), vec![response(2)]);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_head_request_past_its_timeout_is_answered_with_an_error_and_those_held_flush() {
+        let limit = Duration::from_secs(5);
+        let mut order = ordered(limit);
+        let seqs: Vec<u64> = (0..3).map(|id| order.admit(serde_json::json!(id))).collect();
+        assert!(order.complete(seqs[2], Some(response(2))).is_empty());
+        assert!(order.complete(seqs[1], Some(response(1))).is_empty());
+        
+        tokio::time::advance(limit - Duration::from_millis(1)).await;
+        assert!(order.expire_head().is_empty());
+        tokio::time::advance(Duration::from_millis(1)).await;
+        let timeout = HeadOfLineTimeout { limit }.response(&serde_json::json!(0));
+        assert_eq!(timeout["error"]["code"], TIMEOUT_CODE);
+        assert_eq!(order.expire_head(), vec![timeout, response(1), response(2)]);
+        
+        // The stuck request's own response is dropped when it comes
+        assert!(order.complete(seqs[0], Some(response(0))).is_empty());
+        assert_eq!(order.head_deadline(), None);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn an_unsubscribe_pipelined_behind_its_subscribe_runs_after_it() {
+        assert!(is_subscription_call(r#"{"jsonrpc":"2.0","id":1,"method":"eth_unsubscribe","params":["0x1"]}"#));
+        assert!(!is_subscription_call(r#"{"jsonrpc":"2.0","id":1,"method":"eth_blockNumber"}"#));
+        
+        let ran = std::sync::Mutex::new(Vec::new());
+        let mut calls = SubscriptionCalls::default();
+        let mut in_flight = futures::stream::FuturesUnordered::new();
+        for (method, takes) in [("eth_subscribe", Duration::from_millis(50)), ("eth_unsubscribe", Duration::ZERO)] {
+            let (mut turn, ran) = (calls.queue(), &ran);
+            in_flight.push(async move {
+                turn.wait().await;
+                tokio::time::sleep(takes).await;
+                ran.lock().unwrap().push(method);
+            });
+        }
+        while in_flight.next().await.is_some() {}
+        assert_eq!(*ran.lock().unwrap(), vec!["eth_subscribe", "eth_unsubscribe"]);
+        
+        // A call dropped unfinished doesn't hold up those behind it
+        let abandoned = calls.queue();
+        let mut next = calls.queue();
+        drop(abandoned);
+        tokio::time::timeout(Duration::from_secs(1), next.wait()).await.unwrap();
+    }
+}
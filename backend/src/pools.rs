@@ -0,0 +1,186 @@
+//! Per-operator provider pools
+//!
+//! Exit operators can contribute providers under their own pool tag. An exit node serves
+//! its traffic from its own pool first and only falls back to the shared pool, made up of
+//! untagged providers, as its policy allows. Other operators' pools are never used.
+
+use super::*;
+use super::types::RpcProvider;
+
+/// Label under which untagged providers' usage is reported
+pub const SHARED_POOL: &str = "shared";
+
+/// When an exit node with its own pool may use the shared pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolFallback {
+    /// Use the shared pool while no provider in the node's own pool is fit to serve a request
+    WhenUnavailable,
+    /// Never use the shared pool, failing requests instead
+    Never,
+}
+
+/// The provider pool an exit node serves from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// The node's own pool, or `None` to serve from the shared pool only
+    pub pool: Option<String>,
+    /// When the shared pool may be used instead of the node's own
+    pub fallback: PoolFallback,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            pool: None,
+            fallback: PoolFallback::WhenUnavailable,
+        }
+    }
+}
+
+/// The label a provider's usage is reported under
+pub fn label(pool: Option<&str>) -> &str {
+    pool.unwrap_or(SHARED_POOL)
+}
+
+/// No provider of an exit node's own pool is available, and it may not use the shared pool
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("No provider of pool {pool} is available")]
+pub struct PoolExhausted {
+    /// The node's own pool
+    pub pool: String,
+}
+
+/// The providers of `providers` an exit node may ever use: its own pool's, and the shared
+/// pool's unless its policy forbids falling back to it
+pub fn usable(providers: Vec<RpcProvider>, config: &PoolConfig) -> Vec<RpcProvider> {
+    let shared = config.pool.is_none() || config.fallback == PoolFallback::WhenUnavailable;
+    providers
+        .into_iter()
+        .filter(|provider| match &provider.pool {
+            Some(pool) => config.pool.as_ref() == Some(pool),
+            None => shared,
+        })
+        .collect()
+}
+
+/// The providers an exit node serves from, its own pool's if any of `providers` are in it
+///
+/// `providers` are the ones eligible for a request, so a pool whose providers are all
+/// unhealthy, draining or otherwise unfit gives way to the shared pool as the policy allows.
+pub fn select(providers: Vec<RpcProvider>, config: &PoolConfig) -> Result<Vec<RpcProvider>, PoolExhausted> {
+    let providers = usable(providers, config);
+    let own: Vec<RpcProvider> = providers
+        .iter()
+        .filter(|provider| config.pool.is_some() && provider.pool == config.pool)
+        .cloned()
+        .collect();
+    match (&config.pool, own.is_empty()) {
+        (_, false) => Ok(own),
+        (Some(pool), true) if providers.is_empty() => Err(PoolExhausted { pool: pool.clone() }),
+        _ => Ok(providers),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exit_node::ExitNodeConfig;
+    use crate::fixtures;
+    use crate::impls::StoredRpcManager;
+    use crate::storage::MemoryStorage;
+    use crate::traits::RpcManager;
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+    
+    fn pooled(pool: Option<&str>) -> RpcProvider {
+        RpcProvider {
+            pool: pool.map(str::to_string),
+            ..fixtures::provider()
+        }
+    }
+    
+    fn config(pool: Option<&str>, fallback: PoolFallback) -> PoolConfig {
+        PoolConfig {
+            pool: pool.map(str::to_string),
+            fallback,
+        }
+    }
+    
+    fn pools(providers: &[RpcProvider]) -> Vec<Option<&str>> {
+        providers.iter().map(|provider| provider.pool.as_deref()).collect()
+    }
+    
+    #[test]
+    fn the_own_pool_is_served_from_first_and_other_operators_pools_never() {
+        let providers = vec![pooled(Some("other")), pooled(None), pooled(Some("acme"))];
+        let own = config(Some("acme"), PoolFallback::WhenUnavailable);
+        assert_eq!(pools(&select(providers.clone(), &own).unwrap()), vec![Some("acme")]);
+        assert_eq!(pools(&usable(providers.clone(), &own)), vec![None, Some("acme")]);
+        
+        // Without its own pool's provider, the node falls back to the shared pool if it may
+        let left = providers[..2].to_vec();
+        assert_eq!(pools(&select(left.clone(), &own).unwrap()), vec![None]);
+        let strict = config(Some("acme"), PoolFallback::Never);
+        assert_eq!(select(left.clone(), &strict), Err(PoolExhausted { pool: "acme".to_string() }));
+        assert!(usable(left.clone(), &strict).is_empty());
+        
+        // A node without a pool only ever uses the shared one
+        let shared = config(None, PoolFallback::Never);
+        assert_eq!(pools(&select(providers, &shared).unwrap()), vec![None]);
+        assert_eq!(label(None), SHARED_POOL);
+    }
+    
+    /// A provider of `pool` answering every request with `name`
+    fn named(name: &'static str, pool: Option<&str>) -> RpcProvider {
+        RpcProvider {
+            pool: pool.map(str::to_string),
+            ..fixtures::serving(move |request: Value| async move { json!({ "jsonrpc": "2.0", "id": request["id"], "result": name }) })
+        }
+    }
+    
+    #[tokio::test]
+    async fn the_own_pool_is_used_alone_until_it_fails_then_the_shared_pool_takes_over() {
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        let own = named("own", Some("acme"));
+        for provider in [own.clone(), named("shared", None), named("other", Some("other"))] {
+            rpc_manager.register_provider(provider).await.unwrap();
+        }
+        let exit = Arc::new(fixtures::exit_with(
+            rpc_manager.clone(),
+            ExitNodeConfig {
+                pool: config(Some("acme"), PoolFallback::WhenUnavailable),
+                ..Default::default()
+            },
+        ));
+        let payload = fixtures::payload("getSlot", json!([]));
+        let served_by = || async {
+            let response = exit.serve(&payload).await?;
+            let answer: Value = serde_json::from_slice(&response)?;
+            Ok::<_, anyhow::Error>(answer["result"].as_str().unwrap_or_default().to_string())
+        };
+        for _ in 0..4 {
+            assert_eq!(served_by().await.unwrap(), "own");
+        }
+        
+        // The pool's only provider goes down: requests fail until its breaker opens, and
+        // the shared pool serves them from then on
+        let down = RpcProvider {
+            url: "http://127.0.0.1:1/".to_string(),
+            ..own
+        };
+        rpc_manager.update_provider(down).await.unwrap();
+        let failures = crate::breaker::BreakerConfig::default().consecutive_failures;
+        for _ in 0..failures {
+            assert!(served_by().await.is_err());
+        }
+        for _ in 0..4 {
+            assert_eq!(served_by().await.unwrap(), "shared");
+        }
+        
+        // Heartbeats report the requests each pool served
+        let usage = exit.counters().take_pool_usage();
+        assert_eq!(usage, BTreeMap::from([("acme".to_string(), 4), (SHARED_POOL.to_string(), 4)]));
+    }
+}
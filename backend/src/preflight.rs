@@ -0,0 +1,138 @@
+//! Transaction simulation before broadcast
+//!
+//! With pre-flight enabled on a mapping, the exit node simulates a transaction against
+//! the provider it is about to broadcast through, and answers with the simulation error
+//! instead of broadcasting a transaction that would fail or drain fees. Simulation results
+//! are only used to make that decision; they are never audited, cached, or logged.
+
+/// JSON-RPC error code Solana uses for a failed pre-flight simulation
+pub const PREFLIGHT_FAILURE_CODE: i64 = -32002;
+
+/// The simulation to run before forwarding `request`, if it is a send that can be simulated
+///
+/// Solana sends are skipped when the client set `skipPreflight`. Ethereum has no such
+/// flag, and raw Ethereum transactions are forwarded unsimulated since simulating them
+/// would require decoding the signed payload.
+pub fn simulation(request: &serde_json::Value) -> Option<serde_json::Value> {
+    let params = request["params"].as_array()?;
+    let (method, params) = match request["method"].as_str()? {
+        "sendTransaction" => {
+            let config = params.get(1).cloned().unwrap_or_else(|| serde_json::json!({}));
+            if config["skipPreflight"].as_bool() == Some(true) {
+                return None;
+            }
+            let mut simulate = serde_json::json!({
+                "encoding": config["encoding"].as_str().unwrap_or("base58"),
+                "sigVerify": true,
+            });
+            if let Some(commitment) = config["preflightCommitment"].as_str() {
+                simulate["commitment"] = serde_json::json!(commitment);
+            }
+            ("simulateTransaction", serde_json::json!([params.first()?, simulate]))
+        }
+        "eth_sendTransaction" => ("eth_estimateGas", serde_json::json!([params.first()?])),
+        _ => return None,
+    };
+    Some(serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": request["id"].clone(),
+        "method": method,
+        "params": params,
+    }))
+}
+
+/// The response to give the client instead of broadcasting, if the simulation failed
+pub fn rejection(request: &serde_json::Value, simulated: &serde_json::Value) -> Option<serde_json::Value> {
+    let error = if !simulated["error"].is_null() {
+        simulated["error"].clone()
+    } else {
+        let value = &simulated["result"]["value"];
+        if value["err"].is_null() {
+            return None;
+        }
+        serde_json::json!({
+            "code": PREFLIGHT_FAILURE_CODE,
+            "message": format!("Transaction simulation failed: {}", value["err"]),
+            "data": value,
+        })
+    };
+    Some(serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": request["id"].clone(),
+        "error": error,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::impls::StoredRpcManager;
+    use crate::storage::MemoryStorage;
+    use crate::traits::RpcManager;
+    use crate::types::ExitPayload;
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+    
+    /// An exit node in front of a provider whose simulations fail if `fails`, and the methods it was sent
+    async fn exit(fails: bool) -> (Arc<crate::exit_node::ExitNodeService>, Arc<Mutex<Vec<String>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let methods = seen.clone();
+        let provider = fixtures::serving(move |request: Value| {
+            methods.lock().unwrap().push(request["method"].as_str().unwrap_or_default().to_string());
+            async move {
+                let result = match request["method"].as_str() {
+                    Some("simulateTransaction") if fails => {
+                        json!({ "value": { "err": { "InstructionError": [0, { "Custom": 1 }] }, "logs": [] } })
+                    }
+                    Some("simulateTransaction") => json!({ "value": { "err": null, "logs": [] } }),
+                    _ => json!("5VERv8NMvzbJMEkV8xnrLkEaWRtSz9CosKDYjCJjBRnb"),
+                };
+                json!({ "jsonrpc": "2.0", "id": request["id"], "result": result })
+            }
+        });
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        rpc_manager.register_provider(provider).await.unwrap();
+        (Arc::new(fixtures::exit(rpc_manager)), seen)
+    }
+    
+    fn send(config: Value) -> ExitPayload {
+        ExitPayload {
+            preflight: true,
+            ..fixtures::payload("sendTransaction", json!(["AQABAg==", config]))
+        }
+    }
+    
+    #[tokio::test]
+    async fn a_failing_simulation_keeps_the_transaction_from_being_sent() {
+        let (exit, seen) = exit(true).await;
+        let answer: Value = serde_json::from_slice(&exit.serve(&send(json!({ "encoding": "base64" }))).await.unwrap()).unwrap();
+        assert_eq!(answer["error"]["code"], PREFLIGHT_FAILURE_CODE);
+        assert_eq!(*seen.lock().unwrap(), vec!["simulateTransaction"]);
+    }
+    
+    #[tokio::test]
+    async fn a_passing_simulation_lets_the_transaction_through() {
+        let (exit, seen) = exit(false).await;
+        let answer: Value = serde_json::from_slice(&exit.serve(&send(json!({ "encoding": "base64" }))).await.unwrap()).unwrap();
+        assert!(answer["error"].is_null(), "{}", answer);
+        assert!(answer["result"].is_string());
+        assert_eq!(*seen.lock().unwrap(), vec!["simulateTransaction", "sendTransaction"]);
+    }
+    
+    #[tokio::test]
+    async fn skipping_preflight_never_simulates() {
+        let (exit, seen) = exit(true).await;
+        let answer: Value = serde_json::from_slice(&exit.serve(&send(json!({ "skipPreflight": true }))).await.unwrap()).unwrap();
+        assert!(answer["result"].is_string(), "{}", answer);
+        assert_eq!(*seen.lock().unwrap(), vec!["sendTransaction"]);
+    }
+    
+    #[test]
+    fn raw_ethereum_transactions_are_not_simulated() {
+        let raw = json!({ "jsonrpc": "2.0", "id": 1, "method": "eth_sendRawTransaction", "params": ["0xf86c"] });
+        assert!(simulation(&raw).is_none());
+        let unsigned = json!({ "jsonrpc": "2.0", "id": 1, "method": "eth_sendTransaction", "params": [{ "to": "0x00" }] });
+        assert_eq!(simulation(&unsigned).unwrap()["method"], "eth_estimateGas");
+    }
+}
@@ -0,0 +1,159 @@
+//! Differential privacy noise for published network statistics
+//!
+//! Counts published per region and window can single out a user who dominates a quiet
+//! region. Published counts get Laplace noise scaled to `1 / epsilon`, drawn from a seed
+//! derived from the window, so repeating a query for the same window returns the same
+//! value instead of fresh noise that could be averaged away. Counts below the
+//! k-threshold are suppressed outright, judged on the raw count so noise can never lift
+//! them into view, and published values never fall below the threshold either.
+//!
+//! The seed is drawn from a secret kept in storage, shared by every coordinator on it and
+//! kept across restarts, so neither restarting nor asking another coordinator draws fresh
+//! noise for a window. The coordinator publishes requests per region this way on
+//! `GET /stats/regions`; the raw values stay on the operator's `/dashboard` routes.
+
+use super::*;
+use super::storage::{Collection, Storage};
+use rand::{Rng, SeedableRng};
+use sha2::{Digest, Sha256};
+
+/// Collection the noise secret is kept in
+const NOISE_SECRETS: &str = "noise_secrets";
+
+/// Key of the noise secret in its collection
+const NOISE_SECRET: &str = "published_counts";
+
+/// How published counts are noised and suppressed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NoiseConfig {
+    /// Whether published counts are noised at all
+    pub enabled: bool,
+    /// Privacy budget per published count; smaller means more noise
+    pub epsilon: f64,
+    /// Counts below this are never published
+    pub k_threshold: u64,
+}
+
+impl Default for NoiseConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            epsilon: 1.0,
+            k_threshold: 10,
+        }
+    }
+}
+
+/// Applies seeded noise and suppression to counts before they are published
+pub struct NoiseLayer {
+    config: NoiseConfig,
+    secret: [u8; 32],
+}
+
+impl NoiseLayer {
+    /// Create a layer seeding its noise from `secret`, so it can't be predicted from the window alone
+    pub fn new(config: NoiseConfig, secret: [u8; 32]) -> Self {
+        Self { config, secret }
+    }
+    
+    /// Create a layer with the secret kept in `storage`, storing a fresh one if there is none yet
+    pub async fn open(config: NoiseConfig, storage: Arc<dyn Storage + Send + Sync>) -> Result<Self> {
+        let secrets: Collection<[u8; 32]> = Collection::new(storage, NOISE_SECRETS);
+        let fresh: [u8; 32] = rand::random();
+        let secret = match secrets.update(NOISE_SECRET, |stored| Ok(stored.is_none().then_some(fresh))).await? {
+            Some(secret) => secret,
+            None => secrets
+                .get(NOISE_SECRET)
+                .await?
+                .map(|(secret, _)| secret)
+                .ok_or_else(|| anyhow::anyhow!("Noise secret vanished from storage"))?,
+        };
+        Ok(Self::new(config, secret))
+    }
+    
+    /// The count to publish for `raw` in the window identified by `window`, if any
+    ///
+    /// `window` should name both the series and the window, e.g. `"requests/eu-west/1700000000"`,
+    /// so every published count draws its own noise.
+    pub fn publish(&self, raw: u64, window: &str) -> Option<u64> {
+        if raw < self.config.k_threshold {
+            return None;
+        }
+        if !self.config.enabled {
+            return Some(raw);
+        }
+        let noised = (raw as f64 + self.laplace(window)).round().max(0.0) as u64;
+        Some(noised.max(self.config.k_threshold))
+    }
+    
+    /// Laplace noise for sensitivity 1, the same on every call for the same window
+    fn laplace(&self, window: &str) -> f64 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.secret);
+        hasher.update(window.as_bytes());
+        let seed: [u8; 32] = hasher.finalize().into();
+        let mut rng = rand::rngs::StdRng::from_seed(seed);
+        
+        let scale = 1.0 / self.config.epsilon.max(f64::MIN_POSITIVE);
+        let u: f64 = rng.gen_range(-0.5..0.5);
+        -scale * u.signum() * (1.0 - 2.0 * u.abs()).ln()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+    
+    fn layer(epsilon: f64) -> NoiseLayer {
+        let config = NoiseConfig {
+            enabled: true,
+            epsilon,
+            k_threshold: 10,
+        };
+        NoiseLayer::new(config, [7; 32])
+    }
+    
+    #[test]
+    fn a_window_is_published_with_the_same_noise_every_time() {
+        let layer = layer(0.5);
+        let first = layer.publish(1_000, "requests/eu-west/1700000000");
+        for _ in 0..10 {
+            assert_eq!(layer.publish(1_000, "requests/eu-west/1700000000"), first);
+        }
+    }
+    
+    #[test]
+    fn noise_grows_as_epsilon_shrinks() {
+        let spread = |layer: &NoiseLayer| {
+            (0..500)
+                .map(|window| (layer.publish(100_000, &format!("requests/eu-west/{}", window)).unwrap() as f64 - 100_000.0).abs())
+                .sum::<f64>()
+                / 500.0
+        };
+        assert!(spread(&layer(0.01)) > 10.0 * spread(&layer(1.0)));
+    }
+    
+    #[test]
+    fn counts_below_the_threshold_never_appear() {
+        let layer = layer(0.01);
+        for window in 0..500 {
+            assert_eq!(layer.publish(9, &format!("requests/ap-south/{}", window)), None);
+            assert!(layer.publish(10, &format!("requests/ap-south/{}", window)).unwrap() >= 10);
+        }
+    }
+    
+    #[tokio::test]
+    async fn layers_on_the_same_storage_draw_the_same_noise() {
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(MemoryStorage::new());
+        let config = NoiseConfig {
+            enabled: true,
+            epsilon: 0.1,
+            k_threshold: 10,
+        };
+        let first = NoiseLayer::open(config.clone(), storage.clone()).await.unwrap();
+        let restarted = NoiseLayer::open(config, storage).await.unwrap();
+        assert_eq!(first.publish(500, "requests/eu-west/1"), restarted.publish(500, "requests/eu-west/1"));
+    }
+}
@@ -0,0 +1,287 @@
+//! Versions of the protocol nodes speak to each other, and how circuits agree on one
+//!
+//! Wire formats change between releases, and a network is never upgraded all at once.
+//! Every node advertises the range of protocol versions it speaks in its [`Node`] record;
+//! a node registered before versions existed speaks version 1 only. An entry node builds
+//! circuits only through nodes whose range overlaps its own, at the highest version all
+//! of the circuit's nodes speak, and records that version on the circuit. The exit node
+//! is told the version when it joins the circuit, and refuses versions it doesn't speak,
+//! so both ends frame the circuit's plaintext the same way.
+//!
+//! Nodes outside an entry node's range stay in the directory, but are skipped when
+//! building circuits and counted in `darknode_incompatible_nodes_total`. The coordinator
+//! reports how the network's nodes are spread across versions, and across the releases
+//! they run, on `GET /nodes/versions`.
+//!
+//! | Version | Plaintext framing |
+//! |---------|-------------------|
+//! | 1       | The payload as is |
+//! | 2       | A 4-byte big-endian length, the payload, then zeros up to a multiple of [`PADDING_BLOCK`] bytes |
+
+use super::*;
+use super::types::{Node, NodeId};
+use std::collections::BTreeMap;
+
+/// The highest protocol version this release speaks
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// The lowest protocol version this release still speaks
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Block size version 2 pads plaintexts to, so their length only shows to the block
+pub const PADDING_BLOCK: usize = 256;
+
+/// An inclusive range of protocol versions a node speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ProtocolRange {
+    /// The lowest version spoken
+    pub min: u16,
+    /// The highest version spoken
+    pub max: u16,
+}
+
+impl ProtocolRange {
+    /// The versions this release speaks
+    pub const SUPPORTED: ProtocolRange = ProtocolRange {
+        min: MIN_PROTOCOL_VERSION,
+        max: PROTOCOL_VERSION,
+    };
+    
+    /// The versions of a node that doesn't advertise any: version 1 only
+    pub const LEGACY: ProtocolRange = ProtocolRange { min: 1, max: 1 };
+    
+    /// The range of nodes that don't advertise one, for serde defaults
+    pub fn legacy() -> Self {
+        Self::LEGACY
+    }
+    
+    /// Whether `version` is in the range
+    pub fn contains(&self, version: u16) -> bool {
+        self.min <= version && version <= self.max
+    }
+    
+    /// The versions both ranges speak, if any
+    pub fn intersect(&self, other: &ProtocolRange) -> Option<ProtocolRange> {
+        let range = ProtocolRange {
+            min: self.min.max(other.min),
+            max: self.max.min(other.max),
+        };
+        (range.min <= range.max).then_some(range)
+    }
+}
+
+impl Default for ProtocolRange {
+    fn default() -> Self {
+        Self::SUPPORTED
+    }
+}
+
+impl std::fmt::Display for ProtocolRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.min == self.max {
+            write!(f, "v{}", self.min)
+        } else {
+            write!(f, "v{}-v{}", self.min, self.max)
+        }
+    }
+}
+
+/// The version a circuit speaks: the highest there is a node speaking it for every hop
+///
+/// `hops` holds the candidate nodes for each hop; a circuit built only from candidates
+/// speaking the version has every node speak it.
+pub fn negotiate(hops: &[Vec<&Node>]) -> Option<u16> {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION)
+        .rev()
+        .find(|version| speakable(hops, *version))
+}
+
+/// Whether a circuit can speak `version`: this release does, and so does a node for every hop
+pub fn speakable(hops: &[Vec<&Node>], version: u16) -> bool {
+    ProtocolRange::SUPPORTED.contains(version)
+        && hops.iter().all(|nodes| nodes.iter().any(|node| node.protocol.contains(version)))
+}
+
+/// The protocol version of circuits built before versions existed, for serde defaults
+pub fn legacy_version() -> u16 {
+    ProtocolRange::LEGACY.max
+}
+
+/// A node was asked to speak a protocol version it doesn't
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("protocol v{version} is not supported, this node speaks {supported}")]
+pub struct UnsupportedVersion {
+    /// The version asked for
+    pub version: u16,
+    /// The versions the node speaks
+    pub supported: ProtocolRange,
+}
+
+/// Check that `version` is one this release speaks
+pub fn check(version: u16) -> Result<(), UnsupportedVersion> {
+    if ProtocolRange::SUPPORTED.contains(version) {
+        Ok(())
+    } else {
+        Err(UnsupportedVersion {
+            version,
+            supported: ProtocolRange::SUPPORTED,
+        })
+    }
+}
+
+/// Frame a circuit's plaintext as `version` carries it
+pub fn frame(version: u16, payload: &[u8]) -> Vec<u8> {
+    match version {
+        1 => payload.to_vec(),
+        _ => {
+            let framed = 4 + payload.len();
+            let padded = (framed + PADDING_BLOCK - 1) / PADDING_BLOCK * PADDING_BLOCK;
+            let mut bytes = Vec::with_capacity(padded);
+            bytes.extend((payload.len() as u32).to_be_bytes());
+            bytes.extend(payload);
+            bytes.resize(padded, 0);
+            bytes
+        }
+    }
+}
+
+/// The payload of a circuit plaintext framed as `version` carries it
+pub fn unframe(version: u16, bytes: &[u8]) -> Result<Vec<u8>> {
+    match version {
+        1 => Ok(bytes.to_vec()),
+        _ => {
+            let (length, rest) = bytes
+                .split_first_chunk::<4>()
+                .ok_or_else(|| anyhow::anyhow!("Frame of {} bytes is too short for its length", bytes.len()))?;
+            let length = u32::from_be_bytes(*length) as usize;
+            match rest.get(..length) {
+                Some(payload) => Ok(payload.to_vec()),
+                None => anyhow::bail!("Frame of {} bytes claims a {} byte payload", bytes.len(), length),
+            }
+        }
+    }
+}
+
+/// How the network's nodes are spread across protocol versions
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionReport {
+    /// The versions the reporting node speaks
+    pub supported: ProtocolRange,
+    /// Nodes advertising each range, such as `v1-v2`
+    pub by_range: BTreeMap<String, usize>,
+    /// Nodes by the highest version they speak
+    pub by_max_version: BTreeMap<u16, usize>,
+    /// Nodes by the release they run, such as `0.1.0+1a2b3c4d5e6f`, see [`crate::build_info`]
+    #[serde(default)]
+    pub by_release: BTreeMap<String, usize>,
+    /// Nodes sharing no version with the reporting node, left out of circuits it builds
+    pub incompatible: Vec<NodeId>,
+}
+
+impl VersionReport {
+    /// Report on `nodes`, each counted once
+    pub fn of<'a>(nodes: impl IntoIterator<Item = &'a Node>) -> Self {
+        let mut report = Self::default();
+        for node in nodes {
+            *report.by_range.entry(node.protocol.to_string()).or_insert(0) += 1;
+            *report.by_max_version.entry(node.protocol.max).or_insert(0) += 1;
+            *report.by_release.entry(build_info::release_of(node.build.as_ref())).or_insert(0) += 1;
+            if report.supported.intersect(&node.protocol).is_none() {
+                report.incompatible.push(node.id.clone());
+            }
+        }
+        report
+    }
+}
+
+/// Whether a node speaks a version in this release's range, counting it by role if not
+pub fn compatible(node: &Node, role: types::NodeRole) -> bool {
+    let compatible = ProtocolRange::SUPPORTED.intersect(&node.protocol).is_some();
+    if !compatible {
+        metrics::increment_counter!("darknode_incompatible_nodes_total", "role" => role.name());
+    }
+    compatible
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::impls::{CryptoImpl, RouterImpl, StoredNodeManager};
+    use crate::storage::MemoryStorage;
+    use crate::traits::{NodeManager, Router};
+    use crate::types::NodeRole;
+    
+    fn speaking(roles: &[NodeRole], protocol: ProtocolRange) -> Node {
+        Node {
+            protocol,
+            ..fixtures::node(roles)
+        }
+    }
+    
+    /// The count of incompatible nodes of `role` the process has recorded
+    fn incompatible(role: NodeRole) -> u64 {
+        let label = format!("darknode_incompatible_nodes_total{{role=\"{}\"}} ", role.name());
+        fixtures::prometheus()
+            .render()
+            .lines()
+            .find_map(|line| line.strip_prefix(label.as_str()))
+            .map_or(0, |count| count.trim().parse().unwrap())
+    }
+    
+    #[test]
+    fn a_hop_speaking_only_v1_holds_the_circuit_to_v1_framing() {
+        let entry = speaking(&[NodeRole::Entry], ProtocolRange::SUPPORTED);
+        let legacy = speaking(&[NodeRole::Routing], ProtocolRange::LEGACY);
+        let current = speaking(&[NodeRole::Routing], ProtocolRange::SUPPORTED);
+        let exit = speaking(&[NodeRole::Exit], ProtocolRange::SUPPORTED);
+        assert_eq!(negotiate(&[vec![&entry], vec![&legacy], vec![&exit]]), Some(1));
+        assert_eq!(negotiate(&[vec![&entry], vec![&legacy, &current], vec![&exit]]), Some(2));
+        
+        let ahead = speaking(&[NodeRole::Exit], ProtocolRange { min: 3, max: 4 });
+        assert_eq!(negotiate(&[vec![&entry], vec![&current], vec![&ahead]]), None);
+        assert_eq!(check(3), Err(UnsupportedVersion { version: 3, supported: ProtocolRange::SUPPORTED }));
+        
+        // Version 1 carries the payload as is, and version 2 hides its length to the block
+        let payload = br#"{"jsonrpc":"2.0","id":1,"method":"getSlot"}"#;
+        assert_eq!(frame(1, payload), payload);
+        let framed = frame(2, payload);
+        assert_eq!(framed.len(), PADDING_BLOCK);
+        for version in [1, 2] {
+            assert_eq!(unframe(version, &frame(version, payload)).unwrap(), payload);
+        }
+        assert!(unframe(2, &framed[..8]).is_err());
+    }
+    
+    #[tokio::test]
+    async fn circuits_through_a_v1_hop_speak_v1_and_skip_nodes_out_of_range() {
+        let node_manager = Arc::new(StoredNodeManager::new(Arc::new(MemoryStorage::new())));
+        let legacy = speaking(&[NodeRole::Routing], ProtocolRange::LEGACY);
+        let ahead = speaking(&[NodeRole::Routing], ProtocolRange { min: 3, max: 4 });
+        let ends = [
+            speaking(&[NodeRole::Entry], ProtocolRange::SUPPORTED),
+            speaking(&[NodeRole::Exit], ProtocolRange::SUPPORTED),
+        ];
+        let nodes: Vec<Node> = ends.into_iter().chain([legacy.clone(), ahead.clone()]).collect();
+        for node in nodes.clone() {
+            node_manager.register_node(node).await.unwrap();
+        }
+        let router = RouterImpl::new(node_manager.clone(), Arc::new(CryptoImpl::new()));
+        
+        let skipped = incompatible(NodeRole::Routing);
+        for _ in 0..8 {
+            let circuit = router.create_circuit().await.unwrap();
+            assert_eq!(circuit.protocol_version, 1);
+            assert_eq!(circuit.routing_nodes, vec![legacy.id.clone()]);
+        }
+        assert!(incompatible(NodeRole::Routing) >= skipped + 8);
+        
+        // The node out of range stays in the directory, marked as such in the version report
+        assert!(node_manager.get_node(&ahead.id).await.unwrap().is_some());
+        let report = VersionReport::of(&nodes);
+        assert_eq!(report.incompatible, vec![ahead.id.clone()]);
+        assert_eq!(report.by_range["v3-v4"], 1);
+        assert_eq!(report.by_max_version[&2], 2);
+        assert_eq!(report.by_max_version[&1], 1);
+    }
+}
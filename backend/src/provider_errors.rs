@@ -0,0 +1,312 @@
+//! Normalizing provider error responses to standard codes
+//!
+//! Providers for the same chain disagree on error shapes: one returns `-32000` with
+//! "execution reverted" in the message, another a string code and the revert data nested
+//! two levels deep, a third a non-standard code. Clients would see different errors for
+//! the same failure depending on which provider the exit node picked, so errors that are
+//! recognized are rewritten to the standard JSON-RPC and EIP-1474 codes with a fixed
+//! message. A revert keeps its reason, taken from the provider's message or decoded from
+//! the revert data, since it is what the contract says went wrong. Unrecognized errors
+//! pass through untouched.
+
+use serde_json::Value;
+
+/// An error recognized across providers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The request was not valid JSON
+    ParseError,
+    /// The request was not a valid JSON-RPC request
+    InvalidRequest,
+    /// The method does not exist or is not available
+    MethodNotFound,
+    /// The method parameters were invalid
+    InvalidParams,
+    /// The provider failed internally
+    Internal,
+    /// The requested block, transaction, or other resource was not found
+    ResourceNotFound,
+    /// The transaction was rejected before execution
+    TransactionRejected,
+    /// The provider refused the request because a rate or size limit was exceeded
+    LimitExceeded,
+    /// Execution reverted
+    ExecutionReverted,
+}
+
+impl ErrorClass {
+    /// The standard code for the class
+    pub fn code(self) -> i64 {
+        match self {
+            ErrorClass::ParseError => -32700,
+            ErrorClass::InvalidRequest => -32600,
+            ErrorClass::MethodNotFound => -32601,
+            ErrorClass::InvalidParams => -32602,
+            ErrorClass::Internal => -32603,
+            ErrorClass::ResourceNotFound => -32001,
+            ErrorClass::TransactionRejected => -32003,
+            ErrorClass::LimitExceeded => -32005,
+            ErrorClass::ExecutionReverted => 3,
+        }
+    }
+    
+    /// The message every provider's error of this class is reported with
+    pub fn message(self) -> &'static str {
+        match self {
+            ErrorClass::ParseError => "Parse error",
+            ErrorClass::InvalidRequest => "Invalid request",
+            ErrorClass::MethodNotFound => "Method not found",
+            ErrorClass::InvalidParams => "Invalid params",
+            ErrorClass::Internal => "Internal error",
+            ErrorClass::ResourceNotFound => "Resource not found",
+            ErrorClass::TransactionRejected => "Transaction rejected",
+            ErrorClass::LimitExceeded => "Limit exceeded",
+            ErrorClass::ExecutionReverted => "execution reverted",
+        }
+    }
+    
+    /// Label used in metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            ErrorClass::ParseError => "parse_error",
+            ErrorClass::InvalidRequest => "invalid_request",
+            ErrorClass::MethodNotFound => "method_not_found",
+            ErrorClass::InvalidParams => "invalid_params",
+            ErrorClass::Internal => "internal",
+            ErrorClass::ResourceNotFound => "resource_not_found",
+            ErrorClass::TransactionRejected => "transaction_rejected",
+            ErrorClass::LimitExceeded => "limit_exceeded",
+            ErrorClass::ExecutionReverted => "execution_reverted",
+        }
+    }
+    
+    /// The class of a standard code
+    fn from_code(code: i64) -> Option<Self> {
+        [
+            ErrorClass::ParseError,
+            ErrorClass::InvalidRequest,
+            ErrorClass::MethodNotFound,
+            ErrorClass::InvalidParams,
+            ErrorClass::Internal,
+            ErrorClass::ResourceNotFound,
+            ErrorClass::TransactionRejected,
+            ErrorClass::LimitExceeded,
+        ]
+        .into_iter()
+        .find(|class| class.code() == code)
+    }
+}
+
+/// Message fragments of Ethereum provider errors, checked in order before the code
+const ETHEREUM_MESSAGES: &[(&str, ErrorClass)] = &[
+    ("execution reverted", ErrorClass::ExecutionReverted),
+    ("vm execution error", ErrorClass::ExecutionReverted),
+    ("nonce too low", ErrorClass::TransactionRejected),
+    ("nonce too high", ErrorClass::TransactionRejected),
+    ("already known", ErrorClass::TransactionRejected),
+    ("replacement transaction underpriced", ErrorClass::TransactionRejected),
+    ("transaction underpriced", ErrorClass::TransactionRejected),
+    ("insufficient funds", ErrorClass::TransactionRejected),
+    ("intrinsic gas too low", ErrorClass::TransactionRejected),
+    ("rate limit", ErrorClass::LimitExceeded),
+    ("too many requests", ErrorClass::LimitExceeded),
+    ("exceeded its compute units", ErrorClass::LimitExceeded),
+    ("query returned more than", ErrorClass::LimitExceeded),
+    ("header not found", ErrorClass::ResourceNotFound),
+    ("unknown block", ErrorClass::ResourceNotFound),
+    ("method not found", ErrorClass::MethodNotFound),
+    ("does not exist/is not available", ErrorClass::MethodNotFound),
+    ("unsupported method", ErrorClass::MethodNotFound),
+    ("invalid argument", ErrorClass::InvalidParams),
+    ("invalid params", ErrorClass::InvalidParams),
+];
+
+/// Selector of `Error(string)`, the revert data of a `revert("reason")`
+const ERROR_STRING_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+/// What precedes the reason in the message of a revert
+const REVERTED_PREFIX: &str = "execution reverted: ";
+
+/// The code of a provider error, whether sent as a number or a string
+fn error_code(error: &Value) -> Option<i64> {
+    match &error["code"] {
+        Value::Number(code) => code.as_i64(),
+        Value::String(code) => code.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Every string in an error, lowercased, so messages nested in `data` are matched too
+fn error_text(error: &Value) -> String {
+    fn collect(value: &Value, text: &mut String) {
+        match value {
+            Value::String(s) => {
+                text.push_str(&s.to_lowercase());
+                text.push('\n');
+            }
+            Value::Array(values) => values.iter().for_each(|value| collect(value, text)),
+            Value::Object(fields) => fields.values().for_each(|value| collect(value, text)),
+            _ => {}
+        }
+    }
+    let mut text = String::new();
+    collect(error, &mut text);
+    text
+}
+
+/// The revert data of a reverted call, wherever the provider put it
+fn revert_data(error: &Value) -> Option<Value> {
+    fn find(value: &Value) -> Option<&str> {
+        match value {
+            // Some providers prefix the data, as in "Reverted 0x08c3..."
+            Value::String(s) => s.split_whitespace().find(|word| word.starts_with("0x")),
+            Value::Object(fields) => fields.values().find_map(find),
+            _ => None,
+        }
+    }
+    find(&error["data"]).map(|data| Value::String(data.to_string()))
+}
+
+/// The reason of a revert, as the provider's message states it or else decoded from the
+/// revert data of a `revert("reason")`
+fn revert_reason(error: &Value, data: Option<&Value>) -> Option<String> {
+    let message = error["message"].as_str().unwrap_or_default();
+    let stated = message
+        .to_lowercase()
+        .find(REVERTED_PREFIX)
+        .and_then(|at| message.get(at + REVERTED_PREFIX.len()..))
+        .map(str::trim)
+        .filter(|reason| !reason.is_empty());
+    if let Some(reason) = stated {
+        return Some(reason.to_string());
+    }
+    
+    // ABI encoded as the selector, then the string's offset, length and bytes in 32-byte words
+    let data = crate::hex::decode(data?.as_str()?.strip_prefix("0x")?)?;
+    let encoded = data.strip_prefix(&ERROR_STRING_SELECTOR)?;
+    let word = |at: usize| -> Option<usize> {
+        let word = encoded.get(at..at.checked_add(32)?)?;
+        if word[..24].iter().any(|byte| *byte != 0) {
+            return None;
+        }
+        usize::try_from(u64::from_be_bytes(word[24..].try_into().ok()?)).ok()
+    };
+    let offset = word(0)?;
+    let len = word(offset)?;
+    let start = offset.checked_add(32)?;
+    let reason = encoded.get(start..start.checked_add(len)?)?;
+    String::from_utf8(reason.to_vec()).ok().filter(|reason| !reason.is_empty())
+}
+
+/// The class of a provider error, if it is recognized for the provider's type
+pub fn classify(provider_type: &str, error: &Value) -> Option<ErrorClass> {
+    match provider_type {
+        "ethereum" => {
+            let text = error_text(error);
+            ETHEREUM_MESSAGES
+                .iter()
+                .find(|(fragment, _)| text.contains(fragment))
+                .map(|(_, class)| *class)
+                .or_else(|| error_code(error).and_then(ErrorClass::from_code))
+                .or_else(|| (error_code(error) == Some(429)).then_some(ErrorClass::LimitExceeded))
+        }
+        // Solana validators already return one error shape
+        _ => None,
+    }
+}
+
+/// Rewrite the error in a provider response to its standard form
+///
+/// With `debug`, the provider's original error is kept under `error.data.provider_error`;
+/// a revert's data then moves to `error.data.revert`. Responses that aren't errors, or
+/// whose errors aren't recognized, are returned unchanged.
+pub fn normalize(provider_type: &str, response: Vec<u8>, debug: bool) -> Vec<u8> {
+    let Ok(mut parsed) = serde_json::from_slice::<Value>(&response) else { return response };
+    let Some(original) = parsed.get("error").filter(|error| error.is_object()).cloned() else {
+        return response;
+    };
+    let Some(class) = classify(provider_type, &original) else {
+        metrics::increment_counter!("darknode_provider_errors_total", "class" => "unrecognized");
+        return response;
+    };
+    metrics::increment_counter!("darknode_provider_errors_total", "class" => class.label());
+    
+    let data = match class {
+        ErrorClass::ExecutionReverted => revert_data(&original),
+        _ => None,
+    };
+    let message = match class {
+        ErrorClass::ExecutionReverted => match revert_reason(&original, data.as_ref()) {
+            Some(reason) => format!("{}: {}", class.message(), reason),
+            None => class.message().to_string(),
+        },
+        _ => class.message().to_string(),
+    };
+    let mut error = serde_json::json!({
+        "code": class.code(),
+        "message": message,
+    });
+    if debug {
+        let mut debug_data = serde_json::json!({ "provider_error": original });
+        if let Some(data) = data {
+            debug_data["revert"] = data;
+        }
+        error["data"] = debug_data;
+    } else if let Some(data) = data {
+        error["data"] = data;
+    }
+    parsed["error"] = error;
+    serde_json::to_vec(&parsed).unwrap_or(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    /// `revert("Ownable: caller is not the owner")` as ABI-encoded revert data
+    const REVERT_DATA: &str = "0x08c379a0000000000000000000000000000000000000000000000000000000000000002000000000000000000000000000000000000000000000000000000000000000204f776e61626c653a2063616c6c6572206973206e6f7420746865206f776e6572";
+    
+    fn normalized(error: Value, debug: bool) -> Value {
+        let response = serde_json::to_vec(&json!({ "jsonrpc": "2.0", "id": 1, "error": error })).unwrap();
+        serde_json::from_slice(&normalize("ethereum", response, debug)).unwrap()
+    }
+    
+    #[test]
+    fn reverts_from_three_providers_normalize_alike_with_their_reason() {
+        let geth = json!({ "code": 3, "message": "execution reverted: Ownable: caller is not the owner", "data": REVERT_DATA });
+        let infura = json!({ "code": -32000, "message": "execution reverted", "data": REVERT_DATA });
+        let quicknode = json!({ "code": "-32015", "message": "VM execution error.", "data": { "detail": format!("Reverted {}", REVERT_DATA) } });
+        
+        let expected = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": { "code": 3, "message": "execution reverted: Ownable: caller is not the owner", "data": REVERT_DATA },
+        });
+        for error in [geth, infura, quicknode] {
+            assert_eq!(normalized(error, false), expected);
+        }
+    }
+    
+    #[test]
+    fn rate_limits_from_three_providers_normalize_alike() {
+        let alchemy = json!({ "code": 429, "message": "Your app has exceeded its compute units per second capacity" });
+        let infura = json!({ "code": -32005, "message": "daily request count exceeded, request rate limited" });
+        let quicknode = json!({ "code": "-32007", "message": "Too many requests" });
+        for error in [alchemy, infura, quicknode] {
+            assert_eq!(normalized(error, false)["error"], json!({ "code": -32005, "message": "Limit exceeded" }));
+        }
+    }
+    
+    #[test]
+    fn debug_keeps_the_original_error_and_unknown_errors_pass_through() {
+        let original = json!({ "code": -32000, "message": "execution reverted", "data": REVERT_DATA });
+        let error = &normalized(original.clone(), true)["error"];
+        assert_eq!(error["message"], "execution reverted: Ownable: caller is not the owner");
+        assert_eq!(error["data"]["provider_error"], original);
+        assert_eq!(error["data"]["revert"], REVERT_DATA);
+        
+        let unknown = json!({ "code": -32099, "message": "something new" });
+        assert_eq!(normalized(unknown.clone(), false)["error"], unknown);
+    }
+}
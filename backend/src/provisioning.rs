@@ -0,0 +1,440 @@
+//! Importing and exporting a user's RPC mappings in bulk
+//!
+//! Teams moving many dapps over provision their mappings from one file on
+//! `POST /mappings/import` rather than one at a time, and back them up with
+//! `GET /mappings/export`, which writes the same format. A file is either JSON, an array
+//! of rows, or CSV with a header line naming its columns, fields quoted to hold commas:
+//!
+//! | Column         | Required | Value                                                        |
+//! |----------------|----------|--------------------------------------------------------------|
+//! | `original_rpc` | yes      | The provider's URL, `http(s)://` or `ws(s)://`                |
+//! | `chain`        | no       | The chain the mapping serves, `solana` or `ethereum`         |
+//! | `network`      | no       | Its network, `mainnet` if not given, `testnet` or `devnet`   |
+//! | `region`       | no       | Region its circuits prefer exit nodes in, such as `eu-west`  |
+//! | `pool`         | no       | Provider pool its circuits prefer exit nodes from            |
+//! | `consistency`  | no       | `single`, or `quorum:<n>` for reads `n` providers agree on    |
+//!
+//! Rows are numbered from 1, the CSV header not counted. An import is all or nothing:
+//! every row is checked, against the other rows and the user's existing mappings too, and
+//! if any fails, none is created and every failing row is reported. The mappings a user
+//! holds in total are capped by their plan; the cap is checked once more as the mappings
+//! are stored, see [`UserManager::add_rpc_mappings`](crate::traits::UserManager::add_rpc_mappings),
+//! so imports made at once can't together go past it.
+//!
+//! Both routes take the user's API key in the `X-DarkNode-API-Key` header, see
+//! [`crate::api_key`]. The DarkNode URLs of new mappings don't carry the key either: clients
+//! send it with each request.
+
+use super::*;
+use super::chains::{Chain, Network};
+use super::context::Consistency;
+use super::managers::quota::QuotaExceeded;
+use super::types::{Plan, RpcMapping};
+use rand::Rng;
+use std::collections::HashMap;
+
+/// Columns of a mapping file, in the order exports write them
+pub const COLUMNS: [&str; 6] = ["original_rpc", "chain", "network", "region", "pool", "consistency"];
+
+/// Characters the subdomains of generated DarkNode URLs are drawn from
+const SUBDOMAIN_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+/// How mappings are provisioned in bulk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProvisioningConfig {
+    /// Domain the generated DarkNode URLs are under
+    pub domain: String,
+    /// Most rows one import may hold
+    pub max_rows: usize,
+}
+
+impl Default for ProvisioningConfig {
+    fn default() -> Self {
+        Self {
+            domain: "darknode.pro".to_string(),
+            max_rows: 1000,
+        }
+    }
+}
+
+/// The format of a mapping file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MappingFormat {
+    /// A JSON array of rows
+    #[default]
+    Json,
+    /// CSV with a header line
+    Csv,
+}
+
+impl MappingFormat {
+    /// The format a `Content-Type` names: CSV for `text/csv`, JSON otherwise
+    pub fn of_content_type(content_type: &str) -> Self {
+        match content_type.split(';').next().unwrap_or_default().trim() {
+            "text/csv" => MappingFormat::Csv,
+            _ => MappingFormat::Json,
+        }
+    }
+    
+    /// The `Content-Type` of files in the format
+    pub fn content_type(self) -> &'static str {
+        match self {
+            MappingFormat::Json => "application/json",
+            MappingFormat::Csv => "text/csv",
+        }
+    }
+}
+
+/// One mapping in a file, as written
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MappingRow {
+    /// The provider's URL
+    pub original_rpc: String,
+    /// The chain the mapping serves
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain: Option<String>,
+    /// The network the mapping serves
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<String>,
+    /// Region the mapping's circuits prefer exit nodes in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// Provider pool the mapping's circuits prefer exit nodes from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool: Option<String>,
+    /// How many providers must agree on reads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consistency: Option<String>,
+}
+
+impl MappingRow {
+    /// The row a mapping is exported as
+    pub fn of(mapping: &RpcMapping) -> Self {
+        Self {
+            original_rpc: mapping.original_rpc.clone(),
+            chain: mapping.chain.map(|chain| chain.name().to_string()),
+            network: (mapping.network != Network::Mainnet).then(|| mapping.network.name().to_string()),
+            region: mapping.region.clone(),
+            pool: mapping.pool.clone(),
+            consistency: mapping.quorum.map(|size| Consistency::Quorum(size).to_string()),
+        }
+    }
+}
+
+/// A row that can't be imported
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, thiserror::Error)]
+#[error("row {row}: {error}")]
+pub struct RowError {
+    /// The row's number, from 1
+    pub row: usize,
+    /// What is wrong with it
+    pub error: String,
+}
+
+/// An import that created nothing
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ImportError {
+    /// The body isn't a mapping file in its format
+    #[error("malformed mapping file: {0}")]
+    Malformed(String),
+    /// The file holds more rows than one import may
+    #[error("{rows} rows exceed the limit of {max} per import")]
+    TooManyRows {
+        /// Rows in the file
+        rows: usize,
+        /// Rows allowed
+        max: usize,
+    },
+    /// Rows failed their checks
+    #[error("nothing was imported: {}", joined(.0))]
+    Invalid(Vec<RowError>),
+    /// The mappings would take the user past their plan's cap
+    #[error(transparent)]
+    Quota(QuotaExceeded),
+}
+
+impl ImportError {
+    /// The rows that failed their checks, if that is why nothing was imported
+    pub fn rows(&self) -> &[RowError] {
+        match self {
+            ImportError::Invalid(rows) => rows,
+            _ => &[],
+        }
+    }
+}
+
+/// Row errors as one line
+fn joined(rows: &[RowError]) -> String {
+    rows.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+}
+
+/// The rows of a mapping file in `format`
+pub fn parse(format: MappingFormat, body: &[u8]) -> Result<Vec<MappingRow>, ImportError> {
+    match format {
+        MappingFormat::Json => serde_json::from_slice(body).map_err(|e| ImportError::Malformed(e.to_string())),
+        MappingFormat::Csv => parse_csv(body),
+    }
+}
+
+/// The rows of a CSV mapping file, blank lines skipped
+fn parse_csv(body: &[u8]) -> Result<Vec<MappingRow>, ImportError> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).flexible(true).from_reader(body);
+    let columns: Vec<String> = reader
+        .headers()
+        .map_err(|e| ImportError::Malformed(format!("header: {}", e)))?
+        .iter()
+        .map(str::to_string)
+        .collect();
+    if columns.iter().all(String::is_empty) {
+        return Ok(Vec::new());
+    }
+    if let Some(unknown) = columns.iter().find(|column| !COLUMNS.contains(&column.as_str())) {
+        return Err(ImportError::Malformed(format!(
+            "unknown column `{}`, expected some of: {}",
+            unknown,
+            COLUMNS.join(", ")
+        )));
+    }
+    if !columns.iter().any(|column| column == "original_rpc") {
+        return Err(ImportError::Malformed("no `original_rpc` column".to_string()));
+    }
+    
+    reader
+        .records()
+        .enumerate()
+        .map(|(i, record)| {
+            let malformed = |error: String| ImportError::Malformed(RowError { row: i + 1, error }.to_string());
+            let record = record.map_err(|e| malformed(e.to_string()))?;
+            if record.len() != columns.len() {
+                return Err(malformed(format!("{} fields, the header names {}", record.len(), columns.len())));
+            }
+            let mut row = MappingRow::default();
+            for (column, field) in columns.iter().zip(record.iter()) {
+                let optional = (!field.is_empty()).then(|| field.to_string());
+                match column.as_str() {
+                    "original_rpc" => row.original_rpc = field.to_string(),
+                    "chain" => row.chain = optional,
+                    "network" => row.network = optional,
+                    "region" => row.region = optional,
+                    "pool" => row.pool = optional,
+                    _ => row.consistency = optional,
+                }
+            }
+            Ok(row)
+        })
+        .collect()
+}
+
+/// `mappings` as a file in `format`, which imports back to the same rows
+pub fn export(mappings: &[RpcMapping], format: MappingFormat) -> Vec<u8> {
+    let rows: Vec<MappingRow> = mappings.iter().map(MappingRow::of).collect();
+    match format {
+        MappingFormat::Json => serde_json::to_vec_pretty(&rows).unwrap_or_default(),
+        MappingFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            let mut written = writer.write_record(COLUMNS).is_ok();
+            for row in rows {
+                let fields = [
+                    Some(row.original_rpc),
+                    row.chain,
+                    row.network,
+                    row.region,
+                    row.pool,
+                    row.consistency,
+                ];
+                written &= writer
+                    .write_record(fields.iter().map(|field| field.as_deref().unwrap_or_default()))
+                    .is_ok();
+            }
+            match writer.into_inner() {
+                Ok(csv) if written => csv,
+                _ => Vec::new(),
+            }
+        }
+    }
+}
+
+/// Check every row and build the mappings they describe, or report every row that fails
+///
+/// `existing` are the user's mappings, which count toward the plan's cap and whose
+/// provider URLs can't be mapped again.
+pub fn provision(
+    rows: &[MappingRow],
+    existing: &[RpcMapping],
+    plan: &Plan,
+    config: &ProvisioningConfig,
+    now: Timestamp,
+) -> Result<Vec<RpcMapping>, ImportError> {
+    if rows.len() > config.max_rows {
+        return Err(ImportError::TooManyRows {
+            rows: rows.len(),
+            max: config.max_rows,
+        });
+    }
+    
+    // Check every row, so all of the file's mistakes are reported at once
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut mappings = Vec::new();
+    let mut invalid = Vec::new();
+    for (i, row) in rows.iter().enumerate() {
+        let checked = mapping(row, &config.domain, now).and_then(|mapping| {
+            let url = normalized(&mapping.original_rpc);
+            if existing.iter().any(|existing| normalized(&existing.original_rpc) == url) {
+                return Err(format!("`{}` is already mapped", mapping.original_rpc));
+            }
+            if let Some(first) = seen.insert(url, i + 1) {
+                return Err(format!("`{}` is already on row {}", mapping.original_rpc, first));
+            }
+            Ok(mapping)
+        });
+        match checked {
+            Ok(mapping) => mappings.push(mapping),
+            Err(error) => invalid.push(RowError { row: i + 1, error }),
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(ImportError::Invalid(invalid));
+    }
+    
+    if existing.len() + mappings.len() > plan.max_mappings as usize {
+        return Err(ImportError::Quota(QuotaExceeded::mappings(plan)));
+    }
+    Ok(mappings)
+}
+
+/// The mapping a row describes, or what is wrong with it
+fn mapping(row: &MappingRow, domain: &str, now: Timestamp) -> Result<RpcMapping, String> {
+    let original_rpc = row.original_rpc.trim();
+    let url = reqwest::Url::parse(original_rpc).map_err(|e| format!("`{}` is not a URL: {}", original_rpc, e))?;
+    if !matches!(url.scheme(), "http" | "https" | "ws" | "wss") || url.host_str().is_none() {
+        return Err(format!("`{}` is not an http(s) or ws(s) URL", original_rpc));
+    }
+    let present = |value: &Option<String>| value.as_deref().map(str::trim).filter(|value| !value.is_empty()).map(str::to_string);
+    let chain = present(&row.chain)
+        .map(|chain| chain.parse::<Chain>())
+        .transpose()
+        .map_err(|e| e.to_string())?;
+    let network = present(&row.network)
+        .map(|network| network.parse::<Network>())
+        .transpose()
+        .map_err(|e| e.to_string())?
+        .unwrap_or_default();
+    let consistency = present(&row.consistency)
+        .map(|value| {
+            Consistency::parse(&value)
+                .ok_or_else(|| format!("unknown consistency `{}`, expected `single` or `quorum:<n>` with n of 2 or more", value))
+        })
+        .transpose()?;
+    
+    let (darknode_https_rpc, darknode_wss_rpc) = darknode_urls(domain);
+    Ok(RpcMapping {
+        id: Uuid::new_v4(),
+        original_rpc: original_rpc.to_string(),
+        darknode_https_rpc,
+        darknode_wss_rpc,
+        created_at: now,
+        quorum: consistency.and_then(Consistency::quorum),
+        skip_validation: false,
+        preflight: false,
+        pool: present(&row.pool),
+        region: present(&row.region),
+        debug_errors: false,
+        timeouts: Default::default(),
+        require_request_signature: false,
+        chain,
+        network,
+        normalize_results: false,
+        fallback_mode: None,
+        address_scatter: false,
+        routes: Default::default(),
+        provider_attribution: false,
+    })
+}
+
+/// A provider URL as compared for duplicates, or as given if it doesn't parse
+fn normalized(url: &str) -> String {
+    reqwest::Url::parse(url.trim()).map_or_else(|_| url.trim().to_string(), String::from)
+}
+
+/// DarkNode HTTPS and WSS URLs for a new mapping, under a subdomain of its own
+pub fn darknode_urls(domain: &str) -> (String, String) {
+    let mut rng = rand::thread_rng();
+    let subdomain: String = (0..8)
+        .map(|_| SUBDOMAIN_CHARS[rng.gen_range(0..SUBDOMAIN_CHARS.len())] as char)
+        .collect();
+    (
+        format!("https://rpc-{}.{}/", subdomain, domain),
+        format!("wss://rpc-{}.{}/", subdomain, domain),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn row(original_rpc: &str) -> MappingRow {
+        MappingRow {
+            original_rpc: original_rpc.to_string(),
+            ..MappingRow::default()
+        }
+    }
+    
+    fn provisioned(rows: &[MappingRow]) -> Result<Vec<RpcMapping>, ImportError> {
+        let plan = Plan {
+            max_mappings: 100,
+            ..Plan::default()
+        };
+        provision(rows, &[], &plan, &ProvisioningConfig::default(), Timestamp::from_secs(1_700_000_000))
+    }
+    
+    #[test]
+    fn one_bad_row_fails_the_import_and_is_the_only_one_named() {
+        let mut rows: Vec<MappingRow> = (1..=20)
+            .map(|i| row(&format!("https://provider-{}.example/rpc", i)))
+            .collect();
+        rows[6] = row("not a url");
+        
+        match provisioned(&rows) {
+            Err(ImportError::Invalid(invalid)) => {
+                assert_eq!(invalid.len(), 1);
+                assert_eq!(invalid[0].row, 7);
+            }
+            other => panic!("expected row 7 to be refused, got {:?}", other.map(|mappings| mappings.len())),
+        }
+    }
+    
+    #[test]
+    fn exports_import_back_to_the_same_rows_in_either_format() {
+        let rows = vec![
+            MappingRow {
+                original_rpc: "https://eth.example/rpc".to_string(),
+                chain: Some("ethereum".to_string()),
+                network: Some("testnet".to_string()),
+                region: Some("eu-west".to_string()),
+                pool: Some("archive".to_string()),
+                consistency: Some("quorum:2".to_string()),
+            },
+            row("wss://sol.example/ws"),
+        ];
+        let mappings = provisioned(&rows).expect("clean rows provision");
+        assert!(mappings.iter().all(|mapping| !mapping.darknode_https_rpc.contains('?')));
+        assert_eq!(mappings[0].region.as_deref(), Some("eu-west"));
+        
+        for format in [MappingFormat::Json, MappingFormat::Csv] {
+            let exported = export(&mappings, format);
+            assert_eq!(parse(format, &exported).expect("exports parse"), rows, "{:?}", format);
+        }
+    }
+    
+    #[test]
+    fn csv_fields_may_be_quoted_to_hold_commas() {
+        let body = b"original_rpc,pool\n\"https://provider.example/rpc?a=1,2\",\"eu, archive\"\n";
+        let rows = parse(MappingFormat::Csv, body).expect("quoted fields parse");
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].original_rpc, "https://provider.example/rpc?a=1,2");
+        assert_eq!(rows[0].pool.as_deref(), Some("eu, archive"));
+    }
+}
@@ -0,0 +1,162 @@
+//! Comparison of responses from several providers for quorum reads
+
+use super::methods;
+use serde_json::Value;
+
+/// Errors from a quorum read
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum QuorumError {
+    /// Fewer active providers than the requested quorum
+    #[error("quorum of {required} requested but only {available} providers are available")]
+    NotEnoughProviders {
+        /// The requested quorum
+        required: u8,
+        /// The number of active providers
+        available: usize,
+    },
+    /// No response was returned by a majority of providers
+    #[error("providers disagreed: no response was returned by {required} of {queried} providers")]
+    NoMajority {
+        /// Responses needed for a majority
+        required: usize,
+        /// Providers queried
+        queried: usize,
+    },
+}
+
+/// The context slot reported by a Solana response, if any
+fn context_slot(response: &Value) -> Option<u64> {
+    response.pointer("/result/context/slot").and_then(Value::as_u64)
+}
+
+/// Strip fields that legitimately differ between honest providers
+fn normalize(response: &Value) -> Value {
+    let mut normalized = response.clone();
+    if let Some(object) = normalized.as_object_mut() {
+        object.remove("id");
+        object.remove("jsonrpc");
+        object.remove(methods::EXTENSION_KEY);
+    }
+    if let Some(context) = normalized
+        .pointer_mut("/result/context")
+        .and_then(Value::as_object_mut)
+    {
+        context.remove("slot");
+        context.remove("apiVersion");
+    }
+    if let Some(data) = normalized
+        .pointer_mut("/error/data")
+        .and_then(Value::as_object_mut)
+    {
+        data.remove("provider_error");
+    }
+    normalized
+}
+
+/// Whether two provider responses agree, allowing context slots to differ by up to `slot_tolerance`
+pub fn agree(a: &Value, b: &Value, slot_tolerance: u64) -> bool {
+    let slots_close = match (context_slot(a), context_slot(b)) {
+        (Some(x), Some(y)) => x.abs_diff(y) <= slot_tolerance,
+        (None, None) => true,
+        _ => false,
+    };
+    slots_close && normalize(a) == normalize(b)
+}
+
+/// Group responses that agree with each other, returning indices into `responses`
+///
+/// Failed responses (`None`) are never grouped. Groups are ordered largest first.
+pub fn group(responses: &[Option<Value>], slot_tolerance: u64) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (index, response) in responses.iter().enumerate() {
+        let Some(response) = response else { continue };
+        let existing = groups.iter_mut().find(|group| {
+            responses[group[0]]
+                .as_ref()
+                .map_or(false, |representative| agree(representative, response, slot_tolerance))
+        });
+        match existing {
+            Some(group) => group.push(index),
+            None => groups.push(vec![index]),
+        }
+    }
+    groups.sort_by(|a, b| b.len().cmp(&a.len()));
+    groups
+}
+
+/// Number of agreeing responses needed for a majority of `queried` providers
+pub fn majority(queried: usize) -> usize {
+    queried / 2 + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::impls::StoredRpcManager;
+    use crate::storage::MemoryStorage;
+    use crate::traits::RpcManager;
+    use crate::types::{ExitPayload, RpcProvider};
+    use serde_json::json;
+    use std::sync::Arc;
+    
+    /// A provider answering every request with `balance`
+    fn answering(balance: u64) -> RpcProvider {
+        fixtures::serving(move |request: Value| async move {
+            json!({ "jsonrpc": "2.0", "id": request["id"], "result": { "context": { "slot": 100 }, "value": balance } })
+        })
+    }
+    
+    fn read(quorum: u8) -> ExitPayload {
+        ExitPayload {
+            quorum: Some(quorum),
+            ..fixtures::payload("getBalance", json!(["4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T"]))
+        }
+    }
+    
+    async fn providers(providers: &[RpcProvider]) -> Arc<StoredRpcManager> {
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        for provider in providers {
+            rpc_manager.register_provider(provider.clone()).await.unwrap();
+        }
+        rpc_manager
+    }
+    
+    #[tokio::test]
+    async fn one_lying_provider_of_three_is_outvoted_and_reported() {
+        let liar = answering(1);
+        let rpc_manager = providers(&[answering(5_000), answering(5_000), liar.clone()]).await;
+        let exit = Arc::new(fixtures::exit(rpc_manager.clone()));
+        
+        let response: Value = serde_json::from_slice(&exit.serve(&read(3)).await.unwrap()).unwrap();
+        assert_eq!(response["result"]["value"], 5_000);
+        assert_eq!(
+            response[methods::EXTENSION_KEY]["quorum"],
+            json!({ "queried": 3, "agreeing": 2, "divergent": true })
+        );
+        
+        // Only the liar lost standing
+        for provider in rpc_manager.get_providers().await.unwrap() {
+            assert_eq!(provider.success_rate < 1.0, provider.id == liar.id);
+        }
+    }
+    
+    #[tokio::test]
+    async fn answers_all_differing_fail_the_read() {
+        let exit = Arc::new(fixtures::exit(providers(&[answering(1), answering(2), answering(3)]).await));
+        let failed = exit.serve(&read(3)).await.unwrap_err();
+        assert!(matches!(
+            failed.downcast_ref::<QuorumError>(),
+            Some(QuorumError::NoMajority { required: 2, queried: 3 })
+        ));
+    }
+    
+    #[test]
+    fn slots_within_tolerance_still_agree() {
+        let at = |slot: u64| json!({ "jsonrpc": "2.0", "id": slot, "result": { "context": { "slot": slot }, "value": 1 } });
+        assert!(agree(&at(100), &at(102), 2));
+        assert!(!agree(&at(100), &at(103), 2));
+        assert_eq!(group(&[Some(at(100)), None, Some(at(101))], 2), vec![vec![0, 2]]);
+        assert_eq!(majority(3), 2);
+    }
+}
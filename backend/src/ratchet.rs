@@ -0,0 +1,300 @@
+//! Symmetric key ratcheting on circuits, for forward secrecy
+//!
+//! A circuit's key is agreed once, when the circuit is built, so a key taken from one of
+//! its ends later would decrypt everything the circuit carried, had it been recorded. With
+//! ratcheting the circuit's key is never used as is: each direction of the circuit, requests
+//! toward the exit node and responses back, has a chain of keys derived from it, each step's
+//! key derived from the one before and the one before erased. A sender moves to the next
+//! step after [`RatchetConfig::messages_per_step`] messages or once
+//! [`RatchetConfig::step_interval`] has passed, whichever comes first, and names the step
+//! each message is encrypted at in its `key_step`.
+//!
+//! A receiver follows the sender, keeping the keys of the last [`RatchetConfig::window`]
+//! steps so messages arriving out of order still decrypt. The step a message names isn't
+//! authenticated, so the receiver only follows it once the message has decrypted under its
+//! key: a message forged at any step moves nothing. A message from further behind or ahead
+//! than the window is a [`RatchetDesync`], and is refused as if its circuit were unknown,
+//! so the entry node builds another rather than keep one with its ends out of step. A key
+//! taken from a circuit thus exposes at most the window of steps before it, never the
+//! circuit's earlier traffic; keys that leave the window or are replaced are overwritten,
+//! see [`CryptoKey`].
+//!
+//! The key of step `n` is HKDF-SHA256 with no salt, the key of step `n - 1` as input key
+//! material, and `darknode-ratchet:v1`, the direction (`forward` or `backward`), and `n` as
+//! 8 big-endian bytes as info. The key of step 0 is derived the same way from the circuit's
+//! key.
+
+use super::*;
+use super::types::CryptoKey;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// Prefix of the info of every derivation, so ratchet keys can't collide with other uses
+const INFO_PREFIX: &[u8] = b"darknode-ratchet:v1";
+
+/// Length of every derived key
+const KEY_LEN: usize = 32;
+
+/// When the keys of a circuit are ratcheted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RatchetConfig {
+    /// Whether keys are ratcheted, rather than every message using the circuit's key
+    pub enabled: bool,
+    /// Messages sent at one step before moving to the next
+    pub messages_per_step: u64,
+    /// Longest a sender stays at one step
+    pub step_interval: Duration,
+    /// Steps behind the latest whose keys a receiver keeps, and ahead of it it will follow
+    pub window: u64,
+}
+
+impl Default for RatchetConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            messages_per_step: 100,
+            step_interval: Duration::from_secs(60),
+            window: 4,
+        }
+    }
+}
+
+/// A direction of traffic on a circuit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Requests, from the entry node toward the exit node
+    Forward,
+    /// Responses, from the exit node back to the entry node
+    Backward,
+}
+
+impl Direction {
+    /// The direction's part of the derivation info
+    fn label(self) -> &'static [u8] {
+        match self {
+            Direction::Forward => b"forward",
+            Direction::Backward => b"backward",
+        }
+    }
+}
+
+/// Which end of a circuit a ratchet is kept at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// The entry node, which sends requests and receives responses
+    Initiator,
+    /// The exit node, which receives requests and sends responses
+    Responder,
+}
+
+/// The key of step `step` in `direction`, derived from the key of the step before
+pub fn derive(previous: &CryptoKey, direction: Direction, step: u64) -> CryptoKey {
+    let mut info = INFO_PREFIX.to_vec();
+    info.extend_from_slice(direction.label());
+    info.extend_from_slice(&step.to_be_bytes());
+    let mut key = vec![0; KEY_LEN];
+    Hkdf::<Sha256>::new(None, &previous.0)
+        .expand(&info, &mut key)
+        .expect("a 32 byte key is a valid HKDF-SHA256 output length");
+    CryptoKey(key)
+}
+
+/// A message encrypted at a step the receiver can't follow to
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("message at key step {step} is outside the window of {window} steps around step {latest}")]
+pub struct RatchetDesync {
+    /// The step the message named
+    pub step: u64,
+    /// The latest step the receiver has followed to
+    pub latest: u64,
+    /// How far from the latest step the receiver follows
+    pub window: u64,
+}
+
+/// The chain of keys for messages this end sends
+struct SendingChain {
+    direction: Direction,
+    step: u64,
+    key: CryptoKey,
+    sent: u64,
+    stepped_at: Instant,
+}
+
+/// The chain of keys for messages this end receives, with the window of recent steps
+struct ReceivingChain {
+    direction: Direction,
+    /// Keys of the latest steps, oldest first, the last being the latest step
+    keys: VecDeque<(u64, CryptoKey)>,
+}
+
+/// Both chains of keys of a circuit, as kept at one end of it
+pub struct CircuitRatchet {
+    config: RatchetConfig,
+    sending: SendingChain,
+    receiving: ReceivingChain,
+}
+
+impl CircuitRatchet {
+    /// Ratchet from the circuit's `key`, kept at `side` of the circuit
+    ///
+    /// The circuit's key itself isn't kept, so the caller should drop its copy too. With
+    /// ratcheting disabled both chains stay at step 0 under the circuit's key.
+    pub fn new(key: CryptoKey, side: Side, config: RatchetConfig) -> Self {
+        let (sends, receives) = match side {
+            Side::Initiator => (Direction::Forward, Direction::Backward),
+            Side::Responder => (Direction::Backward, Direction::Forward),
+        };
+        let first = |direction| match config.enabled {
+            true => derive(&key, direction, 0),
+            false => key.clone(),
+        };
+        Self {
+            sending: SendingChain {
+                direction: sends,
+                step: 0,
+                key: first(sends),
+                sent: 0,
+                stepped_at: Instant::now(),
+            },
+            receiving: ReceivingChain {
+                direction: receives,
+                keys: VecDeque::from([(0, first(receives))]),
+            },
+            config,
+        }
+    }
+    
+    /// The step to send the next message at, and the key to encrypt it under
+    pub fn seal(&mut self) -> (u64, CryptoKey) {
+        let chain = &mut self.sending;
+        let due = chain.sent >= self.config.messages_per_step || chain.stepped_at.elapsed() >= self.config.step_interval;
+        if self.config.enabled && due {
+            chain.key = derive(&chain.key, chain.direction, chain.step + 1);
+            chain.step += 1;
+            chain.sent = 0;
+            chain.stepped_at = Instant::now();
+        }
+        chain.sent += 1;
+        (chain.step, chain.key.clone())
+    }
+    
+    /// The key to decrypt a message received at `step` under
+    ///
+    /// Nothing changes until the message has decrypted and [`Self::follow`] is called with
+    /// its step.
+    pub fn open(&self, step: u64) -> Result<CryptoKey, RatchetDesync> {
+        let window = self.window();
+        let (latest, latest_key) = self.receiving.keys.back().expect("the latest key is always kept");
+        let desync = RatchetDesync { step, latest: *latest, window };
+        if step > latest.saturating_add(window) || step.saturating_add(window) < *latest {
+            return Err(desync);
+        }
+        if step <= *latest {
+            return self
+                .receiving
+                .keys
+                .iter()
+                .find(|(kept, _)| *kept == step)
+                .map(|(_, key)| key.clone())
+                .ok_or(desync);
+        }
+        let mut key = latest_key.clone();
+        for next in latest + 1..=step {
+            key = derive(&key, self.receiving.direction, next);
+        }
+        Ok(key)
+    }
+    
+    /// Follow the sender to `step`, that of a message that decrypted under [`Self::open`]'s
+    /// key, forgetting the keys that fall out of the window
+    pub fn follow(&mut self, step: u64) {
+        let window = self.window();
+        let chain = &mut self.receiving;
+        let latest = chain.keys.back().map_or(0, |(latest, _)| *latest);
+        if step > latest.saturating_add(window) {
+            return;
+        }
+        for next in latest + 1..=step {
+            let key = derive(&chain.keys.back().expect("the latest key is always kept").1, chain.direction, next);
+            chain.keys.push_back((next, key));
+        }
+        let newest = latest.max(step);
+        while chain.keys.front().map_or(false, |(oldest, _)| oldest + window < newest) {
+            chain.keys.pop_front();
+        }
+    }
+    
+    /// Steps around the latest the receiver follows
+    fn window(&self) -> u64 {
+        if self.config.enabled {
+            self.config.window
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::CryptoImpl;
+    use crate::traits::Crypto;
+    
+    fn ends(config: RatchetConfig) -> (CircuitRatchet, CircuitRatchet) {
+        let key = CryptoKey(vec![9; KEY_LEN]);
+        (
+            CircuitRatchet::new(key.clone(), Side::Initiator, config.clone()),
+            CircuitRatchet::new(key, Side::Responder, config),
+        )
+    }
+    
+    fn every_message() -> RatchetConfig {
+        RatchetConfig {
+            messages_per_step: 1,
+            ..RatchetConfig::default()
+        }
+    }
+    
+    #[tokio::test]
+    async fn messages_decrypt_across_steps_and_earlier_keys_open_none_of_the_later() {
+        let crypto = CryptoImpl::new();
+        let (mut entry, mut exit) = ends(every_message());
+        let (_, first) = entry.seal();
+        for sent in 1..6 {
+            let (step, key) = entry.seal();
+            assert_eq!(step, sent);
+            let sealed = crypto.encrypt(b"ping", &key).await.unwrap();
+            let opened = exit.open(step).unwrap();
+            assert_eq!(crypto.decrypt(&sealed, &opened).await.unwrap(), b"ping");
+            exit.follow(step);
+            assert!(crypto.decrypt(&sealed, &first).await.is_err());
+        }
+    }
+    
+    #[test]
+    fn messages_out_of_order_open_within_the_window_only() {
+        let (mut entry, mut exit) = ends(every_message());
+        let sealed: Vec<(u64, CryptoKey)> = (0..8).map(|_| entry.seal()).collect();
+        exit.follow(4);
+        assert_eq!(exit.open(2).unwrap().0, sealed[2].1 .0);
+        assert_eq!(exit.open(8).unwrap().0, entry.seal().1 .0);
+        assert!(exit.open(9).is_err());
+        exit.follow(7);
+        assert!(exit.open(2).is_err());
+        assert_eq!(exit.open(3).unwrap().0, sealed[3].1 .0);
+    }
+    
+    #[test]
+    fn a_step_is_only_followed_when_told() {
+        let (mut entry, exit) = ends(every_message());
+        let sealed: Vec<(u64, CryptoKey)> = (0..3).map(|_| entry.seal()).collect();
+        // Opening at a step far ahead, as a forged message would, leaves the earlier ones
+        // open
+        let _ = exit.open(4);
+        assert_eq!(exit.open(0).unwrap().0, sealed[0].1 .0);
+        assert_eq!(exit.open(2).unwrap().0, sealed[2].1 .0);
+    }
+}
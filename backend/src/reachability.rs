@@ -0,0 +1,239 @@
+//! Which nodes can reach which, as the nodes themselves see it
+//!
+//! Heartbeats only tell the coordinator that a node can reach the coordinator. Partial
+//! partitions and asymmetric firewalls can leave a node reachable from there and yet not
+//! from half the entry nodes, and circuits through it keep failing to build from those.
+//!
+//! So every `probe_interval` each node sends an HTTP `HEAD` to the `/health` endpoint of a
+//! few peers drawn uniformly from its directory, every role alike, and reports in its next
+//! heartbeat whether each answered within `timeout`. The peers probed are drawn afresh each
+//! round and the rounds are jittered, never following the circuits the node carries, so
+//! the probes say nothing about where user traffic goes. Probes carry no user data.
+//!
+//! The coordinator folds the reports into a [`ReachabilityMatrix`] of directed edges. An
+//! edge counts as broken once its prober failed `failures_to_break` probes in a row, until a
+//! probe along it succeeds or `max_age` passes without one. Recommended paths leave out
+//! broken edges from the entry node to each of the path's nodes and between consecutive
+//! hops, see [`crate::recommend`], and a node most of its probers can't reach is reported on
+//! the dashboard as partitioned. Only signed heartbeats are taken in, so no one but a node
+//! reports the edges from it.
+//!
+//! An entry node also keeps its own probes in a matrix of its own, so that the circuits it
+//! builds from its local directory, when no recommendation fits or arrives in time, leave
+//! the nodes it found it can't reach out of their routing hops, see [`crate::routing::RouterImpl::with_reachability`].
+
+use super::*;
+use super::heartbeat::ActivityCounters;
+use super::traits::NodeManager;
+use super::types::{Node, NodeId, NodeRole};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+
+/// The path peers are probed at
+pub const HEALTH_PATH: &str = "/health";
+
+/// How nodes probe each other, and how the coordinator reads the probes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReachabilityConfig {
+    /// Whether nodes probe their peers, and paths avoid edges found broken
+    pub enabled: bool,
+    /// How often a node probes a sample of its peers
+    pub probe_interval: Duration,
+    /// Peers probed each round
+    pub fan_out: usize,
+    /// How long a peer gets to answer a probe
+    pub timeout: Duration,
+    /// Failed probes in a row after which an edge counts as broken
+    pub failures_to_break: u32,
+    /// How long a probe's outcome is taken into account
+    pub max_age: Duration,
+    /// Share of a node's probers failing to reach it from which it is reported as partitioned
+    pub partition_share: f64,
+    /// Probers a node needs before it can be reported as partitioned
+    pub min_probers: usize,
+}
+
+impl Default for ReachabilityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            probe_interval: Duration::from_secs(30),
+            fan_out: 3,
+            timeout: Duration::from_secs(1),
+            failures_to_break: 2,
+            max_age: Duration::from_secs(10 * 60),
+            partition_share: 0.5,
+            min_probers: 2,
+        }
+    }
+}
+
+/// Whether a peer answered a probe, as reported in heartbeats
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProbeResult {
+    /// The peer probed
+    pub peer: NodeId,
+    /// Whether it answered in time
+    pub reachable: bool,
+}
+
+/// A node most of the nodes probing it can't reach
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartitionWarning {
+    /// The node
+    pub node_id: NodeId,
+    /// Nodes that probed it lately
+    pub probers: usize,
+    /// The probers that can't reach it
+    pub unreachable_from: Vec<NodeId>,
+}
+
+/// Probe a sample of the peers in the directory every `probe_interval`, reporting the
+/// outcomes in this node's heartbeats, and keeping them in `own` if given, until the task
+/// is dropped
+pub async fn probe_peers(
+    config: ReachabilityConfig,
+    node_id: NodeId,
+    node_manager: Arc<dyn NodeManager + Send + Sync>,
+    counters: Arc<ActivityCounters>,
+    own: Option<Arc<ReachabilityMatrix>>,
+) {
+    let client = reqwest::Client::new();
+    let mut ticker = tokio::time::interval(config.probe_interval);
+    
+    loop {
+        ticker.tick().await;
+        
+        // Start somewhere within the first half of the round, so rounds don't line up
+        // across nodes or with anything else the node does
+        let jitter = config.probe_interval.mul_f64(rand::thread_rng().gen_range(0.0..0.5));
+        tokio::time::sleep(jitter).await;
+        
+        let mut peers = Vec::new();
+        for role in NodeRole::ALL {
+            match node_manager.get_available_nodes(role).await {
+                Ok(nodes) => {
+                    for node in nodes.into_iter().filter(|node| node.id != node_id) {
+                        if peers.iter().all(|known: &Node| known.id != node.id) {
+                            peers.push(node);
+                        }
+                    }
+                }
+                Err(e) => tracing::debug!("Failed to list {:?} nodes to probe: {}", role, e),
+            }
+        }
+        let sample: Vec<&Node> = peers.choose_multiple(&mut rand::thread_rng(), config.fan_out).collect();
+        let probes = sample.into_iter().map(|peer| {
+            let url = format!("http://{}:{}{}", peer.ip_address, peer.port, HEALTH_PATH);
+            let probe = client.head(url).timeout(config.timeout).send();
+            async move { (peer.id.clone(), probe.await.is_ok()) }
+        });
+        for (peer, reachable) in futures::future::join_all(probes).await {
+            if !reachable {
+                tracing::debug!("Peer {:?} didn't answer a reachability probe", peer);
+            }
+            metrics::increment_counter!(
+                "darknode_peer_probes_total",
+                "reachable" => if reachable { "true" } else { "false" }
+            );
+            let probe = ProbeResult { peer, reachable };
+            if let Some(own) = &own {
+                own.record(&node_id, std::slice::from_ref(&probe), Timestamp::now());
+            }
+            counters.record_probe(probe);
+        }
+    }
+}
+
+/// Probes along one edge
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+    /// Probes in a row that failed
+    failures: u32,
+    /// When the last probe was reported
+    probed_at: Timestamp,
+}
+
+/// Which nodes reach which, from the probes nodes report, see the module docs
+pub struct ReachabilityMatrix {
+    config: ReachabilityConfig,
+    edges: parking_lot::RwLock<HashMap<(NodeId, NodeId), Edge>>,
+}
+
+impl ReachabilityMatrix {
+    /// Create a matrix with nothing probed yet
+    pub fn new(config: ReachabilityConfig) -> Self {
+        Self {
+            config,
+            edges: parking_lot::RwLock::new(HashMap::new()),
+        }
+    }
+    
+    /// Take in the probes `from` reported at `now`, oldest first
+    pub fn record(&self, from: &NodeId, probes: &[ProbeResult], now: Timestamp) {
+        let mut edges = self.edges.write();
+        edges.retain(|_, edge| self.fresh(edge, now));
+        for probe in probes {
+            let edge = edges.entry((from.clone(), probe.peer.clone())).or_insert(Edge {
+                failures: 0,
+                probed_at: now,
+            });
+            edge.failures = if probe.reachable { 0 } else { edge.failures + 1 };
+            edge.probed_at = now;
+        }
+    }
+    
+    /// Edges, from prober to peer, found broken and not probed successfully since
+    pub fn broken(&self, now: Timestamp) -> HashSet<(NodeId, NodeId)> {
+        if !self.config.enabled {
+            return HashSet::new();
+        }
+        self.edges
+            .read()
+            .iter()
+            .filter(|(_, edge)| self.fresh(edge, now) && self.is_broken(edge))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+    
+    /// Nodes that at least `partition_share` of the nodes probing them lately can't reach
+    pub fn partitions(&self, now: Timestamp) -> Vec<PartitionWarning> {
+        let mut probed: HashMap<&NodeId, (usize, Vec<NodeId>)> = HashMap::new();
+        let edges = self.edges.read();
+        for ((from, to), edge) in edges.iter().filter(|(_, edge)| self.fresh(edge, now)) {
+            let (probers, unreachable_from) = probed.entry(to).or_default();
+            *probers += 1;
+            if self.is_broken(edge) {
+                unreachable_from.push(from.clone());
+            }
+        }
+        let mut warnings: Vec<PartitionWarning> = probed
+            .into_iter()
+            .filter(|(_, (probers, unreachable_from))| {
+                *probers >= self.config.min_probers.max(1)
+                    && !unreachable_from.is_empty()
+                    && unreachable_from.len() as f64 >= self.config.partition_share * *probers as f64
+            })
+            .map(|(node_id, (probers, mut unreachable_from))| {
+                unreachable_from.sort_by(|a, b| a.0.cmp(&b.0));
+                PartitionWarning {
+                    node_id: node_id.clone(),
+                    probers,
+                    unreachable_from,
+                }
+            })
+            .collect();
+        warnings.sort_by(|a, b| a.node_id.0.cmp(&b.node_id.0));
+        warnings
+    }
+    
+    fn is_broken(&self, edge: &Edge) -> bool {
+        edge.failures >= self.config.failures_to_break.max(1)
+    }
+    
+    fn fresh(&self, edge: &Edge, now: Timestamp) -> bool {
+        now.saturating_duration_since(edge.probed_at) <= self.config.max_age
+    }
+}
@@ -0,0 +1,235 @@
+//! Signed receipts proving an entry node served a request, without revealing the request
+//!
+//! A user disputing what happened to a request needs proof that DarkNode served it, at a
+//! given time and with a given response, while DarkNode keeps no record of it. Clients that
+//! send the receipt header get a [`ServiceReceipt`] in the response's `darknode` extension:
+//! the entry node's signature over salted hashes of the request and the response, the time,
+//! and a truncated fingerprint of the circuit that carried it. The node stores nothing; the
+//! user keeps the receipt with their own copies of the request and response, and anyone can
+//! later check all three against the node's receipt key from the directory with [`verify`].
+//! The receipt key is kept apart from the node's rotating identity and never rotated, so a
+//! receipt verifies for as long as the user keeps it.
+//!
+//! Requests and responses are hashed in canonical form, as [`canonical::sorted`] sorts them,
+//! without the members that differ between what the client sent or received and what the
+//! entry node saw: the API key, mapping, `jsonrpc` version, DarkNode extension of the
+//! response, and top-level `null`s. Each receipt has its own random salt, so receipts for the
+//! same request can't be linked; the hashes can confirm a guessed request but never reveal one.
+//!
+//! Only responses that come back through a circuit get receipts, not emulated or streamed ones.
+
+use super::*;
+use super::canonical::{self, CanonicalError, Signable};
+use super::identity::NodeIdentity;
+use super::methods;
+use super::traits::Crypto;
+use super::types::{CircuitId, CryptoKey, NodeId};
+use ed25519_dalek::Verifier;
+use sha2::{Digest, Sha256};
+
+/// Header asking for a receipt, `true` or `false`
+pub const RECEIPT_HEADER: &str = "x-darknode-receipt";
+
+/// Prefix of every signed receipt, so receipt signatures can't be replayed elsewhere
+const MESSAGE_PREFIX: &str = "darknode-receipt:v1";
+
+/// Request members that never reach the entry node service or don't identify the request
+const REQUEST_IGNORED: &[&str] = &["jsonrpc", "api_key", "mapping_id"];
+
+/// Response members that are metadata about how the response was served
+const RESPONSE_IGNORED: &[&str] = &["jsonrpc", methods::EXTENSION_KEY];
+
+/// Bytes of the circuit fingerprint kept in a receipt
+const FINGERPRINT_LEN: usize = 8;
+
+/// An entry node's signed statement that it served a request with a response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceReceipt {
+    /// The entry node that served the request
+    pub node_id: NodeId,
+    /// When the response was served, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// Hex prefix of the hashed circuit ID, enough to tell circuits apart but not to trace one
+    pub circuit: String,
+    /// Hex random salt of the hashes
+    pub salt: String,
+    /// Hex salted hash of the request
+    pub request_hash: String,
+    /// Hex salted hash of the response
+    pub response_hash: String,
+    /// Hex signature by the entry node over the other fields
+    pub signature: String,
+}
+
+impl Signable for ServiceReceipt {
+    /// The prefix line, then each signed field on a line of its own
+    ///
+    /// Receipts predate [`canonical::encode`], and their fields are all plain strings and
+    /// integers, so they keep the line format they were first signed in.
+    fn canonical_bytes(&self) -> Result<Vec<u8>, CanonicalError> {
+        Ok(format!(
+            "{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            MESSAGE_PREFIX, self.node_id.0, self.timestamp, self.circuit, self.salt, self.request_hash, self.response_hash,
+        )
+        .into_bytes())
+    }
+}
+
+/// A receipt doesn't prove what it was presented as proving
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ReceiptInvalid {
+    /// A field of the receipt can't be decoded
+    #[error("receipt is malformed")]
+    Malformed,
+    /// The request presented isn't the one the receipt covers
+    #[error("request does not match the receipt")]
+    RequestMismatch,
+    /// The response presented isn't the one the receipt covers
+    #[error("response does not match the receipt")]
+    ResponseMismatch,
+    /// The signature doesn't verify under any of the keys given
+    #[error("receipt signature does not verify")]
+    BadSignature,
+}
+
+impl ReceiptInvalid {
+    /// Label used in verification results
+    pub fn label(&self) -> &'static str {
+        match self {
+            ReceiptInvalid::Malformed => "malformed",
+            ReceiptInvalid::RequestMismatch => "request_mismatch",
+            ReceiptInvalid::ResponseMismatch => "response_mismatch",
+            ReceiptInvalid::BadSignature => "bad_signature",
+        }
+    }
+}
+
+/// Sign a receipt for `response` to `request`, served through `circuit` at `now`
+pub async fn issue(
+    crypto: &(dyn Crypto + Send + Sync),
+    identity: &NodeIdentity,
+    node_id: &NodeId,
+    circuit: &CircuitId,
+    request: &serde_json::Value,
+    response: &serde_json::Value,
+    now: Timestamp,
+) -> Result<ServiceReceipt> {
+    let salt: [u8; 16] = rand::random();
+    let mut receipt = ServiceReceipt {
+        node_id: node_id.clone(),
+        timestamp: now.as_secs(),
+        circuit: hex::encode(&Sha256::digest(circuit.0.as_bytes())[..FINGERPRINT_LEN]),
+        salt: hex::encode(&salt),
+        request_hash: hex::encode(&digest(&salt, "request", request, REQUEST_IGNORED)?),
+        response_hash: hex::encode(&digest(&salt, "response", response, RESPONSE_IGNORED)?),
+        signature: String::new(),
+    };
+    receipt.signature = hex::encode(&identity.sign(crypto, &receipt.canonical_bytes()?, now).await?);
+    Ok(receipt)
+}
+
+/// Check that `receipt` was signed with one of `keys` for `request` and `response`
+///
+/// The keys are the issuing node's receipt key as published in the directory, which is
+/// never rotated, so a receipt verifies under it however old it is.
+pub fn verify(
+    receipt: &ServiceReceipt,
+    request: &serde_json::Value,
+    response: &serde_json::Value,
+    keys: &[CryptoKey],
+) -> Result<(), ReceiptInvalid> {
+    let salt = hex::decode(&receipt.salt).ok_or(ReceiptInvalid::Malformed)?;
+    let request_hash = digest(&salt, "request", request, REQUEST_IGNORED).map_err(|_| ReceiptInvalid::Malformed)?;
+    if hex::encode(&request_hash) != receipt.request_hash {
+        return Err(ReceiptInvalid::RequestMismatch);
+    }
+    let response_hash = digest(&salt, "response", response, RESPONSE_IGNORED).map_err(|_| ReceiptInvalid::Malformed)?;
+    if hex::encode(&response_hash) != receipt.response_hash {
+        return Err(ReceiptInvalid::ResponseMismatch);
+    }
+    
+    let signature = hex::decode(&receipt.signature)
+        .and_then(|bytes| ed25519_dalek::Signature::from_bytes(&bytes).ok())
+        .ok_or(ReceiptInvalid::Malformed)?;
+    let data = receipt.canonical_bytes().map_err(|_| ReceiptInvalid::Malformed)?;
+    let signed = keys.iter().any(|key| {
+        ed25519_dalek::PublicKey::from_bytes(&key.0).map_or(false, |key| key.verify(&data, &signature).is_ok())
+    });
+    match signed {
+        true => Ok(()),
+        false => Err(ReceiptInvalid::BadSignature),
+    }
+}
+
+/// Salted hash of `value` in canonical form, without its `ignored` and `null` top-level members
+fn digest(salt: &[u8], label: &str, value: &serde_json::Value, ignored: &[&str]) -> Result<[u8; 32]> {
+    let mut value = canonical::sorted(value.clone());
+    if let Some(object) = value.as_object_mut() {
+        object.retain(|key, member| !ignored.contains(&key.as_str()) && !member.is_null());
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(label.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(serde_json::to_vec(&value)?);
+    Ok(hasher.finalize().into())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::CryptoImpl;
+    use serde_json::json;
+    
+    const SECRET: &str = "4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T";
+    
+    async fn issued(request: &serde_json::Value, response: &serde_json::Value) -> (ServiceReceipt, Vec<CryptoKey>) {
+        let crypto = CryptoImpl::new();
+        let identity = NodeIdentity::generate(&crypto, Duration::from_secs(3600)).await.unwrap();
+        let node_id = NodeId(Uuid::new_v4());
+        let circuit = CircuitId(Uuid::new_v4());
+        let now = Timestamp::now();
+        let receipt = issue(&crypto, &identity, &node_id, &circuit, request, response, now).await.unwrap();
+        (receipt, identity.public_keys(now))
+    }
+    
+    fn request() -> serde_json::Value {
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "getBalance", "params": [SECRET] })
+    }
+    
+    fn response(balance: u64) -> serde_json::Value {
+        json!({ "jsonrpc": "2.0", "id": 1, "result": { "context": { "slot": 100 }, "value": balance } })
+    }
+    
+    #[tokio::test]
+    async fn a_receipt_verifies_for_its_own_response_only() {
+        let (receipt, keys) = issued(&request(), &response(5_000)).await;
+        assert_eq!(verify(&receipt, &request(), &response(5_000), &keys), Ok(()));
+        
+        // What the entry node added to the response isn't covered
+        let mut extended = response(5_000);
+        methods::set_extension(&mut extended, "receipt", json!(receipt));
+        assert_eq!(verify(&receipt, &request(), &extended, &keys), Ok(()));
+        
+        assert_eq!(verify(&receipt, &request(), &response(1), &keys), Err(ReceiptInvalid::ResponseMismatch));
+        let other = json!({ "jsonrpc": "2.0", "id": 1, "method": "getBalance", "params": ["9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin"] });
+        assert_eq!(verify(&receipt, &other, &response(5_000), &keys), Err(ReceiptInvalid::RequestMismatch));
+        let (_, stranger) = issued(&request(), &response(5_000)).await;
+        assert_eq!(verify(&receipt, &request(), &response(5_000), &stranger), Err(ReceiptInvalid::BadSignature));
+    }
+    
+    #[tokio::test]
+    async fn a_receipt_reveals_nothing_of_the_request() {
+        let (first, _) = issued(&request(), &response(5_000)).await;
+        let (second, _) = issued(&request(), &response(5_000)).await;
+        
+        let serialized = serde_json::to_string(&first).unwrap();
+        assert!(!serialized.contains(SECRET));
+        assert!(!serialized.contains("getBalance"));
+        
+        // Salted apart, receipts for the same request can't be linked
+        assert_ne!(first.request_hash, second.request_hash);
+        assert_ne!(first.response_hash, second.response_hash);
+    }
+}
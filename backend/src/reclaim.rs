@@ -0,0 +1,160 @@
+//! Reclaiming the state of circuits their entry node abandoned
+//!
+//! An entry node that dies without tearing its circuits down leaves their state behind on
+//! the routing and exit nodes they cross: keys, counters, provider affinity, and upstream
+//! subscriptions. Every node knows when each of its circuits expires, exit nodes from the
+//! lifetime they joined it for and routing nodes from the lifetime every request carries,
+//! and when it last carried traffic. Every [`ReclaimConfig::sweep_interval`], nodes reclaim
+//! the circuits past their expiry, and those that carried nothing for longer than
+//! [`ReclaimConfig::idle_grace`], well beyond the keepalive pings an entry node sends
+//! through its idle circuits, see [`crate::keepalive`]. Exit nodes unsubscribe from the
+//! upstream subscriptions opened through the circuits they reclaim, so providers stop
+//! sending for them.
+//!
+//! A reclaimed circuit is remembered until it would have expired, and for the grace period
+//! at least, so a message arriving late for it is refused as an [`UnknownCircuit`] instead
+//! of counting as forged against the hop that sent it. Routers report an exit node refusing
+//! a circuit as unknown as a hop failure, see [`crate::replay::HopFailureKind::CircuitUnknown`],
+//! so the entry node builds a new circuit rather than keep sending into the old one.
+//!
+//! Reclaimed circuits are counted in `darknode_circuits_reclaimed_total`, by role and reason.
+//!
+//! [`UnknownCircuit`]: crate::membership::UnknownCircuit
+
+use super::*;
+use super::clock::Deadline;
+use super::events::CircuitEnd;
+use super::types::CircuitId;
+use tokio::time::Instant;
+
+/// When abandoned circuits are reclaimed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReclaimConfig {
+    /// How often circuits are checked
+    pub sweep_interval: Duration,
+    /// How long a circuit may carry nothing before it is taken for abandoned
+    pub idle_grace: Duration,
+}
+
+impl Default for ReclaimConfig {
+    fn default() -> Self {
+        Self {
+            sweep_interval: Duration::from_secs(60),
+            idle_grace: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl ReclaimConfig {
+    /// Why a circuit expiring at `expires` and last used at `used_at` is to be reclaimed, if it is
+    pub fn stale(&self, expires: Deadline, used_at: Instant) -> Option<CircuitEnd> {
+        if expires.is_expired() {
+            Some(CircuitEnd::Expired)
+        } else if used_at.elapsed() >= self.idle_grace {
+            Some(CircuitEnd::Abandoned)
+        } else {
+            None
+        }
+    }
+    
+    /// How long a circuit reclaimed while due to expire at `expires` is remembered
+    pub fn remember(&self, expires: Deadline) -> Deadline {
+        Deadline::after(expires.remaining().max(self.idle_grace))
+    }
+}
+
+/// Count a circuit reclaimed by a node in `role`
+pub fn record(role: &'static str, reason: CircuitEnd) {
+    let reason = match reason {
+        CircuitEnd::Expired => "expired",
+        CircuitEnd::Abandoned => "abandoned",
+        _ => "other",
+    };
+    metrics::increment_counter!("darknode_circuits_reclaimed_total", "role" => role, "reason" => reason);
+}
+
+/// Circuits reclaimed recently, and until when each is remembered
+#[derive(Default)]
+pub struct Tombstones {
+    circuits: dashmap::DashMap<CircuitId, Deadline>,
+}
+
+impl Tombstones {
+    /// Remember nothing yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Remember that `circuit_id` was reclaimed, until `until`
+    pub fn bury(&self, circuit_id: CircuitId, until: Deadline) {
+        self.circuits.insert(circuit_id, until);
+    }
+    
+    /// Whether `circuit_id` was reclaimed and is still remembered
+    pub fn contains(&self, circuit_id: &CircuitId) -> bool {
+        self.circuits.get(circuit_id).map_or(false, |until| !until.is_expired())
+    }
+    
+    /// Forget the circuits remembered for long enough
+    pub fn sweep(&self) {
+        self.circuits.retain(|_, until| !until.is_expired());
+    }
+}
+
+/// A subscription a provider holds open for a circuit
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamSubscription {
+    /// The provider holding it
+    pub provider: Uuid,
+    /// The method it was opened with
+    pub method: String,
+    /// The id the provider gave it
+    pub id: serde_json::Value,
+}
+
+impl UpstreamSubscription {
+    /// The subscription `response` opened at `provider`, if `request` is a subscribe call
+    /// and it succeeded
+    pub fn opened(provider: Uuid, request: &serde_json::Value, response: &[u8]) -> Option<Self> {
+        let method = request["method"].as_str()?;
+        unsubscribe_method(method)?;
+        let response: serde_json::Value = serde_json::from_slice(response).ok()?;
+        let id = response.get("result").filter(|id| !id.is_null())?.clone();
+        Some(Self {
+            provider,
+            method: method.to_string(),
+            id,
+        })
+    }
+    
+    /// The request closing the subscription
+    pub fn unsubscribe_request(&self) -> serde_json::Value {
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": unsubscribe_method(&self.method).unwrap_or_default(),
+            "params": [self.id],
+        })
+    }
+}
+
+/// The id of the subscription `request` closes, if it is an unsubscribe call
+pub fn closed(request: &serde_json::Value) -> Option<&serde_json::Value> {
+    let method = request["method"].as_str()?;
+    if method.ends_with("Unsubscribe") || method.ends_with("_unsubscribe") {
+        request["params"].get(0)
+    } else {
+        None
+    }
+}
+
+/// The method closing subscriptions opened with `method`, Solana's `accountSubscribe` by
+/// `accountUnsubscribe` and Ethereum's `eth_subscribe` by `eth_unsubscribe`
+fn unsubscribe_method(method: &str) -> Option<String> {
+    if let Some(kind) = method.strip_suffix("Subscribe") {
+        Some(format!("{}Unsubscribe", kind))
+    } else {
+        method.strip_suffix("_subscribe").map(|namespace| format!("{}_unsubscribe", namespace))
+    }
+}
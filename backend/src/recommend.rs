@@ -0,0 +1,293 @@
+//! Circuit paths recommended by the coordinator to spread load across the network
+//!
+//! An entry node only sees the nodes in its directory, not how busy they are, so entry
+//! nodes building circuits independently can pile onto the same few nodes. The coordinator
+//! sees every node's load in its heartbeats, and on `POST /topology/recommend` suggests a
+//! handful of paths through nodes with headroom left, each weighted by the headroom of its
+//! busiest hop, and valid for a short while.
+//!
+//! The recommendations are only candidates: the entry node draws one of them itself, by
+//! weight, among those that still fit its directory and the user's exit subset, so the
+//! coordinator never learns which path a circuit took. Exit subsets stay on the entry node
+//! and aren't sent with the constraints. When the coordinator can't be reached, or none
+//! of its paths fit, the circuit is built from the local directory as before.
+//!
+//! Entry nodes name themselves in their constraints, so that paths leave out the edges
+//! nodes report they can't cross, see [`crate::reachability`]: from the entry node to any
+//! node of the path, and from each hop to the next.
+
+use super::*;
+use super::budget;
+use super::scoring;
+use super::regions::{LatencyMatrix, MeasuredLatency};
+use super::types::{Node, NodeId};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+/// How the coordinator recommends paths
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecommendConfig {
+    /// Paths drawn for each recommendation
+    pub paths: usize,
+    /// How long entry nodes may use a recommendation
+    pub validity: Duration,
+    /// Load from which a node is left out of recommended paths
+    pub max_load: f64,
+}
+
+impl Default for RecommendConfig {
+    fn default() -> Self {
+        Self {
+            paths: 8,
+            validity: Duration::from_secs(30),
+            max_load: 0.9,
+        }
+    }
+}
+
+/// How entry nodes ask the coordinator for paths
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdviceConfig {
+    /// Whether circuits are built from recommended paths at all
+    pub enabled: bool,
+    /// How long a circuit build waits for a recommendation before using the local directory
+    pub timeout: Duration,
+    /// How long to go without recommendations after the coordinator couldn't be reached
+    pub retry_after: Duration,
+}
+
+impl Default for AdviceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout: Duration::from_millis(250),
+            retry_after: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What an entry node needs from the paths it is recommended
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PathConstraints {
+    /// The entry node the paths start from, if it says
+    #[serde(default)]
+    pub entry_node: Option<NodeId>,
+    /// Prefer exit nodes serving from this provider pool
+    #[serde(default)]
+    pub exit_pool: Option<String>,
+    /// Routing nodes each path should cross
+    pub routing_hops: usize,
+}
+
+/// A path the coordinator suggests, entry node excluded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecommendedPath {
+    /// The routing nodes, in order
+    pub routing_nodes: Vec<NodeId>,
+    /// The exit node
+    pub exit_node: NodeId,
+    /// How strongly the path is suggested, relative to the others
+    pub weight: f64,
+}
+
+/// Paths suggested to an entry node
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recommendation {
+    /// The paths, drawn by the entry node in proportion to their weights
+    pub paths: Vec<RecommendedPath>,
+    /// When the paths stop being worth using
+    pub valid_until: Timestamp,
+    /// Round trips between regions the coordinator has measured, see [`crate::regions`]
+    #[serde(default)]
+    pub latencies: Vec<MeasuredLatency>,
+}
+
+/// Suggest paths through `routing` and `exits` meeting `constraints`, avoiding busy nodes
+///
+/// `loads` holds the latest load each node reported, falling back to the load it
+/// registered with. Paths are drawn at random in proportion to the nodes' headroom, so
+/// entry nodes asking at the same time aren't all sent the same way; a path drawn more
+/// than once carries the combined weight. An exit's headroom shrinks with the share of
+/// its request budget left, and exits with little left are only suggested if no other
+/// has more, see [`crate::budget`]. Paths never cross an edge in `broken`, from prober to
+/// peer, see [`crate::reachability`].
+pub fn recommend(
+    routing: &[Node],
+    exits: &[Node],
+    loads: &HashMap<NodeId, f64>,
+    broken: &HashSet<(NodeId, NodeId)>,
+    constraints: &PathConstraints,
+    config: &RecommendConfig,
+    now: Timestamp,
+) -> Recommendation {
+    let headroom = |node: &&Node| {
+        let load = loads.get(&node.id).copied().unwrap_or(node.load);
+        scoring::headroom(load, config.max_load)
+    };
+    let exit_headroom = |node: &&Node| headroom(node) * node.budget.unwrap_or(1.0);
+    let entry = constraints.entry_node.as_ref();
+    let reaches = |from: Option<&NodeId>, to: &NodeId| {
+        from.map_or(true, |from| !broken.contains(&(from.clone(), to.clone())))
+    };
+    let routing: Vec<&Node> = routing
+        .iter()
+        .filter(|node| headroom(node) > 0.0 && reaches(entry, &node.id))
+        .collect();
+    let mut exits: Vec<&Node> = budget::prefer_funded(
+        exits
+            .iter()
+            .filter(|node| exit_headroom(node) > 0.0 && reaches(entry, &node.id))
+            .collect(),
+    );
+    if constraints.exit_pool.is_some() && exits.iter().any(|node| node.pool == constraints.exit_pool) {
+        exits.retain(|node| node.pool == constraints.exit_pool);
+    }
+    
+    let mut rng = rand::thread_rng();
+    let mut paths: Vec<RecommendedPath> = Vec::new();
+    for _ in 0..config.paths {
+        let Some(exit) = choose(&exits, exit_headroom, &mut rng) else {
+            break;
+        };
+        let mut hops: Vec<&Node> = Vec::new();
+        while hops.len() < constraints.routing_hops.max(1) {
+            let previous = hops.last().map(|hop| &hop.id).or(entry);
+            let last = hops.len() + 1 == constraints.routing_hops.max(1);
+            let unused: Vec<&Node> = routing
+                .iter()
+                .copied()
+                .filter(|node| node.id != exit.id && hops.iter().all(|hop| hop.id != node.id))
+                .filter(|node| reaches(previous, &node.id) && (!last || reaches(Some(&node.id), &exit.id)))
+                .collect();
+            match choose(&unused, headroom, &mut rng) {
+                Some(node) => hops.push(node),
+                None => break,
+            }
+        }
+        if hops.is_empty() {
+            break;
+        }
+        if !hops.last().map_or(false, |hop| reaches(Some(&hop.id), &exit.id)) {
+            // Too few hops to reach the exit from, another draw may do better
+            continue;
+        }
+        let weight = hops.iter().map(headroom).fold(exit_headroom(&exit), f64::min);
+        let routing_nodes: Vec<NodeId> = hops.iter().map(|node| node.id.clone()).collect();
+        match paths
+            .iter_mut()
+            .find(|path| path.exit_node == exit.id && path.routing_nodes == routing_nodes)
+        {
+            Some(path) => path.weight += weight,
+            None => paths.push(RecommendedPath {
+                routing_nodes,
+                exit_node: exit.id.clone(),
+                weight,
+            }),
+        }
+    }
+    
+    Recommendation {
+        paths,
+        valid_until: now + config.validity,
+        latencies: Vec::new(),
+    }
+}
+
+/// One of `items` drawn in proportion to `weight`, or `None` if none weighs anything
+pub fn choose<T: Copy>(items: &[T], weight: impl Fn(&T) -> f64, rng: &mut impl Rng) -> Option<T> {
+    let index = WeightedIndex::new(items.iter().map(|item| weight(item).max(0.0))).ok()?;
+    Some(items[index.sample(rng)])
+}
+
+/// A recommendation held by an entry node, or a failure to get one
+enum Cached {
+    Fetched(Recommendation),
+    Unavailable(Instant),
+}
+
+/// Fetches and caches the coordinator's recommended paths for an entry node
+pub struct PathAdvisor {
+    config: AdviceConfig,
+    client: reqwest::Client,
+    url: String,
+    cache: parking_lot::Mutex<HashMap<PathConstraints, Cached>>,
+    latency: Option<Arc<LatencyMatrix>>,
+}
+
+impl PathAdvisor {
+    /// Create an advisor asking the coordinator at `coordinator_url`
+    pub fn new(coordinator_url: &str, config: AdviceConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            url: format!("{}/topology/recommend", coordinator_url.trim_end_matches('/')),
+            cache: parking_lot::Mutex::new(HashMap::new()),
+            latency: None,
+        }
+    }
+    
+    /// Keep `latency` up to date with the round trips sent along with recommendations
+    pub fn with_latency(mut self, latency: Arc<LatencyMatrix>) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+    
+    /// Paths recommended for `constraints`, or none if there is no recommendation to go by
+    ///
+    /// A recommendation is reused until it expires. After a failed request the
+    /// coordinator isn't asked again for `retry_after`, so an outage doesn't slow down
+    /// every circuit build by the timeout.
+    pub async fn paths(&self, constraints: &PathConstraints) -> Vec<RecommendedPath> {
+        if !self.config.enabled {
+            return Vec::new();
+        }
+        match self.cache.lock().get(constraints) {
+            Some(Cached::Fetched(recommendation)) if recommendation.valid_until > Timestamp::now() => {
+                metrics::increment_counter!("darknode_path_recommendations_total", "outcome" => "cached");
+                return recommendation.paths.clone();
+            }
+            Some(Cached::Unavailable(until)) if *until > Instant::now() => return Vec::new(),
+            _ => {}
+        }
+        
+        let fetched = async {
+            let recommendation: Recommendation = self
+                .client
+                .post(&self.url)
+                .timeout(self.config.timeout)
+                .json(constraints)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            Ok::<_, reqwest::Error>(recommendation)
+        };
+        match fetched.await {
+            Ok(recommendation) => {
+                metrics::increment_counter!("darknode_path_recommendations_total", "outcome" => "fetched");
+                if let Some(latency) = &self.latency {
+                    latency.merge(&recommendation.latencies);
+                }
+                let paths = recommendation.paths.clone();
+                self.cache
+                    .lock()
+                    .insert(constraints.clone(), Cached::Fetched(recommendation));
+                paths
+            }
+            Err(e) => {
+                metrics::increment_counter!("darknode_path_recommendations_total", "outcome" => "unavailable");
+                tracing::debug!("No path recommendation from the coordinator, using the local directory: {}", e);
+                self.cache.lock().insert(
+                    constraints.clone(),
+                    Cached::Unavailable(Instant::now() + self.config.retry_after),
+                );
+                Vec::new()
+            }
+        }
+    }
+}
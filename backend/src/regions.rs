@@ -0,0 +1,612 @@
+//! Where nodes are, and how long it takes to get between them
+//!
+//! A node's `region` is a plain string such as `us-east-1`, read here as a [`Region`] of
+//! continent, area, and zone: `us-east-1` and `us-east-2` share an area, `us-west` is on
+//! the same continent, and `ap-south` is far from all three. Strings that don't follow the
+//! pattern are still regions, only close to themselves.
+//!
+//! Nodes time a TCP handshake to a node of each region in their directory every
+//! `ping_interval` and report the round trips in their heartbeats. The coordinator smooths
+//! them into a [`LatencyMatrix`] and sends it along with its path recommendations. Between
+//! regions nobody has measured lately, latency is estimated from how close they are.
+//!
+//! Circuit builders use the matrix to pick the hops, in order, making the shortest path
+//! among those meeting the circuit's other constraints. Paths must also span `min_areas`
+//! areas where the directory allows it, so a circuit isn't built within one datacenter
+//! just because that is fastest.
+
+use super::*;
+use super::heartbeat::ActivityCounters;
+use super::recommend;
+use super::scoring;
+use super::traits::NodeManager;
+use super::types::{Node, NodeId, NodeRole};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// How long a peer gets to complete the handshake a ping times
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Partial paths kept at each hop of the shortest path search
+pub const SEARCH_WIDTH: usize = 64;
+
+/// Continents of the region prefixes in use, others being taken as their own continent
+const CONTINENTS: &[(&str, &str)] = &[
+    ("us", "na"),
+    ("ca", "na"),
+    ("na", "na"),
+    ("sa", "sa"),
+    ("eu", "eu"),
+    ("uk", "eu"),
+    ("ap", "as"),
+    ("as", "as"),
+    ("me", "as"),
+    ("af", "af"),
+    ("au", "oc"),
+    ("oc", "oc"),
+];
+
+/// How latency between regions is measured and estimated, and how it shapes circuits
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LatencyConfig {
+    /// Whether circuits are built along the shortest path rather than in directory order
+    pub enabled: bool,
+    /// How often nodes ping a node of each region in their directory
+    pub ping_interval: Duration,
+    /// Estimated round trip within a zone, for regions not measured
+    pub same_zone: Duration,
+    /// Estimated round trip between zones of an area
+    pub same_area: Duration,
+    /// Estimated round trip between areas of a continent
+    pub same_continent: Duration,
+    /// Estimated round trip between continents
+    pub distant: Duration,
+    /// Weight of a new measurement in the smoothed round trip, from 0 to 1
+    pub smoothing: f64,
+    /// How long a measurement is used before falling back to the estimate
+    pub max_age: Duration,
+    /// Fewest areas a circuit's hops should span, if the directory has that many
+    pub min_areas: usize,
+}
+
+impl Default for LatencyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ping_interval: Duration::from_secs(60),
+            same_zone: Duration::from_millis(1),
+            same_area: Duration::from_millis(5),
+            same_continent: Duration::from_millis(60),
+            distant: Duration::from_millis(200),
+            smoothing: 0.3,
+            max_age: Duration::from_secs(15 * 60),
+            min_areas: 2,
+        }
+    }
+}
+
+/// A node's region, as continent, area, and zone
+///
+/// Parsed from strings such as `us-east-1`: the first segment names the continent, the
+/// first two the area, and the rest the zone. Parsing never fails; a string without
+/// dashes is an area of its own.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Region {
+    /// The continent, such as `na` for `us-east-1`
+    pub continent: String,
+    /// The area, such as `us-east`
+    pub area: String,
+    /// The zone within the area, such as `1`, if the region names one
+    pub zone: Option<String>,
+}
+
+/// How close two regions are, closest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Proximity {
+    /// The same zone, or the same area if neither names a zone
+    Zone,
+    /// Different zones of the same area
+    Area,
+    /// Different areas of the same continent
+    Continent,
+    /// Different continents
+    Distant,
+}
+
+impl Region {
+    /// Read a region string, as nodes report it
+    pub fn parse(region: &str) -> Self {
+        let region = region.trim().to_ascii_lowercase();
+        let mut segments = region.splitn(3, '-');
+        let first = segments.next().unwrap_or_default().to_string();
+        let continent = CONTINENTS
+            .iter()
+            .find(|(prefix, _)| *prefix == first)
+            .map_or_else(|| first.clone(), |(_, continent)| continent.to_string());
+        let area = match segments.next() {
+            Some(second) => format!("{}-{}", first, second),
+            None => first,
+        };
+        Self {
+            continent,
+            area,
+            zone: segments.next().map(str::to_string),
+        }
+    }
+    
+    /// How close `other` is to this region
+    pub fn proximity(&self, other: &Region) -> Proximity {
+        if self.area == other.area {
+            if self.zone == other.zone {
+                Proximity::Zone
+            } else {
+                Proximity::Area
+            }
+        } else if self.continent == other.continent {
+            Proximity::Continent
+        } else {
+            Proximity::Distant
+        }
+    }
+}
+
+impl FromStr for Region {
+    type Err = std::convert::Infallible;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::parse(s))
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.zone {
+            Some(zone) => write!(f, "{}-{}", self.area, zone),
+            None => write!(f, "{}", self.area),
+        }
+    }
+}
+
+/// A smoothed round trip between two regions, as the coordinator shares it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MeasuredLatency {
+    /// One of the regions
+    pub from: String,
+    /// The other region
+    pub to: String,
+    /// The smoothed round trip between them
+    pub rtt: Duration,
+    /// When it was last measured
+    pub measured_at: Timestamp,
+}
+
+/// A smoothed round trip and when it was last measured
+#[derive(Debug, Clone, Copy)]
+struct Measured {
+    rtt: Duration,
+    at: Timestamp,
+}
+
+/// Round trips between regions, measured where known and estimated elsewhere
+pub struct LatencyMatrix {
+    config: LatencyConfig,
+    measured: parking_lot::RwLock<HashMap<(Region, Region), Measured>>,
+}
+
+impl LatencyMatrix {
+    /// Create a matrix with nothing measured yet
+    pub fn new(config: LatencyConfig) -> Self {
+        Self {
+            config,
+            measured: parking_lot::RwLock::new(HashMap::new()),
+        }
+    }
+    
+    /// How the matrix estimates latency and shapes circuits
+    pub fn config(&self) -> &LatencyConfig {
+        &self.config
+    }
+    
+    /// Fold a round trip measured between two regions into the smoothed one
+    pub fn record(&self, from: &str, to: &str, rtt: Duration, now: Timestamp) {
+        let key = pair(Region::parse(from), Region::parse(to));
+        let mut measured = self.measured.write();
+        let rtt = match measured.get(&key) {
+            Some(previous) if self.fresh(previous, now) => {
+                let smoothing = self.config.smoothing.clamp(0.0, 1.0);
+                Duration::from_secs_f64(
+                    previous.rtt.as_secs_f64() * (1.0 - smoothing) + rtt.as_secs_f64() * smoothing,
+                )
+            }
+            _ => rtt,
+        };
+        measured.insert(key, Measured { rtt, at: now });
+    }
+    
+    /// Take the coordinator's measurements where they are newer than ours
+    pub fn merge(&self, latencies: &[MeasuredLatency]) {
+        let mut measured = self.measured.write();
+        for latency in latencies {
+            let key = pair(Region::parse(&latency.from), Region::parse(&latency.to));
+            if measured.get(&key).map_or(true, |known| known.at < latency.measured_at) {
+                measured.insert(
+                    key,
+                    Measured {
+                        rtt: latency.rtt,
+                        at: latency.measured_at,
+                    },
+                );
+            }
+        }
+    }
+    
+    /// The round trip between two regions, measured if it was lately and estimated if not
+    pub fn between(&self, a: &Region, b: &Region, now: Timestamp) -> Duration {
+        let measured = self
+            .measured
+            .read()
+            .get(&pair(a.clone(), b.clone()))
+            .filter(|measured| self.fresh(measured, now))
+            .map(|measured| measured.rtt);
+        measured.unwrap_or_else(|| match a.proximity(b) {
+            Proximity::Zone => self.config.same_zone,
+            Proximity::Area => self.config.same_area,
+            Proximity::Continent => self.config.same_continent,
+            Proximity::Distant => self.config.distant,
+        })
+    }
+    
+    /// The round trip along a path through `regions` in order
+    pub fn path(&self, regions: &[Region], now: Timestamp) -> Duration {
+        regions
+            .windows(2)
+            .map(|hop| self.between(&hop[0], &hop[1], now))
+            .sum()
+    }
+    
+    /// Round trips measured lately, for the coordinator to share
+    pub fn snapshot(&self, now: Timestamp) -> Vec<MeasuredLatency> {
+        let mut latencies: Vec<MeasuredLatency> = self
+            .measured
+            .read()
+            .iter()
+            .filter(|(_, measured)| self.fresh(measured, now))
+            .map(|((from, to), measured)| MeasuredLatency {
+                from: from.to_string(),
+                to: to.to_string(),
+                rtt: measured.rtt,
+                measured_at: measured.at,
+            })
+            .collect();
+        latencies.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+        latencies
+    }
+    
+    /// The shortest path from `entry` through `hops` of `routing` to one of `exits`, and its round trip
+    ///
+    /// The regions of the hops are searched hop by hop, keeping the [`SEARCH_WIDTH`] most
+    /// promising paths so far, half the shortest and half those spanning the most areas, so
+    /// the search grows with hops times regions rather than regions to the power of hops.
+    /// Paths spanning fewer than `min_areas` areas are only chosen if no path kept can span
+    /// that many. Within a region, nodes are drawn at random in proportion to their
+    /// headroom, so circuits spread over a region's nodes rather than piling onto the
+    /// least loaded, and a node is never used twice.
+    pub fn shortest_path<'a>(
+        &self,
+        entry: &Node,
+        routing: &[&'a Node],
+        exits: &[&'a Node],
+        hops: usize,
+        now: Timestamp,
+    ) -> Option<(Vec<&'a Node>, &'a Node, Duration)> {
+        let entry_region = Region::parse(&entry.region);
+        let routing = groups(routing.iter().copied().filter(|node| node.id != entry.id));
+        let exits = groups(exits.iter().copied().filter(|node| node.id != entry.id));
+        if routing.is_empty() || exits.is_empty() || hops == 0 {
+            return None;
+        }
+        
+        // The most areas any path could span, capped by what is asked for
+        let areas: HashSet<&str> = std::iter::once(entry_region.area.as_str())
+            .chain(routing.iter().chain(&exits).map(|group| group.region.area.as_str()))
+            .collect();
+        let min_areas = self.config.min_areas.min(areas.len()).min(hops + 2);
+        
+        // Extend the kept paths by a region at a time, while regions have nodes left
+        let mut paths = vec![Partial {
+            sequence: Vec::new(),
+            rtt: Duration::ZERO,
+            areas: 1,
+        }];
+        for _ in 0..hops {
+            let mut extended = Vec::new();
+            for path in &paths {
+                let last = path.sequence.last().map_or(&entry_region, |i| &routing[*i].region);
+                for (i, group) in routing.iter().enumerate() {
+                    if path.sequence.iter().filter(|j| **j == i).count() >= group.nodes.len() {
+                        continue;
+                    }
+                    let mut sequence = path.sequence.clone();
+                    sequence.push(i);
+                    extended.push(Partial {
+                        rtt: path.rtt + self.between(last, &group.region, now),
+                        areas: spanned(&entry_region, &routing, &sequence, None),
+                        sequence,
+                    });
+                }
+            }
+            paths = most_promising(extended, min_areas);
+        }
+        
+        // Finish each kept path at each exit region, best first, and draw its nodes
+        let (routing, entry_region) = (&routing, &entry_region);
+        let mut finished: Vec<(bool, Duration, &Partial, &Group<'a>)> = paths
+            .iter()
+            .flat_map(|path| {
+                let last = path.sequence.last().map_or(entry_region, |i| &routing[*i].region);
+                exits.iter().map(move |group| {
+                    let areas = spanned(entry_region, routing, &path.sequence, Some(&group.region));
+                    (areas >= min_areas, path.rtt + self.between(last, &group.region, now), path, group)
+                })
+            })
+            .collect();
+        finished.sort_by(|a, b| (!a.0, a.1).cmp(&(!b.0, b.1)));
+        let mut rng = rand::thread_rng();
+        let (diverse, rtt, nodes, exit) = finished.into_iter().find_map(|(diverse, rtt, path, group)| {
+            let nodes = draw_hops(routing, &path.sequence, &mut rng)?;
+            let exit = draw(&group.nodes, 1, &nodes, &mut rng)?.pop()?;
+            Some((diverse, rtt, nodes, exit))
+        })?;
+        if !diverse {
+            metrics::increment_counter!("darknode_circuit_diversity_relaxed_total");
+        }
+        Some((nodes, exit, rtt))
+    }
+    
+    fn fresh(&self, measured: &Measured, now: Timestamp) -> bool {
+        now.saturating_duration_since(measured.at) <= self.config.max_age
+    }
+}
+
+/// The key of a pair of regions, the same whichever way round they are given
+fn pair(a: Region, b: Region) -> (Region, Region) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// Candidate nodes sharing a region
+struct Group<'a> {
+    region: Region,
+    nodes: Vec<&'a Node>,
+}
+
+/// Nodes grouped by region
+fn groups<'a>(nodes: impl Iterator<Item = &'a Node>) -> Vec<Group<'a>> {
+    let mut groups: BTreeMap<Region, Vec<&'a Node>> = BTreeMap::new();
+    for node in nodes {
+        groups.entry(Region::parse(&node.region)).or_default().push(node);
+    }
+    groups.into_iter().map(|(region, nodes)| Group { region, nodes }).collect()
+}
+
+/// A path searched so far, as the routing groups of its hops
+struct Partial {
+    sequence: Vec<usize>,
+    rtt: Duration,
+    areas: usize,
+}
+
+/// Areas a path from `entry` through the groups at `sequence`, and on to `exit`, spans
+fn spanned(entry: &Region, groups: &[Group<'_>], sequence: &[usize], exit: Option<&Region>) -> usize {
+    std::iter::once(entry)
+        .chain(sequence.iter().map(|i| &groups[*i].region))
+        .chain(exit)
+        .map(|region| region.area.as_str())
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// The paths worth extending: the shortest half of [`SEARCH_WIDTH`], and the half
+/// spanning the most areas, up to `min_areas`, the shortest of those first
+fn most_promising(mut paths: Vec<Partial>, min_areas: usize) -> Vec<Partial> {
+    paths.sort_by_key(|path| path.rtt);
+    let mut kept: Vec<Partial> = paths.drain(..paths.len().min(SEARCH_WIDTH / 2)).collect();
+    paths.sort_by_key(|path| (std::cmp::Reverse(path.areas.min(min_areas)), path.rtt));
+    kept.extend(paths.into_iter().take(SEARCH_WIDTH - kept.len()));
+    kept
+}
+
+/// Distinct nodes for the hops through the groups at `sequence`, or `None` if a group runs out
+fn draw_hops<'a>(groups: &[Group<'a>], sequence: &[usize], rng: &mut impl rand::Rng) -> Option<Vec<&'a Node>> {
+    let mut drawn: HashMap<usize, Vec<&'a Node>> = HashMap::new();
+    for i in sequence {
+        if !drawn.contains_key(i) {
+            let count = sequence.iter().filter(|j| *j == i).count();
+            drawn.insert(*i, draw(&groups[*i].nodes, count, &[], rng)?);
+        }
+    }
+    sequence.iter().map(|i| drawn.get_mut(i)?.pop()).collect()
+}
+
+/// `count` distinct nodes of `nodes` that aren't `taken`, drawn at random in proportion to
+/// their headroom, the least loaded if none has any, or `None` if there aren't enough
+fn draw<'a>(nodes: &[&'a Node], count: usize, taken: &[&'a Node], rng: &mut impl rand::Rng) -> Option<Vec<&'a Node>> {
+    let mut left: Vec<&'a Node> = nodes
+        .iter()
+        .copied()
+        .filter(|node| taken.iter().all(|taken| taken.id != node.id))
+        .collect();
+    let mut drawn = Vec::with_capacity(count);
+    for _ in 0..count {
+        let node = recommend::choose(&left, |node| scoring::headroom(node.load, 1.0), rng)
+            .or_else(|| left.iter().copied().min_by(|a, b| a.load.total_cmp(&b.load)))?;
+        left.retain(|left| left.id != node.id);
+        drawn.push(node);
+    }
+    Some(drawn)
+}
+
+/// Ping a node of each region in the directory every `ping_interval`, reporting the
+/// round trips in this node's heartbeats, until the task is dropped
+pub async fn ping_peers(
+    config: LatencyConfig,
+    node_id: NodeId,
+    node_manager: Arc<dyn NodeManager + Send + Sync>,
+    counters: Arc<ActivityCounters>,
+) {
+    let mut ticker = tokio::time::interval(config.ping_interval);
+    
+    loop {
+        ticker.tick().await;
+        
+        // One node per region is enough, a different one each round
+        let mut peers: HashMap<String, Vec<Node>> = HashMap::new();
+        for role in NodeRole::ALL {
+            match node_manager.get_available_nodes(role).await {
+                Ok(nodes) => {
+                    for node in nodes.into_iter().filter(|node| node.id != node_id) {
+                        let region = peers.entry(Region::parse(&node.region).to_string()).or_default();
+                        if region.iter().all(|known| known.id != node.id) {
+                            region.push(node);
+                        }
+                    }
+                }
+                Err(e) => tracing::debug!("Failed to list {:?} nodes to ping: {}", role, e),
+            }
+        }
+        for (region, nodes) in peers {
+            let Some(peer) = nodes.get(rand::random::<usize>() % nodes.len()) else {
+                continue;
+            };
+            let started = std::time::Instant::now();
+            let connect = tokio::net::TcpStream::connect((peer.ip_address, peer.port));
+            match tokio::time::timeout(PING_TIMEOUT, connect).await {
+                Ok(Ok(_)) => counters.record_peer_latency(&region, started.elapsed()),
+                Ok(Err(e)) => tracing::debug!("Failed to ping {:?} in {}: {}", peer.id, region, e),
+                Err(_) => tracing::debug!("Ping to {:?} in {} timed out", peer.id, region),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn node(region: &str, load: f64) -> Node {
+        Node {
+            region: region.to_string(),
+            load,
+            ..crate::fixtures::node(&[NodeRole::Routing, NodeRole::Exit])
+        }
+    }
+    
+    /// The shortest diverse round trip over every sequence of regions, each used at most twice
+    fn optimum(matrix: &LatencyMatrix, entry: &Region, routing: &[Region], exits: &[Region], hops: usize, now: Timestamp) -> Duration {
+        fn search(
+            matrix: &LatencyMatrix,
+            path: &mut Vec<Region>,
+            routing: &[Region],
+            exits: &[Region],
+            hops: usize,
+            now: Timestamp,
+        ) -> Option<Duration> {
+            if path.len() == hops + 1 {
+                return exits
+                    .iter()
+                    .filter_map(|exit| {
+                        let mut whole = path.clone();
+                        whole.push(exit.clone());
+                        let areas: HashSet<&str> = whole.iter().map(|region| region.area.as_str()).collect();
+                        (areas.len() >= 2).then(|| matrix.path(&whole, now))
+                    })
+                    .min();
+            }
+            let mut best = None;
+            for region in routing {
+                if path.iter().filter(|used| *used == region).count() >= 2 {
+                    continue;
+                }
+                path.push(region.clone());
+                best = best.into_iter().chain(search(matrix, path, routing, exits, hops, now)).min();
+                path.pop();
+            }
+            best
+        }
+        search(matrix, &mut vec![entry.clone()], routing, exits, hops, now).unwrap()
+    }
+    
+    #[test]
+    fn three_hop_paths_are_near_the_optimum_and_span_two_areas() {
+        let matrix = LatencyMatrix::new(LatencyConfig::default());
+        let now = Timestamp::from_secs(1_700_000_000);
+        matrix.record("eu-west-1", "us-east-1", Duration::from_millis(70), now);
+        matrix.record("eu-central-1", "ap-south-1", Duration::from_millis(110), now);
+        
+        let entry = node("eu-west-1", 0.0);
+        let regions = ["eu-west-1", "eu-west-2", "eu-central-1", "us-east-1", "us-west-2", "ap-south-1", "sa-east-1"];
+        let routing: Vec<Node> = regions.iter().flat_map(|region| [node(region, 0.2), node(region, 0.6)]).collect();
+        let exits: Vec<Node> = ["us-east-1", "ap-south-1", "eu-west-1"].iter().map(|region| node(region, 0.1)).collect();
+        let routing_refs: Vec<&Node> = routing.iter().collect();
+        let exit_refs: Vec<&Node> = exits.iter().collect();
+        
+        let (hops, exit, rtt) = matrix.shortest_path(&entry, &routing_refs, &exit_refs, 3, now).unwrap();
+        let best = optimum(
+            &matrix,
+            &Region::parse(&entry.region),
+            &regions.map(Region::parse),
+            &exits.iter().map(|exit| Region::parse(&exit.region)).collect::<Vec<_>>(),
+            3,
+            now,
+        );
+        assert!(rtt <= best * 3 / 2, "{:?} against an optimum of {:?}", rtt, best);
+        
+        let mut path: Vec<&Node> = vec![&entry];
+        path.extend(hops.iter().copied());
+        path.push(exit);
+        let areas: HashSet<String> = path.iter().map(|node| Region::parse(&node.region).area).collect();
+        assert!(areas.len() >= 2);
+        let ids: HashSet<&NodeId> = path.iter().map(|node| &node.id).collect();
+        assert_eq!(ids.len(), path.len());
+    }
+    
+    #[test]
+    fn nodes_of_a_region_are_drawn_by_headroom() {
+        let matrix = LatencyMatrix::new(LatencyConfig::default());
+        let now = Timestamp::from_secs(1_700_000_000);
+        let entry = node("eu-west-1", 0.0);
+        let light = node("us-east-1", 0.1);
+        let heavy = node("us-east-1", 0.9);
+        let exit = node("ap-south-1", 0.0);
+        
+        let mut light_drawn = 0;
+        for _ in 0..500 {
+            let (hops, _, _) = matrix.shortest_path(&entry, &[&light, &heavy], &[&exit], 1, now).unwrap();
+            if hops[0].id == light.id {
+                light_drawn += 1;
+            }
+        }
+        // Nine times the headroom, so drawn nine times as often
+        assert!((400..500).contains(&light_drawn), "{}", light_drawn);
+    }
+    
+    #[test]
+    fn long_paths_over_many_regions_are_searched_quickly() {
+        let matrix = LatencyMatrix::new(LatencyConfig::default());
+        let now = Timestamp::from_secs(1_700_000_000);
+        let entry = node("eu-west-1", 0.0);
+        let routing: Vec<Node> = (0..60).map(|i| node(&format!("r{}-area-{}", i % 7, i), 0.3)).collect();
+        let routing: Vec<&Node> = routing.iter().collect();
+        let exit = node("ap-south-1", 0.0);
+        
+        let started = std::time::Instant::now();
+        let (hops, _, _) = matrix.shortest_path(&entry, &routing, &[&exit], 8, now).unwrap();
+        assert_eq!(hops.len(), 8);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,223 @@
+//! Relaxing the circuit policy while the network is too small for it
+//!
+//! A strict [`CircuitPolicy`], asking for routing hops in distinct regions or for several
+//! of them, protects users best but needs enough nodes to be met. When nodes leave, every
+//! circuit build can start failing even though a shorter or less spread out circuit would
+//! still be built, and the network goes down entirely. An entry node therefore keeps an
+//! [`ErrorBudget`]: once more than [`RelaxationConfig::max_failure_rate`] of the builds in
+//! the last [`RelaxationConfig::window`] failed, it relaxes the next of the
+//! [`RelaxationConfig::relaxable`] constraints, in the order they are listed. Circuits built
+//! under a relaxed policy name the relaxed constraints in their `relaxed` tag, and every
+//! change is emitted on the event bus for operators. Builds that fail only for the exits a
+//! request narrowed itself to, by region, subset, or method class, aren't counted: the
+//! network could meet the policy, so one user's constraints relax it for no one.
+//!
+//! While relaxed, each build first tries the next stricter policy and only falls back to the
+//! relaxed one if that fails. Once builds under the stricter policy fail at most half as
+//! often as the budget allows, the constraint is restored. One routing hop is a hard
+//! constraint: no relaxation ever builds a circuit without one.
+
+use super::*;
+use super::types::Node;
+use std::collections::{HashSet, VecDeque};
+
+/// The constraints circuits are built under
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitPolicy {
+    /// Routing hops a circuit uses when enough nodes are available
+    pub routing_hops: usize,
+    /// Fewest routing hops a circuit may have, never less than 1
+    pub min_routing_hops: usize,
+    /// Whether every node of a circuit must be in a different region
+    pub region_diversity: bool,
+}
+
+impl Default for CircuitPolicy {
+    fn default() -> Self {
+        Self {
+            routing_hops: 2,
+            min_routing_hops: 1,
+            region_diversity: false,
+        }
+    }
+}
+
+impl CircuitPolicy {
+    /// Whether the circuit through `path`, entry first and exit last, meets the policy
+    pub fn admits(&self, path: &[&Node]) -> bool {
+        let routing_hops = path.len().saturating_sub(2);
+        if routing_hops < self.min_routing_hops.max(1) {
+            return false;
+        }
+        let mut regions = HashSet::new();
+        !self.region_diversity || path.iter().all(|node| regions.insert(node.region.as_str()))
+    }
+}
+
+/// A constraint of the circuit policy that may be relaxed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Relaxation {
+    /// Allow several nodes of a circuit in one region
+    RegionDiversity,
+    /// Allow as few routing hops as [`RelaxationConfig::hop_floor`]
+    RoutingHops,
+}
+
+impl Relaxation {
+    /// Label used in events, logs, and metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            Relaxation::RegionDiversity => "region_diversity",
+            Relaxation::RoutingHops => "routing_hops",
+        }
+    }
+}
+
+/// How far the circuit policy may be relaxed, and when
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelaxationConfig {
+    /// The policy circuits are built under while they build
+    pub policy: CircuitPolicy,
+    /// Constraints that may be relaxed, in the order they are
+    pub relaxable: Vec<Relaxation>,
+    /// Fewest routing hops relaxing them goes down to, never less than 1
+    pub hop_floor: usize,
+    /// How far back build outcomes are counted
+    pub window: Duration,
+    /// Share of builds in the window that may fail before the policy is relaxed a step
+    pub max_failure_rate: f64,
+    /// Builds in the window before its failure rate is acted on
+    pub min_builds: usize,
+}
+
+impl Default for RelaxationConfig {
+    fn default() -> Self {
+        Self {
+            policy: CircuitPolicy::default(),
+            relaxable: vec![Relaxation::RegionDiversity, Relaxation::RoutingHops],
+            hop_floor: 1,
+            window: Duration::from_secs(300),
+            max_failure_rate: 0.5,
+            min_builds: 5,
+        }
+    }
+}
+
+impl RelaxationConfig {
+    /// The policy with `relaxed` relaxed
+    pub fn policy(&self, relaxed: &[Relaxation]) -> CircuitPolicy {
+        let mut policy = self.policy.clone();
+        for relaxation in relaxed {
+            match relaxation {
+                Relaxation::RegionDiversity => policy.region_diversity = false,
+                Relaxation::RoutingHops => {
+                    policy.min_routing_hops = policy.min_routing_hops.min(self.hop_floor).max(1);
+                }
+            }
+        }
+        policy
+    }
+}
+
+/// A change the error budget made to the circuit policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyChange {
+    /// The constraint was relaxed
+    Relaxed(Relaxation),
+    /// The constraint was restored
+    Restored(Relaxation),
+}
+
+/// Build outcomes since the policy last changed
+#[derive(Default)]
+struct BudgetState {
+    /// Constraints relaxed, the first this many of the steps
+    level: usize,
+    /// Outcomes of builds under the policy in force, oldest first
+    builds: VecDeque<(Timestamp, bool)>,
+    /// Outcomes of builds under the next stricter policy, oldest first
+    probes: VecDeque<(Timestamp, bool)>,
+}
+
+/// Tracks how often circuits fail to build, relaxing and restoring the policy to match
+pub struct ErrorBudget {
+    config: RelaxationConfig,
+    /// The relaxable constraints that change the policy, in the order they are relaxed
+    steps: Vec<Relaxation>,
+    state: parking_lot::Mutex<BudgetState>,
+}
+
+impl ErrorBudget {
+    /// Track build failures against `config`
+    pub fn new(config: RelaxationConfig) -> Self {
+        let mut steps: Vec<Relaxation> = Vec::new();
+        for relaxation in config.relaxable.iter().copied() {
+            let changes = config.policy(&[relaxation]) != config.policy;
+            if changes && !steps.contains(&relaxation) {
+                steps.push(relaxation);
+            }
+        }
+        Self {
+            config,
+            steps,
+            state: parking_lot::Mutex::new(BudgetState::default()),
+        }
+    }
+    
+    /// The constraints relaxed now
+    pub fn relaxed(&self) -> Vec<Relaxation> {
+        self.steps[..self.state.lock().level].to_vec()
+    }
+    
+    /// The relaxations to build a circuit under, in the order to try them
+    ///
+    /// While the policy is relaxed the next stricter policy comes first.
+    pub fn attempts(&self) -> Vec<Vec<Relaxation>> {
+        let level = self.state.lock().level;
+        let stricter = level.checked_sub(1).map(|stricter| self.steps[..stricter].to_vec());
+        stricter.into_iter().chain(std::iter::once(self.steps[..level].to_vec())).collect()
+    }
+    
+    /// The policy with `relaxed` relaxed
+    pub fn policy(&self, relaxed: &[Relaxation]) -> CircuitPolicy {
+        self.config.policy(relaxed)
+    }
+    
+    /// Count a build under `relaxed` at `now`, returning how the policy changed, if it did
+    pub fn record(&self, relaxed: &[Relaxation], built: bool, now: Timestamp) -> Option<PolicyChange> {
+        let mut state = self.state.lock();
+        let level = state.level;
+        let outcomes = if relaxed.len() == level {
+            &mut state.builds
+        } else if relaxed.len() + 1 == level {
+            &mut state.probes
+        } else {
+            // Tried before the policy last changed
+            return None;
+        };
+        outcomes.push_back((now, built));
+        while outcomes.front().map_or(false, |(at, _)| *at + self.config.window < now) {
+            outcomes.pop_front();
+        }
+        if outcomes.len() < self.config.min_builds.max(1) {
+            return None;
+        }
+        let failure_rate = outcomes.iter().filter(|(_, built)| !built).count() as f64 / outcomes.len() as f64;
+        
+        let change = if relaxed.len() == level && failure_rate > self.config.max_failure_rate && level < self.steps.len() {
+            state.level += 1;
+            PolicyChange::Relaxed(self.steps[level])
+        } else if relaxed.len() < level && failure_rate <= self.config.max_failure_rate / 2.0 {
+            state.level -= 1;
+            PolicyChange::Restored(self.steps[level - 1])
+        } else {
+            return None;
+        };
+        state.builds.clear();
+        state.probes.clear();
+        Some(change)
+    }
+}
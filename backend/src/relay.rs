@@ -0,0 +1,278 @@
+//! Relaying Solana transactions until they confirm
+//!
+//! A transaction sent through several hops spends part of its blockhash lifetime in
+//! transit, and a single broadcast that a provider drops is easily lost. When the client
+//! sets `darknode.refresh_blockhash` on a `sendTransaction`, the exit node fetches the
+//! latest blockhash right before broadcasting, rebroadcasts on a fixed cadence until the
+//! transaction reaches the configured commitment or the deadline passes, and reports each
+//! step back through the circuit as a status notification ahead of the final response.
+//!
+//! Signed transaction bytes are never modified, since changing the blockhash would void
+//! the signatures; the fresh blockhash is attached to the notifications so the wallet can
+//! re-sign against it if the transaction expires. The bytes are held only in memory for
+//! the duration of the relay and never appear in notifications, logs, or audit records.
+
+use super::*;
+use super::methods::{self, EXTENSION_KEY};
+use super::types::ResponseChunk;
+
+/// Extension flag a client sets to have a `sendTransaction` relayed
+pub const REFRESH_BLOCKHASH_FLAG: &str = "refresh_blockhash";
+
+/// Method of the status notifications sent ahead of the final response
+pub const STATUS_METHOD: &str = "darknode_transactionStatus";
+
+/// Commitment levels in increasing order of finality
+const COMMITMENTS: &[&str] = &["processed", "confirmed", "finalized"];
+
+/// How relayed transactions are rebroadcast and when they are given up on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    /// Whether exit nodes honour the client's relay flag at all
+    pub enabled: bool,
+    /// How long to wait between status checks and rebroadcasts
+    pub retry_interval: Duration,
+    /// How long after the first broadcast to give up, roughly a blockhash lifetime
+    pub deadline: Duration,
+    /// Commitment the transaction must reach to count as confirmed
+    pub commitment: String,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retry_interval: Duration::from_secs(2),
+            deadline: Duration::from_secs(60),
+            commitment: "confirmed".to_string(),
+        }
+    }
+}
+
+/// A step of a relayed transaction reported to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayStatus {
+    /// A provider accepted the transaction
+    Broadcast,
+    /// A provider rejected the transaction; it will be sent again
+    Rejected,
+    /// The transaction reached the configured commitment
+    Confirmed,
+    /// The transaction landed but failed on chain
+    Failed,
+    /// The deadline passed before the transaction confirmed
+    Expired,
+}
+
+impl RelayStatus {
+    /// Label used in metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            RelayStatus::Broadcast => "broadcast",
+            RelayStatus::Rejected => "rejected",
+            RelayStatus::Confirmed => "confirmed",
+            RelayStatus::Failed => "failed",
+            RelayStatus::Expired => "expired",
+        }
+    }
+}
+
+/// Whether a client asked for a request to be relayed
+pub fn requested(request: &serde_json::Value) -> bool {
+    methods::method_name(request) == Some("sendTransaction")
+        && request[EXTENSION_KEY][REFRESH_BLOCKHASH_FLAG].as_bool() == Some(true)
+}
+
+/// The request fetching the blockhash attached to status notifications
+pub fn latest_blockhash_request() -> serde_json::Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 0,
+        "method": "getLatestBlockhash",
+        "params": [{"commitment": "confirmed"}],
+    })
+}
+
+/// The blockhash and its last valid block height from a `getLatestBlockhash` response
+pub fn blockhash(response: &serde_json::Value) -> Option<serde_json::Value> {
+    let value = &response["result"]["value"];
+    value["blockhash"].as_str()?;
+    Some(value.clone())
+}
+
+/// The signature a provider returned for an accepted `sendTransaction`
+pub fn signature(response: &[u8]) -> Option<String> {
+    let response: serde_json::Value = serde_json::from_slice(response).ok()?;
+    response["result"].as_str().map(str::to_string)
+}
+
+/// The request checking whether `signature` has landed
+pub fn status_request(signature: &str) -> serde_json::Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 0,
+        "method": "getSignatureStatuses",
+        "params": [[signature], {"searchTransactionHistory": false}],
+    })
+}
+
+/// The outcome a `getSignatureStatuses` response shows, if the transaction is settled
+pub fn settled(response: &serde_json::Value, commitment: &str) -> Option<RelayStatus> {
+    let status = &response["result"]["value"][0];
+    if status.is_null() {
+        return None;
+    }
+    if !status["err"].is_null() {
+        return Some(RelayStatus::Failed);
+    }
+    let rank = |level: &str| COMMITMENTS.iter().position(|c| *c == level);
+    let reached = rank(status["confirmationStatus"].as_str()?)?;
+    (reached >= rank(commitment).unwrap_or(1)).then_some(RelayStatus::Confirmed)
+}
+
+/// A status notification for the client, carrying no transaction bytes
+pub fn notification(
+    request: &serde_json::Value,
+    status: RelayStatus,
+    attempt: u32,
+    signature: Option<&str>,
+    blockhash: Option<&serde_json::Value>,
+) -> serde_json::Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": STATUS_METHOD,
+        "params": {
+            "id": request["id"].clone(),
+            "status": status,
+            "attempt": attempt,
+            "signature": signature,
+            "blockhash": blockhash,
+        },
+    })
+}
+
+/// Delivers a relay's notifications and final response as chunks of one response
+pub struct StatusSink {
+    request_id: Uuid,
+    sequence: u32,
+    sender: futures::channel::mpsc::UnboundedSender<Result<ResponseChunk>>,
+}
+
+impl StatusSink {
+    /// Create a sink for `request_id` and the stream its chunks are read from
+    pub fn new(request_id: Uuid) -> (Self, futures::channel::mpsc::UnboundedReceiver<Result<ResponseChunk>>) {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let sink = Self {
+            request_id,
+            sequence: 0,
+            sender,
+        };
+        (sink, receiver)
+    }
+    
+    /// Send a status notification, newline-terminated so clients can split a chunked body
+    pub fn notify(&mut self, notification: &serde_json::Value) {
+        let mut data = serde_json::to_vec(notification).unwrap_or_default();
+        data.push(b'\n');
+        self.deliver(Ok(data), false);
+    }
+    
+    /// End the response with its final body
+    pub fn finish(&mut self, data: Vec<u8>) {
+        self.deliver(Ok(data), true);
+    }
+    
+    /// End the response with an error
+    pub fn fail(&mut self, err: anyhow::Error) {
+        self.deliver(Err(err), true);
+    }
+    
+    fn deliver(&mut self, data: Result<Vec<u8>>, last: bool) {
+        let chunk = data.map(|data| ResponseChunk {
+            request_id: self.request_id,
+            sequence: self.sequence,
+            data,
+            last,
+        });
+        self.sequence += 1;
+        // The client going away only means nobody is listening for the rest
+        let _ = self.sender.unbounded_send(chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::impls::StoredRpcManager;
+    use crate::storage::MemoryStorage;
+    use crate::traits::RpcManager;
+    use crate::types::ExitPayload;
+    use futures::StreamExt;
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    
+    const SIGNATURE: &str = "5VERv8NMvzbJMEkV8xnrLkEaWRtSz9CosKDYjCJjBRnb";
+    
+    #[tokio::test]
+    async fn a_rejected_broadcast_is_sent_again_until_it_confirms() {
+        let broadcasts = Arc::new(AtomicUsize::new(0));
+        let sent = broadcasts.clone();
+        let provider = fixtures::serving(move |request: Value| {
+            let first = request["method"] == "sendTransaction" && sent.fetch_add(1, Ordering::SeqCst) == 0;
+            async move {
+                let result = match request["method"].as_str() {
+                    _ if first => {
+                        let error = json!({ "code": -32005, "message": "Node is behind" });
+                        return json!({ "jsonrpc": "2.0", "id": request["id"], "error": error });
+                    }
+                    Some("sendTransaction") => json!(SIGNATURE),
+                    Some("getLatestBlockhash") => json!({
+                        "context": { "slot": 1 },
+                        "value": { "blockhash": "EkSnNWid2cvwEVnVx9aBqawnmiCNiDgp3gUdkDPTKN1N", "lastValidBlockHeight": 150 },
+                    }),
+                    Some("getSignatureStatuses") => json!({
+                        "context": { "slot": 2 },
+                        "value": [{ "slot": 2, "err": null, "confirmationStatus": "confirmed" }],
+                    }),
+                    _ => Value::Null,
+                };
+                json!({ "jsonrpc": "2.0", "id": request["id"], "result": result })
+            }
+        });
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        rpc_manager.register_provider(provider).await.unwrap();
+        let exit = Arc::new(fixtures::exit(rpc_manager));
+        
+        let payload = ExitPayload {
+            relay: true,
+            ..fixtures::payload("sendTransaction", json!(["AQABAg==", { "encoding": "base64" }]))
+        };
+        let chunks: Vec<ResponseChunk> = exit.serve_stream(payload, Uuid::new_v4()).map(Result::unwrap).collect().await;
+        let (last, notifications) = chunks.split_last().unwrap();
+        let steps: Vec<(Value, Value)> = notifications
+            .iter()
+            .map(|chunk| {
+                let notification: Value = serde_json::from_slice(&chunk.data).unwrap();
+                assert_eq!(notification["method"], STATUS_METHOD);
+                (notification["params"]["status"].clone(), notification["params"]["attempt"].clone())
+            })
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (json!(RelayStatus::Rejected), json!(1)),
+                (json!(RelayStatus::Broadcast), json!(2)),
+                (json!(RelayStatus::Confirmed), json!(2)),
+            ]
+        );
+        assert_eq!(broadcasts.load(Ordering::SeqCst), 2);
+        
+        assert!(last.last);
+        let response: Value = serde_json::from_slice(&last.data).unwrap();
+        assert_eq!(response["result"], SIGNATURE);
+        assert_eq!(response[EXTENSION_KEY]["relay"], json!({ "status": "confirmed", "attempts": 2 }));
+    }
+}
@@ -0,0 +1,137 @@
+//! Sending reads again on a rebuilt circuit when a hop fails under them
+//!
+//! A hop that goes down while a request is in flight takes the request with it. Routers
+//! report failures they can pin on a hop as a [`HopFailure`]: the next hop refused the
+//! connection, a response failed its integrity check, a hop gave up waiting on the one
+//! after it, the exit node refused the request for having spent its provider budget, a
+//! hop no longer holds the circuit at all, or a hop failed the message with an error of
+//! its own.
+//! Running out of the request's own budget is not one of them.
+//!
+//! For reads, the entry node then drops the circuit, builds the user a new one without the
+//! suspect node, and sends the sanitized request through it once more, within whatever is
+//! left of the request's deadline. Mutating methods such as `sendTransaction` are never
+//! sent twice, since the first attempt may have reached the chain, unless the exit node
+//! refused them for being at capacity, or a hop refused the circuit as one it no longer
+//! holds, both of which happen before the request is sent anywhere. A circuit a hop no
+//! longer holds is dropped even when the request isn't sent again, so later requests get
+//! a new one.
+//! Notifications and requests whose responses are streamed are never sent again. A request replayed this way
+//! carries a `retry` extension in its response naming the kind of failure, never the node.
+
+use super::*;
+use super::methods;
+use super::types::NodeId;
+
+/// When failed reads are sent again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayConfig {
+    /// Whether reads are sent again at all
+    pub enabled: bool,
+    /// Least budget a request must have left to be sent again
+    pub min_remaining: Duration,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_remaining: Duration::from_millis(100),
+        }
+    }
+}
+
+impl ReplayConfig {
+    /// Whether what `request` needs to be sent again should be kept, in case a hop fails under it
+    pub fn keeps(&self, request: &serde_json::Value) -> bool {
+        self.enabled && !methods::is_notification(request) && methods::method_name(request).is_some()
+    }
+    
+    /// Whether `request` may be sent again after a hop failed under it as `kind` says
+    ///
+    /// Requests whose method can't be told are treated as mutating.
+    pub fn allows(&self, request: &serde_json::Value, kind: HopFailureKind) -> bool {
+        self.keeps(request)
+            && (matches!(kind, HopFailureKind::AtCapacity | HopFailureKind::CircuitUnknown)
+                || methods::method_name(request).map_or(false, |method| !methods::is_mutating(method)))
+    }
+}
+
+/// How a hop failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[serde(rename_all = "snake_case")]
+pub enum HopFailureKind {
+    /// The hop could not be connected to
+    #[error("unreachable")]
+    Unreachable,
+    /// What came back through the hop failed its integrity check
+    #[error("tampered with")]
+    Tampered,
+    /// The hop timed out waiting on the next one
+    #[error("timed out")]
+    TimedOut,
+    /// The exit node has spent its request budget, see [`crate::budget`]
+    #[error("is at capacity")]
+    AtCapacity,
+    /// The hop no longer holds the circuit, having reclaimed it, see [`crate::reclaim`]
+    #[error("no longer holds the circuit")]
+    CircuitUnknown,
+    /// The hop answered with an error of its own
+    #[error("failed")]
+    Failed,
+}
+
+impl HopFailureKind {
+    /// Label for metrics and the `retry` extension
+    pub fn label(&self) -> &'static str {
+        match self {
+            HopFailureKind::Unreachable => "unreachable",
+            HopFailureKind::Tampered => "tampered",
+            HopFailureKind::TimedOut => "timed_out",
+            HopFailureKind::AtCapacity => "at_capacity",
+            HopFailureKind::CircuitUnknown => "circuit_unknown",
+            HopFailureKind::Failed => "failed",
+        }
+    }
+}
+
+/// A request failed because of one hop of its circuit
+///
+/// The node is left out of the message, which may reach the client. Hops pass failures
+/// of the hops after them back as JSON, see [`crate::transport`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[error("a hop of the circuit {kind}")]
+pub struct HopFailure {
+    /// The node suspected, if the router could tell which
+    pub node: Option<NodeId>,
+    /// How it failed
+    pub kind: HopFailureKind,
+}
+
+/// The hop failure behind `error`, if any, however deeply it is wrapped
+pub fn hop_failure(error: &anyhow::Error) -> Option<&HopFailure> {
+    error.chain().find_map(|cause| cause.downcast_ref::<HopFailure>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn writes_are_only_sent_again_after_a_hop_refused_them_before_sending_them_on() {
+        let config = ReplayConfig::default();
+        let read = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getBalance" });
+        let write = serde_json::json!({ "jsonrpc": "2.0", "id": 2, "method": "sendTransaction" });
+        
+        assert!(config.keeps(&read) && config.keeps(&write));
+        assert!(config.allows(&read, HopFailureKind::Failed));
+        assert!(!config.allows(&write, HopFailureKind::Failed));
+        assert!(!config.allows(&write, HopFailureKind::TimedOut));
+        assert!(config.allows(&write, HopFailureKind::AtCapacity));
+        assert!(config.allows(&write, HopFailureKind::CircuitUnknown));
+        
+        let disabled = ReplayConfig { enabled: false, ..config };
+        assert!(!disabled.allows(&read, HopFailureKind::AtCapacity));
+    }
+}
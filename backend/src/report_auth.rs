@@ -0,0 +1,521 @@
+//! Authenticating the reports nodes post to the coordinator
+//!
+//! What a node reports about itself, such as its heartbeats, steers how the coordinator
+//! builds the directory, so it can't be taken from whoever posts it. Nodes sign every
+//! report with their long-term identity key, over their ID, the coordinator path it is
+//! posted to, the time it was signed and the body's SHA-256 hash, see [`ReportSigner`].
+//!
+//! The coordinator checks the headers before the body, in [`require_signed_report`]:
+//! reports without them, signed too far from its own clock, or from a node it doesn't
+//! know are refused unread, bodies over `max_body_bytes` are refused, and reports whose
+//! signature doesn't verify under any key the node may be using are refused. A signature
+//! is taken once: replaying a captured report within the freshness window is refused
+//! too. The node the report came from is handed to the handler as a [`ReportSender`],
+//! which checks the report is about that node.
+//!
+//! A node registering isn't known yet, so its registration is checked against the key it
+//! registers, proving the node holds it, see [`ReportVerifier::verify_registration`]. A
+//! node already registered can only register again under a key it is registered with,
+//! and signed with that registered identity; new keys come through rotation.
+//!
+//! The signed message is `darknode-report:v1\n<node id>\n<path>\n<timestamp>\n<body hash>`,
+//! where `<timestamp>` is in milliseconds since the Unix epoch and `<body hash>` is hex.
+//! Signatures are base64-encoded.
+
+use super::*;
+use super::expiring::ExpiringMap;
+use super::identity::{self, NodeIdentity};
+use super::traits::{Crypto, NodeManager};
+use super::types::NodeId;
+use super::upstream;
+use axum::body::Body;
+use axum::extract::Extension;
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use tokio::time::Instant;
+
+/// Header carrying the reporting node's ID
+pub const NODE_HEADER: &str = "x-darknode-report-node";
+
+/// Header carrying when the report was signed, in milliseconds since the Unix epoch
+pub const TIMESTAMP_HEADER: &str = "x-darknode-report-timestamp";
+
+/// Header carrying the node's base64 signature
+pub const SIGNATURE_HEADER: &str = "x-darknode-report-signature";
+
+/// Prefix of every signed message, so report signatures can't be replayed elsewhere
+const MESSAGE_PREFIX: &str = "darknode-report:v1";
+
+/// Most signatures remembered to refuse replays
+const MAX_REMEMBERED: usize = 100_000;
+
+/// How reports posted by nodes are authenticated
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportAuthConfig {
+    /// Whether reports must be signed by the node they come from
+    pub enabled: bool,
+    /// How far a report's timestamp may be from the coordinator's clock
+    pub max_skew: Duration,
+    /// Largest report body taken
+    pub max_body_bytes: usize,
+}
+
+impl Default for ReportAuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_skew: Duration::from_secs(60),
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Why a report was refused
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ReportRejected {
+    /// A header is missing
+    #[error("missing {0} header")]
+    Missing(&'static str),
+    /// A header can't be read
+    #[error("malformed {0} header")]
+    Malformed(&'static str),
+    /// The report was signed too far from the coordinator's clock
+    #[error("report signed at {0}ms is outside the freshness window")]
+    Stale(u64),
+    /// The report was taken before
+    #[error("report from node {} was already taken", .0 .0)]
+    Replayed(NodeId),
+    /// The node isn't registered
+    #[error("node {} is not registered", .0 .0)]
+    UnknownNode(NodeId),
+    /// The signature doesn't verify under any of the node's keys
+    #[error("signature does not verify for node {}", .0 .0)]
+    BadSignature(NodeId),
+    /// The body is larger than taken
+    #[error("report body is larger than {0} bytes")]
+    TooLarge(usize),
+    /// A registration names a node other than the signer, or a key it isn't registered with
+    #[error("registration does not match the identity of node {}", .0 .0)]
+    IdentityMismatch(NodeId),
+}
+
+impl ReportRejected {
+    /// Label used in metrics
+    pub fn label(&self) -> &'static str {
+        match self {
+            ReportRejected::Missing(_) => "missing",
+            ReportRejected::Malformed(_) => "malformed",
+            ReportRejected::Stale(_) => "stale",
+            ReportRejected::Replayed(_) => "replayed",
+            ReportRejected::UnknownNode(_) => "unknown_node",
+            ReportRejected::BadSignature(_) => "bad_signature",
+            ReportRejected::TooLarge(_) => "too_large",
+            ReportRejected::IdentityMismatch(_) => "identity_mismatch",
+        }
+    }
+}
+
+/// The message a node signs to post `body` to `path`
+pub fn signed_message(node_id: &NodeId, path: &str, timestamp: u64, body: &[u8]) -> Vec<u8> {
+    let hash = hex::encode(&Sha256::digest(body));
+    format!("{}\n{}\n{}\n{}\n{}", MESSAGE_PREFIX, node_id.0, path, timestamp, hash).into_bytes()
+}
+
+/// Signs the reports this node posts to the coordinator, see the module docs
+pub struct ReportSigner {
+    node_id: NodeId,
+    identity: Arc<NodeIdentity>,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+}
+
+impl ReportSigner {
+    /// Create a signer for reports from `node_id`
+    pub fn new(node_id: NodeId, identity: Arc<NodeIdentity>, crypto: Arc<dyn Crypto + Send + Sync>) -> Self {
+        Self { node_id, identity, crypto }
+    }
+    
+    /// The node reports are signed for
+    pub fn node_id(&self) -> &NodeId {
+        &self.node_id
+    }
+    
+    /// The headers to post `body` to `path` with at `now`
+    pub async fn headers(&self, path: &str, body: &[u8], now: Timestamp) -> Result<Vec<(&'static str, String)>> {
+        let timestamp = now.as_millis();
+        let message = signed_message(&self.node_id, path, timestamp, body);
+        let signature = self.identity.sign(&*self.crypto, &message, now).await?;
+        Ok(vec![
+            (NODE_HEADER, self.node_id.0.to_string()),
+            (TIMESTAMP_HEADER, timestamp.to_string()),
+            (SIGNATURE_HEADER, STANDARD.encode(signature)),
+        ])
+    }
+    
+    /// Post `body` as JSON to `path` on the coordinator at `base`, signed
+    pub async fn post<T: Serialize + ?Sized>(&self, client: &reqwest::Client, base: &str, path: &str, body: &T) -> Result<reqwest::Response> {
+        let body = serde_json::to_vec(body)?;
+        let mut request = client
+            .post(format!("{}{}", base.trim_end_matches('/'), path))
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        for (name, value) in self.headers(path, &body, Timestamp::now()).await? {
+            request = request.header(name, value);
+        }
+        Ok(request.body(body).send().await?)
+    }
+}
+
+/// The node a report was signed by, for handlers behind [`require_signed_report`]
+///
+/// Unset when report authentication is turned off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportSender(pub Option<NodeId>);
+
+impl ReportSender {
+    /// Whether a report about `node_id` may be taken from this sender
+    pub fn may_report_for(&self, node_id: &NodeId) -> bool {
+        self.0.as_ref().map_or(true, |sender| sender == node_id)
+    }
+}
+
+/// What a report's headers claim, before its body is looked at
+struct Claim {
+    node_id: NodeId,
+    timestamp: u64,
+    signature: Vec<u8>,
+}
+
+/// Checks that reports come from the registered nodes they claim, see the module docs
+pub struct ReportVerifier {
+    config: ReportAuthConfig,
+    node_manager: Arc<dyn NodeManager + Send + Sync>,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    seen: parking_lot::Mutex<ExpiringMap<Vec<u8>, ()>>,
+}
+
+impl ReportVerifier {
+    /// Create a verifier looking nodes up through `node_manager`
+    pub fn new(
+        config: ReportAuthConfig,
+        node_manager: Arc<dyn NodeManager + Send + Sync>,
+        crypto: Arc<dyn Crypto + Send + Sync>,
+    ) -> Self {
+        Self {
+            // A signature is remembered as long as it could pass the freshness check
+            seen: parking_lot::Mutex::new(ExpiringMap::new(config.max_skew * 2, MAX_REMEMBERED)),
+            config,
+            node_manager,
+            crypto,
+        }
+    }
+    
+    /// Whether reports must be signed
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+    
+    /// Check that `body`, posted to `path` with `headers`, was signed by a registered node,
+    /// returning the node
+    pub async fn verify(&self, headers: &HeaderMap, path: &str, body: &[u8], now: Timestamp) -> Result<NodeId, ReportRejected> {
+        let claim = self.claim(headers, now)?;
+        let node = self.node(&claim.node_id).await?;
+        if body.len() > self.config.max_body_bytes {
+            return Err(ReportRejected::TooLarge(self.config.max_body_bytes));
+        }
+        self.check(&claim, &node, path, body, now).await
+    }
+    
+    /// Check that `body`, the registration of `node` posted to `path` with `headers`, was
+    /// signed by the node it registers, returning the node
+    ///
+    /// A new node's signature must verify under the key it registers. A node already in the
+    /// directory must register a key it is registered with, and sign with its registered
+    /// identity, so nobody else can take its ID or an allowed key over.
+    pub async fn verify_registration(
+        &self,
+        headers: &HeaderMap,
+        path: &str,
+        body: &[u8],
+        node: &types::Node,
+        now: Timestamp,
+    ) -> Result<NodeId, ReportRejected> {
+        let claim = self.claim(headers, now)?;
+        if body.len() > self.config.max_body_bytes {
+            return Err(ReportRejected::TooLarge(self.config.max_body_bytes));
+        }
+        if claim.node_id != node.id {
+            return Err(ReportRejected::IdentityMismatch(claim.node_id));
+        }
+        let registered = match self.node_manager.get_node(&node.id).await {
+            Ok(registered) => registered,
+            Err(e) => {
+                tracing::warn!("Failed to look up registering node {}: {}", node.id.0, e);
+                return Err(ReportRejected::UnknownNode(node.id.clone()));
+            }
+        };
+        match registered {
+            Some(registered) => {
+                if !registered.accepted_public_keys(now).iter().any(|key| key.0 == node.public_key.0) {
+                    return Err(ReportRejected::IdentityMismatch(node.id.clone()));
+                }
+                self.check(&claim, &registered, path, body, now).await
+            }
+            None => {
+                let claimed = types::Node {
+                    next_public_key: None,
+                    next_key_activates_at: None,
+                    ..node.clone()
+                };
+                self.check(&claim, &claimed, path, body, now).await
+            }
+        }
+    }
+    
+    /// Read the headers and check the timestamp, touching nothing else
+    fn claim(&self, headers: &HeaderMap, now: Timestamp) -> Result<Claim, ReportRejected> {
+        let header = |name: &'static str| {
+            headers
+                .get(name)
+                .ok_or(ReportRejected::Missing(name))?
+                .to_str()
+                .map_err(|_| ReportRejected::Malformed(name))
+        };
+        let node_id = header(NODE_HEADER)?
+            .parse()
+            .map(NodeId)
+            .map_err(|_| ReportRejected::Malformed(NODE_HEADER))?;
+        let timestamp: u64 = header(TIMESTAMP_HEADER)?
+            .parse()
+            .map_err(|_| ReportRejected::Malformed(TIMESTAMP_HEADER))?;
+        let signature = STANDARD
+            .decode(header(SIGNATURE_HEADER)?)
+            .map_err(|_| ReportRejected::Malformed(SIGNATURE_HEADER))?;
+        
+        if Duration::from_millis(now.as_millis().abs_diff(timestamp)) > self.config.max_skew {
+            return Err(ReportRejected::Stale(timestamp));
+        }
+        Ok(Claim {
+            node_id,
+            timestamp,
+            signature,
+        })
+    }
+    
+    /// The registered record of `node_id`
+    async fn node(&self, node_id: &NodeId) -> Result<types::Node, ReportRejected> {
+        match self.node_manager.get_node(node_id).await {
+            Ok(Some(node)) => Ok(node),
+            Ok(None) => Err(ReportRejected::UnknownNode(node_id.clone())),
+            Err(e) => {
+                tracing::warn!("Failed to look up reporting node {}: {}", node_id.0, e);
+                Err(ReportRejected::UnknownNode(node_id.clone()))
+            }
+        }
+    }
+    
+    /// Check the signature over `body`, then that it wasn't taken before
+    async fn check(&self, claim: &Claim, node: &types::Node, path: &str, body: &[u8], now: Timestamp) -> Result<NodeId, ReportRejected> {
+        let message = signed_message(&claim.node_id, path, claim.timestamp, body);
+        let verifies = identity::verify_node_signature(&*self.crypto, node, &message, &claim.signature, now)
+            .await
+            .unwrap_or(false);
+        if !verifies {
+            return Err(ReportRejected::BadSignature(claim.node_id.clone()));
+        }
+        let mut seen = self.seen.lock();
+        if seen.get(&claim.signature, Instant::now()).is_some() {
+            return Err(ReportRejected::Replayed(claim.node_id.clone()));
+        }
+        seen.insert(claim.signature.clone(), (), Instant::now());
+        Ok(claim.node_id.clone())
+    }
+}
+
+/// Middleware refusing reports not signed by the registered node they come from
+///
+/// Install on the report routes with `axum::middleware::from_fn(require_signed_report)` as
+/// a route layer, with the [`ReportVerifier`] as an extension. The handler finds the node
+/// as a [`ReportSender`] extension. Refused reports are answered with `401 Unauthorized`,
+/// or `413 Payload Too Large`, and counted in `darknode_report_rejections_total`.
+pub async fn require_signed_report(
+    Extension(verifier): Extension<Arc<ReportVerifier>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+    if !verifier.enabled() {
+        parts.extensions.insert(ReportSender(None));
+        return next.run(Request::from_parts(parts, body)).await;
+    }
+    let now = Timestamp::now();
+    
+    // Headers and the node first, so unauthenticated reports are refused unread
+    let claimed = match verifier.claim(&parts.headers, now) {
+        Ok(claim) => verifier.node(&claim.node_id).await.map(|node| (claim, node)),
+        Err(e) => Err(e),
+    };
+    let (claim, node) = match claimed {
+        Ok(claimed) => claimed,
+        Err(e) => return reject(e),
+    };
+    
+    let Some(body) = upstream::read_request_body(body, verifier.config.max_body_bytes).await else {
+        return reject(ReportRejected::TooLarge(verifier.config.max_body_bytes));
+    };
+    let path = parts.uri.path().to_string();
+    match verifier.check(&claim, &node, &path, &body, now).await {
+        Ok(node_id) => parts.extensions.insert(ReportSender(Some(node_id))),
+        Err(e) => return reject(e),
+    };
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+/// Answer a report whose sender couldn't be authenticated
+pub fn reject(e: ReportRejected) -> Response {
+    tracing::debug!("Refusing node report: {}", e);
+    metrics::increment_counter!("darknode_report_rejections_total", "reason" => e.label());
+    match e {
+        ReportRejected::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+        _ => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::{CryptoImpl, StoredNodeManager};
+    use crate::storage::memory::MemoryStorage;
+    use crate::types::{Node, NodeRole};
+    
+    async fn registered(node_manager: &StoredNodeManager, identity: &NodeIdentity) -> NodeId {
+        let node = record(identity);
+        let node_id = node.id.clone();
+        node_manager.register_node(node).await.unwrap();
+        node_id
+    }
+    
+    fn record(identity: &NodeIdentity) -> Node {
+        Node {
+            public_key: identity.public_key(Timestamp::now()),
+            port: 3003,
+            ..crate::fixtures::node(&[NodeRole::Exit])
+        }
+    }
+    
+    fn header_map(headers: Vec<(&'static str, String)>) -> HeaderMap {
+        headers
+            .into_iter()
+            .map(|(name, value)| (axum::http::HeaderName::from_static(name), value.parse().unwrap()))
+            .collect()
+    }
+    
+    #[tokio::test]
+    async fn takes_a_report_once_from_the_node_that_signed_it() {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let node_manager = Arc::new(StoredNodeManager::new(Arc::new(MemoryStorage::new())));
+        let identity = Arc::new(NodeIdentity::generate(&*crypto, Duration::ZERO).await.unwrap());
+        let node_id = registered(&node_manager, &identity).await;
+        let verifier = ReportVerifier::new(ReportAuthConfig::default(), node_manager.clone(), crypto.clone());
+        let signer = ReportSigner::new(node_id.clone(), identity, crypto.clone());
+        
+        let now = Timestamp::now();
+        let headers = header_map(signer.headers("/nodes/heartbeat", b"{}", now).await.unwrap());
+        assert_eq!(verifier.verify(&headers, "/nodes/heartbeat", b"{}", now).await, Ok(node_id.clone()));
+        assert_eq!(
+            verifier.verify(&headers, "/nodes/heartbeat", b"{}", now).await,
+            Err(ReportRejected::Replayed(node_id.clone()))
+        );
+        
+        // Another body, another path, or another node's key don't verify
+        let headers = header_map(signer.headers("/nodes/heartbeat", b"{}", now).await.unwrap());
+        assert_eq!(
+            verifier.verify(&headers, "/nodes/heartbeat", b"{\"load\":0}", now).await,
+            Err(ReportRejected::BadSignature(node_id.clone()))
+        );
+        assert_eq!(
+            verifier.verify(&headers, "/nodes/next-key", b"{}", now).await,
+            Err(ReportRejected::BadSignature(node_id.clone()))
+        );
+        let stranger = Arc::new(NodeIdentity::generate(&*crypto, Duration::ZERO).await.unwrap());
+        let forged = ReportSigner::new(node_id.clone(), stranger, crypto.clone());
+        let headers = header_map(forged.headers("/nodes/heartbeat", b"{}", now).await.unwrap());
+        assert_eq!(
+            verifier.verify(&headers, "/nodes/heartbeat", b"{}", now).await,
+            Err(ReportRejected::BadSignature(node_id))
+        );
+    }
+    
+    #[tokio::test]
+    async fn registrations_are_signed_with_the_key_registered() {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let node_manager = Arc::new(StoredNodeManager::new(Arc::new(MemoryStorage::new())));
+        let verifier = ReportVerifier::new(ReportAuthConfig::default(), node_manager.clone(), crypto.clone());
+        let owner = Arc::new(NodeIdentity::generate(&*crypto, Duration::ZERO).await.unwrap());
+        let stranger = Arc::new(NodeIdentity::generate(&*crypto, Duration::ZERO).await.unwrap());
+        let now = Timestamp::now();
+        let signed = |node: &Node, identity: &Arc<NodeIdentity>, at: Timestamp| {
+            let signer = ReportSigner::new(node.id.clone(), identity.clone(), crypto.clone());
+            let body = serde_json::to_vec(node).unwrap();
+            async move { (header_map(signer.headers("/nodes", &body, at).await.unwrap()), body) }
+        };
+        
+        // A new node proves it holds the key it registers
+        let node = record(&owner);
+        let (headers, body) = signed(&node, &owner, now).await;
+        assert_eq!(verifier.verify_registration(&headers, "/nodes", &body, &node, now).await, Ok(node.id.clone()));
+        let (headers, body) = signed(&node, &stranger, now).await;
+        assert_eq!(
+            verifier.verify_registration(&headers, "/nodes", &body, &node, now).await,
+            Err(ReportRejected::BadSignature(node.id.clone()))
+        );
+        
+        // A registered node can't be taken over under another key, even one signing for it
+        node_manager.register_node(node.clone()).await.unwrap();
+        let taken = Node {
+            public_key: stranger.public_key(now),
+            ..node.clone()
+        };
+        let (headers, body) = signed(&taken, &stranger, now).await;
+        assert_eq!(
+            verifier.verify_registration(&headers, "/nodes", &body, &taken, now).await,
+            Err(ReportRejected::IdentityMismatch(node.id.clone()))
+        );
+        let later = now + Duration::from_millis(1);
+        let (headers, body) = signed(&node, &owner, later).await;
+        assert_eq!(verifier.verify_registration(&headers, "/nodes", &body, &node, later).await, Ok(node.id.clone()));
+    }
+    
+    #[tokio::test]
+    async fn refuses_stale_and_unknown_reports() {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let node_manager = Arc::new(StoredNodeManager::new(Arc::new(MemoryStorage::new())));
+        let identity = Arc::new(NodeIdentity::generate(&*crypto, Duration::ZERO).await.unwrap());
+        let node_id = registered(&node_manager, &identity).await;
+        let verifier = ReportVerifier::new(ReportAuthConfig::default(), node_manager, crypto.clone());
+        
+        let signed_at = Timestamp::now() - Duration::from_secs(120);
+        let signer = ReportSigner::new(node_id, identity.clone(), crypto.clone());
+        let headers = header_map(signer.headers("/nodes/heartbeat", b"{}", signed_at).await.unwrap());
+        assert!(matches!(
+            verifier.verify(&headers, "/nodes/heartbeat", b"{}", Timestamp::now()).await,
+            Err(ReportRejected::Stale(_))
+        ));
+        
+        let unknown = NodeId(Uuid::new_v4());
+        let signer = ReportSigner::new(unknown.clone(), identity, crypto);
+        let now = Timestamp::now();
+        let headers = header_map(signer.headers("/nodes/heartbeat", b"{}", now).await.unwrap());
+        assert_eq!(
+            verifier.verify(&headers, "/nodes/heartbeat", b"{}", now).await,
+            Err(ReportRejected::UnknownNode(unknown))
+        );
+        assert_eq!(
+            verifier.verify(&HeaderMap::new(), "/nodes/heartbeat", b"{}", now).await,
+            Err(ReportRejected::Missing(NODE_HEADER))
+        );
+    }
+}
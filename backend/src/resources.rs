@@ -0,0 +1,425 @@
+//! Guarding a node's memory and file descriptors by shedding load before the kernel does
+//!
+//! A routing or exit node flooded with circuits holds their payloads in memory and their
+//! connections in file descriptors, and once either runs out the node is killed or can't
+//! accept anything at all, which hurts the network more than turning some traffic away.
+//! So every `sample_interval` the [`ResourceGuard`] reads the process's resident memory and
+//! open file descriptors, and compares them with soft and hard thresholds:
+//!
+//! - at the soft threshold the node advertises itself as fully loaded, so the coordinator
+//!   steers new circuits elsewhere, and refuses circuits it doesn't already carry
+//! - at the hard threshold it also drops circuits idle for `idle_after` and closes idle
+//!   pooled provider connections, on every sample until the pressure eases
+//!
+//! Pressure only eases once every reading is below `recovery` times the threshold that
+//! raised it, so a node hovering around a threshold doesn't flap. Every change of pressure
+//! and every shedding action is emitted as an [`Event`] and counted in metrics.
+//!
+//! Readings come from `/proc` on Linux; elsewhere nothing is read and the guard stays idle.
+//! Memory thresholds are unset by default, since only the operator knows what the node may
+//! use; file descriptor thresholds are shares of the process's open file limit.
+
+use super::*;
+use super::events::{Event, EventBus, MetricsSubscriber};
+
+/// Thresholds the guard sheds load at
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceConfig {
+    /// Whether resources are sampled and load shed under pressure
+    pub enabled: bool,
+    /// How often resources are sampled
+    pub sample_interval: Duration,
+    /// Resident memory in bytes from which the node is under soft pressure, if guarded
+    #[serde(default)]
+    pub soft_memory_bytes: Option<u64>,
+    /// Resident memory in bytes from which the node is under hard pressure, if guarded
+    #[serde(default)]
+    pub hard_memory_bytes: Option<u64>,
+    /// Share of the open file limit in use from which the node is under soft pressure
+    pub soft_fd_share: f64,
+    /// Share of the open file limit in use from which the node is under hard pressure
+    pub hard_fd_share: f64,
+    /// Share of a threshold every reading must fall below for the pressure it raised to ease
+    pub recovery: f64,
+    /// How long a circuit or connection must have been idle to be dropped under hard pressure
+    pub idle_after: Duration,
+}
+
+impl Default for ResourceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            sample_interval: Duration::from_secs(5),
+            soft_memory_bytes: None,
+            hard_memory_bytes: None,
+            soft_fd_share: 0.8,
+            hard_fd_share: 0.95,
+            recovery: 0.9,
+            idle_after: Duration::from_secs(30),
+        }
+    }
+}
+
+/// How hard a node is pressed for resources
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pressure {
+    /// Resources to spare
+    #[default]
+    Normal,
+    /// Past a soft threshold: new circuits are refused
+    Soft,
+    /// Past a hard threshold: idle circuits and connections are dropped too
+    Hard,
+}
+
+impl Pressure {
+    /// Label used in logs and metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            Pressure::Normal => "normal",
+            Pressure::Soft => "soft",
+            Pressure::Hard => "hard",
+        }
+    }
+}
+
+/// The resources a process uses, each unset if it couldn't be read
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceReading {
+    /// Resident memory in bytes
+    pub memory_bytes: Option<u64>,
+    /// Open file descriptors
+    pub open_fds: Option<u64>,
+    /// Most file descriptors the process may open
+    pub fd_limit: Option<u64>,
+}
+
+/// Reads the resources the process uses
+pub trait ResourceSampler {
+    /// The resources in use now
+    fn sample(&self) -> ResourceReading;
+}
+
+/// Reads the process's resources from `/proc`
+#[derive(Debug, Default)]
+pub struct ProcSampler;
+
+impl ResourceSampler for ProcSampler {
+    fn sample(&self) -> ResourceReading {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+        let limits = std::fs::read_to_string("/proc/self/limits").unwrap_or_default();
+        ResourceReading {
+            memory_bytes: status
+                .lines()
+                .find_map(|line| line.strip_prefix("VmRSS:"))
+                .and_then(|rss| rss.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+                .map(|kb| kb * 1024),
+            open_fds: std::fs::read_dir("/proc/self/fd").ok().map(|fds| fds.count() as u64),
+            fd_limit: limits
+                .lines()
+                .find_map(|line| line.strip_prefix("Max open files"))
+                .and_then(|limit| limit.split_whitespace().next())
+                .and_then(|soft| soft.parse().ok()),
+        }
+    }
+}
+
+/// A node's circuits refused because it is short of resources
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("node is under {} resource pressure and takes no new circuits", .pressure.label())]
+pub struct ResourcesExhausted {
+    /// The pressure the node is under
+    pub pressure: Pressure,
+}
+
+/// What a service dropped to relieve resource pressure
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Shed {
+    /// Idle circuits dropped
+    pub circuits: usize,
+    /// Provider clients dropped, closing their idle pooled connections
+    pub connections: usize,
+}
+
+/// A service that gives up load under resource pressure
+#[async_trait]
+pub trait LoadShedding {
+    /// Act on `pressure`, taken on every sample: advertise full load from soft pressure on,
+    /// and drop circuits and connections idle for `idle_after` under hard pressure
+    async fn shed(&self, pressure: Pressure, idle_after: Duration) -> Shed;
+}
+
+/// Samples resources and decides the pressure a node is under, see the module docs
+pub struct ResourceGuard {
+    config: ResourceConfig,
+    pressure: parking_lot::RwLock<Pressure>,
+    utilization: parking_lot::Mutex<f64>,
+    events: Arc<EventBus>,
+}
+
+impl ResourceGuard {
+    /// Create a guard under no pressure, recording its events as metrics
+    pub fn new(config: ResourceConfig) -> Self {
+        let events = Arc::new(EventBus::new());
+        events.register(Arc::new(MetricsSubscriber));
+        Self {
+            config,
+            pressure: parking_lot::RwLock::new(Pressure::Normal),
+            utilization: parking_lot::Mutex::new(0.0),
+            events,
+        }
+    }
+    
+    /// The bus the guard's events are emitted on
+    pub fn events(&self) -> Arc<EventBus> {
+        self.events.clone()
+    }
+    
+    /// The pressure the node is under
+    pub fn pressure(&self) -> Pressure {
+        *self.pressure.read()
+    }
+    
+    /// How close the latest reading came to a hard threshold, from 0 (nothing used) to 1
+    /// (at or past it), for the load a node reports in its heartbeats
+    pub fn utilization(&self) -> f64 {
+        *self.utilization.lock()
+    }
+    
+    /// Fail unless the node takes new circuits
+    pub fn admit_new(&self) -> Result<(), ResourcesExhausted> {
+        match self.pressure() {
+            Pressure::Normal => Ok(()),
+            pressure => {
+                metrics::increment_counter!("darknode_resource_refusals_total", "pressure" => pressure.label());
+                Err(ResourcesExhausted { pressure })
+            }
+        }
+    }
+    
+    /// Take in a reading, returning the pressure the node is now under
+    pub fn observe(&self, reading: &ResourceReading) -> Pressure {
+        if let Some(memory) = reading.memory_bytes {
+            metrics::gauge!("darknode_resident_memory_bytes", memory as f64);
+        }
+        if let Some(fds) = reading.open_fds {
+            metrics::gauge!("darknode_open_fds", fds as f64);
+        }
+        
+        let mut pressure = self.pressure.write();
+        let from = *pressure;
+        let memory = match (reading.memory_bytes, self.config.soft_memory_bytes, self.config.hard_memory_bytes) {
+            (Some(used), soft, hard) => self.level(used as f64, soft.map(|soft| soft as f64), hard.map(|hard| hard as f64), from),
+            _ => Pressure::Normal,
+        };
+        let fds = match (reading.open_fds, reading.fd_limit) {
+            (Some(open), Some(limit)) if limit > 0 => self.level(
+                open as f64 / limit as f64,
+                Some(self.config.soft_fd_share),
+                Some(self.config.hard_fd_share),
+                from,
+            ),
+            _ => Pressure::Normal,
+        };
+        let to = memory.max(fds);
+        *pressure = to;
+        drop(pressure);
+        *self.utilization.lock() = self.share_used(reading);
+        
+        if to != from {
+            tracing::warn!("Resource pressure went from {} to {}: {:?}", from.label(), to.label(), reading);
+            self.events.emit(Event::ResourcePressureChanged { from, to });
+        }
+        to
+    }
+    
+    /// The largest share of a threshold `reading` uses, the hard one if set
+    fn share_used(&self, reading: &ResourceReading) -> f64 {
+        let memory = match (reading.memory_bytes, self.config.hard_memory_bytes.or(self.config.soft_memory_bytes)) {
+            (Some(used), Some(threshold)) if threshold > 0 => used as f64 / threshold as f64,
+            _ => 0.0,
+        };
+        let fds = match (reading.open_fds, reading.fd_limit) {
+            (Some(open), Some(limit)) if limit > 0 && self.config.hard_fd_share > 0.0 => {
+                open as f64 / limit as f64 / self.config.hard_fd_share
+            }
+            _ => 0.0,
+        };
+        memory.max(fds).clamp(0.0, 1.0)
+    }
+    
+    /// The pressure `used` puts the node under, given the pressure it is under now
+    fn level(&self, used: f64, soft: Option<f64>, hard: Option<f64>, current: Pressure) -> Pressure {
+        let past = |threshold: Option<f64>, share: f64| threshold.map_or(false, |threshold| used >= threshold * share);
+        if past(hard, 1.0) || (current == Pressure::Hard && past(hard, self.config.recovery)) {
+            Pressure::Hard
+        } else if past(soft, 1.0) || (current >= Pressure::Soft && past(soft, self.config.recovery)) {
+            Pressure::Soft
+        } else {
+            Pressure::Normal
+        }
+    }
+    
+    /// Shed load from `services` as the pressure calls for, reporting what was dropped
+    pub async fn apply(&self, pressure: Pressure, services: &[Arc<dyn LoadShedding + Send + Sync>]) -> Shed {
+        let mut total = Shed::default();
+        for service in services {
+            let shed = service.shed(pressure, self.config.idle_after).await;
+            total.circuits += shed.circuits;
+            total.connections += shed.connections;
+        }
+        if total.circuits > 0 {
+            self.events.emit(Event::LoadShed {
+                action: "drop_idle_circuits",
+                count: total.circuits,
+            });
+        }
+        if total.connections > 0 {
+            self.events.emit(Event::LoadShed {
+                action: "close_idle_connections",
+                count: total.connections,
+            });
+        }
+        total
+    }
+    
+    /// Sample resources with `sampler` every `sample_interval` and shed load from `services`
+    /// as the pressure calls for, until the task is dropped
+    pub async fn run(
+        self: Arc<Self>,
+        sampler: Arc<dyn ResourceSampler + Send + Sync>,
+        services: Vec<Arc<dyn LoadShedding + Send + Sync>>,
+    ) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut ticker = tokio::time::interval(self.config.sample_interval);
+        
+        loop {
+            ticker.tick().await;
+            let pressure = self.observe(&sampler.sample());
+            self.apply(pressure, &services).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Notes the pressure it is asked to act on, dropping two circuits and a connection under hard pressure
+    #[derive(Default)]
+    struct Shedder {
+        asked: parking_lot::Mutex<Vec<Pressure>>,
+    }
+    
+    #[async_trait]
+    impl LoadShedding for Shedder {
+        async fn shed(&self, pressure: Pressure, _idle_after: Duration) -> Shed {
+            self.asked.lock().push(pressure);
+            match pressure {
+                Pressure::Hard => Shed { circuits: 2, connections: 1 },
+                _ => Shed::default(),
+            }
+        }
+    }
+    
+    fn guard() -> ResourceGuard {
+        ResourceGuard::new(ResourceConfig {
+            soft_memory_bytes: Some(800),
+            hard_memory_bytes: Some(1_000),
+            recovery: 0.9,
+            ..Default::default()
+        })
+    }
+    
+    fn memory(bytes: u64) -> ResourceReading {
+        ResourceReading {
+            memory_bytes: Some(bytes),
+            ..Default::default()
+        }
+    }
+    
+    fn drain(receiver: &mut tokio::sync::broadcast::Receiver<Event>) -> Vec<Event> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+    
+    #[tokio::test]
+    async fn soft_then_hard_pressure_refuses_circuits_then_sheds_idle_ones() {
+        let guard = guard();
+        let shedder = Arc::new(Shedder::default());
+        let services: Vec<Arc<dyn LoadShedding + Send + Sync>> = vec![shedder.clone()];
+        let mut events = guard.events().subscribe();
+        
+        let pressure = guard.observe(&memory(500));
+        assert_eq!(guard.apply(pressure, &services).await, Shed::default());
+        assert!(guard.admit_new().is_ok());
+        
+        let pressure = guard.observe(&memory(850));
+        assert_eq!(guard.apply(pressure, &services).await, Shed::default());
+        assert!(matches!(guard.admit_new(), Err(ResourcesExhausted { pressure: Pressure::Soft })));
+        
+        let pressure = guard.observe(&memory(1_000));
+        assert_eq!(guard.apply(pressure, &services).await, Shed { circuits: 2, connections: 1 });
+        assert!(matches!(guard.admit_new(), Err(ResourcesExhausted { pressure: Pressure::Hard })));
+        assert_eq!(guard.utilization(), 1.0);
+        
+        assert_eq!(*shedder.asked.lock(), vec![Pressure::Normal, Pressure::Soft, Pressure::Hard]);
+        let emitted = drain(&mut events);
+        assert!(matches!(
+            &emitted[..],
+            [
+                Event::ResourcePressureChanged { from: Pressure::Normal, to: Pressure::Soft },
+                Event::ResourcePressureChanged { from: Pressure::Soft, to: Pressure::Hard },
+                Event::LoadShed { action: "drop_idle_circuits", count: 2 },
+                Event::LoadShed { action: "close_idle_connections", count: 1 },
+            ]
+        ), "{:?}", emitted);
+    }
+    
+    #[test]
+    fn pressure_eases_only_once_below_the_recovery_share_of_its_threshold() {
+        let guard = guard();
+        assert_eq!(guard.observe(&memory(1_200)), Pressure::Hard);
+        
+        // Within the hysteresis band below each threshold the pressure holds
+        assert_eq!(guard.observe(&memory(950)), Pressure::Hard);
+        assert_eq!(guard.observe(&memory(899)), Pressure::Soft);
+        assert_eq!(guard.observe(&memory(750)), Pressure::Soft);
+        assert!(guard.admit_new().is_err());
+        
+        assert_eq!(guard.observe(&memory(719)), Pressure::Normal);
+        assert!(guard.admit_new().is_ok());
+        
+        // Rising again, pressure returns only at the thresholds themselves
+        assert_eq!(guard.observe(&memory(799)), Pressure::Normal);
+        assert_eq!(guard.observe(&memory(800)), Pressure::Soft);
+    }
+    
+    #[test]
+    fn file_descriptors_count_as_shares_of_the_open_file_limit() {
+        let guard = guard();
+        let fds = |open| ResourceReading {
+            open_fds: Some(open),
+            fd_limit: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(guard.observe(&fds(50)), Pressure::Normal);
+        assert_eq!(guard.observe(&fds(80)), Pressure::Soft);
+        assert_eq!(guard.observe(&fds(95)), Pressure::Hard);
+        
+        // The worse of memory and file descriptors wins
+        let reading = ResourceReading {
+            memory_bytes: Some(1_000),
+            ..fds(10)
+        };
+        assert_eq!(guard.observe(&reading), Pressure::Hard);
+        let reading = ResourceReading {
+            memory_bytes: Some(100),
+            ..fds(10)
+        };
+        assert_eq!(guard.observe(&reading), Pressure::Normal);
+    }
+}
@@ -0,0 +1,886 @@
+//! Circuit construction and request routing
+//!
+//! A router given a [`HopClient`] builds each circuit through the network: it sends the
+//! circuit's handshake to its first routing hop, see [`crate::transport`], and keeps the
+//! circuit's ratchet to seal requests for the exit node and open its responses. Without
+//! one, circuits are only drawn from the directory, for tools that need no traffic.
+
+use super::*;
+use super::traits::*;
+use super::types::*;
+use super::clock::{Clock, Deadline, SystemClock};
+use super::budget;
+use super::circuit_class;
+use super::context::RequestContext;
+use std::collections::{BTreeMap, HashSet};
+use super::diagnostics::{CircuitBuildError, CircuitBuildFailure};
+use super::egress;
+use super::protocol;
+use super::ratchet::{CircuitRatchet, RatchetConfig, Side};
+use super::replay::{self, HopFailure, HopFailureKind};
+use super::transport::{self, CircuitDestroy, ExtendLayer, HopClient, NextHop, RequestMessage, ResponseMessage};
+use super::recommend::{self, PathAdvisor, PathConstraints};
+use super::reachability::ReachabilityMatrix;
+use super::regions::{LatencyMatrix, Region};
+use super::relaxation::CircuitPolicy;
+use std::net::SocketAddr;
+use tokio::task::JoinHandle;
+
+/// How long a circuit's handshake may take to reach its exit node before the build fails
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Error for a hop that could only be filled by a node already in the circuit
+fn distinct_nodes_error(candidates: usize, available: BTreeMap<String, usize>, narrowed: bool) -> anyhow::Error {
+    policy_error("distinct nodes per hop".to_string(), candidates, available, narrowed)
+}
+
+/// Error for a circuit that can't meet `constraint` with `candidates` nodes for its hop
+fn policy_error(constraint: String, candidates: usize, available: BTreeMap<String, usize>, narrowed: bool) -> anyhow::Error {
+    CircuitBuildError {
+        failure: CircuitBuildFailure::ConstraintUnsatisfiable { constraint, candidates },
+        available,
+        narrowed,
+    }
+    .into()
+}
+
+/// Whether a circuit from `entry` through `routing` to one of `exits` can meet `policy`
+///
+/// The rules are the router's: no node twice in a circuit, at least one routing hop, and
+/// under region diversity no region twice, the entry's included.
+pub(crate) fn meets_policy(entry: &Node, routing: &[&Node], exits: &[&Node], policy: &CircuitPolicy) -> bool {
+    let hops = policy.min_routing_hops.max(1);
+    exits.iter().filter(|exit| exit.id != entry.id).any(|exit| {
+        let others = routing.iter().filter(|node| node.id != entry.id && node.id != exit.id);
+        if !policy.region_diversity {
+            return others.count() >= hops;
+        }
+        let regions: HashSet<&str> = others
+            .filter(|node| node.region != exit.region && node.region != entry.region)
+            .map(|node| node.region.as_str())
+            .collect();
+        exit.region != entry.region && regions.len() >= hops
+    })
+}
+
+/// A circuit this router built through the network
+struct Built {
+    first: NodeId,
+    address: SocketAddr,
+    path: &'static str,
+    exit: NodeId,
+    keys: CircuitRatchet,
+    version: u16,
+    expires_at: Timestamp,
+}
+
+/// A request on its way through a circuit, answered once the task completes
+struct InFlight {
+    deadline: Deadline,
+    answer: JoinHandle<Result<Response>>,
+}
+
+/// Implementation of the Router trait
+pub struct RouterImpl {
+    node_manager: Arc<dyn NodeManager + Send + Sync>,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    advisor: Option<Arc<PathAdvisor>>,
+    clock: Arc<dyn Clock>,
+    latency: Option<Arc<LatencyMatrix>>,
+    reachability: Option<Arc<ReachabilityMatrix>>,
+    hops: Option<Arc<HopClient>>,
+    ratchet: RatchetConfig,
+    handshake_timeout: Duration,
+    built: dashmap::DashMap<CircuitId, Built>,
+    in_flight: dashmap::DashMap<Uuid, InFlight>,
+}
+
+impl RouterImpl {
+    /// Create a router that builds circuits from the nodes known to `node_manager`
+    pub fn new(
+        node_manager: Arc<dyn NodeManager + Send + Sync>,
+        crypto: Arc<dyn Crypto + Send + Sync>,
+    ) -> Self {
+        Self {
+            node_manager,
+            crypto,
+            advisor: None,
+            clock: Arc::new(SystemClock),
+            latency: None,
+            reachability: None,
+            hops: None,
+            ratchet: RatchetConfig::default(),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            built: dashmap::DashMap::new(),
+            in_flight: dashmap::DashMap::new(),
+        }
+    }
+    
+    /// Build circuits through the network and carry requests along them with `hops`,
+    /// ratcheting circuit keys as the exit nodes' `ratchet` config has it
+    pub fn with_hops(mut self, hops: Arc<HopClient>, ratchet: RatchetConfig) -> Self {
+        self.hops = Some(hops);
+        self.ratchet = ratchet;
+        self
+    }
+    
+    /// Fail circuit builds whose handshake takes longer than `timeout` to reach the exit node
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+    
+    /// Draw circuits from the paths the coordinator recommends where they fit, see [`crate::recommend`]
+    pub fn with_advisor(mut self, advisor: Arc<PathAdvisor>) -> Self {
+        self.advisor = Some(advisor);
+        self
+    }
+    
+    /// Order hops along the shortest path by the round trips in `latency`, see [`crate::regions`]
+    pub fn with_latency(mut self, latency: Arc<LatencyMatrix>) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+    
+    /// Leave out of the routing hops of circuits drawn locally the nodes this node found it
+    /// can't reach, its own probes kept in `reachability`, see [`crate::reachability`]
+    pub fn with_reachability(mut self, reachability: Arc<ReachabilityMatrix>) -> Self {
+        self.reachability = Some(reachability);
+        self
+    }
+    
+    /// Stamp circuits with the time on `clock` rather than the system's
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    
+    /// Send the handshake of `circuit` along its hops, the exit node's layer sealed with `key`
+    async fn extend(
+        &self,
+        hops: &HopClient,
+        circuit: &Circuit,
+        routing: &[&Node],
+        exit: &Node,
+        key: CryptoKey,
+        class: circuit_class::CircuitClass,
+    ) -> Result<NextHop> {
+        let now = self.clock.now();
+        let ttl = circuit.expires_at.saturating_duration_since(now);
+        let layer = ExtendLayer::Exit {
+            key,
+            version: circuit.protocol_version,
+            class,
+            ttl,
+        };
+        let mut next = NextHop {
+            node_id: exit.id.clone(),
+            address: transport::address_of(exit),
+            role: NodeRole::Exit,
+            extend: transport::seal(&*self.crypto, &circuit.id, &layer, exit, now).await?,
+        };
+        for node in routing.iter().rev() {
+            let layer = ExtendLayer::Relay {
+                ttl,
+                next: Box::new(next),
+            };
+            next = NextHop {
+                node_id: node.id.clone(),
+                address: transport::address_of(node),
+                role: NodeRole::Routing,
+                extend: transport::seal(&*self.crypto, &circuit.id, &layer, node, now).await?,
+            };
+        }
+        let sent = hops.send::<_, ()>(&next.node_id, next.address, transport::EXTEND_PATH, &next.extend);
+        match tokio::time::timeout(self.handshake_timeout, sent).await {
+            Ok(sent) => sent.map(|()| next),
+            Err(_) => Err(HopFailure {
+                node: Some(next.node_id),
+                kind: HopFailureKind::TimedOut,
+            }
+            .into()),
+        }
+    }
+    
+    /// Carry a sealed request to `path` on the `first` hop, which answers with the exit node's response
+    async fn carry(hops: Arc<HopClient>, first: NodeId, address: SocketAddr, path: &'static str, request: Request) -> Result<Response> {
+        let answer: ResponseMessage = hops.send(&first, address, path, &RequestMessage { request }).await?;
+        Ok(answer.response)
+    }
+    
+    /// Available nodes of a role speaking a protocol version we do and not excluded, failing with a classified error if there are none
+    async fn available(&self, role: NodeRole, exclude: &[NodeId], seen: &mut BTreeMap<String, usize>) -> Result<Vec<Node>> {
+        let nodes: Vec<Node> = self
+            .node_manager
+            .get_available_nodes(role)
+            .await?
+            .into_iter()
+            .filter(|node| protocol::compatible(node, role))
+            .filter(|node| !exclude.contains(&node.id))
+            .collect();
+        seen.insert(format!("{:?}", role), nodes.len());
+        if nodes.is_empty() {
+            return Err(CircuitBuildError {
+                failure: CircuitBuildFailure::NoAvailableNodes { role },
+                available: seen.clone(),
+                narrowed: false,
+            }
+            .into());
+        }
+        Ok(nodes)
+    }
+    
+    /// A path drawn by weight from those recommended that fit the directory, exits allowed, and policy
+    ///
+    /// Recommended nodes are looked up among the available ones speaking `version`, so a
+    /// path through a node gone since the coordinator suggested it, or through `entry`,
+    /// isn't used.
+    async fn advised_path<'a>(
+        &self,
+        preferences: &CircuitPreferences,
+        entry: &Node,
+        version: u16,
+        routing: &'a [Node],
+        allowed: &[&'a Node],
+    ) -> Option<(Vec<&'a Node>, &'a Node)> {
+        let advisor = self.advisor.as_ref()?;
+        let constraints = PathConstraints {
+            entry_node: Some(entry.id.clone()),
+            exit_pool: preferences.exit_pool.clone(),
+            routing_hops: preferences.policy.routing_hops,
+        };
+        let mut candidates: Vec<(Vec<&Node>, &Node, f64)> = advisor
+            .paths(&constraints)
+            .await
+            .into_iter()
+            .filter_map(|path| {
+                let hops = path
+                    .routing_nodes
+                    .iter()
+                    .map(|id| routing.iter().find(|node| node.id == *id && node.protocol.contains(version)))
+                    .collect::<Option<Vec<_>>>()?;
+                let exit = *allowed.iter().find(|node| node.id == path.exit_node)?;
+                let mut used = HashSet::new();
+                used.insert(&entry.id);
+                let distinct = hops.iter().chain(std::iter::once(&exit)).all(|node| used.insert(&node.id));
+                let nodes: Vec<&Node> = std::iter::once(entry).chain(hops.iter().copied()).chain(std::iter::once(exit)).collect();
+                (distinct && preferences.policy.admits(&nodes)).then_some((hops, exit, path.weight))
+            })
+            .collect();
+        
+        // Favour the shorter of the recommended paths, still spreading load among them
+        if let Some(latency) = self.latency.as_ref().filter(|latency| latency.config().enabled) {
+            let now = self.clock.now();
+            let rtts: Vec<f64> = candidates
+                .iter()
+                .map(|(hops, exit, _)| {
+                    let regions: Vec<Region> = std::iter::once(entry)
+                        .chain(hops.iter().copied())
+                        .chain(std::iter::once(*exit))
+                        .map(|node| Region::parse(&node.region))
+                        .collect();
+                    latency.path(&regions, now).as_secs_f64()
+                })
+                .collect();
+            let fastest = rtts.iter().copied().fold(f64::INFINITY, f64::min);
+            for (candidate, rtt) in candidates.iter_mut().zip(rtts) {
+                if rtt > 0.0 {
+                    candidate.2 *= fastest / rtt;
+                }
+            }
+        }
+        let indices: Vec<usize> = (0..candidates.len()).collect();
+        let chosen = recommend::choose(&indices, |i| candidates[*i].2, &mut rand::thread_rng())?;
+        let (hops, exit, _) = candidates.into_iter().nth(chosen)?;
+        Some((hops, exit))
+    }
+    
+    /// The shortest path from `entry` through routing nodes to one of `exits`, if latency shapes
+    /// circuits and the path meets `policy`
+    fn shortest_path<'a>(
+        &self,
+        entry: &Node,
+        routing: &[&'a Node],
+        exits: &[&'a Node],
+        policy: &CircuitPolicy,
+    ) -> Option<(Vec<&'a Node>, &'a Node)> {
+        let latency = self.latency.as_ref().filter(|latency| latency.config().enabled)?;
+        let (hops, exit, _) = latency.shortest_path(entry, routing, exits, policy.routing_hops, self.clock.now())?;
+        let path: Vec<&Node> = std::iter::once(entry).chain(hops.iter().copied()).chain(std::iter::once(exit)).collect();
+        policy.admits(&path).then_some((hops, exit))
+    }
+}
+
+#[async_trait]
+impl Router for RouterImpl {
+    async fn create_circuit(&self) -> Result<Circuit> {
+        self.create_circuit_with(&CircuitPreferences::default()).await
+    }
+    
+    async fn create_circuit_with(&self, preferences: &CircuitPreferences) -> Result<Circuit> {
+        let mut seen = BTreeMap::new();
+        
+        // Get available nodes for every hop, only those speaking a protocol version we do and not excluded
+        let entry_nodes = self.available(NodeRole::Entry, &preferences.exclude, &mut seen).await?;
+        let routing_nodes = self.available(NodeRole::Routing, &preferences.exclude, &mut seen).await?;
+        let exit_nodes = self.available(NodeRole::Exit, &preferences.exclude, &mut seen).await?;
+        
+        // Restrict the exits to the user's subset for this epoch, if there is one
+        let allowed = match &preferences.exit_subset {
+            Some(subset) => subset.select(&exit_nodes),
+            None => exit_nodes.iter().collect(),
+        };
+        
+        // Only use exits serving the method classes the circuit is built for, see `crate::egress`
+        let candidates = allowed.len();
+        let mut allowed: Vec<&Node> = allowed
+            .into_iter()
+            .filter(|node| egress::serves(node.method_classes.as_deref(), &preferences.method_classes))
+            .collect();
+        if allowed.is_empty() && candidates > 0 {
+            let classes: Vec<&str> = preferences.method_classes.iter().map(|class| class.label()).collect();
+            return Err(CircuitBuildError {
+                failure: CircuitBuildFailure::ConstraintUnsatisfiable {
+                    constraint: format!("exit serving {} methods", classes.join(", ")),
+                    candidates,
+                },
+                available: seen,
+                narrowed: true,
+            }
+            .into());
+        }
+        
+        // Only use exits in the region the request is pinned to, if it is
+        if let Some(region) = &preferences.exit_region {
+            let candidates = allowed.len();
+            allowed.retain(|node| node.region == *region);
+            if allowed.is_empty() {
+                return Err(CircuitBuildError {
+                    failure: CircuitBuildFailure::ConstraintUnsatisfiable {
+                        constraint: format!("region={}", region),
+                        candidates,
+                    },
+                    available: seen,
+                    narrowed: true,
+                }
+                .into());
+            }
+        }
+        
+        // Leave exits running out of request budget to the circuits they have, if others have budget to spare
+        let allowed = budget::prefer_funded(allowed);
+        
+        // Speak the version asked for, or else the highest there are nodes for at every hop,
+        // and only use nodes speaking it
+        let hops = [entry_nodes.iter().collect(), routing_nodes.iter().collect(), allowed.clone()];
+        let version = match preferences.protocol_version {
+            Some(version) => protocol::speakable(&hops, version).then_some(version),
+            None => protocol::negotiate(&hops),
+        };
+        let Some(version) = version else {
+            return Err(CircuitBuildError {
+                failure: CircuitBuildFailure::ConstraintUnsatisfiable {
+                    constraint: match preferences.protocol_version {
+                        Some(version) => format!("protocol version v{}", version),
+                        None => "common protocol version".to_string(),
+                    },
+                    candidates: allowed.len(),
+                },
+                available: seen,
+                narrowed: preferences.protocol_version.is_some(),
+            }
+            .into());
+        };
+        let speaks = |node: &&Node| node.protocol.contains(version);
+        let allowed: Vec<&Node> = allowed.into_iter().filter(speaks).collect();
+        
+        // Select an entry node (in a real implementation, this would use more sophisticated selection)
+        let Some(entry_node) = entry_nodes.iter().find(speaks) else {
+            return Err(distinct_nodes_error(entry_nodes.len(), seen, false));
+        };
+        
+        // Leave out of the routing hops the nodes this node found it can't reach, as it sends
+        // the circuit's first hop its handshake, see `crate::reachability`
+        let unreachable: HashSet<NodeId> = match &self.reachability {
+            Some(reachability) => reachability.broken(self.clock.now()).into_iter().map(|(_, to)| to).collect(),
+            None => HashSet::new(),
+        };
+        let reachable = |node: &&Node| !unreachable.contains(&node.id);
+        
+        // A node may serve several roles, but must never appear twice in one circuit, nor may
+        // a region if the policy asks for diversity
+        let policy = &preferences.policy;
+        // Whether the policy failed only for the exits the request narrowed itself to, so a
+        // failure isn't taken for the network being too small for it, see `crate::relaxation`
+        let narrowed = || {
+            let routing: Vec<&Node> = routing_nodes.iter().filter(speaks).collect();
+            let exits: Vec<&Node> = exit_nodes.iter().filter(speaks).collect();
+            meets_policy(entry_node, &routing, &exits, policy)
+        };
+        let min_hops = policy.min_routing_hops.max(1);
+        let mut used = HashSet::new();
+        used.insert(entry_node.id.clone());
+        let mut regions = HashSet::new();
+        regions.insert(entry_node.region.as_str());
+        
+        // Select up to the policy's routing hops (in a real implementation, this would use more sophisticated selection).
+        // Nodes that may also be the exit are taken last, and never when they are the last exit left
+        let is_exit = |node: &Node| allowed.iter().any(|exit| exit.id == node.id);
+        let mut candidates: Vec<&Node> = routing_nodes.iter().filter(speaks).filter(reachable).collect();
+        candidates.sort_by_key(|node| is_exit(node));
+        let mut selected_routing_nodes: Vec<&Node> = Vec::new();
+        for node in candidates {
+            if selected_routing_nodes.len() >= policy.routing_hops.max(min_hops) {
+                break;
+            }
+            if used.contains(&node.id) || (policy.region_diversity && regions.contains(node.region.as_str())) {
+                continue;
+            }
+            let exit_left = allowed.iter().any(|exit| {
+                exit.id != node.id
+                    && !used.contains(&exit.id)
+                    && (!policy.region_diversity || (exit.region != node.region && !regions.contains(exit.region.as_str())))
+            });
+            if is_exit(node) && !exit_left {
+                continue;
+            }
+            used.insert(node.id.clone());
+            regions.insert(node.region.as_str());
+            selected_routing_nodes.push(node);
+        }
+        if selected_routing_nodes.is_empty() && !policy.region_diversity {
+            return Err(distinct_nodes_error(routing_nodes.len(), seen, narrowed()));
+        }
+        if selected_routing_nodes.len() < min_hops {
+            let constraint = match policy.region_diversity {
+                true => format!("{} routing hops in distinct regions", min_hops),
+                false => format!("{} routing hops", min_hops),
+            };
+            return Err(policy_error(constraint, routing_nodes.len(), seen, narrowed()));
+        }
+        
+        // Prefer a path the coordinator recommended to spread load, drawn here so it doesn't learn which
+        let (selected_routing_nodes, exit_node) = match self.advised_path(preferences, entry_node, version, &routing_nodes, &allowed).await {
+            Some(path) => {
+                metrics::increment_counter!("darknode_circuit_paths_total", "source" => "recommended");
+                path
+            }
+            None => {
+                // Take the shortest path through the directory, to an exit of the preferred pool if there are any
+                let preferred: Vec<&Node> = allowed
+                    .iter()
+                    .copied()
+                    .filter(|node| preferences.exit_pool.is_some() && node.pool == preferences.exit_pool)
+                    .collect();
+                let speaking: Vec<&Node> = routing_nodes.iter().filter(speaks).filter(reachable).collect();
+                let shortest = self.shortest_path(
+                    entry_node,
+                    &speaking,
+                    if preferred.is_empty() { &allowed } else { &preferred },
+                    policy,
+                );
+                metrics::increment_counter!("darknode_circuit_paths_total", "source" => "local");
+                match shortest {
+                    Some(path) => path,
+                    None => {
+                        // Select an exit node not already used by an earlier hop, nor in a region one is in if the
+                        // policy asks for diversity, from the preferred pool if possible
+                        let unused: Vec<&Node> = allowed.into_iter().filter(|node| !used.contains(&node.id)).collect();
+                        let eligible: Vec<&Node> = unused
+                            .iter()
+                            .copied()
+                            .filter(|node| !policy.region_diversity || !regions.contains(node.region.as_str()))
+                            .collect();
+                        let preferred = eligible
+                            .iter()
+                            .find(|node| preferences.exit_pool.is_some() && node.pool == preferences.exit_pool);
+                        let exit_node = match preferred.or(eligible.first()) {
+                            Some(node) => *node,
+                            None if unused.is_empty() => return Err(distinct_nodes_error(exit_nodes.len(), seen, narrowed())),
+                            None => {
+                                return Err(policy_error("exit in a distinct region".to_string(), exit_nodes.len(), seen, narrowed()))
+                            }
+                        };
+                        (selected_routing_nodes, exit_node)
+                    }
+                }
+            }
+        };
+        
+        // Generate symmetric keys for each hop
+        let mut symmetric_keys = Vec::new();
+        for _ in 0..selected_routing_nodes.len() + 2 {  // +2 for entry and exit nodes
+            let (public_key, _) = self.crypto.generate_keypair().await?;
+            symmetric_keys.push(public_key);
+        }
+        
+        // Create the circuit
+        let created_at = self.clock.now();
+        let regions: Vec<String> = std::iter::once(entry_node)
+            .chain(selected_routing_nodes.iter().copied())
+            .chain(std::iter::once(exit_node))
+            .map(|node| node.region.clone())
+            .collect();
+        let estimated_latency = self.latency.as_ref().map(|latency| {
+            let parsed: Vec<Region> = regions.iter().map(|region| Region::parse(region)).collect();
+            latency.path(&parsed, created_at)
+        });
+        let circuit = Circuit {
+            id: CircuitId(Uuid::new_v4()),
+            entry_node: entry_node.id.clone(),
+            routing_nodes: selected_routing_nodes.iter().map(|node| node.id.clone()).collect(),
+            exit_node: exit_node.id.clone(),
+            symmetric_keys,
+            created_at,
+            expires_at: created_at + preferences.lifetime.unwrap_or(circuit_class::DEFAULT_LIFETIME),
+            regions,
+            protocol_version: version,
+            estimated_latency,
+            relaxed: Vec::new(),
+            exit_classes: exit_node.method_classes.clone(),
+        };
+        
+        // Build the circuit through its hops, keeping the exit node's key to ratchet from
+        if let Some(hops) = &self.hops {
+            let key = circuit
+                .symmetric_keys
+                .last()
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Circuit {} has no keys", circuit.id.0))?;
+            let started = tokio::time::Instant::now();
+            let first = self
+                .extend(hops, &circuit, &selected_routing_nodes, exit_node, key.clone(), preferences.class)
+                .await
+                .map_err(|e| {
+                    // Blame the hop the failure names, the first one past this node if none
+                    let path: Vec<&NodeId> = std::iter::once(entry_node)
+                        .chain(selected_routing_nodes.iter().copied())
+                        .chain(std::iter::once(exit_node))
+                        .map(|node| &node.id)
+                        .collect();
+                    let node_id = replay::hop_failure(&e)
+                        .and_then(|failure| failure.node.clone())
+                        .unwrap_or_else(|| path[1].clone());
+                    let failure = CircuitBuildFailure::HandshakeFailed {
+                        hop: path.iter().position(|id| **id == node_id).unwrap_or(1),
+                        node_id,
+                        latency: started.elapsed(),
+                        error: format!("{:#}", e),
+                    };
+                    e.context(CircuitBuildError {
+                        failure,
+                        available: seen.clone(),
+                        narrowed: false,
+                    })
+                })?;
+            self.built.retain(|_, built| built.expires_at > created_at);
+            self.built.insert(
+                circuit.id.clone(),
+                Built {
+                    first: first.node_id,
+                    address: first.address,
+                    path: transport::request_path(first.role),
+                    exit: exit_node.id.clone(),
+                    keys: CircuitRatchet::new(key, Side::Initiator, self.ratchet.clone()),
+                    version,
+                    expires_at: circuit.expires_at,
+                },
+            );
+        }
+        
+        Ok(circuit)
+    }
+    
+    async fn close_circuit(&self, circuit: &Circuit) -> Result<()> {
+        // Tear the circuit down at its hops too, which would carry it until reclaimed otherwise
+        let (Some(hops), Some((circuit_id, built))) = (&self.hops, self.built.remove(&circuit.id)) else {
+            return Ok(());
+        };
+        let destroy = CircuitDestroy { circuit_id };
+        hops.send::<_, ()>(&built.first, built.address, transport::DESTROY_PATH, &destroy).await
+    }
+    
+    async fn send_request(&self, ctx: &RequestContext, circuit: &Circuit, request: &[u8]) -> Result<Uuid> {
+        let request_id = Uuid::new_v4();
+        let Some(hops) = &self.hops else {
+            return Ok(request_id);
+        };
+        
+        // Seal the request for the exit node at the circuit's next ratchet step
+        let (first, address, path, version, (key_step, key)) = {
+            let mut built = self
+                .built
+                .get_mut(&circuit.id)
+                .ok_or_else(|| anyhow::anyhow!("Circuit {} was not built by this router", circuit.id.0))?;
+            (built.first.clone(), built.address, built.path, built.version, built.keys.seal())
+        };
+        let now = self.clock.now();
+        let ttl = circuit.expires_at.saturating_duration_since(now);
+        let request = Request {
+            id: request_id,
+            circuit_id: circuit.id.clone(),
+            payload: self.crypto.encrypt(&protocol::frame(version, request), &key).await?,
+            created_at: now,
+            ttl,
+            key_step,
+        };
+        let answer = tokio::spawn(Self::carry(hops.clone(), first, address, path, request));
+        
+        // Nobody waits for the answer to a notification, so nothing is kept of it
+        if ctx.notification {
+            return Ok(request_id);
+        }
+        self.in_flight.retain(|_, in_flight| {
+            let expired = in_flight.deadline.is_expired();
+            if expired {
+                in_flight.answer.abort();
+            }
+            !expired
+        });
+        let deadline = ctx.deadline.unwrap_or_else(|| Deadline::after(ttl));
+        self.in_flight.insert(request_id, InFlight { deadline, answer });
+        Ok(request_id)
+    }
+    
+    async fn receive_response(&self, request_id: Uuid) -> Result<Vec<u8>> {
+        if self.hops.is_none() {
+            return Ok(b"dummy response".to_vec());
+        }
+        let (_, in_flight) = self
+            .in_flight
+            .remove(&request_id)
+            .ok_or_else(|| anyhow::anyhow!("No request {} is in flight", request_id))?;
+        let response = in_flight.answer.await??;
+        
+        // A response that doesn't open under the circuit's keys was tampered with on the way back
+        let (exit, version, key) = {
+            let built = self
+                .built
+                .get(&response.circuit_id)
+                .ok_or_else(|| anyhow::anyhow!("Circuit {} was closed", response.circuit_id.0))?;
+            (built.exit.clone(), built.version, built.keys.open(response.key_step))
+        };
+        let tampered = || HopFailure {
+            node: Some(exit.clone()),
+            kind: HopFailureKind::Tampered,
+        };
+        let key = key.map_err(|_| tampered())?;
+        let plaintext = self.crypto.decrypt(&response.payload, &key).await.map_err(|_| tampered())?;
+        if let Some(mut built) = self.built.get_mut(&response.circuit_id) {
+            built.keys.follow(response.key_step);
+        }
+        protocol::unframe(version, &plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::{CryptoImpl, StoredNodeManager};
+    use crate::storage::MemoryStorage;
+    
+    fn node(roles: Vec<NodeRole>, region: &str) -> Node {
+        Node {
+            region: region.to_string(),
+            ..crate::fixtures::node(&roles)
+        }
+    }
+    
+    #[tokio::test]
+    async fn keeps_the_only_exit_for_the_exit_hop() {
+        let node_manager = Arc::new(StoredNodeManager::new(Arc::new(MemoryStorage::new())));
+        let entry = node(vec![NodeRole::Entry], "us-east");
+        let relay = node(vec![NodeRole::Routing], "eu-west");
+        let dual = node(vec![NodeRole::Routing, NodeRole::Exit], "ap-south");
+        for node in [entry.clone(), relay.clone(), dual.clone()] {
+            node_manager.register_node(node).await.unwrap();
+        }
+        let router = RouterImpl::new(node_manager, Arc::new(CryptoImpl::new()));
+        
+        // The policy asks for two routing hops, but the dual-role node is the only exit
+        for _ in 0..8 {
+            let circuit = router.create_circuit().await.unwrap();
+            assert_eq!(circuit.entry_node, entry.id);
+            assert_eq!(circuit.routing_nodes, vec![relay.id.clone()]);
+            assert_eq!(circuit.exit_node, dual.id);
+        }
+    }
+    
+    #[tokio::test]
+    async fn follows_the_coordinator_and_builds_from_the_directory_when_it_is_down() {
+        use crate::recommend::{AdviceConfig, Recommendation, RecommendedPath};
+        
+        let node_manager = Arc::new(StoredNodeManager::new(Arc::new(MemoryStorage::new())));
+        let relay = node(vec![NodeRole::Routing], "eu-west");
+        let exits = [node(vec![NodeRole::Exit], "ap-south"), node(vec![NodeRole::Exit], "ap-south")];
+        for node in [node(vec![NodeRole::Entry], "us-east"), relay.clone()].into_iter().chain(exits.clone()) {
+            node_manager.register_node(node).await.unwrap();
+        }
+        
+        // A coordinator steering every circuit to the second exit
+        let recommendation = Recommendation {
+            paths: vec![RecommendedPath {
+                routing_nodes: vec![relay.id.clone()],
+                exit_node: exits[1].id.clone(),
+                weight: 1.0,
+            }],
+            valid_until: Timestamp::now() + Duration::from_secs(60),
+            latencies: Vec::new(),
+        };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let coordinator = format!("http://{}", listener.local_addr().unwrap());
+        let app = axum::Router::new().route(
+            "/topology/recommend",
+            axum::routing::post(move || async move { axum::Json(recommendation) }),
+        );
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        let advised = RouterImpl::new(node_manager.clone(), Arc::new(CryptoImpl::new()))
+            .with_advisor(Arc::new(PathAdvisor::new(&coordinator, AdviceConfig::default())));
+        for _ in 0..8 {
+            assert_eq!(advised.create_circuit().await.unwrap().exit_node, exits[1].id);
+        }
+        
+        // With nothing listening at the coordinator's address, circuits come from the directory
+        let down = RouterImpl::new(node_manager, Arc::new(CryptoImpl::new()))
+            .with_advisor(Arc::new(PathAdvisor::new("http://127.0.0.1:1", AdviceConfig::default())));
+        for _ in 0..8 {
+            let circuit = down.create_circuit().await.unwrap();
+            assert_eq!(circuit.routing_nodes, vec![relay.id.clone()]);
+            assert!(exits.iter().any(|exit| exit.id == circuit.exit_node));
+        }
+    }
+    
+    #[tokio::test]
+    async fn leaves_out_relays_this_node_cannot_reach() {
+        use crate::reachability::{ProbeResult, ReachabilityConfig};
+        
+        let node_manager = Arc::new(StoredNodeManager::new(Arc::new(MemoryStorage::new())));
+        let relays = [node(vec![NodeRole::Routing], "eu-west"), node(vec![NodeRole::Routing], "eu-west")];
+        let nodes = [node(vec![NodeRole::Entry], "us-east"), node(vec![NodeRole::Exit], "ap-south")];
+        for node in nodes.into_iter().chain(relays.clone()) {
+            node_manager.register_node(node).await.unwrap();
+        }
+        let reachability = Arc::new(ReachabilityMatrix::new(ReachabilityConfig::default()));
+        let failed = ProbeResult {
+            peer: relays[0].id.clone(),
+            reachable: false,
+        };
+        let this_node = NodeId(Uuid::new_v4());
+        for _ in 0..2 {
+            reachability.record(&this_node, std::slice::from_ref(&failed), Timestamp::now());
+        }
+        let router = RouterImpl::new(node_manager, Arc::new(CryptoImpl::new())).with_reachability(reachability);
+        
+        for _ in 0..8 {
+            let circuit = router.create_circuit().await.unwrap();
+            assert_eq!(circuit.routing_nodes, vec![relays[1].id.clone()]);
+        }
+    }
+    
+    #[tokio::test]
+    async fn blames_the_request_when_only_its_exits_miss_the_policy() {
+        let node_manager = Arc::new(StoredNodeManager::new(Arc::new(MemoryStorage::new())));
+        let nodes = [
+            node(vec![NodeRole::Entry], "us-east"),
+            node(vec![NodeRole::Routing], "eu-west"),
+            node(vec![NodeRole::Exit], "eu-west"),
+        ];
+        for node in nodes {
+            node_manager.register_node(node).await.unwrap();
+        }
+        let router = RouterImpl::new(node_manager.clone(), Arc::new(CryptoImpl::new()));
+        let narrowed = |preferences: CircuitPreferences| {
+            let router = &router;
+            async move {
+                let err = router.create_circuit_with(&preferences).await.unwrap_err();
+                err.downcast_ref::<CircuitBuildError>().unwrap().narrowed
+            }
+        };
+        let policy = CircuitPolicy {
+            region_diversity: true,
+            ..Default::default()
+        };
+        
+        // The only exit shares the relay's region: the network can't meet the policy
+        let pinned = CircuitPreferences {
+            exit_region: Some("eu-west".to_string()),
+            policy: policy.clone(),
+            ..Default::default()
+        };
+        assert!(!narrowed(pinned.clone()).await);
+        
+        // Once an exit elsewhere joins it can, and only the request's region is to blame
+        node_manager.register_node(node(vec![NodeRole::Exit], "ap-south")).await.unwrap();
+        assert!(narrowed(pinned).await);
+        let unpinned = CircuitPreferences {
+            policy,
+            ..Default::default()
+        };
+        assert!(router.create_circuit_with(&unpinned).await.is_ok());
+    }
+    
+    /// Where the build of a circuit for `preferences` failed
+    async fn failure(router: &RouterImpl, preferences: &CircuitPreferences) -> CircuitBuildFailure {
+        let err = router.create_circuit_with(preferences).await.unwrap_err();
+        err.downcast_ref::<CircuitBuildError>().unwrap().failure.clone()
+    }
+    
+    #[tokio::test]
+    async fn reports_a_role_without_nodes_and_an_unmet_region() {
+        let node_manager = Arc::new(StoredNodeManager::new(Arc::new(MemoryStorage::new())));
+        for node in [node(vec![NodeRole::Entry], "us-east"), node(vec![NodeRole::Routing], "eu-west")] {
+            node_manager.register_node(node).await.unwrap();
+        }
+        let router = RouterImpl::new(node_manager.clone(), Arc::new(CryptoImpl::new()));
+        
+        let missing = failure(&router, &CircuitPreferences::default()).await;
+        assert!(matches!(missing, CircuitBuildFailure::NoAvailableNodes { role: NodeRole::Exit }), "{:?}", missing);
+        
+        node_manager.register_node(node(vec![NodeRole::Exit], "ap-south")).await.unwrap();
+        let pinned = CircuitPreferences {
+            exit_region: Some("eu-west".to_string()),
+            ..Default::default()
+        };
+        match failure(&router, &pinned).await {
+            CircuitBuildFailure::ConstraintUnsatisfiable { constraint, candidates } => {
+                assert_eq!(constraint, "region=eu-west");
+                assert_eq!(candidates, 1);
+            }
+            failure => panic!("unexpected failure {:?}", failure),
+        }
+    }
+    
+    #[tokio::test]
+    async fn blames_the_hop_whose_handshake_timed_out() {
+        let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+        let node_manager = Arc::new(StoredNodeManager::new(Arc::new(MemoryStorage::new())));
+        
+        // The routing hop takes connections but never answers them
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = silent.local_addr().unwrap();
+        let relay = Node {
+            ip_address: address.ip(),
+            port: address.port(),
+            ..node(vec![NodeRole::Routing], "eu-west")
+        };
+        for node in [node(vec![NodeRole::Entry], "us-east"), relay.clone(), node(vec![NodeRole::Exit], "ap-south")] {
+            let (public_key, _) = crypto.generate_keypair().await.unwrap();
+            node_manager.register_node(Node { public_key, ..node }).await.unwrap();
+        }
+        let identity = Arc::new(crate::identity::NodeIdentity::generate(&*crypto, Duration::ZERO).await.unwrap());
+        let signer = crate::hop_auth::HopSigner::new(NodeId(Uuid::new_v4()), identity, crypto.clone());
+        let router = RouterImpl::new(node_manager, crypto)
+            .with_hops(Arc::new(HopClient::new(signer)), RatchetConfig::default())
+            .with_handshake_timeout(Duration::from_millis(100));
+        
+        match failure(&router, &CircuitPreferences::default()).await {
+            CircuitBuildFailure::HandshakeFailed { hop, node_id, latency, .. } => {
+                assert_eq!(hop, 1);
+                assert_eq!(node_id, relay.id);
+                assert!(latency >= Duration::from_millis(100), "{:?}", latency);
+            }
+            failure => panic!("unexpected failure {:?}", failure),
+        }
+        drop(silent);
+    }
+}
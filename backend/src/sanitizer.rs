@@ -0,0 +1,314 @@
+//! Stripping what could link a user's requests together before they leave the entry node
+//!
+//! Dapp frameworks number their JSON-RPC requests 1, 2, 3, ..., so an exit node watching
+//! the ids go by could tell the requests of one user apart from everyone else's, and
+//! follow them across circuit rotations. The [`Sanitizer`] gives every outgoing request a
+//! fresh random id of the same form, whatever the client sent, and keeps which id it
+//! replaced only on the entry node, for a limited time, to put it back on the response.
+//! Each request of a batch gets its own id, so requests sharing an id within a batch are
+//! still answered with theirs. Notifications carry no id and are sent without one.
+//!
+//! Formatting can identify a client library just as well, so requests are sent as compact
+//! JSON with the members of every object sorted by key: two clients sending the same
+//! request however they format it send the same bytes, but for the id.
+//!
+//! Each user has ids remembered for at most [`SanitizerConfig::max_ids_per_user`] requests
+//! at once, a user with more making way for their own newer ones, so no one's flood of
+//! requests pushes out the ids of everyone else's. A response whose id is no longer
+//! remembered, because it came after the id expired or was pushed out by newer ones, is
+//! answered with a `null` id, as JSON-RPC does for
+//! requests whose id can't be told. Streamed chunks, such as the status notifications of
+//! relayed transactions, have their ids restored too, but leave them remembered until
+//! they expire, since more chunks may follow.
+
+use super::*;
+use super::canonical;
+use super::expiring::ExpiringMap;
+use super::relay;
+use super::traits::RequestSanitizer;
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use tokio::time::Instant;
+
+/// How long the entry node remembers the ids it replaced
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SanitizerConfig {
+    /// How long a replaced id is kept for its response
+    pub id_ttl: Duration,
+    /// Most replaced ids kept at once, the oldest making way for new ones
+    pub max_ids: usize,
+    /// Most replaced ids kept at once for one user, their oldest making way for their new ones
+    pub max_ids_per_user: usize,
+}
+
+impl Default for SanitizerConfig {
+    fn default() -> Self {
+        Self {
+            id_ttl: Duration::from_secs(10 * 60),
+            max_ids: 100_000,
+            max_ids_per_user: 10_000,
+        }
+    }
+}
+
+/// The ids replaced, and whose requests they were sent out on
+struct Remembered {
+    /// The client's id behind each id sent out
+    ids: ExpiringMap<String, serde_json::Value>,
+    /// The ids sent out on each user's requests, with when, oldest first; some may be gone
+    by_user: HashMap<Uuid, VecDeque<(Instant, String)>>,
+}
+
+/// Replaces request ids with random ones and canonicalizes requests, see the module docs
+pub struct Sanitizer {
+    id_ttl: Duration,
+    max_ids: usize,
+    max_ids_per_user: usize,
+    remembered: parking_lot::Mutex<Remembered>,
+}
+
+impl Sanitizer {
+    /// Create a sanitizer remembering ids as `config` allows
+    pub fn new(config: &SanitizerConfig) -> Self {
+        Self {
+            id_ttl: config.id_ttl,
+            max_ids: config.max_ids,
+            max_ids_per_user: config.max_ids_per_user,
+            remembered: parking_lot::Mutex::new(Remembered {
+                ids: ExpiringMap::new(config.id_ttl, config.max_ids),
+                by_user: HashMap::new(),
+            }),
+        }
+    }
+    
+    /// Give `request`, one of `owner`'s, a random id in place of its own, if it has one
+    fn replace_id(&self, owner: Uuid, request: &mut serde_json::Value, now: Instant) {
+        let Some(id) = request.get_mut("id") else { return };
+        let mut remembered = self.remembered.lock();
+        let Remembered { ids, by_user } = &mut *remembered;
+        let opaque = loop {
+            let opaque = format!("{:016x}", rand::thread_rng().gen::<u64>());
+            if ids.get(&opaque, now).is_none() {
+                break opaque;
+            }
+        };
+        
+        // Once the owner is at their quota, forget what their requests had answered or
+        // expired, then their oldest while they still are
+        let sent = by_user.entry(owner).or_default();
+        let max_ids = self.max_ids_per_user.max(1);
+        if sent.len() >= max_ids {
+            sent.retain(|(sent_at, opaque)| now < *sent_at + self.id_ttl && ids.get(opaque, now).is_some());
+        }
+        while sent.len() >= max_ids {
+            let Some((_, oldest)) = sent.pop_front() else { break };
+            ids.remove(&oldest, now);
+        }
+        sent.push_back((now, opaque.clone()));
+        
+        let original = std::mem::replace(id, serde_json::Value::String(opaque.clone()));
+        ids.insert(opaque, original, now);
+        
+        // Users with nothing left remembered are dropped once there are more of them than ids
+        if by_user.len() > self.max_ids {
+            by_user.retain(|_, sent| sent.back().map_or(false, |(sent_at, _)| now < *sent_at + self.id_ttl));
+        }
+    }
+    
+    /// Put the client's id back in place of `id`, forgetting it if `take`
+    fn restore_id(&self, id: &mut serde_json::Value, take: bool, now: Instant) {
+        let serde_json::Value::String(opaque) = id else {
+            return;
+        };
+        let ids = &mut self.remembered.lock().ids;
+        let original = if take {
+            ids.remove(opaque, now)
+        } else {
+            ids.get(opaque, now).cloned()
+        };
+        if original.is_none() {
+            metrics::increment_counter!("darknode_unrestored_ids_total");
+        }
+        *id = original.unwrap_or(serde_json::Value::Null);
+    }
+    
+    /// Restore the ids of a response, or of every response of a batch
+    fn restore(&self, response: &mut serde_json::Value, take: bool, now: Instant) {
+        match response {
+            serde_json::Value::Array(responses) => {
+                for response in responses {
+                    self.restore(response, take, now);
+                }
+            }
+            serde_json::Value::Object(object) => {
+                if object.get("method").and_then(|method| method.as_str()) == Some(relay::STATUS_METHOD) {
+                    if let Some(id) = object.get_mut("params").and_then(|params| params.get_mut("id")) {
+                        self.restore_id(id, false, now);
+                    }
+                } else if let Some(id) = object.get_mut("id") {
+                    self.restore_id(id, take, now);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[async_trait]
+impl RequestSanitizer for Sanitizer {
+    async fn sanitize_request(&self, owner: Uuid, request: &[u8]) -> Result<Vec<u8>> {
+        let mut request: serde_json::Value = serde_json::from_slice(request)?;
+        let now = Instant::now();
+        match &mut request {
+            serde_json::Value::Array(requests) => {
+                for request in requests.iter_mut().filter(|request| request.is_object()) {
+                    self.replace_id(owner, request, now);
+                }
+            }
+            serde_json::Value::Object(_) => self.replace_id(owner, &mut request, now),
+            _ => {}
+        }
+        Ok(serde_json::to_vec(&canonical::sorted(request))?)
+    }
+    
+    async fn prepare_response(&self, response: &[u8]) -> Result<Vec<u8>> {
+        let Ok(mut response) = serde_json::from_slice::<serde_json::Value>(response) else {
+            return Ok(response.to_vec());
+        };
+        self.restore(&mut response, true, Instant::now());
+        Ok(serde_json::to_vec(&response)?)
+    }
+    
+    async fn prepare_response_chunk(&self, chunk: &[u8]) -> Result<Vec<u8>> {
+        // Chunks are whole JSON documents, some followed by a newline that is kept
+        let end = chunk.len() - chunk.iter().rev().take_while(|byte| byte.is_ascii_whitespace()).count();
+        let Ok(mut document) = serde_json::from_slice::<serde_json::Value>(&chunk[..end]) else {
+            return Ok(chunk.to_vec());
+        };
+        self.restore(&mut document, false, Instant::now());
+        let mut prepared = serde_json::to_vec(&document)?;
+        prepared.extend_from_slice(&chunk[end..]);
+        Ok(prepared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn one_users_flood_only_pushes_out_their_own_ids() {
+        let sanitizer = Sanitizer::new(&SanitizerConfig {
+            max_ids_per_user: 3,
+            ..Default::default()
+        });
+        let (flooder, bystander) = (Uuid::new_v4(), Uuid::new_v4());
+        let request = |id: u64| serde_json::to_vec(&serde_json::json!({ "jsonrpc": "2.0", "id": id, "method": "getSlot" })).unwrap();
+        let sent_id = |sanitized: Vec<u8>| serde_json::from_slice::<serde_json::Value>(&sanitized).unwrap()["id"].clone();
+        
+        let waiting = sent_id(sanitizer.sanitize_request(bystander, &request(7)).await.unwrap());
+        let mut flooded = Vec::new();
+        for id in 0..10 {
+            flooded.push(sent_id(sanitizer.sanitize_request(flooder, &request(id)).await.unwrap()));
+        }
+        
+        let restored = |id: serde_json::Value| {
+            let sanitizer = &sanitizer;
+            async move {
+                let response = serde_json::to_vec(&serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": 1 })).unwrap();
+                let prepared = sanitizer.prepare_response(&response).await.unwrap();
+                serde_json::from_slice::<serde_json::Value>(&prepared).unwrap()["id"].clone()
+            }
+        };
+        assert_eq!(restored(waiting).await, serde_json::json!(7));
+        assert_eq!(restored(flooded[0].clone()).await, serde_json::Value::Null);
+        assert_eq!(restored(flooded[9].clone()).await, serde_json::json!(9));
+    }
+    
+    fn sent_id(sanitized: &[u8]) -> String {
+        serde_json::from_slice::<serde_json::Value>(sanitized).unwrap()["id"].as_str().unwrap().to_string()
+    }
+    
+    #[tokio::test]
+    async fn sequential_ids_go_out_as_uncorrelated_random_ones() {
+        let sanitizer = Sanitizer::new(&SanitizerConfig::default());
+        let user = Uuid::new_v4();
+        let mut sent = Vec::new();
+        for id in 1..=20 {
+            let request = serde_json::to_vec(&serde_json::json!({ "jsonrpc": "2.0", "id": id, "method": "getSlot" })).unwrap();
+            sent.push(sent_id(&sanitizer.sanitize_request(user, &request).await.unwrap()));
+        }
+        
+        let opaque: Vec<u64> = sent.iter().map(|id| u64::from_str_radix(id, 16).unwrap()).collect();
+        assert!(sent.iter().all(|id| id.len() == 16));
+        assert_ne!(opaque[0], opaque[1]);
+        let steps: std::collections::HashSet<u64> = opaque.windows(2).map(|pair| pair[1].wrapping_sub(pair[0])).collect();
+        assert!(steps.len() > 1, "ids go out a fixed step apart: {:?}", sent);
+        assert!(opaque.iter().all(|id| *id > 20), "{:?}", sent);
+    }
+    
+    #[tokio::test]
+    async fn batch_ids_are_restored_exactly_even_when_shared() {
+        let sanitizer = Sanitizer::new(&SanitizerConfig::default());
+        let batch = serde_json::json!([
+            { "jsonrpc": "2.0", "id": 1, "method": "getSlot" },
+            { "jsonrpc": "2.0", "id": 1, "method": "getBlockHeight" },
+            { "jsonrpc": "2.0", "method": "getHealth" },
+            { "jsonrpc": "2.0", "id": "a", "method": "getEpochInfo" },
+            { "jsonrpc": "2.0", "id": null, "method": "getVersion" },
+        ]);
+        let sanitized = sanitizer.sanitize_request(Uuid::new_v4(), &serde_json::to_vec(&batch).unwrap()).await.unwrap();
+        let sent: Vec<serde_json::Value> = serde_json::from_slice(&sanitized).unwrap();
+        
+        // Every request with an id gets one of its own, and the notification still has none
+        assert!(sent[2].get("id").is_none());
+        let ids: Vec<String> = [0, 1, 3, 4].iter().map(|&i| sent[i]["id"].as_str().unwrap().to_string()).collect();
+        assert_eq!(ids.iter().collect::<std::collections::HashSet<_>>().len(), 4);
+        
+        // Answered out of order, each response gets back the id of its own request
+        let responses: Vec<serde_json::Value> = [3, 1, 4, 0]
+            .iter()
+            .map(|&i| serde_json::json!({ "jsonrpc": "2.0", "id": sent[i]["id"], "result": sent[i]["method"] }))
+            .collect();
+        let prepared = sanitizer.prepare_response(&serde_json::to_vec(&responses).unwrap()).await.unwrap();
+        let restored: Vec<(serde_json::Value, serde_json::Value)> = serde_json::from_slice::<Vec<serde_json::Value>>(&prepared)
+            .unwrap()
+            .into_iter()
+            .map(|response| (response["id"].clone(), response["result"].clone()))
+            .collect();
+        assert_eq!(
+            restored,
+            vec![
+                (serde_json::json!("a"), serde_json::json!("getEpochInfo")),
+                (serde_json::json!(1), serde_json::json!("getBlockHeight")),
+                (serde_json::Value::Null, serde_json::json!("getVersion")),
+                (serde_json::json!(1), serde_json::json!("getSlot")),
+            ]
+        );
+    }
+    
+    #[tokio::test]
+    async fn the_same_request_however_formatted_goes_out_as_the_same_bytes() {
+        let sanitizer = Sanitizer::new(&SanitizerConfig::default());
+        let from_one = br#"{"jsonrpc":"2.0","id":1,"method":"getBalance","params":["4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T",{"commitment":"finalized","minContextSlot":5}]}"#;
+        let from_other = br#"{
+            "params" : [ "4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T", { "minContextSlot": 5, "commitment": "finalized" } ],
+            "method" : "getBalance",
+            "id" : "req-77",
+            "jsonrpc" : "2.0"
+        }"#;
+        
+        let sent = |sanitized: Vec<u8>| {
+            let id = sent_id(&sanitized);
+            String::from_utf8(sanitized).unwrap().replace(&id, "<id>")
+        };
+        let one = sent(sanitizer.sanitize_request(Uuid::new_v4(), from_one).await.unwrap());
+        let other = sent(sanitizer.sanitize_request(Uuid::new_v4(), from_other).await.unwrap());
+        assert_eq!(one, other);
+        assert_eq!(
+            one,
+            r#"{"id":"<id>","jsonrpc":"2.0","method":"getBalance","params":["4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T",{"commitment":"finalized","minContextSlot":5}]}"#
+        );
+    }
+}
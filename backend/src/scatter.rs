@@ -0,0 +1,134 @@
+//! Spreading the addresses a user queries across circuits, so no exit node sees them all
+//!
+//! Some methods, such as `getSignaturesForAddress`, can't be sanitized: the address is the
+//! request. An exit node serving every request of a circuit learns which addresses its user
+//! asks about, and over a session, much of their address graph. Mappings with
+//! `address_scatter` set have their requests about an address sent on one of a few
+//! circuits per user, picked by a hash of the address: queries about the same address keep
+//! going through the same circuit, while different addresses are spread over
+//! `circuits` circuits, each built through a different exit node than its siblings
+//! where there are enough exits.
+//!
+//! The hash is an HMAC over the user and the address, keyed with
+//! [`ScatterConfig::secret`] or, without one, a secret drawn when the node starts, so which
+//! addresses share a circuit differs from user to user and can't be worked out from outside
+//! the node. Entry nodes sharing the secret spread a user's addresses alike, so an address
+//! keeps to one bucket whichever of them the user connects to.
+//!
+//! Addresses are found in params marked [`ParamKind::Address`] in the chain's param
+//! schemas, see [`crate::schema`]. A request naming several addresses is sent on the
+//! circuit of the first. Requests naming none, and requests on chains without schemas, go
+//! through the user's usual circuit.
+//!
+//! [`ParamKind::Address`]: crate::schema::ParamKind::Address
+
+use super::*;
+use super::chains::Chain;
+use super::schema::ChainSchema;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// How far the addresses a user queries are spread
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScatterConfig {
+    /// Circuits a user's address queries are spread over, at most
+    pub circuits: u8,
+    /// The secret addresses are hashed with, the same on every entry node; drawn at
+    /// random when the node starts if unset
+    pub secret: Option<String>,
+}
+
+impl Default for ScatterConfig {
+    fn default() -> Self {
+        Self {
+            circuits: 3,
+            secret: None,
+        }
+    }
+}
+
+/// Picks the circuit a request about an address goes through, see the module docs
+pub struct AddressScatter {
+    config: ScatterConfig,
+    schemas: HashMap<Chain, ChainSchema>,
+    key: [u8; 32],
+}
+
+impl AddressScatter {
+    /// Create a scatter finding addresses with the schemas of every known chain
+    pub fn new(config: ScatterConfig) -> Self {
+        let schemas = Chain::ALL
+            .into_iter()
+            .filter_map(|chain| Some((chain, ChainSchema::for_chain(chain.name())?)))
+            .collect();
+        let key = match &config.secret {
+            Some(secret) => Sha256::digest(secret.as_bytes()).into(),
+            None => rand::random(),
+        };
+        Self { config, schemas, key }
+    }
+    
+    /// The circuit out of `circuits` a request of `user` on `chain` goes through, if it
+    /// names an address
+    pub fn bucket(&self, user: Uuid, chain: Option<Chain>, request: &serde_json::Value) -> Option<u8> {
+        let schema = self.schemas.get(&chain?)?;
+        let address = *schema.addresses(request).first()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(user.as_bytes());
+        mac.update(address.as_bytes());
+        let hash = mac.finalize().into_bytes();
+        let circuits = self.config.circuits.max(1);
+        Some((u64::from_be_bytes(hash[..8].try_into().ok()?) % circuits as u64) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    
+    fn query(address: &str) -> serde_json::Value {
+        serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "getSignaturesForAddress", "params": [address]})
+    }
+    
+    #[test]
+    fn an_address_keeps_its_circuit_for_a_user_but_not_across_users() {
+        let scatter = AddressScatter::new(ScatterConfig::default());
+        let address = "Vote111111111111111111111111111111111111111";
+        let user = Uuid::new_v4();
+        let bucket = scatter.bucket(user, Some(Chain::Solana), &query(address));
+        assert!(bucket.is_some_and(|bucket| bucket < 3));
+        assert_eq!(scatter.bucket(user, Some(Chain::Solana), &query(address)), bucket);
+        
+        // Which addresses share a circuit depends on the user
+        let buckets: HashSet<_> = (0..64)
+            .map(|_| scatter.bucket(Uuid::new_v4(), Some(Chain::Solana), &query(address)))
+            .collect();
+        assert!(buckets.len() > 1);
+        
+        // Requests naming no address, or on chains without schemas, aren't scattered
+        assert_eq!(scatter.bucket(user, Some(Chain::Solana), &serde_json::json!({"method": "getSlot"})), None);
+        assert_eq!(scatter.bucket(user, None, &query(address)), None);
+    }
+    
+    #[test]
+    fn entry_nodes_sharing_the_secret_scatter_addresses_alike() {
+        let shared = || ScatterConfig {
+            secret: Some("shared".to_string()),
+            ..ScatterConfig::default()
+        };
+        let (first, second) = (AddressScatter::new(shared()), AddressScatter::new(shared()));
+        let user = Uuid::new_v4();
+        for address in [
+            "Vote111111111111111111111111111111111111111",
+            "4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T",
+            "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin",
+        ] {
+            let bucket = first.bucket(user, Some(Chain::Solana), &query(address));
+            assert!(bucket.is_some());
+            assert_eq!(second.bucket(user, Some(Chain::Solana), &query(address)), bucket);
+        }
+    }
+}
@@ -0,0 +1,445 @@
+//! Per-chain JSON-RPC parameter schemas
+//!
+//! The entry node checks the params of common methods against these schemas before any
+//! circuit work, so a malformed request fails fast with a pointer to the offending param
+//! instead of whatever the provider says after a full round trip. Methods without a
+//! schema are passed through unchecked.
+//!
+//! Params naming accounts a user may own are marked as [`ParamKind::Address`], so the
+//! addresses a request is about can be told, see [`crate::scatter`].
+
+use super::*;
+use super::chains::Chain;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::HashMap;
+
+/// Commitment levels accepted by Solana RPC
+pub const SOLANA_COMMITMENTS: &[&str] = &["processed", "confirmed", "finalized"];
+
+/// Account data encodings accepted by Solana RPC
+pub const SOLANA_ACCOUNT_ENCODINGS: &[&str] = &["base58", "base64", "base64+zstd", "jsonParsed"];
+
+/// Transaction encodings accepted by Solana RPC
+pub const SOLANA_TRANSACTION_ENCODINGS: &[&str] = &["base58", "base64"];
+
+/// Longest base58 string decoded, that of the largest Solana transaction (1232 bytes)
+pub const MAX_BASE58_LEN: usize = 1683;
+
+/// Whether the entry node validates params, and against which chain's schemas
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidationConfig {
+    /// Whether params are validated at all
+    pub enabled: bool,
+    /// The chain whose schemas apply to requests neither their method nor their mapping
+    /// ties to a chain
+    pub chain: Chain,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            chain: Chain::Solana,
+        }
+    }
+}
+
+/// Returned when a request's params don't match the method's schema
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("invalid params for {method}: {pointer}: {message}")]
+pub struct InvalidParams {
+    /// The method that was called
+    pub method: String,
+    /// JSON pointer to the offending value within the request (e.g. "/params/1/commitment")
+    pub pointer: String,
+    /// What was wrong with it
+    pub message: String,
+}
+
+/// The shape of a field inside a config object param
+#[derive(Debug, Clone)]
+pub enum FieldKind {
+    /// One of a fixed set of strings
+    OneOf(&'static [&'static str]),
+    /// An unsigned integer
+    Integer,
+    /// A boolean
+    Bool,
+}
+
+/// The shape of a positional param
+#[derive(Debug, Clone)]
+pub enum ParamKind {
+    /// A base58-encoded 32 byte public key
+    Pubkey,
+    /// A base58-encoded 32 byte public key of an account a user may own, such as a wallet
+    Address,
+    /// A base58-encoded 64 byte signature
+    Signature,
+    /// An unsigned integer
+    Integer,
+    /// A serialized transaction, in the encoding named by the config param (base58 by default)
+    Transaction,
+    /// An array whose items all have the same shape
+    Array(Box<ParamKind>),
+    /// An object whose known fields have the given shapes; other fields are passed through
+    Config(Vec<(&'static str, FieldKind)>),
+}
+
+/// A positional param of a method
+#[derive(Debug, Clone)]
+pub struct ParamSchema {
+    /// Name used in error messages
+    pub name: &'static str,
+    /// The expected shape
+    pub kind: ParamKind,
+    /// Whether the param may be omitted
+    pub required: bool,
+}
+
+/// The params a method accepts
+#[derive(Debug, Clone)]
+pub struct MethodSchema {
+    /// The method name
+    pub method: &'static str,
+    /// Positional params, required ones first
+    pub params: Vec<ParamSchema>,
+}
+
+impl MethodSchema {
+    /// A method taking no params
+    pub fn new(method: &'static str) -> Self {
+        Self {
+            method,
+            params: Vec::new(),
+        }
+    }
+    
+    /// Add a required param
+    pub fn required(mut self, name: &'static str, kind: ParamKind) -> Self {
+        self.params.push(ParamSchema {
+            name,
+            kind,
+            required: true,
+        });
+        self
+    }
+    
+    /// Add an optional param
+    pub fn optional(mut self, name: &'static str, kind: ParamKind) -> Self {
+        self.params.push(ParamSchema {
+            name,
+            kind,
+            required: false,
+        });
+        self
+    }
+}
+
+/// Schemas for the methods of one chain
+#[derive(Debug, Clone, Default)]
+pub struct ChainSchema {
+    methods: HashMap<&'static str, MethodSchema>,
+}
+
+impl ChainSchema {
+    /// A chain with no method schemas
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Add or replace a method's schema
+    pub fn with(mut self, schema: MethodSchema) -> Self {
+        self.methods.insert(schema.method, schema);
+        self
+    }
+    
+    /// The schemas for a known chain
+    pub fn for_chain(chain: &str) -> Option<Self> {
+        match chain {
+            "solana" => Some(Self::solana()),
+            _ => None,
+        }
+    }
+    
+    /// Schemas for the most common Solana methods
+    pub fn solana() -> Self {
+        let commitment = || ParamKind::Config(vec![
+            ("commitment", FieldKind::OneOf(SOLANA_COMMITMENTS)),
+            ("minContextSlot", FieldKind::Integer),
+        ]);
+        let account = || ParamKind::Config(vec![
+            ("commitment", FieldKind::OneOf(SOLANA_COMMITMENTS)),
+            ("encoding", FieldKind::OneOf(SOLANA_ACCOUNT_ENCODINGS)),
+            ("minContextSlot", FieldKind::Integer),
+        ]);
+        
+        Self::new()
+            .with(
+                MethodSchema::new("getBalance")
+                    .required("pubkey", ParamKind::Address)
+                    .optional("config", commitment()),
+            )
+            .with(
+                MethodSchema::new("getAccountInfo")
+                    .required("pubkey", ParamKind::Address)
+                    .optional("config", account()),
+            )
+            .with(
+                MethodSchema::new("getMultipleAccounts")
+                    .required("pubkeys", ParamKind::Array(Box::new(ParamKind::Address)))
+                    .optional("config", account()),
+            )
+            .with(
+                MethodSchema::new("getSignaturesForAddress")
+                    .required("address", ParamKind::Address)
+                    .optional("config", ParamKind::Config(vec![
+                        ("commitment", FieldKind::OneOf(SOLANA_COMMITMENTS)),
+                        ("limit", FieldKind::Integer),
+                        ("minContextSlot", FieldKind::Integer),
+                    ])),
+            )
+            .with(
+                MethodSchema::new("getTokenAccountsByOwner")
+                    .required("owner", ParamKind::Address)
+                    .required("filter", ParamKind::Config(vec![]))
+                    .optional("config", account()),
+            )
+            .with(
+                MethodSchema::new("getTokenAccountBalance")
+                    .required("account", ParamKind::Address)
+                    .optional("config", commitment()),
+            )
+            .with(
+                MethodSchema::new("getProgramAccounts")
+                    .required("program_id", ParamKind::Pubkey)
+                    .optional("config", account()),
+            )
+            .with(MethodSchema::new("getSlot").optional("config", commitment()))
+            .with(MethodSchema::new("getBlockHeight").optional("config", commitment()))
+            .with(MethodSchema::new("getLatestBlockhash").optional("config", commitment()))
+            .with(
+                MethodSchema::new("getSignatureStatuses")
+                    .required("signatures", ParamKind::Array(Box::new(ParamKind::Signature)))
+                    .optional("config", ParamKind::Config(vec![("searchTransactionHistory", FieldKind::Bool)])),
+            )
+            .with(
+                MethodSchema::new("getTransaction")
+                    .required("signature", ParamKind::Signature)
+                    .optional("config", ParamKind::Config(vec![
+                        ("commitment", FieldKind::OneOf(SOLANA_COMMITMENTS)),
+                        ("maxSupportedTransactionVersion", FieldKind::Integer),
+                    ])),
+            )
+            .with(
+                MethodSchema::new("getBlock")
+                    .required("slot", ParamKind::Integer)
+                    .optional("config", commitment()),
+            )
+            .with(
+                MethodSchema::new("sendTransaction")
+                    .required("transaction", ParamKind::Transaction)
+                    .optional("config", ParamKind::Config(vec![
+                        ("encoding", FieldKind::OneOf(SOLANA_TRANSACTION_ENCODINGS)),
+                        ("skipPreflight", FieldKind::Bool),
+                        ("preflightCommitment", FieldKind::OneOf(SOLANA_COMMITMENTS)),
+                        ("maxRetries", FieldKind::Integer),
+                        ("minContextSlot", FieldKind::Integer),
+                    ])),
+            )
+            .with(
+                MethodSchema::new("simulateTransaction")
+                    .required("transaction", ParamKind::Transaction)
+                    .optional("config", ParamKind::Config(vec![
+                        ("encoding", FieldKind::OneOf(SOLANA_TRANSACTION_ENCODINGS)),
+                        ("commitment", FieldKind::OneOf(SOLANA_COMMITMENTS)),
+                        ("sigVerify", FieldKind::Bool),
+                        ("replaceRecentBlockhash", FieldKind::Bool),
+                    ])),
+            )
+    }
+    
+    /// The addresses a JSON-RPC request's params name, in the order they appear
+    ///
+    /// Requests for methods without a schema name none.
+    pub fn addresses<'a>(&self, request: &'a serde_json::Value) -> Vec<&'a str> {
+        let Some(schema) = request["method"].as_str().and_then(|method| self.methods.get(method)) else {
+            return Vec::new();
+        };
+        let Some(params) = request["params"].as_array() else {
+            return Vec::new();
+        };
+        let mut addresses = Vec::new();
+        for (param, value) in schema.params.iter().zip(params) {
+            match &param.kind {
+                ParamKind::Address => addresses.extend(value.as_str()),
+                ParamKind::Array(item) if matches!(**item, ParamKind::Address) => {
+                    addresses.extend(value.as_array().into_iter().flatten().filter_map(|item| item.as_str()));
+                }
+                _ => {}
+            }
+        }
+        addresses
+    }
+    
+    /// Check a JSON-RPC request against its method's schema
+    ///
+    /// Requests for methods without a schema always pass.
+    pub fn validate(&self, request: &serde_json::Value) -> Result<(), InvalidParams> {
+        let Some(schema) = request["method"].as_str().and_then(|method| self.methods.get(method)) else {
+            return Ok(());
+        };
+        let fail = |pointer: String, message: String| InvalidParams {
+            method: schema.method.to_string(),
+            pointer,
+            message,
+        };
+        
+        let params = match &request["params"] {
+            serde_json::Value::Null => &[][..],
+            serde_json::Value::Array(params) => params.as_slice(),
+            _ => return Err(fail("/params".to_string(), "params must be an array".to_string())),
+        };
+        if params.len() > schema.params.len() {
+            return Err(fail(
+                "/params".to_string(),
+                format!("expected at most {} params, got {}", schema.params.len(), params.len()),
+            ));
+        }
+        
+        // Transactions are checked in the encoding the config param names
+        let encoding = params
+            .iter()
+            .find_map(|param| param.get("encoding"))
+            .and_then(|encoding| encoding.as_str())
+            .unwrap_or("base58");
+        
+        for (index, param) in schema.params.iter().enumerate() {
+            let pointer = format!("/params/{}", index);
+            match params.get(index) {
+                Some(value) => check_param(&param.kind, value, encoding).map_err(|(suffix, message)| {
+                    fail(pointer + &suffix, format!("{}: {}", param.name, message))
+                })?,
+                None if param.required => {
+                    return Err(fail(pointer, format!("missing required param {}", param.name)));
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check a value against a param shape, returning the pointer suffix and reason on mismatch
+fn check_param(kind: &ParamKind, value: &serde_json::Value, encoding: &str) -> Result<(), (String, String)> {
+    let mismatch = |message: &str| Err((String::new(), message.to_string()));
+    match kind {
+        ParamKind::Pubkey | ParamKind::Address => match value.as_str().and_then(base58_len) {
+            Some(32) => Ok(()),
+            _ => mismatch("expected a base58-encoded public key"),
+        },
+        ParamKind::Signature => match value.as_str().and_then(base58_len) {
+            Some(64) => Ok(()),
+            _ => mismatch("expected a base58-encoded signature"),
+        },
+        ParamKind::Integer => match value.as_u64() {
+            Some(_) => Ok(()),
+            None => mismatch("expected an unsigned integer"),
+        },
+        ParamKind::Transaction => {
+            let Some(transaction) = value.as_str() else {
+                return mismatch("expected an encoded transaction string");
+            };
+            let decodes = match encoding {
+                "base64" => STANDARD.decode(transaction).is_ok(),
+                _ => base58_len(transaction).is_some(),
+            };
+            if decodes {
+                Ok(())
+            } else {
+                mismatch(&format!("transaction is not valid {}; set the encoding config field to match", encoding))
+            }
+        }
+        ParamKind::Array(item) => {
+            let Some(items) = value.as_array() else {
+                return mismatch("expected an array");
+            };
+            for (index, value) in items.iter().enumerate() {
+                check_param(item, value, encoding)
+                    .map_err(|(suffix, message)| (format!("/{}{}", index, suffix), message))?;
+            }
+            Ok(())
+        }
+        ParamKind::Config(fields) => {
+            let Some(object) = value.as_object() else {
+                return mismatch("expected a config object");
+            };
+            for (name, field) in fields {
+                let Some(value) = object.get(*name) else { continue };
+                let valid = match field {
+                    FieldKind::OneOf(allowed) => value.as_str().map_or(false, |value| allowed.contains(&value)),
+                    FieldKind::Integer => value.is_u64(),
+                    FieldKind::Bool => value.is_boolean(),
+                };
+                if !valid {
+                    let expected = match field {
+                        FieldKind::OneOf(allowed) => format!("one of {:?}", allowed),
+                        FieldKind::Integer => "an unsigned integer".to_string(),
+                        FieldKind::Bool => "a boolean".to_string(),
+                    };
+                    return Err((format!("/{}", name), format!("{} must be {}, got {}", name, expected, value)));
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Decoded length of a base58 string, or `None` if it isn't base58
+fn base58_len(encoded: &str) -> Option<usize> {
+    base58_decode(encoded).map(|decoded| decoded.len())
+}
+
+/// Decode a base58 string, as used for Solana keys, signatures and transactions
+///
+/// Strings longer than [`MAX_BASE58_LEN`] aren't decoded, since decoding takes time
+/// quadratic in the length.
+pub fn base58_decode(encoded: &str) -> Option<Vec<u8>> {
+    if encoded.len() > MAX_BASE58_LEN {
+        return None;
+    }
+    bs58::decode(encoded).into_vec().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn decodes_base58_up_to_the_length_cap() {
+        assert_eq!(base58_decode("1112"), Some(vec![0, 0, 0, 1]));
+        assert_eq!(base58_decode("11111111111111111111111111111111"), Some(vec![0; 32]));
+        assert_eq!(base58_decode("0OIl"), None);
+        
+        // The largest transaction still decodes, anything longer is refused before decoding
+        let largest = bs58::encode(vec![0xff; 1232]).into_string();
+        assert_eq!(largest.len(), MAX_BASE58_LEN);
+        assert_eq!(base58_decode(&largest).map(|bytes| bytes.len()), Some(1232));
+        assert_eq!(base58_decode(&"z".repeat(MAX_BASE58_LEN + 1)), None);
+    }
+    
+    #[test]
+    fn validates_against_the_chain_of_the_request() {
+        let solana = ChainSchema::for_chain(Chain::Solana.name()).unwrap();
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "getBalance",
+            "params": ["11111111111111111111111111111111", {"commitment": "eventual"}],
+        });
+        assert_eq!(solana.validate(&request).unwrap_err().pointer, "/params/1/commitment");
+        assert!(ChainSchema::for_chain(Chain::Ethereum.name()).map_or(true, |schema| schema.validate(&request).is_ok()));
+    }
+}
@@ -0,0 +1,201 @@
+//! Scopes limiting what each of a user's API keys may do
+//!
+//! Teams keep one account with a key per environment, and don't want a development key
+//! reaching mainnet. Besides the account's own API key, which may do everything the plan
+//! allows, a user holds further [`ApiKey`]s created on `POST /keys`, each limited by its
+//! [`Scope`]s, which `PATCH /keys/:id` replaces:
+//!
+//! | Scope                      | Limits the key to                                       |
+//! |----------------------------|---------------------------------------------------------|
+//! | `{"networks": [..]}`       | Mappings on one of the [`Network`]s                     |
+//! | `{"method_classes": [..]}` | Methods of one of the [`MethodClass`]es                 |
+//! | `{"rate_multiplier": m}`   | A share `m` of the plan's daily request cap, at most 1  |
+//!
+//! A key without scopes is limited by nothing but the plan. Scopes of the same kind narrow
+//! each other, so the most restrictive wins, and they narrow the plan in turn: a key's
+//! requests count toward the account's daily cap as well as its own share of it.
+//!
+//! Scopes are checked on the entry node as soon as the mapping and method of a request are
+//! known, and a request the key isn't scoped for is refused with a [`ScopeError`] before any
+//! quota or circuit work is spent on it. Only the account's own key manages its keys.
+
+use super::*;
+use super::chains::Network;
+use super::timeouts::MethodClass;
+use super::types::{ApiKey, Plan, User};
+
+/// A limit on what an API key may do
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Networks the mappings the key is used with may be on
+    Networks(Vec<Network>),
+    /// Classes of the methods the key may call
+    MethodClasses(Vec<MethodClass>),
+    /// Share of the plan's daily request cap the key may use
+    RateMultiplier(f64),
+}
+
+/// A request or key outside of what scopes allow
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ScopeError {
+    /// The key isn't scoped for the network of the mapping it was used with
+    #[error("this API key is not scoped for {0} mappings")]
+    Network(Network),
+    /// The key isn't scoped for the class of the method called
+    #[error("this API key is not scoped for {} methods", .0.label())]
+    MethodClass(MethodClass),
+    /// The scopes can't be given to a key
+    #[error("invalid scope: {0}")]
+    Invalid(String),
+}
+
+/// Check that `scopes` can be given to a key
+///
+/// Empty lists, which would allow nothing, and multipliers that aren't above 0 are refused.
+pub fn validate(scopes: &[Scope]) -> Result<(), ScopeError> {
+    for scope in scopes {
+        match scope {
+            Scope::Networks(networks) if networks.is_empty() => {
+                return Err(ScopeError::Invalid("no networks allowed".to_string()))
+            }
+            Scope::MethodClasses(classes) if classes.is_empty() => {
+                return Err(ScopeError::Invalid("no method classes allowed".to_string()))
+            }
+            Scope::RateMultiplier(multiplier) if !(multiplier.is_finite() && *multiplier > 0.0) => {
+                return Err(ScopeError::Invalid(format!("rate multiplier {} is not above 0", multiplier)))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The key of `user`'s further keys that `api_key` is, if it is one
+pub fn key_of<'a>(user: &'a User, api_key: &str) -> Option<&'a ApiKey> {
+    user.keys.iter().find(|key| key.key == api_key)
+}
+
+/// What a key's scopes allow together
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grant {
+    networks: Option<Vec<Network>>,
+    method_classes: Option<Vec<MethodClass>>,
+    rate_multiplier: f64,
+}
+
+impl Grant {
+    /// Everything, as granted to a user's own API key
+    pub fn unrestricted() -> Self {
+        Self {
+            networks: None,
+            method_classes: None,
+            rate_multiplier: 1.0,
+        }
+    }
+    
+    /// What `scopes` allow together, the most restrictive of each kind winning
+    pub fn of(scopes: &[Scope]) -> Self {
+        let mut grant = Self::unrestricted();
+        for scope in scopes {
+            match scope {
+                Scope::Networks(networks) => narrow(&mut grant.networks, networks),
+                Scope::MethodClasses(classes) => narrow(&mut grant.method_classes, classes),
+                Scope::RateMultiplier(multiplier) => grant.rate_multiplier = grant.rate_multiplier.min(*multiplier),
+            }
+        }
+        grant
+    }
+    
+    /// What `api_key` of `user` is allowed
+    pub fn for_key(user: &User, api_key: &str) -> Self {
+        key_of(user, api_key).map_or_else(Self::unrestricted, |key| Self::of(&key.scopes))
+    }
+    
+    /// Refuse a request for a method of `class` to a mapping on `network` unless the grant allows it
+    pub fn admit(&self, network: Network, class: MethodClass) -> Result<(), ScopeError> {
+        let refused = if !allows(&self.networks, &network) {
+            ScopeError::Network(network)
+        } else if !allows(&self.method_classes, &class) {
+            ScopeError::MethodClass(class)
+        } else {
+            return Ok(());
+        };
+        metrics::increment_counter!("darknode_scope_refused_total", "class" => class.label());
+        Err(refused)
+    }
+    
+    /// The key's own share of `plan`, if the grant limits its rate below the plan's
+    pub fn limit(&self, plan: &Plan) -> Option<Plan> {
+        if self.rate_multiplier >= 1.0 {
+            return None;
+        }
+        Some(Plan {
+            daily_request_cap: (plan.daily_request_cap as f64 * self.rate_multiplier) as u64,
+            ..plan.clone()
+        })
+    }
+}
+
+/// Narrow what `allowed` allows to what `scoped` does too
+fn narrow<T: PartialEq + Clone>(allowed: &mut Option<Vec<T>>, scoped: &[T]) {
+    match allowed {
+        Some(allowed) => allowed.retain(|value| scoped.contains(value)),
+        None => *allowed = Some(scoped.to_vec()),
+    }
+}
+
+/// Whether `allowed` allows `value`, everything being allowed if unset
+fn allows<T: PartialEq>(allowed: &Option<Vec<T>>, value: &T) -> bool {
+    allowed.as_ref().map_or(true, |allowed| allowed.contains(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn scopes_of_a_kind_narrow_each_other_and_the_plan() {
+        let grant = Grant::of(&[
+            Scope::Networks(vec![Network::Devnet, Network::Testnet]),
+            Scope::Networks(vec![Network::Devnet, Network::Mainnet]),
+            Scope::RateMultiplier(0.5),
+            Scope::RateMultiplier(0.25),
+        ]);
+        assert_eq!(grant.admit(Network::Devnet, MethodClass::Write), Ok(()));
+        assert_eq!(grant.admit(Network::Testnet, MethodClass::Read), Err(ScopeError::Network(Network::Testnet)));
+        assert_eq!(grant.admit(Network::Mainnet, MethodClass::Read), Err(ScopeError::Network(Network::Mainnet)));
+        
+        let plan = Plan {
+            daily_request_cap: 1_000,
+            ..Plan::default()
+        };
+        assert_eq!(grant.limit(&plan).map(|limited| limited.daily_request_cap), Some(250));
+        assert!(Grant::unrestricted().limit(&plan).is_none());
+        assert!(Grant::of(&[Scope::RateMultiplier(3.0)]).limit(&plan).is_none());
+    }
+    
+    #[test]
+    fn a_read_only_key_is_refused_writes_on_every_network() {
+        let grant = Grant::of(&[Scope::MethodClasses(vec![MethodClass::LightRead, MethodClass::Read])]);
+        assert_eq!(grant.admit(Network::Mainnet, MethodClass::Read), Ok(()));
+        assert_eq!(grant.admit(Network::Devnet, MethodClass::Write), Err(ScopeError::MethodClass(MethodClass::Write)));
+        assert_eq!(
+            grant.admit(Network::Mainnet, MethodClass::HeavyRead),
+            Err(ScopeError::MethodClass(MethodClass::HeavyRead))
+        );
+    }
+    
+    #[test]
+    fn scopes_allowing_nothing_cant_be_given() {
+        assert_eq!(validate(&[Scope::Networks(vec![Network::Devnet]), Scope::RateMultiplier(0.1)]), Ok(()));
+        for scopes in [
+            vec![Scope::Networks(Vec::new())],
+            vec![Scope::MethodClasses(Vec::new())],
+            vec![Scope::RateMultiplier(0.0)],
+            vec![Scope::RateMultiplier(f64::NAN)],
+        ] {
+            assert!(matches!(validate(&scopes), Err(ScopeError::Invalid(_))), "{:?}", scopes);
+        }
+    }
+}
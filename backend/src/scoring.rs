@@ -0,0 +1,152 @@
+//! Node loads, provider success rates, and the arithmetic ranking by them
+//!
+//! Loads and success rates are fractions from 0 to 1, held as `f64`. They used to be
+//! `f32`, which lost precision over repeated moving-average updates and serialized as
+//! noise such as `0.9900000095367432`. A value that is exactly the `f32` nearest a decimal
+//! of at most [`LEGACY_DIGITS`] significant digits, but is written out longer, is taken for
+//! such a widened `f32` of a legacy payload and read as that decimal, so it comes out clean
+//! again. Any other value, `f32`-sized or not, is read as written. A value outside 0 to 1
+//! by no more than rounding could put
+//! it there is clamped, and any other is refused, so an API caller sending an
+//! out-of-range load or success rate gets a validation error rather than a changed value.
+//!
+//! The arithmetic lives in the pure functions here, so that rankings only change when
+//! these do.
+
+use super::*;
+
+/// Weight of the newest probe outcome in a provider's success rate
+pub const PROBE_WEIGHT: f64 = 0.1;
+
+/// How much a provider's success rate drops each time it misbehaves
+pub const MISBEHAVIOR_PENALTY: f64 = 0.1;
+
+/// How far outside 0 to 1 a fraction may be read and clamped, as rounding can put it
+const ROUNDING_TOLERANCE: f64 = 1e-6;
+
+/// Most significant digits of the decimals legacy `f32` fractions are read back as
+pub const LEGACY_DIGITS: usize = 6;
+
+/// A load or success rate outside 0 to 1
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("{0} is not a fraction from 0 to 1")]
+pub struct OutOfRange(pub f64);
+
+/// `value` as a fraction from 0 to 1, read as the decimal it stood for if it is a widened
+/// legacy `f32`, and clamped within rounding
+pub fn fraction_from(value: f64) -> Result<f64, OutOfRange> {
+    if !(-ROUNDING_TOLERANCE..=1.0 + ROUNDING_TOLERANCE).contains(&value) {
+        return Err(OutOfRange(value));
+    }
+    Ok(legacy_decimal(value).unwrap_or(value).clamp(0.0, 1.0))
+}
+
+/// The short decimal `value` stood for, if it is one widened from an `f32`
+fn legacy_decimal(value: f64) -> Option<f64> {
+    let narrow = value as f32;
+    if narrow as f64 != value {
+        return None;
+    }
+    let decimal = narrow.to_string();
+    let digits = decimal.trim_start_matches(['-', '0', '.']).chars().filter(char::is_ascii_digit).count();
+    if digits > LEGACY_DIGITS {
+        return None;
+    }
+    decimal.parse().ok()
+}
+
+/// Deserialize a fraction from 0 to 1, see [`fraction_from`]
+pub fn fraction<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    fraction_from(f64::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+/// Exponentially weighted moving average of `average` and a new `sample` weighing `weight`
+pub fn ewma(average: f64, sample: f64, weight: f64) -> f64 {
+    average * (1.0 - weight) + sample * weight
+}
+
+/// A provider's success rate after a probe that succeeded or not
+pub fn record_outcome(success_rate: f64, success: bool) -> f64 {
+    let sample = if success { 1.0 } else { 0.0 };
+    ewma(success_rate, sample, PROBE_WEIGHT).clamp(0.0, 1.0)
+}
+
+/// A provider's success rate after it misbehaved
+pub fn penalize(success_rate: f64) -> f64 {
+    (success_rate - MISBEHAVIOR_PENALTY).max(0.0)
+}
+
+/// How strongly a provider is preferred, by its success rate and operator-given weight
+pub fn provider_score(success_rate: f64, weight: u32) -> f64 {
+    success_rate * weight as f64
+}
+
+/// The share of a node's capacity left to recommend it for, none from `max_load` on
+pub fn headroom(load: f64, max_load: f64) -> f64 {
+    if load >= max_load {
+        0.0
+    } else {
+        (1.0 - load).clamp(0.0, 1.0)
+    }
+}
+
+/// Mean load of a group of `nodes` nodes averaging `average`, once a node with `load` joins it
+pub fn running_mean(average: f64, nodes: usize, load: f64) -> f64 {
+    (average * nodes as f64 + load) / (nodes + 1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Rated {
+        #[serde(deserialize_with = "fraction")]
+        success_rate: f64,
+    }
+    
+    #[test]
+    fn old_json_is_read_as_the_decimal_it_stood_for_and_written_back_clean() {
+        for old in [r#"{"success_rate":0.9900000095367432}"#, r#"{"success_rate":0.99}"#] {
+            let rated: Rated = serde_json::from_str(old).unwrap();
+            assert_eq!(rated.success_rate, 0.99);
+            assert_eq!(serde_json::to_string(&rated).unwrap(), r#"{"success_rate":0.99}"#);
+        }
+    }
+    
+    #[test]
+    fn only_widened_short_decimals_are_rewritten() {
+        assert_eq!(fraction_from(0.99_f32 as f64), Ok(0.99));
+        assert_eq!(fraction_from(0.125), Ok(0.125));
+        
+        // Exactly an f32, but not one nearest a short decimal, so kept as it is
+        let precise = 0.871_828_1_f32 as f64;
+        assert_eq!(fraction_from(precise), Ok(precise));
+        assert_eq!(fraction_from(record_outcome(0.99, true)), Ok(record_outcome(0.99, true)));
+    }
+    
+    #[test]
+    fn out_of_range_fractions_are_refused_and_rounding_is_clamped() {
+        assert_eq!(fraction_from(1.0 + 1e-9), Ok(1.0));
+        assert_eq!(fraction_from(-1e-9), Ok(0.0));
+        assert_eq!(fraction_from(1.5).unwrap_err().to_string(), "1.5 is not a fraction from 0 to 1");
+        let refused = serde_json::from_str::<Rated>(r#"{"success_rate":-0.25}"#).unwrap_err();
+        assert!(refused.to_string().starts_with("-0.25 is not a fraction from 0 to 1"), "{}", refused);
+    }
+    
+    #[test]
+    fn moving_averages_and_rankings_hold_their_values() {
+        let close = |actual: f64, expected: f64| assert!((actual - expected).abs() < 1e-9, "{} is not {}", actual, expected);
+        close(ewma(0.5, 1.0, 0.1), 0.55);
+        close(record_outcome(0.99, true), 0.991);
+        close(record_outcome(0.99, false), 0.891);
+        let recovered = (0..10).fold(0.0, |rate, _| record_outcome(rate, true));
+        close(recovered, 1.0 - 0.9_f64.powi(10));
+        close(penalize(0.95), 0.85);
+        close(penalize(0.05), 0.0);
+        close(provider_score(0.9, 3), 2.7);
+        close(headroom(0.3, 0.9), 0.7);
+        close(headroom(0.95, 0.9), 0.0);
+        close(running_mean(0.5, 3, 0.9), 0.6);
+    }
+}
@@ -0,0 +1,416 @@
+//! Resumable WebSocket sessions on the entry node
+//!
+//! A session outlives its socket: when a client disconnects, its subscriptions are kept
+//! and notifications are buffered for a grace period, so a client reconnecting with the
+//! session token picks up where it left off.
+//!
+//! Providers are reached over HTTP, which can't carry notifications, so each subscription
+//! is served by polling the read that answers it, see [`Watch`], and notifying the session
+//! when the answer changes. Polls are requests like any other, on the user's subscription
+//! circuit.
+
+use super::*;
+use super::clock::Deadline;
+use std::collections::{BTreeMap, VecDeque};
+use tokio::sync::mpsc;
+
+/// Longest grace period a session may be configured with
+pub const MAX_GRACE_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+/// Largest replay buffer a session may be configured with
+pub const MAX_REPLAY_BUFFER: usize = 4096;
+
+/// Shortest interval subscriptions may be configured to be polled at
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long sessions survive a disconnect and how much they buffer meanwhile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// How long a disconnected session is kept for the client to resume
+    pub grace_period: Duration,
+    /// Notifications buffered while disconnected; the oldest are dropped beyond this
+    pub replay_buffer: usize,
+    /// How often the read serving each subscription is polled
+    pub poll_interval: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            grace_period: Duration::from_secs(30),
+            replay_buffer: 256,
+            poll_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+/// How a subscription is served, by polling the read that answers it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    /// Solana's `accountSubscribe`, polled with `getAccountInfo`
+    Account,
+    /// Solana's `signatureSubscribe`, polled with `getSignatureStatuses` until the
+    /// signature lands, which ends the subscription
+    Signature,
+    /// Ethereum's `eth_subscribe` to `newHeads`, polled with `eth_getBlockByNumber`
+    NewHeads,
+}
+
+impl Watch {
+    /// The watch serving a subscribe call, if the subscription is supported
+    pub fn of(method: &str, params: &serde_json::Value) -> Option<Self> {
+        match method {
+            "accountSubscribe" => Some(Watch::Account),
+            "signatureSubscribe" => Some(Watch::Signature),
+            "eth_subscribe" if params[0] == "newHeads" => Some(Watch::NewHeads),
+            _ => None,
+        }
+    }
+    
+    /// The read to poll for a subscription made with `params`
+    pub fn request(self, params: &serde_json::Value) -> serde_json::Value {
+        let (method, params) = match self {
+            Watch::Account => ("getAccountInfo", params.clone()),
+            Watch::Signature => (
+                "getSignatureStatuses",
+                serde_json::json!([[params[0]], { "searchTransactionHistory": false }]),
+            ),
+            Watch::NewHeads => ("eth_getBlockByNumber", serde_json::json!(["latest", false])),
+        };
+        serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params })
+    }
+    
+    /// The notification for subscription `id` the read's latest `result` calls for, given
+    /// the result of the poll before, if any
+    ///
+    /// The first poll only sets what later ones are compared with, except for signatures,
+    /// which are notified as soon as they land.
+    pub fn notification(
+        self,
+        id: u64,
+        previous: Option<&serde_json::Value>,
+        result: &serde_json::Value,
+    ) -> Option<serde_json::Value> {
+        let (method, result) = match self {
+            // The context moves on every slot, only the account is watched
+            Watch::Account if previous.map_or(false, |previous| previous["value"] != result["value"]) => {
+                ("accountNotification", result.clone())
+            }
+            Watch::Signature if !result["value"][0].is_null() => (
+                "signatureNotification",
+                serde_json::json!({
+                    "context": result["context"],
+                    "value": { "err": result["value"][0]["err"] },
+                }),
+            ),
+            Watch::NewHeads if previous.map_or(false, |previous| previous["hash"] != result["hash"]) => {
+                ("eth_subscription", result.clone())
+            }
+            _ => return None,
+        };
+        Some(serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": { "result": result, "subscription": id },
+        }))
+    }
+    
+    /// Whether the subscription ends with its first notification
+    pub fn is_once(self) -> bool {
+        self == Watch::Signature
+    }
+}
+
+/// A subscription held by a session
+#[derive(Debug, Clone, Serialize)]
+pub struct Subscription {
+    /// The subscription ID returned to the client
+    pub id: u64,
+    /// The subscribe method (e.g. "accountSubscribe")
+    pub method: String,
+    /// The parameters the client subscribed with
+    pub params: serde_json::Value,
+}
+
+/// Returned when a session token is unknown or its grace period has passed
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("session expired")]
+pub struct SessionExpired;
+
+/// A connection's handle on its session
+pub struct Attachment {
+    /// The session token to hand to the client
+    pub token: String,
+    /// The user the session belongs to
+    pub user_id: Uuid,
+    /// Notifications for this connection
+    pub notifications: mpsc::UnboundedReceiver<serde_json::Value>,
+    /// Subscriptions carried over from a previous connection
+    pub subscriptions: Vec<Subscription>,
+    /// Notifications buffered while disconnected, oldest first
+    pub missed: Vec<serde_json::Value>,
+    generation: u64,
+}
+
+/// A session whose grace period ran out
+pub struct ExpiredSession {
+    /// The user the session belonged to
+    pub user_id: Uuid,
+    /// The subscriptions it held
+    pub subscriptions: usize,
+}
+
+/// State kept for one session
+struct Session {
+    user_id: Uuid,
+    subscriptions: BTreeMap<u64, Subscription>,
+    next_subscription: u64,
+    outbox: Option<mpsc::UnboundedSender<serde_json::Value>>,
+    generation: u64,
+    expires: Option<Deadline>,
+    missed: VecDeque<serde_json::Value>,
+}
+
+/// All sessions on an entry node
+pub struct SessionStore {
+    config: SessionConfig,
+    sessions: dashmap::DashMap<String, Session>,
+}
+
+impl SessionStore {
+    /// Create an empty store, capping the configuration at the hard limits
+    pub fn new(config: SessionConfig) -> Self {
+        Self {
+            config: SessionConfig {
+                grace_period: config.grace_period.min(MAX_GRACE_PERIOD),
+                replay_buffer: config.replay_buffer.min(MAX_REPLAY_BUFFER),
+                poll_interval: config.poll_interval.max(MIN_POLL_INTERVAL),
+            },
+            sessions: dashmap::DashMap::new(),
+        }
+    }
+    
+    /// Start a new session for a user
+    pub fn open(&self, user_id: Uuid) -> Attachment {
+        let token = Uuid::new_v4().simple().to_string();
+        let (outbox, notifications) = mpsc::unbounded_channel();
+        self.sessions.insert(
+            token.clone(),
+            Session {
+                user_id,
+                subscriptions: BTreeMap::new(),
+                next_subscription: 1,
+                outbox: Some(outbox),
+                generation: 0,
+                expires: None,
+                missed: VecDeque::new(),
+            },
+        );
+        Attachment {
+            token,
+            user_id,
+            notifications,
+            subscriptions: Vec::new(),
+            missed: Vec::new(),
+            generation: 0,
+        }
+    }
+    
+    /// The user a session belongs to, if it exists
+    pub fn owner(&self, token: &str) -> Option<Uuid> {
+        self.sessions.get(token).map(|session| session.user_id)
+    }
+    
+    /// How often the read serving each subscription is polled
+    pub fn poll_interval(&self) -> Duration {
+        self.config.poll_interval
+    }
+    
+    /// Attach a new connection to an existing session, taking over from any previous one
+    ///
+    /// An expired session is left for [`SessionStore::sweep`] to remove, so its
+    /// subscriptions are released there like those of every other expired session.
+    pub fn resume(&self, token: &str) -> Result<Attachment, SessionExpired> {
+        let mut session = self.sessions.get_mut(token).ok_or(SessionExpired)?;
+        if session.expires.map_or(false, |expires| expires.is_expired()) {
+            return Err(SessionExpired);
+        }
+        
+        let (outbox, notifications) = mpsc::unbounded_channel();
+        session.outbox = Some(outbox);
+        session.generation += 1;
+        session.expires = None;
+        Ok(Attachment {
+            token: token.to_string(),
+            user_id: session.user_id,
+            notifications,
+            subscriptions: session.subscriptions.values().cloned().collect(),
+            missed: session.missed.drain(..).collect(),
+            generation: session.generation,
+        })
+    }
+    
+    /// Mark a connection as gone, starting the session's grace period
+    ///
+    /// Ignored if another connection has already resumed the session.
+    pub fn detach(&self, attachment: &Attachment) {
+        if let Some(mut session) = self.sessions.get_mut(&attachment.token) {
+            if session.generation == attachment.generation {
+                session.outbox = None;
+                session.expires = Some(Deadline::after(self.config.grace_period));
+            }
+        }
+    }
+    
+    /// Record a subscription, returning its ID
+    pub fn subscribe(&self, token: &str, method: &str, params: serde_json::Value) -> Result<u64, SessionExpired> {
+        let mut session = self.sessions.get_mut(token).ok_or(SessionExpired)?;
+        let id = session.next_subscription;
+        session.next_subscription += 1;
+        session.subscriptions.insert(
+            id,
+            Subscription {
+                id,
+                method: method.to_string(),
+                params,
+            },
+        );
+        Ok(id)
+    }
+    
+    /// Whether a session holds subscription `id`
+    pub fn is_subscribed(&self, token: &str, id: u64) -> bool {
+        self.sessions
+            .get(token)
+            .map_or(false, |session| session.subscriptions.contains_key(&id))
+    }
+    
+    /// Whether a session holds any subscriptions
+    pub fn has_subscriptions(&self, token: &str) -> bool {
+        self.sessions
+            .get(token)
+            .map_or(false, |session| !session.subscriptions.is_empty())
+    }
+    
+    /// Drop a subscription, returning whether it existed
+    pub fn unsubscribe(&self, token: &str, id: u64) -> bool {
+        self.sessions
+            .get_mut(token)
+            .map_or(false, |mut session| session.subscriptions.remove(&id).is_some())
+    }
+    
+    /// Deliver a notification to a session, buffering it if the client is disconnected
+    pub fn notify(&self, token: &str, notification: serde_json::Value) {
+        let Some(mut session) = self.sessions.get_mut(token) else { return };
+        let notification = match &session.outbox {
+            Some(outbox) => match outbox.send(notification) {
+                Ok(()) => return,
+                Err(mpsc::error::SendError(notification)) => notification,
+            },
+            None => notification,
+        };
+        
+        if session.missed.len() >= self.config.replay_buffer {
+            session.missed.pop_front();
+            metrics::increment_counter!("darknode_session_notifications_dropped_total");
+        }
+        session.missed.push_back(notification);
+    }
+    
+    /// Sessions of a user and the subscriptions each holds
+    pub fn subscriptions_of(&self, user_id: Uuid) -> Vec<(String, Vec<u64>)> {
+        self.sessions
+            .iter()
+            .filter(|session| session.user_id == user_id && !session.subscriptions.is_empty())
+            .map(|session| (session.key().clone(), session.subscriptions.keys().copied().collect()))
+            .collect()
+    }
+    
+    /// Remove sessions whose grace period has passed
+    pub fn sweep(&self) -> Vec<ExpiredSession> {
+        let mut expired = Vec::new();
+        self.sessions.retain(|_, session| {
+            let keep = !session.expires.map_or(false, |expires| expires.is_expired());
+            if !keep {
+                expired.push(ExpiredSession {
+                    user_id: session.user_id,
+                    subscriptions: session.subscriptions.len(),
+                });
+            }
+            keep
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn store(grace_period: Duration) -> SessionStore {
+        SessionStore::new(SessionConfig {
+            grace_period,
+            ..Default::default()
+        })
+    }
+    
+    #[test]
+    fn replays_what_was_missed_while_disconnected() {
+        let sessions = store(Duration::from_secs(30));
+        let attachment = sessions.open(Uuid::new_v4());
+        let id = sessions.subscribe(&attachment.token, "accountSubscribe", serde_json::json!(["key"])).unwrap();
+        sessions.detach(&attachment);
+        sessions.notify(&attachment.token, serde_json::json!(1));
+        sessions.notify(&attachment.token, serde_json::json!(2));
+        
+        let resumed = sessions.resume(&attachment.token).unwrap();
+        assert_eq!(resumed.subscriptions.iter().map(|s| s.id).collect::<Vec<_>>(), vec![id]);
+        assert_eq!(resumed.missed, vec![serde_json::json!(1), serde_json::json!(2)]);
+    }
+    
+    #[test]
+    fn leaves_an_expired_session_to_the_sweep_to_release() {
+        let sessions = store(Duration::ZERO);
+        let user_id = Uuid::new_v4();
+        let attachment = sessions.open(user_id);
+        sessions.subscribe(&attachment.token, "accountSubscribe", serde_json::json!(["key"])).unwrap();
+        sessions.subscribe(&attachment.token, "accountSubscribe", serde_json::json!(["other"])).unwrap();
+        sessions.detach(&attachment);
+        
+        assert!(sessions.resume(&attachment.token).is_err());
+        let expired = sessions.sweep();
+        assert_eq!(expired.len(), 1);
+        assert_eq!((expired[0].user_id, expired[0].subscriptions), (user_id, 2));
+        assert!(sessions.owner(&attachment.token).is_none());
+    }
+    
+    #[test]
+    fn notifies_accounts_only_when_they_change() {
+        let watch = Watch::of("accountSubscribe", &serde_json::json!(["key"])).unwrap();
+        let at = |slot: u64, lamports: u64| serde_json::json!({ "context": { "slot": slot }, "value": { "lamports": lamports } });
+        
+        assert_eq!(watch.notification(7, None, &at(1, 10)), None);
+        assert_eq!(watch.notification(7, Some(&at(1, 10)), &at(2, 10)), None);
+        let notification = watch.notification(7, Some(&at(2, 10)), &at(3, 20)).unwrap();
+        assert_eq!(notification["method"], "accountNotification");
+        assert_eq!(notification["params"]["subscription"], 7);
+        assert_eq!(notification["params"]["result"], at(3, 20));
+    }
+    
+    #[test]
+    fn notifies_a_signature_once_it_lands() {
+        let watch = Watch::of("signatureSubscribe", &serde_json::json!(["sig"])).unwrap();
+        assert!(watch.is_once());
+        assert_eq!(watch.request(&serde_json::json!(["sig"]))["params"][0], serde_json::json!(["sig"]));
+        
+        let pending = serde_json::json!({ "context": { "slot": 1 }, "value": [null] });
+        assert_eq!(watch.notification(3, None, &pending), None);
+        let landed = serde_json::json!({ "context": { "slot": 2 }, "value": [{ "slot": 2, "err": null }] });
+        let notification = watch.notification(3, Some(&pending), &landed).unwrap();
+        assert_eq!(notification["params"]["result"]["value"], serde_json::json!({ "err": null }));
+        
+        assert_eq!(Watch::of("logsSubscribe", &serde_json::json!([])), None);
+        assert_eq!(Watch::of("eth_subscribe", &serde_json::json!(["logs"])), None);
+    }
+}
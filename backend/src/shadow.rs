@@ -0,0 +1,551 @@
+//! Mirroring sampled reads onto shadow circuits, to try a new path on real traffic
+//!
+//! Before a new protocol version or sanitizer rule set is rolled out, operators want to
+//! see it answer real requests the way the current path does. With shadowing on, an entry
+//! node mirrors `sample` of its read-only requests onto a second circuit per user, built
+//! with the shadow `protocol_version` and `exit_pool`, and sanitized by a sanitizer of its
+//! own, so mirrored ids don't crowd out the ids of users' requests. Mutating methods such
+//! as `sendTransaction`, methods opening or closing subscriptions, which would hold real
+//! subscriptions open upstream, and notifications are never mirrored, nor are requests
+//! whose responses are streamed.
+//!
+//! The mirror runs on its own task and bypasses traffic shaping, so the user's request
+//! neither waits on it nor gives up a shaping slot to it; the user always gets the
+//! primary circuit's response. Once both responses are in, they are compared without
+//! their `id` and DarkNode extension, by digest or by the JSON paths at which they
+//! differ. Discrepancies are kept for the operator on `GET /debug/shadow`, with the
+//! request's method but none of its params or either response's values, and only to
+//! holders of the operator token.
+
+use super::*;
+use super::canonical;
+use super::clock::Deadline;
+use super::context::RequestContext;
+use super::methods;
+use super::sanitizer::SanitizerConfig;
+use super::shaping::ShapingConfig;
+use super::timeouts::TimeoutConfig;
+use super::traits::{RequestSanitizer, Router};
+use super::types::{Circuit, CircuitPreferences};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::oneshot;
+
+/// Most paths a diff discrepancy lists
+const MAX_DIFF_PATHS: usize = 16;
+
+/// Which requests are mirrored, onto what, and how responses are compared
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShadowConfig {
+    /// Whether requests are mirrored at all
+    pub enabled: bool,
+    /// Share of read-only requests mirrored (0.01 = 1%)
+    pub sample: f64,
+    /// Protocol version shadow circuits speak, or the highest available if unset
+    pub protocol_version: Option<u16>,
+    /// Provider pool shadow circuits exit from, or the user's if unset
+    pub exit_pool: Option<String>,
+    /// How responses are compared
+    pub comparison: Comparison,
+    /// Discrepancies kept for the report, newest first
+    pub report_size: usize,
+    /// How the shadow path's sanitizer remembers the ids it replaced
+    pub sanitizer: SanitizerConfig,
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sample: 0.01,
+            protocol_version: None,
+            exit_pool: None,
+            comparison: Comparison::Diff,
+            report_size: 100,
+            sanitizer: SanitizerConfig::default(),
+        }
+    }
+}
+
+/// How a primary and a shadow response are compared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    /// Digests of both responses, reporting the digests when they differ
+    Digest,
+    /// Both responses field by field, reporting the paths at which they differ
+    Diff,
+}
+
+/// How a shadow response differed from the primary one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Difference {
+    /// The responses' digests, which differ
+    Digest {
+        /// Digest of the primary response
+        primary: String,
+        /// Digest of the shadow response
+        shadow: String,
+    },
+    /// JSON pointers to where the responses differ, the first [`MAX_DIFF_PATHS`] of them
+    Diff {
+        /// The paths, such as `/result/context/slot`
+        paths: Vec<String>,
+        /// Whether there were more than are listed
+        truncated: bool,
+    },
+    /// The shadow path gave no response
+    Failed {
+        /// Where it failed: `circuit`, `send`, `timed_out`, or `response`
+        stage: String,
+    },
+}
+
+/// A mirrored request whose shadow response differed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Discrepancy {
+    /// When the responses were compared
+    pub at: Timestamp,
+    /// The request's method label
+    pub method: String,
+    /// The protocol version of the shadow circuit, if one was built
+    pub shadow_version: Option<u16>,
+    /// How the responses differed
+    pub difference: Difference,
+}
+
+/// How mirrored requests have compared since the node started
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShadowReport {
+    /// Requests mirrored
+    pub mirrored: u64,
+    /// Mirrored requests answered the same on both paths
+    pub matched: u64,
+    /// Mirrored requests answered differently on the shadow path
+    pub discrepant: u64,
+    /// Mirrored requests the shadow path failed to answer
+    pub failed: u64,
+    /// The latest discrepancies and failures, newest first
+    pub recent: Vec<Discrepancy>,
+}
+
+/// Mirrors sampled requests onto shadow circuits and keeps the report
+pub struct Shadow {
+    config: ShadowConfig,
+    router: Arc<dyn Router + Send + Sync>,
+    timeouts: TimeoutConfig,
+    shaping: ShapingConfig,
+    circuits: dashmap::DashMap<String, (Circuit, Deadline)>,
+    mirrored: AtomicU64,
+    matched: AtomicU64,
+    discrepant: AtomicU64,
+    failed: AtomicU64,
+    recent: parking_lot::Mutex<VecDeque<Discrepancy>>,
+}
+
+impl Shadow {
+    /// Mirror onto circuits built by `router`, sealing payloads as the entry node does
+    pub fn new(
+        config: ShadowConfig,
+        router: Arc<dyn Router + Send + Sync>,
+        timeouts: TimeoutConfig,
+        shaping: ShapingConfig,
+    ) -> Self {
+        Self {
+            config,
+            router,
+            timeouts,
+            shaping,
+            circuits: dashmap::DashMap::new(),
+            mirrored: AtomicU64::new(0),
+            matched: AtomicU64::new(0),
+            discrepant: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            recent: parking_lot::Mutex::new(VecDeque::new()),
+        }
+    }
+    
+    /// Whether requests are mirrored at all
+    pub fn enabled(&self) -> bool {
+        self.config.enabled && self.config.sample > 0.0
+    }
+    
+    /// Whether to mirror `request`, drawing it into the sample if it is a read
+    pub fn samples(&self, request: &serde_json::Value) -> bool {
+        self.enabled()
+            && !methods::is_notification(request)
+            && methods::method_name(request)
+                .map_or(false, |method| !methods::is_mutating(method) && !methods::is_subscription(method))
+            && rand::random::<f64>() < self.config.sample
+    }
+    
+    /// Mirror `request` onto the user's shadow circuit, sanitized by `sanitizer`
+    ///
+    /// Returns where to send the primary response once the user has it; the comparison
+    /// waits for it, and is dropped if the sender is.
+    pub fn mirror(
+        self: &Arc<Self>,
+        ctx: RequestContext,
+        method: &'static str,
+        request: Vec<u8>,
+        sanitizer: Arc<dyn RequestSanitizer + Send + Sync>,
+    ) -> oneshot::Sender<Vec<u8>> {
+        let (primary, received) = oneshot::channel::<Vec<u8>>();
+        self.mirrored.fetch_add(1, Ordering::Relaxed);
+        let shadow = self.clone();
+        tokio::spawn(async move {
+            let (version, response) = shadow.fetch(&ctx, &request, &*sanitizer).await;
+            let Ok(primary) = received.await else {
+                metrics::increment_counter!("darknode_shadow_requests_total", "outcome" => "abandoned");
+                return;
+            };
+            let difference = match response {
+                Ok(response) => compare(shadow.config.comparison, &primary, &response),
+                Err(stage) => Some(Difference::Failed { stage: stage.to_string() }),
+            };
+            shadow.record(method, version, difference);
+        });
+        primary
+    }
+    
+    /// The shadow response to `request`, prepared for the client, and the version of the
+    /// circuit it came through
+    async fn fetch(
+        &self,
+        ctx: &RequestContext,
+        request: &[u8],
+        sanitizer: &(dyn RequestSanitizer + Send + Sync),
+    ) -> (Option<u16>, Result<Vec<u8>, &'static str>) {
+        let circuit = match self.circuit(ctx).await {
+            Some(circuit) => circuit,
+            None => return (None, Err("circuit")),
+        };
+        let version = Some(circuit.protocol_version);
+        let fetched = async {
+            let owner = ctx.user.as_ref().map_or(Uuid::nil(), |user| user.id);
+            let mut payload = sanitizer.sanitize_payload(owner, request).await.map_err(|_| "send")?;
+            ctx.seal(&mut payload, &self.timeouts, circuit.routing_nodes.len() + 1, &self.shaping);
+            
+            // The audit trail of the user's trace token only holds what the user was served
+            payload.trace_token = None;
+            let payload = serde_json::to_vec(&payload).map_err(|_| "send")?;
+            let request_id = self.router.send_request(ctx, &circuit, &payload).await.map_err(|_| "send")?;
+            let budget = ctx.deadline.map_or(self.timeouts.read, |deadline| deadline.remaining());
+            let response = tokio::time::timeout(budget, self.router.receive_response(request_id))
+                .await
+                .map_err(|_| "timed_out")?
+                .map_err(|_| "response")?;
+            sanitizer.prepare_response(&response).await.map_err(|_| "response")
+        };
+        (version, fetched.await)
+    }
+    
+    /// The user's shadow circuit, built if there is none still alive
+    async fn circuit(&self, ctx: &RequestContext) -> Option<Circuit> {
+        if let Some(entry) = self.circuits.get(&ctx.api_key).filter(|entry| !entry.1.is_expired()) {
+            return Some(entry.0.clone());
+        }
+        
+        // Release shadow circuits that expired, this user's among them
+        let expired: Vec<(String, Circuit)> = self
+            .circuits
+            .iter()
+            .filter(|entry| entry.1.is_expired())
+            .map(|entry| (entry.key().clone(), entry.0.clone()))
+            .collect();
+        for (key, circuit) in expired {
+            self.circuits.remove_if(&key, |_, entry| entry.0.id == circuit.id);
+            if let Err(e) = self.router.close_circuit(&circuit).await {
+                tracing::debug!("Failed to tear down shadow circuit {:?}: {}", circuit.id, e);
+            }
+        }
+        
+        let preferences = CircuitPreferences {
+            exit_pool: self.config.exit_pool.clone().or_else(|| ctx.constraints.exit_pool.clone()),
+            protocol_version: self.config.protocol_version,
+            ..Default::default()
+        };
+        match self.router.create_circuit_with(&preferences).await {
+            Ok(circuit) => {
+                self.circuits
+                    .insert(ctx.api_key.clone(), (circuit.clone(), Deadline::after(circuit.lifetime())));
+                Some(circuit)
+            }
+            Err(e) => {
+                tracing::debug!("Failed to build shadow circuit: {}", e);
+                None
+            }
+        }
+    }
+    
+    /// Count a comparison, keeping it for the report if the responses differed
+    fn record(&self, method: &'static str, shadow_version: Option<u16>, difference: Option<Difference>) {
+        let (counter, outcome) = match &difference {
+            None => (&self.matched, "matched"),
+            Some(Difference::Failed { .. }) => (&self.failed, "failed"),
+            Some(_) => (&self.discrepant, "discrepant"),
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        metrics::increment_counter!("darknode_shadow_requests_total", "outcome" => outcome);
+        let Some(difference) = difference else {
+            return;
+        };
+        
+        tracing::info!("Shadow response to {} differed: {:?}", method, difference);
+        let mut recent = self.recent.lock();
+        recent.push_front(Discrepancy {
+            at: Timestamp::now(),
+            method: method.to_string(),
+            shadow_version,
+            difference,
+        });
+        recent.truncate(self.config.report_size);
+    }
+    
+    /// How mirrored requests have compared so far
+    pub fn report(&self) -> ShadowReport {
+        ShadowReport {
+            mirrored: self.mirrored.load(Ordering::Relaxed),
+            matched: self.matched.load(Ordering::Relaxed),
+            discrepant: self.discrepant.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            recent: self.recent.lock().iter().cloned().collect(),
+        }
+    }
+}
+
+/// How `shadow` differs from `primary`, if it does
+///
+/// Responses that aren't JSON are compared byte for byte.
+pub fn compare(comparison: Comparison, primary: &[u8], shadow: &[u8]) -> Option<Difference> {
+    let (primary, shadow) = match (comparable(primary), comparable(shadow)) {
+        (Some(primary), Some(shadow)) => (primary, shadow),
+        _ if primary == shadow => return None,
+        _ => {
+            return Some(Difference::Digest {
+                primary: digest(primary),
+                shadow: digest(shadow),
+            })
+        }
+    };
+    if primary == shadow {
+        return None;
+    }
+    match comparison {
+        Comparison::Digest => Some(Difference::Digest {
+            primary: digest(&serde_json::to_vec(&primary).unwrap_or_default()),
+            shadow: digest(&serde_json::to_vec(&shadow).unwrap_or_default()),
+        }),
+        Comparison::Diff => {
+            let mut paths = Vec::new();
+            diff(&primary, &shadow, &mut String::new(), &mut paths);
+            let truncated = paths.len() > MAX_DIFF_PATHS;
+            paths.truncate(MAX_DIFF_PATHS);
+            Some(Difference::Diff { paths, truncated })
+        }
+    }
+}
+
+/// A response as compared: parsed, without its `id` and DarkNode extension, keys sorted
+fn comparable(response: &[u8]) -> Option<serde_json::Value> {
+    let mut response: serde_json::Value = serde_json::from_slice(response).ok()?;
+    if let Some(object) = response.as_object_mut() {
+        object.remove("id");
+        object.remove(methods::EXTENSION_KEY);
+    }
+    Some(canonical::sorted(response))
+}
+
+/// Collect the JSON pointers under `path` at which `a` and `b` differ, one past the most listed
+fn diff(a: &serde_json::Value, b: &serde_json::Value, path: &mut String, paths: &mut Vec<String>) {
+    use serde_json::Value;
+    if paths.len() > MAX_DIFF_PATHS || a == b {
+        return;
+    }
+    let len = path.len();
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            let mut keys: Vec<&String> = a.keys().chain(b.keys().filter(|key| !a.contains_key(*key))).collect();
+            keys.sort();
+            for key in keys {
+                path.push('/');
+                path.push_str(&key.replace('~', "~0").replace('/', "~1"));
+                match (a.get(key), b.get(key)) {
+                    (Some(a), Some(b)) => diff(a, b, path, paths),
+                    _ => paths.push(path.clone()),
+                }
+                path.truncate(len);
+            }
+        }
+        (Value::Array(a), Value::Array(b)) if a.len() == b.len() => {
+            for (index, (a, b)) in a.iter().zip(b).enumerate() {
+                path.push_str(&format!("/{}", index));
+                diff(a, b, path, paths);
+                path.truncate(len);
+            }
+        }
+        _ => paths.push(if path.is_empty() { "/".to_string() } else { path.clone() }),
+    }
+}
+
+/// The first 8 bytes of the SHA-256 of `bytes`, in hex
+fn digest(bytes: &[u8]) -> String {
+    hex::encode(&Sha256::digest(bytes)[..8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EntryConfig;
+    use crate::fixtures::{self, StubRouter};
+    use crate::traits::UserManager;
+    use crate::types::ExitPayload;
+    use serde_json::json;
+    use std::collections::HashSet;
+    use tokio::time::Instant;
+    
+    /// How long the shadow path takes to answer
+    const SHADOW_DELAY: Duration = Duration::from_millis(500);
+    
+    /// A router answering mirrored requests, which carry no trace token, late and differently
+    struct Shadowed {
+        stub: StubRouter,
+        mirrored: parking_lot::Mutex<HashSet<Uuid>>,
+    }
+    
+    impl Shadowed {
+        fn new() -> Self {
+            Self {
+                stub: StubRouter::new(|payload| match (payload.request["method"].as_str(), &payload.trace_token) {
+                    (Some("sendTransaction"), _) => json!({ "jsonrpc": "2.0", "result": "5VERv8NMvzbJMEkV" }),
+                    (_, Some(_)) => json!({ "jsonrpc": "2.0", "result": 311_029_712 }),
+                    (_, None) => json!({ "jsonrpc": "2.0", "result": 311_029_713 }),
+                }),
+                mirrored: Default::default(),
+            }
+        }
+    }
+    
+    #[async_trait]
+    impl Router for Shadowed {
+        async fn create_circuit(&self) -> Result<Circuit> {
+            self.stub.create_circuit().await
+        }
+        
+        async fn create_circuit_with(&self, preferences: &CircuitPreferences) -> Result<Circuit> {
+            self.stub.create_circuit_with(preferences).await
+        }
+        
+        async fn send_request(&self, ctx: &RequestContext, circuit: &Circuit, request: &[u8]) -> Result<Uuid> {
+            let payload: ExitPayload = serde_json::from_slice(request)?;
+            let request_id = self.stub.send_request(ctx, circuit, request).await?;
+            if payload.trace_token.is_none() {
+                self.mirrored.lock().insert(request_id);
+            }
+            Ok(request_id)
+        }
+        
+        async fn receive_response(&self, request_id: Uuid) -> Result<Vec<u8>> {
+            if self.mirrored.lock().remove(&request_id) {
+                tokio::time::sleep(SHADOW_DELAY).await;
+            }
+            self.stub.receive_response(request_id).await
+        }
+    }
+    
+    fn mirroring_everything() -> EntryConfig {
+        EntryConfig {
+            shadow: ShadowConfig {
+                enabled: true,
+                sample: 1.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn discrepancies_are_counted_without_holding_up_the_user() {
+        let router = Arc::new(Shadowed::new());
+        let (entry, users) = fixtures::entry(router.clone(), &mirroring_everything()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let request = serde_json::to_vec(&json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" })).unwrap();
+        
+        for _ in 0..5 {
+            let started = Instant::now();
+            let response = entry.handle_request(RequestContext::new(&user.api_key), &request).await.unwrap();
+            let response: serde_json::Value = serde_json::from_slice(&response).unwrap();
+            assert!(started.elapsed() < SHADOW_DELAY);
+            assert_eq!(response["result"], json!(311_029_712));
+        }
+        let report = entry.shadow_report();
+        assert_eq!((report.mirrored, report.matched, report.discrepant), (5, 0, 0));
+        
+        // Once the shadow path answers, every mirrored request is found to differ
+        tokio::time::sleep(SHADOW_DELAY * 2).await;
+        let report = entry.shadow_report();
+        assert_eq!((report.mirrored, report.matched, report.discrepant, report.failed), (5, 0, 5, 0));
+        assert_eq!(report.recent.len(), 5);
+        assert_eq!(report.recent[0].method, "getSlot");
+        assert_eq!(
+            report.recent[0].difference,
+            Difference::Diff {
+                paths: vec!["/result".to_string()],
+                truncated: false,
+            }
+        );
+        
+        // One shadow circuit is kept for the user beside the primary
+        assert_eq!(router.stub.circuits().len(), 2);
+        assert_eq!(router.stub.sent().len(), 10);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn send_transaction_is_never_mirrored() {
+        let router = Arc::new(Shadowed::new());
+        let (entry, users) = fixtures::entry(router.clone(), &mirroring_everything()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        
+        for id in 0..3 {
+            let request = json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": "sendTransaction",
+                "params": ["AQABAg==", { "encoding": "base64" }],
+            });
+            let request = serde_json::to_vec(&request).unwrap();
+            entry.handle_request(RequestContext::new(&user.api_key), &request).await.unwrap();
+        }
+        tokio::time::sleep(SHADOW_DELAY * 2).await;
+        
+        let sent = router.stub.sent();
+        assert_eq!(sent.len(), 3);
+        assert!(sent.iter().all(|(_, payload)| payload.trace_token.is_some()));
+        assert_eq!(router.stub.circuits().len(), 1);
+        assert_eq!(entry.shadow_report().mirrored, 0);
+    }
+    
+    #[test]
+    fn responses_are_compared_without_their_id_or_extension() {
+        let primary = br#"{"jsonrpc":"2.0","id":1,"result":{"context":{"slot":7},"value":10}}"#;
+        let renumbered = br#"{"id":9,"result":{"value":10,"context":{"slot":7}},"jsonrpc":"2.0","darknode":{"trace_token":"x"}}"#;
+        let stale = br#"{"jsonrpc":"2.0","id":1,"result":{"context":{"slot":6},"value":10}}"#;
+        
+        assert_eq!(compare(Comparison::Diff, primary, renumbered), None);
+        assert_eq!(
+            compare(Comparison::Diff, primary, stale),
+            Some(Difference::Diff {
+                paths: vec!["/result/context/slot".to_string()],
+                truncated: false,
+            })
+        );
+        assert!(matches!(
+            compare(Comparison::Digest, primary, stale),
+            Some(Difference::Digest { primary, shadow }) if primary != shadow
+        ));
+        assert!(matches!(compare(Comparison::Diff, primary, b"502 Bad Gateway"), Some(Difference::Digest { .. })));
+    }
+}
@@ -0,0 +1,197 @@
+//! Traffic shaping decorrelating when messages enter and leave the network
+//!
+//! An adversary watching both the entry and the exit node could match a request entering
+//! with one leaving by timing alone. With shaping on, entry nodes hold every message they
+//! send into a circuit, and exit nodes every response they send back, until the next tick
+//! of a fixed interval, and release each tick's messages in random order. Departures then
+//! only reveal which tick a message arrived in, not when, and never in what order.
+//!
+//! Shaping applies to everything a node sends through circuits alike, requests,
+//! notifications, and keepalive traffic, so none stands out. It adds up to one tick at each
+//! end, which the entry node holds back from the budget it gives the exit node, so requests
+//! still time out when their deadline says. The tick is meant to be the same across a
+//! deployment, since the entry node budgets for the exit node's delay by its own.
+//!
+//! The `shaping` feature flag, see [`crate::flags`], lets messages go at once on nodes
+//! configured to shape, should shaping itself misbehave; the tick is still budgeted for.
+
+use super::*;
+use super::flags::{self, FeatureFlags};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use tokio::sync::oneshot;
+
+/// Whether and how messages are held back
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShapingConfig {
+    /// Whether messages are held until the next tick
+    pub enabled: bool,
+    /// Interval between releases
+    pub tick: Duration,
+    /// Seed of the release order, so it can be reproduced; random when unset
+    pub seed: Option<u64>,
+}
+
+impl Default for ShapingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            tick: Duration::from_millis(50),
+            seed: None,
+        }
+    }
+}
+
+impl ShapingConfig {
+    /// Longest delay shaping adds to a request's round trip, one tick at each end
+    pub fn round_trip_delay(&self) -> Duration {
+        match self.enabled {
+            true => self.tick * 2,
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// Holds messages until the next tick and releases each tick's in random order
+pub struct TrafficShaper {
+    config: ShapingConfig,
+    waiting: parking_lot::Mutex<Vec<oneshot::Sender<()>>>,
+    rng: parking_lot::Mutex<StdRng>,
+    flags: FeatureFlags,
+}
+
+impl TrafficShaper {
+    /// Create a shaper holding nothing
+    pub fn new(config: ShapingConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            config,
+            waiting: parking_lot::Mutex::new(Vec::new()),
+            rng: parking_lot::Mutex::new(rng),
+            flags: FeatureFlags::new(),
+        }
+    }
+    
+    /// Let messages go at once while `flags` turn shaping off
+    pub fn with_flags(mut self, flags: FeatureFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+    
+    /// The configuration the shaper runs with
+    pub fn config(&self) -> &ShapingConfig {
+        &self.config
+    }
+    
+    /// Wait until the message about to be sent may go
+    ///
+    /// Returns at once when shaping is off or flagged off; otherwise [`Self::run`] must be running.
+    pub async fn release(&self) {
+        if !self.config.enabled || !self.flags.enabled(flags::SHAPING) {
+            return;
+        }
+        let (release, released) = oneshot::channel();
+        self.waiting.lock().push(release);
+        let _ = released.await;
+    }
+    
+    /// Release the waiting messages every tick, until the task is dropped
+    pub async fn run(self: Arc<Self>) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut ticker = tokio::time::interval(self.config.tick);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            ticker.tick().await;
+            let mut batch = std::mem::take(&mut *self.waiting.lock());
+            if batch.is_empty() {
+                continue;
+            }
+            batch.shuffle(&mut *self.rng.lock());
+            metrics::histogram!("darknode_shaping_batch_size", batch.len() as f64);
+            for release in batch {
+                let _ = release.send(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Deadline;
+    use crate::context::RequestContext;
+    use crate::timeouts::TimeoutConfig;
+    use tokio::time::Instant;
+    
+    fn shaping(seed: Option<u64>) -> ShapingConfig {
+        ShapingConfig {
+            enabled: true,
+            seed,
+            ..Default::default()
+        }
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn messages_are_released_on_tick_boundaries() {
+        let config = shaping(None);
+        let shaper = Arc::new(TrafficShaper::new(config.clone()));
+        let started = Instant::now();
+        tokio::spawn(shaper.clone().run());
+        
+        for (sent_at, released_at) in [(10, 50), (70, 100), (120, 150), (160, 200)] {
+            tokio::time::sleep_until(started + Duration::from_millis(sent_at)).await;
+            shaper.release().await;
+            assert_eq!(started.elapsed(), Duration::from_millis(released_at));
+            assert_eq!(started.elapsed().as_millis() % config.tick.as_millis(), 0);
+        }
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_ticks_messages_are_released_in_the_order_the_seed_shuffles_them() {
+        let shaper = Arc::new(TrafficShaper::new(shaping(Some(7))));
+        let released = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let messages: Vec<_> = (0..10)
+            .map(|message| {
+                let (shaper, released) = (shaper.clone(), released.clone());
+                tokio::spawn(async move {
+                    shaper.release().await;
+                    released.lock().push(message);
+                })
+            })
+            .collect();
+        tokio::spawn(shaper.clone().run());
+        for message in messages {
+            message.await.unwrap();
+        }
+        
+        let mut shuffled: Vec<i32> = (0..10).collect();
+        shuffled.shuffle(&mut StdRng::seed_from_u64(7));
+        assert_eq!(*released.lock(), shuffled);
+        assert_ne!(shuffled, (0..10).collect::<Vec<_>>());
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn the_exit_nodes_budget_leaves_room_for_shaping_at_both_ends() {
+        let ctx = RequestContext {
+            deadline: Some(Deadline::after(Duration::from_secs(10))),
+            ..Default::default()
+        };
+        let timeouts = TimeoutConfig::default();
+        let budget = |shaping: &ShapingConfig| {
+            let mut payload = crate::fixtures::payload("getSlot", serde_json::json!([]));
+            ctx.seal(&mut payload, &timeouts, 2, shaping);
+            payload.timeout.unwrap()
+        };
+        
+        let shaped = shaping(None);
+        assert_eq!(shaped.round_trip_delay(), shaped.tick * 2);
+        assert_eq!(budget(&ShapingConfig::default()) - budget(&shaped), shaped.round_trip_delay());
+    }
+}
@@ -0,0 +1,373 @@
+//! Per-request wallet signatures for mappings that require them
+//!
+//! An API key alone authenticates a user, so whoever steals it can spend the user's quota
+//! and act in their name. Mappings with `require_request_signature` set also need every
+//! request signed with the user's registered wallet key. The client signs the request,
+//! a timestamp, and a nonce, and sends them in headers; the entry node checks the
+//! signature against the wallet's public key before doing anything else with the request,
+//! refuses timestamps outside the freshness window, and refuses nonces it has already seen
+//! within it. Entry nodes sharing storage share the nonces they have seen, so a request
+//! can't be replayed at another entry node either.
+//!
+//! The signed message is `darknode-request:v1\n<timestamp>\n<nonce>\n<request>`, where
+//! `<timestamp>` is in seconds since the Unix epoch and `<request>` is the JSON-RPC request
+//! without its `jsonrpc` member, serialized with sorted keys and no whitespace. The
+//! signature is the wallet's ed25519 signature, base58-encoded as Solana wallets produce it.
+//!
+//! Requests that name no mapping must be signed whenever any of the user's mappings requires
+//! it, so leaving the mapping out can't sidestep the requirement. WebSocket messages carry no
+//! headers, so requests to such mappings can't be sent over WebSocket. Mappings without the
+//! requirement ignore the signature headers, however malformed.
+
+use super::*;
+use super::canonical;
+use super::schema::base58_decode;
+use super::storage::{Collection, Precondition, Storage, VersionConflict};
+use super::traits::Crypto;
+use super::types::{CryptoKey, User};
+use std::collections::HashMap;
+
+/// Header carrying the request's base58 signature
+pub const SIGNATURE_HEADER: &str = "x-darknode-signature";
+
+/// Header carrying when the request was signed, in seconds since the Unix epoch
+pub const TIMESTAMP_HEADER: &str = "x-darknode-timestamp";
+
+/// Header carrying the request's nonce
+pub const NONCE_HEADER: &str = "x-darknode-nonce";
+
+/// Prefix of every signed message, so request signatures can't be replayed elsewhere
+const MESSAGE_PREFIX: &str = "darknode-request:v1";
+
+/// Longest nonce accepted
+const MAX_NONCE_LEN: usize = 128;
+
+/// Longest base58 encoding of a 64-byte signature, checked before decoding it
+const MAX_SIGNATURE_LEN: usize = 88;
+
+/// Collection the nonces of verified requests are shared in
+const REQUEST_NONCES: &str = "request_nonces";
+
+/// How signed requests are checked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningConfig {
+    /// How far a request's timestamp may be from this node's clock, either way
+    pub max_age: Duration,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The signature a client sent with a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestSignature {
+    /// The base58 signature
+    pub signature: String,
+    /// When the request was signed, in seconds since the Unix epoch, as the client sent it
+    pub timestamp: String,
+    /// A value the client never reuses within the freshness window
+    pub nonce: String,
+}
+
+/// A request to a mapping requiring signatures was refused
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SignatureRejected {
+    /// The request carries no signature
+    #[error("this mapping requires every request to be signed with the user's wallet")]
+    Missing,
+    /// The signature, nonce, or wallet address can't be decoded
+    #[error("request signature is malformed")]
+    Malformed,
+    /// The timestamp is outside the freshness window
+    #[error("request signature timestamp is outside the allowed window")]
+    Stale,
+    /// The nonce was already used within the freshness window
+    #[error("request nonce was already used")]
+    Replayed,
+    /// The signature doesn't verify under the user's wallet key
+    #[error("request signature does not verify")]
+    Invalid,
+    /// The nonce couldn't be recorded, so the request can't be told apart from a replay
+    #[error("request nonce could not be recorded")]
+    Unrecorded,
+}
+
+impl SignatureRejected {
+    /// Label used in metrics and error responses
+    pub fn label(&self) -> &'static str {
+        match self {
+            SignatureRejected::Missing => "missing",
+            SignatureRejected::Malformed => "malformed",
+            SignatureRejected::Stale => "stale",
+            SignatureRejected::Replayed => "replayed",
+            SignatureRejected::Invalid => "invalid",
+            SignatureRejected::Unrecorded => "unrecorded",
+        }
+    }
+}
+
+/// Whether requests from `user` to the mapping `mapping_id` must be signed
+pub fn required(user: &User, mapping_id: Option<Uuid>) -> bool {
+    user.rpc_mappings
+        .iter()
+        .filter(|mapping| mapping_id.map_or(true, |id| id == mapping.id))
+        .any(|mapping| mapping.require_request_signature)
+}
+
+/// The message a client signs for `request`
+pub fn signed_message(request: &serde_json::Value, timestamp: u64, nonce: &str) -> Result<Vec<u8>> {
+    let mut request = canonical::sorted(request.clone());
+    if let Some(object) = request.as_object_mut() {
+        object.remove("jsonrpc");
+    }
+    let mut message = format!("{}\n{}\n{}\n", MESSAGE_PREFIX, timestamp, nonce).into_bytes();
+    message.extend(serde_json::to_vec(&request)?);
+    Ok(message)
+}
+
+/// Where the nonces of verified requests are remembered
+enum Nonces {
+    /// In this node's memory only
+    Local(parking_lot::Mutex<HashMap<(Uuid, String), Timestamp>>),
+    /// In storage shared with the other entry nodes, keyed `<user id>/<nonce>`
+    Shared(Collection<Timestamp>),
+}
+
+/// Checks request signatures and remembers the nonces they used
+pub struct RequestVerifier {
+    config: SigningConfig,
+    crypto: Arc<dyn Crypto + Send + Sync>,
+    nonces: Nonces,
+}
+
+impl RequestVerifier {
+    /// Create a verifier that has seen no nonces, remembering them in memory
+    pub fn new(config: SigningConfig, crypto: Arc<dyn Crypto + Send + Sync>) -> Self {
+        Self {
+            config,
+            crypto,
+            nonces: Nonces::Local(parking_lot::Mutex::new(HashMap::new())),
+        }
+    }
+    
+    /// Remember nonces in `storage`, shared with the other entry nodes using it
+    pub fn with_storage(mut self, storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        self.nonces = Nonces::Shared(Collection::new(storage, REQUEST_NONCES));
+        self
+    }
+    
+    /// How long a nonce is remembered: a request signed with it is fresh for that long
+    fn window(&self) -> Duration {
+        self.config.max_age * 2
+    }
+    
+    /// Forget the nonces of requests that are no longer fresh at `now`
+    pub async fn prune(&self, now: Timestamp) -> Result<()> {
+        let window = self.window();
+        match &self.nonces {
+            Nonces::Local(nonces) => nonces.lock().retain(|_, seen| now.saturating_duration_since(*seen) <= window),
+            Nonces::Shared(nonces) => {
+                for (key, seen) in nonces.scan("").await? {
+                    if now.saturating_duration_since(seen) > window {
+                        nonces.delete(&key, Precondition::Any).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    /// Check that `request` was signed by `user`'s wallet recently and not seen before
+    pub async fn verify(
+        &self,
+        user: &User,
+        signature: Option<&RequestSignature>,
+        request: &[u8],
+        now: Timestamp,
+    ) -> Result<(), SignatureRejected> {
+        let outcome = self.check(user, signature, request, now).await;
+        metrics::increment_counter!(
+            "darknode_request_signatures_total",
+            "outcome" => outcome.as_ref().err().map_or("valid", SignatureRejected::label)
+        );
+        outcome
+    }
+    
+    async fn check(
+        &self,
+        user: &User,
+        signature: Option<&RequestSignature>,
+        request: &[u8],
+        now: Timestamp,
+    ) -> Result<(), SignatureRejected> {
+        let signature = signature.ok_or(SignatureRejected::Missing)?;
+        if signature.nonce.is_empty() || signature.nonce.len() > MAX_NONCE_LEN {
+            return Err(SignatureRejected::Malformed);
+        }
+        let timestamp: u64 = signature.timestamp.parse().map_err(|_| SignatureRejected::Malformed)?;
+        let signed_at = Timestamp::from_secs(timestamp);
+        let offset = now.saturating_duration_since(signed_at).max(signed_at.saturating_duration_since(now));
+        if offset > self.config.max_age {
+            return Err(SignatureRejected::Stale);
+        }
+        
+        let wallet = base58_decode(&user.wallet_address)
+            .filter(|wallet| wallet.len() == 32)
+            .ok_or(SignatureRejected::Malformed)?;
+        if signature.signature.len() > MAX_SIGNATURE_LEN {
+            return Err(SignatureRejected::Malformed);
+        }
+        let bytes = base58_decode(&signature.signature)
+            .filter(|bytes| bytes.len() == 64)
+            .ok_or(SignatureRejected::Malformed)?;
+        let request: serde_json::Value = serde_json::from_slice(request).map_err(|_| SignatureRejected::Malformed)?;
+        let message = signed_message(&request, timestamp, &signature.nonce).map_err(|_| SignatureRejected::Malformed)?;
+        let valid = self
+            .crypto
+            .verify(&message, &bytes, &CryptoKey(wallet))
+            .await
+            .unwrap_or(false);
+        if !valid {
+            return Err(SignatureRejected::Invalid);
+        }
+        
+        // Only verified requests use up their nonce, so forgeries can't burn a client's nonces
+        self.use_nonce(user.id, &signature.nonce, now).await
+    }
+    
+    /// Record that `user` used `nonce` at `now`, unless they already did within the window
+    async fn use_nonce(&self, user: Uuid, nonce: &str, now: Timestamp) -> Result<(), SignatureRejected> {
+        let window = self.window();
+        let nonces = match &self.nonces {
+            Nonces::Local(nonces) => {
+                let mut nonces = nonces.lock();
+                nonces.retain(|_, seen| now.saturating_duration_since(*seen) <= window);
+                return match nonces.entry((user, nonce.to_string())) {
+                    std::collections::hash_map::Entry::Occupied(_) => Err(SignatureRejected::Replayed),
+                    std::collections::hash_map::Entry::Vacant(entry) => {
+                        entry.insert(now);
+                        Ok(())
+                    }
+                };
+            }
+            Nonces::Shared(nonces) => nonces,
+        };
+        
+        // A nonce stored longer ago than the window, and not yet pruned, may be used again
+        let key = format!("{}/{}", user, nonce);
+        let expected = match nonces.get(&key).await {
+            Ok(None) => Precondition::Absent,
+            Ok(Some((seen, version))) if now.saturating_duration_since(seen) > window => Precondition::Version(version),
+            Ok(Some(_)) => return Err(SignatureRejected::Replayed),
+            Err(e) => {
+                tracing::warn!("Failed to look up request nonce: {}", e);
+                return Err(SignatureRejected::Unrecorded);
+            }
+        };
+        match nonces.put(&key, &now, expected).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.is::<VersionConflict>() => Err(SignatureRejected::Replayed),
+            Err(e) => {
+                tracing::warn!("Failed to record request nonce: {}", e);
+                Err(SignatureRejected::Unrecorded)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::CryptoImpl;
+    use crate::storage::MemoryStorage;
+    use crate::wallets::WalletChain;
+    
+    const NOW: u64 = 1_700_000_000;
+    
+    fn user(wallet: &CryptoKey) -> User {
+        User {
+            id: Uuid::new_v4(),
+            wallet_address: bs58::encode(&wallet.0).into_string(),
+            wallet_chain: WalletChain::Solana,
+            api_key: "api-key".to_string(),
+            keys: Vec::new(),
+            active: true,
+            expires_at: None,
+            rpc_mappings: Vec::new(),
+            plan_id: None,
+            canary: false,
+            audit_consent: false,
+        }
+    }
+    
+    async fn signed(crypto: &CryptoImpl, wallet: &CryptoKey, request: &[u8], timestamp: u64, nonce: &str) -> RequestSignature {
+        let message = signed_message(&serde_json::from_slice(request).unwrap(), timestamp, nonce).unwrap();
+        RequestSignature {
+            signature: bs58::encode(crypto.sign(&message, wallet).await.unwrap()).into_string(),
+            timestamp: timestamp.to_string(),
+            nonce: nonce.to_string(),
+        }
+    }
+    
+    #[tokio::test]
+    async fn fresh_signatures_pass_once_and_stale_or_replayed_ones_are_refused() {
+        let crypto = Arc::new(CryptoImpl::new());
+        let (public, private) = crypto.generate_keypair().await.unwrap();
+        let user = user(&public);
+        let verifier = RequestVerifier::new(SigningConfig::default(), crypto.clone());
+        let request = br#"{"jsonrpc":"2.0","id":1,"method":"getSlot"}"#;
+        let now = Timestamp::from_secs(NOW);
+        
+        let signature = signed(&crypto, &private, request, NOW, "n-1").await;
+        assert_eq!(verifier.verify(&user, Some(&signature), request, now).await, Ok(()));
+        assert_eq!(verifier.verify(&user, Some(&signature), request, now).await, Err(SignatureRejected::Replayed));
+        
+        let stale = signed(&crypto, &private, request, NOW - 60, "n-2").await;
+        assert_eq!(verifier.verify(&user, Some(&stale), request, now).await, Err(SignatureRejected::Stale));
+        assert_eq!(verifier.verify(&user, None, request, now).await, Err(SignatureRejected::Missing));
+    }
+    
+    #[tokio::test]
+    async fn a_nonce_used_at_one_entry_node_is_refused_at_another() {
+        let crypto = Arc::new(CryptoImpl::new());
+        let (public, private) = crypto.generate_keypair().await.unwrap();
+        let user = user(&public);
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(MemoryStorage::new());
+        let first = RequestVerifier::new(SigningConfig::default(), crypto.clone()).with_storage(storage.clone());
+        let second = RequestVerifier::new(SigningConfig::default(), crypto.clone()).with_storage(storage.clone());
+        let request = br#"{"jsonrpc":"2.0","id":1,"method":"getSlot"}"#;
+        let now = Timestamp::from_secs(NOW);
+        
+        let signature = signed(&crypto, &private, request, NOW, "n-1").await;
+        assert_eq!(first.verify(&user, Some(&signature), request, now).await, Ok(()));
+        assert_eq!(second.verify(&user, Some(&signature), request, now).await, Err(SignatureRejected::Replayed));
+        
+        // Once no longer fresh, the nonce is forgotten
+        let later = now + SigningConfig::default().max_age * 3;
+        first.prune(later).await.unwrap();
+        assert!(Collection::<Timestamp>::new(storage, REQUEST_NONCES).all().await.unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn oversized_signatures_are_refused_before_decoding() {
+        let crypto = Arc::new(CryptoImpl::new());
+        let (public, _) = crypto.generate_keypair().await.unwrap();
+        let verifier = RequestVerifier::new(SigningConfig::default(), crypto);
+        let signature = RequestSignature {
+            signature: "1".repeat(100_000),
+            timestamp: NOW.to_string(),
+            nonce: "n-1".to_string(),
+        };
+        let request = br#"{"jsonrpc":"2.0","id":1,"method":"getSlot"}"#;
+        let refused = verifier
+            .verify(&user(&public), Some(&signature), request, Timestamp::from_secs(NOW))
+            .await;
+        assert_eq!(refused, Err(SignatureRejected::Malformed));
+    }
+}
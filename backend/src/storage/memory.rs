@@ -0,0 +1,92 @@
+//! Storage kept in the process
+
+use super::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// Collections held in memory, lost when the process exits
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    collections: parking_lot::RwLock<HashMap<String, BTreeMap<String, Versioned>>>,
+}
+
+impl MemoryStorage {
+    /// Create empty storage
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    fn check(collection: &str, key: &str, expected: Precondition, found: Option<u64>) -> Result<()> {
+        if expected.holds(found) {
+            return Ok(());
+        }
+        Err(VersionConflict {
+            collection: collection.to_string(),
+            key: key.to_string(),
+            expected,
+            found,
+        }
+        .into())
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn get(&self, collection: &str, key: &str) -> Result<Option<Versioned>> {
+        Ok(self
+            .collections
+            .read()
+            .get(collection)
+            .and_then(|entries| entries.get(key))
+            .cloned())
+    }
+    
+    async fn put(&self, collection: &str, key: &str, value: Vec<u8>, expected: Precondition) -> Result<u64> {
+        let mut collections = self.collections.write();
+        let entries = collections.entry(collection.to_string()).or_default();
+        let found = entries.get(key).map(|stored| stored.version);
+        Self::check(collection, key, expected, found)?;
+        let version = found.unwrap_or(0) + 1;
+        entries.insert(key.to_string(), Versioned { value, version });
+        Ok(version)
+    }
+    
+    async fn delete(&self, collection: &str, key: &str, expected: Precondition) -> Result<bool> {
+        let mut collections = self.collections.write();
+        let Some(entries) = collections.get_mut(collection) else {
+            Self::check(collection, key, expected, None)?;
+            return Ok(false);
+        };
+        let found = entries.get(key).map(|stored| stored.version);
+        Self::check(collection, key, expected, found)?;
+        Ok(entries.remove(key).is_some())
+    }
+    
+    async fn put_all(&self, writes: Vec<Write>) -> Result<()> {
+        let mut collections = self.collections.write();
+        for write in &writes {
+            let found = collections
+                .get(write.collection)
+                .and_then(|entries| entries.get(&write.key))
+                .map(|stored| stored.version);
+            Self::check(write.collection, &write.key, write.expected, found)?;
+        }
+        for write in writes {
+            let entries = collections.entry(write.collection.to_string()).or_default();
+            let version = entries.get(&write.key).map_or(0, |stored| stored.version) + 1;
+            entries.insert(write.key, Versioned { value: write.value, version });
+        }
+        Ok(())
+    }
+    
+    async fn scan(&self, collection: &str, prefix: &str) -> Result<Vec<(String, Versioned)>> {
+        let collections = self.collections.read();
+        let Some(entries) = collections.get(collection) else {
+            return Ok(Vec::new());
+        };
+        Ok(entries
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, stored)| (key.clone(), stored.clone()))
+            .collect())
+    }
+}
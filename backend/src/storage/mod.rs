@@ -0,0 +1,275 @@
+//! Where managers keep their state
+//!
+//! Deployments differ in what they can run: a single box doesn't want a database server,
+//! a large network wants one it already backs up. Managers are written once against
+//! [`Storage`], a set of named collections of keyed values, and the backend is picked in
+//! the configuration:
+//!
+//! - `memory`, the default, keeps everything in the process and loses it on restart
+//! - `sled` keeps it in an embedded database under `path`, with the `sled` feature
+//! - `postgres` keeps it in a PostgreSQL database at `url`, with the `postgres` feature
+//!
+//! Every value carries a version, starting at 1 and raised on each write. Writes may be
+//! made conditional on the version read, so two nodes updating the same record at once
+//! can't both win: the loser gets a [`VersionConflict`] and reads again, which
+//! [`Collection::update`] does for it. Values that must appear together, such as a record
+//! and the index entries pointing to it, are written at once with [`Storage::put_all`]. Scans return entries in byte order of their keys on
+//! every backend.
+
+use super::*;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+
+pub mod memory;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "sled")]
+pub mod sled;
+
+pub use memory::MemoryStorage;
+
+/// How many times [`Collection::update`] reads again after losing a race
+pub const MAX_UPDATE_ATTEMPTS: usize = 16;
+
+/// Which backend managers keep their state in
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case", deny_unknown_fields)]
+pub enum StorageConfig {
+    /// In the process, lost on restart
+    Memory,
+    /// In an embedded sled database
+    Sled {
+        /// Directory of the database, which only one process may have open
+        path: std::path::PathBuf,
+    },
+    /// In a PostgreSQL database
+    Postgres {
+        /// Connection URL, such as `postgres://darknode@localhost/darknode`
+        ///
+        /// Printed configs show only its scheme, host and port, see [`crate::config::redact_url`].
+        /// The password may be left out of it and set in `PGPASSWORD` instead.
+        url: String,
+        /// Most connections kept open to the database
+        #[serde(default = "default_max_connections")]
+        max_connections: u32,
+    },
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig::Memory
+    }
+}
+
+/// Connections to PostgreSQL kept open unless configured otherwise
+fn default_max_connections() -> u32 {
+    8
+}
+
+/// What a write expects to find under its key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    /// Anything or nothing
+    Any,
+    /// Nothing
+    Absent,
+    /// The value at this version
+    Version(u64),
+}
+
+impl Precondition {
+    /// Whether finding `found`, the version stored if any, lets the write through
+    pub fn holds(self, found: Option<u64>) -> bool {
+        match self {
+            Precondition::Any => true,
+            Precondition::Absent => found.is_none(),
+            Precondition::Version(version) => found == Some(version),
+        }
+    }
+}
+
+/// A write refused because the value changed since it was read
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{collection}/{key} was changed concurrently: expected {expected:?}, found version {found:?}")]
+pub struct VersionConflict {
+    /// The collection written to
+    pub collection: String,
+    /// The key written to
+    pub key: String,
+    /// What the write expected
+    pub expected: Precondition,
+    /// The version stored, if any
+    pub found: Option<u64>,
+}
+
+/// A stored value and its version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versioned {
+    /// The value's bytes
+    pub value: Vec<u8>,
+    /// Starts at 1 and goes up by one on every write
+    pub version: u64,
+}
+
+/// A write of one value, made together with others by [`Storage::put_all`]
+#[derive(Debug, Clone)]
+pub struct Write {
+    /// The collection written to
+    pub collection: &'static str,
+    /// The key written to
+    pub key: String,
+    /// The value's bytes
+    pub value: Vec<u8>,
+    /// What the write expects to find
+    pub expected: Precondition,
+}
+
+/// Named collections of versioned values, keyed by strings
+#[async_trait]
+pub trait Storage {
+    /// The value under `key` in `collection`, if any
+    async fn get(&self, collection: &str, key: &str) -> Result<Option<Versioned>>;
+    
+    /// Store `value` under `key` if `expected` holds, returning its new version
+    ///
+    /// Fails with a [`VersionConflict`] if it doesn't.
+    async fn put(&self, collection: &str, key: &str, value: Vec<u8>, expected: Precondition) -> Result<u64>;
+    
+    /// Remove the value under `key` if `expected` holds, returning whether there was one
+    ///
+    /// Fails with a [`VersionConflict`] if it doesn't.
+    async fn delete(&self, collection: &str, key: &str, expected: Precondition) -> Result<bool>;
+    
+    /// Make every one of `writes` if all their preconditions hold, or none of them
+    ///
+    /// Fails with the [`VersionConflict`] of a write whose precondition doesn't hold. No
+    /// two writes may be to the same key.
+    async fn put_all(&self, writes: Vec<Write>) -> Result<()>;
+    
+    /// Every entry of `collection` whose key starts with `prefix`, in byte order of the keys
+    async fn scan(&self, collection: &str, prefix: &str) -> Result<Vec<(String, Versioned)>>;
+}
+
+/// Open the backend `config` names
+///
+/// Fails if it names a backend this build doesn't include.
+pub async fn open(config: &StorageConfig) -> Result<Arc<dyn Storage + Send + Sync>> {
+    match config {
+        StorageConfig::Memory => Ok(Arc::new(MemoryStorage::new())),
+        #[cfg(feature = "sled")]
+        StorageConfig::Sled { path } => Ok(Arc::new(sled::SledStorage::open(path)?)),
+        #[cfg(feature = "postgres")]
+        StorageConfig::Postgres { url, max_connections } => {
+            Ok(Arc::new(postgres::PostgresStorage::connect(url, *max_connections).await?))
+        }
+        #[allow(unreachable_patterns)]
+        other => anyhow::bail!(
+            "Storage backend {:?} is not included in this build; enable its feature",
+            other
+        ),
+    }
+}
+
+/// A collection of `T`s stored as JSON
+pub struct Collection<T> {
+    storage: Arc<dyn Storage + Send + Sync>,
+    name: &'static str,
+    _values: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Collection<T> {
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            name: self.name,
+            _values: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Send> Collection<T> {
+    /// The collection `name` of `storage`
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>, name: &'static str) -> Self {
+        Self {
+            storage,
+            name,
+            _values: PhantomData,
+        }
+    }
+    
+    /// The value under `key` and its version, if any
+    pub async fn get(&self, key: &str) -> Result<Option<(T, u64)>> {
+        match self.storage.get(self.name, key).await? {
+            Some(stored) => Ok(Some((self.decode(key, &stored.value)?, stored.version))),
+            None => Ok(None),
+        }
+    }
+    
+    /// Store `value` under `key` if `expected` holds, returning its new version
+    pub async fn put(&self, key: &str, value: &T, expected: Precondition) -> Result<u64> {
+        self.storage.put(self.name, key, serde_json::to_vec(value)?, expected).await
+    }
+    
+    /// A write of `value` under `key` expecting `expected`, to make with others by [`Storage::put_all`]
+    pub fn write(&self, key: &str, value: &T, expected: Precondition) -> Result<Write> {
+        Ok(Write {
+            collection: self.name,
+            key: key.to_string(),
+            value: serde_json::to_vec(value)?,
+            expected,
+        })
+    }
+    
+    /// Remove the value under `key` if `expected` holds, returning whether there was one
+    pub async fn delete(&self, key: &str, expected: Precondition) -> Result<bool> {
+        self.storage.delete(self.name, key, expected).await
+    }
+    
+    /// Every value whose key starts with `prefix`, in key order
+    pub async fn scan(&self, prefix: &str) -> Result<Vec<(String, T)>> {
+        let mut values = Vec::new();
+        for (key, stored) in self.storage.scan(self.name, prefix).await? {
+            let value = self.decode(&key, &stored.value)?;
+            values.push((key, value));
+        }
+        Ok(values)
+    }
+    
+    /// Every value in the collection, in key order
+    pub async fn all(&self) -> Result<Vec<T>> {
+        Ok(self.scan("").await?.into_iter().map(|(_, value)| value).collect())
+    }
+    
+    /// Replace the value under `key` with what `change` makes of it, retrying on conflicts
+    ///
+    /// `change` is given the value stored, if any, and returns the value to store, or
+    /// `None` to leave it as it is; it may run several times if other writers get there
+    /// first. Returns what was stored, or `None` if nothing was.
+    pub async fn update<F>(&self, key: &str, mut change: F) -> Result<Option<T>>
+    where
+        F: FnMut(Option<T>) -> Result<Option<T>> + Send,
+    {
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let (current, expected) = match self.get(key).await? {
+                Some((value, version)) => (Some(value), Precondition::Version(version)),
+                None => (None, Precondition::Absent),
+            };
+            let Some(changed) = change(current)? else {
+                return Ok(None);
+            };
+            match self.put(key, &changed, expected).await {
+                Ok(_) => return Ok(Some(changed)),
+                Err(e) if e.is::<VersionConflict>() => {
+                    metrics::increment_counter!("darknode_storage_conflicts_total", "collection" => self.name);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        anyhow::bail!("Gave up updating {}/{} after {} conflicting writes", self.name, key, MAX_UPDATE_ATTEMPTS)
+    }
+    
+    fn decode(&self, key: &str, value: &[u8]) -> Result<T> {
+        serde_json::from_slice(value)
+            .map_err(|e| anyhow::anyhow!("Stored value {}/{} is unreadable: {}", self.name, key, e))
+    }
+}
@@ -0,0 +1,180 @@
+//! Storage in a PostgreSQL database
+//!
+//! Every collection shares one table, created on connecting if it doesn't exist. Keys are
+//! compared in the "C" collation, so scans come back in byte order as on the other
+//! backends. Conditional writes check the version in the statement that writes, so they
+//! need no transaction; writes made together share one.
+
+use super::*;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::Row;
+
+const CREATE_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS darknode_storage (
+        collection TEXT NOT NULL,
+        key TEXT COLLATE "C" NOT NULL,
+        value BYTEA NOT NULL,
+        version BIGINT NOT NULL,
+        PRIMARY KEY (collection, key)
+    )
+"#;
+
+/// Collections in a PostgreSQL database
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+impl PostgresStorage {
+    /// Connect to the database at `url`, creating the table if needed
+    pub async fn connect(url: &str, max_connections: u32) -> Result<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(max_connections)
+            .connect(url)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to connect to PostgreSQL: {}", e))?;
+        sqlx::query(CREATE_TABLE).execute(&pool).await?;
+        Ok(Self { pool })
+    }
+    
+    /// The version stored under `key`, if any
+    async fn version(&self, collection: &str, key: &str) -> Result<Option<u64>> {
+        let row = sqlx::query("SELECT version FROM darknode_storage WHERE collection = $1 AND key = $2")
+            .bind(collection)
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| row.get::<i64, _>("version") as u64))
+    }
+    
+    /// The statement storing a value expecting `expected`, bound to the collection, key,
+    /// value and version expected in that order
+    fn put_statement(expected: Precondition) -> &'static str {
+        match expected {
+            Precondition::Any => {
+                "INSERT INTO darknode_storage (collection, key, value, version) VALUES ($1, $2, $3, 1)
+                 ON CONFLICT (collection, key) DO UPDATE
+                 SET value = EXCLUDED.value, version = darknode_storage.version + 1
+                 RETURNING version"
+            }
+            Precondition::Absent => {
+                "INSERT INTO darknode_storage (collection, key, value, version) VALUES ($1, $2, $3, 1)
+                 ON CONFLICT (collection, key) DO NOTHING
+                 RETURNING version"
+            }
+            Precondition::Version(_) => {
+                "UPDATE darknode_storage SET value = $3, version = version + 1
+                 WHERE collection = $1 AND key = $2 AND version = $4
+                 RETURNING version"
+            }
+        }
+    }
+    
+    /// The conflict of a write expecting `expected`, with the version it found
+    async fn conflict(&self, collection: &str, key: &str, expected: Precondition) -> Result<anyhow::Error> {
+        Ok(VersionConflict {
+            collection: collection.to_string(),
+            key: key.to_string(),
+            expected,
+            found: self.version(collection, key).await?,
+        }
+        .into())
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn get(&self, collection: &str, key: &str) -> Result<Option<Versioned>> {
+        let row = sqlx::query("SELECT value, version FROM darknode_storage WHERE collection = $1 AND key = $2")
+            .bind(collection)
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| Versioned {
+            value: row.get("value"),
+            version: row.get::<i64, _>("version") as u64,
+        }))
+    }
+    
+    async fn put(&self, collection: &str, key: &str, value: Vec<u8>, expected: Precondition) -> Result<u64> {
+        let mut query = sqlx::query(Self::put_statement(expected)).bind(collection).bind(key).bind(value);
+        if let Precondition::Version(version) = expected {
+            query = query.bind(version as i64);
+        }
+        let row = query.fetch_optional(&self.pool).await?;
+        match row {
+            Some(row) => Ok(row.get::<i64, _>("version") as u64),
+            None => Err(self.conflict(collection, key, expected).await?),
+        }
+    }
+    
+    async fn delete(&self, collection: &str, key: &str, expected: Precondition) -> Result<bool> {
+        let deleted = match expected {
+            Precondition::Any => sqlx::query("DELETE FROM darknode_storage WHERE collection = $1 AND key = $2")
+                .bind(collection)
+                .bind(key)
+                .execute(&self.pool)
+                .await?
+                .rows_affected(),
+            Precondition::Absent => match self.version(collection, key).await? {
+                Some(_) => return Err(self.conflict(collection, key, expected).await?),
+                None => 0,
+            },
+            Precondition::Version(version) => {
+                let deleted = sqlx::query("DELETE FROM darknode_storage WHERE collection = $1 AND key = $2 AND version = $3")
+                    .bind(collection)
+                    .bind(key)
+                    .bind(version as i64)
+                    .execute(&self.pool)
+                    .await?
+                    .rows_affected();
+                if deleted == 0 {
+                    return Err(self.conflict(collection, key, expected).await?);
+                }
+                deleted
+            }
+        };
+        Ok(deleted > 0)
+    }
+    
+    async fn put_all(&self, writes: Vec<Write>) -> Result<()> {
+        // Dropping the transaction unfinished rolls it back
+        let mut transaction = self.pool.begin().await?;
+        for write in writes {
+            let mut query = sqlx::query(Self::put_statement(write.expected))
+                .bind(write.collection)
+                .bind(&write.key)
+                .bind(write.value);
+            if let Precondition::Version(version) = write.expected {
+                query = query.bind(version as i64);
+            }
+            if query.fetch_optional(&mut *transaction).await?.is_none() {
+                drop(transaction);
+                return Err(self.conflict(write.collection, &write.key, write.expected).await?);
+            }
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+    
+    async fn scan(&self, collection: &str, prefix: &str) -> Result<Vec<(String, Versioned)>> {
+        let rows = sqlx::query(
+            "SELECT key, value, version FROM darknode_storage
+             WHERE collection = $1 AND starts_with(key, $2)
+             ORDER BY key",
+        )
+        .bind(collection)
+        .bind(prefix)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let stored = Versioned {
+                    value: row.get("value"),
+                    version: row.get::<i64, _>("version") as u64,
+                };
+                (row.get("key"), stored)
+            })
+            .collect())
+    }
+}
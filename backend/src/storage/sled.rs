@@ -0,0 +1,166 @@
+//! Storage in an embedded sled database
+//!
+//! Each collection is a tree, and each value is stored after its version as eight
+//! big-endian bytes. Conditional writes are compare-and-swaps on the stored bytes, so a
+//! write that raced another fails rather than overwriting it. Writes made together run in
+//! one transaction over the trees they touch.
+
+use super::*;
+use ::sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use std::path::Path;
+
+/// Bytes of the version stored ahead of each value
+const VERSION_LEN: usize = 8;
+
+/// Collections in a sled database on disk
+pub struct SledStorage {
+    db: ::sled::Db,
+}
+
+impl SledStorage {
+    /// Open the database at `path`, creating it if needed
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = ::sled::open(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open sled database at {}: {}", path.display(), e))?;
+        Ok(Self { db })
+    }
+}
+
+/// The version and value stored in `bytes`
+fn decode(bytes: &[u8]) -> Result<Versioned> {
+    if bytes.len() < VERSION_LEN {
+        anyhow::bail!("Stored entry of {} bytes has no version", bytes.len());
+    }
+    let (version, value) = bytes.split_at(VERSION_LEN);
+    Ok(Versioned {
+        value: value.to_vec(),
+        version: u64::from_be_bytes(version.try_into()?),
+    })
+}
+
+/// The bytes storing `value` at `version`
+fn encode(value: &[u8], version: u64) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(VERSION_LEN + value.len());
+    bytes.extend_from_slice(&version.to_be_bytes());
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+/// The version stored in `bytes`, if any
+fn version_of(bytes: Option<&::sled::IVec>) -> Result<Option<u64>> {
+    bytes.map(|bytes| decode(bytes).map(|stored| stored.version)).transpose()
+}
+
+/// Flush `tree` to disk
+///
+/// sled's `flush_async` can wait forever when flushes overlap, as concurrent writes make
+/// them, so the blocking flush is run off the runtime's threads instead.
+async fn flush(tree: &::sled::Tree) -> Result<()> {
+    let tree = tree.clone();
+    tokio::task::spawn_blocking(move || tree.flush()).await??;
+    Ok(())
+}
+
+fn conflict(collection: &str, key: &str, expected: Precondition, found: Option<u64>) -> anyhow::Error {
+    VersionConflict {
+        collection: collection.to_string(),
+        key: key.to_string(),
+        expected,
+        found,
+    }
+    .into()
+}
+
+#[async_trait]
+impl Storage for SledStorage {
+    async fn get(&self, collection: &str, key: &str) -> Result<Option<Versioned>> {
+        let tree = self.db.open_tree(collection)?;
+        tree.get(key.as_bytes())?.map(|bytes| decode(&bytes)).transpose()
+    }
+    
+    async fn put(&self, collection: &str, key: &str, value: Vec<u8>, expected: Precondition) -> Result<u64> {
+        let tree = self.db.open_tree(collection)?;
+        loop {
+            let current = tree.get(key.as_bytes())?;
+            let found = version_of(current.as_ref())?;
+            if !expected.holds(found) {
+                return Err(conflict(collection, key, expected, found));
+            }
+            let version = found.unwrap_or(0) + 1;
+            let swapped = tree.compare_and_swap(key.as_bytes(), current, Some(encode(&value, version)))?;
+            match swapped {
+                Ok(()) => {
+                    flush(&tree).await?;
+                    return Ok(version);
+                }
+                // Another writer got there first: an unconditional write goes on top of
+                // theirs, a conditional one no longer holds
+                Err(swap) if expected == Precondition::Any => drop(swap),
+                Err(swap) => return Err(conflict(collection, key, expected, version_of(swap.current.as_ref())?)),
+            }
+        }
+    }
+    
+    async fn delete(&self, collection: &str, key: &str, expected: Precondition) -> Result<bool> {
+        let tree = self.db.open_tree(collection)?;
+        loop {
+            let current = tree.get(key.as_bytes())?;
+            let found = version_of(current.as_ref())?;
+            if !expected.holds(found) {
+                return Err(conflict(collection, key, expected, found));
+            }
+            if current.is_none() {
+                return Ok(false);
+            }
+            match tree.compare_and_swap(key.as_bytes(), current, None::<Vec<u8>>)? {
+                Ok(()) => {
+                    flush(&tree).await?;
+                    return Ok(true);
+                }
+                Err(swap) if expected == Precondition::Any => drop(swap),
+                Err(swap) => return Err(conflict(collection, key, expected, version_of(swap.current.as_ref())?)),
+            }
+        }
+    }
+    
+    async fn put_all(&self, writes: Vec<Write>) -> Result<()> {
+        let mut names: Vec<&str> = writes.iter().map(|write| write.collection).collect();
+        names.sort_unstable();
+        names.dedup();
+        let trees = names
+            .iter()
+            .map(|name| self.db.open_tree(name))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let committed = trees[..].transaction(|views| {
+            for write in &writes {
+                let view = &views[names.binary_search(&write.collection).expect("every collection has a tree")];
+                let found = version_of(view.get(write.key.as_bytes())?.as_ref()).map_err(ConflictableTransactionError::Abort)?;
+                if !write.expected.holds(found) {
+                    let conflict = conflict(write.collection, &write.key, write.expected, found);
+                    return Err(ConflictableTransactionError::Abort(conflict));
+                }
+                view.insert(write.key.as_bytes(), encode(&write.value, found.unwrap_or(0) + 1))?;
+            }
+            Ok(())
+        });
+        match committed {
+            Ok(()) => {}
+            Err(TransactionError::Abort(e)) => return Err(e),
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+        }
+        for tree in &trees {
+            flush(tree).await?;
+        }
+        Ok(())
+    }
+    
+    async fn scan(&self, collection: &str, prefix: &str) -> Result<Vec<(String, Versioned)>> {
+        let tree = self.db.open_tree(collection)?;
+        let mut entries = Vec::new();
+        for entry in tree.scan_prefix(prefix.as_bytes()) {
+            let (key, bytes) = entry?;
+            entries.push((String::from_utf8(key.to_vec())?, decode(&bytes)?));
+        }
+        Ok(entries)
+    }
+}
@@ -0,0 +1,548 @@
+//! Restoring the id and adding the envelope of responses streamed to clients in pieces
+//!
+//! Raw account data and relayed transactions are passed on to the client as they come off
+//! the circuit, see [`crate::entry_node::is_streamable`], so a response may be sent before
+//! the whole of it has arrived. Pieces holding whole JSON documents, such as the status
+//! notifications of relayed transactions, are prepared like any other response. A response
+//! arriving in several pieces is scanned instead: the id of the response object, wherever
+//! it comes in it, is held back until the sanitizer has put the client's id in its place,
+//! and the DarkNode extension is added as the object closes, or merged into the one the
+//! exit node set. Everything else flows through as it arrives.
+//!
+//! Should the circuit fail once part of a response was sent, the response is closed where
+//! it was cut off and given an `error` member, so the client gets a complete JSON document
+//! saying the result is incomplete instead of a body that just stops.
+
+use super::*;
+use super::methods::{self, EXTENSION_KEY};
+use super::traits::RequestSanitizer;
+
+/// Most bytes of a member name of the response object that are kept to compare
+const MAX_KEY: usize = 16;
+
+/// JSON-RPC code of a response cut off by a failure partway
+pub const INCOMPLETE_CODE: i64 = -32603;
+
+/// What the scanner makes of a piece of a response
+#[derive(Debug, Clone, PartialEq)]
+pub enum Piece {
+    /// Bytes to send as they are
+    Bytes(Vec<u8>),
+    /// The id of the response, to be restored before it is sent
+    Id(serde_json::Value),
+}
+
+/// Where a byte stands to the id of the response
+enum IdScan {
+    /// Outside it
+    Elsewhere,
+    /// Within it, held back
+    Held,
+    /// Past its end, `last` if the byte was its last
+    Ended { id: serde_json::Value, last: bool },
+}
+
+/// An open object or array, and for objects whether a member name comes next
+#[derive(Debug, Clone, Copy)]
+struct Open {
+    object: bool,
+    expects_key: bool,
+}
+
+/// Scans a JSON-RPC response arriving in pieces, see the module docs
+#[derive(Debug)]
+pub struct ResponseScanner {
+    /// The members of the DarkNode extension to add, serialized without braces
+    extension: Vec<u8>,
+    stack: Vec<Open>,
+    in_string: bool,
+    escaped: bool,
+    /// Whether the string being read is a member name
+    in_key: bool,
+    /// The member name being read at the top, if it is
+    key: Vec<u8>,
+    /// The last member name read at the top
+    last_key: Vec<u8>,
+    /// A member name was read but its colon not yet
+    awaits_colon: bool,
+    /// The last byte outside strings and whitespace
+    last: u8,
+    /// The bare number or literal being read
+    literal: Vec<u8>,
+    /// The id being held back, once its value started
+    id: Option<Vec<u8>>,
+    /// The id is the next value
+    id_next: bool,
+    /// The extension was merged into the exit node's, and whether a comma may be needed after it
+    merged: bool,
+    comma_pending: bool,
+    /// Members of the response object so far
+    members: usize,
+    has_id: bool,
+    closed: bool,
+}
+
+impl ResponseScanner {
+    /// Scan a response, adding `extension` to its DarkNode extension
+    pub fn new(extension: &serde_json::Map<String, serde_json::Value>) -> Self {
+        let members = serde_json::to_vec(extension).unwrap_or_else(|_| b"{}".to_vec());
+        Self {
+            extension: members[1..members.len() - 1].to_vec(),
+            stack: Vec::new(),
+            in_string: false,
+            escaped: false,
+            in_key: false,
+            key: Vec::new(),
+            last_key: Vec::new(),
+            awaits_colon: false,
+            last: 0,
+            literal: Vec::new(),
+            id: None,
+            id_next: false,
+            merged: false,
+            comma_pending: false,
+            members: 0,
+            has_id: false,
+            closed: false,
+        }
+    }
+    
+    /// Whether the response object has closed
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+    
+    /// Whether anything of the response was scanned yet
+    pub fn is_started(&self) -> bool {
+        !self.stack.is_empty() || self.closed
+    }
+    
+    /// Scan the next piece of the response
+    pub fn push(&mut self, piece: &[u8]) -> Vec<Piece> {
+        let mut pieces = Vec::new();
+        let mut out = Vec::with_capacity(piece.len() + self.extension.len());
+        for &byte in piece {
+            match self.scan_id(byte) {
+                IdScan::Elsewhere => {}
+                IdScan::Held => continue,
+                IdScan::Ended { id, last } => {
+                    flush(&mut pieces, &mut out);
+                    pieces.push(Piece::Id(id));
+                    // A bare id ends at the byte after it, which is scanned as usual
+                    if last {
+                        continue;
+                    }
+                }
+            }
+            self.scan(byte, &mut out);
+        }
+        flush(&mut pieces, &mut out);
+        pieces
+    }
+    
+    /// Close the response after a failure cut it off, returning the bytes ending it with
+    /// `error`, or `None` if none of it was sent or all of it was
+    pub fn abort(&mut self, error: &str) -> Option<Vec<u8>> {
+        if self.closed || self.stack.is_empty() {
+            return None;
+        }
+        let mut out = Vec::new();
+        
+        // Finish the value cut off: an id held back goes out as null, an open string is
+        // closed, and a literal or number is completed
+        if self.id.take().is_some() {
+            self.in_string = false;
+            out.extend_from_slice(b"null");
+            self.last = b'l';
+        } else if self.in_string {
+            out.push(b'"');
+            self.in_string = false;
+            if self.in_key {
+                out.extend_from_slice(b":null");
+            }
+            self.last = b'"';
+        } else if !self.literal.is_empty() {
+            let rest = ["true", "false", "null"]
+                .iter()
+                .find(|word| word.as_bytes().starts_with(&self.literal))
+                .map(|word| &word.as_bytes()[self.literal.len()..]);
+            match rest {
+                Some(rest) => out.extend_from_slice(rest),
+                None if matches!(self.last, b'.' | b'e' | b'E' | b'+' | b'-') => out.push(b'0'),
+                None => {}
+            }
+        } else if self.awaits_colon {
+            out.extend_from_slice(b":null");
+        } else {
+            match (self.last, self.stack.last().map_or(false, |open| open.object)) {
+                (b':', _) => out.extend_from_slice(b"null"),
+                (b',', true) => out.extend_from_slice(b"\"\":null"),
+                (b',', false) => out.extend_from_slice(b"null"),
+                _ => {}
+            }
+        }
+        
+        // Close everything inside the response object, and the object itself with the error
+        while self.stack.len() > 1 {
+            let open = self.stack.pop().unwrap_or(Open { object: false, expects_key: false });
+            out.push(if open.object { b'}' } else { b']' });
+        }
+        if self.members > 0 {
+            out.push(b',');
+        }
+        let error = serde_json::json!({ "code": INCOMPLETE_CODE, "message": error });
+        out.extend_from_slice(b"\"error\":");
+        out.extend_from_slice(&serde_json::to_vec(&error).unwrap_or_default());
+        if !self.merged && !self.extension.is_empty() {
+            out.push(b',');
+            self.extend_with_extension(&mut out);
+        }
+        out.push(b'}');
+        self.stack.clear();
+        self.closed = true;
+        Some(out)
+    }
+    
+    /// Hold back the bytes of the id, returning it once it ends
+    fn scan_id(&mut self, byte: u8) -> IdScan {
+        if self.id_next && !byte.is_ascii_whitespace() {
+            self.id_next = false;
+            self.has_id = true;
+            self.id = Some(vec![byte]);
+            self.in_string = byte == b'"';
+            return IdScan::Held;
+        }
+        let Some(id) = self.id.as_mut() else { return IdScan::Elsewhere };
+        let ended = if self.in_string {
+            id.push(byte);
+            match (self.escaped, byte) {
+                (true, _) => {
+                    self.escaped = false;
+                    false
+                }
+                (false, b'\\') => {
+                    self.escaped = true;
+                    false
+                }
+                (false, b'"') => true,
+                _ => false,
+            }
+        } else if byte.is_ascii_whitespace() || byte == b',' || byte == b'}' {
+            true
+        } else {
+            id.push(byte);
+            false
+        };
+        if !ended {
+            return IdScan::Held;
+        }
+        let last = self.in_string;
+        let id = self.id.take().unwrap_or_default();
+        self.in_string = false;
+        self.last = b'l';
+        IdScan::Ended {
+            id: serde_json::from_slice(&id).unwrap_or(serde_json::Value::Null),
+            last,
+        }
+    }
+    
+    /// Pass on one byte, tracking where in the response it is
+    fn scan(&mut self, byte: u8, out: &mut Vec<u8>) {
+        if self.closed {
+            out.push(byte);
+            return;
+        }
+        if self.in_string {
+            out.push(byte);
+            if self.escaped {
+                self.escaped = false;
+            } else if byte == b'\\' {
+                self.escaped = true;
+            } else if byte == b'"' {
+                self.in_string = false;
+                self.last = b'"';
+                if self.in_key {
+                    self.in_key = false;
+                    self.awaits_colon = true;
+                    if self.stack.len() == 1 {
+                        self.last_key = std::mem::take(&mut self.key);
+                    }
+                }
+            } else if self.in_key && self.stack.len() == 1 && self.key.len() < MAX_KEY {
+                self.key.push(byte);
+            }
+            return;
+        }
+        if byte.is_ascii_whitespace() {
+            out.push(byte);
+            return;
+        }
+        if self.comma_pending {
+            self.comma_pending = false;
+            if byte != b'}' {
+                out.push(b',');
+            }
+        }
+        if !matches!(byte, b'"' | b'{' | b'[' | b'}' | b']' | b',' | b':') {
+            self.literal.push(byte);
+            self.last = byte;
+            out.push(byte);
+            return;
+        }
+        self.literal.clear();
+        self.last = byte;
+        match byte {
+            b'"' => {
+                self.in_string = true;
+                if let Some(open) = self.stack.last_mut().filter(|open| open.object && open.expects_key) {
+                    open.expects_key = false;
+                    self.in_key = true;
+                    self.key.clear();
+                    if self.stack.len() == 1 {
+                        self.members += 1;
+                    }
+                }
+                out.push(byte);
+            }
+            b':' => {
+                self.awaits_colon = false;
+                out.push(byte);
+                if self.stack.len() == 1 && self.last_key == b"id" {
+                    self.id_next = true;
+                }
+            }
+            b'{' | b'[' => {
+                let object = byte == b'{';
+                out.push(byte);
+                let merging = object && self.stack.len() == 1 && self.last_key == EXTENSION_KEY.as_bytes();
+                self.stack.push(Open { object, expects_key: object });
+                if merging && !self.extension.is_empty() {
+                    out.extend_from_slice(&self.extension);
+                    self.merged = true;
+                    self.comma_pending = true;
+                }
+            }
+            b',' => {
+                if let Some(open) = self.stack.last_mut() {
+                    open.expects_key = open.object;
+                }
+                out.push(byte);
+            }
+            _ => {
+                if self.stack.len() == 1 && byte == b'}' && self.has_id && !self.merged && !self.extension.is_empty() {
+                    if self.members > 0 {
+                        out.push(b',');
+                    }
+                    self.extend_with_extension(out);
+                }
+                self.stack.pop();
+                out.push(byte);
+                if self.stack.is_empty() {
+                    self.closed = true;
+                }
+            }
+        }
+    }
+    
+    /// Write the DarkNode extension as a member of the response object
+    fn extend_with_extension(&mut self, out: &mut Vec<u8>) {
+        out.push(b'"');
+        out.extend_from_slice(EXTENSION_KEY.as_bytes());
+        out.extend_from_slice(b"\":{");
+        out.extend_from_slice(&self.extension);
+        out.push(b'}');
+        self.merged = true;
+    }
+}
+
+/// Move the bytes gathered so far into `pieces`
+fn flush(pieces: &mut Vec<Piece>, out: &mut Vec<u8>) {
+    if !out.is_empty() {
+        pieces.push(Piece::Bytes(std::mem::take(out)));
+    }
+}
+
+/// The pieces of one streamed response being prepared for the client
+pub struct StreamedResponse {
+    id: serde_json::Value,
+    extension: serde_json::Map<String, serde_json::Value>,
+    scanner: Option<ResponseScanner>,
+    /// Whether anything was sent, and whether the response itself was
+    sent: bool,
+    answered: bool,
+}
+
+impl StreamedResponse {
+    /// Prepare the response to the request with id `id`, giving it `extension` as its
+    /// DarkNode extension
+    pub fn new(id: serde_json::Value, extension: serde_json::Map<String, serde_json::Value>) -> Self {
+        Self {
+            id,
+            extension,
+            scanner: None,
+            sent: false,
+            answered: false,
+        }
+    }
+    
+    /// Prepare the next piece of the response for the client
+    pub async fn prepare(&mut self, sanitizer: &(dyn RequestSanitizer + Send + Sync), data: &[u8]) -> Result<Vec<u8>> {
+        self.sent = true;
+        if self.scanner.is_none() {
+            let end = data.len() - data.iter().rev().take_while(|byte| byte.is_ascii_whitespace()).count();
+            match serde_json::from_slice::<serde_json::Value>(&data[..end]) {
+                Ok(document) if is_response(&document) => {
+                    self.answered = true;
+                    let mut prepared: serde_json::Value = serde_json::from_slice(&sanitizer.prepare_response(&data[..end]).await?)?;
+                    for (name, value) in &self.extension {
+                        methods::set_extension(&mut prepared, name, value.clone());
+                    }
+                    let mut prepared = serde_json::to_vec(&prepared)?;
+                    prepared.extend_from_slice(&data[end..]);
+                    return Ok(prepared);
+                }
+                Ok(_) => return sanitizer.prepare_response_chunk(data).await,
+                Err(_) => self.scanner = Some(ResponseScanner::new(&self.extension)),
+            }
+        }
+        
+        let Some(scanner) = self.scanner.as_mut() else { return Ok(data.to_vec()) };
+        let mut prepared = Vec::with_capacity(data.len());
+        for piece in scanner.push(data) {
+            match piece {
+                Piece::Bytes(bytes) => prepared.extend_from_slice(&bytes),
+                Piece::Id(id) => prepared.extend_from_slice(&serde_json::to_vec(&restore_id(sanitizer, id).await)?),
+            }
+        }
+        if scanner.is_closed() {
+            self.scanner = None;
+            self.answered = true;
+        }
+        Ok(prepared)
+    }
+    
+    /// The bytes ending a response cut off by `err`, if anything was sent before
+    ///
+    /// A response cut off partway is closed with an error, and one that never started after
+    /// notifications were sent is sent as an error response of its own.
+    pub fn abort(&mut self, err: &anyhow::Error) -> Option<Vec<u8>> {
+        let message = format!("Response incomplete: {}", err);
+        if let Some(scanner) = self.scanner.as_mut() {
+            return scanner.abort(&message);
+        }
+        if !self.sent || self.answered {
+            return None;
+        }
+        let mut response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": self.id,
+            "error": { "code": INCOMPLETE_CODE, "message": message },
+        });
+        for (name, value) in &self.extension {
+            methods::set_extension(&mut response, name, value.clone());
+        }
+        let mut response = serde_json::to_vec(&response).ok()?;
+        response.push(b'\n');
+        Some(response)
+    }
+}
+
+/// Whether a JSON document is a response rather than a notification
+fn is_response(document: &serde_json::Value) -> bool {
+    document.get("id").is_some() && document.get("method").is_none()
+}
+
+/// The client's id for the id a response came back with
+async fn restore_id(sanitizer: &(dyn RequestSanitizer + Send + Sync), id: serde_json::Value) -> serde_json::Value {
+    let response = serde_json::to_vec(&serde_json::json!({ "id": id })).unwrap_or_default();
+    match sanitizer.prepare_response(&response).await {
+        Ok(restored) => serde_json::from_slice::<serde_json::Value>(&restored)
+            .ok()
+            .and_then(|mut restored| restored.get_mut("id").map(serde_json::Value::take))
+            .unwrap_or_default(),
+        Err(_) => serde_json::Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    /// Scan `pieces`, putting `"client"` in place of the id, and the bytes sent
+    fn scan(pieces: &[&[u8]], abort: Option<&str>) -> Vec<u8> {
+        let mut extension = serde_json::Map::new();
+        extension.insert("trace".to_string(), json!("t1"));
+        let mut scanner = ResponseScanner::new(&extension);
+        let mut sent = Vec::new();
+        for piece in pieces {
+            for piece in scanner.push(piece) {
+                match piece {
+                    Piece::Bytes(bytes) => sent.extend(bytes),
+                    Piece::Id(id) => {
+                        assert_eq!(id, json!(7));
+                        sent.extend(b"\"client\"");
+                    }
+                }
+            }
+        }
+        if let Some(error) = abort {
+            sent.extend(scanner.abort(error).expect("part of the response was sent"));
+        }
+        sent
+    }
+    
+    fn parsed(sent: &[u8]) -> serde_json::Value {
+        serde_json::from_slice(sent).unwrap_or_else(|e| panic!("{} in {}", e, String::from_utf8_lossy(sent)))
+    }
+    
+    #[test]
+    fn restores_the_id_wherever_it_comes_and_however_it_is_split() {
+        let documents: [&[&[u8]]; 3] = [
+            &[b"{\"jsonrpc\":\"2.0\",\"id\":7,", b"\"result\":[1,2,3]}"],
+            &[b"{\"jsonrpc\":\"2.0\",\"result\":{\"a\":\"}\"},", b"\"i", b"d\":", b"7", b"}"],
+            &[b"{\"jsonrpc\":\"2.0\",\"result\":\"x\",\"id\"", b":7}"],
+        ];
+        for pieces in documents {
+            let response = parsed(&scan(pieces, None));
+            assert_eq!(response["id"], json!("client"));
+            assert_eq!(response[EXTENSION_KEY]["trace"], json!("t1"));
+        }
+    }
+    
+    #[test]
+    fn merges_into_the_extension_the_exit_set() {
+        let response = parsed(&scan(&[b"{\"id\":7,\"darknode\":{\"exit\":", b"\"e\"},\"result\":null}"], None));
+        assert_eq!(response[EXTENSION_KEY], json!({"exit": "e", "trace": "t1"}));
+        assert_eq!(response["result"], json!(null));
+    }
+    
+    #[test]
+    fn closes_a_cut_off_response_with_an_error() {
+        let cut_offs: [&[u8]; 4] = [
+            b"{\"id\":7,\"result\":{\"data\":[\"abc",
+            b"{\"id\":7,\"result\":[1,2,",
+            b"{\"id\":7,\"result\":",
+            b"{\"id\":7,\"result\":tr",
+        ];
+        for cut_off in cut_offs {
+            let response = parsed(&scan(&[cut_off], Some("circuit failed")));
+            assert_eq!(response["id"], json!("client"));
+            assert_eq!(response["error"]["code"], json!(INCOMPLETE_CODE));
+            assert_eq!(response["error"]["message"], json!("circuit failed"));
+            assert_eq!(response[EXTENSION_KEY]["trace"], json!("t1"));
+        }
+    }
+    
+    #[test]
+    fn gives_an_error_response_when_only_notifications_were_sent() {
+        let mut streamed = StreamedResponse::new(json!(7), serde_json::Map::new());
+        assert!(streamed.abort(&anyhow::anyhow!("failed")).is_none());
+        streamed.sent = true;
+        let response = parsed(&streamed.abort(&anyhow::anyhow!("failed")).unwrap());
+        assert_eq!(response["id"], json!(7));
+        assert_eq!(response["error"]["code"], json!(INCOMPLETE_CODE));
+        streamed.answered = true;
+        assert!(streamed.abort(&anyhow::anyhow!("failed")).is_none());
+    }
+}
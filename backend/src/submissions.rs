@@ -0,0 +1,394 @@
+//! Providers proposed by the community, reviewed before they serve anyone
+//!
+//! Anyone holding a wallet can propose a public RPC provider without operator access, by
+//! sending a [`ProviderProposal`] to the coordinator signed with that wallet. The provider
+//! is registered [`Pending`](ProviderState::Pending) and left to the probe scheduler, which
+//! moves it to [`Trial`](ProviderState::Trial) on its first probe and keeps probing it like
+//! any other provider, building up its success rate and latency. Once the trial period is
+//! over an operator approves it, making it [`Active`](ProviderState::Active), or rejects it
+//! with a reason, which stops its probes. Only active providers are ever selected for user
+//! traffic, so nothing is served by a provider until it has been approved.
+//!
+//! The signed message is `darknode-provider-submission:v1\n<timestamp>\n<proposal>`, where
+//! `<timestamp>` is in seconds since the Unix epoch and `<proposal>` is the proposal
+//! serialized with sorted keys and no whitespace. The signature and timestamp travel in the
+//! same headers as request signatures, see [`crate::signing`]. Submissions carry no nonce:
+//! replaying one within the freshness window only proposes a URL that is already registered.
+
+use super::*;
+use super::canonical;
+use super::chains::Network;
+use super::dns::ResolveError;
+use super::schema::base58_decode;
+use super::signing::SignatureRejected;
+use super::traits::Crypto;
+use super::types::{CryptoKey, ProviderState, RpcProvider};
+use super::wallets::InvalidWalletAddress;
+
+/// Prefix of every signed submission, so submission signatures can't be replayed elsewhere
+const MESSAGE_PREFIX: &str = "darknode-provider-submission:v1";
+
+/// Whether the community may propose providers, and how they are tried
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubmissionConfig {
+    /// Whether submissions are accepted
+    pub enabled: bool,
+    /// How long a submitted provider is probed before it can be approved
+    pub trial_period: Duration,
+    /// How far a submission's timestamp may be from the coordinator's clock, either way
+    pub max_age: Duration,
+    /// Providers one wallet may have under review at once
+    pub max_pending_per_wallet: usize,
+}
+
+impl Default for SubmissionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            trial_period: Duration::from_secs(24 * 60 * 60),
+            max_age: Duration::from_secs(30),
+            max_pending_per_wallet: 3,
+        }
+    }
+}
+
+/// A provider proposed by a wallet holder
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderProposal {
+    /// The wallet the proposal is signed with
+    pub wallet_address: String,
+    /// The URL of the provider
+    pub url: String,
+    /// The type of provider (e.g., Solana, Ethereum)
+    pub provider_type: String,
+    /// Capabilities the provider supports (e.g. "archive")
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// The network the provider serves, mainnet if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<Network>,
+}
+
+/// Who proposed a provider, and how its trial and review went
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Submission {
+    /// The wallet that proposed the provider
+    pub submitted_by: String,
+    /// When the provider was proposed
+    pub submitted_at: Timestamp,
+    /// When the provider was first probed
+    #[serde(default)]
+    pub trial_started_at: Option<Timestamp>,
+    /// Probes during the trial
+    #[serde(default)]
+    pub probes: u32,
+    /// Probes during the trial the provider passed
+    #[serde(default)]
+    pub probes_passed: u32,
+    /// When an operator approved or rejected the provider
+    #[serde(default)]
+    pub reviewed_at: Option<Timestamp>,
+    /// Why the provider was rejected, if it was
+    #[serde(default)]
+    pub rejection_reason: Option<String>,
+}
+
+impl Submission {
+    /// Count a probe of the provider during its trial
+    pub fn record_probe(&mut self, healthy: bool, now: Timestamp) {
+        self.trial_started_at.get_or_insert(now);
+        self.probes += 1;
+        self.probes_passed += healthy as u32;
+    }
+    
+    /// When the trial ends, once it has started
+    pub fn trial_ends_at(&self, trial_period: Duration) -> Option<Timestamp> {
+        self.trial_started_at.map(|started| started + trial_period)
+    }
+}
+
+/// An operator's decision on a provider under review
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "decision", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ReviewDecision {
+    /// Let the provider serve traffic
+    Approve,
+    /// Turn the provider away for good
+    Reject {
+        /// Why, kept with the provider for its proposer to see
+        reason: String,
+    },
+}
+
+/// A submission that was turned away
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SubmissionRejected {
+    /// Submissions aren't accepted on this network
+    #[error("provider submissions are not enabled on this network")]
+    Disabled,
+    /// The wallet address isn't valid
+    #[error("{0}")]
+    InvalidWallet(#[from] InvalidWalletAddress),
+    /// The submission isn't signed by the wallet it names
+    #[error("submission must be signed by the wallet it names ({})", .0.label())]
+    Signature(#[from] SignatureRejected),
+    /// The URL isn't an HTTP(S) URL
+    #[error("provider URL must be an http or https URL")]
+    InvalidUrl,
+    /// The URL leads somewhere providers may not be probed at
+    #[error("{0}")]
+    Egress(#[from] ResolveError),
+    /// A provider with the URL is already registered or under review
+    #[error("a provider with this URL is already registered")]
+    AlreadyRegistered,
+    /// The wallet already has as many providers under review as it may
+    #[error("this wallet already has {0} providers under review")]
+    TooManyPending(usize),
+}
+
+/// A review that can't be made
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ReviewRefused {
+    /// The provider isn't under review
+    #[error("provider is not under review")]
+    NotUnderReview,
+    /// The provider is still on trial
+    #[error("provider is still on trial")]
+    OnTrial {
+        /// When the trial ends, if it has started
+        until: Option<Timestamp>,
+    },
+}
+
+/// The message the wallet signs for `proposal`
+pub fn signed_message(proposal: &ProviderProposal, timestamp: u64) -> Result<Vec<u8>> {
+    let proposal = canonical::sorted(serde_json::to_value(proposal)?);
+    let mut message = format!("{}\n{}\n", MESSAGE_PREFIX, timestamp).into_bytes();
+    message.extend(serde_json::to_vec(&proposal)?);
+    Ok(message)
+}
+
+/// Check that `proposal` was signed by the wallet it names, recently
+pub async fn verify(
+    proposal: &ProviderProposal,
+    signature: Option<(&str, u64)>,
+    crypto: &(dyn Crypto + Send + Sync),
+    config: &SubmissionConfig,
+    now: Timestamp,
+) -> Result<(), SignatureRejected> {
+    let (signature, timestamp) = signature.ok_or(SignatureRejected::Missing)?;
+    let signed_at = Timestamp::from_secs(timestamp);
+    let offset = now.saturating_duration_since(signed_at).max(signed_at.saturating_duration_since(now));
+    if offset > config.max_age {
+        return Err(SignatureRejected::Stale);
+    }
+    
+    let wallet = base58_decode(&proposal.wallet_address)
+        .filter(|wallet| wallet.len() == 32)
+        .ok_or(SignatureRejected::Malformed)?;
+    let bytes = base58_decode(signature)
+        .filter(|bytes| bytes.len() == 64)
+        .ok_or(SignatureRejected::Malformed)?;
+    let message = signed_message(proposal, timestamp).map_err(|_| SignatureRejected::Malformed)?;
+    match crypto.verify(&message, &bytes, &CryptoKey(wallet)).await {
+        Ok(true) => Ok(()),
+        _ => Err(SignatureRejected::Invalid),
+    }
+}
+
+/// The provider registered for `proposal`, pending its trial
+pub fn pending_provider(proposal: ProviderProposal, now: Timestamp) -> RpcProvider {
+    RpcProvider {
+        id: Uuid::new_v4(),
+        url: proposal.url,
+        provider_type: proposal.provider_type,
+        state: ProviderState::Pending,
+        success_rate: 0.0,
+        avg_latency: Duration::ZERO,
+        last_checked: now,
+        capabilities: proposal.capabilities,
+        pool: None,
+        network: proposal.network.unwrap_or(Network::Mainnet),
+        auth: None,
+        weight: 1,
+        maintenance_windows: Vec::new(),
+        tripped_breakers: 0,
+        failed_probes: 0,
+        quota: None,
+        submission: Some(Submission {
+            submitted_by: proposal.wallet_address,
+            submitted_at: now,
+            trial_started_at: None,
+            probes: 0,
+            probes_passed: 0,
+            reviewed_at: None,
+            rejection_reason: None,
+        }),
+    }
+}
+
+/// Apply `decision` to `provider`, which must have finished a trial of `trial_period`
+pub fn review(
+    provider: &mut RpcProvider,
+    decision: ReviewDecision,
+    trial_period: Duration,
+    now: Timestamp,
+) -> Result<(), ReviewRefused> {
+    if !provider.state.is_under_review() {
+        return Err(ReviewRefused::NotUnderReview);
+    }
+    let submission = provider.submission.as_mut().ok_or(ReviewRefused::NotUnderReview)?;
+    match decision {
+        ReviewDecision::Approve => {
+            let ends_at = submission.trial_ends_at(trial_period);
+            if ends_at.map_or(true, |ends_at| now < ends_at) {
+                return Err(ReviewRefused::OnTrial { until: ends_at });
+            }
+            provider.state = ProviderState::Active;
+        }
+        ReviewDecision::Reject { reason } => {
+            provider.state = ProviderState::Rejected;
+            submission.rejection_reason = Some(reason);
+        }
+    }
+    submission.reviewed_at = Some(now);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::ResolverConfig;
+    use crate::events::EventBus;
+    use crate::fixtures;
+    use crate::impls::StoredRpcManager;
+    use crate::managers::probe::{ProbeConfig, ProbeScheduler};
+    use crate::storage::MemoryStorage;
+    use crate::traits::RpcManager;
+    use serde_json::json;
+    
+    const WALLET: &str = "4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T";
+    
+    /// A provider on loopback proposed by [`WALLET`], registered pending, and the scheduler to try it
+    async fn submitted(rpc_manager: &Arc<StoredRpcManager>) -> (RpcProvider, ProbeScheduler) {
+        let serving = fixtures::serving(|request: serde_json::Value| async move {
+            json!({ "jsonrpc": "2.0", "id": request["id"], "result": "ok" })
+        });
+        let proposal = ProviderProposal {
+            wallet_address: WALLET.to_string(),
+            url: serving.url,
+            provider_type: "solana".to_string(),
+            capabilities: Vec::new(),
+            network: None,
+        };
+        let provider = pending_provider(proposal, Timestamp::now());
+        rpc_manager.register_provider(provider.clone()).await.unwrap();
+        let config = ProbeConfig {
+            jitter: 0.0,
+            submitted_resolver: ResolverConfig {
+                allow_private_addresses: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let probes = ProbeScheduler::new(rpc_manager.clone(), config, Arc::new(EventBus::new()));
+        (provider, probes)
+    }
+    
+    async fn stored(rpc_manager: &StoredRpcManager, id: Uuid) -> RpcProvider {
+        rpc_manager.get_providers().await.unwrap().into_iter().find(|provider| provider.id == id).unwrap()
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_submitted_provider_is_tried_and_only_serves_once_approved() {
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        let (provider, probes) = submitted(&rpc_manager).await;
+        let exit = Arc::new(fixtures::exit(rpc_manager.clone()));
+        let payload = fixtures::payload("getSlot", json!([]));
+        assert_eq!(provider.state, ProviderState::Pending);
+        assert!(exit.serve(&payload).await.is_err());
+        
+        // Its first probe starts the trial, and every probe is counted
+        probes.sync().await.unwrap();
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        let provider = stored(&rpc_manager, provider.id).await;
+        let submission = provider.submission.clone().unwrap();
+        assert_eq!(provider.state, ProviderState::Trial);
+        assert!(submission.probes >= 2, "{:?}", submission);
+        assert_eq!(submission.probes_passed, submission.probes);
+        assert!(provider.success_rate > 0.0);
+        assert!(rpc_manager.get_active_providers().await.unwrap().is_empty());
+        assert!(exit.serve(&payload).await.is_err());
+        
+        // It can only be approved once the trial is over
+        let trial_period = SubmissionConfig::default().trial_period;
+        let started = submission.trial_started_at.unwrap();
+        let mut reviewed = provider.clone();
+        assert_eq!(
+            review(&mut reviewed, ReviewDecision::Approve, trial_period, started),
+            Err(ReviewRefused::OnTrial {
+                until: Some(started + trial_period)
+            })
+        );
+        review(&mut reviewed, ReviewDecision::Approve, trial_period, started + trial_period).unwrap();
+        assert_eq!(reviewed.state, ProviderState::Active);
+        assert_eq!(reviewed.submission.as_ref().unwrap().reviewed_at, Some(started + trial_period));
+        rpc_manager.update_provider(reviewed.clone()).await.unwrap();
+        
+        let response: serde_json::Value = serde_json::from_slice(&exit.serve(&payload).await.unwrap()).unwrap();
+        assert_eq!(response["result"], "ok");
+        assert_eq!(
+            review(&mut reviewed, ReviewDecision::Approve, trial_period, started + trial_period),
+            Err(ReviewRefused::NotUnderReview)
+        );
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_rejected_provider_never_serves_and_is_probed_no_more() {
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        let (provider, probes) = submitted(&rpc_manager).await;
+        let exit = Arc::new(fixtures::exit(rpc_manager.clone()));
+        probes.sync().await.unwrap();
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        
+        // Rejection needn't wait for the trial to end
+        let mut reviewed = stored(&rpc_manager, provider.id).await;
+        assert_eq!(reviewed.state, ProviderState::Trial);
+        let reason = ReviewDecision::Reject {
+            reason: "serves a fork".to_string(),
+        };
+        review(&mut reviewed, reason, SubmissionConfig::default().trial_period, Timestamp::now()).unwrap();
+        assert_eq!(reviewed.state, ProviderState::Rejected);
+        rpc_manager.update_provider(reviewed).await.unwrap();
+        probes.sync().await.unwrap();
+        assert_eq!(probes.len(), 0);
+        
+        let probed = stored(&rpc_manager, provider.id).await.submission.unwrap().probes;
+        tokio::time::sleep(Duration::from_secs(120)).await;
+        let rejected = stored(&rpc_manager, provider.id).await;
+        let submission = rejected.submission.unwrap();
+        assert_eq!((rejected.state, submission.probes), (ProviderState::Rejected, probed));
+        assert_eq!(submission.rejection_reason.as_deref(), Some("serves a fork"));
+        
+        // Neither traffic nor an operator's reactivation reaches it
+        assert!(exit.serve(&fixtures::payload("getSlot", json!([]))).await.is_err());
+        assert!(rpc_manager.update_provider_status(provider.id, true).await.is_err());
+        assert!(rpc_manager.get_active_providers().await.unwrap().is_empty());
+    }
+    
+    #[test]
+    fn records_with_the_old_active_flag_read_as_a_state() {
+        let mut record = serde_json::to_value(fixtures::provider()).unwrap();
+        assert_eq!(record["state"], "active");
+        record.as_object_mut().unwrap().remove("state");
+        
+        for (active, state) in [(true, ProviderState::Active), (false, ProviderState::Disabled)] {
+            record["active"] = json!(active);
+            let provider: RpcProvider = serde_json::from_value(record.clone()).unwrap();
+            assert_eq!(provider.state, state);
+            assert!(provider.submission.is_none());
+        }
+    }
+}
@@ -0,0 +1,338 @@
+//! Tracing spans inside each node, and their export to an OpenTelemetry collector
+//!
+//! Every HTTP request a node serves gets a span, and the library opens spans at the
+//! boundaries where a request's time goes, under names that are kept stable for dashboards:
+//!
+//! | Span                     | Node    | Covers                                          |
+//! |--------------------------|---------|-------------------------------------------------|
+//! | `darknode.http`          | all     | An HTTP request, named by its route             |
+//! | `darknode.sanitize`      | entry   | Sanitizing a request into its exit payload      |
+//! | `darknode.circuit.build` | entry   | Building a circuit for a request                |
+//! | `darknode.circuit.send`  | entry   | Sending a request into its circuit              |
+//! | `darknode.circuit.wait`  | entry   | Waiting for the response to come back           |
+//! | `darknode.hop.forward`   | routing | Forwarding a request to the next hop            |
+//! | `darknode.hop.return`    | routing | Passing a response back to the previous hop     |
+//! | `darknode.provider.call` | exit    | Calling a provider and reading its response     |
+//!
+//! Spans carry opaque IDs and counts at most, and their durations. Payloads, API keys,
+//! wallets, and the query strings of URLs, which may hold an API key, are never recorded;
+//! HTTP spans are named by the route matched rather than the path.
+//!
+//! Built with the `otel` feature, a node exports its spans over OTLP to `otlp_endpoint`,
+//! sampling `sampling_ratio` of traces. Each node's trace ends at its own edge unless
+//! `propagate` is set: passing the trace context from hop to hop in a W3C `traceparent`
+//! header links a request's path through the network in one trace, which is exactly what
+//! an observer correlating hops needs, so it is off unless an operator running every node
+//! of a test network turns it on. The entry node never takes a trace context from clients.
+
+use super::*;
+use axum::extract::MatchedPath;
+use axum::http::Request;
+use tower_http::trace::MakeSpan;
+use tracing::{Level, Span};
+use tracing_subscriber::{filter::LevelFilter, prelude::*};
+
+/// Span of an HTTP request a node serves
+pub const HTTP_SPAN: &str = "darknode.http";
+
+/// Span of sanitizing a request on the entry node
+pub const SANITIZE_SPAN: &str = "darknode.sanitize";
+
+/// Span of building a circuit
+pub const CIRCUIT_BUILD_SPAN: &str = "darknode.circuit.build";
+
+/// Span of sending a request into its circuit
+pub const CIRCUIT_SEND_SPAN: &str = "darknode.circuit.send";
+
+/// Span of waiting for a response from a circuit
+pub const CIRCUIT_WAIT_SPAN: &str = "darknode.circuit.wait";
+
+/// Span of a routing node forwarding a request
+pub const HOP_FORWARD_SPAN: &str = "darknode.hop.forward";
+
+/// Span of a routing node passing a response back
+pub const HOP_RETURN_SPAN: &str = "darknode.hop.return";
+
+/// Span of an exit node calling a provider
+pub const PROVIDER_CALL_SPAN: &str = "darknode.provider.call";
+
+/// Header carrying the trace context between hops, when propagation is enabled
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Where spans are exported, and whether traces cross hops
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    /// OTLP gRPC endpoint spans are exported to, if any; needs the `otel` feature
+    pub otlp_endpoint: Option<String>,
+    /// Share of traces exported, from 0 to 1
+    pub sampling_ratio: f64,
+    /// Carry the trace context to the next hop, see the module docs
+    pub propagate: bool,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            sampling_ratio: 0.1,
+            propagate: false,
+        }
+    }
+}
+
+/// Install the process's subscriber, logging at `level` and exporting spans as `config` says
+///
+/// `service` names the binary in exported traces.
+pub fn init(config: &TelemetryConfig, service: &'static str, level: Level) -> Result<()> {
+    let registry = tracing_subscriber::registry()
+        .with(LevelFilter::from_level(level))
+        .with(tracing_subscriber::fmt::layer());
+    match &config.otlp_endpoint {
+        #[cfg(feature = "otel")]
+        Some(endpoint) => {
+            let tracer = otel::tracer(endpoint, config.sampling_ratio, service)?;
+            registry.with(tracing_opentelemetry::layer().with_tracer(tracer)).init();
+            tracing::info!("Exporting {} of traces to {}", config.sampling_ratio, endpoint);
+        }
+        #[cfg(not(feature = "otel"))]
+        Some(_) => {
+            registry.init();
+            tracing::warn!("Built without the otel feature, {} spans are not exported", service);
+        }
+        None => registry.init(),
+    }
+    if config.propagate {
+        tracing::warn!("Trace context is propagated between hops, linking requests' paths through the network");
+    }
+    Ok(())
+}
+
+/// The headers carrying the current trace context to the next hop, if `config` propagates it
+pub fn trace_headers(config: &TelemetryConfig) -> Vec<(&'static str, String)> {
+    if !config.propagate {
+        return Vec::new();
+    }
+    otel::traceparent(&Span::current())
+        .map(|traceparent| (TRACEPARENT_HEADER, traceparent))
+        .into_iter()
+        .collect()
+}
+
+/// Makes the span of each HTTP request a node serves, see the module docs
+#[derive(Debug, Clone, Copy)]
+pub struct HttpSpans {
+    continue_traces: bool,
+}
+
+impl HttpSpans {
+    /// Spans for a node serving clients, which never continue a trace they send
+    pub fn client_facing() -> Self {
+        Self { continue_traces: false }
+    }
+    
+    /// Spans for a node reached by other hops, continuing their traces if `config` propagates them
+    pub fn between_hops(config: &TelemetryConfig) -> Self {
+        Self {
+            continue_traces: config.propagate,
+        }
+    }
+}
+
+impl<B> MakeSpan<B> for HttpSpans {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        let route = request
+            .extensions()
+            .get::<MatchedPath>()
+            .map_or("unmatched", |matched| matched.as_str());
+        let span = tracing::info_span!(HTTP_SPAN, method = %request.method(), route);
+        if self.continue_traces {
+            if let Some(traceparent) = request.headers().get(TRACEPARENT_HEADER).and_then(|value| value.to_str().ok()) {
+                otel::continue_trace(&span, traceparent);
+            }
+        }
+        span
+    }
+}
+
+/// Exporting spans and carrying trace contexts over OpenTelemetry
+#[cfg(feature = "otel")]
+mod otel {
+    use super::*;
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use opentelemetry::sdk::trace::{self, Sampler, Tracer};
+    use opentelemetry::sdk::Resource;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use std::collections::HashMap;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    
+    /// A tracer exporting `ratio` of traces to `endpoint` in batches
+    pub fn tracer(endpoint: &str, ratio: f64, service: &'static str) -> Result<Tracer> {
+        let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(ratio)));
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+            .with_trace_config(
+                trace::config()
+                    .with_sampler(sampler)
+                    .with_resource(Resource::new(vec![KeyValue::new("service.name", service)])),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)?;
+        Ok(tracer)
+    }
+    
+    /// The `traceparent` of `span`, if it is part of a trace
+    pub fn traceparent(span: &Span) -> Option<String> {
+        let mut carrier = HashMap::new();
+        TraceContextPropagator::new().inject_context(&span.context(), &mut carrier);
+        carrier.remove(TRACEPARENT_HEADER)
+    }
+    
+    /// Make `span` part of the trace `traceparent` names
+    pub fn continue_trace(span: &Span, traceparent: &str) {
+        let carrier = HashMap::from([(TRACEPARENT_HEADER.to_string(), traceparent.to_string())]);
+        span.set_parent(TraceContextPropagator::new().extract(&carrier));
+    }
+}
+
+/// Without the `otel` feature there are no trace contexts to carry
+#[cfg(not(feature = "otel"))]
+mod otel {
+    use super::*;
+    
+    /// Never a `traceparent`, as no span is part of an exported trace
+    pub fn traceparent(_span: &Span) -> Option<String> {
+        None
+    }
+    
+    /// Nothing to continue
+    pub fn continue_trace(_span: &Span, _traceparent: &str) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::post;
+    use axum::Router;
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+    use tower_http::trace::TraceLayer;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id};
+    use tracing_subscriber::layer::Context;
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+    
+    /// A span as it was opened: its name, its parent's name, and its fields
+    #[derive(Debug, Clone)]
+    struct Opened {
+        name: &'static str,
+        parent: Option<&'static str>,
+        fields: Vec<(String, String)>,
+    }
+    
+    /// Records every span opened while it is the default subscriber
+    #[derive(Clone, Default)]
+    struct Recorder {
+        spans: Arc<Mutex<Vec<Opened>>>,
+    }
+    
+    struct Fields<'a>(&'a mut Vec<(String, String)>);
+    
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+    
+    impl<S> Layer<S> for Recorder
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(&self, attributes: &Attributes<'_>, id: &Id, context: Context<'_, S>) {
+            let parent = context.span(id).and_then(|span| span.parent()).map(|parent| parent.name());
+            let mut fields = Vec::new();
+            attributes.record(&mut Fields(&mut fields));
+            self.spans.lock().unwrap().push(Opened {
+                name: attributes.metadata().name(),
+                parent,
+                fields,
+            });
+        }
+    }
+    
+    fn recording() -> (Recorder, tracing::subscriber::DefaultGuard) {
+        let recorder = Recorder::default();
+        let guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder.clone()));
+        (recorder, guard)
+    }
+    
+    async fn sanitize() -> &'static str {
+        let _span = tracing::info_span!(SANITIZE_SPAN).entered();
+        "ok"
+    }
+    
+    #[tokio::test]
+    async fn http_spans_are_named_by_route_and_nest_the_handlers_spans() {
+        let (recorder, _guard) = recording();
+        let app = Router::new()
+            .route("/rpc/:api_key", post(sanitize))
+            .layer(TraceLayer::new_for_http().make_span_with(HttpSpans::client_facing()));
+        let request = Request::post("/rpc/secret-key?api-key=secret-query")
+            .header(TRACEPARENT_HEADER, "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        app.oneshot(request).await.unwrap();
+        
+        let spans = recorder.spans.lock().unwrap().clone();
+        let http = spans.iter().find(|span| span.name == HTTP_SPAN).expect("no HTTP span");
+        assert_eq!(http.parent, None);
+        assert!(http.fields.contains(&("route".to_string(), "\"/rpc/:api_key\"".to_string())), "{:?}", http.fields);
+        let sanitize = spans.iter().find(|span| span.name == SANITIZE_SPAN).expect("no sanitize span");
+        assert_eq!(sanitize.parent, Some(HTTP_SPAN));
+        for span in &spans {
+            for (name, value) in &span.fields {
+                assert!(!value.contains("secret"), "{} of {} leaks {}", name, span.name, value);
+                assert!(!value.contains("0af7651916cd43dd"), "{} of {} carries the client's trace", name, span.name);
+            }
+        }
+    }
+    
+    #[test]
+    fn trace_context_stays_home_unless_propagated() {
+        let (_recorder, _guard) = recording();
+        let _span = tracing::info_span!(HOP_FORWARD_SPAN).entered();
+        assert!(trace_headers(&TelemetryConfig::default()).is_empty());
+    }
+    
+    #[cfg(feature = "otel")]
+    #[test]
+    fn propagated_trace_context_is_continued_by_the_next_hop() {
+        use opentelemetry::trace::TracerProvider as _;
+        
+        let provider = opentelemetry::sdk::trace::TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let config = TelemetryConfig {
+            propagate: true,
+            ..TelemetryConfig::default()
+        };
+        
+        let forward = tracing::info_span!(HOP_FORWARD_SPAN).entered();
+        let headers = trace_headers(&config);
+        forward.exit();
+        let [(name, traceparent)] = headers.as_slice() else {
+            panic!("expected one trace header, got {:?}", headers);
+        };
+        assert_eq!(*name, TRACEPARENT_HEADER);
+        let trace_id = traceparent.split('-').nth(1).unwrap();
+        
+        let request = Request::post("/hop").header(TRACEPARENT_HEADER, traceparent.as_str()).body(()).unwrap();
+        let continued = HttpSpans::between_hops(&config).make_span(&request);
+        assert_eq!(otel::traceparent(&continued).unwrap().split('-').nth(1), Some(trace_id));
+        let fresh = HttpSpans::client_facing().make_span(&request);
+        assert_ne!(otel::traceparent(&fresh).unwrap().split('-').nth(1), Some(trace_id));
+    }
+}
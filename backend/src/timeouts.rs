@@ -0,0 +1,262 @@
+//! Request time budgets by method class
+//!
+//! One timeout doesn't fit every method: `getLatestBlockhash` is useless after a couple of
+//! seconds, while `getProgramAccounts` over a large program can legitimately take a minute.
+//! The entry node gives each request an end-to-end budget from its method class, or from
+//! the mapping's override. The budget that remains once the request is on its way, less an
+//! allowance for the hops it still has to cross in both directions, travels to the exit
+//! node inside the encrypted payload. The exit node re-anchors it on its own clock as its
+//! upstream timeout, so no wall-clock time is compared between nodes.
+
+use super::*;
+use super::methods;
+use std::collections::BTreeMap;
+
+/// JSON-RPC error code of a request that ran out of time
+pub const TIMEOUT_CODE: i64 = -32000;
+
+/// Cheap reads that should answer quickly or not at all
+const LIGHT_READ_METHODS: &[&str] = &[
+    // Solana
+    "getLatestBlockhash",
+    "getSlot",
+    "getBlockHeight",
+    "getBalance",
+    "getEpochInfo",
+    "getHealth",
+    "getVersion",
+    "getFeeForMessage",
+    "getRecentPrioritizationFees",
+    "isBlockhashValid",
+    // Ethereum
+    "eth_blockNumber",
+    "eth_chainId",
+    "eth_gasPrice",
+    "eth_maxPriorityFeePerGas",
+    "eth_getBalance",
+    "eth_getTransactionCount",
+    "net_version",
+];
+
+/// Reads that scan large amounts of state
+const HEAVY_READ_METHODS: &[&str] = &[
+    // Solana
+    "getProgramAccounts",
+    "getSignaturesForAddress",
+    "getBlock",
+    "getBlocks",
+    "getTokenLargestAccounts",
+    "getLargestAccounts",
+    // Ethereum
+    "eth_getLogs",
+    "debug_traceTransaction",
+    "debug_traceCall",
+    "trace_block",
+    "trace_filter",
+];
+
+/// The class of a JSON-RPC method, which decides its time budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MethodClass {
+    /// Cheap reads answered from recent state
+    LightRead,
+    /// Ordinary reads
+    Read,
+    /// Reads that scan large amounts of state
+    HeavyRead,
+    /// Methods that change chain state
+    Write,
+}
+
+impl MethodClass {
+    /// The class of `method`; unlisted methods are ordinary reads
+    pub fn of(method: &str) -> Self {
+        if methods::is_mutating(method) {
+            MethodClass::Write
+        } else if LIGHT_READ_METHODS.contains(&method) {
+            MethodClass::LightRead
+        } else if HEAVY_READ_METHODS.contains(&method) {
+            MethodClass::HeavyRead
+        } else {
+            MethodClass::Read
+        }
+    }
+    
+    /// Label used in errors and metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            MethodClass::LightRead => "light_read",
+            MethodClass::Read => "read",
+            MethodClass::HeavyRead => "heavy_read",
+            MethodClass::Write => "write",
+        }
+    }
+}
+
+/// End-to-end time budgets for each method class
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// Budget of light reads
+    pub light_read: Duration,
+    /// Budget of ordinary reads
+    pub read: Duration,
+    /// Budget of heavy reads
+    pub heavy_read: Duration,
+    /// Budget of writes
+    pub write: Duration,
+    /// Time allowed for a message to cross one hop, held back from the exit node's budget
+    pub hop_latency: Duration,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            light_read: Duration::from_secs(2),
+            read: Duration::from_secs(10),
+            heavy_read: Duration::from_secs(60),
+            write: Duration::from_secs(30),
+            hop_latency: Duration::from_millis(50),
+        }
+    }
+}
+
+impl TimeoutConfig {
+    /// The budget of `class`, unless `overrides` sets its own
+    pub fn budget(&self, class: MethodClass, overrides: Option<&BTreeMap<MethodClass, Duration>>) -> Duration {
+        if let Some(budget) = overrides.and_then(|overrides| overrides.get(&class)) {
+            return *budget;
+        }
+        match class {
+            MethodClass::LightRead => self.light_read,
+            MethodClass::Read => self.read,
+            MethodClass::HeavyRead => self.heavy_read,
+            MethodClass::Write => self.write,
+        }
+    }
+    
+    /// The budget left for the exit node out of `remaining`, when the request still has
+    /// `hops` hops to cross to reach it and as many to come back
+    pub fn upstream_budget(&self, remaining: Duration, hops: usize) -> Duration {
+        remaining.saturating_sub(self.hop_latency * (2 * hops) as u32)
+    }
+}
+
+/// Where a budget was enforced
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeoutBudget {
+    /// At the entry node, covering the whole round trip through the circuit
+    Edge,
+    /// At the exit node, covering the provider requests
+    Upstream,
+}
+
+impl TimeoutBudget {
+    /// Label used in errors and metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            TimeoutBudget::Edge => "edge",
+            TimeoutBudget::Upstream => "upstream",
+        }
+    }
+}
+
+/// A request ran out of its time budget
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{} budget of {}ms for {} requests exceeded", budget.label(), limit.as_millis(), class.label())]
+pub struct TimedOut {
+    /// Which budget ran out
+    pub budget: TimeoutBudget,
+    /// The class of the request's method
+    pub class: MethodClass,
+    /// The budget that ran out
+    pub limit: Duration,
+}
+
+impl TimedOut {
+    /// Count the timeout and pass it on
+    pub fn record(self) -> Self {
+        metrics::increment_counter!(
+            "darknode_request_timeouts_total",
+            "budget" => self.budget.label(),
+            "class" => self.class.label()
+        );
+        self
+    }
+    
+    /// The JSON-RPC error object reporting the timeout
+    pub fn error(&self) -> serde_json::Value {
+        serde_json::json!({
+            "code": TIMEOUT_CODE,
+            "message": self.to_string(),
+            "data": {
+                "budget": self.budget,
+                "class": self.class,
+                "timeout_ms": self.limit.as_millis() as u64,
+            },
+        })
+    }
+    
+    /// A JSON-RPC response to request `id` reporting the timeout
+    pub fn response(&self, id: &serde_json::Value) -> serde_json::Value {
+        serde_json::json!({ "jsonrpc": "2.0", "id": id, "error": self.error() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::impls::StoredRpcManager;
+    use crate::storage::MemoryStorage;
+    use crate::traits::RpcManager;
+    use crate::types::ExitPayload;
+    use serde_json::json;
+    
+    /// How long the provider takes over every request
+    const LATENCY: Duration = Duration::from_secs(3);
+    
+    #[tokio::test(start_paused = true)]
+    async fn the_same_latency_fits_a_heavy_read_budget_but_not_a_light_one() {
+        let provider = fixtures::serving(|request: serde_json::Value| async move {
+            tokio::time::sleep(LATENCY).await;
+            json!({ "jsonrpc": "2.0", "id": request["id"], "result": [] })
+        });
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        rpc_manager.register_provider(provider).await.unwrap();
+        let exit = Arc::new(fixtures::exit(rpc_manager));
+        
+        // The exit node is given what the entry node leaves it of the class's budget, two hops away
+        let timeouts = TimeoutConfig::default();
+        let mapping = BTreeMap::from([(MethodClass::LightRead, Duration::from_secs(5))]);
+        let sent = |method: &str, overrides: Option<&BTreeMap<MethodClass, Duration>>| ExitPayload {
+            timeout: Some(timeouts.upstream_budget(timeouts.budget(MethodClass::of(method), overrides), 2)),
+            ..fixtures::payload(method, json!([]))
+        };
+        let answer = |response: Vec<u8>| serde_json::from_slice::<serde_json::Value>(&response).unwrap();
+        
+        let heavy = answer(exit.serve(&sent("getProgramAccounts", None)).await.unwrap());
+        assert_eq!(heavy["result"], json!([]));
+        
+        let light = answer(exit.serve(&sent("getLatestBlockhash", None)).await.unwrap());
+        assert_eq!(light["error"]["code"], TIMEOUT_CODE);
+        assert_eq!(light["error"]["data"]["class"], "light_read");
+        assert_eq!(light["error"]["data"]["budget"], "upstream");
+        assert_eq!(light["error"]["data"]["timeout_ms"], 1800);
+        assert!(light["error"]["message"].as_str().unwrap().contains("light_read"));
+        
+        // A mapping that gives light reads longer lets the same request through
+        let overridden = answer(exit.serve(&sent("getLatestBlockhash", Some(&mapping))).await.unwrap());
+        assert_eq!(overridden["result"], json!([]));
+    }
+    
+    #[test]
+    fn methods_fall_into_their_classes() {
+        assert_eq!(MethodClass::of("getLatestBlockhash"), MethodClass::LightRead);
+        assert_eq!(MethodClass::of("getAccountInfo"), MethodClass::Read);
+        assert_eq!(MethodClass::of("getProgramAccounts"), MethodClass::HeavyRead);
+        assert_eq!(MethodClass::of("sendTransaction"), MethodClass::Write);
+    }
+}
@@ -0,0 +1,310 @@
+//! Opt-in breakdowns of where a request's time went
+//!
+//! Clients sending `X-DarkNode-Timing: 1` get a `Server-Timing` header with their
+//! response, and the same breakdown as the `timing` member of its `darknode` extension:
+//!
+//! - `auth`, checking the API key, plan, signature, and admission
+//! - `sanitize`, sanitizing and validating the request and counting it against the quota
+//! - `circuit`, waiting for a share of the network, building the circuit, and the round
+//!   trip through it, less the time the exit node spent upstream
+//! - `upstream`, as reported by the exit node inside the encrypted response
+//! - `prepare`, preparing the response for the client
+//! - `total`, from when the request was accepted until it was answered
+//!
+//! Every value is rounded to [`GRANULARITY`], coarse enough that the transit time can't be
+//! used to tell how many hops a circuit has. The phases add up to the total within that
+//! rounding. Only requests answered whole through a circuit are timed, and only for the
+//! client that asked: the exit node's report is dropped from responses nobody asked to time.
+
+use super::*;
+use super::methods;
+use tokio::time::Instant;
+
+/// Header asking for a timing breakdown, `1` to opt in
+pub const TIMING_HEADER: &str = "x-darknode-timing";
+
+/// Header reporting the breakdown to the client
+pub const SERVER_TIMING_HEADER: &str = "server-timing";
+
+/// Name of the timing member of a response's DarkNode extension
+pub const EXTENSION_FIELD: &str = "timing";
+
+/// What every reported duration is rounded to
+pub const GRANULARITY: Duration = Duration::from_millis(5);
+
+/// Whether a timing header value opts in, `1` or `0`
+pub fn parse_header(value: &str) -> Option<bool> {
+    match value.trim() {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// A phase of serving a request at the entry node, in the order they end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Authenticating the request and admitting it
+    Auth,
+    /// Sanitizing and validating the request
+    Sanitize,
+    /// Carrying the request through a circuit and back
+    Circuit,
+}
+
+/// When each phase of a request ended at the entry node
+#[derive(Debug, Clone, Copy)]
+pub struct Stopwatch {
+    started: Instant,
+    auth: Option<Instant>,
+    sanitize: Option<Instant>,
+    circuit: Option<Instant>,
+}
+
+impl Stopwatch {
+    /// Time a request accepted at `started`
+    pub fn start(started: Instant) -> Self {
+        Self {
+            started,
+            auth: None,
+            sanitize: None,
+            circuit: None,
+        }
+    }
+    
+    /// Note that `phase` ended now
+    pub fn end(&mut self, phase: Phase) {
+        let now = Instant::now();
+        match phase {
+            Phase::Auth => self.auth = Some(now),
+            Phase::Sanitize => self.sanitize = Some(now),
+            Phase::Circuit => self.circuit = Some(now),
+        }
+    }
+    
+    /// The breakdown of a request answered now, the exit node having spent `upstream` on it
+    ///
+    /// A phase that never ended is reported as taking no time.
+    pub fn finish(&self, upstream: Duration) -> ServerTiming {
+        let now = Instant::now();
+        let auth = self.auth.unwrap_or(self.started);
+        let sanitize = self.sanitize.unwrap_or(auth);
+        let circuit = self.circuit.unwrap_or(sanitize);
+        let transit = circuit.saturating_duration_since(sanitize);
+        ServerTiming {
+            auth: rounded(auth.saturating_duration_since(self.started)),
+            sanitize: rounded(sanitize.saturating_duration_since(auth)),
+            circuit: rounded(transit.saturating_sub(upstream)),
+            upstream: rounded(upstream.min(transit)),
+            prepare: rounded(now.saturating_duration_since(circuit)),
+            total: rounded(now.saturating_duration_since(self.started)),
+        }
+    }
+}
+
+/// `duration` to the nearest multiple of [`GRANULARITY`], in milliseconds
+fn rounded(duration: Duration) -> u64 {
+    let granularity = GRANULARITY.as_millis() as u64;
+    let millis = duration.as_millis() as u64;
+    (millis + granularity / 2) / granularity * granularity
+}
+
+/// Where a request's time went, in milliseconds rounded to [`GRANULARITY`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerTiming {
+    /// Authenticating and admitting the request
+    pub auth: u64,
+    /// Sanitizing and validating the request
+    pub sanitize: u64,
+    /// The circuit, less the time spent upstream
+    pub circuit: u64,
+    /// The exit node serving the request from a provider
+    pub upstream: u64,
+    /// Preparing the response
+    pub prepare: u64,
+    /// The whole request at the entry node
+    pub total: u64,
+}
+
+impl ServerTiming {
+    /// The breakdown in a response's DarkNode extension, if it has one
+    pub fn in_extension(extension: &serde_json::Value) -> Option<Self> {
+        serde_json::from_value(extension.get(EXTENSION_FIELD)?.clone()).ok()
+    }
+    
+    /// The value of the `Server-Timing` header reporting the breakdown
+    pub fn header_value(&self) -> String {
+        [
+            ("auth", self.auth),
+            ("sanitize", self.sanitize),
+            ("circuit", self.circuit),
+            ("upstream", self.upstream),
+            ("prepare", self.prepare),
+            ("total", self.total),
+        ]
+        .iter()
+        .map(|(name, millis)| format!("{};dur={}", name, millis))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+/// What the exit node reports of the time it spent upstream
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct UpstreamReport {
+    /// Milliseconds from receiving the request to answering it
+    upstream_ms: u64,
+}
+
+/// Report in `response` that the exit node spent `upstream` serving it
+///
+/// Responses that aren't JSON objects are passed through unchanged.
+pub fn report_upstream(response: Vec<u8>, upstream: Duration) -> Vec<u8> {
+    let mut parsed = match serde_json::from_slice::<serde_json::Value>(&response) {
+        Ok(parsed) if parsed.is_object() => parsed,
+        _ => return response,
+    };
+    let report = UpstreamReport {
+        upstream_ms: upstream.as_millis() as u64,
+    };
+    methods::set_extension(&mut parsed, EXTENSION_FIELD, serde_json::json!(report));
+    serde_json::to_vec(&parsed).unwrap_or(response)
+}
+
+/// Take the exit node's report out of `response`, with how long it spent upstream if it reported
+pub fn take_upstream(response: &mut serde_json::Value) -> Option<Duration> {
+    let extension = response.get_mut(methods::EXTENSION_KEY)?.as_object_mut()?;
+    let report = extension.remove(EXTENSION_FIELD)?;
+    if extension.is_empty() {
+        response.as_object_mut()?.remove(methods::EXTENSION_KEY);
+    }
+    let report: UpstreamReport = serde_json::from_value(report).ok()?;
+    Some(Duration::from_millis(report.upstream_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EntryConfig;
+    use crate::context::RequestContext;
+    use crate::fixtures::{self, StubRouter};
+    use crate::traits::{Router, UserManager};
+    use crate::types::{Circuit, CircuitPreferences};
+    use axum::http::HeaderMap;
+    use serde_json::json;
+    
+    /// How long the stand-in circuit takes to answer, and how much of it the exit node reports upstream
+    const TRANSIT: Duration = Duration::from_millis(60);
+    const UPSTREAM: Duration = Duration::from_millis(40);
+    
+    /// A stand-in circuit taking [`TRANSIT`] to carry each request
+    struct Slow(StubRouter);
+    
+    #[async_trait]
+    impl Router for Slow {
+        async fn create_circuit(&self) -> Result<Circuit> {
+            self.0.create_circuit().await
+        }
+        
+        async fn create_circuit_with(&self, preferences: &CircuitPreferences) -> Result<Circuit> {
+            self.0.create_circuit_with(preferences).await
+        }
+        
+        async fn close_circuit(&self, circuit: &Circuit) -> Result<()> {
+            self.0.close_circuit(circuit).await
+        }
+        
+        async fn send_request(&self, ctx: &RequestContext, circuit: &Circuit, request: &[u8]) -> Result<Uuid> {
+            tokio::time::sleep(TRANSIT).await;
+            self.0.send_request(ctx, circuit, request).await
+        }
+        
+        async fn receive_response(&self, request_id: Uuid) -> Result<Vec<u8>> {
+            self.0.receive_response(request_id).await
+        }
+    }
+    
+    /// The phases of a `Server-Timing` header value, in order, by name
+    fn parse(header: &str) -> Vec<(String, u64)> {
+        header
+            .split(", ")
+            .map(|metric| {
+                let (name, duration) = metric.split_once(";dur=").unwrap();
+                (name.to_string(), duration.parse().unwrap())
+            })
+            .collect()
+    }
+    
+    /// The breakdown given for a `getSlot` with the timing header set to `opt_in`, if any
+    async fn timed(opt_in: Option<&str>) -> (serde_json::Value, Option<ServerTiming>) {
+        // The exit node reports its time upstream whether or not it was asked, to show the
+        // entry node drops reports nobody asked for
+        let router = Arc::new(Slow(StubRouter::new(|_| {
+            let response = serde_json::to_vec(&json!({ "jsonrpc": "2.0", "result": 311_029_712 })).unwrap();
+            serde_json::from_slice(&report_upstream(response, UPSTREAM)).unwrap()
+        })));
+        let (entry, users) = fixtures::entry(router, &EntryConfig::default()).await;
+        let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let mut headers = HeaderMap::new();
+        if let Some(value) = opt_in {
+            headers.insert(TIMING_HEADER, value.parse().unwrap());
+        }
+        let ctx = RequestContext::new(&user.api_key).with_headers(&headers).unwrap();
+        let request = serde_json::to_vec(&json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" })).unwrap();
+        
+        let response: serde_json::Value = serde_json::from_slice(&entry.handle_request(ctx, &request).await.unwrap()).unwrap();
+        let timing = ServerTiming::in_extension(&response[methods::EXTENSION_KEY]);
+        (response, timing)
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn the_phases_of_an_opted_in_request_add_up_to_its_total() {
+        let (response, timing) = timed(Some("1")).await;
+        let timing = timing.unwrap();
+        let phases = parse(&timing.header_value());
+        let names: Vec<&str> = phases.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["auth", "sanitize", "circuit", "upstream", "prepare", "total"]);
+        
+        let granularity = GRANULARITY.as_millis() as u64;
+        assert!(phases.iter().all(|(_, millis)| millis % granularity == 0), "{:?}", phases);
+        let (total, phases) = phases.split_last().unwrap();
+        let sum: u64 = phases.iter().map(|(_, millis)| millis).sum();
+        
+        // Each of the five phases and the total is off by at most half the granularity
+        assert!(sum.abs_diff(total.1) <= 3 * granularity, "{:?} against {:?}", phases, total);
+        assert!(total.1 >= TRANSIT.as_millis() as u64);
+        assert_eq!(timing.upstream, UPSTREAM.as_millis() as u64);
+        assert!(timing.circuit + timing.upstream >= TRANSIT.as_millis() as u64 - granularity);
+        assert_eq!(response["result"], json!(311_029_712));
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn requests_not_opted_in_get_no_breakdown() {
+        for opt_in in [None, Some("0")] {
+            let (response, timing) = timed(opt_in).await;
+            assert_eq!(timing, None);
+            assert!(response[methods::EXTENSION_KEY].get(EXTENSION_FIELD).is_none(), "{}", response);
+            assert_eq!(response["result"], json!(311_029_712));
+        }
+        
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMING_HEADER, "yes".parse().unwrap());
+        assert!(RequestContext::new("api-key").with_headers(&headers).is_err());
+    }
+    
+    #[test]
+    fn durations_are_rounded_to_the_granularity() {
+        assert_eq!(rounded(Duration::from_micros(2_400)), 0);
+        assert_eq!(rounded(Duration::from_micros(2_600)), 5);
+        assert_eq!(rounded(Duration::from_millis(62)), 60);
+        assert_eq!(rounded(Duration::from_millis(63)), 65);
+        
+        let mut response = report_upstream(br#"{"jsonrpc":"2.0","id":1,"result":7}"#.to_vec(), UPSTREAM);
+        let mut parsed: serde_json::Value = serde_json::from_slice(&response).unwrap();
+        assert_eq!(take_upstream(&mut parsed), Some(UPSTREAM));
+        assert_eq!(parsed, json!({ "jsonrpc": "2.0", "id": 1, "result": 7 }));
+        
+        response = report_upstream(b"502 Bad Gateway".to_vec(), UPSTREAM);
+        assert_eq!(response, b"502 Bad Gateway");
+    }
+}
@@ -0,0 +1,259 @@
+//! Method mix and payload size metrics with no user linkage
+//!
+//! Capacity planning needs to know which methods are called and how large requests and
+//! responses are, but nothing recorded here may identify a user or circuit. Metrics carry
+//! a method label from a bounded allowlist and nothing else, and distinct users are only
+//! estimated, with a HyperLogLog whose hash keys are thrown away every day.
+
+use super::*;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+
+/// Methods reported under their own label; everything else is reported as [`OTHER_METHOD`]
+pub const KNOWN_METHODS: &[&str] = &[
+    // Solana
+    "getAccountInfo",
+    "getBalance",
+    "getBlock",
+    "getBlockHeight",
+    "getBlockTime",
+    "getEpochInfo",
+    "getFeeForMessage",
+    "getHealth",
+    "getLatestBlockhash",
+    "getMultipleAccounts",
+    "getProgramAccounts",
+    "getRecentPrioritizationFees",
+    "getSignatureStatuses",
+    "getSignaturesForAddress",
+    "getSlot",
+    "getTokenAccountBalance",
+    "getTokenAccountsByOwner",
+    "getTransaction",
+    "getVersion",
+    "isBlockhashValid",
+    "requestAirdrop",
+    "sendTransaction",
+    "simulateTransaction",
+    // Ethereum
+    "eth_blockNumber",
+    "eth_call",
+    "eth_chainId",
+    "eth_estimateGas",
+    "eth_gasPrice",
+    "eth_getBalance",
+    "eth_getBlockByNumber",
+    "eth_getCode",
+    "eth_getLogs",
+    "eth_getTransactionByHash",
+    "eth_getTransactionCount",
+    "eth_getTransactionReceipt",
+    "eth_sendRawTransaction",
+];
+
+/// Label for methods outside [`KNOWN_METHODS`]
+pub const OTHER_METHOD: &str = "other";
+
+/// Histogram buckets for request and response sizes, in bytes
+pub const SIZE_BUCKETS: &[f64] = &[
+    256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0,
+];
+
+/// Histogram buckets for request latency, in seconds
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Metric for request sizes
+pub const REQUEST_SIZE_METRIC: &str = "darknode_request_size_bytes";
+
+/// Metric for response sizes
+pub const RESPONSE_SIZE_METRIC: &str = "darknode_response_size_bytes";
+
+/// Metric for request latency through the circuit
+pub const LATENCY_METRIC: &str = "darknode_request_duration_seconds";
+
+/// Metric for the estimated number of distinct users today
+pub const UNIQUE_USERS_METRIC: &str = "darknode_daily_unique_users";
+
+/// Install the Prometheus recorder, with buckets for the size and latency histograms
+pub fn install_prometheus() -> Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full(REQUEST_SIZE_METRIC.to_string()), SIZE_BUCKETS)?
+        .set_buckets_for_metric(Matcher::Full(RESPONSE_SIZE_METRIC.to_string()), SIZE_BUCKETS)?
+        .set_buckets_for_metric(Matcher::Full(LATENCY_METRIC.to_string()), LATENCY_BUCKETS)?
+        .install_recorder()?;
+    Ok(handle)
+}
+
+/// The label a method is reported under
+pub fn method_label(method: &str) -> &'static str {
+    KNOWN_METHODS
+        .iter()
+        .find(|known| **known == method)
+        .copied()
+        .unwrap_or(OTHER_METHOD)
+}
+
+/// Record the size of a request
+pub fn record_request(method: &'static str, size: usize) {
+    metrics::histogram!(REQUEST_SIZE_METRIC, size as f64, "method" => method);
+}
+
+/// Record the size and latency of a response
+pub fn record_response(method: &'static str, size: usize, latency: Duration) {
+    metrics::histogram!(RESPONSE_SIZE_METRIC, size as f64, "method" => method);
+    metrics::histogram!(LATENCY_METRIC, latency.as_secs_f64(), "method" => method);
+}
+
+/// Number of index bits; 2^10 registers give a standard error of about 3%
+const HLL_PRECISION: u32 = 10;
+
+/// A HyperLogLog cardinality estimator
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+    keys: RandomState,
+}
+
+impl HyperLogLog {
+    /// Create an empty estimator with fresh random hash keys
+    pub fn new() -> Self {
+        Self {
+            registers: vec![0; 1 << HLL_PRECISION],
+            keys: RandomState::new(),
+        }
+    }
+    
+    /// Count an item
+    pub fn insert(&mut self, item: impl Hash) {
+        let mut hasher = self.keys.build_hasher();
+        item.hash(&mut hasher);
+        let hash = hasher.finish();
+        
+        let index = (hash >> (64 - HLL_PRECISION)) as usize;
+        let rank = ((hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1))).leading_zeros() as u8 + 1;
+        self.registers[index] = self.registers[index].max(rank);
+    }
+    
+    /// Estimated number of distinct items counted
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let raw = alpha * m * m / sum;
+        
+        // Linear counting is far more accurate while most registers are still empty
+        let empty = self.registers.iter().filter(|&&r| r == 0).count();
+        if raw <= 2.5 * m && empty > 0 {
+            m * (m / empty as f64).ln()
+        } else {
+            raw
+        }
+    }
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Estimated distinct users per UTC day
+///
+/// The estimator, including its hash keys, is replaced at midnight, so nothing links one
+/// day's observations to the next.
+pub struct DailyUniqueUsers {
+    state: parking_lot::Mutex<(u64, HyperLogLog)>,
+}
+
+impl DailyUniqueUsers {
+    /// Start counting from zero
+    pub fn new() -> Self {
+        Self {
+            state: parking_lot::Mutex::new((0, HyperLogLog::new())),
+        }
+    }
+    
+    /// Count a user at `now`, returning the estimate for the day so far
+    pub fn observe(&self, user_id: Uuid, now: Timestamp) -> u64 {
+        let mut state = self.state.lock();
+        Self::roll_over(&mut state, now);
+        state.1.insert(user_id);
+        let estimate = state.1.estimate().round() as u64;
+        metrics::gauge!(UNIQUE_USERS_METRIC, estimate as f64);
+        estimate
+    }
+    
+    /// Estimated distinct users so far on the day of `now`
+    pub fn estimate(&self, now: Timestamp) -> u64 {
+        let mut state = self.state.lock();
+        Self::roll_over(&mut state, now);
+        state.1.estimate().round() as u64
+    }
+    
+    /// Start a fresh estimator if `now` is on a later day
+    fn roll_over(state: &mut (u64, HyperLogLog), now: Timestamp) {
+        let day = now.as_secs() / 86_400;
+        if state.0 != day {
+            *state = (day, HyperLogLog::new());
+        }
+    }
+}
+
+impl Default for DailyUniqueUsers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EntryConfig;
+    use crate::context::RequestContext;
+    use crate::fixtures::{self, StubRouter};
+    use crate::traits::UserManager;
+    use serde_json::json;
+    
+    #[tokio::test]
+    async fn two_users_are_counted_without_their_keys_or_circuits_in_any_metric() {
+        let prometheus = fixtures::prometheus();
+        let router = Arc::new(StubRouter::new(|_| json!({ "jsonrpc": "2.0", "result": 311_029_712 })));
+        let (entry, users) = fixtures::entry(router.clone(), &EntryConfig::default()).await;
+        let first = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+        let second = users.create_user("9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin").await.unwrap();
+        let request = serde_json::to_vec(&json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" })).unwrap();
+        for user in [&first, &second, &first] {
+            entry.handle_request(RequestContext::new(&user.api_key), &request).await.unwrap();
+        }
+        
+        // Two users land in the same register about once in a thousand runs, estimating one
+        let unique = entry.counters().unique_users().unwrap();
+        assert!(unique.abs_diff(2) <= 1, "estimated {} users", unique);
+        
+        let exported = prometheus.render();
+        let mut identifiers = vec![first.api_key, second.api_key, first.id.to_string(), second.id.to_string()];
+        identifiers.extend(router.circuits().into_iter().map(|circuit| circuit.id.0.to_string()));
+        for identifier in &identifiers {
+            assert!(!exported.contains(identifier.as_str()), "{} exported", identifier);
+        }
+        
+        // The traffic metrics are labelled by method alone, and histogram buckets by bound
+        let metrics = [REQUEST_SIZE_METRIC, RESPONSE_SIZE_METRIC, LATENCY_METRIC];
+        let recorded: Vec<&str> = exported
+            .lines()
+            .filter(|line| metrics.iter().any(|metric| line.starts_with(metric)))
+            .collect();
+        assert!(recorded.iter().any(|line| line.contains(r#"method="getSlot""#)));
+        for line in recorded {
+            let labels = line
+                .split_once('{')
+                .and_then(|(_, rest)| rest.split_once('}'))
+                .map_or("", |(labels, _)| labels);
+            for label in labels.split(',').filter(|label| !label.is_empty()) {
+                assert!(label.starts_with("method=") || label.starts_with("le="), "{}", line);
+            }
+        }
+    }
+}
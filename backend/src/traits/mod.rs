@@ -0,0 +1,322 @@
+//! Traits defining the behavior of different components in the DarkNode system
+
+use super::*;
+use super::types::*;
+use super::context::RequestContext;
+use super::method_routing::MethodRoutes;
+
+/// A stream of response chunks in delivery order
+pub type ResponseStream = futures::stream::BoxStream<'static, Result<ResponseChunk>>;
+
+/// Trait for components that can encrypt and decrypt data
+#[async_trait]
+pub trait Crypto {
+    /// Generate a new key pair
+    async fn generate_keypair(&self) -> Result<(CryptoKey, CryptoKey)>;
+    
+    /// Encrypt data with a public key
+    async fn encrypt(&self, data: &[u8], public_key: &CryptoKey) -> Result<EncryptedData>;
+    
+    /// Decrypt data with a private key
+    async fn decrypt(&self, data: &EncryptedData, private_key: &CryptoKey) -> Result<Vec<u8>>;
+    
+    /// Sign data with a private key
+    async fn sign(&self, data: &[u8], private_key: &CryptoKey) -> Result<Vec<u8>>;
+    
+    /// Verify a signature with a public key
+    async fn verify(&self, data: &[u8], signature: &[u8], public_key: &CryptoKey) -> Result<bool>;
+}
+
+/// Trait for components that can route requests through the network
+#[async_trait]
+pub trait Router {
+    /// Create a new circuit through the network
+    async fn create_circuit(&self) -> Result<Circuit>;
+    
+    /// Create a new circuit, honouring the preferences where the network allows
+    ///
+    /// Routers that can't select by preference build an ordinary circuit.
+    async fn create_circuit_with(&self, preferences: &CircuitPreferences) -> Result<Circuit> {
+        let _ = preferences;
+        self.create_circuit().await
+    }
+    
+    /// Tear down a circuit the caller will send nothing more through
+    ///
+    /// Routers holding no per-circuit state have nothing to release.
+    async fn close_circuit(&self, circuit: &Circuit) -> Result<()> {
+        let _ = circuit;
+        Ok(())
+    }
+    
+    /// Send a request through a circuit on behalf of the request described by `ctx`
+    ///
+    /// The payload is already sealed; `ctx` is for routers that schedule or give up on
+    /// requests by their deadline or priority. Notifications, flagged in `ctx`, are sent
+    /// fire-and-forget: nothing is kept to correlate an answer with, and
+    /// `receive_response` is never called for them. A failure pinned on one hop of the
+    /// circuit is reported as a [`crate::replay::HopFailure`].
+    async fn send_request(&self, ctx: &RequestContext, circuit: &Circuit, request: &[u8]) -> Result<Uuid>;
+    
+    /// Receive a response from a circuit
+    ///
+    /// As with `send_request`, a failure pinned on one hop is a [`crate::replay::HopFailure`].
+    async fn receive_response(&self, request_id: Uuid) -> Result<Vec<u8>>;
+    
+    /// Receive a response from a circuit as chunks arrive
+    ///
+    /// Routers without internal chunking deliver the whole response as a single chunk.
+    async fn receive_response_stream(&self, request_id: Uuid) -> Result<ResponseStream> {
+        let data = self.receive_response(request_id).await?;
+        let chunk = ResponseChunk {
+            request_id,
+            sequence: 0,
+            data,
+            last: true,
+        };
+        Ok(Box::pin(futures::stream::once(async move { Ok(chunk) })))
+    }
+}
+
+/// Trait for components that can manage nodes in the network
+#[async_trait]
+pub trait NodeManager {
+    /// Register a new node in the network
+    async fn register_node(&self, node: Node) -> Result<()>;
+    
+    /// Update a node's status
+    async fn update_node_status(&self, node_id: &NodeId, status: NodeStatus) -> Result<()>;
+    
+    /// Get a list of available nodes of a specific role
+    async fn get_available_nodes(&self, role: NodeRole) -> Result<Vec<Node>>;
+    
+    /// Get a specific node by ID
+    async fn get_node(&self, node_id: &NodeId) -> Result<Option<Node>>;
+    
+    /// Publish the key a node will switch to at `activates_at`
+    async fn publish_next_key(&self, node_id: &NodeId, next_public_key: CryptoKey, activates_at: Timestamp) -> Result<()>;
+}
+
+/// Trait for components that can manage RPC providers
+#[async_trait]
+pub trait RpcManager {
+    /// Register a new RPC provider
+    async fn register_provider(&self, provider: RpcProvider) -> Result<()>;
+    
+    /// Replace a registered provider's settings, keeping its ID
+    async fn update_provider(&self, provider: RpcProvider) -> Result<()>;
+    
+    /// Update an RPC provider's status
+    async fn update_provider_status(&self, provider_id: Uuid, active: bool) -> Result<()>;
+    
+    /// Get a list of active RPC providers
+    async fn get_active_providers(&self) -> Result<Vec<RpcProvider>>;
+    
+    /// Get every registered RPC provider, active or not
+    async fn get_providers(&self) -> Result<Vec<RpcProvider>>;
+    
+    /// Remove an RPC provider
+    async fn remove_provider(&self, provider_id: Uuid) -> Result<()>;
+    
+    /// Record the outcome of a health probe against a provider
+    async fn record_probe(&self, provider_id: Uuid, healthy: bool, latency: Duration) -> Result<()>;
+    
+    /// Get the best RPC provider based on performance metrics
+    async fn get_best_provider(&self) -> Result<Option<RpcProvider>>;
+    
+    /// Record that a provider returned a response contradicted by its peers
+    async fn record_provider_misbehavior(&self, provider_id: Uuid, reason: &str) -> Result<()>;
+}
+
+/// Trait for components that can manage user accounts
+#[async_trait]
+pub trait UserManager {
+    /// Create a new user for a wallet
+    ///
+    /// Fails with [`crate::wallets::InvalidWalletAddress`] unless the address is a valid
+    /// Solana or Ethereum address, which is stored normalized.
+    async fn create_user(&self, wallet_address: &str) -> Result<User>;
+    
+    /// Get a user by ID
+    async fn get_user(&self, user_id: Uuid) -> Result<Option<User>>;
+    
+    /// Get a user by API key
+    async fn get_user_by_api_key(&self, api_key: &str) -> Result<Option<User>>;
+    
+    /// Get a user by wallet address, compared in its normalized form
+    async fn get_user_by_wallet(&self, wallet_address: &str) -> Result<Option<User>>;
+    
+    /// Add an RPC mapping for a user
+    async fn add_rpc_mapping(&self, user_id: Uuid, mapping: RpcMapping) -> Result<()>;
+    
+    /// Add several RPC mappings for a user, all or none, failing with
+    /// [`QuotaExceeded`](crate::managers::quota::QuotaExceeded) if the user would then hold
+    /// more than `plan` allows
+    ///
+    /// The default checks the cap and then adds the mappings one at a time, so stores that
+    /// can fail part way through, or serve several coordinators, should override it to check
+    /// and add in one transaction.
+    async fn add_rpc_mappings(&self, user_id: Uuid, mappings: Vec<RpcMapping>, plan: &Plan) -> Result<()> {
+        if self.get_rpc_mappings(user_id).await?.len() + mappings.len() > plan.max_mappings as usize {
+            return Err(crate::managers::quota::QuotaExceeded::mappings(plan).into());
+        }
+        for mapping in mappings {
+            self.add_rpc_mapping(user_id, mapping).await?;
+        }
+        Ok(())
+    }
+    
+    /// Get all RPC mappings for a user
+    async fn get_rpc_mappings(&self, user_id: Uuid) -> Result<Vec<RpcMapping>>;
+    
+    /// Remove one of a user's mappings, along with the routing rules of their other
+    /// mappings that send requests to it, see [`crate::method_routing::forget`]
+    async fn remove_rpc_mapping(&self, user_id: Uuid, mapping_id: Uuid) -> Result<()>;
+    
+    /// Replace the routing rules of one of a user's mappings
+    ///
+    /// Fails with [`crate::method_routing::InvalidRoutes`] unless the rules of all the
+    /// user's mappings are valid together, and leaves the rules as they were.
+    async fn set_method_routes(&self, user_id: Uuid, mapping_id: Uuid, routes: MethodRoutes) -> Result<()>;
+    
+    /// Create a new subscription plan
+    async fn create_plan(&self, plan: Plan) -> Result<()>;
+    
+    /// Get a plan by ID
+    async fn get_plan(&self, plan_id: Uuid) -> Result<Option<Plan>>;
+    
+    /// Assign a plan to a user
+    async fn set_user_plan(&self, user_id: Uuid, plan_id: Uuid) -> Result<()>;
+    
+    /// Record whether a user agrees to have their usage audited
+    ///
+    /// Only the user may decide this, so callers must have authenticated them.
+    async fn set_audit_consent(&self, user_id: Uuid, consent: bool) -> Result<()>;
+    
+    /// Create a further API key for a user, limited by `scopes`
+    ///
+    /// Fails with [`crate::scopes::ScopeError`] unless the scopes are valid.
+    async fn create_key(&self, user_id: Uuid, name: &str, scopes: Vec<crate::scopes::Scope>) -> Result<ApiKey>;
+    
+    /// Replace the scopes of one of a user's further API keys
+    ///
+    /// Fails with [`crate::scopes::ScopeError`] unless the scopes are valid, and leaves them
+    /// as they were.
+    async fn set_key_scopes(&self, user_id: Uuid, key_id: Uuid, scopes: Vec<crate::scopes::Scope>) -> Result<ApiKey>;
+}
+
+/// Trait for components that can sanitize requests to remove identifying information
+#[async_trait]
+pub trait RequestSanitizer {
+    /// Sanitize an RPC request of the user `owner`'s to remove identifying information
+    async fn sanitize_request(&self, owner: Uuid, request: &[u8]) -> Result<Vec<u8>>;
+    
+    /// Prepare a response for delivery back to the client
+    async fn prepare_response(&self, response: &[u8]) -> Result<Vec<u8>>;
+    
+    /// Prepare a single chunk of a streamed response for delivery back to the client
+    async fn prepare_response_chunk(&self, chunk: &[u8]) -> Result<Vec<u8>> {
+        Ok(chunk.to_vec())
+    }
+    
+    /// Sanitize a request of the user `owner`'s and wrap it in the payload carried to the exit node
+    ///
+    /// Provider hints and the relay flag in the request's extension field are turned into
+    /// payload settings; the extension itself never leaves the entry node.
+    async fn sanitize_payload(&self, owner: Uuid, request: &[u8]) -> Result<ExitPayload> {
+        let sanitized = self.sanitize_request(owner, request).await?;
+        let mut request: serde_json::Value = serde_json::from_slice(&sanitized)?;
+        let relay = super::relay::requested(&request);
+        let notification = super::methods::is_notification(&request);
+        let capabilities = super::capabilities::take_hints(&mut request)?;
+        let chain = super::methods::method_name(&request).and_then(super::chains::detect);
+        Ok(ExitPayload {
+            request,
+            quorum: None,
+            capabilities,
+            trace_token: None,
+            preflight: false,
+            relay,
+            debug_errors: false,
+            timeout: None,
+            notification,
+            chain,
+            network: super::chains::Network::Mainnet,
+            normalize: false,
+            timing: false,
+            attribution: false,
+            provider: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    
+    /// A router that can't build circuits and answers every request with the same bytes
+    struct Fixed;
+    
+    #[async_trait]
+    impl Router for Fixed {
+        async fn create_circuit(&self) -> Result<Circuit> {
+            anyhow::bail!("no preferences")
+        }
+        
+        async fn send_request(&self, _ctx: &RequestContext, _circuit: &Circuit, _request: &[u8]) -> Result<Uuid> {
+            Ok(Uuid::new_v4())
+        }
+        
+        async fn receive_response(&self, _request_id: Uuid) -> Result<Vec<u8>> {
+            Ok(b"answer".to_vec())
+        }
+    }
+    
+    /// A sanitizer that leaves requests as they are
+    struct Verbatim;
+    
+    #[async_trait]
+    impl RequestSanitizer for Verbatim {
+        async fn sanitize_request(&self, _owner: Uuid, request: &[u8]) -> Result<Vec<u8>> {
+            Ok(request.to_vec())
+        }
+        
+        async fn prepare_response(&self, response: &[u8]) -> Result<Vec<u8>> {
+            Ok(response.to_vec())
+        }
+    }
+    
+    #[tokio::test]
+    async fn routers_without_preferences_build_an_ordinary_circuit() {
+        let err = Fixed.create_circuit_with(&CircuitPreferences::default()).await.unwrap_err();
+        assert_eq!(err.to_string(), "no preferences");
+    }
+    
+    #[tokio::test]
+    async fn routers_without_chunking_stream_the_whole_response_as_one_chunk() {
+        let request_id = Uuid::new_v4();
+        let chunks: Vec<_> = Fixed.receive_response_stream(request_id).await.unwrap().collect().await;
+        
+        assert_eq!(chunks.len(), 1);
+        let chunk = chunks.into_iter().next().unwrap().unwrap();
+        assert_eq!((chunk.request_id, chunk.sequence, chunk.last), (request_id, 0, true));
+        assert_eq!(chunk.data, b"answer");
+    }
+    
+    #[tokio::test]
+    async fn the_extension_is_turned_into_payload_settings_and_never_leaves() {
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "sendTransaction",
+            "params": ["AQABAg=="],
+            "darknode": {"capabilities": ["archive"], "refresh_blockhash": true},
+        });
+        let payload = Verbatim.sanitize_payload(Uuid::new_v4(), &serde_json::to_vec(&request).unwrap()).await.unwrap();
+        
+        assert!(payload.request.get(crate::methods::EXTENSION_KEY).is_none());
+        assert_eq!(payload.capabilities, vec!["archive".to_string()]);
+        assert!(payload.relay && payload.notification);
+        assert_eq!(payload.chain, Some(crate::chains::Chain::Solana));
+    }
+}
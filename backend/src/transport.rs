@@ -0,0 +1,211 @@
+//! Carrying circuit handshakes and requests between nodes
+//!
+//! A circuit is built with a handshake sent along its path before its first request. The
+//! entry node seals each hop's part of it, an [`ExtendLayer`], to that hop's identity key,
+//! and nests the layers inside one another, the exit node's innermost, so each hop learns
+//! only what it needs and the hop after it. A routing node opens its layer, starts carrying
+//! the circuit and passes the rest on; the exit node opens its layer and joins the circuit
+//! under the key, protocol version and class it names, see [`crate::membership`].
+//!
+//! Requests then travel the same path, to `POST /forward` on routing nodes and `POST /`
+//! on the exit node, each hop answering with the response of the one after it. Every
+//! message is signed by the node sending it, see [`crate::hop_auth`]. A hop that can't be
+//! reached, no longer holds the circuit, is at capacity, or fails the message with a server
+//! error of its own, is reported as a [`HopFailure`] naming it, which the hops before it
+//! pass back as they got it. A routing node waits on the next hop only until the request's
+//! deadline, then reports that hop as timed out.
+//!
+//! A circuit the entry node is done with is torn down the same way, a [`CircuitDestroy`]
+//! sent to `POST /destroy` on its first hop and passed on by each routing node, so no hop
+//! keeps carrying it until it is reclaimed.
+
+use super::*;
+use super::circuit_class::CircuitClass;
+use super::hop_auth::HopSigner;
+use super::identity::NodeIdentity;
+use super::replay::{HopFailure, HopFailureKind};
+use super::traits::Crypto;
+use super::types::{CircuitId, CryptoKey, EncryptedData, Node, NodeId, NodeRole, Request, Response};
+use axum::http::StatusCode;
+use serde::de::DeserializeOwned;
+use std::net::SocketAddr;
+
+/// Path of the handshake on routing and exit nodes
+pub const EXTEND_PATH: &str = "/extend";
+
+/// Path routing nodes take requests on
+pub const FORWARD_PATH: &str = "/forward";
+
+/// Path exit nodes take requests on
+pub const EXIT_PATH: &str = "/";
+
+/// Path of a circuit's teardown on routing and exit nodes
+pub const DESTROY_PATH: &str = "/destroy";
+
+/// Body of a request sent to a hop
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestMessage {
+    /// The encrypted request
+    pub request: Request,
+}
+
+/// Body of a hop's answer to a request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseMessage {
+    /// The encrypted response
+    pub response: Response,
+}
+
+/// One hop's part of a circuit's handshake, sealed to the hop's identity key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CircuitExtend {
+    /// The circuit being built
+    pub circuit_id: CircuitId,
+    /// The hop's [`ExtendLayer`], encrypted to its identity key
+    pub layer: EncryptedData,
+}
+
+/// Tells a hop to stop carrying a circuit, and to pass the teardown on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CircuitDestroy {
+    /// The circuit torn down
+    pub circuit_id: CircuitId,
+}
+
+/// What a hop learns from a circuit's handshake
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtendLayer {
+    /// Carry the circuit to the next hop
+    Relay {
+        /// How long the circuit lives
+        ttl: Duration,
+        /// The hop after this one, and its part of the handshake
+        next: Box<NextHop>,
+    },
+    /// Join the circuit as its exit node
+    Exit {
+        /// The circuit's key, which requests and responses are ratcheted from
+        key: CryptoKey,
+        /// The protocol version the circuit's plaintext is framed in, see [`crate::protocol`]
+        version: u16,
+        /// The kind of traffic the circuit carries
+        class: CircuitClass,
+        /// How long the circuit lives
+        ttl: Duration,
+    },
+}
+
+/// The hop a routing node passes a circuit's messages on to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NextHop {
+    /// The node
+    pub node_id: NodeId,
+    /// Where it listens
+    pub address: SocketAddr,
+    /// Whether it routes or exits, which tells the path its requests go to
+    pub role: NodeRole,
+    /// Its part of the handshake
+    pub extend: CircuitExtend,
+}
+
+/// Where a node listens
+pub fn address_of(node: &Node) -> SocketAddr {
+    SocketAddr::new(node.ip_address, node.port)
+}
+
+/// The path requests are sent to on a hop of `role`
+pub fn request_path(role: NodeRole) -> &'static str {
+    match role {
+        NodeRole::Exit => EXIT_PATH,
+        _ => FORWARD_PATH,
+    }
+}
+
+/// Seal `layer` of the handshake of `circuit_id` to `node`'s identity key at `now`
+pub async fn seal(
+    crypto: &(dyn Crypto + Send + Sync),
+    circuit_id: &CircuitId,
+    layer: &ExtendLayer,
+    node: &Node,
+    now: Timestamp,
+) -> Result<CircuitExtend> {
+    let layer = crypto.encrypt(&serde_json::to_vec(layer)?, node.active_public_key(now)).await?;
+    Ok(CircuitExtend {
+        circuit_id: circuit_id.clone(),
+        layer,
+    })
+}
+
+/// Open this node's layer of `extend` with any of its keys live at `now`
+pub async fn open(
+    crypto: &(dyn Crypto + Send + Sync),
+    identity: &NodeIdentity,
+    extend: &CircuitExtend,
+    now: Timestamp,
+) -> Result<ExtendLayer> {
+    let layer = identity.decrypt(crypto, &extend.layer, now).await?;
+    Ok(serde_json::from_slice(&layer)?)
+}
+
+/// Sends signed messages to other nodes
+pub struct HopClient {
+    client: reqwest::Client,
+    signer: HopSigner,
+}
+
+impl HopClient {
+    /// Create a client signing what it sends with `signer`
+    pub fn new(signer: HopSigner) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            signer,
+        }
+    }
+    
+    /// Send `message` to `path` on the node `hop` at `address`, returning its answer
+    ///
+    /// Failures of the hop, or reported by a hop after it, are returned as a [`HopFailure`].
+    pub async fn send<T: Serialize, R: DeserializeOwned>(
+        &self,
+        hop: &NodeId,
+        address: SocketAddr,
+        path: &str,
+        message: &T,
+    ) -> Result<R> {
+        let body = serde_json::to_vec(message)?;
+        let mut request = self
+            .client
+            .post(format!("http://{}{}", address, path))
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        for (name, value) in self.signer.headers(&body, Timestamp::now()).await? {
+            request = request.header(name, value);
+        }
+        let failure = |kind| HopFailure {
+            node: Some(hop.clone()),
+            kind,
+        };
+        let response = match request.body(body).send().await {
+            Ok(response) => response,
+            Err(e) if e.is_timeout() => return Err(failure(HopFailureKind::TimedOut).into()),
+            Err(e) => return Err(anyhow::Error::new(failure(HopFailureKind::Unreachable)).context(e.to_string())),
+        };
+        
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response.json().await?);
+        }
+        let reported = response.json::<HopFailure>().await.ok();
+        Err(match (reported, status.as_u16()) {
+            (Some(reported), _) => reported.into(),
+            (None, 404) => failure(HopFailureKind::CircuitUnknown).into(),
+            (None, 503) => failure(HopFailureKind::AtCapacity).into(),
+            (None, 504) => failure(HopFailureKind::TimedOut).into(),
+            (None, status) if status >= 500 => failure(HopFailureKind::Failed).into(),
+            (None, _) => anyhow::anyhow!("Node {} refused the message with {}", hop.0, status),
+        })
+    }
+}
+
+/// Status a hop answers a failure of a hop after it with, the failure being the body
+pub const RELAYED_FAILURE_STATUS: StatusCode = StatusCode::BAD_GATEWAY;
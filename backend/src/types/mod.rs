@@ -0,0 +1,958 @@
+//! Core types used throughout the DarkNode system
+
+use super::*;
+use std::collections::BTreeMap;
+
+/// Unique identifier for a node in the DarkNode network
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NodeId(pub Uuid);
+
+/// Unique identifier for a circuit through the DarkNode network
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CircuitId(pub Uuid);
+
+/// Represents a cryptographic key used for encryption and authentication
+///
+/// Every copy is overwritten as it is dropped, so a key erased by its owner, such as a
+/// ratchet's, leaves nothing behind in copies handed out for single messages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CryptoKey(pub Vec<u8>);
+
+impl Drop for CryptoKey {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.0);
+    }
+}
+
+/// Represents an encrypted payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedData {
+    /// The encrypted data
+    pub data: Vec<u8>,
+    /// The nonce used for encryption
+    pub nonce: Vec<u8>,
+    /// Additional authenticated data
+    pub aad: Option<Vec<u8>>,
+}
+
+/// Represents a node's role in the DarkNode network
+///
+/// Serialized by variant name, and parsed from it or its lowercase form in any case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String")]
+pub enum NodeRole {
+    /// Entry nodes accept connections from users
+    Entry,
+    /// Routing nodes forward traffic through the network
+    Routing,
+    /// Exit nodes connect to RPC providers
+    Exit,
+    /// Coordinator nodes manage the network topology
+    Coordinator,
+}
+
+impl NodeRole {
+    /// Every role
+    pub const ALL: [NodeRole; 4] = [NodeRole::Entry, NodeRole::Routing, NodeRole::Exit, NodeRole::Coordinator];
+    
+    /// The role's name in paths, flags, and logs
+    pub fn name(self) -> &'static str {
+        match self {
+            NodeRole::Entry => "entry",
+            NodeRole::Routing => "routing",
+            NodeRole::Exit => "exit",
+            NodeRole::Coordinator => "coordinator",
+        }
+    }
+}
+
+impl std::fmt::Display for NodeRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for NodeRole {
+    type Err = UnknownVariant;
+    
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        parse_variant("node role", s, &Self::ALL, |role| role.name())
+    }
+}
+
+impl TryFrom<String> for NodeRole {
+    type Error = UnknownVariant;
+    
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Error parsing a name that isn't one of an enum's variants
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("unknown {kind} `{value}`, expected one of: {}", expected.join(", "))]
+pub struct UnknownVariant {
+    /// What was being parsed
+    pub kind: &'static str,
+    /// The name given
+    pub value: String,
+    /// The names accepted
+    pub expected: Vec<&'static str>,
+}
+
+/// The variant named `s`, ignoring case
+fn parse_variant<T: Copy>(kind: &'static str, s: &str, all: &[T], name: fn(T) -> &'static str) -> std::result::Result<T, UnknownVariant> {
+    all.iter()
+        .copied()
+        .find(|variant| name(*variant).eq_ignore_ascii_case(s.trim()))
+        .ok_or_else(|| UnknownVariant {
+            kind,
+            value: s.to_string(),
+            expected: all.iter().map(|variant| name(*variant)).collect(),
+        })
+}
+
+/// The roles of a node, flattened into the structs carrying them
+///
+/// Written as `roles` and, for older peers that read a single role, as `role` holding the
+/// first of them. Read from `roles` if present, else from `role`, either holding one role
+/// or a list.
+mod roles_field {
+    use super::NodeRole;
+    use serde::de::Error as _;
+    use serde::ser::SerializeMap;
+    use serde::{Deserialize, Deserializer, Serializer};
+    
+    /// Either a single role or a list of roles
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(NodeRole),
+        Many(Vec<NodeRole>),
+    }
+    
+    impl From<OneOrMany> for Vec<NodeRole> {
+        fn from(roles: OneOrMany) -> Self {
+            match roles {
+                OneOrMany::One(role) => vec![role],
+                OneOrMany::Many(roles) => roles,
+            }
+        }
+    }
+    
+    /// Write `roles`, and `role` if there is one
+    pub fn serialize<S: Serializer>(roles: &[NodeRole], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        if let Some(role) = roles.first() {
+            map.serialize_entry("role", role)?;
+        }
+        map.serialize_entry("roles", roles)?;
+        map.end()
+    }
+    
+    /// Read `roles`, falling back on `role`
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<NodeRole>, D::Error> {
+        #[derive(Deserialize)]
+        struct Fields {
+            #[serde(default)]
+            role: Option<OneOrMany>,
+            #[serde(default)]
+            roles: Option<OneOrMany>,
+        }
+        
+        let fields = Fields::deserialize(deserializer)?;
+        fields.roles.or(fields.role).map(Vec::from).ok_or_else(|| D::Error::missing_field("roles"))
+    }
+}
+
+/// Represents the status of a node
+///
+/// Serialized by variant name, and parsed from it or its lowercase form in any case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String")]
+pub enum NodeStatus {
+    /// Node is online and ready to accept connections
+    Online,
+    /// Node is online but not accepting new connections
+    Busy,
+    /// Node is offline
+    Offline,
+    /// Node is in maintenance mode
+    Maintenance,
+}
+
+impl NodeStatus {
+    /// Every status
+    pub const ALL: [NodeStatus; 4] = [NodeStatus::Online, NodeStatus::Busy, NodeStatus::Offline, NodeStatus::Maintenance];
+    
+    /// The status's name in paths, flags, and logs
+    pub fn name(self) -> &'static str {
+        match self {
+            NodeStatus::Online => "online",
+            NodeStatus::Busy => "busy",
+            NodeStatus::Offline => "offline",
+            NodeStatus::Maintenance => "maintenance",
+        }
+    }
+}
+
+impl std::fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for NodeStatus {
+    type Err = UnknownVariant;
+    
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        parse_variant("node status", s, &Self::ALL, |status| status.name())
+    }
+}
+
+impl TryFrom<String> for NodeStatus {
+    type Error = UnknownVariant;
+    
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Represents a node in the DarkNode network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Node {
+    /// Unique identifier for the node
+    pub id: NodeId,
+    /// The roles the node serves; older peers send and read a single `role`
+    #[serde(flatten, with = "roles_field")]
+    pub roles: Vec<NodeRole>,
+    /// The status of the node
+    pub status: NodeStatus,
+    /// The public key of the node
+    pub public_key: CryptoKey,
+    /// The IP address of the node
+    pub ip_address: IpAddr,
+    /// The port the node is listening on
+    pub port: u16,
+    /// When the node was last seen
+    pub last_seen: Timestamp,
+    /// The geographic region of the node
+    pub region: String,
+    /// The load on the node (0.0 - 1.0)
+    #[serde(deserialize_with = "crate::scoring::fraction")]
+    pub load: f64,
+    /// The key that replaces `public_key` once `next_key_activates_at` passes
+    #[serde(default)]
+    pub next_public_key: Option<CryptoKey>,
+    /// When `next_public_key` becomes the node's only key
+    #[serde(default)]
+    pub next_key_activates_at: Option<Timestamp>,
+    /// The provider pool an exit node serves from, if it has its own
+    #[serde(default)]
+    pub pool: Option<String>,
+    /// The protocol versions the node speaks, see [`crate::protocol`]
+    #[serde(default = "crate::protocol::ProtocolRange::legacy")]
+    pub protocol: crate::protocol::ProtocolRange,
+    /// Share of its request budget an exit node has left, if limited, see [`crate::budget`]
+    #[serde(default)]
+    pub budget: Option<f64>,
+    /// What the node runs, as it last reported, see [`crate::build_info`]
+    #[serde(default)]
+    pub build: Option<crate::build_info::BuildInfo>,
+    /// The method classes an exit node serves, every class if unset, see [`crate::egress`]
+    #[serde(default)]
+    pub method_classes: Option<Vec<crate::timeouts::MethodClass>>,
+    /// The key an entry node signs client receipts with, never rotated, see [`crate::receipts`]
+    #[serde(default)]
+    pub receipt_key: Option<CryptoKey>,
+}
+
+impl Node {
+    /// Whether the node serves `role`
+    pub fn has_role(&self, role: NodeRole) -> bool {
+        self.roles.contains(&role)
+    }
+    
+    /// The key new circuits and signatures should use at `now`
+    pub fn active_public_key(&self, now: Timestamp) -> &CryptoKey {
+        match (&self.next_public_key, self.next_key_activates_at) {
+            (Some(next), Some(activates_at)) if now >= activates_at => next,
+            _ => &self.public_key,
+        }
+    }
+    
+    /// Keys a peer should accept from this node at `now`
+    ///
+    /// Both keys are accepted while a rotation is pending; once the next key activates
+    /// the old key is no longer accepted.
+    pub fn accepted_public_keys(&self, now: Timestamp) -> Vec<&CryptoKey> {
+        match (&self.next_public_key, self.next_key_activates_at) {
+            // Keep accepting the old key for a skew window, the node's clock may lag ours
+            (Some(next), Some(activates_at)) if now >= activates_at + super::clock::MAX_CLOCK_SKEW => vec![next],
+            (Some(next), Some(_)) => vec![&self.public_key, next],
+            _ => vec![&self.public_key],
+        }
+    }
+    
+    /// Replace the public key with the next key if it has activated, returning whether it did
+    pub fn promote_next_key(&mut self, now: Timestamp) -> bool {
+        match self.next_key_activates_at {
+            Some(activates_at) if now >= activates_at => {
+                if let Some(next) = self.next_public_key.take() {
+                    self.public_key = next;
+                }
+                self.next_key_activates_at = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Represents an RPC provider
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcProvider {
+    /// Unique identifier for the provider
+    pub id: Uuid,
+    /// The URL of the provider
+    pub url: String,
+    /// The type of provider (e.g., Solana, Ethereum)
+    pub provider_type: String,
+    /// Where the provider is in its lifecycle; older records' `active` flag is read as
+    /// Active or Disabled
+    #[serde(alias = "active", deserialize_with = "state_or_active")]
+    pub state: ProviderState,
+    /// The success rate of requests to this provider (0.0 - 1.0)
+    #[serde(deserialize_with = "crate::scoring::fraction")]
+    pub success_rate: f64,
+    /// The average latency of requests to this provider
+    pub avg_latency: Duration,
+    /// The last time the provider was checked
+    pub last_checked: Timestamp,
+    /// Capabilities the provider supports (e.g. "archive")
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// The operator pool the provider belongs to, or `None` for the shared pool
+    #[serde(default)]
+    pub pool: Option<String>,
+    /// The network of its chain the provider is on
+    #[serde(default)]
+    pub network: crate::chains::Network,
+    /// Value of the `Authorization` header the provider requires, if any
+    #[serde(default)]
+    pub auth: Option<String>,
+    /// Relative weight in provider selection, scaling the provider's success rate
+    #[serde(default = "default_provider_weight")]
+    pub weight: u32,
+    /// Periods the provider's operator announced it will be down for maintenance
+    #[serde(default)]
+    pub maintenance_windows: Vec<crate::maintenance::MaintenanceWindow>,
+    /// Exit nodes currently reporting the provider's circuit breaker open or half-open
+    #[serde(default)]
+    pub tripped_breakers: u32,
+    /// Probes the provider has failed in a row
+    #[serde(default)]
+    pub failed_probes: u32,
+    /// Requests the provider takes per period, if limited, see [`crate::budget`]
+    #[serde(default)]
+    pub quota: Option<crate::budget::ProviderQuota>,
+    /// Who proposed the provider and how its review went, if it was submitted, see
+    /// [`crate::submissions`]
+    #[serde(default)]
+    pub submission: Option<crate::submissions::Submission>,
+}
+
+impl RpcProvider {
+    /// The provider as shown to anyone asking over HTTP, its `auth` credentials masked, and
+    /// whatever of its URL may hold an API key, see [`crate::config::redact_url`]
+    pub fn redacted(mut self) -> Self {
+        if self.auth.is_some() {
+            self.auth = Some(crate::config::REDACTED.to_string());
+        }
+        self.url = crate::config::redact_url(&self.url);
+        self
+    }
+}
+
+/// Weight of providers registered without one
+fn default_provider_weight() -> u32 {
+    1
+}
+
+/// Probes an active provider must fail in a row before it is taken out of service
+pub const PROBE_FAILURES_TO_DISABLE: u32 = 3;
+
+/// Where a provider is in its lifecycle
+///
+/// Only active providers are selected for user traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderState {
+    /// Submitted and waiting for its first probe
+    Pending,
+    /// Being probed before it is reviewed
+    Trial,
+    /// Serving traffic
+    Active,
+    /// Turned away on review, and no longer probed
+    Rejected,
+    /// Approved, but out of service until it passes a probe or an operator reactivates it
+    Disabled,
+    /// Taken out of service by an operator, and kept out, however its probes go, until
+    /// one reactivates it
+    Suspended,
+}
+
+impl ProviderState {
+    /// Whether the provider waits on an operator's review
+    pub fn is_under_review(self) -> bool {
+        matches!(self, ProviderState::Pending | ProviderState::Trial)
+    }
+    
+    /// Whether the provider was approved, and is only in or out of service
+    pub fn is_approved(self) -> bool {
+        matches!(self, ProviderState::Active | ProviderState::Disabled | ProviderState::Suspended)
+    }
+    
+    /// The state after a probe, the last of `failed_probes` failed in a row if it failed;
+    /// probes take approved providers in and out of service, unless an operator suspended
+    /// them, and start the trial of pending ones
+    ///
+    /// A single failure doesn't take a provider out of service, only
+    /// [`PROBE_FAILURES_TO_DISABLE`] in a row.
+    pub fn after_probe(self, healthy: bool, failed_probes: u32) -> Self {
+        match self {
+            ProviderState::Pending | ProviderState::Trial => ProviderState::Trial,
+            ProviderState::Active | ProviderState::Disabled if healthy => ProviderState::Active,
+            ProviderState::Active if failed_probes < PROBE_FAILURES_TO_DISABLE => ProviderState::Active,
+            ProviderState::Active | ProviderState::Disabled => ProviderState::Disabled,
+            ProviderState::Rejected => ProviderState::Rejected,
+            ProviderState::Suspended => ProviderState::Suspended,
+        }
+    }
+}
+
+/// Accept either a provider state or the `active` flag it replaced
+fn state_or_active<'de, D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<ProviderState, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StateOrActive {
+        State(ProviderState),
+        Active(bool),
+    }
+    
+    Ok(match StateOrActive::deserialize(deserializer)? {
+        StateOrActive::State(state) => state,
+        StateOrActive::Active(true) => ProviderState::Active,
+        StateOrActive::Active(false) => ProviderState::Disabled,
+    })
+}
+
+/// Represents a user of the DarkNode service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    /// Unique identifier for the user
+    pub id: Uuid,
+    /// The user's wallet address, normalized for its chain, see [`crate::wallets`]
+    pub wallet_address: String,
+    /// The chain the user's wallet belongs to
+    #[serde(default)]
+    pub wallet_chain: crate::wallets::WalletChain,
+    /// The API key assigned to the user, allowed everything their plan allows
+    pub api_key: String,
+    /// The user's further API keys, each limited by its scopes, see [`crate::scopes`]
+    #[serde(default)]
+    pub keys: Vec<ApiKey>,
+    /// Whether the user's subscription is active
+    pub active: bool,
+    /// When the user's subscription expires
+    pub expires_at: Option<Timestamp>,
+    /// The user's custom RPC mappings
+    pub rpc_mappings: Vec<RpcMapping>,
+    /// The plan the user is subscribed to (the default plan if unset)
+    #[serde(default)]
+    pub plan_id: Option<Uuid>,
+    /// Whether the user is the network's canary, whose traffic is left out of user-facing stats
+    #[serde(default)]
+    pub canary: bool,
+    /// Whether the user agreed to have their usage recorded, see [`crate::compliance`]
+    #[serde(default)]
+    pub audit_consent: bool,
+}
+
+/// One of a user's further API keys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// Unique identifier for the key
+    pub id: Uuid,
+    /// The key itself
+    pub key: String,
+    /// What the key is for, such as the environment it is used in
+    pub name: String,
+    /// What the key is limited to, see [`crate::scopes`]
+    #[serde(default)]
+    pub scopes: Vec<crate::scopes::Scope>,
+    /// When the key was created
+    pub created_at: Timestamp,
+}
+
+/// Scheduling priority granted to a plan's traffic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum PriorityClass {
+    /// Best-effort traffic, shed first under load
+    Low,
+    /// Regular paid traffic
+    Standard,
+    /// Traffic that is served first and shed last
+    High,
+}
+
+/// Represents a subscription plan and the limits it grants
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Plan {
+    /// Unique identifier for the plan
+    pub id: Uuid,
+    /// Human-readable name of the plan
+    pub name: String,
+    /// Maximum number of requests per UTC day
+    pub daily_request_cap: u64,
+    /// Maximum number of concurrently active circuits
+    pub max_circuits: u32,
+    /// Maximum number of concurrent WebSocket subscriptions
+    pub max_subscriptions: u32,
+    /// Scheduling priority of the plan's traffic
+    pub priority_class: PriorityClass,
+    /// Maximum number of RPC mappings a user holds
+    #[serde(default = "Plan::default_max_mappings")]
+    pub max_mappings: u32,
+    /// Largest quorum a request may ask providers to agree in, see [`crate::quorum`]
+    #[serde(default = "Plan::default_max_quorum")]
+    pub max_quorum: u8,
+    /// What the plan costs per billing period, if it is billed, see [`crate::billing`]
+    #[serde(default)]
+    pub pricing: Option<crate::billing::PlanPricing>,
+}
+
+impl Plan {
+    /// The mapping cap of plans created before there was one, for serde defaults
+    pub fn default_max_mappings() -> u32 {
+        Self::default().max_mappings
+    }
+    
+    /// The quorum cap of plans created before there was one, for serde defaults
+    pub fn default_max_quorum() -> u8 {
+        Self::default().max_quorum
+    }
+}
+
+impl Default for Plan {
+    /// The plan applied to users that have not been assigned one
+    fn default() -> Self {
+        Self {
+            id: Uuid::nil(),
+            name: "free".to_string(),
+            daily_request_cap: 10_000,
+            max_circuits: 1,
+            max_subscriptions: 5,
+            priority_class: PriorityClass::Low,
+            max_mappings: 10,
+            max_quorum: 3,
+            pricing: None,
+        }
+    }
+}
+
+/// Represents a mapping from an original RPC to a DarkNode RPC
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcMapping {
+    /// Unique identifier for the mapping
+    pub id: Uuid,
+    /// The original RPC URL
+    pub original_rpc: String,
+    /// The DarkNode HTTPS RPC URL
+    pub darknode_https_rpc: String,
+    /// The DarkNode WSS RPC URL
+    pub darknode_wss_rpc: String,
+    /// When the mapping was created
+    pub created_at: Timestamp,
+    /// Number of providers that must agree on read-only responses, if quorum reads are enabled
+    #[serde(default)]
+    pub quorum: Option<u8>,
+    /// Pass params through unchecked, for exotic methods the entry node's schemas reject
+    #[serde(default)]
+    pub skip_validation: bool,
+    /// Simulate transactions before broadcasting them and reject those that would fail
+    ///
+    /// Covers Solana's `sendTransaction`, unless the client set `skipPreflight`, and
+    /// Ethereum's `eth_sendTransaction`. Signed `eth_sendRawTransaction` payloads are
+    /// broadcast unsimulated, since simulating them means decoding the transaction.
+    #[serde(default)]
+    pub preflight: bool,
+    /// Provider pool the mapping's circuits should prefer exit nodes from
+    #[serde(default)]
+    pub pool: Option<String>,
+    /// Region the mapping's circuits should prefer exit nodes in
+    #[serde(default)]
+    pub region: Option<String>,
+    /// Keep the provider's original error alongside normalized errors, for debugging
+    #[serde(default)]
+    pub debug_errors: bool,
+    /// Time budgets replacing the entry node's for these method classes
+    #[serde(default)]
+    pub timeouts: BTreeMap<crate::timeouts::MethodClass, Duration>,
+    /// Require every request to be signed with the user's wallet, see [`crate::signing`]
+    #[serde(default)]
+    pub require_request_signature: bool,
+    /// The chain the mapping serves; requests detected as another chain's are refused
+    #[serde(default)]
+    pub chain: Option<crate::chains::Chain>,
+    /// The network the mapping serves, only providers on it serving its requests
+    #[serde(default)]
+    pub network: crate::chains::Network,
+    /// Project results onto one shape whichever provider answered, see [`crate::normalize`]
+    #[serde(default)]
+    pub normalize_results: bool,
+    /// How requests are served while no circuit can be built, see [`crate::fallback`]
+    #[serde(default)]
+    pub fallback_mode: Option<crate::fallback::FallbackMode>,
+    /// Spread requests about different addresses across circuits, see [`crate::scatter`]
+    #[serde(default)]
+    pub address_scatter: bool,
+    /// Send requests for some methods to the user's other mappings, see [`crate::method_routing`]
+    #[serde(default, skip_serializing_if = "crate::method_routing::MethodRoutes::is_empty")]
+    pub routes: crate::method_routing::MethodRoutes,
+    /// Attest the class of provider that answered each request, see [`crate::attribution`]
+    #[serde(default)]
+    pub provider_attribution: bool,
+}
+
+/// Preferences for the nodes a circuit is built from
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CircuitPreferences {
+    /// Prefer exit nodes serving from this provider pool
+    pub exit_pool: Option<String>,
+    /// Only use exit nodes in this region
+    #[serde(default)]
+    pub exit_region: Option<String>,
+    /// Only use exit nodes from this subset, see [`crate::epochs`]
+    #[serde(default)]
+    pub exit_subset: Option<crate::epochs::ExitSubset>,
+    /// Leave these nodes out of the circuit, such as a hop that just failed
+    #[serde(default)]
+    pub exclude: Vec<NodeId>,
+    /// Speak this protocol version rather than the highest every hop has nodes for
+    #[serde(default)]
+    pub protocol_version: Option<u16>,
+    /// The constraints the circuit must meet, see [`crate::relaxation`]
+    #[serde(default)]
+    pub policy: crate::relaxation::CircuitPolicy,
+    /// What the circuit is built to carry, see [`crate::circuit_class`]
+    #[serde(default)]
+    pub class: crate::circuit_class::CircuitClass,
+    /// How long the circuit lives, if not the router's default
+    #[serde(default)]
+    pub lifetime: Option<Duration>,
+    /// Which of a user's scattered circuits this is, if it carries requests about addresses, see [`crate::scatter`]
+    #[serde(default)]
+    pub scatter: Option<u8>,
+    /// Only use exit nodes serving all of these method classes, see [`crate::egress`]
+    #[serde(default)]
+    pub method_classes: Vec<crate::timeouts::MethodClass>,
+}
+
+/// Represents a circuit through the DarkNode network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Circuit {
+    /// Unique identifier for the circuit
+    pub id: CircuitId,
+    /// The entry node for the circuit
+    pub entry_node: NodeId,
+    /// The routing nodes for the circuit
+    pub routing_nodes: Vec<NodeId>,
+    /// The exit node for the circuit
+    pub exit_node: NodeId,
+    /// The symmetric keys for each hop
+    pub symmetric_keys: Vec<CryptoKey>,
+    /// When the circuit was created
+    pub created_at: Timestamp,
+    /// When the circuit expires
+    pub expires_at: Timestamp,
+    /// The region of each node, entry first and exit last, if the builder knew them
+    #[serde(default)]
+    pub regions: Vec<String>,
+    /// The protocol version every node of the circuit speaks it in, see [`crate::protocol`]
+    #[serde(default = "crate::protocol::legacy_version")]
+    pub protocol_version: u16,
+    /// The round trip along the circuit's hops, if the builder estimated it, see [`crate::regions`]
+    #[serde(default)]
+    pub estimated_latency: Option<Duration>,
+    /// Constraints of the circuit policy relaxed to build the circuit, see [`crate::relaxation`]
+    #[serde(default)]
+    pub relaxed: Vec<crate::relaxation::Relaxation>,
+    /// The method classes the exit node serves, every class if unset, see [`crate::egress`]
+    #[serde(default)]
+    pub exit_classes: Option<Vec<crate::timeouts::MethodClass>>,
+}
+
+impl Circuit {
+    /// How long the circuit lives, independent of any clock offset from the node that built it
+    pub fn lifetime(&self) -> Duration {
+        self.expires_at.saturating_duration_since(self.created_at)
+    }
+    
+    /// Whether the circuit has expired by `now`
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        now >= self.expires_at
+    }
+}
+
+/// Represents a request through the DarkNode network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    /// Unique identifier for the request
+    pub id: Uuid,
+    /// The circuit ID for the request
+    pub circuit_id: CircuitId,
+    /// The encrypted payload
+    pub payload: EncryptedData,
+    /// When the request was created
+    pub created_at: Timestamp,
+    /// Remaining circuit lifetime when the request left the previous hop, the replay window
+    /// for requests from peers that predate it
+    #[serde(default = "legacy_request_ttl")]
+    pub ttl: Duration,
+    /// The ratchet step the payload is encrypted at, see [`crate::ratchet`]
+    #[serde(default)]
+    pub key_step: u64,
+}
+
+/// Lifetime of requests from peers that don't send one
+fn legacy_request_ttl() -> Duration {
+    crate::clock::MAX_REQUEST_AGE
+}
+
+/// Represents a response through the DarkNode network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    /// The request ID this response is for
+    pub request_id: Uuid,
+    /// The circuit ID for the response
+    pub circuit_id: CircuitId,
+    /// The encrypted payload
+    pub payload: EncryptedData,
+    /// When the response was created
+    pub created_at: Timestamp,
+    /// The ratchet step the payload is encrypted at, see [`crate::ratchet`]
+    #[serde(default)]
+    pub key_step: u64,
+}
+
+/// The plaintext request an exit node serves once the circuit layers are removed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExitPayload {
+    /// The sanitized JSON-RPC request
+    pub request: serde_json::Value,
+    /// Number of providers that must agree on a read-only response, if quorum reads are enabled
+    #[serde(default)]
+    pub quorum: Option<u8>,
+    /// Capabilities the serving provider must support
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Opaque token the client can quote to look up how the request was served
+    #[serde(default)]
+    pub trace_token: Option<String>,
+    /// Whether transactions are simulated before they are broadcast
+    #[serde(default)]
+    pub preflight: bool,
+    /// Whether the client asked for a transaction to be relayed until it confirms
+    #[serde(default)]
+    pub relay: bool,
+    /// Whether normalized provider errors keep the provider's original error
+    #[serde(default)]
+    pub debug_errors: bool,
+    /// Time the exit node has to answer, from when it receives the request
+    #[serde(default)]
+    pub timeout: Option<Duration>,
+    /// Whether the request is a JSON-RPC notification, forwarded without waiting for an answer
+    #[serde(default)]
+    pub notification: bool,
+    /// The chain the serving provider must be on, if known
+    #[serde(default)]
+    pub chain: Option<crate::chains::Chain>,
+    /// The network of its chain the serving provider must be on
+    #[serde(default)]
+    pub network: crate::chains::Network,
+    /// Whether results are projected onto one shape whichever provider answered
+    #[serde(default)]
+    pub normalize: bool,
+    /// Whether the exit node reports the time it spent upstream, see [`crate::timing`]
+    #[serde(default)]
+    pub timing: bool,
+    /// Whether the exit node attests the class of provider that answered, see [`crate::attribution`]
+    #[serde(default)]
+    pub attribution: bool,
+    /// The provider the exit node keeps the request's circuit on, never sent through the circuit
+    #[serde(skip)]
+    pub provider: Option<Uuid>,
+}
+
+/// Activity counters accumulated by a node since its previous heartbeat
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeCounters {
+    /// Circuits built (entry nodes) or joined (routing and exit nodes)
+    pub circuits_built: u64,
+    /// Requests forwarded to the next hop or provider
+    pub requests_forwarded: u64,
+    /// Requests that failed at this node
+    pub errors: u64,
+}
+
+/// Periodic liveness and activity report sent by a node to the coordinator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Heartbeat {
+    /// The node sending the heartbeat
+    pub node_id: NodeId,
+    /// The roles the node serves; older nodes send and read a single `role`
+    #[serde(flatten, with = "roles_field")]
+    pub roles: Vec<NodeRole>,
+    /// The status of the node
+    pub status: NodeStatus,
+    /// The geographic region of the node
+    pub region: String,
+    /// The load on the node (0.0 - 1.0)
+    #[serde(deserialize_with = "crate::scoring::fraction")]
+    pub load: f64,
+    /// Activity since the previous heartbeat
+    pub counters: NodeCounters,
+    /// Requests served per provider pool since the previous heartbeat (exit nodes)
+    #[serde(default)]
+    pub pool_usage: BTreeMap<String, u64>,
+    /// Requests per method label since the previous heartbeat (entry nodes)
+    #[serde(default)]
+    pub method_usage: BTreeMap<String, u64>,
+    /// Estimated distinct users so far today (entry nodes)
+    #[serde(default)]
+    pub unique_users: Option<u64>,
+    /// Work carried for the network per epoch since the previous heartbeat (routing and exit nodes)
+    #[serde(default)]
+    pub work: Vec<crate::accounting::EpochWork>,
+    /// Providers whose circuit breaker is open or half-open (exit nodes)
+    #[serde(default)]
+    pub breakers: BTreeMap<Uuid, crate::breaker::BreakerState>,
+    /// Round trips to peers measured since the previous heartbeat, by the peers' region
+    #[serde(default)]
+    pub peer_latency: BTreeMap<String, Duration>,
+    /// Request budget left, if limited (exit nodes)
+    #[serde(default)]
+    pub budget: Option<crate::budget::BudgetReport>,
+    /// Whether the peers probed since the previous heartbeat answered, oldest first, see [`crate::reachability`]
+    #[serde(default)]
+    pub reachability: Vec<crate::reachability::ProbeResult>,
+    /// What the node runs, see [`crate::build_info`]
+    #[serde(default)]
+    pub build: Option<crate::build_info::BuildInfo>,
+    /// The method classes served, see [`crate::egress`] (exit nodes)
+    #[serde(default)]
+    pub method_classes: Option<Vec<crate::timeouts::MethodClass>>,
+    /// The key provider attestations are signed with, see [`crate::attribution`] (exit nodes)
+    #[serde(default)]
+    pub attestation_key: Option<CryptoKey>,
+    /// When the heartbeat was sent
+    pub sent_at: Timestamp,
+}
+
+/// A piece of a decrypted response delivered by the circuit as it arrives
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseChunk {
+    /// The request ID this chunk belongs to
+    pub request_id: Uuid,
+    /// Position of the chunk within the response, starting at 0
+    pub sequence: u32,
+    /// The response bytes carried by this chunk
+    pub data: Vec<u8>,
+    /// Whether this is the final chunk of the response
+    pub last: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn providers_are_served_without_their_credentials() {
+        let provider: RpcProvider = serde_json::from_value(serde_json::json!({
+            "id": Uuid::new_v4(),
+            "url": "https://rpc.example.com",
+            "provider_type": "Solana",
+            "active": true,
+            "success_rate": 1.0,
+            "avg_latency": { "secs": 0, "nanos": 0 },
+            "last_checked": Timestamp::now(),
+            "auth": "Bearer secret",
+        }))
+        .unwrap();
+        let served = serde_json::to_string(&provider.clone().redacted()).unwrap();
+        assert!(!served.contains("secret"), "{}", served);
+        assert_eq!(provider.redacted().auth.as_deref(), Some(crate::config::REDACTED));
+    }
+    
+    #[test]
+    fn roles_are_read_and_written_for_older_peers() {
+        let node = Node {
+            load: 0.25,
+            ..crate::fixtures::node(&[NodeRole::Routing, NodeRole::Exit])
+        };
+        let mut json = serde_json::to_value(&node).unwrap();
+        assert_eq!(json["roles"], serde_json::json!(["Routing", "Exit"]));
+        assert_eq!(json["role"], serde_json::json!("Routing"));
+        assert_eq!(serde_json::from_value::<Node>(json.clone()).unwrap().roles, node.roles);
+        
+        // Older peers only send `role`, and newer ones may only send `roles`
+        let roles = json.as_object_mut().unwrap().remove("roles").unwrap();
+        assert_eq!(serde_json::from_value::<Node>(json.clone()).unwrap().roles, vec![NodeRole::Routing]);
+        json.as_object_mut().unwrap().remove("role");
+        assert!(serde_json::from_value::<Node>(json.clone()).is_err());
+        json["roles"] = roles;
+        assert_eq!(serde_json::from_value::<Node>(json).unwrap().roles, node.roles);
+    }
+    
+    #[test]
+    fn roles_and_statuses_parse_in_every_spelling_and_serialize_as_before() {
+        for role in NodeRole::ALL {
+            let serialized = format!("{:?}", role);
+            for spelling in [role.name().to_string(), serialized.clone(), role.name().to_uppercase(), format!(" {} ", role.name())] {
+                assert_eq!(spelling.parse::<NodeRole>(), Ok(role), "{:?}", spelling);
+                assert_eq!(serde_json::from_value::<NodeRole>(serde_json::json!(spelling)).unwrap(), role);
+            }
+            assert_eq!(role.to_string(), role.name());
+            assert_eq!(serde_json::to_value(role).unwrap(), serde_json::json!(serialized));
+        }
+        for status in NodeStatus::ALL {
+            let serialized = format!("{:?}", status);
+            for spelling in [status.name().to_string(), serialized.clone(), status.name().to_uppercase(), format!(" {} ", status.name())] {
+                assert_eq!(spelling.parse::<NodeStatus>(), Ok(status), "{:?}", spelling);
+                assert_eq!(serde_json::from_value::<NodeStatus>(serde_json::json!(spelling)).unwrap(), status);
+            }
+            assert_eq!(status.to_string(), status.name());
+            assert_eq!(serde_json::to_value(status).unwrap(), serde_json::json!(serialized));
+        }
+        
+        // Config files spell roles either way
+        let config: crate::config::NodeConfig = toml::from_str("listen_addr = \"0.0.0.0:3000\"\nroles = [\"routing\", \"Exit\"]").unwrap();
+        assert_eq!(config.roles, [NodeRole::Routing, NodeRole::Exit]);
+    }
+    
+    #[test]
+    fn an_unknown_role_or_status_is_refused_with_the_valid_ones() {
+        let refused = "relay".parse::<NodeRole>().unwrap_err();
+        assert_eq!(refused.to_string(), "unknown node role `relay`, expected one of: entry, routing, exit, coordinator");
+        assert_eq!(refused.value, "relay");
+        
+        let refused = serde_json::from_value::<NodeStatus>(serde_json::json!("asleep")).unwrap_err();
+        assert!(
+            refused.to_string().contains("unknown node status `asleep`, expected one of: online, busy, offline, maintenance"),
+            "{}",
+            refused
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! Limits on the responses exit nodes accept from providers
+//!
+//! Nothing about a provider's response can be trusted, including its size or pace. A
+//! broken or malicious provider could answer with a body large enough to exhaust the exit
+//! node's memory, or trickle it out slowly enough to tie up the request for as long as its
+//! budget allows. Provider bodies are therefore read incrementally and abandoned as soon as
+//! they grow past the size limit or fall below the minimum throughput, and responses whose
+//! headers alone are oversized are refused before the body is read. Each of these counts as
+//! misbehavior against the provider and is answered with its own JSON-RPC error.
+
+use super::*;
+use tokio::time::Instant;
+
+/// JSON-RPC error code of a provider response over the size limit
+pub const RESPONSE_TOO_LARGE_CODE: i64 = -32010;
+
+/// JSON-RPC error code of a provider response with oversized headers
+pub const HEADERS_TOO_LARGE_CODE: i64 = -32011;
+
+/// JSON-RPC error code of a provider response arriving too slowly
+pub const TOO_SLOW_CODE: i64 = -32012;
+
+/// What exit nodes accept from providers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamLimits {
+    /// Largest response body accepted, in bytes
+    pub max_response_size: usize,
+    /// Largest total size of a response's header names and values, in bytes
+    pub max_header_size: usize,
+    /// Slowest average rate, in bytes per second, a body may arrive at once the grace period is over
+    pub min_throughput: u64,
+    /// How long a body may take before its throughput is judged
+    pub throughput_grace: Duration,
+}
+
+impl Default for UpstreamLimits {
+    fn default() -> Self {
+        Self {
+            max_response_size: 64 * 1024 * 1024,
+            max_header_size: 64 * 1024,
+            min_throughput: 16 * 1024,
+            throughput_grace: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A provider response broke one of the [`UpstreamLimits`]
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ProviderAbuse {
+    /// The body grew past the size limit
+    #[error("provider response exceeds the {limit} byte limit")]
+    ResponseTooLarge {
+        /// The limit, in bytes
+        limit: usize,
+    },
+    /// The headers are larger than allowed
+    #[error("provider response headers exceed the {limit} byte limit")]
+    HeadersTooLarge {
+        /// The limit, in bytes
+        limit: usize,
+    },
+    /// The body arrived slower than the minimum throughput
+    #[error("provider response arrived slower than {min_throughput} bytes per second")]
+    TooSlow {
+        /// The minimum throughput, in bytes per second
+        min_throughput: u64,
+    },
+}
+
+impl ProviderAbuse {
+    /// Label used in metrics and misbehavior reports
+    pub fn label(&self) -> &'static str {
+        match self {
+            ProviderAbuse::ResponseTooLarge { .. } => "response_too_large",
+            ProviderAbuse::HeadersTooLarge { .. } => "headers_too_large",
+            ProviderAbuse::TooSlow { .. } => "too_slow",
+        }
+    }
+    
+    /// The JSON-RPC error object reporting the abuse
+    pub fn error(&self) -> serde_json::Value {
+        let code = match self {
+            ProviderAbuse::ResponseTooLarge { .. } => RESPONSE_TOO_LARGE_CODE,
+            ProviderAbuse::HeadersTooLarge { .. } => HEADERS_TOO_LARGE_CODE,
+            ProviderAbuse::TooSlow { .. } => TOO_SLOW_CODE,
+        };
+        serde_json::json!({
+            "code": code,
+            "message": self.to_string(),
+            "data": {
+                "reason": self.label(),
+            },
+        })
+    }
+    
+    /// A JSON-RPC response to request `id` reporting the abuse
+    pub fn response(&self, id: &serde_json::Value) -> serde_json::Value {
+        serde_json::json!({ "jsonrpc": "2.0", "id": id, "error": self.error() })
+    }
+}
+
+/// Read a provider's response body within `limits`
+///
+/// The body is read chunk by chunk so an oversized one is abandoned without being held in
+/// full, and each chunk must arrive before the body's average rate would drop below the
+/// minimum throughput.
+pub async fn read_body(mut response: reqwest::Response, limits: &UpstreamLimits) -> Result<Vec<u8>> {
+    let header_size: usize = response
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+    if header_size > limits.max_header_size {
+        return Err(ProviderAbuse::HeadersTooLarge {
+            limit: limits.max_header_size,
+        }
+        .into());
+    }
+    let too_large = ProviderAbuse::ResponseTooLarge {
+        limit: limits.max_response_size,
+    };
+    if response.content_length().map_or(false, |length| length > limits.max_response_size as u64) {
+        return Err(too_large.into());
+    }
+    
+    let started = Instant::now();
+    let mut body = Vec::new();
+    loop {
+        // The body may take as long as the bytes so far justify at the minimum rate
+        let allowed = Duration::from_secs_f64(body.len() as f64 / limits.min_throughput.max(1) as f64);
+        let due = started + allowed.max(limits.throughput_grace);
+        let chunk = tokio::time::timeout_at(due, response.chunk())
+            .await
+            .map_err(|_| ProviderAbuse::TooSlow {
+                min_throughput: limits.min_throughput,
+            })??;
+        let Some(chunk) = chunk else { break };
+        if body.len() + chunk.len() > limits.max_response_size {
+            return Err(too_large.into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Read a request body sent to this node, if it is at most `limit` bytes
+///
+/// Like [`read_body`], the body is read chunk by chunk so an oversized one is abandoned
+/// without being held in full.
+pub async fn read_request_body(mut body: hyper::Body, limit: usize) -> Option<hyper::body::Bytes> {
+    use hyper::body::HttpBody;
+    let mut read = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.ok()?;
+        if read.len() + chunk.len() > limit {
+            return None;
+        }
+        read.extend_from_slice(&chunk);
+    }
+    Some(read.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::impls::StoredRpcManager;
+    use crate::storage::MemoryStorage;
+    use crate::traits::RpcManager;
+    use crate::types::RpcProvider;
+    use axum::body::{Bytes, StreamBody};
+    use futures::Stream;
+    
+    /// The URL of a provider on loopback answering every request with the body `body` streams
+    fn streaming<F, S>(body: F) -> String
+    where
+        F: Fn() -> S + Clone + Send + Sync + 'static,
+        S: Stream<Item = std::io::Result<Bytes>> + Send + 'static,
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::any(move || {
+                let body = body.clone();
+                async move { StreamBody::new(body()) }
+            }),
+        );
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        url
+    }
+    
+    #[tokio::test]
+    async fn a_body_streamed_past_the_size_limit_is_cut_off() {
+        // 100 MB in 1 MB chunks, with no length announced up front
+        let url = streaming(|| futures::stream::iter((0..100).map(|_| Ok(Bytes::from(vec![b' '; 1 << 20])))));
+        let response = reqwest::get(&url).await.unwrap();
+        let limits = UpstreamLimits::default();
+        let refused = read_body(response, &limits).await.unwrap_err();
+        assert_eq!(
+            refused.downcast_ref::<ProviderAbuse>(),
+            Some(&ProviderAbuse::ResponseTooLarge {
+                limit: limits.max_response_size,
+            })
+        );
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn a_provider_trickling_its_body_is_cut_off_and_reported() {
+        let url = streaming(|| {
+            futures::stream::unfold((), |()| async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                Some((Ok(Bytes::from_static(b" ")), ()))
+            })
+        });
+        let provider = RpcProvider {
+            url,
+            ..fixtures::provider()
+        };
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        rpc_manager.register_provider(provider.clone()).await.unwrap();
+        let exit = Arc::new(fixtures::exit(rpc_manager.clone()));
+        
+        let started = Instant::now();
+        let response = exit.serve(&fixtures::payload("getSlot", serde_json::json!([]))).await.unwrap();
+        let response: serde_json::Value = serde_json::from_slice(&response).unwrap();
+        assert_eq!(response["error"]["code"], TOO_SLOW_CODE);
+        assert_eq!(response["error"]["data"]["reason"], "too_slow");
+        
+        // The body is given up on once the grace period is over, not at the request's deadline
+        let grace = UpstreamLimits::default().throughput_grace;
+        assert!(started.elapsed() >= grace && started.elapsed() < grace * 2);
+        let reported = rpc_manager.get_providers().await.unwrap().remove(0);
+        assert!(reported.success_rate < provider.success_rate);
+    }
+}
@@ -0,0 +1,95 @@
+//! Wallet addresses users sign up with, validated and normalized per chain
+//!
+//! A user's account is tied to a wallet, so its address is checked before the account is
+//! created: a typo would otherwise leave an account no signature could ever be verified
+//! for. Two kinds are accepted:
+//!
+//! - Solana public keys, base58 strings decoding to 32 bytes, kept as given since base58
+//!   is case-sensitive
+//! - Ethereum addresses, `0x` and 40 hex digits, kept in their EIP-55 checksummed form. An
+//!   address in one case throughout carries no checksum and is accepted as is; one in mixed
+//!   case must match its checksum, which catches most typos.
+//!
+//! Users are looked up by the normalized address, so an Ethereum address finds its user
+//! however it is cased.
+
+use super::*;
+use super::schema::base58_decode;
+use sha3::{Digest, Keccak256};
+
+/// The chain a wallet address belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WalletChain {
+    /// A Solana public key
+    Solana,
+    /// An Ethereum account
+    Ethereum,
+}
+
+impl Default for WalletChain {
+    fn default() -> Self {
+        // Accounts created before addresses were validated carry no chain; those of Ethereum
+        // wallets are put right by `StoredUserManager::migrate_wallets`
+        WalletChain::Solana
+    }
+}
+
+/// A validated wallet address, in its normalized form
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletAddress {
+    /// The chain the address belongs to
+    pub chain: WalletChain,
+    /// The address, checksummed if the chain has a checksum
+    pub address: String,
+}
+
+/// A wallet address that isn't valid for any supported chain
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidWalletAddress {
+    /// The address is in neither format
+    #[error("wallet address must be a Solana public key (base58, 32 bytes) or an Ethereum address (0x followed by 40 hex digits)")]
+    Unrecognized,
+    /// The address is an Ethereum address whose mixed case doesn't match its checksum
+    #[error("Ethereum address does not match its EIP-55 checksum; check it for typos, or give it in lowercase")]
+    BadChecksum,
+}
+
+/// Validate `input` as a wallet address, returning its chain and normalized form
+pub fn parse(input: &str) -> Result<WalletAddress, InvalidWalletAddress> {
+    if let Some(hex) = input.strip_prefix("0x").or_else(|| input.strip_prefix("0X")) {
+        if hex.len() != 40 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(InvalidWalletAddress::Unrecognized);
+        }
+        let checksummed = eip55(hex);
+        let single_case = hex == hex.to_ascii_lowercase() || hex == hex.to_ascii_uppercase();
+        if !single_case && hex != &checksummed[2..] {
+            return Err(InvalidWalletAddress::BadChecksum);
+        }
+        return Ok(WalletAddress {
+            chain: WalletChain::Ethereum,
+            address: checksummed,
+        });
+    }
+    
+    match base58_decode(input) {
+        Some(key) if key.len() == 32 => Ok(WalletAddress {
+            chain: WalletChain::Solana,
+            address: input.to_string(),
+        }),
+        _ => Err(InvalidWalletAddress::Unrecognized),
+    }
+}
+
+/// The EIP-55 checksummed form of an Ethereum address given as 40 hex digits, with its `0x`
+fn eip55(hex: &str) -> String {
+    let lower = hex.to_ascii_lowercase();
+    let hash = Keccak256::digest(lower.as_bytes());
+    let mut checksummed = String::with_capacity(42);
+    checksummed.push_str("0x");
+    for (i, digit) in lower.chars().enumerate() {
+        let nibble = (hash[i / 2] >> if i % 2 == 0 { 4 } else { 0 }) & 0x0f;
+        checksummed.push(if nibble >= 8 { digit.to_ascii_uppercase() } else { digit });
+    }
+    checksummed
+}
@@ -0,0 +1,241 @@
+//! Warm provider connections on exit nodes
+//!
+//! The first request to a provider pays for DNS, TCP and TLS, which is a few hundred
+//! milliseconds to a distant provider. Exit nodes instead build the client of every
+//! provider they may use at startup and whenever the provider list changes, and send it a
+//! cheap probe so a pooled connection is already open when the first user request arrives.
+//! Connections that sit idle are refreshed with another probe before the provider's own
+//! idle timeout closes them. A request sent over a pooled connection skips the handshake
+//! entirely, which is where the saving comes from; TLS session resumption for new
+//! connections is left to the TLS backend's defaults.
+//!
+//! The client's pool can't be looked into, so the node only knows when it last used each
+//! provider. Metrics report that, whether a request came within the pool's idle timeout of
+//! the last, rather than whether it actually found an open connection.
+
+use super::*;
+use super::types::RpcProvider;
+use std::time::Instant;
+
+/// When provider connections are opened and how long they are kept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WarmupConfig {
+    /// Whether provider connections are warmed at all
+    pub enabled: bool,
+    /// How long a connection may sit idle before it is refreshed
+    ///
+    /// Keep it below the providers' own idle timeouts, commonly 60 seconds or more.
+    pub keepalive_interval: Duration,
+    /// How often the provider list is re-read, so providers registered since are warmed
+    /// before their first request rather than at the next refresh
+    pub sync_interval: Duration,
+    /// How long the client keeps an idle pooled connection open
+    pub pool_idle_timeout: Duration,
+    /// Timeout of a warm-up probe
+    pub probe_timeout: Duration,
+}
+
+impl Default for WarmupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            keepalive_interval: Duration::from_secs(30),
+            sync_interval: Duration::from_secs(5),
+            pool_idle_timeout: Duration::from_secs(90),
+            probe_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// How long before a provider request the provider was last used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LastUse {
+    /// Within the pool's idle timeout, so a pooled connection is likely still open
+    Recent,
+    /// Never, or longer ago than the pool's idle timeout
+    Stale,
+}
+
+impl LastUse {
+    /// Label used in metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            LastUse::Recent => "recent",
+            LastUse::Stale => "stale",
+        }
+    }
+}
+
+/// When each provider was last used, from which connections are refreshed
+pub struct ConnectionTracker {
+    config: WarmupConfig,
+    last_used: dashmap::DashMap<Uuid, Instant>,
+}
+
+impl ConnectionTracker {
+    /// Create a tracker with no connections
+    pub fn new(config: WarmupConfig) -> Self {
+        Self {
+            config,
+            last_used: dashmap::DashMap::new(),
+        }
+    }
+    
+    /// Mark a provider used at `now`, returning how long before it was last used
+    pub fn touch(&self, provider_id: Uuid, now: Instant) -> LastUse {
+        match self.last_used.insert(provider_id, now) {
+            Some(last) if now.duration_since(last) < self.config.pool_idle_timeout => LastUse::Recent,
+            _ => LastUse::Stale,
+        }
+    }
+    
+    /// Whether a provider's connection should be opened or refreshed at `now`
+    pub fn needs_warming(&self, provider_id: Uuid, now: Instant) -> bool {
+        self.last_used
+            .get(&provider_id)
+            .map_or(true, |last| now.duration_since(*last) >= self.config.keepalive_interval)
+    }
+    
+    /// Forget providers not in `active`
+    pub fn retain(&self, active: &[Uuid]) {
+        self.last_used.retain(|id, _| active.contains(id));
+    }
+    
+    /// Providers used within the pool's idle timeout of `now`
+    pub fn recent(&self, now: Instant) -> usize {
+        self.last_used
+            .iter()
+            .filter(|entry| now.duration_since(*entry.value()) < self.config.pool_idle_timeout)
+            .count()
+    }
+}
+
+/// Count a provider request by how long before it the provider was last used
+pub fn record(last_use: LastUse) {
+    metrics::increment_counter!("darknode_provider_requests_by_last_use_total", "last_use" => last_use.label());
+}
+
+/// Send a cheap request to a provider over `client`, opening a pooled connection
+pub async fn probe(client: &reqwest::Client, provider: &RpcProvider, timeout: Duration) -> Result<()> {
+    let method = match provider.provider_type.as_str() {
+        "ethereum" => "eth_chainId",
+        _ => "getHealth",
+    };
+    let mut request = client
+        .post(&provider.url)
+        .json(&serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": method }))
+        .timeout(timeout);
+    if let Some(auth) = &provider.auth {
+        request = request.header(reqwest::header::AUTHORIZATION, auth);
+    }
+    // Read the body so the connection goes back to the pool
+    request.send().await?.error_for_status()?.bytes().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exit_node::{ExitNodeConfig, ExitNodeService};
+    use crate::fixtures;
+    use crate::impls::StoredRpcManager;
+    use crate::storage::MemoryStorage;
+    use crate::traits::RpcManager;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    
+    const CERT: &[u8] = include_bytes!("../tests/fixtures/tls/provider.crt");
+    const KEY: &[u8] = include_bytes!("../tests/fixtures/tls/provider.key");
+    
+    /// A provider on loopback behind TLS, and the handshakes it completed
+    async fn tls_provider() -> (RpcProvider, Arc<AtomicUsize>) {
+        let identity = native_tls::Identity::from_pkcs8(CERT, KEY).unwrap();
+        let acceptor = tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("https://{}/", listener.local_addr().unwrap());
+        let handshakes = Arc::new(AtomicUsize::new(0));
+        let completed = handshakes.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let (acceptor, completed) = (acceptor.clone(), completed.clone());
+                tokio::spawn(async move {
+                    let Ok(stream) = acceptor.accept(socket).await else { return };
+                    completed.fetch_add(1, Ordering::SeqCst);
+                    let answer = hyper::service::service_fn(|_request| async {
+                        let body = hyper::Body::from(r#"{"jsonrpc":"2.0","id":1,"result":42}"#);
+                        Ok::<_, std::convert::Infallible>(hyper::Response::new(body))
+                    });
+                    let _ = hyper::server::conn::Http::new().serve_connection(stream, answer).await;
+                });
+            }
+        });
+        let provider = RpcProvider {
+            url,
+            success_rate: 1.0,
+            ..fixtures::provider()
+        };
+        (provider, handshakes)
+    }
+    
+    fn exit(rpc_manager: Arc<StoredRpcManager>) -> Arc<ExitNodeService> {
+        let roots = vec![reqwest::Certificate::from_pem(CERT).unwrap()];
+        Arc::new(fixtures::exit_with(rpc_manager, ExitNodeConfig::default()).with_provider_roots(roots))
+    }
+    
+    #[tokio::test]
+    async fn requests_after_warming_reuse_its_connection() {
+        let (provider, handshakes) = tls_provider().await;
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        rpc_manager.register_provider(provider).await.unwrap();
+        let exit = exit(rpc_manager);
+        
+        assert_eq!(exit.warm_up().await.unwrap(), 1);
+        assert_eq!(handshakes.load(Ordering::SeqCst), 1);
+        
+        let payload = fixtures::payload("getSlot", serde_json::json!([]));
+        for _ in 0..5 {
+            let response: serde_json::Value = serde_json::from_slice(&exit.serve(&payload).await.unwrap()).unwrap();
+            assert_eq!(response["result"], 42);
+        }
+        assert_eq!(handshakes.load(Ordering::SeqCst), 1);
+        
+        // A provider used since is not probed again until it has sat idle
+        assert_eq!(exit.warm_up().await.unwrap(), 0);
+        assert_eq!(handshakes.load(Ordering::SeqCst), 1);
+    }
+    
+    #[tokio::test]
+    async fn a_provider_registered_later_is_warmed_on_the_next_sync() {
+        let (first, _) = tls_provider().await;
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        rpc_manager.register_provider(first).await.unwrap();
+        let exit = exit(rpc_manager.clone());
+        assert_eq!(exit.warm_up().await.unwrap(), 1);
+        
+        let (second, handshakes) = tls_provider().await;
+        rpc_manager.register_provider(second).await.unwrap();
+        assert_eq!(exit.warm_up().await.unwrap(), 1);
+        assert_eq!(handshakes.load(Ordering::SeqCst), 1);
+    }
+    
+    #[test]
+    fn use_within_the_idle_timeout_counts_as_recent() {
+        let config = WarmupConfig::default();
+        let tracker = ConnectionTracker::new(config.clone());
+        let (provider, start) = (Uuid::new_v4(), Instant::now());
+        
+        assert!(tracker.needs_warming(provider, start));
+        assert_eq!(tracker.touch(provider, start), LastUse::Stale);
+        assert!(!tracker.needs_warming(provider, start + config.keepalive_interval / 2));
+        assert!(tracker.needs_warming(provider, start + config.keepalive_interval));
+        
+        assert_eq!(tracker.touch(provider, start + config.keepalive_interval), LastUse::Recent);
+        assert_eq!(tracker.recent(start + config.keepalive_interval), 1);
+        let idle = start + config.keepalive_interval + config.pool_idle_timeout;
+        assert_eq!(tracker.recent(idle), 0);
+        assert_eq!(tracker.touch(provider, idle), LastUse::Stale);
+        
+        tracker.retain(&[]);
+        assert!(tracker.needs_warming(provider, idle));
+    }
+}
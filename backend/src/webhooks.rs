@@ -0,0 +1,535 @@
+//! Webhooks notifying operators of critical network events
+//!
+//! Operators register HTTPS endpoints on the coordinator with the events they want to hear
+//! about: a node going offline or being flagged for misbehavior, a provider being
+//! deactivated, the directory being republished, or the canary starting to fail. Events
+//! are taken from the coordinator's [`EventBus`] and posted as JSON to every webhook
+//! subscribed to them, each delivery on its own task so a slow endpoint holds up no other.
+//!
+//! Each delivery is signed with the webhook's secret, an HMAC-SHA256 over the timestamp
+//! header, a `.`, and the body, so receivers can check it came from the coordinator and
+//! refuse replays. Failed deliveries are retried with backoff; those still failing after
+//! the last attempt are logged and kept as dead letters for operators to inspect.
+//!
+//! Payloads carry only operational data, such as node and provider ids, epochs, and
+//! reasons, never anything about users or their requests.
+//!
+//! Endpoints are held to the same egress policy as RPC providers, see [`crate::dns`]: the
+//! coordinator resolves them through a [`ProviderResolver`] that refuses private, loopback
+//! and link-local addresses, so a webhook can't be pointed at the coordinator's own network.
+
+use super::*;
+use super::backoff::Backoff;
+use super::dns::{ProviderResolver, ResolveError, ResolverConfig};
+use super::events::{Event, EventBus};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use tokio::sync::broadcast;
+
+/// Header carrying the delivery's signature, `sha256=<hex>`
+pub const SIGNATURE_HEADER: &str = "x-darknode-webhook-signature";
+
+/// Header carrying the time the delivery was signed, in seconds since the Unix epoch
+pub const TIMESTAMP_HEADER: &str = "x-darknode-webhook-timestamp";
+
+/// Retry timing and bounds of webhook deliveries
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Attempts at a delivery before it is dead-lettered
+    pub max_attempts: u32,
+    /// Delay before retrying after the first failed attempt
+    pub initial_backoff: Duration,
+    /// Longest delay between attempts
+    pub max_backoff: Duration,
+    /// How long an endpoint has to answer each attempt
+    pub timeout: Duration,
+    /// Dead letters kept before the oldest are dropped
+    pub dead_letter_capacity: usize,
+    /// How endpoint hosts are resolved, and whether they may be private addresses
+    pub resolver: ResolverConfig,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            timeout: Duration::from_secs(5),
+            dead_letter_capacity: 1000,
+            resolver: ResolverConfig::default(),
+        }
+    }
+}
+
+/// An event webhooks can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// A registered node went offline
+    NodeOffline,
+    /// A node was flagged for misbehavior
+    NodeMisbehavior,
+    /// A provider stopped being used
+    ProviderDeactivated,
+    /// The node directory changed
+    DirectoryPublished,
+    /// The canary started failing
+    CanaryFailed,
+    /// A test delivery requested by an operator
+    Test,
+}
+
+impl WebhookEvent {
+    /// Label used in payloads and metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            WebhookEvent::NodeOffline => "node_offline",
+            WebhookEvent::NodeMisbehavior => "node_misbehavior",
+            WebhookEvent::ProviderDeactivated => "provider_deactivated",
+            WebhookEvent::DirectoryPublished => "directory_published",
+            WebhookEvent::CanaryFailed => "canary_failed",
+            WebhookEvent::Test => "test",
+        }
+    }
+    
+    /// The webhook event `event` is delivered as, and its data, if webhooks hear about it
+    pub fn of(event: &Event) -> Option<(WebhookEvent, serde_json::Value)> {
+        let delivered = match event {
+            Event::NodeOffline { node_id } => (WebhookEvent::NodeOffline, serde_json::json!({ "node_id": node_id })),
+            Event::NodeFlagged { node_id, reason } => (
+                WebhookEvent::NodeMisbehavior,
+                serde_json::json!({ "node_id": node_id, "reason": reason }),
+            ),
+            Event::ProviderDeactivated { provider_id, reason } => (
+                WebhookEvent::ProviderDeactivated,
+                serde_json::json!({ "provider_id": provider_id, "reason": reason }),
+            ),
+            Event::DirectoryPublished { epoch, reason } => (
+                WebhookEvent::DirectoryPublished,
+                serde_json::json!({ "epoch": epoch, "reason": reason }),
+            ),
+            Event::CanaryFailed { entry, failure } => (
+                WebhookEvent::CanaryFailed,
+                serde_json::json!({ "entry": entry, "failure": failure }),
+            ),
+            _ => return None,
+        };
+        Some(delivered)
+    }
+}
+
+/// A webhook as an operator registers it
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookSpec {
+    /// Endpoint deliveries are posted to
+    pub url: String,
+    /// Events delivered to the endpoint
+    pub events: Vec<WebhookEvent>,
+    /// Secret deliveries are signed with; one is generated if not given
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+/// A registered webhook
+///
+/// The secret is never serialized; it is only shown once, when the webhook is created.
+#[derive(Debug, Clone, Serialize)]
+pub struct Webhook {
+    /// Unique identifier for the webhook
+    pub id: Uuid,
+    /// Endpoint deliveries are posted to
+    pub url: String,
+    /// Events delivered to the endpoint
+    pub events: Vec<WebhookEvent>,
+    /// When the webhook was registered
+    pub created_at: Timestamp,
+    #[serde(skip)]
+    secret: String,
+}
+
+impl Webhook {
+    /// Whether the webhook is subscribed to `event`; every webhook gets test deliveries
+    pub fn wants(&self, event: WebhookEvent) -> bool {
+        event == WebhookEvent::Test || self.events.contains(&event)
+    }
+}
+
+/// A newly created webhook with the secret its deliveries are signed with
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatedWebhook {
+    /// The webhook
+    pub webhook: Webhook,
+    /// The signing secret, not shown again
+    pub secret: String,
+}
+
+/// A webhook spec that can't be registered
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidWebhook {
+    /// The URL isn't an HTTP or HTTPS URL
+    #[error("webhook URL must be an http or https URL")]
+    Url,
+    /// The webhook isn't subscribed to any event
+    #[error("webhook must subscribe to at least one event")]
+    NoEvents,
+    /// The secret given is empty
+    #[error("webhook secret must not be empty")]
+    EmptySecret,
+    /// The URL points at an address the egress policy refuses
+    #[error(transparent)]
+    Egress(#[from] ResolveError),
+}
+
+/// The body posted to a webhook
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    /// Unique identifier of the delivery, the same across its retries
+    pub id: Uuid,
+    /// What happened
+    pub event: WebhookEvent,
+    /// When it happened, in seconds since the Unix epoch
+    pub occurred_at: u64,
+    /// Operational details of the event
+    pub data: serde_json::Value,
+}
+
+/// The outcome of delivering a payload to a webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryReport {
+    /// Whether the endpoint accepted the delivery
+    pub delivered: bool,
+    /// Attempts made
+    pub attempts: u32,
+    /// Why the last attempt failed
+    pub error: Option<String>,
+}
+
+/// A delivery given up on after its last attempt
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    /// The webhook it was for
+    pub webhook_id: Uuid,
+    /// The payload that wasn't delivered
+    pub payload: WebhookPayload,
+    /// Attempts made
+    pub attempts: u32,
+    /// Why the last attempt failed
+    pub error: String,
+    /// When the delivery was given up on
+    pub failed_at: Timestamp,
+}
+
+/// Registered webhooks and the deliveries to them
+pub struct Webhooks {
+    config: WebhookConfig,
+    resolver: ProviderResolver,
+    client: reqwest::Client,
+    hooks: parking_lot::RwLock<Vec<Webhook>>,
+    dead_letters: parking_lot::Mutex<VecDeque<DeadLetter>>,
+}
+
+impl Webhooks {
+    /// Create a registry with no webhooks
+    pub fn new(config: WebhookConfig) -> Self {
+        let resolver = ProviderResolver::new(config.resolver.clone());
+        let client = reqwest::Client::builder()
+            .dns_resolver(Arc::new(resolver.clone()))
+            .redirect(resolver.redirect_policy())
+            .build()
+            .expect("webhook client configuration is valid");
+        Self {
+            config,
+            resolver,
+            client,
+            hooks: parking_lot::RwLock::new(Vec::new()),
+            dead_letters: parking_lot::Mutex::new(VecDeque::new()),
+        }
+    }
+    
+    /// Register a webhook
+    pub fn create(&self, spec: WebhookSpec) -> Result<CreatedWebhook, InvalidWebhook> {
+        let url = reqwest::Url::parse(&spec.url).map_err(|_| InvalidWebhook::Url)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(InvalidWebhook::Url);
+        }
+        self.resolver.check_url(&spec.url)?;
+        if spec.events.is_empty() {
+            return Err(InvalidWebhook::NoEvents);
+        }
+        let secret = match spec.secret {
+            Some(secret) if secret.is_empty() => return Err(InvalidWebhook::EmptySecret),
+            Some(secret) => secret,
+            None => hex::encode(&rand::random::<[u8; 32]>()),
+        };
+        
+        let mut events = spec.events;
+        events.sort_by_key(|event| event.label());
+        events.dedup();
+        let webhook = Webhook {
+            id: Uuid::new_v4(),
+            url: spec.url,
+            events,
+            created_at: Timestamp::now(),
+            secret: secret.clone(),
+        };
+        self.hooks.write().push(webhook.clone());
+        Ok(CreatedWebhook { webhook, secret })
+    }
+    
+    /// Registered webhooks, oldest first
+    pub fn list(&self) -> Vec<Webhook> {
+        self.hooks.read().clone()
+    }
+    
+    /// Deliveries given up on, newest first
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters.lock().iter().cloned().collect()
+    }
+    
+    /// Send a test delivery to webhook `id` once, without retrying, if it exists
+    pub async fn test(&self, id: Uuid) -> Option<DeliveryReport> {
+        let webhook = self.hooks.read().iter().find(|webhook| webhook.id == id).cloned()?;
+        let payload = payload(WebhookEvent::Test, serde_json::json!({ "webhook_id": id }));
+        let error = self.attempt(&webhook, &payload).await.err().map(|e| e.to_string());
+        Some(DeliveryReport {
+            delivered: error.is_none(),
+            attempts: 1,
+            error,
+        })
+    }
+    
+    /// Deliver the events on `bus` to the webhooks subscribed to them, until the bus is dropped
+    pub async fn run(self: Arc<Self>, bus: Arc<EventBus>) {
+        let mut events = bus.subscribe();
+        drop(bus);
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("Webhook dispatcher fell behind and missed {} events", missed);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let Some((event, data)) = WebhookEvent::of(&event) else { continue };
+            let payload = payload(event, data);
+            let hooks: Vec<Webhook> = self.hooks.read().iter().filter(|webhook| webhook.wants(event)).cloned().collect();
+            for webhook in hooks {
+                let webhooks = self.clone();
+                let payload = payload.clone();
+                tokio::spawn(async move { webhooks.deliver(&webhook, payload).await });
+            }
+        }
+    }
+    
+    /// Deliver `payload` to `webhook`, retrying with backoff and dead-lettering it if every attempt fails
+    pub async fn deliver(&self, webhook: &Webhook, payload: WebhookPayload) -> DeliveryReport {
+        let mut backoff = Backoff::new(self.config.initial_backoff, self.config.max_backoff);
+        let mut attempts = 0;
+        let error = loop {
+            attempts += 1;
+            let error = match self.attempt(webhook, &payload).await {
+                Ok(()) => {
+                    metrics::increment_counter!(
+                        "darknode_webhook_deliveries_total",
+                        "event" => payload.event.label(),
+                        "outcome" => "delivered"
+                    );
+                    return DeliveryReport {
+                        delivered: true,
+                        attempts,
+                        error: None,
+                    };
+                }
+                Err(e) => e.to_string(),
+            };
+            if attempts >= self.config.max_attempts {
+                break error;
+            }
+            tracing::debug!("Webhook {} delivery {} failed, retrying: {}", webhook.id, payload.id, error);
+            tokio::time::sleep(backoff.next_delay()).await;
+        };
+        
+        tracing::error!(
+            "Giving up on webhook {} delivery {} of {} after {} attempts: {}",
+            webhook.id,
+            payload.id,
+            payload.event.label(),
+            attempts,
+            error
+        );
+        metrics::increment_counter!(
+            "darknode_webhook_deliveries_total",
+            "event" => payload.event.label(),
+            "outcome" => "dead_lettered"
+        );
+        let mut dead_letters = self.dead_letters.lock();
+        dead_letters.push_front(DeadLetter {
+            webhook_id: webhook.id,
+            payload,
+            attempts,
+            error: error.clone(),
+            failed_at: Timestamp::now(),
+        });
+        dead_letters.truncate(self.config.dead_letter_capacity);
+        DeliveryReport {
+            delivered: false,
+            attempts,
+            error: Some(error),
+        }
+    }
+    
+    /// Post `payload` to `webhook` once, signed at the current time
+    async fn attempt(&self, webhook: &Webhook, payload: &WebhookPayload) -> Result<()> {
+        let body = serde_json::to_vec(payload)?;
+        let timestamp = Timestamp::now().as_secs();
+        self.client
+            .post(&webhook.url)
+            .timeout(self.config.timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(SIGNATURE_HEADER, sign(&webhook.secret, timestamp, &body))
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// The signature header value of a delivery of `body` signed with `secret` at `timestamp`
+///
+/// Receivers recompute it from the timestamp header and the raw body to check a delivery.
+pub fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut message = format!("{}.", timestamp).into_bytes();
+    message.extend_from_slice(body);
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(&message);
+    format!("sha256={}", hex::encode(&mac.finalize().into_bytes()))
+}
+
+/// A new delivery of `event` with `data`, occurring now
+fn payload(event: WebhookEvent, data: serde_json::Value) -> WebhookPayload {
+    WebhookPayload {
+        id: Uuid::new_v4(),
+        event,
+        occurred_at: Timestamp::now().as_secs(),
+        data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::NodeId;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::sync::mpsc;
+    
+    /// Webhooks allowed to reach the local receivers below, retrying without delay
+    fn local(max_attempts: u32) -> Webhooks {
+        Webhooks::new(WebhookConfig {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            resolver: ResolverConfig {
+                allow_private_addresses: true,
+                ..ResolverConfig::default()
+            },
+            ..WebhookConfig::default()
+        })
+    }
+    
+    /// A receiver passing on the headers and body of every delivery it accepts
+    async fn receiver() -> (String, mpsc::UnboundedReceiver<(HeaderMap, Vec<u8>)>) {
+        let (deliveries, received) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/",
+            post(move |headers: HeaderMap, body: axum::body::Bytes| async move {
+                let _ = deliveries.send((headers, body.to_vec()));
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        (format!("http://{}/", addr), received)
+    }
+    
+    fn spec(url: &str, events: Vec<WebhookEvent>) -> WebhookSpec {
+        WebhookSpec {
+            url: url.to_string(),
+            events,
+            secret: Some("shared secret".to_string()),
+        }
+    }
+    
+    #[tokio::test]
+    async fn subscribed_events_are_delivered_signed_and_others_filtered_out() {
+        let (url, mut received) = receiver().await;
+        let webhooks = Arc::new(local(1));
+        webhooks.create(spec(&url, vec![WebhookEvent::NodeOffline])).unwrap();
+        let bus = Arc::new(EventBus::new());
+        tokio::spawn(webhooks.clone().run(bus.clone()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        
+        let node_id = NodeId(Uuid::new_v4());
+        bus.emit(Event::ProviderDeactivated {
+            provider_id: Uuid::new_v4(),
+            reason: "probe_failed",
+        });
+        bus.emit(Event::NodeOffline {
+            node_id: node_id.clone(),
+        });
+        
+        let (headers, body) = tokio::time::timeout(Duration::from_secs(5), received.recv()).await.unwrap().unwrap();
+        let payload: WebhookPayload = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload.event, WebhookEvent::NodeOffline);
+        assert_eq!(payload.data, serde_json::json!({ "node_id": node_id }));
+        let timestamp: u64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        assert_eq!(headers[SIGNATURE_HEADER], sign("shared secret", timestamp, &body).as_str());
+        assert!(tokio::time::timeout(Duration::from_millis(200), received.recv()).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn failing_deliveries_are_retried_then_dead_lettered() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counted = attempts.clone();
+        let app = Router::new().route(
+            "/",
+            post(move || async move {
+                counted.fetch_add(1, Ordering::SeqCst);
+                StatusCode::SERVICE_UNAVAILABLE
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        
+        let webhooks = local(3);
+        let created = webhooks.create(spec(&url, vec![WebhookEvent::CanaryFailed])).unwrap();
+        let report = webhooks
+            .deliver(&created.webhook, payload(WebhookEvent::CanaryFailed, serde_json::json!({})))
+            .await;
+        assert!(!report.delivered);
+        assert_eq!(report.attempts, 3);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        let dead_letters = webhooks.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].webhook_id, created.webhook.id);
+    }
+    
+    #[test]
+    fn endpoints_on_private_addresses_are_refused() {
+        let webhooks = Webhooks::new(WebhookConfig::default());
+        for url in ["http://127.0.0.1:8080/hook", "http://10.0.0.5/hook", "http://[::1]/hook"] {
+            let refused = webhooks.create(spec(url, vec![WebhookEvent::NodeOffline])).unwrap_err();
+            assert!(matches!(refused, InvalidWebhook::Egress(ResolveError::EgressDenied { .. })), "{}", url);
+        }
+        assert!(webhooks.list().is_empty());
+    }
+}
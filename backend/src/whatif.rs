@@ -0,0 +1,354 @@
+//! What-if projections of how the network would carry its traffic under a changed scenario
+//!
+//! Operators planning capacity ask questions like "if we lose the ap-south region, can the
+//! remaining nodes carry current traffic?". On `POST /topology/whatif` the coordinator
+//! takes a [`Scenario`], removing the nodes matching its filters, scaling traffic, and
+//! optionally building circuits under another [`CircuitPolicy`], and projects it against
+//! the network as the heartbeats describe it. Nothing is changed by a projection.
+//!
+//! The capacity model is deliberately simple. A node's ceiling is the request rate it
+//! forwarded over [`TRAFFIC_WINDOW`] divided by the load it reported: a node at load 0.5
+//! forwarding 100 requests a second could forward 200. Nodes too idle to measure are
+//! given the mean ceiling of their role, and if none can be measured every node of the
+//! role is taken to carry its load in units of one node. Like recommended paths, a node
+//! takes traffic only up to the recommender's `max_load`. The traffic of removed nodes,
+//! and traffic beyond a node's share, moves to the nodes of the same role in proportion
+//! to the headroom they have left; traffic no node has room for fails to be served. A node
+//! serving both routing and exit traffic is counted in each role, as on the dashboard.
+//!
+//! Provider quotas are projected from the requests served from each pool, shared between
+//! the pool's active providers by weight, over a day or over 30 days for monthly quotas.
+
+use super::*;
+use super::budget::Replenish;
+use super::config::redact_url;
+use super::pools;
+use super::relaxation::CircuitPolicy;
+use super::routing;
+use super::types::{Node, NodeId, NodeRole, RpcProvider};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// How far back traffic is averaged to measure request rates
+pub const TRAFFIC_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// Load below which a node's ceiling isn't measured from its traffic
+const MIN_MEASURED_LOAD: f64 = 0.01;
+
+/// The period a monthly quota is projected over
+const MONTH: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Nodes a scenario removes, matching every field set
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeFilter {
+    /// The node with this ID
+    pub node_id: Option<NodeId>,
+    /// Nodes serving this role
+    pub role: Option<NodeRole>,
+    /// Nodes in this region
+    pub region: Option<String>,
+    /// Exit nodes serving from this provider pool
+    pub pool: Option<String>,
+}
+
+impl NodeFilter {
+    /// Whether `node` matches the filter
+    pub fn matches(&self, node: &Node) -> bool {
+        self.node_id.as_ref().map_or(true, |id| *id == node.id)
+            && self.role.map_or(true, |role| node.has_role(role))
+            && self.region.as_ref().map_or(true, |region| *region == node.region)
+            && self.pool.as_ref().map_or(true, |pool| node.pool.as_ref() == Some(pool))
+    }
+}
+
+/// A change to the network to project
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scenario {
+    /// Nodes matching any of these filters are taken out of the network
+    pub remove: Vec<NodeFilter>,
+    /// What current traffic is multiplied by
+    pub traffic_factor: f64,
+    /// The policy circuits are built under, if not the default one
+    pub policy: Option<CircuitPolicy>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            remove: Vec::new(),
+            traffic_factor: 1.0,
+            policy: None,
+        }
+    }
+}
+
+/// The network as the coordinator sees it, to project scenarios against
+#[derive(Debug, Clone, Default)]
+pub struct NetworkSnapshot {
+    /// Available entry nodes
+    pub entries: Vec<Node>,
+    /// Available routing nodes, with the load they last reported
+    pub routing: Vec<Node>,
+    /// Available exit nodes, with the load they last reported
+    pub exits: Vec<Node>,
+    /// Requests each node forwarded per second over [`TRAFFIC_WINDOW`]
+    pub rates: HashMap<NodeId, f64>,
+    /// Requests served from each provider pool per second over [`TRAFFIC_WINDOW`]
+    pub pool_rates: BTreeMap<String, f64>,
+    /// Active providers
+    pub providers: Vec<RpcProvider>,
+}
+
+/// How loaded a node remaining in the scenario is projected to be
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeProjection {
+    /// The node
+    pub node_id: NodeId,
+    /// The role the load is projected for
+    pub role: NodeRole,
+    /// The node's region
+    pub region: String,
+    /// The load the node last reported
+    pub current_load: f64,
+    /// The load the node is projected to carry
+    pub projected_load: f64,
+    /// Whether the node is projected to have no headroom left
+    pub saturated: bool,
+}
+
+/// A provider projected to spend its quota before it is renewed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaProjection {
+    /// The provider
+    pub provider_id: Uuid,
+    /// The URL of the provider, redacted as [`redact_url`] has it, since it may hold an API key
+    pub url: String,
+    /// The pool the provider serves
+    pub pool: String,
+    /// Requests the provider takes per period
+    pub quota: u64,
+    /// When the quota is renewed
+    pub replenish: Replenish,
+    /// Requests the provider is projected to be sent per period
+    pub projected_requests: u64,
+}
+
+/// How the network is projected to cope with a scenario
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Projection {
+    /// Share of the projected traffic the remaining nodes have room for at every hop,
+    /// 0.0 - 1.0, and none if no circuit can meet the policy
+    pub served_share: f64,
+    /// Whether the remaining nodes can form a circuit meeting the policy at all
+    pub policy_met: bool,
+    /// Nodes the scenario takes out of the network
+    pub removed: Vec<NodeId>,
+    /// Projected load of every remaining routing and exit node
+    pub nodes: Vec<NodeProjection>,
+    /// Providers projected to spend their quota
+    pub providers_over_quota: Vec<QuotaProjection>,
+}
+
+/// Project `scenario` against `snapshot`, with nodes taking traffic up to `max_load`
+pub fn project(snapshot: &NetworkSnapshot, scenario: &Scenario, max_load: f64) -> Projection {
+    let removed: HashSet<&NodeId> = snapshot
+        .entries
+        .iter()
+        .chain(&snapshot.routing)
+        .chain(&snapshot.exits)
+        .filter(|node| scenario.remove.iter().any(|filter| filter.matches(node)))
+        .map(|node| &node.id)
+        .collect();
+    let factor = scenario.traffic_factor.max(0.0);
+    let max_load = max_load.clamp(0.0, 1.0);
+    
+    let project = |role, nodes: &[Node]| project_role(role, nodes, &removed, &snapshot.rates, factor, max_load);
+    let (routing_served, mut nodes) = project(NodeRole::Routing, &snapshot.routing);
+    let (exits_served, exit_nodes) = project(NodeRole::Exit, &snapshot.exits);
+    nodes.extend(exit_nodes);
+    
+    let policy = scenario.policy.clone().unwrap_or_default();
+    let (routing, exits) = (remaining(&snapshot.routing, &removed), remaining(&snapshot.exits, &removed));
+    let policy_met = remaining(&snapshot.entries, &removed)
+        .into_iter()
+        .any(|entry| routing::meets_policy(entry, &routing, &exits, &policy));
+    
+    let mut removed: Vec<NodeId> = removed.into_iter().cloned().collect();
+    removed.sort_by(|a, b| a.0.cmp(&b.0));
+    Projection {
+        served_share: if policy_met { routing_served.min(exits_served) } else { 0.0 },
+        policy_met,
+        removed,
+        nodes,
+        providers_over_quota: over_quota(&snapshot.providers, &snapshot.pool_rates, factor),
+    }
+}
+
+/// The nodes of `nodes` left in by a scenario removing `removed`
+fn remaining<'a>(nodes: &'a [Node], removed: &HashSet<&NodeId>) -> Vec<&'a Node> {
+    nodes.iter().filter(|node| !removed.contains(&node.id)).collect()
+}
+
+/// The share of the role's traffic that is served, and the load of its remaining nodes
+fn project_role(
+    role: NodeRole,
+    nodes: &[Node],
+    removed: &HashSet<&NodeId>,
+    rates: &HashMap<NodeId, f64>,
+    factor: f64,
+    max_load: f64,
+) -> (f64, Vec<NodeProjection>) {
+    let measured: Vec<f64> = nodes
+        .iter()
+        .filter(|node| node.load >= MIN_MEASURED_LOAD)
+        .filter_map(|node| rates.get(&node.id).filter(|rate| **rate > 0.0).map(|rate| rate / node.load))
+        .collect();
+    let mean_ceiling = (!measured.is_empty()).then(|| measured.iter().sum::<f64>() / measured.len() as f64);
+    
+    // Each node's ceiling and the traffic it carries now, scaled by the scenario
+    let traffic: Vec<(&Node, f64, f64)> = nodes
+        .iter()
+        .map(|node| {
+            let rate = rates.get(&node.id).copied().unwrap_or(0.0);
+            let (ceiling, rate) = match mean_ceiling {
+                Some(_) if node.load >= MIN_MEASURED_LOAD && rate > 0.0 => (rate / node.load, rate),
+                Some(mean) => (mean, node.load * mean),
+                None => (1.0, node.load),
+            };
+            (node, ceiling, rate * factor)
+        })
+        .collect();
+    let demand: f64 = traffic.iter().map(|(_, _, traffic)| traffic).sum();
+    let remaining: Vec<&(&Node, f64, f64)> = traffic
+        .iter()
+        .filter(|(node, _, _)| !removed.contains(&node.id))
+        .collect();
+    
+    // Every node keeps its traffic up to its capacity, the rest goes where there is headroom
+    let kept: Vec<f64> = remaining.iter().map(|(_, ceiling, traffic)| traffic.min(ceiling * max_load)).collect();
+    let capacity: f64 = remaining.iter().map(|(_, ceiling, _)| ceiling * max_load).sum();
+    let spare = capacity - kept.iter().sum::<f64>();
+    let displaced = demand - kept.iter().sum::<f64>();
+    let projections = remaining
+        .iter()
+        .zip(&kept)
+        .map(|((node, ceiling, _), kept)| {
+            let headroom = ceiling * max_load - kept;
+            let carried = if displaced <= spare {
+                kept + if spare > 0.0 { displaced * headroom / spare } else { 0.0 }
+            } else {
+                ceiling * max_load
+            };
+            let projected_load = if *ceiling > 0.0 { carried / ceiling } else { 0.0 };
+            NodeProjection {
+                node_id: node.id.clone(),
+                role,
+                region: node.region.clone(),
+                current_load: node.load,
+                projected_load,
+                saturated: projected_load >= max_load - 1e-9,
+            }
+        })
+        .collect();
+    
+    let served = if remaining.is_empty() {
+        0.0
+    } else if demand <= capacity {
+        1.0
+    } else {
+        capacity / demand
+    };
+    (served, projections)
+}
+
+/// Providers whose share of their pool's traffic exceeds their quota
+fn over_quota(providers: &[RpcProvider], pool_rates: &BTreeMap<String, f64>, factor: f64) -> Vec<QuotaProjection> {
+    let mut pool_weights: HashMap<&str, u64> = HashMap::new();
+    for provider in providers {
+        *pool_weights.entry(pools::label(provider.pool.as_deref())).or_insert(0) += provider.weight as u64;
+    }
+    
+    providers
+        .iter()
+        .filter_map(|provider| {
+            let quota = provider.quota?;
+            let pool = pools::label(provider.pool.as_deref());
+            let share = provider.weight as f64 / pool_weights[pool].max(1) as f64;
+            let period = match quota.replenish {
+                Replenish::Daily => Duration::from_secs(24 * 60 * 60),
+                Replenish::Monthly => MONTH,
+            };
+            let rate = pool_rates.get(pool).copied().unwrap_or(0.0) * factor * share;
+            let projected_requests = (rate * period.as_secs_f64()).round() as u64;
+            (projected_requests > quota.requests).then(|| QuotaProjection {
+                provider_id: provider.id,
+                url: redact_url(&provider.url),
+                pool: pool.to_string(),
+                quota: quota.requests,
+                replenish: quota.replenish,
+                projected_requests,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn node(role: NodeRole, region: &str) -> Node {
+        Node {
+            region: region.to_string(),
+            load: 0.5,
+            ..crate::fixtures::node(&[role])
+        }
+    }
+    
+    #[test]
+    fn the_policy_is_held_to_the_routers_rules() {
+        let snapshot = NetworkSnapshot {
+            entries: vec![node(NodeRole::Entry, "us-east")],
+            routing: vec![node(NodeRole::Routing, "eu-west")],
+            exits: vec![node(NodeRole::Exit, "us-east"), node(NodeRole::Exit, "ap-south")],
+            ..Default::default()
+        };
+        let diverse = Scenario {
+            policy: Some(CircuitPolicy {
+                region_diversity: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(project(&snapshot, &diverse, 0.8).policy_met);
+        
+        // Without ap-south the only exit shares the entry's region, which the router refuses
+        let losing_ap_south = Scenario {
+            remove: vec![NodeFilter {
+                region: Some("ap-south".to_string()),
+                ..Default::default()
+            }],
+            ..diverse
+        };
+        let projection = project(&snapshot, &losing_ap_south, 0.8);
+        assert!(!projection.policy_met);
+        assert_eq!(projection.served_share, 0.0);
+    }
+    
+    #[test]
+    fn the_share_served_is_that_of_the_busiest_hop() {
+        let snapshot = NetworkSnapshot {
+            entries: vec![node(NodeRole::Entry, "us-east")],
+            routing: vec![node(NodeRole::Routing, "eu-west"), node(NodeRole::Routing, "eu-west")],
+            exits: vec![node(NodeRole::Exit, "ap-south")],
+            ..Default::default()
+        };
+        // Twice the traffic: the routing nodes have room for 1.6 of their 2 units, the exit 0.8 of 1
+        let doubled = Scenario {
+            traffic_factor: 2.0,
+            ..Default::default()
+        };
+        let projection = project(&snapshot, &doubled, 0.8);
+        assert!((projection.served_share - 0.8).abs() < 1e-9, "{}", projection.served_share);
+    }
+}
@@ -0,0 +1,139 @@
+//! The canary run against the in-process test network, see `darknode_backend::canary`
+
+#![cfg(feature = "canary")]
+
+mod common;
+
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::routing::post;
+use axum::Json;
+use common::{network, serve, TestNetwork};
+use darknode_backend::canary::{CanaryConfig, CanaryRunner, EntrySource};
+use darknode_backend::context::RequestContext;
+use darknode_backend::entry_node::{EntryNodeConfig, EntryNodeService};
+use darknode_backend::fixtures;
+use darknode_backend::impls::StoredUserManager;
+use darknode_backend::sanitizer::{Sanitizer, SanitizerConfig};
+use darknode_backend::traits::UserManager;
+use darknode_backend::types::RpcProvider;
+use serde_json::{json, Value};
+
+/// A provider answering `getSlot`, or failing every request once told to
+async fn provider(failing: Arc<AtomicBool>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let answer = move |Json(request): Json<Value>| {
+        let failing = failing.clone();
+        async move {
+            match failing.load(Ordering::SeqCst) {
+                true => Json(json!({ "jsonrpc": "2.0", "id": request["id"], "error": { "code": -32005, "message": "node is behind" } })),
+                false => Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": 250_000_000 })),
+            }
+        }
+    };
+    serve(listener, axum::Router::new().route("/", post(answer)));
+    url
+}
+
+/// An entry node over the test network's router, taking requests as the canary sends them
+async fn entry(network: &TestNetwork) -> (String, String) {
+    let users = Arc::new(StoredUserManager::new(network.storage.clone()));
+    let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+    let service = Arc::new(EntryNodeService::new(
+        network.entry.record.id.clone(),
+        network.crypto.clone(),
+        network.router.clone(),
+        Arc::new(Sanitizer::new(&SanitizerConfig::default())),
+        users,
+        network.entry.identity.clone(),
+        EntryNodeConfig::default(),
+    ));
+    tokio::spawn(service.clone().run_shaping());
+
+    // The canary's body carries its API key beside the call, as the entry node's `POST /` takes it
+    let handle = |Extension(service): Extension<Arc<EntryNodeService>>, Json(body): Json<Value>| async move {
+        let ctx = RequestContext {
+            api_key: body["api_key"].as_str().unwrap_or_default().to_string(),
+            ..RequestContext::default()
+        };
+        let request = json!({ "jsonrpc": "2.0", "id": body["id"], "method": body["method"], "params": body["params"] });
+        match service.handle_request(ctx, &serde_json::to_vec(&request).unwrap()).await {
+            Ok(response) => Ok(Json(serde_json::from_slice::<Value>(&response).unwrap())),
+            Err(e) => Err((StatusCode::BAD_GATEWAY, e.to_string())),
+        }
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    serve(listener, axum::Router::new().route("/", post(handle)).layer(Extension(service)));
+    (url, user.api_key)
+}
+
+/// Where the canary's webhook posts land
+fn webhook() -> (String, Arc<Mutex<Vec<Value>>>) {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let sink = received.clone();
+    let receive = move |Json(body): Json<Value>| {
+        let sink = sink.clone();
+        async move {
+            sink.lock().unwrap().push(body);
+            StatusCode::NO_CONTENT
+        }
+    };
+    serve(listener, axum::Router::new().route("/hook", post(receive)));
+    (url, received)
+}
+
+#[tokio::test]
+async fn the_canary_records_reads_through_the_network_and_reports_when_they_fail() {
+    let network = network().await;
+    let failing = Arc::new(AtomicBool::new(false));
+    let url = provider(failing.clone()).await;
+    network
+        .rpc_manager
+        .register_provider(RpcProvider {
+            url,
+            success_rate: 1.0,
+            avg_latency: Duration::from_millis(10),
+            ..fixtures::provider()
+        })
+        .await
+        .unwrap();
+    let (entry_url, api_key) = entry(&network).await;
+    let (webhook_url, received) = webhook();
+    let canary = CanaryRunner::new(
+        CanaryConfig {
+            api_key,
+            webhook_url: Some(webhook_url),
+            ..CanaryConfig::default()
+        },
+        EntrySource::Static(vec![entry_url.clone()]),
+    );
+
+    // A read answered through the circuit is recorded as a success, without a webhook
+    let passed = canary.check().await;
+    assert!(passed.success, "{:?}", passed.failure);
+    assert_eq!(passed.entry.as_deref(), Some(entry_url.as_str()));
+    assert!(canary.status().healthy);
+    assert!(received.lock().unwrap().is_empty());
+
+    // Once the provider fails, the canary records the failure and fires the webhook, once
+    failing.store(true, Ordering::SeqCst);
+    for _ in 0..2 {
+        assert!(!canary.check().await.success);
+    }
+    let status = canary.status();
+    assert!(!status.healthy);
+    assert_eq!(status.consecutive_failures, 2);
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["event"], "canary_failed");
+    assert!(received[0]["result"]["failure"].as_str().unwrap().contains("error"), "{}", received[0]);
+}
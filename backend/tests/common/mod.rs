@@ -0,0 +1,241 @@
+//! An in-process network of entry, routing and exit nodes serving on loopback, shared by
+//! the tests that send requests through it
+
+#![allow(dead_code)]
+
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::Extension;
+use darknode_backend::accounting::AccountingConfig;
+use darknode_backend::audit::{AuditConfig, AuditLog};
+use darknode_backend::bandwidth::BandwidthConfig;
+use darknode_backend::clock::Timestamp;
+use darknode_backend::dns::{ProviderResolver, ResolverConfig};
+use darknode_backend::egress::EgressConfig;
+use darknode_backend::exit_node::{ExitNodeConfig, ExitNodeService};
+use darknode_backend::fixtures;
+use darknode_backend::hop_auth::{HopAuthConfig, HopSigner, HopVerifier};
+use darknode_backend::identity::NodeIdentity;
+use darknode_backend::impls::{CryptoImpl, RouterImpl, StoredNodeManager, StoredRpcManager};
+use darknode_backend::membership::MembershipConfig;
+use darknode_backend::nodes::http;
+use darknode_backend::protocol::ProtocolRange;
+use darknode_backend::ratchet::RatchetConfig;
+use darknode_backend::routing_node::RoutingNodeService;
+use darknode_backend::storage::MemoryStorage;
+use darknode_backend::traits::{Crypto, NodeManager, RpcManager};
+use darknode_backend::transport::HopClient;
+use darknode_backend::types::{Node, NodeRole};
+use tokio::task::JoinHandle;
+
+/// A node of the test network: its directory record and what it signs with
+pub struct TestNode {
+    pub record: Node,
+    pub identity: Arc<NodeIdentity>,
+}
+
+impl TestNode {
+    pub async fn new(crypto: &Arc<dyn Crypto + Send + Sync>, role: NodeRole, listener: Option<&TcpListener>) -> Self {
+        let identity = Arc::new(NodeIdentity::generate(&**crypto, Duration::from_secs(3600)).await.unwrap());
+        let address = listener.map_or_else(|| "127.0.0.1:0".parse().unwrap(), |listener| listener.local_addr().unwrap());
+        let record = Node {
+            public_key: identity.public_key(Timestamp::now()),
+            ip_address: address.ip(),
+            port: address.port(),
+            region: "local".to_string(),
+            protocol: ProtocolRange::SUPPORTED,
+            ..fixtures::node(&[role])
+        };
+        Self { record, identity }
+    }
+
+    /// A client signing messages as this node
+    pub fn hops(&self, crypto: &Arc<dyn Crypto + Send + Sync>) -> Arc<HopClient> {
+        Arc::new(HopClient::new(HopSigner::new(
+            self.record.id.clone(),
+            self.identity.clone(),
+            crypto.clone(),
+        )))
+    }
+}
+
+/// An entry node's router and the routing and exit nodes behind it, serving on loopback
+pub struct TestNetwork {
+    pub crypto: Arc<dyn Crypto + Send + Sync>,
+    pub storage: Arc<MemoryStorage>,
+    pub node_manager: Arc<dyn NodeManager + Send + Sync>,
+    /// The providers the exit node serves from, which may be on loopback
+    pub rpc_manager: Arc<dyn RpcManager + Send + Sync>,
+    pub entry: TestNode,
+    pub routing: TestNode,
+    pub exit: TestNode,
+    /// The exit node's service, for driving its sweeps by hand
+    pub exit_service: Arc<ExitNodeService>,
+    pub router: Arc<RouterImpl>,
+}
+
+/// Serve `app` on `listener` until the test ends
+pub fn serve(listener: TcpListener, app: axum::Router) {
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+    tokio::spawn(server);
+}
+
+/// Cuts a node off as if it died: its connections are reset, in flight or not, and new ones refused
+#[derive(Clone, Default)]
+pub struct Kill(Arc<Mutex<Vec<JoinHandle<()>>>>);
+
+impl Kill {
+    pub fn kill(&self) {
+        for task in self.0.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+/// Pass connections to `front` on to `target` until killed
+pub fn proxy(front: TcpListener, target: SocketAddr) -> Kill {
+    let kill = Kill::default();
+    front.set_nonblocking(true).unwrap();
+    let front = tokio::net::TcpListener::from_std(front).unwrap();
+    let tasks = kill.0.clone();
+    let accepting = tokio::spawn(async move {
+        while let Ok((mut inbound, _)) = front.accept().await {
+            let connection = tokio::spawn(async move {
+                if let Ok(mut outbound) = tokio::net::TcpStream::connect(target).await {
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                }
+            });
+            tasks.lock().unwrap().push(connection);
+        }
+    });
+    kill.0.lock().unwrap().push(accepting);
+    kill
+}
+
+/// Circuit keys of the test network step with every message, so a few requests cover several steps
+fn ratchet() -> RatchetConfig {
+    RatchetConfig {
+        messages_per_step: 1,
+        ..RatchetConfig::default()
+    }
+}
+
+/// The service of the exit node `exit`, serving from the providers of `rpc_manager`
+fn exit_service(
+    crypto: &Arc<dyn Crypto + Send + Sync>,
+    rpc_manager: &Arc<dyn RpcManager + Send + Sync>,
+    exit: &TestNode,
+    egress: EgressConfig,
+) -> Arc<ExitNodeService> {
+    // Providers of the test network listen on loopback too
+    let resolver = ResolverConfig {
+        allow_private_addresses: true,
+        ..ResolverConfig::default()
+    };
+    Arc::new(
+        ExitNodeService::new(
+            exit.record.id.clone(),
+            crypto.clone(),
+            rpc_manager.clone(),
+            ProviderResolver::new(resolver),
+            AuditLog::new(AuditConfig::default()),
+            ExitNodeConfig {
+                membership: MembershipConfig {
+                    ratchet: ratchet(),
+                    ..MembershipConfig::default()
+                },
+                ..ExitNodeConfig::default()
+            },
+        )
+        .with_identity(exit.identity.clone())
+        .with_egress(egress),
+    )
+}
+
+pub async fn network() -> TestNetwork {
+    network_serving(EgressConfig::default()).await
+}
+
+/// The test network, its exit node serving only the method classes `egress` lists
+pub async fn network_serving(egress: EgressConfig) -> TestNetwork {
+    let crypto: Arc<dyn Crypto + Send + Sync> = Arc::new(CryptoImpl::new());
+    let storage = Arc::new(MemoryStorage::new());
+    let node_manager: Arc<dyn NodeManager + Send + Sync> = Arc::new(StoredNodeManager::new(storage.clone()));
+    let rpc_manager: Arc<dyn RpcManager + Send + Sync> = Arc::new(StoredRpcManager::new(storage.clone()));
+    let verifier = Arc::new(HopVerifier::new(HopAuthConfig::default(), node_manager.clone(), crypto.clone()));
+
+    let routing_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let exit_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let entry = TestNode::new(&crypto, NodeRole::Entry, None).await;
+    let routing = TestNode::new(&crypto, NodeRole::Routing, Some(&routing_listener)).await;
+    let exit = TestNode::new(&crypto, NodeRole::Exit, Some(&exit_listener)).await;
+    for node in [&entry, &routing, &exit] {
+        node_manager.register_node(node.record.clone()).await.unwrap();
+    }
+
+    let routing_service = Arc::new(RoutingNodeService::new(
+        routing.record.id.clone(),
+        crypto.clone(),
+        routing.identity.clone(),
+        routing.hops(&crypto),
+        AccountingConfig::default(),
+        BandwidthConfig::default(),
+    ));
+    tokio::spawn(routing_service.clone().run_egress());
+    serve(routing_listener, http::routing_routes(routing_service).layer(Extension(verifier.clone())));
+
+    let exit_service = exit_service(&crypto, &rpc_manager, &exit, egress);
+    serve(exit_listener, http::exit_routes(exit_service.clone()).layer(Extension(verifier)));
+
+    let router = Arc::new(RouterImpl::new(node_manager.clone(), crypto.clone()).with_hops(entry.hops(&crypto), ratchet()));
+    TestNetwork {
+        crypto,
+        storage,
+        node_manager,
+        rpc_manager,
+        entry,
+        routing,
+        exit,
+        exit_service,
+        router,
+    }
+}
+
+/// Another routing node of `network`, in `region`, which the other nodes reach through a
+/// proxy so the test can kill it
+pub async fn killable_routing(network: &TestNetwork, region: &str) -> (TestNode, Kill) {
+    let front = TcpListener::bind("127.0.0.1:0").unwrap();
+    let back = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut routing = TestNode::new(&network.crypto, NodeRole::Routing, Some(&front)).await;
+    routing.record.region = region.to_string();
+    network.node_manager.register_node(routing.record.clone()).await.unwrap();
+
+    let verifier = Arc::new(HopVerifier::new(HopAuthConfig::default(), network.node_manager.clone(), network.crypto.clone()));
+    let service = Arc::new(RoutingNodeService::new(
+        routing.record.id.clone(),
+        network.crypto.clone(),
+        routing.identity.clone(),
+        routing.hops(&network.crypto),
+        AccountingConfig::default(),
+        BandwidthConfig::default(),
+    ));
+    tokio::spawn(service.clone().run_egress());
+    let target = back.local_addr().unwrap();
+    serve(back, http::routing_routes(service).layer(Extension(verifier)));
+    (routing, proxy(front, target))
+}
+
+/// Another exit node of `network`, serving from the providers of `rpc_manager`
+pub async fn add_exit(network: &TestNetwork, rpc_manager: Arc<dyn RpcManager + Send + Sync>) -> TestNode {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let exit = TestNode::new(&network.crypto, NodeRole::Exit, Some(&listener)).await;
+    network.node_manager.register_node(exit.record.clone()).await.unwrap();
+    let verifier = Arc::new(HopVerifier::new(HopAuthConfig::default(), network.node_manager.clone(), network.crypto.clone()));
+    let service = exit_service(&network.crypto, &rpc_manager, &exit, EgressConfig::default());
+    serve(listener, http::exit_routes(service).layer(Extension(verifier)));
+    exit
+}
@@ -0,0 +1,94 @@
+//! Compression of responses to clients, see `darknode_backend::compression`
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use darknode_backend::clock::Timestamp;
+use darknode_backend::compression::client_compression;
+use darknode_backend::flags::{Flag, FeatureFlags, FlagValue};
+use tower::ServiceExt;
+
+/// A JSON-RPC response well above the compression threshold
+fn large_response() -> String {
+    let accounts: Vec<String> = (0..500).map(|i| format!("{{\"pubkey\":\"account{}\",\"lamports\":{}}}", i, i)).collect();
+    format!("{{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":[{}]}}", accounts.join(","))
+}
+
+fn app(flags: FeatureFlags) -> Router {
+    Router::new()
+        .route("/", get(|| async { ([(header::CONTENT_TYPE, "application/json")], large_response()) }))
+        .route(
+            "/small",
+            get(|| async { ([(header::CONTENT_TYPE, "application/json")], "{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":1}") }),
+        )
+        .route(
+            "/encoded",
+            get(|| async { ([(header::CONTENT_ENCODING, "br")], large_response()).into_response() }),
+        )
+        .route(
+            "/events",
+            get(|| async { ([(header::CONTENT_TYPE, "text/event-stream")], large_response()) }),
+        )
+        .layer(client_compression(1024, flags))
+}
+
+async fn get_with(app: Router, path: &str, accept: Option<&str>) -> (StatusCode, Option<String>, Vec<u8>) {
+    let mut request = Request::get(path);
+    if let Some(accept) = accept {
+        request = request.header(header::ACCEPT_ENCODING, accept);
+    }
+    let response = app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+    let encoding = response
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .map(|value| value.to_str().unwrap().to_string());
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec();
+    (status, encoding, body)
+}
+
+#[tokio::test]
+async fn compresses_large_responses_for_clients_that_ask() {
+    let (status, encoding, body) = get_with(app(FeatureFlags::new()), "/", Some("gzip")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(encoding.as_deref(), Some("gzip"));
+    assert!(body.len() < large_response().len());
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, large_response());
+    
+    let (_, encoding, body) = get_with(app(FeatureFlags::new()), "/", None).await;
+    assert_eq!(encoding, None);
+    assert_eq!(body, large_response().into_bytes());
+}
+
+#[tokio::test]
+async fn leaves_small_encoded_and_streamed_responses_alone() {
+    for path in ["/small", "/events"] {
+        let (_, encoding, _) = get_with(app(FeatureFlags::new()), path, Some("gzip")).await;
+        assert_eq!(encoding, None, "{} was compressed", path);
+    }
+    let (_, encoding, body) = get_with(app(FeatureFlags::new()), "/encoded", Some("gzip")).await;
+    assert_eq!(encoding.as_deref(), Some("br"));
+    assert_eq!(body, large_response().into_bytes());
+}
+
+#[tokio::test]
+async fn compresses_nothing_while_the_flag_is_off() {
+    let flags = FeatureFlags::new();
+    flags.update(BTreeMap::from([(
+        "compression".to_string(),
+        Flag {
+            value: FlagValue::Bool(false),
+            expires_at: Timestamp::now() + Duration::from_secs(60),
+        },
+    )]));
+    let (_, encoding, _) = get_with(app(flags), "/", Some("gzip")).await;
+    assert_eq!(encoding, None);
+}
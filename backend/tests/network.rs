@@ -0,0 +1,466 @@
+//! Circuits built and used across in-process nodes, see `darknode_backend::transport`
+
+mod common;
+
+use std::collections::HashSet;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::routing::post;
+use axum::Json;
+use common::{add_exit, killable_routing, network, network_serving, serve, TestNode};
+use darknode_backend::chains::Chain;
+use darknode_backend::circuit_class::CircuitClass;
+use darknode_backend::clock::Timestamp;
+use darknode_backend::config::EntryConfig;
+use darknode_backend::context::RequestContext;
+use darknode_backend::egress::EgressConfig;
+use darknode_backend::fixtures;
+use darknode_backend::impls::StoredRpcManager;
+use darknode_backend::keepalive;
+use darknode_backend::reclaim::ReclaimConfig;
+use darknode_backend::scatter::{AddressScatter, ScatterConfig};
+use darknode_backend::storage::MemoryStorage;
+use darknode_backend::replay::{self, HopFailure, HopFailureKind};
+use darknode_backend::timeouts::MethodClass;
+use darknode_backend::traits::Router;
+use darknode_backend::transport::{self, RequestMessage, ResponseMessage};
+use darknode_backend::types::{CircuitId, CircuitPreferences, ExitPayload, NodeRole, NodeStatus, Request, RpcMapping, RpcProvider};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+/// A provider answering every request, keeping the requests it is sent
+fn provider(seen: Arc<Mutex<Vec<Value>>>) -> RpcProvider {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let answer = move |Json(request): Json<Value>| {
+        seen.lock().unwrap().push(request.clone());
+        async move { Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": 250_000_000 })) }
+    };
+    serve(listener, axum::Router::new().route("/", post(answer)));
+    RpcProvider {
+        url,
+        success_rate: 1.0,
+        avg_latency: Duration::from_millis(10),
+        ..fixtures::provider()
+    }
+}
+
+#[tokio::test]
+async fn requests_reach_the_exit_through_the_circuit_built_to_it() {
+    let network = network().await;
+    let circuit = network.router.create_circuit().await.unwrap();
+    assert_eq!(circuit.entry_node, network.entry.record.id);
+    assert_eq!(circuit.routing_nodes, vec![network.routing.record.id.clone()]);
+
+    // The exit node joined the circuit through its handshake, so the ping opens under its
+    // key and the pong under the router's; several requests step the ratchet along
+    for _ in 0..3 {
+        let ping = serde_json::to_vec(&keepalive::ping()).unwrap();
+        let request_id = network.router.send_request(&RequestContext::default(), &circuit, &ping).await.unwrap();
+        let pong: serde_json::Value = serde_json::from_slice(&network.router.receive_response(request_id).await.unwrap()).unwrap();
+        assert_eq!(pong["result"], "pong");
+    }
+
+    // Once closed, the router keeps nothing to send on it with, and its hops no longer carry it
+    network.router.close_circuit(&circuit).await.unwrap();
+    let ping = serde_json::to_vec(&keepalive::ping()).unwrap();
+    assert!(network.router.send_request(&RequestContext::default(), &circuit, &ping).await.is_err());
+    let request = Request {
+        id: Uuid::new_v4(),
+        circuit_id: circuit.id.clone(),
+        payload: network.crypto.encrypt(&ping, &network.entry.record.public_key).await.unwrap(),
+        created_at: Timestamp::now(),
+        ttl: Duration::from_secs(60),
+        key_step: 0,
+    };
+    let routing = &network.routing.record;
+    let refused = network
+        .entry
+        .hops(&network.crypto)
+        .send::<_, ResponseMessage>(&routing.id, transport::address_of(routing), transport::FORWARD_PATH, &RequestMessage { request })
+        .await
+        .unwrap_err();
+    assert_eq!(refused.downcast_ref::<HopFailure>().map(|failure| failure.kind), Some(HopFailureKind::CircuitUnknown));
+}
+
+#[tokio::test]
+async fn exits_refuse_method_classes_they_dont_serve_before_a_provider_sees_them() {
+    let network = network_serving(EgressConfig {
+        serve: vec![MethodClass::LightRead, MethodClass::Read],
+    })
+    .await;
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    network.rpc_manager.register_provider(provider(seen.clone())).await.unwrap();
+    let circuit = network.router.create_circuit().await.unwrap();
+    let payload = |method: &str| {
+        let payload = ExitPayload {
+            request: json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": [] }),
+            ..keepalive::ping()
+        };
+        serde_json::to_vec(&payload).unwrap()
+    };
+
+    // A write never leaves the exit, which fails it back through the circuit
+    let request_id = network.router.send_request(&RequestContext::default(), &circuit, &payload("sendTransaction")).await.unwrap();
+    let refused = network.router.receive_response(request_id).await.unwrap_err();
+    assert_eq!(
+        refused.downcast_ref::<HopFailure>(),
+        Some(&HopFailure {
+            node: Some(network.exit.record.id.clone()),
+            kind: HopFailureKind::Failed,
+        })
+    );
+    assert!(seen.lock().unwrap().is_empty());
+
+    // A read on the same circuit is served
+    let request_id = network.router.send_request(&RequestContext::default(), &circuit, &payload("getSlot")).await.unwrap();
+    let answered: Value = serde_json::from_slice(&network.router.receive_response(request_id).await.unwrap()).unwrap();
+    assert_eq!(answered["result"], 250_000_000);
+    assert_eq!(seen.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn circuits_never_extended_through_a_node_are_refused_by_it() {
+    let network = network().await;
+    let request = Request {
+        id: Uuid::new_v4(),
+        circuit_id: CircuitId(Uuid::new_v4()),
+        payload: network.crypto.encrypt(b"forged", &network.entry.record.public_key).await.unwrap(),
+        created_at: Timestamp::now(),
+        ttl: Duration::from_secs(60),
+        key_step: 0,
+    };
+    let routing = &network.routing.record;
+    let refused = network
+        .entry
+        .hops(&network.crypto)
+        .send::<_, ResponseMessage>(&routing.id, transport::address_of(routing), transport::FORWARD_PATH, &RequestMessage { request })
+        .await
+        .unwrap_err();
+    assert_eq!(
+        refused.downcast_ref::<HopFailure>(),
+        Some(&HopFailure {
+            node: Some(routing.id.clone()),
+            kind: HopFailureKind::CircuitUnknown,
+        })
+    );
+}
+
+#[tokio::test]
+async fn messages_from_nodes_outside_the_directory_are_dropped() {
+    let network = network().await;
+    let stranger = TestNode::new(&network.crypto, NodeRole::Entry, None).await;
+    let routing = &network.routing.record;
+    let request = Request {
+        id: Uuid::new_v4(),
+        circuit_id: CircuitId(Uuid::new_v4()),
+        payload: network.crypto.encrypt(b"forged", &routing.public_key).await.unwrap(),
+        created_at: Timestamp::now(),
+        ttl: Duration::from_secs(60),
+        key_step: 0,
+    };
+    let refused = stranger
+        .hops(&network.crypto)
+        .send::<_, ResponseMessage>(&routing.id, transport::address_of(routing), transport::FORWARD_PATH, &RequestMessage { request })
+        .await
+        .unwrap_err();
+    assert!(refused.downcast_ref::<HopFailure>().is_none(), "{}", refused);
+    assert!(refused.to_string().contains("401"), "{}", refused);
+}
+
+#[tokio::test]
+async fn a_request_the_exit_fails_comes_back_as_a_failure_naming_it() {
+    let network = network().await;
+    let circuit = network.router.create_circuit().await.unwrap();
+
+    // The exit node can't read the request as a payload, fails it, and the routing node
+    // passes the failure back naming the exit
+    let request_id = network.router.send_request(&RequestContext::default(), &circuit, b"not a payload").await.unwrap();
+    let failed = network.router.receive_response(request_id).await.unwrap_err();
+    assert_eq!(
+        failed.downcast_ref::<HopFailure>(),
+        Some(&HopFailure {
+            node: Some(network.exit.record.id.clone()),
+            kind: HopFailureKind::Failed,
+        })
+    );
+}
+
+#[tokio::test]
+async fn an_abandoned_circuit_is_reclaimed_its_subscriptions_closed_and_later_messages_refused() {
+    let network = network().await;
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    network.rpc_manager.register_provider(provider(seen.clone())).await.unwrap();
+    let preferences = CircuitPreferences {
+        class: CircuitClass::Subscription,
+        ..Default::default()
+    };
+    let circuit = network.router.create_circuit_with(&preferences).await.unwrap();
+    let subscribe = ExitPayload {
+        request: json!({ "jsonrpc": "2.0", "id": 1, "method": "accountSubscribe", "params": ["vote111"] }),
+        ..keepalive::ping()
+    };
+    let request_id = network
+        .router
+        .send_request(&RequestContext::default(), &circuit, &serde_json::to_vec(&subscribe).unwrap())
+        .await
+        .unwrap();
+    let subscribed: Value = serde_json::from_slice(&network.router.receive_response(request_id).await.unwrap()).unwrap();
+
+    // Nothing crosses the circuit for the grace period, so the exit's next sweep takes it
+    // for abandoned
+    tokio::time::pause();
+    tokio::time::advance(ReclaimConfig::default().idle_grace).await;
+    tokio::time::resume();
+    network.exit_service.sweep_circuits().await;
+    let unsubscribe = seen.lock().unwrap().last().cloned().unwrap();
+    assert_eq!(unsubscribe["method"], "accountUnsubscribe");
+    assert_eq!(unsubscribe["params"], json!([subscribed["result"]]));
+
+    // A message arriving afterwards is refused as for a circuit the exit no longer holds
+    let ping = serde_json::to_vec(&keepalive::ping()).unwrap();
+    let request_id = network.router.send_request(&RequestContext::default(), &circuit, &ping).await.unwrap();
+    let refused = network.router.receive_response(request_id).await.unwrap_err();
+    assert_eq!(
+        refused.downcast_ref::<HopFailure>(),
+        Some(&HopFailure {
+            node: Some(network.exit.record.id.clone()),
+            kind: HopFailureKind::CircuitUnknown,
+        })
+    );
+}
+
+#[tokio::test]
+async fn a_circuit_built_before_a_key_rotation_completes_while_new_ones_use_the_new_key() {
+    let network = network().await;
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    network.rpc_manager.register_provider(provider(seen.clone())).await.unwrap();
+    let read = ExitPayload {
+        request: json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot", "params": [] }),
+        ..keepalive::ping()
+    };
+    let read = serde_json::to_vec(&read).unwrap();
+    let old_key = network.exit.identity.public_key(Timestamp::now());
+    let before = network.router.create_circuit().await.unwrap();
+
+    // The exit node rotates to a key taking effect at once, keeping the old one for the
+    // circuits built under it
+    let activates_at = Timestamp::now();
+    let new_key = network.exit.identity.begin_rotation(&*network.crypto, activates_at).await.unwrap();
+    network
+        .node_manager
+        .publish_next_key(&network.exit.record.id, new_key.clone(), activates_at)
+        .await
+        .unwrap();
+    assert_eq!(network.exit.identity.public_key(Timestamp::now()).0, new_key.0);
+    assert_ne!(old_key.0, new_key.0);
+
+    let request_id = network.router.send_request(&RequestContext::default(), &before, &read).await.unwrap();
+    let answered: Value = serde_json::from_slice(&network.router.receive_response(request_id).await.unwrap()).unwrap();
+    assert_eq!(answered["result"], 250_000_000);
+
+    // New circuits are extended to the exit under the key it now publishes
+    let exit = network.node_manager.get_node(&network.exit.record.id).await.unwrap().unwrap();
+    assert_eq!(exit.active_public_key(Timestamp::now()).0, new_key.0);
+    let after = network.router.create_circuit().await.unwrap();
+    assert_ne!(after.id, before.id);
+    let request_id = network.router.send_request(&RequestContext::default(), &after, &read).await.unwrap();
+    let answered: Value = serde_json::from_slice(&network.router.receive_response(request_id).await.unwrap()).unwrap();
+    assert_eq!(answered["result"], 250_000_000);
+    assert_eq!(seen.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn a_hop_sending_fifty_forged_requests_is_blocked_and_its_later_traffic_dropped() {
+    let network = network().await;
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    network.rpc_manager.register_provider(provider(seen.clone())).await.unwrap();
+    let circuit = network.router.create_circuit().await.unwrap();
+    let exit = &network.exit.record;
+    let hops = network.routing.hops(&network.crypto);
+    let payload = network.crypto.encrypt(b"forged", &exit.public_key).await.unwrap();
+    let forged = || Request {
+        id: Uuid::new_v4(),
+        circuit_id: CircuitId(Uuid::new_v4()),
+        payload: payload.clone(),
+        created_at: Timestamp::now(),
+        ttl: Duration::from_secs(60),
+        key_step: 0,
+    };
+
+    // Requests for circuits the exit never joined are refused, the fiftieth blocking the
+    // routing node's address
+    for _ in 0..50 {
+        let refused = hops
+            .send::<_, ResponseMessage>(&exit.id, transport::address_of(exit), transport::EXIT_PATH, &RequestMessage { request: forged() })
+            .await
+            .unwrap_err();
+        assert_eq!(refused.downcast_ref::<HopFailure>().map(|failure| failure.kind), Some(HopFailureKind::CircuitUnknown));
+    }
+    let refused = hops
+        .send::<_, ResponseMessage>(&exit.id, transport::address_of(exit), transport::EXIT_PATH, &RequestMessage { request: forged() })
+        .await
+        .unwrap_err();
+    assert!(refused.to_string().contains("403"), "{}", refused);
+
+    // Even requests on a circuit the exit joined no longer get through from there
+    let read = ExitPayload {
+        request: json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot", "params": [] }),
+        ..keepalive::ping()
+    };
+    let request_id = network
+        .router
+        .send_request(&RequestContext::default(), &circuit, &serde_json::to_vec(&read).unwrap())
+        .await
+        .unwrap();
+    assert!(network.router.receive_response(request_id).await.is_err());
+    assert!(seen.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn a_read_whose_hop_dies_under_it_completes_on_a_rebuilt_circuit_while_a_write_fails() {
+    let network = network().await;
+    network
+        .node_manager
+        .update_node_status(&network.routing.record.id, NodeStatus::Offline)
+        .await
+        .unwrap();
+    let relays = [killable_routing(&network, "relay-a").await, killable_routing(&network, "relay-b").await];
+
+    // A provider holding on to the first request of each method, and answering the rest
+    let (arrivals, mut arrived) = tokio::sync::mpsc::unbounded_channel();
+    let held = Arc::new(Mutex::new(HashSet::new()));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let answer = move |Json(request): Json<Value>| {
+        let method = request["method"].as_str().unwrap_or_default().to_string();
+        let first = held.lock().unwrap().insert(method.clone());
+        let _ = arrivals.send(method);
+        async move {
+            if first {
+                std::future::pending::<()>().await;
+            }
+            Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": 250_000_000 }))
+        }
+    };
+    serve(listener, axum::Router::new().route("/", post(answer)));
+    let provider = RpcProvider {
+        url,
+        success_rate: 1.0,
+        avg_latency: Duration::from_millis(10),
+        ..fixtures::provider()
+    };
+    network.rpc_manager.register_provider(provider).await.unwrap();
+
+    let (service, users) = fixtures::entry(network.router.clone(), &EntryConfig::default()).await;
+    let service = Arc::new(service);
+    let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+    let send = |request: Value| {
+        let (service, api_key) = (service.clone(), user.api_key.clone());
+        tokio::spawn(async move {
+            let request = serde_json::to_vec(&request).unwrap();
+            service.handle_request(RequestContext::new(&api_key), &request).await
+        })
+    };
+
+    // The hop before the exit dies while a read is held at the provider
+    let reading = send(json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" }));
+    assert_eq!(arrived.recv().await.as_deref(), Some("getSlot"));
+    let failed = service.circuit_info(&user.api_key, None).await.unwrap().unwrap();
+    let dead = failed.hops[failed.hops.len() - 2].region.clone().unwrap();
+    let (killed, survivor) = match relays[0].0.record.region == dead {
+        true => (&relays[0], &relays[1]),
+        false => (&relays[1], &relays[0]),
+    };
+    killed.1.kill();
+
+    // It is answered through a circuit rebuilt without the dead hop, within its deadline
+    let read = tokio::time::timeout(Duration::from_secs(10), reading).await.unwrap().unwrap().unwrap();
+    let read: Value = serde_json::from_slice(&read).unwrap();
+    assert_eq!(read["result"], 250_000_000);
+    assert_eq!(read["darknode"]["retry"], json!({ "circuit_rebuilt": true, "reason": "unreachable" }));
+    assert_eq!(arrived.recv().await.as_deref(), Some("getSlot"));
+    let rebuilt = service.circuit_info(&user.api_key, None).await.unwrap().unwrap();
+    assert_ne!(rebuilt.fingerprint, failed.fingerprint);
+    assert!(rebuilt.hops.iter().all(|hop| hop.region.as_deref() != Some(dead.as_str())));
+    assert!(rebuilt.hops.iter().any(|hop| hop.region.as_ref() == Some(&survivor.0.record.region)));
+
+    // A write in the same situation may have reached the chain, so its failure surfaces
+    let writing = send(json!({
+        "jsonrpc": "2.0",
+        "id": 2,
+        "method": "sendTransaction",
+        "params": ["AQABAg==", { "encoding": "base64" }],
+    }));
+    assert_eq!(arrived.recv().await.as_deref(), Some("sendTransaction"));
+    survivor.1.kill();
+    let failed = tokio::time::timeout(Duration::from_secs(10), writing).await.unwrap().unwrap().unwrap_err();
+    assert_eq!(replay::hop_failure(&failed).map(|failure| failure.kind), Some(HopFailureKind::Unreachable));
+    assert!(arrived.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn a_users_queries_about_different_addresses_leave_through_different_exits() {
+    let network = network().await;
+    let mut seen = vec![Arc::new(Mutex::new(Vec::new()))];
+    network.rpc_manager.register_provider(provider(seen[0].clone())).await.unwrap();
+    for _ in 0..2 {
+        let rpc_manager = Arc::new(StoredRpcManager::new(Arc::new(MemoryStorage::new())));
+        let at_exit = Arc::new(Mutex::new(Vec::new()));
+        rpc_manager.register_provider(provider(at_exit.clone())).await.unwrap();
+        add_exit(&network, rpc_manager).await;
+        seen.push(at_exit);
+    }
+
+    let config = EntryConfig {
+        scatter: ScatterConfig {
+            secret: Some("shared".to_string()),
+            ..ScatterConfig::default()
+        },
+        ..EntryConfig::default()
+    };
+    let (service, users) = fixtures::entry(network.router.clone(), &config).await;
+    let user = users.create_user("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T").await.unwrap();
+    let mapping = RpcMapping {
+        chain: Some(Chain::Solana),
+        address_scatter: true,
+        ..fixtures::mapping()
+    };
+    users.add_rpc_mapping(user.id, mapping.clone()).await.unwrap();
+    let query = |address: &str| json!({ "jsonrpc": "2.0", "id": 1, "method": "getSignaturesForAddress", "params": [address] });
+
+    // Three addresses the user's queries aren't all scattered onto one circuit of, as
+    // another entry node sharing the secret works out
+    let scatter = AddressScatter::new(config.scatter.clone());
+    let bucket = |address: &String| scatter.bucket(user.id, Some(Chain::Solana), &query(address)).unwrap();
+    let candidates: Vec<String> = (1..=32u8).map(|i| bs58::encode([i; 32]).into_string()).collect();
+    let other = candidates.iter().find(|address| bucket(address) != bucket(&candidates[0])).unwrap();
+    let third = candidates[1..].iter().find(|address| *address != other).unwrap();
+    let addresses = [candidates[0].clone(), other.clone(), third.clone()];
+
+    for _ in 0..2 {
+        for address in &addresses {
+            let request = serde_json::to_vec(&query(address)).unwrap();
+            let ctx = RequestContext::new(&user.api_key).with_mapping(Some(mapping.id));
+            service.handle_request(ctx, &request).await.unwrap();
+        }
+    }
+
+    // Each address kept to one exit, and together they crossed more than one
+    let exit_of = |address: &str| -> Vec<usize> {
+        (0..seen.len())
+            .filter(|&exit| seen[exit].lock().unwrap().iter().any(|request| request["params"][0] == address))
+            .collect()
+    };
+    let mut used = HashSet::new();
+    for address in &addresses {
+        let exit = exit_of(address);
+        assert_eq!(exit.len(), 1, "{} left through {:?}", address, exit);
+        let queried = seen[exit[0]].lock().unwrap().iter().filter(|request| request["params"][0] == address.as_str()).count();
+        assert_eq!(queried, 2);
+        used.insert(exit[0]);
+    }
+    assert!(used.len() >= 2, "every address left through exit {:?}", used);
+}
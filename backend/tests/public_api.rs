@@ -0,0 +1,77 @@
+//! The public paths the crate had before it was split into modules
+//!
+//! Binaries and external users import these paths, so each must keep resolving to the
+//! same kind of item: a type, trait, function or constant that moves or disappears, or a
+//! module that loses its re-export, fails this test at compile time. Items added since are
+//! free to come and go; only this snapshot is held stable.
+//!
+//! The same paths are kept in `public-api.txt` as rendered by `cargo public-api`, one
+//! `<kind> <path>` per line, and checked against the API rustdoc documents. Building
+//! rustdoc JSON takes a nightly toolchain, so that check only runs when asked for, with
+//! `cargo test --test public_api -- --ignored`.
+
+#![allow(unused_imports)]
+
+use darknode_backend::capabilities::{supports, take_hints, CapabilityError, KNOWN_CAPABILITIES};
+use darknode_backend::clock::{admit, is_fresh, Deadline, MAX_CLOCK_SKEW, MAX_REQUEST_AGE};
+use darknode_backend::coordinator::CoordinatorService;
+use darknode_backend::dashboard::{Bucket, Dashboard, DashboardConfig, DashboardMetric, GroupSummary, Overview};
+use darknode_backend::diagnostics::{CircuitBuildError, CircuitBuildFailure, CircuitBuildReport, CircuitUnavailable, FailureLog};
+use darknode_backend::dns::{check_egress, is_public_address, ProviderResolver, ResolveError, ResolverConfig, ResolverMode};
+use darknode_backend::entry_node::{is_streamable, EntryNodeService};
+use darknode_backend::exit_node::ExitNodeService;
+use darknode_backend::heartbeat::{ActivityCounters, HeartbeatSource};
+use darknode_backend::identity::{verify_node_signature, KeyRotator, NodeIdentity, PublishNextKeyRequest, RotationOutcome};
+use darknode_backend::impls::{CryptoImpl, RouterImpl};
+use darknode_backend::methods::{is_mutating, method_name, set_extension, EXTENSION_KEY};
+use darknode_backend::probe::{next_interval, ProbeConfig, ProbeScheduler};
+use darknode_backend::quorum::{agree, group, majority, QuorumError};
+use darknode_backend::quota::{next_utc_midnight, QuotaCap, QuotaExceeded, UsageTracker};
+use darknode_backend::routing_node::RoutingNodeService;
+use darknode_backend::traits::{Crypto, NodeManager, RequestSanitizer, ResponseStream, Router, RpcManager, UserManager};
+use darknode_backend::types::{
+    Circuit, CircuitId, CryptoKey, EncryptedData, ExitPayload, Heartbeat, Node, NodeCounters, NodeId, NodeRole, NodeStatus,
+    Plan, PriorityClass, Request, Response, ResponseChunk, RpcMapping, RpcProvider, User,
+};
+
+/// The functions and constants above, by value, so a change of kind fails to compile too
+#[test]
+fn items_keep_their_kinds() {
+    let _ = (supports, take_hints, admit, is_fresh, check_egress, is_public_address, is_streamable);
+    let _ = (is_mutating, method_name, set_extension, next_interval, next_utc_midnight);
+    let _ = (KNOWN_CAPABILITIES, MAX_CLOCK_SKEW, MAX_REQUEST_AGE, EXTENSION_KEY);
+}
+
+/// The leading `<kind> <path>` of an item as `cargo public-api` renders it, if it is one
+fn kind_and_path(item: &str) -> Option<String> {
+    let mut words = item.strip_prefix("pub ")?.split_whitespace().peekable();
+    // Qualifiers of functions come before `fn`, while a `const` item is followed by its path
+    while let Some(word) = words.next_if(|word| ["async", "unsafe", "const"].contains(word)) {
+        if word == "const" && words.peek().map_or(true, |next| next.contains("::")) {
+            let path = words.next()?.trim_end_matches(':');
+            return Some(format!("const {}", path));
+        }
+    }
+    let kind = words.next()?;
+    if !["mod", "struct", "enum", "trait", "fn", "static", "type", "union", "macro"].contains(&kind) {
+        return None;
+    }
+    let path = words.next()?.split(['(', '<', '=']).next()?.trim_end_matches(':');
+    Some(format!("{} {}", kind, path))
+}
+
+#[test]
+#[ignore = "builds rustdoc JSON, which takes a nightly toolchain"]
+fn the_snapshot_is_still_public() {
+    let json = rustdoc_json::Builder::default()
+        .toolchain("nightly")
+        .manifest_path(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))
+        .build()
+        .unwrap();
+    let api = public_api::Builder::from_rustdoc_json(json).build().unwrap();
+    let public: std::collections::HashSet<String> = api.items().filter_map(|item| kind_and_path(&item.to_string())).collect();
+
+    let snapshot = include_str!("public-api.txt");
+    let missing: Vec<&str> = snapshot.lines().filter(|line| !public.contains(*line)).collect();
+    assert!(missing.is_empty(), "no longer public:\n{}", missing.join("\n"));
+}